@@ -22,6 +22,7 @@ use devices::pl030::PL030_AMBA_ID;
 use devices::IommuDevType;
 use devices::PciAddress;
 use devices::PciInterruptPin;
+use devices::FW_CFG_MMIO_SIZE;
 use hypervisor::PsciVersion;
 use hypervisor::PSCI_0_2;
 use hypervisor::PSCI_1_0;
@@ -30,6 +31,7 @@ use rand::RngCore;
 use vm_memory::GuestAddress;
 use vm_memory::GuestMemory;
 
+use crate::AARCH64_FW_CFG_ADDR;
 // These are GIC address-space location constants.
 use crate::AARCH64_GIC_CPUI_BASE;
 use crate::AARCH64_GIC_CPUI_SIZE;
@@ -572,6 +574,17 @@ fn create_battery_node(fdt: &mut Fdt, mmio_base: u64, irq: u32) -> Result<()> {
     Ok(())
 }
 
+fn create_fw_cfg_node(fdt: &mut Fdt) -> Result<()> {
+    let fw_cfg_name = format!("fw-cfg@{:x}", AARCH64_FW_CFG_ADDR);
+    let reg = [AARCH64_FW_CFG_ADDR, FW_CFG_MMIO_SIZE];
+
+    let fw_cfg_node = fdt.root_mut().subnode_mut(&fw_cfg_name)?;
+    fw_cfg_node.set_prop("compatible", "qemu,fw-cfg-mmio")?;
+    fw_cfg_node.set_prop("reg", &reg)?;
+    fw_cfg_node.set_prop("dma-coherent", ())?;
+    Ok(())
+}
+
 fn create_vmwdt_node(fdt: &mut Fdt, vmwdt_cfg: VmWdtConfig, num_cpus: u32) -> Result<()> {
     let vmwdt_name = format!("vmwdt@{:x}", vmwdt_cfg.base);
     let reg = [vmwdt_cfg.base, vmwdt_cfg.size];
@@ -654,6 +667,7 @@ pub fn create_fdt(
     psci_version: PsciVersion,
     swiotlb: Option<(Option<GuestAddress>, u64)>,
     bat_mmio_base_and_irq: Option<(u64, u32)>,
+    has_fw_cfg: bool,
     vmwdt_cfg: VmWdtConfig,
     dump_device_tree_blob: Option<PathBuf>,
     vm_generator: &impl Fn(&mut Fdt, &BTreeMap<&str, u32>) -> cros_fdt::Result<()>,
@@ -710,6 +724,9 @@ pub fn create_fdt(
     if let Some((bat_mmio_base, bat_irq)) = bat_mmio_base_and_irq {
         create_battery_node(&mut fdt, bat_mmio_base, bat_irq)?;
     }
+    if has_fw_cfg {
+        create_fw_cfg_node(&mut fdt)?;
+    }
     create_vmwdt_node(&mut fdt, vmwdt_cfg, num_cpus)?;
     create_kvm_cpufreq_node(&mut fdt)?;
     vm_generator(&mut fdt, &phandles)?;
@@ -33,6 +33,7 @@ use devices::Bus;
 use devices::BusDeviceObj;
 use devices::BusError;
 use devices::BusType;
+use devices::FwCfgParameters;
 use devices::IrqChip;
 use devices::IrqChipAArch64;
 use devices::IrqEventSource;
@@ -43,6 +44,8 @@ use devices::PciRootCommand;
 use devices::Serial;
 #[cfg(any(target_os = "android", target_os = "linux"))]
 use devices::VirtCpufreq;
+use devices::FW_CFG_MAX_FILE_SLOTS;
+use devices::FW_CFG_MMIO_SIZE;
 #[cfg(feature = "gdb")]
 use gdbstub::arch::Arch;
 #[cfg(feature = "gdb")]
@@ -192,6 +195,9 @@ const AARCH64_VMWDT_ADDR: u64 = 0x3000;
 // The virtual watchdog device gets one 4k page
 const AARCH64_VMWDT_SIZE: u64 = 0x1000;
 
+// Place the fw_cfg device at page 4
+const AARCH64_FW_CFG_ADDR: u64 = 0x4000;
+
 // PCI MMIO configuration region base address.
 const AARCH64_PCI_CFG_BASE: u64 = 0x10000;
 // PCI MMIO configuration region size.
@@ -239,6 +245,8 @@ pub enum Error {
     CreateEvent(base::Error),
     #[error("FDT could not be created: {0}")]
     CreateFdt(cros_fdt::Error),
+    #[error("failed to create fw_cfg device: {0}")]
+    CreateFwCfgDevice(devices::FwCfgError),
     #[error("failed to create GIC: {0}")]
     CreateGICFailure(base::Error),
     #[error("failed to create a PCI root hub: {0}")]
@@ -666,6 +674,10 @@ impl arch::LinuxArch for AArch64 {
             vmwdt_control_tube,
         )?;
 
+        if components.fw_cfg_enable {
+            Self::add_fw_cfg_device(&mmio_bus, &mem, components.fw_cfg_parameters.clone())?;
+        }
+
         let com_evt_1_3 = devices::IrqEdgeEvent::new().map_err(Error::CreateEvent)?;
         let com_evt_2_4 = devices::IrqEdgeEvent::new().map_err(Error::CreateEvent)?;
         let serial_devices = arch::add_serial_devices(
@@ -829,6 +841,7 @@ impl arch::LinuxArch for AArch64 {
                 )
             }),
             bat_mmio_base_and_irq,
+            components.fw_cfg_enable,
             vmwdt_cfg,
             dump_device_tree_blob,
             &|writer, phandles| vm.create_fdt(writer, phandles),
@@ -1257,6 +1270,35 @@ impl AArch64 {
         Ok(())
     }
 
+    /// Adds the memory-mapped fw_cfg device, through which firmware such as ArmVirt can read
+    /// files provided on the command line.
+    ///
+    /// The `bootorder` file is not provided, since the device paths in it are x86-specific.
+    ///
+    /// # Arguments
+    ///
+    /// * `bus` - The bus to add the device to.
+    /// * `mem` - The guest memory, used for DMA transfers.
+    /// * `fw_cfg_parameters` - Files specified on the command line.
+    fn add_fw_cfg_device(
+        bus: &Bus,
+        mem: &GuestMemory,
+        fw_cfg_parameters: Vec<FwCfgParameters>,
+    ) -> Result<()> {
+        let mut fw_cfg = devices::FwCfgDevice::new_mmio(FW_CFG_MAX_FILE_SLOTS, fw_cfg_parameters)
+            .map_err(Error::CreateFwCfgDevice)?;
+        fw_cfg.set_guest_memory(mem.clone());
+
+        bus.insert(
+            Arc::new(Mutex::new(fw_cfg)),
+            AARCH64_FW_CFG_ADDR,
+            FW_CFG_MMIO_SIZE,
+        )
+        .expect("failed to add fw_cfg device");
+
+        Ok(())
+    }
+
     /// Get ARM-specific features for vcpu with index `vcpu_id`.
     ///
     /// # Arguments
@@ -0,0 +1,136 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! The subset of the alsa-lib PCM API used by this crate.
+
+#![allow(non_camel_case_types)]
+
+use std::os::raw::c_char;
+use std::os::raw::c_int;
+use std::os::raw::c_long;
+use std::os::raw::c_uint;
+use std::os::raw::c_ulong;
+use std::os::raw::c_void;
+
+// Opaque blob
+#[repr(C)]
+pub struct snd_pcm_t {
+    _data: [u8; 0],
+    _marker: core::marker::PhantomData<(*mut u8, core::marker::PhantomPinned)>,
+}
+
+// Opaque blob
+#[repr(C)]
+pub struct snd_pcm_hw_params_t {
+    _data: [u8; 0],
+    _marker: core::marker::PhantomData<(*mut u8, core::marker::PhantomPinned)>,
+}
+
+// Opaque blob
+#[repr(C)]
+pub struct snd_pcm_sw_params_t {
+    _data: [u8; 0],
+    _marker: core::marker::PhantomData<(*mut u8, core::marker::PhantomPinned)>,
+}
+
+pub type snd_pcm_uframes_t = c_ulong;
+pub type snd_pcm_sframes_t = c_long;
+pub type snd_pcm_stream_t = c_int;
+pub type snd_pcm_access_t = c_int;
+pub type snd_pcm_format_t = c_int;
+
+pub const SND_PCM_STREAM_PLAYBACK: snd_pcm_stream_t = 0;
+pub const SND_PCM_STREAM_CAPTURE: snd_pcm_stream_t = 1;
+
+pub const SND_PCM_NONBLOCK: c_int = 0x1;
+
+pub const SND_PCM_ACCESS_RW_INTERLEAVED: snd_pcm_access_t = 3;
+
+pub const SND_PCM_FORMAT_U8: snd_pcm_format_t = 1;
+pub const SND_PCM_FORMAT_S16_LE: snd_pcm_format_t = 2;
+pub const SND_PCM_FORMAT_S24_LE: snd_pcm_format_t = 6;
+pub const SND_PCM_FORMAT_S32_LE: snd_pcm_format_t = 10;
+
+#[link(name = "asound")]
+extern "C" {
+    pub fn snd_strerror(errnum: c_int) -> *const c_char;
+
+    pub fn snd_pcm_open(
+        pcm: *mut *mut snd_pcm_t,
+        name: *const c_char,
+        stream: snd_pcm_stream_t,
+        mode: c_int,
+    ) -> c_int;
+    pub fn snd_pcm_close(pcm: *mut snd_pcm_t) -> c_int;
+    pub fn snd_pcm_prepare(pcm: *mut snd_pcm_t) -> c_int;
+    pub fn snd_pcm_start(pcm: *mut snd_pcm_t) -> c_int;
+    pub fn snd_pcm_drop(pcm: *mut snd_pcm_t) -> c_int;
+    pub fn snd_pcm_recover(pcm: *mut snd_pcm_t, err: c_int, silent: c_int) -> c_int;
+    pub fn snd_pcm_avail_update(pcm: *mut snd_pcm_t) -> snd_pcm_sframes_t;
+    pub fn snd_pcm_writei(
+        pcm: *mut snd_pcm_t,
+        buffer: *const c_void,
+        size: snd_pcm_uframes_t,
+    ) -> snd_pcm_sframes_t;
+    pub fn snd_pcm_readi(
+        pcm: *mut snd_pcm_t,
+        buffer: *mut c_void,
+        size: snd_pcm_uframes_t,
+    ) -> snd_pcm_sframes_t;
+
+    pub fn snd_pcm_hw_params_malloc(ptr: *mut *mut snd_pcm_hw_params_t) -> c_int;
+    pub fn snd_pcm_hw_params_free(obj: *mut snd_pcm_hw_params_t);
+    pub fn snd_pcm_hw_params_any(pcm: *mut snd_pcm_t, params: *mut snd_pcm_hw_params_t) -> c_int;
+    pub fn snd_pcm_hw_params_set_access(
+        pcm: *mut snd_pcm_t,
+        params: *mut snd_pcm_hw_params_t,
+        access: snd_pcm_access_t,
+    ) -> c_int;
+    pub fn snd_pcm_hw_params_set_format(
+        pcm: *mut snd_pcm_t,
+        params: *mut snd_pcm_hw_params_t,
+        val: snd_pcm_format_t,
+    ) -> c_int;
+    pub fn snd_pcm_hw_params_set_channels(
+        pcm: *mut snd_pcm_t,
+        params: *mut snd_pcm_hw_params_t,
+        val: c_uint,
+    ) -> c_int;
+    pub fn snd_pcm_hw_params_set_rate(
+        pcm: *mut snd_pcm_t,
+        params: *mut snd_pcm_hw_params_t,
+        val: c_uint,
+        dir: c_int,
+    ) -> c_int;
+    pub fn snd_pcm_hw_params_set_period_size_near(
+        pcm: *mut snd_pcm_t,
+        params: *mut snd_pcm_hw_params_t,
+        val: *mut snd_pcm_uframes_t,
+        dir: *mut c_int,
+    ) -> c_int;
+    pub fn snd_pcm_hw_params_set_buffer_size_near(
+        pcm: *mut snd_pcm_t,
+        params: *mut snd_pcm_hw_params_t,
+        val: *mut snd_pcm_uframes_t,
+    ) -> c_int;
+    pub fn snd_pcm_hw_params(pcm: *mut snd_pcm_t, params: *mut snd_pcm_hw_params_t) -> c_int;
+
+    pub fn snd_pcm_sw_params_malloc(ptr: *mut *mut snd_pcm_sw_params_t) -> c_int;
+    pub fn snd_pcm_sw_params_free(obj: *mut snd_pcm_sw_params_t);
+    pub fn snd_pcm_sw_params_current(
+        pcm: *mut snd_pcm_t,
+        params: *mut snd_pcm_sw_params_t,
+    ) -> c_int;
+    pub fn snd_pcm_sw_params_set_start_threshold(
+        pcm: *mut snd_pcm_t,
+        params: *mut snd_pcm_sw_params_t,
+        val: snd_pcm_uframes_t,
+    ) -> c_int;
+    pub fn snd_pcm_sw_params_set_avail_min(
+        pcm: *mut snd_pcm_t,
+        params: *mut snd_pcm_sw_params_t,
+        val: snd_pcm_uframes_t,
+    ) -> c_int;
+    pub fn snd_pcm_sw_params(pcm: *mut snd_pcm_t, params: *mut snd_pcm_sw_params_t) -> c_int;
+}
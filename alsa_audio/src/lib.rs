@@ -0,0 +1,419 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! An `audio_streams` backend that plays and records through ALSA PCM devices.
+//!
+//! Streams are opened in non-blocking mode and paced by the number of frames the device reports
+//! as available, so each guest period is handed to (or taken from) ALSA as soon as the device has
+//! room (or data) for it.
+
+mod ffi;
+mod pcm;
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+use audio_streams::capture::AsyncCaptureBuffer;
+use audio_streams::capture::AsyncCaptureBufferStream;
+use audio_streams::capture::CaptureBufferStream;
+use audio_streams::AsyncBufferCommit;
+use audio_streams::AsyncPlaybackBuffer;
+use audio_streams::AsyncPlaybackBufferStream;
+use audio_streams::AudioStreamsExecutor;
+use audio_streams::BoxError;
+use audio_streams::NoopStreamControl;
+use audio_streams::PlaybackBufferStream;
+use audio_streams::SampleFormat;
+use audio_streams::StreamControl;
+use audio_streams::StreamEffect;
+use audio_streams::StreamSource;
+use audio_streams::StreamSourceGenerator;
+use base::warn;
+pub use pcm::AlsaErrno;
+use pcm::Direction;
+use pcm::Pcm;
+use remain::sorted;
+use thiserror::Error;
+
+/// The PCM device used when none is configured.
+pub const DEFAULT_DEVICE: &str = "default";
+
+/// Number of guest periods in the ALSA ring buffer.
+const PERIODS: usize = 4;
+
+#[sorted]
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("failed to query available frames: {0}")]
+    Avail(AlsaErrno),
+    #[error("failed to configure {0}: {1}")]
+    Configure(&'static str, AlsaErrno),
+    #[error("invalid PCM device name: {0}")]
+    InvalidDeviceName(String),
+    #[error("failed to open PCM device {0}: {1}")]
+    Open(String, AlsaErrno),
+    #[error("failed to prepare PCM: {0}")]
+    Prepare(AlsaErrno),
+    #[error("failed to read frames: {0}")]
+    Read(AlsaErrno),
+    #[error("failed to start PCM: {0}")]
+    Start(AlsaErrno),
+    #[error("synchronous streams are not supported")]
+    SyncStreamsUnsupported,
+    #[error("failed to write frames: {0}")]
+    Write(AlsaErrno),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Returns how long the device takes to play or record `frames` frames, rounded up to a
+/// millisecond so that pacing never busy-loops.
+fn frames_to_duration(frames: usize, frame_rate: u32) -> Duration {
+    Duration::from_nanos(frames as u64 * 1_000_000_000 / u64::from(frame_rate))
+        .max(Duration::from_millis(1))
+}
+
+/// Common state of playback and capture streams.
+struct AlsaStream {
+    pcm: Pcm,
+    frame_size: usize,
+    frame_rate: u32,
+    period_frames: usize,
+}
+
+impl AlsaStream {
+    fn new(
+        device: &str,
+        direction: Direction,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        period_frames: usize,
+    ) -> Result<AlsaStream> {
+        let mut pcm = Pcm::open(device, direction)?;
+        let config = pcm.configure(format, num_channels, frame_rate, period_frames, PERIODS)?;
+        if config.period_frames != period_frames {
+            warn!(
+                "ALSA device {} uses {} frame periods instead of {}",
+                device, config.period_frames, period_frames
+            );
+        }
+        Ok(AlsaStream {
+            pcm,
+            frame_size: format.sample_bytes() * num_channels,
+            frame_rate,
+            period_frames,
+        })
+    }
+
+    /// Waits until the device can take (or provide) a full guest period.
+    async fn wait_for_period(&mut self, ex: &dyn AudioStreamsExecutor) -> Result<()> {
+        loop {
+            let avail = self.pcm.avail()?;
+            if avail >= self.period_frames {
+                return Ok(());
+            }
+            let wait = frames_to_duration(self.period_frames - avail, self.frame_rate);
+            if let Err(e) = ex.delay(wait).await {
+                warn!("ALSA stream delay failed: {}", e);
+            }
+        }
+    }
+}
+
+/// Writes committed playback periods to the device.
+struct PlaybackCommit {
+    stream: AlsaStream,
+    // Points at `AlsaPlaybackStream::buffer`, which is filled by the `AsyncPlaybackBuffer`
+    // borrowing it before `commit` is called.
+    buffer_ptr: *const u8,
+    buffer_len: usize,
+}
+
+// SAFETY: `buffer_ptr` points into the heap allocation owned by the same `AlsaPlaybackStream`,
+// which moves between threads together with it.
+unsafe impl Send for PlaybackCommit {}
+
+#[async_trait(?Send)]
+impl AsyncBufferCommit for PlaybackCommit {
+    async fn commit(&mut self, nframes: usize) {
+        // SAFETY: `buffer_ptr` and `buffer_len` describe the stream's playback buffer, which is
+        // alive and no longer mutably borrowed once the playback buffer commits.
+        let buffer = unsafe { std::slice::from_raw_parts(self.buffer_ptr, self.buffer_len) };
+        let nframes = nframes.min(self.buffer_len / self.stream.frame_size);
+        match self
+            .stream
+            .pcm
+            .write(buffer, nframes, self.stream.frame_size)
+        {
+            Ok(written) if written < nframes => {
+                warn!("ALSA playback dropped {} frames", nframes - written)
+            }
+            Ok(_) => {}
+            Err(e) => warn!("ALSA playback dropped {} frames: {}", nframes, e),
+        }
+    }
+}
+
+/// A playback stream that writes one guest period to ALSA per buffer.
+pub struct AlsaPlaybackStream {
+    buffer: Box<[u8]>,
+    commit: PlaybackCommit,
+}
+
+impl AlsaPlaybackStream {
+    fn new(
+        device: &str,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        period_frames: usize,
+    ) -> Result<AlsaPlaybackStream> {
+        let stream = AlsaStream::new(
+            device,
+            Direction::Playback,
+            num_channels,
+            format,
+            frame_rate,
+            period_frames,
+        )?;
+        let buffer = vec![0; period_frames * stream.frame_size].into_boxed_slice();
+        let commit = PlaybackCommit {
+            stream,
+            buffer_ptr: buffer.as_ptr(),
+            buffer_len: buffer.len(),
+        };
+        Ok(AlsaPlaybackStream { buffer, commit })
+    }
+}
+
+#[async_trait(?Send)]
+impl AsyncPlaybackBufferStream for AlsaPlaybackStream {
+    async fn next_playback_buffer<'a>(
+        &'a mut self,
+        ex: &dyn AudioStreamsExecutor,
+    ) -> std::result::Result<AsyncPlaybackBuffer<'a>, BoxError> {
+        self.commit.stream.wait_for_period(ex).await?;
+        let frame_size = self.commit.stream.frame_size;
+        Ok(
+            AsyncPlaybackBuffer::new(frame_size, self.buffer.as_mut(), &mut self.commit)
+                .map_err(Box::new)?,
+        )
+    }
+}
+
+/// Capture buffers are read from the device before they are handed out, so committing them has
+/// nothing left to do.
+struct CaptureCommit;
+
+#[async_trait(?Send)]
+impl AsyncBufferCommit for CaptureCommit {
+    async fn commit(&mut self, _nframes: usize) {}
+}
+
+/// A capture stream that reads one guest period from ALSA per buffer.
+pub struct AlsaCaptureStream {
+    stream: AlsaStream,
+    buffer: Box<[u8]>,
+    commit: CaptureCommit,
+}
+
+impl AlsaCaptureStream {
+    fn new(
+        device: &str,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        period_frames: usize,
+    ) -> Result<AlsaCaptureStream> {
+        let stream = AlsaStream::new(
+            device,
+            Direction::Capture,
+            num_channels,
+            format,
+            frame_rate,
+            period_frames,
+        )?;
+        let buffer = vec![0; period_frames * stream.frame_size].into_boxed_slice();
+        Ok(AlsaCaptureStream {
+            stream,
+            buffer,
+            commit: CaptureCommit,
+        })
+    }
+}
+
+#[async_trait(?Send)]
+impl AsyncCaptureBufferStream for AlsaCaptureStream {
+    async fn next_capture_buffer<'a>(
+        &'a mut self,
+        ex: &dyn AudioStreamsExecutor,
+    ) -> std::result::Result<AsyncCaptureBuffer<'a>, BoxError> {
+        self.stream.wait_for_period(ex).await?;
+        let period_frames = self.stream.period_frames;
+        let frame_size = self.stream.frame_size;
+        let read = match self
+            .stream
+            .pcm
+            .read(&mut self.buffer, period_frames, frame_size)
+        {
+            Ok(read) => read,
+            Err(e) => {
+                warn!("ALSA capture read failed: {}", e);
+                0
+            }
+        };
+        if read < period_frames {
+            self.buffer[read * frame_size..].fill(0);
+        }
+        Ok(
+            AsyncCaptureBuffer::new(frame_size, self.buffer.as_mut(), &mut self.commit)
+                .map_err(Box::new)?,
+        )
+    }
+}
+
+/// Opens streams on a single ALSA PCM device.
+pub struct AlsaStreamSource {
+    device: String,
+}
+
+impl AlsaStreamSource {
+    pub fn new(device: String) -> AlsaStreamSource {
+        AlsaStreamSource { device }
+    }
+}
+
+impl StreamSource for AlsaStreamSource {
+    #[allow(clippy::type_complexity)]
+    fn new_playback_stream(
+        &mut self,
+        _num_channels: usize,
+        _format: SampleFormat,
+        _frame_rate: u32,
+        _buffer_size: usize,
+    ) -> std::result::Result<(Box<dyn StreamControl>, Box<dyn PlaybackBufferStream>), BoxError>
+    {
+        Err(Box::new(Error::SyncStreamsUnsupported))
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn new_async_playback_stream(
+        &mut self,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        buffer_size: usize,
+        _ex: &dyn AudioStreamsExecutor,
+    ) -> std::result::Result<(Box<dyn StreamControl>, Box<dyn AsyncPlaybackBufferStream>), BoxError>
+    {
+        let stream =
+            AlsaPlaybackStream::new(&self.device, num_channels, format, frame_rate, buffer_size)?;
+        Ok((Box::new(NoopStreamControl::new()), Box::new(stream)))
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn new_capture_stream(
+        &mut self,
+        _num_channels: usize,
+        _format: SampleFormat,
+        _frame_rate: u32,
+        _buffer_size: usize,
+        _effects: &[StreamEffect],
+    ) -> std::result::Result<(Box<dyn StreamControl>, Box<dyn CaptureBufferStream>), BoxError> {
+        Err(Box::new(Error::SyncStreamsUnsupported))
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn new_async_capture_stream(
+        &mut self,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        buffer_size: usize,
+        _effects: &[StreamEffect],
+        _ex: &dyn AudioStreamsExecutor,
+    ) -> std::result::Result<(Box<dyn StreamControl>, Box<dyn AsyncCaptureBufferStream>), BoxError>
+    {
+        let stream =
+            AlsaCaptureStream::new(&self.device, num_channels, format, frame_rate, buffer_size)?;
+        Ok((Box::new(NoopStreamControl::new()), Box::new(stream)))
+    }
+}
+
+/// Creates `AlsaStreamSource`s for one PCM device.
+pub struct AlsaStreamSourceGenerator {
+    device: String,
+}
+
+impl AlsaStreamSourceGenerator {
+    pub fn new(device: String) -> AlsaStreamSourceGenerator {
+        AlsaStreamSourceGenerator { device }
+    }
+}
+
+impl StreamSourceGenerator for AlsaStreamSourceGenerator {
+    fn generate(&self) -> std::result::Result<Box<dyn StreamSource>, BoxError> {
+        Ok(Box::new(AlsaStreamSource::new(self.device.clone())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cros_async::Executor;
+
+    use super::*;
+
+    // The "null" PCM is provided by alsa-lib itself and does not need any sound hardware.
+    const NULL_DEVICE: &str = "null";
+
+    #[test]
+    fn frames_to_duration_rounds_up_to_a_millisecond() {
+        assert_eq!(frames_to_duration(480, 48000), Duration::from_millis(10));
+        assert_eq!(frames_to_duration(1, 48000), Duration::from_millis(1));
+    }
+
+    #[test]
+    fn open_invalid_device() {
+        let mut source = AlsaStreamSource::new("no-such-alsa-device".to_owned());
+        let ex = Executor::new().unwrap();
+        assert!(source
+            .new_async_playback_stream(2, SampleFormat::S16LE, 48000, 480, &ex)
+            .is_err());
+    }
+
+    #[test]
+    fn playback_null_device() {
+        let ex = Executor::new().unwrap();
+        let mut source = AlsaStreamSource::new(NULL_DEVICE.to_owned());
+        let (_, mut stream) = source
+            .new_async_playback_stream(2, SampleFormat::S16LE, 48000, 480, &ex)
+            .unwrap();
+        ex.run_until(async {
+            for _ in 0..3 {
+                let mut buffer = stream.next_playback_buffer(&ex).await.unwrap();
+                assert_eq!(buffer.frame_capacity(), 480);
+                buffer.copy_cb(480 * 4, |out| out.fill(0x55)).unwrap();
+                buffer.commit().await;
+            }
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn capture_null_device() {
+        let ex = Executor::new().unwrap();
+        let mut source = AlsaStreamSource::new(NULL_DEVICE.to_owned());
+        let (_, mut stream) = source
+            .new_async_capture_stream(1, SampleFormat::S32LE, 44100, 441, &[], &ex)
+            .unwrap();
+        ex.run_until(async {
+            let mut buffer = stream.next_capture_buffer(&ex).await.unwrap();
+            let mut read = 0;
+            buffer.copy_cb(441 * 4, |data| read = data.len()).unwrap();
+            assert_eq!(read, 441 * 4);
+        })
+        .unwrap();
+    }
+}
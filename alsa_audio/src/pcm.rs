@@ -0,0 +1,311 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! A safe wrapper around an alsa-lib PCM handle.
+
+use std::ffi::CStr;
+use std::ffi::CString;
+use std::fmt;
+use std::os::raw::c_int;
+use std::os::raw::c_void;
+
+use audio_streams::SampleFormat;
+use base::warn;
+
+use crate::ffi::*;
+use crate::Error;
+use crate::Result;
+
+/// A negative error code returned by alsa-lib.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AlsaErrno(pub c_int);
+
+impl fmt::Display for AlsaErrno {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // SAFETY: snd_strerror returns a pointer to a static string for any error code.
+        let msg = unsafe { CStr::from_ptr(snd_strerror(self.0)) };
+        write!(f, "{} ({})", msg.to_string_lossy(), self.0)
+    }
+}
+
+fn check(ret: c_int) -> std::result::Result<c_int, AlsaErrno> {
+    if ret < 0 {
+        Err(AlsaErrno(ret))
+    } else {
+        Ok(ret)
+    }
+}
+
+fn check_frames(ret: snd_pcm_sframes_t) -> std::result::Result<usize, AlsaErrno> {
+    if ret < 0 {
+        Err(AlsaErrno(ret as c_int))
+    } else {
+        Ok(ret as usize)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Playback,
+    Capture,
+}
+
+/// The configuration the device accepted, which may differ from the one requested.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HwConfig {
+    pub period_frames: usize,
+    pub buffer_frames: usize,
+}
+
+/// An open PCM handle. The handle is opened in non-blocking mode; callers poll `avail` to pace
+/// reads and writes.
+pub struct Pcm {
+    pcm: *mut snd_pcm_t,
+    direction: Direction,
+}
+
+// SAFETY: alsa-lib PCM handles may be used from any thread as long as calls are not concurrent,
+// which `&mut self` receivers guarantee for every call that touches the stream state.
+unsafe impl Send for Pcm {}
+
+struct HwParams(*mut snd_pcm_hw_params_t);
+
+impl Drop for HwParams {
+    fn drop(&mut self) {
+        // SAFETY: the pointer was allocated by snd_pcm_hw_params_malloc.
+        unsafe { snd_pcm_hw_params_free(self.0) }
+    }
+}
+
+struct SwParams(*mut snd_pcm_sw_params_t);
+
+impl Drop for SwParams {
+    fn drop(&mut self) {
+        // SAFETY: the pointer was allocated by snd_pcm_sw_params_malloc.
+        unsafe { snd_pcm_sw_params_free(self.0) }
+    }
+}
+
+fn alsa_format(format: SampleFormat) -> snd_pcm_format_t {
+    match format {
+        SampleFormat::U8 => SND_PCM_FORMAT_U8,
+        SampleFormat::S16LE => SND_PCM_FORMAT_S16_LE,
+        SampleFormat::S24LE => SND_PCM_FORMAT_S24_LE,
+        SampleFormat::S32LE => SND_PCM_FORMAT_S32_LE,
+    }
+}
+
+impl Pcm {
+    /// Opens the PCM device `name`, e.g. "default" or "hw:0,0".
+    pub fn open(name: &str, direction: Direction) -> Result<Pcm> {
+        let cname = CString::new(name).map_err(|_| Error::InvalidDeviceName(name.to_owned()))?;
+        let stream = match direction {
+            Direction::Playback => SND_PCM_STREAM_PLAYBACK,
+            Direction::Capture => SND_PCM_STREAM_CAPTURE,
+        };
+        let mut pcm = std::ptr::null_mut();
+        // SAFETY: `pcm` is a valid out pointer and `cname` is a NUL terminated string.
+        check(unsafe { snd_pcm_open(&mut pcm, cname.as_ptr(), stream, SND_PCM_NONBLOCK) })
+            .map_err(|e| Error::Open(name.to_owned(), e))?;
+        Ok(Pcm { pcm, direction })
+    }
+
+    /// Configures interleaved access with the given parameters and prepares the device.
+    ///
+    /// The period is set as close as possible to `period_frames` and the ring buffer to
+    /// `periods` periods. Playback starts once two periods are queued.
+    pub fn configure(
+        &mut self,
+        format: SampleFormat,
+        channels: usize,
+        rate: u32,
+        period_frames: usize,
+        periods: usize,
+    ) -> Result<HwConfig> {
+        let mut hw = std::ptr::null_mut();
+        // SAFETY: `hw` is a valid out pointer.
+        check(unsafe { snd_pcm_hw_params_malloc(&mut hw) })
+            .map_err(|e| Error::Configure("allocate hw params", e))?;
+        let hw = HwParams(hw);
+        let mut period = period_frames as snd_pcm_uframes_t;
+        let mut buffer = (period_frames * periods) as snd_pcm_uframes_t;
+        let mut dir = 0;
+        // SAFETY: `self.pcm` is an open handle, `hw.0` was allocated above and the out pointers
+        // are valid for the duration of the calls.
+        unsafe {
+            check(snd_pcm_hw_params_any(self.pcm, hw.0))
+                .map_err(|e| Error::Configure("query hw params", e))?;
+            check(snd_pcm_hw_params_set_access(
+                self.pcm,
+                hw.0,
+                SND_PCM_ACCESS_RW_INTERLEAVED,
+            ))
+            .map_err(|e| Error::Configure("access", e))?;
+            check(snd_pcm_hw_params_set_format(
+                self.pcm,
+                hw.0,
+                alsa_format(format),
+            ))
+            .map_err(|e| Error::Configure("format", e))?;
+            check(snd_pcm_hw_params_set_channels(
+                self.pcm,
+                hw.0,
+                channels as u32,
+            ))
+            .map_err(|e| Error::Configure("channels", e))?;
+            check(snd_pcm_hw_params_set_rate(self.pcm, hw.0, rate, 0))
+                .map_err(|e| Error::Configure("rate", e))?;
+            check(snd_pcm_hw_params_set_period_size_near(
+                self.pcm,
+                hw.0,
+                &mut period,
+                &mut dir,
+            ))
+            .map_err(|e| Error::Configure("period size", e))?;
+            check(snd_pcm_hw_params_set_buffer_size_near(
+                self.pcm,
+                hw.0,
+                &mut buffer,
+            ))
+            .map_err(|e| Error::Configure("buffer size", e))?;
+            check(snd_pcm_hw_params(self.pcm, hw.0))
+                .map_err(|e| Error::Configure("apply hw params", e))?;
+        }
+        let config = HwConfig {
+            period_frames: period as usize,
+            buffer_frames: buffer as usize,
+        };
+
+        let mut sw = std::ptr::null_mut();
+        // SAFETY: `sw` is a valid out pointer.
+        check(unsafe { snd_pcm_sw_params_malloc(&mut sw) })
+            .map_err(|e| Error::Configure("allocate sw params", e))?;
+        let sw = SwParams(sw);
+        let start_threshold = match self.direction {
+            Direction::Playback => (config.period_frames * 2).min(config.buffer_frames),
+            // Capture is started explicitly.
+            Direction::Capture => 1,
+        };
+        // SAFETY: `self.pcm` is an open handle and `sw.0` was allocated above.
+        unsafe {
+            check(snd_pcm_sw_params_current(self.pcm, sw.0))
+                .map_err(|e| Error::Configure("query sw params", e))?;
+            check(snd_pcm_sw_params_set_start_threshold(
+                self.pcm,
+                sw.0,
+                start_threshold as snd_pcm_uframes_t,
+            ))
+            .map_err(|e| Error::Configure("start threshold", e))?;
+            check(snd_pcm_sw_params_set_avail_min(
+                self.pcm,
+                sw.0,
+                config.period_frames as snd_pcm_uframes_t,
+            ))
+            .map_err(|e| Error::Configure("avail min", e))?;
+            check(snd_pcm_sw_params(self.pcm, sw.0))
+                .map_err(|e| Error::Configure("apply sw params", e))?;
+        }
+
+        self.prepare()?;
+        Ok(config)
+    }
+
+    fn prepare(&mut self) -> Result<()> {
+        // SAFETY: `self.pcm` is an open handle.
+        check(unsafe { snd_pcm_prepare(self.pcm) }).map_err(Error::Prepare)?;
+        if self.direction == Direction::Capture {
+            // SAFETY: `self.pcm` is an open, prepared handle.
+            check(unsafe { snd_pcm_start(self.pcm) }).map_err(Error::Start)?;
+        }
+        Ok(())
+    }
+
+    /// Recovers from an underrun, overrun or suspend. Capture streams are restarted.
+    fn recover(&mut self, err: AlsaErrno) -> std::result::Result<(), AlsaErrno> {
+        warn!("ALSA {:?} stream recovering from {}", self.direction, err);
+        // SAFETY: `self.pcm` is an open handle.
+        check(unsafe { snd_pcm_recover(self.pcm, err.0, 1) })?;
+        if self.direction == Direction::Capture {
+            // SAFETY: `self.pcm` is an open, prepared handle.
+            check(unsafe { snd_pcm_start(self.pcm) })?;
+        }
+        Ok(())
+    }
+
+    /// Returns the number of frames that can be written (playback) or read (capture) without
+    /// blocking.
+    pub fn avail(&mut self) -> Result<usize> {
+        // SAFETY: `self.pcm` is an open handle.
+        match check_frames(unsafe { snd_pcm_avail_update(self.pcm) }) {
+            Err(e) => {
+                self.recover(e).map_err(Error::Avail)?;
+                // SAFETY: `self.pcm` is an open handle.
+                check_frames(unsafe { snd_pcm_avail_update(self.pcm) }).map_err(Error::Avail)
+            }
+            avail => avail.map_err(Error::Avail),
+        }
+    }
+
+    fn writei(&mut self, buf: &[u8], frames: usize) -> std::result::Result<usize, AlsaErrno> {
+        // SAFETY: the callers check that `buf` holds at least `frames` frames.
+        let ret = unsafe {
+            snd_pcm_writei(
+                self.pcm,
+                buf.as_ptr() as *const c_void,
+                frames as snd_pcm_uframes_t,
+            )
+        };
+        check_frames(ret)
+    }
+
+    fn readi(&mut self, buf: &mut [u8], frames: usize) -> std::result::Result<usize, AlsaErrno> {
+        // SAFETY: the callers check that `buf` has room for at least `frames` frames.
+        let ret = unsafe {
+            snd_pcm_readi(
+                self.pcm,
+                buf.as_mut_ptr() as *mut c_void,
+                frames as snd_pcm_uframes_t,
+            )
+        };
+        check_frames(ret)
+    }
+
+    /// Writes `frames` interleaved frames from `buf`, recovering once from an underrun. Returns
+    /// the number of frames the device accepted.
+    pub fn write(&mut self, buf: &[u8], frames: usize, frame_size: usize) -> Result<usize> {
+        assert!(buf.len() >= frames * frame_size);
+        match self.writei(buf, frames) {
+            Err(e) if e.0 != -libc::EAGAIN => {
+                self.recover(e).map_err(Error::Write)?;
+                self.writei(buf, frames).map_err(Error::Write)
+            }
+            r => r.map_err(Error::Write),
+        }
+    }
+
+    /// Reads up to `frames` interleaved frames into `buf`, recovering once from an overrun.
+    /// Returns the number of frames read.
+    pub fn read(&mut self, buf: &mut [u8], frames: usize, frame_size: usize) -> Result<usize> {
+        assert!(buf.len() >= frames * frame_size);
+        match self.readi(buf, frames) {
+            Err(e) if e.0 != -libc::EAGAIN => {
+                self.recover(e).map_err(Error::Read)?;
+                self.readi(buf, frames).map_err(Error::Read)
+            }
+            r => r.map_err(Error::Read),
+        }
+    }
+}
+
+impl Drop for Pcm {
+    fn drop(&mut self) {
+        // SAFETY: `self.pcm` is an open handle that is not used after this.
+        unsafe {
+            snd_pcm_drop(self.pcm);
+            if let Err(e) = check(snd_pcm_close(self.pcm)) {
+                warn!("failed to close ALSA PCM: {}", e);
+            }
+        }
+    }
+}
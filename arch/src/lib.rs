@@ -31,6 +31,8 @@ use base::FileReadWriteAtVolatile;
 use base::RecvTube;
 use base::SendTube;
 use base::Tube;
+#[cfg(target_arch = "x86_64")]
+use devices::virtio::TpmBackend;
 use devices::virtio::VirtioDevice;
 use devices::BarRange;
 use devices::Bus;
@@ -389,6 +391,9 @@ pub struct VmComponents {
     #[cfg(target_arch = "x86_64")]
     pub smbios: SmbiosOptions,
     pub swiotlb: Option<u64>,
+    /// Backend of the TPM CRB device, if the guest has one.
+    #[cfg(target_arch = "x86_64")]
+    pub tpm_crb: Option<Box<dyn TpmBackend>>,
     pub vcpu_affinity: Option<VcpuAffinity>,
     pub vcpu_count: usize,
     pub vm_image: VmImage,
@@ -301,6 +301,8 @@ mod tests {
                 out_timestamp: false,
                 debugcon_port: 0,
                 pci_address: None,
+                address: None,
+                ring_size: None,
             },
         );
 
@@ -347,6 +349,8 @@ mod tests {
                 out_timestamp: false,
                 debugcon_port: 0,
                 pci_address: None,
+                address: None,
+                ring_size: None,
             },
         );
 
@@ -366,6 +370,8 @@ mod tests {
                 out_timestamp: false,
                 debugcon_port: 0,
                 pci_address: None,
+                address: None,
+                ring_size: None,
             },
         );
 
@@ -413,6 +419,8 @@ mod tests {
                 out_timestamp: false,
                 debugcon_port: 0,
                 pci_address: None,
+                address: None,
+                ring_size: None,
             },
         );
 
@@ -0,0 +1,481 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Capture streams that play the contents of an audio file in a loop.
+//!
+//! WAV files are decoded once and converted to the format, channel count and frame rate of each
+//! stream the guest opens. Any other file is treated as raw PCM which is played as-is, so it has
+//! to match the format chosen by the guest.
+
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+use async_trait::async_trait;
+use audio_streams::capture::AsyncCaptureBuffer;
+use audio_streams::capture::AsyncCaptureBufferStream;
+use audio_streams::AsyncBufferCommit;
+use audio_streams::AudioStreamsExecutor;
+use audio_streams::BoxError;
+use audio_streams::NoopStreamControl;
+use audio_streams::PlaybackBufferStream;
+use audio_streams::SampleFormat;
+use audio_streams::StreamControl;
+use audio_streams::StreamEffect;
+use audio_streams::StreamSource;
+use audio_streams::StreamSourceGenerator;
+
+use crate::file_streams::Error;
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xfffe;
+
+/// Decoded contents of an audio file.
+enum AudioData {
+    /// Interleaved samples of a WAV file.
+    Wav {
+        channels: usize,
+        frame_rate: u32,
+        samples: Vec<f32>,
+    },
+    /// Bytes of a file without a recognized header.
+    Raw(Vec<u8>),
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+/// Decodes the samples of a WAV file with the given encoding to floats.
+fn decode_wav_samples(
+    tag: u16,
+    bits: u16,
+    block_align: usize,
+    channels: usize,
+    data: &[u8],
+) -> Result<Vec<f32>, Error> {
+    let sample_bytes = usize::from(bits).div_ceil(8);
+    let decode: fn(&[u8]) -> f32 = match (tag, bits) {
+        (WAVE_FORMAT_PCM, 8) => |s| (s[0] as f32 - 128.0) / 128.0,
+        (WAVE_FORMAT_PCM, 16) => |s| i16::from_le_bytes([s[0], s[1]]) as f32 / 32768.0,
+        (WAVE_FORMAT_PCM, 24) => {
+            |s| (i32::from_le_bytes([0, s[0], s[1], s[2]]) >> 8) as f32 / 8388608.0
+        }
+        (WAVE_FORMAT_PCM, 32) => {
+            |s| i32::from_le_bytes([s[0], s[1], s[2], s[3]]) as f32 / 2147483648.0
+        }
+        (WAVE_FORMAT_IEEE_FLOAT, 32) => |s| f32::from_le_bytes([s[0], s[1], s[2], s[3]]),
+        (format, bits) => return Err(Error::UnsupportedWav { format, bits }),
+    };
+    if sample_bytes * channels != block_align {
+        return Err(Error::InvalidWav(
+            "block alignment does not match the sample size",
+        ));
+    }
+    Ok(data.chunks_exact(sample_bytes).map(decode).collect())
+}
+
+impl AudioData {
+    fn parse(data: Vec<u8>) -> Result<AudioData, Error> {
+        if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+            return Ok(AudioData::Raw(data));
+        }
+
+        let mut format = None;
+        let mut offset = 12;
+        while offset + 8 <= data.len() {
+            let id = &data[offset..offset + 4];
+            let size = read_u32(&data, offset + 4) as usize;
+            let body = offset + 8;
+            let end = body
+                .checked_add(size)
+                .filter(|&end| end <= data.len())
+                .ok_or(Error::InvalidWav("truncated chunk"))?;
+            match id {
+                b"fmt " => {
+                    if size < 16 {
+                        return Err(Error::InvalidWav("fmt chunk too short"));
+                    }
+                    let mut tag = read_u16(&data, body);
+                    if tag == WAVE_FORMAT_EXTENSIBLE {
+                        if size < 40 {
+                            return Err(Error::InvalidWav("extensible fmt chunk too short"));
+                        }
+                        // The sub format GUID starts with the actual format tag.
+                        tag = read_u16(&data, body + 24);
+                    }
+                    format = Some((
+                        tag,
+                        read_u16(&data, body + 2) as usize,
+                        read_u32(&data, body + 4),
+                        read_u16(&data, body + 12) as usize,
+                        read_u16(&data, body + 14),
+                    ));
+                }
+                b"data" => {
+                    let (tag, channels, frame_rate, block_align, bits) =
+                        format.ok_or(Error::InvalidWav("data chunk before fmt chunk"))?;
+                    if channels == 0 || frame_rate == 0 {
+                        return Err(Error::InvalidWav("no channels or zero frame rate"));
+                    }
+                    let samples =
+                        decode_wav_samples(tag, bits, block_align, channels, &data[body..end])?;
+                    return Ok(AudioData::Wav {
+                        channels,
+                        frame_rate,
+                        samples,
+                    });
+                }
+                _ => {}
+            }
+            // Chunks are padded to an even size.
+            offset = end + (size & 1);
+        }
+        Err(Error::InvalidWav("no data chunk"))
+    }
+
+    /// Returns the file contents converted to interleaved samples of `format` with
+    /// `num_channels` channels at `frame_rate`. The result holds at least one frame.
+    fn render(&self, num_channels: usize, format: SampleFormat, frame_rate: u32) -> Vec<u8> {
+        let frame_size = num_channels * format.sample_bytes();
+        let mut out = match self {
+            AudioData::Raw(data) => data[..data.len() - data.len() % frame_size].to_vec(),
+            AudioData::Wav {
+                channels,
+                frame_rate: file_rate,
+                samples,
+            } => {
+                let mixed = remix(samples, *channels, num_channels);
+                let resampled = resample(&mixed, num_channels, *file_rate, frame_rate);
+                let mut out = vec![0; resampled.len() * format.sample_bytes()];
+                encode(format, &resampled, &mut out);
+                out
+            }
+        };
+        if out.is_empty() {
+            out.resize(frame_size, 0);
+        }
+        out
+    }
+}
+
+/// Converts interleaved frames of `from` channels to `to` channels. Mono output is the average of
+/// all input channels; otherwise output channels repeat the input channels in order.
+fn remix(samples: &[f32], from: usize, to: usize) -> Vec<f32> {
+    if from == to {
+        return samples.to_vec();
+    }
+    let frames = samples.chunks_exact(from);
+    if to == 1 {
+        return frames
+            .map(|frame| frame.iter().sum::<f32>() / from as f32)
+            .collect();
+    }
+    frames
+        .flat_map(|frame| (0..to).map(move |c| frame[c % from]))
+        .collect()
+}
+
+/// Converts interleaved frames from `from` Hz to `to` Hz with linear interpolation. The input is
+/// treated as a loop, so the last frame is interpolated towards the first one.
+fn resample(samples: &[f32], channels: usize, from: u32, to: u32) -> Vec<f32> {
+    let in_frames = samples.len() / channels;
+    if from == to || in_frames == 0 {
+        return samples.to_vec();
+    }
+    let out_frames = (in_frames as u64 * to as u64 / from as u64).max(1) as usize;
+    let step = from as f64 / to as f64;
+    let mut out = Vec::with_capacity(out_frames * channels);
+    for i in 0..out_frames {
+        let pos = i as f64 * step;
+        let a = pos as usize % in_frames;
+        let b = (a + 1) % in_frames;
+        let t = (pos - pos.floor()) as f32;
+        for c in 0..channels {
+            let (sa, sb) = (samples[a * channels + c], samples[b * channels + c]);
+            out.push(sa + (sb - sa) * t);
+        }
+    }
+    out
+}
+
+/// Converts floats to little endian samples of `format`, clipping values outside [-1.0, 1.0].
+fn encode(format: SampleFormat, src: &[f32], dst: &mut [u8]) {
+    for (s, d) in src.iter().zip(dst.chunks_exact_mut(format.sample_bytes())) {
+        let s = s.clamp(-1.0, 1.0) as f64;
+        match format {
+            SampleFormat::U8 => d[0] = (s * 128.0 + 128.0).round().min(255.0) as u8,
+            SampleFormat::S16LE => {
+                let v = (s * 32768.0).round().min(i16::MAX as f64) as i16;
+                d.copy_from_slice(&v.to_le_bytes());
+            }
+            SampleFormat::S24LE => {
+                let v = (s * 8388608.0).round().min(8388607.0) as i32;
+                d.copy_from_slice(&v.to_le_bytes());
+            }
+            SampleFormat::S32LE => {
+                let v = (s * 2147483648.0).round().min(i32::MAX as f64) as i32;
+                d.copy_from_slice(&v.to_le_bytes());
+            }
+        }
+    }
+}
+
+struct NoopCommit;
+
+#[async_trait(?Send)]
+impl AsyncBufferCommit for NoopCommit {
+    async fn commit(&mut self, _nframes: usize) {}
+}
+
+/// A capture stream that returns the rendered file contents in a loop, paced in real time.
+pub struct FileCaptureStream {
+    data: Vec<u8>,
+    /// Offset in `data` of the next byte to capture.
+    position: usize,
+    buffer: Vec<u8>,
+    frame_size: usize,
+    interval: Duration,
+    next_frame: Duration,
+    start_time: Option<Instant>,
+    commit: NoopCommit,
+}
+
+impl FileCaptureStream {
+    fn fill_buffer(&mut self) {
+        let mut filled = 0;
+        while filled < self.buffer.len() {
+            let len = (self.buffer.len() - filled).min(self.data.len() - self.position);
+            self.buffer[filled..filled + len]
+                .copy_from_slice(&self.data[self.position..self.position + len]);
+            filled += len;
+            self.position = (self.position + len) % self.data.len();
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl AsyncCaptureBufferStream for FileCaptureStream {
+    async fn next_capture_buffer<'a>(
+        &'a mut self,
+        ex: &dyn AudioStreamsExecutor,
+    ) -> Result<AsyncCaptureBuffer<'a>, BoxError> {
+        if let Some(start_time) = self.start_time {
+            let elapsed = start_time.elapsed();
+            if elapsed < self.next_frame {
+                ex.delay(self.next_frame - elapsed).await?;
+            }
+            self.next_frame += self.interval;
+        } else {
+            self.start_time = Some(Instant::now());
+            self.next_frame = self.interval;
+        }
+        self.fill_buffer();
+        Ok(AsyncCaptureBuffer::new(
+            self.frame_size,
+            &mut self.buffer,
+            &mut self.commit,
+        )?)
+    }
+}
+
+struct FileCaptureStreamSource {
+    data: Arc<AudioData>,
+}
+
+impl StreamSource for FileCaptureStreamSource {
+    fn new_playback_stream(
+        &mut self,
+        _num_channels: usize,
+        _format: SampleFormat,
+        _frame_rate: u32,
+        _buffer_size: usize,
+    ) -> Result<(Box<dyn StreamControl>, Box<dyn PlaybackBufferStream>), BoxError> {
+        Err(Box::new(Error::Unimplemented))
+    }
+
+    fn new_async_capture_stream(
+        &mut self,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        buffer_size: usize,
+        _effects: &[StreamEffect],
+        _ex: &dyn AudioStreamsExecutor,
+    ) -> Result<(Box<dyn StreamControl>, Box<dyn AsyncCaptureBufferStream>), BoxError> {
+        let frame_size = format.sample_bytes() * num_channels;
+        let interval = Duration::from_millis(buffer_size as u64 * 1000 / frame_rate as u64);
+        Ok((
+            Box::new(NoopStreamControl::new()),
+            Box::new(FileCaptureStream {
+                data: self.data.render(num_channels, format, frame_rate),
+                position: 0,
+                buffer: vec![0; buffer_size * frame_size],
+                frame_size,
+                interval,
+                next_frame: interval,
+                start_time: None,
+                commit: NoopCommit,
+            }),
+        ))
+    }
+}
+
+/// `FileCaptureStreamSourceGenerator` is a [`StreamSourceGenerator`] for capture streams that play
+/// an audio file in a loop.
+#[derive(Clone)]
+pub struct FileCaptureStreamSourceGenerator {
+    data: Arc<AudioData>,
+}
+
+impl FileCaptureStreamSourceGenerator {
+    /// Reads and decodes the audio file at `path`.
+    pub fn new(path: &Path) -> Result<Self, Error> {
+        let data = fs::read(path).map_err(Error::ReadFile)?;
+        Ok(FileCaptureStreamSourceGenerator {
+            data: Arc::new(AudioData::parse(data)?),
+        })
+    }
+}
+
+impl StreamSourceGenerator for FileCaptureStreamSourceGenerator {
+    fn generate(&self) -> Result<Box<dyn StreamSource>, BoxError> {
+        Ok(Box::new(FileCaptureStreamSource {
+            data: self.data.clone(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wav(tag: u16, channels: u16, rate: u32, bits: u16, data: &[u8]) -> Vec<u8> {
+        let block_align = channels * bits / 8;
+        let mut wav = Vec::new();
+        wav.extend_from_slice(b"RIFF");
+        wav.extend_from_slice(&(36 + data.len() as u32).to_le_bytes());
+        wav.extend_from_slice(b"WAVEfmt ");
+        wav.extend_from_slice(&16u32.to_le_bytes());
+        wav.extend_from_slice(&tag.to_le_bytes());
+        wav.extend_from_slice(&channels.to_le_bytes());
+        wav.extend_from_slice(&rate.to_le_bytes());
+        wav.extend_from_slice(&(rate * block_align as u32).to_le_bytes());
+        wav.extend_from_slice(&block_align.to_le_bytes());
+        wav.extend_from_slice(&bits.to_le_bytes());
+        // An unknown, odd sized chunk that must be skipped along with its padding byte.
+        wav.extend_from_slice(b"LIST");
+        wav.extend_from_slice(&3u32.to_le_bytes());
+        wav.extend_from_slice(&[1, 2, 3, 0]);
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        wav.extend_from_slice(data);
+        wav
+    }
+
+    fn s16(samples: &[i16]) -> Vec<u8> {
+        samples.iter().flat_map(|s| s.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn raw_passthrough() {
+        let data = AudioData::parse(vec![1, 2, 3, 4, 5]).unwrap();
+        // Trailing bytes that don't fill a frame are dropped.
+        assert_eq!(data.render(2, SampleFormat::S16LE, 48000), [1, 2, 3, 4]);
+        assert_eq!(data.render(1, SampleFormat::U8, 8000), [1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn wav_same_format() {
+        let samples = s16(&[0, 1000, -1000, i16::MAX]);
+        let data = AudioData::parse(wav(WAVE_FORMAT_PCM, 2, 48000, 16, &samples)).unwrap();
+        assert_eq!(data.render(2, SampleFormat::S16LE, 48000), samples);
+    }
+
+    #[test]
+    fn wav_channel_conversion() {
+        let data = AudioData::parse(wav(WAVE_FORMAT_PCM, 1, 8000, 16, &s16(&[100, -200]))).unwrap();
+        assert_eq!(
+            data.render(2, SampleFormat::S16LE, 8000),
+            s16(&[100, 100, -200, -200])
+        );
+
+        let data = AudioData::parse(wav(
+            WAVE_FORMAT_PCM,
+            2,
+            8000,
+            16,
+            &s16(&[100, 300, -200, 0]),
+        ))
+        .unwrap();
+        assert_eq!(data.render(1, SampleFormat::S16LE, 8000), s16(&[200, -100]));
+    }
+
+    #[test]
+    fn wav_format_conversion() {
+        let floats: Vec<u8> = [0.5f32, -1.0]
+            .iter()
+            .flat_map(|s| s.to_le_bytes())
+            .collect();
+        let data = AudioData::parse(wav(WAVE_FORMAT_IEEE_FLOAT, 1, 8000, 32, &floats)).unwrap();
+        assert_eq!(data.render(1, SampleFormat::U8, 8000), [192, 0]);
+
+        // 24 bit samples are packed in 3 bytes in WAV files, but use 4 bytes in streams.
+        let data =
+            AudioData::parse(wav(WAVE_FORMAT_PCM, 1, 8000, 24, &[0xff, 0xff, 0xff])).unwrap();
+        assert_eq!(
+            data.render(1, SampleFormat::S24LE, 8000),
+            (-1i32).to_le_bytes()
+        );
+    }
+
+    #[test]
+    fn wav_resample() {
+        let data = AudioData::parse(wav(WAVE_FORMAT_PCM, 1, 8000, 16, &s16(&[0, 1000]))).unwrap();
+        // The loop wraps around, so the last frame is interpolated towards the first one.
+        assert_eq!(
+            data.render(1, SampleFormat::S16LE, 16000),
+            s16(&[0, 500, 1000, 500])
+        );
+    }
+
+    #[test]
+    fn wav_errors() {
+        assert!(matches!(
+            AudioData::parse(wav(WAVE_FORMAT_PCM, 1, 8000, 12, &[0, 0])),
+            Err(Error::UnsupportedWav { bits: 12, .. })
+        ));
+        let mut truncated = wav(WAVE_FORMAT_PCM, 1, 8000, 16, &s16(&[1, 2]));
+        truncated.pop();
+        assert!(matches!(
+            AudioData::parse(truncated),
+            Err(Error::InvalidWav(_))
+        ));
+    }
+
+    #[test]
+    fn stream_loops() {
+        let mut stream = FileCaptureStream {
+            data: vec![1, 2, 3],
+            position: 0,
+            buffer: vec![0; 4],
+            frame_size: 1,
+            interval: Duration::from_millis(1),
+            next_frame: Duration::from_millis(1),
+            start_time: None,
+            commit: NoopCommit,
+        };
+        stream.fill_buffer();
+        assert_eq!(stream.buffer, [1, 2, 3, 1]);
+        stream.fill_buffer();
+        assert_eq!(stream.buffer, [2, 3, 1, 2]);
+    }
+}
@@ -35,8 +35,14 @@ pub enum Error {
     BuildMemoryMapping(MmapError),
     #[error("Failed to clone file descriptor: {0}")]
     Clone(IOError),
+    #[error("Invalid WAV file: {0}")]
+    InvalidWav(&'static str),
+    #[error("Failed to read audio file: {0}")]
+    ReadFile(IOError),
     #[error("Not implemented")]
     Unimplemented,
+    #[error("Unsupported WAV format {format:#x} with {bits} bits per sample")]
+    UnsupportedWav { format: u16, bits: u16 },
 }
 
 /// An Audio Stream that can be used to write playback buffer to a file.
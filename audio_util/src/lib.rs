@@ -2,7 +2,9 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+mod file_capture;
 mod file_streams;
 
+pub use file_capture::FileCaptureStreamSourceGenerator;
 pub use file_streams::Error;
 pub use file_streams::FileStreamSourceGenerator;
@@ -5,25 +5,35 @@
 //! fw_cfg device implementing QEMU's Firmware Configuration interface
 //! <https://www.qemu.org/docs/master/specs/fw_cfg.html>
 
+mod table_loader;
+
 use std::collections::HashSet;
 use std::fs;
 use std::iter::repeat;
 use std::path::PathBuf;
 
-#[cfg(windows)]
 use base::error;
 use serde::Deserialize;
 use serde::Serialize;
 use serde_keyvalue::FromKeyValues;
 use thiserror::Error as ThisError;
-
+use vm_memory::GuestAddress;
+use vm_memory::GuestMemory;
+use vm_memory::GuestMemoryError;
+
+use self::table_loader::acpi_table_files;
+use self::table_loader::ACPI_RSDP_FILE;
+use self::table_loader::ACPI_TABLES_FILE;
+use self::table_loader::TABLE_LOADER_FILE;
 use crate::BusAccessInfo;
 use crate::BusDevice;
 use crate::DeviceId;
 use crate::Suspendable;
 
 pub const FW_CFG_BASE_PORT: u64 = 0x510;
-pub const FW_CFG_WIDTH: u64 = 0x4;
+pub const FW_CFG_WIDTH: u64 = 0xc;
+// Size of the memory-mapped register block used on architectures without port I/O.
+pub const FW_CFG_MMIO_SIZE: u64 = 0x18;
 // For the 16-bit selector, the 2nd highest-order bit represents whether the data port will be read
 // or written to. Because this has been deprecrated by Qemu, this bit is useless. The highest order
 // bit represents whether the selected configuration item is arch-specific. Therefore, only the
@@ -33,11 +43,26 @@ pub const FW_CFG_MAX_FILE_SLOTS: usize = 16384 - FW_CFG_FILE_FIRST;
 const FW_CFG_FILE_FIRST: usize = 0x0020;
 const FW_CFG_SELECTOR_PORT_OFFSET: u64 = 0x0;
 const FW_CFG_DATA_PORT_OFFSET: u64 = 0x1;
+const FW_CFG_DMA_PORT_OFFSET: u64 = 0x4;
+const FW_CFG_MMIO_DATA_OFFSET: u64 = 0x0;
+const FW_CFG_MMIO_SELECTOR_OFFSET: u64 = 0x8;
+const FW_CFG_MMIO_DMA_OFFSET: u64 = 0x10;
 const FW_CFG_SELECTOR_RW_MASK: u16 = 0x2000;
 const FW_CFG_SELECTOR_ARCH_MASK: u16 = 0x4000;
 const FW_CFG_SELECTOR_SELECT_MASK: u16 = 0xbfff;
 const FW_CFG_SIGNATURE: [u8; 4] = [b'Q', b'E', b'M', b'U'];
-const FW_CFG_REVISION: [u8; 4] = [0, 0, 0, 1];
+// The revision item is a little-endian bitmap of the supported interfaces.
+const FW_CFG_FEATURE_TRADITIONAL: u32 = 0x1;
+const FW_CFG_FEATURE_DMA: u32 = 0x2;
+const FW_CFG_REVISION: [u8; 4] = FW_CFG_FEATURE_TRADITIONAL.to_le_bytes();
+// Value read back from the DMA address register, which lets the guest probe for it.
+const FW_CFG_DMA_SIGNATURE: [u8; 8] = *b"QEMU CFG";
+const FW_CFG_DMA_CTL_ERROR: u32 = 0x01;
+const FW_CFG_DMA_CTL_READ: u32 = 0x02;
+const FW_CFG_DMA_CTL_SKIP: u32 = 0x04;
+const FW_CFG_DMA_CTL_SELECT: u32 = 0x08;
+const FW_CFG_DMA_CTL_WRITE: u32 = 0x10;
+const FW_CFG_DMA_DESCRIPTOR_SIZE: usize = 16;
 const FW_CFG_SIGNATURE_SELECTOR: u16 = 0x0000;
 const FW_CFG_REVISION_SELECTOR: u16 = 0x0001;
 const FW_CFG_FILE_DIR_SELECTOR: u16 = 0x0019;
@@ -66,6 +91,9 @@ pub enum Error {
 
     #[error("fw_cfg parameters must have exactly one of string or path")]
     StringOrPathRequired,
+
+    #[error("Unable to publish ACPI tables: {0}")]
+    InvalidAcpiTables(&'static str),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -117,6 +145,22 @@ struct FwCfgEntry {
     pub data: Vec<u8>,
 }
 
+// How the registers are exposed to the guest.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum FwCfgLayout {
+    // x86 I/O ports: 16-bit little-endian selector, 8-bit data port and DMA address at 0x4.
+    Io,
+    // MMIO, as on ARM: data at 0x0, 16-bit big-endian selector at 0x8 and DMA address at 0x10.
+    Mmio,
+}
+
+enum FwCfgRegister {
+    Selector,
+    Data,
+    // Offset within the 64-bit big-endian DMA address register.
+    DmaAddress(u64),
+}
+
 // Device exposed to the rest of crosvm. Contains state information in addition to arrays of
 // FwCfgEntry and FwCfgFile. cur_entry keeps the index of the currently selected entry. cur_offset
 // keeps the byte offset within cur_entry. Storing cur_offset is neccessary because the data IO port
@@ -133,6 +177,13 @@ pub struct FwCfgDevice {
     cur_entry: u16,
     cur_offset: usize,
     file_names: HashSet<String>,
+    layout: FwCfgLayout,
+    // Guest memory, used for DMA transfers and to find the ACPI tables.
+    mem: Option<GuestMemory>,
+    // Upper half of the DMA address, when written as two 32-bit halves.
+    dma_address_high: u32,
+    // Address of the RSDP of ACPI tables that have yet to be published.
+    acpi_rsdp: Option<GuestAddress>,
 }
 
 impl FwCfgDevice {
@@ -154,6 +205,10 @@ impl FwCfgDevice {
             cur_entry: 0,
             cur_offset: 0,
             file_names: HashSet::new(),
+            layout: FwCfgLayout::Io,
+            mem: None,
+            dma_address_high: 0,
+            acpi_rsdp: None,
         };
 
         for param in fw_cfg_parameters {
@@ -177,6 +232,50 @@ impl FwCfgDevice {
         Ok(device)
     }
 
+    /// Creates a device whose registers are memory-mapped instead of I/O ports, as expected by
+    /// firmware on architectures other than x86.
+    pub fn new_mmio(
+        file_slots: usize,
+        fw_cfg_parameters: Vec<FwCfgParameters>,
+    ) -> Result<FwCfgDevice> {
+        let mut device = Self::new(file_slots, fw_cfg_parameters)?;
+        device.layout = FwCfgLayout::Mmio;
+        Ok(device)
+    }
+
+    /// Gives the device access to guest memory, which enables the DMA interface.
+    pub fn set_guest_memory(&mut self, mem: GuestMemory) {
+        self.mem = Some(mem);
+        self.add_bytes(
+            (FW_CFG_FEATURE_TRADITIONAL | FW_CFG_FEATURE_DMA)
+                .to_le_bytes()
+                .to_vec(),
+            FwCfgItemType::RevisionVector,
+        );
+    }
+
+    /// Publishes the ACPI tables found from the RSDP at `rsdp` through `etc/table-loader`.
+    ///
+    /// The tables are usually not written yet when the device is created, so they are read from
+    /// guest memory when the guest first accesses the device. Requires `set_guest_memory`.
+    pub fn publish_acpi_tables(&mut self, rsdp: GuestAddress) {
+        self.acpi_rsdp = Some(rsdp);
+    }
+
+    fn add_pending_acpi_tables(&mut self) -> Result<()> {
+        let (Some(rsdp), Some(mem)) = (self.acpi_rsdp.take(), self.mem.as_ref()) else {
+            return Ok(());
+        };
+        let files = acpi_table_files(mem, rsdp)?;
+        self.add_file(ACPI_RSDP_FILE, files.rsdp, FwCfgItemType::GenericItem)?;
+        self.add_file(ACPI_TABLES_FILE, files.tables, FwCfgItemType::GenericItem)?;
+        self.add_file(
+            TABLE_LOADER_FILE,
+            files.table_loader,
+            FwCfgItemType::GenericItem,
+        )
+    }
+
     /// Adds a file to the device.
     ///
     /// # Arguments
@@ -280,7 +379,160 @@ impl FwCfgDevice {
     }
 }
 
-// We implement two 8-bit registers: a Selector(Control) Register and a Data Register
+impl FwCfgDevice {
+    fn register(&self, offset: u64) -> Option<FwCfgRegister> {
+        let (selector, data, dma) = match self.layout {
+            FwCfgLayout::Io => (
+                FW_CFG_SELECTOR_PORT_OFFSET,
+                FW_CFG_DATA_PORT_OFFSET,
+                FW_CFG_DMA_PORT_OFFSET,
+            ),
+            FwCfgLayout::Mmio => (
+                FW_CFG_MMIO_SELECTOR_OFFSET,
+                FW_CFG_MMIO_DATA_OFFSET,
+                FW_CFG_MMIO_DMA_OFFSET,
+            ),
+        };
+        match offset {
+            o if o == selector => Some(FwCfgRegister::Selector),
+            o if o == data => Some(FwCfgRegister::Data),
+            // The DMA interface is only advertised when the device can access guest memory.
+            o if self.mem.is_some() && (dma..dma + 8).contains(&o) => {
+                Some(FwCfgRegister::DmaAddress(o - dma))
+            }
+            _ => None,
+        }
+    }
+
+    // Returns the remaining bytes of the currently selected item.
+    fn cur_data(&self) -> &[u8] {
+        self.entries[self.cur_item_type.value()]
+            .get(self.cur_entry as usize)
+            .and_then(|entry| entry.data.get(self.cur_offset..))
+            .unwrap_or(&[])
+    }
+
+    fn select(&mut self, selector: u16) {
+        // The guest always selects an item before reading the file directory, so this is the last
+        // chance to add files whose contents depend on guest memory.
+        if let Err(e) = self.add_pending_acpi_tables() {
+            error!("fw_cfg: failed to add ACPI tables: {}", e);
+        }
+
+        self.cur_offset = 0;
+
+        match selector {
+            FW_CFG_FILE_DIR_SELECTOR => {
+                self.cur_entry = FW_CFG_FILE_DIR_SELECTOR;
+            }
+            FW_CFG_REVISION_SELECTOR => {
+                self.cur_entry = FW_CFG_REVISION_SELECTOR;
+            }
+            FW_CFG_SIGNATURE_SELECTOR => {
+                self.cur_entry = FW_CFG_SIGNATURE_SELECTOR;
+            }
+            _ => {
+                let entries_index = selector as usize;
+
+                // Checks if the 15th bit is set. The bit indicates whether the fw_cfg item
+                // selected is archetecture specific.
+                if (FW_CFG_SELECTOR_ARCH_MASK & selector) > 0 {
+                    self.cur_item_type = FwCfgItemType::ArchSpecificItem;
+                } else {
+                    self.cur_item_type = FwCfgItemType::GenericItem;
+                }
+
+                // Check if the selector key is valid.
+                if self.entries[self.cur_item_type.value()].len() <= entries_index {
+                    return;
+                }
+
+                // Checks if the 14th bit is set. The bit indicates whether the fw_cfg item
+                // selected is going to be written to or only read via the data port. Since
+                // writes to the data port have been deprecated as of Qemu v2.4, we don't
+                // support them either. This code is only included for clarity.
+                self.entries[self.cur_item_type.value()][entries_index].allow_write =
+                    (FW_CFG_SELECTOR_RW_MASK & selector) > 0;
+
+                // Checks if the 15th bit is set. The bit indicates whether the fw_cfg item
+                // selected is archetecture specific.
+                if (FW_CFG_SELECTOR_ARCH_MASK & selector) > 0 {
+                    self.cur_item_type = FwCfgItemType::ArchSpecificItem;
+                } else {
+                    self.cur_item_type = FwCfgItemType::GenericItem;
+                }
+
+                // Only the lower 14 bits are used for actual indexing. The 14th bit
+                // determines whether the data item will be written to or only read
+                // from the data port. The 15th bit determines whether the selected
+                // configuration item is architecture specific. Therefore, we mask the 14th
+                // and 15th bit off.
+                self.cur_entry = selector & FW_CFG_SELECTOR_SELECT_MASK;
+            }
+        }
+    }
+
+    // Copies `len` bytes of the selected item to guest memory, padding with zeros past its end.
+    fn dma_read(
+        &mut self,
+        mem: &GuestMemory,
+        addr: GuestAddress,
+        len: usize,
+    ) -> std::result::Result<(), GuestMemoryError> {
+        let data = self.cur_data();
+        let copied = data.len().min(len);
+        mem.write_all_at_addr(&data[..copied], addr)?;
+        // Reads past the end of the item return zeros. The length comes from the guest, so fill
+        // in bounded chunks rather than allocating it all at once.
+        let zeros = [0u8; 4096];
+        let mut filled = copied;
+        while filled < len {
+            let chunk = (len - filled).min(zeros.len());
+            mem.write_all_at_addr(&zeros[..chunk], addr.unchecked_add(filled as u64))?;
+            filled += chunk;
+        }
+        self.cur_offset = self.cur_offset.saturating_add(len);
+        Ok(())
+    }
+
+    // Processes the DMA descriptor at `desc_addr` and reports the result in its control field.
+    fn dma_transfer(&mut self, desc_addr: GuestAddress) {
+        let Some(mem) = self.mem.clone() else {
+            return;
+        };
+        let mut desc = [0u8; FW_CFG_DMA_DESCRIPTOR_SIZE];
+        if let Err(e) = mem.read_exact_at_addr(&mut desc, desc_addr) {
+            error!("fw_cfg: failed to read DMA descriptor: {}", e);
+            return;
+        }
+        let control = u32::from_be_bytes(desc[0..4].try_into().unwrap());
+        let len = u32::from_be_bytes(desc[4..8].try_into().unwrap()) as usize;
+        let addr = GuestAddress(u64::from_be_bytes(desc[8..16].try_into().unwrap()));
+
+        if control & FW_CFG_DMA_CTL_SELECT != 0 {
+            self.select((control >> 16) as u16);
+        }
+        let ok = if control & FW_CFG_DMA_CTL_READ != 0 {
+            self.dma_read(&mem, addr, len)
+                .map_err(|e| error!("fw_cfg: DMA read failed: {}", e))
+                .is_ok()
+        } else if control & FW_CFG_DMA_CTL_SKIP != 0 {
+            self.cur_offset = self.cur_offset.saturating_add(len);
+            true
+        } else {
+            // Like writes through the data port, DMA writes are not supported.
+            control & FW_CFG_DMA_CTL_WRITE == 0
+        };
+
+        let status = if ok { 0 } else { FW_CFG_DMA_CTL_ERROR };
+        if let Err(e) = mem.write_all_at_addr(&status.to_be_bytes(), desc_addr) {
+            error!("fw_cfg: failed to complete DMA descriptor: {}", e);
+        }
+    }
+}
+
+// We implement two 8-bit registers: a Selector(Control) Register and a Data Register, plus the
+// optional DMA address register.
 impl BusDevice for FwCfgDevice {
     fn device_id(&self) -> DeviceId {
         super::CrosvmDeviceId::FwCfg.into()
@@ -290,93 +542,59 @@ impl BusDevice for FwCfgDevice {
         "FwCfg".to_owned()
     }
 
-    // Read a byte from the FwCfgDevice. The byte read is based on the current state of the device.
+    // Read from the FwCfgDevice. The bytes read are based on the current state of the device.
     fn read(&mut self, info: BusAccessInfo, data: &mut [u8]) {
-        if data.len() != 1 {
-            return;
-        }
-
-        // Attemping to read anything other than the data port is a NOP
-        if info.offset == FW_CFG_DATA_PORT_OFFSET {
-            let entries_index = self.cur_entry as usize;
-            // If the caller attempts to read bytes past the current entry, read returns
-            // zero
-            if self.cur_offset
-                >= self.entries[self.cur_item_type.value()][entries_index]
-                    .data
-                    .len()
-            {
-                data[0] = 0x00;
-                return;
+        match self.register(info.offset) {
+            Some(FwCfgRegister::Data) => {
+                // The data port is only 8 bits wide, but the MMIO data register can be read with
+                // wider accesses. If the caller attempts to read bytes past the current entry,
+                // read returns zero.
+                if self.layout == FwCfgLayout::Io && data.len() != 1 {
+                    return;
+                }
+                let cur_data = self.cur_data();
+                let len = cur_data.len().min(data.len());
+                data[..len].copy_from_slice(&cur_data[..len]);
+                data[len..].fill(0);
+                self.cur_offset += len;
             }
-            data[0] = self.entries[self.cur_item_type.value()][entries_index].data[self.cur_offset];
-            self.cur_offset += 1;
+            Some(FwCfgRegister::DmaAddress(offset)) => {
+                let offset = offset as usize;
+                if let Some(signature) = FW_CFG_DMA_SIGNATURE.get(offset..offset + data.len()) {
+                    data.copy_from_slice(signature);
+                }
+            }
+            // Attemping to read anything other than the data port is a NOP
+            _ => {}
         }
     }
 
-    // Write to the FwCfgDevice. Used to set the select register.
+    // Write to the FwCfgDevice. Used to set the select register and to start DMA transfers.
     fn write(&mut self, info: BusAccessInfo, data: &[u8]) {
-        // Attempting to write to any port other than the data port is a NOP
-        if info.offset == FW_CFG_SELECTOR_PORT_OFFSET {
-            if data.len() != 2 {
-                return;
+        match (self.register(info.offset), data.len()) {
+            (Some(FwCfgRegister::Selector), 2) => {
+                let selector = [data[0], data[1]];
+                let selector = match self.layout {
+                    FwCfgLayout::Io => u16::from_le_bytes(selector),
+                    FwCfgLayout::Mmio => u16::from_be_bytes(selector),
+                };
+                self.select(selector);
             }
-
-            let Ok(selector) = data.try_into().map(u16::from_le_bytes) else {
-                return;
-            };
-
-            self.cur_offset = 0;
-
-            match selector {
-                FW_CFG_FILE_DIR_SELECTOR => {
-                    self.cur_entry = FW_CFG_FILE_DIR_SELECTOR;
-                }
-                FW_CFG_REVISION_SELECTOR => {
-                    self.cur_entry = FW_CFG_REVISION_SELECTOR;
-                }
-                FW_CFG_SIGNATURE_SELECTOR => {
-                    self.cur_entry = FW_CFG_SIGNATURE_SELECTOR;
-                }
-                _ => {
-                    let entries_index = selector as usize;
-
-                    // Checks if the 15th bit is set. The bit indicates whether the fw_cfg item
-                    // selected is archetecture specific.
-                    if (FW_CFG_SELECTOR_ARCH_MASK & selector) > 0 {
-                        self.cur_item_type = FwCfgItemType::ArchSpecificItem;
-                    } else {
-                        self.cur_item_type = FwCfgItemType::GenericItem;
-                    }
-
-                    // Check if the selector key is valid.
-                    if self.entries[self.cur_item_type.value()].len() <= entries_index {
-                        return;
-                    }
-
-                    // Checks if the 14th bit is set. The bit indicates whether the fw_cfg item
-                    // selected is going to be written to or only read via the data port. Since
-                    // writes to the data port have been deprecated as of Qemu v2.4, we don't
-                    // support them either. This code is only included for clarity.
-                    self.entries[self.cur_item_type.value()][entries_index].allow_write =
-                        (FW_CFG_SELECTOR_RW_MASK & selector) > 0;
-
-                    // Checks if the 15th bit is set. The bit indicates whether the fw_cfg item
-                    // selected is archetecture specific.
-                    if (FW_CFG_SELECTOR_ARCH_MASK & selector) > 0 {
-                        self.cur_item_type = FwCfgItemType::ArchSpecificItem;
-                    } else {
-                        self.cur_item_type = FwCfgItemType::GenericItem;
-                    }
-
-                    // Only the lower 14 bits are used for actual indexing. The 14th bit
-                    // determines whether the data item will be written to or only read
-                    // from the data port. The 15th bit determines whether the selected
-                    // configuration item is architecture specific. Therefore, we mask the 14th
-                    // and 15th bit off.
-                    self.cur_entry = selector & FW_CFG_SELECTOR_SELECT_MASK;
-                }
+            (Some(FwCfgRegister::DmaAddress(0)), 8) => {
+                let addr = u64::from_be_bytes(data.try_into().unwrap());
+                self.dma_transfer(GuestAddress(addr));
+            }
+            (Some(FwCfgRegister::DmaAddress(0)), 4) => {
+                self.dma_address_high = u32::from_be_bytes(data.try_into().unwrap());
+            }
+            // Writing the lower half of the address starts the transfer.
+            (Some(FwCfgRegister::DmaAddress(4)), 4) => {
+                let low = u32::from_be_bytes(data.try_into().unwrap());
+                let addr = (self.dma_address_high as u64) << 32 | low as u64;
+                self.dma_address_high = 0;
+                self.dma_transfer(GuestAddress(addr));
             }
+            _ => {}
         }
     }
 }
@@ -816,4 +1034,185 @@ mod tests {
 
         assert_read_entries(&FILENAMES, &mut device, bai);
     }
+
+    fn dma_bai(offset: u64) -> BusAccessInfo {
+        BusAccessInfo {
+            offset,
+            address: FW_CFG_BASE_PORT + offset,
+            id: 0,
+        }
+    }
+
+    // Runs a DMA transfer described at `desc` and returns the resulting control field.
+    fn dma_command(
+        device: &mut FwCfgDevice,
+        mem: &GuestMemory,
+        control: u32,
+        len: u32,
+        addr: u64,
+    ) -> u32 {
+        let desc = GuestAddress(0x100);
+        let mut raw = control.to_be_bytes().to_vec();
+        raw.extend_from_slice(&len.to_be_bytes());
+        raw.extend_from_slice(&addr.to_be_bytes());
+        mem.write_all_at_addr(&raw, desc).unwrap();
+        device.write(dma_bai(FW_CFG_DMA_PORT_OFFSET), &0u32.to_be_bytes());
+        device.write(
+            dma_bai(FW_CFG_DMA_PORT_OFFSET + 4),
+            &(desc.offset() as u32).to_be_bytes(),
+        );
+        let mut control = [0u8; 4];
+        mem.read_exact_at_addr(&mut control, desc).unwrap();
+        u32::from_be_bytes(control)
+    }
+
+    #[test]
+    // The DMA register and feature bit only exist once the device has access to guest memory.
+    fn dma_signature() {
+        let mut data = [0u8; 4];
+        let (mut device, bai) = setup_read(&FILENAMES, &get_contents(), FW_CFG_REVISION_SELECTOR);
+        device.read(dma_bai(FW_CFG_DMA_PORT_OFFSET), &mut data);
+        assert_eq!(data, [0; 4]);
+
+        device.set_guest_memory(GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap());
+        device.write(
+            dma_bai(FW_CFG_SELECTOR_PORT_OFFSET),
+            &FW_CFG_REVISION_SELECTOR.to_le_bytes(),
+        );
+        let mut byte = [0u8];
+        let features = read_u32(&mut device, bai, &mut byte);
+        assert_eq!(
+            features.to_be_bytes(),
+            (FW_CFG_FEATURE_TRADITIONAL | FW_CFG_FEATURE_DMA).to_le_bytes()
+        );
+
+        device.read(dma_bai(FW_CFG_DMA_PORT_OFFSET), &mut data);
+        assert_eq!(&data, b"QEMU");
+        device.read(dma_bai(FW_CFG_DMA_PORT_OFFSET + 4), &mut data);
+        assert_eq!(&data, b" CFG");
+    }
+
+    #[test]
+    // Select and read a file with DMA, past its end and then skipping part of it.
+    fn dma_read_file() {
+        let contents = get_contents();
+        let mut device = make_device(&FILENAMES, &contents, &default_params(), &10).unwrap();
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        device.set_guest_memory(mem.clone());
+        mem.write_all_at_addr(&[0xff; 16], GuestAddress(0x200))
+            .unwrap();
+
+        let selector = (FW_CFG_FILE_FIRST as u32 + 2) << 16;
+        let control = dma_command(
+            &mut device,
+            &mem,
+            selector | FW_CFG_DMA_CTL_SELECT | FW_CFG_DMA_CTL_READ,
+            16,
+            0x200,
+        );
+        assert_eq!(control, 0);
+        let mut data = [0u8; 16];
+        mem.read_exact_at_addr(&mut data, GuestAddress(0x200))
+            .unwrap();
+        assert_eq!(&data[..contents[2].len()], &contents[2]);
+        assert!(data[contents[2].len()..].iter().all(|&b| b == 0));
+
+        let selector = (FW_CFG_FILE_FIRST as u32) << 16;
+        let control = dma_command(
+            &mut device,
+            &mem,
+            selector | FW_CFG_DMA_CTL_SELECT | FW_CFG_DMA_CTL_SKIP,
+            2,
+            0,
+        );
+        assert_eq!(control, 0);
+        let control = dma_command(&mut device, &mem, FW_CFG_DMA_CTL_READ, 4, 0x300);
+        assert_eq!(control, 0);
+        let mut data = [0u8; 4];
+        mem.read_exact_at_addr(&mut data, GuestAddress(0x300))
+            .unwrap();
+        assert_eq!(&data, b"OSVM");
+
+        let control = dma_command(&mut device, &mem, FW_CFG_DMA_CTL_WRITE, 4, 0x300);
+        assert_eq!(control, FW_CFG_DMA_CTL_ERROR);
+    }
+
+    #[test]
+    // A huge DMA read past the end of a file is zero-filled until it runs out of guest memory.
+    fn dma_read_past_memory() {
+        let contents = get_contents();
+        let mut device = make_device(&FILENAMES, &contents, &default_params(), &10).unwrap();
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x2000)]).unwrap();
+        device.set_guest_memory(mem.clone());
+        mem.write_all_at_addr(&[0xff; 0x1000], GuestAddress(0x1000))
+            .unwrap();
+
+        let selector = (FW_CFG_FILE_FIRST as u32 + 2) << 16;
+        let control = dma_command(
+            &mut device,
+            &mem,
+            selector | FW_CFG_DMA_CTL_SELECT | FW_CFG_DMA_CTL_READ,
+            u32::MAX,
+            0x1000,
+        );
+        assert_eq!(control, FW_CFG_DMA_CTL_ERROR);
+        let mut data = [0xffu8; 0x1000];
+        mem.read_exact_at_addr(&mut data, GuestAddress(0x1000))
+            .unwrap();
+        assert!(data[contents[2].len()..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    // The MMIO layout has a big-endian selector and a data register that can be read 8 bytes at
+    // a time.
+    fn mmio_read_file() {
+        let contents = get_contents();
+        let mut device = FwCfgDevice::new_mmio(10, default_params()).unwrap();
+        device
+            .add_file(
+                FILENAMES[2],
+                contents[2].clone(),
+                FwCfgItemType::GenericItem,
+            )
+            .unwrap();
+        let mut bai = BusAccessInfo {
+            offset: FW_CFG_MMIO_SELECTOR_OFFSET,
+            address: FW_CFG_MMIO_SELECTOR_OFFSET,
+            id: 0,
+        };
+        device.write(bai, &(FW_CFG_FILE_FIRST as u16).to_be_bytes());
+
+        bai.offset = FW_CFG_MMIO_DATA_OFFSET;
+        let mut data = [0u8; 8];
+        device.read(bai, &mut data);
+        assert_eq!(&data, b"FWCONFIG");
+        device.read(bai, &mut data);
+        assert_eq!(data, [0; 8]);
+    }
+
+    #[test]
+    // ACPI tables are added to the file directory when the guest first selects an item.
+    fn publish_acpi_tables_on_select() {
+        let mut device = FwCfgDevice::new(10, default_params()).unwrap();
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        device.set_guest_memory(mem.clone());
+
+        let mut rsdp = [0u8; 36];
+        rsdp[..8].copy_from_slice(b"RSD PTR ");
+        rsdp[24..32].copy_from_slice(&0x840u64.to_le_bytes());
+        mem.write_all_at_addr(&rsdp, GuestAddress(0x800)).unwrap();
+        let mut xsdt = [0u8; 36];
+        xsdt[..4].copy_from_slice(b"XSDT");
+        xsdt[4..8].copy_from_slice(&36u32.to_le_bytes());
+        mem.write_all_at_addr(&xsdt, GuestAddress(0x840)).unwrap();
+
+        device.publish_acpi_tables(GuestAddress(0x800));
+        assert!(device.files.is_empty());
+        device.write(
+            dma_bai(FW_CFG_SELECTOR_PORT_OFFSET),
+            &FW_CFG_SIGNATURE_SELECTOR.to_le_bytes(),
+        );
+        let names: Vec<&str> = device.files.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, [ACPI_RSDP_FILE, ACPI_TABLES_FILE, TABLE_LOADER_FILE]);
+    }
 }
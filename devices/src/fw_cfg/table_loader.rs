@@ -0,0 +1,442 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! QEMU's ACPI linker/loader interface, published as `etc/table-loader`.
+//!
+//! Instead of finding ACPI tables at fixed guest addresses, firmware such as OVMF and SeaBIOS
+//! reads them from fw_cfg files, allocates memory for each file, patches the pointers between the
+//! tables and recomputes checksums as instructed by the commands in `etc/table-loader`.
+//! <https://github.com/qemu/qemu/blob/master/hw/acpi/bios-linker-loader.c>
+
+use std::collections::BTreeSet;
+
+use vm_memory::GuestAddress;
+use vm_memory::GuestMemory;
+
+use super::Error;
+use super::Result;
+use super::FW_CFG_FILENAME_SIZE;
+
+pub const ACPI_RSDP_FILE: &str = "etc/acpi/rsdp";
+pub const ACPI_TABLES_FILE: &str = "etc/acpi/tables";
+pub const TABLE_LOADER_FILE: &str = "etc/table-loader";
+
+const COMMAND_ALLOCATE: u32 = 0x1;
+const COMMAND_ADD_POINTER: u32 = 0x2;
+const COMMAND_ADD_CHECKSUM: u32 = 0x3;
+const COMMAND_SIZE: usize = 128;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const RSDP_LEN: usize = 36;
+const RSDP_CHECKSUM_OFFSET: usize = 8;
+const RSDP_CHECKSUM_LEN: usize = 20;
+const RSDP_RSDT_ADDR_OFFSET: usize = 16;
+const RSDP_XSDT_ADDR_OFFSET: usize = 24;
+const RSDP_EXT_CHECKSUM_OFFSET: usize = 32;
+
+const SDT_HEADER_LEN: usize = 36;
+const SDT_LENGTH_OFFSET: usize = 4;
+const SDT_CHECKSUM_OFFSET: usize = 9;
+
+const FADT_FACS_ADDR32_OFFSET: usize = 36;
+const FADT_DSDT_ADDR32_OFFSET: usize = 40;
+const FADT_FACS_ADDR_OFFSET: usize = 132;
+const FADT_DSDT_ADDR_OFFSET: usize = 140;
+
+// Upper bound of the memory scanned for tables, to fail early on garbage pointers.
+const ACPI_TABLES_MAX_SIZE: u64 = 16 << 20;
+
+/// Memory zone of an allocation made by the firmware.
+#[derive(Clone, Copy)]
+pub enum AllocZone {
+    /// Anywhere in memory.
+    High = 0x1,
+    /// The legacy BIOS area below 1 MiB, where the RSDP is looked for.
+    Fseg = 0x2,
+}
+
+/// Builder of the `etc/table-loader` command list.
+#[derive(Default)]
+pub struct TableLoader {
+    commands: Vec<u8>,
+}
+
+fn push_file_name(command: &mut Vec<u8>, file: &str) {
+    let mut name = [0u8; FW_CFG_FILENAME_SIZE];
+    name[..file.len()].copy_from_slice(file.as_bytes());
+    command.extend_from_slice(&name);
+}
+
+impl TableLoader {
+    pub fn new() -> TableLoader {
+        Default::default()
+    }
+
+    fn push_command(&mut self, mut command: Vec<u8>) {
+        command.resize(COMMAND_SIZE, 0);
+        self.commands.extend_from_slice(&command);
+    }
+
+    /// Asks the firmware to allocate memory for `file` and load it there.
+    pub fn allocate(&mut self, file: &str, align: u32, zone: AllocZone) {
+        let mut command = COMMAND_ALLOCATE.to_le_bytes().to_vec();
+        push_file_name(&mut command, file);
+        command.extend_from_slice(&align.to_le_bytes());
+        command.push(zone as u8);
+        self.push_command(command);
+    }
+
+    /// Asks the firmware to add the address of `src_file` to the `size` bytes pointer at `offset`
+    /// in `dest_file`.
+    pub fn add_pointer(&mut self, dest_file: &str, src_file: &str, offset: u32, size: u8) {
+        let mut command = COMMAND_ADD_POINTER.to_le_bytes().to_vec();
+        push_file_name(&mut command, dest_file);
+        push_file_name(&mut command, src_file);
+        command.extend_from_slice(&offset.to_le_bytes());
+        command.push(size);
+        self.push_command(command);
+    }
+
+    /// Asks the firmware to update the checksum at `result_offset` in `file` so that the bytes in
+    /// `start..start + length` sum up to zero.
+    pub fn add_checksum(&mut self, file: &str, result_offset: u32, start: u32, length: u32) {
+        let mut command = COMMAND_ADD_CHECKSUM.to_le_bytes().to_vec();
+        push_file_name(&mut command, file);
+        command.extend_from_slice(&result_offset.to_le_bytes());
+        command.extend_from_slice(&start.to_le_bytes());
+        command.extend_from_slice(&length.to_le_bytes());
+        self.push_command(command);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.commands
+    }
+}
+
+/// Contents of the fw_cfg files describing the ACPI tables.
+pub struct AcpiTableFiles {
+    pub rsdp: Vec<u8>,
+    pub tables: Vec<u8>,
+    pub table_loader: Vec<u8>,
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+fn read_table(mem: &GuestMemory, addr: u64) -> Result<Vec<u8>> {
+    let mut header = [0u8; SDT_HEADER_LEN];
+    mem.read_exact_at_addr(&mut header, GuestAddress(addr))
+        .map_err(|_| Error::InvalidAcpiTables("table outside of guest memory"))?;
+    // The FACS has no checksum and a different header, but its length is at the same offset and
+    // it is always larger than an SDT header.
+    let len = read_u32(&header, SDT_LENGTH_OFFSET) as u64;
+    if !(SDT_HEADER_LEN as u64..=ACPI_TABLES_MAX_SIZE).contains(&len) {
+        return Err(Error::InvalidAcpiTables("bad table length"));
+    }
+    let mut table = vec![0u8; len as usize];
+    mem.read_exact_at_addr(&mut table, GuestAddress(addr))
+        .map_err(|_| Error::InvalidAcpiTables("table outside of guest memory"))?;
+    Ok(table)
+}
+
+// Returns the position in the tables blob of the table at `addr`, checking that its first `len`
+// bytes are within the blob.
+fn table_pos(addr: u64, start: u64, len: usize, blob_len: usize) -> Result<usize> {
+    addr.checked_sub(start)
+        .map(|pos| pos as usize)
+        .filter(|pos| pos.checked_add(len).is_some_and(|end| end <= blob_len))
+        .ok_or(Error::InvalidAcpiTables("table outside of the tables blob"))
+}
+
+/// Reads the ACPI tables reachable from the RSDP at `rsdp_addr` and describes them as fw_cfg
+/// files.
+///
+/// All tables are expected to be in one contiguous region of guest memory following the RSDP,
+/// which is where the architecture code puts them.
+pub fn acpi_table_files(mem: &GuestMemory, rsdp_addr: GuestAddress) -> Result<AcpiTableFiles> {
+    let mut rsdp = vec![0u8; RSDP_LEN];
+    mem.read_exact_at_addr(&mut rsdp, rsdp_addr)
+        .map_err(|_| Error::InvalidAcpiTables("RSDP outside of guest memory"))?;
+    if &rsdp[..RSDP_SIGNATURE.len()] != RSDP_SIGNATURE {
+        return Err(Error::InvalidAcpiTables("bad RSDP signature"));
+    }
+
+    // Find every table and the pointers to other tables within it, as (table address, offset of
+    // the pointer, pointer size).
+    let xsdt_addr = read_u64(&rsdp, RSDP_XSDT_ADDR_OFFSET);
+    let xsdt = read_table(mem, xsdt_addr)?;
+    if (xsdt.len() - SDT_HEADER_LEN) % 8 != 0 {
+        return Err(Error::InvalidAcpiTables("bad XSDT length"));
+    }
+    let mut tables = vec![(xsdt_addr, xsdt.len(), true)];
+    let mut pointers = Vec::new();
+    for offset in (SDT_HEADER_LEN..xsdt.len()).step_by(8) {
+        let addr = read_u64(&xsdt, offset);
+        let table = read_table(mem, addr)?;
+        tables.push((addr, table.len(), true));
+        pointers.push((xsdt_addr, offset, 8));
+
+        if &table[..4] == b"FACP" {
+            for (offset, size) in [
+                (FADT_FACS_ADDR32_OFFSET, 4),
+                (FADT_DSDT_ADDR32_OFFSET, 4),
+                (FADT_FACS_ADDR_OFFSET, 8),
+                (FADT_DSDT_ADDR_OFFSET, 8),
+            ] {
+                let target = match size {
+                    4 if offset + 4 <= table.len() => read_u32(&table, offset) as u64,
+                    8 if offset + 8 <= table.len() => read_u64(&table, offset),
+                    _ => 0,
+                };
+                if target == 0 {
+                    continue;
+                }
+                let is_facs = offset == FADT_FACS_ADDR32_OFFSET || offset == FADT_FACS_ADDR_OFFSET;
+                tables.push((target, read_table(mem, target)?.len(), !is_facs));
+                pointers.push((addr, offset, size));
+            }
+        }
+    }
+    // The FADT may point to the FACS and DSDT twice.
+    let tables: BTreeSet<(u64, usize, bool)> = tables.into_iter().collect();
+
+    let start = tables.iter().map(|t| t.0).min().unwrap();
+    let mut end = start;
+    for (addr, len, _) in &tables {
+        let table_end = addr
+            .checked_add(*len as u64)
+            .ok_or(Error::InvalidAcpiTables("table outside of guest memory"))?;
+        end = end.max(table_end);
+    }
+    if end - start > ACPI_TABLES_MAX_SIZE {
+        return Err(Error::InvalidAcpiTables("tables are too far apart"));
+    }
+    let mut blob = vec![0u8; (end - start) as usize];
+    mem.read_exact_at_addr(&mut blob, GuestAddress(start))
+        .map_err(|_| Error::InvalidAcpiTables("table outside of guest memory"))?;
+
+    let mut loader = TableLoader::new();
+    loader.allocate(ACPI_RSDP_FILE, 16, AllocZone::Fseg);
+    loader.allocate(ACPI_TABLES_FILE, 64, AllocZone::High);
+
+    // Turn the pointers into offsets within the tables file, which the firmware relocates.
+    for (table_addr, offset, size) in pointers {
+        let pos = table_pos(table_addr, start, offset + size, blob.len())? + offset;
+        match size {
+            4 => {
+                let target = (read_u32(&blob, pos) as u64)
+                    .checked_sub(start)
+                    .ok_or(Error::InvalidAcpiTables("pointer before the tables"))?;
+                blob[pos..pos + 4].copy_from_slice(&(target as u32).to_le_bytes());
+            }
+            _ => {
+                let target = read_u64(&blob, pos)
+                    .checked_sub(start)
+                    .ok_or(Error::InvalidAcpiTables("pointer before the tables"))?;
+                blob[pos..pos + 8].copy_from_slice(&target.to_le_bytes());
+            }
+        }
+        loader.add_pointer(ACPI_TABLES_FILE, ACPI_TABLES_FILE, pos as u32, size as u8);
+    }
+    for (addr, len, has_checksum) in tables {
+        if has_checksum {
+            if len < SDT_HEADER_LEN {
+                return Err(Error::InvalidAcpiTables("bad table length"));
+            }
+            let pos = table_pos(addr, start, len, blob.len())?;
+            blob[pos + SDT_CHECKSUM_OFFSET] = 0;
+            loader.add_checksum(
+                ACPI_TABLES_FILE,
+                (pos + SDT_CHECKSUM_OFFSET) as u32,
+                pos as u32,
+                len as u32,
+            );
+        }
+    }
+
+    rsdp[RSDP_RSDT_ADDR_OFFSET..RSDP_RSDT_ADDR_OFFSET + 4].fill(0);
+    rsdp[RSDP_XSDT_ADDR_OFFSET..RSDP_XSDT_ADDR_OFFSET + 8]
+        .copy_from_slice(&(xsdt_addr - start).to_le_bytes());
+    rsdp[RSDP_CHECKSUM_OFFSET] = 0;
+    rsdp[RSDP_EXT_CHECKSUM_OFFSET] = 0;
+    loader.add_pointer(
+        ACPI_RSDP_FILE,
+        ACPI_TABLES_FILE,
+        RSDP_XSDT_ADDR_OFFSET as u32,
+        8,
+    );
+    loader.add_checksum(
+        ACPI_RSDP_FILE,
+        RSDP_CHECKSUM_OFFSET as u32,
+        0,
+        RSDP_CHECKSUM_LEN as u32,
+    );
+    loader.add_checksum(
+        ACPI_RSDP_FILE,
+        RSDP_EXT_CHECKSUM_OFFSET as u32,
+        0,
+        RSDP_LEN as u32,
+    );
+
+    Ok(AcpiTableFiles {
+        rsdp,
+        tables: blob,
+        table_loader: loader.into_bytes(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checksum(data: &[u8]) -> u8 {
+        data.iter().fold(0u8, |acc, b| acc.wrapping_add(*b))
+    }
+
+    fn sdt(signature: &[u8; 4], len: usize) -> Vec<u8> {
+        let mut table = vec![0u8; len];
+        table[..4].copy_from_slice(signature);
+        table[SDT_LENGTH_OFFSET..SDT_LENGTH_OFFSET + 4]
+            .copy_from_slice(&(len as u32).to_le_bytes());
+        table[SDT_CHECKSUM_OFFSET] = 0u8.wrapping_sub(checksum(&table));
+        table
+    }
+
+    // Emulates the firmware: loads the tables at `tables_addr` and runs the commands.
+    fn run_loader(files: &mut AcpiTableFiles, tables_addr: u64) {
+        for command in files.table_loader.chunks(COMMAND_SIZE) {
+            let file = |offset: usize| {
+                let name = &command[offset..offset + FW_CFG_FILENAME_SIZE];
+                let len = name.iter().position(|&b| b == 0).unwrap();
+                String::from_utf8(name[..len].to_vec()).unwrap()
+            };
+            match read_u32(command, 0) {
+                COMMAND_ALLOCATE => {}
+                COMMAND_ADD_POINTER => {
+                    assert_eq!(file(4 + FW_CFG_FILENAME_SIZE), ACPI_TABLES_FILE);
+                    let offset = read_u32(command, 4 + 2 * FW_CFG_FILENAME_SIZE) as usize;
+                    let size = command[8 + 2 * FW_CFG_FILENAME_SIZE] as usize;
+                    let dest = match file(4).as_str() {
+                        ACPI_RSDP_FILE => &mut files.rsdp,
+                        _ => &mut files.tables,
+                    };
+                    let mut value = [0u8; 8];
+                    value[..size].copy_from_slice(&dest[offset..offset + size]);
+                    let value = u64::from_le_bytes(value) + tables_addr;
+                    dest[offset..offset + size].copy_from_slice(&value.to_le_bytes()[..size]);
+                }
+                COMMAND_ADD_CHECKSUM => {
+                    let result = read_u32(command, 4 + FW_CFG_FILENAME_SIZE) as usize;
+                    let start = read_u32(command, 8 + FW_CFG_FILENAME_SIZE) as usize;
+                    let len = read_u32(command, 12 + FW_CFG_FILENAME_SIZE) as usize;
+                    let dest = match file(4).as_str() {
+                        ACPI_RSDP_FILE => &mut files.rsdp,
+                        _ => &mut files.tables,
+                    };
+                    let sum = checksum(&dest[start..start + len]);
+                    dest[result] = dest[result].wrapping_sub(sum);
+                }
+                command => panic!("unexpected command {}", command),
+            }
+        }
+    }
+
+    #[test]
+    fn relocate_tables() {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let rsdp_addr = 0x1000u64;
+        let facs_addr = 0x1040u64;
+        let dsdt_addr = 0x1080u64;
+        let fadt_addr = 0x1100u64;
+        let xsdt_addr = 0x1200u64;
+
+        let mut facs = vec![0u8; 64];
+        facs[..4].copy_from_slice(b"FACS");
+        facs[SDT_LENGTH_OFFSET..SDT_LENGTH_OFFSET + 4].copy_from_slice(&64u32.to_le_bytes());
+        mem.write_all_at_addr(&facs, GuestAddress(facs_addr))
+            .unwrap();
+        mem.write_all_at_addr(&sdt(b"DSDT", 40), GuestAddress(dsdt_addr))
+            .unwrap();
+        let mut fadt = sdt(b"FACP", 244);
+        fadt[FADT_FACS_ADDR_OFFSET..FADT_FACS_ADDR_OFFSET + 8]
+            .copy_from_slice(&facs_addr.to_le_bytes());
+        fadt[FADT_DSDT_ADDR_OFFSET..FADT_DSDT_ADDR_OFFSET + 8]
+            .copy_from_slice(&dsdt_addr.to_le_bytes());
+        mem.write_all_at_addr(&fadt, GuestAddress(fadt_addr))
+            .unwrap();
+        let mut xsdt = sdt(b"XSDT", SDT_HEADER_LEN + 8);
+        xsdt[SDT_HEADER_LEN..].copy_from_slice(&fadt_addr.to_le_bytes());
+        mem.write_all_at_addr(&xsdt, GuestAddress(xsdt_addr))
+            .unwrap();
+        let mut rsdp = vec![0u8; RSDP_LEN];
+        rsdp[..8].copy_from_slice(RSDP_SIGNATURE);
+        rsdp[RSDP_XSDT_ADDR_OFFSET..RSDP_XSDT_ADDR_OFFSET + 8]
+            .copy_from_slice(&xsdt_addr.to_le_bytes());
+        mem.write_all_at_addr(&rsdp, GuestAddress(rsdp_addr))
+            .unwrap();
+
+        let mut files = acpi_table_files(&mem, GuestAddress(rsdp_addr)).unwrap();
+        // The tables start with the FACS and end with the XSDT.
+        assert_eq!(
+            files.tables.len() as u64,
+            xsdt_addr + xsdt.len() as u64 - facs_addr
+        );
+        assert_eq!(files.table_loader.len() % COMMAND_SIZE, 0);
+
+        // Load the tables somewhere else and check that all pointers and checksums are right.
+        let tables_addr = 0x8000_0000u64;
+        run_loader(&mut files, tables_addr);
+        let relocate = |addr: u64| addr - facs_addr + tables_addr;
+        assert_eq!(
+            read_u64(&files.rsdp, RSDP_XSDT_ADDR_OFFSET),
+            relocate(xsdt_addr)
+        );
+        assert_eq!(checksum(&files.rsdp[..RSDP_CHECKSUM_LEN]), 0);
+        assert_eq!(checksum(&files.rsdp), 0);
+
+        let table = |addr: u64, len: usize| {
+            let start = (addr - facs_addr) as usize;
+            files.tables[start..start + len].to_vec()
+        };
+        let xsdt = table(xsdt_addr, xsdt.len());
+        assert_eq!(read_u64(&xsdt, SDT_HEADER_LEN), relocate(fadt_addr));
+        assert_eq!(checksum(&xsdt), 0);
+        let fadt = table(fadt_addr, fadt.len());
+        assert_eq!(read_u64(&fadt, FADT_FACS_ADDR_OFFSET), relocate(facs_addr));
+        assert_eq!(read_u64(&fadt, FADT_DSDT_ADDR_OFFSET), relocate(dsdt_addr));
+        assert_eq!(checksum(&fadt), 0);
+        assert_eq!(checksum(&table(dsdt_addr, 40)), 0);
+    }
+
+    #[test]
+    fn bad_xsdt_length() {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let rsdp_addr = 0x1000u64;
+        let xsdt_addr = 0x1200u64;
+        let mut rsdp = vec![0u8; RSDP_LEN];
+        rsdp[..8].copy_from_slice(RSDP_SIGNATURE);
+        rsdp[RSDP_XSDT_ADDR_OFFSET..RSDP_XSDT_ADDR_OFFSET + 8]
+            .copy_from_slice(&xsdt_addr.to_le_bytes());
+        mem.write_all_at_addr(&rsdp, GuestAddress(rsdp_addr))
+            .unwrap();
+
+        // Shorter than an SDT header, then not a whole number of entries.
+        for len in [SDT_HEADER_LEN - 4, SDT_HEADER_LEN + 4] {
+            mem.write_all_at_addr(&sdt(b"XSDT", len), GuestAddress(xsdt_addr))
+                .unwrap();
+            assert!(acpi_table_files(&mem, GuestAddress(rsdp_addr)).is_err());
+        }
+    }
+
+    #[test]
+    fn bad_rsdp() {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        assert!(acpi_table_files(&mem, GuestAddress(0)).is_err());
+    }
+}
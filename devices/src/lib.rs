@@ -27,6 +27,7 @@ mod serial;
 pub mod serial_device;
 mod suspendable;
 mod sys;
+pub mod tpm_crb;
 #[cfg(any(target_os = "android", target_os = "linux"))]
 mod virtcpufreq;
 pub mod virtio;
@@ -84,6 +85,7 @@ pub use self::fw_cfg::FwCfgItemType;
 pub use self::fw_cfg::FwCfgParameters;
 pub use self::fw_cfg::FW_CFG_BASE_PORT;
 pub use self::fw_cfg::FW_CFG_MAX_FILE_SLOTS;
+pub use self::fw_cfg::FW_CFG_MMIO_SIZE;
 pub use self::fw_cfg::FW_CFG_WIDTH;
 pub use self::i8042::I8042Device;
 pub use self::irq_event::IrqEdgeEvent;
@@ -95,6 +97,8 @@ pub use self::pci::GpeScope;
 #[cfg(feature = "pci-hotplug")]
 pub use self::pci::HotPluggable;
 #[cfg(feature = "pci-hotplug")]
+pub use self::pci::InputResourceCarrier;
+#[cfg(feature = "pci-hotplug")]
 pub use self::pci::IntxParameter;
 #[cfg(feature = "pci-hotplug")]
 pub use self::pci::NetResourceCarrier;
@@ -118,6 +122,7 @@ pub use self::pci::PreferredIrq;
 pub use self::pci::ResourceCarrier;
 pub use self::pci::StubPciDevice;
 pub use self::pci::StubPciParameters;
+pub use self::pflash::varstore;
 pub use self::pflash::Pflash;
 pub use self::pflash::PflashParameters;
 pub use self::pl030::Pl030;
@@ -130,6 +135,7 @@ pub use self::serial_device::SerialParameters;
 pub use self::serial_device::SerialType;
 pub use self::suspendable::DeviceState;
 pub use self::suspendable::Suspendable;
+pub use self::tpm_crb::TpmCrb;
 #[cfg(any(target_os = "android", target_os = "linux"))]
 pub use self::virtcpufreq::VirtCpufreq;
 pub use self::virtio::VirtioMmioDevice;
@@ -141,6 +147,7 @@ cfg_if::cfg_if! {
     if #[cfg(any(target_os = "android", target_os = "linux"))] {
         mod platform;
         mod proxy;
+        mod swtpm;
         pub mod vmwdt;
         pub mod vfio;
         #[cfg(feature = "usb")]
@@ -152,15 +159,19 @@ cfg_if::cfg_if! {
         mod utils;
 
         pub use self::pci::{
-            CoIommuDev, CoIommuParameters, CoIommuUnpinPolicy, PciBridge, PcieDownstreamPort,
-            PcieHostPort, PcieRootPort, PcieUpstreamPort, PvPanicCode, PvPanicPciDevice,
-            VfioPciDevice,
+            CoIommuDev, CoIommuParameters, CoIommuUnpinPolicy, NvmeController, NvmeDiskConfig,
+            NvmeError, NvmeOption, PciBridge, PcieDownstreamPort, PcieHostPort, PcieRootPort,
+            PcieUpstreamPort, PvPanicCode, PvPanicPciDevice, VfioPciDevice,
         };
         pub use self::platform::VfioPlatformDevice;
         pub use self::ac_adapter::AcAdapter;
         pub use self::proxy::ChildProcIntf;
         pub use self::proxy::Error as ProxyError;
         pub use self::proxy::ProxyDevice;
+        pub use self::swtpm::Error as SwtpmError;
+        pub use self::swtpm::Swtpm;
+        pub use self::swtpm::SwtpmParameters;
+        pub use self::swtpm::TpmInterface;
         #[cfg(feature = "usb")]
         pub use self::usb::backend::device_provider::DeviceProvider;
         #[cfg(feature = "usb")]
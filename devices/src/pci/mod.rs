@@ -9,6 +9,8 @@ mod acpi;
 mod coiommu;
 mod msi;
 mod msix;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+mod nvme;
 mod pci_address;
 mod pci_configuration;
 mod pci_device;
@@ -38,6 +40,14 @@ pub use self::msi::MsiConfig;
 pub use self::msix::MsixCap;
 pub use self::msix::MsixConfig;
 pub use self::msix::MsixStatus;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub use self::nvme::Error as NvmeError;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub use self::nvme::NvmeController;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub use self::nvme::NvmeDiskConfig;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub use self::nvme::NvmeOption;
 pub use self::pci_address::Error as PciAddressError;
 pub use self::pci_address::PciAddress;
 pub use self::pci_configuration::PciBarConfiguration;
@@ -68,6 +78,8 @@ pub use self::pci_device::PreferredIrq;
 #[cfg(feature = "pci-hotplug")]
 pub use self::pci_hotplug::HotPluggable;
 #[cfg(feature = "pci-hotplug")]
+pub use self::pci_hotplug::InputResourceCarrier;
+#[cfg(feature = "pci-hotplug")]
 pub use self::pci_hotplug::IntxParameter;
 #[cfg(feature = "pci-hotplug")]
 pub use self::pci_hotplug::NetResourceCarrier;
@@ -145,6 +157,7 @@ pub enum CrosvmDeviceId {
     VirtualPmc = 21,
     VirtCpufreq = 22,
     FwCfg = 23,
+    TpmCrb = 24,
 }
 
 impl TryFrom<u16> for CrosvmDeviceId {
@@ -173,6 +186,7 @@ impl TryFrom<u16> for CrosvmDeviceId {
             19 => Ok(CrosvmDeviceId::VirtioMmio),
             20 => Ok(CrosvmDeviceId::AcAdapter),
             21 => Ok(CrosvmDeviceId::VirtualPmc),
+            24 => Ok(CrosvmDeviceId::TpmCrb),
             _ => Err(base::Error::new(EINVAL)),
         }
     }
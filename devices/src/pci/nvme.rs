@@ -0,0 +1,1975 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Emulated NVM Express controller.
+//!
+//! Every configured disk is exposed as a namespace of a single controller, in order, starting
+//! with namespace ID 1. Register accesses are handled as they arrive on the bus, while the
+//! submission queues are processed by a worker thread that is started the first time the driver
+//! enables the controller.
+//!
+//! Completions are signalled through MSI-X only. Drivers that do not enable MSI-X, such as most
+//! firmware, poll the completion queues instead.
+
+mod protocol;
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::mem;
+use std::path::PathBuf;
+use std::sync::atomic::fence;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use anyhow::Context;
+use base::error;
+use base::flock;
+use base::open_file_or_duplicate;
+use base::warn;
+use base::Event;
+use base::FlockOperation;
+use base::RawDescriptor;
+use base::SharedMemory;
+use base::Tube;
+use base::WorkerThread;
+use cros_async::EventAsync;
+use cros_async::Executor;
+use cros_async::MemRegion;
+use cros_async::MemRegionIter;
+use disk::AsyncDisk;
+use disk::DiskFile;
+use futures::channel::mpsc;
+use futures::channel::oneshot;
+use futures::pin_mut;
+use futures::FutureExt;
+use futures::StreamExt;
+use remain::sorted;
+use resources::Alloc;
+use resources::AllocOptions;
+use resources::SystemAllocator;
+use serde::Deserialize;
+use serde::Serialize;
+use sync::Mutex;
+use thiserror::Error;
+use vm_memory::GuestAddress;
+use vm_memory::GuestMemory;
+use zerocopy::AsBytes;
+use zerocopy::FromBytes;
+use zerocopy::FromZeroes;
+
+use self::protocol::*;
+use crate::pci::BarRange;
+use crate::pci::MsixCap;
+use crate::pci::MsixConfig;
+use crate::pci::PciAddress;
+use crate::pci::PciBarConfiguration;
+use crate::pci::PciBarIndex;
+use crate::pci::PciBarPrefetchable;
+use crate::pci::PciBarRegionType;
+use crate::pci::PciClassCode;
+use crate::pci::PciConfiguration;
+use crate::pci::PciDevice;
+use crate::pci::PciDeviceError;
+use crate::pci::PciHeaderType;
+use crate::pci::PciId;
+use crate::pci::PciMassStorageSubclass;
+use crate::pci::PciProgrammingInterface;
+use crate::pci::PreferredIrq;
+use crate::pci::PCI_VENDOR_ID_REDHAT;
+use crate::Suspendable;
+
+const PCI_DEVICE_ID_REDHAT_NVME: u16 = 0x0010;
+const PCI_NVME_REVISION_ID: u8 = 2;
+
+const NVME_BAR_INDEX: PciBarIndex = 0;
+const NVME_BAR_SIZE: u64 = 0x4000;
+const NVME_MSIX_TABLE_OFFSET: u64 = 0x2000;
+const NVME_MSIX_TABLE_LAST: u64 = NVME_MSIX_PBA_OFFSET - 1;
+const NVME_MSIX_PBA_OFFSET: u64 = 0x3000;
+const NVME_MSIX_PBA_LAST: u64 = NVME_BAR_SIZE - 1;
+
+/// Number of I/O queue pairs offered to the driver.
+const NVME_MAX_IO_QUEUES: u16 = 16;
+/// One vector for the admin completion queue and one for each I/O completion queue.
+const NVME_MSIX_VECTORS: u16 = NVME_MAX_IO_QUEUES + 1;
+/// Maximum number of entries in a queue.
+const NVME_MAX_QUEUE_ENTRIES: u32 = 1024;
+/// Maximum data transfer size as a power of two of the page size (512 KiB).
+const NVME_MDTS: u8 = 7;
+const NVME_MAX_TRANSFER: usize = (NVME_PAGE_SIZE as usize) << NVME_MDTS;
+/// Number of Async Event Request commands that may be outstanding, 0's based.
+const NVME_AERL: u8 = 3;
+/// Number of concurrent Abort commands, 0's based.
+const NVME_ACL: u8 = 3;
+const NVME_MAX_NAMESPACES: usize = 256;
+/// Namespace ID that refers to every namespace.
+const NVME_NSID_ALL: u32 = 0xffff_ffff;
+
+/// Composite temperature reported in the SMART log, in Kelvin.
+const NVME_TEMPERATURE: u16 = 0x0141;
+const NVME_WARNING_TEMPERATURE: u16 = 0x0157;
+const NVME_CRITICAL_TEMPERATURE: u16 = 0x0175;
+
+const NVME_SERIAL: &str = "crosvm-nvme";
+const NVME_MODEL: &str = "crosvm NVMe Controller";
+const NVME_FIRMWARE: &str = "1.0";
+const NVME_SUBNQN: &str = "nqn.2024-01.org.chromium:crosvm-nvme";
+
+#[derive(Clone, Copy)]
+enum NvmeProgrammingInterface {
+    NvmExpress = 0x02,
+}
+
+impl PciProgrammingInterface for NvmeProgrammingInterface {
+    fn get_register_value(&self) -> u8 {
+        *self as u8
+    }
+}
+
+#[sorted]
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("failed to get the size of namespace {0}: {1}")]
+    DiskSize(u32, io::Error),
+    #[error("namespace {0} has an invalid block size {1}")]
+    InvalidBlockSize(u32, u32),
+    #[error("an NVMe controller needs at least one namespace")]
+    NoNamespaces,
+    #[error("{0} namespaces exceed the limit of {NVME_MAX_NAMESPACES}")]
+    TooManyNamespaces(usize),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+fn nvme_option_block_size_default() -> u32 {
+    512
+}
+
+fn nvme_option_sparse_default() -> bool {
+    true
+}
+
+/// Parameters for a namespace of the emulated NVMe controller.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, serde_keyvalue::FromKeyValues)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct NvmeOption {
+    /// Path to the disk image.
+    pub path: PathBuf,
+    /// Whether the namespace is write protected.
+    #[serde(default, rename = "ro")]
+    pub read_only: bool,
+    /// The logical block size reported to the driver.
+    #[serde(default = "nvme_option_block_size_default")]
+    pub block_size: u32,
+    /// Whether deallocated blocks are punched out of the disk image.
+    #[serde(default = "nvme_option_sparse_default")]
+    pub sparse: bool,
+}
+
+impl NvmeOption {
+    /// Open the disk image backing the namespace.
+    pub fn open(&self) -> anyhow::Result<Box<dyn DiskFile>> {
+        let mut options = OpenOptions::new();
+        options.read(true).write(!self.read_only);
+
+        let raw_image: File = open_file_or_duplicate(&self.path, &options)
+            .with_context(|| format!("failed to load disk image {}", self.path.display()))?;
+        // Lock the disk image to prevent other crosvm instances from using it.
+        let lock_op = if self.read_only {
+            FlockOperation::LockShared
+        } else {
+            FlockOperation::LockExclusive
+        };
+        flock(&raw_image, lock_op, true)
+            .with_context(|| format!("failed to lock disk image {}", self.path.display()))?;
+
+        disk::create_disk_file(raw_image, self.sparse, disk::MAX_NESTING_DEPTH, &self.path)
+            .context("create_disk_file failed")
+    }
+}
+
+/// A disk exposed as a namespace of the NVMe controller.
+pub struct NvmeDiskConfig {
+    pub file: Box<dyn DiskFile>,
+    pub block_size: u32,
+    pub read_only: bool,
+    pub sparse: bool,
+}
+
+/// Properties of a namespace that are fixed when the controller is created.
+#[derive(Clone, Copy, Debug)]
+struct NamespaceInfo {
+    /// log2 of the logical block size.
+    block_shift: u32,
+    num_blocks: u64,
+    read_only: bool,
+    sparse: bool,
+}
+
+impl NamespaceInfo {
+    /// Returns the byte offset and length of the logical block range of a read or write style
+    /// command.
+    fn lba_range(&self, cmd: &SubmissionEntry) -> CommandResult<(u64, usize)> {
+        let slba = (cmd.cdw11 as u64) << 32 | cmd.cdw10 as u64;
+        let nlb = (cmd.cdw12 & 0xffff) as u64 + 1;
+        self.check_range(slba, nlb)?;
+        Ok((slba << self.block_shift, (nlb << self.block_shift) as usize))
+    }
+
+    fn check_range(&self, slba: u64, nlb: u64) -> CommandResult<()> {
+        match slba.checked_add(nlb) {
+            Some(end) if end <= self.num_blocks => Ok(()),
+            _ => Err(Status::generic(NVME_SC_LBA_RANGE)),
+        }
+    }
+
+    fn identify(&self) -> IdentifyNamespace {
+        let mut id = IdentifyNamespace::new_zeroed();
+        id.nsze = self.num_blocks;
+        id.ncap = self.num_blocks;
+        id.nuse = self.num_blocks;
+        if self.sparse {
+            id.nsfeat = NVME_NSFEAT_THIN;
+            id.dlfeat = NVME_DLFEAT_READ_ZEROES;
+        }
+        if self.read_only {
+            id.nsattr = NVME_NSATTR_WRITE_PROTECTED;
+        }
+        id.lbaf[0].lbads = self.block_shift as u8;
+        id
+    }
+}
+
+/// Completion status of a command, as stored in a completion queue entry without the phase tag.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Status(u16);
+
+impl Status {
+    const DNR: u16 = 1 << 15;
+
+    fn new(sct: u8, sc: u8) -> Status {
+        Status(Status::DNR | (sct as u16) << 9 | (sc as u16) << 1)
+    }
+
+    fn generic(sc: u8) -> Status {
+        Status::new(NVME_SCT_GENERIC, sc)
+    }
+
+    fn command_specific(sc: u8) -> Status {
+        Status::new(NVME_SCT_COMMAND_SPECIFIC, sc)
+    }
+}
+
+/// Result of a command: Dword 0 of the completion entry, or an error status.
+type CommandResult<T = u32> = std::result::Result<T, Status>;
+
+/// Returns the guest memory regions described by the PRP entries `prp1` and `prp2` for a transfer
+/// of `len` bytes.
+fn prp_regions(
+    mem: &GuestMemory,
+    prp1: u64,
+    prp2: u64,
+    len: usize,
+) -> CommandResult<Vec<MemRegion>> {
+    let page_size = NVME_PAGE_SIZE as usize;
+    let mut regions = Vec::new();
+    let mut push = |offset: u64, len: usize| match regions.last_mut() {
+        Some(MemRegion {
+            offset: last_offset,
+            len: last_len,
+        }) if *last_offset + *last_len as u64 == offset => *last_len += len,
+        _ => regions.push(MemRegion { offset, len }),
+    };
+
+    let first_len = len.min(page_size - (prp1 % NVME_PAGE_SIZE) as usize);
+    push(prp1, first_len);
+    let mut remaining = len - first_len;
+    if remaining == 0 {
+        return Ok(regions);
+    }
+    if remaining <= page_size {
+        if prp2 % NVME_PAGE_SIZE != 0 {
+            return Err(Status::generic(NVME_SC_PRP_OFFSET_INVALID));
+        }
+        push(prp2, remaining);
+        return Ok(regions);
+    }
+
+    // PRP2 points to a list of PRP entries. The last entry of each list page points to the next
+    // page of the list when more entries are needed.
+    if prp2 % 8 != 0 {
+        return Err(Status::generic(NVME_SC_PRP_OFFSET_INVALID));
+    }
+    let mut list = prp2;
+    loop {
+        let entries = (NVME_PAGE_SIZE - list % NVME_PAGE_SIZE) / 8;
+        for i in 0..entries {
+            let entry: u64 = mem
+                .read_obj_from_addr(GuestAddress(list + i * 8))
+                .map_err(|_| Status::generic(NVME_SC_DATA_TRANSFER_ERROR))?;
+            if entry % NVME_PAGE_SIZE != 0 {
+                return Err(Status::generic(NVME_SC_PRP_OFFSET_INVALID));
+            }
+            if i == entries - 1 && remaining > page_size {
+                list = entry;
+                break;
+            }
+            let len = remaining.min(page_size);
+            push(entry, len);
+            remaining -= len;
+            if remaining == 0 {
+                return Ok(regions);
+            }
+        }
+    }
+}
+
+/// Copies `data` to the guest buffer described by the PRP entries of `cmd`.
+fn write_to_prps(mem: &GuestMemory, cmd: &SubmissionEntry, data: &[u8]) -> CommandResult<()> {
+    let mut offset = 0;
+    for region in prp_regions(mem, cmd.prp1, cmd.prp2, data.len())? {
+        mem.write_all_at_addr(
+            &data[offset..offset + region.len],
+            GuestAddress(region.offset),
+        )
+        .map_err(|_| Status::generic(NVME_SC_DATA_TRANSFER_ERROR))?;
+        offset += region.len;
+    }
+    Ok(())
+}
+
+/// Reads `len` bytes from the guest buffer described by the PRP entries of `cmd`.
+fn read_from_prps(mem: &GuestMemory, cmd: &SubmissionEntry, len: usize) -> CommandResult<Vec<u8>> {
+    let mut data = vec![0u8; len];
+    let mut offset = 0;
+    for region in prp_regions(mem, cmd.prp1, cmd.prp2, len)? {
+        mem.read_exact_at_addr(
+            &mut data[offset..offset + region.len],
+            GuestAddress(region.offset),
+        )
+        .map_err(|_| Status::generic(NVME_SC_DATA_TRANSFER_ERROR))?;
+        offset += region.len;
+    }
+    Ok(data)
+}
+
+/// Copies `s` to `field`, padded with spaces as required for ASCII fields.
+fn ascii_field(field: &mut [u8], s: &str) {
+    field.fill(b' ');
+    field[..s.len()].copy_from_slice(s.as_bytes());
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct SubmissionQueue {
+    base: GuestAddress,
+    size: u16,
+    head: u16,
+    tail: u16,
+    cqid: u16,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct CompletionQueue {
+    base: GuestAddress,
+    size: u16,
+    head: u16,
+    tail: u16,
+    phase: bool,
+    vector: u16,
+    interrupts_enabled: bool,
+}
+
+impl CompletionQueue {
+    fn is_full(&self) -> bool {
+        (self.tail + 1) % self.size == self.head
+    }
+}
+
+/// Requests from the register interface to the worker thread.
+enum WorkerCmd {
+    /// The controller was enabled with the given admin queues.
+    Enable {
+        asq: GuestAddress,
+        asq_size: u16,
+        acq: GuestAddress,
+        acq_size: u16,
+    },
+    /// The driver wrote `value` to the doorbell register with the given index.
+    Doorbell { index: usize, value: u32 },
+    /// The controller was disabled. All queues are deleted before responding.
+    Reset { response_tx: oneshot::Sender<()> },
+    /// The driver requested a shutdown. All namespaces are flushed before responding.
+    Shutdown { response_tx: oneshot::Sender<()> },
+    /// The device is going to sleep. The worker hands over its state and processes no command
+    /// until it gets a state back with `Wake`.
+    Sleep {
+        response_tx: oneshot::Sender<WorkerState>,
+    },
+    /// The device woke up with the given state, which may have been restored from a snapshot.
+    Wake { state: WorkerState },
+}
+
+/// Counters reported in the SMART / Health Information log page.
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct Stats {
+    bytes_read: u128,
+    bytes_written: u128,
+    read_commands: u128,
+    write_commands: u128,
+}
+
+struct Namespace {
+    info: NamespaceInfo,
+    disk: Box<dyn AsyncDisk>,
+}
+
+/// State of the controller that is handled by the worker, and saved in snapshots.
+#[derive(Clone, Serialize, Deserialize)]
+struct WorkerState {
+    sqs: BTreeMap<u16, SubmissionQueue>,
+    cqs: BTreeMap<u16, CompletionQueue>,
+    features: BTreeMap<u8, u32>,
+    pending_aers: u8,
+    stats: Stats,
+}
+
+impl Default for WorkerState {
+    fn default() -> Self {
+        WorkerState {
+            sqs: BTreeMap::new(),
+            cqs: BTreeMap::new(),
+            features: default_features(),
+            pending_aers: 0,
+            stats: Stats::default(),
+        }
+    }
+}
+
+struct Worker {
+    mem: GuestMemory,
+    msix_config: Arc<Mutex<MsixConfig>>,
+    namespaces: Vec<Namespace>,
+    state: WorkerState,
+    /// Set when the controller hits an unrecoverable error, reported in CSTS.CFS.
+    fatal: Arc<AtomicBool>,
+}
+
+fn default_features() -> BTreeMap<u8, u32> {
+    BTreeMap::from([
+        (NVME_FEAT_ARBITRATION, 0),
+        (NVME_FEAT_POWER_MANAGEMENT, 0),
+        (
+            NVME_FEAT_TEMPERATURE_THRESHOLD,
+            NVME_WARNING_TEMPERATURE as u32,
+        ),
+        (NVME_FEAT_ERROR_RECOVERY, 0),
+        (NVME_FEAT_VOLATILE_WRITE_CACHE, 1),
+        (NVME_FEAT_INTERRUPT_COALESCING, 0),
+        (NVME_FEAT_WRITE_ATOMICITY, 0),
+        (NVME_FEAT_ASYNC_EVENT_CONFIG, 0),
+    ])
+}
+
+impl Worker {
+    async fn handle(&mut self, cmd: WorkerCmd) {
+        match cmd {
+            WorkerCmd::Enable {
+                asq,
+                asq_size,
+                acq,
+                acq_size,
+            } => {
+                self.state.cqs.insert(
+                    0,
+                    CompletionQueue {
+                        base: acq,
+                        size: acq_size,
+                        head: 0,
+                        tail: 0,
+                        phase: true,
+                        vector: 0,
+                        interrupts_enabled: true,
+                    },
+                );
+                self.state.sqs.insert(
+                    0,
+                    SubmissionQueue {
+                        base: asq,
+                        size: asq_size,
+                        head: 0,
+                        tail: 0,
+                        cqid: 0,
+                    },
+                );
+            }
+            WorkerCmd::Doorbell { index, value } => {
+                let qid = (index / 2) as u16;
+                let is_cq = index % 2 == 1;
+                let size = if is_cq {
+                    self.state.cqs.get(&qid).map(|cq| cq.size)
+                } else {
+                    self.state.sqs.get(&qid).map(|sq| sq.size)
+                };
+                match size {
+                    Some(size) if value < size as u32 => {
+                        if is_cq {
+                            self.state.cqs.get_mut(&qid).unwrap().head = value as u16;
+                        } else {
+                            self.state.sqs.get_mut(&qid).unwrap().tail = value as u16;
+                        }
+                    }
+                    _ => {
+                        warn!("nvme: invalid doorbell write {} to queue {}", value, qid);
+                        return;
+                    }
+                }
+                self.process_queues().await;
+            }
+            WorkerCmd::Reset { response_tx } => {
+                self.state.sqs.clear();
+                self.state.cqs.clear();
+                self.state.features = default_features();
+                self.state.pending_aers = 0;
+                let _ = response_tx.send(());
+            }
+            WorkerCmd::Shutdown { response_tx } => {
+                if let Err(e) = self.flush(NVME_NSID_ALL).await {
+                    error!("nvme: failed to flush namespaces on shutdown: {:?}", e);
+                }
+                let _ = response_tx.send(());
+            }
+            WorkerCmd::Sleep { response_tx } => {
+                // Every command submitted before the last doorbell write has been processed, so
+                // only the queue state needs saving. Leave empty queues behind so that stray
+                // doorbell writes are ignored.
+                let _ = response_tx.send(mem::take(&mut self.state));
+            }
+            WorkerCmd::Wake { state } => {
+                self.state = state;
+                // Commands may have been left behind by a completion queue that was full.
+                self.process_queues().await;
+            }
+        }
+    }
+
+    /// Processes the submitted commands of every submission queue, as long as the associated
+    /// completion queue has room for their completions.
+    async fn process_queues(&mut self) {
+        let sqids: Vec<u16> = self.state.sqs.keys().copied().collect();
+        for sqid in sqids {
+            while let Some(cmd) = self.next_command(sqid) {
+                let result = if sqid == 0 {
+                    self.admin_command(&cmd).await
+                } else {
+                    Some(self.io_command(&cmd).await)
+                };
+                if let Some(result) = result {
+                    self.complete(sqid, &cmd, result);
+                }
+            }
+        }
+    }
+
+    fn next_command(&mut self, sqid: u16) -> Option<SubmissionEntry> {
+        let sq = self.state.sqs.get_mut(&sqid)?;
+        if sq.head == sq.tail || self.state.cqs.get(&sq.cqid)?.is_full() {
+            return None;
+        }
+        let addr = sq.base.unchecked_add((sq.head as u64) << NVME_SQES);
+        match self.mem.read_obj_from_addr(addr) {
+            Ok(cmd) => {
+                sq.head = (sq.head + 1) % sq.size;
+                Some(cmd)
+            }
+            Err(e) => {
+                error!("nvme: failed to read submission queue {}: {}", sqid, e);
+                self.fail();
+                None
+            }
+        }
+    }
+
+    fn complete(&mut self, sqid: u16, cmd: &SubmissionEntry, result: CommandResult) {
+        let Some(sq) = self.state.sqs.get(&sqid) else {
+            return;
+        };
+        let (cqid, sq_head) = (sq.cqid, sq.head);
+        let Some(cq) = self.state.cqs.get_mut(&cqid) else {
+            return;
+        };
+        let (result, status) = match result {
+            Ok(result) => (result, 0),
+            Err(status) => (0, status.0),
+        };
+        let entry = CompletionEntry {
+            result,
+            reserved: 0,
+            sq_head,
+            sq_id: sqid,
+            command_id: cmd.command_id,
+            status: status | cq.phase as u16,
+        };
+
+        // The driver polls the phase tag, so it must become visible after the rest of the entry.
+        let addr = cq.base.unchecked_add((cq.tail as u64) << NVME_CQES);
+        let status_offset = mem::offset_of!(CompletionEntry, status);
+        let written = self
+            .mem
+            .write_all_at_addr(&entry.as_bytes()[..status_offset], addr)
+            .and_then(|_| {
+                fence(Ordering::Release);
+                self.mem
+                    .write_obj_at_addr(entry.status, addr.unchecked_add(status_offset as u64))
+            });
+        if let Err(e) = written {
+            error!("nvme: failed to write completion queue {}: {}", cqid, e);
+            self.fail();
+            return;
+        }
+
+        cq.tail = (cq.tail + 1) % cq.size;
+        if cq.tail == 0 {
+            cq.phase = !cq.phase;
+        }
+        if cq.interrupts_enabled {
+            self.msix_config.lock().trigger(cq.vector);
+        }
+    }
+
+    /// Stops processing commands after an unrecoverable error, until the controller is reset.
+    fn fail(&mut self) {
+        self.fatal.store(true, Ordering::SeqCst);
+        self.state.sqs.clear();
+        self.state.cqs.clear();
+    }
+
+    fn namespace(&self, nsid: u32) -> CommandResult<&Namespace> {
+        nsid.checked_sub(1)
+            .and_then(|i| self.namespaces.get(i as usize))
+            .ok_or(Status::generic(NVME_SC_INVALID_NS))
+    }
+
+    /// Returns the admin command result, or `None` if the command completes later.
+    async fn admin_command(&mut self, cmd: &SubmissionEntry) -> Option<CommandResult> {
+        let result = match cmd.opcode {
+            NVME_ADM_DELETE_SQ => self.delete_sq(cmd),
+            NVME_ADM_CREATE_SQ => self.create_sq(cmd),
+            NVME_ADM_GET_LOG_PAGE => self.get_log_page(cmd),
+            NVME_ADM_DELETE_CQ => self.delete_cq(cmd),
+            NVME_ADM_CREATE_CQ => self.create_cq(cmd),
+            NVME_ADM_IDENTIFY => self.identify(cmd),
+            // Commands complete too quickly to be aborted.
+            NVME_ADM_ABORT => Ok(1),
+            NVME_ADM_SET_FEATURES => self.set_features(cmd),
+            NVME_ADM_GET_FEATURES => self.get_features(cmd),
+            NVME_ADM_ASYNC_EVENT_REQUEST => {
+                // No asynchronous events are generated, so the requests stay outstanding until
+                // the controller is reset.
+                if self.state.pending_aers > NVME_AERL {
+                    Err(Status::command_specific(NVME_SC_AER_LIMIT))
+                } else {
+                    self.state.pending_aers += 1;
+                    return None;
+                }
+            }
+            _ => Err(Status::generic(NVME_SC_INVALID_OPCODE)),
+        };
+        Some(result)
+    }
+
+    /// Returns the queue ID and 0's based size from Command Dword 10 of a queue creation command.
+    fn new_queue_params(cmd: &SubmissionEntry) -> CommandResult<(u16, u16)> {
+        let qid = cmd.cdw10 as u16;
+        let size = (cmd.cdw10 >> 16) as u16;
+        if qid == 0 || qid > NVME_MAX_IO_QUEUES {
+            return Err(Status::command_specific(NVME_SC_QID_INVALID));
+        }
+        if size == 0 || size as u32 >= NVME_MAX_QUEUE_ENTRIES {
+            return Err(Status::command_specific(NVME_SC_QUEUE_SIZE));
+        }
+        // Only physically contiguous queues are supported (CAP.CQR).
+        if cmd.cdw11 & 1 == 0 || cmd.prp1 % NVME_PAGE_SIZE != 0 {
+            return Err(Status::generic(NVME_SC_INVALID_FIELD));
+        }
+        Ok((qid, size + 1))
+    }
+
+    fn create_cq(&mut self, cmd: &SubmissionEntry) -> CommandResult {
+        let (qid, size) = Self::new_queue_params(cmd)?;
+        if self.state.cqs.contains_key(&qid) {
+            return Err(Status::command_specific(NVME_SC_QID_INVALID));
+        }
+        let vector = (cmd.cdw11 >> 16) as u16;
+        if vector >= NVME_MSIX_VECTORS {
+            return Err(Status::command_specific(NVME_SC_INVALID_VECTOR));
+        }
+        self.state.cqs.insert(
+            qid,
+            CompletionQueue {
+                base: GuestAddress(cmd.prp1),
+                size,
+                head: 0,
+                tail: 0,
+                phase: true,
+                vector,
+                interrupts_enabled: cmd.cdw11 & (1 << 1) != 0,
+            },
+        );
+        Ok(0)
+    }
+
+    fn create_sq(&mut self, cmd: &SubmissionEntry) -> CommandResult {
+        let (qid, size) = Self::new_queue_params(cmd)?;
+        if self.state.sqs.contains_key(&qid) {
+            return Err(Status::command_specific(NVME_SC_QID_INVALID));
+        }
+        let cqid = (cmd.cdw11 >> 16) as u16;
+        if cqid == 0 || !self.state.cqs.contains_key(&cqid) {
+            return Err(Status::command_specific(NVME_SC_CQ_INVALID));
+        }
+        self.state.sqs.insert(
+            qid,
+            SubmissionQueue {
+                base: GuestAddress(cmd.prp1),
+                size,
+                head: 0,
+                tail: 0,
+                cqid,
+            },
+        );
+        Ok(0)
+    }
+
+    fn delete_sq(&mut self, cmd: &SubmissionEntry) -> CommandResult {
+        let qid = cmd.cdw10 as u16;
+        if qid == 0 || self.state.sqs.remove(&qid).is_none() {
+            return Err(Status::command_specific(NVME_SC_QID_INVALID));
+        }
+        Ok(0)
+    }
+
+    fn delete_cq(&mut self, cmd: &SubmissionEntry) -> CommandResult {
+        let qid = cmd.cdw10 as u16;
+        if qid == 0 || !self.state.cqs.contains_key(&qid) {
+            return Err(Status::command_specific(NVME_SC_QID_INVALID));
+        }
+        if self.state.sqs.values().any(|sq| sq.cqid == qid) {
+            return Err(Status::command_specific(NVME_SC_INVALID_QUEUE_DELETION));
+        }
+        self.state.cqs.remove(&qid);
+        Ok(0)
+    }
+
+    fn identify(&self, cmd: &SubmissionEntry) -> CommandResult {
+        let mut data = vec![0u8; NVME_PAGE_SIZE as usize];
+        match cmd.cdw10 as u8 {
+            NVME_ID_CNS_NAMESPACE => {
+                let ns = self.namespace(cmd.nsid)?;
+                data.copy_from_slice(ns.info.identify().as_bytes());
+            }
+            NVME_ID_CNS_CONTROLLER => {
+                data.copy_from_slice(self.identify_controller().as_bytes());
+            }
+            NVME_ID_CNS_ACTIVE_NS_LIST => {
+                // Namespace IDs greater than the one in the command, in increasing order.
+                let first = cmd.nsid.saturating_add(1).max(1);
+                let nsids = (first..=self.namespaces.len() as u32).take(data.len() / 4);
+                for (chunk, nsid) in data.chunks_exact_mut(4).zip(nsids) {
+                    chunk.copy_from_slice(&nsid.to_le_bytes());
+                }
+            }
+            NVME_ID_CNS_NS_DESCRIPTOR_LIST => {
+                // No namespace identifiers are reported.
+                self.namespace(cmd.nsid)?;
+            }
+            _ => return Err(Status::generic(NVME_SC_INVALID_FIELD)),
+        }
+        write_to_prps(&self.mem, cmd, &data)?;
+        Ok(0)
+    }
+
+    fn identify_controller(&self) -> IdentifyController {
+        let mut id = IdentifyController::new_zeroed();
+        id.vid = PCI_VENDOR_ID_REDHAT;
+        id.ssvid = PCI_VENDOR_ID_REDHAT;
+        ascii_field(&mut id.sn, NVME_SERIAL);
+        ascii_field(&mut id.mn, NVME_MODEL);
+        ascii_field(&mut id.fr, NVME_FIRMWARE);
+        id.rab = 6;
+        id.mdts = NVME_MDTS;
+        id.ver = NVME_VERSION;
+        // I/O controller.
+        id.cntrltype = 1;
+        id.acl = NVME_ACL;
+        id.aerl = NVME_AERL;
+        // One read-only firmware slot.
+        id.frmw = 1 << 1 | 1;
+        // Extended data for Get Log Page.
+        id.lpa = 1 << 2;
+        id.wctemp = NVME_WARNING_TEMPERATURE;
+        id.cctemp = NVME_CRITICAL_TEMPERATURE;
+        id.sqes = (NVME_SQES << 4 | NVME_SQES) as u8;
+        id.cqes = (NVME_CQES << 4 | NVME_CQES) as u8;
+        id.nn = self.namespaces.len() as u32;
+        id.oncs = NVME_ONCS_DSM | NVME_ONCS_WRITE_ZEROES;
+        id.vwc = 1;
+        id.subnqn[..NVME_SUBNQN.len()].copy_from_slice(NVME_SUBNQN.as_bytes());
+        // 25 W.
+        id.psd[0].mp = 2500;
+        id
+    }
+
+    fn get_log_page(&self, cmd: &SubmissionEntry) -> CommandResult {
+        let len = (((cmd.cdw11 & 0xffff) << 16 | cmd.cdw10 >> 16) as usize + 1) * 4;
+        let offset = (cmd.cdw13 as u64) << 32 | cmd.cdw12 as u64;
+        if len > NVME_MAX_TRANSFER {
+            return Err(Status::generic(NVME_SC_INVALID_FIELD));
+        }
+        let log = match cmd.cdw10 as u8 {
+            // A single, empty error information entry.
+            NVME_LOG_ERROR_INFO => vec![0u8; 64],
+            NVME_LOG_SMART => self.smart_log().as_bytes().to_vec(),
+            NVME_LOG_FIRMWARE_SLOT => {
+                let mut log = FirmwareSlotLog::new_zeroed();
+                log.afi = 1;
+                ascii_field(&mut log.frs[0], NVME_FIRMWARE);
+                log.as_bytes().to_vec()
+            }
+            _ => return Err(Status::command_specific(NVME_SC_INVALID_LOG_PAGE)),
+        };
+        if offset > log.len() as u64 || offset % 4 != 0 {
+            return Err(Status::generic(NVME_SC_INVALID_FIELD));
+        }
+        let mut data = log[offset as usize..].to_vec();
+        data.resize(len, 0);
+        write_to_prps(&self.mem, cmd, &data)?;
+        Ok(0)
+    }
+
+    fn smart_log(&self) -> SmartLog {
+        // Data units are thousands of 512 byte units, rounded up.
+        let data_units = |bytes: u128| bytes.div_ceil(512 * 1000).to_le_bytes();
+        let mut log = SmartLog::new_zeroed();
+        log.temperature = NVME_TEMPERATURE.to_le_bytes();
+        log.avail_spare = 100;
+        log.spare_thresh = 10;
+        log.data_units_read = data_units(self.state.stats.bytes_read);
+        log.data_units_written = data_units(self.state.stats.bytes_written);
+        log.host_read_commands = self.state.stats.read_commands.to_le_bytes();
+        log.host_write_commands = self.state.stats.write_commands.to_le_bytes();
+        log
+    }
+
+    fn number_of_queues() -> u32 {
+        let n = (NVME_MAX_IO_QUEUES - 1) as u32;
+        n << 16 | n
+    }
+
+    fn set_features(&mut self, cmd: &SubmissionEntry) -> CommandResult {
+        let fid = cmd.cdw10 as u8;
+        if cmd.cdw10 & (1 << 31) != 0 {
+            return Err(Status::command_specific(NVME_SC_FEATURE_NOT_SAVEABLE));
+        }
+        match fid {
+            NVME_FEAT_NUMBER_OF_QUEUES => Ok(Self::number_of_queues()),
+            // The configuration only affects interrupt coalescing, which is not implemented.
+            NVME_FEAT_INTERRUPT_VECTOR_CONFIG => Ok(0),
+            _ => match self.state.features.get_mut(&fid) {
+                Some(value) => {
+                    *value = cmd.cdw11;
+                    Ok(0)
+                }
+                None => Err(Status::generic(NVME_SC_INVALID_FIELD)),
+            },
+        }
+    }
+
+    fn get_features(&self, cmd: &SubmissionEntry) -> CommandResult {
+        let fid = cmd.cdw10 as u8;
+        let select = (cmd.cdw10 >> 8) & 0x7;
+        match (fid, select) {
+            // Supported capabilities: nothing is saveable, namespace specific or changeable.
+            (_, 3) => Some(0),
+            (NVME_FEAT_NUMBER_OF_QUEUES, _) => Some(Self::number_of_queues()),
+            (NVME_FEAT_INTERRUPT_VECTOR_CONFIG, _) => Some(cmd.cdw11 & 0xffff),
+            // Current value.
+            (fid, 0) => self.state.features.get(&fid).copied(),
+            // Default and saved values.
+            (fid, _) => default_features().get(&fid).copied(),
+        }
+        .ok_or(Status::generic(NVME_SC_INVALID_FIELD))
+    }
+
+    async fn io_command(&mut self, cmd: &SubmissionEntry) -> CommandResult {
+        match cmd.opcode {
+            NVME_CMD_FLUSH => self.flush(cmd.nsid).await,
+            NVME_CMD_WRITE => self.read_write(cmd, true).await,
+            NVME_CMD_READ => self.read_write(cmd, false).await,
+            NVME_CMD_WRITE_ZEROES => self.write_zeroes(cmd).await,
+            NVME_CMD_DSM => self.dataset_management(cmd).await,
+            _ => Err(Status::generic(NVME_SC_INVALID_OPCODE)),
+        }
+    }
+
+    async fn flush(&self, nsid: u32) -> CommandResult {
+        let namespaces = if nsid == NVME_NSID_ALL {
+            &self.namespaces[..]
+        } else {
+            std::slice::from_ref(self.namespace(nsid)?)
+        };
+        for ns in namespaces {
+            ns.disk.fdatasync().await.map_err(|e| {
+                error!("nvme: failed to flush: {}", e);
+                Status::generic(NVME_SC_INTERNAL)
+            })?;
+        }
+        Ok(0)
+    }
+
+    async fn read_write(&mut self, cmd: &SubmissionEntry, write: bool) -> CommandResult {
+        let ns = self.namespace(cmd.nsid)?;
+        if write && ns.info.read_only {
+            return Err(Status::generic(NVME_SC_NS_WRITE_PROTECTED));
+        }
+        let (offset, len) = ns.info.lba_range(cmd)?;
+        if len > NVME_MAX_TRANSFER {
+            return Err(Status::generic(NVME_SC_INVALID_FIELD));
+        }
+        let regions = prp_regions(&self.mem, cmd.prp1, cmd.prp2, len)?;
+        let mem = Arc::new(self.mem.clone());
+        let mut done = 0;
+        while done < len {
+            let mem_offsets = MemRegionIter::new(&regions).skip_bytes(done);
+            let file_offset = offset + done as u64;
+            let result = if write {
+                ns.disk
+                    .write_from_mem(file_offset, mem.clone(), mem_offsets)
+                    .await
+            } else {
+                ns.disk
+                    .read_to_mem(file_offset, mem.clone(), mem_offsets)
+                    .await
+            };
+            match result {
+                Ok(0) => {
+                    error!("nvme: unexpected end of disk at {}", file_offset);
+                    return Err(Status::generic(NVME_SC_INTERNAL));
+                }
+                Ok(n) => done += n,
+                Err(e) => {
+                    error!("nvme: failed to access disk at {}: {}", file_offset, e);
+                    return Err(Status::generic(NVME_SC_INTERNAL));
+                }
+            }
+        }
+
+        if write {
+            self.state.stats.bytes_written += len as u128;
+            self.state.stats.write_commands += 1;
+        } else {
+            self.state.stats.bytes_read += len as u128;
+            self.state.stats.read_commands += 1;
+        }
+        Ok(0)
+    }
+
+    async fn write_zeroes(&self, cmd: &SubmissionEntry) -> CommandResult {
+        let ns = self.namespace(cmd.nsid)?;
+        if ns.info.read_only {
+            return Err(Status::generic(NVME_SC_NS_WRITE_PROTECTED));
+        }
+        let (offset, len) = ns.info.lba_range(cmd)?;
+        let deallocate = cmd.cdw12 & (1 << 25) != 0;
+        let result = if deallocate && ns.info.sparse {
+            ns.disk.punch_hole(offset, len as u64).await
+        } else {
+            ns.disk.write_zeroes_at(offset, len as u64).await
+        };
+        result.map_err(|e| {
+            error!("nvme: failed to write zeroes at {}: {}", offset, e);
+            Status::generic(NVME_SC_INTERNAL)
+        })?;
+        Ok(0)
+    }
+
+    async fn dataset_management(&self, cmd: &SubmissionEntry) -> CommandResult {
+        let ns = self.namespace(cmd.nsid)?;
+        // The other attributes are only hints.
+        if cmd.cdw11 & NVME_DSM_ATTR_DEALLOCATE == 0 {
+            return Ok(0);
+        }
+        if ns.info.read_only {
+            return Err(Status::generic(NVME_SC_NS_WRITE_PROTECTED));
+        }
+        let num_ranges = (cmd.cdw10 & 0xff) as usize + 1;
+        let data = read_from_prps(&self.mem, cmd, num_ranges * mem::size_of::<DsmRange>())?;
+        let ranges: Vec<DsmRange> = data
+            .chunks_exact(mem::size_of::<DsmRange>())
+            .map(|chunk| DsmRange::read_from(chunk).unwrap())
+            .collect();
+        for range in &ranges {
+            ns.info.check_range(range.slba, range.nlb as u64)?;
+        }
+        // Deallocation is advisory, so blocks are only discarded from sparse disks.
+        if !ns.info.sparse {
+            return Ok(0);
+        }
+        for range in ranges.iter().filter(|range| range.nlb > 0) {
+            let offset = range.slba << ns.info.block_shift;
+            let len = (range.nlb as u64) << ns.info.block_shift;
+            ns.disk.punch_hole(offset, len).await.map_err(|e| {
+                error!("nvme: failed to deallocate at {}: {}", offset, e);
+                Status::generic(NVME_SC_INTERNAL)
+            })?;
+        }
+        Ok(0)
+    }
+}
+
+async fn run_worker(
+    ex: &Executor,
+    mut worker: Worker,
+    mut cmd_rx: mpsc::UnboundedReceiver<WorkerCmd>,
+    kill_evt: Event,
+) -> anyhow::Result<()> {
+    let kill_evt = EventAsync::new(kill_evt, ex).context("failed to create async kill event")?;
+    let kill = kill_evt.next_val().fuse();
+    pin_mut!(kill);
+
+    loop {
+        futures::select! {
+            r = kill => {
+                r.context("failed to wait on the kill event")?;
+                return Ok(());
+            }
+            cmd = cmd_rx.next() => match cmd {
+                Some(cmd) => worker.handle(cmd).await,
+                None => return Ok(()),
+            },
+        }
+    }
+}
+
+/// Emulated NVMe controller on the PCI bus.
+pub struct NvmeController {
+    pci_address: Option<PciAddress>,
+    config_regs: PciConfiguration,
+    msix_config: Arc<Mutex<MsixConfig>>,
+    mem: GuestMemory,
+    namespaces: Vec<NamespaceInfo>,
+    /// Disks backing the namespaces, until the worker thread takes them.
+    disks: Vec<Box<dyn DiskFile>>,
+    worker: Option<(WorkerThread<()>, mpsc::UnboundedSender<WorkerCmd>)>,
+    /// State taken from the worker while the device sleeps.
+    sleep_state: Option<WorkerState>,
+    fatal: Arc<AtomicBool>,
+    intms: u32,
+    cc: u32,
+    csts: u32,
+    aqa: u32,
+    asq: u64,
+    acq: u64,
+}
+
+impl NvmeController {
+    /// Creates a controller exposing `disks` as namespaces 1 to N.
+    pub fn new(
+        mem: GuestMemory,
+        disks: Vec<NvmeDiskConfig>,
+        msi_device_tube: Tube,
+    ) -> Result<NvmeController> {
+        if disks.is_empty() {
+            return Err(Error::NoNamespaces);
+        }
+        if disks.len() > NVME_MAX_NAMESPACES {
+            return Err(Error::TooManyNamespaces(disks.len()));
+        }
+
+        let mut namespaces = Vec::new();
+        let mut files = Vec::new();
+        for (nsid, disk) in (1..).zip(disks) {
+            if !disk.block_size.is_power_of_two()
+                || !(512..=NVME_PAGE_SIZE as u32).contains(&disk.block_size)
+            {
+                return Err(Error::InvalidBlockSize(nsid, disk.block_size));
+            }
+            let block_shift = disk.block_size.trailing_zeros();
+            let len = disk.file.get_len().map_err(|e| Error::DiskSize(nsid, e))?;
+            if len % disk.block_size as u64 != 0 {
+                warn!(
+                    "nvme: namespace {} size {} is not a multiple of the block size {}",
+                    nsid, len, disk.block_size
+                );
+            }
+            namespaces.push(NamespaceInfo {
+                block_shift,
+                num_blocks: len >> block_shift,
+                read_only: disk.read_only,
+                sparse: disk.sparse,
+            });
+            files.push(disk.file);
+        }
+
+        let msix_config = Arc::new(Mutex::new(MsixConfig::new(
+            NVME_MSIX_VECTORS,
+            msi_device_tube,
+            PciId::new(PCI_VENDOR_ID_REDHAT, PCI_DEVICE_ID_REDHAT_NVME).into(),
+            "nvme".to_string(),
+        )));
+        let config_regs = PciConfiguration::new(
+            PCI_VENDOR_ID_REDHAT,
+            PCI_DEVICE_ID_REDHAT_NVME,
+            PciClassCode::MassStorage,
+            &PciMassStorageSubclass::NonVolatileMemory,
+            Some(&NvmeProgrammingInterface::NvmExpress),
+            PciHeaderType::Device,
+            PCI_VENDOR_ID_REDHAT,
+            PCI_DEVICE_ID_REDHAT_NVME,
+            PCI_NVME_REVISION_ID,
+        );
+
+        Ok(NvmeController {
+            pci_address: None,
+            config_regs,
+            msix_config,
+            mem,
+            namespaces,
+            disks: files,
+            worker: None,
+            sleep_state: None,
+            fatal: Arc::new(AtomicBool::new(false)),
+            intms: 0,
+            cc: 0,
+            csts: 0,
+            aqa: 0,
+            asq: 0,
+            acq: 0,
+        })
+    }
+
+    fn cap() -> u64 {
+        // Worst case time to become ready, in 500 ms units.
+        const TIMEOUT: u64 = 20;
+        (NVME_MAX_QUEUE_ENTRIES - 1) as u64
+            | NVME_CAP_CQR
+            | TIMEOUT << NVME_CAP_TO_SHIFT
+            | NVME_CAP_CSS_NVM
+    }
+
+    fn start_worker(&mut self) -> &mpsc::UnboundedSender<WorkerCmd> {
+        let (_, cmd_tx) = self.worker.get_or_insert_with(|| {
+            let mem = self.mem.clone();
+            let msix_config = self.msix_config.clone();
+            let namespaces = self.namespaces.clone();
+            let disks = mem::take(&mut self.disks);
+            let fatal = self.fatal.clone();
+            let (cmd_tx, cmd_rx) = mpsc::unbounded();
+            let worker_thread = WorkerThread::start("v_nvme", move |kill_evt| {
+                let ex = Executor::new().expect("failed to create an executor");
+                let namespaces = namespaces
+                    .into_iter()
+                    .zip(disks)
+                    .map(|(info, disk)| {
+                        Ok(Namespace {
+                            info,
+                            disk: disk.to_async_disk(&ex)?,
+                        })
+                    })
+                    .collect::<disk::Result<_>>();
+                let namespaces = match namespaces {
+                    Ok(namespaces) => namespaces,
+                    Err(e) => {
+                        error!("nvme: failed to create async disk: {:#}", e);
+                        return;
+                    }
+                };
+                let worker = Worker {
+                    mem,
+                    msix_config,
+                    namespaces,
+                    state: WorkerState::default(),
+                    fatal,
+                };
+                if let Err(e) = ex
+                    .run_until(run_worker(&ex, worker, cmd_rx, kill_evt))
+                    .expect("run_until failed")
+                {
+                    error!("nvme: worker failed: {:#}", e);
+                }
+            });
+            (worker_thread, cmd_tx)
+        });
+        cmd_tx
+    }
+
+    fn send_to_worker(&mut self, cmd: WorkerCmd) {
+        if let Err(e) = self.start_worker().unbounded_send(cmd) {
+            error!("nvme: failed to send a request to the worker: {}", e);
+            self.fatal.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Sends a request that is answered once the worker has handled it, and waits for the answer.
+    /// Returns `None` if the worker is not running.
+    fn wait_for_worker<T>(
+        &mut self,
+        cmd: impl FnOnce(oneshot::Sender<T>) -> WorkerCmd,
+    ) -> Option<T> {
+        self.worker.as_ref()?;
+        let (response_tx, response_rx) = oneshot::channel();
+        self.send_to_worker(cmd(response_tx));
+        match cros_async::block_on(response_rx) {
+            Ok(response) => Some(response),
+            Err(_) => {
+                error!("nvme: worker stopped before responding");
+                None
+            }
+        }
+    }
+
+    fn enable(&mut self) {
+        let css = (self.cc >> NVME_CC_CSS_SHIFT) & NVME_CC_CSS_MASK;
+        let mps = (self.cc >> NVME_CC_MPS_SHIFT) & NVME_CC_MPS_MASK;
+        let asq_size = (self.aqa & 0xfff) as u16 + 1;
+        let acq_size = ((self.aqa >> 16) & 0xfff) as u16 + 1;
+        if css != 0
+            || mps != 0
+            || asq_size < 2
+            || acq_size < 2
+            || self.asq % NVME_PAGE_SIZE != 0
+            || self.acq % NVME_PAGE_SIZE != 0
+        {
+            error!("nvme: invalid controller configuration");
+            self.fatal.store(true, Ordering::SeqCst);
+            return;
+        }
+        self.send_to_worker(WorkerCmd::Enable {
+            asq: GuestAddress(self.asq),
+            asq_size,
+            acq: GuestAddress(self.acq),
+            acq_size,
+        });
+        self.csts |= NVME_CSTS_RDY;
+    }
+
+    fn reset(&mut self) {
+        self.wait_for_worker(|response_tx| WorkerCmd::Reset { response_tx });
+        self.fatal.store(false, Ordering::SeqCst);
+        self.intms = 0;
+        self.csts = 0;
+    }
+
+    fn write_cc(&mut self, value: u32) {
+        let old = mem::replace(&mut self.cc, value);
+        if old & NVME_CC_EN == 0 && value & NVME_CC_EN != 0 {
+            self.enable();
+        } else if old & NVME_CC_EN != 0 && value & NVME_CC_EN == 0 {
+            self.reset();
+        }
+
+        let shn = |cc: u32| (cc >> NVME_CC_SHN_SHIFT) & NVME_CC_SHN_MASK;
+        if shn(value) != 0 && shn(old) == 0 {
+            self.wait_for_worker(|response_tx| WorkerCmd::Shutdown { response_tx });
+            self.csts |= NVME_CSTS_SHST_COMPLETE;
+        }
+    }
+
+    fn read_reg(&self, offset: u64) -> u32 {
+        match offset {
+            NVME_REG_CAP => Self::cap() as u32,
+            o if o == NVME_REG_CAP + 4 => (Self::cap() >> 32) as u32,
+            NVME_REG_VS => NVME_VERSION,
+            NVME_REG_INTMS | NVME_REG_INTMC => self.intms,
+            NVME_REG_CC => self.cc,
+            NVME_REG_CSTS => {
+                let cfs = if self.fatal.load(Ordering::SeqCst) {
+                    NVME_CSTS_CFS
+                } else {
+                    0
+                };
+                self.csts | cfs
+            }
+            NVME_REG_AQA => self.aqa,
+            NVME_REG_ASQ => self.asq as u32,
+            o if o == NVME_REG_ASQ + 4 => (self.asq >> 32) as u32,
+            NVME_REG_ACQ => self.acq as u32,
+            o if o == NVME_REG_ACQ + 4 => (self.acq >> 32) as u32,
+            _ => 0,
+        }
+    }
+
+    fn write_reg(&mut self, offset: u64, value: u32) {
+        let enabled = self.cc & NVME_CC_EN != 0;
+        match offset {
+            NVME_REG_INTMS => self.intms |= value,
+            NVME_REG_INTMC => self.intms &= !value,
+            NVME_REG_CC => self.write_cc(value),
+            // The admin queue attributes can only change while the controller is disabled.
+            NVME_REG_AQA if !enabled => self.aqa = value,
+            NVME_REG_ASQ if !enabled => self.asq = self.asq & !0xffff_ffff | value as u64,
+            o if o == NVME_REG_ASQ + 4 && !enabled => {
+                self.asq = self.asq & 0xffff_ffff | (value as u64) << 32
+            }
+            NVME_REG_ACQ if !enabled => self.acq = self.acq & !0xffff_ffff | value as u64,
+            o if o == NVME_REG_ACQ + 4 && !enabled => {
+                self.acq = self.acq & 0xffff_ffff | (value as u64) << 32
+            }
+            o if (NVME_REG_DBS..NVME_REG_DBS + NVME_MSIX_VECTORS as u64 * 8).contains(&o) => {
+                if self.csts & NVME_CSTS_RDY != 0 {
+                    self.send_to_worker(WorkerCmd::Doorbell {
+                        index: ((o - NVME_REG_DBS) / 4) as usize,
+                        value,
+                    });
+                }
+            }
+            _ => warn!("nvme: ignored write to register {:#x}", offset),
+        }
+    }
+}
+
+impl PciDevice for NvmeController {
+    fn debug_label(&self) -> String {
+        "nvme".to_owned()
+    }
+
+    fn allocate_address(
+        &mut self,
+        resources: &mut SystemAllocator,
+    ) -> std::result::Result<PciAddress, PciDeviceError> {
+        if self.pci_address.is_none() {
+            self.pci_address = match resources.allocate_pci(0, self.debug_label()) {
+                Some(Alloc::PciBar {
+                    bus,
+                    dev,
+                    func,
+                    bar: _,
+                }) => Some(PciAddress { bus, dev, func }),
+                _ => None,
+            }
+        }
+        self.pci_address.ok_or(PciDeviceError::PciAllocationFailed)
+    }
+
+    fn keep_rds(&self) -> Vec<RawDescriptor> {
+        let mut rds = vec![self.msix_config.lock().get_msi_socket()];
+        for disk in &self.disks {
+            rds.extend(disk.as_raw_descriptors());
+        }
+        rds
+    }
+
+    fn preferred_irq(&self) -> PreferredIrq {
+        PreferredIrq::None
+    }
+
+    fn allocate_io_bars(
+        &mut self,
+        resources: &mut SystemAllocator,
+    ) -> std::result::Result<Vec<BarRange>, PciDeviceError> {
+        let address = self
+            .pci_address
+            .expect("allocate_address must be called prior to allocate_io_bars");
+        // NVMe spec 3.8.1: the controller registers are in a 64-bit memory BAR. Keep it below
+        // 4 GiB for firmware drivers.
+        let bar0_addr = resources
+            .allocate_mmio(
+                NVME_BAR_SIZE,
+                Alloc::PciBar {
+                    bus: address.bus,
+                    dev: address.dev,
+                    func: address.func,
+                    bar: NVME_BAR_INDEX as u8,
+                },
+                "nvme_bar0".to_string(),
+                AllocOptions::new()
+                    .max_address(u32::MAX.into())
+                    .align(NVME_BAR_SIZE),
+            )
+            .map_err(|e| PciDeviceError::IoAllocationFailed(NVME_BAR_SIZE, e))?;
+        let bar0_config = PciBarConfiguration::new(
+            NVME_BAR_INDEX,
+            NVME_BAR_SIZE,
+            PciBarRegionType::Memory64BitRegion,
+            PciBarPrefetchable::NotPrefetchable,
+        )
+        .set_address(bar0_addr);
+        self.config_regs
+            .add_pci_bar(bar0_config)
+            .map_err(|e| PciDeviceError::IoRegistrationFailed(bar0_addr, e))?;
+        Ok(vec![BarRange {
+            addr: bar0_addr,
+            size: NVME_BAR_SIZE,
+            prefetchable: false,
+        }])
+    }
+
+    fn get_bar_configuration(&self, bar_num: usize) -> Option<PciBarConfiguration> {
+        self.config_regs.get_bar_configuration(bar_num)
+    }
+
+    fn register_device_capabilities(&mut self) -> std::result::Result<(), PciDeviceError> {
+        let msix_cap = MsixCap::new(
+            NVME_BAR_INDEX as u8,
+            NVME_MSIX_VECTORS,
+            NVME_MSIX_TABLE_OFFSET as u32,
+            NVME_BAR_INDEX as u8,
+            NVME_MSIX_PBA_OFFSET as u32,
+        );
+        self.config_regs
+            .add_capability(&msix_cap, Some(Box::new(self.msix_config.clone())))
+            .map_err(PciDeviceError::CapabilitiesSetup)?;
+        Ok(())
+    }
+
+    fn read_config_register(&self, reg_idx: usize) -> u32 {
+        self.config_regs.read_reg(reg_idx)
+    }
+
+    fn write_config_register(&mut self, reg_idx: usize, offset: u64, data: &[u8]) {
+        self.config_regs.write_reg(reg_idx, offset, data);
+    }
+
+    fn setup_pci_config_mapping(
+        &mut self,
+        shmem: &SharedMemory,
+        base: usize,
+        len: usize,
+    ) -> std::result::Result<bool, PciDeviceError> {
+        self.config_regs
+            .setup_mapping(shmem, base, len)
+            .map(|_| true)
+            .map_err(PciDeviceError::MmioSetup)
+    }
+
+    fn read_bar(&mut self, bar_index: PciBarIndex, offset: u64, data: &mut [u8]) {
+        if bar_index != NVME_BAR_INDEX {
+            return;
+        }
+        match offset {
+            NVME_MSIX_TABLE_OFFSET..=NVME_MSIX_TABLE_LAST => self
+                .msix_config
+                .lock()
+                .read_msix_table(offset - NVME_MSIX_TABLE_OFFSET, data),
+            NVME_MSIX_PBA_OFFSET..=NVME_MSIX_PBA_LAST => self
+                .msix_config
+                .lock()
+                .read_pba_entries(offset - NVME_MSIX_PBA_OFFSET, data),
+            _ if offset < NVME_REG_DBS && offset % 4 == 0 => {
+                for (i, chunk) in data.chunks_mut(4).enumerate() {
+                    let value = self.read_reg(offset + i as u64 * 4).to_le_bytes();
+                    chunk.copy_from_slice(&value[..chunk.len()]);
+                }
+            }
+            // Doorbells are write only.
+            _ => data.fill(0),
+        }
+    }
+
+    fn write_bar(&mut self, bar_index: PciBarIndex, offset: u64, data: &[u8]) {
+        if bar_index != NVME_BAR_INDEX {
+            return;
+        }
+        match offset {
+            NVME_MSIX_TABLE_OFFSET..=NVME_MSIX_TABLE_LAST => {
+                self.msix_config
+                    .lock()
+                    .write_msix_table(offset - NVME_MSIX_TABLE_OFFSET, data);
+            }
+            NVME_MSIX_PBA_OFFSET..=NVME_MSIX_PBA_LAST => self
+                .msix_config
+                .lock()
+                .write_pba_entries(offset - NVME_MSIX_PBA_OFFSET, data),
+            _ if offset % 4 == 0 && data.len() % 4 == 0 => {
+                for (i, chunk) in data.chunks_exact(4).enumerate() {
+                    let value = u32::from_le_bytes(chunk.try_into().unwrap());
+                    self.write_reg(offset + i as u64 * 4, value);
+                }
+            }
+            _ => warn!(
+                "nvme: ignored unaligned write of {} bytes at {:#x}",
+                data.len(),
+                offset
+            ),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct NvmeControllerSnapshot {
+    config_regs: serde_json::Value,
+    msix_config: serde_json::Value,
+    num_namespaces: usize,
+    fatal: bool,
+    intms: u32,
+    cc: u32,
+    csts: u32,
+    aqa: u32,
+    asq: u64,
+    acq: u64,
+    worker: WorkerState,
+}
+
+// As for virtio-block, the worker thread outlives `sleep` since it cannot give the async disks
+// back. Sleeping reclaims the queue state from it instead, and waking hands it back.
+impl Suspendable for NvmeController {
+    fn sleep(&mut self) -> anyhow::Result<()> {
+        if self.sleep_state.is_some() {
+            return Ok(());
+        }
+        let state = if self.worker.is_some() {
+            self.wait_for_worker(|response_tx| WorkerCmd::Sleep { response_tx })
+                .context("nvme worker stopped before going to sleep")?
+        } else {
+            WorkerState::default()
+        };
+        self.sleep_state = Some(state);
+        Ok(())
+    }
+
+    fn wake(&mut self) -> anyhow::Result<()> {
+        if let Some(state) = self.sleep_state.take() {
+            // A restored controller that was enabled needs a worker even if none was started yet.
+            if self.worker.is_some() || self.csts & NVME_CSTS_RDY != 0 {
+                self.send_to_worker(WorkerCmd::Wake { state });
+            }
+        }
+        Ok(())
+    }
+
+    fn snapshot(&mut self) -> anyhow::Result<serde_json::Value> {
+        let worker = match &self.sleep_state {
+            Some(state) => state.clone(),
+            None => anyhow::bail!("tried snapshotting while awake"),
+        };
+        serde_json::to_value(NvmeControllerSnapshot {
+            config_regs: self.config_regs.snapshot()?,
+            msix_config: self.msix_config.lock().snapshot()?,
+            num_namespaces: self.namespaces.len(),
+            fatal: self.fatal.load(Ordering::SeqCst),
+            intms: self.intms,
+            cc: self.cc,
+            csts: self.csts,
+            aqa: self.aqa,
+            asq: self.asq,
+            acq: self.acq,
+            worker,
+        })
+        .context("failed to serialize NvmeControllerSnapshot")
+    }
+
+    fn restore(&mut self, data: serde_json::Value) -> anyhow::Result<()> {
+        anyhow::ensure!(self.sleep_state.is_some(), "tried restoring while awake");
+        let deser: NvmeControllerSnapshot =
+            serde_json::from_value(data).context("failed to deserialize NvmeControllerSnapshot")?;
+        anyhow::ensure!(
+            deser.num_namespaces == self.namespaces.len(),
+            "snapshot has {} namespaces but the controller has {}",
+            deser.num_namespaces,
+            self.namespaces.len()
+        );
+
+        self.config_regs.restore(deser.config_regs)?;
+        self.msix_config.lock().restore(deser.msix_config)?;
+        self.fatal.store(deser.fatal, Ordering::SeqCst);
+        self.intms = deser.intms;
+        self.cc = deser.cc;
+        self.csts = deser.csts;
+        self.aqa = deser.aqa;
+        self.asq = deser.asq;
+        self.acq = deser.acq;
+        self.sleep_state = Some(deser.worker);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use std::time::Instant;
+
+    use tempfile::tempfile;
+
+    use super::*;
+
+    const ASQ: u64 = 0x1000;
+    const ACQ: u64 = 0x2000;
+    const IOSQ: u64 = 0x3000;
+    const IOCQ: u64 = 0x4000;
+    const DATA: u64 = 0x10000;
+    const PRP_LIST: u64 = 0x8000;
+
+    struct TestController {
+        mem: GuestMemory,
+        nvme: NvmeController,
+        _msi_host_tube: Tube,
+        sq_tails: BTreeMap<u16, u16>,
+        cq_heads: BTreeMap<u16, u16>,
+        next_command_id: u16,
+    }
+
+    impl TestController {
+        fn new(disks: Vec<NvmeDiskConfig>) -> TestController {
+            let mem = GuestMemory::new(&[(GuestAddress(0), 0x100000)]).unwrap();
+            let (msi_host_tube, msi_device_tube) = Tube::pair().unwrap();
+            let mut nvme = NvmeController::new(mem.clone(), disks, msi_device_tube).unwrap();
+
+            nvme.write_bar(0, NVME_REG_AQA, &(7u32 << 16 | 7).to_le_bytes());
+            nvme.write_bar(0, NVME_REG_ASQ, &ASQ.to_le_bytes());
+            nvme.write_bar(0, NVME_REG_ACQ, &ACQ.to_le_bytes());
+            let cc =
+                NVME_CC_EN | NVME_SQES << NVME_CC_IOSQES_SHIFT | NVME_CQES << NVME_CC_IOCQES_SHIFT;
+            nvme.write_bar(0, NVME_REG_CC, &cc.to_le_bytes());
+            let mut csts = [0u8; 4];
+            nvme.read_bar(0, NVME_REG_CSTS, &mut csts);
+            assert_eq!(u32::from_le_bytes(csts), NVME_CSTS_RDY);
+
+            TestController {
+                mem,
+                nvme,
+                _msi_host_tube: msi_host_tube,
+                sq_tails: BTreeMap::from([(0, 0)]),
+                cq_heads: BTreeMap::from([(0, 0)]),
+                next_command_id: 0,
+            }
+        }
+
+        /// Submits `cmd` to queue `qid`, which has 8 entries, and waits for its completion.
+        fn submit(&mut self, qid: u16, mut cmd: SubmissionEntry) -> CompletionEntry {
+            let (sq, cq) = if qid == 0 { (ASQ, ACQ) } else { (IOSQ, IOCQ) };
+            cmd.command_id = self.next_command_id;
+            self.next_command_id += 1;
+
+            let tail = self.sq_tails.get_mut(&qid).unwrap();
+            self.mem
+                .write_obj_at_addr(cmd, GuestAddress(sq + *tail as u64 * 64))
+                .unwrap();
+            *tail = (*tail + 1) % 8;
+            let tail = *tail as u32;
+            self.nvme
+                .write_bar(0, NVME_REG_DBS + qid as u64 * 8, &tail.to_le_bytes());
+
+            // The phase tag of the first pass through the queue is 1.
+            let head = self.cq_heads.get_mut(&qid).unwrap();
+            let addr = GuestAddress(cq + *head as u64 * 16);
+            let deadline = Instant::now() + Duration::from_secs(5);
+            let entry = loop {
+                let entry: CompletionEntry = self.mem.read_obj_from_addr(addr).unwrap();
+                if entry.status & 1 == 1 {
+                    break entry;
+                }
+                assert!(Instant::now() < deadline, "command timed out");
+                std::thread::sleep(Duration::from_millis(1));
+            };
+            *head += 1;
+            let head = *head as u32;
+            self.nvme
+                .write_bar(0, NVME_REG_DBS + qid as u64 * 8 + 4, &head.to_le_bytes());
+            assert_eq!(entry.command_id, cmd.command_id);
+            entry
+        }
+
+        fn create_io_queues(&mut self) {
+            let cq = SubmissionEntry {
+                opcode: NVME_ADM_CREATE_CQ,
+                prp1: IOCQ,
+                cdw10: 7 << 16 | 1,
+                cdw11: 1 << 1 | 1,
+                ..Default::default()
+            };
+            assert_eq!(self.submit(0, cq).status >> 1, 0);
+            let sq = SubmissionEntry {
+                opcode: NVME_ADM_CREATE_SQ,
+                prp1: IOSQ,
+                cdw10: 7 << 16 | 1,
+                cdw11: 1 << 16 | 1,
+                ..Default::default()
+            };
+            assert_eq!(self.submit(0, sq).status >> 1, 0);
+            self.sq_tails.insert(1, 0);
+            self.cq_heads.insert(1, 0);
+        }
+    }
+
+    fn disk(len: u64, block_size: u32, read_only: bool) -> NvmeDiskConfig {
+        let file = tempfile().unwrap();
+        file.set_len(len).unwrap();
+        NvmeDiskConfig {
+            file: Box::new(file),
+            block_size,
+            read_only,
+            sparse: true,
+        }
+    }
+
+    #[test]
+    fn parse_nvme_options() {
+        let option = serde_keyvalue::from_key_values::<NvmeOption>("/path/to/image").unwrap();
+        assert_eq!(
+            option,
+            NvmeOption {
+                path: PathBuf::from("/path/to/image"),
+                read_only: false,
+                block_size: 512,
+                sparse: true,
+            }
+        );
+        let option = serde_keyvalue::from_key_values::<NvmeOption>(
+            "/path/to/image,ro,block-size=4096,sparse=false",
+        )
+        .unwrap();
+        assert!(option.read_only);
+        assert_eq!(option.block_size, 4096);
+        assert!(!option.sparse);
+    }
+
+    #[test]
+    fn invalid_namespaces() {
+        let (_, tube) = Tube::pair().unwrap();
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        assert!(matches!(
+            NvmeController::new(mem.clone(), Vec::new(), tube),
+            Err(Error::NoNamespaces)
+        ));
+        let (_, tube) = Tube::pair().unwrap();
+        assert!(matches!(
+            NvmeController::new(mem, vec![disk(0x10000, 1000, false)], tube),
+            Err(Error::InvalidBlockSize(1, 1000))
+        ));
+    }
+
+    #[test]
+    fn prp_list() {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x100000)]).unwrap();
+        // The transfer starts in the middle of a page, then continues through a list that starts
+        // two entries before the end of a page and chains to a second list page.
+        let list = [0x20000u64, 0x22000, 0x23000, 0x40000];
+        mem.write_obj_at_addr(list[0], GuestAddress(PRP_LIST + 0xff0))
+            .unwrap();
+        mem.write_obj_at_addr(PRP_LIST + 0x1000, GuestAddress(PRP_LIST + 0xff8))
+            .unwrap();
+        for (i, entry) in list[1..].iter().enumerate() {
+            mem.write_obj_at_addr(*entry, GuestAddress(PRP_LIST + 0x1000 + i as u64 * 8))
+                .unwrap();
+        }
+        let regions = prp_regions(&mem, 0x1f800, PRP_LIST + 0xff0, 0x800 + 0x3000 + 0x100).unwrap();
+        assert_eq!(
+            regions,
+            vec![
+                MemRegion {
+                    offset: 0x1f800,
+                    len: 0x1800,
+                },
+                MemRegion {
+                    offset: 0x22000,
+                    len: 0x2000,
+                },
+                MemRegion {
+                    offset: 0x40000,
+                    len: 0x100,
+                },
+            ]
+        );
+
+        assert_eq!(
+            prp_regions(&mem, 0x1000, 0x2010, 0x1800),
+            Err(Status::generic(NVME_SC_PRP_OFFSET_INVALID))
+        );
+    }
+
+    #[test]
+    fn identify_and_io() {
+        let mut t =
+            TestController::new(vec![disk(0x100000, 512, false), disk(0x10000, 4096, true)]);
+
+        let identify = |cns: u32, nsid: u32| SubmissionEntry {
+            opcode: NVME_ADM_IDENTIFY,
+            nsid,
+            prp1: DATA,
+            cdw10: cns,
+            ..Default::default()
+        };
+        assert_eq!(t.submit(0, identify(1, 0)).status >> 1, 0);
+        let id: IdentifyController = t.mem.read_obj_from_addr(GuestAddress(DATA)).unwrap();
+        assert_eq!(id.nn, 2);
+        assert_eq!(id.vid, PCI_VENDOR_ID_REDHAT);
+
+        assert_eq!(t.submit(0, identify(0, 2)).status >> 1, 0);
+        let id: IdentifyNamespace = t.mem.read_obj_from_addr(GuestAddress(DATA)).unwrap();
+        assert_eq!(id.nsze, 16);
+        assert_eq!(id.lbaf[0].lbads, 12);
+        assert_eq!(id.nsattr, NVME_NSATTR_WRITE_PROTECTED);
+
+        assert_eq!(t.submit(0, identify(2, 0)).status >> 1, 0);
+        let nsids: [u32; 3] = t.mem.read_obj_from_addr(GuestAddress(DATA)).unwrap();
+        assert_eq!(nsids, [1, 2, 0]);
+
+        let status = t.submit(0, identify(0, 3)).status;
+        assert_eq!(status & !1, Status::generic(NVME_SC_INVALID_NS).0);
+
+        t.create_io_queues();
+
+        // Write two blocks spanning two pages, then read them back.
+        let pattern: Vec<u8> = (0..1024).map(|i| i as u8).collect();
+        t.mem
+            .write_all_at_addr(&pattern, GuestAddress(DATA + 0xe00))
+            .unwrap();
+        let rw = |opcode: u8, nsid: u32, prp1: u64| SubmissionEntry {
+            opcode,
+            nsid,
+            prp1,
+            prp2: DATA + 0x1000,
+            cdw10: 5,
+            cdw12: 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            t.submit(1, rw(NVME_CMD_WRITE, 1, DATA + 0xe00)).status >> 1,
+            0
+        );
+        t.mem
+            .write_all_at_addr(&[0u8; 0x2000], GuestAddress(DATA))
+            .unwrap();
+        let read = SubmissionEntry {
+            prp2: 0,
+            ..rw(NVME_CMD_READ, 1, DATA)
+        };
+        assert_eq!(t.submit(1, read).status >> 1, 0);
+        let mut data = vec![0u8; 1024];
+        t.mem
+            .read_exact_at_addr(&mut data, GuestAddress(DATA))
+            .unwrap();
+        assert_eq!(data, pattern);
+
+        // Writes to the read-only namespace and beyond the end of a namespace fail.
+        let status = t.submit(1, rw(NVME_CMD_WRITE, 2, DATA)).status;
+        assert_eq!(status & !1, Status::generic(NVME_SC_NS_WRITE_PROTECTED).0);
+        let out_of_range = SubmissionEntry {
+            cdw10: 2047,
+            ..rw(NVME_CMD_READ, 1, DATA)
+        };
+        let status = t.submit(1, out_of_range).status;
+        assert_eq!(status & !1, Status::generic(NVME_SC_LBA_RANGE).0);
+
+        // Deallocate the written blocks, which then read as zeroes.
+        t.mem
+            .write_obj_at_addr(
+                DsmRange {
+                    attributes: 0,
+                    nlb: 2,
+                    slba: 5,
+                },
+                GuestAddress(DATA),
+            )
+            .unwrap();
+        let dsm = SubmissionEntry {
+            opcode: NVME_CMD_DSM,
+            nsid: 1,
+            prp1: DATA,
+            cdw11: NVME_DSM_ATTR_DEALLOCATE,
+            ..Default::default()
+        };
+        assert_eq!(t.submit(1, dsm).status >> 1, 0);
+        assert_eq!(t.submit(1, read).status >> 1, 0);
+        t.mem
+            .read_exact_at_addr(&mut data, GuestAddress(DATA))
+            .unwrap();
+        assert!(data.iter().all(|&b| b == 0));
+
+        let flush = SubmissionEntry {
+            opcode: NVME_CMD_FLUSH,
+            nsid: NVME_NSID_ALL,
+            ..Default::default()
+        };
+        assert_eq!(t.submit(1, flush).status >> 1, 0);
+
+        // Disabling the controller deletes the queues.
+        t.nvme.write_bar(0, NVME_REG_CC, &0u32.to_le_bytes());
+        let mut csts = [0u8; 4];
+        t.nvme.read_bar(0, NVME_REG_CSTS, &mut csts);
+        assert_eq!(u32::from_le_bytes(csts), 0);
+    }
+
+    #[test]
+    fn snapshot_restore() {
+        let file = tempfile().unwrap();
+        file.set_len(0x100000).unwrap();
+        let config = || NvmeDiskConfig {
+            file: Box::new(file.try_clone().unwrap()),
+            block_size: 512,
+            read_only: false,
+            sparse: true,
+        };
+        let mut t = TestController::new(vec![config()]);
+        t.create_io_queues();
+        t.nvme.write_bar(0, NVME_REG_INTMS, &1u32.to_le_bytes());
+
+        let pattern = [0x5au8; 512];
+        t.mem
+            .write_all_at_addr(&pattern, GuestAddress(DATA))
+            .unwrap();
+        let rw = |opcode: u8| SubmissionEntry {
+            opcode,
+            nsid: 1,
+            prp1: DATA,
+            cdw10: 3,
+            ..Default::default()
+        };
+        assert_eq!(t.submit(1, rw(NVME_CMD_WRITE)).status >> 1, 0);
+
+        assert!(t.nvme.snapshot().is_err());
+        t.nvme.sleep().unwrap();
+        t.nvme.sleep().unwrap();
+        let snapshot = t.nvme.snapshot().unwrap();
+        t.nvme.wake().unwrap();
+        t.nvme.wake().unwrap();
+        t.nvme.sleep().unwrap();
+
+        // Restore into a new controller sharing the disk, which continues with the queues of the
+        // first one.
+        let (msi_host_tube, msi_device_tube) = Tube::pair().unwrap();
+        let mut nvme = NvmeController::new(t.mem.clone(), vec![config()], msi_device_tube).unwrap();
+        assert!(nvme.restore(snapshot.clone()).is_err());
+        nvme.sleep().unwrap();
+        nvme.restore(snapshot).unwrap();
+        nvme.wake().unwrap();
+        t.nvme = nvme;
+        t._msi_host_tube = msi_host_tube;
+
+        let mut regs = [0u8; 4];
+        t.nvme.read_bar(0, NVME_REG_INTMS, &mut regs);
+        assert_eq!(u32::from_le_bytes(regs), 1);
+        t.nvme.read_bar(0, NVME_REG_CSTS, &mut regs);
+        assert_eq!(u32::from_le_bytes(regs), NVME_CSTS_RDY);
+
+        t.mem
+            .write_all_at_addr(&[0u8; 512], GuestAddress(DATA))
+            .unwrap();
+        assert_eq!(t.submit(1, rw(NVME_CMD_READ)).status >> 1, 0);
+        let mut data = [0u8; 512];
+        t.mem
+            .read_exact_at_addr(&mut data, GuestAddress(DATA))
+            .unwrap();
+        assert_eq!(data, pattern);
+    }
+
+    #[test]
+    fn restore_namespace_mismatch() {
+        let mut t = TestController::new(vec![disk(0x10000, 512, false)]);
+        t.nvme.sleep().unwrap();
+        let snapshot = t.nvme.snapshot().unwrap();
+
+        let (_msi_host_tube, msi_device_tube) = Tube::pair().unwrap();
+        let mut nvme = NvmeController::new(
+            t.mem.clone(),
+            vec![disk(0x10000, 512, false), disk(0x10000, 512, false)],
+            msi_device_tube,
+        )
+        .unwrap();
+        nvme.sleep().unwrap();
+        assert!(nvme.restore(snapshot).is_err());
+    }
+}
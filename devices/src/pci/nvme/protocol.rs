@@ -0,0 +1,360 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Data structures and constants from the NVM Express Base Specification 1.4 and the NVM Command
+//! Set Specification that the emulated controller needs.
+
+#![allow(dead_code)]
+
+use zerocopy::AsBytes;
+use zerocopy::FromBytes;
+use zerocopy::FromZeroes;
+
+/// Controller register offsets in BAR0.
+pub const NVME_REG_CAP: u64 = 0x00;
+pub const NVME_REG_VS: u64 = 0x08;
+pub const NVME_REG_INTMS: u64 = 0x0c;
+pub const NVME_REG_INTMC: u64 = 0x10;
+pub const NVME_REG_CC: u64 = 0x14;
+pub const NVME_REG_CSTS: u64 = 0x1c;
+pub const NVME_REG_NSSR: u64 = 0x20;
+pub const NVME_REG_AQA: u64 = 0x24;
+pub const NVME_REG_ASQ: u64 = 0x28;
+pub const NVME_REG_ACQ: u64 = 0x30;
+pub const NVME_REG_DBS: u64 = 0x1000;
+
+/// Version 1.4.0.
+pub const NVME_VERSION: u32 = 0x0001_0400;
+
+pub const NVME_CAP_CQR: u64 = 1 << 16;
+pub const NVME_CAP_TO_SHIFT: u64 = 24;
+pub const NVME_CAP_CSS_NVM: u64 = 1 << 37;
+
+pub const NVME_CC_EN: u32 = 1 << 0;
+pub const NVME_CC_CSS_SHIFT: u32 = 4;
+pub const NVME_CC_CSS_MASK: u32 = 0x7;
+pub const NVME_CC_MPS_SHIFT: u32 = 7;
+pub const NVME_CC_MPS_MASK: u32 = 0xf;
+pub const NVME_CC_SHN_SHIFT: u32 = 14;
+pub const NVME_CC_SHN_MASK: u32 = 0x3;
+pub const NVME_CC_IOSQES_SHIFT: u32 = 16;
+pub const NVME_CC_IOCQES_SHIFT: u32 = 20;
+pub const NVME_CC_QES_MASK: u32 = 0xf;
+
+pub const NVME_CSTS_RDY: u32 = 1 << 0;
+pub const NVME_CSTS_CFS: u32 = 1 << 1;
+pub const NVME_CSTS_SHST_COMPLETE: u32 = 2 << 2;
+
+/// log2 of the submission and completion queue entry sizes.
+pub const NVME_SQES: u32 = 6;
+pub const NVME_CQES: u32 = 4;
+
+/// Memory page size used for PRPs. Only CC.MPS == 0 (4 KiB) is supported.
+pub const NVME_PAGE_SIZE: u64 = 4096;
+
+/// Admin command set opcodes.
+pub const NVME_ADM_DELETE_SQ: u8 = 0x00;
+pub const NVME_ADM_CREATE_SQ: u8 = 0x01;
+pub const NVME_ADM_GET_LOG_PAGE: u8 = 0x02;
+pub const NVME_ADM_DELETE_CQ: u8 = 0x04;
+pub const NVME_ADM_CREATE_CQ: u8 = 0x05;
+pub const NVME_ADM_IDENTIFY: u8 = 0x06;
+pub const NVME_ADM_ABORT: u8 = 0x08;
+pub const NVME_ADM_SET_FEATURES: u8 = 0x09;
+pub const NVME_ADM_GET_FEATURES: u8 = 0x0a;
+pub const NVME_ADM_ASYNC_EVENT_REQUEST: u8 = 0x0c;
+
+/// NVM command set opcodes.
+pub const NVME_CMD_FLUSH: u8 = 0x00;
+pub const NVME_CMD_WRITE: u8 = 0x01;
+pub const NVME_CMD_READ: u8 = 0x02;
+pub const NVME_CMD_WRITE_ZEROES: u8 = 0x08;
+pub const NVME_CMD_DSM: u8 = 0x09;
+
+/// Identify Controller or Namespace Structure (CNS) values.
+pub const NVME_ID_CNS_NAMESPACE: u8 = 0x00;
+pub const NVME_ID_CNS_CONTROLLER: u8 = 0x01;
+pub const NVME_ID_CNS_ACTIVE_NS_LIST: u8 = 0x02;
+pub const NVME_ID_CNS_NS_DESCRIPTOR_LIST: u8 = 0x03;
+
+/// Feature identifiers.
+pub const NVME_FEAT_ARBITRATION: u8 = 0x01;
+pub const NVME_FEAT_POWER_MANAGEMENT: u8 = 0x02;
+pub const NVME_FEAT_TEMPERATURE_THRESHOLD: u8 = 0x04;
+pub const NVME_FEAT_ERROR_RECOVERY: u8 = 0x05;
+pub const NVME_FEAT_VOLATILE_WRITE_CACHE: u8 = 0x06;
+pub const NVME_FEAT_NUMBER_OF_QUEUES: u8 = 0x07;
+pub const NVME_FEAT_INTERRUPT_COALESCING: u8 = 0x08;
+pub const NVME_FEAT_INTERRUPT_VECTOR_CONFIG: u8 = 0x09;
+pub const NVME_FEAT_WRITE_ATOMICITY: u8 = 0x0a;
+pub const NVME_FEAT_ASYNC_EVENT_CONFIG: u8 = 0x0b;
+
+/// Log page identifiers.
+pub const NVME_LOG_ERROR_INFO: u8 = 0x01;
+pub const NVME_LOG_SMART: u8 = 0x02;
+pub const NVME_LOG_FIRMWARE_SLOT: u8 = 0x03;
+
+/// Dataset Management attribute: deallocate the ranges.
+pub const NVME_DSM_ATTR_DEALLOCATE: u32 = 1 << 2;
+
+/// Status code types.
+pub const NVME_SCT_GENERIC: u8 = 0x0;
+pub const NVME_SCT_COMMAND_SPECIFIC: u8 = 0x1;
+
+/// Generic command status values.
+pub const NVME_SC_SUCCESS: u8 = 0x00;
+pub const NVME_SC_INVALID_OPCODE: u8 = 0x01;
+pub const NVME_SC_INVALID_FIELD: u8 = 0x02;
+pub const NVME_SC_DATA_TRANSFER_ERROR: u8 = 0x04;
+pub const NVME_SC_INTERNAL: u8 = 0x06;
+pub const NVME_SC_INVALID_NS: u8 = 0x0b;
+pub const NVME_SC_PRP_OFFSET_INVALID: u8 = 0x13;
+pub const NVME_SC_NS_WRITE_PROTECTED: u8 = 0x20;
+pub const NVME_SC_LBA_RANGE: u8 = 0x80;
+
+/// Command specific status values.
+pub const NVME_SC_CQ_INVALID: u8 = 0x00;
+pub const NVME_SC_QID_INVALID: u8 = 0x01;
+pub const NVME_SC_QUEUE_SIZE: u8 = 0x02;
+pub const NVME_SC_ABORT_LIMIT: u8 = 0x03;
+pub const NVME_SC_AER_LIMIT: u8 = 0x05;
+pub const NVME_SC_INVALID_VECTOR: u8 = 0x08;
+pub const NVME_SC_INVALID_LOG_PAGE: u8 = 0x09;
+pub const NVME_SC_INVALID_QUEUE_DELETION: u8 = 0x0c;
+pub const NVME_SC_FEATURE_NOT_SAVEABLE: u8 = 0x0d;
+
+/// Submission queue entry, common to admin and I/O commands.
+#[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes)]
+#[repr(C)]
+pub struct SubmissionEntry {
+    pub opcode: u8,
+    pub flags: u8,
+    pub command_id: u16,
+    pub nsid: u32,
+    pub cdw2: u32,
+    pub cdw3: u32,
+    pub mptr: u64,
+    pub prp1: u64,
+    pub prp2: u64,
+    pub cdw10: u32,
+    pub cdw11: u32,
+    pub cdw12: u32,
+    pub cdw13: u32,
+    pub cdw14: u32,
+    pub cdw15: u32,
+}
+
+/// Completion queue entry.
+#[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes)]
+#[repr(C)]
+pub struct CompletionEntry {
+    pub result: u32,
+    pub reserved: u32,
+    pub sq_head: u16,
+    pub sq_id: u16,
+    pub command_id: u16,
+    /// Phase tag in bit 0, then the status code, status code type, More and Do Not Retry bits.
+    pub status: u16,
+}
+
+/// Power State Descriptor in the Identify Controller data structure.
+#[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes)]
+#[repr(C)]
+pub struct PowerStateDescriptor {
+    /// Maximum power in centiwatts.
+    pub mp: u16,
+    pub reserved0: u8,
+    pub flags: u8,
+    pub enlat: u32,
+    pub exlat: u32,
+    pub rrt: u8,
+    pub rrl: u8,
+    pub rwt: u8,
+    pub rwl: u8,
+    pub idlp: u16,
+    pub ips: u8,
+    pub reserved1: u8,
+    pub actp: u16,
+    pub apw_aps: u8,
+    pub reserved2: [u8; 9],
+}
+
+/// Identify Controller data structure (CNS 01h).
+#[derive(Copy, Clone, Debug, AsBytes, FromZeroes, FromBytes)]
+#[repr(C)]
+pub struct IdentifyController {
+    pub vid: u16,
+    pub ssvid: u16,
+    pub sn: [u8; 20],
+    pub mn: [u8; 40],
+    pub fr: [u8; 8],
+    pub rab: u8,
+    pub ieee: [u8; 3],
+    pub cmic: u8,
+    pub mdts: u8,
+    pub cntlid: u16,
+    pub ver: u32,
+    pub rtd3r: u32,
+    pub rtd3e: u32,
+    pub oaes: u32,
+    pub ctratt: u32,
+    pub rrls: u16,
+    pub reserved0: [u8; 9],
+    pub cntrltype: u8,
+    pub fguid: [u8; 16],
+    pub reserved1: [u8; 128],
+    pub oacs: u16,
+    pub acl: u8,
+    pub aerl: u8,
+    pub frmw: u8,
+    pub lpa: u8,
+    pub elpe: u8,
+    pub npss: u8,
+    pub avscc: u8,
+    pub apsta: u8,
+    pub wctemp: u16,
+    pub cctemp: u16,
+    pub reserved2: [u8; 242],
+    pub sqes: u8,
+    pub cqes: u8,
+    pub maxcmd: u16,
+    pub nn: u32,
+    pub oncs: u16,
+    pub fuses: u16,
+    pub fna: u8,
+    pub vwc: u8,
+    pub awun: u16,
+    pub awupf: u16,
+    pub nvscc: u8,
+    pub nwpc: u8,
+    pub acwu: u16,
+    pub reserved3: [u8; 2],
+    pub sgls: u32,
+    pub mnan: u32,
+    pub reserved4: [u8; 224],
+    pub subnqn: [u8; 256],
+    pub reserved5: [u8; 1024],
+    pub psd: [PowerStateDescriptor; 32],
+    pub vs: [u8; 1024],
+}
+
+/// Optional NVM commands supported (ONCS) bits.
+pub const NVME_ONCS_DSM: u16 = 1 << 2;
+pub const NVME_ONCS_WRITE_ZEROES: u16 = 1 << 3;
+
+/// LBA Format Data Structure.
+#[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes)]
+#[repr(C)]
+pub struct LbaFormat {
+    pub ms: u16,
+    /// log2 of the LBA data size.
+    pub lbads: u8,
+    pub rp: u8,
+}
+
+/// Identify Namespace data structure (CNS 00h).
+#[derive(Copy, Clone, Debug, AsBytes, FromZeroes, FromBytes)]
+#[repr(C)]
+pub struct IdentifyNamespace {
+    pub nsze: u64,
+    pub ncap: u64,
+    pub nuse: u64,
+    pub nsfeat: u8,
+    pub nlbaf: u8,
+    pub flbas: u8,
+    pub mc: u8,
+    pub dpc: u8,
+    pub dps: u8,
+    pub nmic: u8,
+    pub rescap: u8,
+    pub fpi: u8,
+    pub dlfeat: u8,
+    pub nawun: u16,
+    pub nawupf: u16,
+    pub nacwu: u16,
+    pub nabsn: u16,
+    pub nabo: u16,
+    pub nabspf: u16,
+    pub noiob: u16,
+    pub nvmcap: [u8; 16],
+    pub npwg: u16,
+    pub npwa: u16,
+    pub npdg: u16,
+    pub npda: u16,
+    pub nows: u16,
+    pub reserved0: [u8; 18],
+    pub anagrpid: u32,
+    pub reserved1: [u8; 3],
+    pub nsattr: u8,
+    pub nvmsetid: u16,
+    pub endgid: u16,
+    pub nguid: [u8; 16],
+    pub eui64: [u8; 8],
+    pub lbaf: [LbaFormat; 16],
+    pub reserved2: [u8; 192],
+    pub vs: [u8; 3712],
+}
+
+/// Namespace features (NSFEAT): the namespace supports thin provisioning.
+pub const NVME_NSFEAT_THIN: u8 = 1 << 0;
+/// Deallocate logical block features (DLFEAT): deallocated blocks read as zeroes.
+pub const NVME_DLFEAT_READ_ZEROES: u8 = 0x1;
+/// Namespace attributes (NSATTR): the namespace is write protected.
+pub const NVME_NSATTR_WRITE_PROTECTED: u8 = 1 << 0;
+
+/// SMART / Health Information log page (LID 02h).
+#[derive(Copy, Clone, Debug, AsBytes, FromZeroes, FromBytes)]
+#[repr(C)]
+pub struct SmartLog {
+    pub critical_warning: u8,
+    /// Composite temperature in Kelvin.
+    pub temperature: [u8; 2],
+    pub avail_spare: u8,
+    pub spare_thresh: u8,
+    pub percent_used: u8,
+    pub endu_grp_crit_warn: u8,
+    pub reserved0: [u8; 25],
+    pub data_units_read: [u8; 16],
+    pub data_units_written: [u8; 16],
+    pub host_read_commands: [u8; 16],
+    pub host_write_commands: [u8; 16],
+    pub ctrl_busy_time: [u8; 16],
+    pub power_cycles: [u8; 16],
+    pub power_on_hours: [u8; 16],
+    pub unsafe_shutdowns: [u8; 16],
+    pub media_errors: [u8; 16],
+    pub num_err_log_entries: [u8; 16],
+    pub warning_temp_time: u32,
+    pub critical_comp_time: u32,
+    pub temp_sensor: [u16; 8],
+    pub reserved1: [u8; 296],
+}
+
+/// Firmware Slot Information log page (LID 03h).
+#[derive(Copy, Clone, Debug, AsBytes, FromZeroes, FromBytes)]
+#[repr(C)]
+pub struct FirmwareSlotLog {
+    pub afi: u8,
+    pub reserved0: [u8; 7],
+    pub frs: [[u8; 8]; 7],
+    pub reserved1: [u8; 448],
+}
+
+/// Dataset Management range.
+#[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes)]
+#[repr(C)]
+pub struct DsmRange {
+    pub attributes: u32,
+    pub nlb: u32,
+    pub slba: u64,
+}
+
+const _: () = assert!(std::mem::size_of::<SubmissionEntry>() == 1 << NVME_SQES);
+const _: () = assert!(std::mem::size_of::<CompletionEntry>() == 1 << NVME_CQES);
+const _: () = assert!(std::mem::size_of::<PowerStateDescriptor>() == 32);
+const _: () = assert!(std::mem::size_of::<IdentifyController>() == 4096);
+const _: () = assert!(std::mem::size_of::<IdentifyNamespace>() == 4096);
+const _: () = assert!(std::mem::size_of::<SmartLog>() == 512);
+const _: () = assert!(std::mem::size_of::<FirmwareSlotLog>() == 512);
+const _: () = assert!(std::mem::size_of::<DsmRange>() == 16);
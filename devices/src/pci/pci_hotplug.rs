@@ -6,6 +6,10 @@
 
 #![deny(missing_docs)]
 
+use std::fs::File;
+use std::path::PathBuf;
+
+use base::with_as_descriptor;
 use base::AsRawDescriptor;
 use base::AsRawDescriptors;
 use base::RawDescriptor;
@@ -32,6 +36,8 @@ pub type Result<T> = std::result::Result<T, PciDeviceError>;
 pub enum ResourceCarrier {
     /// virtio-net device.
     VirtioNet(NetResourceCarrier),
+    /// virtio-input device backed by a host event device.
+    VirtioInput(InputResourceCarrier),
 }
 
 impl ResourceCarrier {
@@ -39,6 +45,7 @@ impl ResourceCarrier {
     pub fn debug_label(&self) -> String {
         match self {
             ResourceCarrier::VirtioNet(c) => c.debug_label(),
+            ResourceCarrier::VirtioInput(c) => c.debug_label(),
         }
     }
 
@@ -47,6 +54,7 @@ impl ResourceCarrier {
     pub fn keep_rds(&self) -> Vec<RawDescriptor> {
         match self {
             ResourceCarrier::VirtioNet(c) => c.keep_rds(),
+            ResourceCarrier::VirtioInput(c) => c.keep_rds(),
         }
     }
     /// Allocate the preferred address to the device.
@@ -57,6 +65,7 @@ impl ResourceCarrier {
     ) -> Result<()> {
         match self {
             ResourceCarrier::VirtioNet(c) => c.allocate_address(preferred_address, resources),
+            ResourceCarrier::VirtioInput(c) => c.allocate_address(preferred_address, resources),
         }
     }
     /// Assign a legacy PCI IRQ to this device.
@@ -65,6 +74,7 @@ impl ResourceCarrier {
     pub fn assign_irq(&mut self, irq_evt: IrqLevelEvent, pin: PciInterruptPin, irq_num: u32) {
         match self {
             ResourceCarrier::VirtioNet(c) => c.assign_irq(irq_evt, pin, irq_num),
+            ResourceCarrier::VirtioInput(c) => c.assign_irq(irq_evt, pin, irq_num),
         }
     }
 }
@@ -153,29 +163,97 @@ impl NetResourceCarrier {
         preferred_address: PciAddress,
         resources: &mut resources::SystemAllocator,
     ) -> Result<()> {
-        match self.pci_address {
-            None => {
-                if resources.reserve_pci(
-                    Alloc::PciBar {
-                        bus: preferred_address.bus,
-                        dev: preferred_address.dev,
-                        func: preferred_address.func,
-                        bar: 0,
-                    },
-                    self.debug_label(),
-                ) {
-                    self.pci_address = Some(preferred_address);
-                } else {
-                    return Err(PciDeviceError::PciAllocationFailed);
-                }
-            }
-            Some(pci_address) => {
-                if pci_address != preferred_address {
-                    return Err(PciDeviceError::PciAllocationFailed);
-                }
-            }
+        let debug_label = self.debug_label();
+        reserve_pci_address(
+            &mut self.pci_address,
+            preferred_address,
+            resources,
+            debug_label,
+        )
+    }
+
+    fn assign_irq(&mut self, irq_evt: IrqLevelEvent, pin: PciInterruptPin, irq_num: u32) {
+        self.intx_parameter = Some(IntxParameter {
+            irq_evt,
+            pin,
+            irq_num,
+        });
+    }
+}
+
+/// An InputResourceCarrier is a ResourceCarrier specialization for virtio-input devices passing
+/// through a host event device.
+#[derive(Serialize, Deserialize)]
+pub struct InputResourceCarrier {
+    /// Path of the event device node, for logging
+    pub path: PathBuf,
+    /// The opened event device node
+    #[serde(with = "with_as_descriptor")]
+    pub evdev: File,
+    /// Key codes toggling the exclusive grab of the event device
+    pub grab_hotkey: Option<Vec<u16>>,
+    /// msi_device_tube for VirtioPciDevice constructor
+    pub msi_device_tube: Tube,
+    /// ioevent_vm_memory_client for VirtioPciDevice constructor
+    pub ioevent_vm_memory_client: VmMemoryClient,
+    /// pci_address for the hotplugged device
+    pub pci_address: Option<PciAddress>,
+    /// intx_parameter for assign_irq
+    pub intx_parameter: Option<IntxParameter>,
+    /// vm_control_tube for VirtioPciDevice constructor
+    pub vm_control_tube: Tube,
+}
+
+impl InputResourceCarrier {
+    /// Constructs InputResourceCarrier.
+    pub fn new(
+        path: PathBuf,
+        evdev: File,
+        grab_hotkey: Option<Vec<u16>>,
+        msi_device_tube: Tube,
+        ioevent_vm_memory_client: VmMemoryClient,
+        vm_control_tube: Tube,
+    ) -> Self {
+        Self {
+            path,
+            evdev,
+            grab_hotkey,
+            msi_device_tube,
+            ioevent_vm_memory_client,
+            pci_address: None,
+            intx_parameter: None,
+            vm_control_tube,
         }
-        Ok(())
+    }
+
+    fn debug_label(&self) -> String {
+        "virtio-input".to_owned()
+    }
+
+    fn keep_rds(&self) -> Vec<RawDescriptor> {
+        let mut keep_rds = vec![
+            self.evdev.as_raw_descriptor(),
+            self.msi_device_tube.as_raw_descriptor(),
+            self.ioevent_vm_memory_client.as_raw_descriptor(),
+        ];
+        if let Some(intx_parameter) = &self.intx_parameter {
+            keep_rds.extend(intx_parameter.irq_evt.as_raw_descriptors());
+        }
+        keep_rds
+    }
+
+    fn allocate_address(
+        &mut self,
+        preferred_address: PciAddress,
+        resources: &mut resources::SystemAllocator,
+    ) -> Result<()> {
+        let debug_label = self.debug_label();
+        reserve_pci_address(
+            &mut self.pci_address,
+            preferred_address,
+            resources,
+            debug_label,
+        )
     }
 
     fn assign_irq(&mut self, irq_evt: IrqLevelEvent, pin: PciInterruptPin, irq_num: u32) {
@@ -187,6 +265,39 @@ impl NetResourceCarrier {
     }
 }
 
+/// Reserves `preferred_address` for a device unless `pci_address` already holds an address, in
+/// which case it must be the preferred one.
+fn reserve_pci_address(
+    pci_address: &mut Option<PciAddress>,
+    preferred_address: PciAddress,
+    resources: &mut resources::SystemAllocator,
+    debug_label: String,
+) -> Result<()> {
+    match *pci_address {
+        None => {
+            if resources.reserve_pci(
+                Alloc::PciBar {
+                    bus: preferred_address.bus,
+                    dev: preferred_address.dev,
+                    func: preferred_address.func,
+                    bar: 0,
+                },
+                debug_label,
+            ) {
+                *pci_address = Some(preferred_address);
+            } else {
+                return Err(PciDeviceError::PciAllocationFailed);
+            }
+        }
+        Some(pci_address) => {
+            if pci_address != preferred_address {
+                return Err(PciDeviceError::PciAllocationFailed);
+            }
+        }
+    }
+    Ok(())
+}
+
 /// Parameters for legacy INTx interrrupt.
 #[derive(Serialize, Deserialize)]
 pub struct IntxParameter {
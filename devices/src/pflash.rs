@@ -21,11 +21,16 @@
 //!
 //! [QEMU's pflash implementation]: https://github.com/qemu/qemu/blob/master/hw/block/pflash_cfi01.c
 
+pub mod varstore;
+
 use std::path::PathBuf;
 
 use anyhow::bail;
+use anyhow::Context;
 use base::error;
 use base::VolatileSlice;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
 use disk::DiskFile;
 use serde::Deserialize;
 use serde::Serialize;
@@ -45,6 +50,8 @@ const COMMAND_READ_ARRAY: u8 = 0xff;
 
 const STATUS_READY: u8 = 0x80;
 
+const ERASED_BYTE: u8 = 0xff;
+
 fn pflash_parameters_default_block_size() -> u32 {
     // 4K
     4 * (1 << 10)
@@ -65,6 +72,23 @@ enum State {
     Write(u64),
 }
 
+#[derive(Serialize, Deserialize)]
+struct PflashSnapshot {
+    status: u8,
+    state: State,
+    // The whole image, LZ4 compressed and base64 encoded. Variable stores are mostly erased, so
+    // this is much smaller than the image, and far smaller than a JSON array of bytes.
+    image: String,
+}
+
+// Snapshots taken before the image contents were included only hold `(status, state)`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PflashSnapshotVersion {
+    Current(PflashSnapshot),
+    Legacy((u8, State)),
+}
+
 pub struct Pflash {
     image: Box<dyn DiskFile>,
     image_size: u64,
@@ -198,7 +222,9 @@ impl BusDevice for Pflash {
                 }
 
                 if let Err(e) = self.image.write_all_at_volatile(
-                    VolatileSlice::new(&mut [0xff].repeat(self.block_size.try_into().unwrap())),
+                    VolatileSlice::new(
+                        &mut [ERASED_BYTE].repeat(self.block_size.try_into().unwrap()),
+                    ),
                     offset,
                 ) {
                     error!("pflash failed to erase block: {}", e);
@@ -233,13 +259,46 @@ impl BusDevice for Pflash {
 
 impl Suspendable for Pflash {
     fn snapshot(&mut self) -> anyhow::Result<serde_json::Value> {
-        Ok(serde_json::to_value((self.status, self.state))?)
+        // The image is a persistent file that keeps changing after the snapshot, so its contents
+        // have to be part of the snapshot for a restore to see the same UEFI variables.
+        let mut image = vec![0u8; self.image_size as usize];
+        self.image
+            .read_exact_at_volatile(VolatileSlice::new(&mut image), 0)
+            .context("failed to read pflash image")?;
+        Ok(serde_json::to_value(PflashSnapshot {
+            status: self.status,
+            state: self.state,
+            image: BASE64.encode(lz4_flex::block::compress_prepend_size(&image)),
+        })?)
     }
 
     fn restore(&mut self, data: serde_json::Value) -> anyhow::Result<()> {
-        let (status, state) = serde_json::from_value(data)?;
-        self.status = status;
-        self.state = state;
+        let snapshot = match serde_json::from_value(data)? {
+            PflashSnapshotVersion::Current(snapshot) => snapshot,
+            PflashSnapshotVersion::Legacy((status, state)) => {
+                // The image contents were not captured; keep whatever is on disk.
+                self.status = status;
+                self.state = state;
+                return Ok(());
+            }
+        };
+        let compressed = BASE64
+            .decode(snapshot.image)
+            .context("failed to decode pflash image")?;
+        let mut image = lz4_flex::block::decompress_size_prepended(&compressed)
+            .context("failed to decompress pflash image")?;
+        if image.len() as u64 != self.image_size {
+            bail!(
+                "pflash snapshot image is {} bytes, expected {}",
+                image.len(),
+                self.image_size
+            );
+        }
+        self.image
+            .write_all_at_volatile(VolatileSlice::new(&mut image), 0)
+            .context("failed to write pflash image")?;
+        self.status = snapshot.status;
+        self.state = snapshot.state;
         Ok(())
     }
 
@@ -397,6 +456,52 @@ mod tests {
         assert_eq!(want, got);
     }
 
+    #[test]
+    fn snapshot_restore() {
+        let f = empty_image();
+        let mut data = [0xde, 0xad, 0xbe, 0xef];
+        let offset = 0x2000;
+        f.write_all_at_volatile(VolatileSlice::new(&mut data), offset)
+            .unwrap();
+
+        let mut pflash = new(f);
+        let snapshot = pflash.snapshot().unwrap();
+        assert!(snapshot["image"].as_str().unwrap().len() < IMAGE_SIZE / 100);
+
+        // Modify the image after the snapshot, both in the programmed block and an erased one.
+        pflash.write(off(offset), &[COMMAND_BLOCK_ERASE]);
+        pflash.write(off(offset), &[COMMAND_BLOCK_ERASE_CONFIRM]);
+        pflash.write(off(0x3000), &[COMMAND_WRITE_BYTE]);
+        pflash.write(off(0x3000), &[0]);
+
+        pflash.restore(snapshot).unwrap();
+        let mut got = [0u8; 4];
+        pflash.read(off(offset), &mut got);
+        assert_eq!(data, got);
+        pflash.read(off(0x3000), &mut got);
+        assert_eq!([ERASED_BYTE; 4], got);
+    }
+
+    #[test]
+    fn restore_legacy_snapshot() {
+        let f = empty_image();
+        let mut data = [0xde, 0xad, 0xbe, 0xef];
+        let offset = 0x2000;
+        f.write_all_at_volatile(VolatileSlice::new(&mut data), offset)
+            .unwrap();
+
+        // Older snapshots only hold the status and the state machine.
+        let mut pflash = new(f);
+        pflash
+            .restore(serde_json::to_value((0u8, State::ReadStatus)).unwrap())
+            .unwrap();
+        let mut got = [0u8; 4];
+        pflash.read(off(offset), &mut got);
+        assert_eq!([0; 4], got);
+        pflash.read(off(offset), &mut got);
+        assert_eq!(data, got);
+    }
+
     #[test]
     fn overwrite() {
         let f = empty_image();
@@ -0,0 +1,751 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Offline access to the UEFI variable store kept in a pflash image by OVMF/EDK2.
+//!
+//! The image starts with a firmware volume (`EFI_FIRMWARE_VOLUME_HEADER`) containing a variable
+//! store header followed by a list of variables. Like the firmware itself, modifications follow
+//! flash semantics where possible: deleting a variable only clears bits of its state byte, and new
+//! variables are appended after the last one. When the store runs out of space, the live variables
+//! are compacted to the start of the store, which the firmware would do on reclaim.
+//!
+//! The fault tolerant write working area and spare area following the variable store are left
+//! untouched.
+
+use std::fmt;
+use std::fmt::Display;
+use std::str::FromStr;
+
+use chrono::Datelike;
+use chrono::Timelike;
+use remain::sorted;
+use thiserror::Error;
+use zerocopy::AsBytes;
+use zerocopy::FromBytes;
+use zerocopy::FromZeroes;
+
+/// Variable is stored in non-volatile memory.
+pub const EFI_VARIABLE_NON_VOLATILE: u32 = 0x1;
+/// Variable is accessible from boot services.
+pub const EFI_VARIABLE_BOOTSERVICE_ACCESS: u32 = 0x2;
+/// Variable is accessible from runtime services.
+pub const EFI_VARIABLE_RUNTIME_ACCESS: u32 = 0x4;
+/// Variable is protected by a time-based authentication descriptor, as required for the secure
+/// boot key databases.
+pub const EFI_VARIABLE_TIME_BASED_AUTHENTICATED_WRITE_ACCESS: u32 = 0x20;
+
+/// Vendor GUID of the architecturally defined variables, such as `PK` and `KEK`.
+pub const EFI_GLOBAL_VARIABLE_GUID: Guid = Guid::from_fields(
+    0x8be4df61,
+    0x93ca,
+    0x11d2,
+    [0xaa, 0x0d, 0x00, 0xe0, 0x98, 0x03, 0x2b, 0x8c],
+);
+/// Vendor GUID of the image signature databases `db` and `dbx`.
+pub const EFI_IMAGE_SECURITY_DATABASE_GUID: Guid = Guid::from_fields(
+    0xd719b2cb,
+    0x3d3a,
+    0x4596,
+    [0xa3, 0xbc, 0xda, 0xd0, 0x0e, 0x67, 0x65, 0x6f],
+);
+/// Signature type of a DER encoded X.509 certificate in an `EFI_SIGNATURE_LIST`.
+pub const EFI_CERT_X509_GUID: Guid = Guid::from_fields(
+    0xa5c059a1,
+    0x94e4,
+    0x4aa7,
+    [0x87, 0xb5, 0xab, 0x15, 0x5c, 0x2b, 0xf0, 0x72],
+);
+
+const EFI_SYSTEM_NV_DATA_FV_GUID: Guid = Guid::from_fields(
+    0xfff12b8d,
+    0x7696,
+    0x4c8b,
+    [0xa9, 0x85, 0x27, 0x47, 0x07, 0x5b, 0x4f, 0x50],
+);
+const EFI_VARIABLE_GUID: Guid = Guid::from_fields(
+    0xddcf3616,
+    0x3275,
+    0x4164,
+    [0x98, 0xb6, 0xfe, 0x85, 0x70, 0x7f, 0xfe, 0x7d],
+);
+const EFI_AUTHENTICATED_VARIABLE_GUID: Guid = Guid::from_fields(
+    0xaaf32c78,
+    0x947b,
+    0x439a,
+    [0xa1, 0x80, 0x2e, 0x14, 0x4e, 0xc3, 0x77, 0x92],
+);
+
+const FV_SIGNATURE: &[u8; 4] = b"_FVH";
+const VARIABLE_STORE_FORMATTED: u8 = 0x5a;
+const VARIABLE_STORE_HEALTHY: u8 = 0xfe;
+
+const VARIABLE_DATA: u16 = 0x55aa;
+const VAR_IN_DELETED_TRANSITION: u8 = 0xfe;
+const VAR_DELETED: u8 = 0xfd;
+const VAR_ADDED: u8 = 0x3f;
+
+// Variable headers are aligned to 4 bytes on all architectures supported by crosvm.
+const HEADER_ALIGNMENT: usize = 4;
+
+const ERASED_BYTE: u8 = 0xff;
+
+#[sorted]
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum Error {
+    #[error("image is not a UEFI variable store firmware volume")]
+    InvalidFirmwareVolume,
+    #[error("invalid GUID {0}")]
+    InvalidGuid(String),
+    #[error("variable name is not valid UTF-16")]
+    InvalidVariableName,
+    #[error("variable store header is invalid or unformatted")]
+    InvalidVariableStore,
+    #[error("variable store is full")]
+    OutOfSpace,
+    #[error("variable {0} not found")]
+    VariableNotFound(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// A GUID as laid out in UEFI structures, with the first three fields in little-endian order.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, AsBytes, FromZeroes, FromBytes)]
+#[repr(C)]
+pub struct Guid([u8; 16]);
+
+impl Guid {
+    pub const fn from_fields(d1: u32, d2: u16, d3: u16, d4: [u8; 8]) -> Guid {
+        let d1 = d1.to_le_bytes();
+        let d2 = d2.to_le_bytes();
+        let d3 = d3.to_le_bytes();
+        Guid([
+            d1[0], d1[1], d1[2], d1[3], d2[0], d2[1], d3[0], d3[1], d4[0], d4[1], d4[2], d4[3],
+            d4[4], d4[5], d4[6], d4[7],
+        ])
+    }
+}
+
+impl Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:08x}-{:04x}-{:04x}-{:02x}{:02x}-",
+            u32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            u16::from_le_bytes([b[4], b[5]]),
+            u16::from_le_bytes([b[6], b[7]]),
+            b[8],
+            b[9]
+        )?;
+        for byte in &b[10..] {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Guid {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Guid> {
+        let invalid = || Error::InvalidGuid(s.to_owned());
+        let parts: Vec<&str> = s.split('-').collect();
+        if !s.is_ascii()
+            || parts.len() != 5
+            || parts
+                .iter()
+                .zip([8, 4, 4, 4, 12])
+                .any(|(part, len)| part.len() != len)
+        {
+            return Err(invalid());
+        }
+        let d1 = u32::from_str_radix(parts[0], 16).map_err(|_| invalid())?;
+        let d2 = u16::from_str_radix(parts[1], 16).map_err(|_| invalid())?;
+        let d3 = u16::from_str_radix(parts[2], 16).map_err(|_| invalid())?;
+        let d4_str = format!("{}{}", parts[3], parts[4]);
+        let mut d4 = [0u8; 8];
+        for (i, byte) in d4.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&d4_str[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+        }
+        Ok(Guid::from_fields(d1, d2, d3, d4))
+    }
+}
+
+/// `EFI_TIME`, used as the timestamp of time-based authenticated variables.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, AsBytes, FromZeroes, FromBytes)]
+#[repr(C)]
+pub struct EfiTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub pad1: u8,
+    pub nanosecond: u32,
+    pub time_zone: i16,
+    pub daylight: u8,
+    pub pad2: u8,
+}
+
+impl EfiTime {
+    /// Returns the current UTC time.
+    pub fn now() -> EfiTime {
+        let now = chrono::Utc::now();
+        EfiTime {
+            year: now.year() as u16,
+            month: now.month() as u8,
+            day: now.day() as u8,
+            hour: now.hour() as u8,
+            minute: now.minute() as u8,
+            second: now.second() as u8,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, AsBytes, FromZeroes, FromBytes)]
+#[repr(C, packed)]
+struct FirmwareVolumeHeader {
+    zero_vector: [u8; 16],
+    file_system_guid: Guid,
+    fv_length: u64,
+    signature: [u8; 4],
+    attributes: u32,
+    header_length: u16,
+    checksum: u16,
+    ext_header_offset: u16,
+    reserved: u8,
+    revision: u8,
+}
+
+#[derive(Clone, Copy, Debug, Default, AsBytes, FromZeroes, FromBytes)]
+#[repr(C, packed)]
+struct VariableStoreHeader {
+    signature: Guid,
+    size: u32,
+    format: u8,
+    state: u8,
+    reserved: u16,
+    reserved1: u32,
+}
+
+#[derive(Clone, Copy, Debug, Default, AsBytes, FromZeroes, FromBytes)]
+#[repr(C, packed)]
+struct VariableHeader {
+    start_id: u16,
+    state: u8,
+    reserved: u8,
+    attributes: u32,
+    name_size: u32,
+    data_size: u32,
+    vendor_guid: Guid,
+}
+
+#[derive(Clone, Copy, Debug, Default, AsBytes, FromZeroes, FromBytes)]
+#[repr(C, packed)]
+struct AuthenticatedVariableHeader {
+    start_id: u16,
+    state: u8,
+    reserved: u8,
+    attributes: u32,
+    monotonic_count: u64,
+    time_stamp: EfiTime,
+    pub_key_index: u32,
+    name_size: u32,
+    data_size: u32,
+    vendor_guid: Guid,
+}
+
+/// A UEFI variable.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Variable {
+    pub name: String,
+    pub vendor_guid: Guid,
+    pub attributes: u32,
+    pub data: Vec<u8>,
+    /// Only stored in authenticated variable stores.
+    pub monotonic_count: u64,
+    /// Only stored in authenticated variable stores.
+    pub timestamp: EfiTime,
+    /// Only stored in authenticated variable stores.
+    pub pub_key_index: u32,
+}
+
+// Location and state of a variable found in the store.
+struct VariableEntry {
+    offset: usize,
+    state: u8,
+    variable: Variable,
+}
+
+/// The variable store of a pflash image.
+pub struct VarStore {
+    image: Vec<u8>,
+    authenticated: bool,
+    // Range of the image holding variable headers and data.
+    vars_start: usize,
+    vars_end: usize,
+}
+
+fn align_up(offset: usize) -> usize {
+    (offset + HEADER_ALIGNMENT - 1) & !(HEADER_ALIGNMENT - 1)
+}
+
+fn encode_name(name: &str) -> Vec<u8> {
+    name.encode_utf16()
+        .chain(std::iter::once(0))
+        .flat_map(u16::to_le_bytes)
+        .collect()
+}
+
+fn decode_name(name: &[u8]) -> Result<String> {
+    let name: Vec<u16> = name
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .take_while(|&c| c != 0)
+        .collect();
+    String::from_utf16(&name).map_err(|_| Error::InvalidVariableName)
+}
+
+/// Returns the vendor GUID of the secure boot key databases `PK`, `KEK`, `db` and `dbx`.
+pub fn secure_boot_vendor_guid(name: &str) -> Option<Guid> {
+    match name {
+        "PK" | "KEK" => Some(EFI_GLOBAL_VARIABLE_GUID),
+        "db" | "dbx" => Some(EFI_IMAGE_SECURITY_DATABASE_GUID),
+        _ => None,
+    }
+}
+
+/// Wraps a DER encoded X.509 certificate owned by `owner` in an `EFI_SIGNATURE_LIST`, the format
+/// of the secure boot key databases.
+pub fn x509_signature_list(owner: Guid, cert: &[u8]) -> Vec<u8> {
+    const SIGNATURE_LIST_HEADER_SIZE: usize = 28;
+    let signature_size = owner.as_bytes().len() + cert.len();
+    let list_size = SIGNATURE_LIST_HEADER_SIZE + signature_size;
+
+    let mut list = Vec::with_capacity(list_size);
+    list.extend_from_slice(EFI_CERT_X509_GUID.as_bytes());
+    list.extend_from_slice(&(list_size as u32).to_le_bytes());
+    list.extend_from_slice(&0u32.to_le_bytes()); // SignatureHeaderSize
+    list.extend_from_slice(&(signature_size as u32).to_le_bytes());
+    list.extend_from_slice(owner.as_bytes());
+    list.extend_from_slice(cert);
+    list
+}
+
+impl VarStore {
+    /// Parses the variable store at the start of a pflash `image`.
+    pub fn new(image: Vec<u8>) -> Result<VarStore> {
+        let fv_header =
+            FirmwareVolumeHeader::read_from_prefix(&image).ok_or(Error::InvalidFirmwareVolume)?;
+        if &fv_header.signature != FV_SIGNATURE
+            || fv_header.file_system_guid != EFI_SYSTEM_NV_DATA_FV_GUID
+            || fv_header.fv_length > image.len() as u64
+        {
+            return Err(Error::InvalidFirmwareVolume);
+        }
+
+        let store_start = fv_header.header_length as usize;
+        let store_header = image
+            .get(store_start..)
+            .and_then(VariableStoreHeader::read_from_prefix)
+            .ok_or(Error::InvalidVariableStore)?;
+        let authenticated = match store_header.signature {
+            EFI_AUTHENTICATED_VARIABLE_GUID => true,
+            EFI_VARIABLE_GUID => false,
+            _ => return Err(Error::InvalidVariableStore),
+        };
+        let vars_end = store_start
+            .checked_add(store_header.size as usize)
+            .filter(|&end| end as u64 <= fv_header.fv_length)
+            .ok_or(Error::InvalidVariableStore)?;
+        if store_header.format != VARIABLE_STORE_FORMATTED
+            || store_header.state != VARIABLE_STORE_HEALTHY
+        {
+            return Err(Error::InvalidVariableStore);
+        }
+
+        Ok(VarStore {
+            image,
+            authenticated,
+            vars_start: align_up(store_start + std::mem::size_of::<VariableStoreHeader>()),
+            vars_end,
+        })
+    }
+
+    /// Returns the whole pflash image, including any changes made to the variable store.
+    pub fn image(&self) -> &[u8] {
+        &self.image
+    }
+
+    fn header_size(&self) -> usize {
+        if self.authenticated {
+            std::mem::size_of::<AuthenticatedVariableHeader>()
+        } else {
+            std::mem::size_of::<VariableHeader>()
+        }
+    }
+
+    // Parses the variable at `offset`. Returns the entry and the offset of the next variable, or
+    // `None` at the end of the list.
+    fn parse_entry(&self, offset: usize) -> Result<Option<(VariableEntry, usize)>> {
+        let header_size = self.header_size();
+        let Some(header) = self
+            .image
+            .get(offset..self.vars_end.min(offset + header_size))
+        else {
+            return Ok(None);
+        };
+        if header.len() < header_size {
+            return Ok(None);
+        }
+
+        let (state, mut variable, name_size, data_size) = if self.authenticated {
+            let h = AuthenticatedVariableHeader::read_from(header).unwrap();
+            if h.start_id != VARIABLE_DATA {
+                return Ok(None);
+            }
+            let variable = Variable {
+                vendor_guid: h.vendor_guid,
+                attributes: h.attributes,
+                monotonic_count: h.monotonic_count,
+                timestamp: h.time_stamp,
+                pub_key_index: h.pub_key_index,
+                ..Default::default()
+            };
+            (h.state, variable, h.name_size, h.data_size)
+        } else {
+            let h = VariableHeader::read_from(header).unwrap();
+            if h.start_id != VARIABLE_DATA {
+                return Ok(None);
+            }
+            let variable = Variable {
+                vendor_guid: h.vendor_guid,
+                attributes: h.attributes,
+                ..Default::default()
+            };
+            (h.state, variable, h.name_size, h.data_size)
+        };
+
+        let name_start = offset + header_size;
+        let data_start = name_start + name_size as usize;
+        let data_end = data_start + data_size as usize;
+        if data_end > self.vars_end {
+            return Err(Error::InvalidVariableStore);
+        }
+        variable.name = decode_name(&self.image[name_start..data_start])?;
+        variable.data = self.image[data_start..data_end].to_vec();
+
+        Ok(Some((
+            VariableEntry {
+                offset,
+                state,
+                variable,
+            },
+            align_up(data_end),
+        )))
+    }
+
+    // Returns all live variables and the offset following the last variable in the store.
+    fn entries(&self) -> Result<(Vec<VariableEntry>, usize)> {
+        let mut entries: Vec<VariableEntry> = Vec::new();
+        let mut offset = self.vars_start;
+        while let Some((entry, next)) = self.parse_entry(offset)? {
+            offset = next;
+            if entry.state == VAR_ADDED || entry.state == VAR_ADDED & VAR_IN_DELETED_TRANSITION {
+                entries.push(entry);
+            }
+        }
+
+        // A variable interrupted while being updated has its old copy in transition. It is only
+        // valid if the new copy never made it to the store.
+        let is_superseded = |entry: &VariableEntry| {
+            entry.state != VAR_ADDED
+                && entries.iter().any(|other| {
+                    other.state == VAR_ADDED
+                        && other.variable.name == entry.variable.name
+                        && other.variable.vendor_guid == entry.variable.vendor_guid
+                })
+        };
+        let superseded: Vec<bool> = entries.iter().map(is_superseded).collect();
+        let entries = entries
+            .into_iter()
+            .zip(superseded)
+            .filter_map(|(entry, superseded)| (!superseded).then_some(entry))
+            .collect();
+
+        Ok((entries, offset))
+    }
+
+    /// Returns all variables in the store.
+    pub fn variables(&self) -> Result<Vec<Variable>> {
+        Ok(self
+            .entries()?
+            .0
+            .into_iter()
+            .map(|entry| entry.variable)
+            .collect())
+    }
+
+    /// Returns the variable `name` of vendor `vendor_guid`.
+    pub fn get(&self, name: &str, vendor_guid: &Guid) -> Result<Option<Variable>> {
+        Ok(self
+            .variables()?
+            .into_iter()
+            .find(|var| var.name == name && &var.vendor_guid == vendor_guid))
+    }
+
+    /// Deletes the variable `name` of vendor `vendor_guid`.
+    pub fn delete(&mut self, name: &str, vendor_guid: &Guid) -> Result<()> {
+        let (entries, _) = self.entries()?;
+        let mut found = false;
+        for entry in entries {
+            if entry.variable.name == name && &entry.variable.vendor_guid == vendor_guid {
+                // Variables are marked deleted by clearing bits, just like the firmware would.
+                self.image[entry.offset + 2] &= VAR_DELETED;
+                found = true;
+            }
+        }
+        if found {
+            Ok(())
+        } else {
+            Err(Error::VariableNotFound(name.to_owned()))
+        }
+    }
+
+    /// Adds `variable` to the store, replacing any existing variable with the same name and vendor.
+    pub fn set(&mut self, variable: &Variable) -> Result<()> {
+        let encoded = self.encode_variable(variable);
+        let (entries, end) = self.entries()?;
+        let mut end = end;
+        let old: Vec<usize> = entries
+            .iter()
+            .filter(|entry| {
+                entry.variable.name == variable.name
+                    && entry.variable.vendor_guid == variable.vendor_guid
+            })
+            .map(|entry| entry.offset)
+            .collect();
+
+        if end + encoded.len() > self.vars_end {
+            let live: Vec<Variable> = entries
+                .into_iter()
+                .filter(|entry| !old.contains(&entry.offset))
+                .map(|entry| entry.variable)
+                .collect();
+            let used: usize = live
+                .iter()
+                .map(|var| align_up(self.encode_variable(var).len()))
+                .sum();
+            if self.vars_start + used + encoded.len() > self.vars_end {
+                return Err(Error::OutOfSpace);
+            }
+            end = self.write_variables(&live);
+        } else {
+            for offset in old {
+                self.image[offset + 2] &= VAR_DELETED;
+            }
+        }
+
+        self.image[end..end + encoded.len()].copy_from_slice(&encoded);
+        Ok(())
+    }
+
+    /// Deletes all variables and erases the space they used.
+    pub fn clear(&mut self) {
+        self.write_variables(&[]);
+    }
+
+    // Erases the variable area and writes `variables` back to back. Returns the offset following
+    // the last variable.
+    fn write_variables(&mut self, variables: &[Variable]) -> usize {
+        self.image[self.vars_start..self.vars_end].fill(ERASED_BYTE);
+        let mut offset = self.vars_start;
+        for variable in variables {
+            let encoded = self.encode_variable(variable);
+            self.image[offset..offset + encoded.len()].copy_from_slice(&encoded);
+            offset = align_up(offset + encoded.len());
+        }
+        offset
+    }
+
+    fn encode_variable(&self, variable: &Variable) -> Vec<u8> {
+        let name = encode_name(&variable.name);
+        let mut encoded = if self.authenticated {
+            AuthenticatedVariableHeader {
+                start_id: VARIABLE_DATA,
+                state: VAR_ADDED,
+                reserved: 0,
+                attributes: variable.attributes,
+                monotonic_count: variable.monotonic_count,
+                time_stamp: variable.timestamp,
+                pub_key_index: variable.pub_key_index,
+                name_size: name.len() as u32,
+                data_size: variable.data.len() as u32,
+                vendor_guid: variable.vendor_guid,
+            }
+            .as_bytes()
+            .to_vec()
+        } else {
+            VariableHeader {
+                start_id: VARIABLE_DATA,
+                state: VAR_ADDED,
+                reserved: 0,
+                attributes: variable.attributes,
+                name_size: name.len() as u32,
+                data_size: variable.data.len() as u32,
+                vendor_guid: variable.vendor_guid,
+            }
+            .as_bytes()
+            .to_vec()
+        };
+        encoded.extend_from_slice(&name);
+        encoded.extend_from_slice(&variable.data);
+        encoded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STORE_SIZE: usize = 0x1000;
+
+    // Builds an empty, formatted authenticated variable store like OVMF_VARS.fd.
+    fn empty_image() -> Vec<u8> {
+        let header_length = std::mem::size_of::<FirmwareVolumeHeader>() + 16;
+        let mut image = vec![ERASED_BYTE; 2 * STORE_SIZE];
+        let fv_header = FirmwareVolumeHeader {
+            file_system_guid: EFI_SYSTEM_NV_DATA_FV_GUID,
+            fv_length: image.len() as u64,
+            signature: *FV_SIGNATURE,
+            header_length: header_length as u16,
+            revision: 2,
+            ..Default::default()
+        };
+        image[..header_length].fill(0);
+        image[..std::mem::size_of::<FirmwareVolumeHeader>()].copy_from_slice(fv_header.as_bytes());
+        let store_header = VariableStoreHeader {
+            signature: EFI_AUTHENTICATED_VARIABLE_GUID,
+            size: (STORE_SIZE - header_length) as u32,
+            format: VARIABLE_STORE_FORMATTED,
+            state: VARIABLE_STORE_HEALTHY,
+            ..Default::default()
+        };
+        image[header_length..header_length + std::mem::size_of::<VariableStoreHeader>()]
+            .copy_from_slice(store_header.as_bytes());
+        image
+    }
+
+    fn variable(name: &str, data: &[u8]) -> Variable {
+        Variable {
+            name: name.to_owned(),
+            vendor_guid: EFI_GLOBAL_VARIABLE_GUID,
+            attributes: EFI_VARIABLE_NON_VOLATILE
+                | EFI_VARIABLE_BOOTSERVICE_ACCESS
+                | EFI_VARIABLE_RUNTIME_ACCESS,
+            data: data.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn guid_round_trip() {
+        let s = "8be4df61-93ca-11d2-aa0d-00e098032b8c";
+        let guid: Guid = s.parse().unwrap();
+        assert_eq!(guid, EFI_GLOBAL_VARIABLE_GUID);
+        assert_eq!(guid.to_string(), s);
+        assert!("8be4df61-93ca-11d2-aa0d".parse::<Guid>().is_err());
+        assert!("8be4df61-93ca-11d2-aa0d-00e098032bzz"
+            .parse::<Guid>()
+            .is_err());
+    }
+
+    #[test]
+    fn invalid_image() {
+        assert_eq!(
+            VarStore::new(vec![0; STORE_SIZE]).err(),
+            Some(Error::InvalidFirmwareVolume)
+        );
+    }
+
+    #[test]
+    fn set_get_delete() {
+        let mut store = VarStore::new(empty_image()).unwrap();
+        assert!(store.variables().unwrap().is_empty());
+
+        store.set(&variable("Boot0000", &[1, 2, 3])).unwrap();
+        store.set(&variable("Timeout", &[5, 0])).unwrap();
+        store.set(&variable("Boot0000", &[4, 5, 6, 7])).unwrap();
+
+        // Changes survive reparsing the image.
+        let mut store = VarStore::new(store.image().to_vec()).unwrap();
+        let vars = store.variables().unwrap();
+        assert_eq!(
+            vars,
+            vec![
+                variable("Timeout", &[5, 0]),
+                variable("Boot0000", &[4, 5, 6, 7])
+            ]
+        );
+
+        store.delete("Timeout", &EFI_GLOBAL_VARIABLE_GUID).unwrap();
+        assert_eq!(
+            store.get("Timeout", &EFI_GLOBAL_VARIABLE_GUID).unwrap(),
+            None
+        );
+        assert_eq!(
+            store.delete("Timeout", &EFI_GLOBAL_VARIABLE_GUID),
+            Err(Error::VariableNotFound("Timeout".to_owned()))
+        );
+        assert_eq!(
+            store.get("Boot0000", &EFI_GLOBAL_VARIABLE_GUID).unwrap(),
+            Some(variable("Boot0000", &[4, 5, 6, 7]))
+        );
+
+        store.clear();
+        assert!(store.variables().unwrap().is_empty());
+    }
+
+    #[test]
+    fn reclaim_when_full() {
+        let mut store = VarStore::new(empty_image()).unwrap();
+        let data = vec![0xaa; 1000];
+        // Repeatedly updating a variable fills the store with deleted copies, which have to be
+        // reclaimed to make room.
+        for i in 0..10u8 {
+            let mut var = variable("db", &data);
+            var.data[0] = i;
+            store.set(&var).unwrap();
+        }
+        let vars = store.variables().unwrap();
+        assert_eq!(vars.len(), 1);
+        assert_eq!(vars[0].data[0], 9);
+
+        let too_big = variable("KEK", &vec![0; STORE_SIZE]);
+        assert_eq!(store.set(&too_big), Err(Error::OutOfSpace));
+    }
+
+    #[test]
+    fn in_deleted_transition() {
+        let mut store = VarStore::new(empty_image()).unwrap();
+        store.set(&variable("Lang", b"eng")).unwrap();
+        let offset = store.vars_start;
+        // Interrupted update: the old copy is in transition and no new copy was written.
+        store.image[offset + 2] &= VAR_IN_DELETED_TRANSITION;
+        assert_eq!(store.variables().unwrap(), vec![variable("Lang", b"eng")]);
+    }
+
+    #[test]
+    fn signature_list() {
+        let owner = EFI_CERT_X509_GUID;
+        let list = x509_signature_list(owner, &[1, 2, 3]);
+        assert_eq!(list.len(), 28 + 16 + 3);
+        assert_eq!(&list[..16], EFI_CERT_X509_GUID.as_bytes());
+        assert_eq!(&list[16..20], &47u32.to_le_bytes());
+        assert_eq!(&list[24..28], &19u32.to_le_bytes());
+        assert_eq!(&list[44..], &[1, 2, 3]);
+    }
+}
@@ -9,6 +9,7 @@ use std::fs::OpenOptions;
 use std::io;
 use std::io::stdin;
 use std::io::stdout;
+use std::net::SocketAddr;
 use std::path::PathBuf;
 
 use base::error;
@@ -28,6 +29,8 @@ use serde::Serialize;
 use serde_keyvalue::FromKeyValues;
 use thiserror::Error as ThisError;
 
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub use crate::sys::serial_device::read_ring_buffer;
 pub use crate::sys::serial_device::SerialDevice;
 use crate::sys::serial_device::*;
 use crate::PciAddress;
@@ -35,6 +38,8 @@ use crate::PciAddress;
 #[sorted]
 #[derive(ThisError, Debug)]
 pub enum Error {
+    #[error("Serial device type {0} requires an address")]
+    AddressRequired(SerialType),
     #[error("Unable to clone an Event: {0}")]
     CloneEvent(base::Error),
     #[error("Unable to clone file: {0}")]
@@ -43,14 +48,28 @@ pub enum Error {
     FileCreate(std::io::Error, PathBuf),
     #[error("Unable to open file '{1}': {0}")]
     FileOpen(std::io::Error, PathBuf),
+    #[error("Serial device type {0} provides its own input")]
+    InputConflict(SerialType),
     #[error("Serial device path '{0} is invalid")]
     InvalidPath(PathBuf),
+    #[error("Invalid ring buffer size: {0} KiB")]
+    InvalidRingSize(u32),
     #[error("Invalid serial hardware: {0}")]
     InvalidSerialHardware(String),
     #[error("Invalid serial type: {0}")]
     InvalidSerialType(String),
     #[error("Serial device type file requires a path")]
     PathRequired,
+    #[error("Unable to create pipe: {0}")]
+    PipeCreate(base::Error),
+    #[error("Unable to create pseudo-terminal: {0}")]
+    PtyCreate(std::io::Error),
+    #[error("Unable to spawn relay thread: {0}")]
+    RelaySpawn(std::io::Error),
+    #[error("Unable to map ring buffer '{1}': {0}")]
+    RingBufferMap(base::MmapError, PathBuf),
+    #[error("Failed to bind socket '{1}': {0}")]
+    SocketBind(std::io::Error, String),
     #[error("Failed to connect to socket: {0}")]
     SocketConnect(std::io::Error),
     #[error("Failed to create unbound socket: {0}")]
@@ -78,6 +97,18 @@ pub enum SerialType {
     #[cfg_attr(unix, serde(rename = "unix"))]
     #[cfg_attr(windows, serde(rename = "namedpipe"))]
     SystemSerialType,
+    /// Unix socket listening at `path`, with the last client to connect attached to the device.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    UnixServer,
+    /// TCP socket listening at `address`, with the last client to connect attached to the device.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    TcpServer,
+    /// Newly allocated pseudo-terminal, optionally symlinked at `path`.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    Pty,
+    /// File at `path` keeping the last `ring-size` KiB of output, read by `crosvm serial dump`.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    RingBuffer,
 }
 
 impl Default for SerialType {
@@ -94,6 +125,14 @@ impl Display for SerialType {
             SerialType::Sink => "Sink".to_string(),
             SerialType::Syslog => "Syslog".to_string(),
             SerialType::SystemSerialType => SYSTEM_SERIAL_TYPE_NAME.to_string(),
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            SerialType::UnixServer => "UnixServer".to_string(),
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            SerialType::TcpServer => "TcpServer".to_string(),
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            SerialType::Pty => "Pty".to_string(),
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            SerialType::RingBuffer => "RingBuffer".to_string(),
         };
 
         write!(f, "{}", s)
@@ -160,6 +199,8 @@ pub struct SerialParameters {
     )]
     pub debugcon_port: u16,
     pub pci_address: Option<PciAddress>,
+    pub address: Option<SocketAddr>,
+    pub ring_size: Option<u32>,
 }
 
 /// Temporary structure containing the parameters of a serial port for easy passing to
@@ -189,7 +230,8 @@ impl SerialParameters {
         keep_rds.push(evt.as_raw_descriptor());
         cros_tracing::push_descriptors!(keep_rds);
         metrics::push_descriptors(keep_rds);
-        let input: Option<Box<dyn SerialInput>> = if let Some(input_path) = &self.input {
+        #[allow(unused_mut)]
+        let mut input: Option<Box<dyn SerialInput>> = if let Some(input_path) = &self.input {
             let input_path = input_path.as_path();
 
             let input_file = open_file_or_duplicate(input_path, OpenOptions::new().read(true))
@@ -233,6 +275,23 @@ impl SerialParameters {
                 }
                 None => return Err(Error::PathRequired),
             },
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            SerialType::UnixServer | SerialType::TcpServer | SerialType::Pty => {
+                if input.is_some() {
+                    return Err(Error::InputConflict(self.type_.clone()));
+                }
+                let (relay_input, relay_output) = create_relay(self, keep_rds)?;
+                input = Some(relay_input);
+                (Some(relay_output), None)
+            }
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            SerialType::RingBuffer => match &self.path {
+                Some(path) => (
+                    Some(Box::new(RingBuffer::create(path, self.ring_size)?)),
+                    None,
+                ),
+                None => return Err(Error::PathRequired),
+            },
             SerialType::SystemSerialType => {
                 return create_system_type_serial_device(
                     self,
@@ -289,6 +348,8 @@ mod tests {
                 out_timestamp: false,
                 debugcon_port: 0x402,
                 pci_address: None,
+                address: None,
+                ring_size: None,
             }
         );
 
@@ -307,6 +368,17 @@ mod tests {
         let opt = "type=namedpipe";
         let params = from_serial_arg(opt).unwrap();
         assert_eq!(params.type_, SerialType::SystemSerialType);
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        {
+            let params = from_serial_arg("type=unix-server").unwrap();
+            assert_eq!(params.type_, SerialType::UnixServer);
+            let params = from_serial_arg("type=tcp-server").unwrap();
+            assert_eq!(params.type_, SerialType::TcpServer);
+            let params = from_serial_arg("type=pty").unwrap();
+            assert_eq!(params.type_, SerialType::Pty);
+            let params = from_serial_arg("type=ring-buffer").unwrap();
+            assert_eq!(params.type_, SerialType::RingBuffer);
+        }
         let params = from_serial_arg("type=foobar");
         assert!(params.is_err());
 
@@ -382,6 +454,18 @@ mod tests {
         let params = from_serial_arg("debugcon_port=1026").unwrap();
         assert_eq!(params.debugcon_port, 1026);
 
+        // address parameter
+        let params = from_serial_arg("address=127.0.0.1:4555").unwrap();
+        assert_eq!(params.address, Some("127.0.0.1:4555".parse().unwrap()));
+        let params = from_serial_arg("address=\"[::1]:4555\"").unwrap();
+        assert_eq!(params.address, Some("[::1]:4555".parse().unwrap()));
+        let params = from_serial_arg("address=localhost");
+        assert!(params.is_err());
+
+        // ring-size parameter
+        let params = from_serial_arg("ring-size=128").unwrap();
+        assert_eq!(params.ring_size, Some(128));
+
         // all together
         let params = from_serial_arg("type=stdout,path=/some/path,hardware=virtio-console,num=5,earlycon,console,stdin,input=/some/input,out_timestamp,debugcon_port=12,pci-address=00:0e.0").unwrap();
         assert_eq!(
@@ -403,6 +487,8 @@ mod tests {
                     dev: 14,
                     func: 0
                 }),
+                address: None,
+                ring_size: None,
             }
         );
 
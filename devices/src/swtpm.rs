@@ -0,0 +1,366 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! TPM backend that forwards commands to an [swtpm] process.
+//!
+//! swtpm has to be started with a UNIX socket control channel, for example:
+//!
+//! ```text
+//! swtpm socket --tpm2 --tpmstate dir=/var/lib/vm/tpm --ctrl type=unixio,path=/run/vm/swtpm.sock \
+//!     --terminate
+//! ```
+//!
+//! The TPM NVRAM is kept by swtpm in its `--tpmstate` directory. crosvm connects to the control
+//! socket, hands swtpm one end of a socket pair with `CMD_SET_DATAFD` to use as the data channel,
+//! and then sends TPM commands over that data channel. Snapshots include the permanent, volatile
+//! and save-state blobs fetched with `CMD_GET_STATEBLOB`, which are pushed back into swtpm with
+//! `CMD_SET_STATEBLOB` on restore.
+//!
+//! crosvm never sends `CMD_SHUTDOWN`: the backend may be duplicated into a sandboxed device
+//! process, and a copy dropped in another process must not stop the TPM under the owner's feet.
+//! With `--terminate`, swtpm exits once the last copy of the control channel is closed, i.e. when
+//! the process actually using the TPM goes away.
+//!
+//! [swtpm]: https://github.com/stefanberger/swtpm
+
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use base::error;
+use base::AsRawDescriptor;
+use base::RawDescriptor;
+use base::ScmSocket;
+use remain::sorted;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_keyvalue::FromKeyValues;
+use thiserror::Error;
+
+use crate::virtio::TpmBackend;
+
+// Control channel commands, from swtpm's tpm_ioctl.h.
+const CMD_INIT: u32 = 2;
+const CMD_GET_STATEBLOB: u32 = 12;
+const CMD_SET_STATEBLOB: u32 = 13;
+const CMD_STOP: u32 = 14;
+const CMD_SET_DATAFD: u32 = 16;
+
+const PTM_STATE_FLAG_DECRYPTED: u32 = 1;
+
+const PTM_BLOB_TYPE_PERMANENT: u32 = 1;
+const PTM_BLOB_TYPE_VOLATILE: u32 = 2;
+const PTM_BLOB_TYPE_SAVESTATE: u32 = 3;
+
+const TPM_SUCCESS: u32 = 0;
+
+// Size of a TPM 2.0 command or response header: tag (2), size (4) and code (4).
+const TPM_HEADER_SIZE: usize = 10;
+
+// Largest response accepted from swtpm. This matches the buffer size of the virtio-tpm device.
+const TPM_BUFSIZE: usize = 4096;
+
+// The response of TPM_RC_FAILURE
+const TPM_RC_FAILURE_RESPONSE: &[u8] = &[
+    0x80, 0x01, // TPM_ST_NO_SESSIONS
+    0x00, 0x00, 0x00, 0x0A, // Header Size = 10
+    0x00, 0x00, 0x01, 0x01, // TPM_RC_FAILURE
+];
+
+/// Guest-visible interface of a TPM device.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TpmInterface {
+    /// virtio-tpm device.
+    #[default]
+    Virtio,
+    /// TPM Command Response Buffer MMIO interface, usable by firmware for measured boot.
+    Crb,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, FromKeyValues)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct SwtpmParameters {
+    /// Path of the swtpm control channel socket.
+    pub socket: PathBuf,
+    /// How the TPM is exposed to the guest.
+    #[serde(default)]
+    pub interface: TpmInterface,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StateBlob {
+    flags: u32,
+    data: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SwtpmSnapshot {
+    permanent: StateBlob,
+    volatile: StateBlob,
+    savestate: StateBlob,
+}
+
+/// A TPM backend connected to an swtpm control channel.
+pub struct Swtpm {
+    ctrl: ScmSocket<UnixStream>,
+    data: UnixStream,
+    buf: Vec<u8>,
+}
+
+impl Swtpm {
+    /// Connects to the swtpm control socket at `ctrl_path` and starts the TPM.
+    pub fn new(ctrl_path: &Path) -> Result<Swtpm> {
+        let ctrl = UnixStream::connect(ctrl_path)
+            .and_then(ScmSocket::try_from)
+            .map_err(|e| Error::Connect(ctrl_path.to_owned(), e))?;
+        let (data, remote) = UnixStream::pair().map_err(Error::CreateSocketPair)?;
+
+        let mut swtpm = Swtpm {
+            ctrl,
+            data,
+            buf: Vec::new(),
+        };
+
+        swtpm
+            .ctrl
+            .send_with_fds(&CMD_SET_DATAFD.to_be_bytes(), &[remote.as_raw_fd()])
+            .map_err(Error::Ctrl)?;
+        swtpm.read_result(CMD_SET_DATAFD)?;
+        swtpm.init()?;
+
+        Ok(swtpm)
+    }
+
+    fn read_u32(&mut self) -> Result<u32> {
+        let mut val = [0u8; 4];
+        self.ctrl
+            .inner_mut()
+            .read_exact(&mut val)
+            .map_err(Error::Ctrl)?;
+        Ok(u32::from_be_bytes(val))
+    }
+
+    fn read_result(&mut self, cmd: u32) -> Result<()> {
+        match self.read_u32()? {
+            TPM_SUCCESS => Ok(()),
+            result => Err(Error::Command { cmd, result }),
+        }
+    }
+
+    /// Sends a control command with its big-endian encoded parameters.
+    fn send_ctrl_cmd(&mut self, cmd: u32, params: &[u32]) -> Result<()> {
+        let mut msg = cmd.to_be_bytes().to_vec();
+        for param in params {
+            msg.extend_from_slice(&param.to_be_bytes());
+        }
+        self.ctrl.inner_mut().write_all(&msg).map_err(Error::Ctrl)
+    }
+
+    fn init(&mut self) -> Result<()> {
+        // No init flags: keep the volatile state so that a restored TPM resumes from it.
+        self.send_ctrl_cmd(CMD_INIT, &[0])?;
+        self.read_result(CMD_INIT)
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        self.send_ctrl_cmd(CMD_STOP, &[])?;
+        self.read_result(CMD_STOP)
+    }
+
+    fn get_state_blob(&mut self, blob_type: u32) -> Result<StateBlob> {
+        self.send_ctrl_cmd(
+            CMD_GET_STATEBLOB,
+            &[PTM_STATE_FLAG_DECRYPTED, blob_type, 0 /* offset */],
+        )?;
+        self.read_result(CMD_GET_STATEBLOB)?;
+        let flags = self.read_u32()?;
+        let total_length = self.read_u32()?;
+        let length = self.read_u32()?;
+        if total_length != length {
+            return Err(Error::PartialStateBlob {
+                length,
+                total_length,
+            });
+        }
+
+        let mut data = vec![0u8; length as usize];
+        self.ctrl
+            .inner_mut()
+            .read_exact(&mut data)
+            .map_err(Error::Ctrl)?;
+        Ok(StateBlob { flags, data })
+    }
+
+    fn set_state_blob(&mut self, blob_type: u32, blob: &StateBlob) -> Result<()> {
+        let length = u32::try_from(blob.data.len()).map_err(|_| Error::StateBlobTooLarge)?;
+        self.send_ctrl_cmd(CMD_SET_STATEBLOB, &[blob.flags, blob_type, length])?;
+        self.ctrl
+            .inner_mut()
+            .write_all(&blob.data)
+            .map_err(Error::Ctrl)?;
+        self.read_result(CMD_SET_STATEBLOB)
+    }
+
+    fn try_execute_command(&mut self, command: &[u8]) -> Result<()> {
+        self.data.write_all(command).map_err(Error::Data)?;
+
+        let mut header = [0u8; TPM_HEADER_SIZE];
+        self.data.read_exact(&mut header).map_err(Error::Data)?;
+        let size = u32::from_be_bytes(header[2..6].try_into().unwrap()) as usize;
+        if !(TPM_HEADER_SIZE..=TPM_BUFSIZE).contains(&size) {
+            return Err(Error::InvalidResponseSize(size));
+        }
+
+        self.buf.clear();
+        self.buf.extend_from_slice(&header);
+        self.buf.resize(size, 0);
+        self.data
+            .read_exact(&mut self.buf[TPM_HEADER_SIZE..])
+            .map_err(Error::Data)
+    }
+}
+
+impl TpmBackend for Swtpm {
+    fn execute_command<'a>(&'a mut self, command: &[u8]) -> &'a [u8] {
+        match self.try_execute_command(command) {
+            Ok(()) => &self.buf,
+            Err(e) => {
+                error!("{:#}", e);
+                TPM_RC_FAILURE_RESPONSE
+            }
+        }
+    }
+
+    fn keep_rds(&self) -> Vec<RawDescriptor> {
+        vec![self.ctrl.as_raw_descriptor(), self.data.as_raw_descriptor()]
+    }
+
+    fn snapshot(&mut self) -> anyhow::Result<serde_json::Value> {
+        let snapshot = SwtpmSnapshot {
+            permanent: self.get_state_blob(PTM_BLOB_TYPE_PERMANENT)?,
+            volatile: self.get_state_blob(PTM_BLOB_TYPE_VOLATILE)?,
+            savestate: self.get_state_blob(PTM_BLOB_TYPE_SAVESTATE)?,
+        };
+        serde_json::to_value(snapshot).context("failed to serialize swtpm state")
+    }
+
+    fn restore(&mut self, data: serde_json::Value) -> anyhow::Result<()> {
+        let snapshot: SwtpmSnapshot =
+            serde_json::from_value(data).context("failed to deserialize swtpm state")?;
+        self.stop()?;
+        self.set_state_blob(PTM_BLOB_TYPE_PERMANENT, &snapshot.permanent)?;
+        self.set_state_blob(PTM_BLOB_TYPE_VOLATILE, &snapshot.volatile)?;
+        self.set_state_blob(PTM_BLOB_TYPE_SAVESTATE, &snapshot.savestate)?;
+        self.init()?;
+        Ok(())
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[sorted]
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("swtpm control command {cmd} failed with TPM result {result:#x}")]
+    Command { cmd: u32, result: u32 },
+    #[error("failed to connect to swtpm control socket {0}: {1}")]
+    Connect(PathBuf, io::Error),
+    #[error("failed to create swtpm data channel: {0}")]
+    CreateSocketPair(io::Error),
+    #[error("swtpm control channel failure: {0}")]
+    Ctrl(io::Error),
+    #[error("swtpm data channel failure: {0}")]
+    Data(io::Error),
+    #[error("swtpm response has invalid size {0}")]
+    InvalidResponseSize(usize),
+    #[error("swtpm returned {length} of {total_length} bytes of a state blob")]
+    PartialStateBlob { length: u32, total_length: u32 },
+    #[error("swtpm state blob is too large")]
+    StateBlobTooLarge,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    // Reads a control command and its parameters from the fake swtpm end of the socket.
+    fn read_cmd(sock: &mut UnixStream, num_params: usize) -> (u32, Vec<u32>) {
+        let mut buf = vec![0u8; 4 * (num_params + 1)];
+        sock.read_exact(&mut buf).unwrap();
+        let mut words = buf
+            .chunks(4)
+            .map(|w| u32::from_be_bytes(w.try_into().unwrap()));
+        (words.next().unwrap(), words.collect())
+    }
+
+    #[test]
+    fn execute_and_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("swtpm.sock");
+        let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+
+        let fake_swtpm = thread::spawn(move || {
+            let (sock, _) = listener.accept().unwrap();
+            let sock = ScmSocket::try_from(sock).unwrap();
+            let mut cmd = [0u8; 4];
+            let (_, mut fds) = sock.recv_with_fds(&mut cmd, 1).unwrap();
+            assert_eq!(u32::from_be_bytes(cmd), CMD_SET_DATAFD);
+            let mut data = UnixStream::from(fds.remove(0));
+            let mut sock = sock.into_inner();
+            sock.write_all(&TPM_SUCCESS.to_be_bytes()).unwrap();
+
+            assert_eq!(read_cmd(&mut sock, 1), (CMD_INIT, vec![0]));
+            sock.write_all(&TPM_SUCCESS.to_be_bytes()).unwrap();
+
+            // Echo a single TPM command back as its response.
+            let mut command = [0u8; TPM_HEADER_SIZE];
+            data.read_exact(&mut command).unwrap();
+            data.write_all(&command).unwrap();
+
+            for blob_type in [
+                PTM_BLOB_TYPE_PERMANENT,
+                PTM_BLOB_TYPE_VOLATILE,
+                PTM_BLOB_TYPE_SAVESTATE,
+            ] {
+                assert_eq!(
+                    read_cmd(&mut sock, 3),
+                    (
+                        CMD_GET_STATEBLOB,
+                        vec![PTM_STATE_FLAG_DECRYPTED, blob_type, 0]
+                    )
+                );
+                for word in [TPM_SUCCESS, PTM_STATE_FLAG_DECRYPTED, 1, 1] {
+                    sock.write_all(&word.to_be_bytes()).unwrap();
+                }
+                sock.write_all(&[blob_type as u8]).unwrap();
+            }
+
+            // Dropping the backend only closes the control channel; it never asks swtpm to shut
+            // down.
+            let mut rest = Vec::new();
+            sock.read_to_end(&mut rest).unwrap();
+            assert!(rest.is_empty());
+        });
+
+        let mut swtpm = Swtpm::new(&path).unwrap();
+        let command = [0x80, 0x01, 0x00, 0x00, 0x00, 0x0a, 0x00, 0x00, 0x01, 0x44];
+        assert_eq!(swtpm.execute_command(&command), &command);
+
+        let snapshot: SwtpmSnapshot = serde_json::from_value(swtpm.snapshot().unwrap()).unwrap();
+        assert_eq!(snapshot.permanent.data, vec![PTM_BLOB_TYPE_PERMANENT as u8]);
+        assert_eq!(snapshot.volatile.data, vec![PTM_BLOB_TYPE_VOLATILE as u8]);
+        assert_eq!(snapshot.savestate.data, vec![PTM_BLOB_TYPE_SAVESTATE as u8]);
+
+        drop(swtpm);
+        fake_swtpm.join().unwrap();
+    }
+}
@@ -8,6 +8,7 @@ use std::io;
 use std::io::ErrorKind;
 use std::io::Write;
 use std::os::unix::net::UnixDatagram;
+use std::os::unix::net::UnixStream;
 use std::path::Path;
 use std::path::PathBuf;
 use std::thread;
@@ -28,6 +29,13 @@ use crate::serial_device::SerialInput;
 use crate::serial_device::SerialOptions;
 use crate::serial_device::SerialParameters;
 
+mod relay;
+mod ring_buffer;
+
+pub(crate) use relay::create_relay;
+pub use ring_buffer::read_ring_buffer;
+pub(crate) use ring_buffer::RingBuffer;
+
 pub const SYSTEM_SERIAL_TYPE_NAME: &str = "UnixSocket";
 
 // This wrapper is used in place of the libstd native version because we don't want
@@ -54,6 +62,8 @@ impl ReadNotifier for ConsoleInput {
 
 impl SerialInput for ConsoleInput {}
 
+impl SerialInput for UnixStream {}
+
 /// Abstraction over serial-like devices that can be created given an event and optional input and
 /// output streams.
 pub trait SerialDevice {
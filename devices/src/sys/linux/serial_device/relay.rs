@@ -0,0 +1,438 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Host endpoints that clients can attach to and detach from a serial device at will.
+//!
+//! The device itself only ever sees the two ends of a pair of pipes, so it keeps working whether
+//! or not a client is attached and may live in another process than the endpoint. A relay thread
+//! copies data between the pipes and the current client of the endpoint.
+
+use std::ffi::CStr;
+use std::fs::remove_file;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::mem::zeroed;
+use std::net::TcpListener;
+use std::os::fd::FromRawFd;
+use std::os::fd::OwnedFd;
+use std::os::unix::fs::symlink;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+use std::path::PathBuf;
+use std::thread;
+
+use base::add_fd_flags;
+use base::error;
+use base::info;
+use base::pipe;
+use base::AsRawDescriptor;
+use base::EventToken;
+use base::WaitContext;
+
+use crate::serial_device::Error;
+use crate::serial_device::SerialInput;
+use crate::serial_device::SerialParameters;
+use crate::serial_device::SerialType;
+
+/// Input and output handed to a serial device attached to a relay.
+type RelayedIo = (Box<dyn SerialInput>, Box<dyn Write + Send>);
+
+/// Host side of a serial device.
+enum Endpoint {
+    /// Unix socket listening at the given path, removed when the relay stops.
+    Unix(UnixListener, PathBuf),
+    Tcp(TcpListener),
+    /// Pseudo-terminal master. The slave is kept open so that the master does not hang up while
+    /// no program has the terminal open, and the optional symlink to the slave is removed when the
+    /// relay stops.
+    Pty {
+        master: File,
+        _slave: File,
+        link: Option<PathBuf>,
+    },
+}
+
+impl Endpoint {
+    fn accept(&self) -> io::Result<File> {
+        let fd: OwnedFd = match self {
+            Endpoint::Unix(listener, _) => {
+                let (stream, _) = listener.accept()?;
+                stream.set_nonblocking(true)?;
+                stream.into()
+            }
+            Endpoint::Tcp(listener) => {
+                let (stream, _) = listener.accept()?;
+                stream.set_nonblocking(true)?;
+                stream.into()
+            }
+            Endpoint::Pty { .. } => return Err(io::Error::from(io::ErrorKind::Unsupported)),
+        };
+        Ok(fd.into())
+    }
+
+    fn cleanup(&self) {
+        let path = match self {
+            Endpoint::Unix(_, path) => path,
+            Endpoint::Pty {
+                link: Some(link), ..
+            } => link,
+            _ => return,
+        };
+        if let Err(e) = remove_file(path) {
+            error!("failed to remove {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Bind a Unix socket at `path`, replacing a socket left over by a previous run.
+fn bind_unix(path: &Path) -> io::Result<UnixListener> {
+    if let Ok(metadata) = path.symlink_metadata() {
+        if metadata.file_type().is_socket() {
+            remove_file(path)?;
+        }
+    }
+    UnixListener::bind(path)
+}
+
+/// Allocate a pseudo-terminal in raw mode, and return its master and slave.
+fn open_pty() -> io::Result<(File, File, PathBuf)> {
+    // SAFETY:
+    // Safe because we check the return value and take ownership of the returned descriptor.
+    let master = unsafe {
+        let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY | libc::O_CLOEXEC);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        File::from_raw_fd(fd)
+    };
+
+    // SAFETY:
+    // Safe because `master` is a valid pseudo-terminal master and we check the return values.
+    if unsafe { libc::grantpt(master.as_raw_descriptor()) } < 0
+        || unsafe { libc::unlockpt(master.as_raw_descriptor()) } < 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut name = [0 as libc::c_char; 64];
+    // SAFETY:
+    // Safe because `ptsname_r` only writes up to `name.len()` bytes, and we check the return value.
+    let ret = unsafe { libc::ptsname_r(master.as_raw_descriptor(), name.as_mut_ptr(), name.len()) };
+    if ret != 0 {
+        return Err(io::Error::from_raw_os_error(ret));
+    }
+    // SAFETY:
+    // Safe because `ptsname_r` succeeded, so `name` holds a nul-terminated string.
+    let slave_path = PathBuf::from(
+        unsafe { CStr::from_ptr(name.as_ptr()) }
+            .to_string_lossy()
+            .into_owned(),
+    );
+
+    let slave = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOCTTY)
+        .open(&slave_path)?;
+
+    // Pass the guest output and the user input through unmodified.
+    // SAFETY:
+    // Safe because termios is totally overwritten by tcgetattr, only read by tcsetattr, and we
+    // check the return values.
+    unsafe {
+        let mut termios: libc::termios = zeroed();
+        if libc::tcgetattr(slave.as_raw_descriptor(), &mut termios) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        libc::cfmakeraw(&mut termios);
+        if libc::tcsetattr(slave.as_raw_descriptor(), libc::TCSANOW, &termios) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok((master, slave, slave_path))
+}
+
+/// Link `link` to the pseudo-terminal at `pty`, replacing a symlink left over by a previous run.
+fn link_pty(pty: &Path, link: &Path) -> io::Result<()> {
+    if let Ok(metadata) = link.symlink_metadata() {
+        if metadata.file_type().is_symlink() {
+            remove_file(link)?;
+        }
+    }
+    symlink(pty, link)
+}
+
+fn create_endpoint(param: &SerialParameters) -> Result<Endpoint, Error> {
+    match param.type_ {
+        SerialType::UnixServer => {
+            let path = param.path.as_ref().ok_or(Error::PathRequired)?;
+            let listener = bind_unix(path)
+                .and_then(|listener| listener.set_nonblocking(true).map(|_| listener))
+                .map_err(|e| Error::SocketBind(e, path.display().to_string()))?;
+            info!("serial device listening at {}", path.display());
+            Ok(Endpoint::Unix(listener, path.clone()))
+        }
+        SerialType::TcpServer => {
+            let address = param
+                .address
+                .ok_or_else(|| Error::AddressRequired(param.type_.clone()))?;
+            let listener = TcpListener::bind(address)
+                .and_then(|listener| listener.set_nonblocking(true).map(|_| listener))
+                .map_err(|e| Error::SocketBind(e, address.to_string()))?;
+            info!("serial device listening at {}", address);
+            Ok(Endpoint::Tcp(listener))
+        }
+        SerialType::Pty => {
+            let (master, slave, pty) = open_pty().map_err(Error::PtyCreate)?;
+            add_fd_flags(master.as_raw_descriptor(), libc::O_NONBLOCK)
+                .map_err(|e| Error::PtyCreate(e.into()))?;
+            if let Some(link) = &param.path {
+                link_pty(&pty, link).map_err(|e| Error::FileCreate(e, link.clone()))?;
+            }
+            info!("serial device attached to {}", pty.display());
+            Ok(Endpoint::Pty {
+                master,
+                _slave: slave,
+                link: param.path.clone(),
+            })
+        }
+        _ => Err(Error::InvalidSerialType(param.type_.to_string())),
+    }
+}
+
+/// Write as much of `buf` as `client` accepts without blocking. Whatever does not fit is dropped,
+/// so that a slow client cannot stall the guest.
+fn write_client(client: &mut File, mut buf: &[u8]) -> io::Result<()> {
+    while !buf.is_empty() {
+        match client.write(buf) {
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+            Ok(n) => buf = &buf[n..],
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+fn run_relay(endpoint: Endpoint, mut device_input: File, mut device_output: File) {
+    #[derive(EventToken)]
+    enum Token {
+        Accept,
+        Client,
+        DeviceOutput,
+    }
+
+    let mut client = match &endpoint {
+        Endpoint::Pty { master, .. } => match master.try_clone() {
+            Ok(master) => Some(master),
+            Err(e) => {
+                error!("failed to clone pty master: {}", e);
+                return;
+            }
+        },
+        _ => None,
+    };
+
+    let wait_ctx: WaitContext<Token> =
+        match WaitContext::build_with(&[(&device_output, Token::DeviceOutput)]) {
+            Ok(ctx) => ctx,
+            Err(e) => {
+                error!("failed creating WaitContext {:?}", e);
+                return;
+            }
+        };
+    let added = match (&endpoint, &client) {
+        (Endpoint::Unix(listener, _), _) => wait_ctx.add(listener, Token::Accept),
+        (Endpoint::Tcp(listener), _) => wait_ctx.add(listener, Token::Accept),
+        (Endpoint::Pty { .. }, Some(master)) => wait_ctx.add(master, Token::Client),
+        (Endpoint::Pty { .. }, None) => Ok(()),
+    };
+    if let Err(e) = added {
+        error!("failed adding serial endpoint to WaitContext: {}", e);
+        return;
+    }
+
+    let mut buf = [0u8; 4096];
+    'wait: loop {
+        let events = match wait_ctx.wait() {
+            Ok(events) => events,
+            Err(e) => {
+                error!("Failed to wait for events. {}", e);
+                break;
+            }
+        };
+        for event in events.iter() {
+            match event.token {
+                Token::Accept => match endpoint.accept() {
+                    Ok(stream) => {
+                        if let Err(e) = wait_ctx.add(&stream, Token::Client) {
+                            error!("failed adding serial client to WaitContext: {}", e);
+                            continue;
+                        }
+                        // The previous client, if any, is detached and sees its socket closed.
+                        if let Some(previous) = client.replace(stream) {
+                            let _ = wait_ctx.delete(&previous);
+                        }
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(e) => error!("failed to accept serial client: {}", e),
+                },
+                Token::Client => {
+                    let Some(stream) = client.as_mut() else {
+                        continue;
+                    };
+                    match stream.read(&mut buf) {
+                        Ok(0) => {}
+                        Ok(n) => {
+                            if device_input.write_all(&buf[..n]).is_err() {
+                                // The device is gone.
+                                break 'wait;
+                            }
+                            continue;
+                        }
+                        Err(e)
+                            if e.kind() == io::ErrorKind::WouldBlock
+                                || e.kind() == io::ErrorKind::Interrupted =>
+                        {
+                            continue
+                        }
+                        Err(e) => error!("failed to read from serial client: {}", e),
+                    }
+                    if let Some(stream) = client.take() {
+                        let _ = wait_ctx.delete(&stream);
+                    }
+                }
+                Token::DeviceOutput => {
+                    let n = match device_output.read(&mut buf) {
+                        // The device is gone.
+                        Ok(0) => break 'wait,
+                        Ok(n) => n,
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(e) => {
+                            error!("failed to read serial device output: {}", e);
+                            break 'wait;
+                        }
+                    };
+                    // Output produced while no client is attached is dropped.
+                    if let Some(stream) = client.as_mut() {
+                        if let Err(e) = write_client(stream, &buf[..n]) {
+                            error!("failed to write to serial client: {}", e);
+                            let _ = wait_ctx.delete(&*stream);
+                            client = None;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    endpoint.cleanup();
+}
+
+/// Create the host endpoint described by `param` and a thread relaying data between its clients
+/// and the returned device input and output. The thread stops once the device closes its output.
+pub(crate) fn create_relay(
+    param: &SerialParameters,
+    keep_rds: &mut Vec<base::RawDescriptor>,
+) -> Result<RelayedIo, Error> {
+    let endpoint = create_endpoint(param)?;
+    let (input, relay_input) = pipe().map_err(Error::PipeCreate)?;
+    let (relay_output, output) = pipe().map_err(Error::PipeCreate)?;
+
+    let started = thread::Builder::new()
+        .name("serial_relay".to_string())
+        .spawn(move || run_relay(endpoint, relay_input, relay_output));
+    if let Err(e) = started {
+        return Err(Error::RelaySpawn(e));
+    }
+
+    keep_rds.push(input.as_raw_descriptor());
+    keep_rds.push(output.as_raw_descriptor());
+    Ok((Box::new(input), Box::new(output)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixStream;
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn read_exact_from(reader: &mut impl Read, len: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; len];
+        reader.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn unix_server_reconnect() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("serial.sock");
+        let param = SerialParameters {
+            type_: SerialType::UnixServer,
+            path: Some(path.clone()),
+            ..Default::default()
+        };
+        let mut keep_rds = Vec::new();
+        let (mut input, mut output) = create_relay(&param, &mut keep_rds).unwrap();
+        assert_eq!(keep_rds.len(), 2);
+
+        let mut first = UnixStream::connect(&path).unwrap();
+        first.write_all(b"hello").unwrap();
+        assert_eq!(read_exact_from(&mut input, 5), b"hello");
+        output.write_all(b"world").unwrap();
+        assert_eq!(read_exact_from(&mut first, 5), b"world");
+
+        // A new client replaces the first one, which sees its socket closed.
+        let mut second = UnixStream::connect(&path).unwrap();
+        second.write_all(b"again").unwrap();
+        assert_eq!(read_exact_from(&mut input, 5), b"again");
+        let mut rest = Vec::new();
+        first.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+        output.write_all(b"there").unwrap();
+        assert_eq!(read_exact_from(&mut second, 5), b"there");
+
+        // The socket goes away with the device.
+        drop(output);
+        drop(input);
+        let mut rest = Vec::new();
+        second.read_to_end(&mut rest).unwrap();
+        while path.exists() {
+            thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn pty_attach() {
+        let dir = tempdir().unwrap();
+        let link = dir.path().join("serial.pty");
+        let param = SerialParameters {
+            type_: SerialType::Pty,
+            path: Some(link.clone()),
+            ..Default::default()
+        };
+        let mut keep_rds = Vec::new();
+        let (mut input, mut output) = create_relay(&param, &mut keep_rds).unwrap();
+
+        let mut pty = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(&link)
+            .unwrap();
+        pty.write_all(b"hello\n").unwrap();
+        assert_eq!(read_exact_from(&mut input, 6), b"hello\n");
+        output.write_all(b"world\n").unwrap();
+        assert_eq!(read_exact_from(&mut pty, 6), b"world\n");
+    }
+}
@@ -0,0 +1,200 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Serial output kept in a fixed-size file, overwriting the oldest bytes once it is full.
+//!
+//! The file is mapped shared, so the output stays available to `crosvm serial dump` while the
+//! device runs in another process, and after it exits.
+
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::mem::offset_of;
+use std::mem::size_of;
+use std::path::Path;
+
+use base::MemoryMapping;
+use base::MemoryMappingBuilder;
+use zerocopy::AsBytes;
+use zerocopy::FromBytes;
+use zerocopy::FromZeroes;
+
+use crate::serial_device::Error;
+
+/// Ring buffer size used when `ring-size` is not given, in KiB.
+const DEFAULT_RING_SIZE_KIB: u32 = 64;
+
+const RING_BUFFER_MAGIC: u32 = 0x5253_5643; // "CVSR"
+const RING_BUFFER_VERSION: u32 = 1;
+
+/// Header at the start of the file, followed by `size` bytes of data.
+#[derive(Copy, Clone, Debug, AsBytes, FromZeroes, FromBytes)]
+#[repr(C)]
+struct RingBufferHeader {
+    magic: u32,
+    version: u32,
+    size: u64,
+    /// Total number of bytes ever written. The next byte goes at `head % size`.
+    head: u64,
+}
+
+const HEADER_SIZE: usize = size_of::<RingBufferHeader>();
+
+fn mmap_error(e: base::MmapError) -> io::Error {
+    io::Error::other(e)
+}
+
+/// Serial output writing to a ring buffer file.
+pub(crate) struct RingBuffer {
+    mapping: MemoryMapping,
+    size: usize,
+    head: u64,
+}
+
+impl RingBuffer {
+    /// Create the ring buffer file at `path`, holding the last `size_kib` KiB of output. Any
+    /// previous content of the file is discarded.
+    pub(crate) fn create(path: &Path, size_kib: Option<u32>) -> Result<RingBuffer, Error> {
+        let size_kib = size_kib.unwrap_or(DEFAULT_RING_SIZE_KIB);
+        if size_kib == 0 {
+            return Err(Error::InvalidRingSize(size_kib));
+        }
+        let size = size_kib as usize * 1024;
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .map_err(|e| Error::FileCreate(e, path.into()))?;
+        file.set_len((HEADER_SIZE + size) as u64)
+            .map_err(|e| Error::FileCreate(e, path.into()))?;
+        let mapping = MemoryMappingBuilder::new(HEADER_SIZE + size)
+            .from_file(&file)
+            .build()
+            .map_err(|e| Error::RingBufferMap(e, path.into()))?;
+
+        let header = RingBufferHeader {
+            magic: RING_BUFFER_MAGIC,
+            version: RING_BUFFER_VERSION,
+            size: size as u64,
+            head: 0,
+        };
+        mapping
+            .write_obj(header, 0)
+            .map_err(|e| Error::RingBufferMap(e, path.into()))?;
+
+        Ok(RingBuffer {
+            mapping,
+            size,
+            head: 0,
+        })
+    }
+}
+
+impl io::Write for RingBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Only the end of a write larger than the ring buffer survives.
+        let skip = buf.len().saturating_sub(self.size);
+        let data = &buf[skip..];
+        let start = ((self.head + skip as u64) % self.size as u64) as usize;
+        let (first, second) = data.split_at(data.len().min(self.size - start));
+
+        self.mapping
+            .write_slice(first, HEADER_SIZE + start)
+            .map_err(mmap_error)?;
+        self.mapping
+            .write_slice(second, HEADER_SIZE)
+            .map_err(mmap_error)?;
+
+        // Publish the new head once the data is in place.
+        self.head += buf.len() as u64;
+        self.mapping
+            .write_obj_volatile(self.head, offset_of!(RingBufferHeader, head))
+            .map_err(mmap_error)?;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Return the output kept in the ring buffer file at `path`, oldest byte first.
+pub fn read_ring_buffer(path: &Path) -> io::Result<Vec<u8>> {
+    let invalid = |msg: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), msg),
+        )
+    };
+
+    let content = fs::read(path)?;
+    let header = RingBufferHeader::read_from_prefix(&content)
+        .ok_or_else(|| invalid("file too small for a ring buffer"))?;
+    if header.magic != RING_BUFFER_MAGIC {
+        return Err(invalid("not a serial ring buffer"));
+    }
+    if header.version != RING_BUFFER_VERSION {
+        return Err(invalid("unsupported ring buffer version"));
+    }
+    let data = &content[HEADER_SIZE..];
+    if header.size == 0 || header.size != data.len() as u64 {
+        return Err(invalid("ring buffer size does not match the file size"));
+    }
+
+    let len = header.head.min(header.size) as usize;
+    let start = ((header.head - len as u64) % header.size) as usize;
+    let (first, second) = if start + len <= data.len() {
+        (&data[start..start + len], &data[..0])
+    } else {
+        (&data[start..], &data[..start + len - data.len()])
+    };
+    Ok([first, second].concat())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn keeps_last_bytes() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("serial.ring");
+        let mut ring = RingBuffer::create(&path, Some(1)).unwrap();
+        assert!(read_ring_buffer(&path).unwrap().is_empty());
+
+        ring.write_all(b"hello").unwrap();
+        assert_eq!(read_ring_buffer(&path).unwrap(), b"hello");
+
+        // Wrap around the end of the buffer.
+        let filler: Vec<u8> = (0..1021).map(|i| b'a' + (i % 26) as u8).collect();
+        ring.write_all(&filler).unwrap();
+        ring.write_all(b"world").unwrap();
+        let content = read_ring_buffer(&path).unwrap();
+        assert_eq!(content.len(), 1024);
+        assert_eq!(&content[..1019], &filler[2..]);
+        assert_eq!(&content[1019..], b"world");
+
+        // A write larger than the buffer only keeps its end.
+        let large: Vec<u8> = (0..3000).map(|i| (i % 251) as u8).collect();
+        ring.write_all(&large).unwrap();
+        assert_eq!(read_ring_buffer(&path).unwrap(), &large[3000 - 1024..]);
+    }
+
+    #[test]
+    fn invalid_file() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("serial.ring");
+        fs::write(&path, b"not a ring buffer, but long enough").unwrap();
+        assert!(read_ring_buffer(&path).is_err());
+        assert!(RingBuffer::create(&path, Some(0)).is_err());
+    }
+}
@@ -0,0 +1,358 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! TPM 2.0 Command Response Buffer (CRB) interface, as described by the TCG PC Client Platform TPM
+//! Profile specification.
+//!
+//! Unlike virtio-tpm, this interface is found by firmware (e.g. OVMF built with TPM2 support) at a
+//! fixed MMIO address, which allows measured boot. Only locality 0 is supported and commands are
+//! executed synchronously when the guest writes `CTRL_START`, so cancellation is never needed.
+
+use acpi_tables::aml;
+use acpi_tables::aml::Aml;
+use anyhow::Context;
+use base::error;
+use base::warn;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::pci::CrosvmDeviceId;
+use crate::virtio::TpmBackend;
+use crate::BusAccessInfo;
+use crate::BusDevice;
+use crate::DeviceId;
+use crate::Suspendable;
+
+/// Guest physical address of the CRB interface expected by firmware.
+pub const TPM_CRB_BASE: u64 = 0xfed4_0000;
+/// Size of the locality 0 register space, including the data buffer.
+pub const TPM_CRB_SIZE: u64 = 0x1000;
+
+const CRB_LOC_STATE: u64 = 0x00;
+const CRB_LOC_CTRL: u64 = 0x08;
+const CRB_LOC_STS: u64 = 0x0c;
+const CRB_INTF_ID: u64 = 0x30;
+/// Offset of the control area that the ACPI TPM2 table points to.
+pub const CRB_CTRL_REQ: u64 = 0x40;
+const CRB_CTRL_STS: u64 = 0x44;
+const CRB_CTRL_CANCEL: u64 = 0x48;
+const CRB_CTRL_START: u64 = 0x4c;
+const CRB_CTRL_CMD_SIZE: u64 = 0x58;
+const CRB_CTRL_CMD_LADDR: u64 = 0x5c;
+const CRB_CTRL_CMD_HADDR: u64 = 0x60;
+const CRB_CTRL_RSP_SIZE: u64 = 0x64;
+const CRB_CTRL_RSP_ADDR: u64 = 0x68;
+const CRB_DATA_BUFFER: u64 = 0x80;
+
+const CRB_DATA_BUFFER_SIZE: usize = (TPM_CRB_SIZE - CRB_DATA_BUFFER) as usize;
+
+const LOC_STATE_LOC_ASSIGNED: u32 = 1 << 1;
+const LOC_STATE_REG_VALID_STS: u32 = 1 << 7;
+const LOC_CTRL_REQUEST_ACCESS: u32 = 1 << 0;
+const LOC_CTRL_RELINQUISH: u32 = 1 << 1;
+const LOC_STS_GRANTED: u32 = 1 << 0;
+const CTRL_REQ_CMD_READY: u32 = 1 << 0;
+const CTRL_REQ_GO_IDLE: u32 = 1 << 1;
+const CTRL_STS_TPM_IDLE: u32 = 1 << 1;
+const CTRL_START_INVOKE: u32 = 1 << 0;
+
+// CRB interface type and version, 64-byte transfers, CRB capability and CRB interface selected.
+const INTF_ID_LOW: u32 = 0x1 | (0x1 << 4) | (0x3 << 11) | (1 << 14) | (0x1 << 17);
+// Vendor and device ID.
+const INTF_ID_HIGH: u32 = 0x1014 | (0x0001 << 16);
+
+// Offset of the size field in a TPM command header.
+const TPM_HEADER_SIZE_OFFSET: usize = 2;
+const TPM_HEADER_SIZE: usize = 10;
+
+// The response of TPM_RC_FAILURE
+const TPM_RC_FAILURE_RESPONSE: &[u8] = &[
+    0x80, 0x01, // TPM_ST_NO_SESSIONS
+    0x00, 0x00, 0x00, 0x0A, // Header Size = 10
+    0x00, 0x00, 0x01, 0x01, // TPM_RC_FAILURE
+];
+
+#[derive(Serialize, Deserialize)]
+struct TpmCrbSnapshot {
+    regs: Vec<u8>,
+    backend: serde_json::Value,
+}
+
+/// TPM CRB MMIO device.
+pub struct TpmCrb {
+    backend: Box<dyn TpmBackend>,
+    // Register space followed by the data buffer, as seen by the guest.
+    regs: Vec<u8>,
+}
+
+impl TpmCrb {
+    pub fn new(backend: Box<dyn TpmBackend>) -> TpmCrb {
+        let mut crb = TpmCrb {
+            backend,
+            regs: vec![0; TPM_CRB_SIZE as usize],
+        };
+        crb.reset();
+        crb
+    }
+
+    fn reset(&mut self) {
+        self.regs.fill(0);
+        let buffer_addr = TPM_CRB_BASE + CRB_DATA_BUFFER;
+        self.write_reg(CRB_LOC_STATE, LOC_STATE_REG_VALID_STS);
+        self.write_reg(CRB_CTRL_STS, CTRL_STS_TPM_IDLE);
+        self.write_reg(CRB_INTF_ID, INTF_ID_LOW);
+        self.write_reg(CRB_INTF_ID + 4, INTF_ID_HIGH);
+        self.write_reg(CRB_CTRL_CMD_SIZE, CRB_DATA_BUFFER_SIZE as u32);
+        self.write_reg(CRB_CTRL_CMD_LADDR, buffer_addr as u32);
+        self.write_reg(CRB_CTRL_CMD_HADDR, (buffer_addr >> 32) as u32);
+        self.write_reg(CRB_CTRL_RSP_SIZE, CRB_DATA_BUFFER_SIZE as u32);
+        self.write_reg(CRB_CTRL_RSP_ADDR, buffer_addr as u32);
+        self.write_reg(CRB_CTRL_RSP_ADDR + 4, (buffer_addr >> 32) as u32);
+    }
+
+    fn read_reg(&self, offset: u64) -> u32 {
+        let offset = offset as usize;
+        u32::from_le_bytes(self.regs[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn write_reg(&mut self, offset: u64, val: u32) {
+        let offset = offset as usize;
+        self.regs[offset..offset + 4].copy_from_slice(&val.to_le_bytes());
+    }
+
+    fn execute_command(&mut self) {
+        let buffer = &mut self.regs[CRB_DATA_BUFFER as usize..];
+        let size = u32::from_be_bytes(
+            buffer[TPM_HEADER_SIZE_OFFSET..TPM_HEADER_SIZE_OFFSET + 4]
+                .try_into()
+                .unwrap(),
+        ) as usize;
+        if !(TPM_HEADER_SIZE..=CRB_DATA_BUFFER_SIZE).contains(&size) {
+            error!("tpm-crb: invalid command size {}", size);
+            buffer[..TPM_HEADER_SIZE].copy_from_slice(TPM_RC_FAILURE_RESPONSE);
+            return;
+        }
+
+        let mut response = self.backend.execute_command(&buffer[..size]);
+        if response.len() > CRB_DATA_BUFFER_SIZE {
+            error!(
+                "tpm-crb: response is too long: {} > {} bytes",
+                response.len(),
+                CRB_DATA_BUFFER_SIZE
+            );
+            response = TPM_RC_FAILURE_RESPONSE;
+        }
+        buffer[..response.len()].copy_from_slice(response);
+    }
+
+    fn write_reg_access(&mut self, offset: u64, val: u32) {
+        match offset {
+            CRB_LOC_CTRL => {
+                let mut loc_state = self.read_reg(CRB_LOC_STATE);
+                if val & LOC_CTRL_REQUEST_ACCESS != 0 {
+                    loc_state |= LOC_STATE_LOC_ASSIGNED;
+                    self.write_reg(CRB_LOC_STS, LOC_STS_GRANTED);
+                } else if val & LOC_CTRL_RELINQUISH != 0 {
+                    loc_state &= !LOC_STATE_LOC_ASSIGNED;
+                    self.write_reg(CRB_LOC_STS, 0);
+                }
+                self.write_reg(CRB_LOC_STATE, loc_state);
+            }
+            CRB_CTRL_REQ => {
+                let mut ctrl_sts = self.read_reg(CRB_CTRL_STS);
+                if val & CTRL_REQ_CMD_READY != 0 {
+                    ctrl_sts &= !CTRL_STS_TPM_IDLE;
+                } else if val & CTRL_REQ_GO_IDLE != 0 {
+                    ctrl_sts |= CTRL_STS_TPM_IDLE;
+                }
+                self.write_reg(CRB_CTRL_STS, ctrl_sts);
+            }
+            CRB_CTRL_CANCEL => self.write_reg(CRB_CTRL_CANCEL, val),
+            CRB_CTRL_START => {
+                if val & CTRL_START_INVOKE != 0 {
+                    // The command completes before the guest can observe CTRL_START, so it is
+                    // never left set.
+                    self.execute_command();
+                }
+            }
+            _ => warn!("tpm-crb: write to read-only register {:#x}", offset),
+        }
+    }
+}
+
+impl BusDevice for TpmCrb {
+    fn device_id(&self) -> DeviceId {
+        CrosvmDeviceId::TpmCrb.into()
+    }
+
+    fn debug_label(&self) -> String {
+        "tpm-crb".to_owned()
+    }
+
+    fn read(&mut self, info: BusAccessInfo, data: &mut [u8]) {
+        let offset = info.offset as usize;
+        match self.regs.get(offset..offset + data.len()) {
+            Some(src) => data.copy_from_slice(src),
+            None => warn!("tpm-crb: bad read at {:#x}", info.offset),
+        }
+    }
+
+    fn write(&mut self, info: BusAccessInfo, data: &[u8]) {
+        if info.offset >= CRB_DATA_BUFFER {
+            let offset = info.offset as usize;
+            match self.regs.get_mut(offset..offset + data.len()) {
+                Some(dst) => dst.copy_from_slice(data),
+                None => warn!("tpm-crb: bad write at {:#x}", info.offset),
+            }
+            return;
+        }
+
+        if data.len() != 4 || info.offset % 4 != 0 {
+            warn!(
+                "tpm-crb: unsupported register write of {} bytes at {:#x}",
+                data.len(),
+                info.offset
+            );
+            return;
+        }
+        self.write_reg_access(info.offset, u32::from_le_bytes(data.try_into().unwrap()));
+    }
+}
+
+impl Aml for TpmCrb {
+    fn to_aml_bytes(&self, bytes: &mut Vec<u8>) {
+        aml::Device::new(
+            "TPM_".into(),
+            vec![
+                &aml::Name::new("_HID".into(), &"MSFT0101"),
+                &aml::Name::new(
+                    "_CRS".into(),
+                    &aml::ResourceTemplate::new(vec![&aml::Memory32Fixed::new(
+                        true,
+                        TPM_CRB_BASE as u32,
+                        TPM_CRB_SIZE as u32,
+                    )]),
+                ),
+                &aml::Method::new("_STA".into(), 0, false, vec![&aml::Return::new(&0xfu8)]),
+            ],
+        )
+        .to_aml_bytes(bytes);
+    }
+}
+
+impl Suspendable for TpmCrb {
+    fn snapshot(&mut self) -> anyhow::Result<serde_json::Value> {
+        let snapshot = TpmCrbSnapshot {
+            regs: self.regs.clone(),
+            backend: self.backend.snapshot()?,
+        };
+        serde_json::to_value(snapshot).context("failed to serialize tpm-crb state")
+    }
+
+    fn restore(&mut self, data: serde_json::Value) -> anyhow::Result<()> {
+        let snapshot: TpmCrbSnapshot =
+            serde_json::from_value(data).context("failed to deserialize tpm-crb state")?;
+        anyhow::ensure!(
+            snapshot.regs.len() == self.regs.len(),
+            "tpm-crb snapshot has {} bytes of registers, expected {}",
+            snapshot.regs.len(),
+            self.regs.len()
+        );
+        self.backend.restore(snapshot.backend)?;
+        self.regs = snapshot.regs;
+        Ok(())
+    }
+
+    fn sleep(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn wake(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Backend that answers every command with a fixed response.
+    struct FakeBackend;
+
+    const RESPONSE: &[u8] = &[0x80, 0x01, 0x00, 0x00, 0x00, 0x0a, 0x00, 0x00, 0x00, 0x00];
+
+    impl TpmBackend for FakeBackend {
+        fn execute_command<'a>(&'a mut self, command: &[u8]) -> &'a [u8] {
+            assert_eq!(command.len(), 12);
+            RESPONSE
+        }
+    }
+
+    fn off(offset: u64) -> BusAccessInfo {
+        BusAccessInfo {
+            offset,
+            address: TPM_CRB_BASE + offset,
+            id: 0,
+        }
+    }
+
+    fn read32(crb: &mut TpmCrb, offset: u64) -> u32 {
+        let mut data = [0u8; 4];
+        crb.read(off(offset), &mut data);
+        u32::from_le_bytes(data)
+    }
+
+    #[test]
+    fn locality_and_idle() {
+        let mut crb = TpmCrb::new(Box::new(FakeBackend));
+        assert_eq!(read32(&mut crb, CRB_LOC_STS), 0);
+        crb.write(off(CRB_LOC_CTRL), &LOC_CTRL_REQUEST_ACCESS.to_le_bytes());
+        assert_eq!(read32(&mut crb, CRB_LOC_STS), LOC_STS_GRANTED);
+        assert_ne!(read32(&mut crb, CRB_LOC_STATE) & LOC_STATE_LOC_ASSIGNED, 0);
+
+        assert_ne!(read32(&mut crb, CRB_CTRL_STS) & CTRL_STS_TPM_IDLE, 0);
+        crb.write(off(CRB_CTRL_REQ), &CTRL_REQ_CMD_READY.to_le_bytes());
+        assert_eq!(read32(&mut crb, CRB_CTRL_STS) & CTRL_STS_TPM_IDLE, 0);
+        crb.write(off(CRB_CTRL_REQ), &CTRL_REQ_GO_IDLE.to_le_bytes());
+        assert_ne!(read32(&mut crb, CRB_CTRL_STS) & CTRL_STS_TPM_IDLE, 0);
+
+        crb.write(off(CRB_LOC_CTRL), &LOC_CTRL_RELINQUISH.to_le_bytes());
+        assert_eq!(read32(&mut crb, CRB_LOC_STS), 0);
+    }
+
+    #[test]
+    fn command_response() {
+        let mut crb = TpmCrb::new(Box::new(FakeBackend));
+        assert_eq!(
+            read32(&mut crb, CRB_CTRL_CMD_LADDR) as u64,
+            TPM_CRB_BASE + CRB_DATA_BUFFER
+        );
+
+        let command = [
+            0x80, 0x01, 0x00, 0x00, 0x00, 0x0c, 0x00, 0x00, 0x01, 0x44, 0x00, 0x00,
+        ];
+        crb.write(off(CRB_DATA_BUFFER), &command);
+        crb.write(off(CRB_CTRL_START), &CTRL_START_INVOKE.to_le_bytes());
+        assert_eq!(read32(&mut crb, CRB_CTRL_START), 0);
+
+        let mut response = [0u8; 10];
+        crb.read(off(CRB_DATA_BUFFER), &mut response);
+        assert_eq!(response, RESPONSE);
+    }
+
+    #[test]
+    fn invalid_command_size() {
+        let mut crb = TpmCrb::new(Box::new(FakeBackend));
+
+        // The header claims a command larger than the data buffer.
+        let command = [0x80, 0x01, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x01, 0x44];
+        crb.write(off(CRB_DATA_BUFFER), &command);
+        crb.write(off(CRB_CTRL_START), &CTRL_START_INVOKE.to_le_bytes());
+        assert_eq!(read32(&mut crb, CRB_CTRL_START), 0);
+
+        let mut response = [0u8; 10];
+        crb.read(off(CRB_DATA_BUFFER), &mut response);
+        assert_eq!(response, TPM_RC_FAILURE_RESPONSE);
+    }
+}
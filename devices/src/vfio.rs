@@ -45,6 +45,13 @@ use resources::Alloc;
 use resources::Error as ResourcesError;
 use sync::Mutex;
 use thiserror::Error;
+use vfio_sys::iommufd::iommu_fault_alloc;
+use vfio_sys::iommufd::iommu_hwpt_alloc;
+use vfio_sys::iommufd::iommu_ioas_alloc;
+use vfio_sys::iommufd::iommu_vfio_ioas;
+use vfio_sys::iommufd::IOMMU_HWPT_DATA_NONE;
+use vfio_sys::iommufd::IOMMU_HWPT_FAULT_ID_VALID;
+use vfio_sys::iommufd::IOMMU_VFIO_IOAS_SET;
 use vfio_sys::vfio::vfio_acpi_dsm;
 use vfio_sys::vfio::VFIO_IRQ_SET_DATA_BOOL;
 use vfio_sys::*;
@@ -84,6 +91,14 @@ pub enum VfioError {
     IommuGetCapInfo,
     #[error("failed to get IOMMU info from host: {0}")]
     IommuGetInfo(Error),
+    #[error("failed to allocate iommufd fault queue: {0}")]
+    IommufdAllocFaultQueue(Error),
+    #[error("failed to allocate iommufd page table: {0}")]
+    IommufdAllocHwpt(Error),
+    #[error("failed to allocate iommufd IOAS: {0}")]
+    IommufdAllocIoas(Error),
+    #[error("failed to set the VFIO compatibility IOAS of iommufd: {0}")]
+    IommufdSetVfioIoas(Error),
     #[error("failed to attach device to pKVM pvIOMMU: {0}")]
     KvmPviommuSetConfig(Error),
     #[error("failed to set KVM vfio device's attribute: {0}")]
@@ -92,8 +107,14 @@ pub enum VfioError {
     NoRescAlloc,
     #[error("failed to open /dev/vfio/vfio container: {0}")]
     OpenContainer(io::Error),
+    #[error("failed to open {1} device: {0}")]
+    OpenDevice(io::Error, String),
     #[error("failed to open {1} group: {0}")]
     OpenGroup(io::Error, String),
+    #[error("failed to open /dev/iommu: {0}")]
+    OpenIommufd(io::Error),
+    #[error("failed to read {1} directory: {0}")]
+    ReadDir(io::Error, PathBuf),
     #[error("failed to read {1} link: {0}")]
     ReadLink(io::Error, PathBuf),
     #[error("resources error: {0}")]
@@ -112,6 +133,10 @@ pub enum VfioError {
         "vfio API version doesn't match with VFIO_API_VERSION defined in vfio_sys/src/vfio.rs"
     )]
     VfioApiVersion,
+    #[error("failed to attach vfio device to iommufd page table: {0}")]
+    VfioDeviceAttachIommufd(Error),
+    #[error("failed to bind vfio device to iommufd: {0}")]
+    VfioDeviceBindIommufd(Error),
     #[error("failed to get vfio device's info or info doesn't match: {0}")]
     VfioDeviceGetInfo(Error),
     #[error("failed to get vfio device's region info: {0}")]
@@ -151,6 +176,39 @@ enum KvmVfioGroupOps {
     Delete,
 }
 
+// Adds a VFIO group, or a VFIO device opened through its cdev, to the KVM vfio device, or removes
+// it.
+fn kvm_device_set_file(
+    kvm_vfio_file: &SafeDescriptor,
+    descriptor: RawDescriptor,
+    ops: KvmVfioGroupOps,
+) -> Result<()> {
+    let descriptor_ptr = &descriptor as *const i32;
+    let vfio_dev_attr = match ops {
+        KvmVfioGroupOps::Add => kvm_sys::kvm_device_attr {
+            flags: 0,
+            group: kvm_sys::KVM_DEV_VFIO_FILE,
+            attr: kvm_sys::KVM_DEV_VFIO_FILE_ADD as u64,
+            addr: descriptor_ptr as u64,
+        },
+        KvmVfioGroupOps::Delete => kvm_sys::kvm_device_attr {
+            flags: 0,
+            group: kvm_sys::KVM_DEV_VFIO_FILE,
+            attr: kvm_sys::KVM_DEV_VFIO_FILE_DEL as u64,
+            addr: descriptor_ptr as u64,
+        },
+    };
+
+    // SAFETY:
+    // Safe as we are the owner of vfio_dev_descriptor and vfio_dev_attr which are valid value,
+    // and we verify the return value.
+    if 0 != unsafe { ioctl_with_ref(kvm_vfio_file, kvm_sys::KVM_SET_DEVICE_ATTR, &vfio_dev_attr) } {
+        return Err(VfioError::KvmSetDeviceAttr(get_error()));
+    }
+
+    Ok(())
+}
+
 #[derive(Debug)]
 pub struct KvmVfioPviommu {
     file: File,
@@ -312,6 +370,8 @@ pub struct VfioContainer {
     container: File,
     groups: HashMap<u32, Arc<Mutex<VfioGroup>>>,
     iommu_type: Option<IommuType>,
+    // IOAS of the iommufd the container is backed by, see `new_iommufd`.
+    iommufd_ioas: Option<u32>,
 }
 
 fn extract_vfio_struct<T>(bytes: &[u8], offset: usize) -> Option<T>
@@ -346,9 +406,91 @@ impl VfioContainer {
             container,
             groups: HashMap::new(),
             iommu_type: None,
+            iommufd_ioas: None,
         })
     }
 
+    /// Construct a VfioContainer backed by a new iommufd IOAS, for devices opened through their
+    /// VFIO cdev with `VfioDevice::new_passthrough_iommufd`. The IOAS is made the VFIO
+    /// compatibility IOAS of the iommufd, so the type1 ioctls of the container operate on it.
+    pub fn new_iommufd() -> Result<Self> {
+        let iommufd = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/iommu")
+            .map_err(VfioError::OpenIommufd)?;
+
+        let mut ioas_alloc = iommu_ioas_alloc {
+            size: mem::size_of::<iommu_ioas_alloc>() as u32,
+            ..Default::default()
+        };
+        // SAFETY:
+        // Safe as we are the owner of iommufd and ioas_alloc which are valid value, and we verify
+        // the return value.
+        let ret = unsafe { ioctl_with_mut_ref(&iommufd, IOMMU_IOAS_ALLOC, &mut ioas_alloc) };
+        if ret < 0 {
+            return Err(VfioError::IommufdAllocIoas(get_error()));
+        }
+
+        let vfio_ioas = iommu_vfio_ioas {
+            size: mem::size_of::<iommu_vfio_ioas>() as u32,
+            ioas_id: ioas_alloc.out_ioas_id,
+            op: IOMMU_VFIO_IOAS_SET,
+            __reserved: 0,
+        };
+        // SAFETY:
+        // Safe as we are the owner of iommufd and vfio_ioas which are valid value, and we verify
+        // the return value.
+        let ret = unsafe { ioctl_with_ref(&iommufd, IOMMU_VFIO_IOAS, &vfio_ioas) };
+        if ret < 0 {
+            return Err(VfioError::IommufdSetVfioIoas(get_error()));
+        }
+
+        let mut container = Self::new_from_container(iommufd)?;
+        container.set_iommu_checked(IommuType::Type1V2)?;
+        container.iommufd_ioas = Some(ioas_alloc.out_ioas_id);
+        Ok(container)
+    }
+
+    // Allocates a page table of the IOAS for the given iommufd device, which reports the I/O page
+    // faults of the device to a new fault queue. Returns the ID of the page table and the fault
+    // queue.
+    fn iommufd_alloc_fault_hwpt(&self, ioas_id: u32, dev_id: u32) -> Result<(u32, File)> {
+        let mut fault_alloc = iommu_fault_alloc {
+            size: mem::size_of::<iommu_fault_alloc>() as u32,
+            ..Default::default()
+        };
+        // SAFETY:
+        // Safe as we are the owner of self and fault_alloc which are valid value, and we verify
+        // the return value.
+        let ret = unsafe { ioctl_with_mut_ref(self, IOMMU_FAULT_QUEUE_ALLOC, &mut fault_alloc) };
+        if ret < 0 {
+            return Err(VfioError::IommufdAllocFaultQueue(get_error()));
+        }
+        // SAFETY:
+        // Safe as out_fault_fd is a new descriptor owned by nothing else.
+        let fault_queue = unsafe { File::from_raw_descriptor(fault_alloc.out_fault_fd as i32) };
+
+        let mut hwpt_alloc = iommu_hwpt_alloc {
+            size: mem::size_of::<iommu_hwpt_alloc>() as u32,
+            flags: IOMMU_HWPT_FAULT_ID_VALID,
+            dev_id,
+            pt_id: ioas_id,
+            data_type: IOMMU_HWPT_DATA_NONE,
+            fault_id: fault_alloc.out_fault_id,
+            ..Default::default()
+        };
+        // SAFETY:
+        // Safe as we are the owner of self and hwpt_alloc which are valid value, and we verify
+        // the return value.
+        let ret = unsafe { ioctl_with_mut_ref(self, IOMMU_HWPT_ALLOC, &mut hwpt_alloc) };
+        if ret < 0 {
+            return Err(VfioError::IommufdAllocHwpt(get_error()));
+        }
+
+        Ok((hwpt_alloc.out_hwpt_id, fault_queue))
+    }
+
     fn is_group_set(&self, group_id: u32) -> bool {
         self.groups.contains_key(&group_id)
     }
@@ -785,33 +927,7 @@ impl VfioGroup {
         kvm_vfio_file: &SafeDescriptor,
         ops: KvmVfioGroupOps,
     ) -> Result<()> {
-        let group_descriptor = self.as_raw_descriptor();
-        let group_descriptor_ptr = &group_descriptor as *const i32;
-        let vfio_dev_attr = match ops {
-            KvmVfioGroupOps::Add => kvm_sys::kvm_device_attr {
-                flags: 0,
-                group: kvm_sys::KVM_DEV_VFIO_GROUP,
-                attr: kvm_sys::KVM_DEV_VFIO_GROUP_ADD as u64,
-                addr: group_descriptor_ptr as u64,
-            },
-            KvmVfioGroupOps::Delete => kvm_sys::kvm_device_attr {
-                flags: 0,
-                group: kvm_sys::KVM_DEV_VFIO_GROUP,
-                attr: kvm_sys::KVM_DEV_VFIO_GROUP_DEL as u64,
-                addr: group_descriptor_ptr as u64,
-            },
-        };
-
-        // SAFETY:
-        // Safe as we are the owner of vfio_dev_descriptor and vfio_dev_attr which are valid value,
-        // and we verify the return value.
-        if 0 != unsafe {
-            ioctl_with_ref(kvm_vfio_file, kvm_sys::KVM_SET_DEVICE_ATTR, &vfio_dev_attr)
-        } {
-            return Err(VfioError::KvmSetDeviceAttr(get_error()));
-        }
-
-        Ok(())
+        kvm_device_set_file(kvm_vfio_file, self.as_raw_descriptor(), ops)
     }
 
     fn get_device(&self, name: &str) -> Result<File> {
@@ -980,8 +1096,11 @@ pub struct VfioDevice {
     name: String,
     container: Arc<Mutex<VfioContainer>>,
     dev_type: VfioDeviceType,
-    group_descriptor: RawDescriptor,
+    // None when the device is opened through its cdev rather than its group.
+    group_descriptor: Option<RawDescriptor>,
     group_id: u32,
+    // I/O page fault queue of the iommufd page table the device is attached to, if any.
+    fault_queue: Option<File>,
     // vec for vfio device's regions
     regions: Vec<VfioRegion>,
     num_irqs: u32,
@@ -1045,8 +1164,9 @@ impl VfioDevice {
             name,
             container,
             dev_type,
-            group_descriptor,
+            group_descriptor: Some(group_descriptor),
             group_id,
+            fault_queue: None,
             regions,
             num_irqs: dev_info.num_irqs,
             iova_alloc: Arc::new(Mutex::new(iova_alloc)),
@@ -1055,6 +1175,128 @@ impl VfioDevice {
         })
     }
 
+    /// Create a new vfio device opened through its VFIO cdev and bound to the iommufd backing
+    /// `container`, which must come from `VfioContainer::new_iommufd`. The device gets an I/O
+    /// page fault queue, see `take_fault_queue`, if the host IOMMU can report its faults.
+    /// sysfspath specify the vfio device path in sys file system.
+    pub fn new_passthrough_iommufd<P: AsRef<Path>>(
+        sysfspath: &P,
+        vm: &impl Vm,
+        container: Arc<Mutex<VfioContainer>>,
+        dt_symbol: Option<String>,
+    ) -> Result<Self> {
+        let group_id = VfioGroup::get_group_id(sysfspath)?;
+        let name_osstr = sysfspath
+            .as_ref()
+            .file_name()
+            .ok_or(VfioError::InvalidPath)?;
+        let name_str = name_osstr.to_str().ok_or(VfioError::InvalidPath)?;
+        let name = String::from(name_str);
+
+        let dev_path = Self::get_cdev_path(sysfspath)?;
+        let dev = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&dev_path)
+            .map_err(|e| VfioError::OpenDevice(e, dev_path.display().to_string()))?;
+
+        let fault_queue = {
+            let container = container.lock();
+            let ioas_id = container.iommufd_ioas.ok_or(VfioError::InvalidOperation)?;
+
+            let mut bind = vfio_device_bind_iommufd {
+                argsz: mem::size_of::<vfio_device_bind_iommufd>() as u32,
+                flags: 0,
+                iommufd: container.as_raw_descriptor(),
+                out_devid: 0,
+            };
+            // SAFETY:
+            // Safe as we are the owner of dev and bind which are valid value, and we verify the
+            // return value.
+            let ret = unsafe { ioctl_with_mut_ref(&dev, VFIO_DEVICE_BIND_IOMMUFD, &mut bind) };
+            if ret < 0 {
+                return Err(VfioError::VfioDeviceBindIommufd(get_error()));
+            }
+
+            // Faults can only be reported from a page table of the device's own, and attaching to
+            // it fails if the IOMMU or the device can't handle I/O page faults. Fall back to the
+            // IOAS, whose faults go unreported, in that case.
+            match container
+                .iommufd_alloc_fault_hwpt(ioas_id, bind.out_devid)
+                .and_then(|(hwpt_id, fault_queue)| {
+                    Self::attach_iommufd_pt(&dev, hwpt_id)?;
+                    Ok(fault_queue)
+                }) {
+                Ok(fault_queue) => Some(fault_queue),
+                Err(e) => {
+                    warn!(
+                        "{}: translation faults won't be reported to the guest: {}",
+                        name, e
+                    );
+                    Self::attach_iommufd_pt(&dev, ioas_id)?;
+                    None
+                }
+            }
+        };
+
+        let kvm_vfio_file = KVM_VFIO_FILE
+            .get_or_try_init(|| vm.create_device(DeviceKind::Vfio))
+            .map_err(VfioError::CreateVfioKvmDevice)?;
+        kvm_device_set_file(kvm_vfio_file, dev.as_raw_descriptor(), KvmVfioGroupOps::Add)?;
+
+        let (dev_info, dev_type) = Self::get_device_info(&dev)?;
+        let regions = Self::get_regions(&dev, dev_info.num_regions)?;
+
+        let iova_ranges = container.lock().vfio_iommu_iova_get_iova_ranges()?;
+        let iova_alloc = AddressAllocator::new_from_list(iova_ranges, None, None)
+            .map_err(VfioError::Resources)?;
+
+        Ok(VfioDevice {
+            dev,
+            name,
+            container,
+            dev_type,
+            group_descriptor: None,
+            group_id,
+            fault_queue,
+            regions,
+            num_irqs: dev_info.num_irqs,
+            iova_alloc: Arc::new(Mutex::new(iova_alloc)),
+            dt_symbol,
+            pviommu: None,
+        })
+    }
+
+    // Gets the path of the VFIO cdev of the device, e.g. /dev/vfio/devices/vfio0.
+    fn get_cdev_path<P: AsRef<Path>>(sysfspath: P) -> Result<PathBuf> {
+        let vfio_dev_path = sysfspath.as_ref().join("vfio-dev");
+        let cdev = vfio_dev_path
+            .read_dir()
+            .map_err(|e| VfioError::ReadDir(e, vfio_dev_path.clone()))?
+            .filter_map(|entry| entry.ok())
+            .find(|entry| entry.file_name().to_string_lossy().starts_with("vfio"))
+            .ok_or(VfioError::InvalidPath)?;
+
+        Ok(Path::new("/dev/vfio/devices").join(cdev.file_name()))
+    }
+
+    fn attach_iommufd_pt(dev: &File, pt_id: u32) -> Result<()> {
+        let attach = vfio_device_attach_iommufd_pt {
+            argsz: mem::size_of::<vfio_device_attach_iommufd_pt>() as u32,
+            flags: 0,
+            pt_id,
+        };
+        // SAFETY:
+        // Safe as we are the owner of dev and attach which are valid value, and we verify the
+        // return value.
+        let ret = unsafe { ioctl_with_ref(dev, VFIO_DEVICE_ATTACH_IOMMUFD_PT, &attach) };
+        if ret < 0 {
+            return Err(VfioError::VfioDeviceAttachIommufd(get_error()));
+        }
+
+        Ok(())
+    }
+
     pub fn new<P: AsRef<Path>>(
         sysfspath: &P,
         container: Arc<Mutex<VfioContainer>>,
@@ -1101,8 +1343,9 @@ impl VfioDevice {
             name,
             container,
             dev_type,
-            group_descriptor,
+            group_descriptor: Some(group_descriptor),
             group_id,
+            fault_queue: None,
             regions,
             num_irqs: dev_info.num_irqs,
             iova_alloc: Arc::new(Mutex::new(iova_alloc)),
@@ -1824,11 +2067,12 @@ impl VfioDevice {
 
     /// get vfio device's descriptors which are passed into minijail process
     pub fn keep_rds(&self) -> Vec<RawDescriptor> {
-        vec![
+        let mut rds = vec![
             self.dev.as_raw_descriptor(),
-            self.group_descriptor,
             self.container.lock().as_raw_descriptor(),
-        ]
+        ];
+        rds.extend(self.group_descriptor);
+        rds
     }
 
     /// Add (iova, user_addr) map into vfio container iommu table
@@ -1883,9 +2127,34 @@ impl VfioDevice {
         &self.dev
     }
 
+    /// Gets the ID of the IOMMU group of the device.
+    pub fn group_id(&self) -> u32 {
+        self.group_id
+    }
+
+    /// Takes the queue the host IOMMU reports the I/O page faults of the device to, if it was
+    /// opened with `new_passthrough_iommufd` and the IOMMU supports it. Each read returns
+    /// `iommu_hwpt_pgfault` records, and each fault group must be answered by writing an
+    /// `iommu_hwpt_page_response` with the cookie of its last fault.
+    pub fn take_fault_queue(&mut self) -> Option<File> {
+        self.fault_queue.take()
+    }
+
     /// close vfio device
     pub fn close(&self) {
-        self.container.lock().remove_group(self.group_id, true);
+        if self.group_descriptor.is_some() {
+            self.container.lock().remove_group(self.group_id, true);
+        } else if let Some(kvm_vfio_file) = KVM_VFIO_FILE.get() {
+            if kvm_device_set_file(
+                kvm_vfio_file,
+                self.dev.as_raw_descriptor(),
+                KvmVfioGroupOps::Delete,
+            )
+            .is_err()
+            {
+                warn!("failing in remove vfio device from kvm device");
+            }
+        }
     }
 }
 
@@ -6,7 +6,9 @@
 //! Windows ; outside of this use-case, please use [[asynchronous::AsyncConsole]] instead.
 
 pub mod asynchronous;
-mod multiport;
+pub mod multiport;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub mod socket_port;
 mod sys;
 
 use std::collections::BTreeMap;
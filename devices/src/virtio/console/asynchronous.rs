@@ -26,7 +26,11 @@ use cros_async::EventAsync;
 use cros_async::Executor;
 use cros_async::IntoAsync;
 use cros_async::IoSource;
+use cros_async::SelectResult;
+use futures::future::Abortable;
+use futures::future::Pending;
 use futures::FutureExt;
+use futures::StreamExt;
 use hypervisor::ProtectionType;
 use sync::Mutex;
 use vm_memory::GuestMemory;
@@ -42,8 +46,11 @@ use crate::virtio;
 use crate::virtio::async_device::AsyncQueueState;
 use crate::virtio::async_utils;
 use crate::virtio::base_features;
+use crate::virtio::console::multiport::ClientInputs;
 use crate::virtio::console::multiport::ConsolePortInfo;
 use crate::virtio::console::multiport::ControlPort;
+use crate::virtio::console::multiport::PortBackend;
+use crate::virtio::console::multiport::PortTable;
 use crate::virtio::console::virtio_console_config;
 use crate::virtio::console::ConsoleError;
 use crate::virtio::copy_config;
@@ -83,7 +90,7 @@ async fn run_tx_queue(
 async fn run_rx_queue(
     queue: &Arc<Mutex<virtio::Queue>>,
     doorbell: Interrupt,
-    kick_evt: EventAsync,
+    kick_evt: &EventAsync,
     input: &IoSource<AsyncSerialInput>,
 ) {
     // Staging buffer, required because of `handle_input`'s API. We can probably remove this once
@@ -121,8 +128,77 @@ async fn run_rx_queue(
     }
 }
 
+/// Host clients of a port backed by a `PortBackend`.
+struct ClientInput {
+    backend: PortBackend,
+    inputs: ClientInputs,
+    // Input of the connected client, kept here while the queue is stopped.
+    current: Option<(u64, AsyncSerialInput)>,
+}
+
+/// Input of a console port.
+enum PortInput {
+    /// Input that stays the same for the whole life of the port.
+    Fixed(AsyncSerialInput),
+    /// Inputs of the successive host clients of the port.
+    Client(ClientInput),
+}
+
+/// Feeds the input of each successive host client of a port to its receive queue, until `abort`
+/// is signaled.
+async fn run_client_rx_queue(
+    ex: Executor,
+    queue: Arc<Mutex<virtio::Queue>>,
+    doorbell: Interrupt,
+    kick_evt: EventAsync,
+    mut client: ClientInput,
+    mut abort: Abortable<Pending<()>>,
+) -> PortInput {
+    loop {
+        let (generation, input) = match client.current.take() {
+            Some(current) => current,
+            None => match select2(client.inputs.next(), &mut abort).await.0 {
+                SelectResult::Finished(Some((generation, input))) => {
+                    (generation, AsyncSerialInput(input))
+                }
+                // The queue is being stopped.
+                _ => break,
+            },
+        };
+
+        let async_input = match ex.async_from(input) {
+            Ok(async_input) => async_input,
+            Err(e) => {
+                error!("failed to create async input: {}", e);
+                client.backend.disconnect(generation);
+                continue;
+            }
+        };
+
+        let closed = matches!(
+            select2(
+                run_rx_queue(&queue, doorbell.clone(), &kick_evt, &async_input).boxed_local(),
+                &mut abort,
+            )
+            .await
+            .0,
+            SelectResult::Finished(())
+        );
+        let input = async_input.into_source();
+
+        if closed {
+            client.backend.disconnect(generation);
+        } else {
+            client.current = Some((generation, input));
+            break;
+        }
+    }
+
+    PortInput::Client(client)
+}
+
 pub struct ConsolePort {
-    input: Option<AsyncQueueState<AsyncSerialInput>>,
+    input: Option<AsyncQueueState<PortInput>>,
     output: AsyncQueueState<Box<dyn io::Write + Send>>,
     info: ConsolePortInfo,
 }
@@ -137,7 +213,9 @@ impl SerialDevice for ConsolePort {
         options: SerialOptions,
         _keep_rds: Vec<RawDescriptor>,
     ) -> ConsolePort {
-        let input = input.map(AsyncSerialInput).map(AsyncQueueState::Stopped);
+        let input = input
+            .map(|input| PortInput::Fixed(AsyncSerialInput(input)))
+            .map(AsyncQueueState::Stopped);
         let output = AsyncQueueState::Stopped(output.unwrap_or_else(|| Box::new(io::sink())));
         let info = ConsolePortInfo {
             console: options.console,
@@ -165,6 +243,22 @@ impl SerialDevice for ConsolePort {
 }
 
 impl ConsolePort {
+    /// Create a port whose host clients connect and disconnect through `backend`.
+    fn new_client_port(backend: PortBackend, inputs: ClientInputs) -> ConsolePort {
+        let output = AsyncQueueState::Stopped(backend.output());
+        let input = ClientInput {
+            backend,
+            inputs,
+            current: None,
+        };
+
+        ConsolePort {
+            input: Some(AsyncQueueState::Stopped(PortInput::Client(input))),
+            output,
+            info: Default::default(),
+        }
+    }
+
     pub fn start_receive_queue(
         &mut self,
         ex: &Executor,
@@ -185,20 +279,27 @@ impl ConsolePort {
             EventAsync::new(kick_evt, ex).context("Failed to create EventAsync for kick_evt")?;
 
         let closure_ex = ex.clone();
-        let rx_future = move |input, abort| {
-            let async_input = closure_ex
-                .async_from(input)
-                .context("failed to create async input")?;
-
-            Ok(async move {
-                select2(
-                    run_rx_queue(&queue, doorbell, kick_evt, &async_input).boxed_local(),
-                    abort,
-                )
-                .await;
-
-                async_input.into_source()
-            })
+        let rx_future = move |input, abort| match input {
+            PortInput::Fixed(input) => {
+                let async_input = closure_ex
+                    .async_from(input)
+                    .context("failed to create async input")?;
+
+                Ok(async move {
+                    select2(
+                        run_rx_queue(&queue, doorbell, &kick_evt, &async_input).boxed_local(),
+                        abort,
+                    )
+                    .await;
+
+                    PortInput::Fixed(async_input.into_source())
+                }
+                .boxed_local())
+            }
+            PortInput::Client(client) => Ok(run_client_rx_queue(
+                closure_ex, queue, doorbell, kick_evt, client, abort,
+            )
+            .boxed_local()),
         };
 
         input_queue.start(ex, rx_future)
@@ -255,29 +356,48 @@ pub struct ConsoleDevice {
     control_port: Option<ControlPort>,
     // Port 1..n, if they exist.
     extra_ports: Vec<ConsolePort>,
+    // Backends of the ports whose host clients come and go, which follow the extra ports.
+    client_ports: Vec<PortBackend>,
 }
 
 impl ConsoleDevice {
     /// Create a console device with the multiport feature enabled
     /// The multiport feature is referred to virtio spec.
+    ///
+    /// `client_ports` port ids are reserved after `extra_ports` for ports whose host clients come
+    /// and go while the device is running. They are left detached, see `port_backends`.
     pub fn new_multi_port(
         protection_type: ProtectionType,
         port0: ConsolePort,
-        extra_ports: Vec<ConsolePort>,
+        mut extra_ports: Vec<ConsolePort>,
+        client_ports: usize,
     ) -> ConsoleDevice {
         let avail_features =
             virtio::base_features(protection_type) | (1 << VIRTIO_CONSOLE_F_MULTIPORT);
 
         let info = std::iter::once(&port0)
             .chain(extra_ports.iter())
-            .map(|port| port.info.clone())
+            .map(|port| Some(port.info.clone()))
+            .chain(std::iter::repeat(None).take(client_ports))
             .collect::<Vec<_>>();
+        let control_port = ControlPort::new(info);
+
+        let first_client_port = 1 + extra_ports.len() as u32;
+        let client_ports = (0..client_ports as u32)
+            .map(|i| {
+                let (backend, inputs) =
+                    PortBackend::new(first_client_port + i, control_port.ports().clone());
+                extra_ports.push(ConsolePort::new_client_port(backend.clone(), inputs));
+                backend
+            })
+            .collect();
 
         ConsoleDevice {
             avail_features,
             port0,
-            control_port: Some(ControlPort::new(info)),
+            control_port: Some(control_port),
             extra_ports,
+            client_ports,
         }
     }
 
@@ -286,6 +406,16 @@ impl ConsoleDevice {
         self.avail_features
     }
 
+    /// Return the table of the ports of a multi-port console device
+    pub fn port_table(&self) -> Option<&PortTable> {
+        self.control_port.as_ref().map(ControlPort::ports)
+    }
+
+    /// Return the backends of the ports reserved for host clients that come and go
+    pub fn port_backends(&self) -> &[PortBackend] {
+        &self.client_ports
+    }
+
     /// Return whether current console device supports multiport feature
     pub fn is_multi_port(&self) -> bool {
         self.avail_features & (1 << VIRTIO_CONSOLE_F_MULTIPORT) != 0
@@ -409,6 +539,7 @@ impl SerialDevice for ConsoleDevice {
             port0,
             control_port: None,
             extra_ports: vec![],
+            client_ports: vec![],
         }
     }
 
@@ -5,6 +5,7 @@
 //! Implementation of control port used for multi-port enabled virtio-console
 
 use std::collections::VecDeque;
+use std::io;
 use std::sync::Arc;
 
 use anyhow::anyhow;
@@ -19,7 +20,6 @@ use data_model::Le16;
 use data_model::Le32;
 use futures::channel::mpsc;
 use futures::FutureExt;
-use futures::SinkExt;
 use futures::StreamExt;
 use sync::Mutex;
 use zerocopy::AsBytes;
@@ -27,6 +27,7 @@ use zerocopy::FromBytes;
 use zerocopy::FromZeroes;
 
 use super::handle_input;
+use crate::serial_device::SerialInput;
 use crate::virtio;
 use crate::virtio::async_device::AsyncQueueState;
 use crate::virtio::console::ConsoleError;
@@ -76,18 +77,13 @@ impl TryFrom<u16> for ControlEvent {
     }
 }
 
-fn process_tx_ctrl_msg(
-    reader: &mut Reader,
-    ports: &[ConsolePortInfo],
-) -> Result<Vec<ControlMsgBytes>> {
-    let mut messages = Vec::<ControlMsgBytes>::new();
-    let ports_num = ports.len() as u32;
+fn process_tx_ctrl_msg(reader: &mut Reader, ports: &PortTable) -> Result<()> {
     let ctrl_msg: ControlMsg = reader.read_obj().context("failed to read from reader")?;
     let id = ctrl_msg.id.to_native();
     let event = ControlEvent::try_from(ctrl_msg.event.to_native())?;
     let value: u16 = ctrl_msg.value.to_native();
 
-    if id >= ports_num && event != ControlEvent::DeviceReady {
+    if id >= ports.max_ports() && event != ControlEvent::DeviceReady {
         return Err(anyhow!("console: id {} out of range", id));
     }
 
@@ -95,17 +91,7 @@ fn process_tx_ctrl_msg(
         ControlEvent::DeviceReady => {
             // value of 1 indicates success, and 0 indicates failure
             if value == 1 {
-                for id in 0..ports_num {
-                    let msg = ControlMsg::new(id, ControlEvent::DeviceAdd, 0);
-                    let _ = msg.as_bytes();
-                    messages.push(msg.as_bytes().to_owned().into());
-
-                    let name = ports[id as usize].name.clone();
-                    let msg = ControlMsg::new(id, ControlEvent::PortName, 0);
-                    let mut reply: ControlMsgBytes = msg.as_bytes().to_owned().into();
-                    reply.extend(name.as_bytes());
-                    messages.push(reply);
-                }
+                ports.device_ready();
             } else {
                 error!("console: received event {:?} value {}", event, value);
             }
@@ -113,21 +99,14 @@ fn process_tx_ctrl_msg(
         ControlEvent::PortReady => {
             // value of 1 indicates success, and 0 indicates failure
             if value == 1 {
-                let msg = ControlMsg::new(id, ControlEvent::PortOpen, 1);
-                messages.push(msg.as_bytes().to_owned().into());
-
-                let is_console = ports[id as usize].console;
-                if is_console {
-                    let msg = ControlMsg::new(id, ControlEvent::ConsolePort, 1);
-                    messages.push(msg.as_bytes().to_owned().into());
-                }
+                ports.port_ready(id)?;
             } else {
                 error!("console: received event {:?} value {}", event, value);
             }
         }
         ControlEvent::PortOpen => match value {
-            // Currently, port state change is not supported, default is open.
-            // And only print debug info here.
+            // The guest opening or closing a port does not change anything on the host side, so
+            // only print debug info here.
             0 => debug!("console port{} close", id),
             1 => debug!("console port{} open", id),
             _ => error!("console port{} open {}", id, value),
@@ -137,24 +116,16 @@ fn process_tx_ctrl_msg(
         }
     }
 
-    Ok(messages)
+    Ok(())
 }
 
-fn process_tx_ctrl_queue(
-    queue: &Arc<Mutex<Queue>>,
-    doorbell: &Interrupt,
-    ports: &[ConsolePortInfo],
-) -> Vec<ControlMsgBytes> {
+fn process_tx_ctrl_queue(queue: &Arc<Mutex<Queue>>, doorbell: &Interrupt, ports: &PortTable) {
     let mut needs_interrupt = false;
-    let mut messages = Vec::<ControlMsgBytes>::new();
     let mut queue = queue.try_lock().expect("Lock should not be unavailable");
 
     while let Some(mut avail_desc) = queue.pop() {
-        match process_tx_ctrl_msg(&mut avail_desc.reader, ports) {
-            Ok(mut msg) => messages.append(&mut msg),
-            Err(e) => {
-                error!("console: failed to handle control msg: {}", e);
-            }
+        if let Err(e) = process_tx_ctrl_msg(&mut avail_desc.reader, ports) {
+            error!("console: failed to handle control msg: {}", e);
         }
 
         queue.add_used(avail_desc, 0);
@@ -164,16 +135,13 @@ fn process_tx_ctrl_queue(
     if needs_interrupt {
         queue.trigger_interrupt(doorbell);
     }
-
-    messages
 }
 
 async fn run_tx_ctrl_queue(
     queue: &Arc<Mutex<Queue>>,
     doorbell: Interrupt,
     kick_evt: EventAsync,
-    sender: &mut mpsc::UnboundedSender<Vec<ControlMsgBytes>>,
-    ports: Vec<ConsolePortInfo>,
+    ports: &PortTable,
 ) {
     loop {
         if let Err(e) = kick_evt.next_val().await {
@@ -181,12 +149,7 @@ async fn run_tx_ctrl_queue(
             break;
         }
 
-        let messages = process_tx_ctrl_queue(queue, &doorbell, &ports);
-
-        if let Err(e) = sender.send(messages).await {
-            error!("console: failed to send control msg: {}", e);
-            break;
-        }
+        process_tx_ctrl_queue(queue, &doorbell, ports);
     }
 }
 
@@ -225,25 +188,345 @@ pub struct ConsolePortInfo {
     pub name: String,
 }
 
+struct PortState {
+    info: ConsolePortInfo,
+    // Whether the host end of the port is connected, which the guest sees as the port being open.
+    host_connected: bool,
+}
+
+struct PortTableState {
+    // Indexed by port id, `None` for the ids that have no port attached.
+    ports: Vec<Option<PortState>>,
+    // Whether the guest driver has reported itself ready, and expects to be told about ports.
+    device_ready: bool,
+}
+
+fn port_name_msg(id: u32, name: &str) -> ControlMsgBytes {
+    let msg = ControlMsg::new(id, ControlEvent::PortName, 0);
+    let mut reply: ControlMsgBytes = msg.as_bytes().to_owned().into();
+    reply.extend(name.as_bytes());
+    reply
+}
+
+/// Ports of a multi-port virtio-console, shared between the control port and the code adding,
+/// removing, opening and closing ports while the device is running.
+#[derive(Clone)]
+pub struct PortTable {
+    state: Arc<Mutex<PortTableState>>,
+    sender: mpsc::UnboundedSender<Vec<ControlMsgBytes>>,
+}
+
+impl PortTable {
+    fn new(
+        ports: Vec<Option<ConsolePortInfo>>,
+        sender: mpsc::UnboundedSender<Vec<ControlMsgBytes>>,
+    ) -> PortTable {
+        let ports = ports
+            .into_iter()
+            .map(|info| {
+                info.map(|info| PortState {
+                    info,
+                    host_connected: true,
+                })
+            })
+            .collect();
+
+        PortTable {
+            state: Arc::new(Mutex::new(PortTableState {
+                ports,
+                device_ready: false,
+            })),
+            sender,
+        }
+    }
+
+    /// Queue `messages` for the guest. Called with the state locked so that messages are queued
+    /// in the same order as the state changes they describe.
+    fn send(&self, messages: Vec<ControlMsgBytes>) {
+        if let Err(e) = self.sender.unbounded_send(messages) {
+            error!("console: failed to send control msg: {}", e);
+        }
+    }
+
+    /// Return the number of port ids, attached or not.
+    pub fn max_ports(&self) -> u32 {
+        self.state.lock().ports.len() as u32
+    }
+
+    /// Announce all the attached ports to the guest driver, which has just reported itself ready.
+    fn device_ready(&self) {
+        let mut state = self.state.lock();
+        state.device_ready = true;
+
+        let mut messages = Vec::<ControlMsgBytes>::new();
+        for (id, port) in state.ports.iter().enumerate() {
+            if let Some(port) = port {
+                let msg = ControlMsg::new(id as u32, ControlEvent::DeviceAdd, 0);
+                messages.push(msg.as_bytes().to_owned().into());
+                messages.push(port_name_msg(id as u32, &port.info.name));
+            }
+        }
+        self.send(messages);
+    }
+
+    /// Tell the guest driver about the state of port `id`, which it has just set up.
+    fn port_ready(&self, id: u32) -> Result<()> {
+        let state = self.state.lock();
+        let port = state
+            .ports
+            .get(id as usize)
+            .and_then(Option::as_ref)
+            .with_context(|| format!("console: port {} is not attached", id))?;
+
+        let mut messages = Vec::<ControlMsgBytes>::new();
+        if port.host_connected {
+            let msg = ControlMsg::new(id, ControlEvent::PortOpen, 1);
+            messages.push(msg.as_bytes().to_owned().into());
+        }
+        if port.info.console {
+            let msg = ControlMsg::new(id, ControlEvent::ConsolePort, 1);
+            messages.push(msg.as_bytes().to_owned().into());
+        }
+        self.send(messages);
+
+        Ok(())
+    }
+
+    /// Forget about the guest driver, which will report itself ready again once restarted.
+    fn reset(&self) {
+        self.state.lock().device_ready = false;
+    }
+
+    /// Attach a port with the given info as port `id`, and announce it to the guest driver.
+    pub fn add_port(&self, id: u32, info: ConsolePortInfo, host_connected: bool) -> Result<()> {
+        let mut state = self.state.lock();
+        if state
+            .ports
+            .iter()
+            .flatten()
+            .any(|port| port.info.name == info.name)
+        {
+            return Err(anyhow!("console: port name {} already in use", info.name));
+        }
+        let slot = state
+            .ports
+            .get_mut(id as usize)
+            .with_context(|| format!("console: id {} out of range", id))?;
+        if slot.is_some() {
+            return Err(anyhow!("console: port {} already attached", id));
+        }
+
+        let mut messages = Vec::<ControlMsgBytes>::new();
+        let msg = ControlMsg::new(id, ControlEvent::DeviceAdd, 0);
+        messages.push(msg.as_bytes().to_owned().into());
+        messages.push(port_name_msg(id, &info.name));
+
+        *slot = Some(PortState {
+            info,
+            host_connected,
+        });
+        if state.device_ready {
+            self.send(messages);
+        }
+
+        Ok(())
+    }
+
+    /// Detach port `id`, and tell the guest driver it is gone.
+    pub fn remove_port(&self, id: u32) -> Result<ConsolePortInfo> {
+        let mut state = self.state.lock();
+        let port = state
+            .ports
+            .get_mut(id as usize)
+            .and_then(Option::take)
+            .with_context(|| format!("console: port {} is not attached", id))?;
+
+        if state.device_ready {
+            let msg = ControlMsg::new(id, ControlEvent::DeviceRemove, 0);
+            self.send(vec![msg.as_bytes().to_owned().into()]);
+        }
+
+        Ok(port.info)
+    }
+
+    /// Record whether the host end of port `id` is connected, and tell the guest driver.
+    pub fn set_host_connected(&self, id: u32, connected: bool) {
+        let mut state = self.state.lock();
+        let device_ready = state.device_ready;
+        let Some(Some(port)) = state.ports.get_mut(id as usize) else {
+            return;
+        };
+        if port.host_connected == connected {
+            return;
+        }
+
+        port.host_connected = connected;
+        if device_ready {
+            let msg = ControlMsg::new(id, ControlEvent::PortOpen, connected as u16);
+            self.send(vec![msg.as_bytes().to_owned().into()]);
+        }
+    }
+
+    /// Return the id of the attached port named `name`.
+    pub fn find_port(&self, name: &str) -> Option<u32> {
+        self.state
+            .lock()
+            .ports
+            .iter()
+            .position(|port| matches!(port, Some(port) if port.info.name == name))
+            .map(|id| id as u32)
+    }
+
+    /// Return the id, info and host connection state of each attached port.
+    pub fn ports(&self) -> Vec<(u32, ConsolePortInfo, bool)> {
+        self.state
+            .lock()
+            .ports
+            .iter()
+            .enumerate()
+            .filter_map(|(id, port)| {
+                port.as_ref()
+                    .map(|port| (id as u32, port.info.clone(), port.host_connected))
+            })
+            .collect()
+    }
+}
+
+/// Inputs of the successive host clients of a port, tagged with their connection generation.
+pub(in crate::virtio::console) type ClientInputs =
+    mpsc::UnboundedReceiver<(u64, Box<dyn SerialInput>)>;
+
+struct HostClient {
+    // Bumped every time a client connects or the port is detached.
+    generation: u64,
+    output: Option<Box<dyn io::Write + Send>>,
+}
+
+/// Host end of a port of a multi-port virtio-console whose host client can connect and disconnect
+/// while the device is running.
+#[derive(Clone)]
+pub struct PortBackend {
+    id: u32,
+    ports: PortTable,
+    client: Arc<Mutex<HostClient>>,
+    inputs: mpsc::UnboundedSender<(u64, Box<dyn SerialInput>)>,
+}
+
+impl PortBackend {
+    pub(in crate::virtio::console) fn new(
+        id: u32,
+        ports: PortTable,
+    ) -> (PortBackend, ClientInputs) {
+        let (inputs, receiver) = mpsc::unbounded();
+        let backend = PortBackend {
+            id,
+            ports,
+            client: Arc::new(Mutex::new(HostClient {
+                generation: 0,
+                output: None,
+            })),
+            inputs,
+        };
+
+        (backend, receiver)
+    }
+
+    /// Return the port id.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Return the table of all the ports of the device.
+    pub fn ports(&self) -> &PortTable {
+        &self.ports
+    }
+
+    /// Attach the port to the device with the given info. The port stays closed until a host
+    /// client connects.
+    pub fn attach(&self, info: ConsolePortInfo) -> Result<()> {
+        self.ports.add_port(self.id, info, false)
+    }
+
+    /// Disconnect the host client, if any, and detach the port from the device.
+    pub fn detach(&self) -> Result<ConsolePortInfo> {
+        let mut client = self.client.lock();
+        client.generation += 1;
+        client.output = None;
+        self.ports.remove_port(self.id)
+    }
+
+    /// Connect a host client, replacing the current one. The caller is responsible for closing
+    /// the input of the previous client, which is read until it reaches EOF.
+    pub fn connect(&self, input: Box<dyn SerialInput>, output: Box<dyn io::Write + Send>) {
+        let mut client = self.client.lock();
+        client.generation += 1;
+        client.output = Some(output);
+        if let Err(e) = self.inputs.unbounded_send((client.generation, input)) {
+            error!("console: failed to connect port{}: {}", self.id, e);
+        }
+        self.ports.set_host_connected(self.id, true);
+    }
+
+    /// Disconnect the host client of the given generation, unless it was already replaced.
+    pub(in crate::virtio::console) fn disconnect(&self, generation: u64) {
+        let mut client = self.client.lock();
+        if client.generation == generation && client.output.take().is_some() {
+            self.ports.set_host_connected(self.id, false);
+        }
+    }
+
+    /// Return a writer sending guest output to the current host client, or dropping it when no
+    /// client is connected.
+    pub(in crate::virtio::console) fn output(&self) -> Box<dyn io::Write + Send> {
+        Box::new(HostClientOutput(self.client.clone()))
+    }
+}
+
+struct HostClientOutput(Arc<Mutex<HostClient>>);
+
+impl io::Write for HostClientOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.0.lock().output.as_mut() {
+            Some(output) => output.write(buf),
+            // Nobody is listening on the host side.
+            None => Ok(buf.len()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.0.lock().output.as_mut() {
+            Some(output) => output.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
 /// Control port for multi-port virtio-console
 pub struct ControlPort {
-    sender: AsyncQueueState<mpsc::UnboundedSender<Vec<ControlMsgBytes>>>,
+    transmit: AsyncQueueState<PortTable>,
     receiver: AsyncQueueState<mpsc::UnboundedReceiver<Vec<ControlMsgBytes>>>,
-    ports: Vec<ConsolePortInfo>,
+    ports: PortTable,
 }
 
 impl ControlPort {
-    /// Create a control port with the given port info
-    pub fn new(ports: Vec<ConsolePortInfo>) -> ControlPort {
+    /// Create a control port with the given port info, `None` for the port ids left free for
+    /// ports attached later on.
+    pub fn new(ports: Vec<Option<ConsolePortInfo>>) -> ControlPort {
         let (sender, receiver) = mpsc::unbounded::<Vec<ControlMsgBytes>>();
+        let ports = PortTable::new(ports, sender);
 
         ControlPort {
-            sender: AsyncQueueState::Stopped(sender),
+            transmit: AsyncQueueState::Stopped(ports.clone()),
             receiver: AsyncQueueState::Stopped(receiver),
             ports,
         }
     }
 
+    /// Return the table of the ports handled by this control port
+    pub fn ports(&self) -> &PortTable {
+        &self.ports
+    }
+
     /// Start the control receiveq
     pub fn start_receive_queue(
         &mut self,
@@ -277,9 +560,19 @@ impl ControlPort {
 
     /// Stop the control receiveq
     pub fn stop_receive_queue(&mut self) -> anyhow::Result<bool> {
-        self.receiver
+        let stopped = self
+            .receiver
             .stop()
-            .context("failed to stop control rx queue")
+            .context("failed to stop control rx queue")?;
+
+        // Messages still pending were meant for the guest driver that stopped the queue, the next
+        // one starts over with VIRTIO_CONSOLE_DEVICE_READY.
+        self.ports.reset();
+        if let AsyncQueueState::Stopped(receiver) = &mut self.receiver {
+            while let Ok(Some(_)) = receiver.try_next() {}
+        }
+
+        Ok(stopped)
     }
 
     /// Start the control transmitq
@@ -297,28 +590,172 @@ impl ControlPort {
         let kick_evt =
             EventAsync::new(kick_evt, ex).context("Failed to create EventAsync for kick_evt")?;
 
-        let sender = &mut self.sender;
-        let ports = self.ports.clone();
-
-        let tx_future = |mut sender, abort| {
+        let tx_future = |ports, abort| {
             Ok(async move {
                 select2(
-                    run_tx_ctrl_queue(&queue, doorbell, kick_evt, &mut sender, ports).boxed_local(),
+                    run_tx_ctrl_queue(&queue, doorbell, kick_evt, &ports).boxed_local(),
                     abort,
                 )
                 .await;
 
-                sender
+                ports
             })
         };
 
-        sender.start(ex, tx_future)
+        self.transmit.start(ex, tx_future)
     }
 
     /// Stop the control transmitq
     pub fn stop_transmit_queue(&mut self) -> anyhow::Result<bool> {
-        self.sender
+        self.transmit
             .stop()
             .context("failed to stop control tx queue")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use super::*;
+
+    fn info(name: &str, console: bool) -> ConsolePortInfo {
+        ConsolePortInfo {
+            console,
+            name: name.to_owned(),
+        }
+    }
+
+    fn events(control_port: &mut ControlPort) -> Vec<(u32, ControlEvent, u16)> {
+        let AsyncQueueState::Stopped(receiver) = &mut control_port.receiver else {
+            panic!("control receiveq is not stopped");
+        };
+
+        let mut events = Vec::new();
+        while let Ok(Some(messages)) = receiver.try_next() {
+            for msg in messages {
+                let msg = Vec::from(msg);
+                let msg = ControlMsg::read_from_prefix(&msg).unwrap();
+                let event = ControlEvent::try_from(msg.event.to_native()).unwrap();
+                events.push((msg.id.to_native(), event, msg.value.to_native()));
+            }
+        }
+        events
+    }
+
+    #[test]
+    fn attach_connect_detach() {
+        let mut control_port = ControlPort::new(vec![Some(info("console", true)), None]);
+        let ports = control_port.ports().clone();
+        let (backend, _inputs) = PortBackend::new(1, ports.clone());
+
+        // Nothing is sent before the guest driver is ready.
+        backend.attach(info("agent", false)).unwrap();
+        assert!(events(&mut control_port).is_empty());
+
+        ports.device_ready();
+        assert_eq!(
+            events(&mut control_port),
+            vec![
+                (0, ControlEvent::DeviceAdd, 0),
+                (0, ControlEvent::PortName, 0),
+                (1, ControlEvent::DeviceAdd, 0),
+                (1, ControlEvent::PortName, 0),
+            ]
+        );
+
+        ports.port_ready(0).unwrap();
+        assert_eq!(
+            events(&mut control_port),
+            vec![
+                (0, ControlEvent::PortOpen, 1),
+                (0, ControlEvent::ConsolePort, 1),
+            ]
+        );
+        // The port stays closed until a host client connects.
+        ports.port_ready(1).unwrap();
+        assert!(events(&mut control_port).is_empty());
+
+        backend.connect(
+            Box::new(File::open("/dev/null").unwrap()),
+            Box::new(io::sink()),
+        );
+        assert_eq!(
+            events(&mut control_port),
+            vec![(1, ControlEvent::PortOpen, 1)]
+        );
+        backend.disconnect(1);
+        assert_eq!(
+            events(&mut control_port),
+            vec![(1, ControlEvent::PortOpen, 0)]
+        );
+
+        backend.detach().unwrap();
+        assert_eq!(
+            events(&mut control_port),
+            vec![(1, ControlEvent::DeviceRemove, 0)]
+        );
+        assert_eq!(ports.ports().len(), 1);
+        assert!(ports.port_ready(1).is_err());
+    }
+
+    #[test]
+    fn hot_add_remove() {
+        let mut control_port = ControlPort::new(vec![Some(info("console", true)), None, None]);
+        let ports = control_port.ports().clone();
+        let (backend1, _inputs1) = PortBackend::new(1, ports.clone());
+        let (backend2, _inputs2) = PortBackend::new(2, ports.clone());
+        ports.device_ready();
+        events(&mut control_port);
+
+        // Ports attached while the guest driver is ready are announced right away.
+        backend1.attach(info("agent", false)).unwrap();
+        assert_eq!(
+            events(&mut control_port),
+            vec![
+                (1, ControlEvent::DeviceAdd, 0),
+                (1, ControlEvent::PortName, 0),
+            ]
+        );
+        backend1.detach().unwrap();
+        assert_eq!(
+            events(&mut control_port),
+            vec![(1, ControlEvent::DeviceRemove, 0)]
+        );
+        assert!(backend1.detach().is_err());
+
+        // The id of a removed port can be used again.
+        backend1.attach(info("agent", false)).unwrap();
+        assert_eq!(
+            events(&mut control_port),
+            vec![
+                (1, ControlEvent::DeviceAdd, 0),
+                (1, ControlEvent::PortName, 0),
+            ]
+        );
+
+        // Invalid additions are rejected without telling the guest.
+        assert!(backend1.attach(info("other", false)).is_err());
+        assert!(backend2.attach(info("agent", false)).is_err());
+        assert!(ports.add_port(3, info("other", false), false).is_err());
+        assert!(events(&mut control_port).is_empty());
+        assert_eq!(ports.find_port("agent"), Some(1));
+        assert_eq!(ports.find_port("other"), None);
+
+        // Changes made while the guest driver restarts are only reported once it is ready again.
+        control_port.stop_receive_queue().unwrap();
+        backend1.detach().unwrap();
+        backend2.attach(info("other", false)).unwrap();
+        assert!(events(&mut control_port).is_empty());
+        ports.device_ready();
+        assert_eq!(
+            events(&mut control_port),
+            vec![
+                (0, ControlEvent::DeviceAdd, 0),
+                (0, ControlEvent::PortName, 0),
+                (2, ControlEvent::DeviceAdd, 0),
+                (2, ControlEvent::PortName, 0),
+            ]
+        );
+    }
+}
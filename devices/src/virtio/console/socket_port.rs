@@ -0,0 +1,513 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Ports of a multi-port virtio-console bound to Unix sockets listening on the host.
+
+use std::fs::remove_file;
+use std::io;
+use std::net::Shutdown;
+use std::os::unix::net::UnixListener;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use base::error;
+use base::warn;
+use base::AsRawDescriptor;
+use base::Error as SysError;
+use base::Event;
+use base::EventToken;
+use base::WaitContext;
+use base::WorkerThread;
+use remain::sorted;
+use thiserror::Error as ThisError;
+use vm_control::ConsoleControlCommand;
+use vm_control::ConsoleControlResult;
+use vm_control::ConsolePortStatus;
+
+use crate::virtio::console::multiport::ConsolePortInfo;
+use crate::virtio::console::multiport::PortBackend;
+use crate::virtio::console::multiport::PortTable;
+
+// How long to wait for a client to make room in its socket before dropping guest output.
+const CLIENT_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[sorted]
+#[derive(ThisError, Debug)]
+pub enum Error {
+    #[error("failed to attach port: {0:#}")]
+    Attach(anyhow::Error),
+    #[error("failed to bind socket {1}: {0}")]
+    Bind(io::Error, PathBuf),
+    #[error("port name {0} already in use")]
+    NameInUse(String),
+    #[error("no free port id left")]
+    NoFreePort,
+    #[error("no socket port named {0}")]
+    NotFound(String),
+}
+
+impl Error {
+    fn errno(&self) -> i32 {
+        match self {
+            Error::Attach(_) => libc::EINVAL,
+            Error::Bind(e, _) => e.raw_os_error().unwrap_or(libc::EIO),
+            Error::NameInUse(_) => libc::EEXIST,
+            Error::NoFreePort => libc::ENOSPC,
+            Error::NotFound(_) => libc::ENOENT,
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Writes guest output to a client socket. The socket shares its file description with the input
+/// of the port, which the executor puts in non-blocking mode, so wait for room when it is full.
+struct ClientOutput(UnixStream);
+
+impl io::Write for ClientOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        loop {
+            match self.0.write(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => wait_writable(&self.0)?,
+                res => return res,
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+fn wait_writable(stream: &UnixStream) -> io::Result<()> {
+    let mut fds = libc::pollfd {
+        fd: stream.as_raw_descriptor(),
+        events: libc::POLLOUT,
+        revents: 0,
+    };
+    // SAFETY:
+    // Safe because we give a valid pointer to a list (of 1) FD and we check the return value.
+    match unsafe { libc::poll(&mut fds, 1, CLIENT_WRITE_TIMEOUT.as_millis() as i32) } {
+        0 => Err(io::Error::from_raw_os_error(libc::ETIMEDOUT)),
+        ret if ret < 0 => {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                Ok(())
+            } else {
+                Err(e)
+            }
+        }
+        _ => Ok(()),
+    }
+}
+
+fn connect_client(backend: &PortBackend, stream: &UnixStream) -> io::Result<()> {
+    let input = stream.try_clone()?;
+    let output = stream.try_clone()?;
+    backend.connect(Box::new(input), Box::new(ClientOutput(output)));
+    Ok(())
+}
+
+fn run_listener(listener: UnixListener, backend: PortBackend, kill_evt: Event) {
+    #[derive(EventToken)]
+    enum Token {
+        Accept,
+        Kill,
+    }
+
+    let wait_ctx: WaitContext<Token> =
+        match WaitContext::build_with(&[(&listener, Token::Accept), (&kill_evt, Token::Kill)]) {
+            Ok(ctx) => ctx,
+            Err(e) => {
+                error!("failed creating WaitContext {:?}", e);
+                return;
+            }
+        };
+
+    // Socket of the current client. It is shut down when the client is replaced or the port goes
+    // away, so that the receive queue of the port reaches EOF on it.
+    let mut client: Option<UnixStream> = None;
+    'wait: loop {
+        let events = match wait_ctx.wait() {
+            Ok(events) => events,
+            Err(e) => {
+                error!("Failed to wait for events. {}", e);
+                break;
+            }
+        };
+        for event in events.iter() {
+            match event.token {
+                Token::Accept => match listener.accept() {
+                    Ok((stream, _)) => match connect_client(&backend, &stream) {
+                        Ok(()) => {
+                            if let Some(previous) = client.replace(stream) {
+                                let _ = previous.shutdown(Shutdown::Both);
+                            }
+                        }
+                        Err(e) => {
+                            error!("console port{}: failed to connect: {}", backend.id(), e)
+                        }
+                    },
+                    Err(e) => error!("console port{}: failed to accept: {}", backend.id(), e),
+                },
+                Token::Kill => break 'wait,
+            }
+        }
+    }
+
+    if let Some(client) = client {
+        let _ = client.shutdown(Shutdown::Both);
+    }
+}
+
+/// A port of a multi-port virtio-console bound to a Unix socket listening on the host.
+///
+/// The port is attached to the device for as long as the `SocketPort` lives. Each client
+/// connecting to the socket replaces the previous one, and the guest sees the port open while a
+/// client is connected.
+pub struct SocketPort {
+    backend: PortBackend,
+    path: PathBuf,
+    worker: Option<WorkerThread<()>>,
+}
+
+impl SocketPort {
+    /// Bind the port of `backend` to a socket listening at `path`, and attach it to the device
+    /// with the given info.
+    pub fn bind(backend: PortBackend, info: ConsolePortInfo, path: PathBuf) -> Result<SocketPort> {
+        let listener = UnixListener::bind(&path).map_err(|e| Error::Bind(e, path.clone()))?;
+        if let Err(e) = backend.attach(info) {
+            let _ = remove_file(&path);
+            return Err(Error::Attach(e));
+        }
+
+        let worker_backend = backend.clone();
+        let worker =
+            WorkerThread::start(format!("v_console_port{}", backend.id()), move |kill_evt| {
+                run_listener(listener, worker_backend, kill_evt)
+            });
+
+        Ok(SocketPort {
+            backend,
+            path,
+            worker: Some(worker),
+        })
+    }
+
+    /// Return the port id.
+    pub fn id(&self) -> u32 {
+        self.backend.id()
+    }
+
+    /// Return the path of the socket.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for SocketPort {
+    fn drop(&mut self) {
+        if let Some(worker) = self.worker.take() {
+            worker.stop();
+        }
+        if let Err(e) = self.backend.detach() {
+            error!("failed to detach console port{}: {:#}", self.id(), e);
+        }
+        if let Err(e) = remove_file(&self.path) {
+            warn!("failed to remove console port socket file: {}", e);
+        }
+    }
+}
+
+/// Socket ports of a multi-port virtio-console, added and removed while the device is running.
+pub struct SocketPorts {
+    table: PortTable,
+    // Backends of the port ids reserved for socket ports.
+    backends: Vec<PortBackend>,
+    ports: Vec<SocketPort>,
+}
+
+impl SocketPorts {
+    /// Create an empty set of socket ports, using the port ids of `backends`.
+    pub fn new(table: PortTable, backends: Vec<PortBackend>) -> SocketPorts {
+        SocketPorts {
+            table,
+            backends,
+            ports: Vec::new(),
+        }
+    }
+
+    /// Add a port with the given info bound to a socket listening at `path`, and return its id.
+    pub fn add(&mut self, info: ConsolePortInfo, path: PathBuf) -> Result<u32> {
+        if self.table.find_port(&info.name).is_some() {
+            return Err(Error::NameInUse(info.name));
+        }
+        let backend = self
+            .backends
+            .iter()
+            .find(|backend| self.ports.iter().all(|port| port.id() != backend.id()))
+            .ok_or(Error::NoFreePort)?;
+
+        let port = SocketPort::bind(backend.clone(), info, path)?;
+        let id = port.id();
+        self.ports.push(port);
+
+        Ok(id)
+    }
+
+    /// Remove the socket port named `name`.
+    pub fn remove(&mut self, name: &str) -> Result<()> {
+        let index = self
+            .table
+            .find_port(name)
+            .and_then(|id| self.ports.iter().position(|port| port.id() == id))
+            .ok_or_else(|| Error::NotFound(name.to_owned()))?;
+        self.ports.remove(index);
+
+        Ok(())
+    }
+
+    /// Return the status of all the ports of the device, socket bound or not.
+    pub fn status(&self) -> Vec<ConsolePortStatus> {
+        self.table
+            .ports()
+            .into_iter()
+            .map(|(id, info, connected)| ConsolePortStatus {
+                id,
+                name: info.name,
+                console: info.console,
+                path: self
+                    .ports
+                    .iter()
+                    .find(|port| port.id() == id)
+                    .map(|port| port.path().to_owned()),
+                connected,
+            })
+            .collect()
+    }
+
+    /// Execute a control command.
+    pub fn handle_command(&mut self, command: ConsoleControlCommand) -> ConsoleControlResult {
+        let result = match command {
+            ConsoleControlCommand::AddPort {
+                name,
+                path,
+                console,
+            } => self
+                .add(ConsolePortInfo { console, name }, path)
+                .map(|_| ConsoleControlResult::Ok),
+            ConsoleControlCommand::RemovePort { name } => {
+                self.remove(&name).map(|()| ConsoleControlResult::Ok)
+            }
+            ConsoleControlCommand::ListPorts => Ok(ConsoleControlResult::Ports(self.status())),
+        };
+
+        result.unwrap_or_else(|e| {
+            error!("console: {}", e);
+            ConsoleControlResult::Err(SysError::new(e.errno()))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::thread;
+    use std::time::Instant;
+
+    use base::Tube;
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::virtio::console::multiport::ControlPort;
+
+    fn wait_for<F: Fn() -> bool>(condition: F) {
+        let start = Instant::now();
+        while !condition() {
+            assert!(start.elapsed() < Duration::from_secs(5), "timed out");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn socket_ports(client_ports: u32) -> SocketPorts {
+        let info = ConsolePortInfo {
+            console: true,
+            name: "console".to_owned(),
+        };
+        let ports = std::iter::once(Some(info))
+            .chain(std::iter::repeat(None).take(client_ports as usize))
+            .collect();
+        let control_port = ControlPort::new(ports);
+        let table = control_port.ports().clone();
+        let backends = (1..=client_ports)
+            .map(|id| PortBackend::new(id, table.clone()).0)
+            .collect();
+
+        SocketPorts::new(table, backends)
+    }
+
+    fn is_connected(ports: &SocketPorts, id: u32) -> bool {
+        ports
+            .status()
+            .iter()
+            .any(|port| port.id == id && port.connected)
+    }
+
+    #[test]
+    fn add_connect_remove() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("agent.sock");
+        let mut ports = socket_ports(1);
+
+        let info = ConsolePortInfo {
+            console: false,
+            name: "org.qemu.guest_agent.0".to_owned(),
+        };
+        assert_eq!(ports.add(info, path.clone()).unwrap(), 1);
+        assert!(!is_connected(&ports, 1));
+
+        let mut client = UnixStream::connect(&path).unwrap();
+        wait_for(|| is_connected(&ports, 1));
+        assert_eq!(
+            ports.status()[1].path.as_deref(),
+            Some(path.as_path()),
+            "socket path not reported"
+        );
+
+        ports.remove("org.qemu.guest_agent.0").unwrap();
+        assert_eq!(ports.status().len(), 1);
+        assert!(!path.exists());
+        // The client gets disconnected along with the port.
+        assert_eq!(client.read(&mut [0u8; 1]).unwrap(), 0);
+    }
+
+    #[test]
+    fn add_errors() {
+        let dir = tempdir().unwrap();
+        let mut ports = socket_ports(1);
+
+        let info = ConsolePortInfo {
+            console: false,
+            name: "console".to_owned(),
+        };
+        assert!(matches!(
+            ports.add(info, dir.path().join("a.sock")),
+            Err(Error::NameInUse(_))
+        ));
+
+        let info = ConsolePortInfo {
+            console: false,
+            name: "a".to_owned(),
+        };
+        ports.add(info, dir.path().join("a.sock")).unwrap();
+        let info = ConsolePortInfo {
+            console: false,
+            name: "b".to_owned(),
+        };
+        assert!(matches!(
+            ports.add(info, dir.path().join("b.sock")),
+            Err(Error::NoFreePort)
+        ));
+
+        assert!(matches!(ports.remove("console"), Err(Error::NotFound(_))));
+        assert!(matches!(ports.remove("b"), Err(Error::NotFound(_))));
+    }
+
+    #[test]
+    fn control_commands() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("agent.sock");
+        let mut ports = socket_ports(2);
+
+        // Commands go through a tube, as they do between `crosvm console` and the device.
+        let (client, server) = Tube::pair().unwrap();
+        let mut run = |command: ConsoleControlCommand| {
+            client.send(&command).unwrap();
+            let command = server.recv::<ConsoleControlCommand>().unwrap();
+            server.send(&ports.handle_command(command)).unwrap();
+            client.recv::<ConsoleControlResult>().unwrap()
+        };
+        let add = |name: &str, path: &Path| ConsoleControlCommand::AddPort {
+            name: name.to_owned(),
+            path: path.to_owned(),
+            console: false,
+        };
+        let remove = |name: &str| ConsoleControlCommand::RemovePort {
+            name: name.to_owned(),
+        };
+        let console = ConsolePortStatus {
+            id: 0,
+            name: "console".to_owned(),
+            console: true,
+            path: None,
+            connected: true,
+        };
+
+        assert_eq!(run(add("agent", &path)), ConsoleControlResult::Ok);
+        assert_eq!(
+            run(add("agent", &dir.path().join("other.sock"))),
+            ConsoleControlResult::Err(SysError::new(libc::EEXIST))
+        );
+        assert_eq!(
+            run(ConsoleControlCommand::ListPorts),
+            ConsoleControlResult::Ports(vec![
+                console.clone(),
+                ConsolePortStatus {
+                    id: 1,
+                    name: "agent".to_owned(),
+                    console: false,
+                    path: Some(path.clone()),
+                    connected: false,
+                },
+            ])
+        );
+
+        // The id of a removed port is reused by the next one.
+        assert_eq!(run(remove("agent")), ConsoleControlResult::Ok);
+        assert_eq!(
+            run(remove("agent")),
+            ConsoleControlResult::Err(SysError::new(libc::ENOENT))
+        );
+        assert_eq!(
+            run(ConsoleControlCommand::ListPorts),
+            ConsoleControlResult::Ports(vec![console])
+        );
+        assert_eq!(
+            run(add("a", &dir.path().join("a.sock"))),
+            ConsoleControlResult::Ok
+        );
+        assert_eq!(
+            run(add("b", &dir.path().join("b.sock"))),
+            ConsoleControlResult::Ok
+        );
+        assert_eq!(
+            run(add("c", &dir.path().join("c.sock"))),
+            ConsoleControlResult::Err(SysError::new(libc::ENOSPC))
+        );
+        let ConsoleControlResult::Ports(status) = run(ConsoleControlCommand::ListPorts) else {
+            panic!("no port list");
+        };
+        let ids: Vec<(u32, String)> = status
+            .into_iter()
+            .map(|port| (port.id, port.name))
+            .collect();
+        assert_eq!(
+            ids,
+            vec![
+                (0, "console".to_owned()),
+                (1, "a".to_owned()),
+                (2, "b".to_owned())
+            ]
+        );
+
+        // Binding to a path that cannot be created reports the error of the socket.
+        assert_eq!(run(remove("b")), ConsoleControlResult::Ok);
+        assert_eq!(
+            run(add("b", &dir.path().join("missing/b.sock"))),
+            ConsoleControlResult::Err(SysError::new(libc::ENOENT))
+        );
+    }
+}
@@ -153,6 +153,7 @@ pub mod snd {
         pub jacks: Le32,
         pub streams: Le32,
         pub chmaps: Le32,
+        pub controls: Le32,
     }
 }
 
@@ -188,6 +189,8 @@ pub mod video {
         LibvdaVd,
         #[cfg(feature = "ffmpeg")]
         Ffmpeg,
+        #[cfg(feature = "swdec")]
+        Swdec,
         #[cfg(feature = "vaapi")]
         Vaapi,
     }
@@ -204,6 +207,13 @@ pub mod video {
             | 1u64 << VIRTIO_VIDEO_F_RESOURCE_VIRTIO_OBJECT
     }
 
+    /// The software decoder copies frames from and to any kind of resource.
+    pub fn swdec_supported_virtio_features() -> u64 {
+        1u64 << VIRTIO_VIDEO_F_RESOURCE_GUEST_PAGES
+            | 1u64 << VIRTIO_VIDEO_F_RESOURCE_NON_CONTIG
+            | 1u64 << VIRTIO_VIDEO_F_RESOURCE_VIRTIO_OBJECT
+    }
+
     /// The same set of virtio features is supported by the vaapi decoder and encoder.
     pub fn vaapi_supported_virtio_features() -> u64 {
         1u64 << VIRTIO_VIDEO_F_RESOURCE_GUEST_PAGES
@@ -224,6 +234,8 @@ pub mod video {
             }
             #[cfg(feature = "ffmpeg")]
             VideoBackendType::Ffmpeg => ffmpeg_supported_virtio_features(),
+            #[cfg(feature = "swdec")]
+            VideoBackendType::Swdec => swdec_supported_virtio_features(),
             #[cfg(feature = "vaapi")]
             VideoBackendType::Vaapi => vaapi_supported_virtio_features(),
         }
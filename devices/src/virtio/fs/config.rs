@@ -2,13 +2,16 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
 use std::time::Duration;
 
-#[cfg(feature = "arc_quota")]
 use serde::de::Error;
 use serde::Deserialize;
 use serde::Deserializer;
 use serde::Serialize;
+use serde::Serializer;
 use serde_keyvalue::FromKeyValues;
 
 /// The caching policy that the file system should report to the FUSE client. By default the FUSE
@@ -34,6 +37,78 @@ pub enum CachePolicy {
     Always,
 }
 
+/// A range of IDs translated between the guest and the host. It is written like a line of
+/// `/proc/[pid]/uid_map`, i.e. "GUEST HOST COUNT", and maps the guest IDs `GUEST..GUEST+COUNT` to
+/// the host IDs `HOST..HOST+COUNT`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdMapRange {
+    pub guest: u32,
+    pub host: u32,
+    pub count: u32,
+}
+
+impl FromStr for IdMapRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values = s
+            .split_whitespace()
+            .map(|v| v.parse::<u32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("invalid ID in map range \"{s}\": {e}"))?;
+        let [guest, host, count] = values[..] else {
+            return Err(format!(
+                "ID map range \"{s}\" must be of the form \"GUEST HOST COUNT\""
+            ));
+        };
+        if count == 0
+            || guest.checked_add(count - 1).is_none()
+            || host.checked_add(count - 1).is_none()
+        {
+            return Err(format!("invalid ID map range \"{s}\""));
+        }
+        Ok(IdMapRange { guest, host, count })
+    }
+}
+
+impl fmt::Display for IdMapRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {}", self.guest, self.host, self.count)
+    }
+}
+
+impl Serialize for IdMapRange {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for IdMapRange {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(D::Error::custom)
+    }
+}
+
+/// Which requests from the guest are performed with the squash IDs instead of their own
+/// translated IDs, similar to the `root_squash` and `all_squash` NFS export options.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Serialize, Deserialize, FromKeyValues)]
+#[serde(rename_all = "kebab-case")]
+pub enum IdSquash {
+    /// IDs are only translated using the ID maps.
+    #[default]
+    None,
+    /// Requests by the guest root user or group are performed as the squash user or group.
+    Root,
+    /// All requests are performed as the squash user and group.
+    All,
+}
+
+const fn config_default_squash_id() -> u32 {
+    // The overflow ID of the kernel, usually named "nobody".
+    65534
+}
+
 const fn config_default_timeout() -> Duration {
     Duration::from_secs(5)
 }
@@ -188,6 +263,77 @@ pub struct Config {
     // The default value for this option is 0.
     #[serde(default)]
     pub max_dynamic_xattr: usize,
+
+    /// Translation of the user IDs of the guest to user IDs of the host, as a list of
+    /// "GUEST HOST COUNT" ranges, e.g. `uid_map=[0 1000 1,1000 100000 1000]`.
+    ///
+    /// IDs are translated on top of the user namespace of the file system process: the host IDs
+    /// are the ones seen by that process. Requests by guest users outside of the map are performed
+    /// as `squash_uid`, and files owned by host users outside of the map appear as owned by the
+    /// overflow user (65534) in the guest.
+    ///
+    /// The default value for this option is an empty list, which passes IDs through unchanged.
+    #[serde(default)]
+    pub uid_map: Vec<IdMapRange>,
+
+    /// Translation of the group IDs of the guest to group IDs of the host, in the same format as
+    /// `uid_map`.
+    ///
+    /// The default value for this option is an empty list, which passes IDs through unchanged.
+    #[serde(default)]
+    pub gid_map: Vec<IdMapRange>,
+
+    /// Whether requests by the guest root user, or by every user, should be performed as
+    /// `squash_uid` and `squash_gid` instead of their translated IDs.
+    ///
+    /// The default value for this option is `none`.
+    #[serde(default)]
+    pub id_squash: IdSquash,
+
+    /// Host user ID of the requests from squashed or unmapped guest users.
+    ///
+    /// The default value for this option is 65534.
+    #[serde(default = "config_default_squash_id")]
+    pub squash_uid: u32,
+
+    /// Host group ID of the requests from squashed or unmapped guest groups.
+    ///
+    /// The default value for this option is 65534.
+    #[serde(default = "config_default_squash_id")]
+    pub squash_gid: u32,
+
+    /// Read-only host directories to layer the shared directory over, from the highest to the
+    /// lowest, e.g. `overlay_lower=[/srv/base,/srv/image]`.
+    ///
+    /// When set, the shared directory is the writable upper layer of a copy-on-write overlay: the
+    /// guest sees the merged contents of all the directories, and its changes are only written to
+    /// the shared directory. This cannot be combined with DAX, `ascii_casefold` or ID translation.
+    ///
+    /// The default value for this option is an empty list, which shares the directory directly.
+    #[serde(default)]
+    pub overlay_lower: Vec<PathBuf>,
+
+    /// Whether the shared path is a tar archive, a squashfs image or an EROFS image, whose
+    /// contents are served read-only, rather than a directory.
+    ///
+    /// The image is opened before the device enters its sandbox and indexed when the device is
+    /// created. This cannot be combined with DAX or `overlay_lower`.
+    ///
+    /// The default value for this option is `false`.
+    #[serde(default)]
+    pub archive: bool,
+
+    /// Submit large reads and writes through io_uring, which copies file data directly between the
+    /// host page cache and guest memory from the kernel's worker threads.
+    ///
+    /// Each device worker creates its own ring that only accepts `readv` and `writev` operations,
+    /// and keeps handling the requests of its queue while the transfers of earlier ones are in
+    /// flight. If io_uring isn't available, the device falls back to regular `preadv`/`pwritev`
+    /// calls.
+    ///
+    /// The default value for this option is `false`.
+    #[serde(default)]
+    pub io_uring: bool,
 }
 
 impl Default for Config {
@@ -205,6 +351,14 @@ impl Default for Config {
             posix_acl: config_default_posix_acl(),
             max_dynamic_perm: 0,
             max_dynamic_xattr: 0,
+            uid_map: Vec::new(),
+            gid_map: Vec::new(),
+            id_squash: Default::default(),
+            squash_uid: config_default_squash_id(),
+            squash_gid: config_default_squash_id(),
+            overlay_lower: Vec::new(),
+            archive: false,
+            io_uring: false,
         }
     }
 }
@@ -0,0 +1,276 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Translation of user and group IDs between the guest and the host.
+
+use std::io;
+
+use crate::virtio::fs::config::Config;
+use crate::virtio::fs::config::IdMapRange;
+use crate::virtio::fs::config::IdSquash;
+
+/// The ID reported to the guest for host IDs without a mapping, like the kernel does for user
+/// namespaces.
+const OVERFLOW_ID: u32 = 65534;
+
+// Layout of the value of the `system.posix_acl_access` and `system.posix_acl_default` xattrs. See
+// `include/uapi/linux/posix_acl_xattr.h`.
+const ACL_XATTR_VERSION: u32 = 2;
+const ACL_XATTR_HEADER_SIZE: usize = 4;
+const ACL_XATTR_ENTRY_SIZE: usize = 8;
+const ACL_USER: u16 = 0x02;
+const ACL_GROUP: u16 = 0x08;
+
+pub const POSIX_ACL_ACCESS_XATTR: &[u8] = b"system.posix_acl_access";
+pub const POSIX_ACL_DEFAULT_XATTR: &[u8] = b"system.posix_acl_default";
+
+/// Translates IDs with one of the `uid_map` or `gid_map` lists of the configuration.
+struct IdMap {
+    ranges: Vec<IdMapRange>,
+    squash: IdSquash,
+    squash_id: u32,
+}
+
+impl IdMap {
+    fn is_identity(&self) -> bool {
+        self.ranges.is_empty() && self.squash == IdSquash::None
+    }
+
+    /// Returns the host ID mapped to guest ID `id`, ignoring squashing.
+    fn map_to_host(&self, id: u32) -> Option<u32> {
+        if self.ranges.is_empty() {
+            return Some(id);
+        }
+        self.ranges
+            .iter()
+            .find(|r| id >= r.guest && id - r.guest < r.count)
+            .map(|r| r.host + (id - r.guest))
+    }
+
+    /// Returns the host ID that guest ID `id` acts as.
+    fn to_host(&self, id: u32) -> Option<u32> {
+        match self.squash {
+            IdSquash::All => Some(self.squash_id),
+            IdSquash::Root if id == 0 => Some(self.squash_id),
+            _ => self.map_to_host(id),
+        }
+    }
+
+    /// Returns the guest ID that host ID `id` appears as.
+    fn to_guest(&self, id: u32) -> u32 {
+        if self.ranges.is_empty() {
+            return id;
+        }
+        self.ranges
+            .iter()
+            .find(|r| id >= r.host && id - r.host < r.count)
+            .map_or(OVERFLOW_ID, |r| r.guest + (id - r.host))
+    }
+}
+
+/// Translates the IDs found in requests of the guest to host IDs, and the IDs of host files back to
+/// guest IDs.
+pub struct IdTranslator {
+    uids: IdMap,
+    gids: IdMap,
+}
+
+impl IdTranslator {
+    pub fn new(cfg: &Config) -> IdTranslator {
+        IdTranslator {
+            uids: IdMap {
+                ranges: cfg.uid_map.clone(),
+                squash: cfg.id_squash,
+                squash_id: cfg.squash_uid,
+            },
+            gids: IdMap {
+                ranges: cfg.gid_map.clone(),
+                squash: cfg.id_squash,
+                squash_id: cfg.squash_gid,
+            },
+        }
+    }
+
+    /// Returns the host user and group to perform a request from guest user `uid` and group `gid`
+    /// as. Unmapped IDs are replaced by the squash IDs.
+    pub fn host_creds(&self, uid: u32, gid: u32) -> (u32, u32) {
+        (
+            self.uids.to_host(uid).unwrap_or(self.uids.squash_id),
+            self.gids.to_host(gid).unwrap_or(self.gids.squash_id),
+        )
+    }
+
+    /// Returns whether guest user `uid` should bypass permission checks, as the guest root user
+    /// does unless it is squashed.
+    pub fn is_privileged(&self, uid: u32) -> bool {
+        uid == 0 && self.uids.squash == IdSquash::None
+    }
+
+    /// Returns the host user to give ownership of a file to when the guest sets it to `uid`.
+    pub fn host_uid(&self, uid: u32) -> io::Result<u32> {
+        self.uids
+            .to_host(uid)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))
+    }
+
+    /// Returns the host group to give ownership of a file to when the guest sets it to `gid`.
+    pub fn host_gid(&self, gid: u32) -> io::Result<u32> {
+        self.gids
+            .to_host(gid)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))
+    }
+
+    /// Replaces the host owner of `st` with the guest one.
+    pub fn stat_to_guest(&self, st: &mut libc::stat64) {
+        st.st_uid = self.uids.to_guest(st.st_uid);
+        st.st_gid = self.gids.to_guest(st.st_gid);
+    }
+
+    /// Returns whether `name` is an xattr whose value contains IDs.
+    pub fn is_acl_xattr(&self, name: &[u8]) -> bool {
+        (!self.uids.is_identity() || !self.gids.is_identity())
+            && (name == POSIX_ACL_ACCESS_XATTR || name == POSIX_ACL_DEFAULT_XATTR)
+    }
+
+    /// Translates the IDs of the POSIX ACL xattr `value` set by the guest to host IDs.
+    pub fn acl_to_host(&self, value: &mut [u8]) -> io::Result<()> {
+        for_each_acl_id(value, |tag, id| match tag {
+            ACL_USER => self.uids.map_to_host(id),
+            _ => self.gids.map_to_host(id),
+        })
+    }
+
+    /// Translates the IDs of the POSIX ACL xattr `value` read from the host to guest IDs.
+    pub fn acl_to_guest(&self, value: &mut [u8]) -> io::Result<()> {
+        for_each_acl_id(value, |tag, id| match tag {
+            ACL_USER => Some(self.uids.to_guest(id)),
+            _ => Some(self.gids.to_guest(id)),
+        })
+    }
+}
+
+/// Replaces the ID of each named user or group entry of the POSIX ACL xattr `value` by the result
+/// of `f`, or fails with `EINVAL` if it returns `None`.
+fn for_each_acl_id<F>(value: &mut [u8], mut f: F) -> io::Result<()>
+where
+    F: FnMut(u16, u32) -> Option<u32>,
+{
+    let einval = || io::Error::from_raw_os_error(libc::EINVAL);
+    if value.len() < ACL_XATTR_HEADER_SIZE
+        || (value.len() - ACL_XATTR_HEADER_SIZE) % ACL_XATTR_ENTRY_SIZE != 0
+    {
+        return Err(einval());
+    }
+    let (header, entries) = value.split_at_mut(ACL_XATTR_HEADER_SIZE);
+    if u32::from_le_bytes(header.try_into().unwrap()) != ACL_XATTR_VERSION {
+        return Err(einval());
+    }
+
+    for entry in entries.chunks_exact_mut(ACL_XATTR_ENTRY_SIZE) {
+        let tag = u16::from_le_bytes([entry[0], entry[1]]);
+        if tag != ACL_USER && tag != ACL_GROUP {
+            continue;
+        }
+        let id = u32::from_le_bytes(entry[4..8].try_into().unwrap());
+        let id = f(tag, id).ok_or_else(einval)?;
+        entry[4..8].copy_from_slice(&id.to_le_bytes());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn translator(cfg: &str) -> IdTranslator {
+        IdTranslator::new(&serde_keyvalue::from_key_values(cfg).unwrap())
+    }
+
+    fn acl(entries: &[(u16, u32)]) -> Vec<u8> {
+        let mut value = ACL_XATTR_VERSION.to_le_bytes().to_vec();
+        for (tag, id) in entries {
+            value.extend_from_slice(&tag.to_le_bytes());
+            value.extend_from_slice(&7u16.to_le_bytes());
+            value.extend_from_slice(&id.to_le_bytes());
+        }
+        value
+    }
+
+    #[test]
+    fn identity() {
+        let ids = translator("");
+        assert_eq!(ids.host_creds(1000, 1001), (1000, 1001));
+        assert!(ids.is_privileged(0));
+        assert_eq!(ids.host_uid(5).unwrap(), 5);
+        assert!(!ids.is_acl_xattr(POSIX_ACL_ACCESS_XATTR));
+    }
+
+    #[test]
+    fn mapped_ids() {
+        let ids = translator("uid_map=[0 100000 1,1000 2000 10],gid_map=[0 200000 1]");
+        assert_eq!(ids.host_creds(0, 0), (100000, 200000));
+        assert_eq!(ids.host_creds(1009, 1009), (2009, 65534));
+        assert_eq!(ids.host_creds(1010, 0), (65534, 200000));
+        assert!(ids.is_privileged(0));
+
+        assert_eq!(ids.host_uid(1005).unwrap(), 2005);
+        assert!(ids.host_uid(999).is_err());
+        assert!(ids.host_gid(1).is_err());
+
+        // SAFETY: stat64 is a plain C struct for which all zeroes is a valid value.
+        let mut st: libc::stat64 = unsafe { std::mem::zeroed() };
+        st.st_uid = 2003;
+        st.st_gid = 0;
+        ids.stat_to_guest(&mut st);
+        assert_eq!((st.st_uid, st.st_gid), (1003, 65534));
+    }
+
+    #[test]
+    fn squash() {
+        let ids = translator("uid_map=[0 100000 2000],id_squash=root,squash_uid=3000");
+        assert_eq!(ids.host_creds(0, 0), (3000, 65534));
+        assert_eq!(ids.host_creds(1, 1), (100001, 1));
+        assert!(!ids.is_privileged(0));
+        assert_eq!(ids.host_uid(0).unwrap(), 3000);
+
+        let ids = translator("id_squash=all,squash_uid=3000,squash_gid=3001");
+        assert_eq!(ids.host_creds(1000, 1000), (3000, 3001));
+        assert!(ids.is_acl_xattr(POSIX_ACL_DEFAULT_XATTR));
+    }
+
+    #[test]
+    fn acl_xattr() {
+        let ids = translator("uid_map=[0 100000 2000],gid_map=[0 200000 2000]");
+        // Owner, named user, named group and other entries.
+        let mut value = acl(&[
+            (0x01, u32::MAX),
+            (ACL_USER, 1000),
+            (ACL_GROUP, 5),
+            (0x20, u32::MAX),
+        ]);
+        ids.acl_to_host(&mut value).unwrap();
+        assert_eq!(
+            value,
+            acl(&[
+                (0x01, u32::MAX),
+                (ACL_USER, 101000),
+                (ACL_GROUP, 200005),
+                (0x20, u32::MAX),
+            ])
+        );
+        ids.acl_to_guest(&mut value).unwrap();
+        assert_eq!(
+            value,
+            acl(&[
+                (0x01, u32::MAX),
+                (ACL_USER, 1000),
+                (ACL_GROUP, 5),
+                (0x20, u32::MAX),
+            ])
+        );
+
+        assert!(ids.acl_to_host(&mut acl(&[(ACL_USER, 3000)])).is_err());
+        assert!(ids.acl_to_host(&mut value[..6]).is_err());
+    }
+}
@@ -3,10 +3,12 @@
 // found in the LICENSE file.
 
 use std::collections::BTreeMap;
+use std::fs::File;
 use std::io;
 use std::sync::Arc;
 
 use anyhow::anyhow;
+use anyhow::Context;
 use base::error;
 use base::warn;
 use base::AsRawDescriptor;
@@ -17,6 +19,8 @@ use base::WorkerThread;
 use data_model::Le32;
 use remain::sorted;
 use resources::Alloc;
+use serde::Deserialize;
+use serde::Serialize;
 use sync::Mutex;
 use thiserror::Error;
 use virtio_sys::virtio_fs::virtio_fs_config;
@@ -45,16 +49,24 @@ mod arc_ioctl;
 mod caps;
 mod config;
 mod expiring_map;
+mod id_map;
 mod multikey;
+pub mod overlay;
 pub mod passthrough;
 mod read_dir;
 mod worker;
 
 pub use config::CachePolicy;
 pub use config::Config;
+pub use config::IdMapRange;
+pub use config::IdSquash;
+use fuse::archive::ArchiveFs;
+use fuse::filesystem::FileSystem;
 use fuse::Server;
+use overlay::OverlayFs;
 use passthrough::PassthroughFs;
 pub use worker::process_fs_queue;
+pub use worker::FsUring;
 use worker::Worker;
 
 const QUEUE_SIZE: u16 = 1024;
@@ -97,6 +109,9 @@ pub enum Error {
     /// Failed to signal the virio used queue.
     #[error("failed to signal used queue: {0}")]
     SignalUsedQueue(SysError),
+    /// Failed to submit requests to the io_uring of a worker.
+    #[error("failed to submit requests to io_uring: {0}")]
+    SubmitIoUring(io_uring::Error),
     /// The tag for the Fs device was too long to fit in the config space.
     #[error("Fs device tag is too long: len = {0}, max = {}", FS_MAX_TAG_LEN)]
     TagTooLong(usize),
@@ -106,6 +121,9 @@ pub enum Error {
     /// Error while polling for events.
     #[error("failed to wait for events: {0}")]
     WaitError(SysError),
+    /// Failed to wait for the completion of requests submitted to io_uring.
+    #[error("failed to wait for io_uring completions: {0}")]
+    WaitIoUring(io_uring::Error),
 }
 
 impl From<fuse::Error> for Error {
@@ -116,16 +134,112 @@ impl From<fuse::Error> for Error {
 
 pub type Result<T> = ::std::result::Result<T, Error>;
 
-pub struct Fs {
+/// A file system implementation that can back an Fs device.
+pub trait FsBackend: FileSystem + Sync + Send + 'static {
+    /// Returns whether files are mapped into the DAX window of the device.
+    fn use_dax(&self) -> bool;
+    /// Returns whether large reads and writes are submitted through io_uring.
+    fn use_io_uring(&self) -> bool;
+    fn keep_rds(&self) -> Vec<RawDescriptor>;
+    fn snapshot(&self) -> anyhow::Result<serde_json::Value>;
+    fn restore(&self, data: serde_json::Value) -> anyhow::Result<()>;
+}
+
+impl FsBackend for PassthroughFs {
+    fn use_dax(&self) -> bool {
+        self.cfg().use_dax
+    }
+
+    fn use_io_uring(&self) -> bool {
+        self.cfg().io_uring
+    }
+
+    fn keep_rds(&self) -> Vec<RawDescriptor> {
+        PassthroughFs::keep_rds(self)
+    }
+
+    fn snapshot(&self) -> anyhow::Result<serde_json::Value> {
+        serde_json::to_value(PassthroughFs::snapshot(self)?)
+            .context("failed to serialize passthrough fs snapshot")
+    }
+
+    fn restore(&self, data: serde_json::Value) -> anyhow::Result<()> {
+        let snapshot = serde_json::from_value(data)
+            .context("failed to deserialize passthrough fs snapshot")?;
+        Ok(PassthroughFs::restore(self, snapshot)?)
+    }
+}
+
+impl FsBackend for OverlayFs {
+    fn use_dax(&self) -> bool {
+        self.cfg().use_dax
+    }
+
+    fn use_io_uring(&self) -> bool {
+        self.cfg().io_uring
+    }
+
+    fn keep_rds(&self) -> Vec<RawDescriptor> {
+        OverlayFs::keep_rds(self)
+    }
+
+    fn snapshot(&self) -> anyhow::Result<serde_json::Value> {
+        anyhow::bail!("snapshot of the overlay file system is not supported")
+    }
+
+    fn restore(&self, _data: serde_json::Value) -> anyhow::Result<()> {
+        anyhow::bail!("restore of the overlay file system is not supported")
+    }
+}
+
+impl FsBackend for ArchiveFs {
+    fn use_dax(&self) -> bool {
+        false
+    }
+
+    // File data is served from the archive's own block cache rather than read straight into guest
+    // memory.
+    fn use_io_uring(&self) -> bool {
+        false
+    }
+
+    fn keep_rds(&self) -> Vec<RawDescriptor> {
+        ArchiveFs::keep_rds(self)
+    }
+
+    // The index of an image is rebuilt identically from the same image, so the file system has no
+    // state to save.
+    fn snapshot(&self) -> anyhow::Result<serde_json::Value> {
+        Ok(serde_json::Value::Null)
+    }
+
+    fn restore(&self, _data: serde_json::Value) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+pub struct Fs<F: FsBackend = PassthroughFs> {
     cfg: virtio_fs_config,
     tag: String,
-    fs: Option<PassthroughFs>,
+    fs: Option<F>,
+    // The server the file system is moved into on the first activation. It is kept across sleeps.
+    server: Option<Arc<Server<F>>>,
     queue_sizes: Box<[u16]>,
     avail_features: u64,
     acked_features: u64,
     pci_bar: Option<Alloc>,
     tube: Option<Tube>,
-    workers: Vec<WorkerThread<Result<()>>>,
+    socket: Option<Arc<Mutex<Tube>>>,
+    // The memory slot of the DAX window.
+    slot: u32,
+    workers: Vec<(usize, WorkerThread<Queue>)>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct FsSnapshot {
+    avail_features: u64,
+    acked_features: u64,
+    fs: serde_json::Value,
 }
 
 impl Fs {
@@ -136,6 +250,55 @@ impl Fs {
         fs_cfg: Config,
         tube: Tube,
     ) -> Result<Fs> {
+        let fs = PassthroughFs::new(tag, fs_cfg).map_err(Error::CreateFs)?;
+        Fs::with_fs(base_features, tag, num_workers, fs, tube)
+    }
+}
+
+impl Fs<OverlayFs> {
+    /// Creates a device serving the shared directory as the upper layer of an overlay of the
+    /// `overlay_lower` directories of `fs_cfg`.
+    pub fn new_overlay(
+        base_features: u64,
+        tag: &str,
+        num_workers: usize,
+        fs_cfg: Config,
+        tube: Tube,
+    ) -> Result<Fs<OverlayFs>> {
+        let fs = OverlayFs::new(tag, fs_cfg).map_err(Error::CreateFs)?;
+        Fs::with_fs(base_features, tag, num_workers, fs, tube)
+    }
+}
+
+impl Fs<ArchiveFs> {
+    /// Creates a device serving the tar archive, squashfs image or EROFS image `image` read-only.
+    pub fn new_archive(
+        base_features: u64,
+        tag: &str,
+        num_workers: usize,
+        fs_cfg: Config,
+        image: File,
+        tube: Tube,
+    ) -> Result<Fs<ArchiveFs>> {
+        if fs_cfg.use_dax || !fs_cfg.overlay_lower.is_empty() {
+            return Err(Error::CreateFs(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "archives cannot be combined with DAX or overlays",
+            )));
+        }
+        let fs = ArchiveFs::new(image, fs_cfg.timeout).map_err(Error::CreateFs)?;
+        Fs::with_fs(base_features, tag, num_workers, fs, tube)
+    }
+}
+
+impl<F: FsBackend> Fs<F> {
+    fn with_fs(
+        base_features: u64,
+        tag: &str,
+        num_workers: usize,
+        fs: F,
+        tube: Tube,
+    ) -> Result<Fs<F>> {
         if tag.len() > FS_MAX_TAG_LEN {
             return Err(Error::TagTooLong(tag.len()));
         }
@@ -148,8 +311,6 @@ impl Fs {
             num_request_queues: Le32::from(num_workers as u32),
         };
 
-        let fs = PassthroughFs::new(tag, fs_cfg).map_err(Error::CreateFs)?;
-
         // There is always a high priority queue in addition to the request queues.
         let num_queues = num_workers + 1;
 
@@ -157,22 +318,35 @@ impl Fs {
             cfg,
             tag: tag.to_string(),
             fs: Some(fs),
+            server: None,
             queue_sizes: vec![QUEUE_SIZE; num_queues].into_boxed_slice(),
             avail_features: base_features,
             acked_features: 0,
             pci_bar: None,
             tube: Some(tube),
+            socket: None,
+            slot: 0,
             workers: Vec::with_capacity(num_workers + 1),
         })
     }
+
+    fn file_system(&self) -> &F {
+        match &self.server {
+            Some(server) => server.fs(),
+            None => self
+                .fs
+                .as_ref()
+                .expect("missing file system implementation"),
+        }
+    }
 }
 
-impl VirtioDevice for Fs {
+impl<F: FsBackend> VirtioDevice for Fs<F> {
     fn keep_rds(&self) -> Vec<RawDescriptor> {
         let mut fds = self
             .fs
             .as_ref()
-            .map(PassthroughFs::keep_rds)
+            .map(FsBackend::keep_rds)
             .unwrap_or_default();
         if let Some(rd) = self.tube.as_ref().map(|s| s.as_raw_descriptor()) {
             fds.push(rd);
@@ -223,34 +397,45 @@ impl VirtioDevice for Fs {
             ));
         }
 
-        let fs = self.fs.take().expect("missing file system implementation");
-        let use_dax = fs.cfg().use_dax;
-
-        let server = Arc::new(Server::new(fs));
-        let socket = self.tube.take().expect("missing mapping socket");
-        let mut slot = 0;
-
-        // Set up shared memory for DAX.
-        // TODO(b/176129399): Remove cfg! once DAX is supported on ARM.
-        if cfg!(target_arch = "x86_64") && use_dax {
-            // Create the shared memory region now before we start processing requests.
-            let request = FsMappingRequest::AllocateSharedMemoryRegion(
-                self.pci_bar.as_ref().cloned().expect("No pci_bar"),
-            );
-            socket
-                .send(&request)
-                .expect("failed to send allocation message");
-            slot = match socket.recv() {
-                Ok(VmResponse::RegisterMemory { gfn: _, slot }) => slot,
-                Ok(VmResponse::Err(e)) => panic!("failed to allocate shared memory region: {}", e),
-                r => panic!(
-                    "unexpected response to allocate shared memory region: {:?}",
-                    r
-                ),
-            };
+        let server = self
+            .server
+            .get_or_insert_with(|| {
+                let fs = self.fs.take().expect("missing file system implementation");
+                Arc::new(Server::new(fs))
+            })
+            .clone();
+        let use_dax = server.fs().use_dax();
+        let use_io_uring = server.fs().use_io_uring();
+
+        // The mapping socket and the shared memory region are only set up on the first
+        // activation, and kept when waking up from a sleep.
+        if let Some(socket) = self.tube.take() {
+            // Set up shared memory for DAX.
+            // TODO(b/176129399): Remove cfg! once DAX is supported on ARM.
+            if cfg!(target_arch = "x86_64") && use_dax {
+                // Create the shared memory region now before we start processing requests.
+                let request = FsMappingRequest::AllocateSharedMemoryRegion(
+                    self.pci_bar.as_ref().cloned().expect("No pci_bar"),
+                );
+                socket
+                    .send(&request)
+                    .expect("failed to send allocation message");
+                self.slot = match socket.recv() {
+                    Ok(VmResponse::RegisterMemory { gfn: _, slot }) => slot,
+                    Ok(VmResponse::Err(e)) => {
+                        panic!("failed to allocate shared memory region: {}", e)
+                    }
+                    r => panic!(
+                        "unexpected response to allocate shared memory region: {:?}",
+                        r
+                    ),
+                };
+            }
+            self.socket = Some(Arc::new(Mutex::new(socket)));
         }
 
-        let socket = Arc::new(Mutex::new(socket));
+        let socket = self.socket.clone().expect("missing mapping socket");
+        let slot = self.slot;
         let mut watch_resample_event = true;
 
         self.workers = queues
@@ -262,22 +447,26 @@ impl VirtioDevice for Fs {
 
                 let worker =
                     WorkerThread::start(format!("v_fs:{}:{}", self.tag, idx), move |kill_evt| {
-                        let mut worker = Worker::new(queue, server, irq, socket, slot);
-                        worker.run(kill_evt, watch_resample_event)
+                        let mut worker =
+                            Worker::new(queue, server, irq, socket, slot, use_io_uring);
+                        if let Err(e) = worker.run(kill_evt, watch_resample_event) {
+                            error!("virtio-fs worker failed: {}", e);
+                        }
+                        worker.into_queue()
                     });
 
                 if watch_resample_event {
                     watch_resample_event = false;
                 }
 
-                worker
+                (idx, worker)
             })
             .collect();
         Ok(())
     }
 
     fn get_device_bars(&mut self, address: PciAddress) -> Vec<PciBarConfiguration> {
-        if self.fs.as_ref().map_or(false, |fs| !fs.cfg().use_dax) {
+        if self.fs.as_ref().map_or(false, |fs| !fs.use_dax()) {
             return vec![];
         }
 
@@ -297,7 +486,7 @@ impl VirtioDevice for Fs {
     }
 
     fn get_device_caps(&self) -> Vec<Box<dyn PciCapability>> {
-        if self.fs.as_ref().map_or(false, |fs| !fs.cfg().use_dax) {
+        if self.fs.as_ref().map_or(false, |fs| !fs.use_dax()) {
             return vec![];
         }
 
@@ -309,4 +498,57 @@ impl VirtioDevice for Fs {
             VIRTIO_FS_SHMCAP_ID_CACHE as u8,
         ))]
     }
+
+    fn virtio_sleep(&mut self) -> anyhow::Result<Option<BTreeMap<usize, Queue>>> {
+        if self.workers.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(
+            self.workers
+                .drain(..)
+                .map(|(idx, worker)| (idx, worker.stop()))
+                .collect(),
+        ))
+    }
+
+    fn virtio_wake(
+        &mut self,
+        queues_state: Option<(GuestMemory, Interrupt, BTreeMap<usize, Queue>)>,
+    ) -> anyhow::Result<()> {
+        if let Some((mem, interrupt, queues)) = queues_state {
+            self.activate(mem, interrupt, queues)?;
+        }
+        Ok(())
+    }
+
+    fn virtio_snapshot(&mut self) -> anyhow::Result<serde_json::Value> {
+        let fs = self.file_system();
+        // The mappings of the DAX window are not tracked by the device.
+        anyhow::ensure!(
+            !fs.use_dax(),
+            "snapshot of virtio-fs device {} with DAX is not supported",
+            self.tag
+        );
+        serde_json::to_value(FsSnapshot {
+            avail_features: self.avail_features,
+            acked_features: self.acked_features,
+            fs: fs.snapshot().context("failed to snapshot file system")?,
+        })
+        .context("failed to serialize fs snapshot")
+    }
+
+    fn virtio_restore(&mut self, data: serde_json::Value) -> anyhow::Result<()> {
+        let snapshot: FsSnapshot =
+            serde_json::from_value(data).context("failed to deserialize fs snapshot")?;
+        anyhow::ensure!(
+            self.avail_features == snapshot.avail_features,
+            "available features for fs device do not match. expected: {}, got: {}",
+            snapshot.avail_features,
+            self.avail_features
+        );
+        self.acked_features = snapshot.acked_features;
+        self.file_system()
+            .restore(snapshot.fs)
+            .with_context(|| format!("failed to restore file system {}", self.tag))
+    }
 }
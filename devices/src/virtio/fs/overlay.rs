@@ -0,0 +1,1687 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! A copy-on-write file system that layers the shared directory over read-only directories.
+//!
+//! The shared directory is the writable upper layer and the `overlay_lower` directories are the
+//! lower layers, from the highest to the lowest. A name resolves to the entry of the highest layer
+//! that has it, except that directories of the same name are merged. The lower layers are never
+//! modified: files are copied to the upper layer when they are first modified ("copied up"), and
+//! the removal of an entry of a lower layer is recorded with a whiteout, an empty `.wh.NAME` file
+//! in the upper directory. A `.wh..wh..opq` file makes a directory opaque, hiding the directories
+//! of the same name in the lower layers. Names starting with `.wh.` are reserved and never appear
+//! in the guest.
+//!
+//! Unlike the kernel overlayfs, this needs no privileges, but the requests are performed with the
+//! credentials of the device and directories that exist in a lower layer cannot be renamed.
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::ffi::CStr;
+use std::ffi::CString;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use base::syscall;
+use base::AsRawDescriptor;
+use base::FromRawDescriptor;
+use base::RawDescriptor;
+use fuse::filesystem::Context;
+use fuse::filesystem::DirEntry;
+use fuse::filesystem::DirectoryIterator;
+use fuse::filesystem::Entry;
+use fuse::filesystem::FileSystem;
+use fuse::filesystem::FsOptions;
+use fuse::filesystem::OpenOptions;
+use fuse::filesystem::SetattrValid;
+use fuse::filesystem::ZeroCopyReader;
+use fuse::filesystem::ZeroCopyWriter;
+use fuse::filesystem::ROOT_ID;
+use once_cell::sync::OnceCell;
+use sync::Mutex;
+
+use crate::virtio::fs::config::CachePolicy;
+use crate::virtio::fs::config::Config;
+use crate::virtio::fs::config::IdSquash;
+use crate::virtio::fs::passthrough::ebadf;
+use crate::virtio::fs::passthrough::eexist;
+use crate::virtio::fs::passthrough::stat;
+use crate::virtio::fs::passthrough::statat;
+use crate::virtio::fs::read_dir::ReadDir;
+
+type Inode = u64;
+type Handle = u64;
+
+const CURRENT_DIR_CSTR: &[u8] = b".\0";
+const EMPTY_CSTR: &[u8] = b"\0";
+const OPAQUE_CSTR: &[u8] = b".wh..wh..opq\0";
+const PROC_CSTR: &[u8] = b"/proc\0";
+const ROOT_CSTR: &[u8] = b"/\0";
+
+const WHITEOUT_PREFIX: &[u8] = b".wh.";
+const COPY_UP_PREFIX: &str = ".wh..wh.copyup.";
+
+// The layer index of the upper directory. The lower directories follow it in order.
+const UPPER: usize = 0;
+
+fn const_cstr(bytes: &'static [u8]) -> &'static CStr {
+    CStr::from_bytes_with_nul(bytes).expect("invalid C string constant")
+}
+
+fn err(errno: i32) -> io::Error {
+    io::Error::from_raw_os_error(errno)
+}
+
+fn is_enoent(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(libc::ENOENT)
+}
+
+fn is_dir(st: &libc::stat64) -> bool {
+    st.st_mode & libc::S_IFMT == libc::S_IFDIR
+}
+
+fn is_reserved(name: &CStr) -> bool {
+    name.to_bytes().starts_with(WHITEOUT_PREFIX)
+}
+
+fn whiteout_name(name: &CStr) -> CString {
+    CString::new([WHITEOUT_PREFIX, name.to_bytes()].concat()).expect("name contains a nul byte")
+}
+
+/// Returns the path of entry `name` of the directory at `parent`.
+fn child_path(parent: &Path, name: &CStr) -> io::Result<PathBuf> {
+    let bytes = name.to_bytes();
+    if bytes.is_empty() || bytes == b"." || bytes == b".." || bytes.contains(&b'/') {
+        return Err(err(libc::EINVAL));
+    }
+    Ok(parent.join(OsStr::from_bytes(bytes)))
+}
+
+/// Returns the path of the parent directory of the non-root `path`, and the name of its entry.
+fn split_path(path: &Path) -> io::Result<(&Path, CString)> {
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => Ok((
+            parent,
+            CString::new(name.as_bytes()).map_err(|_| err(libc::EINVAL))?,
+        )),
+        _ => Err(err(libc::EINVAL)),
+    }
+}
+
+fn open_at<D: AsRawDescriptor>(
+    dir: &D,
+    name: &CStr,
+    flags: libc::c_int,
+    mode: libc::mode_t,
+) -> io::Result<File> {
+    // SAFETY: this doesn't modify any memory and we check the return value.
+    let fd = syscall!(unsafe {
+        libc::openat64(
+            dir.as_raw_descriptor(),
+            name.as_ptr(),
+            flags | libc::O_CLOEXEC,
+            mode,
+        )
+    })?;
+    // SAFETY: safe because we just opened this descriptor.
+    Ok(unsafe { File::from_raw_descriptor(fd) })
+}
+
+fn open_absolute(path: &CStr, flags: libc::c_int) -> io::Result<File> {
+    // SAFETY: this doesn't modify any memory and we check the return value.
+    let fd = syscall!(unsafe {
+        libc::openat64(libc::AT_FDCWD, path.as_ptr(), flags | libc::O_CLOEXEC)
+    })?;
+    // SAFETY: safe because we just opened this descriptor.
+    Ok(unsafe { File::from_raw_descriptor(fd) })
+}
+
+/// Opens entry `name` of `dir` with `O_PATH`, without following symlinks.
+fn open_path<D: AsRawDescriptor>(dir: &D, name: &CStr) -> io::Result<File> {
+    open_at(dir, name, libc::O_PATH | libc::O_NOFOLLOW, 0)
+}
+
+fn exists<D: AsRawDescriptor>(dir: &D, name: &CStr) -> io::Result<bool> {
+    match statat(dir, name) {
+        Ok(_) => Ok(true),
+        Err(e) if is_enoent(&e) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Creates the empty file `name` in `dir`, used as a whiteout or opaque marker.
+fn create_marker<D: AsRawDescriptor>(dir: &D, name: &CStr) -> io::Result<()> {
+    open_at(
+        dir,
+        name,
+        libc::O_CREAT | libc::O_WRONLY | libc::O_NOFOLLOW,
+        0o600,
+    )
+    .map(drop)
+}
+
+fn unlink_at<D: AsRawDescriptor>(dir: &D, name: &CStr, flags: libc::c_int) -> io::Result<()> {
+    // SAFETY: this doesn't modify any memory and we check the return value.
+    syscall!(unsafe { libc::unlinkat(dir.as_raw_descriptor(), name.as_ptr(), flags) })?;
+    Ok(())
+}
+
+fn rename_at<D: AsRawDescriptor>(
+    olddir: &D,
+    oldname: &CStr,
+    newdir: &D,
+    newname: &CStr,
+) -> io::Result<()> {
+    // SAFETY: this doesn't modify any memory and we check the return value.
+    syscall!(unsafe {
+        libc::renameat(
+            olddir.as_raw_descriptor(),
+            oldname.as_ptr(),
+            newdir.as_raw_descriptor(),
+            newname.as_ptr(),
+        )
+    })?;
+    Ok(())
+}
+
+fn read_link<D: AsRawDescriptor>(f: &D) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; libc::PATH_MAX as usize];
+    let empty = const_cstr(EMPTY_CSTR);
+
+    // SAFETY: this will only modify the contents of `buf` and we check the return value.
+    let res = syscall!(unsafe {
+        libc::readlinkat(
+            f.as_raw_descriptor(),
+            empty.as_ptr(),
+            buf.as_mut_ptr() as *mut libc::c_char,
+            buf.len(),
+        )
+    })?;
+
+    buf.resize(res as usize, 0);
+    Ok(buf)
+}
+
+/// The entries of the layers that make up one file or directory of the overlay, from the highest
+/// layer to the lowest. There is more than one only for merged directories.
+struct Resolved {
+    layers: Vec<(usize, File)>,
+    st: libc::stat64,
+}
+
+impl Resolved {
+    fn top(&self) -> &File {
+        &self.layers[0].1
+    }
+
+    fn in_upper(&self) -> bool {
+        self.layers[0].0 == UPPER
+    }
+
+    fn is_dir(&self) -> bool {
+        is_dir(&self.st)
+    }
+
+    /// Returns the layers of the directory below the upper one.
+    fn lower(&self) -> &[(usize, File)] {
+        if self.in_upper() {
+            &self.layers[1..]
+        } else {
+            &self.layers
+        }
+    }
+}
+
+/// Looks up `name` in the directories of `dirs`, from the highest layer to the lowest.
+fn lookup_layers(dirs: &[(usize, File)], name: &CStr) -> io::Result<Option<Resolved>> {
+    let whiteout = whiteout_name(name);
+    let opaque = const_cstr(OPAQUE_CSTR);
+    let mut layers = Vec::new();
+    let mut top = None;
+
+    for (layer, dir) in dirs {
+        match statat(dir, name) {
+            Ok(st) => {
+                let entry_is_dir = is_dir(&st);
+                // Anything below a directory other than another directory is hidden.
+                if top.is_some() && !entry_is_dir {
+                    break;
+                }
+                let f = open_path(dir, name)?;
+                let is_opaque = entry_is_dir && exists(&f, opaque)?;
+                layers.push((*layer, f));
+                top.get_or_insert(st);
+                if !entry_is_dir || is_opaque {
+                    break;
+                }
+            }
+            Err(e) if is_enoent(&e) => {
+                if exists(dir, &whiteout)? {
+                    break;
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(top.map(|st| Resolved { layers, st }))
+}
+
+/// An entry of the merged listing of a directory.
+struct MergedEntry {
+    ino: libc::ino64_t,
+    type_: u32,
+    name: CString,
+}
+
+/// Iterates over a merged directory listing. The offset of an entry is its index plus one.
+pub struct MergedDirIter {
+    entries: Arc<[MergedEntry]>,
+    next: usize,
+}
+
+impl DirectoryIterator for MergedDirIter {
+    fn next(&mut self) -> Option<DirEntry> {
+        let entry = self.entries.get(self.next)?;
+        self.next += 1;
+        Some(DirEntry {
+            ino: entry.ino,
+            offset: self.next as u64,
+            type_: entry.type_,
+            name: &entry.name,
+        })
+    }
+}
+
+struct InodeData {
+    // The path of the inode relative to the root of the overlay, or `None` once it has been
+    // removed.
+    path: Option<PathBuf>,
+    refcount: u64,
+}
+
+/// The inodes known to the guest. Inodes are identified by their path, as the same file may be
+/// backed by different layers over time.
+#[derive(Default)]
+struct Inodes {
+    data: BTreeMap<Inode, InodeData>,
+    paths: BTreeMap<PathBuf, Inode>,
+}
+
+impl Inodes {
+    fn path(&self, inode: Inode) -> io::Result<PathBuf> {
+        self.data
+            .get(&inode)
+            .ok_or_else(ebadf)?
+            .path
+            .clone()
+            .ok_or_else(|| err(libc::ENOENT))
+    }
+
+    /// Returns the inode at `path`, creating it if needed, and increments its lookup count.
+    fn add(&mut self, path: PathBuf, next_inode: &AtomicU64) -> Inode {
+        let inode = *self
+            .paths
+            .entry(path.clone())
+            .or_insert_with(|| next_inode.fetch_add(1, Ordering::Relaxed));
+        self.data
+            .entry(inode)
+            .or_insert(InodeData {
+                path: Some(path),
+                refcount: 0,
+            })
+            .refcount += 1;
+        inode
+    }
+
+    fn forget(&mut self, inode: Inode, count: u64) {
+        if let Some(data) = self.data.get_mut(&inode) {
+            // Saturating sub because it doesn't make sense for a refcount to go below zero and
+            // we don't want misbehaving clients to cause integer overflow.
+            data.refcount = data.refcount.saturating_sub(count);
+            if data.refcount == 0 {
+                if let Some(path) = self.data.remove(&inode).and_then(|d| d.path) {
+                    self.paths.remove(&path);
+                }
+            }
+        }
+    }
+
+    /// Removes the inodes at `path` and below it from the tree. They stay valid until forgotten,
+    /// but fail with `ENOENT`.
+    fn detach(&mut self, path: &Path) {
+        for (_, inode) in self.take_subtree(path) {
+            if let Some(data) = self.data.get_mut(&inode) {
+                data.path = None;
+            }
+        }
+    }
+
+    /// Moves the inodes at `from` and below it to `to`.
+    fn rename(&mut self, from: &Path, to: &Path) {
+        for (p, inode) in self.take_subtree(from) {
+            let path = match p.strip_prefix(from) {
+                Ok(rest) if rest.as_os_str().is_empty() => to.to_path_buf(),
+                Ok(rest) => to.join(rest),
+                Err(_) => continue,
+            };
+            self.paths.insert(path.clone(), inode);
+            if let Some(data) = self.data.get_mut(&inode) {
+                data.path = Some(path);
+            }
+        }
+    }
+
+    fn take_subtree(&mut self, path: &Path) -> Vec<(PathBuf, Inode)> {
+        let keys: Vec<PathBuf> = self
+            .paths
+            .range(path.to_path_buf()..)
+            .take_while(|(p, _)| p.starts_with(path))
+            .map(|(p, _)| p.clone())
+            .collect();
+        keys.into_iter()
+            .filter_map(|p| self.paths.remove(&p).map(|inode| (p, inode)))
+            .collect()
+    }
+}
+
+enum HandleKind {
+    File(Mutex<File>),
+    // The listing is computed when the guest reads the directory from the start.
+    Dir(Mutex<Arc<[MergedEntry]>>),
+}
+
+struct HandleData {
+    inode: Inode,
+    kind: HandleKind,
+}
+
+/// A file system that serves the shared directory as the writable upper layer of an overlay of
+/// read-only lower directories. Like `PassthroughFs`, it serves the root directory of the process
+/// as the shared directory.
+pub struct OverlayFs {
+    // virtio-fs tag that the guest uses when mounting. This is only used for debugging
+    // when tracing is enabled.
+    #[allow(dead_code)]
+    tag: String,
+    cfg: Config,
+
+    // The upper directory, opened from `upper_path` on `init` since the process is not yet in its
+    // final mount namespace when the file system is created.
+    upper_path: CString,
+    upper: OnceCell<File>,
+    lowers: Vec<File>,
+
+    // File descriptor pointing to the `/proc` directory. This is used to convert an fd from
+    // `inodes` into one that can go into `handles`.
+    proc: File,
+
+    inodes: Mutex<Inodes>,
+    next_inode: AtomicU64,
+
+    handles: Mutex<BTreeMap<Handle, Arc<HandleData>>>,
+    next_handle: AtomicU64,
+
+    next_copy_up: AtomicU64,
+    // Serializes the requests that change the contents of the upper directory, so that copy-ups
+    // and whiteouts are never made concurrently for the same entry.
+    layout_lock: Mutex<()>,
+}
+
+impl OverlayFs {
+    pub fn new(tag: &str, cfg: Config) -> io::Result<OverlayFs> {
+        Self::with_upper(tag, cfg, const_cstr(ROOT_CSTR))
+    }
+
+    fn with_upper(tag: &str, cfg: Config, upper: &CStr) -> io::Result<OverlayFs> {
+        let unsupported = |option: &str| {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not supported with overlay_lower", option),
+            ))
+        };
+        if cfg.use_dax {
+            return unsupported("dax");
+        }
+        if cfg.ascii_casefold {
+            return unsupported("ascii_casefold");
+        }
+        if !cfg.uid_map.is_empty() || !cfg.gid_map.is_empty() || cfg.id_squash != IdSquash::None {
+            return unsupported("ID translation");
+        }
+
+        let proc = open_absolute(const_cstr(PROC_CSTR), libc::O_PATH | libc::O_NOFOLLOW)?;
+
+        let lowers = cfg
+            .overlay_lower
+            .iter()
+            .map(|dir| {
+                let file = File::open(dir).map_err(|e| {
+                    io::Error::new(e.kind(), format!("failed to open {}: {}", dir.display(), e))
+                })?;
+                if !file.metadata()?.is_dir() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("{} is not a directory", dir.display()),
+                    ));
+                }
+                Ok(file)
+            })
+            .collect::<io::Result<Vec<File>>>()?;
+
+        Ok(OverlayFs {
+            tag: tag.to_string(),
+            cfg,
+            upper_path: upper.to_owned(),
+            upper: OnceCell::new(),
+            lowers,
+            proc,
+            inodes: Mutex::new(Inodes::default()),
+            next_inode: AtomicU64::new(ROOT_ID + 1),
+            handles: Mutex::new(BTreeMap::new()),
+            next_handle: AtomicU64::new(1),
+            next_copy_up: AtomicU64::new(0),
+            layout_lock: Mutex::new(()),
+        })
+    }
+
+    pub fn cfg(&self) -> &Config {
+        &self.cfg
+    }
+
+    pub fn keep_rds(&self) -> Vec<RawDescriptor> {
+        let mut keep_rds = vec![self.proc.as_raw_descriptor()];
+        keep_rds.extend(self.lowers.iter().map(|f| f.as_raw_descriptor()));
+        keep_rds
+    }
+
+    fn upper(&self) -> io::Result<&File> {
+        self.upper.get().ok_or_else(ebadf)
+    }
+
+    fn open_fd(&self, f: &File, flags: libc::c_int) -> io::Result<File> {
+        let pathname = CString::new(format!("self/fd/{}", f.as_raw_descriptor()))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        // Clear the `O_NOFOLLOW` flag since we need to follow the `/proc/self/fd` symlink.
+        open_at(
+            &self.proc,
+            &pathname,
+            flags & !(libc::O_NOFOLLOW | libc::O_DIRECT),
+            0,
+        )
+    }
+
+    fn inode_path(&self, inode: Inode) -> io::Result<PathBuf> {
+        self.inodes.lock().path(inode)
+    }
+
+    fn add_entry(&self, path: PathBuf, st: libc::stat64) -> Entry {
+        let inode = self.inodes.lock().add(path, &self.next_inode);
+        Entry {
+            inode,
+            generation: 0,
+            attr: st,
+            // We use the same timeout for the attribute and the entry.
+            attr_timeout: self.cfg.timeout,
+            entry_timeout: self.cfg.timeout,
+        }
+    }
+
+    fn add_handle(&self, inode: Inode, kind: HandleKind) -> Handle {
+        let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
+        self.handles
+            .lock()
+            .insert(handle, Arc::new(HandleData { inode, kind }));
+        handle
+    }
+
+    fn find_handle(&self, handle: Handle, inode: Inode) -> io::Result<Arc<HandleData>> {
+        self.handles
+            .lock()
+            .get(&handle)
+            .filter(|hd| hd.inode == inode)
+            .cloned()
+            .ok_or_else(ebadf)
+    }
+
+    fn find_file(&self, handle: Handle, inode: Inode) -> io::Result<Arc<HandleData>> {
+        let data = self.find_handle(handle, inode)?;
+        match data.kind {
+            HandleKind::File(_) => Ok(data),
+            HandleKind::Dir(_) => Err(ebadf()),
+        }
+    }
+
+    fn open_options(&self, flags: libc::c_int) -> OpenOptions {
+        let is_dir = flags & libc::O_DIRECTORY != 0;
+        match self.cfg.cache_policy {
+            // We only set the direct I/O option on files.
+            CachePolicy::Never if !is_dir => OpenOptions::DIRECT_IO,
+            CachePolicy::Always if is_dir => OpenOptions::CACHE_DIR,
+            CachePolicy::Always => OpenOptions::KEEP_CACHE,
+            _ => OpenOptions::empty(),
+        }
+    }
+
+    /// Returns the root directory of the overlay, which merges the root of every layer.
+    fn root(&self) -> io::Result<Resolved> {
+        let opaque = const_cstr(OPAQUE_CSTR);
+        let upper = self.upper()?;
+        let mut layers = Vec::new();
+        for (layer, dir) in std::iter::once(upper).chain(&self.lowers).enumerate() {
+            layers.push((layer, dir.try_clone()?));
+            if exists(dir, opaque)? {
+                break;
+            }
+        }
+        Ok(Resolved {
+            layers,
+            st: stat(upper)?,
+        })
+    }
+
+    /// Resolves `path`, relative to the root of the overlay, to the layers of its entry.
+    fn resolve(&self, path: &Path) -> io::Result<Resolved> {
+        let mut res = self.root()?;
+        for component in path.components() {
+            if !res.is_dir() {
+                return Err(err(libc::ENOTDIR));
+            }
+            let name =
+                CString::new(component.as_os_str().as_bytes()).map_err(|_| err(libc::EINVAL))?;
+            res = lookup_layers(&res.layers, &name)?.ok_or_else(|| err(libc::ENOENT))?;
+        }
+        Ok(res)
+    }
+
+    /// Returns whether `name` exists in the lower layers of the directory `parent`, ignoring the
+    /// whiteouts of the upper layer.
+    fn in_lower(&self, parent: &Resolved, name: &CStr) -> io::Result<bool> {
+        Ok(lookup_layers(parent.lower(), name)?.is_some())
+    }
+
+    /// Returns the merged listing of the directory `dir`.
+    fn list_dir(&self, dir: &Resolved) -> io::Result<Vec<MergedEntry>> {
+        let mut entries = vec![
+            MergedEntry {
+                ino: dir.st.st_ino,
+                type_: libc::DT_DIR as u32,
+                name: const_cstr(CURRENT_DIR_CSTR).to_owned(),
+            },
+            MergedEntry {
+                ino: dir.st.st_ino,
+                type_: libc::DT_DIR as u32,
+                name: CString::new("..").unwrap(),
+            },
+        ];
+        let mut seen = BTreeSet::new();
+        let mut hidden = BTreeSet::new();
+        let mut buf = vec![0u8; 8192];
+
+        for (_, layer) in &dir.layers {
+            let f = self.open_fd(layer, libc::O_RDONLY | libc::O_DIRECTORY)?;
+            let mut whiteouts = Vec::new();
+            let mut offset = 0;
+            loop {
+                let mut read_dir = ReadDir::new(&f, offset, &mut buf[..])?;
+                let mut empty = true;
+                while let Some(entry) = read_dir.next() {
+                    empty = false;
+                    offset = entry.offset as libc::off64_t;
+                    let name = entry.name.to_bytes();
+                    if name == b"." || name == b".." || is_reserved(entry.name) {
+                        if let Some(hidden_name) = name.strip_prefix(WHITEOUT_PREFIX) {
+                            whiteouts.push(hidden_name.to_vec());
+                        }
+                        continue;
+                    }
+                    if hidden.contains(name) || !seen.insert(name.to_vec()) {
+                        continue;
+                    }
+                    entries.push(MergedEntry {
+                        ino: entry.ino,
+                        type_: entry.type_,
+                        name: entry.name.to_owned(),
+                    });
+                }
+                if empty {
+                    break;
+                }
+            }
+            // The whiteouts of a layer only hide the entries of the layers below it.
+            hidden.extend(whiteouts);
+        }
+
+        Ok(entries)
+    }
+
+    /// Removes the whiteouts and markers left in the upper directory `dir`, which must otherwise
+    /// be empty.
+    fn clear_dir(&self, dir: &File) -> io::Result<()> {
+        let f = self.open_fd(dir, libc::O_RDONLY | libc::O_DIRECTORY)?;
+        let mut names = Vec::new();
+        let mut buf = vec![0u8; 8192];
+        let mut offset = 0;
+        loop {
+            let mut read_dir = ReadDir::new(&f, offset, &mut buf[..])?;
+            let mut empty = true;
+            while let Some(entry) = read_dir.next() {
+                empty = false;
+                offset = entry.offset as libc::off64_t;
+                if is_reserved(entry.name) {
+                    names.push(entry.name.to_owned());
+                }
+            }
+            if empty {
+                break;
+            }
+        }
+        for name in names {
+            unlink_at(dir, &name, 0)?;
+        }
+        Ok(())
+    }
+
+    /// Copies the entry at `path` and its parents to the upper layer if they are not there yet,
+    /// and returns its upper entry opened with `O_PATH`. `layout_lock` must be held.
+    fn copy_up(&self, path: &Path) -> io::Result<File> {
+        let res = self.resolve(path)?;
+        if res.in_upper() {
+            return Ok(res.layers.into_iter().next().unwrap().1);
+        }
+
+        let (parent_path, name) = split_path(path)?;
+        let parent = self.copy_up(parent_path)?;
+        let src = res.top();
+        let st = &res.st;
+        let mode = st.st_mode & 0o7777;
+
+        match st.st_mode & libc::S_IFMT {
+            // SAFETY: this doesn't modify any memory and we check the return value.
+            libc::S_IFDIR => {
+                syscall!(unsafe { libc::mkdirat(parent.as_raw_descriptor(), name.as_ptr(), mode) })
+                    .map(drop)?
+            }
+            libc::S_IFLNK => {
+                let target = CString::new(read_link(src)?).map_err(|_| err(libc::EINVAL))?;
+                // SAFETY: this doesn't modify any memory and we check the return value.
+                syscall!(unsafe {
+                    libc::symlinkat(target.as_ptr(), parent.as_raw_descriptor(), name.as_ptr())
+                })?;
+            }
+            libc::S_IFREG => self.copy_up_file(&parent, &name, src, mode)?,
+            // SAFETY: this doesn't modify any memory and we check the return value.
+            _ => syscall!(unsafe {
+                libc::mknodat(
+                    parent.as_raw_descriptor(),
+                    name.as_ptr(),
+                    st.st_mode,
+                    st.st_rdev,
+                )
+            })
+            .map(drop)?,
+        }
+
+        // Keeping the owner and timestamps is best-effort, as the device may lack the privileges
+        // to set them.
+        let times = [
+            libc::timespec {
+                tv_sec: st.st_atime,
+                tv_nsec: st.st_atime_nsec,
+            },
+            libc::timespec {
+                tv_sec: st.st_mtime,
+                tv_nsec: st.st_mtime_nsec,
+            },
+        ];
+        // SAFETY: these don't modify any memory and failures are ignored.
+        unsafe {
+            if libc::fchownat(
+                parent.as_raw_descriptor(),
+                name.as_ptr(),
+                st.st_uid,
+                st.st_gid,
+                libc::AT_SYMLINK_NOFOLLOW,
+            ) == 0
+                && st.st_mode & libc::S_IFMT != libc::S_IFLNK
+            {
+                // Changing the owner clears the setuid and setgid bits.
+                libc::fchmodat(parent.as_raw_descriptor(), name.as_ptr(), mode, 0);
+            }
+            libc::utimensat(
+                parent.as_raw_descriptor(),
+                name.as_ptr(),
+                times.as_ptr(),
+                libc::AT_SYMLINK_NOFOLLOW,
+            );
+        }
+
+        open_path(&parent, &name)
+    }
+
+    /// Copies the regular file `src` to `name` in the upper directory `parent`. The copy is made
+    /// under a temporary name so that the guest never sees a partial file.
+    fn copy_up_file(
+        &self,
+        parent: &File,
+        name: &CStr,
+        src: &File,
+        mode: libc::mode_t,
+    ) -> io::Result<()> {
+        let tmp = CString::new(format!(
+            "{}{}",
+            COPY_UP_PREFIX,
+            self.next_copy_up.fetch_add(1, Ordering::Relaxed)
+        ))
+        .unwrap();
+        let mut from = self.open_fd(src, libc::O_RDONLY)?;
+        let mut to = open_at(
+            parent,
+            &tmp,
+            libc::O_CREAT | libc::O_EXCL | libc::O_WRONLY | libc::O_NOFOLLOW,
+            mode,
+        )?;
+        let res = io::copy(&mut from, &mut to).and_then(|_| rename_at(parent, &tmp, parent, name));
+        if res.is_err() {
+            let _ = unlink_at(parent, &tmp, 0);
+        }
+        res
+    }
+
+    /// Copies up `path` and returns its upper parent directory and name, for the requests that
+    /// operate on names. The root directory is returned as `.` of itself.
+    fn copy_up_entry(&self, path: &Path) -> io::Result<(File, CString)> {
+        let entry = self.copy_up(path)?;
+        if path.as_os_str().is_empty() {
+            return Ok((entry, const_cstr(CURRENT_DIR_CSTR).to_owned()));
+        }
+        let (parent, name) = split_path(path)?;
+        Ok((self.copy_up(parent)?, name))
+    }
+
+    /// Creates entry `name` of `parent` in the upper layer with `create`, which is passed the
+    /// upper directory, the name, and whether the entry replaces a whiteout.
+    fn create_entry<T, F>(&self, parent: Inode, name: &CStr, create: F) -> io::Result<(Entry, T)>
+    where
+        F: FnOnce(&File, &CStr, bool) -> io::Result<T>,
+    {
+        if is_reserved(name) {
+            return Err(err(libc::EPERM));
+        }
+        let path = child_path(&self.inode_path(parent)?, name)?;
+
+        let _layout = self.layout_lock.lock();
+        match self.resolve(&path) {
+            Ok(_) => return Err(eexist()),
+            Err(e) if is_enoent(&e) => {}
+            Err(e) => return Err(e),
+        }
+        let (parent_path, _) = split_path(&path)?;
+        let dir = self.copy_up(parent_path)?;
+        let whiteout = whiteout_name(name);
+        let replaces_whiteout = exists(&dir, &whiteout)?;
+
+        let out = create(&dir, name, replaces_whiteout)?;
+        if replaces_whiteout {
+            unlink_at(&dir, &whiteout, 0)?;
+        }
+        let st = statat(&dir, name)?;
+        Ok((self.add_entry(path, st), out))
+    }
+
+    /// Removes entry `name` of `parent`, with `AT_REMOVEDIR` in `flags` for directories.
+    fn remove_entry(&self, parent: Inode, name: &CStr, flags: libc::c_int) -> io::Result<()> {
+        if is_reserved(name) {
+            return Err(err(libc::ENOENT));
+        }
+        let parent_path = self.inode_path(parent)?;
+        let path = child_path(&parent_path, name)?;
+
+        let _layout = self.layout_lock.lock();
+        let res = self.resolve(&path)?;
+        if flags & libc::AT_REMOVEDIR == 0 && res.is_dir() {
+            return Err(err(libc::EISDIR));
+        }
+        if flags & libc::AT_REMOVEDIR != 0 {
+            if !res.is_dir() {
+                return Err(err(libc::ENOTDIR));
+            }
+            // Only "." and "..".
+            if self.list_dir(&res)?.len() > 2 {
+                return Err(err(libc::ENOTEMPTY));
+            }
+        }
+
+        let in_lower = self.in_lower(&self.resolve(&parent_path)?, name)?;
+        let dir = self.copy_up(&parent_path)?;
+        if in_lower {
+            create_marker(&dir, &whiteout_name(name))?;
+        }
+        if res.in_upper() {
+            if res.is_dir() {
+                self.clear_dir(res.top())?;
+            }
+            unlink_at(&dir, name, flags)?;
+        }
+
+        self.inodes.lock().detach(&path);
+        Ok(())
+    }
+
+    fn do_rename(&self, old_path: &Path, new_path: &Path, flags: u32) -> io::Result<()> {
+        let (old_parent_path, oldname) = split_path(old_path)?;
+        let (new_parent_path, newname) = split_path(new_path)?;
+
+        let _layout = self.layout_lock.lock();
+        let src = self.resolve(old_path)?;
+        // Moving a merged directory would need to move the lower directories too.
+        if src.is_dir() && src.layers.iter().any(|(layer, _)| *layer != UPPER) {
+            return Err(err(libc::EXDEV));
+        }
+
+        let replaced = match self.resolve(new_path) {
+            Ok(dst) => {
+                if flags & libc::RENAME_NOREPLACE != 0 {
+                    return Err(eexist());
+                }
+                if old_path == new_path {
+                    return Ok(());
+                }
+                match (src.is_dir(), dst.is_dir()) {
+                    (false, true) => return Err(err(libc::EISDIR)),
+                    (true, false) => return Err(err(libc::ENOTDIR)),
+                    (true, true) => {
+                        if self.list_dir(&dst)?.len() > 2 {
+                            return Err(err(libc::ENOTEMPTY));
+                        }
+                        if dst.in_upper() {
+                            self.clear_dir(dst.top())?;
+                        }
+                    }
+                    (false, false) => {}
+                }
+                true
+            }
+            Err(e) if is_enoent(&e) => false,
+            Err(e) => return Err(e),
+        };
+
+        let src_in_lower = self.in_lower(&self.resolve(old_parent_path)?, &oldname)?;
+        let dst_in_lower = self.in_lower(&self.resolve(new_parent_path)?, &newname)?;
+
+        let src_upper = self.copy_up(old_path)?;
+        // The directories of the lower layers at the new name must not be merged with it.
+        if src.is_dir() && dst_in_lower {
+            create_marker(&src_upper, const_cstr(OPAQUE_CSTR))?;
+        }
+        let old_dir = self.copy_up(old_parent_path)?;
+        let new_dir = self.copy_up(new_parent_path)?;
+        if src_in_lower {
+            create_marker(&old_dir, &whiteout_name(&oldname))?;
+        }
+        rename_at(&old_dir, &oldname, &new_dir, &newname)?;
+        let whiteout = whiteout_name(&newname);
+        if exists(&new_dir, &whiteout)? {
+            unlink_at(&new_dir, &whiteout, 0)?;
+        }
+
+        let mut inodes = self.inodes.lock();
+        if replaced {
+            inodes.detach(new_path);
+        }
+        inodes.rename(old_path, new_path);
+        Ok(())
+    }
+
+    fn do_open(&self, inode: Inode, flags: u32) -> io::Result<(Option<Handle>, OpenOptions)> {
+        let flags = flags as libc::c_int & !(libc::O_CREAT | libc::O_EXCL | libc::O_NOCTTY);
+        let path = self.inode_path(inode)?;
+        let src = if flags & libc::O_ACCMODE != libc::O_RDONLY || flags & libc::O_TRUNC != 0 {
+            let _layout = self.layout_lock.lock();
+            self.copy_up(&path)?
+        } else {
+            self.resolve(&path)?.layers.into_iter().next().unwrap().1
+        };
+        let file = self.open_fd(&src, flags)?;
+        let handle = self.add_handle(inode, HandleKind::File(Mutex::new(file)));
+        Ok((Some(handle), self.open_options(flags)))
+    }
+}
+
+impl FileSystem for OverlayFs {
+    type Inode = Inode;
+    type Handle = Handle;
+    type DirIter = MergedDirIter;
+
+    fn init(&self, _capable: FsOptions) -> io::Result<FsOptions> {
+        if self.upper.get().is_none() {
+            let upper = open_absolute(&self.upper_path, libc::O_DIRECTORY | libc::O_NOFOLLOW)?;
+            // A concurrent `init` opened the same directory.
+            let _ = self.upper.set(upper);
+        }
+
+        // SAFETY: this doesn't modify any memory and there is no need to check the return
+        // value because this system call always succeeds. We need to clear the umask here because
+        // we want the client to be able to set all the bits in the mode.
+        unsafe { libc::umask(0o000) };
+
+        let mut inodes = self.inodes.lock();
+        *inodes = Inodes::default();
+        // Not sure why the root inode gets a refcount of 2 but that's what libfuse does.
+        inodes.paths.insert(PathBuf::new(), ROOT_ID);
+        inodes.data.insert(
+            ROOT_ID,
+            InodeData {
+                path: Some(PathBuf::new()),
+                refcount: 2,
+            },
+        );
+
+        Ok(FsOptions::DO_READDIRPLUS | FsOptions::READDIRPLUS_AUTO | FsOptions::DONT_MASK)
+    }
+
+    fn destroy(&self) {
+        self.handles.lock().clear();
+        *self.inodes.lock() = Inodes::default();
+    }
+
+    fn statfs(&self, _ctx: Context, _inode: Inode) -> io::Result<libc::statvfs64> {
+        let mut out = MaybeUninit::<libc::statvfs64>::zeroed();
+
+        // SAFETY: this will only modify `out` and we check the return value.
+        syscall!(unsafe { libc::fstatvfs64(self.upper()?.as_raw_descriptor(), out.as_mut_ptr()) })?;
+
+        // SAFETY: the kernel guarantees that `out` has been initialized.
+        Ok(unsafe { out.assume_init() })
+    }
+
+    fn lookup(&self, _ctx: Context, parent: Inode, name: &CStr) -> io::Result<Entry> {
+        let path = child_path(&self.inode_path(parent)?, name)?;
+        let res = if is_reserved(name) {
+            Err(err(libc::ENOENT))
+        } else {
+            self.resolve(&path)
+        };
+        match res {
+            Ok(res) => Ok(self.add_entry(path, res.st)),
+            Err(e) if is_enoent(&e) && !self.cfg.negative_timeout.is_zero() => {
+                Ok(Entry::new_negative(self.cfg.negative_timeout))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn forget(&self, _ctx: Context, inode: Inode, count: u64) {
+        self.inodes.lock().forget(inode, count);
+    }
+
+    fn batch_forget(&self, _ctx: Context, requests: Vec<(Inode, u64)>) {
+        let mut inodes = self.inodes.lock();
+        for (inode, count) in requests {
+            inodes.forget(inode, count);
+        }
+    }
+
+    fn getattr(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        handle: Option<Handle>,
+    ) -> io::Result<(libc::stat64, Duration)> {
+        let st = match (self.inode_path(inode), handle) {
+            (Ok(path), _) => self.resolve(&path)?.st,
+            // The file was removed but is still open.
+            (Err(e), Some(handle)) if is_enoent(&e) => match &self.find_file(handle, inode)?.kind {
+                HandleKind::File(f) => stat(&*f.lock())?,
+                HandleKind::Dir(_) => return Err(e),
+            },
+            (Err(e), _) => return Err(e),
+        };
+        Ok((st, self.cfg.timeout))
+    }
+
+    fn setattr(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        attr: libc::stat64,
+        handle: Option<Handle>,
+        valid: SetattrValid,
+    ) -> io::Result<(libc::stat64, Duration)> {
+        let path = self.inode_path(inode)?;
+        let _layout = self.layout_lock.lock();
+        let (dir, name) = self.copy_up_entry(&path)?;
+
+        if valid.contains(SetattrValid::MODE) {
+            // SAFETY: this doesn't modify any memory and we check the return value.
+            syscall!(unsafe {
+                libc::fchmodat(
+                    dir.as_raw_descriptor(),
+                    name.as_ptr(),
+                    attr.st_mode & 0o7777,
+                    0,
+                )
+            })?;
+        }
+
+        if valid.intersects(SetattrValid::UID | SetattrValid::GID) {
+            let uid = if valid.contains(SetattrValid::UID) {
+                attr.st_uid
+            } else {
+                // Cannot use -1 here because these are unsigned values.
+                u32::MAX
+            };
+            let gid = if valid.contains(SetattrValid::GID) {
+                attr.st_gid
+            } else {
+                // Cannot use -1 here because these are unsigned values.
+                u32::MAX
+            };
+
+            // SAFETY: this doesn't modify any memory and we check the return value.
+            syscall!(unsafe {
+                libc::fchownat(
+                    dir.as_raw_descriptor(),
+                    name.as_ptr(),
+                    uid,
+                    gid,
+                    libc::AT_SYMLINK_NOFOLLOW,
+                )
+            })?;
+        }
+
+        if valid.contains(SetattrValid::SIZE) {
+            // A handle the guest can truncate with is writable, and so was opened in the upper
+            // layer.
+            let file = match handle.map(|h| self.find_file(h, inode)).transpose()? {
+                Some(data) => match &data.kind {
+                    HandleKind::File(f) => f.lock().try_clone()?,
+                    HandleKind::Dir(_) => return Err(ebadf()),
+                },
+                None => open_at(&dir, &name, libc::O_WRONLY | libc::O_NOFOLLOW, 0)?,
+            };
+            // SAFETY: this doesn't modify any memory and we check the return value.
+            syscall!(unsafe { libc::ftruncate64(file.as_raw_descriptor(), attr.st_size) })?;
+        }
+
+        if valid.intersects(SetattrValid::ATIME | SetattrValid::MTIME) {
+            let mut tvs = [
+                libc::timespec {
+                    tv_sec: 0,
+                    tv_nsec: libc::UTIME_OMIT,
+                },
+                libc::timespec {
+                    tv_sec: 0,
+                    tv_nsec: libc::UTIME_OMIT,
+                },
+            ];
+
+            if valid.contains(SetattrValid::ATIME_NOW) {
+                tvs[0].tv_nsec = libc::UTIME_NOW;
+            } else if valid.contains(SetattrValid::ATIME) {
+                tvs[0].tv_sec = attr.st_atime;
+                tvs[0].tv_nsec = attr.st_atime_nsec;
+            }
+
+            if valid.contains(SetattrValid::MTIME_NOW) {
+                tvs[1].tv_nsec = libc::UTIME_NOW;
+            } else if valid.contains(SetattrValid::MTIME) {
+                tvs[1].tv_sec = attr.st_mtime;
+                tvs[1].tv_nsec = attr.st_mtime_nsec;
+            }
+
+            // SAFETY: this doesn't modify any memory and we check the return value.
+            syscall!(unsafe {
+                libc::utimensat(
+                    dir.as_raw_descriptor(),
+                    name.as_ptr(),
+                    tvs.as_ptr(),
+                    libc::AT_SYMLINK_NOFOLLOW,
+                )
+            })?;
+        }
+
+        Ok((statat(&dir, &name)?, self.cfg.timeout))
+    }
+
+    fn readlink(&self, _ctx: Context, inode: Inode) -> io::Result<Vec<u8>> {
+        let res = self.resolve(&self.inode_path(inode)?)?;
+        read_link(res.top())
+    }
+
+    fn symlink(
+        &self,
+        _ctx: Context,
+        linkname: &CStr,
+        parent: Inode,
+        name: &CStr,
+        _security_ctx: Option<&CStr>,
+    ) -> io::Result<Entry> {
+        self.create_entry(parent, name, |dir, name, _| {
+            // SAFETY: this doesn't modify any memory and we check the return value.
+            syscall!(unsafe {
+                libc::symlinkat(linkname.as_ptr(), dir.as_raw_descriptor(), name.as_ptr())
+            })?;
+            Ok(())
+        })
+        .map(|(entry, ())| entry)
+    }
+
+    fn mknod(
+        &self,
+        _ctx: Context,
+        parent: Inode,
+        name: &CStr,
+        mode: u32,
+        rdev: u32,
+        umask: u32,
+        _security_ctx: Option<&CStr>,
+    ) -> io::Result<Entry> {
+        self.create_entry(parent, name, |dir, name, _| {
+            // SAFETY: this doesn't modify any memory and we check the return value.
+            syscall!(unsafe {
+                libc::mknodat(
+                    dir.as_raw_descriptor(),
+                    name.as_ptr(),
+                    mode & !umask,
+                    rdev.into(),
+                )
+            })?;
+            Ok(())
+        })
+        .map(|(entry, ())| entry)
+    }
+
+    fn mkdir(
+        &self,
+        _ctx: Context,
+        parent: Inode,
+        name: &CStr,
+        mode: u32,
+        umask: u32,
+        _security_ctx: Option<&CStr>,
+    ) -> io::Result<Entry> {
+        self.create_entry(parent, name, |dir, name, replaces_whiteout| {
+            // SAFETY: this doesn't modify any memory and we check the return value.
+            syscall!(unsafe {
+                libc::mkdirat(dir.as_raw_descriptor(), name.as_ptr(), mode & !umask)
+            })?;
+            // The new directory must not be merged with the removed one of a lower layer.
+            if replaces_whiteout {
+                create_marker(&open_path(dir, name)?, const_cstr(OPAQUE_CSTR))?;
+            }
+            Ok(())
+        })
+        .map(|(entry, ())| entry)
+    }
+
+    fn unlink(&self, _ctx: Context, parent: Inode, name: &CStr) -> io::Result<()> {
+        self.remove_entry(parent, name, 0)
+    }
+
+    fn rmdir(&self, _ctx: Context, parent: Inode, name: &CStr) -> io::Result<()> {
+        self.remove_entry(parent, name, libc::AT_REMOVEDIR)
+    }
+
+    fn rename(
+        &self,
+        _ctx: Context,
+        olddir: Inode,
+        oldname: &CStr,
+        newdir: Inode,
+        newname: &CStr,
+        flags: u32,
+    ) -> io::Result<()> {
+        if flags & !libc::RENAME_NOREPLACE != 0 {
+            return Err(err(libc::EINVAL));
+        }
+        if is_reserved(oldname) {
+            return Err(err(libc::ENOENT));
+        }
+        if is_reserved(newname) {
+            return Err(err(libc::EPERM));
+        }
+        let old_path = child_path(&self.inode_path(olddir)?, oldname)?;
+        let new_path = child_path(&self.inode_path(newdir)?, newname)?;
+        self.do_rename(&old_path, &new_path, flags)
+    }
+
+    fn link(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        newparent: Inode,
+        newname: &CStr,
+    ) -> io::Result<Entry> {
+        let src_path = self.inode_path(inode)?;
+        self.create_entry(newparent, newname, |dir, name, _| {
+            let (src_dir, src_name) = self.copy_up_entry(&src_path)?;
+            // SAFETY: this doesn't modify any memory and we check the return value.
+            syscall!(unsafe {
+                libc::linkat(
+                    src_dir.as_raw_descriptor(),
+                    src_name.as_ptr(),
+                    dir.as_raw_descriptor(),
+                    name.as_ptr(),
+                    0,
+                )
+            })?;
+            Ok(())
+        })
+        .map(|(entry, ())| entry)
+    }
+
+    fn open(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        flags: u32,
+    ) -> io::Result<(Option<Handle>, OpenOptions)> {
+        self.do_open(inode, flags)
+    }
+
+    fn create(
+        &self,
+        ctx: Context,
+        parent: Inode,
+        name: &CStr,
+        mode: u32,
+        flags: u32,
+        umask: u32,
+        _security_ctx: Option<&CStr>,
+    ) -> io::Result<(Entry, Option<Handle>, OpenOptions)> {
+        let open_flags = flags as libc::c_int & !(libc::O_CREAT | libc::O_EXCL | libc::O_NOCTTY);
+        let res = self.create_entry(parent, name, |dir, name, _| {
+            open_at(
+                dir,
+                name,
+                open_flags | libc::O_CREAT | libc::O_EXCL | libc::O_NOFOLLOW,
+                mode & !umask,
+            )
+        });
+        match res {
+            Ok((entry, file)) => {
+                let handle = self.add_handle(entry.inode, HandleKind::File(Mutex::new(file)));
+                Ok((entry, Some(handle), self.open_options(open_flags)))
+            }
+            // The guest may have cached a negative entry for a file that exists.
+            Err(e)
+                if e.raw_os_error() == Some(libc::EEXIST) && flags as i32 & libc::O_EXCL == 0 =>
+            {
+                let entry = self.lookup(ctx, parent, name)?;
+                match self.do_open(entry.inode, flags) {
+                    Ok((handle, opts)) => Ok((entry, handle, opts)),
+                    Err(e) => {
+                        self.forget(ctx, entry.inode, 1);
+                        Err(e)
+                    }
+                }
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn read<W: io::Write + ZeroCopyWriter>(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        handle: Handle,
+        mut w: W,
+        size: u32,
+        offset: u64,
+        _lock_owner: Option<u64>,
+        _flags: u32,
+    ) -> io::Result<usize> {
+        match &self.find_file(handle, inode)?.kind {
+            HandleKind::File(f) => w.write_from(&mut f.lock(), size as usize, offset),
+            HandleKind::Dir(_) => Err(ebadf()),
+        }
+    }
+
+    fn write<R: io::Read + ZeroCopyReader>(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        handle: Handle,
+        mut r: R,
+        size: u32,
+        offset: u64,
+        _lock_owner: Option<u64>,
+        _delayed_write: bool,
+        _flags: u32,
+    ) -> io::Result<usize> {
+        match &self.find_file(handle, inode)?.kind {
+            HandleKind::File(f) => r.read_to(&mut f.lock(), size as usize, offset),
+            HandleKind::Dir(_) => Err(ebadf()),
+        }
+    }
+
+    fn flush(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        handle: Handle,
+        _lock_owner: u64,
+    ) -> io::Result<()> {
+        self.find_file(handle, inode).map(drop)
+    }
+
+    fn fsync(&self, _ctx: Context, inode: Inode, datasync: bool, handle: Handle) -> io::Result<()> {
+        match &self.find_file(handle, inode)?.kind {
+            HandleKind::File(f) => {
+                let f = f.lock();
+                if datasync {
+                    f.sync_data()
+                } else {
+                    f.sync_all()
+                }
+            }
+            HandleKind::Dir(_) => Err(ebadf()),
+        }
+    }
+
+    fn release(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        _flags: u32,
+        handle: Handle,
+        _flush: bool,
+        _flock_release: bool,
+        _lock_owner: Option<u64>,
+    ) -> io::Result<()> {
+        self.find_handle(handle, inode)?;
+        self.handles.lock().remove(&handle);
+        Ok(())
+    }
+
+    fn opendir(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        flags: u32,
+    ) -> io::Result<(Option<Handle>, OpenOptions)> {
+        if !self.resolve(&self.inode_path(inode)?)?.is_dir() {
+            return Err(err(libc::ENOTDIR));
+        }
+        let handle = self.add_handle(inode, HandleKind::Dir(Mutex::new(Vec::new().into())));
+        Ok((Some(handle), self.open_options(flags as libc::c_int)))
+    }
+
+    fn readdir(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        handle: Handle,
+        _size: u32,
+        offset: u64,
+    ) -> io::Result<Self::DirIter> {
+        let data = self.find_handle(handle, inode)?;
+        let HandleKind::Dir(entries) = &data.kind else {
+            return Err(ebadf());
+        };
+        let mut entries = entries.lock();
+        if offset == 0 {
+            let dir = self.resolve(&self.inode_path(inode)?)?;
+            *entries = self.list_dir(&dir)?.into();
+        }
+        Ok(MergedDirIter {
+            entries: entries.clone(),
+            next: offset as usize,
+        })
+    }
+
+    fn fsyncdir(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        _datasync: bool,
+        handle: Handle,
+    ) -> io::Result<()> {
+        self.find_handle(handle, inode).map(drop)
+    }
+
+    fn releasedir(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        _flags: u32,
+        handle: Handle,
+    ) -> io::Result<()> {
+        self.find_handle(handle, inode)?;
+        self.handles.lock().remove(&handle);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    struct TestOverlay {
+        fs: OverlayFs,
+        upper: TempDir,
+        lower: TempDir,
+    }
+
+    fn ctx() -> Context {
+        Context {
+            uid: 0,
+            gid: 0,
+            pid: 0,
+        }
+    }
+
+    fn cstr(s: &str) -> CString {
+        CString::new(s).unwrap()
+    }
+
+    /// Creates an overlay of an empty upper directory over a lower one containing `dir/file` and
+    /// `top`.
+    fn overlay() -> TestOverlay {
+        let upper = TempDir::new().unwrap();
+        let lower = TempDir::new().unwrap();
+        fs::create_dir(lower.path().join("dir")).unwrap();
+        fs::write(lower.path().join("dir/file"), "lower").unwrap();
+        fs::write(lower.path().join("top"), "top").unwrap();
+
+        let cfg = Config {
+            overlay_lower: vec![lower.path().to_path_buf()],
+            negative_timeout: Duration::ZERO,
+            ..Default::default()
+        };
+        let upper_path = cstr(upper.path().to_str().unwrap());
+        let fs = OverlayFs::with_upper("tag", cfg, &upper_path).unwrap();
+        fs.init(FsOptions::empty()).unwrap();
+        TestOverlay { fs, upper, lower }
+    }
+
+    fn lookup(fs: &OverlayFs, path: &str) -> io::Result<Inode> {
+        let mut inode = ROOT_ID;
+        for name in path.split('/') {
+            inode = fs.lookup(ctx(), inode, &cstr(name))?.inode;
+        }
+        Ok(inode)
+    }
+
+    fn read(fs: &OverlayFs, path: &str) -> io::Result<Vec<u8>> {
+        let inode = lookup(fs, path)?;
+        let (handle, _) = fs.open(ctx(), inode, libc::O_RDONLY as u32)?;
+        let HandleKind::File(f) = &fs.find_file(handle.unwrap(), inode)?.kind else {
+            unreachable!();
+        };
+        let contents = fs::read(format!("/proc/self/fd/{}", f.lock().as_raw_descriptor()));
+        fs.release(ctx(), inode, 0, handle.unwrap(), false, false, None)?;
+        contents
+    }
+
+    fn list(fs: &OverlayFs, path: &str) -> Vec<String> {
+        let inode = if path.is_empty() {
+            ROOT_ID
+        } else {
+            lookup(fs, path).unwrap()
+        };
+        let (handle, _) = fs.opendir(ctx(), inode, 0).unwrap();
+        let mut iter = fs.readdir(ctx(), inode, handle.unwrap(), 4096, 0).unwrap();
+        let mut names = Vec::new();
+        while let Some(entry) = iter.next() {
+            names.push(entry.name.to_str().unwrap().to_string());
+        }
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn merged_lookup_and_readdir() {
+        let t = overlay();
+        fs::write(t.upper.path().join("new"), "upper").unwrap();
+        fs::create_dir(t.upper.path().join("dir")).unwrap();
+        fs::write(t.upper.path().join("dir/other"), "").unwrap();
+
+        assert_eq!(read(&t.fs, "dir/file").unwrap(), b"lower");
+        assert_eq!(read(&t.fs, "new").unwrap(), b"upper");
+        assert_eq!(list(&t.fs, ""), [".", "..", "dir", "new", "top"]);
+        assert_eq!(list(&t.fs, "dir"), [".", "..", "file", "other"]);
+    }
+
+    #[test]
+    fn write_copies_up() {
+        let t = overlay();
+        let inode = lookup(&t.fs, "dir/file").unwrap();
+        let (handle, _) = t.fs.open(ctx(), inode, libc::O_RDWR as u32).unwrap();
+        t.fs.release(ctx(), inode, 0, handle.unwrap(), false, false, None)
+            .unwrap();
+
+        assert_eq!(fs::read(t.upper.path().join("dir/file")).unwrap(), b"lower");
+
+        // SAFETY: stat64 is a plain C struct for which all zeroes is a valid value.
+        let mut attr: libc::stat64 = unsafe { std::mem::zeroed() };
+        attr.st_mode = 0o600;
+        attr.st_size = 2;
+        t.fs.setattr(
+            ctx(),
+            inode,
+            attr,
+            None,
+            SetattrValid::MODE | SetattrValid::SIZE,
+        )
+        .unwrap();
+        assert_eq!(read(&t.fs, "dir/file").unwrap(), b"lo");
+        assert_eq!(fs::read(t.lower.path().join("dir/file")).unwrap(), b"lower");
+    }
+
+    #[test]
+    fn unlink_and_recreate() {
+        let t = overlay();
+        let root = ROOT_ID;
+        t.fs.unlink(ctx(), root, &cstr("top")).unwrap();
+        assert!(t.upper.path().join(".wh.top").exists());
+        assert!(t.lower.path().join("top").exists());
+        assert!(is_enoent(&lookup(&t.fs, "top").unwrap_err()));
+        assert_eq!(list(&t.fs, ""), [".", "..", "dir"]);
+
+        t.fs.mkdir(ctx(), root, &cstr("top"), 0o755, 0, None)
+            .unwrap();
+        assert!(!t.upper.path().join(".wh.top").exists());
+        assert_eq!(list(&t.fs, ""), [".", "..", "dir", "top"]);
+
+        // Whiteout names are reserved.
+        assert!(is_enoent(&lookup(&t.fs, ".wh.top").unwrap_err()));
+        assert_eq!(
+            t.fs.mkdir(ctx(), root, &cstr(".wh.x"), 0o755, 0, None)
+                .unwrap_err()
+                .raw_os_error(),
+            Some(libc::EPERM)
+        );
+    }
+
+    #[test]
+    fn rmdir_merged_dir() {
+        let t = overlay();
+        let dir = lookup(&t.fs, "dir").unwrap();
+        assert_eq!(
+            t.fs.rmdir(ctx(), ROOT_ID, &cstr("dir"))
+                .unwrap_err()
+                .raw_os_error(),
+            Some(libc::ENOTEMPTY)
+        );
+        t.fs.unlink(ctx(), dir, &cstr("file")).unwrap();
+        assert_eq!(list(&t.fs, "dir"), [".", ".."]);
+        t.fs.rmdir(ctx(), ROOT_ID, &cstr("dir")).unwrap();
+        assert!(!t.upper.path().join("dir").exists());
+        assert!(t.upper.path().join(".wh.dir").exists());
+
+        // A new directory of the same name does not show the lower contents.
+        t.fs.mkdir(ctx(), ROOT_ID, &cstr("dir"), 0o755, 0, None)
+            .unwrap();
+        assert_eq!(list(&t.fs, "dir"), [".", ".."]);
+        assert!(t.lower.path().join("dir/file").exists());
+    }
+
+    #[test]
+    fn rename_file() {
+        let t = overlay();
+        let dir = lookup(&t.fs, "dir").unwrap();
+        let file = lookup(&t.fs, "dir/file").unwrap();
+        t.fs.rename(ctx(), dir, &cstr("file"), ROOT_ID, &cstr("moved"), 0)
+            .unwrap();
+
+        assert_eq!(list(&t.fs, "dir"), [".", ".."]);
+        assert_eq!(read(&t.fs, "moved").unwrap(), b"lower");
+        assert_eq!(t.fs.inode_path(file).unwrap(), Path::new("moved"));
+        assert!(t.lower.path().join("dir/file").exists());
+
+        // Merged directories cannot be moved.
+        assert_eq!(
+            t.fs.rename(ctx(), ROOT_ID, &cstr("dir"), ROOT_ID, &cstr("dir2"), 0)
+                .unwrap_err()
+                .raw_os_error(),
+            Some(libc::EXDEV)
+        );
+    }
+
+    #[test]
+    fn create_file() {
+        let t = overlay();
+        let dir = lookup(&t.fs, "dir").unwrap();
+        let (entry, handle, _) =
+            t.fs.create(
+                ctx(),
+                dir,
+                &cstr("created"),
+                0o644,
+                (libc::O_CREAT | libc::O_RDWR) as u32,
+                0o022,
+                None,
+            )
+            .unwrap();
+        t.fs.release(ctx(), entry.inode, 0, handle.unwrap(), false, false, None)
+            .unwrap();
+        assert_eq!(entry.attr.st_mode & 0o777, 0o644);
+        assert!(t.upper.path().join("dir/created").exists());
+        assert_eq!(list(&t.fs, "dir"), [".", "..", "created", "file"]);
+    }
+
+    #[test]
+    fn rejects_unsupported_options() {
+        let cfg = Config {
+            use_dax: true,
+            ..Default::default()
+        };
+        assert!(OverlayFs::new("tag", cfg).is_err());
+    }
+}
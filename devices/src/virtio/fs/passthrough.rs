@@ -28,7 +28,6 @@ use std::sync::MutexGuard;
 use std::sync::RwLock;
 use std::time::Duration;
 
-#[cfg(feature = "arc_quota")]
 use base::debug;
 use base::error;
 use base::ioctl_ior_nr;
@@ -63,6 +62,8 @@ use fuse::sys::WRITE_KILL_PRIV;
 use fuse::Mapper;
 #[cfg(feature = "arc_quota")]
 use protobuf::Message;
+use serde::Deserialize;
+use serde::Serialize;
 use sync::Mutex;
 #[cfg(feature = "arc_quota")]
 use system_api::client::OrgChromiumSpaced;
@@ -89,6 +90,7 @@ use crate::virtio::fs::caps::Value as CapValue;
 use crate::virtio::fs::config::CachePolicy;
 use crate::virtio::fs::config::Config;
 use crate::virtio::fs::expiring_map::ExpiringMap;
+use crate::virtio::fs::id_map::IdTranslator;
 use crate::virtio::fs::multikey::MultikeyBTreeMap;
 use crate::virtio::fs::read_dir::ReadDir;
 
@@ -277,6 +279,45 @@ impl AsRawDescriptor for HandleData {
     }
 }
 
+/// A reference to a host file returned by `name_to_handle_at(2)`, which remains valid when the
+/// file is renamed.
+#[derive(Serialize, Deserialize)]
+struct FileHandleRef {
+    handle_type: c_int,
+    handle: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct InodeSnapshot {
+    inode: Inode,
+    refcount: u64,
+    open_flags: c_int,
+    path: String,
+    file_handle: Option<FileHandleRef>,
+    // Host inode number, to check that a file reopened by path is still the same one.
+    ino: libc::ino64_t,
+}
+
+#[derive(Serialize, Deserialize)]
+struct HandleSnapshot {
+    handle: Handle,
+    inode: Inode,
+    flags: c_int,
+}
+
+/// The state of a `PassthroughFs` that is visible to the guest, with files referenced by handle or
+/// path instead of by descriptor.
+#[derive(Serialize, Deserialize)]
+pub struct PassthroughFsSnapshot {
+    next_inode: u64,
+    next_handle: u64,
+    writeback: bool,
+    zero_message_open: bool,
+    zero_message_opendir: bool,
+    inodes: Vec<InodeSnapshot>,
+    handles: Vec<HandleSnapshot>,
+}
+
 macro_rules! scoped_cred {
     ($name:ident, $ty:ty, $syscall_nr:expr) => {
         #[derive(Debug)]
@@ -501,15 +542,19 @@ fn drop_cap_fsetid() -> io::Result<ScopedFsetid> {
     Ok(ScopedFsetid(caps))
 }
 
-fn ebadf() -> io::Error {
+pub(crate) fn ebadf() -> io::Error {
     io::Error::from_raw_os_error(libc::EBADF)
 }
 
-fn eexist() -> io::Error {
+fn estale() -> io::Error {
+    io::Error::from_raw_os_error(libc::ESTALE)
+}
+
+pub(crate) fn eexist() -> io::Error {
     io::Error::from_raw_os_error(libc::EEXIST)
 }
 
-fn stat<F: AsRawDescriptor + ?Sized>(f: &F) -> io::Result<libc::stat64> {
+pub(crate) fn stat<F: AsRawDescriptor + ?Sized>(f: &F) -> io::Result<libc::stat64> {
     let mut st: MaybeUninit<libc::stat64> = MaybeUninit::<libc::stat64>::zeroed();
 
     // SAFETY: this is a constant value that is a nul-terminated string without interior nul bytes.
@@ -529,7 +574,7 @@ fn stat<F: AsRawDescriptor + ?Sized>(f: &F) -> io::Result<libc::stat64> {
     Ok(unsafe { st.assume_init() })
 }
 
-fn statat<D: AsRawDescriptor>(dir: &D, name: &CStr) -> io::Result<libc::stat64> {
+pub(crate) fn statat<D: AsRawDescriptor>(dir: &D, name: &CStr) -> io::Result<libc::stat64> {
     let mut st = MaybeUninit::<libc::stat64>::zeroed();
 
     // SAFETY: the kernel will only write data in `st` and we check the return value.
@@ -546,6 +591,95 @@ fn statat<D: AsRawDescriptor>(dir: &D, name: &CStr) -> io::Result<libc::stat64>
     Ok(unsafe { st.assume_init() })
 }
 
+// The largest file handle returned by `name_to_handle_at(2)`.
+const MAX_HANDLE_SZ: usize = 128;
+
+// `struct file_handle` with room for the largest handle.
+#[repr(C)]
+struct FileHandleBuf {
+    handle_bytes: u32,
+    handle_type: c_int,
+    f_handle: [u8; MAX_HANDLE_SZ],
+}
+
+/// Returns a handle to the file `f`, or `None` if its file system does not support them.
+fn name_to_handle<F: AsRawDescriptor + ?Sized>(f: &F) -> Option<FileHandleRef> {
+    let mut buf = FileHandleBuf {
+        handle_bytes: MAX_HANDLE_SZ as u32,
+        handle_type: 0,
+        f_handle: [0; MAX_HANDLE_SZ],
+    };
+    let mut mount_id: c_int = 0;
+
+    // SAFETY: this is a constant value that is a nul-terminated string without interior nul bytes.
+    let pathname = unsafe { CStr::from_bytes_with_nul_unchecked(EMPTY_CSTR) };
+
+    // SAFETY: the kernel will only write data in `buf` and `mount_id`, within the size given by
+    // `buf.handle_bytes`, and we check the return value.
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_name_to_handle_at,
+            f.as_raw_descriptor(),
+            pathname.as_ptr(),
+            &mut buf as *mut FileHandleBuf,
+            &mut mount_id as *mut c_int,
+            libc::AT_EMPTY_PATH,
+        )
+    };
+    if res < 0 {
+        return None;
+    }
+
+    Some(FileHandleRef {
+        handle_type: buf.handle_type,
+        handle: buf.f_handle[..buf.handle_bytes as usize].to_vec(),
+    })
+}
+
+/// Opens the file referenced by `handle` on the file system that `mount` belongs to.
+fn open_by_handle<F: AsRawDescriptor + ?Sized>(
+    mount: &F,
+    handle: &FileHandleRef,
+    flags: c_int,
+) -> io::Result<File> {
+    if handle.handle.len() > MAX_HANDLE_SZ {
+        return Err(io::Error::from_raw_os_error(libc::EINVAL));
+    }
+    let mut buf = FileHandleBuf {
+        handle_bytes: handle.handle.len() as u32,
+        handle_type: handle.handle_type,
+        f_handle: [0; MAX_HANDLE_SZ],
+    };
+    buf.f_handle[..handle.handle.len()].copy_from_slice(&handle.handle);
+
+    // SAFETY: this doesn't modify any memory and we check the return value.
+    let fd = syscall!(unsafe {
+        libc::syscall(
+            libc::SYS_open_by_handle_at,
+            mount.as_raw_descriptor(),
+            &mut buf as *mut FileHandleBuf,
+            flags | libc::O_CLOEXEC,
+        )
+    })?;
+
+    // SAFETY: safe because we just opened this descriptor.
+    Ok(unsafe { File::from_raw_descriptor(fd as RawDescriptor) })
+}
+
+/// Opens the root directory of the file system, which is the root directory of the process.
+fn open_root() -> io::Result<(File, c_int)> {
+    // SAFETY: this is a constant value that is a nul-terminated string without interior
+    // nul bytes.
+    let root = unsafe { CStr::from_bytes_with_nul_unchecked(ROOT_CSTR) };
+
+    let flags = libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+    // SAFETY: this doesn't modify any memory and we check the return value.
+    let raw_descriptor = syscall!(unsafe { libc::openat64(libc::AT_FDCWD, root.as_ptr(), flags) })?;
+
+    // SAFETY: safe because we just opened this descriptor above.
+    Ok((unsafe { File::from_raw_descriptor(raw_descriptor) }, flags))
+}
+
 #[cfg(feature = "arc_quota")]
 fn is_android_project_id(project_id: u32) -> bool {
     // The following constants defines the valid range of project ID used by
@@ -684,6 +818,12 @@ pub struct PassthroughFs {
     handles: Mutex<BTreeMap<Handle, Arc<HandleData>>>,
     next_handle: AtomicU64,
 
+    // Inodes restored from a snapshot whose file could not be reopened, with their refcount, and
+    // the handles of their files. Requests using them fail with `ESTALE` until the guest forgets
+    // or releases them.
+    stale_inodes: Mutex<BTreeMap<Inode, u64>>,
+    stale_handles: Mutex<BTreeMap<Handle, Inode>>,
+
     // File descriptor pointing to the `/proc` directory. This is used to convert an fd from
     // `inodes` into one that can go into `handles`. This is accomplished by reading the
     // `self/fd/{}` symlink. We keep an open fd here in case the file system tree that we are meant
@@ -694,6 +834,11 @@ pub struct PassthroughFs {
     // `cfg.writeback` is true and `init` was called with `FsOptions::WRITEBACK_CACHE`.
     writeback: AtomicBool,
 
+    // Whether regular files are opened in FUSE passthrough mode. This will only be true when
+    // `init` was called with `FsOptions::PASSTHROUGH` and writeback caching is disabled, which is
+    // only possible when serving a host FUSE mount.
+    passthrough: AtomicBool,
+
     // Whether zero message opens are supported by the kernel driver.
     zero_message_open: AtomicBool,
 
@@ -722,6 +867,9 @@ pub struct PassthroughFs {
     #[cfg(feature = "arc_quota")]
     xattr_paths: RwLock<Vec<XattrData>>,
 
+    // Translation between the user and group IDs of the guest and the host.
+    id_map: IdTranslator,
+
     cfg: Config,
 }
 
@@ -733,6 +881,7 @@ impl std::fmt::Debug for PassthroughFs {
             .field("next_handle", &self.next_handle)
             .field("proc", &self.proc)
             .field("writeback", &self.writeback)
+            .field("passthrough", &self.passthrough)
             .field("zero_message_open", &self.zero_message_open)
             .field("zero_message_opendir", &self.zero_message_opendir)
             .field("cfg", &self.cfg)
@@ -788,10 +937,13 @@ impl PassthroughFs {
 
             handles: Mutex::new(BTreeMap::new()),
             next_handle: AtomicU64::new(1),
+            stale_inodes: Mutex::new(BTreeMap::new()),
+            stale_handles: Mutex::new(BTreeMap::new()),
 
             proc,
 
             writeback: AtomicBool::new(false),
+            passthrough: AtomicBool::new(false),
             zero_message_open: AtomicBool::new(false),
             zero_message_opendir: AtomicBool::new(false),
 
@@ -804,6 +956,7 @@ impl PassthroughFs {
             permission_paths: RwLock::new(Vec::new()),
             #[cfg(feature = "arc_quota")]
             xattr_paths: RwLock::new(Vec::new()),
+            id_map: IdTranslator::new(&cfg),
             cfg,
         };
 
@@ -829,6 +982,188 @@ impl PassthroughFs {
         keep_rds
     }
 
+    /// Returns the state of the inodes and handles known to the guest. It must not be called while
+    /// requests are being processed.
+    ///
+    /// The dynamic permissions and xattrs of the `arc_quota` feature are not included.
+    pub fn snapshot(&self) -> io::Result<PassthroughFsSnapshot> {
+        let inodes = self
+            .inodes
+            .lock()
+            .values()
+            .map(|data| {
+                let file = data.file.lock();
+                Ok(InodeSnapshot {
+                    inode: data.inode,
+                    refcount: data.refcount.load(Ordering::Acquire),
+                    open_flags: file.1,
+                    path: data.path.clone(),
+                    file_handle: name_to_handle(&file.0),
+                    ino: stat(&file.0)?.st_ino,
+                })
+            })
+            .collect::<io::Result<_>>()?;
+
+        let handles = self
+            .handles
+            .lock()
+            .iter()
+            .map(|(handle, data)| {
+                // SAFETY: this doesn't modify any memory and we check the return value.
+                let flags =
+                    syscall!(unsafe { libc::fcntl(data.as_raw_descriptor(), libc::F_GETFL) })?;
+                Ok(HandleSnapshot {
+                    handle: *handle,
+                    inode: data.inode,
+                    flags,
+                })
+            })
+            .collect::<io::Result<_>>()?;
+
+        Ok(PassthroughFsSnapshot {
+            next_inode: self.next_inode.load(Ordering::Relaxed),
+            next_handle: self.next_handle.load(Ordering::Relaxed),
+            writeback: self.writeback.load(Ordering::Relaxed),
+            zero_message_open: self.zero_message_open.load(Ordering::Relaxed),
+            zero_message_opendir: self.zero_message_opendir.load(Ordering::Relaxed),
+            inodes,
+            handles,
+        })
+    }
+
+    /// Replaces the inodes and handles with the ones of `snapshot`, reopening their files. This
+    /// takes the place of the `init` request of the guest that created them.
+    ///
+    /// Inodes whose file cannot be reopened, e.g. because it was deleted in the meantime, and the
+    /// handles that refer to them become stale: requests using them fail with `ESTALE`.
+    pub fn restore(&self, snapshot: PassthroughFsSnapshot) -> io::Result<()> {
+        let (root, root_flags) = open_root()?;
+
+        // SAFETY: this doesn't modify any memory and there is no need to check the return
+        // value because this system call always succeeds. See `init`.
+        unsafe { libc::umask(0o000) };
+
+        let mut inodes = MultikeyBTreeMap::new();
+        let mut stale_inodes = BTreeMap::new();
+        for data in snapshot.inodes {
+            let reopened = if data.inode == ROOT_ID {
+                root.try_clone().map(|file| (file, root_flags))
+            } else {
+                self.reopen_inode(&root, &data)
+                    .map(|file| (file, data.open_flags))
+            };
+            let (file, open_flags, st) = match reopened.and_then(|(f, flags)| {
+                let st = stat(&f)?;
+                Ok((f, flags, st))
+            }) {
+                Ok(reopened) => reopened,
+                Err(e) if data.inode == ROOT_ID => return Err(e),
+                Err(e) => {
+                    warn!(
+                        "{}: inode {} ({:?}) is stale: {}",
+                        self.tag, data.inode, data.path, e
+                    );
+                    stale_inodes.insert(data.inode, data.refcount);
+                    continue;
+                }
+            };
+            inodes.insert(
+                data.inode,
+                InodeAltKey {
+                    ino: st.st_ino,
+                    dev: st.st_dev,
+                },
+                Arc::new(InodeData {
+                    inode: data.inode,
+                    file: Mutex::new((file, open_flags)),
+                    refcount: AtomicU64::new(data.refcount),
+                    filetype: st.st_mode.into(),
+                    path: data.path,
+                }),
+            );
+        }
+
+        let mut handles = BTreeMap::new();
+        let mut stale_handles = BTreeMap::new();
+        for data in snapshot.handles {
+            let file = match inodes.get(&data.inode) {
+                Some(inode) => self.open_fd(inode.as_raw_descriptor(), data.flags),
+                None if stale_inodes.contains_key(&data.inode) => Err(estale()),
+                None => Err(ebadf()),
+            };
+            match file {
+                Ok(file) => {
+                    handles.insert(
+                        data.handle,
+                        Arc::new(HandleData {
+                            inode: data.inode,
+                            file: Mutex::new(file),
+                        }),
+                    );
+                }
+                Err(e) => {
+                    warn!(
+                        "{}: handle {} of inode {} is stale: {}",
+                        self.tag, data.handle, data.inode, e
+                    );
+                    stale_handles.insert(data.handle, data.inode);
+                }
+            }
+        }
+
+        *self.inodes.lock() = inodes;
+        *self.handles.lock() = handles;
+        *self.stale_inodes.lock() = stale_inodes;
+        *self.stale_handles.lock() = stale_handles;
+        if let Some(mut caches) = self.lock_casefold_lookup_caches() {
+            *caches = ExpiringCasefoldLookupCaches::new(self.cfg.timeout);
+        }
+
+        self.next_inode
+            .store(snapshot.next_inode, Ordering::Relaxed);
+        self.next_handle
+            .store(snapshot.next_handle, Ordering::Relaxed);
+        self.writeback.store(snapshot.writeback, Ordering::Relaxed);
+        self.zero_message_open
+            .store(snapshot.zero_message_open, Ordering::Relaxed);
+        self.zero_message_opendir
+            .store(snapshot.zero_message_opendir, Ordering::Relaxed);
+        Ok(())
+    }
+
+    // Reopens the file of a snapshotted inode, by handle if possible since its path is not updated
+    // when it is renamed. Opening by handle requires `CAP_DAC_READ_SEARCH`.
+    fn reopen_inode(&self, root: &File, data: &InodeSnapshot) -> io::Result<File> {
+        if let Some(handle) = &data.file_handle {
+            match open_by_handle(root, handle, data.open_flags) {
+                Ok(file) => return Ok(file),
+                Err(e) => debug!(
+                    "failed to open inode {} by handle, falling back to {:?}: {}",
+                    data.inode, data.path, e
+                ),
+            }
+        }
+
+        let path = CString::new(data.path.trim_start_matches('/'))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        // SAFETY: this doesn't modify any memory and we check the return value.
+        let fd = syscall!(unsafe {
+            libc::openat64(
+                root.as_raw_descriptor(),
+                path.as_ptr(),
+                data.open_flags | libc::O_CLOEXEC,
+            )
+        })?;
+
+        // SAFETY: safe because we just opened this descriptor.
+        let file = unsafe { File::from_raw_descriptor(fd) };
+        // Another file may have taken the place of the original one.
+        if stat(&file)?.st_ino != data.ino {
+            return Err(estale());
+        }
+        Ok(file)
+    }
+
     fn rewrite_xattr_name<'xattr>(&self, name: &'xattr CStr) -> Cow<'xattr, CStr> {
         if !self.cfg.rewrite_security_xattrs {
             return Cow::Borrowed(name);
@@ -849,16 +1184,41 @@ impl PassthroughFs {
     }
 
     fn find_inode(&self, inode: Inode) -> io::Result<Arc<InodeData>> {
-        self.inodes.lock().get(&inode).cloned().ok_or_else(ebadf)
+        if let Some(data) = self.inodes.lock().get(&inode) {
+            return Ok(data.clone());
+        }
+        if self.stale_inodes.lock().contains_key(&inode) {
+            Err(estale())
+        } else {
+            Err(ebadf())
+        }
     }
 
     fn find_handle(&self, handle: Handle, inode: Inode) -> io::Result<Arc<HandleData>> {
-        self.handles
+        if let Some(data) = self
+            .handles
             .lock()
             .get(&handle)
             .filter(|hd| hd.inode == inode)
-            .cloned()
-            .ok_or_else(ebadf)
+        {
+            return Ok(data.clone());
+        }
+        if self.stale_handles.lock().get(&handle) == Some(&inode) {
+            Err(estale())
+        } else {
+            Err(ebadf())
+        }
+    }
+
+    /// Decrements the refcount of `inode` if it is stale, dropping it once it reaches 0.
+    fn forget_stale(&self, inode: Inode, count: u64) {
+        let mut stale_inodes = self.stale_inodes.lock();
+        if let btree_map::Entry::Occupied(mut e) = stale_inodes.entry(inode) {
+            *e.get_mut() = e.get().saturating_sub(count);
+            if *e.get() == 0 {
+                e.remove();
+            }
+        }
     }
 
     fn open_fd(&self, fd: RawDescriptor, flags: i32) -> io::Result<File> {
@@ -926,11 +1286,11 @@ impl PassthroughFs {
     fn add_entry(
         &self,
         f: File,
-        #[cfg(feature = "arc_quota")] mut st: libc::stat64,
-        #[cfg(not(feature = "arc_quota"))] st: libc::stat64,
+        mut st: libc::stat64,
         open_flags: libc::c_int,
         path: String,
     ) -> Entry {
+        self.id_map.stat_to_guest(&mut st);
         #[cfg(feature = "arc_quota")]
         self.set_permission(&mut st, &path);
         let mut inodes = self.inodes.lock();
@@ -1008,10 +1368,7 @@ impl PassthroughFs {
     }
 
     fn do_lookup(&self, parent: &InodeData, name: &CStr) -> io::Result<Entry> {
-        #[cfg(feature = "arc_quota")]
         let mut st = statat(parent, name)?;
-        #[cfg(not(feature = "arc_quota"))]
-        let st = statat(parent, name)?;
 
         let altkey = InodeAltKey {
             ino: st.st_ino,
@@ -1027,6 +1384,7 @@ impl PassthroughFs {
         // Check if we already have an entry before opening a new file.
         if let Some(data) = self.inodes.lock().get_alt(&altkey) {
             // Return the same inode with the reference counter increased.
+            self.id_map.stat_to_guest(&mut st);
             #[cfg(feature = "arc_quota")]
             self.set_permission(&mut st, &path);
             return Ok(Entry {
@@ -1134,11 +1492,25 @@ impl PassthroughFs {
 
         self.handles.lock().insert(handle, Arc::new(data));
 
-        let opts = self.get_cache_open_options(flags);
+        let mut opts = self.get_cache_open_options(flags);
+        if inode_data.filetype == FileType::Regular {
+            opts |= self.passthrough_open_options();
+        }
 
         Ok((Some(handle), opts))
     }
 
+    // Returns `OpenOptions::PASSTHROUGH` if the kernel should read and write newly opened regular
+    // files directly. This must not be applied to atomic open replies, where the same bit means
+    // `OpenOptions::FILE_CREATED`.
+    fn passthrough_open_options(&self) -> OpenOptions {
+        if self.passthrough.load(Ordering::Relaxed) {
+            OpenOptions::PASSTHROUGH
+        } else {
+            OpenOptions::empty()
+        }
+    }
+
     fn do_open_at(
         &self,
         parent_data: Arc<InodeData>,
@@ -1184,17 +1556,24 @@ impl PassthroughFs {
                 return Ok(());
             }
         }
+        drop(handles);
+
+        let mut stale_handles = self.stale_handles.lock();
+        if let btree_map::Entry::Occupied(e) = stale_handles.entry(handle) {
+            if *e.get() == inode {
+                e.remove();
+                return Ok(());
+            }
+        }
 
         Err(ebadf())
     }
 
     fn do_getattr(&self, inode: &InodeData) -> io::Result<(libc::stat64, Duration)> {
-        #[cfg(feature = "arc_quota")]
         let mut st = stat(inode)?;
+        self.id_map.stat_to_guest(&mut st);
         #[cfg(feature = "arc_quota")]
         self.set_permission(&mut st, &inode.path);
-        #[cfg(not(feature = "arc_quota"))]
-        let st = stat(inode)?;
         Ok((st, self.cfg.timeout))
     }
 
@@ -1725,7 +2104,7 @@ impl PassthroughFs {
             }
         }
 
-        (ctx.uid, ctx.gid)
+        self.id_map.host_creds(ctx.uid, ctx.gid)
     }
 
     fn read_permission_data<R: io::Read>(&self, mut r: R) -> io::Result<PermissionData> {
@@ -1976,19 +2355,7 @@ impl FileSystem for PassthroughFs {
     type DirIter = ReadDir<Box<[u8]>>;
 
     fn init(&self, capable: FsOptions) -> io::Result<FsOptions> {
-        // SAFETY: this is a constant value that is a nul-terminated string without interior
-        // nul bytes.
-        let root = unsafe { CStr::from_bytes_with_nul_unchecked(ROOT_CSTR) };
-
-        let flags = libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC;
-        // SAFETY: this doesn't modify any memory and we check the return value.
-        let raw_descriptor = unsafe { libc::openat64(libc::AT_FDCWD, root.as_ptr(), flags) };
-        if raw_descriptor < 0 {
-            return Err(io::Error::last_os_error());
-        }
-
-        // SAFETY: safe because we just opened this descriptor above.
-        let f = unsafe { File::from_raw_descriptor(raw_descriptor) };
+        let (f, flags) = open_root()?;
 
         let st = stat(&f)?;
 
@@ -2034,6 +2401,11 @@ impl FileSystem for PassthroughFs {
         if self.cfg.writeback && capable.contains(FsOptions::WRITEBACK_CACHE) {
             opts |= FsOptions::WRITEBACK_CACHE;
             self.writeback.store(true, Ordering::Relaxed);
+        } else if capable.contains(FsOptions::PASSTHROUGH) {
+            // Writeback caching relies on the page cache of the FUSE mount, which passthrough files
+            // bypass.
+            opts |= FsOptions::PASSTHROUGH;
+            self.passthrough.store(true, Ordering::Relaxed);
         }
         if self.cfg.cache_policy == CachePolicy::Always {
             if capable.contains(FsOptions::ZERO_MESSAGE_OPEN) {
@@ -2052,6 +2424,8 @@ impl FileSystem for PassthroughFs {
         cros_tracing::trace_simple_print!(VirtioFs, "{:?}: destroy", self);
         self.handles.lock().clear();
         self.inodes.lock().clear();
+        self.stale_handles.lock().clear();
+        self.stale_inodes.lock().clear();
     }
 
     fn statfs(&self, _ctx: Context, inode: Inode) -> io::Result<libc::statvfs64> {
@@ -2100,6 +2474,7 @@ impl FileSystem for PassthroughFs {
                 c.forget(inode);
             }
         }
+        self.forget_stale(inode, count);
     }
 
     fn batch_forget(&self, _ctx: Context, requests: Vec<(Inode, u64)>) {
@@ -2111,6 +2486,7 @@ impl FileSystem for PassthroughFs {
                     c.forget(inode);
                 }
             }
+            self.forget_stale(inode, count);
         }
     }
 
@@ -2163,7 +2539,7 @@ impl FileSystem for PassthroughFs {
         #[cfg(feature = "arc_quota")]
         let (uid, gid) = self.change_creds(&ctx, &data, name);
         #[cfg(not(feature = "arc_quota"))]
-        let (uid, gid) = (ctx.uid, ctx.gid);
+        let (uid, gid) = self.id_map.host_creds(ctx.uid, ctx.gid);
 
         let (_uid, _gid) = set_creds(uid, gid)?;
         {
@@ -2280,7 +2656,7 @@ impl FileSystem for PassthroughFs {
         #[cfg(feature = "arc_quota")]
         let (uid, gid) = self.change_creds(&ctx, &data, current_dir);
         #[cfg(not(feature = "arc_quota"))]
-        let (uid, gid) = (ctx.uid, ctx.gid);
+        let (uid, gid) = self.id_map.host_creds(ctx.uid, ctx.gid);
         let (_uid, _gid) = set_creds(uid, gid)?;
 
         let fd = {
@@ -2339,7 +2715,7 @@ impl FileSystem for PassthroughFs {
         #[cfg(feature = "arc_quota")]
         let (uid, gid) = self.change_creds(&ctx, &data, name);
         #[cfg(not(feature = "arc_quota"))]
-        let (uid, gid) = (ctx.uid, ctx.gid);
+        let (uid, gid) = self.id_map.host_creds(ctx.uid, ctx.gid);
         let (_uid, _gid) = set_creds(uid, gid)?;
 
         let flags = self.update_open_flags(flags as i32);
@@ -2378,21 +2754,34 @@ impl FileSystem for PassthroughFs {
         let (handle, opts) = if self.zero_message_open.load(Ordering::Relaxed) {
             (None, OpenOptions::KEEP_CACHE)
         } else {
-            self.do_open_at(
-                data,
-                name,
-                entry.inode,
-                (flags & !(libc::O_CREAT | libc::O_EXCL | libc::O_NOCTTY)) as u32,
-            )
-            .map_err(|e| {
-                // Don't leak the entry.
-                self.forget(ctx, entry.inode, 1);
-                e
-            })?
+            let (handle, opts) = self
+                .do_open_at(
+                    data,
+                    name,
+                    entry.inode,
+                    (flags & !(libc::O_CREAT | libc::O_EXCL | libc::O_NOCTTY)) as u32,
+                )
+                .map_err(|e| {
+                    // Don't leak the entry.
+                    self.forget(ctx, entry.inode, 1);
+                    e
+                })?;
+            if FileType::from(entry.attr.st_mode) == FileType::Regular {
+                (handle, opts | self.passthrough_open_options())
+            } else {
+                (handle, opts)
+            }
         };
         Ok((entry, handle, opts))
     }
 
+    fn backing_file(&self, inode: Inode, handle: Handle) -> io::Result<File> {
+        let _trace = fs_trace!(self.tag, "backing_file", inode, handle);
+        let data = self.find_handle(handle, inode)?;
+        let file = data.file.lock();
+        file.try_clone()
+    }
+
     fn unlink(&self, _ctx: Context, parent: Inode, name: &CStr) -> io::Result<()> {
         let _trace = fs_trace!(self.tag, "unlink", parent, name);
         let data = self.find_inode(parent)?;
@@ -2557,13 +2946,13 @@ impl FileSystem for PassthroughFs {
 
         if valid.intersects(SetattrValid::UID | SetattrValid::GID) {
             let uid = if valid.contains(SetattrValid::UID) {
-                attr.st_uid
+                self.id_map.host_uid(attr.st_uid)?
             } else {
                 // Cannot use -1 here because these are unsigned values.
                 u32::MAX
             };
             let gid = if valid.contains(SetattrValid::GID) {
-                attr.st_gid
+                self.id_map.host_gid(attr.st_gid)?
             } else {
                 // Cannot use -1 here because these are unsigned values.
                 u32::MAX
@@ -2708,7 +3097,7 @@ impl FileSystem for PassthroughFs {
         #[cfg(feature = "arc_quota")]
         let (uid, gid) = self.change_creds(&ctx, &data, name);
         #[cfg(not(feature = "arc_quota"))]
-        let (uid, gid) = (ctx.uid, ctx.gid);
+        let (uid, gid) = self.id_map.host_creds(ctx.uid, ctx.gid);
         let (_uid, _gid) = set_creds(uid, gid)?;
         {
             let _scoped_umask = ScopedUmask::new(umask);
@@ -2784,7 +3173,7 @@ impl FileSystem for PassthroughFs {
         #[cfg(feature = "arc_quota")]
         let (uid, gid) = self.change_creds(&ctx, &data, name);
         #[cfg(not(feature = "arc_quota"))]
-        let (uid, gid) = (ctx.uid, ctx.gid);
+        let (uid, gid) = self.id_map.host_creds(ctx.uid, ctx.gid);
         let (_uid, _gid) = set_creds(uid, gid)?;
         {
             let casefold_cache = self.lock_casefold_lookup_caches();
@@ -2900,10 +3289,14 @@ impl FileSystem for PassthroughFs {
             return Ok(());
         }
 
+        // Permissions are checked against the host owner of the file.
+        let privileged = self.id_map.is_privileged(ctx.uid);
+        let (uid, gid) = self.id_map.host_creds(ctx.uid, ctx.gid);
+
         if (mode & libc::R_OK) != 0 {
-            if ctx.uid != 0
-                && (st.st_uid != ctx.uid || st.st_mode & 0o400 == 0)
-                && (st.st_gid != ctx.gid || st.st_mode & 0o040 == 0)
+            if !privileged
+                && (st.st_uid != uid || st.st_mode & 0o400 == 0)
+                && (st.st_gid != gid || st.st_mode & 0o040 == 0)
                 && st.st_mode & 0o004 == 0
             {
                 return Err(io::Error::from_raw_os_error(libc::EACCES));
@@ -2911,9 +3304,9 @@ impl FileSystem for PassthroughFs {
         }
 
         if (mode & libc::W_OK) != 0 {
-            if ctx.uid != 0
-                && (st.st_uid != ctx.uid || st.st_mode & 0o200 == 0)
-                && (st.st_gid != ctx.gid || st.st_mode & 0o020 == 0)
+            if !privileged
+                && (st.st_uid != uid || st.st_mode & 0o200 == 0)
+                && (st.st_gid != gid || st.st_mode & 0o020 == 0)
                 && st.st_mode & 0o002 == 0
             {
                 return Err(io::Error::from_raw_os_error(libc::EACCES));
@@ -2923,9 +3316,9 @@ impl FileSystem for PassthroughFs {
         // root can only execute something if it is executable by one of the owner, the group, or
         // everyone.
         if (mode & libc::X_OK) != 0 {
-            if (ctx.uid != 0 || st.st_mode & 0o111 == 0)
-                && (st.st_uid != ctx.uid || st.st_mode & 0o100 == 0)
-                && (st.st_gid != ctx.gid || st.st_mode & 0o010 == 0)
+            if (!privileged || st.st_mode & 0o111 == 0)
+                && (st.st_uid != uid || st.st_mode & 0o100 == 0)
+                && (st.st_gid != gid || st.st_mode & 0o010 == 0)
                 && st.st_mode & 0o001 == 0
             {
                 return Err(io::Error::from_raw_os_error(libc::EACCES));
@@ -2953,6 +3346,16 @@ impl FileSystem for PassthroughFs {
         let data = self.find_inode(inode)?;
         let name = self.rewrite_xattr_name(name);
 
+        // POSIX ACLs name users and groups by their host IDs.
+        let mut acl;
+        let value = if self.id_map.is_acl_xattr(name.to_bytes()) {
+            acl = value.to_vec();
+            self.id_map.acl_to_host(&mut acl)?;
+            &acl[..]
+        } else {
+            value
+        };
+
         #[cfg(feature = "arc_quota")]
         if self.skip_host_set_xattr(&data.path, &name.to_string_lossy()) {
             debug!(
@@ -3019,6 +3422,7 @@ impl FileSystem for PassthroughFs {
 
         let data = self.find_inode(inode)?;
         let name = self.rewrite_xattr_name(name);
+        let is_acl = self.id_map.is_acl_xattr(name.to_bytes());
         let mut buf = vec![0u8; size as usize];
 
         #[cfg(feature = "arc_quota")]
@@ -3031,6 +3435,9 @@ impl FileSystem for PassthroughFs {
             Ok(GetxattrReply::Count(res as u32))
         } else {
             buf.truncate(res);
+            if is_acl {
+                self.id_map.acl_to_guest(&mut buf)?;
+            }
             Ok(GetxattrReply::Value(buf))
         }
     }
@@ -3276,7 +3683,8 @@ impl FileSystem for PassthroughFs {
         );
         // We need to change credentials during a write so that the kernel will remove setuid or
         // setgid bits from the file if it was written to by someone other than the owner.
-        let (_uid, _gid) = set_creds(ctx.uid, ctx.gid)?;
+        let (uid, gid) = self.id_map.host_creds(ctx.uid, ctx.gid);
+        let (_uid, _gid) = set_creds(uid, gid)?;
         let (src_data, dst_data): (Arc<dyn AsRawDescriptor>, Arc<dyn AsRawDescriptor>) =
             if self.zero_message_open.load(Ordering::Relaxed) {
                 (self.find_inode(inode_src)?, self.find_inode(inode_dst)?)
@@ -3410,7 +3818,7 @@ impl FileSystem for PassthroughFs {
         #[cfg(feature = "arc_quota")]
         let (uid, gid) = self.change_creds(&ctx, &data, name);
         #[cfg(not(feature = "arc_quota"))]
-        let (uid, gid) = (ctx.uid, ctx.gid);
+        let (uid, gid) = self.id_map.host_creds(ctx.uid, ctx.gid);
         let (_uid, _gid) = set_creds(uid, gid)?;
 
         // This lookup serves two purposes:
@@ -3461,6 +3869,7 @@ impl FileSystem for PassthroughFs {
 
 #[cfg(test)]
 mod tests {
+    use std::os::unix::fs::MetadataExt;
     use std::path::Path;
 
     use named_lock::NamedLock;
@@ -3949,6 +4358,221 @@ mod tests {
         test_create_and_forget(true /* ascii_casefold */);
     }
 
+    #[test]
+    fn snapshot_restore() {
+        // Since PassthroughFs may executes process-wide operations such as `fchdir`, acquire
+        // `NamedLock` before starting each unit test creating a `PassthroughFs` instance.
+        let lock = NamedLock::create(UNITTEST_LOCK_NAME).expect("create named lock");
+        let _guard = lock.lock().expect("acquire named lock");
+
+        let temp_dir = TempDir::new().unwrap();
+        create_test_data(&temp_dir, &["dir"], &["dir/a.txt"]);
+
+        let fs = PassthroughFs::new("tag", Default::default()).unwrap();
+        fs.init(FsOptions::empty()).unwrap();
+
+        let (entry, handle, _) = atomic_open(
+            &fs,
+            &temp_dir.path().join("dir/a.txt"),
+            0o666,
+            libc::O_RDWR as u32,
+            0,
+            None,
+        )
+        .expect("open a.txt");
+        let handle = handle.expect("a.txt must have a handle");
+
+        let snapshot = serde_json::to_value(fs.snapshot().expect("snapshot")).unwrap();
+        drop(fs);
+
+        let fs = PassthroughFs::new("tag", Default::default()).unwrap();
+        fs.restore(serde_json::from_value(snapshot).unwrap())
+            .expect("restore");
+
+        let inode = fs
+            .find_inode(entry.inode)
+            .expect("a.txt inode must be restored");
+        assert_eq!(stat(&*inode).unwrap().st_ino, entry.attr.st_ino);
+        let data = fs
+            .find_handle(handle, entry.inode)
+            .expect("a.txt handle must be restored");
+        let flags = FileFlags::from_file(&*data.file.lock()).unwrap();
+        assert_eq!(flags, FileFlags::ReadWrite);
+
+        // Looking up the file again returns the restored inode.
+        assert_eq!(
+            lookup(&fs, &temp_dir.path().join("dir/a.txt")).unwrap(),
+            entry.inode
+        );
+    }
+
+    /// Runs `f` on a thread without `CAP_DAC_READ_SEARCH`, which `open_by_handle_at(2)` requires.
+    fn without_dac_read_search<T: Send>(f: impl FnOnce() -> T + Send) -> T {
+        #[repr(C)]
+        struct CapHeader {
+            version: u32,
+            pid: c_int,
+        }
+        #[repr(C)]
+        #[derive(Clone, Copy, Default)]
+        struct CapData {
+            effective: u32,
+            permitted: u32,
+            inheritable: u32,
+        }
+        const LINUX_CAPABILITY_VERSION_3: u32 = 0x20080522;
+        const CAP_DAC_READ_SEARCH: u32 = 2;
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let mut header = CapHeader {
+                    version: LINUX_CAPABILITY_VERSION_3,
+                    pid: 0,
+                };
+                let mut data = [CapData::default(); 2];
+                // SAFETY: the kernel only writes to `header` and `data`, which are large enough
+                // for version 3 capabilities, and we check the return value.
+                syscall!(unsafe {
+                    libc::syscall(libc::SYS_capget, &mut header, data.as_mut_ptr())
+                })
+                .expect("capget");
+                // Capabilities are per thread, so this only affects the current one.
+                data[0].effective &= !(1 << CAP_DAC_READ_SEARCH);
+                // SAFETY: this doesn't modify any memory and we check the return value.
+                syscall!(unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_ptr()) })
+                    .expect("capset");
+                f()
+            })
+            .join()
+            .unwrap()
+        })
+    }
+
+    fn assert_errno<T>(res: io::Result<T>, errno: c_int) {
+        match res {
+            Ok(_) => panic!("expected error {}", errno),
+            Err(e) => assert_eq!(e.raw_os_error(), Some(errno)),
+        }
+    }
+
+    #[test]
+    fn restore_unlinked_file() {
+        // Since PassthroughFs may executes process-wide operations such as `fchdir`, acquire
+        // `NamedLock` before starting each unit test creating a `PassthroughFs` instance.
+        let lock = NamedLock::create(UNITTEST_LOCK_NAME).expect("create named lock");
+        let _guard = lock.lock().expect("acquire named lock");
+
+        let temp_dir = TempDir::new().unwrap();
+        create_test_data(&temp_dir, &["dir"], &["dir/a.txt", "dir/b.txt"]);
+
+        let fs = PassthroughFs::new("tag", Default::default()).unwrap();
+        fs.init(FsOptions::empty()).unwrap();
+        let (entry, handle, _) = atomic_open(
+            &fs,
+            &temp_dir.path().join("dir/a.txt"),
+            0o666,
+            libc::O_RDWR as u32,
+            0,
+            None,
+        )
+        .expect("open a.txt");
+        let handle = handle.expect("a.txt must have a handle");
+        let other = lookup(&fs, &temp_dir.path().join("dir/b.txt")).unwrap();
+
+        // The file is deleted while the VM runs. It disappears once the device is stopped.
+        std::fs::remove_file(temp_dir.path().join("dir/a.txt")).unwrap();
+        let snapshot = fs.snapshot().expect("snapshot");
+        drop(fs);
+
+        let fs = PassthroughFs::new("tag", Default::default()).unwrap();
+        fs.restore(snapshot).expect("restore");
+
+        let ctx = get_context();
+        assert_errno(fs.getattr(ctx, entry.inode, None), libc::ESTALE);
+        assert_errno(fs.find_handle(handle, entry.inode), libc::ESTALE);
+        // The other inodes are usable.
+        fs.getattr(ctx, other, None).expect("getattr b.txt");
+
+        // The guest can still release and forget the stale handle and inode.
+        fs.release(ctx, entry.inode, 0, handle, false, false, None)
+            .expect("release stale handle");
+        assert_errno(fs.find_handle(handle, entry.inode), libc::EBADF);
+        fs.forget(ctx, entry.inode, u64::MAX);
+        assert_errno(fs.getattr(ctx, entry.inode, None), libc::EBADF);
+    }
+
+    #[test]
+    fn restore_moved_files_without_dac_read_search() {
+        // Since PassthroughFs may executes process-wide operations such as `fchdir`, acquire
+        // `NamedLock` before starting each unit test creating a `PassthroughFs` instance.
+        let lock = NamedLock::create(UNITTEST_LOCK_NAME).expect("create named lock");
+        let _guard = lock.lock().expect("acquire named lock");
+
+        let temp_dir = TempDir::new().unwrap();
+        create_test_data(
+            &temp_dir,
+            &["dir"],
+            &["dir/a.txt", "dir/b.txt", "dir/c.txt", "dir/d.txt"],
+        );
+
+        let fs = PassthroughFs::new("tag", Default::default()).unwrap();
+        fs.init(FsOptions::empty()).unwrap();
+        let renamed = lookup(&fs, &temp_dir.path().join("dir/a.txt")).unwrap();
+        let replaced = lookup(&fs, &temp_dir.path().join("dir/b.txt")).unwrap();
+        let kept = lookup(&fs, &temp_dir.path().join("dir/c.txt")).unwrap();
+        let snapshot = fs.snapshot().expect("snapshot");
+        drop(fs);
+
+        let dir = temp_dir.path().join("dir");
+        std::fs::rename(dir.join("a.txt"), dir.join("e.txt")).unwrap();
+        std::fs::rename(dir.join("d.txt"), dir.join("b.txt")).unwrap();
+
+        // Without handles, inodes can only be found by path.
+        let fs = PassthroughFs::new("tag", Default::default()).unwrap();
+        without_dac_read_search(|| fs.restore(snapshot)).expect("restore");
+
+        let ctx = get_context();
+        assert_errno(fs.getattr(ctx, renamed, None), libc::ESTALE);
+        // The file now found at the path of `b.txt` is another one.
+        assert_errno(fs.getattr(ctx, replaced, None), libc::ESTALE);
+        fs.getattr(ctx, kept, None).expect("getattr c.txt");
+    }
+
+    #[test]
+    fn restore_renamed_file_by_handle() {
+        // Since PassthroughFs may executes process-wide operations such as `fchdir`, acquire
+        // `NamedLock` before starting each unit test creating a `PassthroughFs` instance.
+        let lock = NamedLock::create(UNITTEST_LOCK_NAME).expect("create named lock");
+        let _guard = lock.lock().expect("acquire named lock");
+
+        let temp_dir = TempDir::new().unwrap();
+        create_test_data(&temp_dir, &["dir"], &["dir/a.txt"]);
+
+        // Opening by handle needs `CAP_DAC_READ_SEARCH` and support from the file system.
+        let (root, _) = open_root().unwrap();
+        let file = File::open(temp_dir.path().join("dir/a.txt")).unwrap();
+        match name_to_handle(&file).map(|h| open_by_handle(&root, &h, libc::O_RDONLY)) {
+            Some(Ok(_)) => {}
+            _ => return,
+        }
+
+        let fs = PassthroughFs::new("tag", Default::default()).unwrap();
+        fs.init(FsOptions::empty()).unwrap();
+        let inode = lookup(&fs, &temp_dir.path().join("dir/a.txt")).unwrap();
+        let snapshot = fs.snapshot().expect("snapshot");
+        drop(fs);
+
+        let dir = temp_dir.path().join("dir");
+        std::fs::rename(dir.join("a.txt"), dir.join("b.txt")).unwrap();
+
+        let fs = PassthroughFs::new("tag", Default::default()).unwrap();
+        fs.restore(snapshot).expect("restore");
+        let (st, _) = fs
+            .getattr(get_context(), inode, None)
+            .expect("getattr renamed file");
+        assert_eq!(st.st_ino, file.metadata().unwrap().ino());
+    }
+
     #[test]
     fn casefold_lookup_cache() {
         let temp_dir = TempDir::new().unwrap();
@@ -4719,4 +5343,67 @@ mod tests {
     fn test_atomic_open_create_o_append_writeback() {
         atomic_open_create_o_append(true);
     }
+
+    /// Opens a file and a directory after negotiating FUSE passthrough with the given `capable`
+    /// options. Returns whether each of them was opened in passthrough mode.
+    fn open_with_passthrough(cfg: Config, capable: FsOptions) -> (bool, bool) {
+        // Since PassthroughFs may executes process-wide operations such as `fchdir`, acquire
+        // `NamedLock` before starting each unit test creating a `PassthroughFs` instance.
+        let lock = NamedLock::create(UNITTEST_LOCK_NAME).expect("create named lock");
+        let _guard = lock.lock().expect("acquire named lock");
+
+        let temp_dir = TempDir::new().unwrap();
+        create_test_data(&temp_dir, &["dir"], &["file"]);
+
+        let fs = PassthroughFs::new("tag", cfg).unwrap();
+        fs.init(capable).unwrap();
+        let ctx = get_context();
+
+        let file = lookup(&fs, &temp_dir.path().join("file")).unwrap();
+        let (handle, file_opts) = fs.open(ctx, file, libc::O_RDWR as u32).unwrap();
+        if file_opts.contains(OpenOptions::PASSTHROUGH) {
+            let backing = fs.backing_file(file, handle.unwrap()).unwrap();
+            assert_eq!(
+                backing.metadata().unwrap().ino(),
+                std::fs::metadata(temp_dir.path().join("file"))
+                    .unwrap()
+                    .ino()
+            );
+        }
+
+        let dir = lookup(&fs, &temp_dir.path().join("dir")).unwrap();
+        let (_, dir_opts) = fs
+            .open(ctx, dir, (libc::O_RDONLY | libc::O_DIRECTORY) as u32)
+            .unwrap();
+
+        (
+            file_opts.contains(OpenOptions::PASSTHROUGH),
+            dir_opts.contains(OpenOptions::PASSTHROUGH),
+        )
+    }
+
+    #[test]
+    fn open_passthrough() {
+        let (file, dir) = open_with_passthrough(Default::default(), FsOptions::PASSTHROUGH);
+        assert!(file);
+        assert!(!dir);
+    }
+
+    #[test]
+    fn open_passthrough_not_negotiated() {
+        let (file, dir) = open_with_passthrough(Default::default(), FsOptions::empty());
+        assert!(!file);
+        assert!(!dir);
+    }
+
+    #[test]
+    fn open_passthrough_writeback() {
+        let cfg = Config {
+            writeback: true,
+            ..Default::default()
+        };
+        let (file, _) =
+            open_with_passthrough(cfg, FsOptions::PASSTHROUGH | FsOptions::WRITEBACK_CACHE);
+        assert!(!file);
+    }
 }
@@ -2,30 +2,50 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::borrow::BorrowMut;
+use std::cell::RefCell;
+use std::cmp::min;
+use std::collections::BTreeMap;
 use std::convert::TryFrom;
 use std::convert::TryInto;
 use std::fs::File;
 use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::mem::size_of;
 use std::os::unix::io::AsRawFd;
 use std::sync::Arc;
 
 use base::error;
 use base::syscall;
+use base::warn;
+use base::AsRawDescriptor;
 use base::Event;
 use base::EventToken;
 use base::Protection;
+use base::RawDescriptor;
 use base::SafeDescriptor;
 use base::Tube;
+use base::VolatileSlice;
 use base::WaitContext;
 use fuse::filesystem::FileSystem;
 use fuse::filesystem::ZeroCopyReader;
 use fuse::filesystem::ZeroCopyWriter;
+use fuse::sys::OutHeader;
+use fuse::sys::WriteOut;
+use io_uring::URingAllowlist;
+use io_uring::URingContext;
+use io_uring::URingOperation;
+use io_uring::UserData;
 use sync::Mutex;
 use vm_control::FsMappingRequest;
 use vm_control::VmResponse;
+use zerocopy::AsBytes;
+use zerocopy::FromBytes;
 
 use crate::virtio::fs::Error;
 use crate::virtio::fs::Result;
+use crate::virtio::DescriptorChain;
 use crate::virtio::Interrupt;
 use crate::virtio::Queue;
 use crate::virtio::Reader;
@@ -61,6 +81,361 @@ impl ZeroCopyWriter for Writer {
     }
 }
 
+/// Reads and writes of at least this many bytes are submitted through io_uring when it's enabled.
+/// Smaller ones complete faster with a direct system call.
+const IO_URING_MIN_SIZE: usize = 64 * 1024;
+
+/// Maximum number of requests of a queue whose transfers are in flight in its io_uring at once.
+const IO_URING_DEPTH: usize = 64;
+
+/// A transfer of a request between a file and guest memory that has been submitted to io_uring.
+struct Transfer {
+    // Keeps the file open until the transfer completes, even if the file system closes or replaces
+    // the handle it was made through.
+    _file: File,
+    // Number of bytes that the reply to the request claims have been transferred.
+    count: usize,
+    // Whether the data is written to the file rather than read from it.
+    write: bool,
+    // Whether the request transferred more data after this transfer, at a position of its buffers
+    // that assumes this one completes fully.
+    followed: bool,
+}
+
+/// Submits the transfer of a single request to io_uring.
+struct TransferSubmitter<'a> {
+    ring: &'a URingContext,
+    user_data: UserData,
+    transfer: RefCell<Option<Transfer>>,
+}
+
+impl TransferSubmitter<'_> {
+    /// Submits the transfer of the first `count` bytes of `slices` to `f` at `off` if `write` is
+    /// true, or from `f` at `off` into `slices` otherwise. Returns the number of bytes the request
+    /// may report as transferred, or `None` if the transfer has to be made synchronously.
+    fn submit(
+        &self,
+        f: &File,
+        slices: &[VolatileSlice],
+        count: usize,
+        off: u64,
+        write: bool,
+    ) -> Option<usize> {
+        let mut transfer = self.transfer.borrow_mut();
+        // Only one transfer per request is in flight. This is all that the file systems do.
+        if let Some(transfer) = transfer.as_mut() {
+            transfer.followed = true;
+            return None;
+        }
+        let count = min(count, slices.iter().map(VolatileSlice::size).sum());
+        if count < IO_URING_MIN_SIZE {
+            return None;
+        }
+        let file = f.try_clone().ok()?;
+
+        let mut remaining = count;
+        let iovecs = slices.iter().map_while(|slice| {
+            if remaining == 0 {
+                return None;
+            }
+            let len = min(remaining, slice.size());
+            remaining -= len;
+            Some(libc::iovec {
+                iov_base: slice.as_mut_ptr() as *mut libc::c_void,
+                iov_len: len,
+            })
+        });
+
+        // SAFETY: The iovecs point into the guest memory of the request, which stays mapped and
+        // isn't handed back to the guest before the transfer has completed, and `file` keeps the
+        // file open until then. The operation is made with the credentials this thread has when
+        // submitting it, like a direct system call would.
+        let res = unsafe {
+            if write {
+                self.ring
+                    .add_writev_iter(iovecs, file.as_raw_fd(), Some(off), self.user_data)
+            } else {
+                self.ring
+                    .add_readv_iter(iovecs, file.as_raw_fd(), Some(off), self.user_data)
+            }
+        };
+        if let Err(e) = res {
+            warn!("failed to submit transfer to io_uring: {}", e);
+            return None;
+        }
+
+        *transfer = Some(Transfer {
+            _file: file,
+            count,
+            write,
+            followed: false,
+        });
+        Some(count)
+    }
+}
+
+/// Wraps the `Reader` of a request so that large writes to files are submitted to io_uring.
+struct UringReader<'a> {
+    reader: &'a mut Reader,
+    submitter: &'a TransferSubmitter<'a>,
+}
+
+impl Read for UringReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl fuse::Reader for UringReader<'_> {}
+
+impl ZeroCopyReader for UringReader<'_> {
+    fn read_to(&mut self, f: &mut File, count: usize, off: u64) -> io::Result<usize> {
+        let submitted = self
+            .submitter
+            .submit(f, &self.reader.get_remaining(), count, off, true);
+        match submitted {
+            Some(written) => {
+                self.reader.consume(written);
+                Ok(written)
+            }
+            None => self.reader.read_to_at(f, count, off),
+        }
+    }
+}
+
+/// Wraps the `Writer` of a request so that large reads from files are submitted to io_uring.
+struct UringWriter<'a, W> {
+    writer: W,
+    submitter: &'a TransferSubmitter<'a>,
+}
+
+impl<W: BorrowMut<Writer>> Write for UringWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.borrow_mut().flush()
+    }
+}
+
+impl<'a, W: BorrowMut<Writer>> fuse::Writer for UringWriter<'a, W> {
+    type ClosureWriter = UringWriter<'a, Writer>;
+
+    fn write_at<F>(&mut self, offset: usize, f: F) -> io::Result<usize>
+    where
+        F: Fn(&mut Self::ClosureWriter) -> io::Result<usize>,
+    {
+        let mut writer = UringWriter {
+            writer: self.writer.borrow_mut().split_at(offset),
+            submitter: self.submitter,
+        };
+        f(&mut writer)
+    }
+
+    fn has_sufficient_buffer(&self, size: u32) -> bool {
+        self.writer.borrow().available_bytes() >= size as usize
+    }
+}
+
+impl<W: BorrowMut<Writer>> ZeroCopyWriter for UringWriter<'_, W> {
+    fn write_from(&mut self, f: &mut File, count: usize, off: u64) -> io::Result<usize> {
+        let writer = self.writer.borrow_mut();
+        let submitted = self
+            .submitter
+            .submit(f, &writer.get_remaining(), count, off, false);
+        match submitted {
+            Some(read) => {
+                writer.consume_bytes(read);
+                Ok(read)
+            }
+            None => writer.write_from_at(f, count, off),
+        }
+    }
+}
+
+/// Reads a `T` from the start of `slices`.
+fn peek_obj<T: AsBytes + FromBytes>(slices: &[VolatileSlice]) -> T {
+    let mut val = T::new_zeroed();
+    let mut buf = val.as_bytes_mut();
+    for slice in slices {
+        let len = min(buf.len(), slice.size());
+        let (head, tail) = std::mem::take(&mut buf).split_at_mut(len);
+        slice.copy_to(head);
+        buf = tail;
+    }
+    val
+}
+
+/// A request whose reply is held back until its transfer completes.
+struct InFlight {
+    desc_chain: DescriptorChain,
+    // Writes over the reply of the request, to fix it up if the transfer doesn't complete fully.
+    reply: Writer,
+    transfer: Transfer,
+}
+
+impl InFlight {
+    /// Fixes up the reply of the request according to the result `res` of its transfer, and
+    /// returns the length of the reply.
+    fn finish(&mut self, res: io::Result<u32>) -> u32 {
+        let mut header: OutHeader = peek_obj(&self.reply.get_remaining());
+        let res = match res {
+            // The reply is already right.
+            _ if header.error != 0 => return header.len,
+            Ok(n) if n as usize == self.transfer.count => return header.len,
+            Ok(n) if !self.transfer.followed => {
+                if self.transfer.write {
+                    let out = WriteOut {
+                        size: n,
+                        ..Default::default()
+                    };
+                    self.reply
+                        .write_obj(header)
+                        .and_then(|()| self.reply.write_obj(out))
+                } else {
+                    header.len -= (self.transfer.count - n as usize) as u32;
+                    self.reply.write_obj(header)
+                }
+            }
+            res => {
+                let errno = res
+                    .err()
+                    .and_then(|e| e.raw_os_error())
+                    .unwrap_or(libc::EIO);
+                header.len = size_of::<OutHeader>() as u32;
+                header.error = -errno;
+                self.reply.write_obj(header)
+            }
+        };
+        if let Err(e) = res {
+            error!("failed to update reply of request {}: {}", header.unique, e);
+        }
+        header.len
+    }
+}
+
+/// The io_uring that the large transfers of the requests of a queue are submitted to, and the
+/// requests waiting for them.
+pub struct FsUring {
+    ring: URingContext,
+    in_flight: BTreeMap<UserData, InFlight>,
+    next_user_data: UserData,
+}
+
+impl FsUring {
+    /// Creates a ring that only accepts `readv` and `writev` operations. Returns `None` if
+    /// io_uring isn't available, in which case the queue is processed with regular `preadv` and
+    /// `pwritev` calls.
+    pub fn new() -> Option<FsUring> {
+        let mut allowlist = URingAllowlist::new();
+        allowlist
+            .allow_submit_operation(URingOperation::Readv)
+            .allow_submit_operation(URingOperation::Writev);
+
+        match URingContext::new(IO_URING_DEPTH, Some(&allowlist)) {
+            Ok(ring) => Some(FsUring {
+                ring,
+                in_flight: BTreeMap::new(),
+                next_user_data: 0,
+            }),
+            Err(e) => {
+                warn!(
+                    "io_uring is unavailable, falling back to synchronous I/O: {}",
+                    e
+                );
+                None
+            }
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.in_flight.len() >= IO_URING_DEPTH
+    }
+
+    /// Handles the request of `desc_chain`. Returns the chain and the length of its reply if it has
+    /// been handled completely, or `None` if it waits for its transfer to complete.
+    fn handle_message<F: FileSystem + Sync>(
+        &mut self,
+        server: &fuse::Server<F>,
+        mut desc_chain: DescriptorChain,
+        mapper: &Mapper,
+    ) -> Result<Option<(DescriptorChain, usize)>> {
+        let user_data = self.next_user_data;
+        self.next_user_data = self.next_user_data.wrapping_add(1);
+        let reply = Writer::new_from_regions(
+            desc_chain.mem(),
+            desc_chain.writer.get_remaining_regions().collect(),
+        );
+
+        let submitter = TransferSubmitter {
+            ring: &self.ring,
+            user_data,
+            transfer: RefCell::new(None),
+        };
+        let res = server.handle_message(
+            UringReader {
+                reader: &mut desc_chain.reader,
+                submitter: &submitter,
+            },
+            UringWriter {
+                writer: &mut desc_chain.writer,
+                submitter: &submitter,
+            },
+            mapper,
+        );
+
+        match submitter.transfer.into_inner() {
+            Some(transfer) => {
+                // Even if handling the request failed, its memory may not be handed back before
+                // the transfer completes.
+                self.in_flight.insert(
+                    user_data,
+                    InFlight {
+                        desc_chain,
+                        reply,
+                        transfer,
+                    },
+                );
+                res.map(|_| None).map_err(Error::from)
+            }
+            None => Ok(Some((desc_chain, res?))),
+        }
+    }
+
+    /// Completes the requests whose transfers have finished. If `wait` is true and transfers are in
+    /// flight, blocks until at least one of them finishes.
+    pub fn complete(&mut self, queue: &mut Queue, interrupt: &Interrupt, wait: bool) -> Result<()> {
+        if self.in_flight.is_empty() || (!wait && self.ring.complete_ring.num_ready() == 0) {
+            return Ok(());
+        }
+
+        let completions: Vec<_> = self.ring.wait().map_err(Error::WaitIoUring)?.collect();
+        for (user_data, res) in completions {
+            if let Some(mut in_flight) = self.in_flight.remove(&user_data) {
+                let len = in_flight.finish(res);
+                queue.add_used(in_flight.desc_chain, len);
+            }
+        }
+        queue.trigger_interrupt(interrupt);
+        Ok(())
+    }
+
+    /// Waits for all the transfers in flight and completes their requests.
+    pub fn drain(&mut self, queue: &mut Queue, interrupt: &Interrupt) -> Result<()> {
+        while !self.in_flight.is_empty() {
+            self.complete(queue, interrupt, true)?;
+        }
+        Ok(())
+    }
+}
+
+impl AsRawDescriptor for FsUring {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        self.ring.as_raw_descriptor()
+    }
+}
+
 struct Mapper {
     tube: Arc<Mutex<Tube>>,
     slot: u32,
@@ -144,25 +519,56 @@ pub struct Worker<F: FileSystem + Sync> {
     irq: Interrupt,
     tube: Arc<Mutex<Tube>>,
     slot: u32,
+    use_io_uring: bool,
 }
 
+/// Processes the available requests of `queue`. If `uring` is given, the large transfers of the
+/// requests are submitted to it and these requests are completed by `FsUring::complete` once
+/// their transfers finish. Requests are left in the queue while the ring is full.
 pub fn process_fs_queue<F: FileSystem + Sync>(
     interrupt: &Interrupt,
     queue: &mut Queue,
     server: &Arc<fuse::Server<F>>,
     tube: &Arc<Mutex<Tube>>,
     slot: u32,
+    mut uring: Option<&mut FsUring>,
 ) -> Result<()> {
     let mapper = Mapper::new(Arc::clone(tube), slot);
-    while let Some(mut avail_desc) = queue.pop() {
-        let total =
-            server.handle_message(&mut avail_desc.reader, &mut avail_desc.writer, &mapper)?;
+    let res = loop {
+        if matches!(&uring, Some(uring) if uring.is_full()) {
+            break Ok(());
+        }
+        let Some(mut avail_desc) = queue.pop() else {
+            break Ok(());
+        };
+        let handled = match uring.as_deref_mut() {
+            Some(uring) => uring.handle_message(server, avail_desc, &mapper),
+            None => server
+                .handle_message(&mut avail_desc.reader, &mut avail_desc.writer, &mapper)
+                .map(|total| Some((avail_desc, total)))
+                .map_err(Error::from),
+        };
+        match handled {
+            Ok(Some((avail_desc, total))) => {
+                queue.add_used(avail_desc, total as u32);
+                queue.trigger_interrupt(interrupt);
+            }
+            Ok(None) => {}
+            Err(e) => break Err(e),
+        }
+    };
 
-        queue.add_used(avail_desc, total as u32);
-        queue.trigger_interrupt(interrupt);
+    match uring {
+        Some(uring) if res.is_err() => {
+            // Don't leave transfers into guest memory behind.
+            if let Err(e) = uring.drain(queue, interrupt) {
+                error!("failed to complete io_uring transfers: {}", e);
+            }
+            res
+        }
+        Some(uring) => res.and_then(|()| uring.ring.submit().map_err(Error::SubmitIoUring)),
+        None => res,
     }
-
-    Ok(())
 }
 
 impl<F: FileSystem + Sync> Worker<F> {
@@ -172,6 +578,7 @@ impl<F: FileSystem + Sync> Worker<F> {
         irq: Interrupt,
         tube: Arc<Mutex<Tube>>,
         slot: u32,
+        use_io_uring: bool,
     ) -> Worker<F> {
         Worker {
             queue,
@@ -179,9 +586,15 @@ impl<F: FileSystem + Sync> Worker<F> {
             irq,
             tube,
             slot,
+            use_io_uring,
         }
     }
 
+    /// Returns the queue processed by this worker.
+    pub fn into_queue(self) -> Queue {
+        self.queue
+    }
+
     pub fn run(&mut self, kill_evt: Event, watch_resample_event: bool) -> Result<()> {
         let mut ruid: libc::uid_t = 0;
         let mut euid: libc::uid_t = 0;
@@ -222,10 +635,18 @@ impl<F: FileSystem + Sync> Worker<F> {
         )
         .map_err(Error::UnshareFromParent)?;
 
+        let mut uring = if self.use_io_uring {
+            FsUring::new()
+        } else {
+            None
+        };
+
         #[derive(EventToken)]
         enum Token {
             // A request is ready on the queue.
             QueueReady,
+            // Transfers submitted to io_uring have completed.
+            IoComplete,
             // Check if any interrupts need to be re-asserted.
             InterruptResample,
             // The parent thread requested an exit.
@@ -238,6 +659,12 @@ impl<F: FileSystem + Sync> Worker<F> {
         ])
         .map_err(Error::CreateWaitContext)?;
 
+        if let Some(uring) = &uring {
+            wait_ctx
+                .add(uring, Token::IoComplete)
+                .map_err(Error::CreateWaitContext)?;
+        }
+
         if watch_resample_event {
             if let Some(resample_evt) = self.irq.get_resample_evt() {
                 wait_ctx
@@ -258,6 +685,25 @@ impl<F: FileSystem + Sync> Worker<F> {
                             &self.server,
                             &self.tube,
                             self.slot,
+                            uring.as_mut(),
+                        ) {
+                            error!("virtio-fs transport error: {}", e);
+                            return Err(e);
+                        }
+                    }
+                    Token::IoComplete => {
+                        let Some(uring) = uring.as_mut() else {
+                            continue;
+                        };
+                        uring.complete(&mut self.queue, &self.irq, false)?;
+                        // Take the requests that were left in the queue while the ring was full.
+                        if let Err(e) = process_fs_queue(
+                            &self.irq,
+                            &mut self.queue,
+                            &self.server,
+                            &self.tube,
+                            self.slot,
+                            Some(uring),
                         ) {
                             error!("virtio-fs transport error: {}", e);
                             return Err(e);
@@ -266,9 +712,132 @@ impl<F: FileSystem + Sync> Worker<F> {
                     Token::InterruptResample => {
                         self.irq.interrupt_resample();
                     }
-                    Token::Kill => return Ok(()),
+                    Token::Kill => {
+                        // The requests of the queue must all be completed before it is handed
+                        // back.
+                        if let Some(uring) = uring.as_mut() {
+                            uring.drain(&mut self.queue, &self.irq)?;
+                        }
+                        return Ok(());
+                    }
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempfile;
+    use vm_memory::GuestAddress;
+    use vm_memory::GuestMemory;
+
+    use super::*;
+    use crate::virtio::create_descriptor_chain;
+    use crate::virtio::DescriptorType;
+
+    const REPLY_ADDR: GuestAddress = GuestAddress(0x100);
+
+    /// Returns a request whose transfer of `count` bytes is in flight, with the reply the server
+    /// wrote for it.
+    fn in_flight(mem: &GuestMemory, count: usize, write: bool, followed: bool) -> InFlight {
+        let mut desc_chain = create_descriptor_chain(
+            mem,
+            GuestAddress(0x0),
+            REPLY_ADDR,
+            vec![(DescriptorType::Writable, 0x1000)],
+            0,
+        )
+        .unwrap();
+        let reply = Writer::new_from_regions(
+            desc_chain.mem(),
+            desc_chain.writer.get_remaining_regions().collect(),
+        );
+
+        let len = if write {
+            size_of::<OutHeader>() + size_of::<WriteOut>()
+        } else {
+            size_of::<OutHeader>() + count
+        };
+        let header = OutHeader {
+            len: len as u32,
+            error: 0,
+            unique: 7,
+        };
+        desc_chain.writer.write_obj(header).unwrap();
+        if write {
+            let out = WriteOut {
+                size: count as u32,
+                ..Default::default()
+            };
+            desc_chain.writer.write_obj(out).unwrap();
+        }
+
+        InFlight {
+            desc_chain,
+            reply,
+            transfer: Transfer {
+                _file: tempfile().unwrap(),
+                count,
+                write,
+                followed,
+            },
+        }
+    }
+
+    fn reply_header(mem: &GuestMemory) -> OutHeader {
+        mem.read_obj_from_addr(REPLY_ADDR).unwrap()
+    }
+
+    #[test]
+    fn complete_transfer() {
+        let mem = GuestMemory::new(&[(GuestAddress(0x0), 0x10000)]).unwrap();
+        let mut req = in_flight(&mem, 0x800, false, false);
+        assert_eq!(req.finish(Ok(0x800)), 0x810);
+        assert_eq!(reply_header(&mem).len, 0x810);
+    }
+
+    #[test]
+    fn short_read() {
+        let mem = GuestMemory::new(&[(GuestAddress(0x0), 0x10000)]).unwrap();
+        let mut req = in_flight(&mem, 0x800, false, false);
+        assert_eq!(req.finish(Ok(0x300)), 0x310);
+        let header = reply_header(&mem);
+        assert_eq!(header.len, 0x310);
+        assert_eq!(header.error, 0);
+        assert_eq!(header.unique, 7);
+    }
+
+    #[test]
+    fn short_write() {
+        let mem = GuestMemory::new(&[(GuestAddress(0x0), 0x10000)]).unwrap();
+        let mut req = in_flight(&mem, 0x800, true, false);
+        assert_eq!(req.finish(Ok(0x300)), 0x18);
+        let out: WriteOut = mem
+            .read_obj_from_addr(REPLY_ADDR.unchecked_add(size_of::<OutHeader>() as u64))
+            .unwrap();
+        assert_eq!(out.size, 0x300);
+    }
+
+    #[test]
+    fn failed_transfer() {
+        let mem = GuestMemory::new(&[(GuestAddress(0x0), 0x10000)]).unwrap();
+        let mut req = in_flight(&mem, 0x800, true, false);
+        assert_eq!(
+            req.finish(Err(io::Error::from_raw_os_error(libc::ENOSPC))),
+            0x10
+        );
+        let header = reply_header(&mem);
+        assert_eq!(header.len, 0x10);
+        assert_eq!(header.error, -libc::ENOSPC);
+        assert_eq!(header.unique, 7);
+    }
+
+    #[test]
+    fn short_transfer_followed_by_data() {
+        let mem = GuestMemory::new(&[(GuestAddress(0x0), 0x10000)]).unwrap();
+        let mut req = in_flight(&mem, 0x800, false, true);
+        assert_eq!(req.finish(Ok(0x300)), 0x10);
+        assert_eq!(reply_header(&mem).error, -libc::EIO);
+    }
+}
@@ -0,0 +1,345 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Screenshots, recordings and streams of scanout contents.
+//!
+//! Pixels are expected in the XRGB8888 layout used by the scanouts, i.e. B, G, R and an unused
+//! byte for each pixel.
+//!
+//! Recordings and streams are both written by a `FrameExporter`, which moves the frames off the
+//! GPU worker.
+
+use std::io;
+use std::io::BufWriter;
+use std::io::Write;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::sync_channel;
+use std::sync::mpsc::SyncSender;
+use std::sync::mpsc::TrySendError;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use base::error;
+use rutabaga_gfx::RutabagaRect;
+
+const BYTES_PER_PIXEL: usize = 4;
+
+/// Number of frames a `FrameExporter` holds while its sink is busy. Frames captured while that
+/// many are pending are dropped.
+const FRAME_QUEUE_LEN: usize = 4;
+
+fn check_size(width: u32, height: u32, pixels: &[u8]) -> io::Result<()> {
+    if pixels.len() != width as usize * height as usize * BYTES_PER_PIXEL {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} bytes is not a {}x{} image", pixels.len(), width, height),
+        ));
+    }
+    Ok(())
+}
+
+/// Writes a `width`x`height` XRGB8888 image to `w` as an 8-bit RGB PNG.
+pub fn write_png<W: Write>(w: W, width: u32, height: u32, pixels: &[u8]) -> io::Result<()> {
+    check_size(width, height, pixels)?;
+    let rgb: Vec<u8> = pixels
+        .chunks_exact(BYTES_PER_PIXEL)
+        .flat_map(|px| [px[2], px[1], px[0]])
+        .collect();
+
+    let mut encoder = png::Encoder::new(BufWriter::new(w), width, height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&rgb)?;
+    writer.finish()?;
+    Ok(())
+}
+
+/// A frame captured from a scanout.
+pub struct Frame {
+    /// Time elapsed between the creation of the exporter and the capture of the frame.
+    pub timestamp: Duration,
+    pub width: u32,
+    pub height: u32,
+    /// XRGB8888 pixels, without padding between rows.
+    pub pixels: Vec<u8>,
+    /// Rectangles modified since the previous frame of the exporter, or `None` if the whole frame
+    /// must be considered modified, e.g. because frames were dropped in between.
+    pub damage: Option<Vec<RutabagaRect>>,
+}
+
+/// Destination of the frames of a `FrameExporter`.
+pub trait FrameSink: Send {
+    fn write_frame(&mut self, frame: &Frame) -> io::Result<()>;
+}
+
+/// Passes the frames captured on the GPU worker to a `FrameSink` running on its own thread, so
+/// that a slow file or a stalled pipe never blocks the device.
+///
+/// Frames are dropped while `FRAME_QUEUE_LEN` of them are waiting for the sink. Dropping the
+/// exporter lets the thread write the pending frames and exit; it is not joined.
+pub struct FrameExporter {
+    sender: SyncSender<Frame>,
+    start: Instant,
+    // Frames queued or being written.
+    pending: Arc<AtomicUsize>,
+    // Whether a frame has been dropped since the last one that was queued.
+    dropped: bool,
+}
+
+impl FrameExporter {
+    /// Starts a thread called `name` that writes the exported frames to `sink`. The thread stops
+    /// on the first error of the sink.
+    pub fn new<S: FrameSink + 'static>(name: String, mut sink: S) -> io::Result<Self> {
+        let (sender, receiver) = sync_channel::<Frame>(FRAME_QUEUE_LEN);
+        let pending = Arc::new(AtomicUsize::new(0));
+        let thread_pending = pending.clone();
+        let thread_name = name.clone();
+        thread::Builder::new().name(name).spawn(move || {
+            for frame in receiver {
+                let result = sink.write_frame(&frame);
+                thread_pending.fetch_sub(1, Ordering::AcqRel);
+                if let Err(e) = result {
+                    error!("{}: failed to write frame, stopping: {}", thread_name, e);
+                    break;
+                }
+            }
+        })?;
+        Ok(FrameExporter {
+            sender,
+            start: Instant::now(),
+            pending,
+            dropped: false,
+        })
+    }
+
+    fn is_full(&self) -> bool {
+        self.pending.load(Ordering::Acquire) >= FRAME_QUEUE_LEN
+    }
+
+    /// Returns whether a frame exported now would be queued, so that callers can skip reading back
+    /// frames that would be dropped. A `false` return counts as a dropped frame.
+    pub fn wants_frame(&mut self) -> bool {
+        let full = self.is_full();
+        self.dropped |= full;
+        !full
+    }
+
+    /// Queues a `width`x`height` frame captured now, unless the queue is full. `damage` lists the
+    /// rectangles modified since the previous frame, if known.
+    ///
+    /// Returns false once the sink has failed, after which the exporter should be dropped.
+    pub fn export(
+        &mut self,
+        width: u32,
+        height: u32,
+        pixels: Vec<u8>,
+        damage: Option<Vec<RutabagaRect>>,
+    ) -> bool {
+        if !self.wants_frame() {
+            return true;
+        }
+        let frame = Frame {
+            timestamp: self.start.elapsed(),
+            width,
+            height,
+            pixels,
+            // The sink never saw the changes of the dropped frames.
+            damage: if self.dropped { None } else { damage },
+        };
+        self.pending.fetch_add(1, Ordering::AcqRel);
+        match self.sender.try_send(frame) {
+            Ok(()) => {
+                self.dropped = false;
+                true
+            }
+            Err(e) => {
+                self.pending.fetch_sub(1, Ordering::AcqRel);
+                self.dropped = true;
+                matches!(e, TrySendError::Full(_))
+            }
+        }
+    }
+}
+
+/// Appends the frames presented on a scanout to a file.
+///
+/// Each frame starts with a 16 byte header made of the time elapsed since the start of the
+/// recording in nanoseconds (u64), the width and the height (u32), all little-endian. The header
+/// is followed by the frame pixels in XRGB8888 format, without padding between rows.
+pub struct ScanoutRecorder<W: Write> {
+    writer: BufWriter<W>,
+}
+
+impl<W: Write> ScanoutRecorder<W> {
+    pub fn new(w: W) -> Self {
+        ScanoutRecorder {
+            writer: BufWriter::new(w),
+        }
+    }
+}
+
+impl<W: Write + Send> FrameSink for ScanoutRecorder<W> {
+    fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        check_size(frame.width, frame.height, &frame.pixels)?;
+        let timestamp = frame.timestamp.as_nanos() as u64;
+        self.writer.write_all(&timestamp.to_le_bytes())?;
+        self.writer.write_all(&frame.width.to_le_bytes())?;
+        self.writer.write_all(&frame.height.to_le_bytes())?;
+        self.writer.write_all(&frame.pixels)?;
+        // Flush every frame so that the recording is usable even if it is never stopped.
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::channel;
+    use std::sync::mpsc::Receiver;
+    use std::sync::mpsc::Sender;
+
+    use super::*;
+
+    fn frame(timestamp: u64, width: u32, height: u32, pixels: &[u8]) -> Frame {
+        Frame {
+            timestamp: Duration::from_nanos(timestamp),
+            width,
+            height,
+            pixels: pixels.to_vec(),
+            damage: None,
+        }
+    }
+
+    fn damage() -> Option<Vec<RutabagaRect>> {
+        Some(vec![RutabagaRect {
+            x: 0,
+            y: 0,
+            width: 1,
+            height: 1,
+        }])
+    }
+
+    /// Forwards the width of each frame and whether it has damage information, after waiting for
+    /// a permission to proceed if `gate` is set.
+    struct ChannelSink {
+        gate: Option<Receiver<()>>,
+        frames: Sender<(u32, bool)>,
+    }
+
+    impl FrameSink for ChannelSink {
+        fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+            if let Some(gate) = &self.gate {
+                gate.recv()
+                    .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+            }
+            self.frames
+                .send((frame.width, frame.damage.is_some()))
+                .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+        }
+    }
+
+    #[test]
+    fn png_round_trip() {
+        // Two pixels: pure red and pure blue in XRGB8888.
+        let pixels = [0, 0, 0xff, 0, 0xff, 0, 0, 0];
+        let mut out = Vec::new();
+        write_png(&mut out, 2, 1, &pixels).unwrap();
+
+        let decoder = png::Decoder::new(&out[..]);
+        let mut reader = decoder.read_info().unwrap();
+        let mut rgb = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut rgb).unwrap();
+        assert_eq!((info.width, info.height), (2, 1));
+        assert_eq!(info.color_type, png::ColorType::Rgb);
+        assert_eq!(info.bit_depth, png::BitDepth::Eight);
+        assert_eq!(&rgb[..info.buffer_size()], &[0xff, 0, 0, 0, 0, 0xff]);
+    }
+
+    #[test]
+    fn png_rejects_bad_size() {
+        assert!(write_png(Vec::new(), 2, 2, &[0; 12]).is_err());
+    }
+
+    #[test]
+    fn recorder_frames() {
+        let mut out = Vec::new();
+        {
+            let mut recorder = ScanoutRecorder::new(&mut out);
+            recorder
+                .write_frame(&frame(5, 1, 1, &[1, 2, 3, 4]))
+                .unwrap();
+            recorder.write_frame(&frame(9, 2, 1, &[5; 8])).unwrap();
+            assert!(recorder.write_frame(&frame(10, 2, 2, &[0; 4])).is_err());
+        }
+        assert_eq!(out.len(), 16 + 4 + 16 + 8);
+        assert_eq!(
+            &out[..20],
+            &[5, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 1, 2, 3, 4]
+        );
+        assert_eq!(
+            &out[20..36],
+            &[9, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0]
+        );
+        assert_eq!(&out[36..], &[5; 8]);
+    }
+
+    #[test]
+    fn exporter_drops_frames_when_full() {
+        let (gate_send, gate) = channel();
+        let (frames, written) = channel();
+        let mut exporter = FrameExporter::new(
+            "exporter_drops_frames_when_full".to_string(),
+            ChannelSink {
+                gate: Some(gate),
+                frames,
+            },
+        )
+        .unwrap();
+
+        // The sink is stalled, so only the first FRAME_QUEUE_LEN frames are kept.
+        for i in 0..FRAME_QUEUE_LEN as u32 + 3 {
+            assert!(exporter.export(i, 1, Vec::new(), damage()));
+        }
+        assert!(!exporter.wants_frame());
+
+        for _ in 0..FRAME_QUEUE_LEN {
+            gate_send.send(()).unwrap();
+        }
+        for i in 0..FRAME_QUEUE_LEN as u32 {
+            assert_eq!(written.recv().unwrap(), (i, true));
+        }
+        assert!(exporter.wants_frame());
+
+        // Frames flow again once the sink has caught up. The first one after the drops loses its
+        // damage since the sink missed the previous changes.
+        assert!(exporter.export(42, 1, Vec::new(), damage()));
+        assert!(exporter.export(43, 1, Vec::new(), damage()));
+        gate_send.send(()).unwrap();
+        gate_send.send(()).unwrap();
+        assert_eq!(written.recv().unwrap(), (42, false));
+        assert_eq!(written.recv().unwrap(), (43, true));
+    }
+
+    #[test]
+    fn exporter_stops_on_sink_error() {
+        let (frames, written) = channel();
+        drop(written);
+        let mut exporter = FrameExporter::new(
+            "exporter_stops_on_sink_error".to_string(),
+            ChannelSink { gate: None, frames },
+        )
+        .unwrap();
+
+        assert!(exporter.export(1, 1, Vec::new(), None));
+        // The thread exits after the failed write; the exporter notices on a later frame.
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while exporter.export(1, 1, Vec::new(), None) {
+            assert!(Instant::now() < deadline, "exporter did not stop");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
@@ -8,11 +8,32 @@
 use std::fmt;
 use std::fmt::Debug;
 
+use vm_control::gpu::DisplayTiming;
+use vm_control::gpu::HdrMetadata;
+
+use super::protocol::GpuResponse;
 use super::protocol::GpuResponse::*;
 use super::protocol::VirtioGpuResult;
 use crate::virtio::gpu::GpuDisplayParameters;
 
 const EDID_DATA_LENGTH: usize = 128;
+const DESCRIPTOR_LENGTH: usize = 18;
+// Offsets of the 4 descriptors of the base block.
+const DESCRIPTOR_OFFSETS: [usize; 4] = [54, 72, 90, 108];
+// The display name takes the descriptor following the detailed timings of the base block.
+const MAX_BASE_DETAILED_TIMINGS: usize = 3;
+const EXTENSION_COUNT_OFFSET: usize = 126;
+const CTA_EXTENSION_TAG: u8 = 0x02;
+const CTA_EXTENSION_REVISION: u8 = 0x03;
+const CTA_EXTENDED_TAG: u8 = 0x07;
+const CTA_COLORIMETRY_TAG: u8 = 0x05;
+const CTA_HDR_STATIC_METADATA_TAG: u8 = 0x06;
+// BT2020 RGB and BT2020 YCC.
+const CTA_COLORIMETRY_BT2020: u8 = 0xC0;
+// Traditional SDR gamma, SMPTE ST 2084 (PQ) and HLG.
+const CTA_HDR_EOTFS: u8 = 0x01 | 0x04 | 0x08;
+// Static metadata type 1.
+const CTA_HDR_STATIC_METADATA_TYPE1: u8 = 0x01;
 const DEFAULT_HORIZONTAL_BLANKING: u16 = 560;
 const DEFAULT_VERTICAL_BLANKING: u16 = 50;
 const DEFAULT_HORIZONTAL_FRONT_PORCH: u16 = 64;
@@ -29,16 +50,22 @@ const MILLIMETERS_PER_INCH: f32 = 25.4;
 /// pixel clock).
 ///
 /// The EDID spec defines a number of methods to provide mode information, but in priority order the
-/// "detailed" timing information is first, so we provide a single block of detailed timing
-/// information and no other form of timing information.
-#[repr(C)]
+/// "detailed" timing information is first, so we provide the display modes as detailed timings and
+/// no other form of timing information. Modes that do not fit in the base block, and HDR
+/// capabilities, are described in a CTA-861 extension block.
 pub struct EdidBytes {
-    bytes: [u8; EDID_DATA_LENGTH],
+    bytes: Vec<u8>,
 }
 
 impl EdidBytes {
-    /// Creates a virtual EDID block.
+    /// Creates a virtual EDID.
     pub fn new(info: &DisplayInfo) -> VirtioGpuResult {
+        if let Some(edid) = &info.edid {
+            return Ok(OkEdid(Box::new(Self {
+                bytes: edid.clone(),
+            })));
+        }
+
         let mut edid: [u8; EDID_DATA_LENGTH] = [0; EDID_DATA_LENGTH];
 
         populate_header(&mut edid);
@@ -46,16 +73,28 @@ impl EdidBytes {
         populate_size(&mut edid, info);
         populate_standard_timings(&mut edid)?;
 
-        // 4 available descriptor blocks
-        let block0 = &mut edid[54..72];
-        populate_detailed_timing(block0, info);
+        // 4 available descriptor blocks: the first modes, then the display name.
+        let base_timings = info.timings.len().min(MAX_BASE_DETAILED_TIMINGS);
+        for (timing, offset) in info.timings[..base_timings].iter().zip(DESCRIPTOR_OFFSETS) {
+            populate_detailed_timing(&mut edid[offset..offset + DESCRIPTOR_LENGTH], info, timing);
+        }
+        let name_offset = DESCRIPTOR_OFFSETS[base_timings];
+        populate_display_name(&mut edid[name_offset..name_offset + DESCRIPTOR_LENGTH]);
 
-        let block1 = &mut edid[72..90];
-        populate_display_name(block1);
+        let extension = if info.timings.len() > base_timings || info.hdr.is_some() {
+            edid[EXTENSION_COUNT_OFFSET] = 1;
+            Some(create_cta_extension(info, &info.timings[base_timings..])?)
+        } else {
+            None
+        };
 
         calculate_checksum(&mut edid);
 
-        Ok(OkEdid(Box::new(Self { bytes: edid })))
+        let mut bytes = edid.to_vec();
+        if let Some(extension) = extension {
+            bytes.extend_from_slice(&extension);
+        }
+        Ok(OkEdid(Box::new(Self { bytes })))
     }
 
     pub fn len(&self) -> usize {
@@ -103,8 +142,8 @@ fn gcd(x: u32, y: u32) -> u32 {
     }
 }
 
-#[derive(Copy, Clone)]
-pub struct DisplayInfo {
+#[derive(Clone)]
+struct Timing {
     resolution: Resolution,
     refresh_rate: u32,
     horizontal_blanking: u16,
@@ -113,50 +152,97 @@ pub struct DisplayInfo {
     vertical_front: u16,
     horizontal_sync: u16,
     vertical_sync: u16,
+}
+
+impl Timing {
+    /// Only width, height and refresh rate are required for the graphics stack to work, so the
+    /// other fields default to some typical values.
+    fn new(timing: &DisplayTiming) -> Self {
+        let (horizontal_blanking, vertical_blanking) = timing
+            .blanking
+            .unwrap_or((DEFAULT_HORIZONTAL_BLANKING, DEFAULT_VERTICAL_BLANKING));
+        let (horizontal_front, vertical_front) = timing
+            .front_porch
+            .unwrap_or((DEFAULT_HORIZONTAL_FRONT_PORCH, DEFAULT_VERTICAL_FRONT_PORCH));
+        let (horizontal_sync, vertical_sync) = timing
+            .sync_width
+            .unwrap_or((DEFAULT_HORIZONTAL_SYNC_PULSE, DEFAULT_VERTICAL_SYNC_PULSE));
+        Self {
+            resolution: Resolution::new(timing.width, timing.height),
+            refresh_rate: timing.refresh_rate,
+            horizontal_blanking,
+            vertical_blanking,
+            horizontal_front,
+            vertical_front,
+            horizontal_sync,
+            vertical_sync,
+        }
+    }
+
+    fn width(&self) -> u32 {
+        self.resolution.width
+    }
+
+    fn height(&self) -> u32 {
+        self.resolution.height
+    }
+}
+
+#[derive(Clone)]
+pub struct DisplayInfo {
+    /// Modes in order of preference, never empty.
+    timings: Vec<Timing>,
     width_millimeters: u16,
     height_millimeters: u16,
+    hdr: Option<HdrMetadata>,
+    edid: Option<Vec<u8>>,
 }
 
 impl DisplayInfo {
-    /// Only width, height and refresh rate are required for the graphics stack to work, so instead
-    /// of pulling actual numbers from the system, we just use some typical values to populate other
-    /// fields for now.
+    /// Unless given in `params`, the modes are made of the display size at its refresh rate and
+    /// the physical size is derived from the DPI of the display.
     pub fn new(params: &GpuDisplayParameters) -> Self {
         let (width, height) = params.get_virtual_display_size();
 
-        let width_millimeters = if params.horizontal_dpi() != 0 {
-            ((width as f32 / params.horizontal_dpi() as f32) * MILLIMETERS_PER_INCH) as u16
+        let timings = if params.modes.is_empty() {
+            vec![Timing::new(&DisplayTiming {
+                width,
+                height,
+                refresh_rate: params.refresh_rate,
+                blanking: None,
+                front_porch: None,
+                sync_width: None,
+            })]
         } else {
-            0
+            params.modes.iter().map(Timing::new).collect()
         };
-        let height_millimeters = if params.vertical_dpi() != 0 {
-            ((height as f32 / params.vertical_dpi() as f32) * MILLIMETERS_PER_INCH) as u16
-        } else {
-            0
+
+        let (width_millimeters, height_millimeters) = match params.physical_size_mm {
+            Some(size) => size,
+            None => {
+                let width_millimeters = if params.horizontal_dpi() != 0 {
+                    ((width as f32 / params.horizontal_dpi() as f32) * MILLIMETERS_PER_INCH) as u16
+                } else {
+                    0
+                };
+                let height_millimeters = if params.vertical_dpi() != 0 {
+                    ((height as f32 / params.vertical_dpi() as f32) * MILLIMETERS_PER_INCH) as u16
+                } else {
+                    0
+                };
+                (width_millimeters, height_millimeters)
+            }
         };
 
         Self {
-            resolution: Resolution::new(width, height),
-            refresh_rate: params.refresh_rate,
-            horizontal_blanking: DEFAULT_HORIZONTAL_BLANKING,
-            vertical_blanking: DEFAULT_VERTICAL_BLANKING,
-            horizontal_front: DEFAULT_HORIZONTAL_FRONT_PORCH,
-            vertical_front: DEFAULT_VERTICAL_FRONT_PORCH,
-            horizontal_sync: DEFAULT_HORIZONTAL_SYNC_PULSE,
-            vertical_sync: DEFAULT_VERTICAL_SYNC_PULSE,
+            timings,
             width_millimeters,
             height_millimeters,
+            hdr: params.hdr.clone(),
+            edid: params.edid.as_ref().map(|edid| edid.0.clone()),
         }
     }
 
-    pub fn width(&self) -> u32 {
-        self.resolution.width
-    }
-
-    pub fn height(&self) -> u32 {
-        self.resolution.height
-    }
-
     pub fn width_centimeters(&self) -> u8 {
         (self.width_millimeters / 10) as u8
     }
@@ -172,8 +258,8 @@ fn populate_display_name(edid_block: &mut [u8]) {
     edid_block[5..].clone_from_slice("CrosvmDisplay".as_bytes());
 }
 
-fn populate_detailed_timing(edid_block: &mut [u8], info: &DisplayInfo) {
-    assert_eq!(edid_block.len(), 18);
+fn populate_detailed_timing(edid_block: &mut [u8], display: &DisplayInfo, info: &Timing) {
+    assert_eq!(edid_block.len(), DESCRIPTOR_LENGTH);
 
     // Detailed timings
     //
@@ -214,9 +300,10 @@ fn populate_detailed_timing(edid_block: &mut [u8], info: &DisplayInfo) {
     //
     let htotal = info.width() + (info.horizontal_blanking as u32);
     let vtotal = info.height() + (info.vertical_blanking as u32);
-    let mut clock: u16 = ((info.refresh_rate * htotal * vtotal) / 10000) as u16;
+    let mut clock = (info.refresh_rate as u64 * htotal as u64 * vtotal as u64) / 10000;
     // Round to nearest 10khz.
     clock = ((clock + 5) / 10) * 10;
+    let clock = clock.min(u16::MAX as u64) as u16;
     edid_block[0..2].copy_from_slice(&clock.to_le_bytes());
 
     let width_lsb: u8 = (info.width() & 0xFF) as u8;
@@ -262,11 +349,11 @@ fn populate_detailed_timing(edid_block: &mut [u8], info: &DisplayInfo) {
         | (horizontal_sync_msb << 4)
         | (horizontal_front_msb << 6);
 
-    let width_millimeters_lsb: u8 = (info.width_millimeters & 0xFF) as u8; // least sig 8 bits
-    let width_millimeters_msb: u8 = ((info.width_millimeters >> 8) & 0xF) as u8; // most sig 4 bits
+    let width_millimeters_lsb: u8 = (display.width_millimeters & 0xFF) as u8; // least sig 8 bits
+    let width_millimeters_msb: u8 = ((display.width_millimeters >> 8) & 0xF) as u8; // most sig 4 bits
 
-    let height_millimeters_lsb: u8 = (info.height_millimeters & 0xFF) as u8; // least sig 8 bits
-    let height_millimeters_msb: u8 = ((info.height_millimeters >> 8) & 0xF) as u8; // most sig 4 bits
+    let height_millimeters_lsb: u8 = (display.height_millimeters & 0xFF) as u8; // least sig 8 bits
+    let height_millimeters_msb: u8 = ((display.height_millimeters >> 8) & 0xF) as u8; // most sig 4 bits
 
     edid_block[12] = width_millimeters_lsb;
     edid_block[13] = height_millimeters_lsb;
@@ -347,6 +434,79 @@ fn populate_size(edid: &mut [u8], info: &DisplayInfo) {
     edid[22] = info.height_centimeters();
 }
 
+// Luminance encoded as in the CTA-861 HDR static metadata data block, where the coded value CV
+// stands for 50 * 2 ^ (CV / 32) cd/m².
+fn encode_max_luminance(luminance: u32) -> u8 {
+    let cv = 32.0 * (luminance.max(1) as f64 / 50.0).log2();
+    cv.round().clamp(0.0, 255.0) as u8
+}
+
+// Minimum luminance encoded relative to the maximum one, where the coded value CV stands for
+// max_luminance * (CV / 255) ^ 2 / 100 cd/m².
+fn encode_min_luminance(min_luminance_millis: u32, max_luminance: u32) -> u8 {
+    let ratio = min_luminance_millis as f64 / (10.0 * max_luminance.max(1) as f64);
+    (255.0 * ratio.sqrt()).round().clamp(0.0, 255.0) as u8
+}
+
+// Data blocks advertising BT2020 colorimetry and the HDR static metadata of `hdr`.
+fn hdr_data_blocks(hdr: &HdrMetadata) -> Vec<u8> {
+    let mut blocks = vec![
+        (CTA_EXTENDED_TAG << 5) | 3,
+        CTA_COLORIMETRY_TAG,
+        CTA_COLORIMETRY_BT2020,
+        0,
+    ];
+
+    let mut metadata = vec![
+        CTA_HDR_STATIC_METADATA_TAG,
+        CTA_HDR_EOTFS,
+        CTA_HDR_STATIC_METADATA_TYPE1,
+        encode_max_luminance(hdr.max_luminance),
+    ];
+    // Optional luminances can only be omitted from the end of the block.
+    match (hdr.max_frame_average_luminance, hdr.min_luminance) {
+        (average, Some(min)) => {
+            metadata.push(encode_max_luminance(average.unwrap_or(hdr.max_luminance)));
+            metadata.push(encode_min_luminance(min, hdr.max_luminance));
+        }
+        (Some(average), None) => metadata.push(encode_max_luminance(average)),
+        (None, None) => (),
+    }
+    blocks.push((CTA_EXTENDED_TAG << 5) | metadata.len() as u8);
+    blocks.extend_from_slice(&metadata);
+    blocks
+}
+
+// Creates a CTA-861 extension block holding the HDR capabilities of `info`, if any, and
+// `timings` as detailed timings.
+fn create_cta_extension(info: &DisplayInfo, timings: &[Timing]) -> Result<Vec<u8>, GpuResponse> {
+    let mut block = vec![0u8; EDID_DATA_LENGTH];
+    block[0] = CTA_EXTENSION_TAG;
+    block[1] = CTA_EXTENSION_REVISION;
+
+    let data_blocks = info.hdr.as_ref().map(hdr_data_blocks).unwrap_or_default();
+    let timings_offset = 4 + data_blocks.len();
+    // Byte 2 is the offset of the detailed timings, byte 3 holds flags that we leave unset.
+    block[2] = timings_offset as u8;
+    block[4..timings_offset].copy_from_slice(&data_blocks);
+
+    // The last byte is the checksum.
+    let available = (EDID_DATA_LENGTH - 1 - timings_offset) / DESCRIPTOR_LENGTH;
+    if timings.len() > available {
+        return Err(ErrEdid(format!(
+            "{} modes do not fit in the EDID extension block",
+            timings.len()
+        )));
+    }
+    for (i, timing) in timings.iter().enumerate() {
+        let offset = timings_offset + i * DESCRIPTOR_LENGTH;
+        populate_detailed_timing(&mut block[offset..offset + DESCRIPTOR_LENGTH], info, timing);
+    }
+
+    calculate_checksum(&mut block);
+    Ok(block)
+}
+
 fn calculate_checksum(edid: &mut [u8]) {
     let mut checksum: u8 = 0;
     for byte in edid.iter().take(EDID_DATA_LENGTH - 1) {
@@ -359,3 +519,129 @@ fn calculate_checksum(edid: &mut [u8]) {
 
     edid[127] = checksum;
 }
+
+#[cfg(test)]
+mod tests {
+    use vm_control::gpu::EdidBlob;
+
+    use super::*;
+
+    fn mode(width: u32, height: u32) -> DisplayTiming {
+        DisplayTiming {
+            width,
+            height,
+            refresh_rate: 60,
+            blanking: None,
+            front_porch: None,
+            sync_width: None,
+        }
+    }
+
+    fn edid_bytes(params: &GpuDisplayParameters) -> Vec<u8> {
+        match EdidBytes::new(&DisplayInfo::new(params)) {
+            Ok(OkEdid(edid)) => edid.as_bytes().to_vec(),
+            _ => panic!("failed to create EDID"),
+        }
+    }
+
+    fn timing_size(descriptor: &[u8]) -> (u32, u32) {
+        (
+            descriptor[2] as u32 | ((descriptor[4] as u32 >> 4) << 8),
+            descriptor[5] as u32 | ((descriptor[7] as u32 >> 4) << 8),
+        )
+    }
+
+    fn assert_checksums(edid: &[u8]) {
+        for block in edid.chunks(EDID_DATA_LENGTH) {
+            assert_eq!(block.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)), 0);
+        }
+    }
+
+    #[test]
+    fn single_block() {
+        let edid = edid_bytes(&Default::default());
+        assert_eq!(edid.len(), EDID_DATA_LENGTH);
+        assert_eq!(edid[EXTENSION_COUNT_OFFSET], 0);
+        assert_checksums(&edid);
+        // Display name right after the only detailed timing.
+        assert_eq!(&edid[72..77], &[0x00, 0x00, 0x00, 0xFC, 0x00]);
+    }
+
+    #[test]
+    fn modes_and_physical_size() {
+        let params = GpuDisplayParameters {
+            modes: vec![
+                mode(1920, 1080),
+                mode(1280, 720),
+                mode(800, 600),
+                mode(3840, 2160),
+                mode(640, 480),
+            ],
+            physical_size_mm: Some((600, 340)),
+            ..Default::default()
+        };
+        let edid = edid_bytes(&params);
+        assert_eq!(edid.len(), 2 * EDID_DATA_LENGTH);
+        assert_eq!(edid[EXTENSION_COUNT_OFFSET], 1);
+        assert_checksums(&edid);
+
+        assert_eq!(&edid[21..23], &[60, 34]);
+        assert_eq!(timing_size(&edid[54..72]), (1920, 1080));
+        assert_eq!(&edid[66..69], &[600u16 as u8, 340u16 as u8, 0x21]);
+        assert_eq!(timing_size(&edid[72..90]), (1280, 720));
+        assert_eq!(timing_size(&edid[90..108]), (800, 600));
+        assert_eq!(&edid[108..113], &[0x00, 0x00, 0x00, 0xFC, 0x00]);
+
+        let extension = &edid[EDID_DATA_LENGTH..];
+        assert_eq!(
+            &extension[0..4],
+            &[CTA_EXTENSION_TAG, CTA_EXTENSION_REVISION, 4, 0]
+        );
+        assert_eq!(timing_size(&extension[4..22]), (3840, 2160));
+        assert_eq!(timing_size(&extension[22..40]), (640, 480));
+    }
+
+    #[test]
+    fn hdr_metadata() {
+        let params = GpuDisplayParameters {
+            hdr: Some(HdrMetadata {
+                max_luminance: 1000,
+                max_frame_average_luminance: None,
+                min_luminance: Some(50),
+            }),
+            ..Default::default()
+        };
+        let edid = edid_bytes(&params);
+        assert_eq!(edid.len(), 2 * EDID_DATA_LENGTH);
+        assert_checksums(&edid);
+
+        let extension = &edid[EDID_DATA_LENGTH..];
+        assert_eq!(extension[2], 4 + 4 + 7);
+        assert_eq!(&extension[4..8], &[0xE3, 0x05, 0xC0, 0x00]);
+        // 50 * 2 ^ (138 / 32) ~= 1000 and 1000 * (18 / 255) ^ 2 / 100 ~= 0.05.
+        assert_eq!(&extension[8..15], &[0xE6, 0x06, 0x0D, 0x01, 138, 138, 18]);
+    }
+
+    #[test]
+    fn too_many_modes() {
+        let params = GpuDisplayParameters {
+            modes: vec![mode(640, 480); 10],
+            ..Default::default()
+        };
+        assert!(matches!(
+            EdidBytes::new(&DisplayInfo::new(&params)),
+            Err(ErrEdid(_))
+        ));
+    }
+
+    #[test]
+    fn raw_edid() {
+        let blob: Vec<u8> = (0..=255).collect();
+        let params = GpuDisplayParameters {
+            modes: vec![mode(640, 480)],
+            edid: Some(EdidBlob(blob.clone())),
+            ..Default::default()
+        };
+        assert_eq!(edid_bytes(&params), blob);
+    }
+}
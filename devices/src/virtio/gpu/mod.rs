@@ -2,14 +2,18 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+mod capture;
 mod edid;
 mod parameters;
 mod protocol;
+mod stream;
 mod virtio_gpu;
 
 use std::cell::RefCell;
 use std::collections::BTreeMap;
 use std::io::Read;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use std::net::TcpListener;
 use std::path::PathBuf;
 use std::rc::Rc;
 use std::sync::atomic::AtomicBool;
@@ -1117,6 +1121,9 @@ pub enum DisplayBackend {
     #[cfg(any(target_os = "android", target_os = "linux"))]
     /// Open a connection to the X server at the given display if given.
     X(Option<String>),
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    /// Serve each scanout over VNC to the clients of the listener at the same index.
+    Vnc(Vec<Arc<TcpListener>>),
     /// Emulate a display without actually displaying it.
     Stub,
     #[cfg(windows)]
@@ -1141,6 +1148,13 @@ impl DisplayBackend {
             DisplayBackend::Wayland(path) => GpuDisplay::open_wayland(path.as_ref()),
             #[cfg(any(target_os = "android", target_os = "linux"))]
             DisplayBackend::X(display) => GpuDisplay::open_x(display.as_deref()),
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            DisplayBackend::Vnc(listeners) => GpuDisplay::open_vnc(
+                listeners
+                    .iter()
+                    .map(|listener| listener.try_clone())
+                    .collect::<std::io::Result<_>>()?,
+            ),
             DisplayBackend::Stub => GpuDisplay::open_stub(),
             #[cfg(windows)]
             DisplayBackend::WinApi => match wndproc_thread.take() {
@@ -1677,6 +1691,13 @@ impl VirtioDevice for Gpu {
             keep_rds.push(event_device.as_raw_descriptor());
         }
 
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        for display_backend in &self.display_backends {
+            if let DisplayBackend::Vnc(listeners) = display_backend {
+                keep_rds.extend(listeners.iter().map(|l| l.as_raw_descriptor()));
+            }
+        }
+
         keep_rds
     }
 
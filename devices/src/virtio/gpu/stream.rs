@@ -0,0 +1,356 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Streaming of scanout contents to encoders and remote viewers.
+//!
+//! Frames are sent as the guest flushes them, either as complete PPM images for ffmpeg or as the
+//! rectangles modified since the previous frame. See `vm_control::gpu::StreamEncoding` for the
+//! layout of the latter.
+//!
+//! Streams are written by a `FrameExporter` thread, so frames are dropped rather than stalling the
+//! GPU worker when the receiver is slow.
+
+use std::io;
+use std::io::BufWriter;
+use std::io::Write;
+
+use rutabaga_gfx::Rutabaga2DFrame;
+use rutabaga_gfx::RutabagaRect;
+use vm_control::gpu::StreamEncoding;
+
+use super::capture::Frame;
+use super::capture::FrameSink;
+
+const BYTES_PER_PIXEL: usize = 4;
+
+const RECT_RAW: u32 = 0;
+const RECT_SOLID: u32 = 1;
+
+/// Returns the part of `rect` within a `width`x`height` frame.
+fn clip(rect: &RutabagaRect, width: u32, height: u32) -> RutabagaRect {
+    let x = rect.x.min(width);
+    let y = rect.y.min(height);
+    RutabagaRect {
+        x,
+        y,
+        width: rect.x.saturating_add(rect.width).min(width) - x,
+        height: rect.y.saturating_add(rect.height).min(height) - y,
+    }
+}
+
+/// Returns the rows of `rect` within `frame`, which has no padding between rows.
+fn rows<'a>(frame: &'a Frame, rect: &RutabagaRect) -> impl Iterator<Item = &'a [u8]> {
+    let stride = frame.width as usize * BYTES_PER_PIXEL;
+    let start = rect.x as usize * BYTES_PER_PIXEL;
+    let len = rect.width as usize * BYTES_PER_PIXEL;
+    (rect.y as usize..(rect.y + rect.height) as usize)
+        .map(move |y| &frame.pixels[y * stride + start..y * stride + start + len])
+}
+
+/// Copies the top-left `width`x`height` pixels of `frame` without the padding up to its stride.
+/// Returns them along with the damage of `frame` within that area.
+pub fn compact_frame(
+    frame: &Rutabaga2DFrame,
+    width: u32,
+    height: u32,
+) -> io::Result<(Vec<u8>, Vec<RutabagaRect>)> {
+    let stride = frame.stride as usize;
+    let len = width as usize * BYTES_PER_PIXEL;
+    let min_len = match height {
+        0 => 0,
+        h => (h as usize - 1) * stride + len,
+    };
+    if width > frame.width || height > frame.height || frame.pixels.len() < min_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{}x{} frame does not contain {}x{} pixels",
+                frame.width, frame.height, width, height
+            ),
+        ));
+    }
+
+    let mut pixels = Vec::with_capacity(len * height as usize);
+    for y in 0..height as usize {
+        pixels.extend_from_slice(&frame.pixels[y * stride..y * stride + len]);
+    }
+    let damage = frame
+        .damage
+        .iter()
+        .map(|rect| clip(rect, width, height))
+        .filter(|rect| !rect.is_empty())
+        .collect();
+    Ok((pixels, damage))
+}
+
+/// Writes the frames presented on a scanout to a stream.
+pub struct ScanoutStreamer<W: Write> {
+    writer: BufWriter<W>,
+    encoding: StreamEncoding,
+    // Size of the previous frame. Frames of a different size are sent whole.
+    size: Option<(u32, u32)>,
+}
+
+impl<W: Write> ScanoutStreamer<W> {
+    pub fn new(w: W, encoding: StreamEncoding) -> Self {
+        ScanoutStreamer {
+            writer: BufWriter::new(w),
+            encoding,
+            size: None,
+        }
+    }
+
+    /// Writes a frame made of the rectangles of `damage`, unless there are none.
+    fn write_damage(&mut self, frame: &Frame, damage: &[RutabagaRect]) -> io::Result<()> {
+        if damage.is_empty() {
+            return Ok(());
+        }
+
+        let timestamp = frame.timestamp.as_nanos() as u64;
+        self.writer.write_all(&timestamp.to_le_bytes())?;
+        self.writer.write_all(&frame.width.to_le_bytes())?;
+        self.writer.write_all(&frame.height.to_le_bytes())?;
+        self.writer
+            .write_all(&(damage.len() as u32).to_le_bytes())?;
+
+        for rect in damage {
+            for value in [rect.x, rect.y, rect.width, rect.height] {
+                self.writer.write_all(&value.to_le_bytes())?;
+            }
+            let first = &rows(frame, rect).next().unwrap()[..BYTES_PER_PIXEL];
+            let solid = rows(frame, rect)
+                .all(|row| row.chunks_exact(BYTES_PER_PIXEL).all(|px| px == first));
+            if solid {
+                self.writer.write_all(&RECT_SOLID.to_le_bytes())?;
+                self.writer.write_all(first)?;
+            } else {
+                self.writer.write_all(&RECT_RAW.to_le_bytes())?;
+                for row in rows(frame, rect) {
+                    self.writer.write_all(row)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_ppm(&mut self, frame: &Frame, rect: &RutabagaRect) -> io::Result<()> {
+        write!(self.writer, "P6\n{} {}\n255\n", rect.width, rect.height)?;
+        let mut rgb = Vec::with_capacity(rect.width as usize * 3);
+        for row in rows(frame, rect) {
+            rgb.clear();
+            for px in row.chunks_exact(BYTES_PER_PIXEL) {
+                rgb.extend_from_slice(&[px[2], px[1], px[0]]);
+            }
+            self.writer.write_all(&rgb)?;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write + Send> FrameSink for ScanoutStreamer<W> {
+    fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        let (width, height) = (frame.width, frame.height);
+        if frame.pixels.len() != width as usize * height as usize * BYTES_PER_PIXEL {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} bytes is not a {}x{} frame",
+                    frame.pixels.len(),
+                    width,
+                    height
+                ),
+            ));
+        }
+
+        let full = RutabagaRect {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        };
+        let damage = match &frame.damage {
+            Some(damage) if self.size == Some((width, height)) => damage
+                .iter()
+                .map(|rect| clip(rect, width, height))
+                .filter(|rect| !rect.is_empty())
+                .collect(),
+            _ if full.is_empty() => Vec::new(),
+            _ => vec![full],
+        };
+        self.size = Some((width, height));
+
+        match self.encoding {
+            StreamEncoding::Damage => self.write_damage(frame, &damage)?,
+            StreamEncoding::Ppm => self.write_ppm(frame, &full)?,
+        }
+        // Flush every frame since the receiver is usually displaying or encoding them live.
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn rect(x: u32, y: u32, width: u32, height: u32) -> RutabagaRect {
+        RutabagaRect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    fn frame(width: u32, height: u32, pixels: &[u8], damage: Option<Vec<RutabagaRect>>) -> Frame {
+        Frame {
+            timestamp: Duration::from_nanos(3),
+            width,
+            height,
+            pixels: pixels.to_vec(),
+            damage,
+        }
+    }
+
+    fn read_u32(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn clip_rects() {
+        assert_eq!(clip(&rect(1, 1, 4, 4), 3, 2), rect(1, 1, 2, 1));
+        assert!(clip(&rect(5, 0, 1, 1), 3, 2).is_empty());
+        assert_eq!(clip(&rect(0, 0, u32::MAX, 1), 3, 2), rect(0, 0, 3, 1));
+    }
+
+    #[test]
+    fn compact_frames() {
+        #[rustfmt::skip]
+        let pixels = [
+            1, 1, 1, 0, 2, 2, 2, 0, 9, 9,
+            3, 3, 3, 0, 4, 4, 4, 0, 9, 9,
+        ];
+        let exported = Rutabaga2DFrame {
+            width: 2,
+            height: 2,
+            stride: 10,
+            damage: vec![rect(1, 1, 4, 4), rect(2, 0, 1, 1)],
+            pixels: &pixels,
+        };
+
+        let (compact, damage) = compact_frame(&exported, 1, 2).unwrap();
+        assert_eq!(compact, [1, 1, 1, 0, 3, 3, 3, 0]);
+        assert!(damage.is_empty());
+
+        let (compact, damage) = compact_frame(&exported, 2, 2).unwrap();
+        assert_eq!(compact, [1, 1, 1, 0, 2, 2, 2, 0, 3, 3, 3, 0, 4, 4, 4, 0]);
+        assert_eq!(damage, [rect(1, 1, 1, 1)]);
+
+        assert!(compact_frame(&exported, 3, 2).is_err());
+        let truncated = Rutabaga2DFrame {
+            pixels: &pixels[..17],
+            ..exported
+        };
+        assert!(compact_frame(&truncated, 2, 2).is_err());
+    }
+
+    #[test]
+    fn damage_frames() {
+        #[rustfmt::skip]
+        let pixels = [
+            1, 1, 1, 0, 2, 2, 2, 0,
+            3, 3, 3, 0, 3, 3, 3, 0,
+        ];
+        let mut out = Vec::new();
+        {
+            let mut streamer = ScanoutStreamer::new(&mut out, StreamEncoding::Damage);
+            // The first frame is sent whole regardless of its damage.
+            streamer
+                .write_frame(&frame(2, 2, &pixels, Some(Vec::new())))
+                .unwrap();
+            streamer
+                .write_frame(&frame(2, 2, &pixels, Some(vec![rect(0, 1, 2, 1)])))
+                .unwrap();
+            // Frames without damage are skipped.
+            streamer
+                .write_frame(&frame(2, 2, &pixels, Some(Vec::new())))
+                .unwrap();
+        }
+
+        assert_eq!(read_u32(&out, 0), 3);
+        assert_eq!(read_u32(&out, 8), 2);
+        assert_eq!(read_u32(&out, 12), 2);
+        assert_eq!(read_u32(&out, 16), 1);
+        assert_eq!(
+            &out[20..40],
+            &[0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(&out[40..56], &pixels);
+
+        let second = &out[56..];
+        assert_eq!(read_u32(second, 16), 1);
+        assert_eq!(
+            &second[20..40],
+            &[0, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0]
+        );
+        assert_eq!(&second[40..], &[3, 3, 3, 0]);
+    }
+
+    #[test]
+    fn damage_unknown() {
+        let pixels = [7; 16];
+        let mut out = Vec::new();
+        {
+            let mut streamer = ScanoutStreamer::new(&mut out, StreamEncoding::Damage);
+            streamer
+                .write_frame(&frame(2, 2, &pixels, Some(Vec::new())))
+                .unwrap();
+            // Frames were dropped before this one, so it is sent whole.
+            streamer.write_frame(&frame(2, 2, &pixels, None)).unwrap();
+        }
+        assert_eq!(out.len(), 2 * (20 + 20 + 4));
+        assert_eq!(
+            &out[44 + 20..44 + 36],
+            &[0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 2, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn damage_size_change() {
+        let pixels = [7; 16];
+        let mut out = Vec::new();
+        {
+            let mut streamer = ScanoutStreamer::new(&mut out, StreamEncoding::Damage);
+            streamer
+                .write_frame(&frame(2, 2, &pixels, Some(Vec::new())))
+                .unwrap();
+            streamer
+                .write_frame(&frame(1, 2, &pixels[..8], Some(Vec::new())))
+                .unwrap();
+            assert!(streamer
+                .write_frame(&frame(3, 2, &pixels, Some(Vec::new())))
+                .is_err());
+        }
+        // Two frames made of a single solid rectangle each.
+        assert_eq!(out.len(), 2 * (20 + 20 + 4));
+        assert_eq!(read_u32(&out, 44 + 8), 1);
+        assert_eq!(
+            &out[44 + 20..44 + 36],
+            &[0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn ppm_frames() {
+        let pixels = [1, 2, 3, 0, 4, 5, 6, 0];
+        let mut out = Vec::new();
+        {
+            let mut streamer = ScanoutStreamer::new(&mut out, StreamEncoding::Ppm);
+            streamer.write_frame(&frame(2, 1, &pixels, None)).unwrap();
+        }
+        assert_eq!(&out[..11], b"P6\n2 1\n255\n");
+        assert_eq!(&out[11..], &[3, 2, 1, 6, 5, 4]);
+    }
+}
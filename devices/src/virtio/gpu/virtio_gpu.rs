@@ -5,6 +5,7 @@
 use std::cell::RefCell;
 use std::collections::BTreeMap as Map;
 use std::collections::BTreeSet as Set;
+use std::fs::File;
 use std::io::IoSliceMut;
 use std::num::NonZeroU32;
 use std::rc::Rc;
@@ -27,7 +28,6 @@ use rutabaga_gfx::ResourceCreate3D;
 use rutabaga_gfx::ResourceCreateBlob;
 use rutabaga_gfx::Rutabaga;
 use rutabaga_gfx::RutabagaDescriptor;
-#[cfg(windows)]
 use rutabaga_gfx::RutabagaError;
 use rutabaga_gfx::RutabagaFence;
 use rutabaga_gfx::RutabagaFromRawDescriptor;
@@ -51,18 +51,24 @@ use vm_control::gpu::DisplayParameters;
 use vm_control::gpu::GpuControlCommand;
 use vm_control::gpu::GpuControlResult;
 use vm_control::gpu::MouseMode;
+use vm_control::gpu::StreamEncoding;
 use vm_control::VmMemorySource;
 use vm_memory::udmabuf::UdmabufDriver;
 use vm_memory::udmabuf::UdmabufDriverTrait;
 use vm_memory::GuestAddress;
 use vm_memory::GuestMemory;
 
+use super::capture::write_png;
+use super::capture::FrameExporter;
+use super::capture::ScanoutRecorder;
 use super::protocol::GpuResponse;
 use super::protocol::GpuResponse::*;
 use super::protocol::GpuResponsePlaneInfo;
 use super::protocol::VirtioGpuResult;
 use super::protocol::VIRTIO_GPU_BLOB_FLAG_CREATE_GUEST_HANDLE;
 use super::protocol::VIRTIO_GPU_BLOB_MEM_HOST3D;
+use super::stream::compact_frame;
+use super::stream::ScanoutStreamer;
 use super::VirtioScanoutBlobData;
 use crate::virtio::gpu::edid::DisplayInfo;
 use crate::virtio::gpu::edid::EdidBytes;
@@ -166,6 +172,11 @@ struct VirtioGpuScanout {
 
     resource_id: Option<NonZeroU32>,
     position: Option<(u32, u32)>,
+
+    // Destination of the frames flushed to this scanout, if it is being recorded.
+    recorder: Option<FrameExporter>,
+    // Destination of the frames flushed to this scanout, if it is being streamed.
+    streamer: Option<FrameExporter>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -200,6 +211,8 @@ impl VirtioGpuScanout {
             parent_scanout_id: None,
             resource_id: None,
             position: None,
+            recorder: None,
+            streamer: None,
         }
     }
 
@@ -217,6 +230,8 @@ impl VirtioGpuScanout {
             parent_scanout_id: None,
             resource_id: None,
             position: None,
+            recorder: None,
+            streamer: None,
         }
     }
 
@@ -395,6 +410,75 @@ impl VirtioGpuScanout {
         Ok(OkNoData)
     }
 
+    /// Returns the size of the part of `resource` that is shown on this scanout.
+    fn visible_size(&self, resource: &VirtioGpuResource) -> (u32, u32) {
+        (
+            self.width.min(resource.width),
+            self.height.min(resource.height),
+        )
+    }
+
+    /// Queues the contents of `resource` for the recording of this scanout, if any. The frame is
+    /// dropped if the recording thread is behind. Recording stops on the first error.
+    fn record(&mut self, resource: &VirtioGpuResource, rutabaga: &mut Rutabaga) {
+        let (width, height) = self.visible_size(resource);
+        let recorder = match self.recorder.as_mut() {
+            Some(recorder) if recorder.wants_frame() => recorder,
+            _ => return,
+        };
+        match read_pixels(rutabaga, resource.resource_id, width, height) {
+            Ok(pixels) => {
+                // The recording thread already logged why it stopped.
+                if !recorder.export(width, height, pixels, None) {
+                    self.recorder = None;
+                }
+            }
+            Err(e) => {
+                error!(
+                    "stopping recording of scanout {:?}: {:#}",
+                    self.scanout_id, e
+                );
+                self.recorder = None;
+            }
+        }
+    }
+
+    /// Queues the contents of `resource` for the stream of this scanout, if any. The frame is
+    /// dropped if the streaming thread is behind. Streaming stops on the first error.
+    fn stream(&mut self, resource: &VirtioGpuResource, rutabaga: &mut Rutabaga) {
+        let (width, height) = self.visible_size(resource);
+        let streamer = match self.streamer.as_mut() {
+            Some(streamer) if streamer.wants_frame() => streamer,
+            _ => return,
+        };
+        let result = match rutabaga.export_frame(resource.resource_id) {
+            Ok(frame) => compact_frame(&frame, width, height)
+                .map(|(pixels, damage)| (pixels, Some(damage)))
+                .context("failed to export frame"),
+            // Only 2D resources track damage, others are sent whole.
+            Err(RutabagaError::Unsupported) => {
+                read_pixels(rutabaga, resource.resource_id, width, height)
+                    .map(|pixels| (pixels, None))
+            }
+            Err(e) => Err(e).context("failed to export frame"),
+        };
+        match result {
+            Ok((pixels, damage)) => {
+                // The streaming thread already logged why it stopped.
+                if !streamer.export(width, height, pixels, damage) {
+                    self.streamer = None;
+                }
+            }
+            Err(e) => {
+                error!(
+                    "stopping streaming of scanout {:?}: {:#}",
+                    self.scanout_id, e
+                );
+                self.streamer = None;
+            }
+        }
+    }
+
     fn import_resource_to_display(
         display: &Rc<RefCell<GpuDisplay>>,
         surface_id: u32,
@@ -445,6 +529,23 @@ impl VirtioGpuScanout {
     }
 }
 
+/// Reads the top-left `width`x`height` pixels of a resource in XRGB8888 format.
+fn read_pixels(
+    rutabaga: &mut Rutabaga,
+    resource_id: u32,
+    width: u32,
+    height: u32,
+) -> anyhow::Result<Vec<u8>> {
+    let stride = width * 4;
+    let mut pixels = vec![0u8; stride as usize * height as usize];
+    let mut transfer = Transfer3D::new_2d(0, 0, width, height);
+    transfer.stride = stride;
+    rutabaga
+        .transfer_read(0, resource_id, transfer, Some(IoSliceMut::new(&mut pixels)))
+        .context("failed to read resource")?;
+    Ok(pixels)
+}
+
 /// Handles functionality related to displays, input events and hypervisor memory management.
 pub struct VirtioGpu {
     display: Rc<RefCell<GpuDisplay>>,
@@ -604,6 +705,10 @@ impl VirtioGpu {
             };
         }
 
+        if let Err(e) = displays.iter().try_for_each(DisplayParameters::validate) {
+            return GpuControlResult::ErrString(e);
+        }
+
         let mut available_scanout_ids = (0..VIRTIO_GPU_MAX_SCANOUTS)
             .map(|s| s as u32)
             .collect::<Set<u32>>();
@@ -685,6 +790,106 @@ impl VirtioGpu {
         }
     }
 
+    /// Saves the contents currently shown on a display to `file` as a PNG image.
+    fn take_screenshot(&mut self, display_id: u32, file: File) -> GpuControlResult {
+        let scanout = match self.scanouts.get(&display_id) {
+            Some(scanout) => scanout,
+            None => return GpuControlResult::NoSuchDisplay { display_id },
+        };
+        let resource = match scanout
+            .resource_id
+            .and_then(|id| self.resources.get(&id.get()))
+        {
+            Some(resource) => resource,
+            None => return GpuControlResult::ErrString("display has no content".to_string()),
+        };
+        let (width, height) = scanout.visible_size(resource);
+        let result = read_pixels(&mut self.rutabaga, resource.resource_id, width, height).and_then(
+            |pixels| write_png(file, width, height, &pixels).context("failed to write png"),
+        );
+        match result {
+            Ok(()) => GpuControlResult::ScreenshotTaken,
+            Err(e) => GpuControlResult::ErrString(format!("{:#}", e)),
+        }
+    }
+
+    /// Starts appending the frames flushed to a display to `file`, beginning with the frame it
+    /// currently shows.
+    fn start_recording(&mut self, display_id: u32, file: File) -> GpuControlResult {
+        let scanout = match self.scanouts.get_mut(&display_id) {
+            Some(scanout) => scanout,
+            None => return GpuControlResult::NoSuchDisplay { display_id },
+        };
+        let recorder = match FrameExporter::new(
+            format!("v_gpu_record:{}", display_id),
+            ScanoutRecorder::new(file),
+        ) {
+            Ok(recorder) => recorder,
+            Err(e) => {
+                return GpuControlResult::ErrString(format!("failed to start recording: {}", e))
+            }
+        };
+        scanout.recorder = Some(recorder);
+        if let Some(resource) = scanout
+            .resource_id
+            .and_then(|id| self.resources.get(&id.get()))
+        {
+            scanout.record(resource, &mut self.rutabaga);
+        }
+        GpuControlResult::RecordingStarted
+    }
+
+    fn stop_recording(&mut self, display_id: u32) -> GpuControlResult {
+        match self.scanouts.get_mut(&display_id) {
+            Some(scanout) => {
+                scanout.recorder = None;
+                GpuControlResult::RecordingStopped
+            }
+            None => GpuControlResult::NoSuchDisplay { display_id },
+        }
+    }
+
+    /// Starts writing the frames flushed to a display to `file`, beginning with the frame it
+    /// currently shows.
+    fn start_streaming(
+        &mut self,
+        display_id: u32,
+        encoding: StreamEncoding,
+        file: File,
+    ) -> GpuControlResult {
+        let scanout = match self.scanouts.get_mut(&display_id) {
+            Some(scanout) => scanout,
+            None => return GpuControlResult::NoSuchDisplay { display_id },
+        };
+        let streamer = match FrameExporter::new(
+            format!("v_gpu_stream:{}", display_id),
+            ScanoutStreamer::new(file, encoding),
+        ) {
+            Ok(streamer) => streamer,
+            Err(e) => {
+                return GpuControlResult::ErrString(format!("failed to start streaming: {}", e))
+            }
+        };
+        scanout.streamer = Some(streamer);
+        if let Some(resource) = scanout
+            .resource_id
+            .and_then(|id| self.resources.get(&id.get()))
+        {
+            scanout.stream(resource, &mut self.rutabaga);
+        }
+        GpuControlResult::StreamingStarted
+    }
+
+    fn stop_streaming(&mut self, display_id: u32) -> GpuControlResult {
+        match self.scanouts.get_mut(&display_id) {
+            Some(scanout) => {
+                scanout.streamer = None;
+                GpuControlResult::StreamingStopped
+            }
+            None => GpuControlResult::NoSuchDisplay { display_id },
+        }
+    }
+
     /// Performs the given command to interact with or modify the device.
     pub fn process_gpu_control_command(&mut self, cmd: GpuControlCommand) -> GpuControlResult {
         match cmd {
@@ -695,6 +900,19 @@ impl VirtioGpu {
                 display_id,
                 mouse_mode,
             } => self.set_display_mouse_mode(display_id, mouse_mode),
+            GpuControlCommand::Screenshot { display_id, file } => {
+                self.take_screenshot(display_id, file)
+            }
+            GpuControlCommand::StartRecording { display_id, file } => {
+                self.start_recording(display_id, file)
+            }
+            GpuControlCommand::StopRecording { display_id } => self.stop_recording(display_id),
+            GpuControlCommand::StartStreaming {
+                display_id,
+                encoding,
+                file,
+            } => self.start_streaming(display_id, encoding, file),
+            GpuControlCommand::StopStreaming { display_id } => self.stop_streaming(display_id),
         }
     }
 
@@ -760,6 +978,10 @@ impl VirtioGpu {
 
         for scanout in self.scanouts.values_mut() {
             if scanout.resource_id == resource_id {
+                // Record and stream even when the scanout has no surface so that headless guests
+                // can be captured.
+                scanout.record(resource, &mut self.rutabaga);
+                scanout.stream(resource, &mut self.rutabaga);
                 scanout.flush(&self.display, resource, &mut self.rutabaga)?;
             }
         }
@@ -2,6 +2,7 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::collections::BTreeSet;
 use std::collections::VecDeque;
 use std::io::Read;
 use std::io::Write;
@@ -192,9 +193,84 @@ where
     }
 }
 
+/// Key combination that toggles whether the guest has exclusive access to an event device.
+///
+/// While the device is grabbed its events only reach the guest. Once released the host receives
+/// them instead and nothing is forwarded to the guest until the combination is pressed again.
+struct GrabHotkey {
+    keys: BTreeSet<u16>,
+    // Keys of the combination currently held down.
+    held: BTreeSet<u16>,
+    // Keys whose press was forwarded to the guest and which haven't been released yet.
+    forwarded: BTreeSet<u16>,
+    grabbed: bool,
+}
+
+impl GrabHotkey {
+    fn new(keys: &[u16]) -> GrabHotkey {
+        GrabHotkey {
+            keys: keys.iter().copied().collect(),
+            held: BTreeSet::new(),
+            forwarded: BTreeSet::new(),
+            grabbed: true,
+        }
+    }
+
+    /// Pushes to `queue` the events that should reach the guest as a consequence of `evt`.
+    fn filter(&mut self, evt: virtio_input_event, queue: &mut VecDeque<virtio_input_event>) {
+        if evt.type_ != EV_KEY {
+            if self.grabbed {
+                queue.push_back(evt);
+            }
+            return;
+        }
+
+        let code = evt.code.to_native();
+        let value = evt.value.to_native();
+        if self.keys.contains(&code) {
+            if value == 0 {
+                self.held.remove(&code);
+            } else {
+                self.held.insert(code);
+            }
+            if value == 1 && self.held == self.keys {
+                self.grabbed = !self.grabbed;
+                if !self.grabbed {
+                    // Release the keys the guest still sees as pressed, including the rest of
+                    // the combination, since it won't see them being released.
+                    let pressed = std::mem::take(&mut self.forwarded);
+                    for key in pressed.iter() {
+                        queue.push_back(virtio_input_event::key(*key, false, false));
+                    }
+                    if !pressed.is_empty() {
+                        queue.push_back(virtio_input_event::syn());
+                    }
+                }
+                return;
+            }
+        }
+
+        if !self.grabbed {
+            return;
+        }
+        let forward = match value {
+            0 => self.forwarded.remove(&code),
+            1 => {
+                self.forwarded.insert(code);
+                true
+            }
+            _ => self.forwarded.contains(&code),
+        };
+        if forward {
+            queue.push_back(evt);
+        }
+    }
+}
+
 /// Encapsulates an event device node as an event source
 pub struct EvdevEventSource<T> {
     evt_source_impl: EventSourceImpl<T>,
+    grab_hotkey: Option<GrabHotkey>,
 }
 
 impl<T> EvdevEventSource<T>
@@ -204,8 +280,15 @@ where
     pub fn new(source: T) -> EvdevEventSource<T> {
         EvdevEventSource {
             evt_source_impl: EventSourceImpl::new(source, 16 * input_event::SIZE),
+            grab_hotkey: None,
         }
     }
+
+    /// Lets the host take back the device when all `keys` are pressed, and the guest grab it
+    /// again when they are pressed once more. The combination itself is never forwarded.
+    pub fn set_grab_hotkey(&mut self, keys: &[u16]) {
+        self.grab_hotkey = (!keys.is_empty()).then(|| GrabHotkey::new(keys));
+    }
 }
 
 impl<T: AsRawDescriptor> AsRawDescriptor for EvdevEventSource<T> {
@@ -223,11 +306,33 @@ where
     }
 
     fn finalize(&mut self) -> Result<()> {
-        ungrab_evdev(self)
+        match &self.grab_hotkey {
+            Some(hotkey) if !hotkey.grabbed => Ok(()),
+            _ => ungrab_evdev(self),
+        }
     }
 
     fn receive_events(&mut self) -> Result<usize> {
-        self.evt_source_impl.receive_events::<input_event>()
+        let received = self.evt_source_impl.receive_events::<input_event>()?;
+        let Some(hotkey) = self.grab_hotkey.as_mut() else {
+            return Ok(received);
+        };
+
+        let queue = &mut self.evt_source_impl.queue;
+        let first_new = queue.len() - received;
+        let new_events: Vec<virtio_input_event> = queue.drain(first_new..).collect();
+        let was_grabbed = hotkey.grabbed;
+        for evt in new_events {
+            hotkey.filter(evt, queue);
+        }
+        let available = queue.len() - first_new;
+
+        match (was_grabbed, hotkey.grabbed) {
+            (false, true) => grab_evdev(self)?,
+            (true, false) => ungrab_evdev(self)?,
+            _ => {}
+        }
+        Ok(available)
     }
 
     fn available_events_count(&self) -> usize {
@@ -247,6 +352,7 @@ where
 #[cfg(test)]
 mod tests {
     use std::cmp::min;
+    use std::collections::VecDeque;
     use std::io::Read;
     use std::io::Write;
 
@@ -258,6 +364,7 @@ mod tests {
     use crate::virtio::input::event_source::input_event;
     use crate::virtio::input::event_source::virtio_input_event;
     use crate::virtio::input::event_source::EventSourceImpl;
+    use crate::virtio::input::event_source::GrabHotkey;
 
     struct SourceMock {
         events: Vec<u8>,
@@ -385,4 +492,65 @@ mod tests {
             "no events should pop"
         );
     }
+
+    #[test]
+    fn grab_hotkey() {
+        const KEY_LEFTCTRL: u16 = 29;
+        const KEY_LEFTALT: u16 = 56;
+        const KEY_A: u16 = 30;
+        const KEY_B: u16 = 48;
+
+        let mut hotkey = GrabHotkey::new(&[KEY_LEFTCTRL, KEY_LEFTALT]);
+        let mut queue = VecDeque::new();
+        let mut filter = |evts: &[virtio_input_event]| {
+            for evt in evts {
+                hotkey.filter(*evt, &mut queue);
+            }
+            queue
+                .drain(..)
+                .map(|e| (e.type_.to_native(), e.code.to_native(), e.value.to_native()))
+                .collect::<Vec<_>>()
+        };
+        let key = virtio_input_event::key;
+        let syn = virtio_input_event::syn;
+
+        // Grabbed: everything is forwarded.
+        assert_eq!(
+            filter(&[key(KEY_A, true, false), syn(), key(KEY_A, false, false)]),
+            [(1, KEY_A, 1), (0, 0, 0), (1, KEY_A, 0)]
+        );
+
+        // The combination releases the device and the keys already seen by the guest.
+        assert_eq!(
+            filter(&[
+                key(KEY_LEFTCTRL, true, false),
+                key(KEY_LEFTALT, true, false)
+            ]),
+            [(1, KEY_LEFTCTRL, 1), (1, KEY_LEFTCTRL, 0), (0, 0, 0)]
+        );
+
+        // Released: nothing is forwarded.
+        assert_eq!(
+            filter(&[
+                key(KEY_LEFTCTRL, false, false),
+                key(KEY_LEFTALT, false, false),
+                key(KEY_A, true, false),
+                syn(),
+            ]),
+            []
+        );
+
+        // Grabbing again only forwards keys pressed afterwards.
+        assert_eq!(
+            filter(&[
+                key(KEY_LEFTALT, true, false),
+                key(KEY_LEFTCTRL, true, false),
+                key(KEY_A, false, false),
+                key(KEY_LEFTCTRL, false, false),
+                key(KEY_B, true, false),
+                key(KEY_B, true, true),
+            ]),
+            [(1, KEY_B, 1), (1, KEY_B, 2)]
+        );
+    }
 }
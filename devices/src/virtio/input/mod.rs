@@ -8,6 +8,8 @@ mod evdev;
 mod event_source;
 
 use std::collections::BTreeMap;
+use std::collections::VecDeque;
+use std::fs::File;
 use std::io::Read;
 use std::io::Write;
 
@@ -22,6 +24,7 @@ use base::AsRawDescriptor;
 use base::Event;
 use base::EventToken;
 use base::RawDescriptor;
+use base::Tube;
 use base::WaitContext;
 use base::WorkerThread;
 use data_model::Le16;
@@ -33,6 +36,10 @@ use remain::sorted;
 use serde::Deserialize;
 use serde::Serialize;
 use thiserror::Error;
+use vm_control::input::InputControlCommand;
+use vm_control::input::InputControlResult;
+use vm_control::input::InputEvent;
+use vm_control::input::TraceWriter;
 use vm_memory::GuestMemory;
 use zerocopy::AsBytes;
 use zerocopy::FromBytes;
@@ -347,18 +354,38 @@ struct Worker<T: EventSource> {
     event_source: T,
     event_queue: Queue,
     status_queue: Queue,
+    control_tube: Option<Tube>,
+    // Events received from the control tube, sent after those of the source.
+    injected_events: VecDeque<virtio_input_event>,
+    recorder: Option<TraceWriter<File>>,
 }
 
 impl<T: EventSource> Worker<T> {
+    fn available_events_count(&self) -> usize {
+        self.event_source.available_events_count() + self.injected_events.len()
+    }
+
+    // Returns the next event to send to the guest and appends it to the recording, if any.
+    fn pop_available_event(&mut self) -> Option<virtio_input_event> {
+        let evt = self
+            .event_source
+            .pop_available_event()
+            .or_else(|| self.injected_events.pop_front())?;
+        if let Some(recorder) = self.recorder.as_mut() {
+            if let Err(e) = recorder.write_event(&InputEvent::from(evt)) {
+                error!("Input: stopping recording after write failure: {}", e);
+                self.recorder = None;
+            }
+        }
+        Some(evt)
+    }
+
     // Fills a virtqueue with events from the source.  Returns the number of bytes written.
-    fn fill_event_virtqueue(
-        event_source: &mut T,
-        avail_desc: &mut DescriptorChain,
-    ) -> Result<usize> {
+    fn fill_event_virtqueue(&mut self, avail_desc: &mut DescriptorChain) -> Result<usize> {
         let writer = &mut avail_desc.writer;
 
         while writer.available_bytes() >= virtio_input_event::SIZE {
-            if let Some(evt) = event_source.pop_available_event() {
+            if let Some(evt) = self.pop_available_event() {
                 writer.write_obj(evt).map_err(InputError::WriteQueue)?;
             } else {
                 break;
@@ -373,21 +400,19 @@ impl<T: EventSource> Worker<T> {
         let mut needs_interrupt = false;
 
         // Only consume from the queue iterator if we know we have events to send
-        while self.event_source.available_events_count() > 0 {
+        while self.available_events_count() > 0 {
             match self.event_queue.pop() {
                 None => {
                     break;
                 }
                 Some(mut avail_desc) => {
-                    let bytes_written =
-                        match Worker::fill_event_virtqueue(&mut self.event_source, &mut avail_desc)
-                        {
-                            Ok(count) => count,
-                            Err(e) => {
-                                error!("Input: failed to send events to guest: {}", e);
-                                break;
-                            }
-                        };
+                    let bytes_written = match self.fill_event_virtqueue(&mut avail_desc) {
+                        Ok(count) => count,
+                        Err(e) => {
+                            error!("Input: failed to send events to guest: {}", e);
+                            break;
+                        }
+                    };
 
                     self.event_queue.add_used(avail_desc, bytes_written as u32);
                     needs_interrupt = true;
@@ -431,6 +456,20 @@ impl<T: EventSource> Worker<T> {
         Ok(needs_interrupt)
     }
 
+    fn process_control_command(&mut self, command: InputControlCommand) -> InputControlResult {
+        match command {
+            InputControlCommand::SendEvents { events } => {
+                self.injected_events
+                    .extend(events.into_iter().map(virtio_input_event::from));
+            }
+            InputControlCommand::StartRecording { file } => {
+                self.recorder = Some(TraceWriter::new(file));
+            }
+            InputControlCommand::StopRecording => self.recorder = None,
+        }
+        InputControlResult::Ok
+    }
+
     // Allow error! and early return anywhere in function
     #[allow(clippy::needless_return)]
     fn run(&mut self, kill_evt: Event) {
@@ -444,6 +483,7 @@ impl<T: EventSource> Worker<T> {
             EventQAvailable,
             StatusQAvailable,
             InputEventsAvailable,
+            ControlCommand,
             InterruptResample,
             Kill,
         }
@@ -468,6 +508,12 @@ impl<T: EventSource> Worker<T> {
                 return;
             }
         }
+        if let Some(control_tube) = &self.control_tube {
+            if let Err(e) = wait_ctx.add(control_tube, Token::ControlCommand) {
+                error!("failed adding control tube to WaitContext: {}", e);
+                return;
+            }
+        }
 
         'wait: loop {
             let wait_events = match wait_ctx.wait() {
@@ -500,9 +546,36 @@ impl<T: EventSource> Worker<T> {
                         }
                     }
                     Token::InputEventsAvailable => match self.event_source.receive_events() {
+                        // The host device was unplugged, stop polling it until the guest is told
+                        // to remove this device.
+                        Err(InputError::EventsReadError(e))
+                            if e.raw_os_error() == Some(libc::ENODEV) =>
+                        {
+                            warn!("input event source was removed");
+                            let _ = wait_ctx.delete(&self.event_source);
+                        }
                         Err(e) => error!("error receiving events: {}", e),
                         Ok(_cnt) => eventq_needs_interrupt |= self.send_events(),
                     },
+                    Token::ControlCommand => {
+                        let Some(tube) = self.control_tube.take() else {
+                            continue;
+                        };
+                        match tube.recv::<InputControlCommand>() {
+                            Ok(command) => {
+                                let result = self.process_control_command(command);
+                                if let Err(e) = tube.send(&result) {
+                                    error!("failed sending control command result: {}", e);
+                                }
+                                eventq_needs_interrupt |= self.send_events();
+                                self.control_tube = Some(tube);
+                            }
+                            Err(e) => {
+                                error!("failed receiving control command, closing tube: {}", e);
+                                let _ = wait_ctx.delete(&tube);
+                            }
+                        }
+                    }
                     Token::InterruptResample => {
                         self.interrupt.interrupt_resample();
                     }
@@ -534,6 +607,15 @@ pub struct Input<T: EventSource + Send + 'static> {
     config: VirtioInputConfig,
     source: Option<T>,
     virtio_features: u64,
+    control_tube: Option<Tube>,
+    recorder: Option<TraceWriter<File>>,
+}
+
+impl<T: EventSource + Send + 'static> Input<T> {
+    /// Sets the tube on which the device receives `InputControlCommand`s.
+    pub fn set_control_tube(&mut self, control_tube: Tube) {
+        self.control_tube = Some(control_tube);
+    }
 }
 
 /// Snapshot of [Input]'s state.
@@ -548,10 +630,14 @@ where
     T: 'static + EventSource + Send,
 {
     fn keep_rds(&self) -> Vec<RawDescriptor> {
+        let mut keep_rds = Vec::new();
         if let Some(source) = &self.source {
-            return vec![source.as_raw_descriptor()];
+            keep_rds.push(source.as_raw_descriptor());
         }
-        Vec::new()
+        if let Some(control_tube) = &self.control_tube {
+            keep_rds.push(control_tube.as_raw_descriptor());
+        }
+        keep_rds
     }
 
     fn device_type(&self) -> DeviceType {
@@ -590,12 +676,17 @@ where
             .source
             .take()
             .context("tried to activate device without a source for events")?;
+        let control_tube = self.control_tube.take();
+        let recorder = self.recorder.take();
         self.worker_thread = Some(WorkerThread::start("v_input", move |kill_evt| {
             let mut worker = Worker {
                 interrupt,
                 event_source: source,
                 event_queue,
                 status_queue,
+                control_tube,
+                injected_events: VecDeque::new(),
+                recorder,
             };
             worker.run(kill_evt);
             worker
@@ -608,6 +699,8 @@ where
         if let Some(worker_thread) = self.worker_thread.take() {
             let worker = worker_thread.stop();
             self.source = Some(worker.event_source);
+            self.control_tube = worker.control_tube;
+            self.recorder = worker.recorder;
         }
         Ok(())
     }
@@ -616,6 +709,8 @@ where
         if let Some(worker_thread) = self.worker_thread.take() {
             let worker = worker_thread.stop();
             self.source = Some(worker.event_source);
+            self.control_tube = worker.control_tube;
+            self.recorder = worker.recorder;
             let queues = BTreeMap::from([(0, worker.event_queue), (1, worker.status_queue)]);
             Ok(Some(queues))
         } else {
@@ -655,16 +750,28 @@ where
     }
 }
 
-/// Creates a new virtio input device from an event device node
-pub fn new_evdev<T>(source: T, virtio_features: u64) -> Result<Input<EvdevEventSource<T>>>
+/// Creates a new virtio input device from an event device node. If `grab_hotkey` is given, the
+/// device can be handed back and forth between the guest and the host by pressing those keys.
+pub fn new_evdev<T>(
+    source: T,
+    grab_hotkey: Option<&[u16]>,
+    virtio_features: u64,
+) -> Result<Input<EvdevEventSource<T>>>
 where
     T: Read + Write + AsRawDescriptor + Send + 'static,
 {
+    let config = VirtioInputConfig::from_evdev(&source)?;
+    let mut source = EvdevEventSource::new(source);
+    if let Some(keys) = grab_hotkey {
+        source.set_grab_hotkey(keys);
+    }
     Ok(Input {
         worker_thread: None,
-        config: VirtioInputConfig::from_evdev(&source)?,
-        source: Some(EvdevEventSource::new(source)),
+        config,
+        source: Some(source),
         virtio_features,
+        control_tube: None,
+        recorder: None,
     })
 }
 
@@ -685,6 +792,8 @@ where
         config: defaults::new_single_touch_config(idx, width, height, name),
         source: Some(SocketEventSource::new(source)),
         virtio_features,
+        control_tube: None,
+        recorder: None,
     })
 }
 
@@ -705,6 +814,8 @@ where
         config: defaults::new_multi_touch_config(idx, width, height, name),
         source: Some(SocketEventSource::new(source)),
         virtio_features,
+        control_tube: None,
+        recorder: None,
     })
 }
 
@@ -726,6 +837,8 @@ where
         config: defaults::new_trackpad_config(idx, width, height, name),
         source: Some(SocketEventSource::new(source)),
         virtio_features,
+        control_tube: None,
+        recorder: None,
     })
 }
 
@@ -747,6 +860,8 @@ where
         config: defaults::new_multitouch_trackpad_config(idx, width, height, name),
         source: Some(SocketEventSource::new(source)),
         virtio_features,
+        control_tube: None,
+        recorder: None,
     })
 }
 
@@ -764,6 +879,8 @@ where
         config: defaults::new_mouse_config(idx),
         source: Some(SocketEventSource::new(source)),
         virtio_features,
+        control_tube: None,
+        recorder: None,
     })
 }
 
@@ -781,6 +898,8 @@ where
         config: defaults::new_keyboard_config(idx),
         source: Some(SocketEventSource::new(source)),
         virtio_features,
+        control_tube: None,
+        recorder: None,
     })
 }
 
@@ -798,6 +917,8 @@ where
         config: defaults::new_switches_config(idx),
         source: Some(SocketEventSource::new(source)),
         virtio_features,
+        control_tube: None,
+        recorder: None,
     })
 }
 
@@ -815,5 +936,7 @@ where
         config: defaults::new_rotary_config(idx),
         source: Some(SocketEventSource::new(source)),
         virtio_features,
+        control_tube: None,
+        recorder: None,
     })
 }
@@ -10,6 +10,7 @@ pub(crate) mod sys;
 use std::cell::RefCell;
 use std::collections::btree_map::Entry;
 use std::collections::BTreeMap;
+use std::fs::File;
 use std::io;
 use std::io::Write;
 use std::mem::size_of;
@@ -25,7 +26,6 @@ use anyhow::Context;
 use base::debug;
 use base::error;
 use base::pagesize;
-#[cfg(target_arch = "x86_64")]
 use base::warn;
 use base::AsRawDescriptor;
 use base::Error as SysError;
@@ -42,9 +42,12 @@ use cros_async::AsyncError;
 use cros_async::AsyncTube;
 use cros_async::EventAsync;
 use cros_async::Executor;
+use data_model::Le32;
 use data_model::Le64;
+use futures::channel::mpsc;
 use futures::select;
 use futures::FutureExt;
+use futures::StreamExt;
 use remain::sorted;
 use sync::Mutex;
 use thiserror::Error;
@@ -75,6 +78,9 @@ const QUEUE_SIZE: u16 = 256;
 const NUM_QUEUES: usize = 2;
 const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE; NUM_QUEUES];
 
+// Number of faults kept while the driver provides no event buffer. Later faults are dropped.
+const MAX_PENDING_FAULTS: usize = 64;
+
 // Size of struct virtio_iommu_probe_property
 #[cfg(target_arch = "x86_64")]
 const IOMMU_PROBE_SIZE: usize = size_of::<virtio_iommu_probe_resv_mem>();
@@ -126,6 +132,8 @@ type Result<T> = result::Result<T, IommuError>;
 pub enum IommuError {
     #[error("async executor error: {0}")]
     AsyncExec(AsyncError),
+    #[error("failed to create async fault queue: {0}")]
+    CreateAsyncFaultQueue(AsyncError),
     #[error("failed to create wait context: {0}")]
     CreateWaitContext(SysError),
     #[error("failed getting host address: {0}")]
@@ -138,6 +146,8 @@ pub enum IommuError {
     MemoryMapper(anyhow::Error),
     #[error("Failed to read descriptor asynchronously: {0}")]
     ReadAsyncDesc(AsyncError),
+    #[error("failed to read from fault queue: {0}")]
+    ReadFaultQueue(AsyncError),
     #[error("failed to read from virtio queue Event: {0}")]
     ReadQueueEvent(SysError),
     #[error("tube error: {0}")]
@@ -152,6 +162,8 @@ pub enum IommuError {
     WaitError(SysError),
     #[error("write buffer length too small")]
     WriteBufferTooSmall,
+    #[error("failed to write to fault queue: {0}")]
+    WriteFaultQueue(AsyncError),
 }
 
 // key: domain ID
@@ -172,6 +184,8 @@ struct State {
     // RangeInclusive: (start endpoint PCI address .. =end endpoint PCI address)
     #[cfg_attr(windows, allow(dead_code))]
     hp_endpoints_ranges: Vec<RangeInclusive<u32>>,
+    // Domain IDs the driver may attach endpoints to
+    domain_range: RangeInclusive<u32>,
     // All PCI endpoints that attach to certain IOMMU domain
     // key: endpoint PCI address
     // value: attached domain ID
@@ -185,9 +199,44 @@ struct State {
     // Contains dmabuf regions
     // key: guest physical address
     dmabuf_mem: BTreeMap<u64, DmabufRegionEntry>,
+    // Faults waiting to be reported to the driver through the event queue
+    fault_tx: mpsc::Sender<virtio_iommu_fault>,
 }
 
 impl State {
+    // Report to the driver that an access from the given endpoint to the given address could
+    // not be translated. `access` holds the VIRTIO_IOMMU_FAULT_F_{READ,WRITE,EXEC} flags of the
+    // access, if known.
+    //
+    // DMA from emulated devices, which translates addresses through this device, faults here
+    // directly. VFIO endpoints are translated by the host IOMMU, so their faults are only known
+    // when the device was bound through iommufd and the host reports its I/O page faults.
+    #[cfg_attr(windows, allow(dead_code))]
+    fn report_fault(&mut self, endpoint: u32, address: u64, access: u32) {
+        // Endpoints not attached to a domain can't access anything, since
+        // VIRTIO_IOMMU_F_BYPASS is not offered.
+        let reason = if self.endpoint_map.contains_key(&endpoint) {
+            VIRTIO_IOMMU_FAULT_R_MAPPING
+        } else {
+            VIRTIO_IOMMU_FAULT_R_DOMAIN
+        };
+        warn!(
+            "vIOMMU: translation fault on endpoint {:#x} at iova {:#x} (reason {})",
+            endpoint, address, reason
+        );
+
+        let fault = virtio_iommu_fault {
+            reason: reason as u8,
+            flags: Le32::from(VIRTIO_IOMMU_FAULT_F_ADDRESS | access),
+            endpoint: endpoint.into(),
+            address: address.into(),
+            ..Default::default()
+        };
+        if self.fault_tx.try_send(fault).is_err() {
+            debug!("vIOMMU: event queue full, dropping fault");
+        }
+    }
+
     // Detach the given endpoint if possible, and return whether or not the endpoint
     // was actually detached. If a successfully detached endpoint has exported
     // memory, returns an event that will be signaled once all exported memory is released.
@@ -265,6 +314,13 @@ impl State {
         let domain: u32 = req.domain.into();
         let endpoint: u32 = req.endpoint.into();
 
+        // If the domain field is outside domain_range, the device MUST reject
+        // the request and set status to VIRTIO_IOMMU_S_RANGE.
+        if !self.domain_range.contains(&domain) {
+            tail.status = VIRTIO_IOMMU_S_RANGE;
+            return Ok((0, None));
+        }
+
         if let Some(mapper) = self.endpoints.get(&endpoint) {
             // The same mapper can't be used for two domains at the same time,
             // since that would result in conflicts/permission leaks between
@@ -632,14 +688,44 @@ async fn request_queue(
     }
 }
 
+// Writes each fault reported by the device to a buffer of the event queue.
+async fn event_queue(
+    mut faults: mpsc::Receiver<virtio_iommu_fault>,
+    mut queue: Queue,
+    mut queue_event: EventAsync,
+    interrupt: Interrupt,
+) -> Result<()> {
+    while let Some(fault) = faults.next().await {
+        let mut avail_desc = queue
+            .next_async(&mut queue_event)
+            .await
+            .map_err(IommuError::ReadAsyncDesc)?;
+
+        let len = match avail_desc.writer.write_all(fault.as_bytes()) {
+            Ok(()) => size_of::<virtio_iommu_fault>(),
+            Err(e) => {
+                error!("failed to write fault event: {}", e);
+                0
+            }
+        };
+
+        queue.add_used(avail_desc, len as u32);
+        queue.trigger_interrupt(&interrupt);
+    }
+
+    Ok(())
+}
+
 fn run(
     state: State,
+    fault_rx: mpsc::Receiver<virtio_iommu_fault>,
     iommu_device_tube: Tube,
     mut queues: BTreeMap<usize, Queue>,
     kill_evt: Event,
     interrupt: Interrupt,
     translate_response_senders: Option<BTreeMap<u32, Tube>>,
     translate_request_rx: Option<Tube>,
+    fault_queues: BTreeMap<u32, File>,
 ) -> Result<()> {
     let state = Rc::new(RefCell::new(state));
     let ex = Executor::new().expect("Failed to create an executor");
@@ -651,6 +737,14 @@ fn run(
         .expect("Failed to clone queue event");
     let req_evt = EventAsync::new(req_evt, &ex).expect("Failed to create async event for queue");
 
+    let evt_queue = queues.remove(&1).unwrap();
+    let event_evt = evt_queue
+        .event()
+        .try_clone()
+        .expect("Failed to clone queue event");
+    let event_evt =
+        EventAsync::new(event_evt, &ex).expect("Failed to create async event for queue");
+
     let f_resample = async_utils::handle_irq_resample(&ex, interrupt.clone());
     let f_kill = async_utils::await_and_exit(&ex, kill_evt);
 
@@ -669,7 +763,9 @@ fn run(
 
     let f_handle_translate_request =
         sys::handle_translate_request(&ex, &state, request_tube, response_tubes);
-    let f_request = request_queue(&state, req_queue, req_evt, interrupt);
+    let f_request = request_queue(&state, req_queue, req_evt, interrupt.clone());
+    let f_event = event_queue(fault_rx, evt_queue, event_evt, interrupt);
+    let f_fault_queues = sys::handle_fault_queues(&ex, &state, fault_queues);
 
     let command_tube = AsyncTube::new(&ex, iommu_device_tube).unwrap();
     // Future to handle command messages from host, such as passing vfio containers.
@@ -678,6 +774,8 @@ fn run(
     let done = async {
         select! {
             res = f_request.fuse() => res.context("error in handling request queue"),
+            res = f_event.fuse() => res.context("error in handling event queue"),
+            res = f_fault_queues.fuse() => res.context("error in handling fault queues"),
             res = f_resample.fuse() => res.context("error in handle_irq_resample"),
             res = f_kill.fuse() => res.context("error in await_and_exit"),
             res = f_handle_translate_request.fuse() => {
@@ -707,18 +805,27 @@ pub struct Iommu {
     // Hot-pluggable PCI endpoints ranges
     // RangeInclusive: (start endpoint PCI address .. =end endpoint PCI address)
     hp_endpoints_ranges: Vec<RangeInclusive<u32>>,
+    domain_range: RangeInclusive<u32>,
     translate_response_senders: Option<BTreeMap<u32, Tube>>,
     translate_request_rx: Option<Tube>,
     iommu_device_tube: Option<Tube>,
+    // I/O page fault queues of VFIO endpoints
+    // key: endpoint PCI address
+    fault_queues: BTreeMap<u32, File>,
 }
 
 impl Iommu {
     /// Create a new virtio IOMMU device.
+    ///
+    /// The driver may only attach endpoints to domains whose ID is in `domain_range`. The faults
+    /// read from `fault_queues`, see `VfioDevice::take_fault_queue`, are reported to the driver.
     pub fn new(
         base_features: u64,
         endpoints: BTreeMap<u32, Arc<Mutex<Box<dyn MemoryMapperTrait>>>>,
+        fault_queues: BTreeMap<u32, File>,
         iova_max_addr: u64,
         hp_endpoints_ranges: Vec<RangeInclusive<u32>>,
+        domain_range: RangeInclusive<u32>,
         translate_response_senders: Option<BTreeMap<u32, Tube>>,
         translate_request_rx: Option<Tube>,
         iommu_device_tube: Option<Tube>,
@@ -740,9 +847,17 @@ impl Iommu {
             end: iova_max_addr.into(),
         };
 
+        if domain_range.is_empty() {
+            return Err(SysError::new(libc::EINVAL));
+        }
+
         let config = virtio_iommu_config {
             page_size_mask: page_size_mask.into(),
             input_range,
+            domain_range: virtio_iommu_range_32 {
+                start: (*domain_range.start()).into(),
+                end: (*domain_range.end()).into(),
+            },
             #[cfg(target_arch = "x86_64")]
             probe_size: (IOMMU_PROBE_SIZE as u32).into(),
             ..Default::default()
@@ -751,6 +866,7 @@ impl Iommu {
         let mut avail_features: u64 = base_features;
         avail_features |= 1 << VIRTIO_IOMMU_F_MAP_UNMAP
             | 1 << VIRTIO_IOMMU_F_INPUT_RANGE
+            | 1 << VIRTIO_IOMMU_F_DOMAIN_RANGE
             | 1 << VIRTIO_IOMMU_F_MMIO;
 
         if cfg!(target_arch = "x86_64") {
@@ -763,9 +879,11 @@ impl Iommu {
             avail_features,
             endpoints,
             hp_endpoints_ranges,
+            domain_range,
             translate_response_senders,
             translate_request_rx,
             iommu_device_tube,
+            fault_queues,
         })
     }
 }
@@ -790,6 +908,10 @@ impl VirtioDevice for Iommu {
             rds.push(iommu_device_tube.as_raw_descriptor());
         }
 
+        for (_, fault_queue) in self.fault_queues.iter() {
+            rds.push(fault_queue.as_raw_descriptor());
+        }
+
         rds
     }
 
@@ -830,9 +952,11 @@ impl VirtioDevice for Iommu {
         let page_mask = (1u64 << u64::from(self.config.page_size_mask).trailing_zeros()) - 1;
         let eps = self.endpoints.clone();
         let hp_endpoints_ranges = self.hp_endpoints_ranges.to_owned();
+        let domain_range = self.domain_range.clone();
 
         let translate_response_senders = self.translate_response_senders.take();
         let translate_request_rx = self.translate_request_rx.take();
+        let fault_queues = std::mem::take(&mut self.fault_queues);
 
         let iommu_device_tube = self
             .iommu_device_tube
@@ -840,23 +964,28 @@ impl VirtioDevice for Iommu {
             .context("failed to start virtio-iommu worker: No control tube")?;
 
         self.worker_thread = Some(WorkerThread::start("v_iommu", move |kill_evt| {
+            let (fault_tx, fault_rx) = mpsc::channel(MAX_PENDING_FAULTS);
             let state = State {
                 mem,
                 page_mask,
                 hp_endpoints_ranges,
+                domain_range,
                 endpoint_map: BTreeMap::new(),
                 domain_map: BTreeMap::new(),
                 endpoints: eps,
                 dmabuf_mem: BTreeMap::new(),
+                fault_tx,
             };
             let result = run(
                 state,
+                fault_rx,
                 iommu_device_tube,
                 queues,
                 kill_evt,
                 interrupt,
                 translate_response_senders,
                 translate_request_rx,
+                fault_queues,
             );
             if let Err(e) = result {
                 error!("virtio-iommu worker thread exited with error: {}", e);
@@ -941,3 +1070,250 @@ impl VirtioDevice for Iommu {
         Some(sdts)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use data_model::Le16;
+
+    use super::*;
+    use crate::virtio::create_descriptor_chain;
+    use crate::virtio::DescriptorType;
+    use crate::virtio::QueueConfig;
+
+    const ENDPOINT: u32 = 8;
+
+    // Layout of the guest memory used by the tests.
+    const DESC_TABLE: GuestAddress = GuestAddress(0x0);
+    const AVAIL_RING: GuestAddress = GuestAddress(0x1000);
+    const USED_RING: GuestAddress = GuestAddress(0x2000);
+    const BUFFER: GuestAddress = GuestAddress(0x3000);
+
+    fn new_state(domain_range: RangeInclusive<u32>) -> (State, mpsc::Receiver<virtio_iommu_fault>) {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mapper: Box<dyn MemoryMapperTrait> = Box::new(BasicMemoryMapper::new(u64::MAX));
+        let (fault_tx, fault_rx) = mpsc::channel(MAX_PENDING_FAULTS);
+        let state = State {
+            mem,
+            page_mask: pagesize() as u64 - 1,
+            hp_endpoints_ranges: Vec::new(),
+            domain_range,
+            endpoint_map: BTreeMap::new(),
+            domain_map: BTreeMap::new(),
+            endpoints: BTreeMap::from([(ENDPOINT, Arc::new(Mutex::new(mapper)))]),
+            dmabuf_mem: BTreeMap::new(),
+            fault_tx,
+        };
+        (state, fault_rx)
+    }
+
+    // Sends an ATTACH request for `ENDPOINT` and returns the status of the reply.
+    fn attach(state: &mut State, domain: u32) -> u8 {
+        let req = virtio_iommu_req_attach {
+            domain: domain.into(),
+            endpoint: ENDPOINT.into(),
+            ..Default::default()
+        };
+        state.mem.write_obj_at_addr(req, BUFFER).unwrap();
+        let mut chain = create_descriptor_chain(
+            &state.mem,
+            DESC_TABLE,
+            BUFFER,
+            vec![(
+                DescriptorType::Readable,
+                size_of::<virtio_iommu_req_attach>() as u32,
+            )],
+            0,
+        )
+        .unwrap();
+
+        let mut tail = virtio_iommu_req_tail::default();
+        state
+            .process_attach_request(&mut chain.reader, &mut tail)
+            .unwrap();
+        tail.status
+    }
+
+    fn next_fault(faults: &mut mpsc::Receiver<virtio_iommu_fault>) -> (u8, u32, u64) {
+        let fault = faults.try_next().unwrap().unwrap();
+        (
+            fault.reason,
+            u32::from(fault.endpoint),
+            u64::from(fault.address),
+        )
+    }
+
+    #[test]
+    fn attach_domain_range() {
+        let (mut state, _faults) = new_state(1..=10);
+
+        assert_eq!(attach(&mut state, 0), VIRTIO_IOMMU_S_RANGE);
+        assert_eq!(attach(&mut state, 11), VIRTIO_IOMMU_S_RANGE);
+        assert!(state.endpoint_map.is_empty());
+        assert!(state.domain_map.is_empty());
+
+        assert_eq!(attach(&mut state, 10), VIRTIO_IOMMU_S_OK);
+        assert_eq!(state.endpoint_map.get(&ENDPOINT), Some(&10));
+
+        // A rejected request leaves the current attachment alone.
+        assert_eq!(attach(&mut state, u32::MAX), VIRTIO_IOMMU_S_RANGE);
+        assert_eq!(state.endpoint_map.get(&ENDPOINT), Some(&10));
+    }
+
+    #[test]
+    fn report_fault() {
+        let (mut state, mut faults) = new_state(0..=u32::MAX);
+
+        state.report_fault(ENDPOINT, 0x1000, 0);
+        assert_eq!(
+            next_fault(&mut faults),
+            (VIRTIO_IOMMU_FAULT_R_DOMAIN as u8, ENDPOINT, 0x1000)
+        );
+
+        assert_eq!(attach(&mut state, 1), VIRTIO_IOMMU_S_OK);
+        state.report_fault(ENDPOINT, 0x2000, 0);
+        assert_eq!(
+            next_fault(&mut faults),
+            (VIRTIO_IOMMU_FAULT_R_MAPPING as u8, ENDPOINT, 0x2000)
+        );
+        assert!(faults.try_next().is_err());
+
+        // Faults are dropped once the driver is too far behind.
+        for i in 0..2 * MAX_PENDING_FAULTS as u64 {
+            state.report_fault(ENDPOINT, i << 12, 0);
+        }
+        let mut pending = 0;
+        while let Ok(Some(_)) = faults.try_next() {
+            pending += 1;
+        }
+        assert!(pending >= MAX_PENDING_FAULTS);
+        assert!(pending < 2 * MAX_PENDING_FAULTS);
+    }
+
+    #[test]
+    fn event_queue_delivery() {
+        let (mut state, faults) = new_state(0..=u32::MAX);
+
+        // Make a single buffer available on the event queue.
+        create_descriptor_chain(
+            &state.mem,
+            DESC_TABLE,
+            BUFFER,
+            vec![(
+                DescriptorType::Writable,
+                size_of::<virtio_iommu_fault>() as u32,
+            )],
+            0,
+        )
+        .unwrap();
+        // Avail ring: flags, idx, ring[0].
+        state
+            .mem
+            .write_obj_at_addr(Le16::from(1u16), AVAIL_RING.unchecked_add(2))
+            .unwrap();
+        state
+            .mem
+            .write_obj_at_addr(Le16::from(0u16), AVAIL_RING.unchecked_add(4))
+            .unwrap();
+
+        let mut queue = QueueConfig::new(16, 0);
+        queue.set_desc_table(DESC_TABLE);
+        queue.set_avail_ring(AVAIL_RING);
+        queue.set_used_ring(USED_RING);
+        queue.set_ready(true);
+        let queue = queue.activate(&state.mem, Event::new().unwrap()).unwrap();
+
+        let ex = Executor::new().unwrap();
+        let queue_event = EventAsync::new(queue.event().try_clone().unwrap(), &ex).unwrap();
+
+        state.report_fault(ENDPOINT, 0x5000, 0);
+        let mem = state.mem.clone();
+        // The worker stops once the device state, and with it the sender of faults, is gone.
+        drop(state);
+        ex.run_until(event_queue(
+            faults,
+            queue,
+            queue_event,
+            Interrupt::new_for_test(),
+        ))
+        .unwrap()
+        .unwrap();
+
+        // Used ring: flags, idx, then id and length of each element.
+        let used_idx: Le16 = mem.read_obj_from_addr(USED_RING.unchecked_add(2)).unwrap();
+        let used_len: Le32 = mem.read_obj_from_addr(USED_RING.unchecked_add(8)).unwrap();
+        assert_eq!(u16::from(used_idx), 1);
+        assert_eq!(
+            u32::from(used_len) as usize,
+            size_of::<virtio_iommu_fault>()
+        );
+
+        let fault: virtio_iommu_fault = mem.read_obj_from_addr(BUFFER).unwrap();
+        assert_eq!(fault.reason, VIRTIO_IOMMU_FAULT_R_DOMAIN as u8);
+        assert_eq!(u32::from(fault.flags), VIRTIO_IOMMU_FAULT_F_ADDRESS);
+        assert_eq!(u32::from(fault.endpoint), ENDPOINT);
+        assert_eq!(u64::from(fault.address), 0x5000);
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    #[test]
+    fn fault_queue_delivery() {
+        use std::io::Read;
+        use std::net::Shutdown;
+        use std::os::fd::OwnedFd;
+        use std::os::unix::net::UnixStream;
+
+        use vfio_sys::iommufd::*;
+
+        let (state, mut faults) = new_state(0..=u32::MAX);
+        let state = Rc::new(RefCell::new(state));
+        let (mut host, fault_queue) = UnixStream::pair().unwrap();
+
+        // A group of two faults, which is answered once.
+        for (flags, addr) in [(0, 0x1000), (IOMMU_PGFAULT_FLAGS_LAST_PAGE, 0x2000)] {
+            let fault = iommu_hwpt_pgfault {
+                flags,
+                perm: IOMMU_PGFAULT_PERM_READ | IOMMU_PGFAULT_PERM_WRITE,
+                addr,
+                cookie: 7,
+                ..Default::default()
+            };
+            host.write_all(fault.as_bytes()).unwrap();
+        }
+        // The queue ends once the faults are read.
+        host.shutdown(Shutdown::Write).unwrap();
+
+        let ex = Executor::new().unwrap();
+        let fault_queue = ex
+            .async_from(File::from(OwnedFd::from(fault_queue)))
+            .unwrap();
+        ex.run_until(sys::linux::handle_fault_queue(
+            &state,
+            ENDPOINT,
+            fault_queue,
+        ))
+        .unwrap()
+        .unwrap();
+
+        for address in [0x1000, 0x2000] {
+            let fault = faults.try_next().unwrap().unwrap();
+            assert_eq!(fault.reason, VIRTIO_IOMMU_FAULT_R_DOMAIN as u8);
+            assert_eq!(
+                u32::from(fault.flags),
+                VIRTIO_IOMMU_FAULT_F_ADDRESS
+                    | VIRTIO_IOMMU_FAULT_F_READ
+                    | VIRTIO_IOMMU_FAULT_F_WRITE
+            );
+            assert_eq!(u32::from(fault.endpoint), ENDPOINT);
+            assert_eq!(u64::from(fault.address), address);
+        }
+        assert!(faults.try_next().is_err());
+
+        let mut responses = Vec::new();
+        host.read_to_end(&mut responses).unwrap();
+        let response = iommu_hwpt_page_response {
+            cookie: 7,
+            code: IOMMUFD_PAGE_RESP_INVALID,
+        };
+        assert_eq!(responses, response.as_bytes());
+    }
+}
@@ -168,7 +168,7 @@ pub struct virtio_iommu_req_probe {
 }
 
 #[repr(C, packed)]
-#[derive(Debug, Default, Copy, Clone)]
+#[derive(Debug, Default, Copy, Clone, AsBytes, FromZeroes, FromBytes)]
 pub struct virtio_iommu_fault {
     pub reason: u8,
     pub reserved: [u8; 3usize],
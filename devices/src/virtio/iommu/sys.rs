@@ -13,4 +13,5 @@ cfg_if::cfg_if! {
 }
 
 pub(in crate::virtio::iommu) use platform::handle_command_tube;
+pub(in crate::virtio::iommu) use platform::handle_fault_queues;
 pub(in crate::virtio::iommu) use platform::handle_translate_request;
@@ -7,6 +7,7 @@ pub mod vfio_wrapper;
 use std::cell::RefCell;
 use std::collections::BTreeMap;
 use std::fs::File;
+use std::mem::size_of;
 use std::rc::Rc;
 use std::sync::Arc;
 
@@ -15,22 +16,38 @@ use base::MemoryMappingBuilder;
 use base::TubeError;
 use cros_async::AsyncTube;
 use cros_async::Executor;
+use cros_async::IoSource;
 use sync::Mutex;
+use vfio_sys::iommufd::iommu_hwpt_page_response;
+use vfio_sys::iommufd::iommu_hwpt_pgfault;
+use vfio_sys::iommufd::IOMMUFD_PAGE_RESP_INVALID;
+use vfio_sys::iommufd::IOMMU_PGFAULT_FLAGS_LAST_PAGE;
+use vfio_sys::iommufd::IOMMU_PGFAULT_PERM_EXEC;
+use vfio_sys::iommufd::IOMMU_PGFAULT_PERM_READ;
+use vfio_sys::iommufd::IOMMU_PGFAULT_PERM_WRITE;
 use vm_control::VirtioIOMMURequest;
 use vm_control::VirtioIOMMUResponse;
 use vm_control::VirtioIOMMUVfioCommand;
 use vm_control::VirtioIOMMUVfioResult;
 use vm_control::VmMemoryRegionId;
+use zerocopy::AsBytes;
+use zerocopy::FromBytes;
 
 use self::vfio_wrapper::VfioWrapper;
 use crate::virtio::iommu::ipc_memory_mapper::IommuRequest;
 use crate::virtio::iommu::ipc_memory_mapper::IommuResponse;
+use crate::virtio::iommu::protocol::VIRTIO_IOMMU_FAULT_F_EXEC;
+use crate::virtio::iommu::protocol::VIRTIO_IOMMU_FAULT_F_READ;
+use crate::virtio::iommu::protocol::VIRTIO_IOMMU_FAULT_F_WRITE;
 use crate::virtio::iommu::DmabufRegionEntry;
 use crate::virtio::iommu::Result;
 use crate::virtio::iommu::State;
 use crate::virtio::IommuError;
 use crate::VfioContainer;
 
+// Number of page faults read from a fault queue at once.
+const FAULTS_PER_READ: usize = 16;
+
 impl State {
     pub(in crate::virtio::iommu) fn handle_add_vfio_device(
         &mut self,
@@ -176,6 +193,82 @@ pub(in crate::virtio::iommu) async fn handle_command_tube(
     }
 }
 
+// Converts the access of an iommufd page fault to the flags of a virtio-iommu fault.
+fn fault_access(perm: u32) -> u32 {
+    [
+        (IOMMU_PGFAULT_PERM_READ, VIRTIO_IOMMU_FAULT_F_READ),
+        (IOMMU_PGFAULT_PERM_WRITE, VIRTIO_IOMMU_FAULT_F_WRITE),
+        (IOMMU_PGFAULT_PERM_EXEC, VIRTIO_IOMMU_FAULT_F_EXEC),
+    ]
+    .iter()
+    .filter(|(pgfault_perm, _)| perm & pgfault_perm != 0)
+    .fold(0, |access, (_, flag)| access | flag)
+}
+
+// Reports the page faults read from the fault queue of a VFIO endpoint to the driver, until the
+// queue is closed. Nothing maps the faulting addresses on the driver's behalf, so each fault group
+// is then failed.
+pub(in crate::virtio::iommu) async fn handle_fault_queue(
+    state: &Rc<RefCell<State>>,
+    endpoint: u32,
+    fault_queue: IoSource<File>,
+) -> Result<()> {
+    let mut buf = vec![0u8; size_of::<iommu_hwpt_pgfault>() * FAULTS_PER_READ];
+    loop {
+        let (len, read_buf) = fault_queue
+            .read_to_vec(None, buf)
+            .await
+            .map_err(IommuError::ReadFaultQueue)?;
+        buf = read_buf;
+        if len == 0 {
+            return Ok(());
+        }
+
+        for fault in buf[..len]
+            .chunks_exact(size_of::<iommu_hwpt_pgfault>())
+            .filter_map(iommu_hwpt_pgfault::read_from)
+        {
+            state
+                .borrow_mut()
+                .report_fault(endpoint, fault.addr, fault_access(fault.perm));
+
+            // The faults of a group are delivered together, and answered once with the cookie of
+            // the last one.
+            if fault.flags & IOMMU_PGFAULT_FLAGS_LAST_PAGE != 0 {
+                let response = iommu_hwpt_page_response {
+                    cookie: fault.cookie,
+                    code: IOMMUFD_PAGE_RESP_INVALID,
+                };
+                fault_queue
+                    .write_from_vec(None, response.as_bytes().to_vec())
+                    .await
+                    .map_err(IommuError::WriteFaultQueue)?;
+            }
+        }
+    }
+}
+
+pub(in crate::virtio::iommu) async fn handle_fault_queues(
+    ex: &Executor,
+    state: &Rc<RefCell<State>>,
+    fault_queues: BTreeMap<u32, File>,
+) -> Result<()> {
+    let handlers = fault_queues
+        .into_iter()
+        .map(|(endpoint, fault_queue)| {
+            let fault_queue = ex
+                .async_from(fault_queue)
+                .map_err(IommuError::CreateAsyncFaultQueue)?;
+            Ok(handle_fault_queue(state, endpoint, fault_queue))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    futures::future::try_join_all(handlers).await?;
+
+    // The fault queues live as long as the VFIO devices, so there's nothing left to report.
+    futures::future::pending::<()>().await;
+    Ok(())
+}
+
 pub(in crate::virtio::iommu) async fn handle_translate_request(
     ex: &Executor,
     state: &Rc<RefCell<State>>,
@@ -203,7 +296,12 @@ pub(in crate::virtio::iommu) async fn handle_translate_request(
                 return Err(IommuError::Tube(e));
             }
         };
-        let resp = if let Some(mapper) = state.borrow().endpoints.get(&req.get_endpoint_id()) {
+        let endpoint = req.get_endpoint_id();
+        let export_iova = match req {
+            IommuRequest::Export { iova, .. } => Some(iova),
+            _ => None,
+        };
+        let resp = if let Some(mapper) = state.borrow().endpoints.get(&endpoint) {
             match req {
                 IommuRequest::Export { iova, size, .. } => {
                     mapper.lock().export(iova, size).map(IommuResponse::Export)
@@ -223,7 +321,14 @@ pub(in crate::virtio::iommu) async fn handle_translate_request(
         };
         let resp: IommuResponse = match resp {
             Ok(resp) => resp,
-            Err(e) => IommuResponse::Err(format!("{:?}", e)),
+            Err(e) => {
+                // A failed export is a DMA from an emulated device to an address the driver
+                // didn't map for it.
+                if let Some(iova) = export_iova {
+                    state.borrow_mut().report_fault(endpoint, iova, 0);
+                }
+                IommuResponse::Err(format!("{:?}", e))
+            }
         };
         response_tubes
             .get(&req.get_endpoint_id())
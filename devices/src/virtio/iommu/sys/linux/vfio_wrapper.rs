@@ -4,6 +4,7 @@
 
 //! Wraps VfioContainer for virtio-iommu implementation
 
+use std::fs::File;
 use std::sync::Arc;
 
 use anyhow::Context;
@@ -28,6 +29,8 @@ pub struct VfioWrapper {
     // the fact that no container contains multiple groups.
     id: u32,
     mem: GuestMemory,
+    // I/O page fault queue of the endpoint, until it is handed to the virtio-iommu device.
+    fault_queue: Option<File>,
 }
 
 impl VfioWrapper {
@@ -38,7 +41,12 @@ impl VfioWrapper {
         assert!(groups.len() == 1);
         let id = *groups[0];
         drop(c);
-        Self { container, id, mem }
+        Self {
+            container,
+            id,
+            mem,
+            fault_queue: None,
+        }
     }
 
     pub fn new_with_id(container: VfioContainer, id: u32, mem: GuestMemory) -> Self {
@@ -46,9 +54,31 @@ impl VfioWrapper {
             container: Arc::new(Mutex::new(container)),
             id,
             mem,
+            fault_queue: None,
+        }
+    }
+
+    /// Wraps the iommufd backed container of a single device, see
+    /// `VfioDevice::new_passthrough_iommufd`. `id` is the IOMMU group of the device.
+    pub fn new_iommufd(
+        container: Arc<Mutex<VfioContainer>>,
+        id: u32,
+        fault_queue: Option<File>,
+        mem: GuestMemory,
+    ) -> Self {
+        Self {
+            container,
+            id,
+            mem,
+            fault_queue,
         }
     }
 
+    /// Takes the I/O page fault queue of the device, see `VfioDevice::take_fault_queue`.
+    pub fn take_fault_queue(&mut self) -> Option<File> {
+        self.fault_queue.take()
+    }
+
     pub fn clone_as_raw_descriptor(&self) -> Result<RawDescriptor, VfioError> {
         self.container.lock().clone_as_raw_descriptor()
     }
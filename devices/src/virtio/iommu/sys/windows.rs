@@ -4,6 +4,7 @@
 
 use std::cell::RefCell;
 use std::collections::BTreeMap;
+use std::fs::File;
 use std::rc::Rc;
 
 use cros_async::AsyncTube;
@@ -19,6 +20,14 @@ pub(in crate::virtio::iommu) async fn handle_command_tube(
     panic!("IOMMU is not supported on Windows");
 }
 
+pub(in crate::virtio::iommu) async fn handle_fault_queues(
+    _ex: &Executor,
+    _state: &Rc<RefCell<State>>,
+    _fault_queues: BTreeMap<u32, File>,
+) -> Result<()> {
+    panic!("IOMMU is not supported on Windows");
+}
+
 pub(in crate::virtio::iommu) async fn handle_translate_request(
     _ex: &Executor,
     _state: &Rc<RefCell<State>>,
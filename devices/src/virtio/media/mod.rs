@@ -0,0 +1,1073 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! virtio-media device, exposing V4L2 video devices to the guest.
+//!
+//! The guest driver forwards the V4L2 ioctls performed by its userspace to the device through the
+//! command queue. Each open file of the guest corresponds to a session of the backend, and buffers
+//! are mapped into the guest through the device's shared memory region. Dequeued buffers and V4L2
+//! events are sent to the guest through the event queue.
+
+mod protocol;
+mod proxy;
+mod test_pattern;
+mod v4l2;
+
+use std::collections::BTreeMap;
+use std::collections::VecDeque;
+use std::io::Read;
+use std::io::Write;
+use std::path::PathBuf;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context;
+use base::error;
+use base::pagesize;
+use base::round_up_to_page_size;
+use base::warn;
+use base::AsRawDescriptor;
+use base::Error as SysError;
+use base::Event;
+use base::EventToken;
+use base::Protection;
+use base::RawDescriptor;
+use base::Result as SysResult;
+use base::SafeDescriptor;
+use base::WaitContext;
+use base::WorkerThread;
+use data_model::Le32;
+use data_model::Le64;
+use hypervisor::MemCacheType;
+use resources::address_allocator::AddressAllocator;
+use resources::AddressRange;
+use resources::Alloc;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_keyvalue::FromKeyValues;
+use vm_control::VmMemorySource;
+use vm_memory::GuestMemory;
+use zerocopy::AsBytes;
+
+use self::protocol::*;
+use self::proxy::ProxyBackend;
+use self::test_pattern::TestPatternBackend;
+use self::v4l2::v4l2_buffer;
+use self::v4l2::v4l2_event;
+use self::v4l2::v4l2_plane;
+use self::v4l2::VIDEO_MAX_PLANES;
+use super::copy_config;
+use super::DeviceType;
+use super::Interrupt;
+use super::Queue;
+use super::Reader;
+use super::SharedMemoryMapper;
+use super::SharedMemoryRegion;
+use super::VirtioDevice;
+use super::Writer;
+
+const QUEUE_SIZE: u16 = 256;
+const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE, QUEUE_SIZE];
+
+/// Size of the shared memory region buffers are mapped into.
+const MEDIA_SHMEM_SIZE: u64 = 1 << 32;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MediaBackendType {
+    /// Proxy a V4L2 device of the host.
+    Proxy,
+    /// Emulated camera producing a test pattern.
+    TestPattern,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize, FromKeyValues)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct MediaDeviceConfig {
+    #[serde(rename = "type")]
+    pub backend: MediaBackendType,
+    /// Path to the host V4L2 device node, for the `proxy` backend.
+    #[serde(default)]
+    pub device: Option<PathBuf>,
+}
+
+/// A provider of V4L2 sessions.
+pub trait MediaBackend: Send {
+    /// V4L2 capabilities of the device, as reported by `VIDIOC_QUERYCAP` in `device_caps`.
+    fn device_caps(&self) -> u32;
+    /// Name of the device.
+    fn card(&self) -> &str;
+    /// Opens a new session, the equivalent of opening the device node.
+    fn open(&mut self) -> SysResult<Box<dyn MediaSession>>;
+}
+
+/// Event produced asynchronously by a session.
+pub enum SessionEvent {
+    /// A buffer has been dequeued, along with its planes for multi-planar buffer types.
+    Dqbuf(v4l2_buffer, Vec<v4l2_plane>),
+    /// A V4L2 event the session has subscribed to has been raised.
+    Event(v4l2_event),
+    /// The session encountered an unrecoverable error.
+    Error(i32),
+}
+
+/// An open V4L2 session, the equivalent of an open file of the device node.
+pub trait MediaSession: Send {
+    /// Performs the V4L2 ioctl `code` with argument `payload`, and returns the updated argument.
+    ///
+    /// For ioctls taking a `v4l2_buffer` or `v4l2_ext_controls`, the payload is followed by the
+    /// planes or controls it refers to.
+    fn ioctl(&mut self, code: u32, payload: &[u8]) -> SysResult<Vec<u8>>;
+    /// Returns the descriptor, offset and size to map for the buffer at `offset`, as reported by
+    /// `VIDIOC_QUERYBUF`.
+    fn mmap(&mut self, offset: u32, rw: bool) -> SysResult<(SafeDescriptor, u64, u64)>;
+    /// Returns a descriptor that becomes readable when `process_events` has events to report.
+    fn event_descriptor(&self) -> Option<&dyn AsRawDescriptor>;
+    /// Returns the events that occurred on this session since the last call.
+    fn process_events(&mut self) -> Vec<SessionEvent>;
+}
+
+/// Creates the backend described by `config`.
+fn create_backend(config: &MediaDeviceConfig) -> anyhow::Result<Box<dyn MediaBackend>> {
+    Ok(match config.backend {
+        MediaBackendType::Proxy => {
+            let device = config
+                .device
+                .as_ref()
+                .context("the proxy backend requires a `device` path")?;
+            Box::new(ProxyBackend::new(device)?)
+        }
+        MediaBackendType::TestPattern => {
+            if config.device.is_some() {
+                bail!("the test-pattern backend does not take a `device` path");
+            }
+            Box::new(TestPatternBackend)
+        }
+    })
+}
+
+/// A buffer mapped into the shared memory region.
+struct Mapping {
+    session_id: u32,
+    alloc: Alloc,
+}
+
+/// State of the device that survives the worker being stopped and restarted.
+struct DeviceState {
+    backend: Box<dyn MediaBackend>,
+    mapper: Box<dyn SharedMemoryMapper>,
+    sessions: BTreeMap<u32, Box<dyn MediaSession>>,
+    next_session_id: u32,
+    // Allocator for the shared memory region.
+    address_allocator: AddressAllocator,
+    // Buffers currently mapped, by offset within the shared memory region.
+    mappings: BTreeMap<u64, Mapping>,
+    next_alloc: usize,
+    // Events waiting for a descriptor of the event queue.
+    pending_events: VecDeque<Vec<u8>>,
+}
+
+impl DeviceState {
+    fn new(backend: Box<dyn MediaBackend>, mapper: Box<dyn SharedMemoryMapper>) -> Self {
+        Self {
+            backend,
+            mapper,
+            sessions: BTreeMap::new(),
+            next_session_id: 1,
+            address_allocator: AddressAllocator::new(
+                AddressRange::from_start_and_size(0, MEDIA_SHMEM_SIZE).unwrap(),
+                Some(pagesize() as u64),
+                None,
+            )
+            .expect("failed to create allocator"),
+            mappings: BTreeMap::new(),
+            next_alloc: 0,
+            pending_events: VecDeque::new(),
+        }
+    }
+
+    fn unmap(&mut self, guest_addr: u64) -> SysResult<()> {
+        let mapping = self
+            .mappings
+            .remove(&guest_addr)
+            .ok_or(SysError::new(libc::EINVAL))?;
+        if let Err(e) = self.mapper.remove_mapping(guest_addr) {
+            error!("failed to remove mapping at {:#x}: {:#}", guest_addr, e);
+        }
+        self.address_allocator
+            .release(mapping.alloc)
+            .expect("corrupt address space");
+        Ok(())
+    }
+
+    fn mmap(&mut self, cmd: virtio_media_cmd_mmap) -> SysResult<(u64, u64)> {
+        let session_id = cmd.session_id.to_native();
+        let rw = cmd.flags.to_native() & VIRTIO_MEDIA_MMAP_FLAG_RW != 0;
+        let session = self
+            .sessions
+            .get_mut(&session_id)
+            .ok_or(SysError::new(libc::EINVAL))?;
+        let (descriptor, offset, size) = session.mmap(cmd.offset.to_native(), rw)?;
+        let size = round_up_to_page_size(size as usize) as u64;
+
+        let alloc = Alloc::Anon(self.next_alloc);
+        self.next_alloc += 1;
+        let guest_addr = self
+            .address_allocator
+            .allocate(size, alloc, "virtio-media".to_owned())
+            .map_err(|_| SysError::new(libc::ENOMEM))?;
+
+        let source = VmMemorySource::Descriptor {
+            descriptor,
+            offset,
+            size,
+        };
+        let prot = if rw {
+            Protection::read_write()
+        } else {
+            Protection::read()
+        };
+        if let Err(e) =
+            self.mapper
+                .add_mapping(source, guest_addr, prot, MemCacheType::CacheCoherent)
+        {
+            error!("failed to map buffer: {:#}", e);
+            // We just allocated it ourselves, it must exist.
+            self.address_allocator
+                .release(alloc)
+                .expect("corrupt address space");
+            return Err(SysError::new(libc::ENOMEM));
+        }
+        self.mappings
+            .insert(guest_addr, Mapping { session_id, alloc });
+
+        Ok((guest_addr, size))
+    }
+
+    fn close_session(&mut self, session_id: u32) {
+        let guest_addrs: Vec<u64> = self
+            .mappings
+            .iter()
+            .filter(|(_, m)| m.session_id == session_id)
+            .map(|(&addr, _)| addr)
+            .collect();
+        for guest_addr in guest_addrs {
+            let _ = self.unmap(guest_addr);
+        }
+        self.sessions.remove(&session_id);
+    }
+
+    /// Closes all the sessions, as if the guest had closed all its files.
+    fn reset(&mut self) {
+        let session_ids: Vec<u32> = self.sessions.keys().copied().collect();
+        for session_id in session_ids {
+            self.close_session(session_id);
+        }
+        self.pending_events.clear();
+    }
+
+    fn queue_event(&mut self, session_id: u32, event: SessionEvent) {
+        let hdr = |event| virtio_media_event_header {
+            event: Le32::from(event),
+            session_id: Le32::from(session_id),
+        };
+        let bytes = match event {
+            SessionEvent::Dqbuf(buffer, planes) => {
+                let mut event = virtio_media_event_dqbuf {
+                    hdr: hdr(VIRTIO_MEDIA_EVT_DQBUF),
+                    buffer,
+                    ..Default::default()
+                };
+                let num_planes = planes.len().min(VIDEO_MAX_PLANES);
+                event.planes[..num_planes].copy_from_slice(&planes[..num_planes]);
+                event.as_bytes().to_vec()
+            }
+            SessionEvent::Event(event) => virtio_media_event_event {
+                hdr: hdr(VIRTIO_MEDIA_EVT_EVENT),
+                event,
+            }
+            .as_bytes()
+            .to_vec(),
+            SessionEvent::Error(errno) => virtio_media_event_error {
+                hdr: hdr(VIRTIO_MEDIA_EVT_ERROR),
+                errno: Le32::from(errno as u32),
+                __reserved: Le32::from(0),
+            }
+            .as_bytes()
+            .to_vec(),
+        };
+        self.pending_events.push_back(bytes);
+    }
+}
+
+#[derive(EventToken)]
+enum Token {
+    CmdQueue,
+    EventQueue,
+    Session { id: u32 },
+    InterruptResample,
+    Kill,
+}
+
+struct Worker {
+    interrupt: Interrupt,
+    cmd_queue: Queue,
+    event_queue: Queue,
+    state: DeviceState,
+}
+
+impl Worker {
+    /// Processes a single command, writing its response into `writer`.
+    fn process_cmd(
+        &mut self,
+        reader: &mut Reader,
+        writer: &mut Writer,
+        wait_ctx: &WaitContext<Token>,
+    ) -> anyhow::Result<()> {
+        let hdr: virtio_media_cmd_header = reader.read_obj().context("failed to read command")?;
+        let state = &mut self.state;
+
+        match hdr.cmd.to_native() {
+            VIRTIO_MEDIA_CMD_OPEN => {
+                let resp = match state.backend.open() {
+                    Ok(session) => {
+                        let session_id = state.next_session_id;
+                        state.next_session_id = state.next_session_id.wrapping_add(1).max(1);
+                        if let Some(descriptor) = session.event_descriptor() {
+                            wait_ctx
+                                .add(descriptor, Token::Session { id: session_id })
+                                .context("failed to add session to WaitContext")?;
+                        }
+                        state.sessions.insert(session_id, session);
+                        virtio_media_resp_open {
+                            hdr: virtio_media_resp_header::ok(),
+                            session_id: Le32::from(session_id),
+                            __reserved: Le32::from(0),
+                        }
+                    }
+                    Err(e) => virtio_media_resp_open {
+                        hdr: virtio_media_resp_header::err(e.errno()),
+                        ..Default::default()
+                    },
+                };
+                writer.write_obj(resp)?;
+            }
+            VIRTIO_MEDIA_CMD_CLOSE => {
+                let cmd: virtio_media_cmd_close = reader.read_obj()?;
+                let session_id = cmd.session_id.to_native();
+                if let Some(descriptor) = state
+                    .sessions
+                    .get(&session_id)
+                    .and_then(|s| s.event_descriptor())
+                {
+                    let _ = wait_ctx.delete(descriptor);
+                }
+                state.close_session(session_id);
+            }
+            VIRTIO_MEDIA_CMD_IOCTL => {
+                let cmd: virtio_media_cmd_ioctl = reader.read_obj()?;
+                let mut payload = vec![0u8; reader.available_bytes()];
+                reader.read_exact(&mut payload)?;
+                let result = match state.sessions.get_mut(&cmd.session_id.to_native()) {
+                    Some(session) => session.ioctl(cmd.code.to_native(), &payload),
+                    None => Err(SysError::new(libc::EINVAL)),
+                };
+                match result {
+                    Ok(payload) => {
+                        writer.write_obj(virtio_media_resp_header::ok())?;
+                        let len = payload.len().min(writer.available_bytes());
+                        writer.write_all(&payload[..len])?;
+                    }
+                    Err(e) => writer.write_obj(virtio_media_resp_header::err(e.errno()))?,
+                }
+            }
+            VIRTIO_MEDIA_CMD_MMAP => {
+                let cmd: virtio_media_cmd_mmap = reader.read_obj()?;
+                let resp = match state.mmap(cmd) {
+                    Ok((guest_addr, len)) => virtio_media_resp_mmap {
+                        hdr: virtio_media_resp_header::ok(),
+                        guest_addr: Le64::from(guest_addr),
+                        len: Le64::from(len),
+                    },
+                    Err(e) => virtio_media_resp_mmap {
+                        hdr: virtio_media_resp_header::err(e.errno()),
+                        ..Default::default()
+                    },
+                };
+                writer.write_obj(resp)?;
+            }
+            VIRTIO_MEDIA_CMD_MUNMAP => {
+                let cmd: virtio_media_cmd_munmap = reader.read_obj()?;
+                let resp = match state.unmap(cmd.guest_addr.to_native()) {
+                    Ok(()) => virtio_media_resp_header::ok(),
+                    Err(e) => virtio_media_resp_header::err(e.errno()),
+                };
+                writer.write_obj(resp)?;
+            }
+            cmd => {
+                warn!("unknown virtio-media command {}", cmd);
+                writer.write_obj(virtio_media_resp_header::err(libc::ENOTTY))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn process_cmd_queue(&mut self, wait_ctx: &WaitContext<Token>) {
+        let mut needs_interrupt = false;
+
+        while let Some(mut desc) = self.cmd_queue.pop() {
+            if let Err(e) = self.process_cmd(&mut desc.reader, &mut desc.writer, wait_ctx) {
+                error!("failed to process virtio-media command: {:#}", e);
+            }
+            let len = desc.writer.bytes_written() as u32;
+            self.cmd_queue.add_used(desc, len);
+            needs_interrupt = true;
+        }
+
+        if needs_interrupt {
+            self.cmd_queue.trigger_interrupt(&self.interrupt);
+        }
+    }
+
+    fn process_session(&mut self, session_id: u32, wait_ctx: &WaitContext<Token>) {
+        let Some(session) = self.state.sessions.get_mut(&session_id) else {
+            return;
+        };
+        let events = session.process_events();
+        // A session in error state will not produce further events.
+        if events.iter().any(|e| matches!(e, SessionEvent::Error(_))) {
+            if let Some(descriptor) = session.event_descriptor() {
+                let _ = wait_ctx.delete(descriptor);
+            }
+        }
+        for event in events {
+            self.state.queue_event(session_id, event);
+        }
+    }
+
+    /// Sends as many pending events as there are available descriptors in the event queue.
+    fn send_events(&mut self) {
+        let mut needs_interrupt = false;
+
+        while !self.state.pending_events.is_empty() {
+            let Some(mut desc) = self.event_queue.pop() else {
+                break;
+            };
+            // Unwrapping is safe because we checked the queue is not empty.
+            let event = self.state.pending_events.pop_front().unwrap();
+            if let Err(e) = desc.writer.write_all(&event) {
+                error!("failed to write virtio-media event: {}", e);
+            }
+            let len = desc.writer.bytes_written() as u32;
+            self.event_queue.add_used(desc, len);
+            needs_interrupt = true;
+        }
+
+        if needs_interrupt {
+            self.event_queue.trigger_interrupt(&self.interrupt);
+        }
+    }
+
+    fn run(&mut self, kill_evt: Event) -> anyhow::Result<()> {
+        let wait_ctx = WaitContext::build_with(&[
+            (self.cmd_queue.event(), Token::CmdQueue),
+            (self.event_queue.event(), Token::EventQueue),
+            (&kill_evt, Token::Kill),
+        ])
+        .context("failed creating WaitContext")?;
+
+        if let Some(resample_evt) = self.interrupt.get_resample_evt() {
+            wait_ctx
+                .add(resample_evt, Token::InterruptResample)
+                .context("failed adding resample event to WaitContext.")?;
+        }
+
+        // Sessions opened before the device went to sleep.
+        for (&id, session) in &self.state.sessions {
+            if let Some(descriptor) = session.event_descriptor() {
+                wait_ctx
+                    .add(descriptor, Token::Session { id })
+                    .context("failed adding session to WaitContext")?;
+            }
+        }
+
+        loop {
+            let events = wait_ctx.wait().context("failed polling for events")?;
+            for event in events.iter().filter(|e| e.is_readable) {
+                match event.token {
+                    Token::CmdQueue => {
+                        self.cmd_queue
+                            .event()
+                            .wait()
+                            .context("failed reading command queue Event")?;
+                        self.process_cmd_queue(&wait_ctx);
+                    }
+                    Token::EventQueue => {
+                        self.event_queue
+                            .event()
+                            .wait()
+                            .context("failed reading event queue Event")?;
+                    }
+                    Token::Session { id } => self.process_session(id, &wait_ctx),
+                    Token::InterruptResample => {
+                        self.interrupt.interrupt_resample();
+                    }
+                    Token::Kill => return Ok(()),
+                }
+            }
+            self.send_events();
+        }
+    }
+}
+
+/// Virtio device exposing a V4L2 video device to the guest.
+pub struct MediaDevice {
+    base_features: u64,
+    config: virtio_media_config,
+    backend: Option<Box<dyn MediaBackend>>,
+    mapper: Option<Box<dyn SharedMemoryMapper>>,
+    state: Option<DeviceState>,
+    worker_thread: Option<WorkerThread<Worker>>,
+}
+
+impl MediaDevice {
+    /// Create a new virtio-media device using the backend described by `config`.
+    pub fn new(base_features: u64, config: &MediaDeviceConfig) -> anyhow::Result<Self> {
+        let backend = create_backend(config)?;
+
+        let mut card = [0u8; 32];
+        let name = backend.card().as_bytes();
+        let len = name.len().min(card.len() - 1);
+        card[..len].copy_from_slice(&name[..len]);
+
+        Ok(Self {
+            base_features,
+            config: virtio_media_config {
+                device_caps: Le32::from(backend.device_caps()),
+                device_type: Le32::from(VIRTIO_MEDIA_DEVICE_TYPE_VIDEO),
+                card,
+            },
+            backend: Some(backend),
+            mapper: None,
+            state: None,
+            worker_thread: None,
+        })
+    }
+
+    /// Stops the worker and gets the device state back from it.
+    fn stop_worker(&mut self) -> Option<(Queue, Queue)> {
+        let worker = self.worker_thread.take()?.stop();
+        self.state = Some(worker.state);
+        Some((worker.cmd_queue, worker.event_queue))
+    }
+}
+
+impl VirtioDevice for MediaDevice {
+    fn keep_rds(&self) -> Vec<RawDescriptor> {
+        let mut keep_rds = Vec::new();
+
+        if let Some(mapper) = &self.mapper {
+            if let Some(raw_descriptor) = mapper.as_raw_descriptor() {
+                keep_rds.push(raw_descriptor);
+            }
+        }
+        keep_rds
+    }
+
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Media
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        QUEUE_SIZES
+    }
+
+    fn features(&self) -> u64 {
+        self.base_features
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        copy_config(data, 0, self.config.as_bytes(), offset);
+    }
+
+    fn activate(
+        &mut self,
+        _mem: GuestMemory,
+        interrupt: Interrupt,
+        mut queues: BTreeMap<usize, Queue>,
+    ) -> anyhow::Result<()> {
+        if queues.len() != QUEUE_SIZES.len() {
+            return Err(anyhow!(
+                "expected {} queues, got {}",
+                QUEUE_SIZES.len(),
+                queues.len()
+            ));
+        }
+
+        let state = match self.state.take() {
+            Some(state) => state,
+            None => DeviceState::new(
+                self.backend.take().context("missing backend")?,
+                self.mapper.take().context("missing mapper")?,
+            ),
+        };
+        let cmd_queue = queues.pop_first().unwrap().1;
+        let event_queue = queues.pop_first().unwrap().1;
+
+        self.worker_thread = Some(WorkerThread::start("v_media", move |kill_evt| {
+            let mut worker = Worker {
+                interrupt,
+                cmd_queue,
+                event_queue,
+                state,
+            };
+            if let Err(e) = worker.run(kill_evt) {
+                error!("virtio-media worker thread failed: {:#}", e);
+            }
+            worker
+        }));
+
+        Ok(())
+    }
+
+    fn reset(&mut self) -> anyhow::Result<()> {
+        self.stop_worker();
+        if let Some(state) = self.state.as_mut() {
+            state.reset();
+        }
+        Ok(())
+    }
+
+    fn get_shared_memory_region(&self) -> Option<SharedMemoryRegion> {
+        Some(SharedMemoryRegion {
+            id: VIRTIO_MEDIA_SHM_MMAP,
+            length: MEDIA_SHMEM_SIZE,
+        })
+    }
+
+    fn set_shared_memory_mapper(&mut self, mapper: Box<dyn SharedMemoryMapper>) {
+        self.mapper = Some(mapper);
+    }
+
+    fn virtio_sleep(&mut self) -> anyhow::Result<Option<BTreeMap<usize, Queue>>> {
+        Ok(self
+            .stop_worker()
+            .map(|(cmd_queue, event_queue)| BTreeMap::from([(0, cmd_queue), (1, event_queue)])))
+    }
+
+    fn virtio_wake(
+        &mut self,
+        queues_state: Option<(GuestMemory, Interrupt, BTreeMap<usize, Queue>)>,
+    ) -> anyhow::Result<()> {
+        if let Some((mem, interrupt, queues)) = queues_state {
+            self.activate(mem, interrupt, queues)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::size_of;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use std::time::Instant;
+
+    use base::linux::MemoryMappingBuilderUnix;
+    use base::MemoryMappingBuilder;
+    use data_model::Le16;
+    use serde_keyvalue::from_key_values;
+    use sync::Mutex;
+    use vm_memory::GuestAddress;
+    use zerocopy::FromBytes;
+    use zerocopy::FromZeroes;
+
+    use super::v4l2::*;
+    use super::*;
+    use crate::virtio::create_descriptor_chain;
+    use crate::virtio::DescriptorType;
+    use crate::virtio::QueueConfig;
+
+    // Layout of the guest memory used by the tests.
+    const CMD_DESC_TABLE: GuestAddress = GuestAddress(0x0);
+    const CMD_AVAIL_RING: GuestAddress = GuestAddress(0x1000);
+    const CMD_USED_RING: GuestAddress = GuestAddress(0x2000);
+    const EVENT_DESC_TABLE: GuestAddress = GuestAddress(0x3000);
+    const EVENT_AVAIL_RING: GuestAddress = GuestAddress(0x4000);
+    const EVENT_USED_RING: GuestAddress = GuestAddress(0x5000);
+    const CMD_BUFFER: GuestAddress = GuestAddress(0x6000);
+    const EVENT_BUFFER: GuestAddress = GuestAddress(0x8000);
+
+    const TEST_QUEUE_SIZE: u16 = 16;
+
+    /// Records the buffers the device maps into its shared memory region.
+    struct TestMapper(Arc<Mutex<BTreeMap<u64, VmMemorySource>>>);
+
+    impl SharedMemoryMapper for TestMapper {
+        fn add_mapping(
+            &mut self,
+            source: VmMemorySource,
+            offset: u64,
+            _prot: Protection,
+            _cache: MemCacheType,
+        ) -> anyhow::Result<()> {
+            self.0.lock().insert(offset, source);
+            Ok(())
+        }
+
+        fn remove_mapping(&mut self, offset: u64) -> anyhow::Result<()> {
+            self.0.lock().remove(&offset).context("no such mapping")?;
+            Ok(())
+        }
+    }
+
+    /// Drives an activated `MediaDevice` through its queues, like the guest driver does.
+    struct TestDriver {
+        mem: GuestMemory,
+        cmd_kick: Event,
+        cmd_avail_idx: u16,
+        event_kick: Event,
+        event_avail_idx: u16,
+    }
+
+    fn new_queue(
+        mem: &GuestMemory,
+        desc_table: GuestAddress,
+        avail_ring: GuestAddress,
+        used_ring: GuestAddress,
+    ) -> (Queue, Event) {
+        let mut queue = QueueConfig::new(TEST_QUEUE_SIZE, 0);
+        queue.set_desc_table(desc_table);
+        queue.set_avail_ring(avail_ring);
+        queue.set_used_ring(used_ring);
+        queue.set_ready(true);
+        let kick = Event::new().unwrap();
+        let queue = queue.activate(mem, kick.try_clone().unwrap()).unwrap();
+        (queue, kick)
+    }
+
+    impl TestDriver {
+        /// Makes the chain at the start of `desc_table` available as the `idx`-th element.
+        fn make_available(&self, avail_ring: GuestAddress, idx: u16) {
+            // Avail ring: flags, idx, then the ring of descriptor heads.
+            let slot = 4 + 2 * (idx % TEST_QUEUE_SIZE) as u64;
+            self.mem
+                .write_obj_at_addr(Le16::from(0u16), avail_ring.unchecked_add(slot))
+                .unwrap();
+            self.mem
+                .write_obj_at_addr(Le16::from(idx.wrapping_add(1)), avail_ring.unchecked_add(2))
+                .unwrap();
+        }
+
+        /// Waits until the device has used `count` descriptors of the queue.
+        fn wait_used(&self, used_ring: GuestAddress, count: u16) {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            loop {
+                let used_idx: Le16 = self
+                    .mem
+                    .read_obj_from_addr(used_ring.unchecked_add(2))
+                    .unwrap();
+                if u16::from(used_idx) == count {
+                    return;
+                }
+                assert!(Instant::now() < deadline, "timeout waiting for the device");
+                std::thread::sleep(Duration::from_millis(5));
+            }
+        }
+
+        /// Sends `request` on the command queue and returns the first `resp_len` bytes of the
+        /// response.
+        fn command(&mut self, request: &[u8], resp_len: u32) -> Vec<u8> {
+            self.mem.write_all_at_addr(request, CMD_BUFFER).unwrap();
+            let mut descriptors = vec![(DescriptorType::Readable, request.len() as u32)];
+            if resp_len > 0 {
+                descriptors.push((DescriptorType::Writable, resp_len));
+            }
+            create_descriptor_chain(&self.mem, CMD_DESC_TABLE, CMD_BUFFER, descriptors, 0).unwrap();
+            self.make_available(CMD_AVAIL_RING, self.cmd_avail_idx);
+            self.cmd_avail_idx = self.cmd_avail_idx.wrapping_add(1);
+            self.cmd_kick.signal().unwrap();
+            self.wait_used(CMD_USED_RING, self.cmd_avail_idx);
+
+            let mut resp = vec![0u8; resp_len as usize];
+            self.mem
+                .read_exact_at_addr(&mut resp, CMD_BUFFER.unchecked_add(request.len() as u64))
+                .unwrap();
+            resp
+        }
+
+        fn open(&mut self) -> u32 {
+            let cmd = virtio_media_cmd_header {
+                cmd: Le32::from(VIRTIO_MEDIA_CMD_OPEN),
+                ..Default::default()
+            };
+            let resp = virtio_media_resp_open::read_from(
+                &self.command(cmd.as_bytes(), size_of::<virtio_media_resp_open>() as u32),
+            )
+            .unwrap();
+            assert_eq!(resp.hdr.status.to_native(), 0);
+            resp.session_id.to_native()
+        }
+
+        fn close(&mut self, session_id: u32) {
+            let mut request = virtio_media_cmd_header {
+                cmd: Le32::from(VIRTIO_MEDIA_CMD_CLOSE),
+                ..Default::default()
+            }
+            .as_bytes()
+            .to_vec();
+            request.extend_from_slice(
+                virtio_media_cmd_close {
+                    session_id: Le32::from(session_id),
+                    ..Default::default()
+                }
+                .as_bytes(),
+            );
+            self.command(&request, 0);
+        }
+
+        /// Performs ioctl `code` with argument `arg`, and returns the status and updated argument.
+        fn ioctl<T: AsBytes + FromBytes>(
+            &mut self,
+            session_id: u32,
+            code: base::IoctlNr,
+            arg: T,
+        ) -> (u32, T) {
+            let mut request = virtio_media_cmd_header {
+                cmd: Le32::from(VIRTIO_MEDIA_CMD_IOCTL),
+                ..Default::default()
+            }
+            .as_bytes()
+            .to_vec();
+            request.extend_from_slice(
+                virtio_media_cmd_ioctl {
+                    session_id: Le32::from(session_id),
+                    code: Le32::from(code as u32),
+                }
+                .as_bytes(),
+            );
+            request.extend_from_slice(arg.as_bytes());
+            let resp = self.command(
+                &request,
+                (size_of::<virtio_media_resp_header>() + size_of::<T>()) as u32,
+            );
+            let (hdr, payload) = resp.split_at(size_of::<virtio_media_resp_header>());
+            let hdr = virtio_media_resp_header::read_from(hdr).unwrap();
+            (hdr.status.to_native(), T::read_from(payload).unwrap())
+        }
+
+        fn mmap(&mut self, session_id: u32, offset: u32) -> virtio_media_resp_mmap {
+            let mut request = virtio_media_cmd_header {
+                cmd: Le32::from(VIRTIO_MEDIA_CMD_MMAP),
+                ..Default::default()
+            }
+            .as_bytes()
+            .to_vec();
+            request.extend_from_slice(
+                virtio_media_cmd_mmap {
+                    session_id: Le32::from(session_id),
+                    flags: Le32::from(0),
+                    offset: Le32::from(offset),
+                }
+                .as_bytes(),
+            );
+            virtio_media_resp_mmap::read_from(
+                &self.command(&request, size_of::<virtio_media_resp_mmap>() as u32),
+            )
+            .unwrap()
+        }
+
+        fn munmap(&mut self, guest_addr: u64) -> u32 {
+            let mut request = virtio_media_cmd_header {
+                cmd: Le32::from(VIRTIO_MEDIA_CMD_MUNMAP),
+                ..Default::default()
+            }
+            .as_bytes()
+            .to_vec();
+            request.extend_from_slice(
+                virtio_media_cmd_munmap {
+                    guest_addr: Le64::from(guest_addr),
+                }
+                .as_bytes(),
+            );
+            virtio_media_resp_header::read_from(
+                &self.command(&request, size_of::<virtio_media_resp_header>() as u32),
+            )
+            .unwrap()
+            .status
+            .to_native()
+        }
+
+        /// Makes a single buffer available on the event queue and waits for the device to fill it.
+        fn next_event(&mut self) -> virtio_media_event_dqbuf {
+            create_descriptor_chain(
+                &self.mem,
+                EVENT_DESC_TABLE,
+                EVENT_BUFFER,
+                vec![(
+                    DescriptorType::Writable,
+                    size_of::<virtio_media_event_dqbuf>() as u32,
+                )],
+                0,
+            )
+            .unwrap();
+            self.make_available(EVENT_AVAIL_RING, self.event_avail_idx);
+            self.event_avail_idx = self.event_avail_idx.wrapping_add(1);
+            self.event_kick.signal().unwrap();
+            self.wait_used(EVENT_USED_RING, self.event_avail_idx);
+            self.mem.read_obj_from_addr(EVENT_BUFFER).unwrap()
+        }
+    }
+
+    #[test]
+    fn parse_config() {
+        let config: MediaDeviceConfig = from_key_values("type=test-pattern").unwrap();
+        assert_eq!(config.backend, MediaBackendType::TestPattern);
+        assert_eq!(config.device, None);
+
+        let config: MediaDeviceConfig = from_key_values("type=proxy,device=/dev/video0").unwrap();
+        assert_eq!(config.backend, MediaBackendType::Proxy);
+        assert_eq!(config.device, Some(PathBuf::from("/dev/video0")));
+
+        assert!(from_key_values::<MediaDeviceConfig>("type=foo").is_err());
+        assert!(from_key_values::<MediaDeviceConfig>("device=/dev/video0").is_err());
+    }
+
+    #[test]
+    fn backend_arguments() {
+        assert!(create_backend(&MediaDeviceConfig {
+            backend: MediaBackendType::Proxy,
+            device: None,
+        })
+        .is_err());
+        assert!(create_backend(&MediaDeviceConfig {
+            backend: MediaBackendType::TestPattern,
+            device: Some(PathBuf::from("/dev/video0")),
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn test_pattern_capture() {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mappings = Arc::new(Mutex::new(BTreeMap::new()));
+        let mut device = MediaDevice::new(
+            0,
+            &MediaDeviceConfig {
+                backend: MediaBackendType::TestPattern,
+                device: None,
+            },
+        )
+        .unwrap();
+        device.set_shared_memory_mapper(Box::new(TestMapper(mappings.clone())));
+
+        let (cmd_queue, cmd_kick) = new_queue(&mem, CMD_DESC_TABLE, CMD_AVAIL_RING, CMD_USED_RING);
+        let (event_queue, event_kick) =
+            new_queue(&mem, EVENT_DESC_TABLE, EVENT_AVAIL_RING, EVENT_USED_RING);
+        device
+            .activate(
+                mem.clone(),
+                Interrupt::new_for_test(),
+                BTreeMap::from([(0, cmd_queue), (1, event_queue)]),
+            )
+            .unwrap();
+        let mut driver = TestDriver {
+            mem,
+            cmd_kick,
+            cmd_avail_idx: 0,
+            event_kick,
+            event_avail_idx: 0,
+        };
+
+        let session_id = driver.open();
+
+        let mut format = v4l2_format::new_zeroed();
+        format.type_ = V4L2_BUF_TYPE_VIDEO_CAPTURE;
+        format.set_pix(v4l2_pix_format {
+            width: 300,
+            height: 200,
+            pixelformat: V4L2_PIX_FMT_YUYV,
+            ..Default::default()
+        });
+        let (status, format) = driver.ioctl(session_id, VIDIOC_S_FMT, format);
+        assert_eq!(status, 0);
+        let pix = format.pix();
+        assert_eq!(pix.pixelformat, V4L2_PIX_FMT_YUYV);
+        assert_eq!((pix.width, pix.height), (320, 240));
+        assert_eq!(pix.sizeimage, 320 * 240 * 2);
+
+        let reqbufs = v4l2_requestbuffers {
+            count: 1,
+            type_: V4L2_BUF_TYPE_VIDEO_CAPTURE,
+            memory: V4L2_MEMORY_MMAP,
+            ..Default::default()
+        };
+        let (status, reqbufs) = driver.ioctl(session_id, VIDIOC_REQBUFS, reqbufs);
+        assert_eq!(status, 0);
+        assert_eq!(reqbufs.count, 1);
+
+        let buffer = v4l2_buffer {
+            index: 0,
+            type_: V4L2_BUF_TYPE_VIDEO_CAPTURE,
+            memory: V4L2_MEMORY_MMAP,
+            ..Default::default()
+        };
+        let (status, buffer) = driver.ioctl(session_id, VIDIOC_QUERYBUF, buffer);
+        assert_eq!(status, 0);
+
+        // The buffer is mapped in the shared memory region, from where the guest reads frames.
+        let resp = driver.mmap(session_id, buffer.mem_offset());
+        assert_eq!(resp.hdr.status.to_native(), 0);
+        let guest_addr = resp.guest_addr.to_native();
+        assert_eq!(resp.len.to_native(), buffer.length as u64);
+        let frame = match mappings.lock().get(&guest_addr) {
+            Some(VmMemorySource::Descriptor {
+                descriptor,
+                offset,
+                size,
+            }) => {
+                assert_eq!(*size, resp.len.to_native());
+                MemoryMappingBuilder::new(*size as usize)
+                    .from_descriptor(descriptor)
+                    .offset(*offset)
+                    .build()
+                    .unwrap()
+            }
+            _ => panic!("buffer is not mapped"),
+        };
+
+        let (status, buffer) = driver.ioctl(session_id, VIDIOC_QBUF, buffer);
+        assert_eq!(status, 0);
+        assert_ne!(buffer.flags & V4L2_BUF_FLAG_QUEUED, 0);
+        let (status, _) = driver.ioctl(session_id, VIDIOC_STREAMON, V4L2_BUF_TYPE_VIDEO_CAPTURE);
+        assert_eq!(status, 0);
+
+        let event = driver.next_event();
+        assert_eq!(event.hdr.event.to_native(), VIRTIO_MEDIA_EVT_DQBUF);
+        assert_eq!(event.hdr.session_id.to_native(), session_id);
+        assert_eq!(event.buffer.index, 0);
+        assert_eq!(event.buffer.sequence, 0);
+        assert_eq!(event.buffer.bytesused, 320 * 240 * 2);
+        assert_ne!(event.buffer.flags & V4L2_BUF_FLAG_DONE, 0);
+        assert_eq!(event.buffer.flags & V4L2_BUF_FLAG_QUEUED, 0);
+
+        // Below the scrolling band, the leftmost pixels are in the white bar.
+        let mut pixels = [0u8; 4];
+        frame.read_slice(&mut pixels, 100 * 640).unwrap();
+        assert_eq!(pixels, [180, 128, 180, 128]);
+
+        let (status, _) = driver.ioctl(session_id, VIDIOC_STREAMOFF, V4L2_BUF_TYPE_VIDEO_CAPTURE);
+        assert_eq!(status, 0);
+        assert_eq!(driver.munmap(guest_addr), 0);
+        assert!(mappings.lock().is_empty());
+        assert_eq!(driver.munmap(guest_addr), libc::EINVAL as u32);
+
+        driver.close(session_id);
+        let (status, _) = driver.ioctl(session_id, VIDIOC_QUERYBUF, buffer);
+        assert_eq!(status, libc::EINVAL as u32);
+
+        device.reset().unwrap();
+    }
+}
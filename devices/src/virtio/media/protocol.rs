@@ -0,0 +1,159 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Structures exchanged with the guest over the virtio-media queues.
+//!
+//! Every command starts with a `virtio_media_cmd_header` and is answered with a response starting
+//! with a `virtio_media_resp_header`, except for `VIRTIO_MEDIA_CMD_CLOSE` which has no response.
+//! Events sent through the event queue start with a `virtio_media_event_header`.
+
+#![allow(non_camel_case_types)]
+
+use data_model::Le32;
+use data_model::Le64;
+use zerocopy::AsBytes;
+use zerocopy::FromBytes;
+use zerocopy::FromZeroes;
+
+use super::v4l2::v4l2_buffer;
+use super::v4l2::v4l2_event;
+use super::v4l2::v4l2_plane;
+use super::v4l2::VIDEO_MAX_PLANES;
+
+pub const VIRTIO_MEDIA_CMD_OPEN: u32 = 1;
+pub const VIRTIO_MEDIA_CMD_CLOSE: u32 = 2;
+pub const VIRTIO_MEDIA_CMD_IOCTL: u32 = 3;
+pub const VIRTIO_MEDIA_CMD_MMAP: u32 = 4;
+pub const VIRTIO_MEDIA_CMD_MUNMAP: u32 = 5;
+
+pub const VIRTIO_MEDIA_EVT_ERROR: u32 = 0;
+pub const VIRTIO_MEDIA_EVT_DQBUF: u32 = 1;
+pub const VIRTIO_MEDIA_EVT_EVENT: u32 = 2;
+
+/// The buffer is to be mapped writable by the guest.
+pub const VIRTIO_MEDIA_MMAP_FLAG_RW: u32 = 1 << 0;
+
+/// Identifier of the shared memory region buffers are mapped into.
+pub const VIRTIO_MEDIA_SHM_MMAP: u8 = 0;
+
+/// Value of `device_type` for devices that are not a media controller or subdevice.
+pub const VIRTIO_MEDIA_DEVICE_TYPE_VIDEO: u32 = 0;
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, FromZeroes, FromBytes, AsBytes)]
+pub struct virtio_media_config {
+    /// `device_caps` field the guest reports in `struct v4l2_capability`.
+    pub device_caps: Le32,
+    pub device_type: Le32,
+    /// Name of the device, NUL-padded.
+    pub card: [u8; 32],
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, FromZeroes, FromBytes, AsBytes)]
+pub struct virtio_media_cmd_header {
+    pub cmd: Le32,
+    pub __reserved: Le32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, FromZeroes, FromBytes, AsBytes)]
+pub struct virtio_media_resp_header {
+    /// 0 on success, or a positive errno value.
+    pub status: Le32,
+    pub __reserved: Le32,
+}
+
+impl virtio_media_resp_header {
+    pub fn ok() -> Self {
+        Self::err(0)
+    }
+
+    pub fn err(errno: i32) -> Self {
+        Self {
+            status: Le32::from(errno as u32),
+            __reserved: Le32::from(0),
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, FromZeroes, FromBytes, AsBytes)]
+pub struct virtio_media_resp_open {
+    pub hdr: virtio_media_resp_header,
+    pub session_id: Le32,
+    pub __reserved: Le32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, FromZeroes, FromBytes, AsBytes)]
+pub struct virtio_media_cmd_close {
+    pub session_id: Le32,
+    pub __reserved: Le32,
+}
+
+/// Followed by the ioctl payload, whose size is encoded in `code`. The response is a
+/// `virtio_media_resp_header` followed by the payload as updated by the device.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, FromZeroes, FromBytes, AsBytes)]
+pub struct virtio_media_cmd_ioctl {
+    pub session_id: Le32,
+    pub code: Le32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, FromZeroes, FromBytes, AsBytes)]
+pub struct virtio_media_cmd_mmap {
+    pub session_id: Le32,
+    pub flags: Le32,
+    /// `mem_offset` of the buffer or plane to map, as returned by `VIDIOC_QUERYBUF`.
+    pub offset: Le32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, FromZeroes, FromBytes, AsBytes)]
+pub struct virtio_media_resp_mmap {
+    pub hdr: virtio_media_resp_header,
+    /// Offset of the mapping within the shared memory region.
+    pub guest_addr: Le64,
+    pub len: Le64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, FromZeroes, FromBytes, AsBytes)]
+pub struct virtio_media_cmd_munmap {
+    /// `guest_addr` returned by a previous `VIRTIO_MEDIA_CMD_MMAP`.
+    pub guest_addr: Le64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, FromZeroes, FromBytes, AsBytes)]
+pub struct virtio_media_event_header {
+    pub event: Le32,
+    pub session_id: Le32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, FromZeroes, FromBytes, AsBytes)]
+pub struct virtio_media_event_error {
+    pub hdr: virtio_media_event_header,
+    pub errno: Le32,
+    pub __reserved: Le32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, FromZeroes, FromBytes, AsBytes)]
+pub struct virtio_media_event_dqbuf {
+    pub hdr: virtio_media_event_header,
+    pub buffer: v4l2_buffer,
+    /// Planes of the buffer, valid only for multi-planar buffer types.
+    pub planes: [v4l2_plane; VIDEO_MAX_PLANES],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, FromZeroes, FromBytes, AsBytes)]
+pub struct virtio_media_event_event {
+    pub hdr: virtio_media_event_header,
+    pub event: v4l2_event,
+}
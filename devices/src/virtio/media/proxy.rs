@@ -0,0 +1,538 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! virtio-media backend forwarding V4L2 ioctls to a video device node of the host.
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::mem::size_of;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::bail;
+use anyhow::Context;
+use base::errno_result;
+use base::ioctl_with_mut_ptr;
+use base::ioctl_with_mut_ref;
+use base::AsRawDescriptor;
+use base::Error as SysError;
+use base::FromRawDescriptor;
+use base::IoctlNr;
+use base::Result as SysResult;
+use base::SafeDescriptor;
+use zerocopy::AsBytes;
+use zerocopy::FromBytes;
+use zerocopy::FromZeroes;
+
+use super::v4l2::*;
+use super::MediaBackend;
+use super::MediaSession;
+use super::SessionEvent;
+
+/// Ioctls that can be forwarded to the host device as-is.
+const PASSTHROUGH_IOCTLS: &[IoctlNr] = &[
+    VIDIOC_ENUM_FMT,
+    VIDIOC_G_PARM,
+    VIDIOC_S_PARM,
+    VIDIOC_G_STD,
+    VIDIOC_S_STD,
+    VIDIOC_ENUMSTD,
+    VIDIOC_ENUMINPUT,
+    VIDIOC_G_CTRL,
+    VIDIOC_S_CTRL,
+    VIDIOC_QUERYCTRL,
+    VIDIOC_QUERYMENU,
+    VIDIOC_G_INPUT,
+    VIDIOC_S_INPUT,
+    VIDIOC_G_OUTPUT,
+    VIDIOC_S_OUTPUT,
+    VIDIOC_ENUMOUTPUT,
+    VIDIOC_G_PRIORITY,
+    VIDIOC_S_PRIORITY,
+    VIDIOC_ENUM_FRAMESIZES,
+    VIDIOC_ENUM_FRAMEINTERVALS,
+    VIDIOC_ENCODER_CMD,
+    VIDIOC_TRY_ENCODER_CMD,
+    VIDIOC_SUBSCRIBE_EVENT,
+    VIDIOC_UNSUBSCRIBE_EVENT,
+    VIDIOC_G_SELECTION,
+    VIDIOC_S_SELECTION,
+    VIDIOC_DECODER_CMD,
+    VIDIOC_TRY_DECODER_CMD,
+    VIDIOC_QUERY_EXT_CTRL,
+    VIDIOC_LOG_STATUS,
+];
+
+fn open_device(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK | libc::O_CLOEXEC)
+        .open(path)
+}
+
+/// Returns the capabilities of the host device, keeping only those this backend can proxy.
+fn filter_caps(caps: u32) -> u32 {
+    caps & !(V4L2_CAP_READWRITE | V4L2_CAP_VIDEO_OVERLAY | V4L2_CAP_DEVICE_CAPS)
+}
+
+/// Backend proxying a V4L2 device node of the host, e.g. `/dev/video0`.
+///
+/// Every session opened by the guest opens the device node anew, so the host driver sees the same
+/// sequence of file operations the guest performs. Only `V4L2_MEMORY_MMAP` buffers are supported,
+/// and they are mapped into the guest through the device's shared memory region.
+pub struct ProxyBackend {
+    path: PathBuf,
+    device_caps: u32,
+    card: String,
+}
+
+impl ProxyBackend {
+    pub fn new(path: &Path) -> anyhow::Result<Self> {
+        let device = open_device(path).with_context(|| format!("failed to open {:?}", path))?;
+        let mut caps = v4l2_capability::new_zeroed();
+        // SAFETY:
+        // `caps` is a properly sized `struct v4l2_capability` the kernel can write to.
+        let ret = unsafe { ioctl_with_mut_ref(&device, VIDIOC_QUERYCAP, &mut caps) };
+        if ret < 0 {
+            return Err(SysError::last())
+                .with_context(|| format!("{:?} is not a V4L2 device", path));
+        }
+
+        let device_caps = filter_caps(if caps.capabilities & V4L2_CAP_DEVICE_CAPS != 0 {
+            caps.device_caps
+        } else {
+            caps.capabilities
+        });
+        if device_caps & V4L2_CAP_STREAMING == 0 {
+            bail!("{:?} does not support streaming I/O", path);
+        }
+
+        let card_len = caps
+            .card
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or(caps.card.len());
+        let card = String::from_utf8_lossy(&caps.card[..card_len]).into_owned();
+
+        Ok(Self {
+            path: path.to_owned(),
+            device_caps,
+            card,
+        })
+    }
+}
+
+impl MediaBackend for ProxyBackend {
+    fn device_caps(&self) -> u32 {
+        self.device_caps
+    }
+
+    fn card(&self) -> &str {
+        &self.card
+    }
+
+    fn open(&mut self) -> SysResult<Box<dyn MediaSession>> {
+        let device = open_device(&self.path).map_err(SysError::from)?;
+        Ok(Box::new(ProxySession::new(device, self.device_caps)?))
+    }
+}
+
+/// epoll instance tracking the readiness of a session's device file.
+struct Epoll(SafeDescriptor);
+
+impl Epoll {
+    fn new(device: &File) -> SysResult<Self> {
+        // SAFETY:
+        // Trivially safe, the returned descriptor is checked below.
+        let fd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if fd < 0 {
+            return errno_result();
+        }
+        // SAFETY:
+        // `fd` is a valid descriptor we exclusively own.
+        let epoll = Epoll(unsafe { SafeDescriptor::from_raw_descriptor(fd) });
+        epoll.ctl(libc::EPOLL_CTL_ADD, device, libc::EPOLLPRI as u32)?;
+        Ok(epoll)
+    }
+
+    fn ctl(&self, op: libc::c_int, device: &File, events: u32) -> SysResult<()> {
+        let mut event = libc::epoll_event { events, u64: 0 };
+        // SAFETY:
+        // Both descriptors are valid and `event` is a properly initialized `epoll_event`.
+        let ret = unsafe {
+            libc::epoll_ctl(
+                self.0.as_raw_descriptor(),
+                op,
+                device.as_raw_descriptor(),
+                &mut event,
+            )
+        };
+        if ret < 0 {
+            return errno_result();
+        }
+        Ok(())
+    }
+
+    /// Returns the events currently pending on the device file, without blocking.
+    fn poll(&self) -> SysResult<u32> {
+        let mut event = libc::epoll_event { events: 0, u64: 0 };
+        // SAFETY:
+        // `event` can hold the single event we ask for.
+        let ret = unsafe { libc::epoll_wait(self.0.as_raw_descriptor(), &mut event, 1, 0) };
+        match ret {
+            n if n < 0 => errno_result(),
+            0 => Ok(0),
+            _ => Ok(event.events),
+        }
+    }
+}
+
+struct ProxySession {
+    device: File,
+    device_caps: u32,
+    epoll: Epoll,
+    /// Length of the MMAP buffers or planes reported by `VIDIOC_QUERYBUF`, by `mem_offset`.
+    mmap_lengths: BTreeMap<u32, u32>,
+    /// Number of buffers currently owned by the driver, by buffer type.
+    queued: BTreeMap<u32, u32>,
+    /// Buffer types that are currently streaming.
+    streaming: BTreeSet<u32>,
+    /// Whether we are currently polling the device for buffers to dequeue.
+    polling_buffers: bool,
+}
+
+impl ProxySession {
+    fn new(device: File, device_caps: u32) -> SysResult<Self> {
+        let epoll = Epoll::new(&device)?;
+        Ok(Self {
+            device,
+            device_caps,
+            epoll,
+            mmap_lengths: Default::default(),
+            queued: Default::default(),
+            streaming: Default::default(),
+            polling_buffers: false,
+        })
+    }
+
+    /// Returns the buffer types we may be able to dequeue a buffer from.
+    fn dequeueable_types(&self) -> Vec<u32> {
+        self.streaming
+            .iter()
+            .copied()
+            .filter(|t| self.queued.get(t).copied().unwrap_or(0) > 0)
+            .collect()
+    }
+
+    /// Only poll the device for buffers while some could be dequeued: V4L2 reports `EPOLLERR`
+    /// when a queue has no buffer or is not streaming, which would make us spin otherwise.
+    fn update_polling(&mut self, force_off: bool) -> SysResult<()> {
+        let poll_buffers = !force_off && !self.dequeueable_types().is_empty();
+        if poll_buffers != self.polling_buffers {
+            let mut events = libc::EPOLLPRI as u32;
+            if poll_buffers {
+                events |= (libc::EPOLLIN | libc::EPOLLOUT) as u32;
+            }
+            self.epoll.ctl(libc::EPOLL_CTL_MOD, &self.device, events)?;
+            self.polling_buffers = poll_buffers;
+        }
+        Ok(())
+    }
+
+    fn ioctl_raw(&self, code: IoctlNr, payload: &mut [u8]) -> SysResult<()> {
+        if payload.len() < ioctl_size(code as u32) {
+            return Err(SysError::new(libc::EINVAL));
+        }
+        // SAFETY:
+        // `payload` is large enough for the argument of `code`, whose size is encoded in it.
+        // Ioctls taking pointers to further memory are never passed through this method.
+        let ret = unsafe { ioctl_with_mut_ptr(&self.device, code, payload.as_mut_ptr()) };
+        if ret < 0 {
+            return errno_result();
+        }
+        Ok(())
+    }
+
+    fn passthrough(&self, code: IoctlNr, payload: &[u8]) -> SysResult<Vec<u8>> {
+        let mut arg = vec![0u8; ioctl_size(code as u32)];
+        if ioctl_writes_arg(code as u32) {
+            let len = arg.len().min(payload.len());
+            arg[..len].copy_from_slice(&payload[..len]);
+        }
+        self.ioctl_raw(code, &mut arg)?;
+        if !ioctl_reads_arg(code as u32) {
+            arg.clear();
+        }
+        Ok(arg)
+    }
+
+    fn querycap(&self) -> SysResult<Vec<u8>> {
+        let mut caps = v4l2_capability::new_zeroed();
+        self.ioctl_raw(VIDIOC_QUERYCAP, caps.as_bytes_mut())?;
+        caps.capabilities = self.device_caps | V4L2_CAP_DEVICE_CAPS;
+        caps.device_caps = self.device_caps;
+        Ok(caps.as_bytes().to_vec())
+    }
+
+    fn format(&self, code: IoctlNr, payload: &[u8]) -> SysResult<Vec<u8>> {
+        let mut format =
+            v4l2_format::read_from_prefix(payload).ok_or(SysError::new(libc::EINVAL))?;
+        // Overlay formats contain pointers to guest memory.
+        if matches!(
+            format.type_,
+            V4L2_BUF_TYPE_VIDEO_OVERLAY | V4L2_BUF_TYPE_VIDEO_OUTPUT_OVERLAY
+        ) {
+            return Err(SysError::new(libc::EINVAL));
+        }
+        self.ioctl_raw(code, format.as_bytes_mut())?;
+        Ok(format.as_bytes().to_vec())
+    }
+
+    fn reqbufs(&mut self, payload: &[u8]) -> SysResult<Vec<u8>> {
+        let mut reqbufs =
+            v4l2_requestbuffers::read_from_prefix(payload).ok_or(SysError::new(libc::EINVAL))?;
+        if reqbufs.memory != V4L2_MEMORY_MMAP {
+            return Err(SysError::new(libc::EINVAL));
+        }
+        self.ioctl_raw(VIDIOC_REQBUFS, reqbufs.as_bytes_mut())?;
+        // Freshly allocated buffers are all owned by userspace.
+        self.queued.remove(&reqbufs.type_);
+        reqbufs.capabilities &= V4L2_BUF_CAP_SUPPORTS_MMAP;
+        Ok(reqbufs.as_bytes().to_vec())
+    }
+
+    /// Handles the ioctls taking a `v4l2_buffer`, which the guest sends followed by its planes
+    /// for multi-planar buffer types.
+    fn buffer(&mut self, code: IoctlNr, payload: &[u8]) -> SysResult<Vec<u8>> {
+        let mut buffer =
+            v4l2_buffer::read_from_prefix(payload).ok_or(SysError::new(libc::EINVAL))?;
+        if buffer.memory != V4L2_MEMORY_MMAP {
+            return Err(SysError::new(libc::EINVAL));
+        }
+
+        let mut planes = [v4l2_plane::new_zeroed(); VIDEO_MAX_PLANES];
+        let num_planes = if is_multiplanar(buffer.type_) {
+            let num_planes = buffer.length as usize;
+            if num_planes > VIDEO_MAX_PLANES {
+                return Err(SysError::new(libc::EINVAL));
+            }
+            let planes_payload = &payload[size_of::<v4l2_buffer>()..];
+            for (i, plane) in planes.iter_mut().take(num_planes).enumerate() {
+                *plane = planes_payload
+                    .get(i * size_of::<v4l2_plane>()..)
+                    .and_then(v4l2_plane::read_from_prefix)
+                    .ok_or(SysError::new(libc::EINVAL))?;
+            }
+            num_planes
+        } else {
+            0
+        };
+
+        self.buffer_ioctl(code, &mut buffer, &mut planes[..num_planes])?;
+
+        match code {
+            VIDIOC_QUERYBUF => {
+                if num_planes > 0 {
+                    for plane in &planes[..num_planes] {
+                        self.mmap_lengths.insert(plane.mem_offset(), plane.length);
+                    }
+                } else {
+                    self.mmap_lengths.insert(buffer.mem_offset(), buffer.length);
+                }
+            }
+            VIDIOC_QBUF => {
+                *self.queued.entry(buffer.type_).or_default() += 1;
+                self.update_polling(false)?;
+            }
+            _ => (),
+        }
+
+        let mut response = buffer.as_bytes().to_vec();
+        for plane in &planes[..num_planes] {
+            response.extend_from_slice(plane.as_bytes());
+        }
+        Ok(response)
+    }
+
+    /// Performs `code` on `buffer`, pointing it to `planes` for multi-planar buffer types.
+    fn buffer_ioctl(
+        &self,
+        code: IoctlNr,
+        buffer: &mut v4l2_buffer,
+        planes: &mut [v4l2_plane],
+    ) -> SysResult<()> {
+        if is_multiplanar(buffer.type_) {
+            buffer.m = planes.as_mut_ptr() as u64;
+            buffer.length = planes.len() as u32;
+        }
+        // SAFETY:
+        // `buffer` is a valid `struct v4l2_buffer`, and for multi-planar types its `m.planes`
+        // member points to `length` planes that stay valid for the duration of the call.
+        let ret = unsafe { ioctl_with_mut_ref(&self.device, code, buffer) };
+        // Do not leak host addresses to the guest.
+        if is_multiplanar(buffer.type_) {
+            buffer.m = 0;
+        }
+        if ret < 0 {
+            return errno_result();
+        }
+        Ok(())
+    }
+
+    fn ext_ctrls(&self, code: IoctlNr, payload: &[u8]) -> SysResult<Vec<u8>> {
+        let mut ctrls =
+            v4l2_ext_controls::read_from_prefix(payload).ok_or(SysError::new(libc::EINVAL))?;
+        let ctrls_payload = &payload[size_of::<v4l2_ext_controls>()..];
+        let mut controls = (0..ctrls.count as usize)
+            .map(|i| {
+                ctrls_payload
+                    .get(i * size_of::<v4l2_ext_control>()..)
+                    .and_then(v4l2_ext_control::read_from_prefix)
+            })
+            .collect::<Option<Vec<_>>>()
+            .ok_or(SysError::new(libc::EINVAL))?;
+        // Controls with a payload hold a pointer to guest memory, which we cannot follow.
+        if controls.iter().any(|c| c.size > 0) {
+            return Err(SysError::new(libc::EINVAL));
+        }
+
+        ctrls.controls = controls.as_mut_ptr() as u64;
+        // SAFETY:
+        // `ctrls` is a valid `struct v4l2_ext_controls` and its `controls` member points to
+        // `count` controls that stay valid for the duration of the call. None of these controls
+        // point to further memory.
+        let ret = unsafe { ioctl_with_mut_ref(&self.device, code, &mut ctrls) };
+        ctrls.controls = 0;
+        if ret < 0 {
+            return errno_result();
+        }
+
+        let mut response = ctrls.as_bytes().to_vec();
+        for control in &controls {
+            response.extend_from_slice(control.as_bytes());
+        }
+        Ok(response)
+    }
+
+    fn stream(&mut self, code: IoctlNr, payload: &[u8]) -> SysResult<Vec<u8>> {
+        let mut buf_type = u32::read_from_prefix(payload).ok_or(SysError::new(libc::EINVAL))?;
+        self.ioctl_raw(code, buf_type.as_bytes_mut())?;
+        if code == VIDIOC_STREAMON {
+            self.streaming.insert(buf_type);
+        } else {
+            // Stopping a queue returns all its buffers to userspace.
+            self.streaming.remove(&buf_type);
+            self.queued.remove(&buf_type);
+        }
+        self.update_polling(false)?;
+        Ok(Vec::new())
+    }
+
+    /// Dequeues one buffer of type `buf_type`, if one is ready.
+    fn dequeue_buffer(&mut self, buf_type: u32) -> SysResult<Option<SessionEvent>> {
+        let mut buffer = v4l2_buffer {
+            type_: buf_type,
+            memory: V4L2_MEMORY_MMAP,
+            ..Default::default()
+        };
+        let mut planes = [v4l2_plane::new_zeroed(); VIDEO_MAX_PLANES];
+        let planes = if is_multiplanar(buf_type) {
+            &mut planes[..]
+        } else {
+            &mut planes[..0]
+        };
+        match self.buffer_ioctl(VIDIOC_DQBUF, &mut buffer, planes) {
+            Ok(()) => (),
+            Err(e) if e.errno() == libc::EAGAIN => return Ok(None),
+            Err(e) => return Err(e),
+        }
+
+        if let Some(queued) = self.queued.get_mut(&buf_type) {
+            *queued = queued.saturating_sub(1);
+        }
+        Ok(Some(SessionEvent::Dqbuf(buffer, planes.to_vec())))
+    }
+
+    fn dequeue_event(&self) -> SysResult<Option<v4l2_event>> {
+        let mut event = v4l2_event::new_zeroed();
+        match self.ioctl_raw(VIDIOC_DQEVENT, event.as_bytes_mut()) {
+            Ok(()) => Ok(Some(event)),
+            // No event pending.
+            Err(e) if e.errno() == libc::ENOENT => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn try_process_events(&mut self) -> SysResult<Vec<SessionEvent>> {
+        let mut events = Vec::new();
+        let revents = self.epoll.poll()?;
+        if revents == 0 {
+            return Ok(events);
+        }
+
+        if revents & libc::EPOLLPRI as u32 != 0 {
+            while let Some(event) = self.dequeue_event()? {
+                events.push(SessionEvent::Event(event));
+            }
+        }
+
+        let mut dequeued = false;
+        for buf_type in self.dequeueable_types() {
+            while let Some(event) = self.dequeue_buffer(buf_type)? {
+                events.push(event);
+                dequeued = true;
+            }
+        }
+
+        // The device reports an error condition we cannot dequeue anything for: stop polling for
+        // buffers until the guest queues a new one.
+        let error = revents & libc::EPOLLERR as u32 != 0 && !dequeued;
+        self.update_polling(error)?;
+
+        Ok(events)
+    }
+}
+
+impl MediaSession for ProxySession {
+    fn ioctl(&mut self, code: u32, payload: &[u8]) -> SysResult<Vec<u8>> {
+        match code as IoctlNr {
+            VIDIOC_QUERYCAP => self.querycap(),
+            c @ (VIDIOC_G_FMT | VIDIOC_S_FMT | VIDIOC_TRY_FMT) => self.format(c, payload),
+            VIDIOC_REQBUFS => self.reqbufs(payload),
+            c @ (VIDIOC_QUERYBUF | VIDIOC_QBUF | VIDIOC_PREPARE_BUF) => self.buffer(c, payload),
+            c @ (VIDIOC_G_EXT_CTRLS | VIDIOC_S_EXT_CTRLS | VIDIOC_TRY_EXT_CTRLS) => {
+                self.ext_ctrls(c, payload)
+            }
+            c @ (VIDIOC_STREAMON | VIDIOC_STREAMOFF) => self.stream(c, payload),
+            c if PASSTHROUGH_IOCTLS.contains(&c) => self.passthrough(c, payload),
+            // Buffers and events are dequeued by the device and sent through the event queue.
+            _ => Err(SysError::new(libc::ENOTTY)),
+        }
+    }
+
+    fn mmap(&mut self, offset: u32, _rw: bool) -> SysResult<(SafeDescriptor, u64, u64)> {
+        let length = *self
+            .mmap_lengths
+            .get(&offset)
+            .ok_or(SysError::new(libc::EINVAL))?;
+        // The mapping itself is performed by the VMM, using the offset V4L2 expects.
+        let descriptor = SafeDescriptor::try_from(&self.device as &dyn AsRawDescriptor)?;
+        Ok((descriptor, offset as u64, length as u64))
+    }
+
+    fn event_descriptor(&self) -> Option<&dyn AsRawDescriptor> {
+        Some(&self.epoll.0)
+    }
+
+    fn process_events(&mut self) -> Vec<SessionEvent> {
+        match self.try_process_events() {
+            Ok(events) => events,
+            Err(e) => vec![SessionEvent::Error(e.errno())],
+        }
+    }
+}
@@ -0,0 +1,658 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! virtio-media backend emulating a camera producing a test pattern.
+//!
+//! This backend does not require any host device and is meant to test the guest driver and
+//! userspace stack: frames are made of 75% color bars with a white band scrolling over them, and
+//! are produced at the frame interval selected by the guest.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use base::round_up_to_page_size;
+use base::AsRawDescriptor;
+use base::Error as SysError;
+use base::IoctlNr;
+use base::MemoryMapping;
+use base::MemoryMappingBuilder;
+use base::Result as SysResult;
+use base::SafeDescriptor;
+use base::SharedMemory;
+use base::Timer;
+use base::TimerTrait;
+use zerocopy::AsBytes;
+use zerocopy::FromBytes;
+
+use super::v4l2::*;
+use super::MediaBackend;
+use super::MediaSession;
+use super::SessionEvent;
+
+const DEVICE_CAPS: u32 = V4L2_CAP_VIDEO_CAPTURE | V4L2_CAP_STREAMING;
+const CARD: &str = "Test pattern";
+
+/// Supported pixel formats, and their description.
+const FORMATS: &[(u32, &str)] = &[
+    (V4L2_PIX_FMT_NV12, "Y/UV 4:2:0"),
+    (V4L2_PIX_FMT_YUYV, "YUYV 4:2:2"),
+];
+const SIZES: &[(u32, u32)] = &[(320, 240), (640, 480), (1280, 720)];
+const INTERVALS: &[v4l2_fract] = &[
+    v4l2_fract {
+        numerator: 1,
+        denominator: 30,
+    },
+    v4l2_fract {
+        numerator: 1,
+        denominator: 15,
+    },
+];
+
+const MAX_BUFFERS: u32 = 32;
+
+/// Y, U and V values of the 75% color bars, from left to right.
+const BARS: [(u8, u8, u8); 8] = [
+    (180, 128, 128), // white
+    (162, 44, 142),  // yellow
+    (131, 156, 44),  // cyan
+    (112, 72, 58),   // green
+    (84, 184, 198),  // magenta
+    (65, 100, 212),  // red
+    (35, 212, 114),  // blue
+    (16, 128, 128),  // black
+];
+/// Color of the scrolling band.
+const BAND: (u8, u8, u8) = (235, 128, 128);
+const BAND_HEIGHT: u32 = 16;
+
+/// Returns a `v4l2_pix_format` of `pixelformat` with the supported size closest to
+/// `width`x`height`.
+fn adjust_format(pixelformat: u32, width: u32, height: u32) -> v4l2_pix_format {
+    let pixelformat = FORMATS
+        .iter()
+        .map(|&(f, _)| f)
+        .find(|&f| f == pixelformat)
+        .unwrap_or(FORMATS[0].0);
+    let area = width as i64 * height as i64;
+    let (width, height) = SIZES
+        .iter()
+        .copied()
+        .min_by_key(|&(w, h)| (w as i64 * h as i64 - area).abs())
+        .unwrap();
+
+    let (bytesperline, sizeimage) = match pixelformat {
+        V4L2_PIX_FMT_NV12 => (width, width * height * 3 / 2),
+        _ => (width * 2, width * height * 2),
+    };
+
+    v4l2_pix_format {
+        width,
+        height,
+        pixelformat,
+        field: V4L2_FIELD_NONE,
+        bytesperline,
+        sizeimage,
+        colorspace: V4L2_COLORSPACE_SRGB,
+        ..Default::default()
+    }
+}
+
+/// Returns the color of the pixel at (`x`, `y`) of frame number `frame`.
+fn pixel_color(format: &v4l2_pix_format, frame: u32, x: u32, y: u32) -> (u8, u8, u8) {
+    let band_pos = (frame * 4) % format.height;
+    if y >= band_pos && y < band_pos + BAND_HEIGHT {
+        BAND
+    } else {
+        BARS[(x * BARS.len() as u32 / format.width) as usize]
+    }
+}
+
+/// Renders frame number `frame` of the test pattern into `out`, which must be at least
+/// `format.sizeimage` bytes long.
+fn render(format: &v4l2_pix_format, frame: u32, out: &mut [u8]) {
+    let width = format.width as usize;
+    let height = format.height as usize;
+    let stride = format.bytesperline as usize;
+
+    match format.pixelformat {
+        V4L2_PIX_FMT_NV12 => {
+            let (luma, chroma) = out.split_at_mut(stride * height);
+            for (y, line) in luma.chunks_exact_mut(stride).enumerate() {
+                for (x, pixel) in line[..width].iter_mut().enumerate() {
+                    *pixel = pixel_color(format, frame, x as u32, y as u32).0;
+                }
+            }
+            for (y, line) in chroma.chunks_exact_mut(stride).take(height / 2).enumerate() {
+                for (x, uv) in line[..width].chunks_exact_mut(2).enumerate() {
+                    let (_, u, v) = pixel_color(format, frame, x as u32 * 2, y as u32 * 2);
+                    uv.copy_from_slice(&[u, v]);
+                }
+            }
+        }
+        _ => {
+            for (y, line) in out.chunks_exact_mut(stride).take(height).enumerate() {
+                for (x, yuyv) in line[..width * 2].chunks_exact_mut(4).enumerate() {
+                    let (y0, u, v) = pixel_color(format, frame, x as u32 * 2, y as u32);
+                    let (y1, _, _) = pixel_color(format, frame, x as u32 * 2 + 1, y as u32);
+                    yuyv.copy_from_slice(&[y0, u, y1, v]);
+                }
+            }
+        }
+    }
+}
+
+fn copy_name(dst: &mut [u8], name: &str) {
+    let len = name.len().min(dst.len() - 1);
+    dst[..len].copy_from_slice(&name.as_bytes()[..len]);
+}
+
+/// Returns the current time of the monotonic clock, which V4L2 timestamps are based on.
+fn monotonic_time() -> timeval {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY:
+    // `ts` is a valid `timespec` the kernel can write to.
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    timeval {
+        tv_sec: ts.tv_sec,
+        tv_usec: ts.tv_nsec / 1000,
+    }
+}
+
+/// Backend emulating a camera producing a test pattern.
+pub struct TestPatternBackend;
+
+impl MediaBackend for TestPatternBackend {
+    fn device_caps(&self) -> u32 {
+        DEVICE_CAPS
+    }
+
+    fn card(&self) -> &str {
+        CARD
+    }
+
+    fn open(&mut self) -> SysResult<Box<dyn MediaSession>> {
+        Ok(Box::new(TestPatternSession::new()?))
+    }
+}
+
+/// Memory backing the buffers of a session, all allocated from a single shared memory object.
+struct Buffers {
+    shm: SharedMemory,
+    mapping: MemoryMapping,
+    /// Page-aligned size of each buffer.
+    buffer_size: u32,
+    /// Whether each buffer is currently queued.
+    queued: Vec<bool>,
+}
+
+impl Buffers {
+    fn new(count: u32, sizeimage: u32) -> SysResult<Self> {
+        let buffer_size = round_up_to_page_size(sizeimage as usize) as u32;
+        let size = buffer_size as u64 * count as u64;
+        let shm = SharedMemory::new("virtio-media-test-pattern", size)?;
+        let mapping = MemoryMappingBuilder::new(size as usize)
+            .from_shared_memory(&shm)
+            .build()
+            .map_err(|_| SysError::new(libc::ENOMEM))?;
+        Ok(Self {
+            shm,
+            mapping,
+            buffer_size,
+            queued: vec![false; count as usize],
+        })
+    }
+}
+
+struct TestPatternSession {
+    format: v4l2_pix_format,
+    interval: v4l2_fract,
+    buffers: Option<Buffers>,
+    /// Indices of the buffers queued by the guest, in queuing order.
+    queue: VecDeque<u32>,
+    streaming: bool,
+    timer: Timer,
+    sequence: u32,
+    frame: Vec<u8>,
+}
+
+impl TestPatternSession {
+    fn new() -> SysResult<Self> {
+        Ok(Self {
+            format: adjust_format(FORMATS[0].0, SIZES[1].0, SIZES[1].1),
+            interval: INTERVALS[0],
+            buffers: None,
+            queue: VecDeque::new(),
+            streaming: false,
+            timer: Timer::new()?,
+            sequence: 0,
+            frame: Vec::new(),
+        })
+    }
+
+    fn frame_duration(&self) -> Duration {
+        Duration::from_secs(self.interval.numerator as u64) / self.interval.denominator
+    }
+
+    fn buffer_info(&self, index: u32, bytesused: u32) -> SysResult<v4l2_buffer> {
+        let buffers = self.buffers.as_ref().ok_or(SysError::new(libc::EINVAL))?;
+        let queued = *buffers
+            .queued
+            .get(index as usize)
+            .ok_or(SysError::new(libc::EINVAL))?;
+        Ok(v4l2_buffer {
+            index,
+            type_: V4L2_BUF_TYPE_VIDEO_CAPTURE,
+            bytesused,
+            flags: V4L2_BUF_FLAG_MAPPED
+                | V4L2_BUF_FLAG_TIMESTAMP_MONOTONIC
+                | if queued { V4L2_BUF_FLAG_QUEUED } else { 0 },
+            field: V4L2_FIELD_NONE,
+            memory: V4L2_MEMORY_MMAP,
+            m: index as u64 * buffers.buffer_size as u64,
+            length: buffers.buffer_size,
+            ..Default::default()
+        })
+    }
+
+    fn check_type(buf_type: u32) -> SysResult<()> {
+        if buf_type != V4L2_BUF_TYPE_VIDEO_CAPTURE {
+            return Err(SysError::new(libc::EINVAL));
+        }
+        Ok(())
+    }
+
+    fn querycap(&self) -> SysResult<Vec<u8>> {
+        let mut caps = v4l2_capability {
+            capabilities: DEVICE_CAPS | V4L2_CAP_DEVICE_CAPS,
+            device_caps: DEVICE_CAPS,
+            ..Default::default()
+        };
+        copy_name(&mut caps.driver, "crosvm");
+        copy_name(&mut caps.card, CARD);
+        copy_name(&mut caps.bus_info, "platform:virtio-media");
+        Ok(caps.as_bytes().to_vec())
+    }
+
+    fn enum_fmt(&self, mut desc: v4l2_fmtdesc) -> SysResult<Vec<u8>> {
+        Self::check_type(desc.type_)?;
+        let (pixelformat, description) = FORMATS
+            .get(desc.index as usize)
+            .ok_or(SysError::new(libc::EINVAL))?;
+        desc.flags = 0;
+        desc.pixelformat = *pixelformat;
+        desc.description = [0; 32];
+        copy_name(&mut desc.description, description);
+        Ok(desc.as_bytes().to_vec())
+    }
+
+    fn format(&mut self, code: IoctlNr, mut format: v4l2_format) -> SysResult<Vec<u8>> {
+        Self::check_type(format.type_)?;
+        if code != VIDIOC_G_FMT {
+            let pix = format.pix();
+            let adjusted = adjust_format(pix.pixelformat, pix.width, pix.height);
+            if code == VIDIOC_S_FMT {
+                if self.buffers.is_some() {
+                    return Err(SysError::new(libc::EBUSY));
+                }
+                self.format = adjusted;
+            }
+            format.set_pix(adjusted);
+        } else {
+            format.set_pix(self.format);
+        }
+        Ok(format.as_bytes().to_vec())
+    }
+
+    fn enum_framesizes(&self, mut sizes: v4l2_frmsizeenum) -> SysResult<Vec<u8>> {
+        if !FORMATS.iter().any(|&(f, _)| f == sizes.pixel_format) {
+            return Err(SysError::new(libc::EINVAL));
+        }
+        let (width, height) = SIZES
+            .get(sizes.index as usize)
+            .ok_or(SysError::new(libc::EINVAL))?;
+        sizes.type_ = V4L2_FRMSIZE_TYPE_DISCRETE;
+        sizes.size = [*width, *height, 0, 0, 0, 0];
+        Ok(sizes.as_bytes().to_vec())
+    }
+
+    fn enum_frameintervals(&self, mut intervals: v4l2_frmivalenum) -> SysResult<Vec<u8>> {
+        if !FORMATS.iter().any(|&(f, _)| f == intervals.pixel_format)
+            || !SIZES.contains(&(intervals.width, intervals.height))
+        {
+            return Err(SysError::new(libc::EINVAL));
+        }
+        let interval = INTERVALS
+            .get(intervals.index as usize)
+            .ok_or(SysError::new(libc::EINVAL))?;
+        intervals.type_ = V4L2_FRMIVAL_TYPE_DISCRETE;
+        intervals.interval = [*interval, Default::default(), Default::default()];
+        Ok(intervals.as_bytes().to_vec())
+    }
+
+    fn parm(&mut self, code: IoctlNr, mut parm: v4l2_streamparm) -> SysResult<Vec<u8>> {
+        Self::check_type(parm.type_)?;
+        if code == VIDIOC_S_PARM {
+            let requested = v4l2_captureparm::read_from_prefix(&parm.parm[..])
+                .unwrap()
+                .timeperframe;
+            // Pick the closest supported interval, or keep the current one if none is given.
+            if requested.numerator != 0 && requested.denominator != 0 {
+                let requested = requested.numerator as f64 / requested.denominator as f64;
+                self.interval = *INTERVALS
+                    .iter()
+                    .min_by(|a, b| {
+                        let a = (a.numerator as f64 / a.denominator as f64 - requested).abs();
+                        let b = (b.numerator as f64 / b.denominator as f64 - requested).abs();
+                        a.total_cmp(&b)
+                    })
+                    .unwrap();
+                if self.streaming {
+                    self.timer.reset_repeating(self.frame_duration())?;
+                }
+            }
+        }
+
+        let captureparm = v4l2_captureparm {
+            capability: V4L2_CAP_TIMEPERFRAME,
+            timeperframe: self.interval,
+            readbuffers: MAX_BUFFERS,
+            ..Default::default()
+        };
+        parm.parm = [0; 200];
+        // Unwrapping is safe because `parm` is larger than `v4l2_captureparm`.
+        captureparm.write_to_prefix(&mut parm.parm[..]).unwrap();
+        Ok(parm.as_bytes().to_vec())
+    }
+
+    fn reqbufs(&mut self, mut reqbufs: v4l2_requestbuffers) -> SysResult<Vec<u8>> {
+        Self::check_type(reqbufs.type_)?;
+        if reqbufs.memory != V4L2_MEMORY_MMAP {
+            return Err(SysError::new(libc::EINVAL));
+        }
+        if self.streaming {
+            return Err(SysError::new(libc::EBUSY));
+        }
+
+        self.queue.clear();
+        self.buffers = None;
+        reqbufs.count = reqbufs.count.min(MAX_BUFFERS);
+        if reqbufs.count > 0 {
+            self.buffers = Some(Buffers::new(reqbufs.count, self.format.sizeimage)?);
+        }
+        reqbufs.capabilities = V4L2_BUF_CAP_SUPPORTS_MMAP;
+        Ok(reqbufs.as_bytes().to_vec())
+    }
+
+    fn querybuf(&self, buffer: v4l2_buffer) -> SysResult<Vec<u8>> {
+        Self::check_type(buffer.type_)?;
+        Ok(self.buffer_info(buffer.index, 0)?.as_bytes().to_vec())
+    }
+
+    fn qbuf(&mut self, buffer: v4l2_buffer) -> SysResult<Vec<u8>> {
+        Self::check_type(buffer.type_)?;
+        if buffer.memory != V4L2_MEMORY_MMAP {
+            return Err(SysError::new(libc::EINVAL));
+        }
+        let queued = self
+            .buffers
+            .as_mut()
+            .and_then(|b| b.queued.get_mut(buffer.index as usize))
+            .ok_or(SysError::new(libc::EINVAL))?;
+        if *queued {
+            return Err(SysError::new(libc::EINVAL));
+        }
+        *queued = true;
+        self.queue.push_back(buffer.index);
+        Ok(self.buffer_info(buffer.index, 0)?.as_bytes().to_vec())
+    }
+
+    fn stream(&mut self, code: IoctlNr, buf_type: u32) -> SysResult<Vec<u8>> {
+        Self::check_type(buf_type)?;
+        if code == VIDIOC_STREAMON {
+            if self.buffers.is_none() {
+                return Err(SysError::new(libc::EINVAL));
+            }
+            if !self.streaming {
+                self.timer.reset_repeating(self.frame_duration())?;
+                self.streaming = true;
+                self.sequence = 0;
+            }
+        } else {
+            self.timer.clear()?;
+            self.streaming = false;
+            self.queue.clear();
+            if let Some(buffers) = self.buffers.as_mut() {
+                buffers.queued.iter_mut().for_each(|q| *q = false);
+            }
+        }
+        Ok(Vec::new())
+    }
+
+    fn enum_input(&self, mut input: v4l2_input) -> SysResult<Vec<u8>> {
+        if input.index != 0 {
+            return Err(SysError::new(libc::EINVAL));
+        }
+        input.name = [0; 32];
+        copy_name(&mut input.name, CARD);
+        input.type_ = V4L2_INPUT_TYPE_CAMERA;
+        input.audioset = 0;
+        input.tuner = 0;
+        input.std = 0;
+        input.status = 0;
+        input.capabilities = 0;
+        Ok(input.as_bytes().to_vec())
+    }
+
+    fn set_input(&self, input: i32) -> SysResult<Vec<u8>> {
+        if input != 0 {
+            return Err(SysError::new(libc::EINVAL));
+        }
+        Ok(input.as_bytes().to_vec())
+    }
+
+    /// Fills the oldest queued buffer with the next frame and returns it.
+    fn produce_frame(&mut self) -> SysResult<Option<SessionEvent>> {
+        let Some(index) = self.queue.pop_front() else {
+            return Ok(None);
+        };
+        // Unwrapping is safe because buffers cannot be queued without being allocated.
+        let buffers = self.buffers.as_mut().unwrap();
+
+        let sizeimage = self.format.sizeimage;
+        self.frame.resize(sizeimage as usize, 0);
+        render(&self.format, self.sequence, &mut self.frame);
+        buffers
+            .mapping
+            .write_slice(&self.frame, index as usize * buffers.buffer_size as usize)
+            .map_err(|_| SysError::new(libc::EIO))?;
+        buffers.queued[index as usize] = false;
+
+        let mut buffer = self.buffer_info(index, sizeimage)?;
+        buffer.flags |= V4L2_BUF_FLAG_DONE;
+        buffer.sequence = self.sequence;
+        buffer.timestamp = monotonic_time();
+        self.sequence = self.sequence.wrapping_add(1);
+
+        Ok(Some(SessionEvent::Dqbuf(buffer, Vec::new())))
+    }
+}
+
+fn parse<T: FromBytes>(payload: &[u8]) -> SysResult<T> {
+    T::read_from_prefix(payload).ok_or(SysError::new(libc::EINVAL))
+}
+
+impl MediaSession for TestPatternSession {
+    fn ioctl(&mut self, code: u32, payload: &[u8]) -> SysResult<Vec<u8>> {
+        match code as IoctlNr {
+            VIDIOC_QUERYCAP => self.querycap(),
+            VIDIOC_ENUM_FMT => self.enum_fmt(parse(payload)?),
+            c @ (VIDIOC_G_FMT | VIDIOC_S_FMT | VIDIOC_TRY_FMT) => self.format(c, parse(payload)?),
+            VIDIOC_ENUM_FRAMESIZES => self.enum_framesizes(parse(payload)?),
+            VIDIOC_ENUM_FRAMEINTERVALS => self.enum_frameintervals(parse(payload)?),
+            c @ (VIDIOC_G_PARM | VIDIOC_S_PARM) => self.parm(c, parse(payload)?),
+            VIDIOC_REQBUFS => self.reqbufs(parse(payload)?),
+            VIDIOC_QUERYBUF => self.querybuf(parse(payload)?),
+            VIDIOC_QBUF => self.qbuf(parse(payload)?),
+            c @ (VIDIOC_STREAMON | VIDIOC_STREAMOFF) => self.stream(c, parse(payload)?),
+            VIDIOC_ENUMINPUT => self.enum_input(parse(payload)?),
+            VIDIOC_G_INPUT => Ok(0i32.as_bytes().to_vec()),
+            VIDIOC_S_INPUT => self.set_input(parse(payload)?),
+            _ => Err(SysError::new(libc::ENOTTY)),
+        }
+    }
+
+    fn mmap(&mut self, offset: u32, _rw: bool) -> SysResult<(SafeDescriptor, u64, u64)> {
+        let buffers = self.buffers.as_ref().ok_or(SysError::new(libc::EINVAL))?;
+        if offset % buffers.buffer_size != 0
+            || offset / buffers.buffer_size >= buffers.queued.len() as u32
+        {
+            return Err(SysError::new(libc::EINVAL));
+        }
+        let descriptor = SafeDescriptor::try_from(&buffers.shm as &dyn AsRawDescriptor)?;
+        Ok((descriptor, offset as u64, buffers.buffer_size as u64))
+    }
+
+    fn event_descriptor(&self) -> Option<&dyn AsRawDescriptor> {
+        Some(&self.timer)
+    }
+
+    fn process_events(&mut self) -> Vec<SessionEvent> {
+        match self.timer.mark_waited() {
+            // The timer has been re-armed or cleared since it triggered.
+            Ok(true) => return Vec::new(),
+            Ok(false) => (),
+            Err(e) => return vec![SessionEvent::Error(e.errno())],
+        }
+        if !self.streaming {
+            return Vec::new();
+        }
+
+        match self.produce_frame() {
+            Ok(event) => event.into_iter().collect(),
+            Err(e) => vec![SessionEvent::Error(e.errno())],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_adjustment() {
+        let format = adjust_format(V4L2_PIX_FMT_YUYV, 1920, 1080);
+        assert_eq!(format.pixelformat, V4L2_PIX_FMT_YUYV);
+        assert_eq!((format.width, format.height), (1280, 720));
+        assert_eq!(format.bytesperline, 2560);
+        assert_eq!(format.sizeimage, 1280 * 720 * 2);
+
+        let format = adjust_format(0, 300, 200);
+        assert_eq!(format.pixelformat, V4L2_PIX_FMT_NV12);
+        assert_eq!((format.width, format.height), (320, 240));
+        assert_eq!(format.sizeimage, 320 * 240 * 3 / 2);
+    }
+
+    #[test]
+    fn render_nv12() {
+        let format = adjust_format(V4L2_PIX_FMT_NV12, 320, 240);
+        let mut frame = vec![0u8; format.sizeimage as usize];
+        render(&format, 0, &mut frame);
+
+        let luma_size = 320 * 240;
+        // The band covers the first lines of the first frame.
+        assert!(frame[..320 * BAND_HEIGHT as usize]
+            .iter()
+            .all(|&y| y == BAND.0));
+        // Leftmost and rightmost bars below it.
+        let line = 100 * 320;
+        assert_eq!(frame[line], BARS[0].0);
+        assert_eq!(frame[line + 319], BARS[7].0);
+        let chroma_line = luma_size + 50 * 320;
+        assert_eq!(
+            &frame[chroma_line + 80..chroma_line + 82],
+            &[BARS[2].1, BARS[2].2]
+        );
+    }
+
+    #[test]
+    fn render_yuyv() {
+        let format = adjust_format(V4L2_PIX_FMT_YUYV, 320, 240);
+        let mut frame = vec![0u8; format.sizeimage as usize];
+        render(&format, 0, &mut frame);
+
+        let line = 100 * 640;
+        assert_eq!(
+            &frame[line..line + 4],
+            &[BARS[0].0, BARS[0].1, BARS[0].0, BARS[0].2]
+        );
+        assert_eq!(
+            &frame[line + 636..line + 640],
+            &[BARS[7].0, BARS[7].1, BARS[7].0, BARS[7].2]
+        );
+    }
+
+    #[test]
+    fn capture_frames() {
+        let mut session = TestPatternBackend.open().unwrap();
+
+        let reqbufs = v4l2_requestbuffers {
+            count: 2,
+            type_: V4L2_BUF_TYPE_VIDEO_CAPTURE,
+            memory: V4L2_MEMORY_MMAP,
+            ..Default::default()
+        };
+        let reqbufs: v4l2_requestbuffers = parse(
+            &session
+                .ioctl(VIDIOC_REQBUFS as u32, reqbufs.as_bytes())
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(reqbufs.count, 2);
+
+        for index in 0..2 {
+            let buffer = v4l2_buffer {
+                index,
+                type_: V4L2_BUF_TYPE_VIDEO_CAPTURE,
+                memory: V4L2_MEMORY_MMAP,
+                ..Default::default()
+            };
+            let buffer: v4l2_buffer = parse(
+                &session
+                    .ioctl(VIDIOC_QBUF as u32, buffer.as_bytes())
+                    .unwrap(),
+            )
+            .unwrap();
+            assert_ne!(buffer.flags & V4L2_BUF_FLAG_QUEUED, 0);
+            session.mmap(buffer.mem_offset(), false).unwrap();
+        }
+
+        session
+            .ioctl(
+                VIDIOC_STREAMON as u32,
+                V4L2_BUF_TYPE_VIDEO_CAPTURE.as_bytes(),
+            )
+            .unwrap();
+        for sequence in 0..2 {
+            std::thread::sleep(Duration::from_millis(50));
+            let events = session.process_events();
+            match events.as_slice() {
+                [SessionEvent::Dqbuf(buffer, _)] => {
+                    assert_eq!(buffer.index, sequence);
+                    assert_eq!(buffer.sequence, sequence);
+                    assert_ne!(buffer.flags & V4L2_BUF_FLAG_DONE, 0);
+                    assert_eq!(buffer.bytesused, 640 * 480 * 3 / 2);
+                }
+                _ => panic!("unexpected events: {}", events.len()),
+            }
+        }
+
+        // No more buffers queued.
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(session.process_events().is_empty());
+    }
+}
@@ -0,0 +1,429 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Subset of the V4L2 userspace ABI (`linux/videodev2.h`) used by the virtio-media device.
+//!
+//! The layouts are those of 64-bit little-endian Linux, which virtio-media mandates for the
+//! structures exchanged with the guest. Unions the device does not need to look into are kept as
+//! opaque byte arrays.
+
+#![allow(non_camel_case_types)]
+
+use base::ioctl_io_nr;
+use base::ioctl_ior_nr;
+use base::ioctl_iow_nr;
+use base::ioctl_iowr_nr;
+use zerocopy::AsBytes;
+use zerocopy::FromBytes;
+use zerocopy::FromZeroes;
+
+pub const VIDEO_MAX_PLANES: usize = 8;
+
+pub const V4L2_CAP_VIDEO_CAPTURE: u32 = 0x00000001;
+pub const V4L2_CAP_VIDEO_OUTPUT: u32 = 0x00000002;
+pub const V4L2_CAP_VIDEO_OVERLAY: u32 = 0x00000004;
+pub const V4L2_CAP_VIDEO_CAPTURE_MPLANE: u32 = 0x00001000;
+pub const V4L2_CAP_VIDEO_OUTPUT_MPLANE: u32 = 0x00002000;
+pub const V4L2_CAP_VIDEO_M2M_MPLANE: u32 = 0x00004000;
+pub const V4L2_CAP_VIDEO_M2M: u32 = 0x00008000;
+pub const V4L2_CAP_READWRITE: u32 = 0x01000000;
+pub const V4L2_CAP_STREAMING: u32 = 0x04000000;
+pub const V4L2_CAP_DEVICE_CAPS: u32 = 0x80000000;
+
+pub const V4L2_BUF_TYPE_VIDEO_CAPTURE: u32 = 1;
+pub const V4L2_BUF_TYPE_VIDEO_OUTPUT: u32 = 2;
+pub const V4L2_BUF_TYPE_VIDEO_OVERLAY: u32 = 3;
+pub const V4L2_BUF_TYPE_VIDEO_OUTPUT_OVERLAY: u32 = 8;
+pub const V4L2_BUF_TYPE_VIDEO_CAPTURE_MPLANE: u32 = 9;
+pub const V4L2_BUF_TYPE_VIDEO_OUTPUT_MPLANE: u32 = 10;
+
+pub const V4L2_MEMORY_MMAP: u32 = 1;
+
+pub const V4L2_BUF_CAP_SUPPORTS_MMAP: u32 = 0x00000001;
+
+pub const V4L2_BUF_FLAG_MAPPED: u32 = 0x00000001;
+pub const V4L2_BUF_FLAG_QUEUED: u32 = 0x00000002;
+pub const V4L2_BUF_FLAG_DONE: u32 = 0x00000004;
+pub const V4L2_BUF_FLAG_TIMESTAMP_MONOTONIC: u32 = 0x00002000;
+
+pub const V4L2_FIELD_NONE: u32 = 1;
+pub const V4L2_COLORSPACE_SRGB: u32 = 8;
+
+pub const V4L2_FMT_FLAG_COMPRESSED: u32 = 0x0001;
+
+pub const V4L2_FRMSIZE_TYPE_DISCRETE: u32 = 1;
+pub const V4L2_FRMIVAL_TYPE_DISCRETE: u32 = 1;
+
+pub const V4L2_CAP_TIMEPERFRAME: u32 = 0x1000;
+
+pub const V4L2_INPUT_TYPE_CAMERA: u32 = 2;
+
+/// Returns the V4L2 four-character code of a pixel format.
+pub const fn fourcc(code: &[u8; 4]) -> u32 {
+    code[0] as u32 | (code[1] as u32) << 8 | (code[2] as u32) << 16 | (code[3] as u32) << 24
+}
+
+pub const V4L2_PIX_FMT_NV12: u32 = fourcc(b"NV12");
+pub const V4L2_PIX_FMT_YUYV: u32 = fourcc(b"YUYV");
+
+/// Returns whether buffers of type `buf_type` use the multi-planar API.
+pub fn is_multiplanar(buf_type: u32) -> bool {
+    matches!(
+        buf_type,
+        V4L2_BUF_TYPE_VIDEO_CAPTURE_MPLANE | V4L2_BUF_TYPE_VIDEO_OUTPUT_MPLANE
+    )
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, FromZeroes, FromBytes, AsBytes)]
+pub struct v4l2_capability {
+    pub driver: [u8; 16],
+    pub card: [u8; 32],
+    pub bus_info: [u8; 32],
+    pub version: u32,
+    pub capabilities: u32,
+    pub device_caps: u32,
+    pub reserved: [u32; 3],
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, FromZeroes, FromBytes, AsBytes)]
+pub struct v4l2_fmtdesc {
+    pub index: u32,
+    pub type_: u32,
+    pub flags: u32,
+    pub description: [u8; 32],
+    pub pixelformat: u32,
+    pub mbus_code: u32,
+    pub reserved: [u32; 3],
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, FromZeroes, FromBytes, AsBytes)]
+pub struct v4l2_pix_format {
+    pub width: u32,
+    pub height: u32,
+    pub pixelformat: u32,
+    pub field: u32,
+    pub bytesperline: u32,
+    pub sizeimage: u32,
+    pub colorspace: u32,
+    pub priv_: u32,
+    pub flags: u32,
+    pub ycbcr_enc: u32,
+    pub quantization: u32,
+    pub xfer_func: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, FromZeroes, FromBytes, AsBytes)]
+pub struct v4l2_format {
+    pub type_: u32,
+    pub _pad: u32,
+    /// Union of the per-type formats. Only `v4l2_pix_format` is interpreted by the device.
+    pub fmt: [u64; 25],
+}
+
+impl v4l2_format {
+    pub fn pix(&self) -> v4l2_pix_format {
+        // Unwrapping is safe because `fmt` is larger than `v4l2_pix_format`.
+        v4l2_pix_format::read_from_prefix(self.fmt.as_bytes()).unwrap()
+    }
+
+    pub fn set_pix(&mut self, pix: v4l2_pix_format) {
+        self.fmt = [0; 25];
+        // Unwrapping is safe because `fmt` is larger than `v4l2_pix_format`.
+        pix.write_to_prefix(self.fmt.as_bytes_mut()).unwrap();
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, FromZeroes, FromBytes, AsBytes)]
+pub struct v4l2_requestbuffers {
+    pub count: u32,
+    pub type_: u32,
+    pub memory: u32,
+    pub capabilities: u32,
+    pub flags: u8,
+    pub reserved: [u8; 3],
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, FromZeroes, FromBytes, AsBytes)]
+pub struct timeval {
+    pub tv_sec: i64,
+    pub tv_usec: i64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, FromZeroes, FromBytes, AsBytes)]
+pub struct v4l2_buffer {
+    pub index: u32,
+    pub type_: u32,
+    pub bytesused: u32,
+    pub flags: u32,
+    pub field: u32,
+    pub _pad0: u32,
+    pub timestamp: timeval,
+    pub timecode: [u8; 16],
+    pub sequence: u32,
+    pub memory: u32,
+    /// Union of `offset`, `userptr`, `planes` and `fd`.
+    pub m: u64,
+    pub length: u32,
+    pub reserved2: u32,
+    pub request_fd: u32,
+    pub _pad1: u32,
+}
+
+impl v4l2_buffer {
+    /// Offset to pass to mmap() for single-planar `V4L2_MEMORY_MMAP` buffers.
+    pub fn mem_offset(&self) -> u32 {
+        self.m as u32
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, FromZeroes, FromBytes, AsBytes)]
+pub struct v4l2_plane {
+    pub bytesused: u32,
+    pub length: u32,
+    /// Union of `mem_offset`, `userptr` and `fd`.
+    pub m: u64,
+    pub data_offset: u32,
+    pub reserved: [u32; 11],
+}
+
+impl v4l2_plane {
+    /// Offset to pass to mmap() for `V4L2_MEMORY_MMAP` planes.
+    pub fn mem_offset(&self) -> u32 {
+        self.m as u32
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, FromZeroes, FromBytes, AsBytes)]
+pub struct v4l2_frmsizeenum {
+    pub index: u32,
+    pub pixel_format: u32,
+    pub type_: u32,
+    /// Union of `v4l2_frmsize_discrete` (width, height) and `v4l2_frmsize_stepwise`.
+    pub size: [u32; 6],
+    pub reserved: [u32; 2],
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, FromZeroes, FromBytes, AsBytes)]
+pub struct v4l2_fract {
+    pub numerator: u32,
+    pub denominator: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, FromZeroes, FromBytes, AsBytes)]
+pub struct v4l2_frmivalenum {
+    pub index: u32,
+    pub pixel_format: u32,
+    pub width: u32,
+    pub height: u32,
+    pub type_: u32,
+    /// Union of a discrete `v4l2_fract` and `v4l2_frmival_stepwise`.
+    pub interval: [v4l2_fract; 3],
+    pub reserved: [u32; 2],
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, FromZeroes, FromBytes, AsBytes)]
+pub struct v4l2_captureparm {
+    pub capability: u32,
+    pub capturemode: u32,
+    pub timeperframe: v4l2_fract,
+    pub extendedmode: u32,
+    pub readbuffers: u32,
+    pub reserved: [u32; 4],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, FromZeroes, FromBytes, AsBytes)]
+pub struct v4l2_streamparm {
+    pub type_: u32,
+    /// Union of `v4l2_captureparm` and `v4l2_outputparm`.
+    pub parm: [u8; 200],
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, FromZeroes, FromBytes, AsBytes)]
+pub struct v4l2_input {
+    pub index: u32,
+    pub name: [u8; 32],
+    pub type_: u32,
+    pub audioset: u32,
+    pub tuner: u32,
+    pub std: u64,
+    pub status: u32,
+    pub capabilities: u32,
+    pub reserved: [u32; 3],
+    pub _pad: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, FromZeroes, FromBytes, AsBytes)]
+pub struct v4l2_ext_controls {
+    pub which: u32,
+    pub count: u32,
+    pub error_idx: u32,
+    pub request_fd: i32,
+    pub reserved: [u32; 1],
+    pub _pad: u32,
+    /// Pointer to an array of `count` `v4l2_ext_control`.
+    pub controls: u64,
+}
+
+#[repr(C, packed)]
+#[derive(Debug, Default, Copy, Clone, FromZeroes, FromBytes, AsBytes)]
+pub struct v4l2_ext_control {
+    pub id: u32,
+    /// Size of the payload pointed to by `value`, or 0 for controls passed by value.
+    pub size: u32,
+    pub reserved2: [u32; 1],
+    /// Union of the control value and of a pointer to its payload.
+    pub value: u64,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, FromZeroes, FromBytes, AsBytes)]
+pub struct v4l2_event {
+    pub type_: u32,
+    pub _pad0: u32,
+    pub u: [u64; 8],
+    pub pending: u32,
+    pub sequence: u32,
+    pub timestamp: [i64; 2],
+    pub id: u32,
+    pub reserved: [u32; 8],
+    pub _pad1: u32,
+}
+
+/// Base of all the V4L2 ioctls.
+const V4L2_IOCTL_BASE: u32 = b'V' as u32;
+
+ioctl_ior_nr!(VIDIOC_QUERYCAP, V4L2_IOCTL_BASE, 0, v4l2_capability);
+ioctl_iowr_nr!(VIDIOC_ENUM_FMT, V4L2_IOCTL_BASE, 2, v4l2_fmtdesc);
+ioctl_iowr_nr!(VIDIOC_G_FMT, V4L2_IOCTL_BASE, 4, v4l2_format);
+ioctl_iowr_nr!(VIDIOC_S_FMT, V4L2_IOCTL_BASE, 5, v4l2_format);
+ioctl_iowr_nr!(VIDIOC_REQBUFS, V4L2_IOCTL_BASE, 8, v4l2_requestbuffers);
+ioctl_iowr_nr!(VIDIOC_QUERYBUF, V4L2_IOCTL_BASE, 9, v4l2_buffer);
+ioctl_iowr_nr!(VIDIOC_QBUF, V4L2_IOCTL_BASE, 15, v4l2_buffer);
+ioctl_iowr_nr!(VIDIOC_DQBUF, V4L2_IOCTL_BASE, 17, v4l2_buffer);
+ioctl_iow_nr!(VIDIOC_STREAMON, V4L2_IOCTL_BASE, 18, u32);
+ioctl_iow_nr!(VIDIOC_STREAMOFF, V4L2_IOCTL_BASE, 19, u32);
+ioctl_iowr_nr!(VIDIOC_G_PARM, V4L2_IOCTL_BASE, 21, v4l2_streamparm);
+ioctl_iowr_nr!(VIDIOC_S_PARM, V4L2_IOCTL_BASE, 22, v4l2_streamparm);
+ioctl_ior_nr!(VIDIOC_G_STD, V4L2_IOCTL_BASE, 23, u64);
+ioctl_iow_nr!(VIDIOC_S_STD, V4L2_IOCTL_BASE, 24, u64);
+// struct v4l2_standard
+ioctl_iowr_nr!(VIDIOC_ENUMSTD, V4L2_IOCTL_BASE, 25, [u8; 72]);
+ioctl_iowr_nr!(VIDIOC_ENUMINPUT, V4L2_IOCTL_BASE, 26, v4l2_input);
+// struct v4l2_control
+ioctl_iowr_nr!(VIDIOC_G_CTRL, V4L2_IOCTL_BASE, 27, [u32; 2]);
+ioctl_iowr_nr!(VIDIOC_S_CTRL, V4L2_IOCTL_BASE, 28, [u32; 2]);
+// struct v4l2_queryctrl
+ioctl_iowr_nr!(VIDIOC_QUERYCTRL, V4L2_IOCTL_BASE, 36, [u32; 17]);
+// struct v4l2_querymenu
+ioctl_iowr_nr!(VIDIOC_QUERYMENU, V4L2_IOCTL_BASE, 37, [u8; 44]);
+ioctl_ior_nr!(VIDIOC_G_INPUT, V4L2_IOCTL_BASE, 38, i32);
+ioctl_iowr_nr!(VIDIOC_S_INPUT, V4L2_IOCTL_BASE, 39, i32);
+ioctl_ior_nr!(VIDIOC_G_OUTPUT, V4L2_IOCTL_BASE, 46, i32);
+ioctl_iowr_nr!(VIDIOC_S_OUTPUT, V4L2_IOCTL_BASE, 47, i32);
+// struct v4l2_output
+ioctl_iowr_nr!(VIDIOC_ENUMOUTPUT, V4L2_IOCTL_BASE, 48, [u8; 72]);
+ioctl_iowr_nr!(VIDIOC_TRY_FMT, V4L2_IOCTL_BASE, 64, v4l2_format);
+ioctl_ior_nr!(VIDIOC_G_PRIORITY, V4L2_IOCTL_BASE, 67, u32);
+ioctl_iow_nr!(VIDIOC_S_PRIORITY, V4L2_IOCTL_BASE, 68, u32);
+ioctl_iowr_nr!(VIDIOC_G_EXT_CTRLS, V4L2_IOCTL_BASE, 71, v4l2_ext_controls);
+ioctl_iowr_nr!(VIDIOC_S_EXT_CTRLS, V4L2_IOCTL_BASE, 72, v4l2_ext_controls);
+ioctl_iowr_nr!(VIDIOC_TRY_EXT_CTRLS, V4L2_IOCTL_BASE, 73, v4l2_ext_controls);
+ioctl_iowr_nr!(
+    VIDIOC_ENUM_FRAMESIZES,
+    V4L2_IOCTL_BASE,
+    74,
+    v4l2_frmsizeenum
+);
+ioctl_iowr_nr!(
+    VIDIOC_ENUM_FRAMEINTERVALS,
+    V4L2_IOCTL_BASE,
+    75,
+    v4l2_frmivalenum
+);
+// struct v4l2_encoder_cmd
+ioctl_iowr_nr!(VIDIOC_ENCODER_CMD, V4L2_IOCTL_BASE, 77, [u32; 10]);
+ioctl_iowr_nr!(VIDIOC_TRY_ENCODER_CMD, V4L2_IOCTL_BASE, 78, [u32; 10]);
+ioctl_ior_nr!(VIDIOC_DQEVENT, V4L2_IOCTL_BASE, 89, v4l2_event);
+// struct v4l2_event_subscription
+ioctl_iow_nr!(VIDIOC_SUBSCRIBE_EVENT, V4L2_IOCTL_BASE, 90, [u32; 8]);
+ioctl_iow_nr!(VIDIOC_UNSUBSCRIBE_EVENT, V4L2_IOCTL_BASE, 91, [u32; 8]);
+ioctl_iowr_nr!(VIDIOC_PREPARE_BUF, V4L2_IOCTL_BASE, 93, v4l2_buffer);
+// struct v4l2_selection
+ioctl_iowr_nr!(VIDIOC_G_SELECTION, V4L2_IOCTL_BASE, 94, [u32; 16]);
+ioctl_iowr_nr!(VIDIOC_S_SELECTION, V4L2_IOCTL_BASE, 95, [u32; 16]);
+// struct v4l2_decoder_cmd
+ioctl_iowr_nr!(VIDIOC_DECODER_CMD, V4L2_IOCTL_BASE, 96, [u64; 9]);
+ioctl_iowr_nr!(VIDIOC_TRY_DECODER_CMD, V4L2_IOCTL_BASE, 97, [u64; 9]);
+// struct v4l2_query_ext_ctrl
+ioctl_iowr_nr!(VIDIOC_QUERY_EXT_CTRL, V4L2_IOCTL_BASE, 103, [u64; 29]);
+ioctl_io_nr!(VIDIOC_LOG_STATUS, V4L2_IOCTL_BASE, 70);
+
+/// Returns the size of the argument of ioctl `code`.
+pub fn ioctl_size(code: u32) -> usize {
+    ((code >> base::linux::ioctl::_IOC_SIZESHIFT) & base::linux::ioctl::_IOC_SIZEMASK) as usize
+}
+
+/// Returns whether ioctl `code` reads its argument from userspace.
+pub fn ioctl_writes_arg(code: u32) -> bool {
+    (code >> base::linux::ioctl::_IOC_DIRSHIFT) & base::linux::ioctl::_IOC_WRITE != 0
+}
+
+/// Returns whether ioctl `code` writes its argument back to userspace.
+pub fn ioctl_reads_arg(code: u32) -> bool {
+    (code >> base::linux::ioctl::_IOC_DIRSHIFT) & base::linux::ioctl::_IOC_READ != 0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::size_of;
+
+    use super::*;
+
+    #[test]
+    fn struct_sizes() {
+        assert_eq!(size_of::<v4l2_capability>(), 104);
+        assert_eq!(size_of::<v4l2_fmtdesc>(), 64);
+        assert_eq!(size_of::<v4l2_format>(), 208);
+        assert_eq!(size_of::<v4l2_requestbuffers>(), 20);
+        assert_eq!(size_of::<v4l2_buffer>(), 88);
+        assert_eq!(size_of::<v4l2_plane>(), 64);
+        assert_eq!(size_of::<v4l2_frmsizeenum>(), 44);
+        assert_eq!(size_of::<v4l2_frmivalenum>(), 52);
+        assert_eq!(size_of::<v4l2_streamparm>(), 204);
+        assert_eq!(size_of::<v4l2_input>(), 80);
+        assert_eq!(size_of::<v4l2_ext_controls>(), 32);
+        assert_eq!(size_of::<v4l2_ext_control>(), 20);
+        assert_eq!(size_of::<v4l2_event>(), 136);
+    }
+
+    #[test]
+    fn ioctl_codes() {
+        assert_eq!(VIDIOC_QUERYCAP as u32, 0x80685600);
+        assert_eq!(VIDIOC_S_FMT as u32, 0xc0d05605);
+        assert_eq!(VIDIOC_QBUF as u32, 0xc058560f);
+        assert_eq!(VIDIOC_STREAMON as u32, 0x40045612);
+        assert_eq!(VIDIOC_S_EXT_CTRLS as u32, 0xc0205648);
+        assert_eq!(VIDIOC_DQEVENT as u32, 0x80885659);
+        assert_eq!(VIDIOC_QUERY_EXT_CTRL as u32, 0xc0e85667);
+        assert_eq!(ioctl_size(VIDIOC_QBUF as u32), 88);
+        assert!(ioctl_reads_arg(VIDIOC_QUERYCAP as u32));
+        assert!(!ioctl_writes_arg(VIDIOC_QUERYCAP as u32));
+        assert!(ioctl_writes_arg(VIDIOC_STREAMON as u32));
+    }
+}
@@ -20,7 +20,6 @@ pub mod net;
 pub mod pvclock;
 mod queue;
 mod rng;
-#[cfg(feature = "vtpm")]
 mod tpm;
 #[cfg(any(feature = "video-decoder", feature = "video-encoder"))]
 mod video;
@@ -91,9 +90,7 @@ pub use self::queue::QueueConfig;
 pub use self::rng::Rng;
 pub use self::scsi::Controller as ScsiController;
 pub use self::scsi::DiskConfig as ScsiDiskConfig;
-#[cfg(feature = "vtpm")]
 pub use self::tpm::Tpm;
-#[cfg(feature = "vtpm")]
 pub use self::tpm::TpmBackend;
 pub use self::vhost_user_frontend::VhostUserFrontend;
 #[cfg(any(feature = "video-decoder", feature = "video-encoder"))]
@@ -117,8 +114,12 @@ cfg_if::cfg_if! {
 
         pub mod wl;
         pub mod fs;
+        #[cfg(feature = "media")]
+        pub mod media;
 
         pub use self::iommu::sys::linux::vfio_wrapper;
+        #[cfg(feature = "media")]
+        pub use self::media::MediaDevice;
         #[cfg(feature = "net")]
         pub use self::net::VhostNetParameters;
         #[cfg(feature = "net")]
@@ -183,6 +184,7 @@ pub enum DeviceType {
     Wl = virtio_ids::VIRTIO_ID_WL,
     Tpm = virtio_ids::VIRTIO_ID_TPM,
     Pvclock = virtio_ids::VIRTIO_ID_PVCLOCK,
+    Media = virtio_ids::VIRTIO_ID_MEDIA,
 }
 
 impl DeviceType {
@@ -213,6 +215,7 @@ impl DeviceType {
             DeviceType::Wl => 2,            // in, out
             DeviceType::Tpm => 1,           // request queue
             DeviceType::Pvclock => 1,       // request queue
+            DeviceType::Media => 2,         // commandq, eventq
         }
     }
 }
@@ -242,6 +245,7 @@ impl std::fmt::Display for DeviceType {
             DeviceType::VideoEncoder => write!(f, "video-encoder"),
             DeviceType::Mac80211HwSim => write!(f, "mac80211-hwsim"),
             DeviceType::Scmi => write!(f, "scmi"),
+            DeviceType::Media => write!(f, "media"),
         }
     }
 }
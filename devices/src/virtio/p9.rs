@@ -4,11 +4,13 @@
 
 use std::collections::BTreeMap;
 use std::io;
+use std::io::Read;
 use std::io::Write;
 use std::mem;
 use std::result;
 
 use anyhow::anyhow;
+use anyhow::bail;
 use anyhow::Context;
 use base::error;
 use base::warn;
@@ -18,7 +20,11 @@ use base::EventToken;
 use base::RawDescriptor;
 use base::WaitContext;
 use base::WorkerThread;
+use cros_async::MemRegion;
 use remain::sorted;
+use serde::Deserialize;
+use serde::Serialize;
+use smallvec::SmallVec;
 use thiserror::Error;
 use vm_memory::GuestMemory;
 
@@ -26,6 +32,7 @@ use super::copy_config;
 use super::queue::Queue;
 use super::DeviceType;
 use super::Interrupt;
+use super::Reader;
 use super::VirtioDevice;
 
 const QUEUE_SIZE: u16 = 128;
@@ -34,6 +41,30 @@ const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE];
 // The only virtio_9p feature.
 const VIRTIO_9P_MOUNT_TAG: u8 = 0;
 
+// 9P2000.L messages that change the fids of the guest. The reply to a successful request has the
+// type of the request plus one.
+const P9_RLERROR: u8 = 7;
+const P9_TLOPEN: u8 = 12;
+const P9_TLCREATE: u8 = 14;
+const P9_TRENAME: u8 = 20;
+const P9_TRENAMEAT: u8 = 74;
+const P9_TVERSION: u8 = 100;
+const P9_TATTACH: u8 = 104;
+const P9_TWALK: u8 = 110;
+const P9_TCLUNK: u8 = 120;
+const P9_TREMOVE: u8 = 122;
+
+// Size of the header of every 9P message: size[4] type[1] tag[2].
+const P9_HEADER_SIZE: usize = 7;
+const P9_NOFID: u32 = u32::MAX;
+// Maximum number of names in a single Twalk.
+const P9_MAXWELEM: usize = 16;
+const P9_CREATE: u32 = 0o100;
+const P9_EXCL: u32 = 0o200;
+const P9_TRUNC: u32 = 0o1000;
+// Open flags that must not be applied again when a file is reopened on restore.
+const P9_REOPEN_IGNORED_FLAGS: u32 = P9_CREATE | P9_EXCL | P9_TRUNC;
+
 /// Errors that occur during operation of a virtio 9P device.
 #[sorted]
 #[derive(Error, Debug)]
@@ -69,21 +100,379 @@ pub enum P9Error {
 
 pub type P9Result<T> = result::Result<T, P9Error>;
 
+/// Reads the fields of a 9P message in wire format.
+struct WireReader<'a>(&'a [u8]);
+
+impl WireReader<'_> {
+    fn bytes(&mut self, len: usize) -> Option<&[u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.bytes(2)?.try_into().ok()?))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.bytes(4)?.try_into().ok()?))
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let len = self.u16()?;
+        String::from_utf8(self.bytes(len.into())?.to_vec()).ok()
+    }
+
+    /// Reads the header of the message and returns its type.
+    fn header(&mut self) -> Option<u8> {
+        let _size = self.u32()?;
+        let ty = self.u8()?;
+        let _tag = self.u16()?;
+        Some(ty)
+    }
+}
+
+/// Builds a 9P message in wire format.
+struct WireWriter(Vec<u8>);
+
+impl WireWriter {
+    fn new(ty: u8) -> WireWriter {
+        // The size is filled in by `finish`, and the tag doesn't matter as requests are sent one at
+        // a time.
+        let mut writer = WireWriter(Vec::new());
+        writer.u32(0).u8(ty).u16(0);
+        writer
+    }
+
+    fn u8(&mut self, val: u8) -> &mut Self {
+        self.0.push(val);
+        self
+    }
+
+    fn u16(&mut self, val: u16) -> &mut Self {
+        self.0.extend_from_slice(&val.to_le_bytes());
+        self
+    }
+
+    fn u32(&mut self, val: u32) -> &mut Self {
+        self.0.extend_from_slice(&val.to_le_bytes());
+        self
+    }
+
+    fn string(&mut self, val: &str) -> &mut Self {
+        self.u16(val.len() as u16);
+        self.0.extend_from_slice(val.as_bytes());
+        self
+    }
+
+    fn finish(&mut self) -> Vec<u8> {
+        let size = self.0.len() as u32;
+        self.0[..4].copy_from_slice(&size.to_le_bytes());
+        mem::take(&mut self.0)
+    }
+}
+
+/// Arguments of the `Tattach` that created the root of a fid.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Attach {
+    uname: String,
+    aname: String,
+    n_uname: u32,
+}
+
+/// A fid of the guest, described by path so that it can be recreated in another server.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct FidState {
+    attach: Attach,
+    /// Names walked from the root of the attach, without `.` and `..`.
+    path: Vec<String>,
+    /// Flags of the `Tlopen` or `Tlcreate` of the fid, if it is open.
+    open_flags: Option<u32>,
+}
+
+impl FidState {
+    fn walk<'a>(&mut self, names: impl IntoIterator<Item = &'a str>) {
+        for name in names {
+            match name {
+                "." | "" => {}
+                ".." => {
+                    self.path.pop();
+                }
+                name => self.path.push(name.to_string()),
+            }
+        }
+    }
+
+    /// Recreates the fid in `server` by attaching, walking to its path and opening it again.
+    fn reopen(&self, server: &mut p9::Server, fid: u32) -> anyhow::Result<()> {
+        send_message(
+            server,
+            WireWriter::new(P9_TATTACH)
+                .u32(fid)
+                .u32(P9_NOFID)
+                .string(&self.attach.uname)
+                .string(&self.attach.aname)
+                .u32(self.attach.n_uname)
+                .finish(),
+        )
+        .context("failed to attach")?;
+        if let Err(e) = self.walk_and_open(server, fid) {
+            // Don't leave the fid pointing at another file than the one of the guest.
+            let _ = send_message(server, WireWriter::new(P9_TCLUNK).u32(fid).finish());
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn walk_and_open(&self, server: &mut p9::Server, fid: u32) -> anyhow::Result<()> {
+        for names in self.path.chunks(P9_MAXWELEM) {
+            let mut walk = WireWriter::new(P9_TWALK);
+            walk.u32(fid).u32(fid).u16(names.len() as u16);
+            for name in names {
+                walk.string(name);
+            }
+            let reply = send_message(server, walk.finish())
+                .with_context(|| format!("failed to walk to {}", self.path.join("/")))?;
+            let mut reply = WireReader(&reply);
+            reply.header();
+            if reply.u16() != Some(names.len() as u16) {
+                bail!("{} no longer exists", self.path.join("/"));
+            }
+        }
+        if let Some(flags) = self.open_flags {
+            send_message(
+                server,
+                WireWriter::new(P9_TLOPEN)
+                    .u32(fid)
+                    .u32(flags & !P9_REOPEN_IGNORED_FLAGS)
+                    .finish(),
+            )
+            .with_context(|| format!("failed to open {}", self.path.join("/")))?;
+        }
+        Ok(())
+    }
+}
+
+/// Sends `request` to `server` and returns its reply, or the error the server replied with.
+fn send_message(server: &mut p9::Server, request: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    let mut reply = Vec::new();
+    server.handle_message(&mut request.as_slice(), &mut reply)?;
+    let mut fields = WireReader(&reply);
+    match fields.header() {
+        Some(ty) if ty == request[4] + 1 => Ok(reply),
+        Some(P9_RLERROR) => {
+            let ecode = fields.u32().context("truncated Rlerror")?;
+            Err(io::Error::from_raw_os_error(ecode as i32).into())
+        }
+        _ => bail!("unexpected reply to 9p request {}", request[4]),
+    }
+}
+
+/// The fids of the guest, recorded from the requests it sends because `p9::Server` keeps its own
+/// fid table private.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct FidTable {
+    /// `msize` and version string of the last `Tversion`.
+    version: Option<(u32, String)>,
+    fids: BTreeMap<u32, FidState>,
+}
+
+impl FidTable {
+    /// Whether requests of type `ty` change the fid table.
+    fn records(ty: u8) -> bool {
+        matches!(
+            ty,
+            P9_TLOPEN
+                | P9_TLCREATE
+                | P9_TRENAME
+                | P9_TRENAMEAT
+                | P9_TVERSION
+                | P9_TATTACH
+                | P9_TWALK
+                | P9_TCLUNK
+                | P9_TREMOVE
+        )
+    }
+
+    /// Updates the table with a request of the guest and the reply of the server to it. `reply`
+    /// only needs to hold the start of the reply.
+    fn record(&mut self, request: &[u8], reply: &[u8]) -> Option<()> {
+        let mut request = WireReader(request);
+        let mut reply = WireReader(reply);
+        let ty = request.header()?;
+        let succeeded = reply.header()? == ty.wrapping_add(1);
+        match ty {
+            // The fid is released even if the server fails to remove the file.
+            P9_TCLUNK | P9_TREMOVE => {
+                self.fids.remove(&request.u32()?);
+            }
+            _ if !succeeded => {}
+            P9_TVERSION => {
+                let msize = request.u32()?;
+                let version = request.string()?;
+                self.version = Some((msize, version));
+                self.fids.clear();
+            }
+            P9_TATTACH => {
+                let fid = request.u32()?;
+                let _afid = request.u32()?;
+                let attach = Attach {
+                    uname: request.string()?,
+                    aname: request.string()?,
+                    n_uname: request.u32()?,
+                };
+                self.fids.insert(
+                    fid,
+                    FidState {
+                        attach,
+                        path: Vec::new(),
+                        open_flags: None,
+                    },
+                );
+            }
+            P9_TWALK => {
+                let fid = request.u32()?;
+                let newfid = request.u32()?;
+                let nwname = request.u16()?;
+                let names = (0..nwname)
+                    .map(|_| request.string())
+                    .collect::<Option<Vec<_>>>()?;
+                // A partial walk doesn't create `newfid`.
+                if reply.u16()? != nwname {
+                    return None;
+                }
+                let mut state = self.fids.get(&fid)?.clone();
+                state.open_flags = None;
+                state.walk(names.iter().map(String::as_str));
+                self.fids.insert(newfid, state);
+            }
+            P9_TLOPEN => {
+                let fid = request.u32()?;
+                self.fids.get_mut(&fid)?.open_flags = Some(request.u32()?);
+            }
+            P9_TLCREATE => {
+                let state = self.fids.get_mut(&request.u32()?)?;
+                let name = request.string()?;
+                state.walk([name.as_str()]);
+                state.open_flags = Some(request.u32()?);
+            }
+            P9_TRENAME => {
+                let fid = request.u32()?;
+                let dfid = request.u32()?;
+                let name = request.string()?;
+                let from = self.fids.get(&fid)?.path.clone();
+                let mut to = self.fids.get(&dfid)?.clone();
+                to.walk([name.as_str()]);
+                self.rename(&from, &to.path);
+            }
+            P9_TRENAMEAT => {
+                let mut from = self.fids.get(&request.u32()?)?.clone();
+                from.walk([request.string()?.as_str()]);
+                let mut to = self.fids.get(&request.u32()?)?.clone();
+                to.walk([request.string()?.as_str()]);
+                self.rename(&from.path, &to.path);
+            }
+            _ => {}
+        }
+        Some(())
+    }
+
+    /// Moves the fids of `from` and of the files below it to `to`.
+    fn rename(&mut self, from: &[String], to: &[String]) {
+        for state in self.fids.values_mut() {
+            if state.path.starts_with(from) {
+                state.path.splice(..from.len(), to.iter().cloned());
+            }
+        }
+    }
+
+    /// Recreates the fids in `server`, which must not have been used by the guest yet. Fids whose
+    /// file no longer exists are left out, and the guest gets `EBADF` when it uses them.
+    fn restore(&self, server: &mut p9::Server) -> anyhow::Result<()> {
+        let Some((msize, version)) = &self.version else {
+            return Ok(());
+        };
+        send_message(
+            server,
+            WireWriter::new(P9_TVERSION)
+                .u32(*msize)
+                .string(version)
+                .finish(),
+        )
+        .context("failed to negotiate the 9p version")?;
+        for (fid, state) in &self.fids {
+            if let Err(e) = state.reopen(server, *fid) {
+                warn!("failed to restore 9p fid {}: {:#}", fid, e);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads the first `max_len` bytes of the 9P message in `regions`.
+fn read_message(
+    mem: &GuestMemory,
+    regions: SmallVec<[MemRegion; 2]>,
+    max_len: usize,
+) -> io::Result<Vec<u8>> {
+    let reader = Reader::new_from_regions(mem, regions);
+    let mut message = Vec::new();
+    reader.take(max_len as u64).read_to_end(&mut message)?;
+    Ok(message)
+}
+
 struct Worker {
     interrupt: Interrupt,
     queue: Queue,
     server: p9::Server,
+    fids: FidTable,
 }
 
 impl Worker {
     fn process_queue(&mut self) -> P9Result<()> {
         while let Some(mut avail_desc) = self.queue.pop() {
+            // Requests that change the fids are recorded with the reply of the server, so that the
+            // fids can be recreated when the device is restored.
+            let recorded = match avail_desc.reader.peek_obj::<[u8; P9_HEADER_SIZE]>() {
+                Ok(header) if FidTable::records(header[4]) => {
+                    let size = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+                    let request = read_message(
+                        avail_desc.mem(),
+                        avail_desc.reader.get_remaining_regions().collect(),
+                        size,
+                    )
+                    .map_err(P9Error::Internal)?;
+                    let reply_regions: SmallVec<[MemRegion; 2]> =
+                        avail_desc.writer.get_remaining_regions().collect();
+                    Some((request, reply_regions))
+                }
+                _ => None,
+            };
+
             self.server
                 .handle_message(&mut avail_desc.reader, &mut avail_desc.writer)
                 .map_err(P9Error::Internal)?;
 
             let len = avail_desc.writer.bytes_written() as u32;
 
+            if let Some((request, reply_regions)) = recorded {
+                // The header and the number of qids of an Rwalk are enough to tell the outcome.
+                let reply = read_message(
+                    avail_desc.mem(),
+                    reply_regions,
+                    (len as usize).min(P9_HEADER_SIZE + 2),
+                )
+                .map_err(P9Error::Internal)?;
+                self.fids.record(&request, &reply);
+            }
+
             self.queue.add_used(avail_desc, len);
         }
         self.queue.trigger_interrupt(&self.interrupt);
@@ -137,7 +526,16 @@ pub struct P9 {
     server: Option<p9::Server>,
     avail_features: u64,
     acked_features: u64,
-    worker: Option<WorkerThread<P9Result<()>>>,
+    // Fids of the guest in `server`, while the worker isn't running.
+    fids: FidTable,
+    worker: Option<WorkerThread<Worker>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct P9Snapshot {
+    avail_features: u64,
+    acked_features: u64,
+    fids: FidTable,
 }
 
 impl P9 {
@@ -159,6 +557,7 @@ impl P9 {
             server: Some(server),
             avail_features: base_features | 1 << VIRTIO_9P_MOUNT_TAG,
             acked_features: 0,
+            fids: FidTable::default(),
             worker: None,
         })
     }
@@ -215,17 +614,179 @@ impl VirtioDevice for P9 {
         let queue = queues.remove(&0).unwrap();
 
         let server = self.server.take().context("missing server")?;
+        let fids = mem::take(&mut self.fids);
 
         self.worker = Some(WorkerThread::start("v_9p", move |kill_evt| {
             let mut worker = Worker {
                 interrupt,
                 queue,
                 server,
+                fids,
             };
 
-            worker.run(kill_evt)
+            if let Err(e) = worker.run(kill_evt) {
+                error!("virtio-9p worker failed: {}", e);
+            }
+            worker
         }));
 
         Ok(())
     }
+
+    fn virtio_sleep(&mut self) -> anyhow::Result<Option<BTreeMap<usize, Queue>>> {
+        if let Some(worker_thread) = self.worker.take() {
+            let worker = worker_thread.stop();
+            self.server = Some(worker.server);
+            self.fids = worker.fids;
+            return Ok(Some(BTreeMap::from([(0, worker.queue)])));
+        }
+        Ok(None)
+    }
+
+    fn virtio_wake(
+        &mut self,
+        queues_state: Option<(GuestMemory, Interrupt, BTreeMap<usize, Queue>)>,
+    ) -> anyhow::Result<()> {
+        if let Some((mem, interrupt, queues)) = queues_state {
+            self.activate(mem, interrupt, queues)?;
+        }
+        Ok(())
+    }
+
+    fn virtio_snapshot(&mut self) -> anyhow::Result<serde_json::Value> {
+        anyhow::ensure!(
+            self.worker.is_none(),
+            "virtio-9p device must be asleep to be snapshotted"
+        );
+        serde_json::to_value(P9Snapshot {
+            avail_features: self.avail_features,
+            acked_features: self.acked_features,
+            fids: self.fids.clone(),
+        })
+        .context("failed to serialize 9p snapshot")
+    }
+
+    fn virtio_restore(&mut self, data: serde_json::Value) -> anyhow::Result<()> {
+        let snapshot: P9Snapshot =
+            serde_json::from_value(data).context("failed to deserialize 9p snapshot")?;
+        anyhow::ensure!(
+            self.avail_features == snapshot.avail_features,
+            "available features for 9p device do not match. expected: {}, got: {}",
+            snapshot.avail_features,
+            self.avail_features
+        );
+        self.acked_features = snapshot.acked_features;
+        let server = self
+            .server
+            .as_mut()
+            .context("virtio-9p device must be asleep to be restored")?;
+        snapshot
+            .fids
+            .restore(server)
+            .context("failed to restore 9p fids")?;
+        self.fids = snapshot.fids;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply(ty: u8) -> Vec<u8> {
+        WireWriter::new(ty).finish()
+    }
+
+    fn attach(fids: &mut FidTable, fid: u32) {
+        let request = WireWriter::new(P9_TATTACH)
+            .u32(fid)
+            .u32(P9_NOFID)
+            .string("root")
+            .string("")
+            .u32(0)
+            .finish();
+        fids.record(&request, &reply(P9_TATTACH + 1));
+    }
+
+    fn walk(fids: &mut FidTable, fid: u32, newfid: u32, names: &[&str], nwqid: u16) {
+        let mut request = WireWriter::new(P9_TWALK);
+        request.u32(fid).u32(newfid).u16(names.len() as u16);
+        for name in names {
+            request.string(name);
+        }
+        let reply = WireWriter::new(P9_TWALK + 1).u16(nwqid).finish();
+        fids.record(&request.finish(), &reply);
+    }
+
+    fn path(fids: &FidTable, fid: u32) -> Vec<&str> {
+        fids.fids[&fid].path.iter().map(String::as_str).collect()
+    }
+
+    #[test]
+    fn record_walk_and_open() {
+        let mut fids = FidTable::default();
+        attach(&mut fids, 1);
+        walk(&mut fids, 1, 2, &["a", "b", "..", "c"], 4);
+        assert_eq!(path(&fids, 2), ["a", "c"]);
+
+        // A partial walk doesn't create the new fid.
+        walk(&mut fids, 2, 3, &["d", "e"], 1);
+        assert!(!fids.fids.contains_key(&3));
+
+        let open = WireWriter::new(P9_TLOPEN).u32(2).u32(2).finish();
+        fids.record(&open, &reply(P9_TLOPEN + 1));
+        assert_eq!(fids.fids[&2].open_flags, Some(2));
+
+        let create = WireWriter::new(P9_TLCREATE)
+            .u32(1)
+            .string("new")
+            .u32(0o1102)
+            .u32(0o644)
+            .u32(0)
+            .finish();
+        fids.record(&create, &reply(P9_TLCREATE + 1));
+        assert_eq!(path(&fids, 1), ["new"]);
+        assert_eq!(fids.fids[&1].open_flags, Some(0o1102));
+    }
+
+    #[test]
+    fn record_failed_request() {
+        let mut fids = FidTable::default();
+        attach(&mut fids, 1);
+        let open = WireWriter::new(P9_TLOPEN).u32(1).u32(0).finish();
+        let error = WireWriter::new(P9_RLERROR).u32(2).finish();
+        fids.record(&open, &error);
+        assert_eq!(fids.fids[&1].open_flags, None);
+    }
+
+    #[test]
+    fn record_rename_and_clunk() {
+        let mut fids = FidTable::default();
+        attach(&mut fids, 1);
+        walk(&mut fids, 1, 2, &["dir"], 1);
+        walk(&mut fids, 2, 3, &["file"], 1);
+        walk(&mut fids, 1, 4, &["other"], 1);
+
+        let renameat = WireWriter::new(P9_TRENAMEAT)
+            .u32(1)
+            .string("dir")
+            .u32(4)
+            .string("moved")
+            .finish();
+        fids.record(&renameat, &reply(P9_TRENAMEAT + 1));
+        assert_eq!(path(&fids, 2), ["other", "moved"]);
+        assert_eq!(path(&fids, 3), ["other", "moved", "file"]);
+
+        let clunk = WireWriter::new(P9_TCLUNK).u32(3).finish();
+        fids.record(&clunk, &reply(P9_RLERROR));
+        assert!(!fids.fids.contains_key(&3));
+
+        let version = WireWriter::new(P9_TVERSION)
+            .u32(8192)
+            .string("9P2000.L")
+            .finish();
+        fids.record(&version, &reply(P9_TVERSION + 1));
+        assert_eq!(fids.version, Some((8192, "9P2000.L".to_string())));
+        assert!(fids.fids.is_empty());
+    }
 }
@@ -14,36 +14,62 @@ use zerocopy::AsBytes;
 use zerocopy::FromBytes;
 use zerocopy::FromZeroes;
 
+use crate::virtio::scsi::constants::GET_CONFIGURATION;
+use crate::virtio::scsi::constants::GET_EVENT_STATUS_NOTIFICATION;
+use crate::virtio::scsi::constants::ILLEGAL_REQUEST;
 use crate::virtio::scsi::constants::INQUIRY;
 use crate::virtio::scsi::constants::MAINTENANCE_IN;
+use crate::virtio::scsi::constants::MEDIA_EVENT_NO_CHANGE;
 use crate::virtio::scsi::constants::MODE_SELECT_6;
+use crate::virtio::scsi::constants::MODE_SENSE_10;
 use crate::virtio::scsi::constants::MODE_SENSE_6;
+use crate::virtio::scsi::constants::NOT_READY;
+use crate::virtio::scsi::constants::NO_SENSE;
+use crate::virtio::scsi::constants::PERSISTENT_RESERVE_IN;
+use crate::virtio::scsi::constants::PERSISTENT_RESERVE_OUT;
+use crate::virtio::scsi::constants::PREVENT_ALLOW_MEDIUM_REMOVAL;
+use crate::virtio::scsi::constants::PR_IN_READ_KEYS;
+use crate::virtio::scsi::constants::PR_IN_READ_RESERVATION;
+use crate::virtio::scsi::constants::PR_IN_REPORT_CAPABILITIES;
 use crate::virtio::scsi::constants::READ_10;
+use crate::virtio::scsi::constants::READ_12;
 use crate::virtio::scsi::constants::READ_6;
 use crate::virtio::scsi::constants::READ_CAPACITY_10;
 use crate::virtio::scsi::constants::READ_CAPACITY_16;
+use crate::virtio::scsi::constants::READ_TOC;
 use crate::virtio::scsi::constants::REPORT_LUNS;
 use crate::virtio::scsi::constants::REPORT_SUPPORTED_TASK_MANAGEMENT_FUNCTIONS;
+use crate::virtio::scsi::constants::REQUEST_SENSE;
 use crate::virtio::scsi::constants::SERVICE_ACTION_IN_16;
+use crate::virtio::scsi::constants::START_STOP_UNIT;
 use crate::virtio::scsi::constants::SYNCHRONIZE_CACHE_10;
 use crate::virtio::scsi::constants::TEST_UNIT_READY;
 use crate::virtio::scsi::constants::TYPE_DISK;
+use crate::virtio::scsi::constants::TYPE_NO_LUN;
 use crate::virtio::scsi::constants::UNMAP;
 use crate::virtio::scsi::constants::WRITE_10;
 use crate::virtio::scsi::constants::WRITE_SAME_10;
 use crate::virtio::scsi::constants::WRITE_SAME_16;
 use crate::virtio::scsi::device::AsyncLogicalUnit;
+use crate::virtio::scsi::device::AsyncTarget;
 use crate::virtio::scsi::device::ExecuteError;
+use crate::virtio::scsi::device::Sense;
+use crate::virtio::scsi::reservation;
+use crate::virtio::scsi::reservation::Access;
+use crate::virtio::scsi::reservation::ReserveOutParams;
 use crate::virtio::Reader;
 use crate::virtio::Writer;
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum Command {
     TestUnitReady(TestUnitReady),
+    RequestSense(RequestSense),
     Read6(Read6),
     Inquiry(Inquiry),
     ModeSelect6(ModeSelect6),
     ModeSense6(ModeSense6),
+    StartStopUnit(StartStopUnit),
+    PreventAllowMediumRemoval(PreventAllowMediumRemoval),
     ReadCapacity10(ReadCapacity10),
     ReadCapacity16(ReadCapacity16),
     Read10(Read10),
@@ -51,9 +77,16 @@ pub enum Command {
     SynchronizeCache10(SynchronizeCache10),
     WriteSame10(WriteSame10),
     Unmap(Unmap),
+    ReadToc(ReadToc),
+    GetConfiguration(GetConfiguration),
+    GetEventStatusNotification(GetEventStatusNotification),
+    ModeSense10(ModeSense10),
+    PersistentReserveIn(PersistentReserveIn),
+    PersistentReserveOut(PersistentReserveOut),
     WriteSame16(WriteSame16),
     ReportLuns(ReportLuns),
     ReportSupportedTMFs(ReportSupportedTMFs),
+    Read12(Read12),
 }
 
 impl Command {
@@ -61,20 +94,34 @@ impl Command {
         let op = cdb[0];
         match op {
             TEST_UNIT_READY => Ok(Self::TestUnitReady(Self::parse_command(cdb)?)),
+            REQUEST_SENSE => Ok(Self::RequestSense(Self::parse_command(cdb)?)),
             READ_6 => Ok(Self::Read6(Self::parse_command(cdb)?)),
             INQUIRY => Ok(Self::Inquiry(Self::parse_command(cdb)?)),
             MODE_SELECT_6 => Ok(Self::ModeSelect6(Self::parse_command(cdb)?)),
             MODE_SENSE_6 => Ok(Self::ModeSense6(Self::parse_command(cdb)?)),
+            START_STOP_UNIT => Ok(Self::StartStopUnit(Self::parse_command(cdb)?)),
+            PREVENT_ALLOW_MEDIUM_REMOVAL => {
+                Ok(Self::PreventAllowMediumRemoval(Self::parse_command(cdb)?))
+            }
             READ_CAPACITY_10 => Ok(Self::ReadCapacity10(Self::parse_command(cdb)?)),
             READ_10 => Ok(Self::Read10(Self::parse_command(cdb)?)),
             WRITE_10 => Ok(Self::Write10(Self::parse_command(cdb)?)),
             SYNCHRONIZE_CACHE_10 => Ok(Self::SynchronizeCache10(Self::parse_command(cdb)?)),
             WRITE_SAME_10 => Ok(Self::WriteSame10(Self::parse_command(cdb)?)),
             UNMAP => Ok(Self::Unmap(Self::parse_command(cdb)?)),
+            READ_TOC => Ok(Self::ReadToc(Self::parse_command(cdb)?)),
+            GET_CONFIGURATION => Ok(Self::GetConfiguration(Self::parse_command(cdb)?)),
+            GET_EVENT_STATUS_NOTIFICATION => {
+                Ok(Self::GetEventStatusNotification(Self::parse_command(cdb)?))
+            }
+            MODE_SENSE_10 => Ok(Self::ModeSense10(Self::parse_command(cdb)?)),
+            PERSISTENT_RESERVE_IN => Ok(Self::PersistentReserveIn(Self::parse_command(cdb)?)),
+            PERSISTENT_RESERVE_OUT => Ok(Self::PersistentReserveOut(Self::parse_command(cdb)?)),
             WRITE_SAME_16 => Ok(Self::WriteSame16(Self::parse_command(cdb)?)),
             SERVICE_ACTION_IN_16 => Self::parse_service_action_in_16(cdb),
             REPORT_LUNS => Ok(Self::ReportLuns(Self::parse_command(cdb)?)),
             MAINTENANCE_IN => Self::parse_maintenance_in(cdb),
+            READ_12 => Ok(Self::Read12(Self::parse_command(cdb)?)),
             _ => {
                 warn!("SCSI command {:#x?} is not implemented", op);
                 Err(ExecuteError::Unsupported(op))
@@ -127,18 +174,41 @@ impl Command {
         }
     }
 
+    /// Executes the command on `dev`, the logical unit of `target` the command is addressed to,
+    /// or on `target` itself if there is no such logical unit.
     pub async fn execute(
         &self,
         reader: &mut Reader,
         writer: &mut Writer,
-        dev: &AsyncLogicalUnit,
+        dev: Option<&AsyncLogicalUnit>,
+        target: &AsyncTarget,
     ) -> Result<(), ExecuteError> {
+        let dev = match dev {
+            Some(dev) => dev,
+            None => return self.execute_without_logical_unit(writer, target),
+        };
+        self.check_conditions(dev)?;
+        if let Some(access) = self.access() {
+            let reservations = dev.state.lock().reservations.clone();
+            // The cached reservation is enough unless another crosvm instance changed it.
+            match reservations.try_check_access(access) {
+                Some(result) => result?,
+                None => {
+                    reservations
+                        .unblock(move |r| r.check_access(access))
+                        .await?
+                }
+            }
+        }
         match self {
             Self::TestUnitReady(_) => Ok(()), // noop as the device is ready.
+            Self::RequestSense(request_sense) => request_sense.emulate(writer, dev),
             Self::Read6(read6) => read6.emulate(writer, dev).await,
             Self::Inquiry(inquiry) => inquiry.emulate(writer, dev),
             Self::ModeSelect6(mode_select_6) => mode_select_6.emulate(reader, dev),
             Self::ModeSense6(mode_sense_6) => mode_sense_6.emulate(writer, dev),
+            Self::StartStopUnit(start_stop_unit) => start_stop_unit.emulate(dev),
+            Self::PreventAllowMediumRemoval(prevent_allow) => prevent_allow.emulate(dev),
             Self::ReadCapacity10(read_capacity_10) => read_capacity_10.emulate(writer, dev),
             Self::ReadCapacity16(read_capacity_16) => read_capacity_16.emulate(writer, dev),
             Self::Read10(read_10) => read_10.emulate(writer, dev).await,
@@ -148,12 +218,93 @@ impl Command {
             }
             Self::WriteSame10(write_same_10) => write_same_10.emulate(reader, dev).await,
             Self::Unmap(unmap) => unmap.emulate(reader, dev).await,
+            Self::ReadToc(read_toc) => read_toc.emulate(writer, dev),
+            Self::GetConfiguration(get_configuration) => get_configuration.emulate(writer, dev),
+            Self::GetEventStatusNotification(get_event_status) => {
+                get_event_status.emulate(writer, dev)
+            }
+            Self::ModeSense10(mode_sense_10) => mode_sense_10.emulate(writer, dev),
+            Self::PersistentReserveIn(reserve_in) => reserve_in.emulate(writer, dev).await,
+            Self::PersistentReserveOut(reserve_out) => reserve_out.emulate(reader, dev).await,
             Self::WriteSame16(write_same_16) => write_same_16.emulate(reader, dev).await,
-            Self::ReportLuns(report_luns) => report_luns.emulate(writer),
+            Self::ReportLuns(report_luns) => report_luns.emulate(writer, target),
             Self::ReportSupportedTMFs(report_supported_tmfs) => {
                 report_supported_tmfs.emulate(writer)
             }
+            Self::Read12(read_12) => read_12.emulate(writer, dev).await,
+        }
+    }
+
+    // Executes a command addressed to a LUN without a logical unit. Only the commands that
+    // report the inventory of the target are answered, as described in SPC-3 section 4.4.
+    fn execute_without_logical_unit(
+        &self,
+        writer: &mut Writer,
+        target: &AsyncTarget,
+    ) -> Result<(), ExecuteError> {
+        match self {
+            Self::Inquiry(inquiry) => inquiry.emulate_without_logical_unit(writer),
+            Self::ReportLuns(report_luns) => report_luns.emulate(writer, target),
+            Self::RequestSense(request_sense) => request_sense.write_sense(
+                writer,
+                // LOGICAL UNIT NOT SUPPORTED
+                Sense {
+                    key: ILLEGAL_REQUEST,
+                    asc: 0x25,
+                    ascq: 0x00,
+                },
+            ),
+            _ => Err(ExecuteError::LogicalUnitNotSupported),
+        }
+    }
+
+    // Returns how the command accesses the medium, for the commands that a persistent reservation
+    // held by another I_T nexus can forbid.
+    fn access(&self) -> Option<Access> {
+        match self {
+            Self::Read6(_) | Self::Read10(_) | Self::Read12(_) => Some(Access::Read),
+            Self::Write10(_)
+            | Self::WriteSame10(_)
+            | Self::WriteSame16(_)
+            | Self::Unmap(_)
+            | Self::SynchronizeCache10(_) => Some(Access::Write),
+            _ => None,
+        }
+    }
+
+    // Reports a pending unit attention condition, or the absence of a medium, before executing
+    // the command.
+    fn check_conditions(&self, dev: &AsyncLogicalUnit) -> Result<(), ExecuteError> {
+        // Only CD-ROM drives establish unit attention conditions.
+        if !dev.cdrom {
+            return Ok(());
+        }
+        let reports_unit_attention = !matches!(
+            self,
+            Self::Inquiry(_)
+                | Self::ReportLuns(_)
+                | Self::RequestSense(_)
+                | Self::GetEventStatusNotification(_)
+        );
+        if reports_unit_attention {
+            if let Some(sense) = dev.state.lock().unit_attention.take() {
+                return Err(ExecuteError::UnitAttention(sense));
+            }
         }
+        let requires_medium = matches!(
+            self,
+            Self::TestUnitReady(_)
+                | Self::Read6(_)
+                | Self::ReadCapacity10(_)
+                | Self::ReadCapacity16(_)
+                | Self::Read10(_)
+                | Self::Read12(_)
+                | Self::ReadToc(_)
+        );
+        if requires_medium && dev.disk_image.is_none() {
+            return Err(ExecuteError::MediumNotPresent);
+        }
+        Ok(())
     }
 }
 
@@ -165,6 +316,54 @@ pub struct TestUnitReady {
     control: u8,
 }
 
+#[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct RequestSense {
+    opcode: u8,
+    desc_field: u8,
+    _reserved: [u8; 2],
+    alloc_len: u8,
+    control: u8,
+}
+
+impl RequestSense {
+    fn alloc_len(&self) -> usize {
+        self.alloc_len as usize
+    }
+
+    fn emulate(&self, writer: &mut Writer, dev: &AsyncLogicalUnit) -> Result<(), ExecuteError> {
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "REQUEST_SENSE");
+        // Sense data is returned with the response of every failed command, so only pending
+        // conditions are reported here.
+        let sense = if let Some(sense) = dev.state.lock().unit_attention.take() {
+            sense
+        } else if dev.cdrom && dev.disk_image.is_none() {
+            // MEDIUM NOT PRESENT
+            Sense {
+                key: NOT_READY,
+                asc: 0x3a,
+                ascq: 0x00,
+            }
+        } else {
+            Sense {
+                key: NO_SENSE,
+                asc: 0x00,
+                ascq: 0x00,
+            }
+        };
+        self.write_sense(writer, sense)
+    }
+
+    fn write_sense(&self, writer: &mut Writer, sense: Sense) -> Result<(), ExecuteError> {
+        // crosvm only returns the sense data in fixed format.
+        let sense_data = sense.fixed_format();
+        let len = cmp::min(self.alloc_len(), sense_data.len());
+        writer
+            .write_all(&sense_data[..len])
+            .map_err(ExecuteError::Write)
+    }
+}
+
 fn check_lba_range(max_lba: u64, sector_num: u64, sector_len: usize) -> Result<(), ExecuteError> {
     // Checking `sector_num + sector_len - 1 <= max_lba`, but we are being careful about overflows
     // and underflows.
@@ -188,9 +387,10 @@ async fn read_from_disk(
     let block_size = dev.block_size;
     let count = xfer_blocks * block_size as usize;
     let offset = lba * block_size as u64;
+    let disk_image = dev.disk()?;
     let before = writer.bytes_written();
     writer
-        .write_all_from_at_fut(&*dev.disk_image, count, offset)
+        .write_all_from_at_fut(disk_image, count, offset)
         .await
         .map_err(|desc_error| {
             let resid = count - (writer.bytes_written() - before);
@@ -274,9 +474,9 @@ impl Inquiry {
         let alloc_len = self.alloc_len();
         let mut outbuf = vec![0u8; cmp::max(writer.available_bytes(), alloc_len)];
         // Peripheral
-        outbuf[0] = TYPE_DISK;
-        // Removable bit. We currently do not support removable SCSI devices.
-        outbuf[1] = 0x0;
+        outbuf[0] = dev.peripheral_device_type();
+        // Removable bit. Only CD-ROM drives have a removable medium.
+        outbuf[1] = if dev.cdrom { 0x80 } else { 0x0 };
         // Version 0x5 indicates that the device complies to SPC-3.
         outbuf[2] = 0x5;
         // Hierarchical Support | Response Data Format
@@ -294,7 +494,12 @@ impl Inquiry {
         // Vendor
         Self::fill_left_aligned_ascii(&mut outbuf[8..16], "CROSVM");
         // Product ID
-        Self::fill_left_aligned_ascii(&mut outbuf[16..32], "CROSVM HARDDISK");
+        let product_id = if dev.cdrom {
+            "CROSVM CD-ROM"
+        } else {
+            "CROSVM HARDDISK"
+        };
+        Self::fill_left_aligned_ascii(&mut outbuf[16..32], product_id);
         // Product revision level
         Self::fill_left_aligned_ascii(&mut outbuf[32..36], "0.1");
 
@@ -303,6 +508,24 @@ impl Inquiry {
             .map_err(ExecuteError::Write)
     }
 
+    // Reports that no logical unit is present at the addressed LUN.
+    fn emulate_without_logical_unit(&self, writer: &mut Writer) -> Result<(), ExecuteError> {
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "INQUIRY");
+        if self.vital_product_data_enabled() {
+            return Err(ExecuteError::LogicalUnitNotSupported);
+        }
+        let mut outbuf = [0u8; 36];
+        // Peripheral qualifier 011b: the target cannot have a logical unit at this LUN.
+        outbuf[0] = TYPE_NO_LUN;
+        outbuf[2] = 0x5;
+        outbuf[3] = 0x10 | 0x2;
+        outbuf[4] = 36 - 5;
+        let len = cmp::min(self.alloc_len(), outbuf.len());
+        writer
+            .write_all(&outbuf[..len])
+            .map_err(ExecuteError::Write)
+    }
+
     fn emulate_vital_product_data_page(
         &self,
         writer: &mut Writer,
@@ -311,7 +534,7 @@ impl Inquiry {
         let alloc_len = self.alloc_len();
         let mut outbuf = vec![0u8; cmp::max(4096, alloc_len)];
         // Peripheral
-        outbuf[0] = TYPE_DISK;
+        outbuf[0] = dev.peripheral_device_type();
         let page_code = self.page_code();
         outbuf[1] = page_code;
         match page_code {
@@ -322,14 +545,19 @@ impl Inquiry {
                 // 0x83: Device Identification
                 // 0xb0: Block Limits
                 // 0xb2: Logical Block Provisioning
-                const SUPPORTED_VPD_PAGE_CODES: [u8; 4] = [0x00, 0x83, 0xb0, 0xb2];
-                let page_code_len: u8 = SUPPORTED_VPD_PAGE_CODES
+                // CD-ROM drives do not support block provisioning.
+                let supported_vpd_page_codes: &[u8] = if dev.cdrom {
+                    &[0x00, 0x83]
+                } else {
+                    &[0x00, 0x83, 0xb0, 0xb2]
+                };
+                let page_code_len: u8 = supported_vpd_page_codes
                     .len()
                     .try_into()
                     .expect("The number of vpd page codes cannot exceed u8::MAX");
                 // Page legth
                 outbuf[3] = page_code_len;
-                outbuf[4..4 + page_code_len as usize].copy_from_slice(&SUPPORTED_VPD_PAGE_CODES);
+                outbuf[4..4 + page_code_len as usize].copy_from_slice(supported_vpd_page_codes);
             }
             // Device Identification
             0x83 => {
@@ -351,7 +579,7 @@ impl Inquiry {
                 outbuf[8..8 + device_id_len as usize].copy_from_slice(DEVICE_ID);
             }
             // Block Limits
-            0xb0 => {
+            0xb0 if !dev.cdrom => {
                 // Page length
                 outbuf[3] = 0x3c;
                 // We do not support a value of zero in the NUMBER OF LOGICAL BLOCKS field in the
@@ -371,7 +599,7 @@ impl Inquiry {
                 outbuf[36..44].copy_from_slice(&dev.max_lba.to_be_bytes());
             }
             // Logical Block Provisioning
-            0xb2 => {
+            0xb2 if !dev.cdrom => {
                 // Page length
                 outbuf[3] = 4;
                 // skip outbuf[4]: crosvm does not support logical block provisioning threshold
@@ -407,6 +635,7 @@ fn fill_mode_page(
     page_code: u8,
     subpage_code: u8,
     page_control: PageControl,
+    dev: &AsyncLogicalUnit,
     outbuf: &mut [u8],
 ) -> Option<u8> {
     // outbuf[0]: page code
@@ -426,7 +655,7 @@ fn fill_mode_page(
             Some(LEN + 2)
         }
         // Caching.
-        (0x08, 0x00) => {
+        (0x08, 0x00) if !dev.cdrom => {
             const LEN: u8 = 0x12;
             outbuf[0] = page_code;
             outbuf[1] = LEN;
@@ -436,10 +665,108 @@ fn fill_mode_page(
             }
             Some(LEN + 2)
         }
+        // MM capabilities and mechanical status. Refer to the Table 764 in the MMC-6 spec.
+        (0x2a, 0x00) if dev.cdrom => {
+            const LEN: u8 = 0x14;
+            outbuf[0] = page_code;
+            outbuf[1] = LEN;
+            if page_control != PageControl::Changable {
+                let locked = dev
+                    .state
+                    .lock()
+                    .medium
+                    .as_ref()
+                    .is_some_and(|medium| medium.prevent_removal);
+                // Tray loading mechanism, eject supported, lock supported and the lock state.
+                outbuf[6] = 0x29 | if locked { 0x02 } else { 0x00 };
+                // Maximum and current read speed in kB/s (obsolete).
+                outbuf[8..10].copy_from_slice(&1408u16.to_be_bytes());
+                outbuf[14..16].copy_from_slice(&1408u16.to_be_bytes());
+            }
+            Some(LEN + 2)
+        }
         _ => None,
     }
 }
 
+// Fill in the mode pages specified by the pair of the page code and the subpage code, starting at
+// `outbuf[*idx]`. Refer to the Table 99 in the SPC-3 spec for more details:
+// <https://www.t10.org/cgi-bin/ac.pl?t=f&f=spc3r23.pdf>
+fn fill_mode_pages(
+    page_code: u8,
+    subpage_code: u8,
+    page_control: PageControl,
+    dev: &AsyncLogicalUnit,
+    outbuf: &mut [u8],
+    idx: &mut usize,
+) -> Result<(), ExecuteError> {
+    match (page_code, subpage_code) {
+        // Return all mode pages with subpage 0.
+        (0x3f, 0x00) => add_all_page_codes(subpage_code, page_control, dev, outbuf, idx),
+        // Return all mode pages with subpages 0x00-0xfe.
+        (0x3f, 0xff) => {
+            for subpage_code in 0..0xff {
+                add_all_page_codes(subpage_code, page_control, dev, outbuf, idx)
+            }
+        }
+        // subpage_code other than 0x00 or 0xff are reserved.
+        (0x3f, _) => return Err(ExecuteError::InvalidField),
+        // Return a specific mode page with subpages 0x00-0xfe.
+        (_, 0xff) => {
+            for subpage_code in 0..0xff {
+                match fill_mode_page(
+                    page_code,
+                    subpage_code,
+                    page_control,
+                    dev,
+                    &mut outbuf[*idx..],
+                ) {
+                    Some(n) => *idx += n as usize,
+                    None => return Err(ExecuteError::InvalidField),
+                };
+            }
+        }
+        (_, _) => {
+            match fill_mode_page(
+                page_code,
+                subpage_code,
+                page_control,
+                dev,
+                &mut outbuf[*idx..],
+            ) {
+                Some(n) => *idx += n as usize,
+                None => return Err(ExecuteError::InvalidField),
+            };
+        }
+    };
+    Ok(())
+}
+
+// Fill in mode pages with a specific subpage_code.
+fn add_all_page_codes(
+    subpage_code: u8,
+    page_control: PageControl,
+    dev: &AsyncLogicalUnit,
+    outbuf: &mut [u8],
+    idx: &mut usize,
+) {
+    for page_code in 1..0x3f {
+        if let Some(n) = fill_mode_page(
+            page_code,
+            subpage_code,
+            page_control,
+            dev,
+            &mut outbuf[*idx..],
+        ) {
+            *idx += n as usize;
+        }
+    }
+    // Add mode page 0 after all other mode pages were returned.
+    if let Some(n) = fill_mode_page(0, subpage_code, page_control, dev, &mut outbuf[*idx..]) {
+        *idx += n as usize;
+    }
+}
+
 // According to the spec, devices that implement MODE SENSE(6) shall also implement MODE SELECT(6)
 // as well.
 #[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes, PartialEq, Eq)]
@@ -505,12 +832,12 @@ impl ModeSelect6 {
             _ => return Err(ExecuteError::InvalidField),
         };
         while reader.available_bytes() > 0 {
-            Self::handle_mode_page(reader)?;
+            Self::handle_mode_page(reader, dev)?;
         }
         Ok(())
     }
 
-    fn handle_mode_page(reader: &mut Reader) -> Result<(), ExecuteError> {
+    fn handle_mode_page(reader: &mut Reader, dev: &AsyncLogicalUnit) -> Result<(), ExecuteError> {
         #[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes, PartialEq, Eq)]
         #[repr(C, packed)]
         struct Page0Header {
@@ -543,7 +870,13 @@ impl ModeSelect6 {
             )
         };
         let mut outbuf = vec![0; page_len as usize];
-        fill_mode_page(page_code, subpage_code, PageControl::Current, &mut outbuf);
+        fill_mode_page(
+            page_code,
+            subpage_code,
+            PageControl::Current,
+            dev,
+            &mut outbuf,
+        );
         let mut input = vec![0; page_len as usize];
         reader.read_exact(&mut input).map_err(ExecuteError::Read)?;
         // crosvm does not allow any values to be changed.
@@ -628,79 +961,85 @@ impl ModeSense6 {
             4
         };
 
-        let page_control = self.page_control()?;
-        let page_code = self.page_code();
-        let subpage_code = self.subpage_code();
-        // The pair of the page code and the subpage code specifies which mode pages and subpages
-        // to return. Refer to the Table 99 in the SPC-3 spec for more details:
-        // <https://www.t10.org/cgi-bin/ac.pl?t=f&f=spc3r23.pdf>
-        match (page_code, subpage_code) {
-            // Return all mode pages with subpage 0.
-            (0x3f, 0x00) => {
-                Self::add_all_page_codes(subpage_code, page_control, &mut outbuf, &mut idx)
-            }
-            // Return all mode pages with subpages 0x00-0xfe.
-            (0x3f, 0xff) => {
-                for subpage_code in 0..0xff {
-                    Self::add_all_page_codes(subpage_code, page_control, &mut outbuf, &mut idx)
-                }
-            }
-            // subpage_code other than 0x00 or 0xff are reserved.
-            (0x3f, _) => return Err(ExecuteError::InvalidField),
-            // Return a specific mode page with subpages 0x00-0xfe.
-            (_, 0xff) => {
-                for subpage_code in 0..0xff {
-                    match fill_mode_page(
-                        page_code,
-                        subpage_code,
-                        page_control,
-                        &mut outbuf[idx as usize..],
-                    ) {
-                        Some(n) => idx += n,
-                        None => return Err(ExecuteError::InvalidField),
-                    };
-                }
-            }
-            (_, _) => {
-                match fill_mode_page(
-                    page_code,
-                    subpage_code,
-                    page_control,
-                    &mut outbuf[idx as usize..],
-                ) {
-                    Some(n) => idx += n,
-                    None => return Err(ExecuteError::InvalidField),
-                };
-            }
-        };
-        outbuf[0] = idx - 1;
+        fill_mode_pages(
+            self.page_code(),
+            self.subpage_code(),
+            self.page_control()?,
+            dev,
+            &mut outbuf,
+            &mut idx,
+        )?;
+        // The mode data length does not include itself.
+        outbuf[0] = u8::try_from(idx - 1).map_err(|_| ExecuteError::InvalidField)?;
         writer
             .write_all(&outbuf[..alloc_len])
             .map_err(ExecuteError::Write)
     }
+}
 
-    // Fill in mode pages with a specific subpage_code.
-    fn add_all_page_codes(
-        subpage_code: u8,
-        page_control: PageControl,
-        outbuf: &mut [u8],
-        idx: &mut u8,
-    ) {
-        for page_code in 1..0x3f {
-            if let Some(n) = fill_mode_page(
-                page_code,
-                subpage_code,
-                page_control,
-                &mut outbuf[*idx as usize..],
-            ) {
-                *idx += n;
+#[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct StartStopUnit {
+    opcode: u8,
+    immed_field: u8,
+    _reserved: u8,
+    power_condition_modifier: u8,
+    power_condition_and_start: u8,
+    control: u8,
+}
+
+impl StartStopUnit {
+    fn load_eject(&self) -> bool {
+        self.power_condition_and_start & 0x2 != 0
+    }
+
+    fn start(&self) -> bool {
+        self.power_condition_and_start & 0x1 != 0
+    }
+
+    fn emulate(&self, dev: &AsyncLogicalUnit) -> Result<(), ExecuteError> {
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "START_STOP_UNIT");
+        // Power conditions are not emulated, and loading a medium is left to the control tube
+        // since the drive has no medium to load by itself.
+        if !self.load_eject() || self.start() {
+            return Ok(());
+        }
+        let mut state = dev.state.lock();
+        match state.medium.as_mut() {
+            Some(medium) if medium.file.is_some() => {
+                if medium.change(None, false) {
+                    Ok(())
+                } else {
+                    Err(ExecuteError::MediumRemovalPrevented)
+                }
             }
+            // Fixed disks and empty drives have nothing to eject.
+            _ => Ok(()),
         }
-        // Add mode page 0 after all other mode pages were returned.
-        if let Some(n) = fill_mode_page(0, subpage_code, page_control, &mut outbuf[*idx as usize..])
-        {
-            *idx += n;
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct PreventAllowMediumRemoval {
+    opcode: u8,
+    _reserved: [u8; 3],
+    prevent_field: u8,
+    control: u8,
+}
+
+impl PreventAllowMediumRemoval {
+    fn prevent(&self) -> bool {
+        // crosvm does not distinguish the persistent prevention from the prevention.
+        self.prevent_field & 0x1 != 0
+    }
+
+    fn emulate(&self, dev: &AsyncLogicalUnit) -> Result<(), ExecuteError> {
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "PREVENT_ALLOW_MEDIUM_REMOVAL");
+        if let Some(medium) = dev.state.lock().medium.as_mut() {
+            medium.prevent_removal = self.prevent();
         }
+        Ok(())
     }
 }
 
@@ -746,8 +1085,10 @@ impl ReadCapacity16 {
         outbuf[..8].copy_from_slice(&dev.max_lba.saturating_sub(1).to_be_bytes());
         // Block size
         outbuf[8..12].copy_from_slice(&dev.block_size.to_be_bytes());
-        // crosvm implements logical block provisioning management.
-        outbuf[14] = 1 << 7;
+        // crosvm implements logical block provisioning management for fixed disks.
+        if !dev.cdrom {
+            outbuf[14] = 1 << 7;
+        }
         writer.write_all(&outbuf).map_err(ExecuteError::Write)
     }
 }
@@ -829,9 +1170,10 @@ async fn write_to_disk(
     let block_size = dev.block_size;
     let count = xfer_blocks * block_size as usize;
     let offset = lba * block_size as u64;
+    let disk_image = dev.disk()?;
     let before = reader.bytes_read();
     reader
-        .read_exact_to_at_fut(&*dev.disk_image, count, offset)
+        .read_exact_to_at_fut(disk_image, count, offset)
         .await
         .map_err(|desc_error| {
             let resid = count - (reader.bytes_read() - before);
@@ -856,7 +1198,7 @@ impl SynchronizeCache10 {
         if dev.read_only {
             return Err(ExecuteError::ReadOnly);
         }
-        dev.disk_image.fdatasync().await.map_err(|e| {
+        dev.disk()?.fdatasync().await.map_err(|e| {
             warn!("failed to sync: {e}");
             ExecuteError::SynchronizationError
         })
@@ -868,7 +1210,7 @@ async fn unmap(dev: &AsyncLogicalUnit, lba: u64, nblocks: u64) -> Result<(), Exe
     let offset = lba * dev.block_size as u64;
     let length = nblocks * dev.block_size as u64;
     // Ignore the errors here since the device is not strictly required to unmap the LBAs.
-    let _ = dev.disk_image.punch_hole(offset, length).await;
+    let _ = dev.disk()?.punch_hole(offset, length).await;
     Ok(())
 }
 
@@ -886,7 +1228,7 @@ async fn write_same(
         let block_size = dev.block_size as u64;
         // Ignore the errors here since the device is not strictly required to unmap the LBAs.
         let _ = dev
-            .disk_image
+            .disk()?
             .write_zeroes_at(lba * block_size, nblocks * block_size)
             .await;
         Ok(())
@@ -1017,6 +1359,479 @@ impl Unmap {
     }
 }
 
+#[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct ReadToc {
+    opcode: u8,
+    msf_field: u8,
+    format_field: u8,
+    _reserved: [u8; 3],
+    track_number: u8,
+    alloc_len_bytes: [u8; 2],
+    control: u8,
+}
+
+impl ReadToc {
+    fn msf(&self) -> bool {
+        self.msf_field & 0x2 != 0
+    }
+
+    fn format(&self) -> u8 {
+        self.format_field & 0xf
+    }
+
+    fn alloc_len(&self) -> usize {
+        u16::from_be_bytes(self.alloc_len_bytes) as usize
+    }
+
+    // Returns the address of `lba` in the format requested by the MSF bit.
+    fn address(&self, lba: u64) -> [u8; 4] {
+        if self.msf() {
+            // The first track starts 2 seconds (150 frames) after the beginning of the disc.
+            let frames = lba + 150;
+            let minutes = (frames / (75 * 60)).try_into().unwrap_or(u8::MAX);
+            [0, minutes, ((frames / 75) % 60) as u8, (frames % 75) as u8]
+        } else {
+            (lba as u32).to_be_bytes()
+        }
+    }
+
+    fn emulate(&self, writer: &mut Writer, dev: &AsyncLogicalUnit) -> Result<(), ExecuteError> {
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "READ_TOC");
+        if !dev.cdrom {
+            return Err(ExecuteError::Unsupported(READ_TOC));
+        }
+        // A medium is emulated as a single session data disc with a single track.
+        // Q sub-channel ADR 1 and CONTROL of a data track.
+        const ADR_CONTROL: u8 = 0x14;
+        const LEAD_OUT: u8 = 0xaa;
+        // outbuf[0..2]: TOC data length. Will be filled later.
+        let mut outbuf = vec![0u8; 4];
+        match self.format() {
+            // Formatted TOC
+            0x0 => {
+                // First and last track number
+                outbuf[2] = 1;
+                outbuf[3] = 1;
+                let tracks: &[(u8, u64)] = match self.track_number {
+                    0 | 1 => &[(1, 0), (LEAD_OUT, dev.max_lba)],
+                    LEAD_OUT => &[(LEAD_OUT, dev.max_lba)],
+                    _ => return Err(ExecuteError::InvalidField),
+                };
+                for (track, lba) in tracks {
+                    outbuf.extend_from_slice(&[0, ADR_CONTROL, *track, 0]);
+                    outbuf.extend_from_slice(&self.address(*lba));
+                }
+            }
+            // Multi-session information
+            0x1 => {
+                // First and last complete session number
+                outbuf[2] = 1;
+                outbuf[3] = 1;
+                // The first track of the last session.
+                outbuf.extend_from_slice(&[0, ADR_CONTROL, 1, 0]);
+                outbuf.extend_from_slice(&self.address(0));
+            }
+            format => {
+                warn!("unsupported READ TOC format: {:#x?}", format);
+                return Err(ExecuteError::InvalidField);
+            }
+        }
+        // The TOC data length does not include itself.
+        let data_len = (outbuf.len() - 2) as u16;
+        outbuf[..2].copy_from_slice(&data_len.to_be_bytes());
+        let len = cmp::min(self.alloc_len(), outbuf.len());
+        writer
+            .write_all(&outbuf[..len])
+            .map_err(ExecuteError::Write)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct GetConfiguration {
+    opcode: u8,
+    rt_field: u8,
+    starting_feature_bytes: [u8; 2],
+    _reserved: [u8; 3],
+    alloc_len_bytes: [u8; 2],
+    control: u8,
+}
+
+impl GetConfiguration {
+    fn request_type(&self) -> u8 {
+        self.rt_field & 0x3
+    }
+
+    fn starting_feature(&self) -> u16 {
+        u16::from_be_bytes(self.starting_feature_bytes)
+    }
+
+    fn alloc_len(&self) -> usize {
+        u16::from_be_bytes(self.alloc_len_bytes) as usize
+    }
+
+    fn emulate(&self, writer: &mut Writer, dev: &AsyncLogicalUnit) -> Result<(), ExecuteError> {
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "GET_CONFIGURATION");
+        if !dev.cdrom {
+            return Err(ExecuteError::Unsupported(GET_CONFIGURATION));
+        }
+        const PROFILE_CD_ROM: u16 = 0x0008;
+        let has_medium = dev.disk_image.is_some();
+        // The features of a CD-ROM drive. Refer to the section 5.3 in the MMC-6 spec.
+        // (feature code, version, persistent, current, feature dependent data)
+        let mut profile_list = PROFILE_CD_ROM.to_be_bytes().to_vec();
+        profile_list.extend_from_slice(&[has_medium as u8, 0]);
+        let features: [(u16, u8, bool, bool, Vec<u8>); 6] = [
+            // Profile List
+            (0x0000, 0, true, true, profile_list),
+            // Core: SCSI physical interface and device busy events.
+            (0x0001, 2, true, true, vec![0, 0, 0, 1, 0x1, 0, 0, 0]),
+            // Morphing: GET EVENT STATUS NOTIFICATION is supported.
+            (0x0002, 1, true, true, vec![0x2, 0, 0, 0]),
+            // Removable Medium: tray loading mechanism, eject and lock supported.
+            (0x0003, 0, true, true, vec![0x29, 0, 0, 0]),
+            // Random Readable
+            (
+                0x0010,
+                0,
+                false,
+                has_medium,
+                [&dev.block_size.to_be_bytes()[..], &[0, 1, 0, 0]].concat(),
+            ),
+            // CD Read
+            (0x001e, 2, false, has_medium, vec![0, 0, 0, 0]),
+        ];
+        // Feature header. outbuf[0..4]: Data length. Will be filled later.
+        let mut outbuf = vec![0u8; 8];
+        if has_medium {
+            outbuf[6..8].copy_from_slice(&PROFILE_CD_ROM.to_be_bytes());
+        }
+        let starting_feature = self.starting_feature();
+        for (code, version, persistent, current, data) in features {
+            let requested = match self.request_type() {
+                // All features
+                0x0 => code >= starting_feature,
+                // All current features
+                0x1 => code >= starting_feature && current,
+                // The starting feature only
+                0x2 => code == starting_feature,
+                _ => return Err(ExecuteError::InvalidField),
+            };
+            if !requested {
+                continue;
+            }
+            outbuf.extend_from_slice(&code.to_be_bytes());
+            outbuf.push(version << 2 | (persistent as u8) << 1 | current as u8);
+            outbuf.push(data.len() as u8);
+            outbuf.extend_from_slice(&data);
+        }
+        // The data length does not include itself.
+        let data_len = (outbuf.len() - 4) as u32;
+        outbuf[..4].copy_from_slice(&data_len.to_be_bytes());
+        let len = cmp::min(self.alloc_len(), outbuf.len());
+        writer
+            .write_all(&outbuf[..len])
+            .map_err(ExecuteError::Write)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct GetEventStatusNotification {
+    opcode: u8,
+    polled_field: u8,
+    _reserved: [u8; 2],
+    notification_class_request: u8,
+    _reserved2: [u8; 2],
+    alloc_len_bytes: [u8; 2],
+    control: u8,
+}
+
+impl GetEventStatusNotification {
+    fn polled(&self) -> bool {
+        self.polled_field & 0x1 != 0
+    }
+
+    fn alloc_len(&self) -> usize {
+        u16::from_be_bytes(self.alloc_len_bytes) as usize
+    }
+
+    fn emulate(&self, writer: &mut Writer, dev: &AsyncLogicalUnit) -> Result<(), ExecuteError> {
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "GET_EVENT_STATUS_NOTIFICATION");
+        // crosvm does not support the asynchronous operation.
+        if !self.polled() {
+            return Err(ExecuteError::InvalidField);
+        }
+        const NO_EVENT_AVAILABLE: u8 = 0x80;
+        const MEDIA_CLASS: u8 = 0x4;
+        // outbuf[0..2]: Event data length. Will be filled later.
+        let mut outbuf = vec![0u8; 4];
+        let mut state = dev.state.lock();
+        match state.medium.as_mut() {
+            Some(medium) => {
+                // Only media events are supported.
+                outbuf[3] = 1 << MEDIA_CLASS;
+                if self.notification_class_request & (1 << MEDIA_CLASS) != 0 {
+                    outbuf[2] = MEDIA_CLASS;
+                    let media_present = if medium.file.is_some() { 0x2 } else { 0x0 };
+                    outbuf.extend_from_slice(&[medium.event, media_present, 0, 0]);
+                    medium.event = MEDIA_EVENT_NO_CHANGE;
+                } else {
+                    outbuf[2] = NO_EVENT_AVAILABLE;
+                }
+            }
+            None => outbuf[2] = NO_EVENT_AVAILABLE,
+        }
+        // The event data length does not include itself.
+        let data_len = (outbuf.len() - 2) as u16;
+        outbuf[..2].copy_from_slice(&data_len.to_be_bytes());
+        let len = cmp::min(self.alloc_len(), outbuf.len());
+        writer
+            .write_all(&outbuf[..len])
+            .map_err(ExecuteError::Write)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct ModeSense10 {
+    opcode: u8,
+    dbd_field: u8,
+    page_control_and_page_code: u8,
+    subpage_code: u8,
+    _reserved: [u8; 3],
+    alloc_len_bytes: [u8; 2],
+    control: u8,
+}
+
+impl ModeSense10 {
+    fn alloc_len(&self) -> usize {
+        u16::from_be_bytes(self.alloc_len_bytes) as usize
+    }
+
+    fn disable_block_desc(&self) -> bool {
+        self.dbd_field & 0x8 != 0
+    }
+
+    fn page_code(&self) -> u8 {
+        // The top two bits represents page control field, and the rest is page code.
+        self.page_control_and_page_code & 0x3f
+    }
+
+    fn page_control(&self) -> Result<PageControl, ExecuteError> {
+        match self.page_control_and_page_code >> 6 {
+            0 => Ok(PageControl::Current),
+            1 => Ok(PageControl::Changable),
+            2 => Ok(PageControl::Default),
+            3 => Err(ExecuteError::SavingParamNotSupported),
+            _ => Err(ExecuteError::InvalidField),
+        }
+    }
+
+    fn subpage_code(&self) -> u8 {
+        self.subpage_code
+    }
+
+    fn emulate(&self, writer: &mut Writer, dev: &AsyncLogicalUnit) -> Result<(), ExecuteError> {
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "MODE_SENSE(10)");
+        let alloc_len = self.alloc_len();
+        let mut outbuf = vec![0u8; cmp::max(4096, alloc_len)];
+        // outbuf[0..2]: Represents data length. Will be filled later.
+        // outbuf[2]: Medium type should be 0.
+
+        // Device specific parameter
+        // We do not support the disabled page out (DPO) and forced unit access (FUA) bit.
+        outbuf[3] = if dev.read_only { 0x80 } else { 0x00 };
+        // outbuf[4]: We do not return long LBA block descriptors.
+        let mut idx = if !self.disable_block_desc() && dev.max_lba > 0 {
+            // Block descriptor length.
+            outbuf[6..8].copy_from_slice(&8u16.to_be_bytes());
+            // outbuf[8]: Density code is 0.
+            let sectors = dev.max_lba;
+            // Fill in the number of sectors if not bigger than 0xffffff, leave it with 0
+            // otherwise.
+            if sectors <= 0xffffff {
+                outbuf[9..12].copy_from_slice(&(sectors as u32).to_be_bytes()[1..]);
+            }
+            // outbuf[12]: reserved.
+            outbuf[13..16].copy_from_slice(&dev.block_size.to_be_bytes()[1..]);
+            16
+        } else {
+            8
+        };
+
+        fill_mode_pages(
+            self.page_code(),
+            self.subpage_code(),
+            self.page_control()?,
+            dev,
+            &mut outbuf,
+            &mut idx,
+        )?;
+        // The mode data length does not include itself.
+        let data_len = u16::try_from(idx - 2).map_err(|_| ExecuteError::InvalidField)?;
+        outbuf[..2].copy_from_slice(&data_len.to_be_bytes());
+        writer
+            .write_all(&outbuf[..alloc_len])
+            .map_err(ExecuteError::Write)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct PersistentReserveIn {
+    opcode: u8,
+    service_action_field: u8,
+    _reserved: [u8; 5],
+    alloc_len_bytes: [u8; 2],
+    control: u8,
+}
+
+impl PersistentReserveIn {
+    fn service_action(&self) -> u8 {
+        // Top three bits are reserved.
+        self.service_action_field & 0x1f
+    }
+
+    fn alloc_len(&self) -> usize {
+        u16::from_be_bytes(self.alloc_len_bytes) as usize
+    }
+
+    async fn emulate(
+        &self,
+        writer: &mut Writer,
+        dev: &AsyncLogicalUnit,
+    ) -> Result<(), ExecuteError> {
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "PERSISTENT_RESERVE_IN");
+        let reservations = dev.state.lock().reservations.clone();
+        let mut outbuf = Vec::new();
+        match self.service_action() {
+            PR_IN_READ_KEYS => {
+                let (generation, keys) = reservations.unblock(|r| r.read_keys()).await?;
+                outbuf.extend_from_slice(&generation.to_be_bytes());
+                outbuf.extend_from_slice(&(keys.len() as u32 * 8).to_be_bytes());
+                for key in keys {
+                    outbuf.extend_from_slice(&key.to_be_bytes());
+                }
+            }
+            PR_IN_READ_RESERVATION => {
+                let (generation, reservation) =
+                    reservations.unblock(|r| r.read_reservation()).await?;
+                outbuf.extend_from_slice(&generation.to_be_bytes());
+                match reservation {
+                    Some((key, reservation_type)) => {
+                        outbuf.extend_from_slice(&16u32.to_be_bytes());
+                        outbuf.extend_from_slice(&key.to_be_bytes());
+                        // Obsolete scope-specific address and a reserved byte.
+                        outbuf.extend_from_slice(&[0; 5]);
+                        // The scope is always the logical unit.
+                        outbuf.push(reservation_type);
+                        // Obsolete
+                        outbuf.extend_from_slice(&[0; 2]);
+                    }
+                    None => outbuf.extend_from_slice(&0u32.to_be_bytes()),
+                }
+            }
+            PR_IN_REPORT_CAPABILITIES => {
+                // Length
+                outbuf.extend_from_slice(&8u16.to_be_bytes());
+                // crosvm does not support the SPEC_I_PT and ALL_TG_PT bits. Reservations kept in
+                // a file always persist, so APTPL is supported (PTPL_C) and active (PTPL_A).
+                let persistent = reservations.persistent() as u8;
+                outbuf.push(persistent);
+                // The type mask is valid.
+                outbuf.push(0x80 | persistent);
+                outbuf.extend_from_slice(&reservation::supported_types_mask());
+                // Reserved
+                outbuf.extend_from_slice(&[0; 2]);
+            }
+            service_action => {
+                warn!(
+                    "service action {:#x?} for PERSISTENT_RESERVE_IN is not implemented",
+                    service_action
+                );
+                return Err(ExecuteError::InvalidField);
+            }
+        }
+        let len = cmp::min(self.alloc_len(), outbuf.len());
+        writer
+            .write_all(&outbuf[..len])
+            .map_err(ExecuteError::Write)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct PersistentReserveOut {
+    opcode: u8,
+    service_action_field: u8,
+    scope_and_type: u8,
+    _reserved: [u8; 2],
+    param_list_len_bytes: [u8; 4],
+    control: u8,
+}
+
+impl PersistentReserveOut {
+    fn service_action(&self) -> u8 {
+        // Top three bits are reserved.
+        self.service_action_field & 0x1f
+    }
+
+    fn scope(&self) -> u8 {
+        self.scope_and_type >> 4
+    }
+
+    fn reservation_type(&self) -> u8 {
+        self.scope_and_type & 0xf
+    }
+
+    fn param_list_len(&self) -> u32 {
+        u32::from_be_bytes(self.param_list_len_bytes)
+    }
+
+    async fn emulate(
+        &self,
+        reader: &mut Reader,
+        dev: &AsyncLogicalUnit,
+    ) -> Result<(), ExecuteError> {
+        #[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes, PartialEq, Eq)]
+        #[repr(C, packed)]
+        struct ParameterList {
+            key_bytes: [u8; 8],
+            service_action_key_bytes: [u8; 8],
+            _obsolete: [u8; 4],
+            flags: u8,
+            _reserved: u8,
+            _obsolete2: [u8; 2],
+        }
+
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "PERSISTENT_RESERVE_OUT");
+        // crosvm does not support the SPEC_I_PT bit, so the parameter list has a fixed length.
+        if self.param_list_len() as usize != std::mem::size_of::<ParameterList>() {
+            return Err(ExecuteError::InvalidParamLen);
+        }
+        let params = reader
+            .read_obj::<ParameterList>()
+            .map_err(ExecuteError::Read)?;
+        // SPEC_I_PT and ALL_TG_PT
+        if params.flags & 0x0c != 0 {
+            return Err(ExecuteError::InvalidParamField);
+        }
+        let (service_action, scope, reservation_type) =
+            (self.service_action(), self.scope(), self.reservation_type());
+        let params = ReserveOutParams {
+            key: u64::from_be_bytes(params.key_bytes),
+            service_action_key: u64::from_be_bytes(params.service_action_key_bytes),
+            aptpl: params.flags & 0x01 != 0,
+        };
+        let reservations = dev.state.lock().reservations.clone();
+        reservations
+            .unblock(move |r| r.execute_out(service_action, scope, reservation_type, params))
+            .await
+    }
+}
+
 #[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes, PartialEq, Eq)]
 #[repr(C, packed)]
 pub struct WriteSame16 {
@@ -1086,22 +1901,28 @@ impl ReportLuns {
         u32::from_be_bytes(self.alloc_len_bytes) as usize
     }
 
-    fn emulate(&self, writer: &mut Writer) -> Result<(), ExecuteError> {
+    fn emulate(&self, writer: &mut Writer, target: &AsyncTarget) -> Result<(), ExecuteError> {
         let _trace = cros_tracing::trace_event!(VirtioScsi, "REPORT_LUNS");
         // We need at least 16 bytes.
         if self.alloc_len() < 16 {
             return Err(ExecuteError::InvalidField);
         }
-        // Each LUN takes 8 bytes and we only support LUN0.
-        let lun_list_len = 8u32;
-        writer
-            .write_all(&lun_list_len.to_be_bytes())
-            .map_err(ExecuteError::Write)?;
-        let reserved = [0; 4];
-        writer.write_all(&reserved).map_err(ExecuteError::Write)?;
-        let lun0 = 0u64;
+        // Each LUN takes 8 bytes.
+        let lun_list_len = target.len() * 8;
+        let mut outbuf = vec![0u8; 8 + lun_list_len];
+        outbuf[..4].copy_from_slice(&(lun_list_len as u32).to_be_bytes());
+        // outbuf[4..8] is reserved.
+        for (i, lun) in target.keys().enumerate() {
+            let offset = 8 * (i + 1);
+            // LUNs below 256 use the peripheral device addressing method, and the others use the
+            // flat space addressing method. Refer to the Table 6 in the SAM-5 spec.
+            let [hi, lo] = lun.to_be_bytes();
+            outbuf[offset] = if *lun < 256 { 0 } else { 0x40 | hi };
+            outbuf[offset + 1] = lo;
+        }
+        let len = cmp::min(self.alloc_len(), outbuf.len());
         writer
-            .write_all(&lun0.to_be_bytes())
+            .write_all(&outbuf[..len])
             .map_err(ExecuteError::Write)
     }
 }
@@ -1141,6 +1962,38 @@ impl ReportSupportedTMFs {
     }
 }
 
+#[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct Read12 {
+    opcode: u8,
+    rdprotect: u8,
+    lba_bytes: [u8; 4],
+    xfer_len_bytes: [u8; 4],
+    group_number: u8,
+    control: u8,
+}
+
+impl Read12 {
+    fn xfer_len(&self) -> usize {
+        u32::from_be_bytes(self.xfer_len_bytes) as usize
+    }
+
+    fn lba(&self) -> u64 {
+        u32::from_be_bytes(self.lba_bytes) as u64
+    }
+
+    async fn emulate(
+        &self,
+        writer: &mut Writer,
+        dev: &AsyncLogicalUnit,
+    ) -> Result<(), ExecuteError> {
+        let xfer_len = self.xfer_len();
+        let lba = self.lba();
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "READ(12)", lba, xfer_len);
+        read_from_disk(writer, dev, xfer_len, lba).await
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1273,4 +2126,90 @@ mod tests {
         };
         assert_eq!(report_supported_tmfs.alloc_len(), 0xabcdef12);
     }
+
+    #[test]
+    fn parse_start_stop_unit() {
+        let cdb = [0x1b, 0x00, 0x00, 0x00, 0x02, 0x00];
+        let command = Command::new(&cdb).unwrap();
+        let start_stop_unit = match command {
+            Command::StartStopUnit(s) => s,
+            _ => panic!("unexpected command type: {:?}", command),
+        };
+        assert!(start_stop_unit.load_eject());
+        assert!(!start_stop_unit.start());
+    }
+
+    #[test]
+    fn parse_read_toc() {
+        let cdb = [0x43, 0x02, 0x01, 0x00, 0x00, 0x00, 0xaa, 0x03, 0x24, 0x00];
+        let command = Command::new(&cdb).unwrap();
+        let read_toc = match command {
+            Command::ReadToc(r) => r,
+            _ => panic!("unexpected command type: {:?}", command),
+        };
+        assert!(read_toc.msf());
+        assert_eq!(read_toc.format(), 0x1);
+        assert_eq!(read_toc.alloc_len(), 0x0324);
+        // LBA 0 is at 00:02:00.
+        assert_eq!(read_toc.address(0), [0, 0, 2, 0]);
+        assert_eq!(read_toc.address(75 * 60 - 150 + 76), [0, 1, 1, 1]);
+    }
+
+    #[test]
+    fn parse_get_configuration() {
+        let cdb = [0x46, 0x02, 0x00, 0x1e, 0x00, 0x00, 0x00, 0x00, 0x40, 0x00];
+        let command = Command::new(&cdb).unwrap();
+        let get_configuration = match command {
+            Command::GetConfiguration(g) => g,
+            _ => panic!("unexpected command type: {:?}", command),
+        };
+        assert_eq!(get_configuration.request_type(), 0x2);
+        assert_eq!(get_configuration.starting_feature(), 0x001e);
+        assert_eq!(get_configuration.alloc_len(), 0x40);
+    }
+
+    #[test]
+    fn parse_mode_sense_10() {
+        let cdb = [0x5a, 0x08, 0x6a, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00];
+        let command = Command::new(&cdb).unwrap();
+        let mode_sense_10 = match command {
+            Command::ModeSense10(m) => m,
+            _ => panic!("unexpected command type: {:?}", command),
+        };
+        assert!(mode_sense_10.disable_block_desc());
+        assert_eq!(mode_sense_10.alloc_len(), 0x0100);
+        assert_eq!(mode_sense_10.page_code(), 0x2a);
+        assert_eq!(
+            mode_sense_10.page_control().unwrap(),
+            PageControl::Changable
+        );
+    }
+
+    #[test]
+    fn parse_persistent_reserve_out() {
+        let cdb = [0x5f, 0x01, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x18, 0x00];
+        let command = Command::new(&cdb).unwrap();
+        let reserve_out = match command {
+            Command::PersistentReserveOut(r) => r,
+            _ => panic!("unexpected command type: {:?}", command),
+        };
+        assert_eq!(reserve_out.service_action(), 0x1);
+        assert_eq!(reserve_out.scope(), 0x0);
+        assert_eq!(reserve_out.reservation_type(), 0x3);
+        assert_eq!(reserve_out.param_list_len(), 24);
+    }
+
+    #[test]
+    fn parse_read12() {
+        let cdb = [
+            0xa8, 0x00, 0x00, 0x00, 0x12, 0x34, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
+        ];
+        let command = Command::new(&cdb).unwrap();
+        let read12 = match command {
+            Command::Read12(r) => r,
+            _ => panic!("unexpected command type: {:?}", command),
+        };
+        assert_eq!(read12.xfer_len(), 0x00010000);
+        assert_eq!(read12.lba(), 0x1234);
+    }
 }
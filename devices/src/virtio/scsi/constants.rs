@@ -6,6 +6,7 @@
 //! This file contains values specified in spec.
 //! SPC-3: <https://www.t10.org/cgi-bin/ac.pl?t=f&f=spc3r23.pdf>
 //! SAM-5: <https://www.t10.org/cgi-bin/ac.pl?t=f&f=sam5r21.pdf>
+//! MMC-6: <https://www.t10.org/cgi-bin/ac.pl?t=f&f=mmc6r02g.pdf>
 
 // SCSI opcodes
 /// Opcode for TEST UNIT READY command.
@@ -20,6 +21,10 @@ pub const INQUIRY: u8 = 0x12;
 pub const MODE_SELECT_6: u8 = 0x15;
 /// Opcode for MODE SENSE(6) command.
 pub const MODE_SENSE_6: u8 = 0x1a;
+/// Opcode for START STOP UNIT command.
+pub const START_STOP_UNIT: u8 = 0x1b;
+/// Opcode for PREVENT ALLOW MEDIUM REMOVAL command.
+pub const PREVENT_ALLOW_MEDIUM_REMOVAL: u8 = 0x1e;
 /// Opcode for READ CAPACITY(10) command.
 pub const READ_CAPACITY_10: u8 = 0x25;
 /// Opcode for READ(10) command.
@@ -32,6 +37,18 @@ pub const SYNCHRONIZE_CACHE_10: u8 = 0x35;
 pub const WRITE_SAME_10: u8 = 0x41;
 /// Opcode for UNMAP command.
 pub const UNMAP: u8 = 0x42;
+/// Opcode for READ TOC/PMA/ATIP command.
+pub const READ_TOC: u8 = 0x43;
+/// Opcode for GET CONFIGURATION command.
+pub const GET_CONFIGURATION: u8 = 0x46;
+/// Opcode for GET EVENT STATUS NOTIFICATION command.
+pub const GET_EVENT_STATUS_NOTIFICATION: u8 = 0x4a;
+/// Opcode for MODE SENSE(10) command.
+pub const MODE_SENSE_10: u8 = 0x5a;
+/// Opcode for PERSISTENT RESERVE IN command.
+pub const PERSISTENT_RESERVE_IN: u8 = 0x5e;
+/// Opcode for PERSISTENT RESERVE OUT command.
+pub const PERSISTENT_RESERVE_OUT: u8 = 0x5f;
 /// Opcode for WRITE SAME(16) command.
 pub const WRITE_SAME_16: u8 = 0x93;
 /// Opcode for SERVICE ACTION IN(16) command.
@@ -40,6 +57,8 @@ pub const SERVICE_ACTION_IN_16: u8 = 0x9e;
 pub const REPORT_LUNS: u8 = 0xa0;
 /// Opcode for MAINTENANCE IN command.
 pub const MAINTENANCE_IN: u8 = 0xa3;
+/// Opcode for READ(12) command.
+pub const READ_12: u8 = 0xa8;
 
 // The service actions of MAINTENANCE IN command.
 /// REPORT SUPPORTED TASK MANAGEMENT FUNCTIONS
@@ -49,23 +68,67 @@ pub const REPORT_SUPPORTED_TASK_MANAGEMENT_FUNCTIONS: u8 = 0x0d;
 /// READ CAPACITY(16)
 pub const READ_CAPACITY_16: u8 = 0x10;
 
+// The service actions of PERSISTENT RESERVE IN command.
+/// READ KEYS
+pub const PR_IN_READ_KEYS: u8 = 0x00;
+/// READ RESERVATION
+pub const PR_IN_READ_RESERVATION: u8 = 0x01;
+/// REPORT CAPABILITIES
+pub const PR_IN_REPORT_CAPABILITIES: u8 = 0x02;
+
+// The service actions of PERSISTENT RESERVE OUT command.
+/// REGISTER
+pub const PR_OUT_REGISTER: u8 = 0x00;
+/// RESERVE
+pub const PR_OUT_RESERVE: u8 = 0x01;
+/// RELEASE
+pub const PR_OUT_RELEASE: u8 = 0x02;
+/// CLEAR
+pub const PR_OUT_CLEAR: u8 = 0x03;
+/// PREEMPT
+pub const PR_OUT_PREEMPT: u8 = 0x04;
+/// PREEMPT AND ABORT
+pub const PR_OUT_PREEMPT_AND_ABORT: u8 = 0x05;
+/// REGISTER AND IGNORE EXISTING KEY
+pub const PR_OUT_REGISTER_AND_IGNORE_EXISTING_KEY: u8 = 0x06;
+
 // SAM status code
 /// Indicates the completion of the command without error.
 pub const GOOD: u8 = 0x00;
 /// Indicates that sense data has been delivered in the buffer.
 pub const CHECK_CONDITION: u8 = 0x02;
+/// Indicates that the command conflicts with a persistent reservation.
+pub const RESERVATION_CONFLICT: u8 = 0x18;
 
 // Device Types
 /// Indicates the id of disk type.
 pub const TYPE_DISK: u8 = 0x00;
+/// Indicates the id of CD/DVD device type.
+pub const TYPE_ROM: u8 = 0x05;
+/// Indicates that no logical unit is present at the addressed LUN.
+pub const TYPE_NO_LUN: u8 = 0x7f;
 
 // SENSE KEYS
 /// Indicates that there is no specific sense data to be reported.
 pub const NO_SENSE: u8 = 0x00;
+/// Indicates that the logical unit is not accessible, e.g. because it has no medium.
+pub const NOT_READY: u8 = 0x02;
 /// Indicates an error that may have been caused by a flaw in the medium or an error in the
 /// recorded data.
 pub const MEDIUM_ERROR: u8 = 0x03;
+/// Indicates a non-recoverable failure of the device.
+pub const HARDWARE_ERROR: u8 = 0x04;
 /// Indicates an illegal request.
 pub const ILLEGAL_REQUEST: u8 = 0x05;
 /// Indicates that a unit attention condition has been established.
 pub const UNIT_ATTENTION: u8 = 0x06;
+
+// Media event codes reported by GET EVENT STATUS NOTIFICATION command.
+/// Indicates that the media status has not changed.
+pub const MEDIA_EVENT_NO_CHANGE: u8 = 0x00;
+/// Indicates that the user requested the medium to be ejected.
+pub const MEDIA_EVENT_EJECT_REQUEST: u8 = 0x01;
+/// Indicates that a medium has been inserted.
+pub const MEDIA_EVENT_NEW_MEDIA: u8 = 0x02;
+/// Indicates that the medium has been removed.
+pub const MEDIA_EVENT_MEDIA_REMOVAL: u8 = 0x03;
@@ -4,22 +4,27 @@
 
 #![deny(missing_docs)]
 //! A SCSI controller has SCSI target(s), a SCSI target has logical unit(s).
-//! crosvm currently supports only one logical unit in a target (LUN0), therefore a SCSI target is
-//! tied to a logical unit and a disk image belongs to a logical unit in crosvm.
+//! A disk image belongs to a logical unit in crosvm. A logical unit is either a fixed disk or a
+//! CD-ROM drive, whose medium can be ejected and inserted by the guest or through the control tube.
 
 use std::cell::RefCell;
 use std::collections::BTreeMap;
-use std::collections::BTreeSet;
 use std::io;
 use std::io::Read;
 use std::io::Write;
+use std::path::Path;
 use std::rc::Rc;
+use std::sync::Arc;
 
 use anyhow::Context;
 use base::error;
 use base::warn;
+use base::AsRawDescriptor;
+use base::Error as SysError;
 use base::Event;
+use base::Tube;
 use base::WorkerThread;
+use cros_async::AsyncTube;
 use cros_async::EventAsync;
 use cros_async::Executor;
 use cros_async::ExecutorKind;
@@ -30,6 +35,7 @@ use futures::stream::FuturesUnordered;
 use futures::FutureExt;
 use futures::StreamExt;
 use remain::sorted;
+use sync::Mutex;
 use thiserror::Error as ThisError;
 use virtio_sys::virtio_scsi::virtio_scsi_config;
 use virtio_sys::virtio_scsi::virtio_scsi_ctrl_an_resp;
@@ -48,6 +54,8 @@ use virtio_sys::virtio_scsi::VIRTIO_SCSI_T_AN_SUBSCRIBE;
 use virtio_sys::virtio_scsi::VIRTIO_SCSI_T_TMF;
 use virtio_sys::virtio_scsi::VIRTIO_SCSI_T_TMF_I_T_NEXUS_RESET;
 use virtio_sys::virtio_scsi::VIRTIO_SCSI_T_TMF_LOGICAL_UNIT_RESET;
+use vm_control::ScsiControlCommand;
+use vm_control::ScsiControlResult;
 use vm_memory::GuestMemory;
 use zerocopy::AsBytes;
 use zerocopy::FromBytes;
@@ -59,8 +67,19 @@ use crate::virtio::copy_config;
 use crate::virtio::scsi::commands::Command;
 use crate::virtio::scsi::constants::CHECK_CONDITION;
 use crate::virtio::scsi::constants::GOOD;
+use crate::virtio::scsi::constants::HARDWARE_ERROR;
 use crate::virtio::scsi::constants::ILLEGAL_REQUEST;
+use crate::virtio::scsi::constants::MEDIA_EVENT_EJECT_REQUEST;
+use crate::virtio::scsi::constants::MEDIA_EVENT_MEDIA_REMOVAL;
+use crate::virtio::scsi::constants::MEDIA_EVENT_NEW_MEDIA;
+use crate::virtio::scsi::constants::MEDIA_EVENT_NO_CHANGE;
 use crate::virtio::scsi::constants::MEDIUM_ERROR;
+use crate::virtio::scsi::constants::NOT_READY;
+use crate::virtio::scsi::constants::RESERVATION_CONFLICT;
+use crate::virtio::scsi::constants::TYPE_DISK;
+use crate::virtio::scsi::constants::TYPE_ROM;
+use crate::virtio::scsi::constants::UNIT_ATTENTION;
+use crate::virtio::scsi::reservation::Reservations;
 use crate::virtio::DescriptorChain;
 use crate::virtio::DeviceType as VirtioDeviceType;
 use crate::virtio::Interrupt;
@@ -86,6 +105,9 @@ const DEFAULT_MAX_TARGET: u16 = 255;
 // Max lun should be less than or equal to 16383
 const DEFAULT_MAX_LUN: u32 = 16383;
 
+// CD-ROM media always have 2048-byte logical blocks.
+const CDROM_BLOCK_SIZE: u32 = 2048;
+
 const DEFAULT_QUEUE_SIZE: u16 = 1024;
 
 // The maximum number of linked commands.
@@ -135,14 +157,24 @@ impl VirtioScsiCmdRespHeader {
 pub enum ExecuteError {
     #[error("invalid cdb field")]
     InvalidField,
+    #[error("invalid field in parameter list")]
+    InvalidParamField,
     #[error("invalid parameter length")]
     InvalidParamLen,
+    #[error("invalid release of persistent reservation")]
+    InvalidRelease,
     #[error("{length} bytes from sector {sector} exceeds end of this device {max_lba}")]
     LbaOutOfRange {
         length: usize,
         sector: u64,
         max_lba: u64,
     },
+    #[error("logical unit not supported")]
+    LogicalUnitNotSupported,
+    #[error("medium not present")]
+    MediumNotPresent,
+    #[error("medium removal prevented")]
+    MediumRemovalPrevented,
     #[error("failed to read message: {0}")]
     Read(io::Error),
     #[error("failed to read command from cdb")]
@@ -154,10 +186,16 @@ pub enum ExecuteError {
     },
     #[error("writing to a read only device")]
     ReadOnly,
+    #[error("reservation conflict")]
+    ReservationConflict,
+    #[error("failed to access the reservation file: {0}")]
+    ReservationFile(io::Error),
     #[error("saving parameters not supported")]
     SavingParamNotSupported,
     #[error("synchronization error")]
     SynchronizationError,
+    #[error("unit attention: {0:?}")]
+    UnitAttention(Sense),
     #[error("unsupported scsi command: {0}")]
     Unsupported(u8),
     #[error("failed to write message: {0}")]
@@ -208,6 +246,47 @@ impl ExecuteError {
                     ascq: 0x00,
                 }
             }
+            Self::InvalidParamField => {
+                // INVALID FIELD IN PARAMETER LIST
+                Sense {
+                    key: ILLEGAL_REQUEST,
+                    asc: 0x26,
+                    ascq: 0x00,
+                }
+            }
+            Self::InvalidRelease => {
+                // INVALID RELEASE OF PERSISTENT RESERVATION
+                Sense {
+                    key: ILLEGAL_REQUEST,
+                    asc: 0x26,
+                    ascq: 0x04,
+                }
+            }
+            Self::LogicalUnitNotSupported => {
+                // LOGICAL UNIT NOT SUPPORTED
+                Sense {
+                    key: ILLEGAL_REQUEST,
+                    asc: 0x25,
+                    ascq: 0x00,
+                }
+            }
+            Self::MediumNotPresent => {
+                // MEDIUM NOT PRESENT
+                Sense {
+                    key: NOT_READY,
+                    asc: 0x3a,
+                    ascq: 0x00,
+                }
+            }
+            Self::MediumRemovalPrevented => {
+                // MEDIUM REMOVAL PREVENTED
+                Sense {
+                    key: ILLEGAL_REQUEST,
+                    asc: 0x53,
+                    ascq: 0x02,
+                }
+            }
+            Self::UnitAttention(sense) => *sense,
             Self::Unsupported(_) => {
                 // INVALID COMMAND OPERATION CODE
                 Sense {
@@ -230,12 +309,26 @@ impl ExecuteError {
                 asc: 0x39,
                 ascq: 0x00,
             },
+            Self::ReservationFile(_) => Sense {
+                // INTERNAL TARGET FAILURE
+                key: HARDWARE_ERROR,
+                asc: 0x44,
+                ascq: 0x00,
+            },
             Self::SynchronizationError => Sense {
                 // SYNCHRONIZATION ERROR
                 key: MEDIUM_ERROR,
                 asc: 0x16,
                 ascq: 0x00,
             },
+            // A reservation conflict is reported with the status only.
+            Self::ReservationConflict => {
+                let hdr = VirtioScsiCmdRespHeader {
+                    status: RESERVATION_CONFLICT,
+                    ..resp
+                };
+                return (hdr, Sense::default());
+            }
             // Ignore these errors.
             Self::ReadIo { resid, desc_error } | Self::WriteIo { resid, desc_error } => {
                 warn!("error while performing I/O {}", desc_error);
@@ -271,7 +364,28 @@ pub struct Sense {
 }
 
 impl Sense {
+    /// NOT READY TO READY CHANGE, MEDIUM MAY HAVE CHANGED
+    const MEDIUM_CHANGED: Sense = Sense {
+        key: UNIT_ATTENTION,
+        asc: 0x28,
+        ascq: 0x00,
+    };
+    /// MEDIUM NOT PRESENT, reported as a unit attention.
+    const MEDIUM_REMOVED: Sense = Sense {
+        key: UNIT_ATTENTION,
+        asc: 0x3a,
+        ascq: 0x00,
+    };
+
     fn write_to(&self, writer: &mut Writer, sense_size: u32) -> Result<(), ExecuteError> {
+        let sense_data = self.fixed_format();
+        writer.write_all(&sense_data).map_err(ExecuteError::Write)?;
+        writer.consume_bytes(sense_size as usize - sense_data.len());
+        Ok(())
+    }
+
+    /// Returns the sense data in fixed format.
+    pub fn fixed_format(&self) -> [u8; FIXED_FORMAT_SENSE_SIZE as usize] {
         let mut sense_data = [0u8; FIXED_FORMAT_SENSE_SIZE as usize];
         // Fixed format sense data has response code:
         // 1) 0x70 for current errors
@@ -290,12 +404,62 @@ impl Sense {
         sense_data[13] = self.ascq;
         // sense_data[14]: Field replaceable unit code, which we do not support.
         // sense_data[15..18]: Field replaceable unit code, which we do not support.
-        writer.write_all(&sense_data).map_err(ExecuteError::Write)?;
-        writer.consume_bytes(sense_size as usize - sense_data.len());
-        Ok(())
+        sense_data
+    }
+}
+
+/// The removable medium of a CD-ROM logical unit.
+pub struct Medium {
+    /// Incremented every time a medium is inserted or removed.
+    pub generation: u64,
+    /// The disk image of the inserted medium. `None` if the drive is empty.
+    pub file: Option<Box<dyn DiskFile>>,
+    /// Whether the guest prevents the medium from being removed.
+    pub prevent_removal: bool,
+    /// The media event to be reported by the next GET EVENT STATUS NOTIFICATION command.
+    pub event: u8,
+}
+
+impl Medium {
+    fn new(file: Box<dyn DiskFile>) -> Self {
+        Medium {
+            generation: 0,
+            file: Some(file),
+            prevent_removal: false,
+            event: MEDIA_EVENT_NO_CHANGE,
+        }
+    }
+
+    /// Replaces the medium with `file`, or removes it if `file` is `None`.
+    /// Returns false if the guest prevents the current medium from being removed, unless `force`
+    /// is set.
+    pub fn change(&mut self, file: Option<Box<dyn DiskFile>>, force: bool) -> bool {
+        if self.file.is_some() && self.prevent_removal && !force {
+            self.event = MEDIA_EVENT_EJECT_REQUEST;
+            return false;
+        }
+        self.event = if file.is_some() {
+            MEDIA_EVENT_NEW_MEDIA
+        } else {
+            MEDIA_EVENT_MEDIA_REMOVAL
+        };
+        self.file = file;
+        self.generation += 1;
+        true
     }
 }
 
+/// State of a logical unit shared by all request queues and the control tube.
+#[derive(Default)]
+pub struct LogicalUnitState {
+    /// The persistent reservations of the logical unit.
+    pub reservations: Reservations,
+    /// The medium of a CD-ROM logical unit. `None` for fixed disks.
+    pub medium: Option<Medium>,
+    /// A unit attention condition to be reported to the next command.
+    pub unit_attention: Option<Sense>,
+}
+
 /// Describes each SCSI logical unit.
 struct LogicalUnit {
     /// The maximum logical block address of the target device.
@@ -303,21 +467,28 @@ struct LogicalUnit {
     /// Block size of the target device.
     block_size: u32,
     read_only: bool,
-    // Represents the image on disk.
-    disk_image: Box<dyn DiskFile>,
+    // Represents the image on disk. CD-ROM logical units keep their image in `state`.
+    disk_image: Option<Box<dyn DiskFile>>,
+    state: Arc<Mutex<LogicalUnitState>>,
 }
 
 impl LogicalUnit {
     fn make_async(self, ex: &Executor) -> anyhow::Result<AsyncLogicalUnit> {
-        let disk_image = self
-            .disk_image
+        let disk_image = match self.disk_image {
+            Some(disk_image) => disk_image,
+            None => return AsyncLogicalUnit::load_medium(self.state, ex),
+        };
+        let disk_image = disk_image
             .to_async_disk(ex)
             .context("Failed to create async disk")?;
         Ok(AsyncLogicalUnit {
             max_lba: self.max_lba,
             block_size: self.block_size,
             read_only: self.read_only,
-            disk_image,
+            cdrom: false,
+            disk_image: Some(disk_image),
+            state: self.state,
+            medium_generation: 0,
         })
     }
 }
@@ -327,47 +498,158 @@ pub struct AsyncLogicalUnit {
     pub max_lba: u64,
     pub block_size: u32,
     pub read_only: bool,
-    // Represents the async image on disk.
-    pub disk_image: Box<dyn AsyncDisk>,
+    pub cdrom: bool,
+    // Represents the async image on disk. `None` if a CD-ROM drive has no medium.
+    pub disk_image: Option<Box<dyn AsyncDisk>>,
+    pub state: Arc<Mutex<LogicalUnitState>>,
+    // The generation of the medium `disk_image` was created from.
+    medium_generation: u64,
+}
+
+impl AsyncLogicalUnit {
+    // Creates a CD-ROM logical unit from the medium currently inserted in the drive.
+    fn load_medium(state: Arc<Mutex<LogicalUnitState>>, ex: &Executor) -> anyhow::Result<Self> {
+        let (medium_generation, disk_image) = {
+            let state = state.lock();
+            let medium = state.medium.as_ref().context("not a CD-ROM logical unit")?;
+            let disk_image = match &medium.file {
+                Some(file) => Some(
+                    file.try_clone()
+                        .context("Failed to clone the medium")?
+                        .to_async_disk(ex)
+                        .context("Failed to create async disk")?,
+                ),
+                None => None,
+            };
+            (medium.generation, disk_image)
+        };
+        let max_lba = match &disk_image {
+            Some(disk_image) => {
+                disk_image
+                    .get_len()
+                    .context("Failed to get the length of the medium")?
+                    / CDROM_BLOCK_SIZE as u64
+            }
+            None => 0,
+        };
+        Ok(AsyncLogicalUnit {
+            max_lba,
+            block_size: CDROM_BLOCK_SIZE,
+            read_only: true,
+            cdrom: true,
+            disk_image,
+            state,
+            medium_generation,
+        })
+    }
+
+    /// Returns the peripheral device type reported by INQUIRY.
+    pub fn peripheral_device_type(&self) -> u8 {
+        if self.cdrom {
+            TYPE_ROM
+        } else {
+            TYPE_DISK
+        }
+    }
+
+    /// Returns the disk image, or an error if a CD-ROM drive has no medium.
+    pub fn disk(&self) -> Result<&dyn AsyncDisk, ExecuteError> {
+        self.disk_image
+            .as_deref()
+            .ok_or(ExecuteError::MediumNotPresent)
+    }
 }
 
 type TargetId = u8;
-struct Targets(BTreeMap<TargetId, LogicalUnit>);
+/// Logical unit number of a logical unit in its target.
+pub type Lun = u16;
+
+/// The logical units of a target as seen by a request queue. CD-ROM logical units are replaced
+/// whenever their medium changes.
+pub type AsyncTarget = BTreeMap<Lun, RefCell<Rc<AsyncLogicalUnit>>>;
+
+struct Targets(BTreeMap<TargetId, BTreeMap<Lun, LogicalUnit>>);
 
 impl Targets {
     fn try_clone(&self) -> io::Result<Self> {
-        let logical_units = self
+        let targets = self
             .0
             .iter()
-            .map(|(id, logical_unit)| {
-                let disk_image = logical_unit.disk_image.try_clone()?;
-                Ok((
-                    *id,
-                    LogicalUnit {
-                        disk_image,
-                        max_lba: logical_unit.max_lba,
-                        block_size: logical_unit.block_size,
-                        read_only: logical_unit.read_only,
-                    },
-                ))
+            .map(|(id, logical_units)| {
+                let logical_units = logical_units
+                    .iter()
+                    .map(|(lun, logical_unit)| {
+                        let disk_image = logical_unit
+                            .disk_image
+                            .as_ref()
+                            .map(|disk_image| disk_image.try_clone())
+                            .transpose()?;
+                        Ok((
+                            *lun,
+                            LogicalUnit {
+                                disk_image,
+                                max_lba: logical_unit.max_lba,
+                                block_size: logical_unit.block_size,
+                                read_only: logical_unit.read_only,
+                                state: logical_unit.state.clone(),
+                            },
+                        ))
+                    })
+                    .collect::<io::Result<_>>()?;
+                Ok((*id, logical_units))
             })
             .collect::<io::Result<_>>()?;
-        Ok(Self(logical_units))
+        Ok(Self(targets))
     }
 
-    fn target_ids(&self) -> BTreeSet<TargetId> {
-        self.0.keys().cloned().collect()
+    fn states(&self) -> LogicalUnitStates {
+        self.0
+            .iter()
+            .map(|(id, logical_units)| {
+                let states = logical_units
+                    .iter()
+                    .map(|(lun, logical_unit)| (*lun, logical_unit.state.clone()))
+                    .collect();
+                (*id, states)
+            })
+            .collect()
+    }
+
+    fn make_async(self, ex: &Executor) -> anyhow::Result<BTreeMap<TargetId, AsyncTarget>> {
+        self.0
+            .into_iter()
+            .map(|(id, logical_units)| {
+                let logical_units = logical_units
+                    .into_iter()
+                    .map(|(lun, unit)| {
+                        let async_unit = unit.make_async(ex)?;
+                        Ok((lun, RefCell::new(Rc::new(async_unit))))
+                    })
+                    .collect::<anyhow::Result<_>>()?;
+                Ok((id, logical_units))
+            })
+            .collect()
     }
 }
 
+type LogicalUnitStates = BTreeMap<TargetId, BTreeMap<Lun, Arc<Mutex<LogicalUnitState>>>>;
+
 /// Configuration of each SCSI device.
 pub struct DiskConfig {
     /// The disk file of the device.
     pub file: Box<dyn DiskFile>,
-    /// The block size of the SCSI disk.
+    /// The block size of the SCSI disk. Ignored for CD-ROM drives.
     pub block_size: u32,
     /// Indicates whether the SCSI disk is read only.
     pub read_only: bool,
+    /// The target the device belongs to.
+    pub target: u8,
+    /// The logical unit number of the device in its target.
+    pub lun: u16,
+    /// Indicates whether the device is a CD-ROM drive with a removable medium.
+    pub cdrom: bool,
+    /// The persistent reservations of the device.
+    pub reservations: Reservations,
 }
 
 /// Vitio device for exposing SCSI command operations on a host file.
@@ -384,40 +666,73 @@ pub struct Controller {
     cdb_size: u32,
     executor_kind: ExecutorKind,
     worker_threads: Vec<WorkerThread<()>>,
-    // Stores target devices by its target id. Currently we only support bus id 0.
+    // Stores target devices by its target id and LUN. Currently we only support bus id 0.
     targets: Option<Targets>,
     // Whether the devices handles requests in multiple request queues.
     // If true, each virtqueue will be handled in a separate worker thread.
     multi_queue: bool,
+    // Receives commands to change the media of CD-ROM drives.
+    control_tube: Option<Tube>,
 }
 
 impl Controller {
     /// Creates a virtio-scsi device.
-    pub fn new(base_features: u64, disks: Vec<DiskConfig>) -> anyhow::Result<Self> {
+    pub fn new(
+        base_features: u64,
+        disks: Vec<DiskConfig>,
+        control_tube: Option<Tube>,
+    ) -> anyhow::Result<Self> {
         let multi_queue = disks.iter().all(|disk| disk.file.try_clone().is_ok());
         let num_queues = if multi_queue {
             MAX_NUM_QUEUES
         } else {
             MIN_NUM_QUEUES
         };
-        let logical_units = disks
-            .into_iter()
-            .enumerate()
-            .map(|(i, disk)| {
+        let mut targets = BTreeMap::new();
+        for disk in disks {
+            if disk.target as u16 > DEFAULT_MAX_TARGET || disk.lun as u32 > DEFAULT_MAX_LUN {
+                anyhow::bail!("invalid address {}:{}", disk.target, disk.lun);
+            }
+            let logical_unit = if disk.cdrom {
+                LogicalUnit {
+                    // Computed from the medium by each request queue.
+                    max_lba: 0,
+                    block_size: CDROM_BLOCK_SIZE,
+                    read_only: true,
+                    disk_image: None,
+                    state: Arc::new(Mutex::new(LogicalUnitState {
+                        reservations: disk.reservations,
+                        medium: Some(Medium::new(disk.file)),
+                        ..Default::default()
+                    })),
+                }
+            } else {
                 let max_lba = disk
                     .file
                     .get_len()
                     .context("Failed to get the length of the disk image")?
                     / disk.block_size as u64;
-                let target = LogicalUnit {
+                LogicalUnit {
                     max_lba,
                     block_size: disk.block_size,
                     read_only: disk.read_only,
-                    disk_image: disk.file,
-                };
-                Ok((i as TargetId, target))
-            })
-            .collect::<anyhow::Result<_>>()?;
+                    disk_image: Some(disk.file),
+                    state: Arc::new(Mutex::new(LogicalUnitState {
+                        reservations: disk.reservations,
+                        ..Default::default()
+                    })),
+                }
+            };
+            let logical_units: &mut BTreeMap<Lun, LogicalUnit> =
+                targets.entry(disk.target).or_default();
+            if logical_units.insert(disk.lun, logical_unit).is_some() {
+                anyhow::bail!(
+                    "more than one device at address {}:{}",
+                    disk.target,
+                    disk.lun
+                );
+            }
+        }
         // b/300560198: Support feature bits in virtio-scsi.
         Ok(Self {
             avail_features: base_features,
@@ -427,8 +742,9 @@ impl Controller {
             cdb_size: VIRTIO_SCSI_CDB_DEFAULT_SIZE,
             executor_kind: ExecutorKind::default(),
             worker_threads: vec![],
-            targets: Some(Targets(logical_units)),
+            targets: Some(Targets(targets)),
             multi_queue,
+            control_tube,
         })
     }
 
@@ -453,7 +769,7 @@ impl Controller {
     fn execute_control(
         reader: &mut Reader,
         writer: &mut Writer,
-        target_ids: &BTreeSet<TargetId>,
+        states: &LogicalUnitStates,
     ) -> Result<(), ExecuteError> {
         let typ = reader.peek_obj::<u32>().map_err(ExecuteError::Read)?;
         match typ {
@@ -461,7 +777,7 @@ impl Controller {
                 let tmf = reader
                     .read_obj::<virtio_scsi_ctrl_tmf_req>()
                     .map_err(ExecuteError::Read)?;
-                let resp = Self::execute_tmf(tmf, target_ids);
+                let resp = Self::execute_tmf(tmf, states);
                 writer.write_obj(resp).map_err(ExecuteError::Write)?;
                 Ok(())
             }
@@ -485,22 +801,29 @@ impl Controller {
     // Executes a TMF (task management function) request.
     fn execute_tmf(
         tmf: virtio_scsi_ctrl_tmf_req,
-        target_ids: &BTreeSet<TargetId>,
+        states: &LogicalUnitStates,
     ) -> virtio_scsi_ctrl_tmf_resp {
         match tmf.subtype {
             VIRTIO_SCSI_T_TMF_LOGICAL_UNIT_RESET | VIRTIO_SCSI_T_TMF_I_T_NEXUS_RESET => {
-                // We only have LUN0.
-                let lun = tmf.lun;
-                let target_id = lun[1];
-                let response = if target_ids.contains(&target_id) {
-                    let is_lun0 = u16::from_be_bytes([lun[2], lun[3]]) & 0x3fff == 0;
-                    if is_lun0 {
-                        VIRTIO_SCSI_S_FUNCTION_SUCCEEDED as u8
-                    } else {
-                        VIRTIO_SCSI_S_INCORRECT_LUN as u8
-                    }
-                } else {
-                    VIRTIO_SCSI_S_BAD_TARGET as u8
+                let response = match Self::parse_lun(tmf.lun) {
+                    Some((target_id, lun)) => match states.get(&target_id) {
+                        Some(logical_units) => {
+                            if tmf.subtype == VIRTIO_SCSI_T_TMF_I_T_NEXUS_RESET {
+                                VIRTIO_SCSI_S_FUNCTION_SUCCEEDED as u8
+                            } else if let Some(state) = logical_units.get(&lun) {
+                                // A reset allows the medium to be removed again. Persistent
+                                // reservations are kept.
+                                if let Some(medium) = &mut state.lock().medium {
+                                    medium.prevent_removal = false;
+                                }
+                                VIRTIO_SCSI_S_FUNCTION_SUCCEEDED as u8
+                            } else {
+                                VIRTIO_SCSI_S_INCORRECT_LUN as u8
+                            }
+                        }
+                        None => VIRTIO_SCSI_S_BAD_TARGET as u8,
+                    },
+                    None => VIRTIO_SCSI_S_BAD_TARGET as u8,
                 };
                 virtio_scsi_ctrl_tmf_resp { response }
             }
@@ -517,19 +840,26 @@ impl Controller {
         reader: &mut Reader,
         resp_writer: &mut Writer,
         data_writer: &mut Writer,
-        targets: &BTreeMap<TargetId, AsyncLogicalUnit>,
+        targets: &BTreeMap<TargetId, AsyncTarget>,
+        ex: &Executor,
         sense_size: u32,
         cdb_size: u32,
     ) -> Result<(), ExecuteError> {
         let req_header = reader
             .read_obj::<VirtioScsiCmdReqHeader>()
             .map_err(ExecuteError::Read)?;
-        match Self::get_logical_unit(req_header.lun, targets) {
-            Some(target) => {
+        match Self::get_target(req_header.lun, targets) {
+            Some((target, lun)) => {
                 let mut cdb = vec![0; cdb_size as usize];
                 reader.read_exact(&mut cdb).map_err(ExecuteError::Read)?;
                 let command = Command::new(&cdb)?;
-                match command.execute(reader, data_writer, target).await {
+                let logical_unit = target
+                    .get(&lun)
+                    .map(|logical_unit| Self::current_logical_unit(logical_unit, ex));
+                match command
+                    .execute(reader, data_writer, logical_unit.as_deref(), target)
+                    .await
+                {
                     Ok(()) => {
                         let hdr = VirtioScsiCmdRespHeader {
                             sense_len: 0,
@@ -562,35 +892,132 @@ impl Controller {
         }
     }
 
-    fn get_logical_unit(
-        lun: [u8; 8],
-        targets: &BTreeMap<TargetId, AsyncLogicalUnit>,
-    ) -> Option<&AsyncLogicalUnit> {
+    // Returns the logical unit, reloading the medium of a CD-ROM drive if it has been changed
+    // since the last request.
+    fn current_logical_unit(
+        logical_unit: &RefCell<Rc<AsyncLogicalUnit>>,
+        ex: &Executor,
+    ) -> Rc<AsyncLogicalUnit> {
+        let current = logical_unit.borrow().clone();
+        if !current.cdrom {
+            return current;
+        }
+        let generation = match &current.state.lock().medium {
+            Some(medium) => medium.generation,
+            None => return current,
+        };
+        if generation == current.medium_generation {
+            return current;
+        }
+        let reloaded = match AsyncLogicalUnit::load_medium(current.state.clone(), ex) {
+            Ok(reloaded) => Rc::new(reloaded),
+            Err(e) => {
+                error!("failed to load the new medium: {e:#}");
+                return current;
+            }
+        };
+        *logical_unit.borrow_mut() = reloaded.clone();
+        reloaded
+    }
+
+    // Decodes the target id and the LUN from the LUN field of a virtio-scsi request, which uses
+    // the single level LUN structure with flat space addressing.
+    fn parse_lun(lun: [u8; 8]) -> Option<(TargetId, Lun)> {
         // First byte should be 1.
         if lun[0] != 1 {
             return None;
         }
-        // General search strategy for scsi devices is as follows:
-        // 1) Look for a device which has the same bus id and lun indicated by the given lun. If
-        //    there is one, that is the target device.
-        // 2) If we cannot find such device, then we return the first device that has the same bus
-        //    id.
-        // Since we only support one LUN per target, we only need to use the target id.
-        let target_id = lun[1];
-        targets.get(&target_id)
+        Some((lun[1], u16::from_be_bytes([lun[2], lun[3]]) & 0x3fff))
+    }
+
+    fn get_target(
+        lun: [u8; 8],
+        targets: &BTreeMap<TargetId, AsyncTarget>,
+    ) -> Option<(&AsyncTarget, Lun)> {
+        // If the target exists but the LUN does not, the command is still sent to the target so
+        // that INQUIRY and REPORT LUNS can be answered for the missing logical unit.
+        let (target_id, lun) = Self::parse_lun(lun)?;
+        targets.get(&target_id).map(|target| (target, lun))
+    }
+}
+
+// Executes a command received through the control tube.
+fn execute_control_command(
+    command: ScsiControlCommand,
+    states: &LogicalUnitStates,
+) -> ScsiControlResult {
+    let (target, lun) = match &command {
+        ScsiControlCommand::EjectMedium { target, lun, .. }
+        | ScsiControlCommand::InsertMedium { target, lun, .. } => (*target, *lun),
+    };
+    let state = match states.get(&target).and_then(|t| t.get(&lun)) {
+        Some(state) => state,
+        None => return ScsiControlResult::Err(SysError::new(libc::ENODEV)),
+    };
+    let (file, force, unit_attention) = match command {
+        ScsiControlCommand::EjectMedium { force, .. } => (None, force, Sense::MEDIUM_REMOVED),
+        ScsiControlCommand::InsertMedium { file, path, .. } => {
+            match disk::create_disk_file(file, false, disk::MAX_NESTING_DEPTH, Path::new(&path)) {
+                Ok(file) => (Some(file), false, Sense::MEDIUM_CHANGED),
+                Err(e) => {
+                    error!("failed to open medium {}: {e}", path.display());
+                    return ScsiControlResult::Err(SysError::new(libc::EINVAL));
+                }
+            }
+        }
+    };
+    let mut state = state.lock();
+    let medium = match &mut state.medium {
+        Some(medium) => medium,
+        None => return ScsiControlResult::Err(SysError::new(libc::ENOTSUP)),
+    };
+    if !medium.change(file, force) {
+        return ScsiControlResult::Err(SysError::new(libc::EBUSY));
+    }
+    state.unit_attention = Some(unit_attention);
+    ScsiControlResult::Ok
+}
+
+async fn handle_command_tube(
+    command_tube: &AsyncTube,
+    states: &LogicalUnitStates,
+) -> anyhow::Result<()> {
+    loop {
+        let command = command_tube
+            .next::<ScsiControlCommand>()
+            .await
+            .context("failed to receive a control command")?;
+        let result = execute_control_command(command, states);
+        command_tube
+            .send(result)
+            .await
+            .context("failed to send a control result")?;
     }
 }
 
 impl VirtioDevice for Controller {
     fn keep_rds(&self) -> Vec<base::RawDescriptor> {
-        match &self.targets {
+        let mut keep_rds = match &self.targets {
             Some(targets) => targets
                 .0
                 .values()
-                .flat_map(|t| t.disk_image.as_raw_descriptors())
+                .flat_map(|t| t.values())
+                .flat_map(|t| match &t.disk_image {
+                    Some(disk_image) => disk_image.as_raw_descriptors(),
+                    None => match &t.state.lock().medium {
+                        Some(Medium {
+                            file: Some(file), ..
+                        }) => file.as_raw_descriptors(),
+                        _ => vec![],
+                    },
+                })
                 .collect(),
             None => vec![],
+        };
+        if let Some(control_tube) = &self.control_tube {
+            keep_rds.push(control_tube.as_raw_descriptor());
         }
+        keep_rds
     }
 
     fn features(&self) -> u64 {
@@ -652,7 +1079,8 @@ impl VirtioDevice for Controller {
         // We do not send any events through eventq.
         let _eventq = queues.remove(&1).context("eventq should be present")?;
         let targets = self.targets.take().context("failed to take SCSI targets")?;
-        let target_ids = targets.target_ids();
+        let states = targets.states();
+        let control_tube = self.control_tube.take();
         let sense_size = self.sense_size;
         let cdb_size = self.cdb_size;
         // The rest of the queues are request queues.
@@ -680,13 +1108,18 @@ impl VirtioDevice for Controller {
         let worker_thread = WorkerThread::start("v_scsi_ctrlq", move |kill_evt| {
             let ex =
                 Executor::with_executor_kind(executor_kind).expect("Failed to create an executor");
+            let control_tube = control_tube
+                .map(|tube| AsyncTube::new(&ex, tube).expect("failed to create async tube"));
             if let Err(err) = ex
                 .run_until(run_worker(
                     &ex,
                     intr,
                     controlq,
                     kill_evt,
-                    QueueType::Control { target_ids },
+                    QueueType::Control {
+                        states,
+                        control_tube,
+                    },
                     sense_size,
                     cdb_size,
                 ))
@@ -703,21 +1136,17 @@ impl VirtioDevice for Controller {
                 WorkerThread::start(format!("v_scsi_req_{}", i + 2), move |kill_evt| {
                     let ex = Executor::with_executor_kind(executor_kind)
                         .expect("Failed to create an executor");
-                    let async_logical_unit = targets
-                        .0
-                        .into_iter()
-                        .map(|(idx, unit)| match unit.make_async(&ex) {
-                            Ok(async_unit) => (idx, async_unit),
-                            Err(err) => panic!("{err}"),
-                        })
-                        .collect();
+                    let async_targets = match targets.make_async(&ex) {
+                        Ok(async_targets) => async_targets,
+                        Err(err) => panic!("{err:#}"),
+                    };
                     if let Err(err) = ex
                         .run_until(run_worker(
                             &ex,
                             interrupt,
                             queue,
                             kill_evt,
-                            QueueType::Request(async_logical_unit),
+                            QueueType::Request(async_targets),
                             sense_size,
                             cdb_size,
                         ))
@@ -733,8 +1162,11 @@ impl VirtioDevice for Controller {
 }
 
 enum QueueType {
-    Control { target_ids: BTreeSet<TargetId> },
-    Request(BTreeMap<TargetId, AsyncLogicalUnit>),
+    Control {
+        states: LogicalUnitStates,
+        control_tube: Option<AsyncTube>,
+    },
+    Request(BTreeMap<TargetId, AsyncTarget>),
 }
 
 async fn run_worker(
@@ -756,11 +1188,24 @@ async fn run_worker(
         .event()
         .try_clone()
         .expect("Failed to clone queue event");
+    let command_tube = async {
+        match &queue_type {
+            QueueType::Control {
+                states,
+                control_tube: Some(control_tube),
+            } => handle_command_tube(control_tube, states).await,
+            _ => futures::future::pending().await,
+        }
+    }
+    .fuse();
+    pin_mut!(command_tube);
+
     let queue_handler = handle_queue(
         Rc::new(RefCell::new(queue)),
         EventAsync::new(kick_evt, ex).expect("Failed to create async event for queue"),
         interrupt,
-        queue_type,
+        &queue_type,
+        ex,
         sense_size,
         cdb_size,
     )
@@ -769,6 +1214,7 @@ async fn run_worker(
 
     futures::select! {
         _ = queue_handler => anyhow::bail!("queue handler exited unexpectedly"),
+        r = command_tube => r.context("failed to handle the control tube"),
         r = resample => r.context("failed to resample an irq value"),
         r = kill => r.context("failed to wait on the kill event"),
     }
@@ -778,7 +1224,8 @@ async fn handle_queue(
     queue: Rc<RefCell<Queue>>,
     evt: EventAsync,
     interrupt: Interrupt,
-    queue_type: QueueType,
+    queue_type: &QueueType,
+    ex: &Executor,
     sense_size: u32,
     cdb_size: u32,
 ) {
@@ -798,12 +1245,7 @@ async fn handle_queue(
         }
         while let Some(chain) = queue.borrow_mut().pop() {
             background_tasks.push(process_one_chain(
-                &queue,
-                chain,
-                &interrupt,
-                &queue_type,
-                sense_size,
-                cdb_size,
+                &queue, chain, &interrupt, queue_type, ex, sense_size, cdb_size,
             ));
         }
     }
@@ -814,11 +1256,12 @@ async fn process_one_chain(
     mut avail_desc: DescriptorChain,
     interrupt: &Interrupt,
     queue_type: &QueueType,
+    ex: &Executor,
     sense_size: u32,
     cdb_size: u32,
 ) {
     let _trace = cros_tracing::trace_event!(VirtioScsi, "process_one_chain");
-    let len = process_one_request(&mut avail_desc, queue_type, sense_size, cdb_size).await;
+    let len = process_one_request(&mut avail_desc, queue_type, ex, sense_size, cdb_size).await;
     let mut queue = queue.borrow_mut();
     queue.add_used(avail_desc, len as u32);
     queue.trigger_interrupt(interrupt);
@@ -827,14 +1270,15 @@ async fn process_one_chain(
 async fn process_one_request(
     avail_desc: &mut DescriptorChain,
     queue_type: &QueueType,
+    ex: &Executor,
     sense_size: u32,
     cdb_size: u32,
 ) -> usize {
     let reader = &mut avail_desc.reader;
     let resp_writer = &mut avail_desc.writer;
     match queue_type {
-        QueueType::Control { target_ids } => {
-            if let Err(err) = Controller::execute_control(reader, resp_writer, target_ids) {
+        QueueType::Control { states, .. } => {
+            if let Err(err) = Controller::execute_control(reader, resp_writer, states) {
                 error!("failed to execute control request: {err}");
             }
             resp_writer.bytes_written()
@@ -847,6 +1291,7 @@ async fn process_one_request(
                 resp_writer,
                 &mut data_writer,
                 async_targets,
+                ex,
                 sense_size,
                 cdb_size,
             )
@@ -888,7 +1333,10 @@ mod tests {
 
     use super::*;
     use crate::virtio::create_descriptor_chain;
+    use crate::virtio::scsi::constants::PR_OUT_REGISTER;
+    use crate::virtio::scsi::constants::PR_OUT_RESERVE;
     use crate::virtio::scsi::constants::READ_10;
+    use crate::virtio::scsi::reservation::ReserveOutParams;
     use crate::virtio::DescriptorType;
 
     fn setup_disk(disk_size: u64) -> (File, Vec<u8>) {
@@ -908,7 +1356,7 @@ mod tests {
         cdb[5] = start_lba;
         cdb[8] = xfer_blocks;
         virtio_scsi_cmd_req {
-            lun: [1, 0, 0, target_id, 0, 0, 0, 0],
+            lun: [1, target_id, 0, 0, 0, 0, 0, 0],
             cdb,
             ..Default::default()
         }
@@ -949,6 +1397,7 @@ mod tests {
         start_lba: u8,
         xfer_blocks: u8,
         block_size: u32,
+        state: &Arc<Mutex<LogicalUnitState>>,
     ) -> (virtio_scsi_cmd_resp, Vec<u8>) {
         let xfer_bytes = xfer_blocks as u32 * block_size;
         let mem = Rc::new(
@@ -971,14 +1420,19 @@ mod tests {
                     max_lba: 0x1000,
                     block_size,
                     read_only: false,
-                    disk_image,
+                    cdrom: false,
+                    disk_image: Some(disk_image),
+                    state: state.clone(),
+                    medium_generation: 0,
                 };
-                (i as TargetId, logical_unit)
+                let target = [(0, RefCell::new(Rc::new(logical_unit)))].into();
+                (i as TargetId, target)
             })
             .collect();
         ex.run_until(process_one_request(
             &mut avail_desc,
             &QueueType::Request(targets),
+            ex,
             VIRTIO_SCSI_SENSE_DEFAULT_SIZE,
             VIRTIO_SCSI_CDB_DEFAULT_SIZE,
         ))
@@ -1020,6 +1474,7 @@ mod tests {
                 start_lba,
                 xfer_blocks,
                 block_size,
+                &Default::default(),
             );
 
             let sense_len = resp.sense_len;
@@ -1078,4 +1533,34 @@ mod tests {
         test_read_blocks(3, blocks, start_lba, xfer_blocks, 128u32);
         test_read_blocks(3, blocks, start_lba, xfer_blocks, 512u32);
     }
+
+    #[test]
+    fn read_with_reservation_conflict() {
+        let ex = Executor::new().expect("creating an executor failed");
+        let (file, _) = setup_disk(8 * 512);
+        let pr_file = tempfile().unwrap();
+
+        // Another crosvm instance sharing the image takes an exclusive access reservation.
+        let other = Reservations::shared(pr_file.try_clone().unwrap(), "other".to_string());
+        let params = |key, service_action_key| ReserveOutParams {
+            key,
+            service_action_key,
+            aptpl: false,
+        };
+        other
+            .execute_out(PR_OUT_REGISTER, 0, 0, params(0, 0x1))
+            .unwrap();
+        // 0x3 == EXCLUSIVE ACCESS
+        other
+            .execute_out(PR_OUT_RESERVE, 0, 0x3, params(0x1, 0))
+            .unwrap();
+
+        let state = Arc::new(Mutex::new(LogicalUnitState {
+            reservations: Reservations::shared(pr_file, "guest".to_string()),
+            ..Default::default()
+        }));
+        let (resp, _) = read_blocks(&ex, &[file], 0, 0, 1, 512, &state);
+        assert_eq!(resp.status, RESERVATION_CONFLICT);
+        assert_eq!(resp.response, VIRTIO_SCSI_S_OK as u8);
+    }
 }
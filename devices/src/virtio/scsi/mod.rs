@@ -12,9 +12,11 @@ pub(crate) mod sys;
 pub mod commands;
 pub mod constants;
 mod device;
+mod reservation;
 
 pub use device::Controller;
 pub use device::DiskConfig;
+pub use reservation::Reservations;
 
 fn scsi_option_block_size_default() -> u32 {
     512
@@ -36,6 +38,24 @@ pub struct ScsiOption {
     /// adding specific command-line options.
     #[serde(default)]
     pub root: bool,
+    /// The target ID of the device. Defaults to the index of the device on the command line.
+    #[serde(default)]
+    pub target: Option<u8>,
+    /// The logical unit number of the device in its target.
+    #[serde(default)]
+    pub lun: u16,
+    /// Whether the device is a CD-ROM drive whose medium can be ejected and inserted. Implies
+    /// `ro`.
+    #[serde(default)]
+    pub cdrom: bool,
+    /// File keeping the persistent reservations of the device, shared by the crosvm instances
+    /// using the same image. Reservations are kept in memory if unset.
+    #[serde(default)]
+    pub pr_file: Option<PathBuf>,
+    /// The name identifying this crosvm instance as an initiator in `pr_file`. Required when
+    /// `pr_file` is set.
+    #[serde(default)]
+    pub pr_initiator: Option<String>,
 }
 
 #[cfg(test)]
@@ -56,6 +76,11 @@ mod tests {
                 read_only: false,
                 block_size: 512,
                 root: false,
+                target: None,
+                lun: 0,
+                cdrom: false,
+                pr_file: None,
+                pr_initiator: None,
             }
         );
 
@@ -67,6 +92,11 @@ mod tests {
                 read_only: true,
                 block_size: 512,
                 root: false,
+                target: None,
+                lun: 0,
+                cdrom: false,
+                pr_file: None,
+                pr_initiator: None,
             }
         );
 
@@ -78,6 +108,11 @@ mod tests {
                 read_only: false,
                 block_size: 1024,
                 root: false,
+                target: None,
+                lun: 0,
+                cdrom: false,
+                pr_file: None,
+                pr_initiator: None,
             }
         );
 
@@ -90,7 +125,39 @@ mod tests {
                 read_only: false,
                 block_size: 1024,
                 root: true,
+                target: None,
+                lun: 0,
+                cdrom: false,
+                pr_file: None,
+                pr_initiator: None,
+            }
+        );
+
+        let scsi_option =
+            from_key_values::<ScsiOption>("/path/to/cd.iso,target=1,lun=2,cdrom").unwrap();
+        assert_eq!(
+            scsi_option,
+            ScsiOption {
+                path: Path::new("/path/to/cd.iso").to_path_buf(),
+                read_only: false,
+                block_size: 512,
+                root: false,
+                target: Some(1),
+                lun: 2,
+                cdrom: true,
+                pr_file: None,
+                pr_initiator: None,
             }
         );
+
+        let scsi_option = from_key_values::<ScsiOption>(
+            "/path/to/shared.img,pr-file=/path/to/pr,pr-initiator=vm1",
+        )
+        .unwrap();
+        assert_eq!(
+            scsi_option.pr_file,
+            Some(Path::new("/path/to/pr").to_path_buf())
+        );
+        assert_eq!(scsi_option.pr_initiator, Some("vm1".to_string()));
     }
 }
@@ -0,0 +1,892 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Persistent reservations of a logical unit, as described in SPC-3 section 5.6.
+//!
+//! virtio-scsi does not tell the device which initiator sent a command, so each crosvm instance is
+//! a single I_T nexus of its logical units, named by an initiator name. By default reservations are
+//! kept in memory and the guest is the only I_T nexus. A logical unit whose image is shared by
+//! several crosvm instances can keep its reservations in a file shared by all of them instead, so
+//! that each instance sees the registrations of the others and gets reservation conflicts.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use base::error;
+use base::FileReadWriteAtVolatile;
+use base::VolatileSlice;
+use serde::Deserialize;
+use serde::Serialize;
+use sync::Mutex;
+
+use crate::virtio::scsi::constants::PR_OUT_CLEAR;
+use crate::virtio::scsi::constants::PR_OUT_PREEMPT;
+use crate::virtio::scsi::constants::PR_OUT_PREEMPT_AND_ABORT;
+use crate::virtio::scsi::constants::PR_OUT_REGISTER;
+use crate::virtio::scsi::constants::PR_OUT_REGISTER_AND_IGNORE_EXISTING_KEY;
+use crate::virtio::scsi::constants::PR_OUT_RELEASE;
+use crate::virtio::scsi::constants::PR_OUT_RESERVE;
+use crate::virtio::scsi::device::ExecuteError;
+use crate::virtio::scsi::sys::try_lock_reservation_file;
+use crate::virtio::scsi::sys::unlock_reservation_file;
+
+// Persistent reservation types. Refer to the Table 167 in the SPC-3 spec.
+const WRITE_EXCLUSIVE: u8 = 0x1;
+const EXCLUSIVE_ACCESS: u8 = 0x3;
+const WRITE_EXCLUSIVE_REGISTRANTS_ONLY: u8 = 0x5;
+const EXCLUSIVE_ACCESS_REGISTRANTS_ONLY: u8 = 0x6;
+const WRITE_EXCLUSIVE_ALL_REGISTRANTS: u8 = 0x7;
+const EXCLUSIVE_ACCESS_ALL_REGISTRANTS: u8 = 0x8;
+
+// The only supported scope: the reservation applies to the whole logical unit.
+const LU_SCOPE: u8 = 0x0;
+
+/// The parameter list of a PERSISTENT RESERVE OUT command.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReserveOutParams {
+    /// The reservation key of the I_T nexus sending the command.
+    pub key: u64,
+    /// The key to register, or the key of the registrants to preempt.
+    pub service_action_key: u64,
+    /// Whether the initiator asks for the reservations to persist through power loss.
+    pub aptpl: bool,
+}
+
+/// How a command accesses the medium, which a reservation held by another I_T nexus may forbid.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+// With these types, every registrant holds the reservation.
+fn is_all_registrants(reservation_type: u8) -> bool {
+    matches!(
+        reservation_type,
+        WRITE_EXCLUSIVE_ALL_REGISTRANTS | EXCLUSIVE_ACCESS_ALL_REGISTRANTS
+    )
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Reservation {
+    // The initiator that created the reservation.
+    holder: String,
+    reservation_type: u8,
+}
+
+/// The registrations and the reservation of a logical unit, as stored in a reservation file.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct State {
+    // Incremented every time the registrations are changed.
+    generation: u32,
+    // The reservation key registered by each initiator.
+    registrations: BTreeMap<String, u64>,
+    reservation: Option<Reservation>,
+}
+
+impl State {
+    fn is_holder(&self, initiator: &str) -> bool {
+        match &self.reservation {
+            Some(r) if is_all_registrants(r.reservation_type) => {
+                self.registrations.contains_key(initiator)
+            }
+            Some(r) => r.holder == initiator,
+            None => false,
+        }
+    }
+
+    // Returns the key and the type of the reservation. The key of an all registrants reservation
+    // is reported as zero.
+    fn reservation(&self) -> Option<(u64, u8)> {
+        let r = self.reservation.as_ref()?;
+        let key = if is_all_registrants(r.reservation_type) {
+            0
+        } else {
+            self.registrations.get(&r.holder).copied().unwrap_or(0)
+        };
+        Some((key, r.reservation_type))
+    }
+
+    // Refer to the Table 31 in the SPC-3 spec.
+    fn check_access(&self, initiator: &str, access: Access) -> Result<(), ExecuteError> {
+        let reservation_type = match &self.reservation {
+            Some(r) if !self.is_holder(initiator) => r.reservation_type,
+            _ => return Ok(()),
+        };
+        let registered = self.registrations.contains_key(initiator);
+        let allowed = match reservation_type {
+            WRITE_EXCLUSIVE => access == Access::Read,
+            WRITE_EXCLUSIVE_REGISTRANTS_ONLY | WRITE_EXCLUSIVE_ALL_REGISTRANTS => {
+                access == Access::Read || registered
+            }
+            EXCLUSIVE_ACCESS_REGISTRANTS_ONLY | EXCLUSIVE_ACCESS_ALL_REGISTRANTS => registered,
+            _ => false,
+        };
+        if allowed {
+            Ok(())
+        } else {
+            Err(ExecuteError::ReservationConflict)
+        }
+    }
+
+    fn execute_out(
+        &mut self,
+        initiator: &str,
+        service_action: u8,
+        scope: u8,
+        reservation_type: u8,
+        params: ReserveOutParams,
+    ) -> Result<(), ExecuteError> {
+        match service_action {
+            PR_OUT_REGISTER => self.register(initiator, params, false),
+            PR_OUT_REGISTER_AND_IGNORE_EXISTING_KEY => self.register(initiator, params, true),
+            PR_OUT_RESERVE => {
+                check_scope_and_type(scope, reservation_type)?;
+                self.check_key(initiator, params.key)?;
+                match &self.reservation {
+                    Some(r) if r.reservation_type != reservation_type => {
+                        Err(ExecuteError::ReservationConflict)
+                    }
+                    Some(_) if !self.is_holder(initiator) => Err(ExecuteError::ReservationConflict),
+                    Some(_) => Ok(()),
+                    None => {
+                        self.reservation = Some(Reservation {
+                            holder: initiator.to_string(),
+                            reservation_type,
+                        });
+                        Ok(())
+                    }
+                }
+            }
+            PR_OUT_RELEASE => {
+                check_scope_and_type(scope, reservation_type)?;
+                self.check_key(initiator, params.key)?;
+                match &self.reservation {
+                    // Releasing a reservation held by another I_T nexus does nothing.
+                    Some(r) if self.is_holder(initiator) => {
+                        if r.reservation_type != reservation_type {
+                            return Err(ExecuteError::InvalidRelease);
+                        }
+                        self.reservation = None;
+                        Ok(())
+                    }
+                    _ => Ok(()),
+                }
+            }
+            PR_OUT_CLEAR => {
+                self.check_key(initiator, params.key)?;
+                self.registrations.clear();
+                self.reservation = None;
+                self.generation = self.generation.wrapping_add(1);
+                Ok(())
+            }
+            PR_OUT_PREEMPT | PR_OUT_PREEMPT_AND_ABORT => {
+                check_scope_and_type(scope, reservation_type)?;
+                self.check_key(initiator, params.key)?;
+                self.preempt(initiator, reservation_type, params.service_action_key)
+            }
+            _ => Err(ExecuteError::InvalidField),
+        }
+    }
+
+    fn register(
+        &mut self,
+        initiator: &str,
+        params: ReserveOutParams,
+        ignore_key: bool,
+    ) -> Result<(), ExecuteError> {
+        match self.registrations.get(initiator) {
+            None if !ignore_key && params.key != 0 => {
+                return Err(ExecuteError::ReservationConflict)
+            }
+            // Registering a zero key without a registration does nothing.
+            None if params.service_action_key == 0 => return Ok(()),
+            Some(&key) if !ignore_key && key != params.key => {
+                return Err(ExecuteError::ReservationConflict)
+            }
+            _ => (),
+        }
+        if params.service_action_key == 0 {
+            self.registrations.remove(initiator);
+            // Unregistering the holder releases the reservation, unless other registrants share
+            // it.
+            let released = match &self.reservation {
+                Some(r) if is_all_registrants(r.reservation_type) => self.registrations.is_empty(),
+                Some(r) => r.holder == initiator,
+                None => false,
+            };
+            if released {
+                self.reservation = None;
+            }
+        } else {
+            self.registrations
+                .insert(initiator.to_string(), params.service_action_key);
+        }
+        self.generation = self.generation.wrapping_add(1);
+        Ok(())
+    }
+
+    // Removes the registrations with `key`, other than the one of `initiator`, and takes over the
+    // reservation if it was held with that key.
+    fn preempt(
+        &mut self,
+        initiator: &str,
+        reservation_type: u8,
+        key: u64,
+    ) -> Result<(), ExecuteError> {
+        let (takes_reservation, removed_key) = match self.reservation() {
+            // Preempting an all registrants reservation with a zero key removes all the other
+            // registrants.
+            Some((_, t)) if is_all_registrants(t) && key == 0 => (true, None),
+            Some((holder_key, t)) if !is_all_registrants(t) && holder_key == key => {
+                (true, Some(key))
+            }
+            _ if key == 0 => return Err(ExecuteError::InvalidParamField),
+            _ => (false, Some(key)),
+        };
+        let num_registrations = self.registrations.len();
+        self.registrations
+            .retain(|i, k| i == initiator || matches!(removed_key, Some(key) if *k != key));
+        if !takes_reservation && self.registrations.len() == num_registrations {
+            return Err(ExecuteError::ReservationConflict);
+        }
+        if takes_reservation {
+            self.reservation = Some(Reservation {
+                holder: initiator.to_string(),
+                reservation_type,
+            });
+        }
+        self.generation = self.generation.wrapping_add(1);
+        Ok(())
+    }
+
+    fn check_key(&self, initiator: &str, key: u64) -> Result<(), ExecuteError> {
+        match self.registrations.get(initiator) {
+            Some(&k) if k == key => Ok(()),
+            _ => Err(ExecuteError::ReservationConflict),
+        }
+    }
+}
+
+fn check_scope_and_type(scope: u8, reservation_type: u8) -> Result<(), ExecuteError> {
+    match (scope, reservation_type) {
+        (
+            LU_SCOPE,
+            WRITE_EXCLUSIVE
+            | EXCLUSIVE_ACCESS
+            | WRITE_EXCLUSIVE_REGISTRANTS_ONLY
+            | EXCLUSIVE_ACCESS_REGISTRANTS_ONLY
+            | WRITE_EXCLUSIVE_ALL_REGISTRANTS
+            | EXCLUSIVE_ACCESS_ALL_REGISTRANTS,
+        ) => Ok(()),
+        _ => Err(ExecuteError::InvalidField),
+    }
+}
+
+// A reservation file starts with a sequence number incremented by every update, followed by the
+// serialized `State`.
+const SEQUENCE_SIZE: usize = std::mem::size_of::<u64>();
+
+// Delays between the attempts to lock a reservation file held by another crosvm instance.
+const LOCK_RETRY_MIN_DELAY: Duration = Duration::from_millis(1);
+const LOCK_RETRY_MAX_DELAY: Duration = Duration::from_millis(100);
+// How long to wait for the other crosvm instances to release a reservation file.
+const LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+struct SharedFile {
+    file: File,
+    // The state last read from or written to the file, with its sequence number.
+    cache: Mutex<Option<(u64, State)>>,
+    // Serializes the threads of this instance using the file, as they share its lock.
+    lock: Mutex<()>,
+}
+
+impl SharedFile {
+    fn read_sequence(&self) -> io::Result<u64> {
+        let mut sequence = [0u8; SEQUENCE_SIZE];
+        match self
+            .file
+            .read_exact_at_volatile(VolatileSlice::new(&mut sequence), 0)
+        {
+            Ok(()) => Ok(u64::from_le_bytes(sequence)),
+            // A new file has no registrations.
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Returns the result of `f` on the cached state, or `None` if another crosvm instance has
+    // updated the file since it was cached.
+    fn with_cached_state<T>(&self, f: impl FnOnce(&State) -> T) -> Result<Option<T>, ExecuteError> {
+        let cache = self.cache.lock();
+        match &*cache {
+            Some((sequence, state))
+                if *sequence
+                    == self
+                        .read_sequence()
+                        .map_err(ExecuteError::ReservationFile)? =>
+            {
+                Ok(Some(f(state)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    // Locks the file, retrying until the other crosvm instances release it.
+    fn lock(&self, exclusive: bool) -> io::Result<()> {
+        let start = Instant::now();
+        let mut delay = LOCK_RETRY_MIN_DELAY;
+        while !try_lock_reservation_file(&self.file, exclusive)? {
+            if start.elapsed() >= LOCK_TIMEOUT {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "the reservation file is held by another crosvm instance",
+                ));
+            }
+            thread::sleep(delay);
+            delay = std::cmp::min(delay * 2, LOCK_RETRY_MAX_DELAY);
+        }
+        Ok(())
+    }
+
+    // Runs `f` on the state read from the file, which is saved afterwards if `update` is set and
+    // `f` succeeded.
+    fn with_state<T>(
+        &self,
+        update: bool,
+        f: impl FnOnce(&mut State) -> Result<T, ExecuteError>,
+    ) -> Result<T, ExecuteError> {
+        let _lock = self.lock.lock();
+        self.lock(update).map_err(ExecuteError::ReservationFile)?;
+        let result = self.with_locked_state(update, f);
+        if let Err(e) = unlock_reservation_file(&self.file) {
+            error!("failed to unlock the reservation file: {}", e);
+        }
+        result
+    }
+
+    fn with_locked_state<T>(
+        &self,
+        update: bool,
+        f: impl FnOnce(&mut State) -> Result<T, ExecuteError>,
+    ) -> Result<T, ExecuteError> {
+        let (sequence, state) = self.read().map_err(ExecuteError::ReservationFile)?;
+        let mut new_state = state.clone();
+        let result = f(&mut new_state);
+        if update && result.is_ok() {
+            let sequence = sequence.wrapping_add(1);
+            self.write(sequence, &new_state)
+                .map_err(ExecuteError::ReservationFile)?;
+            *self.cache.lock() = Some((sequence, new_state));
+        } else {
+            *self.cache.lock() = Some((sequence, state));
+        }
+        result
+    }
+
+    fn read(&self) -> io::Result<(u64, State)> {
+        let sequence = self.read_sequence()?;
+        let len = (self.file.metadata()?.len() as usize).saturating_sub(SEQUENCE_SIZE);
+        if len == 0 {
+            return Ok((sequence, State::default()));
+        }
+        let mut contents = vec![0u8; len];
+        self.file
+            .read_exact_at_volatile(VolatileSlice::new(&mut contents), SEQUENCE_SIZE as u64)?;
+        let state = serde_json::from_slice(&contents)?;
+        Ok((sequence, state))
+    }
+
+    // The sequence number is written last, so that the instances checking it without the lock
+    // only see the new state once it is complete.
+    fn write(&self, sequence: u64, state: &State) -> io::Result<()> {
+        let mut contents = serde_json::to_vec(state)?;
+        self.file
+            .write_all_at_volatile(VolatileSlice::new(&mut contents), SEQUENCE_SIZE as u64)?;
+        self.file.set_len((SEQUENCE_SIZE + contents.len()) as u64)?;
+        self.file
+            .write_all_at_volatile(VolatileSlice::new(&mut sequence.to_le_bytes()), 0)?;
+        self.file.sync_data()
+    }
+}
+
+#[derive(Debug)]
+enum Store {
+    // Reservations only seen by this crosvm instance, lost when it exits.
+    Memory(Mutex<State>),
+    // A file shared with the other crosvm instances using the image.
+    File(SharedFile),
+}
+
+/// The persistent reservations of a logical unit, as seen by the I_T nexus of this crosvm
+/// instance. Clones refer to the same reservations.
+///
+/// Reservations kept in a file may have to wait for the other crosvm instances to release it, so
+/// they should be used from a blocking thread, e.g. with `unblock`.
+#[derive(Clone, Debug)]
+pub struct Reservations {
+    initiator: String,
+    store: Arc<Store>,
+}
+
+impl Default for Reservations {
+    fn default() -> Self {
+        Reservations {
+            initiator: String::new(),
+            store: Arc::new(Store::Memory(Mutex::new(State::default()))),
+        }
+    }
+}
+
+impl Reservations {
+    /// Keeps the reservations in `file`, which is shared by every crosvm instance using the image.
+    /// `initiator` names this instance and must be unique among them.
+    pub fn shared(file: File, initiator: String) -> Self {
+        Reservations {
+            initiator,
+            store: Arc::new(Store::File(SharedFile {
+                file,
+                cache: Mutex::new(None),
+                lock: Mutex::new(()),
+            })),
+        }
+    }
+
+    /// Returns whether the reservations persist when crosvm exits.
+    pub fn persistent(&self) -> bool {
+        matches!(*self.store, Store::File(_))
+    }
+
+    /// Runs `f` on these reservations, on a thread of the blocking pool if they are kept in a file.
+    pub async fn unblock<T, F>(&self, f: F) -> Result<T, ExecuteError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Reservations) -> Result<T, ExecuteError> + Send + 'static,
+    {
+        if !self.persistent() {
+            return f(self);
+        }
+        let reservations = self.clone();
+        cros_async::unblock(
+            move || f(&reservations),
+            || {
+                Err(ExecuteError::ReservationFile(io::Error::from(
+                    io::ErrorKind::Interrupted,
+                )))
+            },
+        )
+        .await
+    }
+
+    /// Returns the PRGENERATION value and the registered reservation keys.
+    pub fn read_keys(&self) -> Result<(u32, Vec<u64>), ExecuteError> {
+        self.with_state(false, |state, _| {
+            Ok((
+                state.generation,
+                state.registrations.values().copied().collect(),
+            ))
+        })
+    }
+
+    /// Returns the PRGENERATION value, and the reservation key and the type of the current
+    /// reservation, if any.
+    pub fn read_reservation(&self) -> Result<(u32, Option<(u64, u8)>), ExecuteError> {
+        self.with_state(false, |state, _| {
+            Ok((state.generation, state.reservation()))
+        })
+    }
+
+    /// Fails with a reservation conflict if the reservation forbids `access` to this I_T nexus.
+    /// Returns `None` if the reservation file must be read again, which `check_access` does.
+    ///
+    /// This doesn't wait for the reservation file, and can be called for every command.
+    pub fn try_check_access(&self, access: Access) -> Option<Result<(), ExecuteError>> {
+        match &*self.store {
+            // Without a shared file, the guest holds any reservation there is.
+            Store::Memory(_) => Some(Ok(())),
+            Store::File(file) => {
+                match file.with_cached_state(|state| state.check_access(&self.initiator, access)) {
+                    Ok(Some(result)) => Some(result),
+                    Ok(None) => None,
+                    Err(e) => Some(Err(e)),
+                }
+            }
+        }
+    }
+
+    /// Fails with a reservation conflict if the reservation forbids `access` to this I_T nexus.
+    pub fn check_access(&self, access: Access) -> Result<(), ExecuteError> {
+        if !self.persistent() {
+            return Ok(());
+        }
+        self.with_state(false, |state, initiator| {
+            state.check_access(initiator, access)
+        })
+    }
+
+    /// Executes the service action of a PERSISTENT RESERVE OUT command.
+    pub fn execute_out(
+        &self,
+        service_action: u8,
+        scope: u8,
+        reservation_type: u8,
+        params: ReserveOutParams,
+    ) -> Result<(), ExecuteError> {
+        // APTPL is only meaningful when registering, and can only be honored by a reservation
+        // file.
+        let registers = matches!(
+            service_action,
+            PR_OUT_REGISTER | PR_OUT_REGISTER_AND_IGNORE_EXISTING_KEY
+        );
+        if params.aptpl && registers && !self.persistent() {
+            return Err(ExecuteError::InvalidParamField);
+        }
+        self.with_state(true, |state, initiator| {
+            state.execute_out(initiator, service_action, scope, reservation_type, params)
+        })
+    }
+
+    // Runs `f` on the current state, which is saved afterwards if `update` is set and `f`
+    // succeeded.
+    fn with_state<T>(
+        &self,
+        update: bool,
+        f: impl FnOnce(&mut State, &str) -> Result<T, ExecuteError>,
+    ) -> Result<T, ExecuteError> {
+        match &*self.store {
+            Store::Memory(state) => {
+                let mut state = state.lock();
+                let mut new_state = state.clone();
+                let result = f(&mut new_state, &self.initiator)?;
+                if update {
+                    *state = new_state;
+                }
+                Ok(result)
+            }
+            Store::File(file) => file.with_state(update, |state| f(state, &self.initiator)),
+        }
+    }
+}
+
+/// Returns the PERSISTENT RESERVATION TYPE MASK reported by REPORT CAPABILITIES.
+pub fn supported_types_mask() -> [u8; 2] {
+    [
+        // WR_EX_AR | EX_AC_RO | WR_EX_RO | EX_AC | WR_EX
+        0x80 | 0x40 | 0x20 | 0x08 | 0x02,
+        // EX_AC_AR
+        0x01,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempfile;
+
+    use super::*;
+
+    fn params(key: u64, service_action_key: u64) -> ReserveOutParams {
+        ReserveOutParams {
+            key,
+            service_action_key,
+            aptpl: false,
+        }
+    }
+
+    fn keys(r: &Reservations) -> Vec<u64> {
+        r.read_keys().unwrap().1
+    }
+
+    fn generation(r: &Reservations) -> u32 {
+        r.read_keys().unwrap().0
+    }
+
+    fn reservation(r: &Reservations) -> Option<(u64, u8)> {
+        r.read_reservation().unwrap().1
+    }
+
+    // Returns the reservations of two initiators sharing a reservation file.
+    fn shared_pair() -> (Reservations, Reservations) {
+        let file = tempfile().unwrap();
+        (
+            Reservations::shared(file.try_clone().unwrap(), "a".to_string()),
+            Reservations::shared(file, "b".to_string()),
+        )
+    }
+
+    #[test]
+    fn register_reserve_release() {
+        let r = Reservations::default();
+        // Reserving without a registration conflicts.
+        assert!(matches!(
+            r.execute_out(PR_OUT_RESERVE, LU_SCOPE, WRITE_EXCLUSIVE, params(0x1234, 0)),
+            Err(ExecuteError::ReservationConflict)
+        ));
+        r.execute_out(PR_OUT_REGISTER, 0, 0, params(0, 0x1234))
+            .unwrap();
+        assert_eq!(keys(&r), vec![0x1234]);
+        assert_eq!(generation(&r), 1);
+
+        r.execute_out(PR_OUT_RESERVE, LU_SCOPE, WRITE_EXCLUSIVE, params(0x1234, 0))
+            .unwrap();
+        assert_eq!(reservation(&r), Some((0x1234, WRITE_EXCLUSIVE)));
+        // A reservation of another type conflicts, a wrong key too.
+        assert!(matches!(
+            r.execute_out(
+                PR_OUT_RESERVE,
+                LU_SCOPE,
+                EXCLUSIVE_ACCESS,
+                params(0x1234, 0)
+            ),
+            Err(ExecuteError::ReservationConflict)
+        ));
+        assert!(matches!(
+            r.execute_out(PR_OUT_RESERVE, LU_SCOPE, WRITE_EXCLUSIVE, params(0x9999, 0)),
+            Err(ExecuteError::ReservationConflict)
+        ));
+        assert!(matches!(
+            r.execute_out(
+                PR_OUT_RELEASE,
+                LU_SCOPE,
+                EXCLUSIVE_ACCESS,
+                params(0x1234, 0)
+            ),
+            Err(ExecuteError::InvalidRelease)
+        ));
+        r.execute_out(PR_OUT_RELEASE, LU_SCOPE, WRITE_EXCLUSIVE, params(0x1234, 0))
+            .unwrap();
+        assert_eq!(reservation(&r), None);
+        // Reserving and releasing do not change the registrations.
+        assert_eq!(generation(&r), 1);
+    }
+
+    #[test]
+    fn preempt_and_clear() {
+        let r = Reservations::default();
+        r.execute_out(
+            PR_OUT_REGISTER_AND_IGNORE_EXISTING_KEY,
+            0,
+            0,
+            params(0x5555, 0xabcd),
+        )
+        .unwrap();
+        r.execute_out(PR_OUT_RESERVE, LU_SCOPE, WRITE_EXCLUSIVE, params(0xabcd, 0))
+            .unwrap();
+        r.execute_out(
+            PR_OUT_PREEMPT,
+            LU_SCOPE,
+            EXCLUSIVE_ACCESS_REGISTRANTS_ONLY,
+            params(0xabcd, 0xabcd),
+        )
+        .unwrap();
+        assert_eq!(
+            reservation(&r),
+            Some((0xabcd, EXCLUSIVE_ACCESS_REGISTRANTS_ONLY))
+        );
+        assert!(matches!(
+            r.execute_out(
+                PR_OUT_PREEMPT,
+                LU_SCOPE,
+                WRITE_EXCLUSIVE,
+                params(0xabcd, 0x1)
+            ),
+            Err(ExecuteError::ReservationConflict)
+        ));
+
+        r.execute_out(PR_OUT_CLEAR, LU_SCOPE, 0, params(0xabcd, 0))
+            .unwrap();
+        assert!(keys(&r).is_empty());
+        assert_eq!(reservation(&r), None);
+        assert_eq!(generation(&r), 3);
+    }
+
+    #[test]
+    fn invalid_requests() {
+        let r = Reservations::default();
+        let aptpl = ReserveOutParams {
+            aptpl: true,
+            ..params(0, 0x1)
+        };
+        assert!(matches!(
+            r.execute_out(PR_OUT_REGISTER, 0, 0, aptpl),
+            Err(ExecuteError::InvalidParamField)
+        ));
+        r.execute_out(PR_OUT_REGISTER, 0, 0, params(0, 0x1))
+            .unwrap();
+        // Unknown reservation type and element scope.
+        assert!(matches!(
+            r.execute_out(PR_OUT_RESERVE, LU_SCOPE, 0x2, params(0x1, 0)),
+            Err(ExecuteError::InvalidField)
+        ));
+        assert!(matches!(
+            r.execute_out(PR_OUT_RESERVE, 0x2, WRITE_EXCLUSIVE, params(0x1, 0)),
+            Err(ExecuteError::InvalidField)
+        ));
+        // Unregistering releases the reservation.
+        r.execute_out(PR_OUT_RESERVE, LU_SCOPE, WRITE_EXCLUSIVE, params(0x1, 0))
+            .unwrap();
+        r.execute_out(PR_OUT_REGISTER, 0, 0, params(0x1, 0))
+            .unwrap();
+        assert!(keys(&r).is_empty());
+        assert_eq!(reservation(&r), None);
+    }
+
+    #[test]
+    fn shared_registrations() {
+        let (a, b) = shared_pair();
+        assert!(a.persistent());
+        a.execute_out(PR_OUT_REGISTER, 0, 0, params(0, 0xa))
+            .unwrap();
+        b.execute_out(PR_OUT_REGISTER, 0, 0, params(0, 0xb))
+            .unwrap();
+        assert_eq!(keys(&a), vec![0xa, 0xb]);
+        assert_eq!(generation(&b), 2);
+        // The registration of an initiator can only be changed with its own key.
+        assert!(matches!(
+            b.execute_out(PR_OUT_REGISTER, 0, 0, params(0xa, 0xc)),
+            Err(ExecuteError::ReservationConflict)
+        ));
+
+        a.execute_out(PR_OUT_RESERVE, LU_SCOPE, WRITE_EXCLUSIVE, params(0xa, 0))
+            .unwrap();
+        assert_eq!(reservation(&b), Some((0xa, WRITE_EXCLUSIVE)));
+        assert!(matches!(
+            b.execute_out(PR_OUT_RESERVE, LU_SCOPE, WRITE_EXCLUSIVE, params(0xb, 0)),
+            Err(ExecuteError::ReservationConflict)
+        ));
+        // Releasing the reservation of another initiator does nothing.
+        b.execute_out(PR_OUT_RELEASE, LU_SCOPE, WRITE_EXCLUSIVE, params(0xb, 0))
+            .unwrap();
+        assert_eq!(reservation(&b), Some((0xa, WRITE_EXCLUSIVE)));
+
+        // Preempting the holder removes its registration and takes over the reservation.
+        b.execute_out(PR_OUT_PREEMPT, LU_SCOPE, EXCLUSIVE_ACCESS, params(0xb, 0xa))
+            .unwrap();
+        assert_eq!(keys(&a), vec![0xb]);
+        assert_eq!(reservation(&a), Some((0xb, EXCLUSIVE_ACCESS)));
+        assert!(matches!(
+            a.execute_out(PR_OUT_RESERVE, LU_SCOPE, EXCLUSIVE_ACCESS, params(0xa, 0)),
+            Err(ExecuteError::ReservationConflict)
+        ));
+    }
+
+    #[test]
+    fn shared_access_conflicts() {
+        let (a, b) = shared_pair();
+        a.execute_out(PR_OUT_REGISTER, 0, 0, params(0, 0xa))
+            .unwrap();
+        let reserve = |r: &Reservations, reservation_type: u8| {
+            r.execute_out(PR_OUT_RESERVE, LU_SCOPE, reservation_type, params(0xa, 0))
+                .unwrap();
+        };
+        let release = |r: &Reservations, reservation_type: u8| {
+            r.execute_out(PR_OUT_RELEASE, LU_SCOPE, reservation_type, params(0xa, 0))
+                .unwrap();
+        };
+        let allowed = |r: &Reservations| {
+            (
+                r.check_access(Access::Read).is_ok(),
+                r.check_access(Access::Write).is_ok(),
+            )
+        };
+
+        assert_eq!(allowed(&b), (true, true));
+        reserve(&a, WRITE_EXCLUSIVE);
+        assert_eq!(allowed(&a), (true, true));
+        assert_eq!(allowed(&b), (true, false));
+        release(&a, WRITE_EXCLUSIVE);
+        reserve(&a, EXCLUSIVE_ACCESS);
+        assert_eq!(allowed(&b), (false, false));
+        release(&a, EXCLUSIVE_ACCESS);
+
+        // Registrants-only reservations let registered initiators in.
+        reserve(&a, EXCLUSIVE_ACCESS_REGISTRANTS_ONLY);
+        assert_eq!(allowed(&b), (false, false));
+        b.execute_out(PR_OUT_REGISTER, 0, 0, params(0, 0xb))
+            .unwrap();
+        assert_eq!(allowed(&b), (true, true));
+        release(&a, EXCLUSIVE_ACCESS_REGISTRANTS_ONLY);
+
+        // All registrants reservations are held by every registrant, and outlive the
+        // registration of the initiator that created them.
+        reserve(&a, WRITE_EXCLUSIVE_ALL_REGISTRANTS);
+        a.execute_out(PR_OUT_REGISTER, 0, 0, params(0xa, 0))
+            .unwrap();
+        assert_eq!(reservation(&a), Some((0, WRITE_EXCLUSIVE_ALL_REGISTRANTS)));
+        assert_eq!(allowed(&a), (true, false));
+        assert_eq!(allowed(&b), (true, true));
+        b.execute_out(PR_OUT_REGISTER, 0, 0, params(0xb, 0))
+            .unwrap();
+        assert_eq!(reservation(&a), None);
+    }
+
+    #[test]
+    fn shared_file_persists() {
+        let file = tempfile().unwrap();
+        let r = Reservations::shared(file.try_clone().unwrap(), "a".to_string());
+        let aptpl = ReserveOutParams {
+            aptpl: true,
+            ..params(0, 0xa)
+        };
+        r.execute_out(PR_OUT_REGISTER, 0, 0, aptpl).unwrap();
+        r.execute_out(PR_OUT_RESERVE, LU_SCOPE, EXCLUSIVE_ACCESS, params(0xa, 0))
+            .unwrap();
+        drop(r);
+
+        // A restarted initiator finds its registration and its reservation.
+        let r = Reservations::shared(file, "a".to_string());
+        assert_eq!(keys(&r), vec![0xa]);
+        assert_eq!(reservation(&r), Some((0xa, EXCLUSIVE_ACCESS)));
+        assert!(r.check_access(Access::Write).is_ok());
+    }
+
+    #[test]
+    fn cached_access_is_revalidated() {
+        let (a, b) = shared_pair();
+        // Nothing is cached before the file is read.
+        assert!(b.try_check_access(Access::Write).is_none());
+        b.check_access(Access::Write).unwrap();
+        assert!(matches!(b.try_check_access(Access::Write), Some(Ok(()))));
+
+        a.execute_out(PR_OUT_REGISTER, 0, 0, params(0, 0xa))
+            .unwrap();
+        a.execute_out(PR_OUT_RESERVE, LU_SCOPE, EXCLUSIVE_ACCESS, params(0xa, 0))
+            .unwrap();
+        // The update of the other initiator invalidates the cache.
+        assert!(b.try_check_access(Access::Write).is_none());
+        assert!(matches!(
+            b.check_access(Access::Write),
+            Err(ExecuteError::ReservationConflict)
+        ));
+        assert!(matches!(
+            b.try_check_access(Access::Write),
+            Some(Err(ExecuteError::ReservationConflict))
+        ));
+        // The cache of the initiator updating the file stays valid.
+        assert!(matches!(a.try_check_access(Access::Write), Some(Ok(()))));
+    }
+
+    #[test]
+    fn locked_file_is_retried() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let other = File::open(file.path()).unwrap();
+        let r = Reservations::shared(file.reopen().unwrap(), "a".to_string());
+
+        // Another crosvm instance holds the file for a while.
+        assert!(try_lock_reservation_file(&other, true).unwrap());
+        let holder = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            unlock_reservation_file(&other).unwrap();
+        });
+        r.execute_out(PR_OUT_REGISTER, 0, 0, params(0, 0xa))
+            .unwrap();
+        holder.join().unwrap();
+        assert_eq!(keys(&r), vec![0xa]);
+    }
+}
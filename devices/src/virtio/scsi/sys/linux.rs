@@ -4,6 +4,7 @@
 
 use std::fs::File;
 use std::fs::OpenOptions;
+use std::io;
 
 use anyhow::Context;
 use base::flock;
@@ -11,17 +12,21 @@ use base::open_file_or_duplicate;
 use base::FlockOperation;
 use disk::DiskFile;
 
+use crate::virtio::scsi::Reservations;
 use crate::virtio::scsi::ScsiOption;
 
 impl ScsiOption {
     pub fn open(&self) -> anyhow::Result<Box<dyn DiskFile>> {
+        // The medium of a CD-ROM drive is always read only.
+        let read_only = self.read_only || self.cdrom;
         let mut options = OpenOptions::new();
-        options.read(true).write(!self.read_only);
+        options.read(true).write(!read_only);
 
         let raw_image: File = open_file_or_duplicate(&self.path, &options)
             .with_context(|| format!("failed to load disk image {}", self.path.display()))?;
-        // Lock the disk image to prevent other crosvm instances from using it.
-        let lock_op = if self.read_only {
+        // Lock the disk image to prevent other crosvm instances from using it, unless they
+        // coordinate through a reservation file.
+        let lock_op = if read_only || self.pr_file.is_some() {
             FlockOperation::LockShared
         } else {
             FlockOperation::LockExclusive
@@ -33,4 +38,45 @@ impl ScsiOption {
         disk::create_disk_file(raw_image, true, disk::MAX_NESTING_DEPTH, &self.path)
             .context("create_disk_file failed")
     }
+
+    /// Returns the persistent reservations of the device, shared through `pr_file` if it is set.
+    pub fn open_reservations(&self) -> anyhow::Result<Reservations> {
+        let path = match &self.pr_file {
+            Some(path) => path,
+            None => return Ok(Reservations::default()),
+        };
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .with_context(|| format!("failed to open reservation file {}", path.display()))?;
+        // The registrations of this instance must still be its own after crosvm restarts.
+        let initiator = self
+            .pr_initiator
+            .clone()
+            .context("pr-initiator must be set along with pr-file")?;
+        Ok(Reservations::shared(file, initiator))
+    }
+}
+
+/// Locks a reservation file against the other crosvm instances sharing it. Returns `false`
+/// without waiting if one of them holds a conflicting lock.
+pub fn try_lock_reservation_file(file: &File, exclusive: bool) -> io::Result<bool> {
+    let lock_op = if exclusive {
+        FlockOperation::LockExclusive
+    } else {
+        FlockOperation::LockShared
+    };
+    match flock(file, lock_op, true) {
+        Ok(()) => Ok(true),
+        Err(e) if e.errno() == libc::EWOULDBLOCK => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Releases the lock taken by `try_lock_reservation_file`.
+pub fn unlock_reservation_file(file: &File) -> io::Result<()> {
+    Ok(flock(file, FlockOperation::Unlock, false)?)
 }
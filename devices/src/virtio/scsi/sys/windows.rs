@@ -2,14 +2,30 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::fs::File;
+use std::io;
+
 use anyhow::bail;
 use anyhow::Context;
 use disk::DiskFile;
 
+use crate::virtio::scsi::Reservations;
 use crate::virtio::scsi::ScsiOption;
 
 impl ScsiOption {
     pub fn open(&self) -> anyhow::Result<Box<dyn DiskFile>> {
         bail!("ScsiOption::open() is yet to be implemented for windows.")
     }
+
+    pub fn open_reservations(&self) -> anyhow::Result<Reservations> {
+        bail!("ScsiOption::open_reservations() is yet to be implemented for windows.")
+    }
+}
+
+pub fn try_lock_reservation_file(_file: &File, _exclusive: bool) -> io::Result<bool> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+pub fn unlock_reservation_file(_file: &File) -> io::Result<()> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
 }
@@ -15,8 +15,11 @@ use audio_streams::AsyncPlaybackBuffer;
 use audio_streams::BoxError;
 use base::debug;
 use base::error;
+use base::Error as SysError;
+use base::TubeError;
 use cros_async::sync::Condvar;
 use cros_async::sync::RwLock as AsyncRwLock;
+use cros_async::AsyncTube;
 use cros_async::EventAsync;
 use cros_async::Executor;
 use cros_async::TimerAsync;
@@ -28,12 +31,17 @@ use futures::FutureExt;
 use futures::SinkExt;
 use futures::StreamExt;
 use thiserror::Error as ThisError;
+use vm_control::SndControlCommand;
+use vm_control::SndControlResult;
 use zerocopy::AsBytes;
 
 use super::Error;
 use super::SndData;
 use super::WorkerStatus;
 use crate::virtio::snd::common::*;
+use crate::virtio::snd::common_backend::controls::CardState;
+use crate::virtio::snd::common_backend::controls::Gain;
+use crate::virtio::snd::common_backend::controls::StreamVolume;
 use crate::virtio::snd::common_backend::stream_info::SetParams;
 use crate::virtio::snd::common_backend::stream_info::StreamInfo;
 use crate::virtio::snd::common_backend::DirectionalStream;
@@ -65,11 +73,11 @@ pub trait PlaybackBufferWriter {
     /// Returns the period of the endpoint device.
     fn endpoint_period_bytes(&self) -> usize;
 
-    /// Read audio samples from the tx virtqueue.
+    /// Read audio samples from the tx virtqueue, or from a copy of them after volume was applied.
     fn copy_to_buffer(
         &mut self,
         dst_buf: &mut AsyncPlaybackBuffer<'_>,
-        reader: &mut Reader,
+        reader: &mut dyn Read,
     ) -> Result<usize, Error> {
         dst_buf.copy_from(reader).map_err(Error::Io)
     }
@@ -216,10 +224,20 @@ async fn write_data(
     mut dst_buf: AsyncPlaybackBuffer<'_>,
     reader: Option<&mut Reader>,
     buffer_writer: &mut Box<dyn PlaybackBufferWriter>,
+    gain: Option<Gain>,
 ) -> Result<u32, Error> {
-    let transferred = match reader {
-        Some(reader) => buffer_writer.copy_to_buffer(&mut dst_buf, reader)?,
-        None => dst_buf
+    let transferred = match (reader, gain) {
+        (Some(reader), None) => buffer_writer.copy_to_buffer(&mut dst_buf, reader)?,
+        (Some(reader), Some(gain)) => {
+            let mut samples = Vec::with_capacity(buffer_writer.endpoint_period_bytes());
+            reader
+                .take(buffer_writer.endpoint_period_bytes() as u64)
+                .read_to_end(&mut samples)
+                .map_err(Error::Io)?;
+            gain.apply(&mut samples);
+            buffer_writer.copy_to_buffer(&mut dst_buf, &mut samples.as_slice())?
+        }
+        (None, _) => dst_buf
             .copy_from(&mut io::repeat(0).take(buffer_writer.endpoint_period_bytes() as u64))
             .map_err(Error::Io)?,
     };
@@ -241,10 +259,20 @@ async fn read_data<'a>(
     mut src_buf: AsyncCaptureBuffer<'a>,
     writer: Option<&mut Writer>,
     period_bytes: usize,
+    gain: Option<Gain>,
 ) -> Result<u32, Error> {
-    let transferred = match writer {
-        Some(writer) => src_buf.copy_to(writer),
-        None => src_buf.copy_to(&mut io::sink()),
+    let transferred = match (writer, gain) {
+        (Some(writer), None) => src_buf.copy_to(writer),
+        (Some(writer), Some(gain)) => {
+            let mut samples = Vec::with_capacity(period_bytes);
+            src_buf
+                .copy_cb(period_bytes, |data| samples.extend_from_slice(data))
+                .and_then(|transferred| {
+                    gain.apply(&mut samples);
+                    writer.write_all(&samples).map(|()| transferred)
+                })
+        }
+        (None, _) => src_buf.copy_to(&mut io::sink()),
     }
     .map_err(Error::Io)?;
     if transferred != period_bytes {
@@ -316,6 +344,7 @@ async fn drain_desc_receiver(
 pub async fn start_pcm_worker(
     ex: Executor,
     dstream: DirectionalStream,
+    volume: Option<StreamVolume>,
     mut desc_receiver: mpsc::UnboundedReceiver<DescriptorChain>,
     status_mutex: Rc<AsyncRwLock<WorkerStatus>>,
     mut sender: mpsc::UnboundedSender<PcmResponse>,
@@ -326,6 +355,7 @@ pub async fn start_pcm_worker(
     let res = pcm_worker_loop(
         ex,
         dstream,
+        volume.as_ref(),
         &mut desc_receiver,
         &status_mutex,
         &mut sender,
@@ -351,6 +381,7 @@ pub async fn start_pcm_worker(
 async fn pcm_worker_loop(
     ex: Executor,
     dstream: DirectionalStream,
+    volume: Option<&StreamVolume>,
     desc_receiver: &mut mpsc::UnboundedReceiver<DescriptorChain>,
     status_mutex: &Rc<AsyncRwLock<WorkerStatus>>,
     sender: &mut mpsc::UnboundedSender<PcmResponse>,
@@ -404,7 +435,7 @@ async fn pcm_worker_loop(
             match *worker_status {
                 WorkerStatus::Quit => {
                     drain_desc_receiver(desc_receiver, sender).await?;
-                    if let Err(e) = write_data(dst_buf, None, buffer_writer).await {
+                    if let Err(e) = write_data(dst_buf, None, buffer_writer, None).await {
                         error!(
                             "[Card {}] Error on write_data after worker quit: {}",
                             card_index, e
@@ -413,7 +444,7 @@ async fn pcm_worker_loop(
                     break Ok(());
                 }
                 WorkerStatus::Pause => {
-                    write_data(dst_buf, None, buffer_writer).await?;
+                    write_data(dst_buf, None, buffer_writer, None).await?;
                 }
                 WorkerStatus::Running => match desc_receiver.try_next() {
                     Err(e) => {
@@ -421,17 +452,21 @@ async fn pcm_worker_loop(
                             "[Card {}] Underrun. No new DescriptorChain while running: {}",
                             card_index, e
                         );
-                        write_data(dst_buf, None, buffer_writer).await?;
+                        write_data(dst_buf, None, buffer_writer, None).await?;
                     }
                     Ok(None) => {
                         error!("[Card {}] Unreachable. status should be Quit when the channel is closed", card_index);
-                        write_data(dst_buf, None, buffer_writer).await?;
+                        write_data(dst_buf, None, buffer_writer, None).await?;
                         return Err(Error::InvalidPCMWorkerState);
                     }
                     Ok(Some(mut desc_chain)) => {
+                        let gain = match volume {
+                            Some(volume) => volume.gain().await,
+                            None => None,
+                        };
                         // stream_id was already read in handle_pcm_queue
                         let status =
-                            write_data(dst_buf, Some(&mut desc_chain.reader), buffer_writer)
+                            write_data(dst_buf, Some(&mut desc_chain.reader), buffer_writer, gain)
                                 .await
                                 .into();
                         sender
@@ -462,7 +497,7 @@ async fn pcm_worker_loop(
             match *worker_status {
                 WorkerStatus::Quit => {
                     drain_desc_receiver(desc_receiver, sender).await?;
-                    if let Err(e) = read_data(src_buf, None, period_bytes).await {
+                    if let Err(e) = read_data(src_buf, None, period_bytes, None).await {
                         error!(
                             "[Card {}] Error on read_data after worker quit: {}",
                             card_index, e
@@ -471,7 +506,7 @@ async fn pcm_worker_loop(
                     break Ok(());
                 }
                 WorkerStatus::Pause => {
-                    read_data(src_buf, None, period_bytes).await?;
+                    read_data(src_buf, None, period_bytes, None).await?;
                 }
                 WorkerStatus::Running => match desc_receiver.try_next() {
                     Err(e) => {
@@ -479,17 +514,22 @@ async fn pcm_worker_loop(
                             "[Card {}] Overrun. No new DescriptorChain while running: {}",
                             card_index, e
                         );
-                        read_data(src_buf, None, period_bytes).await?;
+                        read_data(src_buf, None, period_bytes, None).await?;
                     }
                     Ok(None) => {
                         error!("[Card {}] Unreachable. status should be Quit when the channel is closed", card_index);
-                        read_data(src_buf, None, period_bytes).await?;
+                        read_data(src_buf, None, period_bytes, None).await?;
                         return Err(Error::InvalidPCMWorkerState);
                     }
                     Ok(Some(mut desc_chain)) => {
-                        let status = read_data(src_buf, Some(&mut desc_chain.writer), period_bytes)
-                            .await
-                            .into();
+                        let gain = match volume {
+                            Some(volume) => volume.gain().await,
+                            None => None,
+                        };
+                        let status =
+                            read_data(src_buf, Some(&mut desc_chain.writer), period_bytes, gain)
+                                .await
+                                .into();
                         sender
                             .send(PcmResponse {
                                 desc_chain,
@@ -678,6 +718,7 @@ pub async fn handle_ctrl_queue(
     ex: &Executor,
     streams: &Rc<AsyncRwLock<Vec<AsyncRwLock<StreamInfo>>>>,
     snd_data: &SndData,
+    card_state: &Rc<AsyncRwLock<CardState>>,
     queue: Rc<AsyncRwLock<Queue>>,
     queue_event: &mut EventAsync,
     interrupt: Interrupt,
@@ -736,9 +777,12 @@ pub async fn handle_ctrl_queue(
                     writer
                         .write_obj(VIRTIO_SND_S_OK)
                         .map_err(Error::WriteResponse)?;
+                    let card_state = card_state.read_lock().await;
                     for i in start_id..(start_id + count) {
+                        let mut jack_info = snd_data.jack_info[i];
+                        jack_info.connected = card_state.jacks_connected[i] as u8;
                         writer
-                            .write_all(snd_data.jack_info[i].as_bytes())
+                            .write_all(jack_info.as_bytes())
                             .map_err(Error::WriteResponse)?;
                     }
                     Ok(())
@@ -808,6 +852,85 @@ pub async fn handle_ctrl_queue(
                 VIRTIO_SND_R_JACK_REMAP => {
                     unreachable!("remap is unsupported");
                 }
+                VIRTIO_SND_R_CTL_INFO => {
+                    let query_info: virtio_snd_query_info =
+                        reader.read_obj().map_err(Error::ReadMessage)?;
+                    let start_id: usize = u32::from(query_info.start_id) as usize;
+                    let count: usize = u32::from(query_info.count) as usize;
+                    if start_id + count > snd_data.controls.len() {
+                        error!(
+                            "[Card {}] start_id({}) + count({}) must be smaller than \
+                            the number of controls ({})",
+                            card_index,
+                            start_id,
+                            count,
+                            snd_data.controls.len()
+                        );
+                        return writer
+                            .write_obj(VIRTIO_SND_S_BAD_MSG)
+                            .map_err(Error::WriteResponse);
+                    }
+                    writer
+                        .write_obj(VIRTIO_SND_S_OK)
+                        .map_err(Error::WriteResponse)?;
+                    for control in &snd_data.controls[start_id..(start_id + count)] {
+                        writer
+                            .write_all(control.info().as_bytes())
+                            .map_err(Error::WriteResponse)?;
+                    }
+                    Ok(())
+                }
+                VIRTIO_SND_R_CTL_READ => {
+                    let hdr: virtio_snd_ctl_hdr = reader.read_obj().map_err(Error::ReadMessage)?;
+                    let control_id = u32::from(hdr.control_id) as usize;
+                    match card_state.read_lock().await.read_control(control_id) {
+                        Some(value) => {
+                            // The status is followed by a virtio_snd_ctl_value.
+                            writer
+                                .write_obj(VIRTIO_SND_S_OK)
+                                .map_err(Error::WriteResponse)?;
+                            writer.write_all(&value).map_err(Error::WriteResponse)
+                        }
+                        None => {
+                            error!("[Card {}] Invalid control id {}", card_index, control_id);
+                            writer
+                                .write_obj(VIRTIO_SND_S_BAD_MSG)
+                                .map_err(Error::WriteResponse)
+                        }
+                    }
+                }
+                VIRTIO_SND_R_CTL_WRITE => {
+                    let hdr: virtio_snd_ctl_hdr = reader.read_obj().map_err(Error::ReadMessage)?;
+                    let control_id = u32::from(hdr.control_id) as usize;
+                    let mut value = [0u8; VIRTIO_SND_CTL_VALUE_SIZE];
+                    reader.read_exact(&mut value).map_err(Error::ReadMessage)?;
+                    if card_state
+                        .lock()
+                        .await
+                        .write_control(snd_data, control_id, &value)
+                    {
+                        writer
+                            .write_obj(VIRTIO_SND_S_OK)
+                            .map_err(Error::WriteResponse)
+                    } else {
+                        error!(
+                            "[Card {}] Invalid value for control id {}",
+                            card_index, control_id
+                        );
+                        writer
+                            .write_obj(VIRTIO_SND_S_BAD_MSG)
+                            .map_err(Error::WriteResponse)
+                    }
+                }
+                VIRTIO_SND_R_CTL_ENUM_ITEMS
+                | VIRTIO_SND_R_CTL_TLV_READ
+                | VIRTIO_SND_R_CTL_TLV_WRITE
+                | VIRTIO_SND_R_CTL_TLV_COMMAND => {
+                    // Only boolean and integer controls without TLV data are exposed.
+                    writer
+                        .write_obj(VIRTIO_SND_S_NOT_SUPP)
+                        .map_err(Error::WriteResponse)
+                }
                 VIRTIO_SND_R_PCM_SET_PARAMS => {
                     // Raise VIRTIO_SND_S_BAD_MSG or IO error?
                     let set_params: virtio_snd_pcm_set_params =
@@ -939,18 +1062,149 @@ pub async fn handle_ctrl_queue(
 
 /// Send events to the audio driver.
 pub async fn handle_event_queue(
-    mut queue: Queue,
-    mut queue_event: EventAsync,
+    queue: Rc<AsyncRwLock<Queue>>,
+    queue_event: &mut EventAsync,
     interrupt: Interrupt,
+    events: &mut mpsc::UnboundedReceiver<virtio_snd_event>,
+    reset_signal: Option<&(AsyncRwLock<bool>, Condvar)>,
 ) -> Result<(), Error> {
+    let on_reset = await_reset_signal(reset_signal).fuse();
+    pin_mut!(on_reset);
+
     loop {
-        let desc_chain = queue
-            .next_async(&mut queue_event)
-            .await
-            .map_err(Error::Async)?;
+        let event = {
+            let next_event = events.next().fuse();
+            pin_mut!(next_event);
 
-        // TODO(woodychow): Poll and forward events from cras asynchronously (API to be added)
-        queue.add_used(desc_chain, 0);
+            select! {
+                _ = on_reset => break,
+                res = next_event => match res {
+                    Some(event) => event,
+                    None => break,
+                },
+            }
+        };
+
+        // Events are delivered in order, so wait for the driver to provide a buffer.
+        let mut queue = queue.lock().await;
+        let mut desc_chain = {
+            let next_async = queue.next_async(queue_event).fuse();
+            pin_mut!(next_async);
+
+            select! {
+                _ = on_reset => break,
+                res = next_async => res.map_err(Error::Async)?,
+            }
+        };
+
+        desc_chain
+            .writer
+            .write_obj(event)
+            .map_err(Error::WriteResponse)?;
+        let len = desc_chain.writer.bytes_written() as u32;
+        queue.add_used(desc_chain, len);
         queue.trigger_interrupt(&interrupt);
     }
+    Ok(())
+}
+
+fn snd_event(code: u32, data: u32) -> virtio_snd_event {
+    virtio_snd_event {
+        hdr: virtio_snd_hdr { code: code.into() },
+        data: data.into(),
+    }
+}
+
+/// Handle commands sent by the host on the control tube of the card and forward the resulting
+/// jack and control element changes to the driver.
+pub async fn handle_control_tube(
+    control_tube: &Option<AsyncTube>,
+    snd_data: &SndData,
+    card_state: &Rc<AsyncRwLock<CardState>>,
+    event_sender: mpsc::UnboundedSender<virtio_snd_event>,
+    card_index: usize,
+    reset_signal: Option<&(AsyncRwLock<bool>, Condvar)>,
+) -> Result<(), Error> {
+    let on_reset = await_reset_signal(reset_signal).fuse();
+    pin_mut!(on_reset);
+
+    let control_tube = match control_tube {
+        Some(t) => t,
+        None => {
+            on_reset.await;
+            return Ok(());
+        }
+    };
+
+    loop {
+        let command = {
+            let next_command = control_tube.next::<SndControlCommand>().fuse();
+            pin_mut!(next_command);
+
+            select! {
+                _ = on_reset => break,
+                res = next_command => res,
+            }
+        };
+        let command = match command {
+            Ok(command) => command,
+            Err(TubeError::Disconnected) => {
+                debug!("[Card {}] Control tube disconnected", card_index);
+                on_reset.await;
+                break;
+            }
+            Err(e) => return Err(Error::ControlTubeRecv(e)),
+        };
+
+        let (result, event) = match command {
+            SndControlCommand::SetJackConnected { jack_id, connected } => {
+                let mut card_state = card_state.lock().await;
+                match card_state.jacks_connected.get_mut(jack_id as usize) {
+                    Some(jack_connected) if *jack_connected != connected => {
+                        *jack_connected = connected;
+                        let code = if connected {
+                            VIRTIO_SND_EVT_JACK_CONNECTED
+                        } else {
+                            VIRTIO_SND_EVT_JACK_DISCONNECTED
+                        };
+                        (SndControlResult::Ok, Some(snd_event(code, jack_id)))
+                    }
+                    Some(_) => (SndControlResult::Ok, None),
+                    None => (SndControlResult::Err(SysError::new(libc::EINVAL)), None),
+                }
+            }
+            SndControlCommand::ListControls => (
+                SndControlResult::Controls(card_state.read_lock().await.list_controls(snd_data)),
+                None,
+            ),
+            SndControlCommand::SetControl { control_id, values } => {
+                if card_state
+                    .lock()
+                    .await
+                    .set_control(snd_data, control_id as usize, values)
+                {
+                    // struct virtio_snd_ctl_event packs the 16 bit control id and event mask.
+                    let mask = 1 << VIRTIO_SND_CTL_EVT_MASK_VALUE;
+                    let data = mask << 16 | (control_id & 0xffff);
+                    (
+                        SndControlResult::Ok,
+                        Some(snd_event(VIRTIO_SND_EVT_CTL_NOTIFY, data)),
+                    )
+                } else {
+                    (SndControlResult::Err(SysError::new(libc::EINVAL)), None)
+                }
+            }
+        };
+
+        if let Some(event) = event {
+            event_sender
+                .unbounded_send(event)
+                .map_err(|e| Error::MpscSend(e.into_send_error()))?;
+        }
+        control_tube
+            .send(result)
+            .await
+            .map_err(Error::ControlTubeSend)?;
+    }
+    Ok(())
 }
@@ -0,0 +1,414 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Jack and control element state of a virtio-snd card.
+
+use std::rc::Rc;
+
+use audio_streams::SampleFormat;
+use cros_async::sync::RwLock as AsyncRwLock;
+use serde::Deserialize;
+use serde::Serialize;
+use vm_control::SndControlValue;
+
+use crate::virtio::snd::common_backend::SndData;
+use crate::virtio::snd::constants::*;
+use crate::virtio::snd::layout::*;
+
+/// Static description of a control element.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub struct SndControl {
+    pub(crate) hda_fn_nid: u32,
+    pub(crate) direction: u8, /* VIRTIO_SND_D_XXX of the streams it applies to */
+    pub(crate) role: u32,     /* VIRTIO_SND_CTL_ROLE_XXX */
+    pub(crate) ctl_type: u32, /* VIRTIO_SND_CTL_TYPE_BOOLEAN or VIRTIO_SND_CTL_TYPE_INTEGER */
+    pub(crate) name: String,
+    pub(crate) index: u32,
+    pub(crate) count: u32,
+    pub(crate) min: i32,
+    pub(crate) max: i32,
+}
+
+impl SndControl {
+    /// Returns the information structure reported to the guest in response to
+    /// `VIRTIO_SND_R_CTL_INFO`.
+    pub fn info(&self) -> virtio_snd_ctl_info {
+        let mut name = [0u8; VIRTIO_SND_CTL_NAME_MAX];
+        // Keep the terminating NUL byte.
+        let len = self.name.len().min(VIRTIO_SND_CTL_NAME_MAX - 1);
+        name[..len].copy_from_slice(&self.name.as_bytes()[..len]);
+        virtio_snd_ctl_info {
+            hdr: virtio_snd_info {
+                hda_fn_nid: self.hda_fn_nid.into(),
+            },
+            role: self.role.into(),
+            type_: self.ctl_type.into(),
+            access: (1 << VIRTIO_SND_CTL_ACCESS_READ | 1 << VIRTIO_SND_CTL_ACCESS_WRITE).into(),
+            count: self.count.into(),
+            index: self.index.into(),
+            name,
+            name_padding: [0; 4],
+            min: (self.min as u32).into(),
+            max: (self.max as u32).into(),
+            step: 1.into(),
+            padding: [0; 12],
+        }
+    }
+
+    /// Returns whether `values` is a valid set of values for this control.
+    pub fn is_valid(&self, values: &[i32]) -> bool {
+        values.len() == self.count as usize
+            && values.iter().all(|v| (self.min..=self.max).contains(v))
+    }
+
+    /// Volumes start at their maximum and switches start on.
+    fn initial_values(&self) -> Vec<i32> {
+        vec![self.max; self.count as usize]
+    }
+}
+
+/// Mutable state of a card which is shared between the control queue, the event queue and the
+/// control tube.
+#[derive(Clone, Default, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub struct CardState {
+    pub(crate) jacks_connected: Vec<bool>,
+    pub(crate) control_values: Vec<Vec<i32>>,
+}
+
+impl CardState {
+    pub fn new(snd_data: &SndData) -> CardState {
+        CardState {
+            jacks_connected: snd_data
+                .jack_info
+                .iter()
+                .map(|jack| jack.connected != 0)
+                .collect(),
+            control_values: snd_data
+                .controls
+                .iter()
+                .map(SndControl::initial_values)
+                .collect(),
+        }
+    }
+
+    /// Returns every control element of the card with its current values.
+    pub fn list_controls(&self, snd_data: &SndData) -> Vec<SndControlValue> {
+        snd_data
+            .controls
+            .iter()
+            .zip(self.control_values.iter())
+            .enumerate()
+            .map(|(id, (control, values))| SndControlValue {
+                control_id: id as u32,
+                name: control.name.clone(),
+                index: control.index,
+                values: values.clone(),
+            })
+            .collect()
+    }
+
+    /// Encodes the values of control `control_id` as a `virtio_snd_ctl_value`.
+    pub fn read_control(&self, control_id: usize) -> Option<[u8; VIRTIO_SND_CTL_VALUE_SIZE]> {
+        let values = self.control_values.get(control_id)?;
+        let mut value = [0u8; VIRTIO_SND_CTL_VALUE_SIZE];
+        for (v, bytes) in values.iter().zip(value.chunks_exact_mut(4)) {
+            bytes.copy_from_slice(&v.to_le_bytes());
+        }
+        Some(value)
+    }
+
+    /// Decodes a `virtio_snd_ctl_value` written by the guest into the values of `control_id`.
+    /// Returns false if the control does not exist or the values are out of range.
+    pub fn write_control(
+        &mut self,
+        snd_data: &SndData,
+        control_id: usize,
+        value: &[u8; VIRTIO_SND_CTL_VALUE_SIZE],
+    ) -> bool {
+        let Some(control) = snd_data.controls.get(control_id) else {
+            return false;
+        };
+        let values: Vec<i32> = value
+            .chunks_exact(4)
+            .take(control.count as usize)
+            .map(|b| i32::from_le_bytes(b.try_into().unwrap()))
+            .collect();
+        self.set_control(snd_data, control_id, values)
+    }
+
+    /// Sets the values of `control_id`. Returns false if the control does not exist or the
+    /// values are invalid.
+    pub fn set_control(&mut self, snd_data: &SndData, control_id: usize, values: Vec<i32>) -> bool {
+        match snd_data.controls.get(control_id) {
+            Some(control) if control.is_valid(&values) => {
+                self.control_values[control_id] = values;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The volume and switch controls of the device a stream belongs to.
+#[derive(Clone)]
+pub struct StreamControls {
+    card_state: Rc<AsyncRwLock<CardState>>,
+    volume: usize,
+    volume_max: i32,
+    switch: usize,
+}
+
+impl StreamControls {
+    /// Returns the controls that apply to stream `stream_id`, if the card has them.
+    pub fn new(
+        snd_data: &SndData,
+        card_state: &Rc<AsyncRwLock<CardState>>,
+        stream_id: usize,
+    ) -> Option<StreamControls> {
+        let pcm_info = snd_data.pcm_info.get(stream_id)?;
+        let find = |role| {
+            snd_data.controls.iter().position(|control| {
+                control.role == role
+                    && control.hda_fn_nid == u32::from(pcm_info.hdr.hda_fn_nid)
+                    && control.direction == pcm_info.direction
+            })
+        };
+        let volume = find(VIRTIO_SND_CTL_ROLE_VOLUME)?;
+        let switch = find(VIRTIO_SND_CTL_ROLE_MUTE)?;
+        Some(StreamControls {
+            card_state: card_state.clone(),
+            volume,
+            volume_max: snd_data.controls[volume].max,
+            switch,
+        })
+    }
+
+    /// Binds the controls to the sample format and channel count a stream has been prepared with.
+    pub fn volume(&self, format: SampleFormat, channels: usize) -> StreamVolume {
+        StreamVolume {
+            controls: self.clone(),
+            format,
+            channels,
+        }
+    }
+}
+
+/// The controls of a prepared stream, which are applied to every period of its samples.
+pub struct StreamVolume {
+    controls: StreamControls,
+    format: SampleFormat,
+    channels: usize,
+}
+
+impl StreamVolume {
+    /// Returns the gain to apply to the next period, or `None` if it must be left unchanged.
+    pub async fn gain(&self) -> Option<Gain> {
+        let state = self.controls.card_state.read_lock().await;
+        let muted = state.control_values[self.controls.switch]
+            .iter()
+            .all(|&v| v == 0);
+        let volumes = &state.control_values[self.controls.volume];
+        // Channels beyond the count of the volume control, such as the rear ones of a 5.1 stream
+        // with a stereo control, reuse its values in turn.
+        let channels: Vec<f64> = (0..self.channels)
+            .map(|c| {
+                if muted {
+                    0.0
+                } else {
+                    volumes[c % volumes.len()] as f64 / self.controls.volume_max as f64
+                }
+            })
+            .collect();
+        if channels.iter().all(|&g| g == 1.0) {
+            return None;
+        }
+        Some(Gain {
+            format: self.format,
+            channels,
+        })
+    }
+}
+
+/// Linear gain of each channel of a stream.
+#[derive(Debug, PartialEq)]
+pub struct Gain {
+    format: SampleFormat,
+    channels: Vec<f64>,
+}
+
+impl Gain {
+    /// Scales the interleaved samples in `data` in place. A trailing partial sample is left as is.
+    pub fn apply(&self, data: &mut [u8]) {
+        let samples = data.chunks_exact_mut(self.format.sample_bytes());
+        for (sample, gain) in samples.zip(self.channels.iter().cycle()) {
+            match self.format {
+                SampleFormat::U8 => {
+                    sample[0] = ((sample[0] as f64 - 128.0) * gain + 128.0).round() as u8;
+                }
+                SampleFormat::S16LE => {
+                    let v = i16::from_le_bytes([sample[0], sample[1]]);
+                    sample.copy_from_slice(&((v as f64 * gain).round() as i16).to_le_bytes());
+                }
+                // 24-bit samples are stored in the low three bytes of 32-bit words.
+                SampleFormat::S24LE | SampleFormat::S32LE => {
+                    let mut v = i32::from_le_bytes(sample.try_into().unwrap());
+                    if self.format == SampleFormat::S24LE {
+                        v = v << 8 >> 8;
+                    }
+                    sample.copy_from_slice(&((v as f64 * gain).round() as i32).to_le_bytes());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snd_data() -> SndData {
+        SndData {
+            jack_info: Vec::new(),
+            pcm_info: Vec::new(),
+            chmap_info: Vec::new(),
+            controls: vec![
+                SndControl {
+                    hda_fn_nid: 0,
+                    direction: VIRTIO_SND_D_OUTPUT,
+                    role: VIRTIO_SND_CTL_ROLE_VOLUME,
+                    ctl_type: VIRTIO_SND_CTL_TYPE_INTEGER,
+                    name: "PCM Playback Volume".to_owned(),
+                    index: 0,
+                    count: 2,
+                    min: 0,
+                    max: 100,
+                },
+                SndControl {
+                    hda_fn_nid: 0,
+                    direction: VIRTIO_SND_D_OUTPUT,
+                    role: VIRTIO_SND_CTL_ROLE_MUTE,
+                    ctl_type: VIRTIO_SND_CTL_TYPE_BOOLEAN,
+                    name: "PCM Playback Switch".to_owned(),
+                    index: 0,
+                    count: 1,
+                    min: 0,
+                    max: 1,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn initial_values() {
+        let state = CardState::new(&snd_data());
+        assert_eq!(state.control_values, vec![vec![100, 100], vec![1]]);
+    }
+
+    #[test]
+    fn set_control() {
+        let data = snd_data();
+        let mut state = CardState::new(&data);
+        assert!(state.set_control(&data, 0, vec![20, 30]));
+        assert!(!state.set_control(&data, 0, vec![20]));
+        assert!(!state.set_control(&data, 0, vec![20, 101]));
+        assert!(!state.set_control(&data, 2, vec![0]));
+        assert_eq!(state.control_values[0], vec![20, 30]);
+    }
+
+    #[test]
+    fn read_write_control() {
+        let data = snd_data();
+        let mut state = CardState::new(&data);
+        let mut value = state.read_control(0).unwrap();
+        assert_eq!(&value[..8], &[100, 0, 0, 0, 100, 0, 0, 0]);
+        value[0] = 42;
+        assert!(state.write_control(&data, 0, &value));
+        assert_eq!(state.control_values[0], vec![42, 100]);
+        assert!(state.read_control(2).is_none());
+    }
+
+    #[test]
+    fn info_name_is_terminated() {
+        let mut control = snd_data().controls.remove(0);
+        control.name = "x".repeat(VIRTIO_SND_CTL_NAME_MAX);
+        let info = control.info();
+        assert_eq!(info.name[VIRTIO_SND_CTL_NAME_MAX - 1], 0);
+        assert_eq!(u32::from(info.count), 2);
+    }
+
+    #[test]
+    fn gain_s16() {
+        let gain = Gain {
+            format: SampleFormat::S16LE,
+            channels: vec![0.5, 0.0],
+        };
+        let mut data: Vec<u8> = [1000i16, 1000, -2000, -2000]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect();
+        gain.apply(&mut data);
+        assert_eq!(data, [500i16, 0, -1000, 0].map(i16::to_le_bytes).concat());
+    }
+
+    #[test]
+    fn gain_u8() {
+        let gain = Gain {
+            format: SampleFormat::U8,
+            channels: vec![0.5],
+        };
+        let mut data = vec![128, 228, 28];
+        gain.apply(&mut data);
+        assert_eq!(data, [128, 178, 78]);
+    }
+
+    #[test]
+    fn gain_s24() {
+        let gain = Gain {
+            format: SampleFormat::S24LE,
+            channels: vec![0.5],
+        };
+        let mut data = [-0x100000i32 & 0xffffff, 0x100000]
+            .map(i32::to_le_bytes)
+            .concat();
+        gain.apply(&mut data);
+        assert_eq!(data, [-0x80000i32, 0x80000].map(i32::to_le_bytes).concat());
+    }
+
+    #[test]
+    fn stream_volume() {
+        let mut data = snd_data();
+        data.pcm_info.push(virtio_snd_pcm_info {
+            direction: VIRTIO_SND_D_OUTPUT,
+            ..Default::default()
+        });
+        let card_state = Rc::new(AsyncRwLock::new(CardState::new(&data)));
+        let volume = StreamControls::new(&data, &card_state, 0)
+            .unwrap()
+            .volume(SampleFormat::S16LE, 3);
+        // Full volume leaves the samples untouched.
+        assert_eq!(cros_async::block_on(volume.gain()), None);
+
+        cros_async::block_on(card_state.lock()).control_values[0] = vec![50, 25];
+        assert_eq!(
+            cros_async::block_on(volume.gain()),
+            Some(Gain {
+                format: SampleFormat::S16LE,
+                channels: vec![0.5, 0.25, 0.5],
+            })
+        );
+
+        cros_async::block_on(card_state.lock()).control_values[1] = vec![0];
+        assert_eq!(
+            cros_async::block_on(volume.gain()),
+            Some(Gain {
+                format: SampleFormat::S16LE,
+                channels: vec![0.0; 3],
+            })
+        );
+
+        // Controls of the other direction do not apply.
+        data.pcm_info[0].direction = VIRTIO_SND_D_INPUT;
+        assert!(StreamControls::new(&data, &card_state, 0).is_none());
+    }
+}
@@ -20,11 +20,13 @@ use base::Descriptor;
 use base::Error as SysError;
 use base::Event;
 use base::RawDescriptor;
+use base::Tube;
 use base::WorkerThread;
 use cros_async::block_on;
 use cros_async::sync::Condvar;
 use cros_async::sync::RwLock as AsyncRwLock;
 use cros_async::AsyncError;
+use cros_async::AsyncTube;
 use cros_async::EventAsync;
 use cros_async::Executor;
 use futures::channel::mpsc;
@@ -45,6 +47,9 @@ use crate::virtio::async_utils;
 use crate::virtio::copy_config;
 use crate::virtio::device_constants::snd::virtio_snd_config;
 use crate::virtio::snd::common_backend::async_funcs::*;
+use crate::virtio::snd::common_backend::controls::CardState;
+use crate::virtio::snd::common_backend::controls::SndControl;
+use crate::virtio::snd::common_backend::controls::StreamControls;
 use crate::virtio::snd::common_backend::stream_info::StreamInfo;
 use crate::virtio::snd::common_backend::stream_info::StreamInfoBuilder;
 use crate::virtio::snd::common_backend::stream_info::StreamInfoSnapshot;
@@ -67,6 +72,7 @@ use crate::virtio::Queue;
 use crate::virtio::VirtioDevice;
 
 pub mod async_funcs;
+pub mod controls;
 pub mod stream_info;
 
 // control + event + tx + rx queue
@@ -92,6 +98,12 @@ pub enum Error {
     CreateWaitContext(SysError),
     #[error("Failed to create file stream source generator")]
     CreateFileStreamSourceGenerator(FileError),
+    /// Receiving a command from the control tube failed.
+    #[error("Failed to receive control command: {0}")]
+    ControlTubeRecv(base::TubeError),
+    /// Sending a response to the control tube failed.
+    #[error("Failed to send control response: {0}")]
+    ControlTubeSend(base::TubeError),
     /// Cloning kill event failed.
     #[error("Failed to clone kill event: {0}")]
     CloneKillEvent(SysError),
@@ -163,6 +175,8 @@ pub struct SndData {
     pub(crate) jack_info: Vec<virtio_snd_jack_info>,
     pub(crate) pcm_info: Vec<virtio_snd_pcm_info>,
     pub(crate) chmap_info: Vec<virtio_snd_chmap_info>,
+    #[serde(default)]
+    pub(crate) controls: Vec<SndControl>,
 }
 
 impl SndData {
@@ -173,6 +187,15 @@ impl SndData {
     pub fn pcm_info_iter(&self) -> std::slice::Iter<'_, virtio_snd_pcm_info> {
         self.pcm_info.iter()
     }
+
+    /// Returns the device features required by the jacks and controls of the card.
+    pub fn features(&self) -> u64 {
+        if self.controls.is_empty() {
+            0
+        } else {
+            1 << VIRTIO_SND_F_CTLS
+        }
+    }
 }
 
 const SUPPORTED_FORMATS: u64 = 1 << VIRTIO_SND_PCM_FMT_U8
@@ -205,6 +228,8 @@ pub struct VirtioSnd {
     keep_rds: Vec<Descriptor>,
     streams_state: Option<Vec<StreamInfoSnapshot>>,
     card_index: usize,
+    control_tube: Option<Tube>,
+    card_state: CardState,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -214,19 +239,30 @@ struct VirtioSndSnapshot {
     queue_sizes: Vec<u16>,
     streams_state: Option<Vec<StreamInfoSnapshot>>,
     snd_data: SndData,
+    #[serde(default)]
+    card_state: Option<CardState>,
 }
 
 impl VirtioSnd {
-    pub fn new(base_features: u64, params: Parameters) -> Result<VirtioSnd, Error> {
+    pub fn new(
+        base_features: u64,
+        params: Parameters,
+        control_tube: Option<Tube>,
+    ) -> Result<VirtioSnd, Error> {
         let params = resize_parameters_pcm_device_config(params);
         let cfg = hardcoded_virtio_snd_config(&params);
         let snd_data = hardcoded_snd_data(&params);
-        let avail_features = base_features;
+        let avail_features = base_features | snd_data.features();
         let mut keep_rds: Vec<RawDescriptor> = Vec::new();
+        if let Some(control_tube) = &control_tube {
+            keep_rds.push(control_tube.as_raw_descriptor());
+        }
 
         let stream_info_builders =
             create_stream_info_builders(&params, &snd_data, &mut keep_rds, params.card_index)?;
 
+        let card_state = CardState::new(&snd_data);
+
         Ok(VirtioSnd {
             cfg,
             snd_data,
@@ -238,6 +274,8 @@ impl VirtioSnd {
             keep_rds: keep_rds.iter().map(|rd| Descriptor(*rd)).collect(),
             streams_state: None,
             card_index: params.card_index,
+            control_tube,
+            card_state,
         })
     }
 }
@@ -283,16 +321,18 @@ pub(crate) fn create_stream_info_builders(
 
 // To be used with hardcoded_snd_data
 pub fn hardcoded_virtio_snd_config(params: &Parameters) -> virtio_snd_config {
+    let num_devices = params.num_output_devices + params.num_input_devices;
     virtio_snd_config {
-        jacks: 0.into(),
+        jacks: if params.jacks { num_devices } else { 0 }.into(),
         streams: params.get_total_streams().into(),
         chmaps: (params.num_output_devices * 3 + params.num_input_devices).into(),
+        controls: if params.controls { num_devices * 2 } else { 0 }.into(),
     }
 }
 
 // To be used with hardcoded_virtio_snd_config
 pub fn hardcoded_snd_data(params: &Parameters) -> SndData {
-    let jack_info: Vec<virtio_snd_jack_info> = Vec::new();
+    let mut jack_info: Vec<virtio_snd_jack_info> = Vec::new();
     let mut pcm_info: Vec<virtio_snd_pcm_info> = Vec::new();
     let mut chmap_info: Vec<virtio_snd_chmap_info> = Vec::new();
 
@@ -379,13 +419,101 @@ pub fn hardcoded_snd_data(params: &Parameters) -> SndData {
         });
     }
 
+    if params.jacks {
+        // One line out jack per output device and one mic in jack per input device, each in its
+        // own HDA association.
+        for dev in 0..params.num_output_devices {
+            jack_info.push(hardcoded_jack_info(
+                dev,
+                HDA_DEFCONF_LINE_OUT,
+                HDA_CAPS_OUTPUT,
+                dev + 1,
+            ));
+        }
+        for dev in 0..params.num_input_devices {
+            jack_info.push(hardcoded_jack_info(
+                dev,
+                HDA_DEFCONF_MIC_IN,
+                HDA_CAPS_INPUT,
+                params.num_output_devices + dev + 1,
+            ));
+        }
+    }
+
+    let mut controls = Vec::new();
+    if params.controls {
+        for dev in 0..params.num_output_devices {
+            controls.extend(hardcoded_controls(dev, VIRTIO_SND_D_OUTPUT, "PCM Playback"));
+        }
+        for dev in 0..params.num_input_devices {
+            controls.extend(hardcoded_controls(dev, VIRTIO_SND_D_INPUT, "Capture"));
+        }
+    }
+
     SndData {
         jack_info,
         pcm_info,
         chmap_info,
+        controls,
+    }
+}
+
+// Default device values of the HDA pin configuration register.
+const HDA_DEFCONF_LINE_OUT: u32 = 0x0;
+const HDA_DEFCONF_MIC_IN: u32 = 0xa;
+// HDA pin capabilities: presence detect plus output or input.
+const HDA_CAPS_OUTPUT: u32 = 1 << 2 | 1 << 4;
+const HDA_CAPS_INPUT: u32 = 1 << 2 | 1 << 5;
+
+fn hardcoded_jack_info(
+    dev: u32,
+    default_device: u32,
+    caps: u32,
+    association: u32,
+) -> virtio_snd_jack_info {
+    // Fixed function device with an external 1/8" jack.
+    let defconf = 0x01 << 24 | default_device << 20 | 0x1 << 16 | (association & 0xf) << 4;
+    virtio_snd_jack_info {
+        hdr: virtio_snd_info {
+            hda_fn_nid: dev.into(),
+        },
+        features: 0.into(),
+        hda_reg_defconf: defconf.into(),
+        hda_reg_caps: caps.into(),
+        connected: 1,
+        padding: [0; 7],
     }
 }
 
+/// Returns a stereo volume and a switch control for the `direction` streams of device `dev`, named
+/// like the ALSA mixer elements `"<prefix> Volume"` and `"<prefix> Switch"`.
+fn hardcoded_controls(dev: u32, direction: u8, prefix: &str) -> [SndControl; 2] {
+    [
+        SndControl {
+            hda_fn_nid: dev,
+            direction,
+            role: VIRTIO_SND_CTL_ROLE_VOLUME,
+            ctl_type: VIRTIO_SND_CTL_TYPE_INTEGER,
+            name: format!("{prefix} Volume"),
+            index: dev,
+            count: 2,
+            min: 0,
+            max: 100,
+        },
+        SndControl {
+            hda_fn_nid: dev,
+            direction,
+            role: VIRTIO_SND_CTL_ROLE_MUTE,
+            ctl_type: VIRTIO_SND_CTL_TYPE_BOOLEAN,
+            name: format!("{prefix} Switch"),
+            index: dev,
+            count: 1,
+            min: 0,
+            max: 1,
+        },
+    ]
+}
+
 fn resize_parameters_pcm_device_config(mut params: Parameters) -> Parameters {
     if params.output_device_config.len() > params.num_output_devices as usize {
         warn!("Truncating output device config due to length > number of output devices");
@@ -458,6 +586,8 @@ impl VirtioDevice for VirtioSnd {
         let stream_info_builders = self.stream_info_builders.to_vec();
         let streams_state = self.streams_state.take();
         let card_index = self.card_index;
+        let control_tube = self.control_tube.take();
+        let card_state = self.card_state.clone();
         self.worker_thread = Some(WorkerThread::start("v_snd_common", move |kill_evt| {
             let _thread_priority_handle = set_audio_thread_priority();
             if let Err(e) = _thread_priority_handle {
@@ -471,6 +601,8 @@ impl VirtioDevice for VirtioSnd {
                 stream_info_builders,
                 streams_state,
                 card_index,
+                control_tube,
+                card_state,
             )
         }));
 
@@ -490,6 +622,8 @@ impl VirtioDevice for VirtioSnd {
             let worker = worker_thread.stop().unwrap();
             self.snd_data = worker.snd_data;
             self.streams_state = Some(worker.streams_state);
+            self.control_tube = worker.control_tube;
+            self.card_state = worker.card_state;
             return Ok(Some(BTreeMap::from_iter(
                 worker.queues.into_iter().enumerate(),
             )));
@@ -529,6 +663,7 @@ impl VirtioDevice for VirtioSnd {
             queue_sizes: self.queue_sizes.to_vec(),
             streams_state,
             snd_data: self.snd_data.clone(),
+            card_state: Some(self.card_state.clone()),
         })
         .context("failed to Serialize Sound device")
     }
@@ -557,6 +692,10 @@ impl VirtioDevice for VirtioSnd {
         );
         self.acked_features = deser.acked_features;
         self.streams_state = deser.streams_state.take();
+        // Snapshots taken before jacks and controls existed don't have a card state.
+        if let Some(card_state) = deser.card_state {
+            self.card_state = card_state;
+        }
         Ok(())
     }
 }
@@ -575,8 +714,13 @@ fn run_worker(
     stream_info_builders: Vec<StreamInfoBuilder>,
     streams_state: Option<Vec<StreamInfoSnapshot>>,
     card_index: usize,
+    control_tube: Option<Tube>,
+    card_state: CardState,
 ) -> Result<WorkerReturn, String> {
     let ex = Executor::new().expect("Failed to create an executor");
+    let control_tube =
+        control_tube.map(|t| AsyncTube::new(&ex, t).expect("Failed to create async tube"));
+    let card_state = Rc::new(AsyncRwLock::new(card_state));
 
     if snd_data.pcm_info_len() != stream_info_builders.len() {
         error!(
@@ -587,11 +731,16 @@ fn run_worker(
     }
     let streams: Vec<AsyncRwLock<StreamInfo>> = stream_info_builders
         .into_iter()
-        .map(StreamInfoBuilder::build)
-        .map(AsyncRwLock::new)
+        .enumerate()
+        .map(|(stream_id, builder)| {
+            let mut stream = builder.build();
+            stream.controls = StreamControls::new(&snd_data, &card_state, stream_id);
+            AsyncRwLock::new(stream)
+        })
         .collect();
 
     let (tx_send, mut tx_recv) = mpsc::unbounded();
+    let (event_send, mut event_recv) = mpsc::unbounded();
     let (rx_send, mut rx_recv) = mpsc::unbounded();
     let tx_send_clone = tx_send.clone();
     let rx_send_clone = rx_send.clone();
@@ -639,7 +788,8 @@ fn run_worker(
 
     let (ctrl_queue, mut ctrl_queue_evt) = queues.remove(0);
     let ctrl_queue = Rc::new(AsyncRwLock::new(ctrl_queue));
-    let (_event_queue, _event_queue_evt) = queues.remove(0);
+    let (event_queue, mut event_queue_evt) = queues.remove(0);
+    let event_queue = Rc::new(AsyncRwLock::new(event_queue));
     let (tx_queue, tx_queue_evt) = queues.remove(0);
     let (rx_queue, rx_queue_evt) = queues.remove(0);
 
@@ -663,6 +813,12 @@ fn run_worker(
             &mut f_resample,
             ctrl_queue.clone(),
             &mut ctrl_queue_evt,
+            event_queue.clone(),
+            &mut event_queue_evt,
+            event_send.clone(),
+            &mut event_recv,
+            &control_tube,
+            &card_state,
             tx_queue.clone(),
             &tx_queue_evt,
             tx_send.clone(),
@@ -704,6 +860,10 @@ fn run_worker(
         Ok(q) => q.into_inner(),
         Err(_) => panic!("Too many refs to ctrl_queue"),
     };
+    let event_queue = match Rc::try_unwrap(event_queue) {
+        Ok(q) => q.into_inner(),
+        Err(_) => panic!("Too many refs to event_queue"),
+    };
     let tx_queue = match Rc::try_unwrap(tx_queue) {
         Ok(q) => q.into_inner(),
         Err(_) => panic!("Too many refs to tx_queue"),
@@ -712,12 +872,18 @@ fn run_worker(
         Ok(q) => q.into_inner(),
         Err(_) => panic!("Too many refs to rx_queue"),
     };
-    let queues = vec![ctrl_queue, _event_queue, tx_queue, rx_queue];
+    // The streams keep references to the card state for their volume controls.
+    let card_state = ex
+        .run_until(async move { card_state.read_lock().await.clone() })
+        .expect("failed to save card state");
+    let queues = vec![ctrl_queue, event_queue, tx_queue, rx_queue];
 
     Ok(WorkerReturn {
         queues,
         snd_data,
         streams_state,
+        control_tube: control_tube.map(Tube::from),
+        card_state,
     })
 }
 
@@ -725,6 +891,8 @@ struct WorkerReturn {
     queues: Vec<Queue>,
     snd_data: SndData,
     streams_state: Vec<StreamInfoSnapshot>,
+    control_tube: Option<Tube>,
+    card_state: CardState,
 }
 
 async fn notify_reset_signal(reset_signal: &(AsyncRwLock<bool>, Condvar)) {
@@ -750,6 +918,12 @@ fn run_worker_once(
     mut f_resample: &mut (impl FusedFuture<Output = anyhow::Result<()>> + Unpin),
     ctrl_queue: Rc<AsyncRwLock<Queue>>,
     ctrl_queue_evt: &mut EventAsync,
+    event_queue: Rc<AsyncRwLock<Queue>>,
+    event_queue_evt: &mut EventAsync,
+    event_send: mpsc::UnboundedSender<virtio_snd_event>,
+    event_recv: &mut mpsc::UnboundedReceiver<virtio_snd_event>,
+    control_tube: &Option<AsyncTube>,
+    card_state: &Rc<AsyncRwLock<CardState>>,
     tx_queue: Rc<AsyncRwLock<Queue>>,
     tx_queue_evt: &EventAsync,
     tx_send: mpsc::UnboundedSender<PcmResponse>,
@@ -769,6 +943,7 @@ fn run_worker_once(
         ex,
         streams,
         snd_data,
+        card_state,
         ctrl_queue,
         ctrl_queue_evt,
         interrupt.clone(),
@@ -779,13 +954,23 @@ fn run_worker_once(
     )
     .fuse();
 
-    // TODO(woodychow): Enable this when libcras sends jack connect/disconnect evts
-    // let f_event = handle_event_queue(
-    //     snd_state,
-    //     event_queue,
-    //     event_queue_evt,
-    //     interrupt,
-    // );
+    let f_event = handle_event_queue(
+        event_queue,
+        event_queue_evt,
+        interrupt.clone(),
+        event_recv,
+        Some(&reset_signal),
+    )
+    .fuse();
+    let f_control_tube = handle_control_tube(
+        control_tube,
+        snd_data,
+        card_state,
+        event_send,
+        card_index,
+        Some(&reset_signal),
+    )
+    .fuse();
     let f_tx = handle_pcm_queue(
         streams,
         tx_send2,
@@ -809,11 +994,21 @@ fn run_worker_once(
     let f_rx_response =
         send_pcm_response_worker(rx_queue, interrupt, rx_recv, Some(&reset_signal)).fuse();
 
-    pin_mut!(f_ctrl, f_tx, f_tx_response, f_rx, f_rx_response);
+    pin_mut!(
+        f_ctrl,
+        f_event,
+        f_control_tube,
+        f_tx,
+        f_tx_response,
+        f_rx,
+        f_rx_response
+    );
 
     let done = async {
         select! {
             res = f_ctrl => (res.context("error in handling ctrl queue"), LoopState::Continue),
+            res = f_event => (res.context("error in handling event queue"), LoopState::Continue),
+            res = f_control_tube => (res.context("error in handling control tube"), LoopState::Continue),
             res = f_tx => (res.context("error in handling tx queue"), LoopState::Continue),
             res = f_tx_response => (res.context("error in handling tx response"), LoopState::Continue),
             res = f_rx => (res.context("error in handling rx queue"), LoopState::Continue),
@@ -846,6 +1041,8 @@ fn run_worker_once(
         loop {
             let (res, worker_name) = select!(
                 res = f_ctrl => (res, "f_ctrl"),
+                res = f_event => (res, "f_event"),
+                res = f_control_tube => (res, "f_control_tube"),
                 res = f_tx => (res, "f_tx"),
                 res = f_tx_response => (res, "f_tx_response"),
                 res = f_rx => (res, "f_rx"),
@@ -958,7 +1155,7 @@ mod tests {
             ..Default::default()
         };
 
-        let res = VirtioSnd::new(123, params).unwrap();
+        let res = VirtioSnd::new(123, params, None).unwrap();
 
         // Default values
         assert_eq!(res.snd_data.jack_info.len(), 0);
@@ -1013,6 +1210,50 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_virtio_snd_new_jacks_controls() {
+        let params = Parameters {
+            num_output_devices: 2,
+            num_input_devices: 1,
+            jacks: true,
+            controls: true,
+            ..Default::default()
+        };
+
+        let res = VirtioSnd::new(0, params, None).unwrap();
+
+        assert_eq!(res.avail_features, 1 << VIRTIO_SND_F_CTLS);
+        assert_eq!(res.cfg.jacks.to_native(), 3);
+        assert_eq!(res.cfg.controls.to_native(), 6);
+        assert_eq!(res.snd_data.jack_info.len(), 3);
+        assert_eq!(res.snd_data.controls.len(), 6);
+        assert_eq!(res.card_state.jacks_connected, vec![true; 3]);
+
+        // The first jacks are line outs, the last one is a mic in.
+        let default_device =
+            |i: usize| (res.snd_data.jack_info[i].hda_reg_defconf.to_native() >> 20) & 0xf;
+        assert_eq!(default_device(1), HDA_DEFCONF_LINE_OUT);
+        assert_eq!(default_device(2), HDA_DEFCONF_MIC_IN);
+
+        let names: Vec<_> = res
+            .snd_data
+            .controls
+            .iter()
+            .map(|c| (c.name.as_str(), c.index))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                ("PCM Playback Volume", 0),
+                ("PCM Playback Switch", 0),
+                ("PCM Playback Volume", 1),
+                ("PCM Playback Switch", 1),
+                ("Capture Volume", 0),
+                ("Capture Switch", 0),
+            ]
+        );
+    }
+
     #[test]
     fn test_resize_parameters_pcm_device_config_truncate() {
         // If pcm_device_config is larger than number of devices, it will be truncated
@@ -25,6 +25,7 @@ use super::PcmResponse;
 use super::WorkerStatus;
 use crate::virtio::snd::common::*;
 use crate::virtio::snd::common_backend::async_funcs::*;
+use crate::virtio::snd::common_backend::controls::StreamControls;
 use crate::virtio::snd::common_backend::DirectionalStream;
 use crate::virtio::snd::common_backend::SysAsyncStreamObjects;
 use crate::virtio::snd::constants::*;
@@ -109,6 +110,8 @@ pub struct StreamInfo {
     pub state: u32, // VIRTIO_SND_R_PCM_SET_PARAMS -> VIRTIO_SND_R_PCM_STOP, or 0 (uninitialized)
     // Stream effects to use when creating a new stream on [`prepare()`].
     pub(crate) effects: Vec<StreamEffect>,
+    // Volume and switch controls applied to the samples of the stream, if the card has them.
+    pub(crate) controls: Option<StreamControls>,
 
     // just_reset set to true after reset. Make invalid state transition return Ok. Set to false
     // after a valid state transition to SET_PARAMS or PREPARE.
@@ -192,6 +195,7 @@ impl From<StreamInfoBuilder> for StreamInfo {
             direction: 0,
             state: 0,
             effects: builder.effects,
+            controls: None,
             just_reset: false,
             status_mutex: Rc::new(AsyncRwLock::new(WorkerStatus::Pause)),
             sender: None,
@@ -324,9 +328,14 @@ impl StreamInfo {
         );
         let release_signal = Rc::new((AsyncRwLock::new(false), Condvar::new()));
         self.release_signal = Some(release_signal.clone());
+        let volume = self
+            .controls
+            .as_ref()
+            .map(|controls| controls.volume(self.format, self.channels as usize));
         let f = start_pcm_worker(
             ex.clone(),
             stream_objects.stream,
+            volume,
             receiver,
             self.status_mutex.clone(),
             stream_objects.pcm_sender,
@@ -11,6 +11,7 @@ use std::io::Write;
 use std::path::Path;
 
 use audio_streams::NoopStreamSourceGenerator;
+use audio_util::FileCaptureStreamSourceGenerator;
 use audio_util::FileStreamSourceGenerator;
 use base::error;
 use base::open_file_or_duplicate;
@@ -27,6 +28,8 @@ use crate::virtio::snd::sys::SysAudioStreamSourceGenerator;
 pub enum Error {
     #[error("Failed to allocate space: {0}")]
     AllocateSpace(IOError),
+    #[error("Failed to open capture file: {0}")]
+    OpenCaptureFile(audio_util::Error),
     #[error("Failed to open file: {0}")]
     OpenFile(base::Error),
 }
@@ -57,6 +60,15 @@ pub(crate) fn create_file_stream_source_generators(
     keep_rds: &mut Vec<RawDescriptor>,
 ) -> Result<Vec<SysAudioStreamSourceGenerator>, Error> {
     let mut generators = Vec::new();
+    // The capture file is decoded once and shared by all capture streams.
+    let capture = if params.capture_path.is_empty() {
+        None
+    } else {
+        Some(
+            FileCaptureStreamSourceGenerator::new(Path::new(&params.capture_path))
+                .map_err(Error::OpenCaptureFile)?,
+        )
+    };
 
     for (stream, pcm_info) in snd_data.pcm_info.iter().enumerate() {
         let generator: SysAudioStreamSourceGenerator = if pcm_info.direction == VIRTIO_SND_D_OUTPUT
@@ -66,8 +78,9 @@ pub(crate) fn create_file_stream_source_generators(
             keep_rds.push(file.as_raw_descriptor());
 
             Box::new(FileStreamSourceGenerator::new(file, params.playback_size))
+        } else if let Some(capture) = &capture {
+            Box::new(capture.clone())
         } else {
-            // Capture is not supported yet
             Box::new(NoopStreamSourceGenerator::new())
         };
 
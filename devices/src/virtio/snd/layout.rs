@@ -12,6 +12,7 @@ use zerocopy::FromZeroes;
 
 use crate::virtio::snd::constants::StatusCode;
 use crate::virtio::snd::constants::VIRTIO_SND_CHMAP_MAX_SIZE;
+use crate::virtio::snd::constants::VIRTIO_SND_CTL_NAME_MAX;
 
 #[derive(Copy, Clone, Default, AsBytes, FromZeroes, FromBytes, Serialize, Deserialize)]
 #[repr(C)]
@@ -179,3 +180,31 @@ pub struct virtio_snd_chmap_info {
     pub channels: u8,
     pub positions: [u8; VIRTIO_SND_CHMAP_MAX_SIZE],
 }
+
+#[derive(Copy, Clone, Default, AsBytes, FromZeroes, FromBytes)]
+#[repr(C)]
+pub struct virtio_snd_ctl_hdr {
+    pub hdr: virtio_snd_hdr,
+    pub control_id: Le32,
+}
+
+#[derive(Copy, Clone, AsBytes, FromZeroes, FromBytes)]
+#[repr(C)]
+pub struct virtio_snd_ctl_info {
+    pub hdr: virtio_snd_info,
+    pub role: Le32,   /* VIRTIO_SND_CTL_ROLE_XXX */
+    pub type_: Le32,  /* VIRTIO_SND_CTL_TYPE_XXX */
+    pub access: Le32, /* 1 << VIRTIO_SND_CTL_ACCESS_XXX */
+    pub count: Le32,
+    pub index: Le32,
+    pub name: [u8; VIRTIO_SND_CTL_NAME_MAX],
+    /* The value union holds 64-bit members and is therefore 8-byte aligned. */
+    pub name_padding: [u8; 4],
+    /* Only the integer member of the value union is used, the rest of its 24 bytes is padding. */
+    pub min: Le32,
+    pub max: Le32,
+    pub step: Le32,
+    pub padding: [u8; 12],
+}
+
+const _: () = assert!(std::mem::size_of::<virtio_snd_ctl_info>() == 96);
@@ -86,6 +86,10 @@ pub struct PCMDeviceParameters {
     pub client_type: Option<CrasClientType>,
     #[cfg(all(unix, feature = "audio_cras"))]
     pub stream_type: Option<CrasStreamType>,
+    /// Host device the PCM device is connected to: an ALSA PCM name (e.g. `hw:0,0`) with the
+    /// alsa backend, or the name of the target node with the pipewire backend.
+    #[cfg(all(unix, any(feature = "audio_alsa", feature = "audio_pipewire")))]
+    pub device: Option<String>,
     pub effects: Option<Vec<StreamEffect>>,
 }
 
@@ -101,6 +105,9 @@ pub struct Parameters {
     pub num_input_streams: u32,
     pub playback_path: String,
     pub playback_size: usize,
+    pub capture_path: String,
+    pub jacks: bool,
+    pub controls: bool,
     #[cfg(all(unix, feature = "audio_cras"))]
     #[serde(deserialize_with = "libcras::deserialize_cras_client_type")]
     pub client_type: CrasClientType,
@@ -122,6 +129,9 @@ impl Default for Parameters {
             num_input_streams: 1,
             playback_path: "".to_string(),
             playback_size: 0,
+            capture_path: "".to_string(),
+            jacks: false,
+            controls: false,
             #[cfg(all(unix, feature = "audio_cras"))]
             client_type: CrasClientType::CRAS_CLIENT_TYPE_CROSVM,
             #[cfg(all(unix, feature = "audio_cras"))]
@@ -295,6 +305,23 @@ mod tests {
         check_failure("output_device_config=[[effects=[none]]]");
     }
 
+    #[test]
+    fn file_capture_jacks_controls_fromstr() {
+        let params: Parameters = serde_keyvalue::from_key_values(
+            "backend=file,capture=true,capture_path=/tmp/in.wav,jacks=true,controls=true",
+        )
+        .expect("parse should have succeded");
+        assert_eq!(params.backend, StreamSourceBackend::FILE);
+        assert_eq!(params.capture_path, "/tmp/in.wav");
+        assert!(params.jacks);
+        assert!(params.controls);
+
+        let params: Parameters = serde_keyvalue::from_key_values("backend=file").unwrap();
+        assert!(params.capture_path.is_empty());
+        assert!(!params.jacks);
+        assert!(!params.controls);
+    }
+
     #[test]
     #[cfg(all(unix, feature = "audio_cras"))]
     fn cras_parameters_fromstr() {
@@ -366,11 +393,13 @@ mod tests {
                     client_type: Some(CrasClientType::CRAS_CLIENT_TYPE_CROSVM),
                     stream_type: None,
                     effects: None,
+                    ..Default::default()
                 },
                 PCMDeviceParameters{
                     client_type: Some(CrasClientType::CRAS_CLIENT_TYPE_ARCVM),
                     stream_type: Some(CrasStreamType::CRAS_STREAM_TYPE_PRO_AUDIO),
                     effects: None,
+                    ..Default::default()
                 },
                 Default::default(),
                 ],
@@ -387,16 +416,19 @@ mod tests {
                     client_type: Some(CrasClientType::CRAS_CLIENT_TYPE_CROSVM),
                     stream_type: None,
                     effects: None,
+                    ..Default::default()
                 },
                 PCMDeviceParameters{
                     client_type: Some(CrasClientType::CRAS_CLIENT_TYPE_ARCVM),
                     stream_type: Some(CrasStreamType::CRAS_STREAM_TYPE_PRO_AUDIO),
                     effects: Some(vec![StreamEffect::EchoCancellation]),
+                    ..Default::default()
                 },
                 PCMDeviceParameters{
                     client_type: None,
                     stream_type: None,
                     effects: Some(vec![StreamEffect::EchoCancellation]),
+                    ..Default::default()
                 },
                 Default::default(),
                 ],
@@ -409,6 +441,50 @@ mod tests {
         check_failure("output_device_config=[[stream_type=none]]");
     }
 
+    #[test]
+    #[cfg(all(unix, feature = "audio_alsa"))]
+    fn alsa_parameters_fromstr() {
+        check_success(
+            "backend=alsa,output_device_config=[[device=hw:0]],input_device_config=[[],[device=default]]",
+            false,
+            StreamSourceBackend::Sys(SysStreamSourceBackend::ALSA),
+            1,
+            1,
+            1,
+            1,
+            vec![PCMDeviceParameters {
+                device: Some("hw:0".to_owned()),
+                ..Default::default()
+            }],
+            vec![
+                Default::default(),
+                PCMDeviceParameters {
+                    device: Some("default".to_owned()),
+                    ..Default::default()
+                },
+            ],
+        );
+    }
+
+    #[test]
+    #[cfg(all(unix, feature = "audio_pipewire"))]
+    fn pipewire_parameters_fromstr() {
+        check_success(
+            "backend=pipewire,output_device_config=[[device=speakers]]",
+            false,
+            StreamSourceBackend::Sys(SysStreamSourceBackend::PIPEWIRE),
+            1,
+            1,
+            1,
+            1,
+            vec![PCMDeviceParameters {
+                device: Some("speakers".to_owned()),
+                ..Default::default()
+            }],
+            vec![],
+        );
+    }
+
     #[test]
     fn get_device_params_output() {
         let params = Parameters {
@@ -2,6 +2,8 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+#[cfg(feature = "audio_alsa")]
+use alsa_audio::AlsaStreamSourceGenerator;
 #[cfg(feature = "audio_aaudio")]
 use android_audio::AndroidAudioStreamSourceGenerator;
 use async_trait::async_trait;
@@ -11,7 +13,11 @@ use audio_streams::AsyncPlaybackBufferStream;
 use audio_streams::BoxError;
 use audio_streams::StreamSource;
 use audio_streams::StreamSourceGenerator;
-#[cfg(feature = "audio_cras")]
+#[cfg(any(
+    feature = "audio_alsa",
+    feature = "audio_cras",
+    feature = "audio_pipewire"
+))]
 use base::error;
 use base::set_rt_prio_limit;
 use base::set_rt_round_robin;
@@ -21,6 +27,10 @@ use futures::channel::mpsc::UnboundedSender;
 use libcras::CrasStreamSourceGenerator;
 #[cfg(feature = "audio_cras")]
 use libcras::CrasStreamType;
+#[cfg(feature = "audio_pipewire")]
+pub use pipewire_audio::socket_path as pipewire_socket_path;
+#[cfg(feature = "audio_pipewire")]
+use pipewire_audio::PipeWireStreamSourceGenerator;
 use serde::Deserialize;
 use serde::Serialize;
 
@@ -54,8 +64,12 @@ pub(crate) struct SysAsyncStreamObjects {
 pub enum StreamSourceBackend {
     #[cfg(feature = "audio_aaudio")]
     AAUDIO,
+    #[cfg(feature = "audio_alsa")]
+    ALSA,
     #[cfg(feature = "audio_cras")]
     CRAS,
+    #[cfg(feature = "audio_pipewire")]
+    PIPEWIRE,
 }
 
 // Implemented to make backend serialization possible, since we deserialize from str.
@@ -66,6 +80,10 @@ impl From<StreamSourceBackend> for String {
             StreamSourceBackend::AAUDIO => "aaudio".to_owned(),
             #[cfg(feature = "audio_cras")]
             StreamSourceBackend::CRAS => "cras".to_owned(),
+            #[cfg(feature = "audio_alsa")]
+            StreamSourceBackend::ALSA => "alsa".to_owned(),
+            #[cfg(feature = "audio_pipewire")]
+            StreamSourceBackend::PIPEWIRE => "pipewire".to_owned(),
         }
     }
 }
@@ -79,6 +97,10 @@ impl TryFrom<&str> for StreamSourceBackend {
             "aaudio" => Ok(StreamSourceBackend::AAUDIO),
             #[cfg(feature = "audio_cras")]
             "cras" => Ok(StreamSourceBackend::CRAS),
+            #[cfg(feature = "audio_alsa")]
+            "alsa" => Ok(StreamSourceBackend::ALSA),
+            #[cfg(feature = "audio_pipewire")]
+            "pipewire" => Ok(StreamSourceBackend::PIPEWIRE),
             _ => Err(ParametersError::InvalidBackend),
         }
     }
@@ -121,6 +143,46 @@ pub(crate) fn create_cras_stream_source_generators(
     generators
 }
 
+#[cfg(feature = "audio_alsa")]
+pub(crate) fn create_alsa_stream_source_generators(
+    params: &Parameters,
+    snd_data: &SndData,
+) -> Vec<Box<dyn StreamSourceGenerator>> {
+    let mut generators: Vec<Box<dyn StreamSourceGenerator>> =
+        Vec::with_capacity(snd_data.pcm_info_len());
+    for pcm_info in snd_data.pcm_info_iter() {
+        let device_params = params.get_device_params(pcm_info).unwrap_or_else(|err| {
+            error!("Create alsa stream source generator error: {}", err);
+            Default::default()
+        });
+        generators.push(Box::new(AlsaStreamSourceGenerator::new(
+            device_params
+                .device
+                .unwrap_or_else(|| alsa_audio::DEFAULT_DEVICE.to_owned()),
+        )));
+    }
+    generators
+}
+
+#[cfg(feature = "audio_pipewire")]
+pub(crate) fn create_pipewire_stream_source_generators(
+    params: &Parameters,
+    snd_data: &SndData,
+) -> Vec<Box<dyn StreamSourceGenerator>> {
+    let mut generators: Vec<Box<dyn StreamSourceGenerator>> =
+        Vec::with_capacity(snd_data.pcm_info_len());
+    for pcm_info in snd_data.pcm_info_iter() {
+        let device_params = params.get_device_params(pcm_info).unwrap_or_else(|err| {
+            error!("Create pipewire stream source generator error: {}", err);
+            Default::default()
+        });
+        generators.push(Box::new(PipeWireStreamSourceGenerator::new(
+            device_params.device,
+        )));
+    }
+    generators
+}
+
 #[allow(unused_variables)]
 pub(crate) fn create_stream_source_generators(
     backend: StreamSourceBackend,
@@ -132,6 +194,10 @@ pub(crate) fn create_stream_source_generators(
         StreamSourceBackend::AAUDIO => create_aaudio_stream_source_generators(snd_data),
         #[cfg(feature = "audio_cras")]
         StreamSourceBackend::CRAS => create_cras_stream_source_generators(params, snd_data),
+        #[cfg(feature = "audio_alsa")]
+        StreamSourceBackend::ALSA => create_alsa_stream_source_generators(params, snd_data),
+        #[cfg(feature = "audio_pipewire")]
+        StreamSourceBackend::PIPEWIRE => create_pipewire_stream_source_generators(params, snd_data),
     }
 }
 
@@ -13,6 +13,11 @@ cfg_if::cfg_if! {
 }
 
 pub(crate) use platform::create_stream_source_generators;
+#[cfg(all(
+    any(target_os = "android", target_os = "linux"),
+    feature = "audio_pipewire"
+))]
+pub use platform::pipewire_socket_path;
 pub(crate) use platform::set_audio_thread_priority;
 pub use platform::StreamSourceBackend;
 pub(crate) use platform::SysAsyncStreamObjects;
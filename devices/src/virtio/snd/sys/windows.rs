@@ -48,7 +48,6 @@ use crate::virtio::snd::layout::virtio_snd_pcm_status;
 use crate::virtio::snd::parameters::Error as ParametersError;
 use crate::virtio::snd::parameters::Parameters;
 use crate::virtio::DescriptorChain;
-use crate::virtio::Reader;
 
 pub(crate) type SysAudioStreamSourceGenerator = Box<dyn WinStreamSourceGenerator>;
 pub(crate) type SysAudioStreamSource = Box<dyn WinAudioServer>;
@@ -219,7 +218,7 @@ impl PlaybackBufferWriter for WinBufferWriter {
     fn copy_to_buffer(
         &mut self,
         dst_buf: &mut AsyncPlaybackBuffer<'_>,
-        reader: &mut Reader,
+        reader: &mut dyn std::io::Read,
     ) -> Result<usize, Error> {
         dst_buf.copy_from(reader).map_err(Error::Io)
     }
@@ -281,6 +281,7 @@ pub fn new_sound<P: AsRef<Path>>(path: P, virtio_features: u64) -> Result<Sound>
             jacks,
             streams,
             chmaps,
+            controls: 0.into(),
         },
         virtio_features,
         worker_thread: None,
@@ -9,6 +9,7 @@ use std::io::Write;
 use std::ops::BitOrAssign;
 
 use anyhow::anyhow;
+use anyhow::bail;
 use anyhow::Context;
 use base::error;
 use base::Event;
@@ -45,6 +46,21 @@ struct Worker {
 
 pub trait TpmBackend: Send {
     fn execute_command<'a>(&'a mut self, command: &[u8]) -> &'a [u8];
+
+    /// Returns the descriptors that must stay open when the device is jailed.
+    fn keep_rds(&self) -> Vec<RawDescriptor> {
+        Vec::new()
+    }
+
+    /// Saves the TPM state (including NVRAM) so it can be included in a VM snapshot.
+    fn snapshot(&mut self) -> anyhow::Result<serde_json::Value> {
+        bail!("snapshot is not supported by this TPM backend");
+    }
+
+    /// Loads TPM state previously returned by `snapshot`.
+    fn restore(&mut self, _data: serde_json::Value) -> anyhow::Result<()> {
+        bail!("restore is not supported by this TPM backend");
+    }
 }
 
 impl Worker {
@@ -98,7 +114,7 @@ impl Worker {
         needs_interrupt
     }
 
-    fn run(mut self, kill_evt: Event) -> anyhow::Result<()> {
+    fn run(&mut self, kill_evt: Event) -> anyhow::Result<()> {
         #[derive(EventToken, Debug)]
         enum Token {
             // A request is ready on the queue.
@@ -146,7 +162,7 @@ impl Worker {
 /// Virtio vTPM device.
 pub struct Tpm {
     backend: Option<Box<dyn TpmBackend>>,
-    worker_thread: Option<WorkerThread<()>>,
+    worker_thread: Option<WorkerThread<Worker>>,
     features: u64,
 }
 
@@ -162,7 +178,10 @@ impl Tpm {
 
 impl VirtioDevice for Tpm {
     fn keep_rds(&self) -> Vec<RawDescriptor> {
-        Vec::new()
+        self.backend
+            .as_ref()
+            .map(|backend| backend.keep_rds())
+            .unwrap_or_default()
     }
 
     fn device_type(&self) -> DeviceType {
@@ -190,20 +209,62 @@ impl VirtioDevice for Tpm {
 
         let backend = self.backend.take().context("no backend in vtpm")?;
 
-        let worker = Worker {
+        let mut worker = Worker {
             interrupt,
             queue,
             backend,
         };
 
-        self.worker_thread = Some(WorkerThread::start("v_tpm", |kill_evt| {
+        self.worker_thread = Some(WorkerThread::start("v_tpm", move |kill_evt| {
             if let Err(e) = worker.run(kill_evt) {
                 error!("virtio-tpm worker failed: {:#}", e);
             }
+            worker
         }));
 
         Ok(())
     }
+
+    fn reset(&mut self) -> anyhow::Result<()> {
+        if let Some(worker_thread) = self.worker_thread.take() {
+            let worker = worker_thread.stop();
+            self.backend = Some(worker.backend);
+        }
+        Ok(())
+    }
+
+    fn virtio_sleep(&mut self) -> anyhow::Result<Option<BTreeMap<usize, Queue>>> {
+        if let Some(worker_thread) = self.worker_thread.take() {
+            let worker = worker_thread.stop();
+            self.backend = Some(worker.backend);
+            return Ok(Some(BTreeMap::from([(0, worker.queue)])));
+        }
+        Ok(None)
+    }
+
+    fn virtio_wake(
+        &mut self,
+        queues_state: Option<(GuestMemory, Interrupt, BTreeMap<usize, Queue>)>,
+    ) -> anyhow::Result<()> {
+        if let Some((mem, interrupt, queues)) = queues_state {
+            self.activate(mem, interrupt, queues)?;
+        }
+        Ok(())
+    }
+
+    fn virtio_snapshot(&mut self) -> anyhow::Result<serde_json::Value> {
+        self.backend
+            .as_mut()
+            .context("no backend in vtpm")?
+            .snapshot()
+    }
+
+    fn virtio_restore(&mut self, data: serde_json::Value) -> anyhow::Result<()> {
+        self.backend
+            .as_mut()
+            .context("no backend in vtpm")?
+            .restore(data)
+    }
 }
 
 #[derive(PartialEq, Eq)]
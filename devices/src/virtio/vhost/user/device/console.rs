@@ -9,13 +9,20 @@ use anyhow::bail;
 use anyhow::Context;
 use argh::FromArgs;
 use base::error;
+use base::info;
 use base::Event;
 use base::RawDescriptor;
 use base::Terminal;
+use base::Tube;
+use base::UnixSeqpacketListener;
+use base::UnlinkUnixSeqpacketListener;
 use cros_async::Executor;
 use data_model::Le32;
 use hypervisor::ProtectionType;
+use serde::Deserialize;
+use serde_keyvalue::FromKeyValues;
 use sync::Mutex;
+use vm_control::ConsoleControlCommand;
 use vm_memory::GuestMemory;
 use vmm_vhost::message::VhostUserProtocolFeatures;
 use vmm_vhost::VHOST_USER_F_PROTOCOL_FEATURES;
@@ -24,6 +31,8 @@ use zerocopy::AsBytes;
 use crate::virtio;
 use crate::virtio::console::asynchronous::ConsoleDevice;
 use crate::virtio::console::asynchronous::ConsolePort;
+use crate::virtio::console::multiport::ConsolePortInfo;
+use crate::virtio::console::socket_port::SocketPorts;
 use crate::virtio::console::virtio_console_config;
 use crate::virtio::copy_config;
 use crate::virtio::vhost::user::device::handler::DeviceRequestHandler;
@@ -170,10 +179,33 @@ pub struct Options {
     #[argh(option, arg_name = "type=TYPE,[path=PATH,input=PATH,console]")]
     /// multiport parameters
     port: Vec<SerialParameters>,
+    #[argh(option, arg_name = "name=NAME,path=PATH[,console]")]
+    /// multiport port named NAME bound to a Unix socket listening at PATH, open in the guest
+    /// while a host client is connected
+    socket_port: Vec<SocketPortParameters>,
+    #[argh(option, arg_name = "NUM")]
+    /// maximum number of ports of a multiport console, leaving room for socket ports added at
+    /// runtime (default: number of `--port` and `--socket-port`)
+    max_ports: Option<usize>,
+    #[argh(option, arg_name = "PATH")]
+    /// path of a control socket for adding and removing socket ports at runtime with
+    /// `crosvm console`
+    control_socket: Option<PathBuf>,
+}
+
+/// Parameters of a multiport port bound to a Unix socket.
+#[derive(Clone, Debug, Deserialize, FromKeyValues)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct SocketPortParameters {
+    name: String,
+    path: PathBuf,
+    #[serde(default)]
+    console: bool,
 }
 
 fn create_vu_multi_port_device(
     params: &[SerialParameters],
+    client_ports: usize,
     keep_rds: &mut Vec<RawDescriptor>,
 ) -> anyhow::Result<VhostUserConsoleDevice> {
     let mut ports = params
@@ -194,7 +226,8 @@ fn create_vu_multi_port_device(
         .collect::<anyhow::Result<Vec<_>>>()?;
 
     let port0 = ports.remove(0);
-    let device = ConsoleDevice::new_multi_port(ProtectionType::Unprotected, port0, ports);
+    let device =
+        ConsoleDevice::new_multi_port(ProtectionType::Unprotected, port0, ports, client_ports);
 
     Ok(VhostUserConsoleDevice {
         console: device,
@@ -202,6 +235,35 @@ fn create_vu_multi_port_device(
     })
 }
 
+fn run_control_server(control_socket: UnlinkUnixSeqpacketListener, mut ports: SocketPorts) {
+    info!("Start console control server");
+    loop {
+        let socket = match control_socket.accept() {
+            Ok(socket) => socket,
+            Err(e) => {
+                error!("failed to establish connection: {}", e);
+                continue;
+            }
+        };
+        let tube = match Tube::new_from_unix_seqpacket(socket) {
+            Ok(tube) => tube,
+            Err(e) => {
+                error!("failed to open tube: {:#}", e);
+                continue;
+            }
+        };
+        match tube.recv::<ConsoleControlCommand>() {
+            Ok(command) => {
+                let result = ports.handle_command(command);
+                if let Err(e) = tube.send(&result) {
+                    error!("failed to send console control result: {}", e);
+                }
+            }
+            Err(e) => error!("failed to receive console control command: {}", e),
+        }
+    }
+}
+
 /// Starts a multiport enabled vhost-user console device.
 /// Returns an error if the given `args` is invalid or the device fails to run.
 fn run_multi_port_device(opts: Options) -> anyhow::Result<()> {
@@ -209,9 +271,51 @@ fn run_multi_port_device(opts: Options) -> anyhow::Result<()> {
         bail!("console: must have at least one `--port`");
     }
 
+    let min_ports = opts.port.len() + opts.socket_port.len();
+    let max_ports = opts.max_ports.unwrap_or(min_ports);
+    if max_ports < min_ports {
+        bail!(
+            "console: --max-ports must be at least {}, the number of configured ports",
+            min_ports
+        );
+    }
+
     // We won't jail the device and can simply ignore `keep_rds`.
-    let device = Box::new(create_vu_multi_port_device(&opts.port, &mut Vec::new())?);
+    let device = Box::new(create_vu_multi_port_device(
+        &opts.port,
+        max_ports - opts.port.len(),
+        &mut Vec::new(),
+    )?);
+    let table = device
+        .console
+        .port_table()
+        .context("console device is not multiport")?
+        .clone();
+    let mut ports = SocketPorts::new(table, device.console.port_backends().to_vec());
+    for params in opts.socket_port {
+        let info = ConsolePortInfo {
+            console: params.console,
+            name: params.name,
+        };
+        ports
+            .add(info, params.path)
+            .context("failed to create socket port")?;
+    }
+
     let ex = Executor::new().context("Failed to create executor")?;
+    // The socket ports are handed over to the control server, or kept here for as long as the
+    // device runs.
+    let _ports = match opts.control_socket {
+        Some(path) => {
+            let control_socket = UnlinkUnixSeqpacketListener(
+                UnixSeqpacketListener::bind(path).context("failed to create control socket")?,
+            );
+            ex.spawn_blocking(move || run_control_server(control_socket, ports))
+                .detach();
+            None
+        }
+        None => Some(ports),
+    };
 
     let listener = VhostUserListener::new_socket(&opts.socket, None)?;
 
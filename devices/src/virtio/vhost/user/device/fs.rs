@@ -5,6 +5,7 @@
 mod sys;
 
 use std::cell::RefCell;
+use std::fs::File;
 use std::path::PathBuf;
 use std::rc::Rc;
 use std::sync::Arc;
@@ -14,13 +15,20 @@ use anyhow::Context;
 use argh::FromArgs;
 use base::error;
 use base::warn;
+use base::AsRawDescriptor;
 use base::AsRawDescriptors;
 use base::RawDescriptor;
+use base::SafeDescriptor;
 use base::Tube;
 use cros_async::EventAsync;
 use cros_async::Executor;
+use cros_async::IoSource;
 use data_model::Le32;
 use fuse::Server;
+use futures::future;
+use futures::pin_mut;
+use futures::select_biased;
+use futures::FutureExt;
 use hypervisor::ProtectionType;
 use sync::Mutex;
 pub use sys::start_device as run_fs_device;
@@ -36,6 +44,7 @@ use crate::virtio::device_constants::fs::FS_MAX_TAG_LEN;
 use crate::virtio::fs::passthrough::PassthroughFs;
 use crate::virtio::fs::process_fs_queue;
 use crate::virtio::fs::Config;
+use crate::virtio::fs::FsUring;
 use crate::virtio::vhost::user::device::handler::Error as DeviceError;
 use crate::virtio::vhost::user::device::handler::VhostUserDevice;
 use crate::virtio::vhost::user::device::handler::WorkerState;
@@ -44,22 +53,110 @@ use crate::virtio::Queue;
 
 const MAX_QUEUE_NUM: usize = 2; /* worker queue and high priority queue */
 
+/// The io_uring of a queue handler. The requests with transfers in flight are completed when the
+/// handler is stopped, before the queue is handed back.
+struct QueueUring {
+    uring: FsUring,
+    queue: Rc<RefCell<virtio::Queue>>,
+    doorbell: Interrupt,
+    // Readable when transfers have completed.
+    completions: IoSource<File>,
+}
+
+impl QueueUring {
+    fn new(
+        ex: &Executor,
+        queue: Rc<RefCell<virtio::Queue>>,
+        doorbell: Interrupt,
+    ) -> anyhow::Result<Option<Self>> {
+        let Some(uring) = FsUring::new() else {
+            return Ok(None);
+        };
+        let completions = SafeDescriptor::try_from(&uring as &dyn AsRawDescriptor)
+            .context("failed to clone io_uring descriptor")?;
+        let completions = ex
+            .async_from(File::from(completions))
+            .context("failed to create async io_uring source")?;
+        Ok(Some(QueueUring {
+            uring,
+            queue,
+            doorbell,
+            completions,
+        }))
+    }
+}
+
+impl Drop for QueueUring {
+    fn drop(&mut self) {
+        if let Err(e) = self
+            .uring
+            .drain(&mut self.queue.borrow_mut(), &self.doorbell)
+        {
+            error!("failed to complete io_uring transfers: {}", e);
+        }
+    }
+}
+
 async fn handle_fs_queue(
     queue: Rc<RefCell<virtio::Queue>>,
     doorbell: Interrupt,
     kick_evt: EventAsync,
     server: Arc<fuse::Server<PassthroughFs>>,
     tube: Arc<Mutex<Tube>>,
+    mut uring: Option<QueueUring>,
 ) {
     // Slot is always going to be 0 because we do not support DAX
     let slot: u32 = 0;
 
+    let kick_evt_future = kick_evt.next_val().fuse();
+    pin_mut!(kick_evt_future);
     loop {
-        if let Err(e) = kick_evt.next_val().await {
-            error!("Failed to read kick event for fs queue: {}", e);
-            break;
-        }
-        if let Err(e) = process_fs_queue(&doorbell, &mut queue.borrow_mut(), &server, &tube, slot) {
+        let kicked = {
+            let completions = async {
+                match &uring {
+                    Some(uring) => uring.completions.wait_readable().await,
+                    None => future::pending().await,
+                }
+            }
+            .fuse();
+            pin_mut!(completions);
+            select_biased! {
+                kick = kick_evt_future => {
+                    kick_evt_future.set(kick_evt.next_val().fuse());
+                    if let Err(e) = kick {
+                        error!("Failed to read kick event for fs queue: {}", e);
+                        break;
+                    }
+                    true
+                }
+                res = completions => {
+                    if let Err(e) = res {
+                        error!("Failed to wait for io_uring completions: {}", e);
+                        break;
+                    }
+                    false
+                }
+            }
+        };
+        let uring = uring.as_mut().map(|uring| &mut uring.uring);
+        let uring = match uring {
+            Some(uring) if !kicked => {
+                if let Err(e) = uring.complete(&mut queue.borrow_mut(), &doorbell, false) {
+                    error!("Completing FS requests failed: {}", e);
+                    break;
+                }
+                Some(uring)
+            }
+            uring => uring,
+        };
+        if let Err(e) = process_fs_queue(
+            &doorbell,
+            &mut queue.borrow_mut(),
+            &server,
+            &tube,
+            slot,
+            uring,
+        ) {
             error!("Process FS queue failed: {}", e);
             break;
         }
@@ -160,12 +257,18 @@ impl VhostUserDevice for FsBackend {
         let (_, fs_device_tube) = Tube::pair()?;
 
         let queue = Rc::new(RefCell::new(queue));
+        let uring = if self.server.fs().cfg().io_uring {
+            QueueUring::new(&self.ex, queue.clone(), doorbell.clone())?
+        } else {
+            None
+        };
         let queue_task = self.ex.spawn_local(handle_fs_queue(
             queue.clone(),
             doorbell,
             kick_evt,
             self.server.clone(),
             Arc::new(Mutex::new(fs_device_tube)),
+            uring,
         ));
 
         self.workers[idx] = Some(WorkerState { queue_task, queue });
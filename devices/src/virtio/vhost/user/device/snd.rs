@@ -33,11 +33,12 @@ use crate::virtio::device_constants::snd::virtio_snd_config;
 use crate::virtio::snd::common_backend::async_funcs::handle_ctrl_queue;
 use crate::virtio::snd::common_backend::async_funcs::handle_pcm_queue;
 use crate::virtio::snd::common_backend::async_funcs::send_pcm_response_worker;
+use crate::virtio::snd::common_backend::controls::CardState;
+use crate::virtio::snd::common_backend::controls::StreamControls;
 use crate::virtio::snd::common_backend::create_stream_info_builders;
 use crate::virtio::snd::common_backend::hardcoded_snd_data;
 use crate::virtio::snd::common_backend::hardcoded_virtio_snd_config;
 use crate::virtio::snd::common_backend::stream_info::StreamInfo;
-use crate::virtio::snd::common_backend::stream_info::StreamInfoBuilder;
 use crate::virtio::snd::common_backend::stream_info::StreamInfoSnapshot;
 use crate::virtio::snd::common_backend::Error;
 use crate::virtio::snd::common_backend::PcmResponse;
@@ -68,6 +69,7 @@ struct SndBackend {
     // tx and rx
     response_workers: [Option<WorkerState<Rc<AsyncRwLock<Queue>>, Result<(), Error>>>; 2],
     snd_data: Rc<SndData>,
+    card_state: Rc<AsyncRwLock<CardState>>,
     streams: Rc<AsyncRwLock<Vec<AsyncRwLock<StreamInfo>>>>,
     tx_send: mpsc::UnboundedSender<PcmResponse>,
     rx_send: mpsc::UnboundedSender<PcmResponse>,
@@ -82,6 +84,8 @@ struct SndBackendSnapshot {
     avail_features: u64,
     stream_infos: Option<Vec<StreamInfoSnapshot>>,
     snd_data: SndData,
+    #[serde(default)]
+    card_state: Option<CardState>,
 }
 
 impl SndBackend {
@@ -92,10 +96,12 @@ impl SndBackend {
         card_index: usize,
     ) -> anyhow::Result<Self> {
         let cfg = hardcoded_virtio_snd_config(&params);
+        let snd_data = hardcoded_snd_data(&params);
         let avail_features = virtio::base_features(ProtectionType::Unprotected)
-            | 1 << VHOST_USER_F_PROTOCOL_FEATURES;
+            | 1 << VHOST_USER_F_PROTOCOL_FEATURES
+            | snd_data.features();
+        let card_state = CardState::new(&snd_data);
 
-        let snd_data = hardcoded_snd_data(&params);
         let mut keep_rds = Vec::new();
         let builders = create_stream_info_builders(&params, &snd_data, &mut keep_rds, card_index)?;
 
@@ -114,9 +120,14 @@ impl SndBackend {
         let streams = streams
             .map(|stream_builder| stream_builder.audio_client_guid(audio_client_guid.clone()));
 
+        let card_state = Rc::new(AsyncRwLock::new(card_state));
         let streams = streams
-            .map(StreamInfoBuilder::build)
-            .map(AsyncRwLock::new)
+            .enumerate()
+            .map(|(stream_id, builder)| {
+                let mut stream = builder.build();
+                stream.controls = StreamControls::new(&snd_data, &card_state, stream_id);
+                AsyncRwLock::new(stream)
+            })
             .collect();
         let streams = Rc::new(AsyncRwLock::new(streams));
 
@@ -130,6 +141,7 @@ impl SndBackend {
             workers: Default::default(),
             response_workers: Default::default(),
             snd_data: Rc::new(snd_data),
+            card_state,
             streams,
             tx_send,
             rx_send,
@@ -200,6 +212,7 @@ impl VhostUserDevice for SndBackend {
                 // ctrl queue
                 let streams = self.streams.clone();
                 let snd_data = self.snd_data.clone();
+                let card_state = self.card_state.clone();
                 let tx_send = self.tx_send.clone();
                 let rx_send = self.rx_send.clone();
                 let ctrl_queue = queue.clone();
@@ -210,6 +223,7 @@ impl VhostUserDevice for SndBackend {
                         &ex_clone,
                         &streams,
                         &snd_data,
+                        &card_state,
                         ctrl_queue,
                         &mut kick_evt,
                         doorbell,
@@ -331,6 +345,13 @@ impl VhostUserDevice for SndBackend {
             avail_features: self.avail_features,
             stream_infos: stream_info_snaps,
             snd_data: snd_data_ref.clone(),
+            card_state: Some(
+                self.card_state
+                    .lock()
+                    .now_or_never()
+                    .expect("failed to lock card state during snapshot")
+                    .clone(),
+            ),
         })
         .context(format!(
             "[Card {}] Failed to serialize SndBackendSnapshot",
@@ -339,7 +360,7 @@ impl VhostUserDevice for SndBackend {
     }
 
     fn restore(&mut self, data: Vec<u8>) -> anyhow::Result<()> {
-        let deser: SndBackendSnapshot =
+        let mut deser: SndBackendSnapshot =
             serde_json::from_slice(data.as_slice()).context(format!(
                 "[Card {}] Failed to deserialize SndBackendSnapshot",
                 self.card_index
@@ -359,6 +380,15 @@ impl VhostUserDevice for SndBackend {
             deser.snd_data,
             snd_data,
         );
+        // Snapshots taken before jacks and controls existed don't have a card state.
+        // The streams share the card state, so it is updated in place.
+        if let Some(card_state) = deser.card_state.take() {
+            *self
+                .card_state
+                .lock()
+                .now_or_never()
+                .expect("failed to lock card state during restore") = card_state;
+        }
 
         let ex_clone = self.ex.clone();
         let streams_rc = self.streams.clone();
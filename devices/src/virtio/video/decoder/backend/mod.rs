@@ -17,6 +17,8 @@ use crate::virtio::video::resource::GuestResourceHandle;
 
 #[cfg(feature = "ffmpeg")]
 pub mod ffmpeg;
+#[cfg(feature = "swdec")]
+pub mod swdec;
 
 #[cfg(feature = "vaapi")]
 pub mod vaapi;
@@ -0,0 +1,868 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! A software decoder backend that depends neither on video acceleration hardware nor on FFmpeg.
+//! H.264 streams are decoded by the system OpenH264, VP8 and VP9 streams by the system libvpx, and
+//! AV1 streams by the system dav1d.
+//!
+//! Decoding happens synchronously when input is submitted. Decoded frames are kept in NV12 layout
+//! until an output buffer is available to copy them into, so the guest does not need to provide
+//! as many output buffers as the stream has reference frames.
+
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::collections::BinaryHeap;
+use std::collections::VecDeque;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context;
+use base::error;
+use base::info;
+use base::MappedRegion;
+use dav1d::Dav1dDecoder;
+use dav1d::Dav1dFrame;
+use libvpx::VpxCodec;
+use libvpx::VpxDecoder;
+use libvpx::VpxImage;
+use openh264::H264Decoder;
+use openh264::H264Frame;
+
+use crate::virtio::video::decoder::backend::*;
+use crate::virtio::video::format::FormatDesc;
+use crate::virtio::video::format::FormatRange;
+use crate::virtio::video::format::FrameFormat;
+use crate::virtio::video::format::FramePlane;
+use crate::virtio::video::format::Level;
+use crate::virtio::video::format::Profile;
+use crate::virtio::video::resource::BufferHandle;
+use crate::virtio::video::resource::GuestResource;
+use crate::virtio::video::resource::GuestResourceHandle;
+use crate::virtio::video::utils::EventQueue;
+use crate::virtio::video::utils::OutputQueue;
+
+/// Number of output buffers we ask the guest for. Decoded frames are copied, so this only needs to
+/// be large enough to keep the guest pipeline busy.
+const MIN_NUM_OUTPUT_BUFFERS: u32 = 4;
+
+/// A decoded frame in NV12 layout, waiting to be copied into an output buffer.
+struct Picture {
+    width: usize,
+    height: usize,
+    timestamp: u64,
+    /// Luma plane, `width` bytes per line.
+    y: Vec<u8>,
+    /// Interleaved chroma plane, `uv_line_size()` bytes per line.
+    uv: Vec<u8>,
+}
+
+/// Returns the `lines` lines of `line_size` bytes of a plane of `stride` bytes per line.
+fn plane_lines(
+    data: &[u8],
+    stride: usize,
+    line_size: usize,
+    lines: usize,
+) -> anyhow::Result<impl Iterator<Item = &[u8]>> {
+    if stride < line_size || data.len() < stride * lines.saturating_sub(1) + line_size {
+        bail!(
+            "plane of {} bytes is too small for {} lines of {} bytes with stride {}",
+            data.len(),
+            lines,
+            line_size,
+            stride
+        );
+    }
+
+    Ok((0..lines).map(move |line| &data[line * stride..line * stride + line_size]))
+}
+
+impl Picture {
+    /// Build a picture from the planes of an 8-bit YUV 4:2:0 frame, given as (stride, data) pairs.
+    fn from_i420(
+        width: usize,
+        height: usize,
+        timestamp: u64,
+        y: (usize, &[u8]),
+        u: (usize, &[u8]),
+        v: (usize, &[u8]),
+    ) -> anyhow::Result<Self> {
+        if width == 0 || height == 0 {
+            bail!("invalid frame size {}x{}", width, height);
+        }
+
+        let (chroma_width, chroma_height) = ((width + 1) / 2, (height + 1) / 2);
+
+        let mut y_plane = Vec::with_capacity(width * height);
+        for line in plane_lines(y.1, y.0, width, height)? {
+            y_plane.extend_from_slice(line);
+        }
+
+        let mut uv_plane = Vec::with_capacity(chroma_width * 2 * chroma_height);
+        let u_lines = plane_lines(u.1, u.0, chroma_width, chroma_height)?;
+        let v_lines = plane_lines(v.1, v.0, chroma_width, chroma_height)?;
+        for (u_line, v_line) in u_lines.zip(v_lines) {
+            for (u, v) in u_line.iter().zip(v_line) {
+                uv_plane.push(*u);
+                uv_plane.push(*v);
+            }
+        }
+
+        Ok(Self {
+            width,
+            height,
+            timestamp,
+            y: y_plane,
+            uv: uv_plane,
+        })
+    }
+
+    fn uv_line_size(&self) -> usize {
+        (self.width + 1) / 2 * 2
+    }
+
+    fn uv_lines(&self) -> usize {
+        (self.height + 1) / 2
+    }
+
+    /// Copy this picture into the NV12 output buffer `resource`.
+    fn write_into(&self, resource: &GuestResource) -> anyhow::Result<()> {
+        let (y_plane, uv_plane) = match resource.planes.as_slice() {
+            [y_plane, uv_plane, ..] => (y_plane, uv_plane),
+            _ => bail!("output buffer has {} planes, need 2", resource.planes.len()),
+        };
+
+        // Returns the end of the area of `plane` that we will write to.
+        let plane_end = |plane: &FramePlane, line_size: usize, lines: usize| {
+            let end = plane.stride * lines.saturating_sub(1) + line_size;
+            if plane.stride < line_size || end > plane.size {
+                Err(anyhow!(
+                    "plane of {} bytes with stride {} cannot hold {} lines of {} bytes",
+                    plane.size,
+                    plane.stride,
+                    lines,
+                    line_size
+                ))
+            } else {
+                Ok(plane.offset + end)
+            }
+        };
+        let mapping_size = std::cmp::max(
+            plane_end(y_plane, self.width, self.height)?,
+            plane_end(uv_plane, self.uv_line_size(), self.uv_lines())?,
+        );
+
+        let mapping = resource
+            .handle
+            .get_mapping(0, mapping_size)
+            .context("while mapping output buffer")?;
+        // SAFETY:
+        // Safe because the mapping is linear, we own it, and it is dropped at the end of this
+        // function.
+        let buffer = unsafe { std::slice::from_raw_parts_mut(mapping.as_ptr(), mapping.size()) };
+
+        for (plane, data, line_size) in [
+            (y_plane, &self.y, self.width),
+            (uv_plane, &self.uv, self.uv_line_size()),
+        ] {
+            for (line, src) in data.chunks(line_size).enumerate() {
+                let start = plane.offset + line * plane.stride;
+                buffer[start..start + src.len()].copy_from_slice(src);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Interface over the codec libraries we are using.
+trait SoftwareCodec {
+    /// Decode the compressed data in `data`, returning the frames that became available as a
+    /// result.
+    fn decode(&mut self, data: &[u8], timestamp: u64) -> anyhow::Result<Vec<Picture>>;
+
+    /// Signal the end of the stream and return all the frames the codec was still holding.
+    fn flush(&mut self) -> anyhow::Result<Vec<Picture>>;
+}
+
+/// H.264 decoder backed by OpenH264.
+struct H264Codec {
+    decoder: H264Decoder,
+    /// Timestamps of the input buffers for which no frame has been output yet. OpenH264 does not
+    /// carry timestamps, so frames, which are output in presentation order, are given the lowest
+    /// pending one.
+    pending_timestamps: BinaryHeap<Reverse<u64>>,
+}
+
+impl H264Codec {
+    fn new() -> anyhow::Result<Self> {
+        Ok(Self {
+            decoder: H264Decoder::new().context("while creating H.264 decoder")?,
+            pending_timestamps: Default::default(),
+        })
+    }
+}
+
+/// Build a `Picture` from a frame decoded by OpenH264.
+fn picture_from_h264(frame: &H264Frame, timestamp: u64) -> anyhow::Result<Picture> {
+    let plane = |index| {
+        frame
+            .plane(index)
+            .ok_or_else(|| anyhow!("invalid decoded frame"))
+    };
+    Picture::from_i420(
+        frame.width(),
+        frame.height(),
+        timestamp,
+        plane(0)?,
+        plane(1)?,
+        plane(2)?,
+    )
+}
+
+/// Returns whether `nal`, which may start with a start code, contains a coded slice.
+fn is_slice_nal(nal: &[u8]) -> bool {
+    let header = match nal.iter().position(|&b| b != 0) {
+        Some(pos) if pos >= 2 && nal[pos] == 0x1 => nal.get(pos + 1),
+        Some(pos) => nal.get(pos),
+        None => None,
+    };
+
+    matches!(header.map(|h| h & 0x1f), Some(0x1 | 0x5))
+}
+
+impl SoftwareCodec for H264Codec {
+    fn decode(&mut self, data: &[u8], timestamp: u64) -> anyhow::Result<Vec<Picture>> {
+        // Only buffers containing frame data will produce a frame carrying their timestamp.
+        if openh264::nal_units(data).any(is_slice_nal) {
+            self.pending_timestamps.push(Reverse(timestamp));
+        }
+
+        let mut pictures = Vec::new();
+        for nal in openh264::nal_units(data) {
+            if let Some(frame) = self
+                .decoder
+                .decode(nal)
+                .context("while decoding H.264 NAL")?
+            {
+                let Reverse(timestamp) = self.pending_timestamps.pop().unwrap_or_default();
+                pictures.push(picture_from_h264(&frame, timestamp)?);
+            }
+        }
+
+        Ok(pictures)
+    }
+
+    fn flush(&mut self) -> anyhow::Result<Vec<Picture>> {
+        let mut pictures = Vec::new();
+        while let Some(frame) = self
+            .decoder
+            .flush()
+            .context("while flushing H.264 decoder")?
+        {
+            let Reverse(timestamp) = self.pending_timestamps.pop().unwrap_or_default();
+            pictures.push(picture_from_h264(&frame, timestamp)?);
+        }
+        self.pending_timestamps.clear();
+
+        Ok(pictures)
+    }
+}
+
+/// VP8 and VP9 decoder backed by libvpx.
+struct VpxCodecWrapper(VpxDecoder);
+
+impl VpxCodecWrapper {
+    /// Retrieve all the frames made available by the last decoding operation.
+    fn take_pictures(&mut self) -> anyhow::Result<Vec<Picture>> {
+        self.0
+            .frames()
+            .map(|image| picture_from_vpx(&image))
+            .collect()
+    }
+}
+
+/// Build a `Picture` from a frame decoded by libvpx.
+fn picture_from_vpx(image: &VpxImage) -> anyhow::Result<Picture> {
+    let plane = |index| {
+        image
+            .plane(index)
+            .ok_or_else(|| anyhow!("unsupported decoded frame format"))
+    };
+    Picture::from_i420(
+        image.width(),
+        image.height(),
+        image.user_priv(),
+        plane(0)?,
+        plane(1)?,
+        plane(2)?,
+    )
+}
+
+impl SoftwareCodec for VpxCodecWrapper {
+    fn decode(&mut self, data: &[u8], timestamp: u64) -> anyhow::Result<Vec<Picture>> {
+        self.0
+            .decode(data, timestamp)
+            .context("while decoding VPx frame")?;
+        self.take_pictures()
+    }
+
+    fn flush(&mut self) -> anyhow::Result<Vec<Picture>> {
+        self.0.flush().context("while flushing VPx decoder")?;
+        self.take_pictures()
+    }
+}
+
+/// AV1 decoder backed by dav1d.
+struct Av1Codec(Dav1dDecoder);
+
+/// Build a `Picture` from a frame decoded by dav1d.
+fn picture_from_av1(frame: &Dav1dFrame) -> anyhow::Result<Picture> {
+    let plane = |index| {
+        frame
+            .plane(index)
+            .ok_or_else(|| anyhow!("unsupported decoded frame format"))
+    };
+    Picture::from_i420(
+        frame.width(),
+        frame.height(),
+        frame.timestamp(),
+        plane(0)?,
+        plane(1)?,
+        plane(2)?,
+    )
+}
+
+impl SoftwareCodec for Av1Codec {
+    fn decode(&mut self, data: &[u8], timestamp: u64) -> anyhow::Result<Vec<Picture>> {
+        self.0
+            .decode(data, timestamp)
+            .context("while decoding AV1 data")?
+            .iter()
+            .map(picture_from_av1)
+            .collect()
+    }
+
+    fn flush(&mut self) -> anyhow::Result<Vec<Picture>> {
+        self.0
+            .drain()
+            .context("while draining AV1 decoder")?
+            .iter()
+            .map(picture_from_av1)
+            .collect()
+    }
+}
+
+/// Create a new codec instance for `format`.
+fn new_codec(format: Format) -> VideoResult<Box<dyn SoftwareCodec>> {
+    let codec: Box<dyn SoftwareCodec> = match format {
+        Format::H264 => Box::new(H264Codec::new().map_err(VideoError::BackendFailure)?),
+        Format::VP8 | Format::VP9 => {
+            let codec = if format == Format::VP8 {
+                VpxCodec::Vp8
+            } else {
+                VpxCodec::Vp9
+            };
+            Box::new(VpxCodecWrapper(
+                VpxDecoder::new(codec)
+                    .context("while creating VPx decoder")
+                    .map_err(VideoError::BackendFailure)?,
+            ))
+        }
+        Format::AV1 => Box::new(Av1Codec(
+            Dav1dDecoder::new()
+                .context("while creating AV1 decoder")
+                .map_err(VideoError::BackendFailure)?,
+        )),
+        _ => return Err(VideoError::InvalidFormat),
+    };
+
+    Ok(codec)
+}
+
+/// A crosvm decoder needs to go through a number if setup stages before being able to decode, and
+/// can require some setup to be redone when a dynamic resolution change occurs. This enum ensures
+/// that the data associated with a given state only exists when we actually are in this state.
+enum SessionState {
+    /// Waiting for the first frame to be decoded to learn the resolution of the stream.
+    AwaitingInitialResolution,
+    /// Waiting for the client to call `set_output_buffer_count`.
+    AwaitingBufferCount,
+    /// Decoding and producing frames.
+    Decoding { output_queue: OutputQueue },
+    /// Dynamic Resolution Change - we can still accept buffers in the old
+    /// format, but are waiting for new parameters before doing any decoding.
+    Drc,
+}
+
+/// A decoder session for the software decoder backend.
+pub struct SwDecoderSession {
+    /// Format of the stream being decoded.
+    format: Format,
+    /// Codec instance for this session.
+    codec: Box<dyn SoftwareCodec>,
+
+    /// Queue of events waiting to be read by the client.
+    event_queue: EventQueue<DecoderEvent>,
+
+    /// Current state of the session.
+    state: SessionState,
+    /// Visible size of the decoded frames (width, height).
+    current_visible_res: (usize, usize),
+
+    /// Decoded frames waiting to be copied into an output buffer and sent to the client.
+    pictures: VecDeque<Picture>,
+    /// If a flush is in progress, number of pictures that must be sent before it completes.
+    pending_flush: Option<usize>,
+}
+
+impl SwDecoderSession {
+    /// Start the resolution change process, buffers will now be of size `new_visible_res`.
+    fn change_resolution(&mut self, new_visible_res: (usize, usize)) -> base::Result<()> {
+        info!("resolution changed to {:?}", new_visible_res);
+
+        // Ask the client for new buffers.
+        self.event_queue
+            .queue_event(DecoderEvent::ProvidePictureBuffers {
+                min_num_buffers: MIN_NUM_OUTPUT_BUFFERS,
+                width: new_visible_res.0 as i32,
+                height: new_visible_res.1 as i32,
+                visible_rect: Rect {
+                    left: 0,
+                    top: 0,
+                    right: new_visible_res.0 as i32,
+                    bottom: new_visible_res.1 as i32,
+                },
+            })?;
+
+        self.current_visible_res = new_visible_res;
+
+        // Drop our output queue and wait for the new number of output buffers.
+        self.state = match self.state {
+            SessionState::AwaitingInitialResolution => SessionState::AwaitingBufferCount,
+            _ => SessionState::Drc,
+        };
+
+        Ok(())
+    }
+
+    /// Try to send the oldest decoded frame to the client by copying its content into an output
+    /// buffer.
+    ///
+    /// Returns `true` if a frame has been emitted, `false` if the conditions were not met for it to
+    /// happen yet.
+    fn try_send_frame(&mut self) -> anyhow::Result<bool> {
+        let picture_res = match self.pictures.front() {
+            // No decoded frame available at the moment.
+            None => return Ok(false),
+            Some(picture) => (picture.width, picture.height),
+        };
+
+        // Only change resolution once all the frames of the previous resolution have been sent.
+        if picture_res != self.current_visible_res
+            && matches!(
+                self.state,
+                SessionState::AwaitingInitialResolution | SessionState::Decoding { .. }
+            )
+        {
+            self.change_resolution(picture_res)?;
+            return Ok(false);
+        }
+
+        let output_queue = match &mut self.state {
+            SessionState::Decoding { output_queue } => output_queue,
+            // Frames can only be emitted if we are actively decoding.
+            _ => return Ok(false),
+        };
+
+        let (picture_buffer_id, target_buffer) = match output_queue.try_get_ready_buffer() {
+            // Keep the decoded frame since we don't have a destination buffer to process it.
+            None => return Ok(false),
+            Some(buffer) => buffer,
+        };
+
+        // Unwrapping is safe because we checked that there was a picture to send above.
+        let picture = self.pictures.pop_front().unwrap();
+        picture.write_into(target_buffer)?;
+        self.event_queue.queue_event(DecoderEvent::PictureReady {
+            picture_buffer_id: picture_buffer_id as i32,
+            timestamp: picture.timestamp,
+            visible_rect: Rect {
+                left: 0,
+                top: 0,
+                right: picture.width as i32,
+                bottom: picture.height as i32,
+            },
+        })?;
+
+        if let Some(remaining) = &mut self.pending_flush {
+            *remaining -= 1;
+        }
+
+        Ok(true)
+    }
+
+    /// Send as many decoded frames as possible, and signal the completion of a pending flush once
+    /// all the frames it was waiting for have been sent.
+    fn try_decode(&mut self) -> anyhow::Result<()> {
+        while self.try_send_frame()? {}
+
+        if self.pending_flush == Some(0) {
+            self.pending_flush = None;
+            self.event_queue
+                .queue_event(DecoderEvent::FlushCompleted(Ok(())))?;
+        }
+
+        Ok(())
+    }
+
+    /// Add the result of a decoding operation to our decoded frames, or signal the error.
+    fn queue_pictures(&mut self, pictures: anyhow::Result<Vec<Picture>>) {
+        match pictures {
+            Ok(pictures) => self.pictures.extend(pictures),
+            // This is a decoding error, so signal it using a `NotifyError` event to reflect the
+            // same asynchronous flow as a hardware decoder would.
+            Err(e) => {
+                if let Err(e) = self
+                    .event_queue
+                    .queue_event(DecoderEvent::NotifyError(VideoError::BackendFailure(e)))
+                {
+                    error!("failed to notify error: {}", e);
+                }
+            }
+        }
+    }
+}
+
+impl DecoderSession for SwDecoderSession {
+    fn set_output_parameters(&mut self, buffer_count: usize, format: Format) -> VideoResult<()> {
+        match self.state {
+            // It is valid to set an output format before the the initial DRC, but we won't do
+            // anything with it.
+            SessionState::AwaitingInitialResolution => Ok(()),
+            SessionState::AwaitingBufferCount | SessionState::Drc => {
+                if format != Format::NV12 {
+                    return Err(VideoError::InvalidFormat);
+                }
+
+                self.state = SessionState::Decoding {
+                    output_queue: OutputQueue::new(buffer_count),
+                };
+                Ok(())
+            }
+            _ => Err(VideoError::BackendFailure(anyhow!(
+                "invalid state while calling set_output_parameters"
+            ))),
+        }
+    }
+
+    fn decode(
+        &mut self,
+        resource_id: u32,
+        timestamp: u64,
+        resource: GuestResourceHandle,
+        offset: u32,
+        bytes_used: u32,
+    ) -> VideoResult<()> {
+        if bytes_used > 0 {
+            let mapping = resource
+                .get_mapping(offset as usize, bytes_used as usize)
+                .context("while mapping input buffer")
+                .map_err(VideoError::BackendFailure)?;
+            // SAFETY:
+            // Safe because the mapping is linear, we own it, and it outlives the slice.
+            let data = unsafe { std::slice::from_raw_parts(mapping.as_ptr(), mapping.size()) };
+            let pictures = self.codec.decode(data, timestamp);
+            self.queue_pictures(pictures);
+        }
+
+        // The input has been entirely consumed, so the buffer can be reused right away.
+        self.event_queue
+            .queue_event(DecoderEvent::NotifyEndOfBitstreamBuffer(resource_id))
+            .context("while signaling end of bitstream buffer")
+            .map_err(VideoError::BackendFailure)?;
+
+        self.try_decode()
+            .context("while decoding")
+            .map_err(VideoError::BackendFailure)
+    }
+
+    fn flush(&mut self) -> VideoResult<()> {
+        if self.pending_flush.is_some() {
+            return Err(VideoError::BackendFailure(anyhow!(
+                "flush is already in progress"
+            )));
+        }
+
+        let pictures = self.codec.flush();
+        self.queue_pictures(pictures);
+        // Start from a fresh codec so the stream can be resumed after the flush.
+        self.codec = new_codec(self.format)?;
+
+        self.pending_flush = Some(self.pictures.len());
+        self.try_decode()
+            .context("while flushing")
+            .map_err(VideoError::BackendFailure)
+    }
+
+    fn reset(&mut self) -> VideoResult<()> {
+        // Reset the codec.
+        self.codec = new_codec(self.format)?;
+
+        // Drop the queued output buffers and decoded frames.
+        self.clear_output_buffers()?;
+
+        self.event_queue
+            .queue_event(DecoderEvent::ResetCompleted(Ok(())))
+            .context("while resetting")
+            .map_err(VideoError::BackendFailure)
+    }
+
+    fn clear_output_buffers(&mut self) -> VideoResult<()> {
+        // Cancel any ongoing flush.
+        self.pending_flush = None;
+
+        // Drop all output buffers we currently hold.
+        if let SessionState::Decoding { output_queue } = &mut self.state {
+            output_queue.clear_ready_buffers();
+        }
+
+        // Drop all the decoded frames.
+        self.pictures.clear();
+
+        // Drop all decoded frames signaled as ready and cancel any reported flush.
+        self.event_queue.retain(|event| {
+            !matches!(
+                event,
+                DecoderEvent::PictureReady { .. } | DecoderEvent::FlushCompleted(_)
+            )
+        });
+
+        Ok(())
+    }
+
+    fn event_pipe(&self) -> &dyn AsRawDescriptor {
+        &self.event_queue
+    }
+
+    fn use_output_buffer(
+        &mut self,
+        picture_buffer_id: i32,
+        resource: GuestResource,
+    ) -> VideoResult<()> {
+        let output_queue = match &mut self.state {
+            SessionState::Decoding { output_queue } => output_queue,
+            // It is valid to receive buffers before the the initial DRC or during DRC, but we
+            // won't decode anything into them.
+            _ => return Ok(()),
+        };
+
+        output_queue
+            .import_buffer(picture_buffer_id as u32, resource)
+            .context("while importing output buffer")
+            .map_err(VideoError::BackendFailure)?;
+        self.try_decode()
+            .context("while decoding output buffer")
+            .map_err(VideoError::BackendFailure)
+    }
+
+    fn reuse_output_buffer(&mut self, picture_buffer_id: i32) -> VideoResult<()> {
+        let output_queue = match &mut self.state {
+            SessionState::Decoding { output_queue } => output_queue,
+            // Reusing buffers before the initial DRC or during DRC is valid, but we won't use
+            // them.
+            SessionState::AwaitingInitialResolution | SessionState::Drc => return Ok(()),
+            SessionState::AwaitingBufferCount => {
+                return Err(VideoError::BackendFailure(anyhow!(
+                    "invalid state while calling reuse_output_buffer"
+                )))
+            }
+        };
+
+        output_queue
+            .reuse_buffer(picture_buffer_id as u32)
+            .context("while reusing output buffer")
+            .map_err(VideoError::BackendFailure)?;
+        self.try_decode()
+            .context("while reusing output buffer")
+            .map_err(VideoError::BackendFailure)
+    }
+
+    fn read_event(&mut self) -> VideoResult<DecoderEvent> {
+        self.event_queue
+            .dequeue_event()
+            .context("while reading decoder event")
+            .map_err(VideoError::BackendFailure)
+    }
+}
+
+/// Software decoder backend.
+#[derive(Default)]
+pub struct SwDecoder;
+
+impl SwDecoder {
+    /// Create a new software decoder backend instance.
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl DecoderBackend for SwDecoder {
+    type Session = SwDecoderSession;
+
+    fn get_capabilities(&self) -> Capability {
+        const SUPPORTED_OUTPUT_FORMATS: [Format; 1] = [Format::NV12];
+
+        // Frames are copied line by line, so we support any resolution our codecs can decode.
+        let frame_formats = vec![FrameFormat {
+            width: FormatRange {
+                min: 16,
+                max: 4096,
+                step: 1,
+            },
+            height: FormatRange {
+                min: 16,
+                max: 4096,
+                step: 1,
+            },
+            bitrates: Default::default(),
+        }];
+
+        let mut profiles_map: BTreeMap<Format, Vec<Profile>> = Default::default();
+        profiles_map.insert(
+            Format::H264,
+            vec![Profile::H264Baseline, Profile::H264Main, Profile::H264High],
+        );
+        // All VP8 profiles are 8-bit 4:2:0.
+        profiles_map.insert(
+            Format::VP8,
+            vec![
+                Profile::VP8Profile0,
+                Profile::VP8Profile1,
+                Profile::VP8Profile2,
+                Profile::VP8Profile3,
+            ],
+        );
+        // The other VP9 profiles use bit depths or chroma subsamplings we cannot output.
+        profiles_map.insert(Format::VP9, vec![Profile::VP9Profile0]);
+        // Same for the other AV1 profiles, and for the 10-bit and 12-bit streams of this one.
+        profiles_map.insert(Format::AV1, vec![Profile::AV1Main]);
+
+        let mut levels: BTreeMap<Format, Vec<Level>> = Default::default();
+        levels.insert(Format::H264, vec![Level::H264_1_0]);
+
+        let in_formats = profiles_map
+            .keys()
+            .map(|&format| FormatDesc {
+                mask: !(u64::MAX << SUPPORTED_OUTPUT_FORMATS.len()),
+                format,
+                frame_formats: frame_formats.clone(),
+                plane_align: 1,
+            })
+            .collect::<Vec<_>>();
+
+        let out_formats = SUPPORTED_OUTPUT_FORMATS
+            .iter()
+            .map(|&format| FormatDesc {
+                mask: !(u64::MAX << in_formats.len()),
+                format,
+                frame_formats: frame_formats.clone(),
+                plane_align: 1,
+            })
+            .collect::<Vec<_>>();
+
+        Capability::new(in_formats, out_formats, profiles_map, levels)
+    }
+
+    fn new_session(&mut self, format: Format) -> VideoResult<Self::Session> {
+        Ok(SwDecoderSession {
+            format,
+            codec: new_codec(format)?,
+            event_queue: EventQueue::new()
+                .context("while creating decoder session")
+                .map_err(VideoError::BackendFailure)?,
+            state: SessionState::AwaitingInitialResolution,
+            current_visible_res: (0, 0),
+            pictures: Default::default(),
+            pending_flush: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::*;
+    use super::*;
+
+    #[test]
+    fn test_get_capabilities() {
+        let decoder = SwDecoder::new();
+        let caps = decoder.get_capabilities();
+        assert_eq!(caps.input_formats().len(), 4);
+        assert_eq!(caps.output_formats().len(), 1);
+    }
+
+    #[test]
+    fn test_picture_from_i420() {
+        // 3x3 frame, with 2x2 chroma planes and padded strides.
+        let y = [0u8, 1, 2, 0xff, 3, 4, 5, 0xff, 6, 7, 8];
+        let u = [10u8, 11, 0xff, 12, 13];
+        let v = [20u8, 21, 0xff, 22, 23];
+        let picture = Picture::from_i420(3, 3, 42, (4, &y), (3, &u), (3, &v)).unwrap();
+        assert_eq!(picture.timestamp, 42);
+        assert_eq!(picture.y, [0, 1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(picture.uv, [10, 20, 11, 21, 12, 22, 13, 23]);
+
+        // Planes too small for the frame size.
+        assert!(Picture::from_i420(4, 3, 0, (4, &y), (3, &u), (3, &v)).is_err());
+    }
+
+    #[test]
+    fn test_is_slice_nal() {
+        assert!(is_slice_nal(&[0x0, 0x0, 0x0, 0x1, 0x65, 0x88]));
+        assert!(is_slice_nal(&[0x0, 0x0, 0x1, 0x41, 0x9a]));
+        assert!(is_slice_nal(&[0x41, 0x9a]));
+        assert!(!is_slice_nal(&[0x0, 0x0, 0x0, 0x1, 0x67, 0x4d]));
+        assert!(!is_slice_nal(&[0x0, 0x0, 0x1, 0x68]));
+        assert!(!is_slice_nal(&[0x0, 0x0, 0x0]));
+    }
+
+    #[test]
+    fn test_decode_h264_guestmem_to_guestmem() {
+        decode_h264_generic(
+            &mut SwDecoder::new(),
+            build_guest_mem_handle,
+            build_guest_mem_handle,
+        );
+    }
+
+    // Decode using guest memory input and virtio object output buffers.
+    #[test]
+    fn test_decode_h264_guestmem_to_object() {
+        decode_h264_generic(
+            &mut SwDecoder::new(),
+            build_guest_mem_handle,
+            build_object_handle,
+        );
+    }
+
+    // Decode using virtio object input and guest memory output buffers.
+    #[test]
+    fn test_decode_h264_object_to_guestmem() {
+        decode_h264_generic(
+            &mut SwDecoder::new(),
+            build_object_handle,
+            build_guest_mem_handle,
+        );
+    }
+
+    // Decode using virtio object input and output buffers.
+    #[test]
+    fn test_decode_h264_object_to_object() {
+        decode_h264_generic(
+            &mut SwDecoder::new(),
+            build_object_handle,
+            build_object_handle,
+        );
+    }
+}
@@ -850,6 +850,7 @@ impl<D: DecoderBackend> Decoder<D> {
                     Some(Format::VP9) => Profile::VP9Profile0,
                     Some(Format::H264) => Profile::H264Baseline,
                     Some(Format::Hevc) => Profile::HevcMain,
+                    Some(Format::AV1) => Profile::AV1Main,
                     Some(f) => {
                         error!("specified format is invalid: {}", f);
                         return Err(VideoError::InvalidArgument);
@@ -45,6 +45,9 @@ pub enum Profile {
     VP9Profile1 = VIRTIO_VIDEO_PROFILE_VP9_PROFILE1,
     VP9Profile2 = VIRTIO_VIDEO_PROFILE_VP9_PROFILE2,
     VP9Profile3 = VIRTIO_VIDEO_PROFILE_VP9_PROFILE3,
+    AV1Main = VIRTIO_VIDEO_PROFILE_AV1_MAIN,
+    AV1High = VIRTIO_VIDEO_PROFILE_AV1_HIGH,
+    AV1Professional = VIRTIO_VIDEO_PROFILE_AV1_PROFESSIONAL,
 }
 impl_try_from_le32_for_enumn!(Profile, "profile");
 
@@ -67,6 +70,7 @@ impl Profile {
             HevcMain | HevcMain10 | HevcMainStillPicture => Format::Hevc,
             VP8Profile0 | VP8Profile1 | VP8Profile2 | VP8Profile3 => Format::VP8,
             VP9Profile0 | VP9Profile1 | VP9Profile2 | VP9Profile3 => Format::VP9,
+            AV1Main | AV1High | AV1Professional => Format::AV1,
         }
     }
 }
@@ -104,6 +108,7 @@ pub enum Format {
     Hevc = VIRTIO_VIDEO_FORMAT_HEVC,
     VP8 = VIRTIO_VIDEO_FORMAT_VP8,
     VP9 = VIRTIO_VIDEO_FORMAT_VP9,
+    AV1 = VIRTIO_VIDEO_FORMAT_AV1,
 }
 impl_try_from_le32_for_enumn!(Format, "format");
 
@@ -117,6 +122,7 @@ impl Display for Format {
             Hevc => write!(f, "HEVC"),
             VP8 => write!(f, "VP8"),
             VP9 => write!(f, "VP9"),
+            AV1 => write!(f, "AV1"),
         }
     }
 }
@@ -54,9 +54,14 @@ pub mod worker;
 
 #[cfg(all(
     feature = "video-decoder",
-    not(any(feature = "libvda", feature = "ffmpeg", feature = "vaapi"))
+    not(any(
+        feature = "libvda",
+        feature = "ffmpeg",
+        feature = "swdec",
+        feature = "vaapi"
+    ))
 ))]
-compile_error!("The \"video-decoder\" feature requires at least one of \"ffmpeg\", \"libvda\" or \"vaapi\" to also be enabled.");
+compile_error!("The \"video-decoder\" feature requires at least one of \"ffmpeg\", \"libvda\", \"swdec\" or \"vaapi\" to also be enabled.");
 
 #[cfg(all(
     feature = "video-encoder",
@@ -171,6 +176,8 @@ pub fn build_config(backend: VideoBackendType) -> virtio_video_config {
         VideoBackendType::LibvdaVd => device_name[0..8].copy_from_slice("libvdavd".as_bytes()),
         #[cfg(feature = "ffmpeg")]
         VideoBackendType::Ffmpeg => device_name[0..6].copy_from_slice("ffmpeg".as_bytes()),
+        #[cfg(feature = "swdec")]
+        VideoBackendType::Swdec => device_name[0..5].copy_from_slice("swdec".as_bytes()),
         #[cfg(feature = "vaapi")]
         VideoBackendType::Vaapi => device_name[0..5].copy_from_slice("vaapi".as_bytes()),
     };
@@ -298,6 +305,11 @@ impl VirtioDevice for VideoDevice {
                                 }
                             }
                         }
+                        #[cfg(feature = "swdec")]
+                        VideoBackendType::Swdec => {
+                            error!("Invalid backend for encoder");
+                            return;
+                        }
                         #[cfg(feature = "vaapi")]
                         VideoBackendType::Vaapi => {
                             error!("The VA-API encoder is not supported yet");
@@ -358,6 +370,8 @@ pub fn create_decoder_device(
         VideoBackendType::Ffmpeg => {
             decoder::backend::ffmpeg::FfmpegDecoder::new().into_trait_object()
         }
+        #[cfg(feature = "swdec")]
+        VideoBackendType::Swdec => decoder::backend::swdec::SwDecoder::new().into_trait_object(),
         #[cfg(feature = "vaapi")]
         VideoBackendType::Vaapi => decoder::backend::vaapi::VaapiDecoder::new()
             .map_err(|e| {
@@ -23,6 +23,7 @@
 //!   dynamically.
 //! * Moved some definitions such as virtio_video_config to device_constants to make them visible to
 //!   vhost-user modules, and also pub-use them.
+//! * Added the AV1 format and profiles, which follow the VP9 ones.
 
 #![allow(dead_code, non_snake_case, non_camel_case_types)]
 
@@ -47,7 +48,8 @@ pub const VIRTIO_VIDEO_FORMAT_H264: virtio_video_format = 4098;
 pub const VIRTIO_VIDEO_FORMAT_HEVC: virtio_video_format = 4099;
 pub const VIRTIO_VIDEO_FORMAT_VP8: virtio_video_format = 4100;
 pub const VIRTIO_VIDEO_FORMAT_VP9: virtio_video_format = 4101;
-pub const VIRTIO_VIDEO_FORMAT_CODED_MAX: virtio_video_format = 4101;
+pub const VIRTIO_VIDEO_FORMAT_AV1: virtio_video_format = 4102;
+pub const VIRTIO_VIDEO_FORMAT_CODED_MAX: virtio_video_format = 4102;
 pub type virtio_video_format = u32;
 pub const VIRTIO_VIDEO_PROFILE_H264_MIN: virtio_video_profile = 256;
 pub const VIRTIO_VIDEO_PROFILE_H264_BASELINE: virtio_video_profile = 256;
@@ -79,6 +81,11 @@ pub const VIRTIO_VIDEO_PROFILE_VP9_PROFILE1: virtio_video_profile = 1025;
 pub const VIRTIO_VIDEO_PROFILE_VP9_PROFILE2: virtio_video_profile = 1026;
 pub const VIRTIO_VIDEO_PROFILE_VP9_PROFILE3: virtio_video_profile = 1027;
 pub const VIRTIO_VIDEO_PROFILE_VP9_MAX: virtio_video_profile = 1027;
+pub const VIRTIO_VIDEO_PROFILE_AV1_MIN: virtio_video_profile = 1280;
+pub const VIRTIO_VIDEO_PROFILE_AV1_MAIN: virtio_video_profile = 1280;
+pub const VIRTIO_VIDEO_PROFILE_AV1_HIGH: virtio_video_profile = 1281;
+pub const VIRTIO_VIDEO_PROFILE_AV1_PROFESSIONAL: virtio_video_profile = 1282;
+pub const VIRTIO_VIDEO_PROFILE_AV1_MAX: virtio_video_profile = 1282;
 pub type virtio_video_profile = u32;
 pub const VIRTIO_VIDEO_LEVEL_H264_MIN: virtio_video_level = 256;
 pub const VIRTIO_VIDEO_LEVEL_H264_1_0: virtio_video_level = 256;
@@ -460,6 +460,10 @@ impl VirtioPciDevice {
                 PciClassCode::BaseSystemPeripheral,
                 &PciBaseSystemPeripheralSubclass::Other as &dyn PciSubclass,
             ),
+            DeviceType::Media => (
+                PciClassCode::MultimediaController,
+                &PciMultimediaSubclass::VideoController as &dyn PciSubclass,
+            ),
         };
 
         let num_interrupts = device.num_interrupts();
@@ -0,0 +1,86 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Sequential throughput of virtio-fs, with and without io_uring.
+
+#![cfg(any(target_os = "android", target_os = "linux"))]
+
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::time::Instant;
+
+use fixture::vm::Config;
+use fixture::vm::TestVm;
+use tempfile::TempDir;
+
+const FILE_SIZE_MIB: usize = 256;
+/// Number of `dd` processes running at the same time in the guest, so that several requests are
+/// in flight in the device.
+const JOBS: usize = 4;
+
+fn create_data_files(dir: &Path) -> anyhow::Result<()> {
+    let chunk = vec![0x5a; 1 << 20];
+    for job in 0..JOBS {
+        let mut data = File::create(dir.join(format!("data{job}")))?;
+        for _ in 0..FILE_SIZE_MIB {
+            data.write_all(&chunk)?;
+        }
+        data.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Runs `command` once per job in parallel in the guest and returns the throughput in MiB/s.
+fn parallel_throughput(vm: &mut TestVm, command: impl Fn(usize) -> String) -> anyhow::Result<f64> {
+    let jobs: Vec<String> = (0..JOBS).map(|job| format!("{} &", command(job))).collect();
+    let start = Instant::now();
+    vm.exec_in_guest(&format!("{} wait", jobs.join(" ")))?;
+    Ok((JOBS * FILE_SIZE_MIB) as f64 / start.elapsed().as_secs_f64())
+}
+
+/// Reads the files of the shared directory in the guest, then writes new ones, and returns the
+/// read and write throughput.
+fn measure(dir: &Path, io_uring: bool) -> anyhow::Result<(f64, f64)> {
+    let cfg = Config::from_env().extra_args(vec![
+        "--shared-dir".to_string(),
+        format!(
+            "{}:bench:type=fs:cache=auto:io_uring={io_uring}",
+            dir.to_str().unwrap()
+        ),
+    ]);
+    let mut vm = TestVm::new(cfg)?;
+    vm.exec_in_guest("mount -t virtiofs bench /mnt")?;
+    // The guest page cache is empty, so every read goes through the device.
+    let read = parallel_throughput(&mut vm, |job| {
+        format!("dd if=/mnt/data{job} of=/dev/null bs=1M 2>/dev/null")
+    })?;
+    let write = parallel_throughput(&mut vm, |job| {
+        format!(
+            "dd if=/dev/zero of=/mnt/out{job} bs=1M count={FILE_SIZE_MIB} conv=fsync 2>/dev/null"
+        )
+    })?;
+    vm.exec_in_guest("rm -f /mnt/out* && umount /mnt")?;
+    Ok((read, write))
+}
+
+/// Compares the throughput of the synchronous request handling with the io_uring one, which keeps
+/// several large transfers in flight.
+#[test]
+fn virtiofs_sequential_throughput() -> anyhow::Result<()> {
+    let dir = TempDir::new()?;
+    create_data_files(dir.path())?;
+
+    let (sync_read, sync_write) = measure(dir.path(), false)?;
+    let (uring_read, uring_write) = measure(dir.path(), true)?;
+    println!(
+        "read:  sync {sync_read:.0} MiB/s, io_uring {uring_read:.0} MiB/s ({:.2}x)",
+        uring_read / sync_read
+    );
+    println!(
+        "write: sync {sync_write:.0} MiB/s, io_uring {uring_write:.0} MiB/s ({:.2}x)",
+        uring_write / sync_write
+    );
+    Ok(())
+}
@@ -0,0 +1,565 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Indexing of uncompressed EROFS images.
+
+use std::cmp::min;
+use std::ffi::CString;
+use std::fs::File;
+use std::io;
+
+use super::check_name;
+use super::decode_dev;
+use super::invalid_data;
+use super::read_at;
+use super::read_extents;
+use super::unsupported;
+use super::Attr;
+use super::Bytes;
+use super::Content;
+use super::Extent;
+use super::Image;
+use super::InodeMap;
+use super::Node;
+use super::Source;
+use super::ROOT_ID;
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 128;
+const MAGIC: u32 = 0xe0f5_e1e2;
+const SLOT_SIZE: u64 = 32;
+const COMPACT_INODE_SIZE: u64 = 32;
+const EXTENDED_INODE_SIZE: u64 = 64;
+const DIRENT_SIZE: usize = 12;
+const NULL_ADDR: u32 = 0xffff_ffff;
+
+const FLAT_PLAIN: u16 = 0;
+const COMPRESSED_FULL: u16 = 1;
+const FLAT_INLINE: u16 = 2;
+const COMPRESSED_COMPACT: u16 = 3;
+const CHUNK_BASED: u16 = 4;
+
+const CHUNK_FORMAT_BLKBITS_MASK: u16 = 0x1f;
+const CHUNK_FORMAT_INDEXES: u16 = 0x20;
+
+/// Returns whether `header` holds an EROFS superblock.
+pub(super) fn is_erofs(header: &[u8]) -> bool {
+    Bytes::new(header).u32(SUPERBLOCK_OFFSET as usize).ok() == Some(MAGIC)
+}
+
+struct Loader<'a> {
+    file: &'a File,
+    file_len: u64,
+    block_bits: u32,
+    meta: u64,
+    build_time: (i64, i64),
+}
+
+impl<'a> Loader<'a> {
+    fn block_size(&self) -> u64 {
+        1 << self.block_bits
+    }
+
+    /// Parses the inode `nid`, returning its node and, for directories, the extents of their
+    /// entries.
+    fn inode(&self, nid: u64) -> io::Result<(Node, Option<Vec<Extent>>)> {
+        let pos = nid
+            .checked_mul(SLOT_SIZE)
+            .and_then(|off| off.checked_add(self.meta))
+            .filter(|pos| *pos < self.file_len)
+            .ok_or_else(|| invalid_data("invalid EROFS inode number"))?;
+        let compact = read_at(self.file, pos, COMPACT_INODE_SIZE as usize)?;
+        let format = Bytes::new(&compact).u16(0)?;
+        let extended = format & 1 != 0;
+        let layout = (format >> 1) & 0x7;
+
+        let (buf, inode_size) = if extended {
+            (
+                read_at(self.file, pos, EXTENDED_INODE_SIZE as usize)?,
+                EXTENDED_INODE_SIZE,
+            )
+        } else {
+            (compact, COMPACT_INODE_SIZE)
+        };
+        let b = Bytes::new(&buf);
+        let xattr_count = u64::from(b.u16(2)?);
+        let mode = u32::from(b.u16(4)?);
+        let i_u = b.u32(16)?;
+        let (size, mut attr) = if extended {
+            let size = b.u64(8)?;
+            let attr = Attr {
+                mode,
+                uid: b.u32(24)?,
+                gid: b.u32(28)?,
+                size,
+                nlink: b.u32(44)?,
+                mtime: b.u64(32)? as i64,
+                mtime_nsec: b.u32(40)?.into(),
+                ..Default::default()
+            };
+            (size, attr)
+        } else {
+            let size = b.u32(8)?.into();
+            let attr = Attr {
+                mode,
+                uid: b.u16(24)?.into(),
+                gid: b.u16(26)?.into(),
+                size,
+                nlink: b.u16(6)?.into(),
+                mtime: self.build_time.0,
+                mtime_nsec: self.build_time.1,
+                ..Default::default()
+            };
+            (size, attr)
+        };
+        // The inline xattrs start with a 12-byte header followed by `xattr_count - 1` slots.
+        let xattr_size = if xattr_count == 0 {
+            0
+        } else {
+            12 + (xattr_count - 1) * 4
+        };
+        let data_pos = pos + inode_size + xattr_size;
+
+        let file_type = mode & libc::S_IFMT;
+        let extents = match layout {
+            COMPRESSED_FULL | COMPRESSED_COMPACT if file_type == libc::S_IFREG => vec![Extent {
+                start: 0,
+                len: size,
+                source: Source::Unsupported,
+            }],
+            COMPRESSED_FULL | COMPRESSED_COMPACT => {
+                return Err(unsupported("compressed EROFS metadata"))
+            }
+            _ if matches!(file_type, libc::S_IFCHR | libc::S_IFBLK) => {
+                attr.rdev = decode_dev(i_u);
+                Vec::new()
+            }
+            _ => self.extents(layout, i_u, size, data_pos)?,
+        };
+
+        let content = match file_type {
+            libc::S_IFDIR => {
+                if size > self.file_len {
+                    return Err(invalid_data("invalid EROFS directory size"));
+                }
+                let node = Node {
+                    attr,
+                    content: Content::Dir {
+                        parent: ROOT_ID,
+                        entries: Vec::new(),
+                    },
+                };
+                return Ok((node, Some(extents)));
+            }
+            libc::S_IFREG => Content::File(extents),
+            libc::S_IFLNK => {
+                if size > libc::PATH_MAX as u64 {
+                    return Err(invalid_data("EROFS symlink target is too long"));
+                }
+                Content::Symlink(read_extents(self.file, &extents, size)?)
+            }
+            libc::S_IFCHR | libc::S_IFBLK | libc::S_IFIFO | libc::S_IFSOCK => Content::Special,
+            _ => return Err(invalid_data(format!("invalid EROFS file mode {:o}", mode))),
+        };
+        Ok((Node { attr, content }, None))
+    }
+
+    /// Maps the data of an uncompressed inode whose data or chunk table starts at `data_pos`
+    /// after the inode.
+    fn extents(&self, layout: u16, i_u: u32, size: u64, data_pos: u64) -> io::Result<Vec<Extent>> {
+        let block_size = self.block_size();
+        let mut extents = Vec::new();
+        match layout {
+            FLAT_PLAIN | FLAT_INLINE => {
+                // The last block of inline inodes is stored right after the inode.
+                let blocks = size.div_ceil(block_size);
+                let plain = if layout == FLAT_INLINE {
+                    blocks.saturating_sub(1) * block_size
+                } else {
+                    size
+                };
+                if plain > 0 {
+                    extents.push(Extent {
+                        start: 0,
+                        len: plain,
+                        source: Source::Image(u64::from(i_u) << self.block_bits),
+                    });
+                }
+                if plain < size {
+                    let tail = size - plain;
+                    if data_pos % block_size + tail > block_size {
+                        return Err(invalid_data("EROFS inline data crosses a block boundary"));
+                    }
+                    extents.push(Extent {
+                        start: plain,
+                        len: tail,
+                        source: Source::Image(data_pos),
+                    });
+                }
+            }
+            CHUNK_BASED => {
+                let format = i_u as u16;
+                if format & !(CHUNK_FORMAT_BLKBITS_MASK | CHUNK_FORMAT_INDEXES) != 0 {
+                    return Err(unsupported("unsupported EROFS chunk format"));
+                }
+                let chunk_bits = self.block_bits + u32::from(format & CHUNK_FORMAT_BLKBITS_MASK);
+                let chunk_size = 1u64 << chunk_bits;
+                let count = size.div_ceil(chunk_size);
+                let entry_size = if format & CHUNK_FORMAT_INDEXES != 0 {
+                    8
+                } else {
+                    4
+                };
+                if count.saturating_mul(entry_size) > self.file_len {
+                    return Err(invalid_data("invalid EROFS file size"));
+                }
+                let table = read_at(
+                    self.file,
+                    data_pos.next_multiple_of(entry_size),
+                    (count * entry_size) as usize,
+                )?;
+                let table = Bytes::new(&table);
+                for i in 0..count as usize {
+                    let (device, addr) = if entry_size == 8 {
+                        (table.u16(i * 8 + 2)?, table.u32(i * 8 + 4)?)
+                    } else {
+                        (0, table.u32(i * 4)?)
+                    };
+                    // Unmapped chunks are holes.
+                    if addr == NULL_ADDR {
+                        continue;
+                    }
+                    if device != 0 {
+                        return Err(unsupported("multi-device EROFS images are not supported"));
+                    }
+                    let start = i as u64 * chunk_size;
+                    extents.push(Extent {
+                        start,
+                        len: min(chunk_size, size - start),
+                        source: Source::Image(u64::from(addr) << self.block_bits),
+                    });
+                }
+            }
+            _ => {
+                return Err(unsupported(format!(
+                    "unsupported EROFS data layout {}",
+                    layout
+                )))
+            }
+        }
+        Ok(extents)
+    }
+}
+
+/// Parses the entries of a directory from its data, made of blocks of dirents followed by names.
+fn parse_dir(data: &[u8], block_size: usize) -> io::Result<Vec<(Vec<u8>, u64)>> {
+    let mut entries = Vec::new();
+    for block in data.chunks(block_size) {
+        let b = Bytes::new(block);
+        // The names start right after the dirents.
+        let first = usize::from(b.u16(8)?);
+        if first < DIRENT_SIZE || first > block.len() || first % DIRENT_SIZE != 0 {
+            return Err(invalid_data("invalid EROFS directory block"));
+        }
+        let count = first / DIRENT_SIZE;
+        for i in 0..count {
+            let nid = b.u64(i * DIRENT_SIZE)?;
+            let start = usize::from(b.u16(i * DIRENT_SIZE + 8)?);
+            let end = if i + 1 < count {
+                usize::from(b.u16((i + 1) * DIRENT_SIZE + 8)?)
+            } else {
+                block.len()
+            };
+            if start > end || end > block.len() {
+                return Err(invalid_data("invalid EROFS directory entry"));
+            }
+            // The last name of a block is padded with NUL bytes.
+            let name = block[start..end].split(|c| *c == 0).next().unwrap();
+            if name != b"." && name != b".." {
+                entries.push((name.to_vec(), nid));
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// Indexes the EROFS image `image`.
+pub(super) fn load(image: &File) -> io::Result<Image> {
+    let buf = read_at(image, SUPERBLOCK_OFFSET, SUPERBLOCK_SIZE)?;
+    let sb = Bytes::new(&buf);
+    if sb.u32(0)? != MAGIC {
+        return Err(invalid_data("not an EROFS image"));
+    }
+    let block_bits = u32::from(sb.u8(12)?);
+    if !(9..=16).contains(&block_bits) {
+        return Err(unsupported("unsupported EROFS block size"));
+    }
+    if sb.u8(90)? != 0 {
+        return Err(unsupported("unsupported EROFS directory block size"));
+    }
+    let loader = Loader {
+        file: image,
+        file_len: image.metadata()?.len(),
+        block_bits,
+        meta: u64::from(sb.u32(40)?) << block_bits,
+        build_time: (sb.u64(24)? as i64, sb.u32(32)?.into()),
+    };
+
+    let root_nid = u64::from(sb.u16(14)?);
+    let (root, extents) = loader.inode(root_nid)?;
+    let extents = extents.ok_or_else(|| invalid_data("EROFS root is not a directory"))?;
+    let mut index = Image::new(root.attr);
+
+    let mut inodes = InodeMap::new();
+    inodes.insert(root_nid, ROOT_ID);
+    let mut pending = vec![(ROOT_ID, extents)];
+    while let Some((dir, extents)) = pending.pop() {
+        let size = index.node(dir).unwrap().attr.size;
+        let data = read_extents(image, &extents, size)?;
+        let mut children = Vec::new();
+        for (name, nid) in parse_dir(&data, loader.block_size() as usize)? {
+            check_name(&name)?;
+            let child = match inodes.get(&nid) {
+                Some(child) => *child,
+                None => {
+                    let (mut node, extents) = loader.inode(nid)?;
+                    if let Content::Dir { parent, .. } = &mut node.content {
+                        *parent = dir;
+                    }
+                    let child = index.push(node);
+                    inodes.insert(nid, child);
+                    if let Some(extents) = extents {
+                        pending.push((child, extents));
+                    }
+                    child
+                }
+            };
+            children.push((CString::new(name).unwrap(), child));
+        }
+        if let Some(Node {
+            content: Content::Dir { entries, .. },
+            ..
+        }) = index.node_mut(dir)
+        {
+            *entries = children;
+        }
+    }
+
+    Ok(index)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::super::tests::*;
+    use super::super::ArchiveFormat;
+    use super::super::ArchiveFs;
+    use super::*;
+    use crate::filesystem::FileSystem;
+
+    const BLOCK: usize = 4096;
+    const META: usize = BLOCK;
+
+    fn put(image: &mut [u8], offset: usize, bytes: &[u8]) {
+        image[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+
+    fn compact(
+        image: &mut [u8],
+        nid: usize,
+        layout: u16,
+        mode: u32,
+        nlink: u16,
+        size: u32,
+        i_u: u32,
+    ) {
+        let off = META + nid * SLOT_SIZE as usize;
+        put(image, off, &(layout << 1).to_le_bytes());
+        put(image, off + 4, &(mode as u16).to_le_bytes());
+        put(image, off + 6, &nlink.to_le_bytes());
+        put(image, off + 8, &size.to_le_bytes());
+        put(image, off + 16, &i_u.to_le_bytes());
+        put(image, off + 20, &(nid as u32).to_le_bytes());
+    }
+
+    /// Writes the blocks of dirents and names for `entries`.
+    fn dir_block(entries: &[(&str, u64, u8)]) -> Vec<u8> {
+        let mut block = Vec::new();
+        let mut nameoff = entries.len() * DIRENT_SIZE;
+        for (name, nid, file_type) in entries {
+            block.extend_from_slice(&nid.to_le_bytes());
+            block.extend_from_slice(&(nameoff as u16).to_le_bytes());
+            block.push(*file_type);
+            block.push(0);
+            nameoff += name.len();
+        }
+        for (name, _, _) in entries {
+            block.extend_from_slice(name.as_bytes());
+        }
+        block
+    }
+
+    /// Builds an image with "hello" (an extended inode with xattrs and inline data), "plain" (two
+    /// blocks), "link", "chunked" (three chunks with a hole), "null" (a device) and "sub" (an
+    /// inline directory holding "hard", a hard link to "plain").
+    fn build() -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+        let mut image = vec![0u8; 7 * BLOCK];
+        let plain: Vec<u8> = (0..5000u32).map(|i| (i % 253) as u8).collect();
+        let chunked: Vec<u8> = (0..8202u32).map(|i| (i % 7) as u8 + 1).collect();
+
+        let sb = SUPERBLOCK_OFFSET as usize;
+        put(&mut image, sb, &MAGIC.to_le_bytes());
+        image[sb + 12] = 12;
+        put(&mut image, sb + 14, &0u16.to_le_bytes());
+        put(&mut image, sb + 24, &1_700_000_000u64.to_le_bytes());
+        put(&mut image, sb + 40, &1u32.to_le_bytes());
+
+        let root = dir_block(&[
+            (".", 0, 2),
+            ("..", 0, 2),
+            ("chunked", 7, 1),
+            ("hello", 1, 1),
+            ("link", 5, 7),
+            ("null", 9, 3),
+            ("plain", 4, 1),
+            ("sub", 10, 2),
+        ]);
+        compact(
+            &mut image,
+            0,
+            FLAT_PLAIN,
+            libc::S_IFDIR | 0o755,
+            3,
+            root.len() as u32,
+            2,
+        );
+        put(&mut image, 2 * BLOCK, &root);
+
+        // nid 1: an extended inode with one xattr slot and inline data.
+        let off = META + 32;
+        put(&mut image, off, &((FLAT_INLINE << 1) | 1).to_le_bytes());
+        put(&mut image, off + 2, &1u16.to_le_bytes());
+        put(
+            &mut image,
+            off + 4,
+            &((libc::S_IFREG | 0o640) as u16).to_le_bytes(),
+        );
+        put(&mut image, off + 8, &11u64.to_le_bytes());
+        put(&mut image, off + 24, &1000u32.to_le_bytes());
+        put(&mut image, off + 28, &1001u32.to_le_bytes());
+        put(&mut image, off + 32, &1_600_000_000u64.to_le_bytes());
+        put(&mut image, off + 40, &5u32.to_le_bytes());
+        put(&mut image, off + 44, &1u32.to_le_bytes());
+        put(&mut image, off + 64 + 12, b"hello world");
+
+        compact(&mut image, 4, FLAT_PLAIN, libc::S_IFREG | 0o644, 2, 5000, 3);
+        put(&mut image, 3 * BLOCK, &plain);
+
+        compact(&mut image, 5, FLAT_INLINE, libc::S_IFLNK | 0o777, 1, 5, 0);
+        put(&mut image, META + 5 * 32 + 32, b"hello");
+
+        compact(
+            &mut image,
+            7,
+            CHUNK_BASED,
+            libc::S_IFREG | 0o644,
+            1,
+            8202,
+            0,
+        );
+        let table = META + 7 * 32 + 32;
+        put(&mut image, table, &5u32.to_le_bytes());
+        put(&mut image, table + 4, &NULL_ADDR.to_le_bytes());
+        put(&mut image, table + 8, &6u32.to_le_bytes());
+        put(&mut image, 5 * BLOCK, &chunked[..4096]);
+        put(&mut image, 6 * BLOCK, &chunked[8192..]);
+
+        compact(
+            &mut image,
+            9,
+            FLAT_PLAIN,
+            libc::S_IFCHR | 0o666,
+            1,
+            0,
+            (1 << 8) | 3,
+        );
+
+        let sub = dir_block(&[(".", 10, 2), ("..", 0, 2), ("hard", 4, 1)]);
+        compact(
+            &mut image,
+            10,
+            FLAT_INLINE,
+            libc::S_IFDIR | 0o700,
+            2,
+            sub.len() as u32,
+            0,
+        );
+        put(&mut image, META + 10 * 32 + 32, &sub);
+
+        let mut expected_chunked = chunked;
+        expected_chunked[4096..8192].fill(0);
+        (image, plain, expected_chunked)
+    }
+
+    #[test]
+    fn erofs_image() {
+        let (image, plain, chunked) = build();
+        let file = image_file(&image);
+        assert_eq!(ArchiveFormat::detect(&file).unwrap(), ArchiveFormat::Erofs);
+        let fs = ArchiveFs::new(file, Duration::ZERO).unwrap();
+
+        assert_eq!(
+            list(&fs, ROOT_ID),
+            [".", "..", "chunked", "hello", "link", "null", "plain", "sub"]
+        );
+        let root = lookup_path(&fs, ".").unwrap();
+        assert_eq!(root.attr.st_mtime, 1_700_000_000);
+
+        let hello = lookup_path(&fs, "hello").unwrap();
+        assert_eq!(hello.attr.st_mode, libc::S_IFREG | 0o640);
+        assert_eq!((hello.attr.st_uid, hello.attr.st_gid), (1000, 1001));
+        assert_eq!(
+            (hello.attr.st_mtime, hello.attr.st_mtime_nsec),
+            (1_600_000_000, 5)
+        );
+        assert_eq!(read(&fs, hello.inode, 0, 100).unwrap(), b"hello world");
+
+        let entry = lookup_path(&fs, "plain").unwrap();
+        assert_eq!(read(&fs, entry.inode, 0, 8192).unwrap(), plain);
+        assert_eq!(lookup_path(&fs, "sub/hard").unwrap().inode, entry.inode);
+        assert_eq!(
+            list(&fs, lookup_path(&fs, "sub").unwrap().inode),
+            [".", "..", "hard"]
+        );
+
+        let entry = lookup_path(&fs, "chunked").unwrap();
+        assert_eq!(read(&fs, entry.inode, 0, 16384).unwrap(), chunked);
+        assert_eq!(
+            read(&fs, entry.inode, 4090, 10).unwrap(),
+            chunked[4090..4100]
+        );
+
+        let link = lookup_path(&fs, "link").unwrap();
+        assert_eq!(fs.readlink(ctx(), link.inode).unwrap(), b"hello");
+        let null = lookup_path(&fs, "null").unwrap();
+        assert_eq!(null.attr.st_rdev, libc::makedev(1, 3));
+    }
+
+    #[test]
+    fn compressed_files_are_unsupported() {
+        let (mut image, _, _) = build();
+        put(
+            &mut image,
+            META + 4 * 32,
+            &(COMPRESSED_FULL << 1).to_le_bytes(),
+        );
+        let fs = ArchiveFs::new(image_file(&image), Duration::ZERO).unwrap();
+        let plain = lookup_path(&fs, "plain").unwrap();
+        assert_eq!(
+            read(&fs, plain.inode, 0, 10).unwrap_err().raw_os_error(),
+            Some(libc::EOPNOTSUPP)
+        );
+    }
+}
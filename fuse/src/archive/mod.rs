@@ -0,0 +1,1007 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! A read-only file system serving the contents of an archive or file system image.
+//!
+//! [`ArchiveFs`] indexes a tar archive, a squashfs image or an EROFS image when it is created, and
+//! then answers requests from the index and the image file without unpacking it. It implements
+//! [`FileSystem`], so it can be used by the virtio-fs device or served to the host kernel:
+//!
+//! ```no_run
+//! # fn main() -> std::io::Result<()> {
+//! use std::fs::File;
+//! use std::os::unix::io::AsRawFd;
+//! use std::time::Duration;
+//!
+//! use fuse::archive::ArchiveFs;
+//! use fuse::mount::MountOption;
+//!
+//! let fs = ArchiveFs::new(File::open("rootfs.squashfs")?, Duration::from_secs(60))?;
+//! let dev_fuse = File::options().read(true).write(true).open("/dev/fuse")?;
+//! fuse::mount(
+//!     "/mnt",
+//!     "rootfs",
+//!     libc::MS_RDONLY | libc::MS_NOSUID | libc::MS_NODEV,
+//!     &[
+//!         MountOption::FD(dev_fuse.as_raw_fd()),
+//!         MountOption::RootMode(libc::S_IFDIR),
+//!         MountOption::UserId(0),
+//!         MountOption::GroupId(0),
+//!         MountOption::AllowOther,
+//!     ],
+//! )?;
+//! fuse::worker::start_message_loop(dev_fuse, 1 << 20, 1 << 20, fs)
+//!     .map_err(std::io::Error::other)?;
+//! # Ok(())
+//! # }
+//! ```
+
+mod erofs;
+mod squashfs;
+mod tar;
+
+use std::cmp::min;
+use std::collections::BTreeMap;
+use std::ffi::CStr;
+use std::ffi::CString;
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::os::unix::fs::FileExt;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use base::AsRawDescriptor;
+use base::RawDescriptor;
+
+use crate::filesystem::Context;
+use crate::filesystem::DirEntry;
+use crate::filesystem::DirectoryIterator;
+use crate::filesystem::Entry;
+use crate::filesystem::FileSystem;
+use crate::filesystem::FsOptions;
+use crate::filesystem::OpenOptions;
+use crate::filesystem::SetattrValid;
+use crate::filesystem::ZeroCopyReader;
+use crate::filesystem::ZeroCopyWriter;
+
+/// The inode of the root directory.
+pub const ROOT_ID: u64 = 1;
+
+const BLOCK_SIZE: u64 = 4096;
+
+/// The formats of the images served by [`ArchiveFs`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    /// A POSIX (ustar, pax) or GNU tar archive.
+    Tar,
+    /// A squashfs 4.0 image.
+    Squashfs,
+    /// An EROFS image.
+    Erofs,
+}
+
+impl ArchiveFormat {
+    /// Detects the format of `image` from its magic numbers.
+    pub fn detect(image: &File) -> io::Result<ArchiveFormat> {
+        let mut header = [0u8; 1028];
+        let len = read_prefix(image, &mut header)?;
+        let header = &header[..len];
+        if squashfs::is_squashfs(header) {
+            Ok(ArchiveFormat::Squashfs)
+        } else if erofs::is_erofs(header) {
+            Ok(ArchiveFormat::Erofs)
+        } else if tar::is_tar(header) {
+            Ok(ArchiveFormat::Tar)
+        } else {
+            Err(unsupported("unknown archive format"))
+        }
+    }
+}
+
+/// The compression algorithms of the data blocks of an image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Compression {
+    /// zlib streams.
+    Gzip,
+    /// Raw LZ4 blocks.
+    Lz4,
+}
+
+impl Compression {
+    /// Decompresses `data`, which expands to at most `max` bytes.
+    fn decompress(self, data: &[u8], max: usize) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::Gzip => miniz_oxide::inflate::decompress_to_vec_zlib_with_limit(data, max)
+                .map_err(|e| invalid_data(format!("corrupted zlib block: {:?}", e.status))),
+            Compression::Lz4 => lz4_flex::block::decompress(data, max)
+                .map_err(|e| invalid_data(format!("corrupted lz4 block: {}", e))),
+        }
+    }
+}
+
+/// Where the bytes of an extent come from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Source {
+    /// Bytes stored as-is at an offset of the image.
+    Image(u64),
+    /// The bytes at `skip` of the compressed block of `size` bytes at `offset` of the image.
+    Block { offset: u64, size: u32, skip: u32 },
+    /// Data stored in a way that is not supported.
+    Unsupported,
+}
+
+/// A contiguous range of the contents of a file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Extent {
+    start: u64,
+    len: u64,
+    source: Source,
+}
+
+/// The attributes of a node.
+#[derive(Clone, Copy, Debug, Default)]
+struct Attr {
+    /// The file type and permissions.
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    size: u64,
+    nlink: u32,
+    rdev: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+}
+
+/// The contents of a node.
+#[derive(Debug)]
+enum Content {
+    /// A directory with its parent and its entries, sorted by name once the image is loaded.
+    Dir {
+        parent: u64,
+        entries: Vec<(CString, u64)>,
+    },
+    /// A regular file, made of non-overlapping extents sorted by offset. Ranges that no extent
+    /// covers read as zeroes.
+    File(Vec<Extent>),
+    /// A symbolic link with its target.
+    Symlink(Vec<u8>),
+    /// A device, FIFO or socket.
+    Special,
+}
+
+#[derive(Debug)]
+struct Node {
+    attr: Attr,
+    content: Content,
+}
+
+/// The index of an image, as built by the loader of its format.
+struct Image {
+    /// The nodes of the image. The inode of a node is its index plus `ROOT_ID`.
+    nodes: Vec<Node>,
+    /// The compression of the `Source::Block` extents.
+    compression: Option<Compression>,
+    /// The maximum decompressed size of a block.
+    block_size: u32,
+}
+
+impl Image {
+    /// Creates an image index holding a root directory with `attr`.
+    fn new(attr: Attr) -> Image {
+        Image {
+            nodes: vec![Node {
+                attr,
+                content: Content::Dir {
+                    parent: ROOT_ID,
+                    entries: Vec::new(),
+                },
+            }],
+            compression: None,
+            block_size: 0,
+        }
+    }
+
+    /// Adds a node and returns its inode.
+    fn push(&mut self, node: Node) -> u64 {
+        self.nodes.push(node);
+        self.nodes.len() as u64
+    }
+
+    fn node(&self, inode: u64) -> Option<&Node> {
+        let index = inode.checked_sub(ROOT_ID)?;
+        self.nodes.get(usize::try_from(index).ok()?)
+    }
+
+    fn node_mut(&mut self, inode: u64) -> Option<&mut Node> {
+        let index = inode.checked_sub(ROOT_ID)?;
+        self.nodes.get_mut(usize::try_from(index).ok()?)
+    }
+
+    /// Sorts the entries of the directories so that they can be looked up by name, keeping the
+    /// last of duplicated names.
+    fn finish(&mut self) {
+        for node in &mut self.nodes {
+            if let Content::Dir { entries, .. } = &mut node.content {
+                entries.reverse();
+                entries.sort_by(|a, b| a.0.cmp(&b.0));
+                entries.dedup_by(|a, b| a.0 == b.0);
+            }
+        }
+    }
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+fn unsupported<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, e)
+}
+
+fn erofs_error() -> io::Error {
+    io::Error::from_raw_os_error(libc::EROFS)
+}
+
+/// Reads as many bytes as possible from the start of `file` into `buf`.
+fn read_prefix(file: &File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match file.read_at(&mut buf[len..], len as u64) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
+
+/// Reads exactly `len` bytes at `offset` of `file`.
+fn read_at(file: &File, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0u8; len];
+    file.read_exact_at(&mut buf, offset).map_err(|e| {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            invalid_data("truncated image")
+        } else {
+            e
+        }
+    })?;
+    Ok(buf)
+}
+
+/// A bounds-checked little-endian decoder of on-disk structures.
+struct Bytes<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Bytes<'a> {
+    fn new(buf: &'a [u8]) -> Bytes<'a> {
+        Bytes { buf }
+    }
+
+    fn slice(&self, offset: usize, len: usize) -> io::Result<&'a [u8]> {
+        offset
+            .checked_add(len)
+            .and_then(|end| self.buf.get(offset..end))
+            .ok_or_else(|| invalid_data("truncated structure"))
+    }
+
+    fn u8(&self, offset: usize) -> io::Result<u8> {
+        Ok(self.slice(offset, 1)?[0])
+    }
+
+    fn u16(&self, offset: usize) -> io::Result<u16> {
+        Ok(u16::from_le_bytes(
+            self.slice(offset, 2)?.try_into().unwrap(),
+        ))
+    }
+
+    fn u32(&self, offset: usize) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(
+            self.slice(offset, 4)?.try_into().unwrap(),
+        ))
+    }
+
+    fn u64(&self, offset: usize) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(
+            self.slice(offset, 8)?.try_into().unwrap(),
+        ))
+    }
+}
+
+/// Decodes a device number in the encoding of the Linux `new_encode_dev`.
+fn decode_dev(dev: u32) -> u64 {
+    let major = (dev & 0xfff00) >> 8;
+    let minor = (dev & 0xff) | ((dev >> 12) & 0xfff00);
+    libc::makedev(major, minor)
+}
+
+/// Returns the `DT_*` directory entry type of `mode`.
+fn dirent_type(mode: u32) -> u32 {
+    (mode & libc::S_IFMT) >> 12
+}
+
+/// Checks that `name` can be used as a directory entry.
+fn check_name(name: &[u8]) -> io::Result<()> {
+    if name.is_empty() || name == b"." || name == b".." || name.contains(&b'/') || name.contains(&0)
+    {
+        Err(invalid_data(format!(
+            "invalid file name {:?}",
+            String::from_utf8_lossy(name)
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+/// A read-only file system serving the contents of a tar archive, a squashfs image or an EROFS
+/// image.
+///
+/// The whole directory tree of the image is indexed in memory when the file system is created;
+/// the contents of the files are read from the image, and decompressed if needed, on demand.
+/// Requests that would modify the file system fail with `EROFS`.
+pub struct ArchiveFs {
+    format: ArchiveFormat,
+    image: Mutex<File>,
+    image_size: u64,
+    nodes: Arc<[Node]>,
+    compression: Option<Compression>,
+    block_size: u32,
+    timeout: Duration,
+    // The most recently decompressed block and its offset in the image.
+    block_cache: Mutex<Option<(u64, Arc<[u8]>)>>,
+}
+
+impl ArchiveFs {
+    /// Indexes `image`, detecting its format. `timeout` is how long the kernel may cache entries
+    /// and attributes, which never change.
+    pub fn new(image: File, timeout: Duration) -> io::Result<ArchiveFs> {
+        let format = ArchiveFormat::detect(&image)?;
+        ArchiveFs::with_format(image, format, timeout)
+    }
+
+    /// Indexes `image`, which is in the given `format`.
+    pub fn with_format(
+        image: File,
+        format: ArchiveFormat,
+        timeout: Duration,
+    ) -> io::Result<ArchiveFs> {
+        let mut index = match format {
+            ArchiveFormat::Tar => tar::load(&image)?,
+            ArchiveFormat::Squashfs => squashfs::load(&image)?,
+            ArchiveFormat::Erofs => erofs::load(&image)?,
+        };
+        index.finish();
+        let image_size = image.metadata()?.len();
+
+        Ok(ArchiveFs {
+            format,
+            image: Mutex::new(image),
+            image_size,
+            nodes: index.nodes.into(),
+            compression: index.compression,
+            block_size: index.block_size,
+            timeout,
+            block_cache: Mutex::new(None),
+        })
+    }
+
+    /// Returns the format of the image.
+    pub fn format(&self) -> ArchiveFormat {
+        self.format
+    }
+
+    /// Returns the descriptors that must stay open for the file system to work.
+    pub fn keep_rds(&self) -> Vec<RawDescriptor> {
+        vec![self.image.lock().unwrap().as_raw_descriptor()]
+    }
+
+    fn node(&self, inode: u64) -> io::Result<&Node> {
+        inode
+            .checked_sub(ROOT_ID)
+            .and_then(|index| self.nodes.get(usize::try_from(index).ok()?))
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EBADF))
+    }
+
+    fn stat(&self, inode: u64, attr: &Attr) -> libc::stat64 {
+        // SAFETY: zero-initializing a struct with only POD fields.
+        let mut st: libc::stat64 = unsafe { mem::zeroed() };
+        st.st_ino = inode;
+        st.st_mode = attr.mode;
+        st.st_nlink = attr.nlink.into();
+        st.st_uid = attr.uid;
+        st.st_gid = attr.gid;
+        st.st_rdev = attr.rdev;
+        st.st_size = attr.size as libc::off64_t;
+        st.st_blksize = BLOCK_SIZE as libc::blksize_t;
+        st.st_blocks = attr.size.div_ceil(512) as libc::blkcnt64_t;
+        st.st_atime = attr.mtime;
+        st.st_atime_nsec = attr.mtime_nsec;
+        st.st_mtime = attr.mtime;
+        st.st_mtime_nsec = attr.mtime_nsec;
+        st.st_ctime = attr.mtime;
+        st.st_ctime_nsec = attr.mtime_nsec;
+        st
+    }
+
+    fn entry(&self, inode: u64) -> io::Result<Entry> {
+        let node = self.node(inode)?;
+        Ok(Entry {
+            inode,
+            generation: 0,
+            attr: self.stat(inode, &node.attr),
+            attr_timeout: self.timeout,
+            entry_timeout: self.timeout,
+        })
+    }
+
+    /// Returns the decompressed block of `size` bytes at `offset` of the image.
+    fn block(&self, offset: u64, size: u32) -> io::Result<Arc<[u8]>> {
+        let mut cache = self.block_cache.lock().unwrap();
+        if let Some((cached, data)) = &*cache {
+            if *cached == offset {
+                return Ok(data.clone());
+            }
+        }
+
+        let compression = self
+            .compression
+            .ok_or_else(|| unsupported("unsupported compression"))?;
+        let stored = read_at(&self.image.lock().unwrap(), offset, size as usize)?;
+        let data: Arc<[u8]> = compression
+            .decompress(&stored, self.block_size as usize)?
+            .into();
+        *cache = Some((offset, data.clone()));
+        Ok(data)
+    }
+
+    /// Writes `len` bytes from `pos` of the extent `e` to `w`.
+    fn write_extent<W: io::Write + ZeroCopyWriter>(
+        &self,
+        w: &mut W,
+        e: &Extent,
+        pos: u64,
+        len: usize,
+    ) -> io::Result<()> {
+        let offset = pos - e.start;
+        match e.source {
+            Source::Image(start) => {
+                let mut image = self.image.lock().unwrap();
+                w.write_all_from(&mut image, len, start + offset)
+            }
+            Source::Block {
+                offset: block,
+                size,
+                skip,
+            } => {
+                let data = self.block(block, size)?;
+                let start = skip as usize + offset as usize;
+                let bytes = data
+                    .get(start..start + len)
+                    .ok_or_else(|| invalid_data("data block is too short"))?;
+                w.write_all(bytes)
+            }
+            Source::Unsupported => Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP)),
+        }
+    }
+}
+
+fn write_zeroes<W: io::Write>(w: &mut W, mut len: usize) -> io::Result<()> {
+    const ZEROES: [u8; 4096] = [0; 4096];
+    while len > 0 {
+        let n = min(len, ZEROES.len());
+        w.write_all(&ZEROES[..n])?;
+        len -= n;
+    }
+    Ok(())
+}
+
+/// Iterates over the entries of a directory of an [`ArchiveFs`], starting with "." and "..".
+pub struct ArchiveDirIter {
+    nodes: Arc<[Node]>,
+    inode: u64,
+    offset: u64,
+}
+
+impl DirectoryIterator for ArchiveDirIter {
+    fn next(&mut self) -> Option<DirEntry> {
+        let node = self.nodes.get((self.inode - ROOT_ID) as usize)?;
+        let Content::Dir { parent, entries } = &node.content else {
+            return None;
+        };
+        let (ino, type_, name) = match self.offset {
+            0 => (self.inode, libc::DT_DIR.into(), c"."),
+            1 => (*parent, libc::DT_DIR.into(), c".."),
+            n => {
+                let (name, child) = entries.get((n - 2) as usize)?;
+                let mode = self.nodes[(child - ROOT_ID) as usize].attr.mode;
+                (*child, dirent_type(mode), name.as_c_str())
+            }
+        };
+        self.offset += 1;
+        Some(DirEntry {
+            ino,
+            offset: self.offset,
+            type_,
+            name,
+        })
+    }
+}
+
+impl FileSystem for ArchiveFs {
+    type Inode = u64;
+    type Handle = u64;
+    type DirIter = ArchiveDirIter;
+
+    fn init(&self, capable: FsOptions) -> io::Result<FsOptions> {
+        let wanted =
+            FsOptions::DO_READDIRPLUS | FsOptions::READDIRPLUS_AUTO | FsOptions::CACHE_SYMLINKS;
+        Ok(capable & wanted)
+    }
+
+    fn statfs(&self, _ctx: Context, _inode: u64) -> io::Result<libc::statvfs64> {
+        // SAFETY: zero-initializing a struct with only POD fields.
+        let mut st: libc::statvfs64 = unsafe { mem::zeroed() };
+        st.f_bsize = BLOCK_SIZE;
+        st.f_frsize = BLOCK_SIZE;
+        st.f_blocks = self.image_size.div_ceil(BLOCK_SIZE);
+        st.f_files = self.nodes.len() as u64;
+        st.f_namemax = 255;
+        st.f_flag = libc::ST_RDONLY;
+        Ok(st)
+    }
+
+    fn lookup(&self, _ctx: Context, parent: u64, name: &CStr) -> io::Result<Entry> {
+        let Content::Dir {
+            parent: up,
+            entries,
+        } = &self.node(parent)?.content
+        else {
+            return Err(io::Error::from_raw_os_error(libc::ENOTDIR));
+        };
+        let inode = match name.to_bytes() {
+            b"." => parent,
+            b".." => *up,
+            _ => match entries.binary_search_by(|(n, _)| n.as_c_str().cmp(name)) {
+                Ok(index) => entries[index].1,
+                Err(_) => return Err(io::Error::from_raw_os_error(libc::ENOENT)),
+            },
+        };
+        self.entry(inode)
+    }
+
+    fn getattr(
+        &self,
+        _ctx: Context,
+        inode: u64,
+        _handle: Option<u64>,
+    ) -> io::Result<(libc::stat64, Duration)> {
+        let node = self.node(inode)?;
+        Ok((self.stat(inode, &node.attr), self.timeout))
+    }
+
+    fn setattr(
+        &self,
+        _ctx: Context,
+        _inode: u64,
+        _attr: libc::stat64,
+        _handle: Option<u64>,
+        _valid: SetattrValid,
+    ) -> io::Result<(libc::stat64, Duration)> {
+        Err(erofs_error())
+    }
+
+    fn readlink(&self, _ctx: Context, inode: u64) -> io::Result<Vec<u8>> {
+        match &self.node(inode)?.content {
+            Content::Symlink(target) => Ok(target.clone()),
+            _ => Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        }
+    }
+
+    fn symlink(
+        &self,
+        _ctx: Context,
+        _linkname: &CStr,
+        _parent: u64,
+        _name: &CStr,
+        _security_ctx: Option<&CStr>,
+    ) -> io::Result<Entry> {
+        Err(erofs_error())
+    }
+
+    fn mknod(
+        &self,
+        _ctx: Context,
+        _inode: u64,
+        _name: &CStr,
+        _mode: u32,
+        _rdev: u32,
+        _umask: u32,
+        _security_ctx: Option<&CStr>,
+    ) -> io::Result<Entry> {
+        Err(erofs_error())
+    }
+
+    fn mkdir(
+        &self,
+        _ctx: Context,
+        _parent: u64,
+        _name: &CStr,
+        _mode: u32,
+        _umask: u32,
+        _security_ctx: Option<&CStr>,
+    ) -> io::Result<Entry> {
+        Err(erofs_error())
+    }
+
+    fn unlink(&self, _ctx: Context, _parent: u64, _name: &CStr) -> io::Result<()> {
+        Err(erofs_error())
+    }
+
+    fn rmdir(&self, _ctx: Context, _parent: u64, _name: &CStr) -> io::Result<()> {
+        Err(erofs_error())
+    }
+
+    fn rename(
+        &self,
+        _ctx: Context,
+        _olddir: u64,
+        _oldname: &CStr,
+        _newdir: u64,
+        _newname: &CStr,
+        _flags: u32,
+    ) -> io::Result<()> {
+        Err(erofs_error())
+    }
+
+    fn link(
+        &self,
+        _ctx: Context,
+        _inode: u64,
+        _newparent: u64,
+        _newname: &CStr,
+    ) -> io::Result<Entry> {
+        Err(erofs_error())
+    }
+
+    fn open(
+        &self,
+        _ctx: Context,
+        inode: u64,
+        flags: u32,
+    ) -> io::Result<(Option<u64>, OpenOptions)> {
+        let node = self.node(inode)?;
+        if flags & libc::O_ACCMODE as u32 != libc::O_RDONLY as u32
+            || flags & libc::O_TRUNC as u32 != 0
+        {
+            return Err(erofs_error());
+        }
+        if let Content::Dir { .. } = node.content {
+            return Err(io::Error::from_raw_os_error(libc::EISDIR));
+        }
+        Ok((None, OpenOptions::KEEP_CACHE))
+    }
+
+    fn create(
+        &self,
+        _ctx: Context,
+        _parent: u64,
+        _name: &CStr,
+        _mode: u32,
+        _flags: u32,
+        _umask: u32,
+        _security_ctx: Option<&CStr>,
+    ) -> io::Result<(Entry, Option<u64>, OpenOptions)> {
+        Err(erofs_error())
+    }
+
+    fn read<W: io::Write + ZeroCopyWriter>(
+        &self,
+        _ctx: Context,
+        inode: u64,
+        _handle: u64,
+        mut w: W,
+        size: u32,
+        offset: u64,
+        _lock_owner: Option<u64>,
+        _flags: u32,
+    ) -> io::Result<usize> {
+        let node = self.node(inode)?;
+        let Content::File(extents) = &node.content else {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        };
+        if offset >= node.attr.size {
+            return Ok(0);
+        }
+        let end = min(node.attr.size, offset.saturating_add(size.into()));
+
+        let mut pos = offset;
+        let mut index = extents.partition_point(|e| e.start + e.len <= pos);
+        while pos < end {
+            match extents.get(index) {
+                Some(e) if e.start <= pos => {
+                    let len = min(e.start + e.len, end) - pos;
+                    self.write_extent(&mut w, e, pos, len as usize)?;
+                    pos += len;
+                    index += 1;
+                }
+                next => {
+                    // A hole between extents or after the last one.
+                    let hole_end = next.map_or(end, |e| min(e.start, end));
+                    write_zeroes(&mut w, (hole_end - pos) as usize)?;
+                    pos = hole_end;
+                }
+            }
+        }
+        Ok((end - offset) as usize)
+    }
+
+    fn write<R: io::Read + ZeroCopyReader>(
+        &self,
+        _ctx: Context,
+        _inode: u64,
+        _handle: u64,
+        _r: R,
+        _size: u32,
+        _offset: u64,
+        _lock_owner: Option<u64>,
+        _delayed_write: bool,
+        _flags: u32,
+    ) -> io::Result<usize> {
+        Err(erofs_error())
+    }
+
+    fn fallocate(
+        &self,
+        _ctx: Context,
+        _inode: u64,
+        _handle: u64,
+        _mode: u32,
+        _offset: u64,
+        _length: u64,
+    ) -> io::Result<()> {
+        Err(erofs_error())
+    }
+
+    fn release(
+        &self,
+        _ctx: Context,
+        _inode: u64,
+        _flags: u32,
+        _handle: u64,
+        _flush: bool,
+        _flock_release: bool,
+        _lock_owner: Option<u64>,
+    ) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn setxattr(
+        &self,
+        _ctx: Context,
+        _inode: u64,
+        _name: &CStr,
+        _value: &[u8],
+        _flags: u32,
+    ) -> io::Result<()> {
+        Err(erofs_error())
+    }
+
+    fn removexattr(&self, _ctx: Context, _inode: u64, _name: &CStr) -> io::Result<()> {
+        Err(erofs_error())
+    }
+
+    fn opendir(
+        &self,
+        _ctx: Context,
+        inode: u64,
+        _flags: u32,
+    ) -> io::Result<(Option<u64>, OpenOptions)> {
+        match self.node(inode)?.content {
+            Content::Dir { .. } => Ok((None, OpenOptions::KEEP_CACHE | OpenOptions::CACHE_DIR)),
+            _ => Err(io::Error::from_raw_os_error(libc::ENOTDIR)),
+        }
+    }
+
+    fn readdir(
+        &self,
+        _ctx: Context,
+        inode: u64,
+        _handle: u64,
+        _size: u32,
+        offset: u64,
+    ) -> io::Result<ArchiveDirIter> {
+        match self.node(inode)?.content {
+            Content::Dir { .. } => Ok(ArchiveDirIter {
+                nodes: self.nodes.clone(),
+                inode,
+                offset,
+            }),
+            _ => Err(io::Error::from_raw_os_error(libc::ENOTDIR)),
+        }
+    }
+
+    fn releasedir(&self, _ctx: Context, _inode: u64, _flags: u32, _handle: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn access(&self, _ctx: Context, inode: u64, mask: u32) -> io::Result<()> {
+        self.node(inode)?;
+        if mask & libc::W_OK as u32 != 0 {
+            Err(erofs_error())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Reads the whole contents described by `extents` of a file of `size` bytes, for the loaders
+/// that keep directories and symbolic links in data blocks.
+fn read_extents(image: &File, extents: &[Extent], size: u64) -> io::Result<Vec<u8>> {
+    let len = usize::try_from(size).map_err(|_| invalid_data("file is too large"))?;
+    let mut data = vec![0u8; len];
+    for e in extents {
+        let start = e.start as usize;
+        let end = min(e.start.saturating_add(e.len), size) as usize;
+        if start >= end {
+            continue;
+        }
+        match e.source {
+            Source::Image(offset) => image
+                .read_exact_at(&mut data[start..end], offset)
+                .map_err(|_| invalid_data("truncated image"))?,
+            Source::Block { .. } | Source::Unsupported => {
+                return Err(unsupported("compressed metadata"))
+            }
+        }
+    }
+    Ok(data)
+}
+
+/// Maps the identifiers of the inodes of an image to the inodes of its index, so that hard links
+/// share a node and directories are visited once.
+type InodeMap = BTreeMap<u64, u64>;
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    pub(super) fn ctx() -> Context {
+        Context {
+            uid: 0,
+            gid: 0,
+            pid: 0,
+        }
+    }
+
+    /// Writes `data` to a new unlinked temporary file.
+    pub(super) fn image_file(data: &[u8]) -> File {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(data).unwrap();
+        file
+    }
+
+    /// Looks up the slash-separated `path` from the root.
+    pub(super) fn lookup_path(fs: &ArchiveFs, path: &str) -> io::Result<Entry> {
+        let mut entry = fs.entry(ROOT_ID)?;
+        for name in path.split('/') {
+            let name = CString::new(name).unwrap();
+            entry = fs.lookup(ctx(), entry.inode, &name)?;
+        }
+        Ok(entry)
+    }
+
+    struct VecWriter(Vec<u8>);
+
+    impl io::Write for VecWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl ZeroCopyWriter for VecWriter {
+        fn write_from(&mut self, f: &mut File, count: usize, off: u64) -> io::Result<usize> {
+            let mut buf = vec![0u8; count];
+            let n = f.read_at(&mut buf, off)?;
+            self.0.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+    }
+
+    /// Reads `size` bytes at `offset` of `inode`.
+    pub(super) fn read(fs: &ArchiveFs, inode: u64, offset: u64, size: u32) -> io::Result<Vec<u8>> {
+        let mut w = VecWriter(Vec::new());
+        let len = fs.read(ctx(), inode, 0, &mut w, size, offset, None, 0)?;
+        assert_eq!(len, w.0.len());
+        Ok(w.0)
+    }
+
+    /// Lists the names of the entries of `inode`.
+    pub(super) fn list(fs: &ArchiveFs, inode: u64) -> Vec<String> {
+        let mut iter = fs.readdir(ctx(), inode, 0, 4096, 0).unwrap();
+        let mut names = Vec::new();
+        while let Some(entry) = iter.next() {
+            names.push(entry.name.to_str().unwrap().to_owned());
+        }
+        names
+    }
+
+    #[test]
+    fn read_holes_and_blocks() {
+        let mut data = vec![0u8; 8];
+        data[4..8].copy_from_slice(b"tail");
+        let block = miniz_oxide::deflate::compress_to_vec_zlib(b"0123456789", 6);
+        data.extend_from_slice(&block);
+
+        let mut image = Image::new(Attr {
+            mode: libc::S_IFDIR | 0o755,
+            ..Default::default()
+        });
+        image.compression = Some(Compression::Gzip);
+        image.block_size = 16;
+        let file = image.push(Node {
+            attr: Attr {
+                mode: libc::S_IFREG | 0o644,
+                size: 20,
+                nlink: 1,
+                ..Default::default()
+            },
+            content: Content::File(vec![
+                Extent {
+                    start: 2,
+                    len: 4,
+                    source: Source::Image(4),
+                },
+                Extent {
+                    start: 8,
+                    len: 6,
+                    source: Source::Block {
+                        offset: 8,
+                        size: block.len() as u32,
+                        skip: 4,
+                    },
+                },
+            ]),
+        });
+        let Content::Dir { entries, .. } = &mut image.node_mut(ROOT_ID).unwrap().content else {
+            unreachable!()
+        };
+        entries.push((CString::new("file").unwrap(), file));
+        image.finish();
+
+        let fs = ArchiveFs {
+            format: ArchiveFormat::Tar,
+            image: Mutex::new(image_file(&data)),
+            image_size: data.len() as u64,
+            nodes: image.nodes.into(),
+            compression: image.compression,
+            block_size: image.block_size,
+            timeout: Duration::ZERO,
+            block_cache: Mutex::new(None),
+        };
+
+        let inode = lookup_path(&fs, "file").unwrap().inode;
+        assert_eq!(
+            read(&fs, inode, 0, 100).unwrap(),
+            [&[0; 2][..], b"tail", &[0; 2], b"456789", &[0; 6]].concat()
+        );
+        assert_eq!(read(&fs, inode, 9, 3).unwrap(), b"567");
+        assert_eq!(read(&fs, inode, 20, 3).unwrap(), b"");
+        assert_eq!(list(&fs, ROOT_ID), [".", "..", "file"]);
+        assert_eq!(
+            fs.open(ctx(), inode, libc::O_RDWR as u32)
+                .unwrap_err()
+                .raw_os_error(),
+            Some(libc::EROFS)
+        );
+    }
+}
@@ -0,0 +1,741 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Indexing of squashfs 4.0 images.
+
+use std::cmp::min;
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::fs::File;
+use std::io;
+use std::rc::Rc;
+
+use super::check_name;
+use super::decode_dev;
+use super::invalid_data;
+use super::read_at;
+use super::unsupported;
+use super::Attr;
+use super::Bytes;
+use super::Compression;
+use super::Content;
+use super::Extent;
+use super::Image;
+use super::InodeMap;
+use super::Node;
+use super::Source;
+use super::ROOT_ID;
+
+const MAGIC: &[u8] = b"hsqs";
+const SUPERBLOCK_SIZE: usize = 96;
+const METADATA_SIZE: usize = 8192;
+const UNCOMPRESSED_METADATA: u16 = 1 << 15;
+const UNCOMPRESSED_BLOCK: u32 = 1 << 24;
+const NO_FRAGMENT: u32 = 0xffff_ffff;
+const MAX_DIR_HEADER_ENTRIES: u32 = 256;
+
+const GZIP: u16 = 1;
+const LZ4: u16 = 5;
+
+const BASIC_DIR: u16 = 1;
+const BASIC_FILE: u16 = 2;
+const BASIC_SYMLINK: u16 = 3;
+const BASIC_BLKDEV: u16 = 4;
+const BASIC_CHRDEV: u16 = 5;
+const BASIC_FIFO: u16 = 6;
+const BASIC_SOCKET: u16 = 7;
+const EXT_DIR: u16 = 8;
+const EXT_FILE: u16 = 9;
+const EXT_SYMLINK: u16 = 10;
+const EXT_BLKDEV: u16 = 11;
+const EXT_CHRDEV: u16 = 12;
+const EXT_FIFO: u16 = 13;
+const EXT_SOCKET: u16 = 14;
+
+/// Returns whether `header` starts with a squashfs superblock.
+pub(super) fn is_squashfs(header: &[u8]) -> bool {
+    header.starts_with(MAGIC)
+}
+
+struct Superblock {
+    mod_time: u32,
+    block_size: u32,
+    fragment_count: u32,
+    compressor: u16,
+    id_count: u16,
+    root_inode: u64,
+    id_table: u64,
+    inode_table: u64,
+    dir_table: u64,
+    fragment_table: u64,
+}
+
+impl Superblock {
+    fn parse(buf: &[u8]) -> io::Result<Superblock> {
+        let b = Bytes::new(buf);
+        if b.slice(0, 4)? != MAGIC {
+            return Err(invalid_data("not a squashfs image"));
+        }
+        let (major, minor) = (b.u16(28)?, b.u16(30)?);
+        if (major, minor) != (4, 0) {
+            return Err(unsupported(format!(
+                "unsupported squashfs version {}.{}",
+                major, minor
+            )));
+        }
+        let block_size = b.u32(12)?;
+        let block_log = b.u16(22)?;
+        if !(12..=20).contains(&block_log) || block_size != 1 << block_log {
+            return Err(invalid_data("invalid squashfs block size"));
+        }
+
+        Ok(Superblock {
+            mod_time: b.u32(8)?,
+            block_size,
+            fragment_count: b.u32(16)?,
+            compressor: b.u16(20)?,
+            id_count: b.u16(26)?,
+            root_inode: b.u64(32)?,
+            id_table: b.u64(48)?,
+            inode_table: b.u64(64)?,
+            dir_table: b.u64(72)?,
+            fragment_table: b.u64(80)?,
+        })
+    }
+}
+
+/// A position in a metadata table: the offset of a metadata block in the image and an offset in
+/// its decompressed contents.
+#[derive(Clone, Copy)]
+struct Cursor {
+    block: u64,
+    offset: usize,
+}
+
+/// The location of the listing of a directory in the directory table.
+#[derive(Clone, Copy)]
+struct Listing {
+    block: u32,
+    offset: u16,
+    size: u32,
+}
+
+struct Loader<'a> {
+    file: &'a File,
+    file_len: u64,
+    sb: Superblock,
+    compression: Option<Compression>,
+    // The decompressed metadata blocks read so far and the offsets of the blocks that follow them.
+    blocks: BTreeMap<u64, (Rc<[u8]>, u64)>,
+    ids: Vec<u32>,
+    fragments: Vec<(u64, u32)>,
+}
+
+impl<'a> Loader<'a> {
+    fn decompress(&self, data: &[u8], max: usize) -> io::Result<Vec<u8>> {
+        match self.compression {
+            Some(compression) => compression.decompress(data, max),
+            None => Err(unsupported(format!(
+                "unsupported squashfs compressor {}",
+                self.sb.compressor
+            ))),
+        }
+    }
+
+    /// Returns the contents of the metadata block at `pos` and the offset of the next block.
+    fn block(&mut self, pos: u64) -> io::Result<(Rc<[u8]>, u64)> {
+        if let Some((data, next)) = self.blocks.get(&pos) {
+            return Ok((data.clone(), *next));
+        }
+
+        let header = Bytes::new(&read_at(self.file, pos, 2)?).u16(0)?;
+        let size = usize::from(header & !UNCOMPRESSED_METADATA);
+        if size == 0 || size > METADATA_SIZE {
+            return Err(invalid_data("invalid squashfs metadata block"));
+        }
+        let data = read_at(self.file, pos + 2, size)?;
+        let data: Rc<[u8]> = if header & UNCOMPRESSED_METADATA != 0 {
+            data.into()
+        } else {
+            self.decompress(&data, METADATA_SIZE)?.into()
+        };
+        if data.is_empty() {
+            return Err(invalid_data("empty squashfs metadata block"));
+        }
+        let next = pos + 2 + size as u64;
+        self.blocks.insert(pos, (data.clone(), next));
+        Ok((data, next))
+    }
+
+    /// Reads `len` bytes of metadata at `cur` and advances it.
+    fn read(&mut self, cur: &mut Cursor, len: usize) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        while out.len() < len {
+            let (data, next) = self.block(cur.block)?;
+            if cur.offset >= data.len() {
+                if cur.offset > data.len() {
+                    return Err(invalid_data("invalid squashfs metadata offset"));
+                }
+                *cur = Cursor {
+                    block: next,
+                    offset: 0,
+                };
+                continue;
+            }
+            let n = min(len - out.len(), data.len() - cur.offset);
+            out.extend_from_slice(&data[cur.offset..cur.offset + n]);
+            cur.offset += n;
+        }
+        Ok(out)
+    }
+
+    /// Reads a lookup table of `len` bytes, stored in the metadata blocks listed at `pos`.
+    fn table(&mut self, pos: u64, len: usize) -> io::Result<Vec<u8>> {
+        let count = len.div_ceil(METADATA_SIZE);
+        if count as u64 * 8 > self.file_len {
+            return Err(invalid_data("invalid squashfs lookup table"));
+        }
+        let index = read_at(self.file, pos, count * 8)?;
+        let mut data = Vec::new();
+        for i in 0..count {
+            let (block, _) = self.block(Bytes::new(&index).u64(i * 8)?)?;
+            data.extend_from_slice(&block);
+        }
+        if data.len() < len {
+            return Err(invalid_data("truncated squashfs lookup table"));
+        }
+        data.truncate(len);
+        Ok(data)
+    }
+
+    fn id(&self, index: u16) -> io::Result<u32> {
+        self.ids
+            .get(usize::from(index))
+            .copied()
+            .ok_or_else(|| invalid_data("invalid squashfs id index"))
+    }
+
+    /// Parses the inode at `reference`, returning its number, its node and, for directories, the
+    /// location of its listing.
+    fn inode(&mut self, reference: u64) -> io::Result<(u32, Node, Option<Listing>)> {
+        let mut cur = Cursor {
+            block: self.sb.inode_table + (reference >> 16),
+            offset: (reference & 0xffff) as usize,
+        };
+        let header = self.read(&mut cur, 16)?;
+        let h = Bytes::new(&header);
+        let kind = h.u16(0)?;
+        let mut attr = Attr {
+            mode: u32::from(h.u16(2)?) & 0o7777,
+            uid: self.id(h.u16(4)?)?,
+            gid: self.id(h.u16(6)?)?,
+            nlink: 1,
+            mtime: h.u32(8)?.into(),
+            ..Default::default()
+        };
+        let number = h.u32(12)?;
+
+        let mut listing = None;
+        let content = match kind {
+            BASIC_DIR | EXT_DIR => {
+                let (block, nlink, size, offset) = if kind == BASIC_DIR {
+                    let buf = self.read(&mut cur, 16)?;
+                    let b = Bytes::new(&buf);
+                    (b.u32(0)?, b.u32(4)?, u32::from(b.u16(8)?), b.u16(10)?)
+                } else {
+                    let buf = self.read(&mut cur, 24)?;
+                    let b = Bytes::new(&buf);
+                    (b.u32(8)?, b.u32(0)?, b.u32(4)?, b.u16(18)?)
+                };
+                attr.mode |= libc::S_IFDIR;
+                attr.nlink = nlink;
+                attr.size = size.into();
+                listing = Some(Listing {
+                    block,
+                    offset,
+                    size,
+                });
+                Content::Dir {
+                    parent: ROOT_ID,
+                    entries: Vec::new(),
+                }
+            }
+            BASIC_FILE | EXT_FILE => {
+                let (blocks_start, size, fragment, fragment_offset) = if kind == BASIC_FILE {
+                    let buf = self.read(&mut cur, 16)?;
+                    let b = Bytes::new(&buf);
+                    (b.u32(0)?.into(), b.u32(12)?.into(), b.u32(4)?, b.u32(8)?)
+                } else {
+                    let buf = self.read(&mut cur, 40)?;
+                    let b = Bytes::new(&buf);
+                    attr.nlink = b.u32(24)?;
+                    (b.u64(0)?, b.u64(8)?, b.u32(28)?, b.u32(32)?)
+                };
+                attr.mode |= libc::S_IFREG;
+                attr.size = size;
+                Content::File(self.extents(
+                    &mut cur,
+                    blocks_start,
+                    size,
+                    fragment,
+                    fragment_offset,
+                )?)
+            }
+            BASIC_SYMLINK | EXT_SYMLINK => {
+                let buf = self.read(&mut cur, 8)?;
+                let b = Bytes::new(&buf);
+                attr.nlink = b.u32(0)?;
+                let len = b.u32(4)? as usize;
+                if len > libc::PATH_MAX as usize {
+                    return Err(invalid_data("squashfs symlink target is too long"));
+                }
+                let target = self.read(&mut cur, len)?;
+                attr.mode |= libc::S_IFLNK;
+                attr.size = target.len() as u64;
+                Content::Symlink(target)
+            }
+            BASIC_BLKDEV | BASIC_CHRDEV | EXT_BLKDEV | EXT_CHRDEV => {
+                let buf = self.read(&mut cur, 8)?;
+                let b = Bytes::new(&buf);
+                attr.nlink = b.u32(0)?;
+                attr.rdev = decode_dev(b.u32(4)?);
+                attr.mode |= match kind {
+                    BASIC_BLKDEV | EXT_BLKDEV => libc::S_IFBLK,
+                    _ => libc::S_IFCHR,
+                };
+                Content::Special
+            }
+            BASIC_FIFO | BASIC_SOCKET | EXT_FIFO | EXT_SOCKET => {
+                let buf = self.read(&mut cur, 4)?;
+                attr.nlink = Bytes::new(&buf).u32(0)?;
+                attr.mode |= match kind {
+                    BASIC_FIFO | EXT_FIFO => libc::S_IFIFO,
+                    _ => libc::S_IFSOCK,
+                };
+                Content::Special
+            }
+            _ => {
+                return Err(invalid_data(format!(
+                    "invalid squashfs inode type {}",
+                    kind
+                )))
+            }
+        };
+        Ok((number, Node { attr, content }, listing))
+    }
+
+    /// Reads the block list of a file at `cur` and maps the file to its data blocks and fragment.
+    fn extents(
+        &mut self,
+        cur: &mut Cursor,
+        blocks_start: u64,
+        size: u64,
+        fragment: u32,
+        fragment_offset: u32,
+    ) -> io::Result<Vec<Extent>> {
+        let block_size = u64::from(self.sb.block_size);
+        let count = if fragment == NO_FRAGMENT {
+            size.div_ceil(block_size)
+        } else {
+            size / block_size
+        };
+        // Each block needs 4 bytes in the block list.
+        if count.saturating_mul(4) > self.file_len {
+            return Err(invalid_data("invalid squashfs file size"));
+        }
+        let sizes = self.read(cur, count as usize * 4)?;
+
+        let mut extents = Vec::new();
+        let mut pos = blocks_start;
+        for (i, stored) in sizes.chunks_exact(4).enumerate() {
+            let stored = u32::from_le_bytes(stored.try_into().unwrap());
+            let on_disk = stored & !UNCOMPRESSED_BLOCK;
+            if u64::from(on_disk) > block_size {
+                return Err(invalid_data("invalid squashfs block size"));
+            }
+            let start = i as u64 * block_size;
+            // Blocks of zero bytes are holes.
+            if on_disk != 0 {
+                extents.push(Extent {
+                    start,
+                    len: min(block_size, size - start),
+                    source: if stored & UNCOMPRESSED_BLOCK != 0 {
+                        Source::Image(pos)
+                    } else {
+                        Source::Block {
+                            offset: pos,
+                            size: on_disk,
+                            skip: 0,
+                        }
+                    },
+                });
+            }
+            pos += u64::from(on_disk);
+        }
+
+        let start = count * block_size;
+        if start < size {
+            let (offset, stored) = *self
+                .fragments
+                .get(fragment as usize)
+                .ok_or_else(|| invalid_data("invalid squashfs fragment index"))?;
+            let on_disk = stored & !UNCOMPRESSED_BLOCK;
+            if u64::from(fragment_offset) + (size - start) > block_size {
+                return Err(invalid_data("invalid squashfs fragment offset"));
+            }
+            extents.push(Extent {
+                start,
+                len: size - start,
+                source: if stored & UNCOMPRESSED_BLOCK != 0 {
+                    Source::Image(offset + u64::from(fragment_offset))
+                } else {
+                    Source::Block {
+                        offset,
+                        size: on_disk,
+                        skip: fragment_offset,
+                    }
+                },
+            });
+        }
+        Ok(extents)
+    }
+
+    /// Reads the names and inode references of the entries of a directory.
+    fn listing(&mut self, listing: Listing) -> io::Result<Vec<(Vec<u8>, u64)>> {
+        let mut cur = Cursor {
+            block: self.sb.dir_table + u64::from(listing.block),
+            offset: listing.offset.into(),
+        };
+        // The size counts the "." and ".." entries, which are not stored, as 3 bytes.
+        let mut remaining = listing.size.saturating_sub(3) as usize;
+        let mut entries = Vec::new();
+        while remaining > 0 {
+            if remaining < 12 {
+                return Err(invalid_data("invalid squashfs directory"));
+            }
+            let buf = self.read(&mut cur, 12)?;
+            let header = Bytes::new(&buf);
+            let count = header.u32(0)? + 1;
+            let block = header.u32(4)?;
+            remaining -= 12;
+            if count > MAX_DIR_HEADER_ENTRIES {
+                return Err(invalid_data("invalid squashfs directory"));
+            }
+
+            for _ in 0..count {
+                if remaining < 8 {
+                    return Err(invalid_data("invalid squashfs directory"));
+                }
+                let buf = self.read(&mut cur, 8)?;
+                let entry = Bytes::new(&buf);
+                let offset = entry.u16(0)?;
+                let name_len = usize::from(entry.u16(6)?) + 1;
+                remaining -= 8;
+                if remaining < name_len {
+                    return Err(invalid_data("invalid squashfs directory"));
+                }
+                let name = self.read(&mut cur, name_len)?;
+                remaining -= name_len;
+                entries.push((name, (u64::from(block) << 16) | u64::from(offset)));
+            }
+        }
+        Ok(entries)
+    }
+}
+
+/// Indexes the squashfs image `image`.
+pub(super) fn load(image: &File) -> io::Result<Image> {
+    let sb = Superblock::parse(&read_at(image, 0, SUPERBLOCK_SIZE)?)?;
+    let compression = match sb.compressor {
+        GZIP => Some(Compression::Gzip),
+        LZ4 => Some(Compression::Lz4),
+        _ => None,
+    };
+    let mut loader = Loader {
+        file: image,
+        file_len: image.metadata()?.len(),
+        sb,
+        compression,
+        blocks: BTreeMap::new(),
+        ids: Vec::new(),
+        fragments: Vec::new(),
+    };
+
+    let ids = loader.table(loader.sb.id_table, usize::from(loader.sb.id_count) * 4)?;
+    loader.ids = ids
+        .chunks_exact(4)
+        .map(|id| u32::from_le_bytes(id.try_into().unwrap()))
+        .collect();
+    if loader.sb.fragment_count > 0 {
+        let fragments = loader.table(
+            loader.sb.fragment_table,
+            loader.sb.fragment_count as usize * 16,
+        )?;
+        loader.fragments = fragments
+            .chunks_exact(16)
+            .map(|entry| {
+                let entry = Bytes::new(entry);
+                Ok((entry.u64(0)?, entry.u32(8)?))
+            })
+            .collect::<io::Result<_>>()?;
+    }
+
+    let (number, mut root, listing) = loader.inode(loader.sb.root_inode)?;
+    let listing = listing.ok_or_else(|| invalid_data("squashfs root is not a directory"))?;
+    if root.attr.mtime == 0 {
+        root.attr.mtime = loader.sb.mod_time.into();
+    }
+    let mut index = Image::new(root.attr);
+    index.compression = loader.compression;
+    index.block_size = loader.sb.block_size;
+
+    let mut inodes = InodeMap::new();
+    inodes.insert(number.into(), ROOT_ID);
+    let mut pending = vec![(ROOT_ID, listing)];
+    while let Some((dir, listing)) = pending.pop() {
+        let mut children = Vec::new();
+        for (name, reference) in loader.listing(listing)? {
+            check_name(&name)?;
+            let (number, mut node, listing) = loader.inode(reference)?;
+            let child = match inodes.get(&number.into()) {
+                Some(child) => *child,
+                None => {
+                    if let Content::Dir { parent, .. } = &mut node.content {
+                        *parent = dir;
+                    }
+                    let child = index.push(node);
+                    inodes.insert(number.into(), child);
+                    if let Some(listing) = listing {
+                        pending.push((child, listing));
+                    }
+                    child
+                }
+            };
+            children.push((CString::new(name).unwrap(), child));
+        }
+        if let Some(Node {
+            content: Content::Dir { entries, .. },
+            ..
+        }) = index.node_mut(dir)
+        {
+            *entries = children;
+        }
+    }
+
+    Ok(index)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::super::tests::*;
+    use super::super::ArchiveFormat;
+    use super::super::ArchiveFs;
+    use super::*;
+    use crate::filesystem::FileSystem;
+
+    fn put16(buf: &mut Vec<u8>, v: u16) {
+        buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn put32(buf: &mut Vec<u8>, v: u32) {
+        buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn put64(buf: &mut Vec<u8>, v: u64) {
+        buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn inode_header(buf: &mut Vec<u8>, kind: u16, perm: u16, number: u32) {
+        put16(buf, kind);
+        put16(buf, perm);
+        put16(buf, 1);
+        put16(buf, 0);
+        put32(buf, 1_700_000_000);
+        put32(buf, number);
+    }
+
+    fn metadata(buf: &mut Vec<u8>, contents: &[u8], compress: bool) {
+        if compress {
+            let compressed = miniz_oxide::deflate::compress_to_vec_zlib(contents, 6);
+            put16(buf, compressed.len() as u16);
+            buf.extend_from_slice(&compressed);
+        } else {
+            put16(buf, contents.len() as u16 | UNCOMPRESSED_METADATA);
+            buf.extend_from_slice(contents);
+        }
+    }
+
+    fn dir_entry(buf: &mut Vec<u8>, offset: u16, delta: i16, kind: u16, name: &str) {
+        put16(buf, offset);
+        buf.extend_from_slice(&delta.to_le_bytes());
+        put16(buf, kind);
+        put16(buf, name.len() as u16 - 1);
+        buf.extend_from_slice(name.as_bytes());
+    }
+
+    /// Builds an image with "hello", "big" (one compressed block and a tail in a compressed
+    /// fragment), "null" (a device) and "sub" holding "big2" (a hard link to "big") and "link".
+    fn build() -> (Vec<u8>, Vec<u8>) {
+        let big: Vec<u8> = (0..4196u32).map(|i| (i % 251) as u8).collect();
+        let mut image = vec![0u8; SUPERBLOCK_SIZE];
+
+        let hello_start = image.len() as u64;
+        image.extend_from_slice(b"hello world");
+        let big_start = image.len() as u64;
+        let block = miniz_oxide::deflate::compress_to_vec_zlib(&big[..4096], 6);
+        image.extend_from_slice(&block);
+        let fragment_start = image.len() as u64;
+        let fragment = [&b"padding"[..], &big[4096..]].concat();
+        let fragment = miniz_oxide::deflate::compress_to_vec_zlib(&fragment, 6);
+        image.extend_from_slice(&fragment);
+
+        // Inodes: root at 0, hello at 32, big at 68, sub at 128, link at 160, null at 192.
+        let mut inodes = Vec::new();
+        inode_header(&mut inodes, BASIC_DIR, 0o755, 1);
+        put32(&mut inodes, 0);
+        put32(&mut inodes, 3);
+        put16(&mut inodes, 62);
+        put16(&mut inodes, 0);
+        put32(&mut inodes, 7);
+        inode_header(&mut inodes, BASIC_FILE, 0o644, 2);
+        put32(&mut inodes, hello_start as u32);
+        put32(&mut inodes, NO_FRAGMENT);
+        put32(&mut inodes, 0);
+        put32(&mut inodes, 11);
+        put32(&mut inodes, 11 | UNCOMPRESSED_BLOCK);
+        inode_header(&mut inodes, EXT_FILE, 0o600, 3);
+        put64(&mut inodes, big_start);
+        put64(&mut inodes, big.len() as u64);
+        put64(&mut inodes, 0);
+        put32(&mut inodes, 2);
+        put32(&mut inodes, 0);
+        put32(&mut inodes, 7);
+        put32(&mut inodes, 0xffff_ffff);
+        put32(&mut inodes, block.len() as u32);
+        inode_header(&mut inodes, BASIC_DIR, 0o700, 4);
+        put32(&mut inodes, 0);
+        put32(&mut inodes, 2);
+        put16(&mut inodes, 39);
+        put16(&mut inodes, 59);
+        put32(&mut inodes, 1);
+        inode_header(&mut inodes, BASIC_SYMLINK, 0o777, 5);
+        put32(&mut inodes, 1);
+        put32(&mut inodes, 8);
+        inodes.extend_from_slice(b"../hello");
+        inode_header(&mut inodes, BASIC_CHRDEV, 0o666, 6);
+        put32(&mut inodes, 1);
+        put32(&mut inodes, (1 << 8) | 3);
+        assert_eq!(inodes.len(), 216);
+
+        let mut dirs = Vec::new();
+        put32(&mut dirs, 3);
+        put32(&mut dirs, 0);
+        put32(&mut dirs, 1);
+        dir_entry(&mut dirs, 68, 2, BASIC_FILE, "big");
+        dir_entry(&mut dirs, 32, 1, BASIC_FILE, "hello");
+        dir_entry(&mut dirs, 192, 5, BASIC_CHRDEV, "null");
+        dir_entry(&mut dirs, 128, 3, BASIC_DIR, "sub");
+        assert_eq!(dirs.len(), 59);
+        put32(&mut dirs, 1);
+        put32(&mut dirs, 0);
+        put32(&mut dirs, 3);
+        dir_entry(&mut dirs, 68, 0, BASIC_FILE, "big2");
+        dir_entry(&mut dirs, 160, 2, BASIC_SYMLINK, "link");
+
+        let inode_table = image.len() as u64;
+        metadata(&mut image, &inodes, false);
+        let dir_table = image.len() as u64;
+        metadata(&mut image, &dirs, true);
+
+        let fragment_block = image.len() as u64;
+        let mut entry = Vec::new();
+        put64(&mut entry, fragment_start);
+        put32(&mut entry, fragment.len() as u32);
+        put32(&mut entry, 0);
+        metadata(&mut image, &entry, false);
+        let fragment_table = image.len() as u64;
+        put64(&mut image, fragment_block);
+
+        let id_block = image.len() as u64;
+        let mut ids = Vec::new();
+        put32(&mut ids, 0);
+        put32(&mut ids, 1000);
+        metadata(&mut image, &ids, false);
+        let id_table = image.len() as u64;
+        put64(&mut image, id_block);
+
+        let mut sb = Vec::new();
+        sb.extend_from_slice(MAGIC);
+        put32(&mut sb, 6);
+        put32(&mut sb, 1_700_000_000);
+        put32(&mut sb, 4096);
+        put32(&mut sb, 1);
+        put16(&mut sb, GZIP);
+        put16(&mut sb, 12);
+        put16(&mut sb, 0);
+        put16(&mut sb, 2);
+        put16(&mut sb, 4);
+        put16(&mut sb, 0);
+        put64(&mut sb, 0);
+        put64(&mut sb, image.len() as u64);
+        put64(&mut sb, id_table);
+        put64(&mut sb, u64::MAX);
+        put64(&mut sb, inode_table);
+        put64(&mut sb, dir_table);
+        put64(&mut sb, fragment_table);
+        put64(&mut sb, u64::MAX);
+        image[..SUPERBLOCK_SIZE].copy_from_slice(&sb);
+
+        (image, big)
+    }
+
+    #[test]
+    fn squashfs_image() {
+        let (image, big) = build();
+        let file = image_file(&image);
+        assert_eq!(
+            ArchiveFormat::detect(&file).unwrap(),
+            ArchiveFormat::Squashfs
+        );
+        let fs = ArchiveFs::new(file, Duration::ZERO).unwrap();
+
+        assert_eq!(
+            list(&fs, ROOT_ID),
+            [".", "..", "big", "hello", "null", "sub"]
+        );
+        let hello = lookup_path(&fs, "hello").unwrap();
+        assert_eq!(hello.attr.st_mode, libc::S_IFREG | 0o644);
+        assert_eq!(hello.attr.st_uid, 1000);
+        assert_eq!(hello.attr.st_mtime, 1_700_000_000);
+        assert_eq!(read(&fs, hello.inode, 0, 100).unwrap(), b"hello world");
+
+        let entry = lookup_path(&fs, "big").unwrap();
+        assert_eq!(entry.attr.st_nlink, 2);
+        assert_eq!(read(&fs, entry.inode, 0, 8192).unwrap(), big);
+        assert_eq!(
+            read(&fs, entry.inode, 4090, 10).unwrap(),
+            big[4090..4100].to_vec()
+        );
+        assert_eq!(lookup_path(&fs, "sub/big2").unwrap().inode, entry.inode);
+
+        let link = lookup_path(&fs, "sub/link").unwrap();
+        assert_eq!(fs.readlink(ctx(), link.inode).unwrap(), b"../hello");
+        let sub = lookup_path(&fs, "sub").unwrap();
+        assert_eq!(lookup_path(&fs, "sub/..").unwrap().inode, ROOT_ID);
+        assert_eq!(sub.attr.st_mode, libc::S_IFDIR | 0o700);
+
+        let null = lookup_path(&fs, "null").unwrap();
+        assert_eq!(null.attr.st_mode, libc::S_IFCHR | 0o666);
+        assert_eq!(null.attr.st_rdev, libc::makedev(1, 3));
+    }
+
+    #[test]
+    fn truncated_image() {
+        let (image, _) = build();
+        let file = image_file(&image[..image.len() - 20]);
+        assert!(ArchiveFs::new(file, Duration::ZERO).is_err());
+    }
+}
@@ -0,0 +1,570 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Indexing of POSIX (ustar, pax) and GNU tar archives.
+
+use std::ffi::CString;
+use std::fs::File;
+use std::io;
+use std::str::FromStr;
+
+use super::check_name;
+use super::invalid_data;
+use super::read_at;
+use super::unsupported;
+use super::Attr;
+use super::Content;
+use super::Extent;
+use super::Image;
+use super::Node;
+use super::Source;
+use super::ROOT_ID;
+
+const BLOCK: u64 = 512;
+
+/// Returns whether `header` starts with a ustar or GNU tar header.
+pub(super) fn is_tar(header: &[u8]) -> bool {
+    header.len() >= BLOCK as usize && &header[257..262] == b"ustar" && checksum_ok(header)
+}
+
+fn checksum_ok(header: &[u8]) -> bool {
+    let Ok(expected) = parse_number(&header[148..156]) else {
+        return false;
+    };
+    let sum: u64 = header[..BLOCK as usize]
+        .iter()
+        .enumerate()
+        .map(|(i, b)| if (148..156).contains(&i) { b' ' } else { *b } as u64)
+        .sum();
+    sum == expected
+}
+
+/// Parses a numeric header field, in octal or in the GNU base-256 encoding.
+fn parse_number(field: &[u8]) -> io::Result<u64> {
+    if field.first().is_some_and(|b| b & 0x80 != 0) {
+        if field[0] & 0x40 != 0 {
+            return Err(invalid_data("negative number in tar header"));
+        }
+        let mut value = u64::from(field[0] & 0x3f);
+        for b in &field[1..] {
+            value = value
+                .checked_mul(256)
+                .and_then(|v| v.checked_add(u64::from(*b)))
+                .ok_or_else(|| invalid_data("number overflow in tar header"))?;
+        }
+        return Ok(value);
+    }
+
+    let digits = field
+        .iter()
+        .skip_while(|b| **b == b' ')
+        .take_while(|b| (b'0'..=b'7').contains(*b));
+    let mut value = 0u64;
+    for b in digits {
+        value = value
+            .checked_mul(8)
+            .map(|v| v + u64::from(b - b'0'))
+            .ok_or_else(|| invalid_data("number overflow in tar header"))?;
+    }
+    Ok(value)
+}
+
+/// Returns `field` up to its first NUL byte.
+fn cstr_field(field: &[u8]) -> &[u8] {
+    field.split(|b| *b == 0).next().unwrap_or_default()
+}
+
+/// The fields of a pax extended header that override the ones of the next entry.
+#[derive(Default)]
+struct Pax {
+    path: Option<Vec<u8>>,
+    linkpath: Option<Vec<u8>>,
+    size: Option<u64>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    mtime: Option<(i64, i64)>,
+}
+
+impl Pax {
+    /// Parses the "LENGTH KEY=VALUE\n" records of a pax extended header.
+    fn parse(mut data: &[u8]) -> io::Result<Pax> {
+        let mut pax = Pax::default();
+        while !data.is_empty() && data[0] != 0 {
+            let space = data
+                .iter()
+                .position(|b| *b == b' ')
+                .ok_or_else(|| invalid_data("malformed pax record"))?;
+            let len: usize = std::str::from_utf8(&data[..space])
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .filter(|len| *len > space && *len <= data.len())
+                .ok_or_else(|| invalid_data("malformed pax record"))?;
+            let record = data[space + 1..len]
+                .strip_suffix(b"\n")
+                .ok_or_else(|| invalid_data("malformed pax record"))?;
+            data = &data[len..];
+
+            let eq = record
+                .iter()
+                .position(|b| *b == b'=')
+                .ok_or_else(|| invalid_data("malformed pax record"))?;
+            let (key, value) = (&record[..eq], &record[eq + 1..]);
+            match key {
+                b"path" => pax.path = Some(value.to_vec()),
+                b"linkpath" => pax.linkpath = Some(value.to_vec()),
+                b"size" => pax.size = Some(parse_pax_number(value)?),
+                b"uid" => pax.uid = Some(parse_pax_number(value)?),
+                b"gid" => pax.gid = Some(parse_pax_number(value)?),
+                b"mtime" => pax.mtime = Some(parse_time(value)?),
+                _ => {}
+            }
+        }
+        Ok(pax)
+    }
+}
+
+fn parse_pax_number<T: FromStr>(value: &[u8]) -> io::Result<T> {
+    std::str::from_utf8(value)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| invalid_data("malformed pax number"))
+}
+
+/// Parses a pax time, in seconds with an optional fraction.
+fn parse_time(value: &[u8]) -> io::Result<(i64, i64)> {
+    let value = std::str::from_utf8(value).map_err(|_| invalid_data("malformed pax time"))?;
+    let (secs, frac) = value.split_once('.').unwrap_or((value, ""));
+    let secs = secs
+        .parse()
+        .map_err(|_| invalid_data("malformed pax time"))?;
+    let mut nsec = 0i64;
+    for (i, digit) in frac.bytes().take(9).enumerate() {
+        if !digit.is_ascii_digit() {
+            return Err(invalid_data("malformed pax time"));
+        }
+        nsec += i64::from(digit - b'0') * 10i64.pow(8 - i as u32);
+    }
+    Ok((secs, nsec))
+}
+
+/// Splits `path` into its components, ignoring empty and "." components.
+fn components(path: &[u8]) -> io::Result<Vec<&[u8]>> {
+    let components: Vec<&[u8]> = path
+        .split(|b| *b == b'/')
+        .filter(|c| !c.is_empty() && *c != b".")
+        .collect();
+    for c in &components {
+        check_name(c)?;
+    }
+    Ok(components)
+}
+
+/// Builds the index of an archive from its paths.
+struct Builder {
+    image: Image,
+}
+
+impl Builder {
+    /// Returns the directory at `path`, creating the missing ones.
+    fn dir(&mut self, path: &[&[u8]]) -> io::Result<u64> {
+        let mut dir = ROOT_ID;
+        for name in path {
+            dir = match self.child(dir, name)? {
+                Some(child) => child,
+                None => {
+                    let child = self.image.push(Node {
+                        attr: Attr {
+                            mode: libc::S_IFDIR | 0o755,
+                            ..Default::default()
+                        },
+                        content: Content::Dir {
+                            parent: dir,
+                            entries: Vec::new(),
+                        },
+                    });
+                    self.add_entry(dir, name, child);
+                    child
+                }
+            };
+        }
+        Ok(dir)
+    }
+
+    /// Returns the inode of the entry `name` of `dir`, which must be a directory.
+    fn child(&self, dir: u64, name: &[u8]) -> io::Result<Option<u64>> {
+        let node = self.image.node(dir).unwrap();
+        let Content::Dir { entries, .. } = &node.content else {
+            return Err(invalid_data("tar entry below a non-directory"));
+        };
+        let child = entries
+            .iter()
+            .rev()
+            .find(|(n, _)| n.as_bytes() == name)
+            .map(|(_, inode)| *inode);
+        match child.map(|inode| &self.image.node(inode).unwrap().content) {
+            Some(Content::Dir { .. }) | None => Ok(child),
+            Some(_) => Err(invalid_data("tar entry below a non-directory")),
+        }
+    }
+
+    fn add_entry(&mut self, dir: u64, name: &[u8], inode: u64) {
+        let Content::Dir { entries, .. } = &mut self.image.node_mut(dir).unwrap().content else {
+            unreachable!()
+        };
+        // Names were checked for NUL bytes when they were parsed.
+        entries.retain(|(n, _)| n.as_bytes() != name);
+        entries.push((CString::new(name).unwrap(), inode));
+    }
+
+    /// Adds `node` at `path`, replacing any earlier entry of the same name except directories,
+    /// whose contents are kept.
+    fn insert(&mut self, path: &[&[u8]], mut node: Node) -> io::Result<()> {
+        let Some((name, parent)) = path.split_last() else {
+            // The root directory itself.
+            if let Content::Dir { .. } = node.content {
+                self.image.node_mut(ROOT_ID).unwrap().attr = node.attr;
+                return Ok(());
+            }
+            return Err(invalid_data("tar entry replaces the root directory"));
+        };
+        let dir = self.dir(parent)?;
+        let existing = self
+            .image
+            .node(dir)
+            .and_then(|n| match &n.content {
+                Content::Dir { entries, .. } => entries.iter().find(|(n, _)| n.as_bytes() == *name),
+                _ => None,
+            })
+            .map(|(_, inode)| *inode);
+        if let (Some(existing), Content::Dir { .. }) = (existing, &node.content) {
+            let old = self.image.node_mut(existing).unwrap();
+            if let Content::Dir { .. } = old.content {
+                old.attr = node.attr;
+                return Ok(());
+            }
+        }
+        if let Content::Dir { parent, .. } = &mut node.content {
+            *parent = dir;
+        }
+        let inode = self.image.push(node);
+        self.add_entry(dir, name, inode);
+        Ok(())
+    }
+
+    /// Adds a hard link at `path` to the entry at `target`.
+    fn link(&mut self, path: &[&[u8]], target: &[&[u8]]) -> io::Result<()> {
+        let mut inode = ROOT_ID;
+        for name in target {
+            inode = self
+                .child_any(inode, name)
+                .ok_or_else(|| invalid_data("hard link to a missing tar entry"))?;
+        }
+        let node = self.image.node_mut(inode).unwrap();
+        if let Content::Dir { .. } = node.content {
+            return Err(invalid_data("hard link to a directory"));
+        }
+        node.attr.nlink += 1;
+
+        let (name, parent) = path
+            .split_last()
+            .ok_or_else(|| invalid_data("hard link replaces the root directory"))?;
+        let dir = self.dir(parent)?;
+        self.add_entry(dir, name, inode);
+        Ok(())
+    }
+
+    fn child_any(&self, dir: u64, name: &[u8]) -> Option<u64> {
+        match &self.image.node(dir)?.content {
+            Content::Dir { entries, .. } => entries
+                .iter()
+                .find(|(n, _)| n.as_bytes() == name)
+                .map(|(_, inode)| *inode),
+            _ => None,
+        }
+    }
+
+    /// Counts the links of the directories, which tar archives do not record.
+    fn finish(mut self) -> Image {
+        let mut links = vec![2u32; self.image.nodes.len()];
+        for node in &self.image.nodes {
+            if let Content::Dir { parent, .. } = node.content {
+                links[(parent - ROOT_ID) as usize] += 1;
+            }
+        }
+        // The root is its own parent.
+        links[0] -= 1;
+        for (node, links) in self.image.nodes.iter_mut().zip(links) {
+            if let Content::Dir { .. } = node.content {
+                node.attr.nlink = links;
+            }
+        }
+        self.image
+    }
+}
+
+/// Indexes the tar archive `image`. The contents of regular files are served from the archive,
+/// which must not be compressed.
+pub(super) fn load(image: &File) -> io::Result<Image> {
+    let len = image.metadata()?.len();
+    let mut builder = Builder {
+        image: Image::new(Attr {
+            mode: libc::S_IFDIR | 0o755,
+            ..Default::default()
+        }),
+    };
+
+    let mut pos = 0;
+    let mut pax = Pax::default();
+    let mut long_name = None;
+    let mut long_link = None;
+    while pos + BLOCK <= len {
+        let header = read_at(image, pos, BLOCK as usize)?;
+        if header.iter().all(|b| *b == 0) {
+            break;
+        }
+        if !checksum_ok(&header) {
+            return Err(invalid_data(format!("bad tar header checksum at {}", pos)));
+        }
+
+        let typeflag = header[156];
+        let mut size = parse_number(&header[124..136])?;
+        if !matches!(typeflag, b'x' | b'g' | b'L' | b'K') {
+            size = pax.size.unwrap_or(size);
+        }
+        let data = pos + BLOCK;
+        pos = size
+            .checked_next_multiple_of(BLOCK)
+            .and_then(|s| data.checked_add(s))
+            .ok_or_else(|| invalid_data("tar entry is too large"))?;
+        if pos > len {
+            return Err(invalid_data("truncated tar archive"));
+        }
+
+        let read_data = || {
+            usize::try_from(size)
+                .map_err(|_| invalid_data("tar header is too large"))
+                .and_then(|size| read_at(image, data, size))
+        };
+        match typeflag {
+            b'x' => {
+                pax = Pax::parse(&read_data()?)?;
+                continue;
+            }
+            b'g' => continue,
+            b'L' => {
+                long_name = Some(cstr_field(&read_data()?).to_vec());
+                continue;
+            }
+            b'K' => {
+                long_link = Some(cstr_field(&read_data()?).to_vec());
+                continue;
+            }
+            b'S' => return Err(unsupported("sparse tar entries are not supported")),
+            _ => {}
+        }
+
+        let name = match long_name.take().or(pax.path.take()) {
+            Some(name) => name,
+            None => {
+                let name = cstr_field(&header[0..100]);
+                let prefix = cstr_field(&header[345..500]);
+                // The prefix field is only used by POSIX archives.
+                if &header[257..263] == b"ustar\0" && !prefix.is_empty() {
+                    [prefix, b"/", name].concat()
+                } else {
+                    name.to_vec()
+                }
+            }
+        };
+        let link = long_link
+            .take()
+            .or(pax.linkpath.take())
+            .unwrap_or_else(|| cstr_field(&header[157..257]).to_vec());
+
+        let mtime = match pax.mtime {
+            Some(mtime) => mtime,
+            None => (parse_number(&header[136..148])? as i64, 0),
+        };
+        let mode = parse_number(&header[100..108])? as u32 & 0o7777;
+        let mut attr = Attr {
+            mode,
+            uid: match pax.uid {
+                Some(uid) => uid,
+                None => parse_number(&header[108..116])? as u32,
+            },
+            gid: match pax.gid {
+                Some(gid) => gid,
+                None => parse_number(&header[116..124])? as u32,
+            },
+            nlink: 1,
+            mtime: mtime.0,
+            mtime_nsec: mtime.1,
+            ..Default::default()
+        };
+        pax = Pax::default();
+
+        let path = components(&name)?;
+        let content = match typeflag {
+            b'1' => {
+                builder.link(&path, &components(&link)?)?;
+                continue;
+            }
+            b'2' => {
+                attr.mode |= libc::S_IFLNK;
+                attr.size = link.len() as u64;
+                Content::Symlink(link)
+            }
+            b'3' | b'4' | b'6' => {
+                attr.mode |= match typeflag {
+                    b'3' => libc::S_IFCHR,
+                    b'4' => libc::S_IFBLK,
+                    _ => libc::S_IFIFO,
+                };
+                let major = parse_number(&header[329..337])? as u32;
+                let minor = parse_number(&header[337..345])? as u32;
+                attr.rdev = libc::makedev(major, minor);
+                Content::Special
+            }
+            b'5' => {
+                attr.mode |= libc::S_IFDIR;
+                Content::Dir {
+                    parent: ROOT_ID,
+                    entries: Vec::new(),
+                }
+            }
+            // Regular files, contiguous files and unknown types, which POSIX requires to be
+            // treated as regular files.
+            _ => {
+                attr.mode |= libc::S_IFREG;
+                attr.size = size;
+                let extents = if size > 0 {
+                    vec![Extent {
+                        start: 0,
+                        len: size,
+                        source: Source::Image(data),
+                    }]
+                } else {
+                    Vec::new()
+                };
+                Content::File(extents)
+            }
+        };
+        builder.insert(&path, Node { attr, content })?;
+    }
+
+    Ok(builder.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::super::tests::*;
+    use super::super::ArchiveFormat;
+    use super::super::ArchiveFs;
+    use super::*;
+    use crate::filesystem::FileSystem;
+
+    /// Appends a tar entry to `archive`.
+    fn append(archive: &mut Vec<u8>, name: &str, typeflag: u8, link: &str, data: &[u8]) {
+        let mut header = [0u8; 512];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[100..107].copy_from_slice(b"0000644");
+        header[108..115].copy_from_slice(b"0001750");
+        header[116..123].copy_from_slice(b"0001750");
+        header[124..135].copy_from_slice(format!("{:011o}", data.len()).as_bytes());
+        header[136..147].copy_from_slice(b"14000000000");
+        header[156] = typeflag;
+        header[157..157 + link.len()].copy_from_slice(link.as_bytes());
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        header[148..156].fill(b' ');
+        let sum: u32 = header.iter().map(|b| u32::from(*b)).sum();
+        header[148..155].copy_from_slice(format!("{:06o}\0", sum).as_bytes());
+
+        archive.extend_from_slice(&header);
+        archive.extend_from_slice(data);
+        archive.resize(archive.len().next_multiple_of(512), 0);
+    }
+
+    fn pax_record(key: &str, value: &str) -> String {
+        let len = key.len() + value.len() + 3;
+        let mut total = len + len.to_string().len();
+        if total.to_string().len() != len.to_string().len() {
+            total += 1;
+        }
+        format!("{} {}={}\n", total, key, value)
+    }
+
+    #[test]
+    fn parse_numbers() {
+        assert_eq!(parse_number(b"0000644\0").unwrap(), 0o644);
+        assert_eq!(parse_number(b"  755 \0").unwrap(), 0o755);
+        assert_eq!(parse_number(b"\0\0\0\0").unwrap(), 0);
+        assert_eq!(
+            parse_number(&[0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0]).unwrap(),
+            256
+        );
+        assert!(parse_number(&[0xff; 12]).is_err());
+    }
+
+    #[test]
+    fn tar_archive() {
+        let long = "d/".to_owned() + &"x".repeat(150);
+        let mut archive = Vec::new();
+        append(&mut archive, "./d/", b'5', "", b"");
+        append(&mut archive, "./d/hello", b'0', "", b"hello world");
+        append(&mut archive, "./d/link", b'2', "hello", b"");
+        append(&mut archive, "./hard", b'1', "d/hello", b"");
+        append(&mut archive, "implicit/file", b'0', "", b"x");
+        let pax = pax_record("path", &long) + &pax_record("mtime", "1700000000.5");
+        append(&mut archive, "PaxHeader", b'x', "", pax.as_bytes());
+        append(&mut archive, "truncated", b'0', "", b"long");
+        append(&mut archive, "d/hello", b'0', "", b"replaced");
+        archive.resize(archive.len() + 1024, 0);
+
+        let file = image_file(&archive);
+        assert_eq!(ArchiveFormat::detect(&file).unwrap(), ArchiveFormat::Tar);
+        let fs = ArchiveFs::new(file, Duration::ZERO).unwrap();
+
+        assert_eq!(list(&fs, ROOT_ID), [".", "..", "d", "hard", "implicit"]);
+        let d = lookup_path(&fs, "d").unwrap();
+        assert_eq!(d.attr.st_mode, libc::S_IFDIR | 0o644);
+        assert_eq!(d.attr.st_nlink, 2);
+
+        let hello = lookup_path(&fs, "d/hello").unwrap();
+        assert_eq!(hello.attr.st_uid, 1000);
+        assert_eq!(read(&fs, hello.inode, 0, 100).unwrap(), b"replaced");
+        let hard = lookup_path(&fs, "hard").unwrap();
+        assert_ne!(hard.inode, hello.inode);
+        assert_eq!(hard.attr.st_nlink, 2);
+        assert_eq!(read(&fs, hard.inode, 6, 100).unwrap(), b"world");
+
+        let link = lookup_path(&fs, "d/link").unwrap();
+        assert_eq!(link.attr.st_mode & libc::S_IFMT, libc::S_IFLNK);
+        assert_eq!(fs.readlink(ctx(), link.inode).unwrap(), b"hello");
+
+        let long = lookup_path(&fs, &long).unwrap();
+        assert_eq!(long.attr.st_mtime, 1700000000);
+        assert_eq!(long.attr.st_mtime_nsec, 500000000);
+        assert_eq!(read(&fs, long.inode, 0, 100).unwrap(), b"long");
+
+        assert_eq!(
+            read(&fs, lookup_path(&fs, "implicit/file").unwrap().inode, 0, 1).unwrap(),
+            b"x"
+        );
+        assert_eq!(
+            lookup_path(&fs, "d/missing").unwrap_err().raw_os_error(),
+            Some(libc::ENOENT)
+        );
+    }
+
+    #[test]
+    fn reject_parent_components() {
+        let mut archive = Vec::new();
+        append(&mut archive, "../escape", b'0', "", b"x");
+        let file = image_file(&archive);
+        assert!(ArchiveFs::new(file, Duration::ZERO).is_err());
+    }
+}
@@ -694,6 +694,11 @@ pub trait FileSystem {
     /// implementation and the kernel, then the file system may return an error of `ENOSYS`. This
     /// will be interpreted by the kernel as success and future calls to `open` and `release` will
     /// be handled by the kernel without being passed on to the file system.
+    ///
+    /// If the `FsOptions::PASSTHROUGH` feature is enabled then the file system may return
+    /// `OpenOptions::PASSTHROUGH` for regular files along with a `Handle`. The server will then
+    /// call `backing_file` and, if the transport supports it, the kernel will read and write that
+    /// file directly instead of sending `read` and `write` requests.
     fn open(
         &self,
         ctx: Context,
@@ -731,6 +736,16 @@ pub trait FileSystem {
         Err(io::Error::from_raw_os_error(libc::ENOSYS))
     }
 
+    /// Get the host file backing an open file.
+    ///
+    /// This is called after `open` or `create` returned `OpenOptions::PASSTHROUGH` for `handle`.
+    /// The returned `File` is registered with the kernel, which then performs all reads and writes
+    /// on the open file directly on it. If this method returns an error then the file is opened
+    /// without passthrough and the kernel falls back to sending `read` and `write` requests.
+    fn backing_file(&self, inode: Self::Inode, handle: Self::Handle) -> io::Result<File> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
     /// Read data from a file.
     ///
     /// Returns `size` bytes of data starting from offset `off` from the file associated with
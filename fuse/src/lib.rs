@@ -13,6 +13,7 @@ use std::io;
 use remain::sorted;
 use thiserror::Error as ThisError;
 
+pub mod archive;
 pub mod filesystem;
 pub mod fuzzing;
 pub mod mount;
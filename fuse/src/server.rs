@@ -12,6 +12,7 @@ use std::mem::MaybeUninit;
 use std::os::unix::io::AsRawFd;
 use std::time::Duration;
 
+use base::debug;
 use base::error;
 use base::pagesize;
 use base::Protection;
@@ -91,12 +92,15 @@ impl<W: Writer> Writer for &'_ mut W {
     }
 }
 
-/// A trait for memory mapping for DAX.
+/// A trait for memory mapping for DAX and for registering FUSE passthrough backing files.
 ///
 /// For some transports (like virtio) it may be possible to share a region of memory with the
 /// FUSE kernel driver so that it can access file contents directly without issuing read or
 /// write requests.  In this case the driver will instead send requests to map a section of a
 /// file into the shared memory region.
+///
+/// Similarly, when talking to the kernel through `/dev/fuse` the driver can be handed a host file
+/// to read and write directly for files opened with `OpenOptions::PASSTHROUGH`.
 pub trait Mapper {
     /// Maps `size` bytes starting at `file_offset` bytes from within the given `fd` at `mem_offset`
     /// bytes from the start of the memory region with `prot` protections. `mem_offset` must be
@@ -124,6 +128,19 @@ pub trait Mapper {
     /// * `offset` - Page aligned offset into the arena in bytes.
     /// * `size` - Size of memory region in bytes.
     fn unmap(&self, offset: u64, size: u64) -> io::Result<()>;
+
+    /// Registers `fd` with the FUSE driver as a passthrough backing file and returns the id that
+    /// the driver assigned to it. Transports that don't support FUSE passthrough return
+    /// `EOPNOTSUPP`.
+    fn open_backing(&self, _fd: &dyn AsRawFd) -> io::Result<i32> {
+        Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+    }
+
+    /// Drops the server's reference to the backing file `backing_id` returned by `open_backing`.
+    /// Files that were already opened with it keep using it until they are released.
+    fn close_backing(&self, _backing_id: i32) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+    }
 }
 
 impl<'a, M: Mapper> Mapper for &'a M {
@@ -141,6 +158,14 @@ impl<'a, M: Mapper> Mapper for &'a M {
     fn unmap(&self, offset: u64, size: u64) -> io::Result<()> {
         (**self).unmap(offset, size)
     }
+
+    fn open_backing(&self, fd: &dyn AsRawFd) -> io::Result<i32> {
+        (**self).open_backing(fd)
+    }
+
+    fn close_backing(&self, backing_id: i32) -> io::Result<()> {
+        (**self).close_backing(backing_id)
+    }
 }
 
 pub struct Server<F: FileSystem + Sync> {
@@ -152,6 +177,11 @@ impl<F: FileSystem + Sync> Server<F> {
         Server { fs }
     }
 
+    /// Returns the file system that requests are forwarded to.
+    pub fn fs(&self) -> &F {
+        &self.fs
+    }
+
     pub fn handle_message<R: Reader + ZeroCopyReader, W: Writer + ZeroCopyWriter, M: Mapper>(
         &self,
         mut r: R,
@@ -183,7 +213,7 @@ impl<F: FileSystem + Sync> Server<F> {
             Some(Opcode::Rmdir) => self.rmdir(in_header, r, w),
             Some(Opcode::Rename) => self.rename(in_header, r, w),
             Some(Opcode::Link) => self.link(in_header, r, w),
-            Some(Opcode::Open) => self.open(in_header, r, w),
+            Some(Opcode::Open) => self.open(in_header, r, w, mapper),
             Some(Opcode::Read) => self.read(in_header, r, w),
             Some(Opcode::Write) => self.write(in_header, r, w),
             Some(Opcode::Statfs) => self.statfs(in_header, w),
@@ -203,7 +233,7 @@ impl<F: FileSystem + Sync> Server<F> {
             Some(Opcode::Setlk) => self.setlk(in_header, r, w),
             Some(Opcode::Setlkw) => self.setlkw(in_header, r, w),
             Some(Opcode::Access) => self.access(in_header, r, w),
-            Some(Opcode::Create) => self.create(in_header, r, w),
+            Some(Opcode::Create) => self.create(in_header, r, w, mapper),
             Some(Opcode::Interrupt) => self.interrupt(in_header),
             Some(Opcode::Bmap) => self.bmap(in_header, r, w),
             Some(Opcode::Destroy) => self.destroy(),
@@ -604,26 +634,65 @@ impl<F: FileSystem + Sync> Server<F> {
         }
     }
 
-    fn open<R: Reader, W: Writer>(&self, in_header: InHeader, mut r: R, w: W) -> Result<usize> {
+    fn open<R: Reader, W: Writer, M: Mapper>(
+        &self,
+        in_header: InHeader,
+        mut r: R,
+        w: W,
+        mapper: M,
+    ) -> Result<usize> {
         let OpenIn { flags, .. } = r.read_struct()?;
 
         match self
             .fs
             .open(Context::from(in_header), in_header.nodeid.into(), flags)
         {
-            Ok((handle, opts)) => {
+            Ok((handle, mut opts)) => {
+                let fh = handle.map(Into::into).unwrap_or(0);
+                let backing_id = self.open_backing(in_header.nodeid, fh, &mut opts, &mapper);
                 let out = OpenOut {
-                    fh: handle.map(Into::into).unwrap_or(0),
+                    fh,
                     open_flags: opts.bits(),
-                    ..Default::default()
+                    backing_id: backing_id.unwrap_or(0),
                 };
 
-                reply_ok(Some(out), None, in_header.unique, w)
+                let res = reply_ok(Some(out), None, in_header.unique, w);
+                close_backing(backing_id, &mapper);
+                res
             }
             Err(e) => reply_error(e, in_header.unique, w),
         }
     }
 
+    /// Registers the backing file of an open file with the FUSE driver if the file system asked for
+    /// it to be opened in passthrough mode. Falls back to regular reads and writes by clearing
+    /// `OpenOptions::PASSTHROUGH` from `opts` if either the file system or the transport can't
+    /// provide one.
+    fn open_backing<M: Mapper>(
+        &self,
+        inode: u64,
+        fh: u64,
+        opts: &mut OpenOptions,
+        mapper: M,
+    ) -> Option<i32> {
+        if !opts.contains(OpenOptions::PASSTHROUGH) {
+            return None;
+        }
+
+        match self
+            .fs
+            .backing_file(inode.into(), fh.into())
+            .and_then(|f| mapper.open_backing(&f))
+        {
+            Ok(backing_id) => Some(backing_id),
+            Err(e) => {
+                debug!("failed to open passthrough backing file for inode {inode}: {e}");
+                opts.remove(OpenOptions::PASSTHROUGH);
+                None
+            }
+        }
+    }
+
     fn read<R: Reader, W: ZeroCopyWriter + Writer>(
         &self,
         in_header: InHeader,
@@ -1028,6 +1097,15 @@ impl<F: FileSystem + Sync> Server<F> {
                     enabled.remove(FsOptions::ATOMIC_O_TRUNC);
                 }
 
+                // The kernel only enables passthrough if it is told how many file systems may be
+                // stacked on top of the backing files. Passthrough files are always regular host
+                // files here, so they add a single level.
+                let max_stack_depth = if enabled.contains(FsOptions::PASSTHROUGH) {
+                    1
+                } else {
+                    0
+                };
+
                 let max_write = self.fs.max_buffer_size();
                 let max_pages = min(
                     max(max_readahead, max_write) / pagesize() as u32,
@@ -1045,6 +1123,7 @@ impl<F: FileSystem + Sync> Server<F> {
                     max_pages,
                     map_alignment: pagesize().trailing_zeros() as u16,
                     flags2: (enabled.bits() >> 32) as u32,
+                    max_stack_depth,
                     ..Default::default()
                 };
 
@@ -1329,7 +1408,13 @@ impl<F: FileSystem + Sync> Server<F> {
         }
     }
 
-    fn create<R: Reader, W: Writer>(&self, in_header: InHeader, mut r: R, w: W) -> Result<usize> {
+    fn create<R: Reader, W: Writer, M: Mapper>(
+        &self,
+        in_header: InHeader,
+        mut r: R,
+        w: W,
+        mapper: M,
+    ) -> Result<usize> {
         let CreateIn {
             flags, mode, umask, ..
         } = r.read_struct()?;
@@ -1361,7 +1446,7 @@ impl<F: FileSystem + Sync> Server<F> {
             umask,
             security_ctx,
         ) {
-            Ok((entry, handle, opts)) => {
+            Ok((entry, handle, mut opts)) => {
                 let entry_out = EntryOut {
                     nodeid: entry.inode,
                     generation: entry.generation,
@@ -1371,19 +1456,23 @@ impl<F: FileSystem + Sync> Server<F> {
                     attr_valid_nsec: entry.attr_timeout.subsec_nanos(),
                     attr: entry.attr.into(),
                 };
+                let fh = handle.map(Into::into).unwrap_or(0);
+                let backing_id = self.open_backing(entry.inode, fh, &mut opts, &mapper);
                 let open_out = OpenOut {
-                    fh: handle.map(Into::into).unwrap_or(0),
+                    fh,
                     open_flags: opts.bits(),
-                    ..Default::default()
+                    backing_id: backing_id.unwrap_or(0),
                 };
 
                 // Kind of a hack to write both structs.
-                reply_ok(
+                let res = reply_ok(
                     Some(entry_out),
                     Some(open_out.as_bytes()),
                     in_header.unique,
                     w,
-                )
+                );
+                close_backing(backing_id, &mapper);
+                res
             }
             Err(e) => reply_error(e, in_header.unique, w),
         }
@@ -1813,6 +1902,19 @@ fn reply_readdir<W: Writer>(len: usize, unique: u64, mut w: W) -> Result<usize>
     Ok(out.len as usize)
 }
 
+/// Drops the server's reference to a passthrough backing file once the open reply that uses it
+/// has been sent. The driver keeps its own reference for as long as the file stays open.
+fn close_backing<M: Mapper>(backing_id: Option<i32>, mapper: M) {
+    if let Some(backing_id) = backing_id {
+        if let Err(e) = mapper.close_backing(backing_id) {
+            error!(
+                "failed to close passthrough backing file {}: {}",
+                backing_id, e
+            );
+        }
+    }
+}
+
 fn reply_ok<T: AsBytes, W: Writer>(
     out: Option<T>,
     data: Option<&[u8]>,
@@ -2040,6 +2142,9 @@ fn parse_selinux_xattr(buf: &[u8]) -> Result<Option<&CStr>> {
 
 #[cfg(test)]
 mod tests {
+    use std::cell::RefCell;
+    use std::fs::File;
+
     use super::*;
 
     fn create_secctx(ctxs: &[(&[u8], &[u8])], size_truncation: u32) -> Vec<u8> {
@@ -2156,4 +2261,177 @@ mod tests {
         let res = parse_selinux_xattr(&v);
         assert!(matches!(res, Err(Error::InvalidHeaderLength)));
     }
+
+    struct TestReader(io::Cursor<Vec<u8>>);
+
+    impl io::Read for TestReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl Reader for TestReader {}
+
+    impl ZeroCopyReader for TestReader {
+        fn read_to(&mut self, _f: &mut File, _count: usize, _off: u64) -> io::Result<usize> {
+            Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+        }
+    }
+
+    struct TestWriter(Vec<u8>);
+
+    impl io::Write for TestWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Writer for TestWriter {
+        type ClosureWriter = Self;
+
+        fn write_at<F>(&mut self, _offset: usize, f: F) -> io::Result<usize>
+        where
+            F: Fn(&mut Self) -> io::Result<usize>,
+        {
+            f(self)
+        }
+
+        fn has_sufficient_buffer(&self, _size: u32) -> bool {
+            true
+        }
+    }
+
+    impl ZeroCopyWriter for TestWriter {
+        fn write_from(&mut self, _f: &mut File, _count: usize, _off: u64) -> io::Result<usize> {
+            Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+        }
+    }
+
+    struct EmptyDir;
+
+    impl DirectoryIterator for EmptyDir {
+        fn next(&mut self) -> Option<DirEntry> {
+            None
+        }
+    }
+
+    // Opens every file in passthrough mode with a temporary file as its backing file.
+    struct PassthroughTestFs;
+
+    impl FileSystem for PassthroughTestFs {
+        type Inode = u64;
+        type Handle = u64;
+        type DirIter = EmptyDir;
+
+        fn open(
+            &self,
+            _ctx: Context,
+            _inode: u64,
+            _flags: u32,
+        ) -> io::Result<(Option<u64>, OpenOptions)> {
+            Ok((Some(7), OpenOptions::KEEP_CACHE | OpenOptions::PASSTHROUGH))
+        }
+
+        fn backing_file(&self, _inode: u64, handle: u64) -> io::Result<File> {
+            assert_eq!(handle, 7);
+            tempfile::tempfile()
+        }
+    }
+
+    // Assigns backing id 5 to every backing file if `supported` is true, and records the ids that
+    // were closed.
+    struct BackingMapper {
+        supported: bool,
+        closed: RefCell<Vec<i32>>,
+    }
+
+    impl Mapper for BackingMapper {
+        fn map(
+            &self,
+            _mem_offset: u64,
+            _size: usize,
+            _fd: &dyn AsRawFd,
+            _file_offset: u64,
+            _prot: Protection,
+        ) -> io::Result<()> {
+            Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+        }
+
+        fn unmap(&self, _offset: u64, _size: u64) -> io::Result<()> {
+            Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+        }
+
+        fn open_backing(&self, _fd: &dyn AsRawFd) -> io::Result<i32> {
+            if self.supported {
+                Ok(5)
+            } else {
+                Err(io::Error::from_raw_os_error(libc::EPERM))
+            }
+        }
+
+        fn close_backing(&self, backing_id: i32) -> io::Result<()> {
+            self.closed.borrow_mut().push(backing_id);
+            Ok(())
+        }
+    }
+
+    /// Sends an open request for inode 3 and returns the reply.
+    fn open_passthrough(mapper: &BackingMapper) -> OpenOut {
+        let header = InHeader {
+            len: (size_of::<InHeader>() + size_of::<OpenIn>()) as u32,
+            opcode: Opcode::Open as u32,
+            unique: 1,
+            nodeid: 3,
+            ..Default::default()
+        };
+        let open_in = OpenIn {
+            flags: libc::O_RDWR as u32,
+            ..Default::default()
+        };
+        let mut r = TestReader(io::Cursor::new(
+            [header.as_bytes(), open_in.as_bytes()].concat(),
+        ));
+        let mut w = TestWriter(Vec::new());
+
+        let server = Server::new(PassthroughTestFs);
+        server.handle_message(&mut r, &mut w, mapper).unwrap();
+
+        let out = OutHeader::read_from_prefix(&w.0).unwrap();
+        assert_eq!(out.error, 0);
+        OpenOut::read_from(&w.0[size_of::<OutHeader>()..]).unwrap()
+    }
+
+    #[test]
+    fn open_passthrough_registers_backing_file() {
+        let mapper = BackingMapper {
+            supported: true,
+            closed: RefCell::new(Vec::new()),
+        };
+        let out = open_passthrough(&mapper);
+        assert_eq!(out.fh, 7);
+        assert_eq!(
+            out.open_flags,
+            (OpenOptions::KEEP_CACHE | OpenOptions::PASSTHROUGH).bits()
+        );
+        assert_eq!(out.backing_id, 5);
+        // The backing id is only needed until the reply has been sent.
+        assert_eq!(*mapper.closed.borrow(), [5]);
+    }
+
+    #[test]
+    fn open_passthrough_unsupported() {
+        let mapper = BackingMapper {
+            supported: false,
+            closed: RefCell::new(Vec::new()),
+        };
+        let out = open_passthrough(&mapper);
+        assert_eq!(out.fh, 7);
+        assert_eq!(out.open_flags, OpenOptions::KEEP_CACHE.bits());
+        assert_eq!(out.backing_id, 0);
+        assert!(mapper.closed.borrow().is_empty());
+    }
 }
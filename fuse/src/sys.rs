@@ -71,6 +71,10 @@ const FOPEN_STREAM: u32 = 1 << 4;
 /// New file was created in atomic open
 const FOPEN_FILE_CREATED: u32 = 1 << 7;
 
+/// Reads and writes go directly to the backing file registered with the FUSE driver. This shares
+/// its value with `FOPEN_FILE_CREATED`, which is only meaningful in atomic open replies.
+const FOPEN_PASSTHROUGH: u32 = 1 << 7;
+
 bitflags! {
     /// Options controlling the behavior of files opened by the server in response
     /// to an open or create request.
@@ -83,6 +87,7 @@ bitflags! {
         const CACHE_DIR = FOPEN_CACHE_DIR;
         const STREAM = FOPEN_STREAM;
         const FILE_CREATED = FOPEN_FILE_CREATED;
+        const PASSTHROUGH = FOPEN_PASSTHROUGH;
     }
 }
 
@@ -176,6 +181,10 @@ const INIT_EXT: u64 = 1073741824;
 /// requests.
 const SECURITY_CONTEXT: u64 = 4294967296;
 
+/// The server supports opening files in passthrough mode, where the FUSE driver reads and writes a
+/// host file directly instead of sending requests to the server.
+const PASSTHROUGH: u64 = 137438953472;
+
 bitflags! {
     /// A bitfield passed in as a parameter to and returned from the `init` method of the
     /// `FileSystem` trait.
@@ -394,6 +403,17 @@ bitflags! {
 
         /// Indicates support for sending the security context with creation requests.
         const SECURITY_CONTEXT = SECURITY_CONTEXT;
+
+        /// Indicates that the file system may return `OpenOptions::PASSTHROUGH` from `open` and
+        /// `create`, in which case the kernel performs reads and writes on the file returned by
+        /// `FileSystem::backing_file` without involving the server.
+        ///
+        /// This is only supported when the server is talking to the kernel directly through
+        /// `/dev/fuse` and the server has `CAP_SYS_ADMIN`. Files fall back to regular reads and
+        /// writes otherwise.
+        ///
+        /// This feature is not enabled by default.
+        const PASSTHROUGH = PASSTHROUGH;
     }
 }
 
@@ -807,7 +827,7 @@ pub struct CreateIn {
 pub struct OpenOut {
     pub fh: u64,
     pub open_flags: u32,
-    pub padding: u32,
+    pub backing_id: i32,
 }
 
 #[repr(C)]
@@ -947,7 +967,8 @@ pub struct InitOut {
     pub max_pages: u16,
     pub map_alignment: u16,
     pub flags2: u32,
-    pub unused: [u32; 7],
+    pub max_stack_depth: u32,
+    pub unused: [u32; 6],
 }
 
 #[repr(C)]
@@ -1207,3 +1228,17 @@ pub struct SecctxHeader {
     pub size: u32,
     pub nr_secctx: u32,
 }
+
+/// Registers a backing file for FUSE passthrough with `FUSE_DEV_IOC_BACKING_OPEN`.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, AsBytes, FromZeroes, FromBytes)]
+pub struct BackingMap {
+    pub fd: i32,
+    pub flags: u32,
+    pub padding: u64,
+}
+
+const FUSE_DEV_IOC_MAGIC: u32 = 229;
+
+base::ioctl_iow_nr!(FUSE_DEV_IOC_BACKING_OPEN, FUSE_DEV_IOC_MAGIC, 1, BackingMap);
+base::ioctl_iow_nr!(FUSE_DEV_IOC_BACKING_CLOSE, FUSE_DEV_IOC_MAGIC, 2, u32);
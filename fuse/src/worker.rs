@@ -14,6 +14,7 @@ use std::os::unix::fs::FileExt;
 use std::os::unix::io::AsRawFd;
 use std::sync::Arc;
 
+use base::ioctl_with_ref;
 use base::Protection;
 
 use crate::filesystem::FileSystem;
@@ -132,11 +133,13 @@ impl ZeroCopyWriter for DevFuseWriter {
     }
 }
 
-struct DevFuseMapper;
+struct DevFuseMapper {
+    dev_fuse: File,
+}
 
 impl DevFuseMapper {
-    fn new() -> Self {
-        Self {}
+    fn new(dev_fuse: File) -> Self {
+        Self { dev_fuse }
     }
 }
 
@@ -155,6 +158,29 @@ impl Mapper for DevFuseMapper {
     fn unmap(&self, _offset: u64, _size: u64) -> io::Result<()> {
         Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
     }
+
+    fn open_backing(&self, fd: &dyn AsRawFd) -> io::Result<i32> {
+        let map = sys::BackingMap {
+            fd: fd.as_raw_fd(),
+            ..Default::default()
+        };
+        // SAFETY: the kernel only reads `map`, and `fd` stays open for the duration of the call.
+        let ret = unsafe { ioctl_with_ref(&self.dev_fuse, sys::FUSE_DEV_IOC_BACKING_OPEN, &map) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(ret)
+    }
+
+    fn close_backing(&self, backing_id: i32) -> io::Result<()> {
+        // SAFETY: the kernel only reads `backing_id`.
+        let ret =
+            unsafe { ioctl_with_ref(&self.dev_fuse, sys::FUSE_DEV_IOC_BACKING_CLOSE, &backing_id) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
 }
 
 /// Start the FUSE message handling loop. Returns when an error happens.
@@ -191,12 +217,15 @@ fn do_start_message_loop<F: FileSystem + Sync>(
         );
         DevFuseReader::new(buf_reader)
     };
+    let dev_fuse_mapper = {
+        let mfile = dev_fuse.try_clone().map_err(Error::EndpointSetup)?;
+        DevFuseMapper::new(mfile)
+    };
     let mut dev_fuse_writer = {
         let wfile = dev_fuse;
         let write_buf = Cursor::new(Vec::with_capacity(output_buffer_size as usize));
         DevFuseWriter::new(wfile, write_buf)
     };
-    let dev_fuse_mapper = DevFuseMapper::new();
     loop {
         server.handle_message(&mut dev_fuse_reader, &mut dev_fuse_writer, &dev_fuse_mapper)?;
 
@@ -0,0 +1,1397 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! A display backend that serves the scanouts over the RFB protocol, so that they can be viewed
+//! with any VNC client on hosts without a windowing system.
+//!
+//! Each scanout is exported on its own listening socket. Only the "None" security type is offered,
+//! so the sockets must only be reachable by trusted clients. Framebuffer updates use the raw
+//! encoding and only cover the tiles that changed since the previous update sent to the client.
+//! Keyboard events are forwarded to the keyboard event devices, the left button drives a single
+//! touch on the touchscreen event devices and the wheel is forwarded to the mouse event devices.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::collections::VecDeque;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Write;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::rc::Rc;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context;
+use base::error;
+use base::info;
+use base::warn;
+use base::AsRawDescriptor;
+use base::EventToken;
+use base::EventType;
+use base::RawDescriptor;
+use base::VolatileSlice;
+use base::WaitContext;
+use linux_input_sys::constants::*;
+use linux_input_sys::virtio_input_event;
+use vm_control::gpu::DisplayParameters;
+
+use crate::DisplayT;
+use crate::EventDeviceKind;
+use crate::GpuDisplayError;
+use crate::GpuDisplayEvents;
+use crate::GpuDisplayFramebuffer;
+use crate::GpuDisplayResult;
+use crate::GpuDisplaySurface;
+use crate::SurfaceType;
+use crate::SysDisplayT;
+
+// Framebuffers are XRGB8888.
+const BYTES_PER_PIXEL: u32 = 4;
+// Width and height of the regions compared to find the damaged parts of a flipped framebuffer.
+const TILE_SIZE: u32 = 64;
+const SERVER_NAME: &[u8] = b"crosvm";
+const SERVER_VERSION: &[u8] = b"RFB 003.008\n";
+// Clipboard text is ignored, but has to be buffered until it has been received completely.
+const MAX_CUT_TEXT_LEN: usize = 1 << 20;
+
+const SECURITY_TYPE_NONE: u8 = 1;
+const ENCODING_RAW: i32 = 0;
+const ENCODING_DESKTOP_SIZE: i32 = -223;
+
+// Client to server messages.
+const MSG_SET_PIXEL_FORMAT: u8 = 0;
+const MSG_SET_ENCODINGS: u8 = 2;
+const MSG_FRAMEBUFFER_UPDATE_REQUEST: u8 = 3;
+const MSG_KEY_EVENT: u8 = 4;
+const MSG_POINTER_EVENT: u8 = 5;
+const MSG_CLIENT_CUT_TEXT: u8 = 6;
+
+// Server to client messages.
+const MSG_FRAMEBUFFER_UPDATE: u8 = 0;
+
+// Bits of the button mask of pointer events.
+const BUTTON_LEFT: u8 = 1 << 0;
+const BUTTON_WHEEL_UP: u8 = 1 << 3;
+const BUTTON_WHEEL_DOWN: u8 = 1 << 4;
+
+/// Layout of the pixels sent to a client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct PixelFormat {
+    bits_per_pixel: u8,
+    depth: u8,
+    big_endian: bool,
+    true_color: bool,
+    red_max: u16,
+    green_max: u16,
+    blue_max: u16,
+    red_shift: u8,
+    green_shift: u8,
+    blue_shift: u8,
+}
+
+impl PixelFormat {
+    /// The format of the framebuffers, which is used until the client asks for another one.
+    const XRGB8888: PixelFormat = PixelFormat {
+        bits_per_pixel: 32,
+        depth: 24,
+        big_endian: false,
+        true_color: true,
+        red_max: 255,
+        green_max: 255,
+        blue_max: 255,
+        red_shift: 16,
+        green_shift: 8,
+        blue_shift: 0,
+    };
+
+    fn parse(b: &[u8]) -> PixelFormat {
+        PixelFormat {
+            bits_per_pixel: b[0],
+            depth: b[1],
+            big_endian: b[2] != 0,
+            true_color: b[3] != 0,
+            red_max: u16::from_be_bytes([b[4], b[5]]),
+            green_max: u16::from_be_bytes([b[6], b[7]]),
+            blue_max: u16::from_be_bytes([b[8], b[9]]),
+            red_shift: b[10],
+            green_shift: b[11],
+            blue_shift: b[12],
+        }
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&[
+            self.bits_per_pixel,
+            self.depth,
+            self.big_endian as u8,
+            self.true_color as u8,
+        ]);
+        out.extend_from_slice(&self.red_max.to_be_bytes());
+        out.extend_from_slice(&self.green_max.to_be_bytes());
+        out.extend_from_slice(&self.blue_max.to_be_bytes());
+        out.extend_from_slice(&[self.red_shift, self.green_shift, self.blue_shift, 0, 0, 0]);
+    }
+
+    /// Color maps are not supported, so clients have to use a true color format.
+    fn is_supported(&self) -> bool {
+        matches!(self.bits_per_pixel, 8 | 16 | 32)
+            && self.true_color
+            && [self.red_shift, self.green_shift, self.blue_shift]
+                .iter()
+                .all(|&shift| shift < self.bits_per_pixel)
+    }
+
+    /// Appends the XRGB8888 pixel `xrgb` converted to this format to `out`.
+    fn write_pixel(&self, xrgb: u32, out: &mut Vec<u8>) {
+        let channel = |offset: u32, max: u16, shift: u8| {
+            (((xrgb >> offset) & 0xff) * u32::from(max) / 255) << shift
+        };
+        let value = channel(16, self.red_max, self.red_shift)
+            | channel(8, self.green_max, self.green_shift)
+            | channel(0, self.blue_max, self.blue_shift);
+        match (self.bits_per_pixel, self.big_endian) {
+            (8, _) => out.push(value as u8),
+            (16, false) => out.extend_from_slice(&(value as u16).to_le_bytes()),
+            (16, true) => out.extend_from_slice(&(value as u16).to_be_bytes()),
+            (_, false) => out.extend_from_slice(&value.to_le_bytes()),
+            (_, true) => out.extend_from_slice(&value.to_be_bytes()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Rect {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+impl Rect {
+    fn encode_header(&self, encoding: i32, out: &mut Vec<u8>) {
+        for v in [self.x, self.y, self.width, self.height] {
+            out.extend_from_slice(&(v as u16).to_be_bytes());
+        }
+        out.extend_from_slice(&encoding.to_be_bytes());
+    }
+}
+
+/// The tiles of a screen that changed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Damage {
+    width: u32,
+    height: u32,
+    tiles_x: u32,
+    tiles: Vec<bool>,
+}
+
+impl Damage {
+    fn new(width: u32, height: u32) -> Damage {
+        let tiles_x = width.div_ceil(TILE_SIZE);
+        let tiles_y = height.div_ceil(TILE_SIZE);
+        Damage {
+            width,
+            height,
+            tiles_x,
+            tiles: vec![false; (tiles_x * tiles_y) as usize],
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        !self.tiles.contains(&true)
+    }
+
+    fn add_all(&mut self) {
+        self.tiles.fill(true);
+    }
+
+    /// Marks every tile intersecting `rect` as changed.
+    fn add_rect(&mut self, rect: &Rect) {
+        let x_end = rect.x.saturating_add(rect.width).min(self.width);
+        let y_end = rect.y.saturating_add(rect.height).min(self.height);
+        if rect.x >= x_end || rect.y >= y_end {
+            return;
+        }
+        for tile_y in rect.y / TILE_SIZE..y_end.div_ceil(TILE_SIZE) {
+            for tile_x in rect.x / TILE_SIZE..x_end.div_ceil(TILE_SIZE) {
+                self.tiles[(tile_y * self.tiles_x + tile_x) as usize] = true;
+            }
+        }
+    }
+
+    /// Adds the tiles changed in `other`. Everything is considered changed if `other` describes a
+    /// screen of a different size.
+    fn merge(&mut self, other: &Damage) {
+        if (self.width, self.height) != (other.width, other.height) {
+            *self = Damage::new(other.width, other.height);
+            self.add_all();
+            return;
+        }
+        for (tile, &changed) in self.tiles.iter_mut().zip(other.tiles.iter()) {
+            *tile |= changed;
+        }
+    }
+
+    /// Clears the damage and returns the areas that changed, merging adjacent tiles of a row.
+    fn take_rects(&mut self) -> Vec<Rect> {
+        let mut rects = Vec::new();
+        if self.tiles_x == 0 {
+            return rects;
+        }
+        for (tile_y, row) in self
+            .tiles
+            .chunks_exact_mut(self.tiles_x as usize)
+            .enumerate()
+        {
+            let y = tile_y as u32 * TILE_SIZE;
+            let mut tile_x = 0;
+            while tile_x < row.len() {
+                if !row[tile_x] {
+                    tile_x += 1;
+                    continue;
+                }
+                let x = tile_x as u32 * TILE_SIZE;
+                while tile_x < row.len() && row[tile_x] {
+                    row[tile_x] = false;
+                    tile_x += 1;
+                }
+                rects.push(Rect {
+                    x,
+                    y,
+                    width: (tile_x as u32 * TILE_SIZE).min(self.width) - x,
+                    height: (y + TILE_SIZE).min(self.height) - y,
+                });
+            }
+        }
+        rects
+    }
+}
+
+/// The contents of a scanout as of the last flip.
+struct Screen {
+    surface_id: u32,
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+}
+
+impl Screen {
+    fn new(surface_id: u32, width: u32, height: u32) -> Screen {
+        Screen {
+            surface_id,
+            width,
+            height,
+            pixels: vec![0; (width * height * BYTES_PER_PIXEL) as usize],
+        }
+    }
+
+    fn stride(&self) -> usize {
+        (self.width * BYTES_PER_PIXEL) as usize
+    }
+
+    /// Replaces the contents of the screen with `pixels` and returns the tiles that changed.
+    fn update(&mut self, pixels: &[u8]) -> Damage {
+        let mut damage = Damage::new(self.width, self.height);
+        let stride = self.stride();
+        for y in (0..self.height).step_by(TILE_SIZE as usize) {
+            for x in (0..self.width).step_by(TILE_SIZE as usize) {
+                let tile = Rect {
+                    x,
+                    y,
+                    width: TILE_SIZE.min(self.width - x),
+                    height: TILE_SIZE.min(self.height - y),
+                };
+                let changed = (tile.y..tile.y + tile.height).any(|row| {
+                    let start = row as usize * stride + (tile.x * BYTES_PER_PIXEL) as usize;
+                    let end = start + (tile.width * BYTES_PER_PIXEL) as usize;
+                    self.pixels[start..end] != pixels[start..end]
+                });
+                if changed {
+                    damage.add_rect(&tile);
+                }
+            }
+        }
+        self.pixels.copy_from_slice(pixels);
+        damage
+    }
+
+    /// Appends the pixels of `rect` in `format` to `out`.
+    fn encode_raw(&self, rect: &Rect, format: &PixelFormat, out: &mut Vec<u8>) {
+        let stride = self.stride();
+        for row in rect.y..rect.y + rect.height {
+            let start = row as usize * stride + (rect.x * BYTES_PER_PIXEL) as usize;
+            let line = &self.pixels[start..start + (rect.width * BYTES_PER_PIXEL) as usize];
+            if *format == PixelFormat::XRGB8888 {
+                out.extend_from_slice(line);
+            } else {
+                for pixel in line.chunks_exact(BYTES_PER_PIXEL as usize) {
+                    format.write_pixel(u32::from_le_bytes(pixel.try_into().unwrap()), out);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum ClientMessage {
+    SetPixelFormat(PixelFormat),
+    SetEncodings(Vec<i32>),
+    FramebufferUpdateRequest { incremental: bool, rect: Rect },
+    KeyEvent { down: bool, keysym: u32 },
+    PointerEvent { buttons: u8, x: u16, y: u16 },
+    ClientCutText,
+}
+
+/// Parses the message at the start of `buf`. Returns the message and its length, or `None` if
+/// `buf` does not hold a complete message yet.
+fn parse_message(buf: &[u8]) -> anyhow::Result<Option<(ClientMessage, usize)>> {
+    let Some(&msg_type) = buf.first() else {
+        return Ok(None);
+    };
+    let be16 = |i: usize| u16::from_be_bytes([buf[i], buf[i + 1]]);
+    let be32 = |i: usize| u32::from_be_bytes(buf[i..i + 4].try_into().unwrap());
+    let len = match msg_type {
+        MSG_SET_PIXEL_FORMAT => 20,
+        MSG_SET_ENCODINGS if buf.len() < 4 => return Ok(None),
+        MSG_SET_ENCODINGS => 4 + 4 * be16(2) as usize,
+        MSG_FRAMEBUFFER_UPDATE_REQUEST => 10,
+        MSG_KEY_EVENT => 8,
+        MSG_POINTER_EVENT => 6,
+        MSG_CLIENT_CUT_TEXT if buf.len() < 8 => return Ok(None),
+        MSG_CLIENT_CUT_TEXT => {
+            let text_len = be32(4) as usize;
+            if text_len > MAX_CUT_TEXT_LEN {
+                bail!("client cut text of {} bytes is too long", text_len);
+            }
+            8 + text_len
+        }
+        _ => bail!("unknown message type {}", msg_type),
+    };
+    if buf.len() < len {
+        return Ok(None);
+    }
+    let msg = match msg_type {
+        MSG_SET_PIXEL_FORMAT => ClientMessage::SetPixelFormat(PixelFormat::parse(&buf[4..20])),
+        MSG_SET_ENCODINGS => ClientMessage::SetEncodings(
+            buf[4..len]
+                .chunks_exact(4)
+                .map(|b| i32::from_be_bytes(b.try_into().unwrap()))
+                .collect(),
+        ),
+        MSG_FRAMEBUFFER_UPDATE_REQUEST => ClientMessage::FramebufferUpdateRequest {
+            incremental: buf[1] != 0,
+            rect: Rect {
+                x: be16(2).into(),
+                y: be16(4).into(),
+                width: be16(6).into(),
+                height: be16(8).into(),
+            },
+        },
+        MSG_KEY_EVENT => ClientMessage::KeyEvent {
+            down: buf[1] != 0,
+            keysym: be32(4),
+        },
+        MSG_POINTER_EVENT => ClientMessage::PointerEvent {
+            buttons: buf[1],
+            x: be16(2),
+            y: be16(4),
+        },
+        _ => ClientMessage::ClientCutText,
+    };
+    Ok(Some((msg, len)))
+}
+
+/// Parses the ProtocolVersion message of a client and returns the minor version of the protocol
+/// to use: 3, 7 or 8.
+fn parse_version(buf: &[u8]) -> Option<u8> {
+    let version = std::str::from_utf8(buf.strip_prefix(b"RFB ")?.strip_suffix(b"\n")?).ok()?;
+    let (major, minor) = version.split_once('.')?;
+    if major.parse::<u32>().ok()? != 3 {
+        return None;
+    }
+    // Unknown minor versions must be treated as the closest version the server supports.
+    Some(match minor.parse::<u32>().ok()? {
+        0..=6 => 3,
+        7 => 7,
+        _ => 8,
+    })
+}
+
+/// Translates a X keysym to the Linux key code of the key producing it on a US keyboard. Modifiers
+/// are sent as separate key events by clients, so shifted symbols map to their unshifted key.
+fn keysym_to_linux_keycode(keysym: u32) -> Option<u16> {
+    const LETTERS: [u16; 26] = [
+        KEY_A, KEY_B, KEY_C, KEY_D, KEY_E, KEY_F, KEY_G, KEY_H, KEY_I, KEY_J, KEY_K, KEY_L, KEY_M,
+        KEY_N, KEY_O, KEY_P, KEY_Q, KEY_R, KEY_S, KEY_T, KEY_U, KEY_V, KEY_W, KEY_X, KEY_Y, KEY_Z,
+    ];
+    const DIGITS: [u16; 10] = [
+        KEY_0, KEY_1, KEY_2, KEY_3, KEY_4, KEY_5, KEY_6, KEY_7, KEY_8, KEY_9,
+    ];
+    const FUNCTION_KEYS: [u16; 12] = [
+        KEY_F1, KEY_F2, KEY_F3, KEY_F4, KEY_F5, KEY_F6, KEY_F7, KEY_F8, KEY_F9, KEY_F10, KEY_F11,
+        KEY_F12,
+    ];
+
+    let code = match keysym {
+        0x61..=0x7a => LETTERS[(keysym - 0x61) as usize],
+        0x41..=0x5a => LETTERS[(keysym - 0x41) as usize],
+        0x30..=0x39 => DIGITS[(keysym - 0x30) as usize],
+        0xffb0..=0xffb9 => [
+            KEY_KP0, KEY_KP1, KEY_KP2, KEY_KP3, KEY_KP4, KEY_KP5, KEY_KP6, KEY_KP7, KEY_KP8,
+            KEY_KP9,
+        ][(keysym - 0xffb0) as usize],
+        0xffbe..=0xffc9 => FUNCTION_KEYS[(keysym - 0xffbe) as usize],
+        _ => match char::from_u32(keysym).unwrap_or('\0') {
+            ' ' => KEY_SPACE,
+            '!' => KEY_1,
+            '@' => KEY_2,
+            '#' => KEY_3,
+            '$' => KEY_4,
+            '%' => KEY_5,
+            '^' => KEY_6,
+            '&' => KEY_7,
+            '*' => KEY_8,
+            '(' => KEY_9,
+            ')' => KEY_0,
+            '-' | '_' => KEY_MINUS,
+            '=' | '+' => KEY_EQUAL,
+            '[' | '{' => KEY_LEFTBRACE,
+            ']' | '}' => KEY_RIGHTBRACE,
+            '\\' | '|' => KEY_BACKSLASH,
+            ';' | ':' => KEY_SEMICOLON,
+            '\'' | '"' => KEY_APOSTROPHE,
+            '`' | '~' => KEY_GRAVE,
+            ',' | '<' => KEY_COMMA,
+            '.' | '>' => KEY_DOT,
+            '/' | '?' => KEY_SLASH,
+            _ => match keysym {
+                0xfe03 => KEY_RIGHTALT, // ISO_Level3_Shift
+                0xff08 => KEY_BACKSPACE,
+                0xff09 => KEY_TAB,
+                0xff0d => KEY_ENTER,
+                0xff13 => KEY_PAUSE,
+                0xff14 => KEY_SCROLLLOCK,
+                0xff15 => KEY_SYSRQ,
+                0xff1b => KEY_ESC,
+                0xff50 => KEY_HOME,
+                0xff51 => KEY_LEFT,
+                0xff52 => KEY_UP,
+                0xff53 => KEY_RIGHT,
+                0xff54 => KEY_DOWN,
+                0xff55 => KEY_PAGEUP,
+                0xff56 => KEY_PAGEDOWN,
+                0xff57 => KEY_END,
+                0xff61 => KEY_SYSRQ, // Print
+                0xff63 => KEY_INSERT,
+                0xff67 => KEY_COMPOSE, // Menu
+                0xff7f => KEY_NUMLOCK,
+                0xff8d => KEY_KPENTER,
+                0xff95 => KEY_KP7,   // KP_Home
+                0xff96 => KEY_KP4,   // KP_Left
+                0xff97 => KEY_KP8,   // KP_Up
+                0xff98 => KEY_KP6,   // KP_Right
+                0xff99 => KEY_KP2,   // KP_Down
+                0xff9a => KEY_KP9,   // KP_Prior
+                0xff9b => KEY_KP3,   // KP_Next
+                0xff9c => KEY_KP1,   // KP_End
+                0xff9d => KEY_KP5,   // KP_Begin
+                0xff9e => KEY_KP0,   // KP_Insert
+                0xff9f => KEY_KPDOT, // KP_Delete
+                0xffaa => KEY_KPASTERISK,
+                0xffab => KEY_KPPLUS,
+                0xffad => KEY_KPMINUS,
+                0xffae => KEY_KPDOT,
+                0xffaf => KEY_KPSLASH,
+                0xffbd => KEY_KPEQUAL,
+                0xffe1 => KEY_LEFTSHIFT,
+                0xffe2 => KEY_RIGHTSHIFT,
+                0xffe3 => KEY_LEFTCTRL,
+                0xffe4 => KEY_RIGHTCTRL,
+                0xffe5 => KEY_CAPSLOCK,
+                0xffe7 | 0xffeb => KEY_LEFTMETA,  // Meta_L, Super_L
+                0xffe8 | 0xffec => KEY_RIGHTMETA, // Meta_R, Super_R
+                0xffe9 => KEY_LEFTALT,
+                0xffea => KEY_RIGHTALT,
+                0xffff => KEY_DELETE,
+                _ => return None,
+            },
+        },
+    };
+    Some(code)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ClientState {
+    /// Waiting for the ProtocolVersion message.
+    Version,
+    /// Waiting for the security type chosen by the client.
+    Security,
+    /// Waiting for the ClientInit message.
+    Init,
+    /// Waiting for the scanout to be created before sending the ServerInit message.
+    WaitingForScreen,
+    /// Exchanging normal protocol messages.
+    Running,
+}
+
+struct Client {
+    stream: TcpStream,
+    scanout_id: u32,
+    state: ClientState,
+    minor_version: u8,
+    in_buf: Vec<u8>,
+    out_buf: Vec<u8>,
+    polling_writable: bool,
+    format: PixelFormat,
+    supports_desktop_size: bool,
+    // Size of the framebuffer known to the client.
+    width: u32,
+    height: u32,
+    damage: Damage,
+    update_requested: bool,
+    buttons: u8,
+}
+
+impl Client {
+    fn new(stream: TcpStream, scanout_id: u32) -> Client {
+        Client {
+            stream,
+            scanout_id,
+            state: ClientState::Version,
+            minor_version: 3,
+            in_buf: Vec::new(),
+            out_buf: SERVER_VERSION.to_vec(),
+            polling_writable: true,
+            format: PixelFormat::XRGB8888,
+            supports_desktop_size: false,
+            width: 0,
+            height: 0,
+            damage: Damage::new(0, 0),
+            update_requested: false,
+            buttons: 0,
+        }
+    }
+
+    /// Reads everything available from the socket into `in_buf`.
+    fn read(&mut self) -> anyhow::Result<()> {
+        let mut buf = [0u8; 4096];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => bail!("connection closed"),
+                Ok(len) => self.in_buf.extend_from_slice(&buf[..len]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e).context("failed to read from client"),
+            }
+        }
+    }
+
+    /// Writes as much of `out_buf` as the socket accepts.
+    fn write(&mut self) -> anyhow::Result<()> {
+        while !self.out_buf.is_empty() {
+            match self.stream.write(&self.out_buf) {
+                Ok(0) => bail!("connection closed"),
+                Ok(len) => {
+                    self.out_buf.drain(..len);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e).context("failed to write to client"),
+            }
+        }
+        Ok(())
+    }
+
+    fn send_server_init(&mut self, screen: &Screen) {
+        self.width = screen.width;
+        self.height = screen.height;
+        self.damage = Damage::new(screen.width, screen.height);
+        self.damage.add_all();
+        self.out_buf
+            .extend_from_slice(&(screen.width as u16).to_be_bytes());
+        self.out_buf
+            .extend_from_slice(&(screen.height as u16).to_be_bytes());
+        PixelFormat::XRGB8888.encode(&mut self.out_buf);
+        self.out_buf
+            .extend_from_slice(&(SERVER_NAME.len() as u32).to_be_bytes());
+        self.out_buf.extend_from_slice(SERVER_NAME);
+        self.state = ClientState::Running;
+    }
+
+    /// Queues a FramebufferUpdate message if the client asked for one, the previous update has
+    /// been sent and some part of `screen` changed since then.
+    fn queue_update(&mut self, screen: &Screen) {
+        if self.state != ClientState::Running || !self.update_requested || !self.out_buf.is_empty()
+        {
+            return;
+        }
+        let resize = (self.width, self.height) != (screen.width, screen.height);
+        if resize {
+            self.width = screen.width;
+            self.height = screen.height;
+            self.damage = Damage::new(screen.width, screen.height);
+            self.damage.add_all();
+        } else if self.damage.is_empty() {
+            return;
+        }
+        let rects = self.damage.take_rects();
+        let count = rects.len() + resize as usize;
+        self.out_buf.extend_from_slice(&[MSG_FRAMEBUFFER_UPDATE, 0]);
+        self.out_buf
+            .extend_from_slice(&(count.min(u16::MAX as usize) as u16).to_be_bytes());
+        if resize {
+            Rect {
+                x: 0,
+                y: 0,
+                width: screen.width,
+                height: screen.height,
+            }
+            .encode_header(ENCODING_DESKTOP_SIZE, &mut self.out_buf);
+        }
+        for rect in rects.iter().take(u16::MAX as usize - resize as usize) {
+            rect.encode_header(ENCODING_RAW, &mut self.out_buf);
+            screen.encode_raw(rect, &self.format, &mut self.out_buf);
+        }
+        self.update_requested = false;
+    }
+}
+
+#[derive(EventToken)]
+enum VncToken {
+    Listener { scanout_id: u32 },
+    Client { client_id: u32 },
+}
+
+/// State shared between the display and its surfaces.
+struct VncState {
+    wait_ctx: WaitContext<VncToken>,
+    listeners: Vec<TcpListener>,
+    screens: BTreeMap<u32, Screen>,
+    clients: BTreeMap<u32, Client>,
+    next_client_id: u32,
+    tracking_id: u16,
+    events: VecDeque<(u32, GpuDisplayEvents)>,
+}
+
+impl VncState {
+    /// Handles the sockets that are ready without blocking.
+    fn process_io(&mut self) -> GpuDisplayResult<()> {
+        let triggered = self.wait_ctx.wait_timeout(Duration::ZERO)?;
+        for event in triggered.iter() {
+            match event.token {
+                VncToken::Listener { scanout_id } => self.accept_clients(scanout_id),
+                VncToken::Client { client_id } => {
+                    let readable = event.is_readable || event.is_hungup;
+                    self.with_client(client_id, |state, client| {
+                        if readable {
+                            client.read()?;
+                            state.process_input(client)?;
+                        }
+                        state.send_pending(client_id, client)
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn accept_clients(&mut self, scanout_id: u32) {
+        loop {
+            match self.listeners[scanout_id as usize].accept() {
+                Ok((stream, addr)) => match self.add_client(stream, scanout_id) {
+                    Ok(()) => info!("vnc client {} connected to scanout {}", addr, scanout_id),
+                    Err(e) => error!("failed to add vnc client {}: {:#}", addr, e),
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    error!("failed to accept vnc client: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+
+    fn add_client(&mut self, stream: TcpStream, scanout_id: u32) -> anyhow::Result<()> {
+        stream
+            .set_nonblocking(true)
+            .context("failed to set client socket non-blocking")?;
+        let client_id = self.next_client_id;
+        self.next_client_id = self.next_client_id.wrapping_add(1);
+        let client = Client::new(stream, scanout_id);
+        // The ProtocolVersion message is pending, so start by polling for writability.
+        self.wait_ctx
+            .add_for_event(
+                &client.stream,
+                EventType::ReadWrite,
+                VncToken::Client { client_id },
+            )
+            .context("failed to add client to wait context")?;
+        self.clients.insert(client_id, client);
+        Ok(())
+    }
+
+    /// Runs `f` on client `client_id` and disconnects the client if `f` fails.
+    fn with_client<F>(&mut self, client_id: u32, f: F)
+    where
+        F: FnOnce(&mut VncState, &mut Client) -> anyhow::Result<()>,
+    {
+        let Some(mut client) = self.clients.remove(&client_id) else {
+            return;
+        };
+        match f(self, &mut client) {
+            Ok(()) => {
+                self.clients.insert(client_id, client);
+            }
+            Err(e) => {
+                info!("vnc client {} disconnected: {:#}", client_id, e);
+                let _ = self.wait_ctx.delete(&client.stream);
+            }
+        }
+    }
+
+    fn clients_of(&self, scanout_id: u32) -> Vec<u32> {
+        self.clients
+            .iter()
+            .filter(|(_, client)| client.scanout_id == scanout_id)
+            .map(|(&client_id, _)| client_id)
+            .collect()
+    }
+
+    /// Handles the complete messages received from `client`.
+    fn process_input(&mut self, client: &mut Client) -> anyhow::Result<()> {
+        loop {
+            match client.state {
+                ClientState::Version => {
+                    let Some(pos) = client.in_buf.iter().position(|&b| b == b'\n') else {
+                        if client.in_buf.len() >= SERVER_VERSION.len() {
+                            bail!("invalid protocol version");
+                        }
+                        break;
+                    };
+                    client.minor_version = parse_version(&client.in_buf[..=pos])
+                        .ok_or_else(|| anyhow!("unsupported protocol version"))?;
+                    client.in_buf.drain(..=pos);
+                    if client.minor_version == 3 {
+                        // In version 3.3, the server decides the security type.
+                        client
+                            .out_buf
+                            .extend_from_slice(&u32::from(SECURITY_TYPE_NONE).to_be_bytes());
+                        client.state = ClientState::Init;
+                    } else {
+                        client.out_buf.extend_from_slice(&[1, SECURITY_TYPE_NONE]);
+                        client.state = ClientState::Security;
+                    }
+                }
+                ClientState::Security => {
+                    let Some(&security_type) = client.in_buf.first() else {
+                        break;
+                    };
+                    client.in_buf.drain(..1);
+                    if security_type != SECURITY_TYPE_NONE {
+                        bail!("unsupported security type {}", security_type);
+                    }
+                    // Version 3.7 has no SecurityResult message for the None security type.
+                    if client.minor_version >= 8 {
+                        client.out_buf.extend_from_slice(&0u32.to_be_bytes());
+                    }
+                    client.state = ClientState::Init;
+                }
+                ClientState::Init => {
+                    if client.in_buf.is_empty() {
+                        break;
+                    }
+                    // The shared flag is ignored: clients always share the scanout.
+                    client.in_buf.drain(..1);
+                    match self.screens.get(&client.scanout_id) {
+                        Some(screen) => client.send_server_init(screen),
+                        None => client.state = ClientState::WaitingForScreen,
+                    }
+                }
+                ClientState::WaitingForScreen => break,
+                ClientState::Running => {
+                    let Some((msg, len)) = parse_message(&client.in_buf)? else {
+                        break;
+                    };
+                    client.in_buf.drain(..len);
+                    self.handle_message(client, msg)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_message(&mut self, client: &mut Client, msg: ClientMessage) -> anyhow::Result<()> {
+        match msg {
+            ClientMessage::SetPixelFormat(format) => {
+                if !format.is_supported() {
+                    bail!("unsupported pixel format {:?}", format);
+                }
+                client.format = format;
+            }
+            ClientMessage::SetEncodings(encodings) => {
+                client.supports_desktop_size = encodings.contains(&ENCODING_DESKTOP_SIZE);
+            }
+            ClientMessage::FramebufferUpdateRequest { incremental, rect } => {
+                if !incremental {
+                    client.damage.add_rect(&rect);
+                }
+                client.update_requested = true;
+            }
+            ClientMessage::KeyEvent { down, keysym } => match keysym_to_linux_keycode(keysym) {
+                Some(code) => self.queue_event(
+                    client.scanout_id,
+                    EventDeviceKind::Keyboard,
+                    vec![virtio_input_event::key(code, down, false)],
+                ),
+                None => warn!("ignoring unknown vnc keysym {:#x}", keysym),
+            },
+            ClientMessage::PointerEvent { buttons, x, y } => {
+                self.handle_pointer(client, buttons, x.into(), y.into())
+            }
+            ClientMessage::ClientCutText => {}
+        }
+        Ok(())
+    }
+
+    fn handle_pointer(&mut self, client: &mut Client, buttons: u8, x: i32, y: i32) {
+        let pressed = buttons & !client.buttons;
+        let released = client.buttons & !buttons;
+        client.buttons = buttons;
+
+        // Like the other backends, only a single touch driven by the left button is supported.
+        // The touch event *must* be first per the Linux input subsystem's guidance.
+        let touch = if pressed & BUTTON_LEFT != 0 {
+            self.tracking_id = self.tracking_id.wrapping_add(1);
+            Some(vec![
+                virtio_input_event::multitouch_slot(0),
+                virtio_input_event::multitouch_tracking_id(self.tracking_id.into()),
+                virtio_input_event::multitouch_absolute_x(x),
+                virtio_input_event::multitouch_absolute_y(y),
+            ])
+        } else if released & BUTTON_LEFT != 0 {
+            Some(vec![
+                virtio_input_event::multitouch_slot(0),
+                virtio_input_event::multitouch_tracking_id(-1),
+            ])
+        } else if buttons & BUTTON_LEFT != 0 {
+            Some(vec![
+                virtio_input_event::multitouch_slot(0),
+                virtio_input_event::multitouch_tracking_id(self.tracking_id.into()),
+                virtio_input_event::multitouch_absolute_x(x),
+                virtio_input_event::multitouch_absolute_y(y),
+            ])
+        } else {
+            None
+        };
+        if let Some(events) = touch {
+            self.queue_event(client.scanout_id, EventDeviceKind::Touchscreen, events);
+        }
+
+        // Clients report each wheel step as a press and release of a button.
+        let wheel =
+            i32::from(pressed & BUTTON_WHEEL_UP != 0) - i32::from(pressed & BUTTON_WHEEL_DOWN != 0);
+        if wheel != 0 {
+            self.queue_event(
+                client.scanout_id,
+                EventDeviceKind::Mouse,
+                vec![virtio_input_event::wheel(wheel)],
+            );
+        }
+    }
+
+    /// Queues `events` for the surface of `scanout_id`. Events are dropped while the scanout has
+    /// no surface.
+    fn queue_event(
+        &mut self,
+        scanout_id: u32,
+        device_type: EventDeviceKind,
+        events: Vec<virtio_input_event>,
+    ) {
+        if let Some(screen) = self.screens.get(&scanout_id) {
+            self.events.push_back((
+                screen.surface_id,
+                GpuDisplayEvents {
+                    events,
+                    device_type,
+                },
+            ));
+        }
+    }
+
+    /// Sends any pending data to `client`, along with a framebuffer update if possible.
+    fn send_pending(&mut self, client_id: u32, client: &mut Client) -> anyhow::Result<()> {
+        client.write()?;
+        if let Some(screen) = self.screens.get(&client.scanout_id) {
+            client.queue_update(screen);
+            client.write()?;
+        }
+        let polling_writable = !client.out_buf.is_empty();
+        if polling_writable != client.polling_writable {
+            let event_type = if polling_writable {
+                EventType::ReadWrite
+            } else {
+                EventType::Read
+            };
+            self.wait_ctx
+                .modify(&client.stream, event_type, VncToken::Client { client_id })
+                .context("failed to modify wait context")?;
+            client.polling_writable = polling_writable;
+        }
+        Ok(())
+    }
+
+    fn create_screen(&mut self, scanout_id: u32, surface_id: u32, width: u32, height: u32) {
+        self.screens
+            .insert(scanout_id, Screen::new(surface_id, width, height));
+        for client_id in self.clients_of(scanout_id) {
+            self.with_client(client_id, |state, client| {
+                let screen = &state.screens[&scanout_id];
+                match client.state {
+                    ClientState::WaitingForScreen => client.send_server_init(screen),
+                    ClientState::Running => {
+                        if (client.width, client.height) != (screen.width, screen.height)
+                            && !client.supports_desktop_size
+                        {
+                            bail!("client does not support resizing");
+                        }
+                        client.damage.add_all();
+                    }
+                    _ => {}
+                }
+                state.send_pending(client_id, client)
+            });
+        }
+    }
+
+    fn flip_screen(&mut self, scanout_id: u32, surface_id: u32, pixels: &[u8]) {
+        let damage = match self.screens.get_mut(&scanout_id) {
+            Some(screen) if screen.surface_id == surface_id => screen.update(pixels),
+            _ => return,
+        };
+        if damage.is_empty() {
+            return;
+        }
+        for client_id in self.clients_of(scanout_id) {
+            self.with_client(client_id, |state, client| {
+                if client.state == ClientState::Running {
+                    client.damage.merge(&damage);
+                }
+                state.send_pending(client_id, client)
+            });
+        }
+    }
+
+    fn remove_screen(&mut self, scanout_id: u32, surface_id: u32) {
+        if self
+            .screens
+            .get(&scanout_id)
+            .is_some_and(|screen| screen.surface_id == surface_id)
+        {
+            self.screens.remove(&scanout_id);
+        }
+    }
+}
+
+struct VncSurface {
+    state: Rc<RefCell<VncState>>,
+    surface_id: u32,
+    scanout_id: u32,
+    width: u32,
+    buffer: Vec<u8>,
+}
+
+impl GpuDisplaySurface for VncSurface {
+    fn surface_descriptor(&self) -> u64 {
+        self.surface_id.into()
+    }
+
+    fn framebuffer(&mut self) -> Option<GpuDisplayFramebuffer> {
+        Some(GpuDisplayFramebuffer::new(
+            VolatileSlice::new(self.buffer.as_mut_slice()),
+            self.width * BYTES_PER_PIXEL,
+            BYTES_PER_PIXEL,
+        ))
+    }
+
+    fn flip(&mut self) {
+        self.state
+            .borrow_mut()
+            .flip_screen(self.scanout_id, self.surface_id, &self.buffer);
+    }
+}
+
+impl Drop for VncSurface {
+    fn drop(&mut self) {
+        self.state
+            .borrow_mut()
+            .remove_screen(self.scanout_id, self.surface_id);
+    }
+}
+
+pub struct DisplayVnc {
+    state: Rc<RefCell<VncState>>,
+    current_event: Option<GpuDisplayEvents>,
+}
+
+impl DisplayVnc {
+    /// Creates a display that serves scanout `i` to the clients connecting to `listeners[i]`.
+    pub fn new(listeners: Vec<TcpListener>) -> GpuDisplayResult<DisplayVnc> {
+        let wait_ctx = WaitContext::new()?;
+        for (scanout_id, listener) in listeners.iter().enumerate() {
+            listener.set_nonblocking(true)?;
+            wait_ctx.add(
+                listener,
+                VncToken::Listener {
+                    scanout_id: scanout_id as u32,
+                },
+            )?;
+        }
+
+        Ok(DisplayVnc {
+            state: Rc::new(RefCell::new(VncState {
+                wait_ctx,
+                listeners,
+                screens: BTreeMap::new(),
+                clients: BTreeMap::new(),
+                next_client_id: 0,
+                tracking_id: 0,
+                events: VecDeque::new(),
+            })),
+            current_event: None,
+        })
+    }
+}
+
+impl DisplayT for DisplayVnc {
+    fn pending_events(&self) -> bool {
+        !self.state.borrow().events.is_empty()
+    }
+
+    fn flush(&self) {
+        if let Err(e) = self.state.borrow_mut().process_io() {
+            error!("failed to process vnc connections: {}", e);
+        }
+    }
+
+    fn next_event(&mut self) -> GpuDisplayResult<u64> {
+        // Surface ids are non-zero, so events that were not queued match no surface.
+        let Some((surface_id, events)) = self.state.borrow_mut().events.pop_front() else {
+            return Ok(0);
+        };
+        self.current_event = Some(events);
+        Ok(surface_id.into())
+    }
+
+    fn handle_next_event(
+        &mut self,
+        _surface: &mut Box<dyn GpuDisplaySurface>,
+    ) -> Option<GpuDisplayEvents> {
+        self.current_event.take()
+    }
+
+    fn create_surface(
+        &mut self,
+        parent_surface_id: Option<u32>,
+        surface_id: u32,
+        scanout_id: Option<u32>,
+        display_params: &DisplayParameters,
+        _surf_type: SurfaceType,
+    ) -> GpuDisplayResult<Box<dyn GpuDisplaySurface>> {
+        if parent_surface_id.is_some() {
+            return Err(GpuDisplayError::Unsupported);
+        }
+
+        let scanout_id = scanout_id.unwrap_or(0);
+        let (width, height) = display_params.get_virtual_display_size();
+        let mut state = self.state.borrow_mut();
+        if scanout_id as usize >= state.listeners.len() {
+            warn!(
+                "scanout {} has no vnc listener and will not be exported",
+                scanout_id
+            );
+        }
+        state.create_screen(scanout_id, surface_id, width, height);
+
+        Ok(Box::new(VncSurface {
+            state: self.state.clone(),
+            surface_id,
+            scanout_id,
+            width,
+            buffer: vec![0; (width * height * BYTES_PER_PIXEL) as usize],
+        }))
+    }
+}
+
+impl SysDisplayT for DisplayVnc {}
+
+impl AsRawDescriptor for DisplayVnc {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        self.state.borrow().wait_ctx.as_raw_descriptor()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use vm_control::gpu::DisplayMode;
+
+    use super::*;
+
+    #[test]
+    fn pixel_format_conversion() {
+        let rgb565 = PixelFormat {
+            bits_per_pixel: 16,
+            depth: 16,
+            big_endian: true,
+            true_color: true,
+            red_max: 31,
+            green_max: 63,
+            blue_max: 31,
+            red_shift: 11,
+            green_shift: 5,
+            blue_shift: 0,
+        };
+        assert!(rgb565.is_supported());
+        let mut out = Vec::new();
+        rgb565.write_pixel(0x00ff_0000, &mut out);
+        rgb565.write_pixel(0x0000_ff00, &mut out);
+        rgb565.write_pixel(0x0000_00ff, &mut out);
+        assert_eq!(out, [0xf8, 0x00, 0x07, 0xe0, 0x00, 0x1f]);
+
+        let mut encoded = Vec::new();
+        rgb565.encode(&mut encoded);
+        assert_eq!(PixelFormat::parse(&encoded), rgb565);
+
+        let color_map = PixelFormat {
+            true_color: false,
+            ..PixelFormat::XRGB8888
+        };
+        assert!(!color_map.is_supported());
+    }
+
+    #[test]
+    fn damage_rects() {
+        let mut damage = Damage::new(150, 70);
+        assert!(damage.is_empty());
+        damage.add_rect(&Rect {
+            x: 10,
+            y: 10,
+            width: 60,
+            height: 1,
+        });
+        damage.add_rect(&Rect {
+            x: 140,
+            y: 65,
+            width: 100,
+            height: 100,
+        });
+        assert_eq!(
+            damage.take_rects(),
+            [
+                Rect {
+                    x: 0,
+                    y: 0,
+                    width: 128,
+                    height: 64
+                },
+                Rect {
+                    x: 128,
+                    y: 64,
+                    width: 22,
+                    height: 6
+                },
+            ]
+        );
+        assert!(damage.is_empty());
+    }
+
+    #[test]
+    fn screen_update_damage() {
+        let mut screen = Screen::new(1, 130, 10);
+        let mut pixels = screen.pixels.clone();
+        assert!(screen.update(&pixels).is_empty());
+        // Change one pixel of the last tile.
+        pixels[(129 * BYTES_PER_PIXEL) as usize] = 0xff;
+        assert_eq!(
+            screen.update(&pixels).take_rects(),
+            [Rect {
+                x: 128,
+                y: 0,
+                width: 2,
+                height: 10
+            }]
+        );
+        assert_eq!(screen.pixels, pixels);
+    }
+
+    #[test]
+    fn parse_messages() {
+        assert!(parse_message(&[MSG_KEY_EVENT, 1, 0, 0]).unwrap().is_none());
+        assert_eq!(
+            parse_message(&[MSG_KEY_EVENT, 1, 0, 0, 0, 0, 0xff, 0x0d, 42])
+                .unwrap()
+                .unwrap(),
+            (
+                ClientMessage::KeyEvent {
+                    down: true,
+                    keysym: 0xff0d
+                },
+                8
+            )
+        );
+        assert_eq!(
+            parse_message(&[
+                MSG_SET_ENCODINGS,
+                0,
+                0,
+                2,
+                0,
+                0,
+                0,
+                0,
+                0xff,
+                0xff,
+                0xff,
+                0x21
+            ])
+            .unwrap()
+            .unwrap(),
+            (
+                ClientMessage::SetEncodings(vec![ENCODING_RAW, ENCODING_DESKTOP_SIZE]),
+                12
+            )
+        );
+        assert_eq!(
+            parse_message(&[MSG_FRAMEBUFFER_UPDATE_REQUEST, 0, 0, 1, 0, 2, 0, 3, 0, 4])
+                .unwrap()
+                .unwrap(),
+            (
+                ClientMessage::FramebufferUpdateRequest {
+                    incremental: false,
+                    rect: Rect {
+                        x: 1,
+                        y: 2,
+                        width: 3,
+                        height: 4
+                    }
+                },
+                10
+            )
+        );
+        assert!(parse_message(&[MSG_CLIENT_CUT_TEXT, 0, 0, 0, 0xff, 0xff, 0xff, 0xff]).is_err());
+        assert!(parse_message(&[200]).is_err());
+    }
+
+    #[test]
+    fn versions() {
+        assert_eq!(parse_version(b"RFB 003.003\n"), Some(3));
+        assert_eq!(parse_version(b"RFB 003.005\n"), Some(3));
+        assert_eq!(parse_version(b"RFB 003.007\n"), Some(7));
+        assert_eq!(parse_version(b"RFB 003.889\n"), Some(8));
+        assert_eq!(parse_version(b"RFB 004.000\n"), None);
+        assert_eq!(parse_version(b"HTTP/1.1 200\n"), None);
+    }
+
+    #[test]
+    fn keysyms() {
+        assert_eq!(keysym_to_linux_keycode('a' as u32), Some(KEY_A));
+        assert_eq!(keysym_to_linux_keycode('Z' as u32), Some(KEY_Z));
+        assert_eq!(keysym_to_linux_keycode('?' as u32), Some(KEY_SLASH));
+        assert_eq!(keysym_to_linux_keycode(0xff0d), Some(KEY_ENTER));
+        assert_eq!(keysym_to_linux_keycode(0xffc9), Some(KEY_F12));
+        assert_eq!(keysym_to_linux_keycode(0xffb5), Some(KEY_KP5));
+        assert_eq!(keysym_to_linux_keycode(0x20ac), None);
+    }
+
+    fn read_exact(stream: &mut TcpStream, display: &DisplayVnc, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        let mut read = 0;
+        while read < len {
+            display.flush();
+            match stream.read(&mut buf[read..]) {
+                Ok(0) => panic!("connection closed"),
+                Ok(n) => read += n,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => panic!("read failed: {}", e),
+            }
+        }
+        buf
+    }
+
+    #[test]
+    fn serve_client() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut display = DisplayVnc::new(vec![listener]).unwrap();
+        let mut surface = display
+            .create_surface(
+                None,
+                1,
+                Some(0),
+                &DisplayParameters::default_with_mode(DisplayMode::Windowed(100, 10)),
+                SurfaceType::Scanout,
+            )
+            .unwrap();
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_nonblocking(true).unwrap();
+        assert_eq!(read_exact(&mut stream, &display, 12), SERVER_VERSION);
+        stream.write_all(b"RFB 003.008\n").unwrap();
+        assert_eq!(
+            read_exact(&mut stream, &display, 2),
+            [1, SECURITY_TYPE_NONE]
+        );
+        stream.write_all(&[SECURITY_TYPE_NONE]).unwrap();
+        assert_eq!(read_exact(&mut stream, &display, 4), [0, 0, 0, 0]);
+        stream.write_all(&[1]).unwrap();
+        let server_init = read_exact(&mut stream, &display, 24 + SERVER_NAME.len());
+        assert_eq!(server_init[..4], [0, 100, 0, 10]);
+        assert_eq!(
+            PixelFormat::parse(&server_init[4..20]),
+            PixelFormat::XRGB8888
+        );
+
+        // The first update covers the whole screen.
+        stream
+            .write_all(&[MSG_FRAMEBUFFER_UPDATE_REQUEST, 1, 0, 0, 0, 0, 0, 100, 0, 10])
+            .unwrap();
+        let update = read_exact(&mut stream, &display, 4 + 12 + 100 * 10 * 4);
+        assert_eq!(update[..4], [MSG_FRAMEBUFFER_UPDATE, 0, 0, 1]);
+        assert_eq!(update[4..16], [0, 0, 0, 0, 0, 100, 0, 10, 0, 0, 0, 0]);
+
+        // Later updates only cover the damaged tiles.
+        stream
+            .write_all(&[MSG_FRAMEBUFFER_UPDATE_REQUEST, 1, 0, 0, 0, 0, 0, 100, 0, 10])
+            .unwrap();
+        display.flush();
+        surface
+            .framebuffer()
+            .unwrap()
+            .as_volatile_slice()
+            .sub_slice(70 * 4, 4)
+            .unwrap()
+            .write_bytes(0x80);
+        surface.flip();
+        let update = read_exact(&mut stream, &display, 4 + 12 + 36 * 10 * 4);
+        assert_eq!(update[..4], [MSG_FRAMEBUFFER_UPDATE, 0, 0, 1]);
+        assert_eq!(update[4..16], [0, 64, 0, 0, 0, 36, 0, 10, 0, 0, 0, 0]);
+        assert_eq!(update[16 + 6 * 4..16 + 7 * 4], [0x80; 4]);
+
+        // Input is routed to the surface of the scanout.
+        stream
+            .write_all(&[MSG_KEY_EVENT, 1, 0, 0, 0, 0, 0, b'a'])
+            .unwrap();
+        while !display.pending_events() {
+            display.flush();
+        }
+        assert_eq!(display.next_event().unwrap(), 1);
+        let events = display.handle_next_event(&mut surface).unwrap();
+        assert!(events.device_type == EventDeviceKind::Keyboard);
+        assert_eq!(events.events, [virtio_input_event::key(KEY_A, true, false)]);
+    }
+}
@@ -3,7 +3,7 @@
 // found in the LICENSE file.
 
 //! Crate for displaying simple surfaces and GPU buffers over a low-level display backend such as
-//! Wayland or X.
+//! Wayland, X or VNC.
 
 use std::collections::BTreeMap;
 use std::io::Error as IoError;
@@ -33,6 +33,8 @@ mod gpu_display_android;
 #[cfg(feature = "android_display_stub")]
 mod gpu_display_android_stub;
 mod gpu_display_stub;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+mod gpu_display_vnc;
 #[cfg(windows)]
 mod gpu_display_win;
 #[cfg(any(target_os = "android", target_os = "linux"))]
@@ -2,12 +2,14 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::net::TcpListener;
 use std::path::Path;
 
 use base::AsRawDescriptor;
 use base::RawDescriptor;
 use base::WaitContext;
 
+use crate::gpu_display_vnc::DisplayVnc;
 use crate::gpu_display_wl::DisplayWl;
 use crate::DisplayEventToken;
 use crate::DisplayT;
@@ -45,6 +47,9 @@ impl GpuDisplayExt for GpuDisplay {
 pub trait UnixGpuDisplayExt {
     /// Opens a fresh connection to the compositor.
     fn open_wayland<P: AsRef<Path>>(wayland_path: Option<P>) -> GpuDisplayResult<GpuDisplay>;
+
+    /// Starts serving scanout `i` over VNC to the clients connecting to `listeners[i]`.
+    fn open_vnc(listeners: Vec<TcpListener>) -> GpuDisplayResult<GpuDisplay>;
 }
 
 impl UnixGpuDisplayExt for GpuDisplay {
@@ -65,6 +70,21 @@ impl UnixGpuDisplayExt for GpuDisplay {
             wait_ctx,
         })
     }
+
+    fn open_vnc(listeners: Vec<TcpListener>) -> GpuDisplayResult<GpuDisplay> {
+        let display = DisplayVnc::new(listeners)?;
+
+        let wait_ctx = WaitContext::new()?;
+        wait_ctx.add(&display, DisplayEventToken::Display)?;
+
+        Ok(GpuDisplay {
+            inner: Box::new(display),
+            next_id: 1,
+            event_devices: Default::default(),
+            surfaces: Default::default(),
+            wait_ctx,
+        })
+    }
 }
 
 impl AsRawDescriptor for GpuDisplay {
@@ -36,6 +36,16 @@ pub enum HypervisorCap {
     /// If this capability is declared, then crosvm will not try to initialize vcpu
     /// registers when creating the VM.
     HypervisorInitializedBootContext,
+    /// The complete architectural state of a vCPU can be read back and written again through
+    /// the `Vcpu` interface.
+    ///
+    /// Snapshot and restore of a VM is refused when this capability is not declared.
+    VcpuSnapshot,
+    /// Guest memory is ordinary host memory that may be moved out and faulted back in with
+    /// userfaultfd, without the hypervisor pinning it or taking ownership of it.
+    ///
+    /// vmm-swap is refused when this capability is not declared.
+    SwappableGuestMemory,
 }
 
 /// A capability the `Vm` can possibly expose.
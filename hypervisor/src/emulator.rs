@@ -0,0 +1,136 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! A hypervisor that interprets x86_64 guest code in userspace.
+//!
+//! The emulator implements the `Hypervisor`, `Vm` and `Vcpu` traits without any kernel support, so
+//! that device models and boot paths can be tested deterministically on hosts without
+//! virtualization. It covers the general purpose instruction set in real, protected and long mode,
+//! paging, interrupt and exception delivery, and MMX; SSE arithmetic, AVX, XSAVE and virtualization
+//! extensions are not implemented and stop the vCPU with `VcpuExit::InternalError`.
+
+use std::arch::x86_64::CpuidResult;
+
+use base::Result;
+
+use crate::CpuId;
+use crate::CpuIdEntry;
+use crate::Hypervisor;
+use crate::HypervisorCap;
+use crate::HypervisorX86_64;
+
+mod cpu;
+mod exec;
+mod vcpu;
+pub use vcpu::*;
+mod vm;
+pub use vm::*;
+
+/// Guest physical address width reported to the guest and the VMM.
+const PHYS_ADDR_BITS: u8 = 40;
+
+/// Vendor string of CPUID leaf 0, in ebx, edx, ecx order.
+const VENDOR: &[u8; 12] = b"CrosVMEmuCPU";
+
+/// The emulator "hypervisor". It holds no state; every VM created from it is independent.
+#[derive(Clone, Debug, Default)]
+pub struct Emulator;
+
+impl Emulator {
+    pub fn new() -> Emulator {
+        Emulator
+    }
+}
+
+impl Hypervisor for Emulator {
+    fn try_clone(&self) -> Result<Self> {
+        Ok(Emulator)
+    }
+
+    fn check_capability(&self, cap: HypervisorCap) -> bool {
+        matches!(
+            cap,
+            HypervisorCap::UserMemory | HypervisorCap::ImmediateExit | HypervisorCap::VcpuSnapshot
+        )
+    }
+}
+
+fn cpuid_entry(function: u32, eax: u32, ebx: u32, ecx: u32, edx: u32) -> CpuIdEntry {
+    CpuIdEntry {
+        function,
+        index: 0,
+        flags: 0,
+        cpuid: CpuidResult { eax, ebx, ecx, edx },
+    }
+}
+
+impl HypervisorX86_64 for Emulator {
+    /// Returns the features implemented by the emulator; unlike hardware backends this does not
+    /// depend on the host processor.
+    fn get_supported_cpuid(&self) -> Result<CpuId> {
+        let vendor = |i: usize| u32::from_le_bytes(VENDOR[i * 4..i * 4 + 4].try_into().unwrap());
+        // PSE, TSC, MSR, PAE, CX8, PGE, CMOV, MMX and FXSR.
+        let features_1_edx = (1 << 3)
+            | (1 << 4)
+            | (1 << 5)
+            | (1 << 6)
+            | (1 << 8)
+            | (1 << 13)
+            | (1 << 15)
+            | (1 << 23)
+            | (1 << 24);
+        // CMPXCHG16B, POPCNT and the hypervisor bit.
+        let features_1_ecx = (1 << 13) | (1 << 23) | (1 << 31);
+        // NX, RDTSCP and long mode.
+        let features_80000001_edx = (1 << 20) | (1 << 27) | (1 << 29);
+        // LAHF/SAHF in long mode.
+        let features_80000001_ecx = 1;
+
+        Ok(CpuId {
+            cpu_id_entries: vec![
+                cpuid_entry(0, 7, vendor(0), vendor(2), vendor(1)),
+                cpuid_entry(1, 0x6f1, 0, features_1_ecx, features_1_edx),
+                CpuIdEntry {
+                    // FSGSBASE.
+                    flags: 1,
+                    ..cpuid_entry(7, 0, 1, 0, 0)
+                },
+                cpuid_entry(0x8000_0000, 0x8000_0008, 0, 0, 0),
+                cpuid_entry(
+                    0x8000_0001,
+                    0,
+                    0,
+                    features_80000001_ecx,
+                    features_80000001_edx,
+                ),
+                // 48 bits of linear address space.
+                cpuid_entry(0x8000_0008, PHYS_ADDR_BITS as u32 | (48 << 8), 0, 0, 0),
+            ],
+        })
+    }
+
+    fn get_emulated_cpuid(&self) -> Result<CpuId> {
+        Ok(CpuId::new(0))
+    }
+
+    fn get_msr_index_list(&self) -> Result<Vec<u32>> {
+        Ok(cpu::SUPPORTED_MSRS.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn supported_cpuid_reports_long_mode() {
+        let cpuid = Emulator::new().get_supported_cpuid().unwrap();
+        let leaf = cpuid
+            .cpu_id_entries
+            .iter()
+            .find(|e| e.function == 0x8000_0001)
+            .unwrap();
+        assert_ne!(leaf.cpuid.edx & (1 << 29), 0);
+    }
+}
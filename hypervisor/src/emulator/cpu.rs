@@ -0,0 +1,1355 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Architectural state of an emulated vCPU along with the parts of the processor that are shared
+//! by all instructions: segmentation, paging, MSRs and event delivery.
+
+use std::arch::x86_64::_rdtsc;
+use std::arch::x86_64::CpuidResult;
+
+use crate::DebugRegs;
+use crate::Fpu;
+use crate::Regs;
+use crate::Segment;
+use crate::Sregs;
+use crate::MSR_IA32_TSC;
+
+pub(super) const FLAG_CF: u64 = 1 << 0;
+pub(super) const FLAG_PF: u64 = 1 << 2;
+pub(super) const FLAG_AF: u64 = 1 << 4;
+pub(super) const FLAG_ZF: u64 = 1 << 6;
+pub(super) const FLAG_SF: u64 = 1 << 7;
+pub(super) const FLAG_TF: u64 = 1 << 8;
+pub(super) const FLAG_IF: u64 = 1 << 9;
+pub(super) const FLAG_DF: u64 = 1 << 10;
+pub(super) const FLAG_OF: u64 = 1 << 11;
+pub(super) const FLAG_IOPL: u64 = 3 << 12;
+pub(super) const FLAG_NT: u64 = 1 << 14;
+pub(super) const FLAG_RF: u64 = 1 << 16;
+pub(super) const FLAG_VM: u64 = 1 << 17;
+pub(super) const FLAG_AC: u64 = 1 << 18;
+pub(super) const FLAG_ID: u64 = 1 << 21;
+/// Bit 1 of RFLAGS always reads as 1.
+pub(super) const FLAG_FIXED: u64 = 1 << 1;
+/// RFLAGS bits that `popf` and `iret` may change.
+pub(super) const FLAGS_WRITABLE: u64 = FLAG_CF
+    | FLAG_PF
+    | FLAG_AF
+    | FLAG_ZF
+    | FLAG_SF
+    | FLAG_TF
+    | FLAG_IF
+    | FLAG_DF
+    | FLAG_OF
+    | FLAG_IOPL
+    | FLAG_NT
+    | FLAG_RF
+    | FLAG_AC
+    | FLAG_ID;
+/// The arithmetic status flags.
+pub(super) const FLAGS_STATUS: u64 = FLAG_CF | FLAG_PF | FLAG_AF | FLAG_ZF | FLAG_SF | FLAG_OF;
+
+pub(super) const CR0_PE: u64 = 1 << 0;
+pub(super) const CR0_MP: u64 = 1 << 1;
+pub(super) const CR0_EM: u64 = 1 << 2;
+pub(super) const CR0_TS: u64 = 1 << 3;
+pub(super) const CR0_ET: u64 = 1 << 4;
+pub(super) const CR0_NE: u64 = 1 << 5;
+pub(super) const CR0_WP: u64 = 1 << 16;
+pub(super) const CR0_AM: u64 = 1 << 18;
+pub(super) const CR0_NW: u64 = 1 << 29;
+pub(super) const CR0_CD: u64 = 1 << 30;
+pub(super) const CR0_PG: u64 = 1 << 31;
+const CR0_VALID: u64 = CR0_PE
+    | CR0_MP
+    | CR0_EM
+    | CR0_TS
+    | CR0_ET
+    | CR0_NE
+    | CR0_WP
+    | CR0_AM
+    | CR0_NW
+    | CR0_CD
+    | CR0_PG;
+
+pub(super) const CR4_TSD: u64 = 1 << 2;
+pub(super) const CR4_PSE: u64 = 1 << 4;
+pub(super) const CR4_PAE: u64 = 1 << 5;
+pub(super) const CR4_PGE: u64 = 1 << 7;
+pub(super) const CR4_FSGSBASE: u64 = 1 << 16;
+/// CR4 bits the emulator accepts. Everything else, including features that are not advertised
+/// through CPUID such as VMX, SMX, LA57 or XSAVE, raises #GP.
+const CR4_VALID: u64 = (1 << 0) // VME
+    | (1 << 1) // PVI
+    | CR4_TSD
+    | (1 << 3) // DE
+    | CR4_PSE
+    | CR4_PAE
+    | (1 << 6) // MCE
+    | CR4_PGE
+    | (1 << 8) // PCE
+    | (1 << 9) // OSFXSR
+    | (1 << 10) // OSXMMEXCPT
+    | (1 << 11) // UMIP
+    | CR4_FSGSBASE;
+
+pub(super) const EFER_SCE: u64 = 1 << 0;
+pub(super) const EFER_LME: u64 = 1 << 8;
+pub(super) const EFER_LMA: u64 = 1 << 10;
+pub(super) const EFER_NXE: u64 = 1 << 11;
+const EFER_VALID: u64 = EFER_SCE | EFER_LME | EFER_LMA | EFER_NXE;
+
+pub(super) const MSR_IA32_APICBASE: u32 = 0x1b;
+pub(super) const MSR_IA32_SYSENTER_CS: u32 = 0x174;
+pub(super) const MSR_IA32_SYSENTER_ESP: u32 = 0x175;
+pub(super) const MSR_IA32_SYSENTER_EIP: u32 = 0x176;
+pub(super) const MSR_IA32_MISC_ENABLE: u32 = 0x1a0;
+pub(super) const MSR_IA32_CR_PAT: u32 = 0x277;
+pub(super) const MSR_EFER: u32 = 0xc0000080;
+pub(super) const MSR_STAR: u32 = 0xc0000081;
+pub(super) const MSR_LSTAR: u32 = 0xc0000082;
+pub(super) const MSR_CSTAR: u32 = 0xc0000083;
+pub(super) const MSR_SYSCALL_MASK: u32 = 0xc0000084;
+pub(super) const MSR_FS_BASE: u32 = 0xc0000100;
+pub(super) const MSR_GS_BASE: u32 = 0xc0000101;
+pub(super) const MSR_KERNEL_GS_BASE: u32 = 0xc0000102;
+pub(super) const MSR_TSC_AUX: u32 = 0xc0000103;
+
+/// Every MSR the emulator implements. Accessing any other MSR raises #GP in the guest.
+pub(super) const SUPPORTED_MSRS: [u32; 17] = [
+    MSR_IA32_TSC,
+    MSR_IA32_APICBASE,
+    MSR_IA32_SYSENTER_CS,
+    MSR_IA32_SYSENTER_ESP,
+    MSR_IA32_SYSENTER_EIP,
+    MSR_IA32_MISC_ENABLE,
+    MSR_IA32_CR_PAT,
+    MSR_EFER,
+    MSR_STAR,
+    MSR_LSTAR,
+    MSR_CSTAR,
+    MSR_SYSCALL_MASK,
+    MSR_FS_BASE,
+    MSR_GS_BASE,
+    MSR_KERNEL_GS_BASE,
+    MSR_TSC_AUX,
+    // Writes are ignored; reads return 0.
+    0x8b, // IA32_BIOS_SIGN_ID
+];
+
+pub(super) const VECTOR_DE: u8 = 0;
+pub(super) const VECTOR_DB: u8 = 1;
+pub(super) const VECTOR_NMI: u8 = 2;
+pub(super) const VECTOR_BP: u8 = 3;
+pub(super) const VECTOR_OF: u8 = 4;
+pub(super) const VECTOR_UD: u8 = 6;
+pub(super) const VECTOR_NM: u8 = 7;
+pub(super) const VECTOR_DF: u8 = 8;
+pub(super) const VECTOR_TS: u8 = 10;
+pub(super) const VECTOR_NP: u8 = 11;
+pub(super) const VECTOR_SS: u8 = 12;
+pub(super) const VECTOR_GP: u8 = 13;
+pub(super) const VECTOR_PF: u8 = 14;
+
+const PF_PRESENT: u32 = 1 << 0;
+const PF_WRITE: u32 = 1 << 1;
+const PF_USER: u32 = 1 << 2;
+const PF_RSVD: u32 = 1 << 3;
+const PF_FETCH: u32 = 1 << 4;
+
+const PTE_PRESENT: u64 = 1 << 0;
+const PTE_WRITABLE: u64 = 1 << 1;
+const PTE_USER: u64 = 1 << 2;
+const PTE_ACCESSED: u64 = 1 << 5;
+const PTE_DIRTY: u64 = 1 << 6;
+const PTE_LARGE: u64 = 1 << 7;
+const PTE_NX: u64 = 1 << 63;
+const PTE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// An exception raised while executing an instruction or delivering an event.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) struct Exception {
+    pub vector: u8,
+    pub error_code: Option<u32>,
+    /// Faulting linear address, only meaningful for #PF.
+    pub address: u64,
+}
+
+/// Reasons the current instruction could not be retired.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum Stop {
+    /// The instruction faulted and the exception must be delivered to the guest.
+    Exception(Exception),
+    /// The instruction reads device memory, which must be provided by the VMM before the
+    /// instruction can be restarted.
+    MmioRead { address: u64, size: usize },
+    /// The instruction reads an I/O port, which must be provided by the VMM before the instruction
+    /// can be restarted.
+    PioRead { port: u16, size: usize },
+    /// The instruction or the state it needs is not implemented by the emulator.
+    Unsupported(&'static str),
+    /// An exception occurred while delivering a double fault.
+    TripleFault,
+}
+
+impl Stop {
+    pub fn exception(vector: u8) -> Stop {
+        Stop::Exception(Exception {
+            vector,
+            error_code: None,
+            address: 0,
+        })
+    }
+
+    pub fn exception_with_code(vector: u8, error_code: u32) -> Stop {
+        Stop::Exception(Exception {
+            vector,
+            error_code: Some(error_code),
+            address: 0,
+        })
+    }
+
+    pub fn gp(error_code: u32) -> Stop {
+        Stop::exception_with_code(VECTOR_GP, error_code)
+    }
+
+    pub fn ud() -> Stop {
+        Stop::exception(VECTOR_UD)
+    }
+
+    fn page_fault(address: u64, error_code: u32) -> Stop {
+        Stop::Exception(Exception {
+            vector: VECTOR_PF,
+            error_code: Some(error_code),
+            address,
+        })
+    }
+}
+
+/// The guest physical address space and I/O ports as seen by the emulated processor.
+///
+/// All accesses made while executing one instruction form a transaction: if the instruction does
+/// not retire, `rollback` undoes every memory write made through `write`.
+pub(super) trait Bus {
+    /// Reads guest physical memory. Device memory that the VMM has not provided data for yet
+    /// returns `Stop::MmioRead`.
+    fn read(&mut self, address: u64, data: &mut [u8]) -> Result<(), Stop>;
+
+    /// Writes guest physical memory. Writes to device memory are queued for the VMM.
+    fn write(&mut self, address: u64, data: &[u8]) -> Result<(), Stop>;
+
+    /// Reads guest RAM without involving the VMM, for instruction fetches and page walks.
+    fn read_ram(&mut self, address: u64, data: &mut [u8]) -> Result<(), Stop>;
+
+    /// Writes guest RAM outside of the transaction, for page table accessed and dirty bits.
+    fn write_ram(&mut self, address: u64, data: &[u8]);
+
+    /// Reads an I/O port. Returns `Stop::PioRead` until the VMM has provided the data.
+    fn io_in(&mut self, port: u16, size: usize) -> Result<u64, Stop>;
+
+    /// Writes an I/O port. The write is queued for the VMM.
+    fn io_out(&mut self, port: u16, size: usize, value: u64);
+
+    /// Returns whether the transaction has accessed device memory or I/O ports.
+    fn touched_device(&self) -> bool;
+
+    /// Undoes all writes made in the current transaction.
+    fn rollback(&mut self);
+
+    /// Looks up the CPUID result configured for `function` and `index`.
+    fn cpuid(&self, function: u32, index: u32) -> CpuidResult;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum SegReg {
+    Es = 0,
+    Cs = 1,
+    Ss = 2,
+    Ds = 3,
+    Fs = 4,
+    Gs = 5,
+}
+
+impl SegReg {
+    pub fn from_index(index: u8) -> Option<SegReg> {
+        Some(match index {
+            0 => SegReg::Es,
+            1 => SegReg::Cs,
+            2 => SegReg::Ss,
+            3 => SegReg::Ds,
+            4 => SegReg::Fs,
+            5 => SegReg::Gs,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum Access {
+    Read,
+    Write,
+    Fetch,
+}
+
+/// How an event reaches `ArchState::deliver`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum EventSource {
+    /// External interrupt or NMI; ignores the gate DPL.
+    External,
+    /// Processor exception.
+    Exception,
+    /// `int n`, `int3` or `into`; subject to the gate DPL check.
+    Software,
+}
+
+/// Register state of one emulated processor.
+///
+/// Everything here is `Copy` so that an instruction can execute against a scratch copy that is only
+/// written back once the instruction retires.
+#[derive(Clone, Copy, Debug)]
+pub(super) struct ArchState {
+    /// General purpose registers in encoding order (rax, rcx, rdx, rbx, rsp, rbp, rsi, rdi, r8..).
+    pub gpr: [u64; 16],
+    pub rip: u64,
+    pub rflags: u64,
+    pub sregs: Sregs,
+    pub debugregs: DebugRegs,
+    pub fpu: Fpu,
+    pub xcr0: u64,
+    /// Set while an NMI handler runs, until the next `iret`.
+    pub nmi_blocked: bool,
+    pub tsc_offset: u64,
+    pub apic_base: u64,
+    pub sysenter_cs: u64,
+    pub sysenter_esp: u64,
+    pub sysenter_eip: u64,
+    pub misc_enable: u64,
+    pub pat: u64,
+    pub star: u64,
+    pub lstar: u64,
+    pub cstar: u64,
+    pub syscall_mask: u64,
+    pub kernel_gs_base: u64,
+    pub tsc_aux: u64,
+}
+
+impl ArchState {
+    /// Returns the state of a processor that just came out of reset.
+    pub fn new(bootstrap: bool) -> ArchState {
+        let mut apic_base = 0xfee0_0000 | (1 << 11);
+        if bootstrap {
+            apic_base |= 1 << 8;
+        }
+        ArchState {
+            gpr: [0; 16],
+            rip: Regs::default().rip,
+            rflags: FLAG_FIXED,
+            sregs: Sregs::default(),
+            debugregs: DebugRegs {
+                dr6: 0xffff_0ff0,
+                dr7: 0x400,
+                ..Default::default()
+            },
+            fpu: Fpu::default(),
+            xcr0: 1,
+            nmi_blocked: false,
+            tsc_offset: 0,
+            apic_base,
+            sysenter_cs: 0,
+            sysenter_esp: 0,
+            sysenter_eip: 0,
+            misc_enable: 1, // Fast strings.
+            pat: 0x0007_0406_0007_0406,
+            star: 0,
+            lstar: 0,
+            cstar: 0,
+            syscall_mask: 0,
+            kernel_gs_base: 0,
+            tsc_aux: 0,
+        }
+    }
+
+    pub fn regs(&self) -> Regs {
+        Regs {
+            rax: self.gpr[0],
+            rcx: self.gpr[1],
+            rdx: self.gpr[2],
+            rbx: self.gpr[3],
+            rsp: self.gpr[4],
+            rbp: self.gpr[5],
+            rsi: self.gpr[6],
+            rdi: self.gpr[7],
+            r8: self.gpr[8],
+            r9: self.gpr[9],
+            r10: self.gpr[10],
+            r11: self.gpr[11],
+            r12: self.gpr[12],
+            r13: self.gpr[13],
+            r14: self.gpr[14],
+            r15: self.gpr[15],
+            rip: self.rip,
+            rflags: self.rflags,
+        }
+    }
+
+    pub fn set_regs(&mut self, regs: &Regs) {
+        self.gpr = [
+            regs.rax, regs.rcx, regs.rdx, regs.rbx, regs.rsp, regs.rbp, regs.rsi, regs.rdi,
+            regs.r8, regs.r9, regs.r10, regs.r11, regs.r12, regs.r13, regs.r14, regs.r15,
+        ];
+        self.rip = regs.rip;
+        self.rflags = (regs.rflags & !(1 << 3 | 1 << 5 | 1 << 15)) | FLAG_FIXED;
+    }
+
+    pub fn seg(&self, seg: SegReg) -> &Segment {
+        match seg {
+            SegReg::Es => &self.sregs.es,
+            SegReg::Cs => &self.sregs.cs,
+            SegReg::Ss => &self.sregs.ss,
+            SegReg::Ds => &self.sregs.ds,
+            SegReg::Fs => &self.sregs.fs,
+            SegReg::Gs => &self.sregs.gs,
+        }
+    }
+
+    pub fn seg_mut(&mut self, seg: SegReg) -> &mut Segment {
+        match seg {
+            SegReg::Es => &mut self.sregs.es,
+            SegReg::Cs => &mut self.sregs.cs,
+            SegReg::Ss => &mut self.sregs.ss,
+            SegReg::Ds => &mut self.sregs.ds,
+            SegReg::Fs => &mut self.sregs.fs,
+            SegReg::Gs => &mut self.sregs.gs,
+        }
+    }
+
+    pub fn protected_mode(&self) -> bool {
+        self.sregs.cr0 & CR0_PE != 0
+    }
+
+    pub fn long_mode(&self) -> bool {
+        self.sregs.efer & EFER_LMA != 0
+    }
+
+    /// Returns whether the processor executes 64-bit code.
+    pub fn code64(&self) -> bool {
+        self.long_mode() && self.sregs.cs.l != 0
+    }
+
+    pub fn cpl(&self) -> u8 {
+        if self.protected_mode() && self.rflags & FLAG_VM == 0 {
+            self.sregs.cs.dpl
+        } else {
+            0
+        }
+    }
+
+    /// Width in bytes of stack pointer updates.
+    pub fn stack_size(&self) -> usize {
+        if self.code64() {
+            8
+        } else if self.sregs.ss.db != 0 {
+            4
+        } else {
+            2
+        }
+    }
+
+    /// Writes `value` to the low `size` bytes of `rsp`, following the operand size rules.
+    pub fn set_rsp(&mut self, value: u64, size: usize) {
+        self.gpr[4] = merge(self.gpr[4], value, size);
+    }
+
+    /// Sets the instruction pointer, truncated to the current code size.
+    pub fn set_rip(&mut self, rip: u64, size: usize) {
+        self.rip = rip & mask(size);
+    }
+
+    /// Writes CR0, raising #GP for invalid combinations.
+    pub fn write_cr0(&mut self, value: u64) -> Result<(), Stop> {
+        if value & !CR0_VALID != 0
+            || (value & CR0_PG != 0 && value & CR0_PE == 0)
+            || (value & CR0_NW != 0 && value & CR0_CD == 0)
+        {
+            return Err(Stop::gp(0));
+        }
+        let old = self.sregs.cr0;
+        if value & CR0_PG != 0 && old & CR0_PG == 0 && self.sregs.efer & EFER_LME != 0 {
+            if self.sregs.cr4 & CR4_PAE == 0 || self.sregs.cs.l != 0 {
+                return Err(Stop::gp(0));
+            }
+            self.sregs.efer |= EFER_LMA;
+        }
+        if value & CR0_PG == 0 && old & CR0_PG != 0 {
+            if self.code64() {
+                return Err(Stop::gp(0));
+            }
+            self.sregs.efer &= !EFER_LMA;
+        }
+        // ET is hardwired to 1.
+        self.sregs.cr0 = value | CR0_ET;
+        Ok(())
+    }
+
+    /// Writes CR4, raising #GP for reserved bits and invalid combinations.
+    pub fn write_cr4(&mut self, value: u64) -> Result<(), Stop> {
+        if value & !CR4_VALID != 0 || (self.long_mode() && value & CR4_PAE == 0) {
+            return Err(Stop::gp(0));
+        }
+        self.sregs.cr4 = value;
+        Ok(())
+    }
+
+    /// Writes CR3, raising #GP for bits above the physical address width in long mode.
+    pub fn write_cr3(&mut self, value: u64) -> Result<(), Stop> {
+        if self.long_mode() && value & 0xfff0_0000_0000_0000 != 0 {
+            return Err(Stop::gp(0));
+        }
+        self.sregs.cr3 = value;
+        Ok(())
+    }
+
+    fn write_efer(&mut self, value: u64) -> Result<(), Stop> {
+        if value & !EFER_VALID != 0 {
+            return Err(Stop::gp(0));
+        }
+        let old = self.sregs.efer;
+        if self.sregs.cr0 & CR0_PG != 0 && (value ^ old) & EFER_LME != 0 {
+            return Err(Stop::gp(0));
+        }
+        // LMA is owned by the processor.
+        self.sregs.efer = (value & !EFER_LMA) | (old & EFER_LMA);
+        Ok(())
+    }
+
+    /// Reads an MSR. Returns `None` for MSRs the emulator does not implement.
+    pub fn read_msr(&self, index: u32) -> Option<u64> {
+        Some(match index {
+            MSR_IA32_TSC => host_tsc().wrapping_add(self.tsc_offset),
+            MSR_IA32_APICBASE => self.apic_base,
+            MSR_IA32_SYSENTER_CS => self.sysenter_cs,
+            MSR_IA32_SYSENTER_ESP => self.sysenter_esp,
+            MSR_IA32_SYSENTER_EIP => self.sysenter_eip,
+            MSR_IA32_MISC_ENABLE => self.misc_enable,
+            MSR_IA32_CR_PAT => self.pat,
+            MSR_EFER => self.sregs.efer,
+            MSR_STAR => self.star,
+            MSR_LSTAR => self.lstar,
+            MSR_CSTAR => self.cstar,
+            MSR_SYSCALL_MASK => self.syscall_mask,
+            MSR_FS_BASE => self.sregs.fs.base,
+            MSR_GS_BASE => self.sregs.gs.base,
+            MSR_KERNEL_GS_BASE => self.kernel_gs_base,
+            MSR_TSC_AUX => self.tsc_aux,
+            0x8b => 0,
+            _ => return None,
+        })
+    }
+
+    /// Writes an MSR. Unknown MSRs and invalid values raise #GP.
+    pub fn write_msr(&mut self, index: u32, value: u64) -> Result<(), Stop> {
+        let canonical = |value: u64| {
+            if is_canonical(value) {
+                Ok(value)
+            } else {
+                Err(Stop::gp(0))
+            }
+        };
+        match index {
+            MSR_IA32_TSC => self.tsc_offset = value.wrapping_sub(host_tsc()),
+            MSR_IA32_APICBASE => self.apic_base = value,
+            MSR_IA32_SYSENTER_CS => self.sysenter_cs = value & 0xffff,
+            MSR_IA32_SYSENTER_ESP => self.sysenter_esp = canonical(value)?,
+            MSR_IA32_SYSENTER_EIP => self.sysenter_eip = canonical(value)?,
+            MSR_IA32_MISC_ENABLE => self.misc_enable = value,
+            MSR_IA32_CR_PAT => self.pat = value,
+            MSR_EFER => self.write_efer(value)?,
+            MSR_STAR => self.star = value,
+            MSR_LSTAR => self.lstar = canonical(value)?,
+            MSR_CSTAR => self.cstar = canonical(value)?,
+            MSR_SYSCALL_MASK => self.syscall_mask = value & 0xffff_ffff,
+            MSR_FS_BASE => self.sregs.fs.base = canonical(value)?,
+            MSR_GS_BASE => self.sregs.gs.base = canonical(value)?,
+            MSR_KERNEL_GS_BASE => self.kernel_gs_base = canonical(value)?,
+            MSR_TSC_AUX => self.tsc_aux = value & 0xffff_ffff,
+            0x8b => {}
+            _ => return Err(Stop::gp(0)),
+        }
+        Ok(())
+    }
+
+    /// Computes the linear address of `offset` in `seg`.
+    pub fn linear(&self, seg: SegReg, offset: u64) -> Result<u64, Stop> {
+        if self.code64() {
+            let base = match seg {
+                SegReg::Fs | SegReg::Gs => self.seg(seg).base,
+                _ => 0,
+            };
+            let linear = base.wrapping_add(offset);
+            if !is_canonical(linear) {
+                return Err(if seg == SegReg::Ss {
+                    Stop::exception_with_code(VECTOR_SS, 0)
+                } else {
+                    Stop::gp(0)
+                });
+            }
+            Ok(linear)
+        } else {
+            Ok(self.seg(seg).base.wrapping_add(offset) & 0xffff_ffff)
+        }
+    }
+
+    /// Translates a linear address to a guest physical address, walking the page tables if paging
+    /// is enabled.
+    pub fn translate(&self, bus: &mut dyn Bus, linear: u64, access: Access) -> Result<u64, Stop> {
+        if self.sregs.cr0 & CR0_PG == 0 {
+            return Ok(linear & 0xffff_ffff);
+        }
+
+        let user = self.cpl() == 3;
+        let nxe = self.sregs.efer & EFER_NXE != 0;
+        let mut fault_code = 0;
+        if access == Access::Write {
+            fault_code |= PF_WRITE;
+        }
+        if user {
+            fault_code |= PF_USER;
+        }
+        if access == Access::Fetch && nxe {
+            fault_code |= PF_FETCH;
+        }
+
+        let cr3 = self.sregs.cr3;
+        // (address of the table, index shift, entry size, index mask)
+        let (mut table, levels): (u64, &[(u32, u64)]) = if self.sregs.cr4 & CR4_PAE == 0 {
+            (cr3 & 0xffff_f000, &[(22, 0x3ff), (12, 0x3ff)])
+        } else if self.long_mode() {
+            (
+                cr3 & PTE_ADDR_MASK,
+                &[(39, 0x1ff), (30, 0x1ff), (21, 0x1ff), (12, 0x1ff)],
+            )
+        } else {
+            (cr3 & 0xffff_ffe0, &[(30, 0x3), (21, 0x1ff), (12, 0x1ff)])
+        };
+        let legacy = self.sregs.cr4 & CR4_PAE == 0;
+        let pae_legacy = !legacy && !self.long_mode();
+        let entry_size = if legacy { 4 } else { 8 };
+
+        let mut writable = true;
+        let mut user_ok = true;
+        let mut no_exec = false;
+        let mut walked = Vec::with_capacity(4);
+        for (level, &(shift, index_mask)) in levels.iter().enumerate() {
+            let entry_addr = table + ((linear >> shift) & index_mask) * entry_size;
+            let mut buf = [0u8; 8];
+            bus.read_ram(entry_addr, &mut buf[..entry_size as usize])
+                .map_err(|_| Stop::Unsupported("page table outside of guest memory"))?;
+            let entry = u64::from_le_bytes(buf);
+            if entry & PTE_PRESENT == 0 {
+                return Err(Stop::page_fault(linear, fault_code));
+            }
+            if !legacy && !nxe && entry & PTE_NX != 0 {
+                return Err(Stop::page_fault(linear, fault_code | PF_PRESENT | PF_RSVD));
+            }
+            let last = level == levels.len() - 1;
+            // PDPTEs in PAE mode only have the present bit and the address.
+            if pae_legacy && level == 0 {
+                table = entry & PTE_ADDR_MASK;
+                continue;
+            }
+            writable &= entry & PTE_WRITABLE != 0;
+            user_ok &= entry & PTE_USER != 0;
+            no_exec |= nxe && entry & PTE_NX != 0;
+            walked.push((entry_addr, entry));
+
+            // PML4 entries cannot map pages and 32-bit paging needs CR4.PSE for 4 MiB pages.
+            let large = !last
+                && entry & PTE_LARGE != 0
+                && if legacy {
+                    self.sregs.cr4 & CR4_PSE != 0
+                } else {
+                    level > 0
+                };
+            if last || large {
+                let page_mask = (1u64 << shift) - 1;
+                let frame = if legacy && large {
+                    entry & 0xffc0_0000
+                } else if legacy {
+                    entry & 0xffff_f000
+                } else {
+                    entry & PTE_ADDR_MASK & !page_mask
+                };
+
+                let denied = match access {
+                    Access::Write => {
+                        (!writable && (user || self.sregs.cr0 & CR0_WP != 0)) || (user && !user_ok)
+                    }
+                    Access::Read => user && !user_ok,
+                    Access::Fetch => no_exec || (user && !user_ok),
+                };
+                if denied {
+                    return Err(Stop::page_fault(linear, fault_code | PF_PRESENT));
+                }
+
+                let leaf = walked.len() - 1;
+                for (i, &(addr, entry)) in walked.iter().enumerate() {
+                    let mut updated = entry | PTE_ACCESSED;
+                    if i == leaf && access == Access::Write {
+                        updated |= PTE_DIRTY;
+                    }
+                    if updated != entry {
+                        bus.write_ram(addr, &updated.to_le_bytes()[..entry_size as usize]);
+                    }
+                }
+                return Ok(frame | (linear & page_mask));
+            }
+            table = if legacy {
+                entry & 0xffff_f000
+            } else {
+                entry & PTE_ADDR_MASK
+            };
+        }
+        unreachable!("page walk always ends at the last level");
+    }
+
+    /// Reads `data.len()` bytes at a linear address.
+    pub fn read_linear(
+        &self,
+        bus: &mut dyn Bus,
+        linear: u64,
+        data: &mut [u8],
+        access: Access,
+    ) -> Result<(), Stop> {
+        let mut done = 0;
+        while done < data.len() {
+            let address = linear.wrapping_add(done as u64);
+            let chunk = (0x1000 - (address & 0xfff) as usize).min(data.len() - done);
+            let phys = self.translate(bus, address, access)?;
+            if access == Access::Fetch {
+                bus.read_ram(phys, &mut data[done..done + chunk])
+                    .map_err(|_| Stop::Unsupported("instruction fetch outside of guest memory"))?;
+            } else {
+                bus.read(phys, &mut data[done..done + chunk])?;
+            }
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    /// Writes `data` at a linear address.
+    pub fn write_linear(&self, bus: &mut dyn Bus, linear: u64, data: &[u8]) -> Result<(), Stop> {
+        // Translate both pages first so that a fault on the second page does not leave a partial
+        // write behind.
+        let split = (0x1000 - (linear & 0xfff) as usize).min(data.len());
+        let first = self.translate(bus, linear, Access::Write)?;
+        let second = if split < data.len() {
+            Some(self.translate(bus, linear.wrapping_add(split as u64), Access::Write)?)
+        } else {
+            None
+        };
+        bus.write(first, &data[..split])?;
+        if let Some(second) = second {
+            bus.write(second, &data[split..])?;
+        }
+        Ok(())
+    }
+
+    pub fn read_virt(
+        &self,
+        bus: &mut dyn Bus,
+        seg: SegReg,
+        offset: u64,
+        size: usize,
+    ) -> Result<u64, Stop> {
+        let linear = self.linear(seg, offset)?;
+        let mut buf = [0u8; 8];
+        self.read_linear(bus, linear, &mut buf[..size], Access::Read)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    pub fn write_virt(
+        &self,
+        bus: &mut dyn Bus,
+        seg: SegReg,
+        offset: u64,
+        size: usize,
+        value: u64,
+    ) -> Result<(), Stop> {
+        let linear = self.linear(seg, offset)?;
+        self.write_linear(bus, linear, &value.to_le_bytes()[..size])
+    }
+
+    pub fn push(&mut self, bus: &mut dyn Bus, value: u64, size: usize) -> Result<(), Stop> {
+        let stack_size = self.stack_size();
+        let rsp = self.gpr[4].wrapping_sub(size as u64) & mask(stack_size);
+        self.write_virt(bus, SegReg::Ss, rsp, size, value)?;
+        self.set_rsp(rsp, stack_size);
+        Ok(())
+    }
+
+    pub fn pop(&mut self, bus: &mut dyn Bus, size: usize) -> Result<u64, Stop> {
+        let stack_size = self.stack_size();
+        let rsp = self.gpr[4] & mask(stack_size);
+        let value = self.read_virt(bus, SegReg::Ss, rsp, size)?;
+        self.set_rsp(rsp.wrapping_add(size as u64), stack_size);
+        Ok(value)
+    }
+
+    /// Reads the raw 8-byte descriptor for `selector` from the GDT or LDT.
+    pub fn read_descriptor(&self, bus: &mut dyn Bus, selector: u16) -> Result<u64, Stop> {
+        let (base, limit) = if selector & 4 != 0 {
+            (self.sregs.ldt.base, self.sregs.ldt.limit_bytes as u64)
+        } else {
+            (self.sregs.gdt.base, self.sregs.gdt.limit as u64)
+        };
+        let offset = (selector & !7) as u64;
+        if offset + 7 > limit {
+            return Err(Stop::gp((selector & !3) as u32));
+        }
+        let mut buf = [0u8; 8];
+        self.read_linear(bus, base.wrapping_add(offset), &mut buf, Access::Read)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    /// Reads the upper half of a 16-byte system descriptor in long mode.
+    pub fn read_descriptor_high(&self, bus: &mut dyn Bus, selector: u16) -> Result<u64, Stop> {
+        let high = (selector & !7) + 8;
+        if self.long_mode() {
+            self.read_descriptor(bus, high | (selector & 4))
+        } else {
+            Ok(0)
+        }
+    }
+
+    /// Loads a data or stack segment register, as `mov sreg`, `pop sreg` or a far pointer load
+    /// would.
+    pub fn load_segment(
+        &mut self,
+        bus: &mut dyn Bus,
+        seg: SegReg,
+        selector: u16,
+    ) -> Result<(), Stop> {
+        if !self.protected_mode() || self.rflags & FLAG_VM != 0 {
+            let segment = self.seg_mut(seg);
+            segment.selector = selector;
+            segment.base = (selector as u64) << 4;
+            if segment.present == 0 {
+                segment.present = 1;
+            }
+            return Ok(());
+        }
+
+        let error_code = (selector & !3) as u32;
+        if selector & !3 == 0 {
+            if seg == SegReg::Ss && !(self.code64() && self.cpl() != 3) {
+                return Err(Stop::gp(0));
+            }
+            let code64 = self.code64();
+            let segment = self.seg_mut(seg);
+            *segment = Segment {
+                selector,
+                // Stack segment attributes are still used for the stack width in 64-bit mode.
+                db: if seg == SegReg::Ss && code64 { 1 } else { 0 },
+                ..Default::default()
+            };
+            return Ok(());
+        }
+
+        let desc = self.read_descriptor(bus, selector)?;
+        let new = segment_from_descriptor(selector, desc);
+        let code = new.type_ & 0x8 != 0;
+        let writable = !code && new.type_ & 0x2 != 0;
+        let readable = !code || new.type_ & 0x2 != 0;
+        if new.s == 0 || (seg == SegReg::Ss && !writable) || !readable {
+            return Err(Stop::gp(error_code));
+        }
+        if new.present == 0 {
+            let vector = if seg == SegReg::Ss {
+                VECTOR_SS
+            } else {
+                VECTOR_NP
+            };
+            return Err(Stop::exception_with_code(vector, error_code));
+        }
+        *self.seg_mut(seg) = new;
+        Ok(())
+    }
+
+    /// Loads CS for a far transfer to `selector`.
+    pub fn load_code_segment(&mut self, bus: &mut dyn Bus, selector: u16) -> Result<(), Stop> {
+        if !self.protected_mode() || self.rflags & FLAG_VM != 0 {
+            let cs = &mut self.sregs.cs;
+            cs.selector = selector;
+            cs.base = (selector as u64) << 4;
+            return Ok(());
+        }
+        if selector & !3 == 0 {
+            return Err(Stop::gp(0));
+        }
+        let desc = self.read_descriptor(bus, selector)?;
+        let new = segment_from_descriptor(selector, desc);
+        if new.s == 0 {
+            return Err(Stop::Unsupported(
+                "far transfer through a gate or task segment",
+            ));
+        }
+        if new.type_ & 0x8 == 0 {
+            return Err(Stop::gp((selector & !3) as u32));
+        }
+        if new.present == 0 {
+            return Err(Stop::exception_with_code(VECTOR_NP, (selector & !3) as u32));
+        }
+        if new.dpl != self.cpl() && new.type_ & 0x4 == 0 {
+            return Err(Stop::Unsupported("privilege level change"));
+        }
+        if self.long_mode() && new.l != 0 && new.db != 0 {
+            return Err(Stop::gp((selector & !3) as u32));
+        }
+        let cpl = self.cpl();
+        self.sregs.cs = Segment {
+            selector: (selector & !3) | cpl as u16,
+            dpl: cpl,
+            ..new
+        };
+        Ok(())
+    }
+
+    /// Transfers control to the handler of `vector` through the IVT or IDT.
+    ///
+    /// `return_rip` is the address pushed on the handler's stack and `error_code` is pushed after
+    /// it for exceptions that have one.
+    pub fn deliver(
+        &mut self,
+        bus: &mut dyn Bus,
+        vector: u8,
+        error_code: Option<u32>,
+        source: EventSource,
+        return_rip: u64,
+    ) -> Result<(), Stop> {
+        let external = if source == EventSource::Software {
+            0
+        } else {
+            1
+        };
+        let idt_error = (vector as u32) * 8 + 2 + external;
+
+        if !self.protected_mode() {
+            let offset = vector as u64 * 4;
+            if offset + 3 > self.sregs.idt.limit as u64 {
+                return Err(Stop::gp(idt_error));
+            }
+            let mut entry = [0u8; 4];
+            self.read_linear(
+                bus,
+                self.sregs.idt.base.wrapping_add(offset),
+                &mut entry,
+                Access::Read,
+            )?;
+            let ip = u16::from_le_bytes([entry[0], entry[1]]);
+            let cs = u16::from_le_bytes([entry[2], entry[3]]);
+            self.push(bus, self.rflags & 0xffff, 2)?;
+            self.push(bus, self.sregs.cs.selector as u64, 2)?;
+            self.push(bus, return_rip & 0xffff, 2)?;
+            self.rflags &= !(FLAG_IF | FLAG_TF | FLAG_AC | FLAG_RF);
+            self.load_code_segment(bus, cs)?;
+            self.rip = ip as u64;
+            return Ok(());
+        }
+
+        let gate_size = if self.long_mode() { 16 } else { 8 };
+        let offset = vector as u64 * gate_size;
+        if offset + gate_size - 1 > self.sregs.idt.limit as u64 {
+            return Err(Stop::gp(idt_error));
+        }
+        let mut gate = [0u8; 16];
+        self.read_linear(
+            bus,
+            self.sregs.idt.base.wrapping_add(offset),
+            &mut gate[..gate_size as usize],
+            Access::Read,
+        )?;
+        let low = u64::from_le_bytes(gate[..8].try_into().unwrap());
+        let high = u64::from_le_bytes(gate[8..].try_into().unwrap());
+        let gate_type = ((low >> 40) & 0xf) as u8;
+        let gate_dpl = ((low >> 45) & 3) as u8;
+        let present = low & (1 << 47) != 0;
+        let selector = ((low >> 16) & 0xffff) as u16;
+        let mut target = (low & 0xffff) | ((low >> 32) & 0xffff_0000);
+
+        let (width, trap) = match (self.long_mode(), gate_type) {
+            (true, 0xe) | (true, 0xf) => {
+                target |= high << 32;
+                (8, gate_type == 0xf)
+            }
+            (false, 0xe) | (false, 0xf) => (4, gate_type == 0xf),
+            (false, 0x6) | (false, 0x7) => {
+                target &= 0xffff;
+                (2, gate_type == 0x7)
+            }
+            (false, 0x5) => return Err(Stop::Unsupported("task gate")),
+            _ => return Err(Stop::gp(idt_error)),
+        };
+        if source == EventSource::Software && gate_dpl < self.cpl() {
+            return Err(Stop::gp(idt_error));
+        }
+        if !present {
+            return Err(Stop::exception_with_code(VECTOR_NP, idt_error));
+        }
+        if selector & !3 == 0 {
+            return Err(Stop::gp(external));
+        }
+
+        let desc = self.read_descriptor(bus, selector)?;
+        let new_cs = segment_from_descriptor(selector, desc);
+        if new_cs.s == 0 || new_cs.type_ & 0x8 == 0 || (self.long_mode() && new_cs.l == 0) {
+            return Err(Stop::gp((selector as u32 & !3) | external));
+        }
+        if new_cs.present == 0 {
+            return Err(Stop::exception_with_code(
+                VECTOR_NP,
+                (selector as u32 & !3) | external,
+            ));
+        }
+        if new_cs.dpl < self.cpl() && new_cs.type_ & 0x4 == 0 {
+            return Err(Stop::Unsupported("interrupt with privilege level change"));
+        }
+
+        let old_flags = self.rflags;
+        let old_cs = self.sregs.cs.selector as u64;
+        if self.long_mode() {
+            let old_ss = self.sregs.ss.selector as u64;
+            let old_rsp = self.gpr[4];
+            let ist = (low >> 32) & 7;
+            let mut rsp = old_rsp;
+            if ist != 0 {
+                let mut buf = [0u8; 8];
+                self.read_linear(
+                    bus,
+                    self.sregs.tr.base.wrapping_add(0x24 + (ist - 1) * 8),
+                    &mut buf,
+                    Access::Read,
+                )?;
+                rsp = u64::from_le_bytes(buf);
+            }
+            // The interrupt stack frame is always made of 64-bit values on a 16-byte aligned
+            // stack, whatever the mode of the interrupted code.
+            let mut frame = vec![old_ss, old_rsp, old_flags, old_cs, return_rip];
+            frame.extend(error_code.map(u64::from));
+            let mut rsp = rsp & !0xf;
+            for value in frame {
+                rsp = rsp.wrapping_sub(8);
+                if !is_canonical(rsp) {
+                    return Err(Stop::exception_with_code(VECTOR_SS, external));
+                }
+                self.write_linear(bus, rsp, &value.to_le_bytes())?;
+            }
+            self.gpr[4] = rsp;
+        } else {
+            self.push(bus, old_flags & mask(width), width)?;
+            self.push(bus, old_cs, width)?;
+            self.push(bus, return_rip & mask(width), width)?;
+            if let Some(code) = error_code {
+                self.push(bus, code as u64, width)?;
+            }
+        }
+
+        self.rflags &= !(FLAG_TF | FLAG_NT | FLAG_RF | FLAG_VM);
+        if !trap {
+            self.rflags &= !FLAG_IF;
+        }
+        let cpl = self.cpl();
+        self.sregs.cs = Segment {
+            selector: (selector & !3) | cpl as u16,
+            dpl: cpl,
+            ..new_cs
+        };
+        self.rip = target;
+        Ok(())
+    }
+
+    /// Delivers an exception or interrupt, escalating faults raised during delivery to a double
+    /// fault and then to a triple fault.
+    pub fn deliver_event(
+        &mut self,
+        bus: &mut dyn Bus,
+        vector: u8,
+        error_code: Option<u32>,
+        source: EventSource,
+        return_rip: u64,
+    ) -> Result<(), Stop> {
+        let saved = *self;
+        let (mut vector, mut error_code) = (vector, error_code);
+        loop {
+            let result = self.deliver(bus, vector, error_code, source, return_rip);
+            let exception = match result {
+                Err(Stop::Exception(e)) => e,
+                Err(Stop::MmioRead { .. }) | Err(Stop::PioRead { .. }) => {
+                    return Err(Stop::Unsupported("event delivery through device memory"))
+                }
+                other => return other,
+            };
+            bus.rollback();
+            *self = saved;
+            if exception.vector == VECTOR_PF {
+                self.sregs.cr2 = exception.address;
+            }
+            if vector == VECTOR_DF {
+                return Err(Stop::TripleFault);
+            }
+            let contributory =
+                |v: u8| matches!(v, VECTOR_DE | VECTOR_TS | VECTOR_NP | VECTOR_SS | VECTOR_GP);
+            let double = (contributory(vector) && contributory(exception.vector))
+                || (vector == VECTOR_PF
+                    && (contributory(exception.vector) || exception.vector == VECTOR_PF));
+            if double {
+                vector = VECTOR_DF;
+                error_code = Some(0);
+            } else {
+                vector = exception.vector;
+                error_code = exception.error_code;
+            }
+        }
+    }
+}
+
+/// Builds a segment register from a raw GDT/LDT descriptor.
+pub(super) fn segment_from_descriptor(selector: u16, desc: u64) -> Segment {
+    let limit = ((desc & 0xffff) | ((desc >> 32) & 0xf_0000)) as u32;
+    let g = ((desc >> 55) & 1) as u8;
+    Segment {
+        base: ((desc >> 16) & 0xff_ffff) | ((desc >> 32) & 0xff00_0000),
+        limit_bytes: if g != 0 { (limit << 12) | 0xfff } else { limit },
+        selector,
+        type_: ((desc >> 40) & 0xf) as u8,
+        s: ((desc >> 44) & 1) as u8,
+        dpl: ((desc >> 45) & 3) as u8,
+        present: ((desc >> 47) & 1) as u8,
+        avl: ((desc >> 52) & 1) as u8,
+        l: ((desc >> 53) & 1) as u8,
+        db: ((desc >> 54) & 1) as u8,
+        g,
+    }
+}
+
+/// Returns a mask covering the low `size` bytes.
+pub(super) fn mask(size: usize) -> u64 {
+    if size >= 8 {
+        u64::MAX
+    } else {
+        (1u64 << (size * 8)) - 1
+    }
+}
+
+/// Replaces the low `size` bytes of `old` with `value`, zero-extending 32-bit writes the way
+/// general purpose registers do.
+pub(super) fn merge(old: u64, value: u64, size: usize) -> u64 {
+    match size {
+        1 | 2 => (old & !mask(size)) | (value & mask(size)),
+        4 => value & 0xffff_ffff,
+        _ => value,
+    }
+}
+
+pub(super) fn is_canonical(address: u64) -> bool {
+    ((address as i64) << 16 >> 16) as u64 == address
+}
+
+pub(super) fn host_tsc() -> u64 {
+    // SAFETY: _rdtsc takes no arguments.
+    unsafe { _rdtsc() }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+
+    /// Flat guest RAM starting at address 0, with no devices behind it.
+    pub(in crate::emulator) struct RamBus {
+        pub ram: Vec<u8>,
+        /// Port writes, as (port, size, value).
+        pub io: Vec<(u16, usize, u64)>,
+    }
+
+    impl RamBus {
+        pub fn new(size: usize) -> RamBus {
+            RamBus {
+                ram: vec![0; size],
+                io: Vec::new(),
+            }
+        }
+
+        pub fn write_u32(&mut self, address: u64, value: u32) {
+            let address = address as usize;
+            self.ram[address..address + 4].copy_from_slice(&value.to_le_bytes());
+        }
+
+        pub fn read_u32(&self, address: u64) -> u32 {
+            let address = address as usize;
+            u32::from_le_bytes(self.ram[address..address + 4].try_into().unwrap())
+        }
+
+        fn range(&self, address: u64, len: usize) -> Option<std::ops::Range<usize>> {
+            let start = usize::try_from(address).ok()?;
+            let end = start.checked_add(len)?;
+            (end <= self.ram.len()).then_some(start..end)
+        }
+    }
+
+    impl Bus for RamBus {
+        fn read(&mut self, address: u64, data: &mut [u8]) -> Result<(), Stop> {
+            let range = self.range(address, data.len()).ok_or(Stop::MmioRead {
+                address,
+                size: data.len(),
+            })?;
+            data.copy_from_slice(&self.ram[range]);
+            Ok(())
+        }
+
+        fn write(&mut self, address: u64, data: &[u8]) -> Result<(), Stop> {
+            if let Some(range) = self.range(address, data.len()) {
+                self.ram[range].copy_from_slice(data);
+            }
+            Ok(())
+        }
+
+        fn read_ram(&mut self, address: u64, data: &mut [u8]) -> Result<(), Stop> {
+            let range = self
+                .range(address, data.len())
+                .ok_or(Stop::Unsupported("outside of guest memory"))?;
+            data.copy_from_slice(&self.ram[range]);
+            Ok(())
+        }
+
+        fn write_ram(&mut self, address: u64, data: &[u8]) {
+            let range = self.range(address, data.len()).unwrap();
+            self.ram[range].copy_from_slice(data);
+        }
+
+        fn io_in(&mut self, port: u16, size: usize) -> Result<u64, Stop> {
+            Err(Stop::PioRead { port, size })
+        }
+
+        fn io_out(&mut self, port: u16, size: usize, value: u64) {
+            self.io.push((port, size, value));
+        }
+
+        fn touched_device(&self) -> bool {
+            !self.io.is_empty()
+        }
+
+        fn rollback(&mut self) {}
+
+        fn cpuid(&self, _function: u32, _index: u32) -> CpuidResult {
+            CpuidResult {
+                eax: 0,
+                ebx: 0,
+                ecx: 0,
+                edx: 0,
+            }
+        }
+    }
+
+    const PAGE_DIRECTORY: u64 = 0x1000;
+    const PAGE_TABLE: u64 = 0x2000;
+
+    // 32-bit paging with linear 0x5000 mapped writable to 0x7000 and 0x6000 mapped read-only to
+    // 0x8000.
+    fn paged() -> (ArchState, RamBus) {
+        let mut bus = RamBus::new(0x10000);
+        bus.write_u32(
+            PAGE_DIRECTORY,
+            (PAGE_TABLE | PTE_PRESENT | PTE_WRITABLE) as u32,
+        );
+        bus.write_u32(
+            PAGE_TABLE + 5 * 4,
+            (0x7000 | PTE_PRESENT | PTE_WRITABLE) as u32,
+        );
+        bus.write_u32(PAGE_TABLE + 6 * 4, (0x8000 | PTE_PRESENT) as u32);
+
+        let mut cpu = ArchState::new(true);
+        cpu.sregs.cr3 = PAGE_DIRECTORY;
+        cpu.write_cr0(CR0_PE | CR0_PG).unwrap();
+        (cpu, bus)
+    }
+
+    #[test]
+    fn translate_without_paging_is_identity() {
+        let cpu = ArchState::new(true);
+        let mut bus = RamBus::new(0x1000);
+        assert_eq!(
+            cpu.translate(&mut bus, 0x1234_5678, Access::Write).unwrap(),
+            0x1234_5678
+        );
+    }
+
+    #[test]
+    fn translate_sets_accessed_and_dirty() {
+        let (cpu, mut bus) = paged();
+        assert_eq!(
+            cpu.translate(&mut bus, 0x5123, Access::Read).unwrap(),
+            0x7123
+        );
+        assert_ne!(bus.read_u32(PAGE_DIRECTORY) as u64 & PTE_ACCESSED, 0);
+        assert_eq!(bus.read_u32(PAGE_TABLE + 5 * 4) as u64 & PTE_DIRTY, 0);
+
+        cpu.translate(&mut bus, 0x5123, Access::Write).unwrap();
+        let pte = bus.read_u32(PAGE_TABLE + 5 * 4) as u64;
+        assert_ne!(pte & PTE_ACCESSED, 0);
+        assert_ne!(pte & PTE_DIRTY, 0);
+    }
+
+    #[test]
+    fn translate_faults() {
+        let (mut cpu, mut bus) = paged();
+        assert_eq!(
+            cpu.translate(&mut bus, 0x9000, Access::Write),
+            Err(Stop::page_fault(0x9000, PF_WRITE))
+        );
+
+        // Supervisor writes to read-only pages only fault with CR0.WP.
+        assert_eq!(
+            cpu.translate(&mut bus, 0x6010, Access::Write).unwrap(),
+            0x8010
+        );
+        cpu.write_cr0(cpu.sregs.cr0 | CR0_WP).unwrap();
+        assert_eq!(
+            cpu.translate(&mut bus, 0x6010, Access::Write),
+            Err(Stop::page_fault(0x6010, PF_PRESENT | PF_WRITE))
+        );
+    }
+
+    #[test]
+    fn write_cr0_checks() {
+        let mut cpu = ArchState::new(true);
+        assert_eq!(cpu.write_cr0(CR0_PG), Err(Stop::gp(0)));
+        assert_eq!(cpu.write_cr0(CR0_NW), Err(Stop::gp(0)));
+
+        // Enabling paging with EFER.LME set but without PAE is not allowed.
+        cpu.sregs.efer = EFER_LME;
+        assert_eq!(cpu.write_cr0(CR0_PE | CR0_PG), Err(Stop::gp(0)));
+        cpu.sregs.cr4 = CR4_PAE;
+        cpu.write_cr0(CR0_PE | CR0_PG).unwrap();
+        assert!(cpu.long_mode());
+        assert_eq!(cpu.sregs.cr0 & CR0_ET, CR0_ET);
+    }
+
+    #[test]
+    fn linear_checks_canonical_addresses() {
+        let mut cpu = ArchState::new(true);
+        cpu.sregs.ds.base = 0x1000;
+        assert_eq!(cpu.linear(SegReg::Ds, 0x10).unwrap(), 0x1010);
+
+        cpu.sregs.efer = EFER_LME | EFER_LMA;
+        cpu.sregs.cs.l = 1;
+        assert_eq!(cpu.linear(SegReg::Ds, 0x10).unwrap(), 0x10);
+        assert_eq!(
+            cpu.linear(SegReg::Ds, 0x0000_8000_0000_0000),
+            Err(Stop::gp(0))
+        );
+        assert_eq!(
+            cpu.linear(SegReg::Ss, 0x0000_8000_0000_0000),
+            Err(Stop::exception_with_code(VECTOR_SS, 0))
+        );
+    }
+}
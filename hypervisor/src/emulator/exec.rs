@@ -0,0 +1,2425 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Instruction decoder and interpreter.
+//!
+//! `step` executes one instruction against a scratch copy of the architectural state. Errors leave
+//! the copy in an unspecified state; the caller is expected to discard it and roll back the bus.
+
+use super::cpu::*;
+
+/// Outcome of a retired instruction.
+#[derive(Clone, Copy, Debug, Default)]
+pub(super) struct Retired {
+    /// The instruction was `hlt`.
+    pub halted: bool,
+    /// The instruction blocks interrupts until the next instruction retires (`sti`, `mov ss`).
+    pub shadow: bool,
+}
+
+/// Executes the instruction at `cpu.rip`.
+pub(super) fn step(cpu: &mut ArchState, bus: &mut dyn Bus) -> Result<Retired, Stop> {
+    let mut insn = Insn::new(cpu, bus);
+    insn.decode_prefixes()?;
+    insn.execute()?;
+    if !insn.branched {
+        insn.cpu.rip = insn.next_rip();
+    }
+    Ok(insn.retired)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Rep {
+    None,
+    /// `rep`/`repe` (F3).
+    Rep,
+    /// `repne` (F2).
+    Repne,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Operand {
+    Reg(usize),
+    Mem(SegReg, u64),
+}
+
+#[derive(Clone, Copy, Debug)]
+struct ModRm {
+    /// The `reg` field extended with REX.R, naming a register operand.
+    reg: usize,
+    /// The raw `reg` field, used as an opcode extension by group instructions.
+    ext: u8,
+    rm: Operand,
+}
+
+const ALU_ADD: u8 = 0;
+const ALU_OR: u8 = 1;
+const ALU_ADC: u8 = 2;
+const ALU_SBB: u8 = 3;
+const ALU_AND: u8 = 4;
+const ALU_SUB: u8 = 5;
+const ALU_XOR: u8 = 6;
+const ALU_CMP: u8 = 7;
+
+/// Maximum number of iterations of a `rep` string instruction executed in a single step.
+const MAX_STRING_ITERATIONS: u32 = 4096;
+
+struct Insn<'a> {
+    cpu: &'a mut ArchState,
+    bus: &'a mut dyn Bus,
+    start: u64,
+    len: u64,
+    code64: bool,
+    /// Default operand and address size of the code segment.
+    code_size: usize,
+    opsize: usize,
+    addrsize: usize,
+    opsize_prefix: bool,
+    seg_override: Option<SegReg>,
+    rex: u8,
+    rep: Rep,
+    branched: bool,
+    retired: Retired,
+}
+
+fn sign_extend(value: u64, size: usize) -> u64 {
+    match size {
+        1 => value as u8 as i8 as i64 as u64,
+        2 => value as u16 as i16 as i64 as u64,
+        4 => value as u32 as i32 as i64 as u64,
+        _ => value,
+    }
+}
+
+fn sign_bit(size: usize) -> u64 {
+    1 << (size * 8 - 1)
+}
+
+/// Returns ZF, SF and PF for `result`.
+fn szp(result: u64, size: usize) -> u64 {
+    let result = result & mask(size);
+    let mut flags = 0;
+    if result == 0 {
+        flags |= FLAG_ZF;
+    }
+    if result & sign_bit(size) != 0 {
+        flags |= FLAG_SF;
+    }
+    // PF reflects even parity of the low byte only.
+    if (result as u8).count_ones() & 1 == 0 {
+        flags |= FLAG_PF;
+    }
+    flags
+}
+
+impl<'a> Insn<'a> {
+    fn new(cpu: &'a mut ArchState, bus: &'a mut dyn Bus) -> Insn<'a> {
+        let code64 = cpu.code64();
+        let code_size = if code64 {
+            8
+        } else if cpu.sregs.cs.db != 0 {
+            4
+        } else {
+            2
+        };
+        Insn {
+            start: cpu.rip,
+            cpu,
+            bus,
+            len: 0,
+            code64,
+            code_size,
+            opsize: code_size.min(4),
+            addrsize: code_size,
+            opsize_prefix: false,
+            seg_override: None,
+            rex: 0,
+            rep: Rep::None,
+            branched: false,
+            retired: Retired::default(),
+        }
+    }
+
+    fn fetch(&mut self, size: usize) -> Result<u64, Stop> {
+        if self.len + size as u64 > 15 {
+            return Err(Stop::gp(0));
+        }
+        let offset = self.start.wrapping_add(self.len);
+        let linear = if self.code64 {
+            offset
+        } else {
+            self.cpu.sregs.cs.base.wrapping_add(offset) & 0xffff_ffff
+        };
+        let mut buf = [0u8; 8];
+        self.cpu
+            .read_linear(self.bus, linear, &mut buf[..size], Access::Fetch)?;
+        self.len += size as u64;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    fn fetch_u8(&mut self) -> Result<u8, Stop> {
+        Ok(self.fetch(1)? as u8)
+    }
+
+    /// Fetches a sign-extended 8-bit immediate.
+    fn imm8s(&mut self) -> Result<u64, Stop> {
+        Ok(sign_extend(self.fetch(1)?, 1))
+    }
+
+    /// Fetches an immediate for an operation of `size` bytes; 64-bit operations take a
+    /// sign-extended 32-bit immediate.
+    fn imm(&mut self, size: usize) -> Result<u64, Stop> {
+        match size {
+            8 => Ok(sign_extend(self.fetch(4)?, 4)),
+            size => self.fetch(size),
+        }
+    }
+
+    fn next_rip(&self) -> u64 {
+        self.start.wrapping_add(self.len) & mask(self.code_size)
+    }
+
+    /// Transfers control to `target` in the current code segment.
+    fn branch(&mut self, target: u64, size: usize) {
+        self.cpu.set_rip(target, size);
+        self.branched = true;
+    }
+
+    fn decode_prefixes(&mut self) -> Result<(), Stop> {
+        let mut addr_prefix = false;
+        loop {
+            let byte = self.fetch_u8()?;
+            match byte {
+                0x26 | 0x2e | 0x36 | 0x3e => {
+                    // Only FS and GS overrides have an effect in 64-bit mode.
+                    if !self.code64 {
+                        self.seg_override = SegReg::from_index((byte >> 3) & 3);
+                    }
+                }
+                0x64 => self.seg_override = Some(SegReg::Fs),
+                0x65 => self.seg_override = Some(SegReg::Gs),
+                0x66 => self.opsize_prefix = true,
+                0x67 => addr_prefix = true,
+                0xf0 => {}
+                0xf2 => self.rep = Rep::Repne,
+                0xf3 => self.rep = Rep::Rep,
+                0x40..=0x4f if self.code64 => {
+                    self.rex = byte;
+                    // REX must immediately precede the opcode.
+                    let next = self.fetch_u8()?;
+                    self.len -= 1;
+                    if matches!(next, 0x40..=0x4f) {
+                        continue;
+                    }
+                    if matches!(
+                        next,
+                        0x26 | 0x2e | 0x36 | 0x3e | 0x64 | 0x65 | 0x66 | 0x67 | 0xf0 | 0xf2 | 0xf3
+                    ) {
+                        self.rex = 0;
+                        continue;
+                    }
+                    break;
+                }
+                _ => {
+                    self.len -= 1;
+                    break;
+                }
+            }
+        }
+        if self.code64 {
+            self.opsize = if self.rex & 8 != 0 {
+                8
+            } else if self.opsize_prefix {
+                2
+            } else {
+                4
+            };
+            self.addrsize = if addr_prefix { 4 } else { 8 };
+        } else {
+            let big = self.code_size == 4;
+            self.opsize = if big != self.opsize_prefix { 4 } else { 2 };
+            self.addrsize = if big != addr_prefix { 4 } else { 2 };
+        }
+        Ok(())
+    }
+
+    /// Operand size of instructions that default to 64 bits in 64-bit mode (stack operations and
+    /// near branches).
+    fn opsize64(&self) -> usize {
+        if self.code64 {
+            if self.opsize_prefix && self.rex & 8 == 0 {
+                2
+            } else {
+                8
+            }
+        } else {
+            self.opsize
+        }
+    }
+
+    fn modrm(&mut self, imm_size: usize) -> Result<ModRm, Stop> {
+        let byte = self.fetch_u8()?;
+        let md = byte >> 6;
+        let ext = (byte >> 3) & 7;
+        let rm = byte & 7;
+        let reg = ext as usize | if self.rex & 4 != 0 { 8 } else { 0 };
+        if md == 3 {
+            let rm = rm as usize | if self.rex & 1 != 0 { 8 } else { 0 };
+            return Ok(ModRm {
+                reg,
+                ext,
+                rm: Operand::Reg(rm),
+            });
+        }
+        let (seg, ea) = if self.addrsize == 2 {
+            self.ea16(md, rm)?
+        } else {
+            self.ea32(md, rm, imm_size)?
+        };
+        Ok(ModRm {
+            reg,
+            ext,
+            rm: Operand::Mem(self.seg_override.unwrap_or(seg), ea),
+        })
+    }
+
+    /// Decodes the ModRM byte of the moves to and from control and debug registers, which always
+    /// name two registers: the `mod` field is ignored and no SIB byte or displacement follows.
+    fn modrm_regs(&mut self) -> Result<(usize, usize), Stop> {
+        let byte = self.fetch_u8()?;
+        let reg = ((byte >> 3) & 7) as usize | if self.rex & 4 != 0 { 8 } else { 0 };
+        let rm = (byte & 7) as usize | if self.rex & 1 != 0 { 8 } else { 0 };
+        Ok((reg, rm))
+    }
+
+    fn ea16(&mut self, md: u8, rm: u8) -> Result<(SegReg, u64), Stop> {
+        let gpr = &self.cpu.gpr;
+        let (bx, bp, si, di) = (gpr[3], gpr[5], gpr[6], gpr[7]);
+        let (base, seg) = match rm {
+            0 => (bx.wrapping_add(si), SegReg::Ds),
+            1 => (bx.wrapping_add(di), SegReg::Ds),
+            2 => (bp.wrapping_add(si), SegReg::Ss),
+            3 => (bp.wrapping_add(di), SegReg::Ss),
+            4 => (si, SegReg::Ds),
+            5 => (di, SegReg::Ds),
+            6 if md == 0 => (self.fetch(2)?, SegReg::Ds),
+            6 => (bp, SegReg::Ss),
+            _ => (bx, SegReg::Ds),
+        };
+        let disp = match md {
+            1 => self.imm8s()?,
+            2 => self.fetch(2)?,
+            _ => 0,
+        };
+        Ok((seg, base.wrapping_add(disp) & 0xffff))
+    }
+
+    fn ea32(&mut self, md: u8, rm: u8, imm_size: usize) -> Result<(SegReg, u64), Stop> {
+        let rex_b = if self.rex & 1 != 0 { 8 } else { 0 };
+        let mut seg = SegReg::Ds;
+        let mut ea;
+        if rm == 4 {
+            let sib = self.fetch_u8()?;
+            let scale = sib >> 6;
+            let index = ((sib >> 3) & 7) as usize | if self.rex & 2 != 0 { 8 } else { 0 };
+            let base = sib & 7;
+            ea = if index != 4 {
+                self.cpu.gpr[index] << scale
+            } else {
+                0
+            };
+            if base == 5 && md == 0 {
+                ea = ea.wrapping_add(sign_extend(self.fetch(4)?, 4));
+            } else {
+                ea = ea.wrapping_add(self.cpu.gpr[base as usize | rex_b]);
+                if base == 4 || base == 5 {
+                    seg = SegReg::Ss;
+                }
+            }
+        } else if rm == 5 && md == 0 {
+            let disp = sign_extend(self.fetch(4)?, 4);
+            ea = if self.code64 {
+                // RIP-relative addressing is relative to the end of the instruction.
+                self.start
+                    .wrapping_add(self.len + imm_size as u64)
+                    .wrapping_add(disp)
+            } else {
+                disp
+            };
+        } else {
+            ea = self.cpu.gpr[rm as usize | rex_b];
+            if rm == 5 {
+                seg = SegReg::Ss;
+            }
+        }
+        match md {
+            1 => ea = ea.wrapping_add(self.imm8s()?),
+            2 => ea = ea.wrapping_add(sign_extend(self.fetch(4)?, 4)),
+            _ => {}
+        }
+        Ok((seg, ea & mask(self.addrsize)))
+    }
+
+    fn get_reg(&self, index: usize, size: usize) -> u64 {
+        if size == 1 && self.rex == 0 && (4..8).contains(&index) {
+            (self.cpu.gpr[index - 4] >> 8) & 0xff
+        } else {
+            self.cpu.gpr[index] & mask(size)
+        }
+    }
+
+    fn set_reg(&mut self, index: usize, size: usize, value: u64) {
+        if size == 1 && self.rex == 0 && (4..8).contains(&index) {
+            let reg = &mut self.cpu.gpr[index - 4];
+            *reg = (*reg & !0xff00) | ((value & 0xff) << 8);
+        } else {
+            self.cpu.gpr[index] = merge(self.cpu.gpr[index], value, size);
+        }
+    }
+
+    fn read_mem(&mut self, seg: SegReg, offset: u64, size: usize) -> Result<u64, Stop> {
+        self.cpu
+            .read_virt(self.bus, seg, offset & mask(self.addrsize), size)
+    }
+
+    fn write_mem(&mut self, seg: SegReg, offset: u64, size: usize, value: u64) -> Result<(), Stop> {
+        self.cpu
+            .write_virt(self.bus, seg, offset & mask(self.addrsize), size, value)
+    }
+
+    fn read(&mut self, operand: Operand, size: usize) -> Result<u64, Stop> {
+        match operand {
+            Operand::Reg(index) => Ok(self.get_reg(index, size)),
+            Operand::Mem(seg, offset) => self.read_mem(seg, offset, size),
+        }
+    }
+
+    fn write(&mut self, operand: Operand, size: usize, value: u64) -> Result<(), Stop> {
+        match operand {
+            Operand::Reg(index) => {
+                self.set_reg(index, size, value);
+                Ok(())
+            }
+            Operand::Mem(seg, offset) => self.write_mem(seg, offset, size, value),
+        }
+    }
+
+    /// Returns the memory operand or raises #UD for a register operand.
+    fn mem_operand(operand: Operand) -> Result<(SegReg, u64), Stop> {
+        match operand {
+            Operand::Mem(seg, offset) => Ok((seg, offset)),
+            Operand::Reg(_) => Err(Stop::ud()),
+        }
+    }
+
+    fn flag(&self, flag: u64) -> bool {
+        self.cpu.rflags & flag != 0
+    }
+
+    fn set_status(&mut self, flags: u64) {
+        self.cpu.rflags = (self.cpu.rflags & !FLAGS_STATUS) | (flags & FLAGS_STATUS);
+    }
+
+    /// Updates the flags in `which` from `flags`, leaving the others untouched.
+    fn set_flags_masked(&mut self, flags: u64, which: u64) {
+        self.cpu.rflags = (self.cpu.rflags & !which) | (flags & which);
+    }
+
+    fn cond(&self, cc: u8) -> bool {
+        let result = match cc >> 1 {
+            0 => self.flag(FLAG_OF),
+            1 => self.flag(FLAG_CF),
+            2 => self.flag(FLAG_ZF),
+            3 => self.flag(FLAG_CF) || self.flag(FLAG_ZF),
+            4 => self.flag(FLAG_SF),
+            5 => self.flag(FLAG_PF),
+            6 => self.flag(FLAG_SF) != self.flag(FLAG_OF),
+            _ => self.flag(FLAG_ZF) || (self.flag(FLAG_SF) != self.flag(FLAG_OF)),
+        };
+        result != (cc & 1 != 0)
+    }
+
+    fn add_flags(a: u64, b: u64, result: u64, carry: bool, size: usize) -> u64 {
+        let sign = sign_bit(size);
+        let mut flags = szp(result, size);
+        if carry {
+            flags |= FLAG_CF;
+        }
+        if (a ^ result) & (b ^ result) & sign != 0 {
+            flags |= FLAG_OF;
+        }
+        if (a ^ b ^ result) & 0x10 != 0 {
+            flags |= FLAG_AF;
+        }
+        flags
+    }
+
+    fn sub_flags(a: u64, b: u64, result: u64, borrow: bool, size: usize) -> u64 {
+        let sign = sign_bit(size);
+        let mut flags = szp(result, size);
+        if borrow {
+            flags |= FLAG_CF;
+        }
+        if (a ^ b) & (a ^ result) & sign != 0 {
+            flags |= FLAG_OF;
+        }
+        if (a ^ b ^ result) & 0x10 != 0 {
+            flags |= FLAG_AF;
+        }
+        flags
+    }
+
+    /// Performs one of the eight classic ALU operations and updates the flags. Returns `None` for
+    /// `cmp`, which does not write its result.
+    fn alu(&mut self, op: u8, a: u64, b: u64, size: usize) -> Option<u64> {
+        let m = mask(size);
+        let (a, b) = (a & m, b & m);
+        let carry_in = self.flag(FLAG_CF) as u64;
+        let (result, flags) = match op {
+            ALU_ADD | ALU_ADC => {
+                let c = if op == ALU_ADC { carry_in } else { 0 };
+                let wide = a as u128 + b as u128 + c as u128;
+                let result = wide as u64 & m;
+                let carry = wide > m as u128;
+                (result, Self::add_flags(a, b, result, carry, size))
+            }
+            ALU_SUB | ALU_SBB | ALU_CMP => {
+                let c = if op == ALU_SBB { carry_in } else { 0 };
+                let result = a.wrapping_sub(b).wrapping_sub(c) & m;
+                let borrow = (a as u128) < b as u128 + c as u128;
+                (result, Self::sub_flags(a, b, result, borrow, size))
+            }
+            ALU_OR => (a | b, szp(a | b, size)),
+            ALU_AND => (a & b, szp(a & b, size)),
+            ALU_XOR => (a ^ b, szp(a ^ b, size)),
+            _ => unreachable!("ALU operations are three bits"),
+        };
+        self.set_status(flags);
+        if op == ALU_CMP {
+            None
+        } else {
+            Some(result)
+        }
+    }
+
+    fn inc_dec(&mut self, value: u64, size: usize, dec: bool) -> u64 {
+        let m = mask(size);
+        let value = value & m;
+        let (result, flags) = if dec {
+            let result = value.wrapping_sub(1) & m;
+            (result, Self::sub_flags(value, 1, result, false, size))
+        } else {
+            let result = value.wrapping_add(1) & m;
+            (result, Self::add_flags(value, 1, result, false, size))
+        };
+        self.set_flags_masked(flags, FLAGS_STATUS & !FLAG_CF);
+        result
+    }
+
+    /// Group 2 rotates and shifts.
+    fn shift(&mut self, op: u8, value: u64, count: u64, size: usize) -> u64 {
+        let bits = (size * 8) as u64;
+        let count = count & if size == 8 { 0x3f } else { 0x1f };
+        let m = mask(size);
+        let value = value & m;
+        if count == 0 {
+            return value;
+        }
+        let msb = |v: u64| (v >> (bits - 1)) & 1;
+        match op {
+            0 | 1 => {
+                let c = count % bits;
+                let result = if c == 0 {
+                    value
+                } else if op == 0 {
+                    ((value << c) | (value >> (bits - c))) & m
+                } else {
+                    ((value >> c) | (value << (bits - c))) & m
+                };
+                let (cf, of) = if op == 0 {
+                    (result & 1, msb(result) ^ (result & 1))
+                } else {
+                    (msb(result), msb(result) ^ ((result >> (bits - 2)) & 1))
+                };
+                let flags = if cf != 0 { FLAG_CF } else { 0 } | if of != 0 { FLAG_OF } else { 0 };
+                self.set_flags_masked(flags, FLAG_CF | FLAG_OF);
+                result
+            }
+            2 | 3 => {
+                let count = count % (bits + 1);
+                let mut cf = self.flag(FLAG_CF) as u64;
+                let mut result = value;
+                let mut of = 0;
+                if op == 3 {
+                    of = msb(result) ^ cf;
+                }
+                for _ in 0..count {
+                    if op == 2 {
+                        let out = msb(result);
+                        result = ((result << 1) | cf) & m;
+                        cf = out;
+                    } else {
+                        let out = result & 1;
+                        result = (result >> 1) | (cf << (bits - 1));
+                        cf = out;
+                    }
+                }
+                if op == 2 {
+                    of = msb(result) ^ cf;
+                }
+                let flags = if cf != 0 { FLAG_CF } else { 0 } | if of != 0 { FLAG_OF } else { 0 };
+                self.set_flags_masked(flags, FLAG_CF | FLAG_OF);
+                result
+            }
+            4 | 6 => {
+                let result = ((value as u128) << count) as u64 & m;
+                let cf = if count <= bits {
+                    (value >> (bits - count)) & 1
+                } else {
+                    0
+                };
+                let mut flags = szp(result, size);
+                if cf != 0 {
+                    flags |= FLAG_CF;
+                }
+                if msb(result) ^ cf != 0 {
+                    flags |= FLAG_OF;
+                }
+                self.set_status(flags);
+                result
+            }
+            5 => {
+                let result = if count >= bits { 0 } else { value >> count };
+                let cf = if count <= bits {
+                    (value >> (count - 1)) & 1
+                } else {
+                    0
+                };
+                let mut flags = szp(result, size);
+                if cf != 0 {
+                    flags |= FLAG_CF;
+                }
+                if msb(value) != 0 {
+                    flags |= FLAG_OF;
+                }
+                self.set_status(flags);
+                result
+            }
+            _ => {
+                let signed = sign_extend(value, size) as i64;
+                let result = (signed >> count.min(63)) as u64 & m;
+                let cf = (signed >> (count - 1).min(63)) & 1;
+                let mut flags = szp(result, size);
+                if cf != 0 {
+                    flags |= FLAG_CF;
+                }
+                self.set_status(flags);
+                result
+            }
+        }
+    }
+
+    /// Reads the implicit accumulator pair used by `mul` and `div` (`ax`, `dx:ax`, `edx:eax` or
+    /// `rdx:rax`).
+    fn read_acc_pair(&self, size: usize) -> u128 {
+        if size == 1 {
+            (self.cpu.gpr[0] & 0xffff) as u128
+        } else {
+            let bits = size * 8;
+            ((self.get_reg(2, size) as u128) << bits) | self.get_reg(0, size) as u128
+        }
+    }
+
+    fn write_acc_pair(&mut self, size: usize, low: u64, high: u64) {
+        if size == 1 {
+            self.set_reg(0, 2, (low & 0xff) | ((high & 0xff) << 8));
+        } else {
+            self.set_reg(0, size, low);
+            self.set_reg(2, size, high);
+        }
+    }
+
+    /// Group 3: test, not, neg, mul, imul, div and idiv.
+    fn group3(&mut self, modrm: ModRm, size: usize) -> Result<(), Stop> {
+        let imm = if modrm.ext < 2 { self.imm(size)? } else { 0 };
+        let value = self.read(modrm.rm, size)?;
+        let m = mask(size);
+        let bits = size * 8;
+        match modrm.ext {
+            0 | 1 => {
+                self.alu(ALU_AND, value, imm, size);
+            }
+            2 => self.write(modrm.rm, size, !value)?,
+            3 => {
+                let result = 0u64.wrapping_sub(value) & m;
+                let mut flags = Self::sub_flags(0, value, result, value != 0, size);
+                if value == sign_bit(size) {
+                    flags |= FLAG_OF;
+                }
+                self.set_status(flags);
+                self.write(modrm.rm, size, result)?;
+            }
+            4 => {
+                let product = self.get_reg(0, size) as u128 * value as u128;
+                let low = product as u64 & m;
+                let high = (product >> bits) as u64 & m;
+                if size == 1 {
+                    self.set_reg(0, 2, product as u64);
+                } else {
+                    self.write_acc_pair(size, low, high);
+                }
+                let mut flags = szp(low, size);
+                if high != 0 {
+                    flags |= FLAG_CF | FLAG_OF;
+                }
+                self.set_status(flags);
+            }
+            5 => {
+                let a = sign_extend(self.get_reg(0, size), size) as i64 as i128;
+                let b = sign_extend(value, size) as i64 as i128;
+                let product = a * b;
+                let low = product as u64 & m;
+                let high = (product >> bits) as u64 & m;
+                if size == 1 {
+                    self.set_reg(0, 2, product as u64);
+                } else {
+                    self.write_acc_pair(size, low, high);
+                }
+                let mut flags = szp(low, size);
+                if sign_extend(low, size) as i64 as i128 != product {
+                    flags |= FLAG_CF | FLAG_OF;
+                }
+                self.set_status(flags);
+            }
+            6 => {
+                if value == 0 {
+                    return Err(Stop::exception(VECTOR_DE));
+                }
+                let dividend = self.read_acc_pair(size);
+                let quotient = dividend / value as u128;
+                let remainder = dividend % value as u128;
+                if quotient > m as u128 {
+                    return Err(Stop::exception(VECTOR_DE));
+                }
+                self.write_acc_pair(size, quotient as u64, remainder as u64);
+            }
+            _ => {
+                if value == 0 {
+                    return Err(Stop::exception(VECTOR_DE));
+                }
+                let dividend = self.read_acc_pair(size);
+                let dividend = ((dividend << (128 - 2 * bits)) as i128) >> (128 - 2 * bits);
+                let divisor = sign_extend(value, size) as i64 as i128;
+                let quotient = dividend.wrapping_div(divisor);
+                let remainder = dividend.wrapping_rem(divisor);
+                let min = -(1i128 << (bits - 1));
+                let max = (1i128 << (bits - 1)) - 1;
+                if quotient < min || quotient > max {
+                    return Err(Stop::exception(VECTOR_DE));
+                }
+                self.write_acc_pair(size, quotient as u64, remainder as u64);
+            }
+        }
+        Ok(())
+    }
+
+    /// Two and three operand `imul`.
+    fn imul(&mut self, a: u64, b: u64, size: usize) -> u64 {
+        let product = sign_extend(a, size) as i64 as i128 * sign_extend(b, size) as i64 as i128;
+        let result = product as u64 & mask(size);
+        let mut flags = szp(result, size);
+        if sign_extend(result, size) as i64 as i128 != product {
+            flags |= FLAG_CF | FLAG_OF;
+        }
+        self.set_status(flags);
+        result
+    }
+
+    fn push(&mut self, value: u64, size: usize) -> Result<(), Stop> {
+        self.cpu.push(self.bus, value, size)
+    }
+
+    fn pop(&mut self, size: usize) -> Result<u64, Stop> {
+        self.cpu.pop(self.bus, size)
+    }
+
+    fn require_not64(&self) -> Result<(), Stop> {
+        if self.code64 {
+            Err(Stop::ud())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn require_protected(&self) -> Result<(), Stop> {
+        if !self.cpu.protected_mode() || self.cpu.rflags & FLAG_VM != 0 {
+            Err(Stop::ud())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn require_cpl0(&self) -> Result<(), Stop> {
+        if self.cpu.cpl() != 0 {
+            Err(Stop::gp(0))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn execute(&mut self) -> Result<(), Stop> {
+        let opcode = self.fetch_u8()?;
+        match opcode {
+            0x00..=0x3f if opcode & 7 < 6 => {
+                let op = opcode >> 3;
+                match opcode & 7 {
+                    0..=3 => {
+                        let size = if opcode & 1 == 0 { 1 } else { self.opsize };
+                        let modrm = self.modrm(0)?;
+                        let reg = self.get_reg(modrm.reg, size);
+                        let rm = self.read(modrm.rm, size)?;
+                        if opcode & 2 == 0 {
+                            if let Some(result) = self.alu(op, rm, reg, size) {
+                                self.write(modrm.rm, size, result)?;
+                            }
+                        } else if let Some(result) = self.alu(op, reg, rm, size) {
+                            self.set_reg(modrm.reg, size, result);
+                        }
+                    }
+                    _ => {
+                        let size = if opcode & 1 == 0 { 1 } else { self.opsize };
+                        let imm = self.imm(size)?;
+                        let acc = self.get_reg(0, size);
+                        if let Some(result) = self.alu(op, acc, imm, size) {
+                            self.set_reg(0, size, result);
+                        }
+                    }
+                }
+            }
+            0x06 | 0x0e | 0x16 | 0x1e => {
+                self.require_not64()?;
+                let seg = SegReg::from_index(opcode >> 3).unwrap();
+                let selector = self.cpu.seg(seg).selector as u64;
+                self.push(selector, self.opsize)?;
+            }
+            0x07 | 0x17 | 0x1f => {
+                self.require_not64()?;
+                let seg = SegReg::from_index(opcode >> 3).unwrap();
+                let selector = self.pop(self.opsize)? as u16;
+                self.cpu.load_segment(self.bus, seg, selector)?;
+                if seg == SegReg::Ss {
+                    self.retired.shadow = true;
+                }
+            }
+            0x0f => self.execute_0f()?,
+            0x27 | 0x2f | 0x37 | 0x3f => {
+                self.require_not64()?;
+                return Err(Stop::Unsupported("BCD arithmetic"));
+            }
+            0x40..=0x4f => {
+                // REX prefixes are consumed by the prefix decoder in 64-bit mode.
+                let index = (opcode & 7) as usize;
+                let value = self.get_reg(index, self.opsize);
+                let result = self.inc_dec(value, self.opsize, opcode >= 0x48);
+                self.set_reg(index, self.opsize, result);
+            }
+            0x50..=0x57 => {
+                let index = (opcode & 7) as usize | if self.rex & 1 != 0 { 8 } else { 0 };
+                let size = self.opsize64();
+                let value = self.get_reg(index, size);
+                self.push(value, size)?;
+            }
+            0x58..=0x5f => {
+                let index = (opcode & 7) as usize | if self.rex & 1 != 0 { 8 } else { 0 };
+                let size = self.opsize64();
+                let value = self.pop(size)?;
+                self.set_reg(index, size, value);
+            }
+            0x60 => {
+                self.require_not64()?;
+                let size = self.opsize;
+                let sp = self.get_reg(4, size);
+                for index in 0..8 {
+                    let value = if index == 4 {
+                        sp
+                    } else {
+                        self.get_reg(index, size)
+                    };
+                    self.push(value, size)?;
+                }
+            }
+            0x61 => {
+                self.require_not64()?;
+                let size = self.opsize;
+                for index in (0..8).rev() {
+                    let value = self.pop(size)?;
+                    if index != 4 {
+                        self.set_reg(index, size, value);
+                    }
+                }
+            }
+            0x63 if self.code64 => {
+                let modrm = self.modrm(0)?;
+                let value = self.read(modrm.rm, 4)?;
+                let value = if self.opsize == 8 {
+                    sign_extend(value, 4)
+                } else {
+                    value
+                };
+                self.set_reg(modrm.reg, self.opsize, value);
+            }
+            0x68 => {
+                let size = self.opsize64();
+                let imm = self.imm(size)?;
+                self.push(imm, size)?;
+            }
+            0x6a => {
+                let size = self.opsize64();
+                let imm = self.imm8s()?;
+                self.push(imm, size)?;
+            }
+            0x69 | 0x6b => {
+                let size = self.opsize;
+                let imm_size = if opcode == 0x69 { size.min(4) } else { 1 };
+                let modrm = self.modrm(imm_size)?;
+                let imm = if opcode == 0x69 {
+                    self.imm(size)?
+                } else {
+                    self.imm8s()?
+                };
+                let value = self.read(modrm.rm, size)?;
+                let result = self.imul(value, imm, size);
+                self.set_reg(modrm.reg, size, result);
+            }
+            0x6c..=0x6f | 0xa4..=0xa7 | 0xaa..=0xaf => self.string_op(opcode)?,
+            0x70..=0x7f => {
+                let rel = self.imm8s()?;
+                if self.cond(opcode & 0xf) {
+                    let target = self.next_rip().wrapping_add(rel);
+                    self.branch(target, self.opsize64());
+                }
+            }
+            0x80..=0x83 => {
+                if opcode == 0x82 {
+                    self.require_not64()?;
+                }
+                let size = if opcode & 1 == 0 { 1 } else { self.opsize };
+                let imm_size = if opcode == 0x81 { size.min(4) } else { 1 };
+                let modrm = self.modrm(imm_size)?;
+                let imm = if opcode == 0x81 {
+                    self.imm(size)?
+                } else {
+                    self.imm8s()?
+                };
+                let value = self.read(modrm.rm, size)?;
+                if let Some(result) = self.alu(modrm.ext, value, imm, size) {
+                    self.write(modrm.rm, size, result)?;
+                }
+            }
+            0x84 | 0x85 => {
+                let size = if opcode & 1 == 0 { 1 } else { self.opsize };
+                let modrm = self.modrm(0)?;
+                let a = self.read(modrm.rm, size)?;
+                let b = self.get_reg(modrm.reg, size);
+                self.alu(ALU_AND, a, b, size);
+            }
+            0x86 | 0x87 => {
+                let size = if opcode & 1 == 0 { 1 } else { self.opsize };
+                let modrm = self.modrm(0)?;
+                let a = self.read(modrm.rm, size)?;
+                let b = self.get_reg(modrm.reg, size);
+                self.write(modrm.rm, size, b)?;
+                self.set_reg(modrm.reg, size, a);
+            }
+            0x88..=0x8b => {
+                let size = if opcode & 1 == 0 { 1 } else { self.opsize };
+                let modrm = self.modrm(0)?;
+                if opcode & 2 == 0 {
+                    let value = self.get_reg(modrm.reg, size);
+                    self.write(modrm.rm, size, value)?;
+                } else {
+                    let value = self.read(modrm.rm, size)?;
+                    self.set_reg(modrm.reg, size, value);
+                }
+            }
+            0x8c => {
+                let modrm = self.modrm(0)?;
+                let seg = SegReg::from_index(modrm.ext).ok_or_else(Stop::ud)?;
+                let selector = self.cpu.seg(seg).selector as u64;
+                match modrm.rm {
+                    Operand::Reg(_) => self.write(modrm.rm, self.opsize, selector)?,
+                    Operand::Mem(..) => self.write(modrm.rm, 2, selector)?,
+                }
+            }
+            0x8d => {
+                let modrm = self.modrm(0)?;
+                let (_, offset) = Self::mem_operand(modrm.rm)?;
+                self.set_reg(modrm.reg, self.opsize, offset);
+            }
+            0x8e => {
+                let modrm = self.modrm(0)?;
+                let seg = SegReg::from_index(modrm.ext).ok_or_else(Stop::ud)?;
+                if seg == SegReg::Cs {
+                    return Err(Stop::ud());
+                }
+                let selector = self.read(modrm.rm, 2)? as u16;
+                self.cpu.load_segment(self.bus, seg, selector)?;
+                if seg == SegReg::Ss {
+                    self.retired.shadow = true;
+                }
+            }
+            0x8f => {
+                let size = self.opsize64();
+                // The stack pointer is incremented before the destination address is computed.
+                let value = self.pop(size)?;
+                let modrm = self.modrm(0)?;
+                self.write(modrm.rm, size, value)?;
+            }
+            0x90 if self.rex & 1 == 0 => {
+                // nop and pause.
+            }
+            0x90..=0x97 => {
+                let index = (opcode & 7) as usize | if self.rex & 1 != 0 { 8 } else { 0 };
+                let size = self.opsize;
+                let a = self.get_reg(0, size);
+                let b = self.get_reg(index, size);
+                self.set_reg(0, size, b);
+                self.set_reg(index, size, a);
+            }
+            0x98 => {
+                let half = self.opsize / 2;
+                let value = sign_extend(self.get_reg(0, half), half);
+                self.set_reg(0, self.opsize, value);
+            }
+            0x99 => {
+                let size = self.opsize;
+                let high = if self.get_reg(0, size) & sign_bit(size) != 0 {
+                    u64::MAX
+                } else {
+                    0
+                };
+                self.set_reg(2, size, high);
+            }
+            0x9a => {
+                self.require_not64()?;
+                let offset = self.fetch(self.opsize)?;
+                let selector = self.fetch(2)? as u16;
+                self.far_call(selector, offset)?;
+            }
+            0x9b => {
+                if self.cpu.sregs.cr0 & (CR0_MP | CR0_TS) == CR0_MP | CR0_TS {
+                    return Err(Stop::exception(VECTOR_NM));
+                }
+            }
+            0x9c => {
+                let size = self.opsize64();
+                let flags = self.cpu.rflags & !(FLAG_VM | FLAG_RF);
+                self.push(flags, size)?;
+            }
+            0x9d => {
+                let size = self.opsize64();
+                let value = self.pop(size)?;
+                self.load_flags(value, size);
+            }
+            0x9e => {
+                let ah = self.get_reg(4, 1) & !0x2a;
+                self.set_flags_masked(ah, FLAG_SF | FLAG_ZF | FLAG_AF | FLAG_PF | FLAG_CF);
+            }
+            0x9f => {
+                let flags = (self.cpu.rflags & 0xd5) | FLAG_FIXED;
+                self.cpu.gpr[0] = (self.cpu.gpr[0] & !0xff00) | (flags << 8);
+            }
+            0xa0..=0xa3 => {
+                let size = if opcode & 1 == 0 { 1 } else { self.opsize };
+                let offset = self.fetch(self.addrsize)?;
+                let seg = self.seg_override.unwrap_or(SegReg::Ds);
+                if opcode & 2 == 0 {
+                    let value = self.read_mem(seg, offset, size)?;
+                    self.set_reg(0, size, value);
+                } else {
+                    let value = self.get_reg(0, size);
+                    self.write_mem(seg, offset, size, value)?;
+                }
+            }
+            0xa8 | 0xa9 => {
+                let size = if opcode & 1 == 0 { 1 } else { self.opsize };
+                let imm = self.imm(size)?;
+                let acc = self.get_reg(0, size);
+                self.alu(ALU_AND, acc, imm, size);
+            }
+            0xb0..=0xb7 => {
+                let index = (opcode & 7) as usize | if self.rex & 1 != 0 { 8 } else { 0 };
+                let imm = self.fetch(1)?;
+                self.set_reg(index, 1, imm);
+            }
+            0xb8..=0xbf => {
+                let index = (opcode & 7) as usize | if self.rex & 1 != 0 { 8 } else { 0 };
+                let imm = self.fetch(self.opsize)?;
+                self.set_reg(index, self.opsize, imm);
+            }
+            0xc0 | 0xc1 | 0xd0..=0xd3 => {
+                let size = if opcode & 1 == 0 { 1 } else { self.opsize };
+                let imm_size = if opcode < 0xd0 { 1 } else { 0 };
+                let modrm = self.modrm(imm_size)?;
+                let count = match opcode {
+                    0xc0 | 0xc1 => self.fetch(1)?,
+                    0xd0 | 0xd1 => 1,
+                    _ => self.get_reg(1, 1),
+                };
+                let value = self.read(modrm.rm, size)?;
+                let result = self.shift(modrm.ext, value, count, size);
+                self.write(modrm.rm, size, result)?;
+            }
+            0xc2 | 0xc3 => {
+                let release = if opcode == 0xc2 { self.fetch(2)? } else { 0 };
+                let size = self.opsize64();
+                let target = self.pop(size)?;
+                let stack_size = self.cpu.stack_size();
+                let rsp = self.cpu.gpr[4].wrapping_add(release);
+                self.cpu.set_rsp(rsp, stack_size);
+                self.branch(target, size);
+            }
+            0xc4 | 0xc5 => {
+                if self.code64 {
+                    return Err(Stop::Unsupported("VEX-encoded instruction"));
+                }
+                let modrm = self.modrm(0)?;
+                if let Operand::Reg(_) = modrm.rm {
+                    return Err(Stop::Unsupported("VEX-encoded instruction"));
+                }
+                let seg = if opcode == 0xc4 {
+                    SegReg::Es
+                } else {
+                    SegReg::Ds
+                };
+                self.load_far_pointer(modrm, seg)?;
+            }
+            0xc6 | 0xc7 => {
+                let size = if opcode & 1 == 0 { 1 } else { self.opsize };
+                let modrm = self.modrm(size.min(4))?;
+                if modrm.ext != 0 {
+                    return Err(Stop::Unsupported("unimplemented opcode"));
+                }
+                let imm = self.imm(size)?;
+                self.write(modrm.rm, size, imm)?;
+            }
+            0xc8 => {
+                let frame_size = self.fetch(2)?;
+                let level = self.fetch(1)? & 0x1f;
+                self.enter(frame_size, level)?;
+            }
+            0xc9 => {
+                let stack_size = self.cpu.stack_size();
+                let rbp = self.cpu.gpr[5];
+                self.cpu.set_rsp(rbp, stack_size);
+                let size = self.opsize64();
+                let value = self.pop(size)?;
+                self.set_reg(5, size, value);
+            }
+            0xca | 0xcb => {
+                let release = if opcode == 0xca { self.fetch(2)? } else { 0 };
+                let size = self.opsize;
+                let offset = self.pop(size)?;
+                let selector = self.pop(size)? as u16;
+                self.cpu.load_code_segment(self.bus, selector)?;
+                let stack_size = self.cpu.stack_size();
+                let rsp = self.cpu.gpr[4].wrapping_add(release);
+                self.cpu.set_rsp(rsp, stack_size);
+                self.branch(offset, size);
+            }
+            0xcc => self.software_interrupt(VECTOR_BP)?,
+            0xcd => {
+                let vector = self.fetch_u8()?;
+                self.software_interrupt(vector)?;
+            }
+            0xce => {
+                self.require_not64()?;
+                if self.flag(FLAG_OF) {
+                    self.software_interrupt(VECTOR_OF)?;
+                }
+            }
+            0xcf => self.iret()?,
+            0xd7 => {
+                let seg = self.seg_override.unwrap_or(SegReg::Ds);
+                let offset = self.cpu.gpr[3].wrapping_add(self.get_reg(0, 1));
+                let value = self.read_mem(seg, offset, 1)?;
+                self.set_reg(0, 1, value);
+            }
+            0xd8..=0xdf => self.x87(opcode)?,
+            0xe0..=0xe3 => {
+                let rel = self.imm8s()?;
+                let asize = self.addrsize;
+                let taken = if opcode == 0xe3 {
+                    self.get_reg(1, asize) == 0
+                } else {
+                    let count = self.get_reg(1, asize).wrapping_sub(1) & mask(asize);
+                    self.cpu.gpr[1] = merge(self.cpu.gpr[1], count, asize);
+                    count != 0
+                        && match opcode {
+                            0xe0 => !self.flag(FLAG_ZF),
+                            0xe1 => self.flag(FLAG_ZF),
+                            _ => true,
+                        }
+                };
+                if taken {
+                    let target = self.next_rip().wrapping_add(rel);
+                    self.branch(target, self.opsize64());
+                }
+            }
+            0xe4..=0xe7 | 0xec..=0xef => {
+                let size = if opcode & 1 == 0 {
+                    1
+                } else {
+                    self.opsize.min(4)
+                };
+                let port = if opcode < 0xe8 {
+                    self.fetch(1)? as u16
+                } else {
+                    self.get_reg(2, 2) as u16
+                };
+                if opcode & 2 == 0 {
+                    let value = self.bus.io_in(port, size)?;
+                    self.set_reg(0, size, value);
+                } else {
+                    let value = self.get_reg(0, size);
+                    self.bus.io_out(port, size, value);
+                }
+            }
+            0xe8 => {
+                let size = self.opsize64();
+                let rel = self.imm(size)?;
+                let next = self.next_rip();
+                self.push(next, size)?;
+                self.branch(next.wrapping_add(rel), size);
+            }
+            0xe9 => {
+                let size = self.opsize64();
+                let rel = self.imm(size)?;
+                let target = self.next_rip().wrapping_add(rel);
+                self.branch(target, size);
+            }
+            0xea => {
+                self.require_not64()?;
+                let offset = self.fetch(self.opsize)?;
+                let selector = self.fetch(2)? as u16;
+                self.cpu.load_code_segment(self.bus, selector)?;
+                self.branch(offset, self.opsize);
+            }
+            0xeb => {
+                let rel = self.imm8s()?;
+                let target = self.next_rip().wrapping_add(rel);
+                self.branch(target, self.opsize64());
+            }
+            0xf1 => {
+                let next = self.next_rip();
+                self.cpu
+                    .deliver(self.bus, VECTOR_DB, None, EventSource::Exception, next)?;
+                self.branched = true;
+            }
+            0xf4 => {
+                self.require_cpl0()?;
+                self.retired.halted = true;
+            }
+            0xf5 => self.cpu.rflags ^= FLAG_CF,
+            0xf6 | 0xf7 => {
+                let size = if opcode & 1 == 0 { 1 } else { self.opsize };
+                let modrm = self.modrm(size.min(4))?;
+                self.group3(modrm, size)?;
+            }
+            0xf8 => self.cpu.rflags &= !FLAG_CF,
+            0xf9 => self.cpu.rflags |= FLAG_CF,
+            0xfa => self.cpu.rflags &= !FLAG_IF,
+            0xfb => {
+                if !self.flag(FLAG_IF) {
+                    self.retired.shadow = true;
+                }
+                self.cpu.rflags |= FLAG_IF;
+            }
+            0xfc => self.cpu.rflags &= !FLAG_DF,
+            0xfd => self.cpu.rflags |= FLAG_DF,
+            0xfe => {
+                let modrm = self.modrm(0)?;
+                if modrm.ext > 1 {
+                    return Err(Stop::ud());
+                }
+                let value = self.read(modrm.rm, 1)?;
+                let result = self.inc_dec(value, 1, modrm.ext == 1);
+                self.write(modrm.rm, 1, result)?;
+            }
+            0xff => self.group5()?,
+            _ => return Err(Stop::Unsupported("unimplemented opcode")),
+        }
+        Ok(())
+    }
+
+    fn group5(&mut self) -> Result<(), Stop> {
+        let modrm = self.modrm(0)?;
+        match modrm.ext {
+            0 | 1 => {
+                let size = self.opsize;
+                let value = self.read(modrm.rm, size)?;
+                let result = self.inc_dec(value, size, modrm.ext == 1);
+                self.write(modrm.rm, size, result)?;
+            }
+            2 | 4 => {
+                let size = self.opsize64();
+                let target = self.read(modrm.rm, size)?;
+                if modrm.ext == 2 {
+                    let next = self.next_rip();
+                    self.push(next, size)?;
+                }
+                self.branch(target, size);
+            }
+            3 | 5 => {
+                let (seg, offset) = Self::mem_operand(modrm.rm)?;
+                let size = self.opsize;
+                let target = self.read_mem(seg, offset, size)?;
+                let selector = self.read_mem(seg, offset.wrapping_add(size as u64), 2)? as u16;
+                if modrm.ext == 3 {
+                    self.far_call(selector, target)?;
+                } else {
+                    self.cpu.load_code_segment(self.bus, selector)?;
+                    self.branch(target, size);
+                }
+            }
+            6 => {
+                let size = self.opsize64();
+                let value = self.read(modrm.rm, size)?;
+                self.push(value, size)?;
+            }
+            _ => return Err(Stop::ud()),
+        }
+        Ok(())
+    }
+
+    fn far_call(&mut self, selector: u16, offset: u64) -> Result<(), Stop> {
+        let size = self.opsize;
+        let old_cs = self.cpu.sregs.cs.selector as u64;
+        let next = self.next_rip();
+        self.cpu.load_code_segment(self.bus, selector)?;
+        self.push(old_cs, size)?;
+        self.push(next, size)?;
+        self.branch(offset, size);
+        Ok(())
+    }
+
+    /// `les`, `lds`, `lss`, `lfs` and `lgs`.
+    fn load_far_pointer(&mut self, modrm: ModRm, seg: SegReg) -> Result<(), Stop> {
+        let (mem_seg, offset) = Self::mem_operand(modrm.rm)?;
+        let size = self.opsize;
+        let value = self.read_mem(mem_seg, offset, size)?;
+        let selector = self.read_mem(mem_seg, offset.wrapping_add(size as u64), 2)? as u16;
+        self.cpu.load_segment(self.bus, seg, selector)?;
+        self.set_reg(modrm.reg, size, value);
+        if seg == SegReg::Ss {
+            self.retired.shadow = true;
+        }
+        Ok(())
+    }
+
+    fn enter(&mut self, frame_size: u64, level: u64) -> Result<(), Stop> {
+        let size = self.opsize64();
+        let stack_size = self.cpu.stack_size();
+        let rbp = self.get_reg(5, size);
+        self.push(rbp, size)?;
+        let frame = self.cpu.gpr[4] & mask(stack_size);
+        if level > 0 {
+            let mut rbp = self.cpu.gpr[5];
+            for _ in 1..level {
+                rbp = rbp.wrapping_sub(size as u64) & mask(stack_size);
+                let value = self.cpu.read_virt(self.bus, SegReg::Ss, rbp, size)?;
+                self.push(value, size)?;
+            }
+            self.push(frame, size)?;
+        }
+        self.set_reg(5, size, frame);
+        let rsp = self.cpu.gpr[4].wrapping_sub(frame_size);
+        self.cpu.set_rsp(rsp, stack_size);
+        Ok(())
+    }
+
+    fn software_interrupt(&mut self, vector: u8) -> Result<(), Stop> {
+        let next = self.next_rip();
+        self.cpu
+            .deliver(self.bus, vector, None, EventSource::Software, next)?;
+        self.branched = true;
+        Ok(())
+    }
+
+    /// Replaces the writable bits of RFLAGS covered by `size`, as `popf` does.
+    fn load_flags(&mut self, value: u64, size: usize) {
+        let writable = FLAGS_WRITABLE & mask(size);
+        self.cpu.rflags = (self.cpu.rflags & !writable) | (value & writable) | FLAG_FIXED;
+    }
+
+    fn iret(&mut self) -> Result<(), Stop> {
+        let size = self.opsize;
+        if self.cpu.protected_mode() && self.flag(FLAG_NT) && !self.cpu.long_mode() {
+            return Err(Stop::Unsupported("task return"));
+        }
+        let rip = self.pop(size)?;
+        let selector = self.pop(size)? as u16;
+        let flags = self.pop(size)?;
+        if self.cpu.protected_mode() && (selector & 3) as u8 != self.cpu.cpl() {
+            return Err(Stop::Unsupported("privilege level change"));
+        }
+        let (ss_rsp, ss) = if self.code64 {
+            (Some(self.pop(size)?), Some(self.pop(size)? as u16))
+        } else {
+            (None, None)
+        };
+        self.cpu.load_code_segment(self.bus, selector)?;
+        if let (Some(rsp), Some(ss)) = (ss_rsp, ss) {
+            self.cpu.load_segment(self.bus, SegReg::Ss, ss)?;
+            self.cpu.gpr[4] = rsp;
+        }
+        self.load_flags(flags, size);
+        self.cpu.rflags &= !FLAG_VM;
+        self.cpu.nmi_blocked = false;
+        self.branch(rip, size);
+        Ok(())
+    }
+
+    /// Executes a string instruction, including its `rep` prefix. Up to
+    /// `MAX_STRING_ITERATIONS` iterations run in one step; if more remain, the instruction pointer
+    /// is left on the instruction so that the next step resumes it.
+    fn string_op(&mut self, opcode: u8) -> Result<(), Stop> {
+        let size = match opcode {
+            0x6c..=0x6f => {
+                if opcode & 1 == 0 {
+                    1
+                } else {
+                    self.opsize.min(4)
+                }
+            }
+            _ if opcode & 1 == 0 => 1,
+            _ => self.opsize,
+        };
+        let asize = self.addrsize;
+        let step = if self.flag(FLAG_DF) {
+            (size as u64).wrapping_neg()
+        } else {
+            size as u64
+        };
+        let src_seg = self.seg_override.unwrap_or(SegReg::Ds);
+        let counted = self.rep != Rep::None;
+        let compares = matches!(opcode, 0xa6 | 0xa7 | 0xae | 0xaf);
+        let advance = |cpu: &mut ArchState, index: usize| {
+            let value = cpu.gpr[index].wrapping_add(step) & mask(asize);
+            cpu.gpr[index] = merge(cpu.gpr[index], value, asize);
+        };
+
+        let mut iterations = 0;
+        loop {
+            if counted && self.get_reg(1, asize) == 0 {
+                return Ok(());
+            }
+            let rsi = self.cpu.gpr[6];
+            let rdi = self.cpu.gpr[7];
+            match opcode {
+                0x6c | 0x6d => {
+                    let port = self.get_reg(2, 2) as u16;
+                    let value = self.bus.io_in(port, size)?;
+                    self.write_mem(SegReg::Es, rdi, size, value)?;
+                    advance(self.cpu, 7);
+                }
+                0x6e | 0x6f => {
+                    let port = self.get_reg(2, 2) as u16;
+                    let value = self.read_mem(src_seg, rsi, size)?;
+                    self.bus.io_out(port, size, value);
+                    advance(self.cpu, 6);
+                }
+                0xa4 | 0xa5 => {
+                    let value = self.read_mem(src_seg, rsi, size)?;
+                    self.write_mem(SegReg::Es, rdi, size, value)?;
+                    advance(self.cpu, 6);
+                    advance(self.cpu, 7);
+                }
+                0xa6 | 0xa7 => {
+                    let a = self.read_mem(src_seg, rsi, size)?;
+                    let b = self.read_mem(SegReg::Es, rdi, size)?;
+                    self.alu(ALU_CMP, a, b, size);
+                    advance(self.cpu, 6);
+                    advance(self.cpu, 7);
+                }
+                0xaa | 0xab => {
+                    let value = self.get_reg(0, size);
+                    self.write_mem(SegReg::Es, rdi, size, value)?;
+                    advance(self.cpu, 7);
+                }
+                0xac | 0xad => {
+                    let value = self.read_mem(src_seg, rsi, size)?;
+                    self.set_reg(0, size, value);
+                    advance(self.cpu, 6);
+                }
+                _ => {
+                    let a = self.get_reg(0, size);
+                    let b = self.read_mem(SegReg::Es, rdi, size)?;
+                    self.alu(ALU_CMP, a, b, size);
+                    advance(self.cpu, 7);
+                }
+            }
+            if !counted {
+                return Ok(());
+            }
+            let count = self.get_reg(1, asize).wrapping_sub(1) & mask(asize);
+            self.cpu.gpr[1] = merge(self.cpu.gpr[1], count, asize);
+            if compares {
+                let zf = self.flag(FLAG_ZF);
+                if (self.rep == Rep::Rep && !zf) || (self.rep == Rep::Repne && zf) {
+                    return Ok(());
+                }
+            }
+            if count == 0 {
+                return Ok(());
+            }
+            iterations += 1;
+            if iterations >= MAX_STRING_ITERATIONS || self.bus.touched_device() {
+                self.branch(self.start, self.code_size);
+                return Ok(());
+            }
+        }
+    }
+
+    /// The handful of x87 control instructions that firmware uses to probe for an FPU.
+    fn x87(&mut self, opcode: u8) -> Result<(), Stop> {
+        if self.cpu.sregs.cr0 & (CR0_EM | CR0_TS) != 0 {
+            return Err(Stop::exception(VECTOR_NM));
+        }
+        let modrm_byte = self.fetch(1)? as u8;
+        self.len -= 1;
+        match (opcode, modrm_byte) {
+            (0xdb, 0xe3) => {
+                self.len += 1;
+                let fpu = &mut self.cpu.fpu;
+                fpu.fcw = 0x37f;
+                fpu.fsw = 0;
+                fpu.ftwx = 0;
+                fpu.last_opcode = 0;
+                fpu.last_ip = 0;
+                fpu.last_dp = 0;
+            }
+            (0xdb, 0xe2) => {
+                self.len += 1;
+                self.cpu.fpu.fsw &= 0x7f00;
+            }
+            (0xdf, 0xe0) => {
+                self.len += 1;
+                let fsw = self.cpu.fpu.fsw as u64;
+                self.set_reg(0, 2, fsw);
+            }
+            (0xd9, _) if modrm_byte < 0xc0 && (modrm_byte >> 3) & 7 >= 5 => {
+                let modrm = self.modrm(0)?;
+                let (seg, offset) = Self::mem_operand(modrm.rm)?;
+                match modrm.ext {
+                    5 => self.cpu.fpu.fcw = self.read_mem(seg, offset, 2)? as u16,
+                    7 => {
+                        let fcw = self.cpu.fpu.fcw as u64;
+                        self.write_mem(seg, offset, 2, fcw)?;
+                    }
+                    _ => return Err(Stop::Unsupported("x87 instruction")),
+                }
+            }
+            (0xdd, _) if modrm_byte < 0xc0 && (modrm_byte >> 3) & 7 == 7 => {
+                let modrm = self.modrm(0)?;
+                let (seg, offset) = Self::mem_operand(modrm.rm)?;
+                let fsw = self.cpu.fpu.fsw as u64;
+                self.write_mem(seg, offset, 2, fsw)?;
+            }
+            _ => return Err(Stop::Unsupported("x87 instruction")),
+        }
+        Ok(())
+    }
+
+    fn execute_0f(&mut self) -> Result<(), Stop> {
+        let opcode = self.fetch_u8()?;
+        match opcode {
+            0x00 => self.group6()?,
+            0x01 => self.group7()?,
+            0x02 | 0x03 => {
+                self.require_protected()?;
+                let modrm = self.modrm(0)?;
+                let selector = self.read(modrm.rm, 2)? as u16;
+                match self.descriptor_info(selector, opcode == 0x02)? {
+                    Some(value) => {
+                        self.cpu.rflags |= FLAG_ZF;
+                        self.set_reg(modrm.reg, self.opsize, value);
+                    }
+                    None => self.cpu.rflags &= !FLAG_ZF,
+                }
+            }
+            0x06 => {
+                self.require_cpl0()?;
+                self.cpu.sregs.cr0 &= !CR0_TS;
+            }
+            0x08 | 0x09 => self.require_cpl0()?,
+            0x0b | 0xb9 | 0xff => return Err(Stop::ud()),
+            0x0d | 0x18..=0x1f => {
+                // Prefetch hints and multi-byte nops.
+                self.modrm(0)?;
+            }
+            0x20 | 0x22 => {
+                self.require_cpl0()?;
+                let (reg, index) = self.modrm_regs()?;
+                let size = if self.cpu.long_mode() { 8 } else { 4 };
+                if opcode == 0x20 {
+                    let value = match reg {
+                        0 => self.cpu.sregs.cr0,
+                        2 => self.cpu.sregs.cr2,
+                        3 => self.cpu.sregs.cr3,
+                        4 => self.cpu.sregs.cr4,
+                        8 => self.cpu.sregs.cr8,
+                        _ => return Err(Stop::ud()),
+                    };
+                    self.cpu.gpr[index] = value & mask(size);
+                } else {
+                    let value = self.cpu.gpr[index] & mask(size);
+                    match reg {
+                        0 => self.cpu.write_cr0(value)?,
+                        2 => self.cpu.sregs.cr2 = value,
+                        3 => self.cpu.write_cr3(value)?,
+                        4 => self.cpu.write_cr4(value)?,
+                        8 => {
+                            if value > 0xf {
+                                return Err(Stop::gp(0));
+                            }
+                            self.cpu.sregs.cr8 = value;
+                        }
+                        _ => return Err(Stop::ud()),
+                    }
+                }
+            }
+            0x21 | 0x23 => {
+                self.require_cpl0()?;
+                let (reg, index) = self.modrm_regs()?;
+                let size = if self.code64 { 8 } else { 4 };
+                let de = self.cpu.sregs.cr4 & (1 << 3) != 0;
+                let dr = match reg {
+                    4 | 5 if de => return Err(Stop::ud()),
+                    4 => 6,
+                    5 => 7,
+                    dr if dr < 8 => dr,
+                    _ => return Err(Stop::ud()),
+                };
+                let debugregs = &mut self.cpu.debugregs;
+                if opcode == 0x21 {
+                    let value = match dr {
+                        0..=3 => debugregs.db[dr],
+                        6 => debugregs.dr6,
+                        _ => debugregs.dr7,
+                    };
+                    self.cpu.gpr[index] = value & mask(size);
+                } else {
+                    let value = self.cpu.gpr[index] & mask(size);
+                    match dr {
+                        0..=3 => debugregs.db[dr] = value,
+                        6 => debugregs.dr6 = (value & 0xf00f) | 0xffff_0ff0,
+                        _ => debugregs.dr7 = (value & 0xffff_2bff) | 0x400,
+                    }
+                }
+            }
+            0x30 => {
+                self.require_cpl0()?;
+                let index = self.get_reg(1, 4) as u32;
+                let value = (self.get_reg(2, 4) << 32) | self.get_reg(0, 4);
+                self.cpu.write_msr(index, value)?;
+            }
+            0x31 => {
+                if self.cpu.sregs.cr4 & CR4_TSD != 0 {
+                    self.require_cpl0()?;
+                }
+                let tsc = self.cpu.read_msr(crate::MSR_IA32_TSC).unwrap_or(0);
+                self.set_reg(0, 4, tsc);
+                self.set_reg(2, 4, tsc >> 32);
+            }
+            0x32 => {
+                self.require_cpl0()?;
+                let index = self.get_reg(1, 4) as u32;
+                let value = self.cpu.read_msr(index).ok_or_else(|| Stop::gp(0))?;
+                self.set_reg(0, 4, value);
+                self.set_reg(2, 4, value >> 32);
+            }
+            0x40..=0x4f => {
+                let size = self.opsize;
+                let modrm = self.modrm(0)?;
+                let value = self.read(modrm.rm, size)?;
+                let dest = if self.cond(opcode & 0xf) {
+                    value
+                } else {
+                    self.get_reg(modrm.reg, size)
+                };
+                self.set_reg(modrm.reg, size, dest);
+            }
+            0x6e | 0x6f | 0x7e | 0x7f | 0x77 | 0xdb | 0xdf | 0xeb | 0xef
+                if !self.opsize_prefix && self.rep == Rep::None =>
+            {
+                self.mmx(opcode)?
+            }
+            0x80..=0x8f => {
+                let size = self.opsize64();
+                let rel = self.imm(size.min(4))?;
+                let rel = sign_extend(rel, size.min(4));
+                if self.cond(opcode & 0xf) {
+                    let target = self.next_rip().wrapping_add(rel);
+                    self.branch(target, size);
+                }
+            }
+            0x90..=0x9f => {
+                let modrm = self.modrm(0)?;
+                let value = self.cond(opcode & 0xf) as u64;
+                self.write(modrm.rm, 1, value)?;
+            }
+            0xa0 | 0xa8 => {
+                let seg = if opcode == 0xa0 {
+                    SegReg::Fs
+                } else {
+                    SegReg::Gs
+                };
+                let selector = self.cpu.seg(seg).selector as u64;
+                self.push(selector, self.opsize64())?;
+            }
+            0xa1 | 0xa9 => {
+                let seg = if opcode == 0xa1 {
+                    SegReg::Fs
+                } else {
+                    SegReg::Gs
+                };
+                let selector = self.pop(self.opsize64())? as u16;
+                self.cpu.load_segment(self.bus, seg, selector)?;
+            }
+            0xa2 => {
+                let function = self.get_reg(0, 4) as u32;
+                let index = self.get_reg(1, 4) as u32;
+                let result = self.bus.cpuid(function, index);
+                self.set_reg(0, 4, result.eax as u64);
+                self.set_reg(3, 4, result.ebx as u64);
+                self.set_reg(1, 4, result.ecx as u64);
+                self.set_reg(2, 4, result.edx as u64);
+            }
+            0xa3 | 0xab | 0xb3 | 0xbb => {
+                let modrm = self.modrm(0)?;
+                let offset = self.get_reg(modrm.reg, self.opsize);
+                self.bit_test((opcode >> 3) & 3, modrm.rm, offset, true)?;
+            }
+            0xba => {
+                let modrm = self.modrm(1)?;
+                let offset = self.fetch(1)?;
+                if modrm.ext < 4 {
+                    return Err(Stop::ud());
+                }
+                self.bit_test(modrm.ext & 3, modrm.rm, offset, false)?;
+            }
+            0xa4 | 0xa5 | 0xac | 0xad => {
+                let size = self.opsize;
+                let modrm = self.modrm(if opcode & 1 == 0 { 1 } else { 0 })?;
+                let count = if opcode & 1 == 0 {
+                    self.fetch(1)?
+                } else {
+                    self.get_reg(1, 1)
+                };
+                let dest = self.read(modrm.rm, size)?;
+                let src = self.get_reg(modrm.reg, size);
+                if let Some(result) = self.double_shift(dest, src, count, size, opcode >= 0xac) {
+                    self.write(modrm.rm, size, result)?;
+                }
+            }
+            0xae => self.group15()?,
+            0xaf => {
+                let size = self.opsize;
+                let modrm = self.modrm(0)?;
+                let a = self.get_reg(modrm.reg, size);
+                let b = self.read(modrm.rm, size)?;
+                let result = self.imul(a, b, size);
+                self.set_reg(modrm.reg, size, result);
+            }
+            0xb0 | 0xb1 => {
+                let size = if opcode & 1 == 0 { 1 } else { self.opsize };
+                let modrm = self.modrm(0)?;
+                let dest = self.read(modrm.rm, size)?;
+                let acc = self.get_reg(0, size);
+                self.alu(ALU_CMP, acc, dest, size);
+                if self.flag(FLAG_ZF) {
+                    let src = self.get_reg(modrm.reg, size);
+                    self.write(modrm.rm, size, src)?;
+                } else {
+                    self.write(modrm.rm, size, dest)?;
+                    self.set_reg(0, size, dest);
+                }
+            }
+            0xb2 | 0xb4 | 0xb5 => {
+                let modrm = self.modrm(0)?;
+                let seg = match opcode {
+                    0xb2 => SegReg::Ss,
+                    0xb4 => SegReg::Fs,
+                    _ => SegReg::Gs,
+                };
+                self.load_far_pointer(modrm, seg)?;
+            }
+            0xb6 | 0xb7 | 0xbe | 0xbf => {
+                let src_size = if opcode & 1 == 0 { 1 } else { 2 };
+                let modrm = self.modrm(0)?;
+                let value = self.read(modrm.rm, src_size)?;
+                let value = if opcode >= 0xbe {
+                    sign_extend(value, src_size)
+                } else {
+                    value
+                };
+                self.set_reg(modrm.reg, self.opsize, value);
+            }
+            0xb8 if self.rep == Rep::Rep => {
+                let size = self.opsize;
+                let modrm = self.modrm(0)?;
+                let value = self.read(modrm.rm, size)?;
+                self.set_status(if value == 0 { FLAG_ZF } else { 0 });
+                self.set_reg(modrm.reg, size, value.count_ones() as u64);
+            }
+            0xbc | 0xbd => {
+                let size = self.opsize;
+                let modrm = self.modrm(0)?;
+                let value = self.read(modrm.rm, size)?;
+                if value == 0 {
+                    self.cpu.rflags |= FLAG_ZF;
+                } else {
+                    self.cpu.rflags &= !FLAG_ZF;
+                    let index = if opcode == 0xbc {
+                        value.trailing_zeros()
+                    } else {
+                        63 - value.leading_zeros()
+                    };
+                    self.set_reg(modrm.reg, size, index as u64);
+                }
+            }
+            0xc0 | 0xc1 => {
+                let size = if opcode & 1 == 0 { 1 } else { self.opsize };
+                let modrm = self.modrm(0)?;
+                let dest = self.read(modrm.rm, size)?;
+                let src = self.get_reg(modrm.reg, size);
+                let sum = self.alu(ALU_ADD, dest, src, size).unwrap();
+                self.write(modrm.rm, size, sum)?;
+                self.set_reg(modrm.reg, size, dest);
+            }
+            0xc7 => {
+                let modrm = self.modrm(0)?;
+                match (modrm.ext, modrm.rm) {
+                    (1, Operand::Mem(seg, offset)) => self.cmpxchg8b(seg, offset)?,
+                    _ => return Err(Stop::Unsupported("unimplemented opcode")),
+                }
+            }
+            0xc8..=0xcf => {
+                let index = (opcode & 7) as usize | if self.rex & 1 != 0 { 8 } else { 0 };
+                let value = match self.opsize {
+                    8 => self.cpu.gpr[index].swap_bytes(),
+                    4 => (self.cpu.gpr[index] as u32).swap_bytes() as u64,
+                    _ => 0,
+                };
+                self.set_reg(index, self.opsize, value);
+            }
+            _ => return Err(Stop::Unsupported("unimplemented opcode")),
+        }
+        Ok(())
+    }
+
+    /// `lar` and `lsl`: returns the access rights or limit of the segment named by `selector`,
+    /// or `None` if the selector is not visible at the current privilege level.
+    fn descriptor_info(&mut self, selector: u16, access_rights: bool) -> Result<Option<u64>, Stop> {
+        if selector & !3 == 0 {
+            return Ok(None);
+        }
+        let desc = match self.cpu.read_descriptor(self.bus, selector) {
+            Ok(desc) => desc,
+            Err(Stop::Exception(_)) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let segment = segment_from_descriptor(selector, desc);
+        let valid_type = if segment.s != 0 {
+            true
+        } else if self.cpu.long_mode() {
+            matches!(segment.type_, 2 | 9 | 0xb) || (access_rights && segment.type_ == 0xc)
+        } else {
+            matches!(segment.type_, 1 | 2 | 3 | 9 | 0xb)
+                || (access_rights && matches!(segment.type_, 4 | 5 | 0xc))
+        };
+        let conforming = segment.s != 0 && segment.type_ & 0xc == 0xc;
+        let rpl = (selector & 3) as u8;
+        if !valid_type || (!conforming && segment.dpl < self.cpu.cpl().max(rpl)) {
+            return Ok(None);
+        }
+        Ok(Some(if access_rights {
+            (desc >> 32) & 0x00f0_ff00
+        } else {
+            segment.limit_bytes as u64
+        }))
+    }
+
+    fn bit_test(
+        &mut self,
+        op: u8,
+        operand: Operand,
+        offset: u64,
+        register_offset: bool,
+    ) -> Result<(), Stop> {
+        let size = self.opsize;
+        let bits = (size * 8) as i64;
+        let (operand, bit) = match operand {
+            Operand::Mem(seg, ea) if register_offset => {
+                let offset = sign_extend(offset, size) as i64;
+                let ea = ea.wrapping_add((offset.div_euclid(bits) * size as i64) as u64);
+                (
+                    Operand::Mem(seg, ea & mask(self.addrsize)),
+                    offset.rem_euclid(bits) as u64,
+                )
+            }
+            _ => (operand, offset & (bits as u64 - 1)),
+        };
+        let value = self.read(operand, size)?;
+        let set = (value >> bit) & 1 != 0;
+        self.set_flags_masked(if set { FLAG_CF } else { 0 }, FLAG_CF);
+        let result = match op {
+            0 => return Ok(()),
+            1 => value | (1 << bit),
+            2 => value & !(1 << bit),
+            _ => value ^ (1 << bit),
+        };
+        self.write(operand, size, result)
+    }
+
+    /// `shld` and `shrd`. Returns `None` if the count is zero.
+    fn double_shift(
+        &mut self,
+        dest: u64,
+        src: u64,
+        count: u64,
+        size: usize,
+        right: bool,
+    ) -> Option<u64> {
+        let bits = (size * 8) as u32;
+        let count = (count & if size == 8 { 0x3f } else { 0x1f }) as u32;
+        if count == 0 {
+            return None;
+        }
+        let m = mask(size);
+        let (dest, src) = (dest & m, src & m);
+        let (result, cf) = if right {
+            let combined = ((src as u128) << bits) | dest as u128;
+            (
+                (combined >> count) as u64 & m,
+                (dest as u128 >> (count - 1)) & 1,
+            )
+        } else {
+            let combined = ((dest as u128) << bits) | src as u128;
+            (
+                ((combined << count) >> bits) as u64 & m,
+                ((combined << count) >> (2 * bits)) & 1,
+            )
+        };
+        let mut flags = szp(result, size);
+        if cf != 0 {
+            flags |= FLAG_CF;
+        }
+        if (result ^ dest) & sign_bit(size) != 0 {
+            flags |= FLAG_OF;
+        }
+        self.set_status(flags);
+        Some(result)
+    }
+
+    fn cmpxchg8b(&mut self, seg: SegReg, offset: u64) -> Result<(), Stop> {
+        if self.opsize == 8 {
+            if offset & 0xf != 0 {
+                return Err(Stop::gp(0));
+            }
+            let low = self.read_mem(seg, offset, 8)?;
+            let high = self.read_mem(seg, offset.wrapping_add(8), 8)?;
+            if low == self.cpu.gpr[0] && high == self.cpu.gpr[2] {
+                let (new_low, new_high) = (self.cpu.gpr[3], self.cpu.gpr[1]);
+                self.write_mem(seg, offset, 8, new_low)?;
+                self.write_mem(seg, offset.wrapping_add(8), 8, new_high)?;
+                self.cpu.rflags |= FLAG_ZF;
+            } else {
+                self.write_mem(seg, offset, 8, low)?;
+                self.write_mem(seg, offset.wrapping_add(8), 8, high)?;
+                self.cpu.gpr[0] = low;
+                self.cpu.gpr[2] = high;
+                self.cpu.rflags &= !FLAG_ZF;
+            }
+        } else {
+            let value = self.read_mem(seg, offset, 8)?;
+            let expected = (self.get_reg(2, 4) << 32) | self.get_reg(0, 4);
+            if value == expected {
+                let new = (self.get_reg(1, 4) << 32) | self.get_reg(3, 4);
+                self.write_mem(seg, offset, 8, new)?;
+                self.cpu.rflags |= FLAG_ZF;
+            } else {
+                self.write_mem(seg, offset, 8, value)?;
+                self.set_reg(0, 4, value);
+                self.set_reg(2, 4, value >> 32);
+                self.cpu.rflags &= !FLAG_ZF;
+            }
+        }
+        Ok(())
+    }
+
+    fn group6(&mut self) -> Result<(), Stop> {
+        self.require_protected()?;
+        let modrm = self.modrm(0)?;
+        match modrm.ext {
+            0 | 1 => {
+                let selector = if modrm.ext == 0 {
+                    self.cpu.sregs.ldt.selector
+                } else {
+                    self.cpu.sregs.tr.selector
+                } as u64;
+                let size = match modrm.rm {
+                    Operand::Reg(_) => self.opsize,
+                    Operand::Mem(..) => 2,
+                };
+                self.write(modrm.rm, size, selector)?;
+            }
+            2 | 3 => {
+                self.require_cpl0()?;
+                let selector = self.read(modrm.rm, 2)? as u16;
+                self.load_system_segment(selector, modrm.ext == 3)?;
+            }
+            4 | 5 => {
+                let selector = self.read(modrm.rm, 2)? as u16;
+                let accessible = match self.cpu.read_descriptor(self.bus, selector) {
+                    Ok(desc) if selector & !3 != 0 => {
+                        let segment = segment_from_descriptor(selector, desc);
+                        let code = segment.type_ & 0x8 != 0;
+                        segment.s != 0
+                            && if modrm.ext == 4 {
+                                !code || segment.type_ & 0x2 != 0
+                            } else {
+                                !code && segment.type_ & 0x2 != 0
+                            }
+                    }
+                    Ok(_) | Err(Stop::Exception(_)) => false,
+                    Err(e) => return Err(e),
+                };
+                self.set_flags_masked(if accessible { FLAG_ZF } else { 0 }, FLAG_ZF);
+            }
+            _ => return Err(Stop::ud()),
+        }
+        Ok(())
+    }
+
+    /// `lldt` and `ltr`.
+    fn load_system_segment(&mut self, selector: u16, task: bool) -> Result<(), Stop> {
+        let error_code = (selector & !3) as u32;
+        if selector & !3 == 0 {
+            if task {
+                return Err(Stop::gp(0));
+            }
+            self.cpu.sregs.ldt = crate::Segment {
+                selector,
+                ..Default::default()
+            };
+            return Ok(());
+        }
+        if selector & 4 != 0 {
+            return Err(Stop::gp(error_code));
+        }
+        let desc = self.cpu.read_descriptor(self.bus, selector)?;
+        let high = self.cpu.read_descriptor_high(self.bus, selector)?;
+        let mut segment = segment_from_descriptor(selector, desc);
+        let valid = segment.s == 0
+            && if task {
+                segment.type_ == 9 || (!self.cpu.long_mode() && segment.type_ == 1)
+            } else {
+                segment.type_ == 2
+            };
+        if !valid {
+            return Err(Stop::gp(error_code));
+        }
+        if segment.present == 0 {
+            return Err(Stop::exception_with_code(VECTOR_NP, error_code));
+        }
+        segment.base |= (high & 0xffff_ffff) << 32;
+        if task {
+            // Mark the TSS busy.
+            segment.type_ |= 2;
+            let gdt = self.cpu.sregs.gdt.base;
+            let busy = desc | (2 << 40);
+            self.cpu.write_linear(
+                self.bus,
+                gdt.wrapping_add((selector & !7) as u64),
+                &busy.to_le_bytes(),
+            )?;
+            self.cpu.sregs.tr = segment;
+        } else {
+            self.cpu.sregs.ldt = segment;
+        }
+        Ok(())
+    }
+
+    fn group7(&mut self) -> Result<(), Stop> {
+        let modrm = self.modrm(0)?;
+        if let Operand::Mem(seg, offset) = modrm.rm {
+            match modrm.ext {
+                0 | 1 => {
+                    let table = if modrm.ext == 0 {
+                        self.cpu.sregs.gdt
+                    } else {
+                        self.cpu.sregs.idt
+                    };
+                    let base_size = if self.code64 { 8 } else { 4 };
+                    self.write_mem(seg, offset, 2, table.limit as u64)?;
+                    self.write_mem(seg, offset.wrapping_add(2), base_size, table.base)?;
+                }
+                2 | 3 => {
+                    self.require_cpl0()?;
+                    let limit = self.read_mem(seg, offset, 2)? as u16;
+                    let base = if self.code64 {
+                        self.read_mem(seg, offset.wrapping_add(2), 8)?
+                    } else {
+                        let base = self.read_mem(seg, offset.wrapping_add(2), 4)?;
+                        if self.opsize == 2 {
+                            base & 0xff_ffff
+                        } else {
+                            base
+                        }
+                    };
+                    let table = if modrm.ext == 2 {
+                        &mut self.cpu.sregs.gdt
+                    } else {
+                        &mut self.cpu.sregs.idt
+                    };
+                    table.base = base;
+                    table.limit = limit;
+                }
+                4 => {
+                    let cr0 = self.cpu.sregs.cr0;
+                    self.write_mem(seg, offset, 2, cr0)?;
+                }
+                6 => {
+                    self.require_cpl0()?;
+                    let value = self.read_mem(seg, offset, 2)?;
+                    self.lmsw(value)?;
+                }
+                7 => {
+                    // invlpg: there is no TLB to invalidate.
+                    self.require_cpl0()?;
+                }
+                _ => return Err(Stop::ud()),
+            }
+            return Ok(());
+        }
+
+        match (modrm.ext, modrm.rm) {
+            (4, operand) => {
+                let cr0 = self.cpu.sregs.cr0;
+                self.write(operand, self.opsize, cr0)?;
+            }
+            (6, operand) => {
+                self.require_cpl0()?;
+                let value = self.read(operand, 2)?;
+                self.lmsw(value)?;
+            }
+            (7, Operand::Reg(0)) if self.code64 => {
+                // swapgs
+                self.require_cpl0()?;
+                std::mem::swap(&mut self.cpu.sregs.gs.base, &mut self.cpu.kernel_gs_base);
+            }
+            (7, Operand::Reg(1)) => {
+                // rdtscp
+                if self.cpu.sregs.cr4 & CR4_TSD != 0 {
+                    self.require_cpl0()?;
+                }
+                let tsc = self.cpu.read_msr(crate::MSR_IA32_TSC).unwrap_or(0);
+                self.set_reg(0, 4, tsc);
+                self.set_reg(2, 4, tsc >> 32);
+                let aux = self.cpu.tsc_aux;
+                self.set_reg(1, 4, aux);
+            }
+            _ => {
+                // VMX, SVM, monitor/mwait and XSAVE feature control are not modeled.
+                return Err(Stop::Unsupported("unimplemented opcode"));
+            }
+        }
+        Ok(())
+    }
+
+    fn lmsw(&mut self, value: u64) -> Result<(), Stop> {
+        let cr0 = self.cpu.sregs.cr0;
+        // lmsw can set PE but not clear it.
+        let new = (cr0 & !0xe) | (value & 0xf) | (cr0 & CR0_PE);
+        self.cpu.write_cr0(new)
+    }
+
+    fn group15(&mut self) -> Result<(), Stop> {
+        let modrm = self.modrm(0)?;
+        match modrm.rm {
+            Operand::Reg(index) => match (modrm.ext, self.rep) {
+                (0..=3, Rep::Rep) => {
+                    if !self.code64 || self.cpu.sregs.cr4 & CR4_FSGSBASE == 0 {
+                        return Err(Stop::ud());
+                    }
+                    let size = if self.rex & 8 != 0 { 8 } else { 4 };
+                    let seg = if modrm.ext & 1 == 0 {
+                        SegReg::Fs
+                    } else {
+                        SegReg::Gs
+                    };
+                    if modrm.ext < 2 {
+                        let base = self.cpu.seg(seg).base;
+                        self.set_reg(index, size, base);
+                    } else {
+                        let base = self.get_reg(index, size);
+                        if !is_canonical(base) {
+                            return Err(Stop::gp(0));
+                        }
+                        self.cpu.seg_mut(seg).base = base;
+                    }
+                }
+                // lfence, mfence and sfence.
+                (5..=7, Rep::None) => {}
+                _ => return Err(Stop::Unsupported("unimplemented opcode")),
+            },
+            Operand::Mem(seg, offset) => match modrm.ext {
+                0 | 1 => {
+                    let linear = self.cpu.linear(seg, offset)?;
+                    if linear & 0xf != 0 {
+                        return Err(Stop::gp(0));
+                    }
+                    let mut image = [0u8; FXSAVE_SIZE];
+                    if modrm.ext == 0 {
+                        fxsave(&self.cpu.fpu, &mut image);
+                        self.cpu.write_linear(self.bus, linear, &image)?;
+                    } else {
+                        self.cpu
+                            .read_linear(self.bus, linear, &mut image, Access::Read)?;
+                        fxrstor(&mut self.cpu.fpu, &image);
+                    }
+                }
+                2 => {
+                    let value = self.read_mem(seg, offset, 4)?;
+                    if value & !0xffff != 0 {
+                        return Err(Stop::gp(0));
+                    }
+                    self.cpu.fpu.mxcsr = value as u32;
+                }
+                3 => {
+                    let mxcsr = self.cpu.fpu.mxcsr as u64;
+                    self.write_mem(seg, offset, 4, mxcsr)?;
+                }
+                // clflush
+                7 if self.rep == Rep::None && !self.opsize_prefix => {}
+                _ => return Err(Stop::Unsupported("unimplemented opcode")),
+            },
+        }
+        Ok(())
+    }
+
+    /// The MMX moves and logical operations.
+    fn mmx(&mut self, opcode: u8) -> Result<(), Stop> {
+        if self.cpu.sregs.cr0 & CR0_EM != 0 {
+            return Err(Stop::ud());
+        }
+        if self.cpu.sregs.cr0 & CR0_TS != 0 {
+            return Err(Stop::exception(VECTOR_NM));
+        }
+        if opcode == 0x77 {
+            // emms
+            self.cpu.fpu.ftwx = 0;
+            return Ok(());
+        }
+        let modrm = self.modrm(0)?;
+        let mm = modrm.ext as usize;
+        // MMX registers ignore REX.B and alias the x87 register file.
+        let source = match modrm.rm {
+            Operand::Reg(index) => Operand::Reg(index & 7),
+            mem => mem,
+        };
+        let read_mm = |cpu: &ArchState, index: usize| cpu.fpu.fpr[index].significand;
+        match opcode {
+            0x6e => {
+                let size = if self.rex & 8 != 0 { 8 } else { 4 };
+                let value = self.read(modrm.rm, size)?;
+                self.write_mm(mm, value);
+            }
+            0x7e => {
+                let size = if self.rex & 8 != 0 { 8 } else { 4 };
+                let value = read_mm(self.cpu, mm) & mask(size);
+                self.write(modrm.rm, size, value)?;
+                self.enter_mmx_mode();
+            }
+            0x6f => {
+                let value = match source {
+                    Operand::Reg(index) => read_mm(self.cpu, index),
+                    Operand::Mem(seg, offset) => self.read_mem(seg, offset, 8)?,
+                };
+                self.write_mm(mm, value);
+            }
+            0x7f => {
+                let value = read_mm(self.cpu, mm);
+                match source {
+                    Operand::Reg(index) => self.write_mm(index, value),
+                    Operand::Mem(seg, offset) => {
+                        self.write_mem(seg, offset, 8, value)?;
+                        self.enter_mmx_mode();
+                    }
+                }
+            }
+            _ => {
+                let b = match source {
+                    Operand::Reg(index) => read_mm(self.cpu, index),
+                    Operand::Mem(seg, offset) => self.read_mem(seg, offset, 8)?,
+                };
+                let a = read_mm(self.cpu, mm);
+                let value = match opcode {
+                    0xdb => a & b,
+                    0xdf => !a & b,
+                    0xeb => a | b,
+                    _ => a ^ b,
+                };
+                self.write_mm(mm, value);
+            }
+        }
+        Ok(())
+    }
+
+    fn enter_mmx_mode(&mut self) {
+        // Any MMX instruction marks every register valid and resets the x87 top of stack.
+        self.cpu.fpu.ftwx = 0xff;
+        self.cpu.fpu.fsw &= !(7 << 11);
+    }
+
+    fn write_mm(&mut self, index: usize, value: u64) {
+        self.enter_mmx_mode();
+        let reg = &mut self.cpu.fpu.fpr[index];
+        reg.significand = value;
+        reg.sign_exp = 0xffff;
+    }
+}
+
+/// Size of the legacy region written by `fxsave`.
+pub(super) const FXSAVE_SIZE: usize = 512;
+
+/// Serializes the x87/SSE state into the `fxsave` memory layout.
+pub(super) fn fxsave(fpu: &crate::Fpu, image: &mut [u8]) {
+    image[0..2].copy_from_slice(&fpu.fcw.to_le_bytes());
+    image[2..4].copy_from_slice(&fpu.fsw.to_le_bytes());
+    image[4] = fpu.ftwx;
+    image[6..8].copy_from_slice(&fpu.last_opcode.to_le_bytes());
+    image[8..16].copy_from_slice(&fpu.last_ip.to_le_bytes());
+    image[16..24].copy_from_slice(&fpu.last_dp.to_le_bytes());
+    image[24..28].copy_from_slice(&fpu.mxcsr.to_le_bytes());
+    image[28..32].copy_from_slice(&0xffffu32.to_le_bytes());
+    for (i, reg) in crate::FpuReg::to_16byte_arrays(&fpu.fpr).iter().enumerate() {
+        image[32 + i * 16..48 + i * 16].copy_from_slice(reg);
+    }
+    for (i, xmm) in fpu.xmm.iter().enumerate() {
+        image[160 + i * 16..176 + i * 16].copy_from_slice(xmm);
+    }
+}
+
+/// Loads the x87/SSE state from the `fxsave` memory layout.
+pub(super) fn fxrstor(fpu: &mut crate::Fpu, image: &[u8]) {
+    let u16_at = |offset: usize| u16::from_le_bytes([image[offset], image[offset + 1]]);
+    let u64_at = |offset: usize| u64::from_le_bytes(image[offset..offset + 8].try_into().unwrap());
+    fpu.fcw = u16_at(0);
+    fpu.fsw = u16_at(2);
+    fpu.ftwx = image[4];
+    fpu.last_opcode = u16_at(6);
+    fpu.last_ip = u64_at(8);
+    fpu.last_dp = u64_at(16);
+    fpu.mxcsr = u32::from_le_bytes(image[24..28].try_into().unwrap());
+    let mut regs = [[0u8; 16]; 8];
+    for (i, reg) in regs.iter_mut().enumerate() {
+        reg.copy_from_slice(&image[32 + i * 16..48 + i * 16]);
+    }
+    fpu.fpr = crate::FpuReg::from_16byte_arrays(&regs);
+    for (i, xmm) in fpu.xmm.iter_mut().enumerate() {
+        xmm.copy_from_slice(&image[160 + i * 16..176 + i * 16]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::cpu::tests::RamBus;
+
+    const CODE: u64 = 0x1000;
+
+    // A processor in real mode about to execute `code`.
+    fn real_mode(code: &[u8]) -> (ArchState, RamBus) {
+        let mut bus = RamBus::new(0x10000);
+        bus.ram[CODE as usize..CODE as usize + code.len()].copy_from_slice(code);
+        let mut cpu = ArchState::new(true);
+        cpu.sregs.cs.base = 0;
+        cpu.sregs.cs.selector = 0;
+        cpu.rip = CODE;
+        cpu.gpr[4] = 0x8000;
+        (cpu, bus)
+    }
+
+    #[test]
+    fn mov_cr_ignores_mod() {
+        // mov eax, cr0 and mov cr3, ebx, encoded with mod=01 and mod=10: neither takes a
+        // displacement.
+        let (mut cpu, mut bus) = real_mode(&[0x0f, 0x20, 0x40, 0x0f, 0x22, 0x9b]);
+        cpu.gpr[3] = 0x5000;
+        step(&mut cpu, &mut bus).unwrap();
+        assert_eq!(cpu.gpr[0], cpu.sregs.cr0 & 0xffff_ffff);
+        assert_eq!(cpu.rip, CODE + 3);
+        step(&mut cpu, &mut bus).unwrap();
+        assert_eq!(cpu.sregs.cr3, 0x5000);
+        assert_eq!(cpu.rip, CODE + 6);
+    }
+
+    #[test]
+    fn mov_dr_ignores_mod() {
+        // mov dr0, esi with mod=00 and rm=110, which would otherwise be a 16-bit displacement.
+        let (mut cpu, mut bus) = real_mode(&[0x0f, 0x23, 0x06, 0x0f, 0x21, 0xc1]);
+        cpu.gpr[6] = 0x1234;
+        step(&mut cpu, &mut bus).unwrap();
+        assert_eq!(cpu.debugregs.db[0], 0x1234);
+        assert_eq!(cpu.rip, CODE + 3);
+        // mov ecx, dr0
+        step(&mut cpu, &mut bus).unwrap();
+        assert_eq!(cpu.gpr[1], 0x1234);
+    }
+
+    #[test]
+    fn mov_invalid_cr_is_ud() {
+        // mov eax, cr1
+        let (mut cpu, mut bus) = real_mode(&[0x0f, 0x20, 0xc8]);
+        assert_eq!(step(&mut cpu, &mut bus).unwrap_err(), Stop::ud());
+    }
+
+    #[test]
+    fn add_sets_flags() {
+        // mov al, 1; add al, 0xff
+        let (mut cpu, mut bus) = real_mode(&[0xb0, 0x01, 0x04, 0xff]);
+        step(&mut cpu, &mut bus).unwrap();
+        step(&mut cpu, &mut bus).unwrap();
+        assert_eq!(cpu.gpr[0] & 0xff, 0);
+        assert_eq!(cpu.rflags & (FLAG_ZF | FLAG_CF), FLAG_ZF | FLAG_CF);
+        assert_eq!(cpu.rflags & (FLAG_SF | FLAG_OF), 0);
+    }
+
+    #[test]
+    fn push_pop() {
+        // push bx; pop ax
+        let (mut cpu, mut bus) = real_mode(&[0x53, 0x58]);
+        cpu.gpr[3] = 0xbeef;
+        step(&mut cpu, &mut bus).unwrap();
+        assert_eq!(cpu.gpr[4], 0x7ffe);
+        assert_eq!(&bus.ram[0x7ffe..0x8000], &[0xef, 0xbe]);
+        step(&mut cpu, &mut bus).unwrap();
+        assert_eq!(cpu.gpr[0], 0xbeef);
+        assert_eq!(cpu.gpr[4], 0x8000);
+    }
+
+    #[test]
+    fn out_is_queued_on_the_bus() {
+        // mov dx, 0x3f8; mov al, 0x41; out dx, al; hlt
+        let (mut cpu, mut bus) = real_mode(&[0xba, 0xf8, 0x03, 0xb0, 0x41, 0xee, 0xf4]);
+        for _ in 0..3 {
+            step(&mut cpu, &mut bus).unwrap();
+        }
+        assert_eq!(bus.io, vec![(0x3f8, 1, 0x41)]);
+        assert!(step(&mut cpu, &mut bus).unwrap().halted);
+    }
+}
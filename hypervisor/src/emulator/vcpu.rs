@@ -0,0 +1,834 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::arch::x86_64::CpuidResult;
+use std::collections::BTreeMap;
+use std::collections::VecDeque;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use base::error;
+use base::Error;
+use base::Result;
+use libc::EAGAIN;
+use libc::EINVAL;
+use libc::EIO;
+use libc::ENOENT;
+use libc::ENOTSUP;
+use libc::ENXIO;
+use serde::Deserialize;
+use serde::Serialize;
+use sync::Mutex;
+use vm_memory::GuestAddress;
+
+use super::cpu::*;
+use super::exec;
+use super::vm::IoEvent;
+use super::vm::MemoryMap;
+use crate::CpuId;
+use crate::CpuIdEntry;
+use crate::DebugRegs;
+use crate::Fpu;
+use crate::IoEventAddress;
+use crate::IoOperation;
+use crate::IoParams;
+use crate::Regs;
+use crate::Sregs;
+use crate::Vcpu;
+use crate::VcpuExit;
+use crate::VcpuShutdownError;
+use crate::VcpuShutdownErrorKind;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use crate::VcpuSignalHandle;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use crate::VcpuSignalHandleInner;
+use crate::VcpuX86_64;
+use crate::Xsave;
+
+/// Size of the XSAVE image returned by `get_xsave`: the legacy region followed by the header.
+const XSAVE_SIZE: usize = exec::FXSAVE_SIZE + 64;
+/// XSTATE_BV covering the x87 and SSE components, the only ones the emulator implements.
+const XSTATE_X87_SSE: u64 = 0x3;
+
+/// A device access that has to be completed by the VMM through `handle_io` or `handle_mmio`.
+#[derive(Clone, Copy, Debug)]
+enum DeviceAccess {
+    Io(IoParams),
+    Mmio(IoParams),
+}
+
+impl DeviceAccess {
+    fn exit(&self) -> VcpuExit {
+        match self {
+            DeviceAccess::Io(_) => VcpuExit::Io,
+            DeviceAccess::Mmio(_) => VcpuExit::Mmio,
+        }
+    }
+}
+
+/// Pending event state, serialized as the hypervisor data of a vCPU snapshot.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+struct InterruptState {
+    pending_irq: Option<u8>,
+    pending_nmi: bool,
+    nmi_blocked: bool,
+    interrupt_shadow: bool,
+}
+
+struct VcpuState {
+    arch: ArchState,
+    cpuid: Vec<CpuIdEntry>,
+    /// Interrupts are blocked until the next instruction retires (after `sti` or `mov ss`).
+    interrupt_shadow: bool,
+    /// The shadow of a retired instruction whose device writes are still being reported. Like a
+    /// hardware vCPU, the instruction has not completed from the VMM's point of view until then.
+    deferred_shadow: Option<bool>,
+    pending_irq: Option<u8>,
+    pending_nmi: bool,
+    interrupt_window_requested: bool,
+    /// Device writes of a retired instruction that have not been reported to the VMM yet.
+    exits: VecDeque<DeviceAccess>,
+    /// The access reported by the last exit, waiting for `handle_io` or `handle_mmio`.
+    current: Option<DeviceAccess>,
+    /// Data returned by the VMM for the device reads of the instruction being restarted, in the
+    /// order the instruction performs them.
+    read_data: Vec<[u8; 8]>,
+}
+
+/// A vCPU interpreted in userspace.
+///
+/// Each instruction runs as a transaction against a copy of the register state and an undo log of
+/// memory writes. Device reads abort the transaction and exit to the VMM; once the VMM has
+/// provided the data, the instruction restarts from the beginning and replays it. Device writes
+/// are queued and reported once the instruction retires.
+pub struct EmulatorVcpu {
+    id: usize,
+    memory: Arc<MemoryMap>,
+    ioevents: Arc<Mutex<Vec<IoEvent>>>,
+    state: Arc<Mutex<VcpuState>>,
+    immediate_exit: Arc<AtomicBool>,
+}
+
+impl EmulatorVcpu {
+    pub(super) fn new(
+        id: usize,
+        memory: Arc<MemoryMap>,
+        ioevents: Arc<Mutex<Vec<IoEvent>>>,
+    ) -> EmulatorVcpu {
+        EmulatorVcpu {
+            id,
+            memory,
+            ioevents,
+            state: Arc::new(Mutex::new(VcpuState {
+                arch: ArchState::new(id == 0),
+                cpuid: Vec::new(),
+                interrupt_shadow: false,
+                deferred_shadow: None,
+                pending_irq: None,
+                pending_nmi: false,
+                interrupt_window_requested: false,
+                exits: VecDeque::new(),
+                current: None,
+                read_data: Vec::new(),
+            })),
+            immediate_exit: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Runs `f` as one transaction. The register state and memory writes are committed only if it
+    /// succeeds, in which case its device writes are queued as exits.
+    fn transact<T>(
+        &self,
+        state: &mut VcpuState,
+        f: impl FnOnce(&mut ArchState, &mut dyn Bus) -> std::result::Result<T, Stop>,
+    ) -> std::result::Result<T, Stop> {
+        let mut arch = state.arch;
+        let mut txn = Transaction {
+            memory: &self.memory,
+            cpuid: &state.cpuid,
+            read_data: &state.read_data,
+            reads: 0,
+            undo: Vec::new(),
+            writes: Vec::new(),
+        };
+        match f(&mut arch, &mut txn) {
+            Ok(value) => {
+                let writes = txn.writes;
+                state.arch = arch;
+                state.read_data.clear();
+                let ioevents = self.ioevents.lock();
+                for access in writes {
+                    let (addr, params) = match access {
+                        DeviceAccess::Io(params) => (IoEventAddress::Pio(params.address), params),
+                        DeviceAccess::Mmio(params) => {
+                            (IoEventAddress::Mmio(params.address), params)
+                        }
+                    };
+                    let IoOperation::Write { data } = params.operation else {
+                        continue;
+                    };
+                    let data = &data[..params.size];
+                    match ioevents.iter().find(|e| e.matches(addr, data)) {
+                        Some(evt) => {
+                            if let Err(e) = evt.signal() {
+                                error!("failed to signal ioevent: {}", e);
+                            }
+                        }
+                        None => state.exits.push_back(access),
+                    }
+                }
+                Ok(value)
+            }
+            Err(stop) => {
+                txn.rollback();
+                Err(stop)
+            }
+        }
+    }
+
+    /// Delivers an event at an instruction boundary.
+    fn deliver(
+        &self,
+        state: &mut VcpuState,
+        vector: u8,
+        error_code: Option<u32>,
+        source: EventSource,
+    ) -> std::result::Result<(), Stop> {
+        self.transact(state, |arch, bus| {
+            let rip = arch.rip;
+            arch.deliver_event(bus, vector, error_code, source, rip)
+        })
+    }
+
+    /// Handles a transaction that did not complete. Returns the exit to report, if any.
+    fn handle_stop(&self, state: &mut VcpuState, stop: Stop) -> Option<VcpuExit> {
+        let stop = match stop {
+            Stop::Exception(e) => {
+                state.read_data.clear();
+                if e.vector == VECTOR_PF {
+                    state.arch.sregs.cr2 = e.address;
+                }
+                match self.deliver(state, e.vector, e.error_code, EventSource::Exception) {
+                    Ok(()) => return None,
+                    Err(stop) => stop,
+                }
+            }
+            stop => stop,
+        };
+        match stop {
+            Stop::MmioRead { address, size } => {
+                let access = DeviceAccess::Mmio(IoParams {
+                    address,
+                    size,
+                    operation: IoOperation::Read,
+                });
+                state.current = Some(access);
+                Some(VcpuExit::Mmio)
+            }
+            Stop::PioRead { port, size } => {
+                let access = DeviceAccess::Io(IoParams {
+                    address: port as u64,
+                    size,
+                    operation: IoOperation::Read,
+                });
+                state.current = Some(access);
+                Some(VcpuExit::Io)
+            }
+            Stop::TripleFault => {
+                state.read_data.clear();
+                Some(VcpuExit::Shutdown(Err(VcpuShutdownError::new(
+                    VcpuShutdownErrorKind::TripleFault,
+                    0,
+                ))))
+            }
+            Stop::Unsupported(what) => {
+                state.read_data.clear();
+                let mut bytes = [0u8; 15];
+                let rip = state.arch.rip;
+                let cs_base = state.arch.sregs.cs.base;
+                let _ = self.memory.read(cs_base.wrapping_add(rip), &mut bytes);
+                error!(
+                    "emulator vcpu {} cannot execute {:02x?} at rip {:#x}: {}",
+                    self.id, bytes, rip, what
+                );
+                Some(VcpuExit::InternalError)
+            }
+            Stop::Exception(_) => unreachable!("exceptions are delivered above"),
+        }
+    }
+
+    /// Injects pending events at an instruction boundary, or reports an open interrupt window.
+    fn boundary(&self, state: &mut VcpuState) -> Option<VcpuExit> {
+        let interruptible =
+            state.arch.rflags & FLAG_IF != 0 && !state.interrupt_shadow && state.exits.is_empty();
+        let event = if state.pending_nmi && !state.arch.nmi_blocked && !state.interrupt_shadow {
+            state.pending_nmi = false;
+            state.arch.nmi_blocked = true;
+            VECTOR_NMI
+        } else if let (Some(irq), true) = (state.pending_irq, interruptible) {
+            state.pending_irq = None;
+            irq
+        } else {
+            if state.interrupt_window_requested && interruptible && state.pending_irq.is_none() {
+                return Some(VcpuExit::IrqWindowOpen);
+            }
+            return None;
+        };
+        let result = self.deliver(state, event, None, EventSource::External);
+        match result {
+            Ok(()) => None,
+            Err(stop) => self.handle_stop(state, stop),
+        }
+    }
+
+    /// Executes instructions until one of them needs the VMM.
+    fn run_locked(&self, state: &mut VcpuState) -> VcpuExit {
+        loop {
+            if let Some(access) = state.exits.pop_front() {
+                state.current = Some(access);
+                return access.exit();
+            }
+            if let Some(shadow) = state.deferred_shadow.take() {
+                state.interrupt_shadow = shadow;
+            }
+            if self.immediate_exit.load(Ordering::Acquire) {
+                return VcpuExit::Intr;
+            }
+            // An instruction being restarted with device data has already passed its boundary.
+            if state.read_data.is_empty() {
+                if let Some(exit) = self.boundary(state) {
+                    return exit;
+                }
+                if !state.exits.is_empty() {
+                    continue;
+                }
+            }
+
+            let single_step = state.arch.rflags & FLAG_TF != 0;
+            let retired = match self.transact(state, exec::step) {
+                Ok(retired) => retired,
+                Err(stop) => match self.handle_stop(state, stop) {
+                    Some(exit) => return exit,
+                    None => continue,
+                },
+            };
+            if state.exits.is_empty() {
+                state.interrupt_shadow = retired.shadow;
+            } else {
+                state.deferred_shadow = Some(retired.shadow);
+            }
+            if single_step {
+                state.arch.debugregs.dr6 |= 1 << 14;
+                if let Err(stop) = self.deliver(state, VECTOR_DB, None, EventSource::Exception) {
+                    if let Some(exit) = self.handle_stop(state, stop) {
+                        return exit;
+                    }
+                }
+            }
+            if retired.halted {
+                if let Some(access) = state.exits.pop_front() {
+                    state.current = Some(access);
+                    return access.exit();
+                }
+                return VcpuExit::Hlt;
+            }
+        }
+    }
+
+    fn handle_device_access(
+        &self,
+        mmio: bool,
+        handle_fn: &mut dyn FnMut(IoParams) -> Option<[u8; 8]>,
+    ) -> Result<()> {
+        let params = {
+            let mut state = self.state.lock();
+            match state.current.take() {
+                Some(DeviceAccess::Mmio(params)) if mmio => params,
+                Some(DeviceAccess::Io(params)) if !mmio => params,
+                other => {
+                    state.current = other;
+                    return Err(Error::new(EINVAL));
+                }
+            }
+        };
+        let data = handle_fn(params);
+        if let IoOperation::Read = params.operation {
+            self.state.lock().read_data.push(data.unwrap_or_default());
+        }
+        Ok(())
+    }
+}
+
+/// The bus seen by one instruction.
+struct Transaction<'a> {
+    memory: &'a MemoryMap,
+    cpuid: &'a [CpuIdEntry],
+    read_data: &'a [[u8; 8]],
+    /// Number of entries of `read_data` consumed so far.
+    reads: usize,
+    /// Previous contents of the RAM written by this instruction.
+    undo: Vec<(u64, Vec<u8>)>,
+    writes: Vec<DeviceAccess>,
+}
+
+impl Transaction<'_> {
+    fn next_read(&mut self, size: usize) -> Option<u64> {
+        let data = self.read_data.get(self.reads)?;
+        self.reads += 1;
+        let mut buf = [0u8; 8];
+        buf[..size].copy_from_slice(&data[..size]);
+        Some(u64::from_le_bytes(buf))
+    }
+}
+
+impl Bus for Transaction<'_> {
+    fn read(&mut self, address: u64, data: &mut [u8]) -> std::result::Result<(), Stop> {
+        if self.memory.read(address, data) {
+            return Ok(());
+        }
+        if data.len() > 8 {
+            return Err(Stop::Unsupported("wide device memory read"));
+        }
+        match self.next_read(data.len()) {
+            Some(value) => {
+                data.copy_from_slice(&value.to_le_bytes()[..data.len()]);
+                Ok(())
+            }
+            None => Err(Stop::MmioRead {
+                address,
+                size: data.len(),
+            }),
+        }
+    }
+
+    fn write(&mut self, address: u64, data: &[u8]) -> std::result::Result<(), Stop> {
+        let mut old = vec![0u8; data.len()];
+        if self.memory.read(address, &mut old) && self.memory.write(address, data) {
+            self.undo.push((address, old));
+            return Ok(());
+        }
+        if data.len() > 8 {
+            return Err(Stop::Unsupported("wide device memory write"));
+        }
+        let mut buf = [0u8; 8];
+        buf[..data.len()].copy_from_slice(data);
+        self.writes.push(DeviceAccess::Mmio(IoParams {
+            address,
+            size: data.len(),
+            operation: IoOperation::Write { data: buf },
+        }));
+        Ok(())
+    }
+
+    fn read_ram(&mut self, address: u64, data: &mut [u8]) -> std::result::Result<(), Stop> {
+        if self.memory.read(address, data) {
+            Ok(())
+        } else {
+            Err(Stop::Unsupported("access to device memory"))
+        }
+    }
+
+    fn write_ram(&mut self, address: u64, data: &[u8]) {
+        self.memory.write(address, data);
+    }
+
+    fn io_in(&mut self, port: u16, size: usize) -> std::result::Result<u64, Stop> {
+        self.next_read(size).ok_or(Stop::PioRead { port, size })
+    }
+
+    fn io_out(&mut self, port: u16, size: usize, value: u64) {
+        let mut data = [0u8; 8];
+        data[..size].copy_from_slice(&value.to_le_bytes()[..size]);
+        self.writes.push(DeviceAccess::Io(IoParams {
+            address: port as u64,
+            size,
+            operation: IoOperation::Write { data },
+        }));
+    }
+
+    fn touched_device(&self) -> bool {
+        self.reads > 0 || !self.writes.is_empty()
+    }
+
+    fn rollback(&mut self) {
+        for (address, old) in self.undo.drain(..).rev() {
+            self.memory.write(address, &old);
+        }
+        self.writes.clear();
+        self.reads = 0;
+    }
+
+    fn cpuid(&self, function: u32, index: u32) -> CpuidResult {
+        self.cpuid
+            .iter()
+            .find(|e| e.function == function && e.index == index)
+            .or_else(|| {
+                self.cpuid
+                    .iter()
+                    .find(|e| e.function == function && e.flags & 1 == 0)
+            })
+            .map(|e| e.cpuid)
+            .unwrap_or(CpuidResult {
+                eax: 0,
+                ebx: 0,
+                ecx: 0,
+                edx: 0,
+            })
+    }
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+struct EmulatorVcpuSignalHandle {
+    immediate_exit: Arc<AtomicBool>,
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+impl VcpuSignalHandleInner for EmulatorVcpuSignalHandle {
+    fn signal_immediate_exit(&self) {
+        self.immediate_exit.store(true, Ordering::Release);
+    }
+}
+
+impl Vcpu for EmulatorVcpu {
+    fn try_clone(&self) -> Result<Self> {
+        Ok(EmulatorVcpu {
+            id: self.id,
+            memory: self.memory.clone(),
+            ioevents: self.ioevents.clone(),
+            state: self.state.clone(),
+            immediate_exit: self.immediate_exit.clone(),
+        })
+    }
+
+    fn as_vcpu(&self) -> &dyn Vcpu {
+        self
+    }
+
+    fn run(&mut self) -> Result<VcpuExit> {
+        let mut state = self.state.lock();
+        // An exit that the VMM did not handle is dropped, as if the device ignored the access.
+        state.current = None;
+        Ok(self.run_locked(&mut state))
+    }
+
+    fn id(&self) -> usize {
+        self.id
+    }
+
+    fn set_immediate_exit(&self, exit: bool) {
+        self.immediate_exit.store(exit, Ordering::Release);
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn signal_handle(&self) -> VcpuSignalHandle {
+        VcpuSignalHandle {
+            inner: Box::new(EmulatorVcpuSignalHandle {
+                immediate_exit: self.immediate_exit.clone(),
+            }),
+        }
+    }
+
+    fn handle_mmio(&self, handle_fn: &mut dyn FnMut(IoParams) -> Option<[u8; 8]>) -> Result<()> {
+        self.handle_device_access(true, handle_fn)
+    }
+
+    fn handle_io(&self, handle_fn: &mut dyn FnMut(IoParams) -> Option<[u8; 8]>) -> Result<()> {
+        self.handle_device_access(false, handle_fn)
+    }
+
+    fn on_suspend(&self) -> Result<()> {
+        Ok(())
+    }
+
+    unsafe fn enable_raw_capability(&self, _cap: u32, _args: &[u64; 4]) -> Result<()> {
+        Err(Error::new(ENXIO))
+    }
+}
+
+impl VcpuX86_64 for EmulatorVcpu {
+    fn set_interrupt_window_requested(&self, requested: bool) {
+        self.state.lock().interrupt_window_requested = requested;
+    }
+
+    fn ready_for_interrupt(&self) -> bool {
+        let state = self.state.lock();
+        state.arch.rflags & FLAG_IF != 0 && !state.interrupt_shadow
+    }
+
+    /// Like KVM, an interrupt that has not been delivered yet is replaced by a later one.
+    fn interrupt(&self, irq: u8) -> Result<()> {
+        let mut state = self.state.lock();
+        if state.arch.rflags & FLAG_IF == 0 || state.interrupt_shadow {
+            return Err(Error::new(EAGAIN));
+        }
+        state.pending_irq = Some(irq);
+        Ok(())
+    }
+
+    fn inject_nmi(&self) -> Result<()> {
+        self.state.lock().pending_nmi = true;
+        Ok(())
+    }
+
+    fn get_regs(&self) -> Result<Regs> {
+        Ok(self.state.lock().arch.regs())
+    }
+
+    fn set_regs(&self, regs: &Regs) -> Result<()> {
+        self.state.lock().arch.set_regs(regs);
+        Ok(())
+    }
+
+    fn get_sregs(&self) -> Result<Sregs> {
+        Ok(self.state.lock().arch.sregs)
+    }
+
+    fn set_sregs(&self, sregs: &Sregs) -> Result<()> {
+        self.state.lock().arch.sregs = *sregs;
+        Ok(())
+    }
+
+    fn get_fpu(&self) -> Result<Fpu> {
+        Ok(self.state.lock().arch.fpu)
+    }
+
+    fn set_fpu(&self, fpu: &Fpu) -> Result<()> {
+        self.state.lock().arch.fpu = *fpu;
+        Ok(())
+    }
+
+    fn get_debugregs(&self) -> Result<DebugRegs> {
+        Ok(self.state.lock().arch.debugregs)
+    }
+
+    fn set_debugregs(&self, debugregs: &DebugRegs) -> Result<()> {
+        self.state.lock().arch.debugregs = *debugregs;
+        Ok(())
+    }
+
+    fn get_xcrs(&self) -> Result<BTreeMap<u32, u64>> {
+        Ok(BTreeMap::from([(0, self.state.lock().arch.xcr0)]))
+    }
+
+    fn set_xcr(&self, xcr: u32, value: u64) -> Result<()> {
+        // Only the x87 and SSE components exist, and x87 must always be enabled.
+        if xcr != 0 || value & !XSTATE_X87_SSE != 0 || value & 1 == 0 {
+            return Err(Error::new(EINVAL));
+        }
+        self.state.lock().arch.xcr0 = value;
+        Ok(())
+    }
+
+    fn get_xsave(&self) -> Result<Xsave> {
+        let mut image = [0u8; XSAVE_SIZE];
+        exec::fxsave(&self.state.lock().arch.fpu, &mut image);
+        image[exec::FXSAVE_SIZE..exec::FXSAVE_SIZE + 8]
+            .copy_from_slice(&XSTATE_X87_SSE.to_le_bytes());
+        let mut xsave = Xsave::new(XSAVE_SIZE);
+        // SAFETY:
+        // Safe because the Xsave buffer was allocated with XSAVE_SIZE bytes.
+        unsafe {
+            std::ptr::copy_nonoverlapping(image.as_ptr(), xsave.as_mut_ptr() as *mut u8, XSAVE_SIZE)
+        };
+        Ok(xsave)
+    }
+
+    fn set_xsave(&self, xsave: &Xsave) -> Result<()> {
+        if xsave.len() < XSAVE_SIZE {
+            return Err(Error::new(EINVAL));
+        }
+        let mut image = [0u8; XSAVE_SIZE];
+        // SAFETY:
+        // Safe because the Xsave buffer holds at least XSAVE_SIZE bytes.
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                xsave.as_ptr() as *const u8,
+                image.as_mut_ptr(),
+                XSAVE_SIZE,
+            )
+        };
+        exec::fxrstor(&mut self.state.lock().arch.fpu, &image);
+        Ok(())
+    }
+
+    fn get_interrupt_state(&self) -> Result<serde_json::Value> {
+        let state = self.state.lock();
+        let interrupt_state = InterruptState {
+            pending_irq: state.pending_irq,
+            pending_nmi: state.pending_nmi,
+            nmi_blocked: state.arch.nmi_blocked,
+            interrupt_shadow: state.deferred_shadow.unwrap_or(state.interrupt_shadow),
+        };
+        serde_json::to_value(interrupt_state).map_err(|e| {
+            error!("failed to serialize interrupt state: {:?}", e);
+            Error::new(EIO)
+        })
+    }
+
+    fn set_interrupt_state(&self, data: serde_json::Value) -> Result<()> {
+        let interrupt_state: InterruptState = serde_json::from_value(data).map_err(|e| {
+            error!("failed to deserialize interrupt state: {:?}", e);
+            Error::new(EIO)
+        })?;
+        let mut state = self.state.lock();
+        state.pending_irq = interrupt_state.pending_irq;
+        state.pending_nmi = interrupt_state.pending_nmi;
+        state.arch.nmi_blocked = interrupt_state.nmi_blocked;
+        state.interrupt_shadow = interrupt_state.interrupt_shadow;
+        state.deferred_shadow = None;
+        Ok(())
+    }
+
+    fn get_msr(&self, msr_index: u32) -> Result<u64> {
+        self.state
+            .lock()
+            .arch
+            .read_msr(msr_index)
+            .ok_or_else(|| Error::new(ENOENT))
+    }
+
+    fn get_all_msrs(&self) -> Result<BTreeMap<u32, u64>> {
+        let state = self.state.lock();
+        Ok(SUPPORTED_MSRS
+            .iter()
+            .filter_map(|&index| Some((index, state.arch.read_msr(index)?)))
+            .collect())
+    }
+
+    fn set_msr(&self, msr_index: u32, value: u64) -> Result<()> {
+        self.state
+            .lock()
+            .arch
+            .write_msr(msr_index, value)
+            .map_err(|_| Error::new(EINVAL))
+    }
+
+    fn set_cpuid(&self, cpuid: &CpuId) -> Result<()> {
+        self.state.lock().cpuid = cpuid.cpu_id_entries.clone();
+        Ok(())
+    }
+
+    fn set_guest_debug(&self, _addrs: &[GuestAddress], _enable_singlestep: bool) -> Result<()> {
+        Err(Error::new(ENOTSUP))
+    }
+
+    /// CPUID is answered from the table given to `set_cpuid` without exiting.
+    fn handle_cpuid(&mut self, _entry: &CpuIdEntry) -> Result<()> {
+        Err(Error::new(ENXIO))
+    }
+
+    fn get_tsc_offset(&self) -> Result<u64> {
+        Ok(self.state.lock().arch.tsc_offset)
+    }
+
+    fn set_tsc_offset(&self, offset: u64) -> Result<()> {
+        self.state.lock().arch.tsc_offset = offset;
+        Ok(())
+    }
+
+    fn restore_timekeeping(&self, _host_tsc_reference_moment: u64, tsc_offset: u64) -> Result<()> {
+        self.set_tsc_offset(tsc_offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use vm_memory::GuestMemory;
+
+    use super::*;
+    use crate::emulator::Emulator;
+    use crate::emulator::EmulatorVm;
+    use crate::HypervisorX86_64;
+    use crate::Vm;
+    use crate::VmX86_64;
+
+    fn setup(code: &[u8]) -> (EmulatorVm, Box<dyn VcpuX86_64>) {
+        let emulator = Emulator::new();
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0xc000)]).unwrap();
+        mem.write_all_at_addr(code, GuestAddress(0x1000)).unwrap();
+        let vm = EmulatorVm::new(&emulator, mem).unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+        let mut sregs = vcpu.get_sregs().unwrap();
+        sregs.cs.base = 0;
+        sregs.cs.selector = 0;
+        vcpu.set_sregs(&sregs).unwrap();
+        vcpu.set_regs(&Regs {
+            rip: 0x1000,
+            rflags: 2,
+            rsp: 0x8000,
+            ..Default::default()
+        })
+        .unwrap();
+        vcpu.set_cpuid(&emulator.get_supported_cpuid().unwrap())
+            .unwrap();
+        (vm, vcpu)
+    }
+
+    #[test]
+    fn mmio_read_restarts_instruction() {
+        // mov ax, [0xf000] (outside of guest memory); add ax, 1; hlt
+        let (_vm, mut vcpu) = setup(&[0xa1, 0x00, 0xf0, 0x05, 0x01, 0x00, 0xf4]);
+        assert!(matches!(vcpu.run().unwrap(), VcpuExit::Mmio));
+        vcpu.handle_mmio(&mut |params| {
+            assert_eq!(params.address, 0xf000);
+            assert_eq!(params.size, 2);
+            Some([0x34, 0x12, 0, 0, 0, 0, 0, 0])
+        })
+        .unwrap();
+        assert!(matches!(vcpu.run().unwrap(), VcpuExit::Hlt));
+        assert_eq!(vcpu.get_regs().unwrap().rax, 0x1235);
+    }
+
+    #[test]
+    fn io_writes_are_reported_after_retirement() {
+        // mov dx, 0x3f8; mov al, 0x41; out dx, al; hlt
+        let (_vm, mut vcpu) = setup(&[0xba, 0xf8, 0x03, 0xb0, 0x41, 0xee, 0xf4]);
+        assert!(matches!(vcpu.run().unwrap(), VcpuExit::Io));
+        assert_eq!(vcpu.get_regs().unwrap().rip, 0x1006);
+        vcpu.handle_io(&mut |params| {
+            assert_eq!(params.address, 0x3f8);
+            assert!(matches!(
+                params.operation,
+                IoOperation::Write { data } if data[0] == 0x41
+            ));
+            None
+        })
+        .unwrap();
+        vcpu.handle_mmio(&mut |_| None)
+            .expect_err("there is no pending MMIO access");
+        assert!(matches!(vcpu.run().unwrap(), VcpuExit::Hlt));
+    }
+
+    #[test]
+    fn interrupt_is_delivered_through_ivt() {
+        // sti; nop; hlt
+        let (vm, mut vcpu) = setup(&[0xfb, 0x90, 0xf4]);
+        // Vector 0x20 handler at 0000:0x2000, which halts.
+        vm.get_memory()
+            .write_all_at_addr(&[0x00, 0x20, 0x00, 0x00], GuestAddress(0x80))
+            .unwrap();
+        vm.get_memory()
+            .write_all_at_addr(&[0xf4], GuestAddress(0x2000))
+            .unwrap();
+        vcpu.set_interrupt_window_requested(true);
+        // sti enables interrupts only after the following instruction.
+        assert!(matches!(vcpu.run().unwrap(), VcpuExit::IrqWindowOpen));
+        assert_eq!(vcpu.get_regs().unwrap().rip, 0x1002);
+        vcpu.set_interrupt_window_requested(false);
+        vcpu.interrupt(0x20).unwrap();
+        assert!(matches!(vcpu.run().unwrap(), VcpuExit::Hlt));
+        let regs = vcpu.get_regs().unwrap();
+        assert_eq!(regs.rip, 0x2001);
+        assert_eq!(regs.rflags & FLAG_IF, 0);
+    }
+
+    #[test]
+    fn unsupported_instruction_is_internal_error() {
+        // xgetbv
+        let (_vm, mut vcpu) = setup(&[0x0f, 0x01, 0xd0]);
+        assert!(matches!(vcpu.run().unwrap(), VcpuExit::InternalError));
+        assert_eq!(vcpu.get_regs().unwrap().rip, 0x1000);
+    }
+}
@@ -0,0 +1,515 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::collections::BinaryHeap;
+use std::sync::Arc;
+
+use base::error;
+use base::AsRawDescriptor;
+use base::Error;
+use base::Event;
+use base::MappedRegion;
+use base::MmapError;
+use base::Protection;
+use base::Result;
+use base::SafeDescriptor;
+use libc::EFAULT;
+use libc::EINVAL;
+use libc::EIO;
+use libc::ENODEV;
+use libc::ENOENT;
+use libc::ENOSPC;
+use libc::ENOTSUP;
+use libc::ENXIO;
+use libc::EOVERFLOW;
+use sync::Mutex;
+use vm_memory::GuestAddress;
+use vm_memory::GuestMemory;
+
+use super::vcpu::EmulatorVcpu;
+use super::Emulator;
+use crate::BalloonEvent;
+use crate::ClockState;
+use crate::Datamatch;
+use crate::DeviceKind;
+use crate::HypervisorX86_64;
+use crate::IoEventAddress;
+use crate::MemCacheType;
+use crate::MemSlot;
+use crate::VcpuX86_64;
+use crate::Vm;
+use crate::VmCap;
+use crate::VmX86_64;
+
+/// A memory region added with `Vm::add_memory_region`.
+struct MemoryRegion {
+    guest_addr: GuestAddress,
+    mem: Box<dyn MappedRegion>,
+    read_only: bool,
+}
+
+impl MemoryRegion {
+    /// Returns the host pointer backing `size` bytes at `addr`, if they are all inside this region.
+    fn host_ptr(&self, addr: u64, size: usize) -> Option<*mut u8> {
+        let offset = addr.checked_sub(self.guest_addr.offset())? as usize;
+        if offset.checked_add(size)? > self.mem.size() {
+            return None;
+        }
+        // SAFETY:
+        // Safe because the offset was checked against the size of the mapping.
+        Some(unsafe { self.mem.as_ptr().add(offset) })
+    }
+}
+
+/// The guest physical address space shared by the VM and its vCPUs.
+///
+/// Anything that is not backed by guest memory or an added memory region is device memory, whose
+/// accesses are forwarded to the VMM as MMIO exits.
+pub(super) struct MemoryMap {
+    guest_mem: GuestMemory,
+    regions: Mutex<BTreeMap<MemSlot, MemoryRegion>>,
+}
+
+impl MemoryMap {
+    /// Reads guest RAM at `addr`. Returns false without reading anything if any part of the range
+    /// is not RAM.
+    pub fn read(&self, addr: u64, data: &mut [u8]) -> bool {
+        if self
+            .guest_mem
+            .is_valid_range(GuestAddress(addr), data.len() as u64)
+        {
+            return self
+                .guest_mem
+                .read_exact_at_addr(data, GuestAddress(addr))
+                .is_ok();
+        }
+        let regions = self.regions.lock();
+        match regions.values().find_map(|r| r.host_ptr(addr, data.len())) {
+            Some(ptr) => {
+                // SAFETY:
+                // Safe because `host_ptr` checked that the range lies within the mapping, which
+                // stays mapped while the regions lock is held.
+                unsafe { std::ptr::copy_nonoverlapping(ptr, data.as_mut_ptr(), data.len()) };
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Writes guest RAM at `addr`. Returns false without writing anything if any part of the range
+    /// is not writable RAM.
+    pub fn write(&self, addr: u64, data: &[u8]) -> bool {
+        if self
+            .guest_mem
+            .is_valid_range(GuestAddress(addr), data.len() as u64)
+        {
+            return self
+                .guest_mem
+                .write_all_at_addr(data, GuestAddress(addr))
+                .is_ok();
+        }
+        let regions = self.regions.lock();
+        match regions
+            .values()
+            .filter(|r| !r.read_only)
+            .find_map(|r| r.host_ptr(addr, data.len()))
+        {
+            Some(ptr) => {
+                // SAFETY:
+                // Safe because `host_ptr` checked that the range lies within the mapping, which
+                // stays mapped while the regions lock is held.
+                unsafe { std::ptr::copy_nonoverlapping(data.as_ptr(), ptr, data.len()) };
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// An ioevent registered with `Vm::register_ioevent`.
+pub(super) struct IoEvent {
+    addr: IoEventAddress,
+    datamatch: Datamatch,
+    evt: Event,
+}
+
+impl IoEvent {
+    /// Returns whether a write of `data` to `addr` triggers this event.
+    pub fn matches(&self, addr: IoEventAddress, data: &[u8]) -> bool {
+        if self.addr != addr {
+            return false;
+        }
+        // Returns whether the write has the given length and, if `expected` is set, that value.
+        let check = |len: usize, expected: Option<u64>| -> bool {
+            if data.len() != len {
+                return false;
+            }
+            let mut buf = [0u8; 8];
+            buf[..len].copy_from_slice(data);
+            match expected {
+                Some(v) => u64::from_le_bytes(buf) == v,
+                None => true,
+            }
+        };
+        match self.datamatch {
+            Datamatch::AnyLength => true,
+            Datamatch::U8(v) => check(1, v.map(u64::from)),
+            Datamatch::U16(v) => check(2, v.map(u64::from)),
+            Datamatch::U32(v) => check(4, v.map(u64::from)),
+            Datamatch::U64(v) => check(8, v),
+        }
+    }
+
+    pub fn signal(&self) -> Result<()> {
+        self.evt.signal()
+    }
+}
+
+/// A VM whose vCPUs are interpreted in userspace by `EmulatorVcpu`.
+pub struct EmulatorVm {
+    emulator: Emulator,
+    guest_mem: GuestMemory,
+    memory: Arc<MemoryMap>,
+    /// A min heap of MemSlot numbers that were used and then removed and can now be re-used
+    mem_slot_gaps: Arc<Mutex<BinaryHeap<Reverse<MemSlot>>>>,
+    ioevents: Arc<Mutex<Vec<IoEvent>>>,
+}
+
+impl EmulatorVm {
+    /// Constructs a new `EmulatorVm` with the given guest memory.
+    pub fn new(emulator: &Emulator, guest_mem: GuestMemory) -> Result<EmulatorVm> {
+        Ok(EmulatorVm {
+            emulator: emulator.clone(),
+            guest_mem: guest_mem.clone(),
+            memory: Arc::new(MemoryMap {
+                guest_mem,
+                regions: Mutex::new(BTreeMap::new()),
+            }),
+            mem_slot_gaps: Arc::new(Mutex::new(BinaryHeap::new())),
+            ioevents: Arc::new(Mutex::new(Vec::new())),
+        })
+    }
+}
+
+fn mmap_error_to_errno(err: MmapError) -> Error {
+    match err {
+        MmapError::InvalidAddress => Error::new(EFAULT),
+        MmapError::NotPageAligned => Error::new(EINVAL),
+        MmapError::SystemCallFailed(e) => e,
+        _ => Error::new(EIO),
+    }
+}
+
+impl Vm for EmulatorVm {
+    fn try_clone(&self) -> Result<Self> {
+        Ok(EmulatorVm {
+            emulator: self.emulator.clone(),
+            guest_mem: self.guest_mem.clone(),
+            memory: self.memory.clone(),
+            mem_slot_gaps: self.mem_slot_gaps.clone(),
+            ioevents: self.ioevents.clone(),
+        })
+    }
+
+    fn check_capability(&self, c: VmCap) -> bool {
+        match c {
+            VmCap::DirtyLog => false,
+            VmCap::PvClock => false,
+            VmCap::Protected => false,
+            VmCap::EarlyInitCpuid => false,
+            VmCap::BusLockDetect => false,
+            VmCap::ReadOnlyMemoryRegion => true,
+            VmCap::MemNoncoherentDma => false,
+        }
+    }
+
+    fn get_guest_phys_addr_bits(&self) -> u8 {
+        super::PHYS_ADDR_BITS
+    }
+
+    fn get_memory(&self) -> &GuestMemory {
+        &self.guest_mem
+    }
+
+    fn add_memory_region(
+        &mut self,
+        guest_addr: GuestAddress,
+        mem: Box<dyn MappedRegion>,
+        read_only: bool,
+        _log_dirty_pages: bool,
+        _cache: MemCacheType,
+    ) -> Result<MemSlot> {
+        let size = mem.size() as u64;
+        let end_addr = guest_addr
+            .checked_add(size)
+            .ok_or_else(|| Error::new(EOVERFLOW))?;
+        if self.guest_mem.range_overlap(guest_addr, end_addr) {
+            return Err(Error::new(ENOSPC));
+        }
+        let mut regions = self.memory.regions.lock();
+        let overlaps = regions.values().any(|r| {
+            let start = r.guest_addr;
+            let end = start.unchecked_add(r.mem.size() as u64);
+            guest_addr < end && start < end_addr
+        });
+        if overlaps {
+            return Err(Error::new(ENOSPC));
+        }
+        let slot = match self.mem_slot_gaps.lock().pop() {
+            Some(gap) => gap.0,
+            None => (regions.len() + self.guest_mem.num_regions() as usize) as MemSlot,
+        };
+        regions.insert(
+            slot,
+            MemoryRegion {
+                guest_addr,
+                mem,
+                read_only,
+            },
+        );
+        Ok(slot)
+    }
+
+    fn msync_memory_region(&mut self, slot: MemSlot, offset: usize, size: usize) -> Result<()> {
+        let regions = self.memory.regions.lock();
+        let region = regions.get(&slot).ok_or_else(|| Error::new(ENOENT))?;
+        region.mem.msync(offset, size).map_err(mmap_error_to_errno)
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn madvise_pageout_memory_region(
+        &mut self,
+        slot: MemSlot,
+        offset: usize,
+        size: usize,
+    ) -> Result<()> {
+        let regions = self.memory.regions.lock();
+        let region = regions.get(&slot).ok_or_else(|| Error::new(ENOENT))?;
+        region
+            .mem
+            .madvise(offset, size, libc::MADV_PAGEOUT)
+            .map_err(mmap_error_to_errno)
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn madvise_remove_memory_region(
+        &mut self,
+        slot: MemSlot,
+        offset: usize,
+        size: usize,
+    ) -> Result<()> {
+        let regions = self.memory.regions.lock();
+        let region = regions.get(&slot).ok_or_else(|| Error::new(ENOENT))?;
+        region
+            .mem
+            .madvise(offset, size, libc::MADV_REMOVE)
+            .map_err(mmap_error_to_errno)
+    }
+
+    fn remove_memory_region(&mut self, slot: MemSlot) -> Result<Box<dyn MappedRegion>> {
+        let mut regions = self.memory.regions.lock();
+        let region = regions.remove(&slot).ok_or_else(|| Error::new(ENOENT))?;
+        self.mem_slot_gaps.lock().push(Reverse(slot));
+        Ok(region.mem)
+    }
+
+    fn create_device(&self, _kind: DeviceKind) -> Result<SafeDescriptor> {
+        // There is no kernel to provide in-kernel devices.
+        Err(Error::new(ENXIO))
+    }
+
+    fn get_dirty_log(&self, _slot: MemSlot, _dirty_log: &mut [u8]) -> Result<()> {
+        Err(Error::new(ENOTSUP))
+    }
+
+    fn register_ioevent(
+        &mut self,
+        evt: &Event,
+        addr: IoEventAddress,
+        datamatch: Datamatch,
+    ) -> Result<()> {
+        self.ioevents.lock().push(IoEvent {
+            addr,
+            datamatch,
+            evt: evt.try_clone()?,
+        });
+        Ok(())
+    }
+
+    fn unregister_ioevent(
+        &mut self,
+        evt: &Event,
+        addr: IoEventAddress,
+        datamatch: Datamatch,
+    ) -> Result<()> {
+        let mut ioevents = self.ioevents.lock();
+        let index = ioevents
+            .iter()
+            .position(|e| e.addr == addr && e.datamatch == datamatch && &e.evt == evt)
+            .ok_or_else(|| Error::new(ENOENT))?;
+        ioevents.remove(index);
+        Ok(())
+    }
+
+    /// Ioevents are signaled by the vCPU before the write would have exited to the VMM, so there is
+    /// nothing left to do here.
+    fn handle_io_events(&self, _addr: IoEventAddress, _data: &[u8]) -> Result<()> {
+        Ok(())
+    }
+
+    fn get_pvclock(&self) -> Result<ClockState> {
+        Err(Error::new(ENODEV))
+    }
+
+    fn set_pvclock(&self, _state: &ClockState) -> Result<()> {
+        Err(Error::new(ENODEV))
+    }
+
+    fn add_fd_mapping(
+        &mut self,
+        slot: u32,
+        offset: usize,
+        size: usize,
+        fd: &dyn AsRawDescriptor,
+        fd_offset: u64,
+        prot: Protection,
+    ) -> Result<()> {
+        let mut regions = self.memory.regions.lock();
+        let region = regions.get_mut(&slot).ok_or_else(|| Error::new(EINVAL))?;
+        match region.mem.add_fd_mapping(offset, size, fd, fd_offset, prot) {
+            Ok(()) => Ok(()),
+            Err(MmapError::SystemCallFailed(e)) => Err(e),
+            Err(_) => Err(Error::new(EIO)),
+        }
+    }
+
+    fn remove_mapping(&mut self, slot: u32, offset: usize, size: usize) -> Result<()> {
+        let mut regions = self.memory.regions.lock();
+        let region = regions.get_mut(&slot).ok_or_else(|| Error::new(EINVAL))?;
+        match region.mem.remove_mapping(offset, size) {
+            Ok(()) => Ok(()),
+            Err(MmapError::SystemCallFailed(e)) => Err(e),
+            Err(_) => Err(Error::new(EIO)),
+        }
+    }
+
+    fn handle_balloon_event(&mut self, event: BalloonEvent) -> Result<()> {
+        match event {
+            BalloonEvent::Inflate(m) => {
+                match self.guest_mem.remove_range(m.guest_address, m.size) {
+                    Ok(_) => Ok(()),
+                    Err(vm_memory::Error::MemoryAccess(_, MmapError::SystemCallFailed(e))) => {
+                        Err(e)
+                    }
+                    Err(e) => {
+                        error!("failed to release ballooned memory: {}", e);
+                        Err(Error::new(EIO))
+                    }
+                }
+            }
+            // Released pages read back as zero the next time the guest touches them.
+            BalloonEvent::Deflate(_) | BalloonEvent::BalloonTargetReached(_) => Ok(()),
+        }
+    }
+}
+
+impl VmX86_64 for EmulatorVm {
+    fn get_hypervisor(&self) -> &dyn HypervisorX86_64 {
+        &self.emulator
+    }
+
+    fn create_vcpu(&self, id: usize) -> Result<Box<dyn VcpuX86_64>> {
+        Ok(Box::new(EmulatorVcpu::new(
+            id,
+            self.memory.clone(),
+            self.ioevents.clone(),
+        )))
+    }
+
+    /// The emulator runs real mode code directly, so no TSS is needed.
+    fn set_tss_addr(&self, _addr: GuestAddress) -> Result<()> {
+        Ok(())
+    }
+
+    /// The emulator runs real mode code directly, so no identity map is needed.
+    fn set_identity_map_addr(&self, _addr: GuestAddress) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use base::EventWaitResult;
+    use base::MemoryMappingBuilder;
+
+    use super::*;
+
+    #[test]
+    fn add_memory_region_rejects_overlap() {
+        let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut vm = EmulatorVm::new(&Emulator::new(), gm).unwrap();
+        let mem = MemoryMappingBuilder::new(0x1000).build().unwrap();
+        vm.add_memory_region(
+            GuestAddress(0x8000),
+            Box::new(mem),
+            false,
+            false,
+            MemCacheType::CacheCoherent,
+        )
+        .expect_err("overlapping guest memory should be rejected");
+
+        let mem = MemoryMappingBuilder::new(0x1000).build().unwrap();
+        let slot = vm
+            .add_memory_region(
+                GuestAddress(0x20000),
+                Box::new(mem),
+                true,
+                false,
+                MemCacheType::CacheCoherent,
+            )
+            .unwrap();
+        assert!(vm.memory.read(0x20000, &mut [0u8; 4]));
+        assert!(!vm.memory.write(0x20000, &[0u8; 4]));
+        vm.remove_memory_region(slot).unwrap();
+        assert!(!vm.memory.read(0x20000, &mut [0u8; 4]));
+    }
+
+    #[test]
+    fn ioevent_datamatch() {
+        let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut vm = EmulatorVm::new(&Emulator::new(), gm).unwrap();
+        let evt = Event::new().unwrap();
+        vm.register_ioevent(
+            &evt,
+            IoEventAddress::Pio(0x1000),
+            Datamatch::U16(Some(0x1234)),
+        )
+        .unwrap();
+
+        let ioevents = vm.ioevents.lock();
+        assert!(ioevents[0].matches(IoEventAddress::Pio(0x1000), &[0x34, 0x12]));
+        assert!(!ioevents[0].matches(IoEventAddress::Pio(0x1000), &[0x35, 0x12]));
+        assert!(!ioevents[0].matches(IoEventAddress::Pio(0x1000), &[0x34, 0x12, 0, 0]));
+        assert!(!ioevents[0].matches(IoEventAddress::Mmio(0x1000), &[0x34, 0x12]));
+        ioevents[0].signal().unwrap();
+        assert_ne!(
+            evt.wait_timeout(Duration::from_millis(10)).unwrap(),
+            EventWaitResult::TimedOut
+        );
+        drop(ioevents);
+
+        vm.unregister_ioevent(&evt, IoEventAddress::Pio(0x1000), Datamatch::AnyLength)
+            .expect_err("datamatch must match the registration");
+        vm.unregister_ioevent(
+            &evt,
+            IoEventAddress::Pio(0x1000),
+            Datamatch::U16(Some(0x1234)),
+        )
+        .unwrap();
+    }
+}
@@ -570,6 +570,12 @@ impl Hypervisor for Geniezone {
             HypervisorCap::StaticSwiotlbAllocationRequired => true,
             HypervisorCap::HypervisorInitializedBootContext => false,
             HypervisorCap::S390UserSigp | HypervisorCap::TscDeadlineTimer => false,
+            // Geniezone cannot read back the in-kernel irqchip state, so a VM cannot be
+            // snapshotted.
+            HypervisorCap::VcpuSnapshot => false,
+            // Guest memory is pinned by the Geniezone driver, so it cannot be swapped out by
+            // userfaultfd.
+            HypervisorCap::SwappableGuestMemory => false,
         }
     }
 }
@@ -884,7 +890,8 @@ impl Vm for GeniezoneVm {
             return val;
         }
         match c {
-            VmCap::DirtyLog => true,
+            // Geniezone has no ioctl to fetch the dirty bitmap of a memory slot.
+            VmCap::DirtyLog => false,
             VmCap::PvClock => false,
             VmCap::Protected => self.check_raw_capability(GeniezoneCap::ArmProtectedVm),
             VmCap::EarlyInitCpuid => false,
@@ -211,7 +211,7 @@ impl VcpuAArch64 for GunyahVcpu {
     }
 
     fn set_one_reg(&self, _reg_id: VcpuRegAArch64, _data: u64) -> Result<()> {
-        unimplemented!()
+        Err(Error::new(ENOTSUP))
     }
 
     fn get_one_reg(&self, _reg_id: VcpuRegAArch64) -> Result<u64> {
@@ -219,11 +219,11 @@ impl VcpuAArch64 for GunyahVcpu {
     }
 
     fn set_vector_reg(&self, _reg_num: u8, _data: u128) -> Result<()> {
-        unimplemented!()
+        Err(Error::new(ENOTSUP))
     }
 
     fn get_vector_reg(&self, _reg_num: u8) -> Result<u128> {
-        unimplemented!()
+        Err(Error::new(ENOTSUP))
     }
 
     fn get_psci_version(&self) -> Result<PsciVersion> {
@@ -251,10 +251,10 @@ impl VcpuAArch64 for GunyahVcpu {
     }
 
     fn hypervisor_specific_snapshot(&self) -> anyhow::Result<serde_json::Value> {
-        unimplemented!()
+        anyhow::bail!("Gunyah: vCPU state cannot be snapshotted")
     }
 
     fn hypervisor_specific_restore(&self, _data: serde_json::Value) -> anyhow::Result<()> {
-        unimplemented!()
+        anyhow::bail!("Gunyah: vCPU state cannot be restored")
     }
 }
@@ -100,6 +100,9 @@ impl Hypervisor for Gunyah {
             HypervisorCap::S390UserSigp | HypervisorCap::TscDeadlineTimer => false,
             #[cfg(target_arch = "x86_64")]
             HypervisorCap::Xcrs | HypervisorCap::CalibratedTscLeafRequired => false,
+            // vCPU registers are owned by the hypervisor and guest memory is lent to it, so
+            // neither can be saved or swapped out from the host.
+            HypervisorCap::VcpuSnapshot | HypervisorCap::SwappableGuestMemory => false,
         }
     }
 }
@@ -536,7 +539,7 @@ impl Vm for GunyahVm {
     }
 
     fn get_dirty_log(&self, _slot: MemSlot, _dirty_log: &mut [u8]) -> Result<()> {
-        unimplemented!()
+        Err(Error::new(ENOTSUP))
     }
 
     fn register_ioevent(
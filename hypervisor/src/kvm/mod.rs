@@ -210,6 +210,15 @@ impl Hypervisor for Kvm {
     }
 
     fn check_capability(&self, cap: HypervisorCap) -> bool {
+        // These are properties of the crosvm KVM backend rather than KVM extensions: every vCPU
+        // register is reachable through the ONE_REG/state ioctls and guest memory is plain
+        // anonymous or shared host memory.
+        if matches!(
+            cap,
+            HypervisorCap::VcpuSnapshot | HypervisorCap::SwappableGuestMemory
+        ) {
+            return true;
+        }
         if let Ok(kvm_cap) = KvmCap::try_from(cap) {
             // SAFETY:
             // this ioctl is safe because we know this kvm descriptor is valid,
@@ -1199,6 +1208,8 @@ impl TryFrom<HypervisorCap> for KvmCap {
             HypervisorCap::CalibratedTscLeafRequired => Err(Error::new(libc::EINVAL)),
             HypervisorCap::StaticSwiotlbAllocationRequired => Err(Error::new(libc::EINVAL)),
             HypervisorCap::HypervisorInitializedBootContext => Err(Error::new(libc::EINVAL)),
+            HypervisorCap::VcpuSnapshot => Err(Error::new(libc::EINVAL)),
+            HypervisorCap::SwappableGuestMemory => Err(Error::new(libc::EINVAL)),
         }
     }
 }
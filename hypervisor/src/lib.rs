@@ -6,6 +6,8 @@
 #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
 pub mod aarch64;
 pub mod caps;
+#[cfg(all(target_arch = "x86_64", feature = "emulator"))]
+pub mod emulator;
 
 #[cfg(all(
     unix,
@@ -275,7 +275,16 @@ pub fn run_configurable_test<H: HypervisorTestSetup>(
 macro_rules! run_tests {
     ($setup:expr, $regs_matcher:expr, $exit_matcher:expr) => {
         #[cfg(any(target_os = "android", target_os = "linux"))]
-        run_configurable_test::<Kvm>(HypervisorType::Kvm, &$setup, $regs_matcher, $exit_matcher);
+        if std::path::Path::new("/dev/kvm").exists() {
+            run_configurable_test::<Kvm>(
+                HypervisorType::Kvm,
+                &$setup,
+                $regs_matcher,
+                $exit_matcher,
+            );
+        } else {
+            println!("Skipping test on hypervisor: KVM (/dev/kvm does not exist)");
+        }
 
         #[cfg(all(windows, feature = "whpx"))]
         run_configurable_test::<Whpx>(HypervisorType::Whpx, &$setup, $regs_matcher, $exit_matcher);
@@ -0,0 +1,35 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::path::PathBuf;
+
+use pkg_config::Config;
+
+fn main() {
+    // Skip building dependencies when generating documents.
+    if std::env::var("CARGO_DOC").is_ok() {
+        return;
+    }
+
+    // dav1d is currently only supported on unix
+    if std::env::var("CARGO_CFG_UNIX").is_err() {
+        return;
+    }
+
+    // dav1d 1.0 is the first version with the `n_threads` and `max_frame_delay` settings.
+    Config::new().atleast_version("1.0").probe("dav1d").unwrap();
+
+    let bindings = bindgen::Builder::default()
+        .header("src/bindings.h")
+        .allowlist_function("dav1d_.*")
+        .allowlist_type("Dav1d.*")
+        .allowlist_var("DAV1D_.*")
+        .prepend_enum_name(false)
+        .generate()
+        .expect("failed to generate bindings");
+    let out_path = PathBuf::from(std::env::var("OUT_DIR").unwrap());
+    bindings
+        .write_to_file(out_path.join("bindings.rs"))
+        .expect("writing bindings to file failed");
+}
@@ -0,0 +1,255 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Safe wrapper over the AV1 decoder of dav1d.
+
+#![cfg(any(target_os = "android", target_os = "linux"))]
+
+use std::io;
+use std::mem::MaybeUninit;
+use std::ptr;
+
+use libc::c_int;
+use thiserror::Error as ThisError;
+
+mod ffi {
+    #![allow(clippy::missing_safety_doc)]
+    #![allow(clippy::undocumented_unsafe_blocks)]
+    #![allow(clippy::upper_case_acronyms)]
+    #![allow(non_upper_case_globals)]
+    #![allow(non_camel_case_types)]
+    #![allow(non_snake_case)]
+    #![allow(dead_code)]
+    include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
+}
+
+#[derive(Debug, ThisError)]
+pub enum Dav1dError {
+    #[error("failed to open decoder: {0}")]
+    Open(io::Error),
+    #[error("failed to decode data: {0}")]
+    Decode(io::Error),
+    #[error("failed to get decoded picture: {0}")]
+    GetPicture(io::Error),
+    #[error("failed to allocate a {0} bytes input buffer")]
+    OutOfMemory(usize),
+}
+
+/// Converts the `DAV1D_ERR()` value `ret` into the error it stands for.
+fn dav1d_err(ret: c_int) -> io::Error {
+    io::Error::from_raw_os_error(-ret)
+}
+
+/// A dav1d decoding context.
+pub struct Dav1dDecoder {
+    ctx: *mut ffi::Dav1dContext,
+}
+
+// SAFETY:
+// The decoding context is not tied to the thread that created it and is only ever accessed through
+// `&mut self`.
+unsafe impl Send for Dav1dDecoder {}
+
+impl Dav1dDecoder {
+    /// Create a new decoder.
+    pub fn new() -> Result<Self, Dav1dError> {
+        let mut settings = MaybeUninit::<ffi::Dav1dSettings>::uninit();
+        // SAFETY:
+        // `settings` is valid for writes and fully initialized by `dav1d_default_settings`.
+        let mut settings = unsafe {
+            ffi::dav1d_default_settings(settings.as_mut_ptr());
+            settings.assume_init()
+        };
+        // Decode synchronously and output each frame as soon as it is decoded, so frames are made
+        // available by the call that submitted their data.
+        settings.n_threads = 1;
+        settings.max_frame_delay = 1;
+
+        let mut ctx = ptr::null_mut();
+        // SAFETY:
+        // `ctx` is a valid location for dav1d to store the pointer to the new context, and
+        // `settings` is valid for the duration of the call.
+        let ret = unsafe { ffi::dav1d_open(&mut ctx, &settings) };
+        if ret < 0 || ctx.is_null() {
+            return Err(Dav1dError::Open(dav1d_err(ret)));
+        }
+
+        Ok(Self { ctx })
+    }
+
+    /// Submit the compressed data in `data` for decoding, and return the frames that became
+    /// available as a result. `timestamp` will be reported by all the frames decoded from it.
+    pub fn decode(&mut self, data: &[u8], timestamp: u64) -> Result<Vec<Dav1dFrame>, Dav1dError> {
+        let mut frames = Vec::new();
+        if data.is_empty() {
+            return Ok(frames);
+        }
+
+        // SAFETY:
+        // An all-zeroes data descriptor is what `dav1d_data_create` expects.
+        let mut input = unsafe { MaybeUninit::<ffi::Dav1dData>::zeroed().assume_init() };
+        // SAFETY:
+        // `input` is a valid, empty data descriptor.
+        let buf = unsafe { ffi::dav1d_data_create(&mut input, data.len()) };
+        if buf.is_null() {
+            return Err(Dav1dError::OutOfMemory(data.len()));
+        }
+        // SAFETY:
+        // `dav1d_data_create` returned a buffer of `data.len()` bytes that we are the only user
+        // of.
+        unsafe { ptr::copy_nonoverlapping(data.as_ptr(), buf, data.len()) };
+        input.m.timestamp = timestamp as i64;
+
+        let res = self.send_data(&mut input, &mut frames);
+        // SAFETY:
+        // `input` is either fully consumed or still holds the reference to its buffer, which we
+        // release here.
+        unsafe { ffi::dav1d_data_unref(&mut input) };
+        res.map(|()| frames)
+    }
+
+    /// Send `input` to the decoder until it is fully consumed, appending the frames produced along
+    /// the way to `frames`.
+    fn send_data(
+        &mut self,
+        input: &mut ffi::Dav1dData,
+        frames: &mut Vec<Dav1dFrame>,
+    ) -> Result<(), Dav1dError> {
+        while input.sz > 0 {
+            // SAFETY:
+            // `ctx` has been successfully opened and `input` is a valid data descriptor.
+            let ret = unsafe { ffi::dav1d_send_data(self.ctx, input) };
+            // EAGAIN means that pictures must be retrieved before the rest of the data can be
+            // consumed.
+            if ret < 0 && ret != -libc::EAGAIN {
+                return Err(Dav1dError::Decode(dav1d_err(ret)));
+            }
+            while let Some(frame) = self.get_picture()? {
+                frames.push(frame);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the next decoded frame, if one is available.
+    fn get_picture(&mut self) -> Result<Option<Dav1dFrame>, Dav1dError> {
+        // SAFETY:
+        // An all-zeroes picture is what `dav1d_get_picture` expects as output parameter.
+        let mut pic = unsafe { MaybeUninit::<ffi::Dav1dPicture>::zeroed().assume_init() };
+        // SAFETY:
+        // `ctx` has been successfully opened and `pic` is valid for writes.
+        let ret = unsafe { ffi::dav1d_get_picture(self.ctx, &mut pic) };
+        match ret {
+            0 => Ok(Some(Dav1dFrame { pic })),
+            ret if ret == -libc::EAGAIN => Ok(None),
+            ret => Err(Dav1dError::GetPicture(dav1d_err(ret))),
+        }
+    }
+
+    /// Signal the end of the stream and return all the frames the decoder was still holding.
+    pub fn drain(&mut self) -> Result<Vec<Dav1dFrame>, Dav1dError> {
+        let mut frames = Vec::new();
+        while let Some(frame) = self.get_picture()? {
+            frames.push(frame);
+        }
+
+        Ok(frames)
+    }
+}
+
+impl Drop for Dav1dDecoder {
+    fn drop(&mut self) {
+        // SAFETY:
+        // `ctx` has been successfully opened and is not used after this point. The frames we
+        // returned hold their own references and stay valid.
+        unsafe { ffi::dav1d_close(&mut self.ctx) };
+    }
+}
+
+/// A decoded frame.
+pub struct Dav1dFrame {
+    pic: ffi::Dav1dPicture,
+}
+
+// SAFETY:
+// The picture data is reference counted by dav1d and can be released from any thread.
+unsafe impl Send for Dav1dFrame {}
+
+impl Dav1dFrame {
+    /// Displayed width of the frame.
+    pub fn width(&self) -> usize {
+        self.pic.p.w as usize
+    }
+
+    /// Displayed height of the frame.
+    pub fn height(&self) -> usize {
+        self.pic.p.h as usize
+    }
+
+    /// Whether the frame is in planar 8-bit YUV 4:2:0, the only format `plane()` supports.
+    pub fn is_i420(&self) -> bool {
+        self.pic.p.layout == ffi::DAV1D_PIXEL_LAYOUT_I420 && self.pic.p.bpc == 8
+    }
+
+    /// Value of `timestamp` passed to the `decode()` call this frame has been decoded from.
+    pub fn timestamp(&self) -> u64 {
+        self.pic.m.timestamp as u64
+    }
+
+    /// Returns the stride and data of plane `index` (0 for Y, 1 for U, 2 for V) of an I420 frame.
+    pub fn plane(&self, index: usize) -> Option<(usize, &[u8])> {
+        if !self.is_i420() || index > 2 {
+            return None;
+        }
+
+        let (width, height, stride) = match index {
+            0 => (self.width(), self.height(), self.pic.stride[0]),
+            _ => (
+                (self.width() + 1) / 2,
+                (self.height() + 1) / 2,
+                self.pic.stride[1],
+            ),
+        };
+        let stride = usize::try_from(stride).ok()?;
+        let data = self.pic.data[index] as *const u8;
+        if data.is_null() || height == 0 || stride < width {
+            return None;
+        }
+
+        // SAFETY:
+        // dav1d guarantees that a plane holds `height` lines of `stride` bytes, the last of which
+        // contains at least `width` valid bytes. The data stays valid until the picture is
+        // released when this frame is dropped.
+        let data = unsafe { std::slice::from_raw_parts(data, stride * (height - 1) + width) };
+        Some((stride, data))
+    }
+}
+
+impl Drop for Dav1dFrame {
+    fn drop(&mut self) {
+        // SAFETY:
+        // `pic` has been returned by `dav1d_get_picture` and is not used after this point.
+        unsafe { ffi::dav1d_picture_unref(&mut self.pic) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn create_decoder() {
+        let mut decoder = Dav1dDecoder::new().unwrap();
+        assert!(decoder.drain().unwrap().is_empty());
+    }
+
+    #[test]
+    fn decode_garbage() {
+        let mut decoder = Dav1dDecoder::new().unwrap();
+        // An OBU header with the forbidden bit set.
+        assert!(decoder.decode(&[0xff; 16], 0).is_err());
+        assert!(decoder.drain().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,40 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::path::PathBuf;
+
+use pkg_config::Config;
+
+fn main() {
+    // Skip building dependencies when generating documents.
+    if std::env::var("CARGO_DOC").is_ok() {
+        return;
+    }
+
+    // libvpx is currently only supported on unix
+    if std::env::var("CARGO_CFG_UNIX").is_err() {
+        return;
+    }
+
+    // The user data pointer is used to carry 64-bit timestamps, which requires 64-bit pointers.
+    if std::env::var("CARGO_CFG_TARGET_ARCH").unwrap() == "arm" {
+        return;
+    }
+
+    // libvpx 1.8 is the first version to support all the VP9 profiles we advertise.
+    Config::new().atleast_version("1.8").probe("vpx").unwrap();
+
+    let bindings = bindgen::Builder::default()
+        .header("src/bindings.h")
+        .allowlist_function("vpx_codec_.*")
+        .allowlist_type("vpx_.*")
+        .allowlist_var("VPX_.*")
+        .prepend_enum_name(false)
+        .generate()
+        .expect("failed to generate bindings");
+    let out_path = PathBuf::from(std::env::var("OUT_DIR").unwrap());
+    bindings
+        .write_to_file(out_path.join("bindings.rs"))
+        .expect("writing bindings to file failed");
+}
@@ -0,0 +1,256 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Safe wrapper over the VP8 and VP9 decoders of libvpx.
+
+#![cfg(all(
+    any(target_os = "android", target_os = "linux"),
+    not(target_arch = "arm")
+))]
+
+use std::ffi::CStr;
+use std::marker::PhantomData;
+use std::mem::MaybeUninit;
+use std::ptr;
+
+use libc::c_int;
+use libc::c_uint;
+use libc::c_void;
+use thiserror::Error as ThisError;
+
+mod ffi {
+    #![allow(clippy::missing_safety_doc)]
+    #![allow(clippy::undocumented_unsafe_blocks)]
+    #![allow(clippy::upper_case_acronyms)]
+    #![allow(non_upper_case_globals)]
+    #![allow(non_camel_case_types)]
+    #![allow(non_snake_case)]
+    #![allow(dead_code)]
+    include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
+}
+
+/// Codecs that can be decoded by a `VpxDecoder`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VpxCodec {
+    Vp8,
+    Vp9,
+}
+
+#[derive(Debug, ThisError)]
+pub enum VpxError {
+    #[error("failed to initialize decoder: {0}")]
+    Init(String),
+    #[error("failed to decode frame: {0}")]
+    Decode(String),
+    #[error("input buffer is too large ({0} bytes)")]
+    InputTooLarge(usize),
+}
+
+/// Returns the description of `err` as provided by libvpx.
+fn err_to_string(err: ffi::vpx_codec_err_t) -> String {
+    // SAFETY:
+    // `vpx_codec_err_to_string` returns a pointer to a static, NUL-terminated string for any
+    // value of `err`.
+    unsafe { CStr::from_ptr(ffi::vpx_codec_err_to_string(err)) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// A libvpx decoding context.
+pub struct VpxDecoder {
+    // Boxed so the context keeps the same address for its whole lifetime.
+    ctx: Box<ffi::vpx_codec_ctx_t>,
+}
+
+// SAFETY:
+// The decoding context is not tied to the thread that created it and is only ever accessed through
+// `&mut self`.
+unsafe impl Send for VpxDecoder {}
+
+impl VpxDecoder {
+    /// Create a new decoder for `codec`.
+    pub fn new(codec: VpxCodec) -> Result<Self, VpxError> {
+        // SAFETY:
+        // These functions take no argument and return a pointer to a static interface description.
+        let iface = unsafe {
+            match codec {
+                VpxCodec::Vp8 => ffi::vpx_codec_vp8_dx(),
+                VpxCodec::Vp9 => ffi::vpx_codec_vp9_dx(),
+            }
+        };
+
+        // SAFETY:
+        // An all-zeroes context is what libvpx expects before initialization.
+        let mut ctx =
+            Box::new(unsafe { MaybeUninit::<ffi::vpx_codec_ctx_t>::zeroed().assume_init() });
+        // SAFETY:
+        // `ctx` is a valid, writable context and `iface` a valid interface. A NULL configuration
+        // makes libvpx use its defaults. `vpx_codec_dec_init` is a macro passing the ABI version
+        // we have been built against, which we reproduce here.
+        let ret = unsafe {
+            ffi::vpx_codec_dec_init_ver(
+                ctx.as_mut(),
+                iface,
+                ptr::null(),
+                0,
+                ffi::VPX_DECODER_ABI_VERSION as c_int,
+            )
+        };
+        if ret != ffi::VPX_CODEC_OK {
+            return Err(VpxError::Init(err_to_string(ret)));
+        }
+
+        Ok(Self { ctx })
+    }
+
+    /// Submit the compressed frame in `data` for decoding. `user_priv` will be reported by all the
+    /// frames decoded from it.
+    ///
+    /// All the frames decoded by a previous call must be retrieved using `frames()` before calling
+    /// this method again, as libvpx may reuse their memory.
+    pub fn decode(&mut self, data: &[u8], user_priv: u64) -> Result<(), VpxError> {
+        let data_size =
+            c_uint::try_from(data.len()).map_err(|_| VpxError::InputTooLarge(data.len()))?;
+        // SAFETY:
+        // `ctx` has been successfully initialized, and `data` is valid for `data_size` bytes for
+        // the duration of the call. `user_priv` is only stored as an opaque value.
+        let ret = unsafe {
+            ffi::vpx_codec_decode(
+                self.ctx.as_mut(),
+                data.as_ptr(),
+                data_size,
+                user_priv as usize as *mut c_void,
+                0,
+            )
+        };
+        match ret {
+            ffi::VPX_CODEC_OK => Ok(()),
+            err => Err(VpxError::Decode(err_to_string(err))),
+        }
+    }
+
+    /// Signal the end of the stream, making all the frames still held by the decoder available
+    /// through `frames()`.
+    pub fn flush(&mut self) -> Result<(), VpxError> {
+        // SAFETY:
+        // `ctx` has been successfully initialized, and passing NULL data is how libvpx is asked to
+        // flush.
+        let ret =
+            unsafe { ffi::vpx_codec_decode(self.ctx.as_mut(), ptr::null(), 0, ptr::null_mut(), 0) };
+        match ret {
+            ffi::VPX_CODEC_OK => Ok(()),
+            err => Err(VpxError::Decode(err_to_string(err))),
+        }
+    }
+
+    /// Returns an iterator over the frames decoded by the last call to `decode()` or `flush()`.
+    pub fn frames(&mut self) -> VpxFrameIter<'_> {
+        VpxFrameIter {
+            decoder: self,
+            iter: ptr::null(),
+        }
+    }
+}
+
+impl Drop for VpxDecoder {
+    fn drop(&mut self) {
+        // SAFETY:
+        // `ctx` has been successfully initialized and is not used after this point.
+        unsafe { ffi::vpx_codec_destroy(self.ctx.as_mut()) };
+    }
+}
+
+/// Iterator over the frames made available by the last decoding operation.
+pub struct VpxFrameIter<'a> {
+    decoder: &'a mut VpxDecoder,
+    iter: ffi::vpx_codec_iter_t,
+}
+
+impl<'a> Iterator for VpxFrameIter<'a> {
+    type Item = VpxImage<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // SAFETY:
+        // `ctx` has been successfully initialized and `iter` is the iterator state libvpx expects,
+        // initialized to NULL.
+        let img = unsafe { ffi::vpx_codec_get_frame(self.decoder.ctx.as_mut(), &mut self.iter) };
+        // SAFETY:
+        // A non-NULL image returned by libvpx stays valid until the next call to
+        // `vpx_codec_decode`, which cannot happen while the decoder is borrowed by this iterator.
+        unsafe { img.as_ref() }.map(|img| VpxImage {
+            img,
+            _decoder: PhantomData,
+        })
+    }
+}
+
+/// A decoded frame.
+pub struct VpxImage<'a> {
+    img: &'a ffi::vpx_image_t,
+    _decoder: PhantomData<&'a VpxDecoder>,
+}
+
+impl<'a> VpxImage<'a> {
+    /// Displayed width of the frame.
+    pub fn width(&self) -> usize {
+        self.img.d_w as usize
+    }
+
+    /// Displayed height of the frame.
+    pub fn height(&self) -> usize {
+        self.img.d_h as usize
+    }
+
+    /// Whether the frame is in planar 8-bit YUV 4:2:0, the only format `plane()` supports.
+    pub fn is_i420(&self) -> bool {
+        self.img.fmt == ffi::VPX_IMG_FMT_I420
+    }
+
+    /// Value of `user_priv` passed to the `decode()` call this frame has been decoded from.
+    pub fn user_priv(&self) -> u64 {
+        self.img.user_priv as usize as u64
+    }
+
+    /// Returns the stride and data of plane `index` (0 for Y, 1 for U, 2 for V) of an I420 frame.
+    pub fn plane(&self, index: usize) -> Option<(usize, &'a [u8])> {
+        if !self.is_i420() || index > 2 {
+            return None;
+        }
+
+        let (width, height) = match index {
+            0 => (self.width(), self.height()),
+            _ => ((self.width() + 1) / 2, (self.height() + 1) / 2),
+        };
+        let stride = usize::try_from(self.img.stride[index]).ok()?;
+        let data = self.img.planes[index];
+        if data.is_null() || height == 0 || stride < width {
+            return None;
+        }
+
+        // SAFETY:
+        // libvpx guarantees that a plane holds `height` lines of `stride` bytes, the last of which
+        // contains at least `width` valid bytes. The data stays valid for as long as the decoder
+        // is borrowed.
+        let data = unsafe { std::slice::from_raw_parts(data, stride * (height - 1) + width) };
+        Some((stride, data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn create_decoders() {
+        VpxDecoder::new(VpxCodec::Vp8).unwrap();
+        VpxDecoder::new(VpxCodec::Vp9).unwrap();
+    }
+
+    #[test]
+    fn decode_garbage() {
+        let mut decoder = VpxDecoder::new(VpxCodec::Vp9).unwrap();
+        assert!(decoder.decode(&[0xff; 16], 0).is_err());
+        assert!(decoder.frames().next().is_none());
+    }
+}
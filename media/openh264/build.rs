@@ -0,0 +1,42 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::path::PathBuf;
+
+use pkg_config::Config;
+
+fn main() {
+    // Skip building dependencies when generating documents.
+    if std::env::var("CARGO_DOC").is_ok() {
+        return;
+    }
+
+    // OpenH264 is currently only supported on unix
+    if std::env::var("CARGO_CFG_UNIX").is_err() {
+        return;
+    }
+
+    // OpenH264 2.1 is the first version with `FlushFrame`, which we need to drain the decoder.
+    Config::new()
+        .atleast_version("2.1")
+        .probe("openh264")
+        .unwrap();
+
+    let bindings = bindgen::Builder::default()
+        .header("src/bindings.h")
+        .allowlist_function("WelsCreateDecoder")
+        .allowlist_function("WelsDestroyDecoder")
+        .allowlist_type("ISVCDecoder.*")
+        .allowlist_type("SBufferInfo")
+        .allowlist_type("SDecodingParam")
+        .allowlist_type("DECODER_OPTION")
+        .allowlist_type("DECODING_STATE")
+        .prepend_enum_name(false)
+        .generate()
+        .expect("failed to generate bindings");
+    let out_path = PathBuf::from(std::env::var("OUT_DIR").unwrap());
+    bindings
+        .write_to_file(out_path.join("bindings.rs"))
+        .expect("writing bindings to file failed");
+}
@@ -0,0 +1,280 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Safe wrapper over the H.264 decoder of OpenH264.
+
+#![cfg(any(target_os = "android", target_os = "linux"))]
+
+use std::marker::PhantomData;
+use std::mem::MaybeUninit;
+use std::os::raw::c_int;
+use std::os::raw::c_void;
+use std::ptr;
+
+use thiserror::Error as ThisError;
+
+mod ffi {
+    #![allow(clippy::missing_safety_doc)]
+    #![allow(clippy::undocumented_unsafe_blocks)]
+    #![allow(clippy::upper_case_acronyms)]
+    #![allow(non_upper_case_globals)]
+    #![allow(non_camel_case_types)]
+    #![allow(non_snake_case)]
+    #![allow(dead_code)]
+    include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
+}
+
+#[derive(Debug, ThisError)]
+pub enum H264Error {
+    #[error("failed to create decoder: {0}")]
+    Create(i64),
+    #[error("failed to initialize decoder: {0}")]
+    Init(i64),
+    #[error("failed to decode NAL unit: state {0:#x}")]
+    Decode(u32),
+    #[error("failed to flush decoder: {0:#x}")]
+    Flush(u32),
+    #[error("input buffer is too large ({0} bytes)")]
+    InputTooLarge(usize),
+}
+
+/// Returns the position of the first three-byte start code in `data`.
+fn find_start_code(data: &[u8]) -> Option<usize> {
+    data.windows(3).position(|w| w == [0, 0, 1])
+}
+
+/// Splits an Annex B byte stream into its NAL units. Each unit keeps its leading start code, which
+/// is what the decoder expects.
+pub fn nal_units(stream: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut rest = stream;
+    std::iter::from_fn(move || {
+        if rest.is_empty() {
+            return None;
+        }
+        // Look for the next start code after the one of the current unit, if it has one.
+        let header = match rest.iter().position(|&b| b != 0) {
+            Some(pos) if pos >= 2 && rest[pos] == 1 => pos + 1,
+            _ => 0,
+        };
+        let len = find_start_code(&rest[header..]).map_or(rest.len(), |pos| header + pos);
+        let (nal, tail) = rest.split_at(len);
+        rest = tail;
+        Some(nal)
+    })
+}
+
+/// An OpenH264 decoder.
+pub struct H264Decoder {
+    decoder: *mut ffi::ISVCDecoder,
+}
+
+// SAFETY:
+// The decoder is not tied to the thread that created it and is only ever accessed through
+// `&mut self`.
+unsafe impl Send for H264Decoder {}
+
+impl H264Decoder {
+    /// Create a new decoder.
+    pub fn new() -> Result<Self, H264Error> {
+        let mut decoder = ptr::null_mut();
+        // SAFETY:
+        // `decoder` is a valid location for OpenH264 to store the pointer to the new decoder.
+        let ret = unsafe { ffi::WelsCreateDecoder(&mut decoder) };
+        if ret != 0 || decoder.is_null() {
+            return Err(H264Error::Create(ret as i64));
+        }
+        // Destroys the decoder if initialization fails.
+        let decoder = Self { decoder };
+
+        // SAFETY:
+        // An all-zeroes parameter block is a valid, default configuration.
+        let mut param = unsafe { MaybeUninit::<ffi::SDecodingParam>::zeroed().assume_init() };
+        // Decode all the layers of the stream.
+        param.uiTargetDqLayer = u8::MAX;
+        param.sVideoProperty.size = std::mem::size_of::<ffi::SVideoProperty>() as u32;
+        param.sVideoProperty.eVideoBsType = ffi::VIDEO_BITSTREAM_AVC;
+        // SAFETY:
+        // `decoder` has been successfully created and `param` is valid for the duration of the
+        // call.
+        let ret = unsafe { (decoder.vtbl().Initialize.unwrap())(decoder.decoder, &param) };
+        if ret != 0 {
+            return Err(H264Error::Init(ret as i64));
+        }
+
+        Ok(decoder)
+    }
+
+    fn vtbl(&self) -> &ffi::ISVCDecoderVtbl {
+        // SAFETY:
+        // `decoder` points to the pointer to the method table of a successfully created decoder,
+        // which stays valid until the decoder is destroyed.
+        unsafe { &**self.decoder }
+    }
+
+    /// Decode the NAL unit in `data`, returning the frame it completes, if any.
+    pub fn decode(&mut self, data: &[u8]) -> Result<Option<H264Frame<'_>>, H264Error> {
+        let len = c_int::try_from(data.len()).map_err(|_| H264Error::InputTooLarge(data.len()))?;
+        let mut dst = [ptr::null_mut(); 3];
+        // SAFETY:
+        // An all-zeroes buffer info is what OpenH264 expects as output parameter.
+        let mut info = unsafe { MaybeUninit::<ffi::SBufferInfo>::zeroed().assume_init() };
+        // SAFETY:
+        // `decoder` has been successfully initialized, `data` is valid for `len` bytes and `dst`
+        // and `info` are valid for writes for the duration of the call.
+        let ret = unsafe {
+            (self.vtbl().DecodeFrameNoDelay.unwrap())(
+                self.decoder,
+                data.as_ptr(),
+                len,
+                dst.as_mut_ptr(),
+                &mut info,
+            )
+        };
+        if ret != ffi::dsErrorFree {
+            return Err(H264Error::Decode(ret));
+        }
+        Ok(H264Frame::new(dst, &info))
+    }
+
+    /// Returns the next frame the decoder is still holding, if any. To be called repeatedly at the
+    /// end of the stream until it returns `None`.
+    pub fn flush(&mut self) -> Result<Option<H264Frame<'_>>, H264Error> {
+        let mut remaining: c_int = 0;
+        // SAFETY:
+        // `decoder` has been successfully initialized and this option writes an int to
+        // `remaining`.
+        unsafe {
+            (self.vtbl().GetOption.unwrap())(
+                self.decoder,
+                ffi::DECODER_OPTION_NUM_OF_FRAMES_REMAINING_IN_BUFFER,
+                &mut remaining as *mut c_int as *mut c_void,
+            )
+        };
+        if remaining <= 0 {
+            return Ok(None);
+        }
+
+        let mut dst = [ptr::null_mut(); 3];
+        // SAFETY:
+        // An all-zeroes buffer info is what OpenH264 expects as output parameter.
+        let mut info = unsafe { MaybeUninit::<ffi::SBufferInfo>::zeroed().assume_init() };
+        // SAFETY:
+        // `decoder` has been successfully initialized and `dst` and `info` are valid for writes
+        // for the duration of the call.
+        let ret =
+            unsafe { (self.vtbl().FlushFrame.unwrap())(self.decoder, dst.as_mut_ptr(), &mut info) };
+        if ret != ffi::dsErrorFree {
+            return Err(H264Error::Flush(ret));
+        }
+        Ok(H264Frame::new(dst, &info))
+    }
+}
+
+impl Drop for H264Decoder {
+    fn drop(&mut self) {
+        // SAFETY:
+        // `decoder` has been successfully created and is not used after this point.
+        // `Uninitialize` is a no-op if `Initialize` failed.
+        unsafe {
+            (self.vtbl().Uninitialize.unwrap())(self.decoder);
+            ffi::WelsDestroyDecoder(self.decoder);
+        }
+    }
+}
+
+/// A decoded frame in planar 8-bit YUV 4:2:0.
+pub struct H264Frame<'a> {
+    width: usize,
+    height: usize,
+    // Strides of the luma and of both chroma planes.
+    strides: [usize; 2],
+    planes: [*mut u8; 3],
+    _decoder: PhantomData<&'a mut H264Decoder>,
+}
+
+impl<'a> H264Frame<'a> {
+    /// Builds the frame described by the outputs of a decoding call, if it produced one.
+    fn new(planes: [*mut u8; 3], info: &ffi::SBufferInfo) -> Option<Self> {
+        if info.iBufferStatus != 1 {
+            return None;
+        }
+        // SAFETY:
+        // `sSystemBuffer` is the only member of the union.
+        let buffer = unsafe { info.UsrData.sSystemBuffer };
+        Some(Self {
+            width: usize::try_from(buffer.iWidth).ok()?,
+            height: usize::try_from(buffer.iHeight).ok()?,
+            strides: [
+                usize::try_from(buffer.iStride[0]).ok()?,
+                usize::try_from(buffer.iStride[1]).ok()?,
+            ],
+            planes,
+            _decoder: PhantomData,
+        })
+    }
+
+    /// Displayed width of the frame.
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// Displayed height of the frame.
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Returns the stride and data of plane `index` (0 for Y, 1 for U, 2 for V).
+    pub fn plane(&self, index: usize) -> Option<(usize, &'a [u8])> {
+        let (width, height, stride) = match index {
+            0 => (self.width, self.height, self.strides[0]),
+            1 | 2 => ((self.width + 1) / 2, (self.height + 1) / 2, self.strides[1]),
+            _ => return None,
+        };
+        let data = self.planes[index];
+        if data.is_null() || height == 0 || stride < width {
+            return None;
+        }
+
+        // SAFETY:
+        // OpenH264 guarantees that a plane holds `height` lines of `stride` bytes, the last of
+        // which contains at least `width` valid bytes. The data stays valid until the next call
+        // to the decoder, which cannot happen while it is borrowed by this frame.
+        let data = unsafe { std::slice::from_raw_parts(data, stride * (height - 1) + width) };
+        Some((stride, data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_nal_units() {
+        let stream = [
+            0, 0, 0, 1, 0x67, 0xaa, 0, 0, 1, 0x68, 0xbb, 0, 0, 0, 1, 0x65, 0, 0xcc,
+        ];
+        let nals: Vec<&[u8]> = nal_units(&stream).collect();
+        assert_eq!(
+            nals,
+            [
+                &[0, 0, 0, 1, 0x67, 0xaa][..],
+                &[0, 0, 1, 0x68, 0xbb, 0][..],
+                &[0, 0, 1, 0x65, 0, 0xcc][..],
+            ]
+        );
+    }
+
+    #[test]
+    fn split_without_start_code() {
+        let nals: Vec<&[u8]> = nal_units(&[0x65, 1, 2]).collect();
+        assert_eq!(nals, [&[0x65, 1, 2][..]]);
+        assert_eq!(nal_units(&[]).count(), 0);
+    }
+
+    #[test]
+    fn create_decoder() {
+        let mut decoder = H264Decoder::new().unwrap();
+        assert!(decoder.flush().unwrap().is_none());
+    }
+}
@@ -0,0 +1,183 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Conversion between the guest's sample formats and the 32 bit float samples PipeWire ports
+//! carry, and sample rate conversion for when the graph runs at a different rate than the guest.
+
+use audio_streams::SampleFormat;
+
+/// Converts interleaved samples in `format` to floats. `dst` must hold one float per sample.
+pub fn to_f32(format: SampleFormat, src: &[u8], dst: &mut [f32]) {
+    let size = format.sample_bytes();
+    for (s, d) in src.chunks_exact(size).zip(dst.iter_mut()) {
+        *d = match format {
+            SampleFormat::U8 => (s[0] as f32 - 128.0) / 128.0,
+            SampleFormat::S16LE => i16::from_le_bytes([s[0], s[1]]) as f32 / 32768.0,
+            SampleFormat::S24LE => {
+                // The sample is in the low 24 bits of the container; shift it up to sign extend.
+                let v = i32::from_le_bytes([s[0], s[1], s[2], s[3]]) << 8 >> 8;
+                v as f32 / 8388608.0
+            }
+            SampleFormat::S32LE => {
+                i32::from_le_bytes([s[0], s[1], s[2], s[3]]) as f32 / 2147483648.0
+            }
+        };
+    }
+}
+
+/// Converts floats to interleaved samples in `format`, clipping values outside [-1.0, 1.0].
+pub fn from_f32(format: SampleFormat, src: &[f32], dst: &mut [u8]) {
+    let size = format.sample_bytes();
+    for (s, d) in src.iter().zip(dst.chunks_exact_mut(size)) {
+        let s = s.clamp(-1.0, 1.0) as f64;
+        match format {
+            SampleFormat::U8 => d[0] = (s * 128.0 + 128.0).round().min(255.0) as u8,
+            SampleFormat::S16LE => {
+                let v = (s * 32768.0).round().min(i16::MAX as f64) as i16;
+                d.copy_from_slice(&v.to_le_bytes());
+            }
+            SampleFormat::S24LE => {
+                let v = (s * 8388608.0).round().min(8388607.0) as i32;
+                d.copy_from_slice(&v.to_le_bytes());
+            }
+            SampleFormat::S32LE => {
+                let v = (s * 2147483648.0).round().min(i32::MAX as f64) as i32;
+                d.copy_from_slice(&v.to_le_bytes());
+            }
+        }
+    }
+}
+
+/// A linear interpolating resampler for interleaved float frames.
+pub struct Resampler {
+    channels: usize,
+    // Input frames consumed per output frame.
+    step: f64,
+    // Position of the next output frame, in input frames, relative to `last`.
+    pos: f64,
+    // The last input frame of the previous call.
+    last: Vec<f32>,
+}
+
+impl Resampler {
+    pub fn new(channels: usize, from_rate: u32, to_rate: u32) -> Resampler {
+        Resampler {
+            channels,
+            step: from_rate as f64 / to_rate as f64,
+            pos: 1.0,
+            last: vec![0.0; channels],
+        }
+    }
+
+    /// Resamples `input` and appends the result to `output`.
+    pub fn process(&mut self, input: &[f32], output: &mut Vec<f32>) {
+        let frames = input.len() / self.channels;
+        if frames == 0 {
+            return;
+        }
+        // Frame `i` of the virtual input that starts with `last`.
+        let frame = |i: usize| -> &[f32] {
+            if i == 0 {
+                &self.last
+            } else {
+                &input[(i - 1) * self.channels..i * self.channels]
+            }
+        };
+        while self.pos < frames as f64 {
+            let i = self.pos as usize;
+            let t = (self.pos - i as f64) as f32;
+            let (a, b) = (frame(i), frame(i + 1));
+            output.extend(a.iter().zip(b).map(|(a, b)| a + (b - a) * t));
+            self.pos += self.step;
+        }
+        self.pos -= frames as f64;
+        self.last
+            .copy_from_slice(&input[(frames - 1) * self.channels..frames * self.channels]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn s16_round_trip() {
+        let samples: Vec<u8> = [0i16, 1, -1, i16::MAX, i16::MIN, 1234]
+            .iter()
+            .flat_map(|s| s.to_le_bytes())
+            .collect();
+        let mut floats = vec![0.0; 6];
+        to_f32(SampleFormat::S16LE, &samples, &mut floats);
+        assert_eq!(floats[0], 0.0);
+        assert_eq!(floats[4], -1.0);
+        let mut out = vec![0; samples.len()];
+        from_f32(SampleFormat::S16LE, &floats, &mut out);
+        assert_eq!(out, samples);
+    }
+
+    #[test]
+    fn u8_round_trip() {
+        let samples = vec![0u8, 1, 127, 128, 129, 255];
+        let mut floats = vec![0.0; 6];
+        to_f32(SampleFormat::U8, &samples, &mut floats);
+        assert_eq!(floats[0], -1.0);
+        assert_eq!(floats[3], 0.0);
+        let mut out = vec![0; samples.len()];
+        from_f32(SampleFormat::U8, &floats, &mut out);
+        assert_eq!(out, samples);
+    }
+
+    #[test]
+    fn s24_sign_extension() {
+        // -1 in 24 bits with garbage in the unused top byte.
+        let samples = [0xff, 0xff, 0xff, 0x12, 0x00, 0x00, 0x40, 0x00];
+        let mut floats = vec![0.0; 2];
+        to_f32(SampleFormat::S24LE, &samples, &mut floats);
+        assert_eq!(floats, [-1.0 / 8388608.0, 0.5]);
+        let mut out = vec![0; 8];
+        from_f32(SampleFormat::S24LE, &floats, &mut out);
+        assert_eq!(out, [0xff, 0xff, 0xff, 0xff, 0x00, 0x00, 0x40, 0x00]);
+    }
+
+    #[test]
+    fn s32_clipping() {
+        let mut out = vec![0; 8];
+        from_f32(SampleFormat::S32LE, &[2.0, -2.0], &mut out);
+        assert_eq!(out[..4], i32::MAX.to_le_bytes());
+        assert_eq!(out[4..], i32::MIN.to_le_bytes());
+    }
+
+    #[test]
+    fn resample_same_rate() {
+        let mut r = Resampler::new(2, 48000, 48000);
+        let input: Vec<f32> = (0..20).map(|i| i as f32).collect();
+        let mut output = Vec::new();
+        r.process(&input[..8], &mut output);
+        r.process(&input[8..], &mut output);
+        // The last frame is held back until the next input arrives.
+        assert_eq!(output, input[..18]);
+    }
+
+    #[test]
+    fn resample_up() {
+        let mut r = Resampler::new(1, 24000, 48000);
+        let mut output = Vec::new();
+        r.process(&[1.0, 3.0], &mut output);
+        r.process(&[5.0], &mut output);
+        assert_eq!(output, [1.0, 2.0, 3.0, 4.0]);
+    }
+
+    #[test]
+    fn resample_down_frame_count() {
+        let mut r = Resampler::new(2, 48000, 44100);
+        let input = vec![0.25; 2 * 480];
+        let mut output = Vec::new();
+        for _ in 0..100 {
+            r.process(&input, &mut output);
+        }
+        // One second of input produces one second of output, give or take a frame.
+        assert!((output.len() / 2).abs_diff(44100) <= 1);
+        assert!(output.iter().all(|&s| s == 0.25));
+    }
+}
@@ -0,0 +1,440 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! An `audio_streams` backend that plays and records through a PipeWire server.
+//!
+//! The backend speaks the PipeWire native protocol directly rather than linking libpipewire.
+//! Each stream exports its own client-node, with one port per channel, and runs the node's
+//! process cycle on a dedicated thread. Samples move between that thread and the async stream
+//! through a ring holding a few guest periods, so the guest's period size paces the stream while
+//! the graph's quantum paces the node.
+
+mod convert;
+mod node;
+mod pod;
+mod protocol;
+
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::thread::JoinHandle;
+
+use async_trait::async_trait;
+use audio_streams::capture::AsyncCaptureBuffer;
+use audio_streams::capture::AsyncCaptureBufferStream;
+use audio_streams::capture::CaptureBufferStream;
+use audio_streams::AsyncBufferCommit;
+use audio_streams::AsyncPlaybackBuffer;
+use audio_streams::AsyncPlaybackBufferStream;
+use audio_streams::AudioStreamsExecutor;
+use audio_streams::BoxError;
+use audio_streams::NoopStreamControl;
+use audio_streams::PlaybackBufferStream;
+use audio_streams::SampleFormat;
+use audio_streams::StreamControl;
+use audio_streams::StreamEffect;
+use audio_streams::StreamSource;
+use audio_streams::StreamSourceGenerator;
+use base::error;
+use base::AsRawDescriptor;
+use base::Event;
+use node::Direction;
+use node::Node;
+use node::NodeConfig;
+use node::Shared;
+use node::SharedState;
+pub use protocol::socket_path;
+use remain::sorted;
+use thiserror::Error;
+
+/// Number of guest periods buffered between the guest and the graph.
+const PERIODS: usize = 4;
+
+#[sorted]
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("failed to connect to PipeWire at {0}: {1}")]
+    Connect(PathBuf, io::Error),
+    #[error("failed to create event: {0}")]
+    CreateEvent(base::Error),
+    #[error("disconnected from PipeWire")]
+    Disconnected,
+    #[error("invalid PipeWire message: {0}")]
+    InvalidMessage(&'static str),
+    #[error("PipeWire socket I/O failed: {0}")]
+    Io(io::Error),
+    #[error("failed to map PipeWire memory: {0}")]
+    Mmap(base::MmapError),
+    #[error("neither PIPEWIRE_RUNTIME_DIR nor XDG_RUNTIME_DIR is set")]
+    NoRuntimeDir,
+    #[error("failed to wait for or signal a graph cycle: {0}")]
+    Process(base::Error),
+    #[error("PipeWire error {0}: {1}")]
+    Server(i32, String),
+    #[error("failed to spawn PipeWire node thread: {0}")]
+    SpawnThread(io::Error),
+    #[error("synchronous streams are not supported")]
+    SyncStreamsUnsupported,
+    #[error("unsupported buffer data type {0}")]
+    UnsupportedDataType(u32),
+    #[error("failed to wait for the PipeWire node: {0}")]
+    WaitContext(base::Error),
+    #[error("failed to wait for stream event: {0}")]
+    WaitEvent(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Runs a node's process cycle until dropped.
+struct NodeThread {
+    kill: Event,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl NodeThread {
+    fn start(node: Node) -> Result<NodeThread> {
+        let kill = Event::new().map_err(Error::CreateEvent)?;
+        let thread_kill = kill.try_clone().map_err(Error::CreateEvent)?;
+        let handle = thread::Builder::new()
+            .name("pipewire_node".to_owned())
+            .spawn(move || node.run(thread_kill))
+            .map_err(Error::SpawnThread)?;
+        Ok(NodeThread {
+            kill,
+            handle: Some(handle),
+        })
+    }
+}
+
+impl Drop for NodeThread {
+    fn drop(&mut self) {
+        if let Err(e) = self.kill.signal() {
+            error!("failed to stop PipeWire node thread: {}", e);
+            return;
+        }
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                error!("PipeWire node thread panicked");
+            }
+        }
+    }
+}
+
+/// Common state of playback and capture streams.
+struct PipeWireStream {
+    shared: Arc<Shared>,
+    format: SampleFormat,
+    num_channels: usize,
+    period_frames: usize,
+    _thread: NodeThread,
+}
+
+impl PipeWireStream {
+    fn new(
+        direction: Direction,
+        target: Option<String>,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        period_frames: usize,
+    ) -> Result<PipeWireStream> {
+        let shared = Arc::new(Shared::new()?);
+        let config = NodeConfig {
+            direction,
+            num_channels,
+            frame_rate,
+            period_frames,
+            target,
+        };
+        let node = Node::new(config, shared.clone())?;
+        Ok(PipeWireStream {
+            shared,
+            format,
+            num_channels,
+            period_frames,
+            _thread: NodeThread::start(node)?,
+        })
+    }
+
+    fn frame_size(&self) -> usize {
+        self.format.sample_bytes() * self.num_channels
+    }
+
+    fn period_samples(&self) -> usize {
+        self.period_frames * self.num_channels
+    }
+
+    /// Waits until `ready` holds for the shared ring, or fails if the node stopped.
+    async fn wait(
+        &self,
+        ex: &dyn AudioStreamsExecutor,
+        ready: impl Fn(&SharedState) -> bool,
+    ) -> Result<()> {
+        loop {
+            {
+                let state = self.shared.lock();
+                if state.closed {
+                    return Err(Error::Disconnected);
+                }
+                if ready(&state) {
+                    return Ok(());
+                }
+            }
+            ex.wait_fd_readable(self.shared.event.as_raw_descriptor())
+                .await
+                .map_err(Error::WaitEvent)?;
+            self.shared
+                .event
+                .wait()
+                .map_err(|e| Error::WaitEvent(e.into()))?;
+        }
+    }
+}
+
+/// Converts committed playback periods and queues them for the node.
+struct PlaybackCommit {
+    stream: PipeWireStream,
+    // Points at `PipeWirePlaybackStream::buffer`, which is filled by the `AsyncPlaybackBuffer`
+    // borrowing it before `commit` is called.
+    buffer_ptr: *const u8,
+    buffer_len: usize,
+    samples: Vec<f32>,
+}
+
+// SAFETY: `buffer_ptr` points into the heap allocation owned by the same
+// `PipeWirePlaybackStream`, which moves between threads together with it.
+unsafe impl Send for PlaybackCommit {}
+
+#[async_trait(?Send)]
+impl AsyncBufferCommit for PlaybackCommit {
+    async fn commit(&mut self, nframes: usize) {
+        // SAFETY: `buffer_ptr` and `buffer_len` describe the stream's playback buffer, which is
+        // alive and no longer mutably borrowed once the playback buffer commits.
+        let buffer = unsafe { std::slice::from_raw_parts(self.buffer_ptr, self.buffer_len) };
+        let nframes = nframes.min(self.buffer_len / self.stream.frame_size());
+        self.samples.resize(nframes * self.stream.num_channels, 0.0);
+        convert::to_f32(self.stream.format, buffer, &mut self.samples);
+        self.stream
+            .shared
+            .lock()
+            .samples
+            .extend(self.samples.iter());
+    }
+}
+
+/// A playback stream that queues one guest period for the graph per buffer.
+pub struct PipeWirePlaybackStream {
+    buffer: Box<[u8]>,
+    commit: PlaybackCommit,
+}
+
+impl PipeWirePlaybackStream {
+    fn new(
+        target: Option<String>,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        period_frames: usize,
+    ) -> Result<PipeWirePlaybackStream> {
+        let stream = PipeWireStream::new(
+            Direction::Playback,
+            target,
+            num_channels,
+            format,
+            frame_rate,
+            period_frames,
+        )?;
+        let buffer = vec![0; period_frames * stream.frame_size()].into_boxed_slice();
+        let commit = PlaybackCommit {
+            stream,
+            buffer_ptr: buffer.as_ptr(),
+            buffer_len: buffer.len(),
+            samples: Vec::new(),
+        };
+        Ok(PipeWirePlaybackStream { buffer, commit })
+    }
+}
+
+#[async_trait(?Send)]
+impl AsyncPlaybackBufferStream for PipeWirePlaybackStream {
+    async fn next_playback_buffer<'a>(
+        &'a mut self,
+        ex: &dyn AudioStreamsExecutor,
+    ) -> std::result::Result<AsyncPlaybackBuffer<'a>, BoxError> {
+        let stream = &self.commit.stream;
+        let period = stream.period_samples();
+        let capacity = PERIODS * period;
+        stream
+            .wait(ex, |state| state.samples.len() + period <= capacity)
+            .await?;
+        let frame_size = stream.frame_size();
+        Ok(
+            AsyncPlaybackBuffer::new(frame_size, self.buffer.as_mut(), &mut self.commit)
+                .map_err(Box::new)?,
+        )
+    }
+}
+
+/// Capture buffers are filled before they are handed out, so committing them has nothing left
+/// to do.
+struct CaptureCommit;
+
+#[async_trait(?Send)]
+impl AsyncBufferCommit for CaptureCommit {
+    async fn commit(&mut self, _nframes: usize) {}
+}
+
+/// A capture stream that hands one guest period recorded by the graph to the guest per buffer.
+pub struct PipeWireCaptureStream {
+    stream: PipeWireStream,
+    buffer: Box<[u8]>,
+    samples: Vec<f32>,
+    commit: CaptureCommit,
+}
+
+impl PipeWireCaptureStream {
+    fn new(
+        target: Option<String>,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        period_frames: usize,
+    ) -> Result<PipeWireCaptureStream> {
+        let stream = PipeWireStream::new(
+            Direction::Capture,
+            target,
+            num_channels,
+            format,
+            frame_rate,
+            period_frames,
+        )?;
+        let buffer = vec![0; period_frames * stream.frame_size()].into_boxed_slice();
+        Ok(PipeWireCaptureStream {
+            stream,
+            buffer,
+            samples: Vec::new(),
+            commit: CaptureCommit,
+        })
+    }
+}
+
+#[async_trait(?Send)]
+impl AsyncCaptureBufferStream for PipeWireCaptureStream {
+    async fn next_capture_buffer<'a>(
+        &'a mut self,
+        ex: &dyn AudioStreamsExecutor,
+    ) -> std::result::Result<AsyncCaptureBuffer<'a>, BoxError> {
+        let period = self.stream.period_samples();
+        self.stream
+            .wait(ex, |state| state.samples.len() >= period)
+            .await?;
+        self.samples.clear();
+        self.samples
+            .extend(self.stream.shared.lock().samples.drain(..period));
+        convert::from_f32(self.stream.format, &self.samples, &mut self.buffer);
+        let frame_size = self.stream.frame_size();
+        Ok(
+            AsyncCaptureBuffer::new(frame_size, self.buffer.as_mut(), &mut self.commit)
+                .map_err(Box::new)?,
+        )
+    }
+}
+
+/// Opens streams on a PipeWire server, optionally connecting them to a specific node.
+pub struct PipeWireStreamSource {
+    target: Option<String>,
+}
+
+impl PipeWireStreamSource {
+    /// Creates a source whose streams connect to the node named `target`, or to the default sink
+    /// or source if `target` is `None`.
+    pub fn new(target: Option<String>) -> PipeWireStreamSource {
+        PipeWireStreamSource { target }
+    }
+}
+
+impl StreamSource for PipeWireStreamSource {
+    #[allow(clippy::type_complexity)]
+    fn new_playback_stream(
+        &mut self,
+        _num_channels: usize,
+        _format: SampleFormat,
+        _frame_rate: u32,
+        _buffer_size: usize,
+    ) -> std::result::Result<(Box<dyn StreamControl>, Box<dyn PlaybackBufferStream>), BoxError>
+    {
+        Err(Box::new(Error::SyncStreamsUnsupported))
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn new_async_playback_stream(
+        &mut self,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        buffer_size: usize,
+        _ex: &dyn AudioStreamsExecutor,
+    ) -> std::result::Result<(Box<dyn StreamControl>, Box<dyn AsyncPlaybackBufferStream>), BoxError>
+    {
+        let stream = PipeWirePlaybackStream::new(
+            self.target.clone(),
+            num_channels,
+            format,
+            frame_rate,
+            buffer_size,
+        )?;
+        Ok((Box::new(NoopStreamControl::new()), Box::new(stream)))
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn new_capture_stream(
+        &mut self,
+        _num_channels: usize,
+        _format: SampleFormat,
+        _frame_rate: u32,
+        _buffer_size: usize,
+        _effects: &[StreamEffect],
+    ) -> std::result::Result<(Box<dyn StreamControl>, Box<dyn CaptureBufferStream>), BoxError> {
+        Err(Box::new(Error::SyncStreamsUnsupported))
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn new_async_capture_stream(
+        &mut self,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        buffer_size: usize,
+        _effects: &[StreamEffect],
+        _ex: &dyn AudioStreamsExecutor,
+    ) -> std::result::Result<(Box<dyn StreamControl>, Box<dyn AsyncCaptureBufferStream>), BoxError>
+    {
+        let stream = PipeWireCaptureStream::new(
+            self.target.clone(),
+            num_channels,
+            format,
+            frame_rate,
+            buffer_size,
+        )?;
+        Ok((Box::new(NoopStreamControl::new()), Box::new(stream)))
+    }
+}
+
+/// Creates `PipeWireStreamSource`s that connect to one target node.
+pub struct PipeWireStreamSourceGenerator {
+    target: Option<String>,
+}
+
+impl PipeWireStreamSourceGenerator {
+    pub fn new(target: Option<String>) -> PipeWireStreamSourceGenerator {
+        PipeWireStreamSourceGenerator { target }
+    }
+}
+
+impl StreamSourceGenerator for PipeWireStreamSourceGenerator {
+    fn generate(&self) -> std::result::Result<Box<dyn StreamSource>, BoxError> {
+        Ok(Box::new(PipeWireStreamSource::new(self.target.clone())))
+    }
+}
@@ -0,0 +1,1308 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! A client-node exported to the PipeWire server.
+//!
+//! The node has one 32 bit float mono port per channel, like the DSP ports of PipeWire's own
+//! adapters, so that the server never has to convert our format. Each graph cycle the server
+//! signals the transport's eventfd; the node then moves one quantum between its ports and the
+//! ring shared with the async stream, marks itself finished and triggers the nodes that depend
+//! on it.
+
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::MutexGuard;
+
+use base::error;
+use base::linux::MemoryMappingBuilderUnix;
+use base::pagesize;
+use base::warn;
+use base::AsRawDescriptor;
+use base::Event;
+use base::EventToken;
+use base::MappedRegion;
+use base::MemoryMapping;
+use base::MemoryMappingBuilder;
+use base::SafeDescriptor;
+use base::WaitContext;
+use sync::Mutex;
+
+use crate::convert::Resampler;
+use crate::pod::Builder;
+use crate::pod::Pod;
+use crate::protocol::*;
+use crate::Error;
+use crate::Result;
+
+/// Proxy id of the client-node; ids 0 and 1 are the core and the client.
+const NODE_ID: u32 = 2;
+
+const ID_INVALID: u32 = u32::MAX;
+
+const SPA_DIRECTION_INPUT: i32 = 0;
+const SPA_DIRECTION_OUTPUT: i32 = 1;
+
+const SPA_TYPE_OBJECT_FORMAT: u32 = 0x40003;
+const SPA_TYPE_OBJECT_PARAM_BUFFERS: u32 = 0x40004;
+const SPA_TYPE_OBJECT_PARAM_IO: u32 = 0x40006;
+
+const SPA_PARAM_ENUM_FORMAT: u32 = 3;
+const SPA_PARAM_FORMAT: u32 = 4;
+const SPA_PARAM_BUFFERS: u32 = 5;
+const SPA_PARAM_IO: u32 = 7;
+
+const SPA_PARAM_INFO_READ: i32 = 1 << 1;
+const SPA_PARAM_INFO_WRITE: i32 = 1 << 2;
+
+const SPA_FORMAT_MEDIA_TYPE: u32 = 1;
+const SPA_FORMAT_MEDIA_SUBTYPE: u32 = 2;
+const SPA_FORMAT_AUDIO_FORMAT: u32 = 0x10001;
+const SPA_MEDIA_TYPE_AUDIO: u32 = 1;
+const SPA_MEDIA_SUBTYPE_DSP: u32 = 2;
+const SPA_AUDIO_FORMAT_F32P: u32 = 0x206;
+
+const SPA_PARAM_BUFFERS_BUFFERS: u32 = 1;
+const SPA_PARAM_BUFFERS_BLOCKS: u32 = 2;
+const SPA_PARAM_BUFFERS_SIZE: u32 = 3;
+const SPA_PARAM_BUFFERS_STRIDE: u32 = 4;
+const SPA_PARAM_IO_ID: u32 = 1;
+const SPA_PARAM_IO_SIZE: u32 = 2;
+
+const SPA_IO_BUFFERS: u32 = 1;
+const SPA_IO_POSITION: u32 = 7;
+
+const SPA_STATUS_NEED_DATA: i32 = 1 << 0;
+const SPA_STATUS_HAVE_DATA: i32 = 1 << 1;
+
+const SPA_DATA_MEM_PTR: u32 = 1;
+const SPA_DATA_MEM_ID: u32 = 4;
+
+const SPA_NODE_CHANGE_MASK_FLAGS: i64 = 1 << 0;
+const SPA_NODE_CHANGE_MASK_PROPS: i64 = 1 << 1;
+const SPA_NODE_FLAG_RT: i64 = 1 << 0;
+const SPA_PORT_CHANGE_MASK_FLAGS: i64 = 1 << 0;
+const SPA_PORT_CHANGE_MASK_PROPS: i64 = 1 << 2;
+const SPA_PORT_CHANGE_MASK_PARAMS: i64 = 1 << 3;
+
+const CLIENT_NODE_UPDATE_PARAMS: i32 = 1 << 0;
+const CLIENT_NODE_UPDATE_INFO: i32 = 1 << 1;
+
+// Layout of `struct pw_node_activation`.
+const ACTIVATION_STATUS: usize = 0;
+const ACTIVATION_PENDING: usize = 16;
+const ACTIVATION_SIGNAL_TIME: usize = 32;
+const ACTIVATION_AWAKE_TIME: usize = 40;
+const ACTIVATION_FINISH_TIME: usize = 48;
+const ACTIVATION_TRIGGERED: u32 = 1;
+const ACTIVATION_AWAKE: u32 = 2;
+const ACTIVATION_FINISHED: u32 = 3;
+
+// Layout of `struct spa_io_position`, which starts with a `struct spa_io_clock`.
+const POSITION_RATE_DENOM: usize = 84;
+const POSITION_DURATION: usize = 96;
+
+/// Largest quantum the ports accept, in frames.
+const MAX_QUANTUM: usize = 8192;
+
+const SAMPLE_SIZE: usize = std::mem::size_of::<f32>();
+
+/// Whether the node plays audio from the guest or records audio for it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Playback,
+    Capture,
+}
+
+/// Parameters of the stream the node carries.
+#[derive(Clone, Debug)]
+pub struct NodeConfig {
+    pub direction: Direction,
+    pub num_channels: usize,
+    pub frame_rate: u32,
+    pub period_frames: usize,
+    /// Name or serial of the node to connect to instead of the default sink or source.
+    pub target: Option<String>,
+}
+
+/// Interleaved float samples at the stream's rate, shared by the node thread and the stream.
+pub struct Shared {
+    state: Mutex<SharedState>,
+    /// Signaled whenever the node thread consumes or produces samples, or stops.
+    pub event: Event,
+}
+
+pub struct SharedState {
+    pub samples: VecDeque<f32>,
+    pub closed: bool,
+}
+
+impl Shared {
+    pub fn new() -> Result<Shared> {
+        Ok(Shared {
+            state: Mutex::new(SharedState {
+                samples: VecDeque::new(),
+                closed: false,
+            }),
+            event: Event::new().map_err(Error::CreateEvent)?,
+        })
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, SharedState> {
+        self.state.lock()
+    }
+
+    fn notify(&self) {
+        if let Err(e) = self.event.signal() {
+            error!("failed to signal PipeWire stream: {}", e);
+        }
+    }
+
+    fn close(&self) {
+        self.lock().closed = true;
+        self.notify();
+    }
+}
+
+/// Names of the channel positions for a stream with `num_channels` channels.
+fn channel_names(num_channels: usize) -> Vec<String> {
+    const SURROUND: [&str; 8] = ["FL", "FR", "FC", "LFE", "RL", "RR", "SL", "SR"];
+    match num_channels {
+        1 => vec!["MONO".to_owned()],
+        n if n <= SURROUND.len() => SURROUND[..n].iter().map(|s| s.to_string()).collect(),
+        n => (0..n).map(|i| format!("AUX{}", i)).collect(),
+    }
+}
+
+/// Returns CLOCK_MONOTONIC in nanoseconds, the clock PipeWire timestamps activations with.
+fn monotonic_nsec() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: `ts` is a valid timespec to write to.
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+/// Adds the only format the ports support, mono 32 bit float samples at the graph rate.
+fn dsp_format(b: &mut Builder, param_id: u32) {
+    b.push_object(SPA_TYPE_OBJECT_FORMAT, param_id)
+        .prop(SPA_FORMAT_MEDIA_TYPE)
+        .id(SPA_MEDIA_TYPE_AUDIO)
+        .prop(SPA_FORMAT_MEDIA_SUBTYPE)
+        .id(SPA_MEDIA_SUBTYPE_DSP)
+        .prop(SPA_FORMAT_AUDIO_FORMAT)
+        .id(SPA_AUDIO_FORMAT_F32P)
+        .pop();
+}
+
+/// Returns whether the format the server picked for a port is the one `dsp_format` offers.
+fn is_dsp_format(param: &Pod) -> Result<bool> {
+    let (_, _, props) = param.as_object()?;
+    for prop in props {
+        let (key, value) = prop?;
+        let expected = match key {
+            SPA_FORMAT_MEDIA_TYPE => SPA_MEDIA_TYPE_AUDIO,
+            SPA_FORMAT_MEDIA_SUBTYPE => SPA_MEDIA_SUBTYPE_DSP,
+            SPA_FORMAT_AUDIO_FORMAT => SPA_AUDIO_FORMAT_F32P,
+            _ => continue,
+        };
+        if value.as_id()? != expected {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Server memory mapped at an arbitrary offset.
+struct Mapping {
+    map: MemoryMapping,
+    // Offset of the requested region from the page aligned start of `map`.
+    start: usize,
+    size: usize,
+}
+
+impl Mapping {
+    fn new(descriptor: &dyn AsRawDescriptor, offset: u32, size: u32) -> Result<Mapping> {
+        let aligned = offset as usize & !(pagesize() - 1);
+        let start = offset as usize - aligned;
+        let map = MemoryMappingBuilder::new(start + size as usize)
+            .from_descriptor(descriptor)
+            .offset(aligned as u64)
+            .build()
+            .map_err(Error::Mmap)?;
+        Ok(Mapping {
+            map,
+            start,
+            size: size as usize,
+        })
+    }
+
+    fn check(&self, offset: usize, len: usize) -> Result<usize> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.size => Ok(self.start + offset),
+            _ => Err(Error::InvalidMessage("access outside of server memory")),
+        }
+    }
+
+    fn read_u32(&self, offset: usize) -> Result<u32> {
+        let offset = self.check(offset, 4)?;
+        self.map.read_obj_volatile(offset).map_err(Error::Mmap)
+    }
+
+    fn write_u32(&self, offset: usize, val: u32) -> Result<()> {
+        let offset = self.check(offset, 4)?;
+        self.map
+            .write_obj_volatile(val, offset)
+            .map_err(Error::Mmap)
+    }
+
+    fn read_u64(&self, offset: usize) -> Result<u64> {
+        let offset = self.check(offset, 8)?;
+        self.map.read_obj_volatile(offset).map_err(Error::Mmap)
+    }
+
+    fn write_u64(&self, offset: usize, val: u64) -> Result<()> {
+        let offset = self.check(offset, 8)?;
+        self.map
+            .write_obj_volatile(val, offset)
+            .map_err(Error::Mmap)
+    }
+
+    fn read_slice(&self, buf: &mut [u8], offset: usize) -> Result<()> {
+        let offset = self.check(offset, buf.len())?;
+        self.map.read_slice(buf, offset).map_err(Error::Mmap)?;
+        Ok(())
+    }
+
+    fn write_slice(&self, buf: &[u8], offset: usize) -> Result<()> {
+        let offset = self.check(offset, buf.len())?;
+        self.map.write_slice(buf, offset).map_err(Error::Mmap)?;
+        Ok(())
+    }
+
+    /// Returns the word at `offset`, which other processes update concurrently.
+    fn atomic_u32(&self, offset: usize) -> Result<&AtomicU32> {
+        let offset = self.check(offset, 4)?;
+        if offset % 4 != 0 {
+            return Err(Error::InvalidMessage("unaligned atomic"));
+        }
+        // SAFETY: the word is in bounds and aligned, and lives as long as `self.map`.
+        Ok(unsafe { &*(self.map.as_ptr().add(offset) as *const AtomicU32) })
+    }
+}
+
+/// A buffer the server allocated for a port.
+struct PortBuffer {
+    // Holds the buffer's metadata and chunk, and its data unless `data` is set.
+    mem: Mapping,
+    chunk_offset: usize,
+    data: Option<Mapping>,
+    data_offset: usize,
+    maxsize: usize,
+}
+
+impl PortBuffer {
+    fn data(&self) -> &Mapping {
+        self.data.as_ref().unwrap_or(&self.mem)
+    }
+}
+
+#[derive(Default)]
+struct Port {
+    format: Option<Vec<u8>>,
+    buffers: Vec<PortBuffer>,
+    // `struct spa_io_buffers` the port exchanges buffers through.
+    io: Option<Mapping>,
+    next_buffer: usize,
+}
+
+/// A node this node triggers when it finishes a cycle.
+struct Target {
+    activation: Mapping,
+    signal: Event,
+}
+
+#[derive(EventToken)]
+pub enum Token {
+    Socket,
+    Process,
+    Kill,
+}
+
+pub struct Node {
+    conn: Connection,
+    wait_ctx: WaitContext<Token>,
+    config: NodeConfig,
+    shared: Arc<Shared>,
+    mems: HashMap<u32, SafeDescriptor>,
+    // Eventfd the server signals to start a cycle.
+    process: Option<Event>,
+    // Our own `struct pw_node_activation` and where it came from.
+    activation: Option<(Mapping, u32, u32)>,
+    position: Option<Mapping>,
+    ports: Vec<Port>,
+    targets: HashMap<u32, Target>,
+    done_seq: Option<u32>,
+    // Rate the graph runs at and the resampler bridging it to the stream rate.
+    graph_rate: Option<u32>,
+    resampler: Option<Resampler>,
+    // Interleaved samples at the graph rate that did not fit in the last cycle.
+    pending: Vec<f32>,
+    scratch: Vec<u8>,
+}
+
+impl Node {
+    /// Connects to the server and exports a node for `config`. Returns once the server has
+    /// processed the node and its ports.
+    pub fn new(config: NodeConfig, shared: Arc<Shared>) -> Result<Node> {
+        let conn = Connection::connect()?;
+        let wait_ctx =
+            WaitContext::build_with(&[(&conn, Token::Socket)]).map_err(Error::WaitContext)?;
+        let mut node = Node {
+            conn,
+            wait_ctx,
+            ports: (0..config.num_channels).map(|_| Port::default()).collect(),
+            graph_rate: None,
+            config,
+            shared,
+            mems: HashMap::new(),
+            process: None,
+            activation: None,
+            position: None,
+            targets: HashMap::new(),
+            done_seq: None,
+            resampler: None,
+            pending: Vec::new(),
+            scratch: Vec::new(),
+        };
+        node.export()?;
+        Ok(node)
+    }
+
+    fn props(&self) -> Vec<(&'static str, String)> {
+        let (class, category, name) = match self.config.direction {
+            Direction::Playback => ("Stream/Output/Audio", "Playback", "crosvm-playback"),
+            Direction::Capture => ("Stream/Input/Audio", "Capture", "crosvm-capture"),
+        };
+        let mut props = vec![
+            ("application.name", "crosvm".to_owned()),
+            ("node.name", name.to_owned()),
+            ("media.type", "Audio".to_owned()),
+            ("media.category", category.to_owned()),
+            ("media.class", class.to_owned()),
+            (
+                "node.latency",
+                format!("{}/{}", self.config.period_frames, self.config.frame_rate),
+            ),
+            ("node.rate", format!("1/{}", self.config.frame_rate)),
+            ("node.autoconnect", "true".to_owned()),
+        ];
+        if let Some(target) = &self.config.target {
+            props.push(("target.object", target.clone()));
+        }
+        props
+    }
+
+    fn send(&mut self, id: u32, opcode: u8, b: Builder) -> Result<u32> {
+        self.conn.send(id, opcode, &b.finish(), &[])
+    }
+
+    fn export(&mut self) -> Result<()> {
+        let mut b = Builder::new();
+        b.push_struct().int(CORE_VERSION).pop();
+        self.send(CORE_ID, CORE_HELLO, b)?;
+
+        let mut b = Builder::new();
+        b.push_struct().push_struct().int(1);
+        b.string("application.name").string("crosvm");
+        b.pop().pop();
+        self.send(CLIENT_ID, CLIENT_UPDATE_PROPERTIES, b)?;
+
+        let props = self.props();
+        let mut b = Builder::new();
+        b.push_struct()
+            .string("client-node")
+            .string("PipeWire:Interface:ClientNode")
+            .int(CLIENT_NODE_VERSION)
+            .push_struct()
+            .int(props.len() as i32);
+        for (k, v) in &props {
+            b.string(k).string(v);
+        }
+        b.pop().int(NODE_ID as i32).pop();
+        self.send(CORE_ID, CORE_CREATE_OBJECT, b)?;
+
+        let (inputs, outputs) = match self.config.direction {
+            Direction::Playback => (0, self.config.num_channels),
+            Direction::Capture => (self.config.num_channels, 0),
+        };
+        let mut b = Builder::new();
+        b.push_struct()
+            .int(CLIENT_NODE_UPDATE_PARAMS | CLIENT_NODE_UPDATE_INFO)
+            .int(0)
+            .push_struct()
+            .int(inputs as i32)
+            .int(outputs as i32)
+            .long(SPA_NODE_CHANGE_MASK_FLAGS | SPA_NODE_CHANGE_MASK_PROPS)
+            .long(SPA_NODE_FLAG_RT)
+            .int(props.len() as i32);
+        for (k, v) in &props {
+            b.string(k).string(v);
+        }
+        b.int(0).pop().pop();
+        self.send(NODE_ID, CLIENT_NODE_UPDATE, b)?;
+
+        for port in 0..self.ports.len() {
+            self.port_update(port)?;
+        }
+
+        let mut b = Builder::new();
+        b.push_struct().bool(true).pop();
+        self.send(NODE_ID, CLIENT_NODE_SET_ACTIVE, b)?;
+
+        let mut b = Builder::new();
+        let seq = self.conn.seq();
+        b.push_struct().int(CORE_ID as i32).int(seq as i32).pop();
+        self.send(CORE_ID, CORE_SYNC, b)?;
+        while self.done_seq != Some(seq) {
+            for msg in self.conn.recv()? {
+                self.handle_message(msg)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn port_direction(&self) -> i32 {
+        match self.config.direction {
+            Direction::Playback => SPA_DIRECTION_OUTPUT,
+            Direction::Capture => SPA_DIRECTION_INPUT,
+        }
+    }
+
+    /// Describes port `port` and its parameters to the server.
+    fn port_update(&mut self, port: usize) -> Result<()> {
+        let channel = &channel_names(self.config.num_channels)[port];
+        let prefix = match self.config.direction {
+            Direction::Playback => "output",
+            Direction::Capture => "input",
+        };
+        let props = [
+            ("format.dsp", "32 bit float mono audio".to_owned()),
+            ("port.name", format!("{}_{}", prefix, channel)),
+            ("audio.channel", channel.clone()),
+        ];
+        let format = self.ports[port].format.as_deref();
+
+        let mut b = Builder::new();
+        b.push_struct()
+            .int(self.port_direction())
+            .int(port as i32)
+            .int(CLIENT_NODE_UPDATE_PARAMS | CLIENT_NODE_UPDATE_INFO)
+            .int(if format.is_some() { 4 } else { 3 });
+        dsp_format(&mut b, SPA_PARAM_ENUM_FORMAT);
+        b.push_object(SPA_TYPE_OBJECT_PARAM_BUFFERS, SPA_PARAM_BUFFERS)
+            .prop(SPA_PARAM_BUFFERS_BUFFERS)
+            .int_range(2, 1, 8)
+            .prop(SPA_PARAM_BUFFERS_BLOCKS)
+            .int(1)
+            .prop(SPA_PARAM_BUFFERS_SIZE)
+            .int_step(
+                (MAX_QUANTUM * SAMPLE_SIZE) as i32,
+                SAMPLE_SIZE as i32,
+                i32::MAX,
+                SAMPLE_SIZE as i32,
+            )
+            .prop(SPA_PARAM_BUFFERS_STRIDE)
+            .int(SAMPLE_SIZE as i32)
+            .pop();
+        b.push_object(SPA_TYPE_OBJECT_PARAM_IO, SPA_PARAM_IO)
+            .prop(SPA_PARAM_IO_ID)
+            .id(SPA_IO_BUFFERS)
+            .prop(SPA_PARAM_IO_SIZE)
+            .int(8)
+            .pop();
+        if let Some(format) = format {
+            b.raw(format);
+        }
+
+        b.push_struct()
+            .long(
+                SPA_PORT_CHANGE_MASK_FLAGS
+                    | SPA_PORT_CHANGE_MASK_PROPS
+                    | SPA_PORT_CHANGE_MASK_PARAMS,
+            )
+            .long(0)
+            .int(0)
+            .int(1)
+            .int(props.len() as i32);
+        for (k, v) in &props {
+            b.string(k).string(v);
+        }
+        let format_flags = if format.is_some() {
+            SPA_PARAM_INFO_READ | SPA_PARAM_INFO_WRITE
+        } else {
+            SPA_PARAM_INFO_WRITE
+        };
+        b.int(4)
+            .id(SPA_PARAM_ENUM_FORMAT)
+            .int(SPA_PARAM_INFO_READ)
+            .id(SPA_PARAM_BUFFERS)
+            .int(SPA_PARAM_INFO_READ)
+            .id(SPA_PARAM_IO)
+            .int(SPA_PARAM_INFO_READ)
+            .id(SPA_PARAM_FORMAT)
+            .int(format_flags);
+        b.pop().pop();
+        self.send(NODE_ID, CLIENT_NODE_PORT_UPDATE, b)?;
+        Ok(())
+    }
+
+    /// Maps `size` bytes at `offset` of the memory the server registered as `mem_id`.
+    fn map(&self, mem_id: u32, offset: u32, size: u32) -> Result<Mapping> {
+        let fd = self
+            .mems
+            .get(&mem_id)
+            .ok_or(Error::InvalidMessage("unknown memory id"))?;
+        Mapping::new(fd, offset, size)
+    }
+
+    /// Returns the index of our port `port_id` in `direction`.
+    fn port_index(&self, direction: i32, port_id: u32) -> Result<usize> {
+        if direction != self.port_direction() || port_id as usize >= self.ports.len() {
+            return Err(Error::InvalidMessage("unknown port"));
+        }
+        Ok(port_id as usize)
+    }
+
+    fn handle_message(&mut self, mut msg: Message) -> Result<()> {
+        match (msg.id, msg.opcode) {
+            (CORE_ID, CORE_EVENT_DONE) => {
+                let mut r = msg.reader()?;
+                let (id, seq) = (r.next_uint()?, r.next_uint()?);
+                if id == CORE_ID {
+                    self.done_seq = Some(seq);
+                }
+            }
+            (CORE_ID, CORE_EVENT_PING) => {
+                let mut r = msg.reader()?;
+                let (id, seq) = (r.next_int()?, r.next_int()?);
+                let mut b = Builder::new();
+                b.push_struct().int(id).int(seq).pop();
+                self.send(CORE_ID, CORE_PONG, b)?;
+            }
+            (CORE_ID, CORE_EVENT_ERROR) => {
+                let mut r = msg.reader()?;
+                let id = r.next_uint()?;
+                let _seq = r.next_int()?;
+                let res = r.next_int()?;
+                let message = r.next_string()?.to_owned();
+                if id == CORE_ID || id == NODE_ID {
+                    return Err(Error::Server(res, message));
+                }
+                warn!("PipeWire error on object {}: {} ({})", id, message, res);
+            }
+            (CORE_ID, CORE_EVENT_ADD_MEM) => {
+                let (id, fd) = {
+                    let mut r = msg.reader()?;
+                    let id = r.next_uint()?;
+                    let _type = r.next_id()?;
+                    (id, r.next_fd()?)
+                };
+                let fd = msg.take_fd(fd)?;
+                self.mems.insert(id, fd);
+            }
+            (CORE_ID, CORE_EVENT_REMOVE_MEM) => {
+                let id = msg.reader()?.next_uint()?;
+                self.mems.remove(&id);
+            }
+            (NODE_ID, CLIENT_NODE_EVENT_TRANSPORT) => self.transport(&mut msg)?,
+            (NODE_ID, CLIENT_NODE_EVENT_SET_IO) => {
+                let mut r = msg.reader()?;
+                let id = r.next_id()?;
+                let (mem_id, offset, size) = (r.next_uint()?, r.next_uint()?, r.next_uint()?);
+                if id == SPA_IO_POSITION {
+                    self.position = if mem_id == ID_INVALID {
+                        None
+                    } else {
+                        Some(self.map(mem_id, offset, size)?)
+                    };
+                }
+            }
+            (NODE_ID, CLIENT_NODE_EVENT_COMMAND) => {}
+            (NODE_ID, CLIENT_NODE_EVENT_PORT_SET_PARAM) => {
+                let mut r = msg.reader()?;
+                let port = self.port_index(r.next_int()?, r.next_uint()?)?;
+                let id = r.next_id()?;
+                let _flags = r.next_int()?;
+                let param = r.next_pod()?;
+                if id == SPA_PARAM_FORMAT {
+                    if !param.is_none() && !is_dsp_format(&param)? {
+                        return Err(Error::InvalidMessage("unsupported port format"));
+                    }
+                    self.ports[port].format = (!param.is_none()).then(|| param.as_bytes().to_vec());
+                    self.port_update(port)?;
+                }
+            }
+            (NODE_ID, CLIENT_NODE_EVENT_PORT_USE_BUFFERS) => self.use_buffers(&msg)?,
+            (NODE_ID, CLIENT_NODE_EVENT_PORT_SET_IO) => {
+                let mut r = msg.reader()?;
+                let port = self.port_index(r.next_int()?, r.next_uint()?)?;
+                let _mix_id = r.next_int()?;
+                let id = r.next_id()?;
+                let (mem_id, offset, size) = (r.next_uint()?, r.next_uint()?, r.next_uint()?);
+                if id == SPA_IO_BUFFERS {
+                    self.ports[port].io = if mem_id == ID_INVALID {
+                        None
+                    } else {
+                        Some(self.map(mem_id, offset, size)?)
+                    };
+                }
+            }
+            (NODE_ID, CLIENT_NODE_EVENT_SET_ACTIVATION) => self.set_activation(&mut msg)?,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn transport(&mut self, msg: &mut Message) -> Result<()> {
+        let (read_fd, mem_id, offset, size) = {
+            let mut r = msg.reader()?;
+            let read_fd = r.next_fd()?;
+            let _write_fd = r.next_fd()?;
+            (read_fd, r.next_uint()?, r.next_uint()?, r.next_uint()?)
+        };
+        let process = Event::from(msg.take_fd(read_fd)?);
+        if let Some(old) = self.process.take() {
+            let _ = self.wait_ctx.delete(&old);
+        }
+        self.wait_ctx
+            .add(&process, Token::Process)
+            .map_err(Error::WaitContext)?;
+        self.process = Some(process);
+        self.activation = Some((self.map(mem_id, offset, size)?, mem_id, offset));
+        Ok(())
+    }
+
+    fn set_activation(&mut self, msg: &mut Message) -> Result<()> {
+        let (node, signal_fd, mem_id, offset, size) = {
+            let mut r = msg.reader()?;
+            let node = r.next_uint()?;
+            let signal_fd = r.next_fd()?;
+            (
+                node,
+                signal_fd,
+                r.next_uint()?,
+                r.next_uint()?,
+                r.next_uint()?,
+            )
+        };
+        let own = matches!(&self.activation, Some((_, id, off)) if (*id, *off) == (mem_id, offset));
+        if mem_id == ID_INVALID || signal_fd < 0 || own {
+            self.targets.remove(&node);
+            return Ok(());
+        }
+        let target = Target {
+            activation: self.map(mem_id, offset, size)?,
+            signal: Event::from(msg.take_fd(signal_fd)?),
+        };
+        self.targets.insert(node, target);
+        Ok(())
+    }
+
+    fn use_buffers(&mut self, msg: &Message) -> Result<()> {
+        let mut r = msg.reader()?;
+        let port = self.port_index(r.next_int()?, r.next_uint()?)?;
+        let _mix_id = r.next_int()?;
+        let _flags = r.next_int()?;
+        let n_buffers = r.next_uint()?;
+        let mut buffers = Vec::new();
+        for _ in 0..n_buffers {
+            let (mem_id, offset, size) = (r.next_uint()?, r.next_uint()?, r.next_uint()?);
+            let mem = self.map(mem_id, offset, size)?;
+            // Metadata is laid out first, each padded to 8 bytes, followed by the chunks.
+            let mut chunk_offset = 0;
+            for _ in 0..r.next_uint()? {
+                let _type = r.next_id()?;
+                chunk_offset += (r.next_uint()? as usize + 7) & !7;
+            }
+            let n_datas = r.next_uint()?;
+            let mut datas = Vec::new();
+            for _ in 0..n_datas {
+                let data_type = r.next_id()?;
+                let data = r.next_uint()?;
+                let _flags = r.next_int()?;
+                let map_offset = r.next_uint()?;
+                let maxsize = r.next_uint()?;
+                datas.push((data_type, data, map_offset, maxsize));
+            }
+            let &(data_type, data, map_offset, maxsize) = datas
+                .first()
+                .ok_or(Error::InvalidMessage("buffer without data"))?;
+            let (data, data_offset) = match data_type {
+                SPA_DATA_MEM_PTR => (None, data as usize),
+                SPA_DATA_MEM_ID => (Some(self.map(data, map_offset, maxsize)?), 0),
+                _ => return Err(Error::UnsupportedDataType(data_type)),
+            };
+            buffers.push(PortBuffer {
+                mem,
+                chunk_offset,
+                data,
+                data_offset,
+                maxsize: maxsize as usize,
+            });
+        }
+        let port = &mut self.ports[port];
+        port.buffers = buffers;
+        port.next_buffer = 0;
+        Ok(())
+    }
+
+    /// Returns the number of frames in this cycle and the rate the graph runs at.
+    fn quantum(&self) -> Result<(usize, u32)> {
+        match &self.position {
+            Some(position) => {
+                let duration = position.read_u64(POSITION_DURATION)? as usize;
+                let rate = position.read_u32(POSITION_RATE_DENOM)?;
+                Ok((
+                    duration.min(MAX_QUANTUM),
+                    if rate == 0 {
+                        self.config.frame_rate
+                    } else {
+                        rate
+                    },
+                ))
+            }
+            None => Ok((self.config.period_frames, self.config.frame_rate)),
+        }
+    }
+
+    fn update_resampler(&mut self, graph_rate: u32) {
+        if self.graph_rate == Some(graph_rate) {
+            return;
+        }
+        self.graph_rate = Some(graph_rate);
+        self.pending.clear();
+        self.resampler = (graph_rate != self.config.frame_rate).then(|| {
+            let channels = self.config.num_channels;
+            match self.config.direction {
+                Direction::Playback => Resampler::new(channels, self.config.frame_rate, graph_rate),
+                Direction::Capture => Resampler::new(channels, graph_rate, self.config.frame_rate),
+            }
+        });
+    }
+
+    /// Runs one graph cycle.
+    fn process(&mut self) -> Result<()> {
+        if let Some(process) = &self.process {
+            process.wait().map_err(Error::Process)?;
+        }
+        if let Some((activation, _, _)) = &self.activation {
+            activation.write_u32(ACTIVATION_STATUS, ACTIVATION_AWAKE)?;
+            activation.write_u64(ACTIVATION_AWAKE_TIME, monotonic_nsec())?;
+        }
+        let (frames, rate) = self.quantum()?;
+        self.update_resampler(rate);
+        match self.config.direction {
+            Direction::Playback => self.playback(frames)?,
+            Direction::Capture => self.capture(frames)?,
+        }
+        if let Some((activation, _, _)) = &self.activation {
+            activation.write_u32(ACTIVATION_STATUS, ACTIVATION_FINISHED)?;
+            activation.write_u64(ACTIVATION_FINISH_TIME, monotonic_nsec())?;
+        }
+        self.trigger_targets()
+    }
+
+    fn trigger_targets(&self) -> Result<()> {
+        for target in self.targets.values() {
+            let pending = target.activation.atomic_u32(ACTIVATION_PENDING)?;
+            if pending.fetch_sub(1, Ordering::AcqRel) == 1 {
+                target
+                    .activation
+                    .write_u32(ACTIVATION_STATUS, ACTIVATION_TRIGGERED)?;
+                target
+                    .activation
+                    .write_u64(ACTIVATION_SIGNAL_TIME, monotonic_nsec())?;
+                target.signal.signal().map_err(Error::Process)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Fills the output ports with `frames` frames from the stream.
+    fn playback(&mut self, frames: usize) -> Result<()> {
+        let channels = self.config.num_channels;
+        let needed = frames * channels;
+        while self.pending.len() < needed {
+            let chunk: Vec<f32> = {
+                let mut state = self.shared.lock();
+                let len = state
+                    .samples
+                    .len()
+                    .min(self.config.period_frames * channels);
+                state.samples.drain(..len).collect()
+            };
+            if chunk.is_empty() {
+                break;
+            }
+            match &mut self.resampler {
+                Some(resampler) => resampler.process(&chunk, &mut self.pending),
+                None => self.pending.extend_from_slice(&chunk),
+            }
+        }
+        self.shared.notify();
+        // Play silence for whatever the guest has not provided in time.
+        if self.pending.len() < needed {
+            self.pending.resize(needed, 0.0);
+        }
+
+        for (index, port) in self.ports.iter_mut().enumerate() {
+            let io = match &port.io {
+                Some(io) if !port.buffers.is_empty() => io,
+                _ => continue,
+            };
+            let buffer_id = port.next_buffer;
+            port.next_buffer = (port.next_buffer + 1) % port.buffers.len();
+            let buffer = &port.buffers[buffer_id];
+            let frames = frames.min(buffer.maxsize / SAMPLE_SIZE);
+            self.scratch.clear();
+            self.scratch.extend(
+                self.pending
+                    .iter()
+                    .skip(index)
+                    .step_by(channels)
+                    .take(frames)
+                    .flat_map(|s| s.to_ne_bytes()),
+            );
+            buffer
+                .data()
+                .write_slice(&self.scratch, buffer.data_offset)?;
+            // struct spa_chunk { offset, size, stride, flags }
+            buffer.mem.write_u32(buffer.chunk_offset, 0)?;
+            buffer
+                .mem
+                .write_u32(buffer.chunk_offset + 4, self.scratch.len() as u32)?;
+            buffer
+                .mem
+                .write_u32(buffer.chunk_offset + 8, SAMPLE_SIZE as u32)?;
+            buffer.mem.write_u32(buffer.chunk_offset + 12, 0)?;
+            // struct spa_io_buffers { status, buffer_id }
+            io.write_u32(4, buffer_id as u32)?;
+            io.write_u32(0, SPA_STATUS_HAVE_DATA as u32)?;
+        }
+        self.pending.drain(..needed);
+        Ok(())
+    }
+
+    /// Moves `frames` frames from the input ports to the stream.
+    fn capture(&mut self, frames: usize) -> Result<()> {
+        let channels = self.config.num_channels;
+        let mut interleaved = vec![0.0f32; frames * channels];
+        for (index, port) in self.ports.iter().enumerate() {
+            let io = match &port.io {
+                Some(io) => io,
+                None => continue,
+            };
+            let status = io.read_u32(0)? as i32;
+            let buffer_id = io.read_u32(4)? as usize;
+            if status & SPA_STATUS_HAVE_DATA != 0 {
+                if let Some(buffer) = port.buffers.get(buffer_id) {
+                    let offset = buffer.mem.read_u32(buffer.chunk_offset)? as usize;
+                    let size = buffer.mem.read_u32(buffer.chunk_offset + 4)? as usize;
+                    let len = size.min(frames * SAMPLE_SIZE) & !(SAMPLE_SIZE - 1);
+                    self.scratch.resize(len, 0);
+                    buffer
+                        .data()
+                        .read_slice(&mut self.scratch, buffer.data_offset + offset)?;
+                    for (i, s) in self.scratch.chunks_exact(SAMPLE_SIZE).enumerate() {
+                        interleaved[i * channels + index] =
+                            f32::from_ne_bytes(s.try_into().unwrap());
+                    }
+                }
+            }
+            io.write_u32(0, SPA_STATUS_NEED_DATA as u32)?;
+        }
+
+        let samples = match &mut self.resampler {
+            Some(resampler) => {
+                self.pending.clear();
+                resampler.process(&interleaved, &mut self.pending);
+                &self.pending
+            }
+            None => &interleaved,
+        };
+        {
+            let mut state = self.shared.lock();
+            state.samples.extend(samples.iter());
+            // Drop the oldest samples if the guest is not keeping up.
+            let capacity = crate::PERIODS * self.config.period_frames * channels;
+            if state.samples.len() > capacity {
+                let excess = state.samples.len() - capacity;
+                state.samples.drain(..excess);
+            }
+        }
+        self.shared.notify();
+        Ok(())
+    }
+
+    fn run_loop(&mut self, kill: &Event) -> Result<()> {
+        self.wait_ctx
+            .add(kill, Token::Kill)
+            .map_err(Error::WaitContext)?;
+        loop {
+            let events = self.wait_ctx.wait().map_err(Error::WaitContext)?;
+            for event in events.iter() {
+                match event.token {
+                    Token::Kill => return Ok(()),
+                    Token::Socket => {
+                        if event.is_hungup && !event.is_readable {
+                            return Err(Error::Disconnected);
+                        }
+                        for msg in self.conn.recv()? {
+                            self.handle_message(msg)?;
+                        }
+                    }
+                    Token::Process => self.process()?,
+                }
+            }
+        }
+    }
+
+    /// Runs the node until `kill` is signaled or the connection fails, then closes the stream.
+    pub fn run(mut self, kill: Event) {
+        if let Err(e) = self.run_loop(&kill) {
+            error!("PipeWire {:?} stream stopped: {}", self.config.direction, e);
+        }
+        self.shared.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+    use std::time::Duration;
+
+    use base::EventWaitResult;
+    use base::RawDescriptor;
+    use base::SharedMemory;
+
+    use super::*;
+
+    #[test]
+    fn channel_positions() {
+        assert_eq!(channel_names(1), ["MONO"]);
+        assert_eq!(channel_names(2), ["FL", "FR"]);
+        assert_eq!(channel_names(6), ["FL", "FR", "FC", "LFE", "RL", "RR"]);
+        assert_eq!(channel_names(10)[9], "AUX9");
+    }
+
+    #[test]
+    fn dsp_format_matches() {
+        let mut b = Builder::new();
+        dsp_format(&mut b, SPA_PARAM_FORMAT);
+        let buf = b.finish();
+        let (pod, _) = Pod::parse(&buf).unwrap();
+        assert!(is_dsp_format(&pod).unwrap());
+
+        // Interleaved 32 bit float audio.
+        let mut b = Builder::new();
+        b.push_object(SPA_TYPE_OBJECT_FORMAT, SPA_PARAM_FORMAT)
+            .prop(SPA_FORMAT_MEDIA_TYPE)
+            .id(SPA_MEDIA_TYPE_AUDIO)
+            .prop(SPA_FORMAT_MEDIA_SUBTYPE)
+            .id(1)
+            .prop(SPA_FORMAT_AUDIO_FORMAT)
+            .id(0x11b)
+            .pop();
+        let buf = b.finish();
+        let (pod, _) = Pod::parse(&buf).unwrap();
+        assert!(!is_dsp_format(&pod).unwrap());
+
+        let mut b = Builder::new();
+        b.push_struct().pop();
+        let buf = b.finish();
+        let (pod, _) = Pod::parse(&buf).unwrap();
+        assert!(is_dsp_format(&pod).is_err());
+    }
+
+    /// Serializes the tests that point `PIPEWIRE_REMOTE` at their own fake server.
+    static REMOTE: Mutex<()> = Mutex::new(());
+
+    /// Accepts one client, waits for it to export its node and then sets the node up the way the
+    /// server would: everything lives in `shm`, with the activation at 0, the port io areas at
+    /// 256, the position at 512, one buffer per port from 1024 and a peer's activation at 8192.
+    fn fake_server(
+        listener: UnixListener,
+        direction: i32,
+        shm: &SharedMemory,
+        process: &Event,
+        peer: &Event,
+    ) -> Connection {
+        let (stream, _) = listener.accept().unwrap();
+        let mut conn = Connection::new(stream).unwrap();
+        let mut ports = 0;
+        let mut sync_seq = None;
+        while sync_seq.is_none() {
+            for msg in conn.recv().unwrap() {
+                match (msg.id, msg.opcode) {
+                    (NODE_ID, CLIENT_NODE_PORT_UPDATE) => ports += 1,
+                    (CORE_ID, CORE_SYNC) => {
+                        let mut r = msg.reader().unwrap();
+                        r.next_int().unwrap();
+                        sync_seq = Some(r.next_int().unwrap());
+                    }
+                    _ => {}
+                }
+            }
+        }
+        assert_eq!(ports, 2);
+
+        let mut send = |id, opcode, build: &dyn Fn(&mut Builder), fds: &[RawDescriptor]| {
+            let mut b = Builder::new();
+            b.push_struct();
+            build(&mut b);
+            b.pop();
+            conn.send(id, opcode, &b.finish(), fds).unwrap();
+        };
+        send(
+            CORE_ID,
+            CORE_EVENT_ADD_MEM,
+            &|b| {
+                b.int(0).id(2).fd(0).int(0);
+            },
+            &[shm.as_raw_descriptor()],
+        );
+        send(
+            NODE_ID,
+            CLIENT_NODE_EVENT_TRANSPORT,
+            &|b| {
+                b.fd(0).fd(1).int(0).int(0).int(256);
+            },
+            &[process.as_raw_descriptor(), process.as_raw_descriptor()],
+        );
+        send(
+            NODE_ID,
+            CLIENT_NODE_EVENT_SET_IO,
+            &|b| {
+                b.id(SPA_IO_POSITION).int(0).int(512).int(256);
+            },
+            &[],
+        );
+        for port in 0..2 {
+            send(
+                NODE_ID,
+                CLIENT_NODE_EVENT_PORT_SET_PARAM,
+                &|b| {
+                    b.int(direction).int(port).id(SPA_PARAM_FORMAT).int(0);
+                    dsp_format(b, SPA_PARAM_FORMAT);
+                },
+                &[],
+            );
+            send(
+                NODE_ID,
+                CLIENT_NODE_EVENT_PORT_SET_IO,
+                &|b| {
+                    b.int(direction)
+                        .int(port)
+                        .int(0)
+                        .id(SPA_IO_BUFFERS)
+                        .int(0)
+                        .int(256 + port * 8)
+                        .int(8);
+                },
+                &[],
+            );
+            // One buffer without metadata whose data follows its chunk.
+            send(
+                NODE_ID,
+                CLIENT_NODE_EVENT_PORT_USE_BUFFERS,
+                &|b| {
+                    b.int(direction)
+                        .int(port)
+                        .int(0)
+                        .int(0)
+                        .int(1)
+                        .int(0)
+                        .int(1024 + port * 2048)
+                        .int(2048)
+                        .int(0)
+                        .int(1)
+                        .id(SPA_DATA_MEM_PTR)
+                        .int(64)
+                        .int(0)
+                        .int(0)
+                        .int(1024);
+                },
+                &[],
+            );
+        }
+        send(
+            NODE_ID,
+            CLIENT_NODE_EVENT_SET_ACTIVATION,
+            &|b| {
+                b.int(5).fd(0).int(0).int(8192).int(256);
+            },
+            &[peer.as_raw_descriptor()],
+        );
+        send(
+            CORE_ID,
+            CORE_EVENT_DONE,
+            &|b| {
+                b.int(CORE_ID as i32).int(sync_seq.unwrap());
+            },
+            &[],
+        );
+        conn
+    }
+
+    /// A stream node connected to a fake server, with the memory the server shares with it.
+    struct TestNode {
+        node: Node,
+        shared: Arc<Shared>,
+        mem: MemoryMapping,
+        process: Event,
+        peer: Event,
+        _server_conn: Connection,
+        _dir: tempfile::TempDir,
+    }
+
+    /// Connects a two channel stream node to a fake server and sets up a four frame quantum at the
+    /// stream's rate, with one peer waiting on the node.
+    fn connect_node(direction: Direction) -> TestNode {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pipewire-0");
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let shm = SharedMemory::new("pipewire_test", 16384).unwrap();
+        let mem = MemoryMappingBuilder::new(16384)
+            .from_shared_memory(&shm)
+            .build()
+            .unwrap();
+        let process = Event::new().unwrap();
+        let peer = Event::new().unwrap();
+        let server = {
+            let shm = shm.try_clone().unwrap();
+            let process = process.try_clone().unwrap();
+            let peer = peer.try_clone().unwrap();
+            let spa_direction = match direction {
+                Direction::Playback => SPA_DIRECTION_OUTPUT,
+                Direction::Capture => SPA_DIRECTION_INPUT,
+            };
+            thread::spawn(move || fake_server(listener, spa_direction, &shm, &process, &peer))
+        };
+
+        let shared = Arc::new(Shared::new().unwrap());
+        let config = NodeConfig {
+            direction,
+            num_channels: 2,
+            frame_rate: 48000,
+            period_frames: 4,
+            target: None,
+        };
+        let node = {
+            let _remote = REMOTE.lock();
+            std::env::set_var("PIPEWIRE_REMOTE", &path);
+            Node::new(config, shared.clone()).unwrap()
+        };
+        let server_conn = server.join().unwrap();
+        assert!(node.ports.iter().all(|p| p.format.is_some()));
+
+        mem.write_obj(4u64, 512 + POSITION_DURATION).unwrap();
+        mem.write_obj(48000u32, 512 + POSITION_RATE_DENOM).unwrap();
+        mem.write_obj(1u32, 8192 + ACTIVATION_PENDING).unwrap();
+
+        TestNode {
+            node,
+            shared,
+            mem,
+            process,
+            peer,
+            _server_conn: server_conn,
+            _dir: dir,
+        }
+    }
+
+    /// Checks that the node finished its cycle and woke up its peer.
+    fn assert_cycle_finished(t: &TestNode) {
+        assert_eq!(t.mem.read_obj::<u32>(0).unwrap(), ACTIVATION_FINISHED);
+        assert_eq!(t.mem.read_obj::<u32>(8192).unwrap(), ACTIVATION_TRIGGERED);
+        assert_eq!(
+            t.peer.wait_timeout(Duration::from_secs(1)).unwrap(),
+            EventWaitResult::Signaled
+        );
+    }
+
+    #[test]
+    fn playback_cycle() {
+        let mut t = connect_node(Direction::Playback);
+        t.shared
+            .lock()
+            .samples
+            .extend([0.0, 0.5, 0.25, -0.5, 0.125, 1.0]);
+
+        t.process.signal().unwrap();
+        t.node.process().unwrap();
+
+        for (port, expected) in [[0.0f32, 0.25, 0.125, 0.0], [0.5, -0.5, 1.0, 0.0]]
+            .iter()
+            .enumerate()
+        {
+            let buffer = 1024 + port * 2048;
+            let data: Vec<f32> = (0..4)
+                .map(|i| t.mem.read_obj(buffer + 64 + i * 4).unwrap())
+                .collect();
+            assert_eq!(&data, expected);
+            assert_eq!(t.mem.read_obj::<u32>(buffer + 4).unwrap(), 16);
+            assert_eq!(
+                t.mem.read_obj::<u32>(256 + port * 8).unwrap(),
+                SPA_STATUS_HAVE_DATA as u32
+            );
+        }
+        assert!(t.shared.lock().samples.is_empty());
+        assert_cycle_finished(&t);
+    }
+
+    #[test]
+    fn capture_cycle() {
+        let mut t = connect_node(Direction::Capture);
+
+        // The server filled the buffer of each port: four frames on the left channel, only three
+        // on the right one, and the node must read the missing frame as silence.
+        for (port, samples) in [&[0.0f32, 0.25, 0.125, -1.0][..], &[0.5, -0.5, 1.0][..]]
+            .iter()
+            .enumerate()
+        {
+            let buffer = 1024 + port * 2048;
+            for (i, sample) in samples.iter().enumerate() {
+                t.mem.write_obj(*sample, buffer + 64 + i * 4).unwrap();
+            }
+            t.mem.write_obj(0u32, buffer).unwrap();
+            t.mem
+                .write_obj((samples.len() * 4) as u32, buffer + 4)
+                .unwrap();
+            t.mem.write_obj(0u32, 256 + port * 8 + 4).unwrap();
+            t.mem
+                .write_obj(SPA_STATUS_HAVE_DATA as u32, 256 + port * 8)
+                .unwrap();
+        }
+
+        t.process.signal().unwrap();
+        t.node.process().unwrap();
+
+        let samples: Vec<f32> = t.shared.lock().samples.iter().copied().collect();
+        assert_eq!(samples, [0.0, 0.5, 0.25, -0.5, 0.125, 1.0, -1.0, 0.0]);
+        for port in 0..2 {
+            assert_eq!(
+                t.mem.read_obj::<u32>(256 + port * 8).unwrap(),
+                SPA_STATUS_NEED_DATA as u32
+            );
+        }
+        assert_cycle_finished(&t);
+    }
+}
@@ -0,0 +1,413 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Encoding and decoding of SPA PODs, the self-describing serialization format used for every
+//! message of the PipeWire native protocol.
+//!
+//! A POD is an 8 byte header (body size and type, both native endian `u32`) followed by the body,
+//! padded to a multiple of 8 bytes.
+
+use crate::Error;
+use crate::Result;
+
+pub const TYPE_NONE: u32 = 1;
+const TYPE_BOOL: u32 = 2;
+pub const TYPE_ID: u32 = 3;
+pub const TYPE_INT: u32 = 4;
+const TYPE_LONG: u32 = 5;
+pub const TYPE_STRING: u32 = 8;
+pub const TYPE_STRUCT: u32 = 14;
+pub const TYPE_OBJECT: u32 = 15;
+pub const TYPE_FD: u32 = 18;
+pub const TYPE_CHOICE: u32 = 19;
+
+pub const CHOICE_RANGE: u32 = 1;
+pub const CHOICE_STEP: u32 = 2;
+
+fn pad8(len: usize) -> usize {
+    (len + 7) & !7
+}
+
+/// Serializes PODs into a byte vector.
+#[derive(Default)]
+pub struct Builder {
+    buf: Vec<u8>,
+    // Offsets of the headers of the containers that are still open.
+    frames: Vec<usize>,
+}
+
+impl Builder {
+    pub fn new() -> Builder {
+        Default::default()
+    }
+
+    fn header(&mut self, size: u32, ty: u32) {
+        self.buf.extend_from_slice(&size.to_ne_bytes());
+        self.buf.extend_from_slice(&ty.to_ne_bytes());
+    }
+
+    fn pad(&mut self) {
+        self.buf.resize(pad8(self.buf.len()), 0);
+    }
+
+    fn primitive(&mut self, ty: u32, body: &[u8]) -> &mut Builder {
+        self.header(body.len() as u32, ty);
+        self.buf.extend_from_slice(body);
+        self.pad();
+        self
+    }
+
+    pub fn bool(&mut self, val: bool) -> &mut Builder {
+        self.primitive(TYPE_BOOL, &(val as i32).to_ne_bytes())
+    }
+
+    pub fn id(&mut self, val: u32) -> &mut Builder {
+        self.primitive(TYPE_ID, &val.to_ne_bytes())
+    }
+
+    pub fn int(&mut self, val: i32) -> &mut Builder {
+        self.primitive(TYPE_INT, &val.to_ne_bytes())
+    }
+
+    pub fn long(&mut self, val: i64) -> &mut Builder {
+        self.primitive(TYPE_LONG, &val.to_ne_bytes())
+    }
+
+    /// Adds a file descriptor, encoded as its index in the message's descriptor list.
+    #[cfg(test)]
+    pub fn fd(&mut self, index: i64) -> &mut Builder {
+        self.primitive(TYPE_FD, &index.to_ne_bytes())
+    }
+
+    pub fn string(&mut self, val: &str) -> &mut Builder {
+        let mut body = Vec::with_capacity(val.len() + 1);
+        body.extend_from_slice(val.as_bytes());
+        body.push(0);
+        self.primitive(TYPE_STRING, &body)
+    }
+
+    /// Adds an integer choice between `min` and `max`, defaulting to `default`.
+    pub fn int_range(&mut self, default: i32, min: i32, max: i32) -> &mut Builder {
+        self.int_choice(CHOICE_RANGE, &[default, min, max])
+    }
+
+    /// Adds an integer choice between `min` and `max` in increments of `step`.
+    pub fn int_step(&mut self, default: i32, min: i32, max: i32, step: i32) -> &mut Builder {
+        self.int_choice(CHOICE_STEP, &[default, min, max, step])
+    }
+
+    fn int_choice(&mut self, choice: u32, values: &[i32]) -> &mut Builder {
+        self.header((16 + values.len() * 4) as u32, TYPE_CHOICE);
+        self.buf.extend_from_slice(&choice.to_ne_bytes());
+        // flags
+        self.buf.extend_from_slice(&0u32.to_ne_bytes());
+        self.header(4, TYPE_INT);
+        for v in values {
+            self.buf.extend_from_slice(&v.to_ne_bytes());
+        }
+        self.pad();
+        self
+    }
+
+    /// Appends an already encoded POD.
+    pub fn raw(&mut self, pod: &[u8]) -> &mut Builder {
+        self.buf.extend_from_slice(pod);
+        self.pad();
+        self
+    }
+
+    /// Opens a struct. Every POD added until the matching `pop` is a member of the struct.
+    pub fn push_struct(&mut self) -> &mut Builder {
+        self.frames.push(self.buf.len());
+        self.header(0, TYPE_STRUCT);
+        self
+    }
+
+    /// Opens an object of type `object_type` and id `object_id`. Members are added with `prop`.
+    pub fn push_object(&mut self, object_type: u32, object_id: u32) -> &mut Builder {
+        self.frames.push(self.buf.len());
+        self.header(0, TYPE_OBJECT);
+        self.buf.extend_from_slice(&object_type.to_ne_bytes());
+        self.buf.extend_from_slice(&object_id.to_ne_bytes());
+        self
+    }
+
+    /// Starts an object property. The next POD added is its value.
+    pub fn prop(&mut self, key: u32) -> &mut Builder {
+        self.buf.extend_from_slice(&key.to_ne_bytes());
+        // flags
+        self.buf.extend_from_slice(&0u32.to_ne_bytes());
+        self
+    }
+
+    /// Closes the innermost struct or object.
+    pub fn pop(&mut self) -> &mut Builder {
+        let start = self.frames.pop().expect("pop without a matching push");
+        let size = (self.buf.len() - start - 8) as u32;
+        self.buf[start..start + 4].copy_from_slice(&size.to_ne_bytes());
+        self
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        assert!(self.frames.is_empty(), "unterminated container");
+        self.buf
+    }
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> Result<u32> {
+    buf.get(offset..offset + 4)
+        .map(|b| u32::from_ne_bytes(b.try_into().unwrap()))
+        .ok_or(Error::InvalidMessage("truncated POD"))
+}
+
+/// A decoded POD that borrows the message it was read from.
+#[derive(Clone, Copy, Debug)]
+pub struct Pod<'a> {
+    ty: u32,
+    body: &'a [u8],
+    // The header and body, without padding.
+    raw: &'a [u8],
+}
+
+impl<'a> Pod<'a> {
+    /// Decodes the POD at the start of `buf`, returning it and the padded length it occupies.
+    pub fn parse(buf: &'a [u8]) -> Result<(Pod<'a>, usize)> {
+        let size = read_u32(buf, 0)? as usize;
+        let ty = read_u32(buf, 4)?;
+        let raw = buf
+            .get(..8 + size)
+            .ok_or(Error::InvalidMessage("truncated POD"))?;
+        let pod = Pod {
+            ty,
+            body: &raw[8..],
+            raw,
+        };
+        Ok((pod, pad8(8 + size).min(buf.len())))
+    }
+
+    /// Returns the encoded POD, suitable for `Builder::raw`.
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.raw
+    }
+
+    pub fn is_none(&self) -> bool {
+        self.ty == TYPE_NONE
+    }
+
+    fn scalar(&self, ty: u32, what: &'static str) -> Result<&'a [u8]> {
+        if self.ty != ty {
+            return Err(Error::InvalidMessage(what));
+        }
+        Ok(self.body)
+    }
+
+    pub fn as_id(&self) -> Result<u32> {
+        read_u32(self.scalar(TYPE_ID, "expected id")?, 0)
+    }
+
+    pub fn as_int(&self) -> Result<i32> {
+        Ok(read_u32(self.scalar(TYPE_INT, "expected int")?, 0)? as i32)
+    }
+
+    /// Returns the index of the file descriptor in the message's descriptor list.
+    pub fn as_fd(&self) -> Result<i64> {
+        self.scalar(TYPE_FD, "expected fd")?
+            .get(..8)
+            .map(|b| i64::from_ne_bytes(b.try_into().unwrap()))
+            .ok_or(Error::InvalidMessage("truncated fd"))
+    }
+
+    pub fn as_string(&self) -> Result<&'a str> {
+        let body = self.scalar(TYPE_STRING, "expected string")?;
+        let end = body
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or(Error::InvalidMessage("unterminated string"))?;
+        std::str::from_utf8(&body[..end]).map_err(|_| Error::InvalidMessage("invalid string"))
+    }
+
+    /// Returns a reader over the members of a struct.
+    pub fn as_struct(&self) -> Result<Reader<'a>> {
+        Ok(Reader {
+            buf: self.scalar(TYPE_STRUCT, "expected struct")?,
+        })
+    }
+
+    /// Returns the type, id and properties of an object.
+    pub fn as_object(&self) -> Result<(u32, u32, PropIter<'a>)> {
+        let body = self.scalar(TYPE_OBJECT, "expected object")?;
+        let object_type = read_u32(body, 0)?;
+        let object_id = read_u32(body, 4)?;
+        Ok((object_type, object_id, PropIter { buf: &body[8..] }))
+    }
+}
+
+/// Reads the members of a struct in order.
+pub struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    /// Returns a reader over the struct at the start of `payload`. Anything after it, such as a
+    /// message footer, is ignored.
+    pub fn new(payload: &'a [u8]) -> Result<Reader<'a>> {
+        Pod::parse(payload)?.0.as_struct()
+    }
+
+    pub fn next_pod(&mut self) -> Result<Pod<'a>> {
+        let (pod, len) = Pod::parse(self.buf)?;
+        self.buf = &self.buf[len..];
+        Ok(pod)
+    }
+
+    pub fn next_id(&mut self) -> Result<u32> {
+        self.next_pod()?.as_id()
+    }
+
+    pub fn next_int(&mut self) -> Result<i32> {
+        self.next_pod()?.as_int()
+    }
+
+    /// Reads an int that holds an unsigned value such as an object or memory id.
+    pub fn next_uint(&mut self) -> Result<u32> {
+        self.next_int().map(|v| v as u32)
+    }
+
+    pub fn next_fd(&mut self) -> Result<i64> {
+        self.next_pod()?.as_fd()
+    }
+
+    pub fn next_string(&mut self) -> Result<&'a str> {
+        self.next_pod()?.as_string()
+    }
+}
+
+/// Iterates over the `(key, value)` properties of an object.
+pub struct PropIter<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Iterator for PropIter<'a> {
+    type Item = Result<(u32, Pod<'a>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buf.is_empty() {
+            return None;
+        }
+        let prop = (|| {
+            let key = read_u32(self.buf, 0)?;
+            let value_buf = self
+                .buf
+                .get(8..)
+                .ok_or(Error::InvalidMessage("truncated property"))?;
+            let (value, len) = Pod::parse(value_buf)?;
+            self.buf = &value_buf[len..];
+            Ok((key, value))
+        })();
+        if prop.is_err() {
+            self.buf = &[];
+        }
+        Some(prop)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn struct_round_trip() {
+        let mut b = Builder::new();
+        b.push_struct()
+            .int(-3)
+            .id(7)
+            .string("hello")
+            .long(1 << 40)
+            .fd(2)
+            .bool(true)
+            .push_struct()
+            .int(1)
+            .pop()
+            .pop();
+        let buf = b.finish();
+        assert_eq!(buf.len() % 8, 0);
+
+        let mut r = Reader::new(&buf).unwrap();
+        assert_eq!(r.next_int().unwrap(), -3);
+        assert_eq!(r.next_id().unwrap(), 7);
+        assert_eq!(r.next_string().unwrap(), "hello");
+        assert_eq!(
+            r.next_pod().unwrap().as_bytes()[8..],
+            (1i64 << 40).to_ne_bytes()
+        );
+        assert_eq!(r.next_fd().unwrap(), 2);
+        assert_eq!(
+            r.next_int().unwrap_err().to_string(),
+            "invalid PipeWire message: expected int"
+        );
+        let mut inner = r.next_pod().unwrap().as_struct().unwrap();
+        assert_eq!(inner.next_int().unwrap(), 1);
+        assert!(inner.next_pod().is_err());
+        assert!(r.next_pod().is_err());
+    }
+
+    #[test]
+    fn string_padding() {
+        let mut b = Builder::new();
+        b.string("1234567");
+        // 8 byte header, 7 characters and the terminator.
+        assert_eq!(b.finish().len(), 16);
+
+        let mut b = Builder::new();
+        b.string("12345678");
+        assert_eq!(b.finish().len(), 24);
+    }
+
+    #[test]
+    fn object_props() {
+        let mut b = Builder::new();
+        b.push_object(0x40004, 5)
+            .prop(1)
+            .int_range(2, 1, 8)
+            .prop(3)
+            .int(4096)
+            .pop();
+        let buf = b.finish();
+
+        let (pod, len) = Pod::parse(&buf).unwrap();
+        assert_eq!(len, buf.len());
+        assert_eq!(pod.as_bytes(), &buf[..]);
+        let (ty, id, props) = pod.as_object().unwrap();
+        assert_eq!((ty, id), (0x40004, 5));
+        let props: Vec<_> = props.map(|p| p.unwrap()).collect();
+        assert_eq!(props.len(), 2);
+        assert_eq!(props[0].0, 1);
+        assert_eq!(
+            props[0].1.as_bytes()[8..],
+            [CHOICE_RANGE, 0, 4, TYPE_INT, 2, 1, 8]
+                .iter()
+                .flat_map(|v: &u32| v.to_ne_bytes())
+                .collect::<Vec<u8>>()
+        );
+        assert_eq!(props[1].0, 3);
+        assert_eq!(props[1].1.as_int().unwrap(), 4096);
+    }
+
+    #[test]
+    fn type_mismatch() {
+        let mut b = Builder::new();
+        b.push_struct().int(1).pop();
+        let buf = b.finish();
+        let mut r = Reader::new(&buf).unwrap();
+        assert!(r.next_string().is_err());
+    }
+
+    #[test]
+    fn truncated() {
+        let mut b = Builder::new();
+        b.push_struct().string("truncated").pop();
+        let buf = b.finish();
+        assert!(Reader::new(&buf[..buf.len() - 10]).is_err());
+    }
+}
@@ -0,0 +1,312 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Framing of the PipeWire native protocol.
+//!
+//! Every message starts with a 16 byte header: the target object id, the opcode in the top 8 bits
+//! of a word whose low 24 bits hold the payload size, a sequence number and the number of file
+//! descriptors passed along with the message. The payload is a POD struct, optionally followed by
+//! a footer that this crate ignores.
+
+use std::collections::VecDeque;
+use std::env;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+
+use base::AsRawDescriptor;
+use base::RawDescriptor;
+use base::SafeDescriptor;
+use base::ScmSocket;
+use base::SCM_SOCKET_MAX_FD_COUNT;
+
+use crate::pod::Reader;
+use crate::Error;
+use crate::Result;
+
+const HEADER_SIZE: usize = 16;
+const MAX_PAYLOAD: usize = 0xff_ffff;
+
+/// Version of the core interface spoken by this client.
+pub const CORE_VERSION: i32 = 3;
+/// Version of the client-node interface spoken by this client.
+pub const CLIENT_NODE_VERSION: i32 = 4;
+
+pub const CORE_ID: u32 = 0;
+pub const CLIENT_ID: u32 = 1;
+
+// Methods of the core object.
+pub const CORE_HELLO: u8 = 1;
+pub const CORE_SYNC: u8 = 2;
+pub const CORE_PONG: u8 = 3;
+pub const CORE_CREATE_OBJECT: u8 = 6;
+
+// Events of the core object.
+pub const CORE_EVENT_DONE: u8 = 1;
+pub const CORE_EVENT_PING: u8 = 2;
+pub const CORE_EVENT_ERROR: u8 = 3;
+pub const CORE_EVENT_ADD_MEM: u8 = 6;
+pub const CORE_EVENT_REMOVE_MEM: u8 = 7;
+
+// Methods of the client object.
+pub const CLIENT_UPDATE_PROPERTIES: u8 = 2;
+
+// Methods of client-node objects.
+pub const CLIENT_NODE_UPDATE: u8 = 2;
+pub const CLIENT_NODE_PORT_UPDATE: u8 = 3;
+pub const CLIENT_NODE_SET_ACTIVE: u8 = 4;
+
+// Events of client-node objects.
+pub const CLIENT_NODE_EVENT_TRANSPORT: u8 = 0;
+pub const CLIENT_NODE_EVENT_SET_IO: u8 = 2;
+pub const CLIENT_NODE_EVENT_COMMAND: u8 = 4;
+pub const CLIENT_NODE_EVENT_PORT_SET_PARAM: u8 = 7;
+pub const CLIENT_NODE_EVENT_PORT_USE_BUFFERS: u8 = 8;
+pub const CLIENT_NODE_EVENT_PORT_SET_IO: u8 = 9;
+pub const CLIENT_NODE_EVENT_SET_ACTIVATION: u8 = 10;
+
+/// Returns the path of the PipeWire socket, honoring the same environment variables as
+/// libpipewire.
+pub fn socket_path() -> Result<PathBuf> {
+    let name = env::var("PIPEWIRE_REMOTE").unwrap_or_else(|_| "pipewire-0".to_owned());
+    if name.starts_with('/') {
+        return Ok(PathBuf::from(name));
+    }
+    let dir = env::var("PIPEWIRE_RUNTIME_DIR")
+        .or_else(|_| env::var("XDG_RUNTIME_DIR"))
+        .map_err(|_| Error::NoRuntimeDir)?;
+    Ok(PathBuf::from(dir).join(name))
+}
+
+/// A message received from the server.
+pub struct Message {
+    pub id: u32,
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+    fds: Vec<Option<SafeDescriptor>>,
+}
+
+impl Message {
+    /// Returns a reader over the members of the payload struct.
+    pub fn reader(&self) -> Result<Reader<'_>> {
+        Reader::new(&self.payload)
+    }
+
+    /// Takes ownership of the descriptor referenced by a fd POD of the payload.
+    pub fn take_fd(&mut self, index: i64) -> Result<SafeDescriptor> {
+        usize::try_from(index)
+            .ok()
+            .and_then(|i| self.fds.get_mut(i))
+            .and_then(Option::take)
+            .ok_or(Error::InvalidMessage("invalid fd index"))
+    }
+}
+
+/// A connection to the PipeWire server.
+pub struct Connection {
+    socket: ScmSocket<UnixStream>,
+    // Bytes received but not yet parsed into a message.
+    in_buf: Vec<u8>,
+    in_fds: VecDeque<SafeDescriptor>,
+    seq: u32,
+}
+
+impl Connection {
+    /// Connects to the server listening at `socket_path()`.
+    pub fn connect() -> Result<Connection> {
+        let path = socket_path()?;
+        let stream = UnixStream::connect(&path).map_err(|e| Error::Connect(path, e))?;
+        Connection::new(stream)
+    }
+
+    /// Wraps a connected stream socket.
+    pub fn new(stream: UnixStream) -> Result<Connection> {
+        let socket = ScmSocket::try_from(stream).map_err(Error::Io)?;
+        Ok(Connection {
+            socket,
+            in_buf: Vec::new(),
+            in_fds: VecDeque::new(),
+            seq: 0,
+        })
+    }
+
+    /// Returns the sequence number the next message will be sent with.
+    pub fn seq(&self) -> u32 {
+        self.seq
+    }
+
+    /// Sends the `opcode` method of object `id` with `payload` and returns the sequence number of
+    /// the message. `fds` are referenced by index from fd PODs in the payload.
+    pub fn send(
+        &mut self,
+        id: u32,
+        opcode: u8,
+        payload: &[u8],
+        fds: &[RawDescriptor],
+    ) -> Result<u32> {
+        if payload.len() > MAX_PAYLOAD {
+            return Err(Error::InvalidMessage("payload too large"));
+        }
+        let seq = self.seq;
+        self.seq = self.seq.wrapping_add(1);
+        let mut msg = Vec::with_capacity(HEADER_SIZE + payload.len());
+        msg.extend_from_slice(&id.to_ne_bytes());
+        msg.extend_from_slice(&((opcode as u32) << 24 | payload.len() as u32).to_ne_bytes());
+        msg.extend_from_slice(&seq.to_ne_bytes());
+        msg.extend_from_slice(&(fds.len() as u32).to_ne_bytes());
+        msg.extend_from_slice(payload);
+        let sent = self.socket.send_with_fds(&msg, fds).map_err(Error::Io)?;
+        if sent != msg.len() {
+            // The socket is blocking, so a short write means the connection is going away.
+            return Err(Error::Disconnected);
+        }
+        Ok(seq)
+    }
+
+    /// Blocks until data arrives and returns every complete message received so far.
+    pub fn recv(&mut self) -> Result<Vec<Message>> {
+        let mut buf = vec![0; 65536];
+        let (len, fds) = self
+            .socket
+            .recv_with_fds(&mut buf, SCM_SOCKET_MAX_FD_COUNT)
+            .map_err(Error::Io)?;
+        if len == 0 {
+            return Err(Error::Disconnected);
+        }
+        self.in_buf.extend_from_slice(&buf[..len]);
+        self.in_fds.extend(fds);
+        self.parse()
+    }
+
+    fn parse(&mut self) -> Result<Vec<Message>> {
+        let mut messages = Vec::new();
+        let mut offset = 0;
+        while self.in_buf.len() - offset >= HEADER_SIZE {
+            let header = &self.in_buf[offset..offset + HEADER_SIZE];
+            // The third word is the server's sequence number, which clients have no use for.
+            let word = |i: usize| u32::from_ne_bytes(header[i * 4..i * 4 + 4].try_into().unwrap());
+            let size = (word(1) & 0xff_ffff) as usize;
+            let n_fds = word(3) as usize;
+            if self.in_buf.len() - offset - HEADER_SIZE < size {
+                break;
+            }
+            if n_fds > self.in_fds.len() {
+                return Err(Error::InvalidMessage("missing file descriptors"));
+            }
+            let start = offset + HEADER_SIZE;
+            messages.push(Message {
+                id: word(0),
+                opcode: (word(1) >> 24) as u8,
+                payload: self.in_buf[start..start + size].to_vec(),
+                fds: self.in_fds.drain(..n_fds).map(Some).collect(),
+            });
+            offset = start + size;
+        }
+        self.in_buf.drain(..offset);
+        Ok(messages)
+    }
+}
+
+impl AsRawDescriptor for Connection {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        self.socket.as_raw_descriptor()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixStream;
+
+    use base::Event;
+
+    use super::*;
+    use crate::pod::Builder;
+
+    fn pair() -> (Connection, Connection) {
+        let (a, b) = UnixStream::pair().unwrap();
+        (Connection::new(a).unwrap(), Connection::new(b).unwrap())
+    }
+
+    #[test]
+    fn send_recv() {
+        let (mut client, mut server) = pair();
+        let mut b = Builder::new();
+        b.push_struct().int(CORE_VERSION).pop();
+        assert_eq!(
+            client.send(CORE_ID, CORE_HELLO, &b.finish(), &[]).unwrap(),
+            0
+        );
+
+        let evt = Event::new().unwrap();
+        let mut b = Builder::new();
+        b.push_struct().int(7).fd(0).pop();
+        assert_eq!(
+            client
+                .send(
+                    5,
+                    CLIENT_NODE_UPDATE,
+                    &b.finish(),
+                    &[evt.as_raw_descriptor()]
+                )
+                .unwrap(),
+            1
+        );
+
+        let mut messages = Vec::new();
+        while messages.len() < 2 {
+            messages.extend(server.recv().unwrap());
+        }
+        assert_eq!(messages[0].id, CORE_ID);
+        assert_eq!(messages[0].opcode, CORE_HELLO);
+        assert_eq!(messages[0].reader().unwrap().next_int().unwrap(), 3);
+
+        let msg = &mut messages[1];
+        assert_eq!(msg.id, 5);
+        assert_eq!(msg.opcode, CLIENT_NODE_UPDATE);
+        let fd = {
+            let mut r = msg.reader().unwrap();
+            assert_eq!(r.next_int().unwrap(), 7);
+            r.next_fd().unwrap()
+        };
+        assert!(msg.take_fd(fd).is_ok());
+        assert!(msg.take_fd(fd).is_err());
+    }
+
+    #[test]
+    fn partial_message() {
+        let (_client, mut server) = pair();
+        let mut b = Builder::new();
+        b.push_struct().string("partial").pop();
+        let payload = b.finish();
+        let mut msg = Vec::new();
+        msg.extend_from_slice(&2u32.to_ne_bytes());
+        msg.extend_from_slice(&(3 << 24 | payload.len() as u32).to_ne_bytes());
+        msg.extend_from_slice(&9u32.to_ne_bytes());
+        msg.extend_from_slice(&0u32.to_ne_bytes());
+        msg.extend_from_slice(&payload);
+
+        server.in_buf.extend_from_slice(&msg[..20]);
+        assert!(server.parse().unwrap().is_empty());
+        server.in_buf.extend_from_slice(&msg[20..]);
+        let messages = server.parse().unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].opcode, 3);
+        assert_eq!(
+            messages[0].reader().unwrap().next_string().unwrap(),
+            "partial"
+        );
+        assert!(server.in_buf.is_empty());
+    }
+
+    #[test]
+    fn missing_fds() {
+        let (_client, mut server) = pair();
+        let mut msg = Vec::new();
+        msg.extend_from_slice(&0u32.to_ne_bytes());
+        msg.extend_from_slice(&(6u32 << 24).to_ne_bytes());
+        msg.extend_from_slice(&0u32.to_ne_bytes());
+        msg.extend_from_slice(&1u32.to_ne_bytes());
+        server.in_buf.extend_from_slice(&msg);
+        assert!(server.parse().is_err());
+    }
+}
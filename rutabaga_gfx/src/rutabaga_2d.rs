@@ -14,6 +14,40 @@ use crate::rutabaga_core::RutabagaComponent;
 use crate::rutabaga_core::RutabagaResource;
 use crate::rutabaga_utils::*;
 
+/// Number of damaged rectangles tracked per resource, beyond which they are merged into their
+/// bounding box.
+const MAX_DAMAGE_RECTS: usize = 16;
+
+/// Records that `rect` was modified in a resource whose damage so far is `damage`.
+pub(crate) fn add_damage(damage: &mut Vec<RutabagaRect>, rect: RutabagaRect) {
+    if rect.is_empty() || damage.iter().any(|r| r.contains(&rect)) {
+        return;
+    }
+
+    damage.retain(|r| !rect.contains(r));
+    if damage.len() < MAX_DAMAGE_RECTS {
+        damage.push(rect);
+    } else {
+        let bounds = damage.iter().fold(rect, |acc, r| acc.bounding_box(r));
+        *damage = vec![bounds];
+    }
+}
+
+/// Returns the damage of a resource that was modified in its entirety.
+pub(crate) fn full_damage(width: u32, height: u32) -> Vec<RutabagaRect> {
+    let mut damage = Vec::new();
+    add_damage(
+        &mut damage,
+        RutabagaRect {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        },
+    );
+    damage
+}
+
 /// Transfers a resource from potentially many chunked src slices to a dst slice.
 fn transfer_2d(
     resource_w: u32,
@@ -169,6 +203,7 @@ impl RutabagaComponent for Rutabaga2D {
             width: resource_create_3d.width,
             height: resource_create_3d.height,
             host_mem: vec![0; resource_size],
+            damage: full_damage(resource_create_3d.width, resource_create_3d.height),
         };
 
         Ok(RutabagaResource {
@@ -239,6 +274,16 @@ impl RutabagaComponent for Rutabaga2D {
             &src_slices,
         )?;
 
+        add_damage(
+            &mut info_2d.damage,
+            RutabagaRect {
+                x: transfer.x,
+                y: transfer.y,
+                width: transfer.w,
+                height: transfer.h,
+            },
+        );
+
         resource.info_2d = Some(info_2d);
         resource.backing_iovecs = Some(iovecs);
         Ok(())
@@ -13,6 +13,7 @@ use std::sync::Arc;
 use crate::cross_domain::CrossDomain;
 #[cfg(feature = "gfxstream")]
 use crate::gfxstream::Gfxstream;
+use crate::rutabaga_2d::full_damage;
 use crate::rutabaga_2d::Rutabaga2D;
 use crate::rutabaga_os::MemoryMapping;
 use crate::rutabaga_os::SafeDescriptor;
@@ -30,6 +31,8 @@ pub struct Rutabaga2DInfo {
     pub width: u32,
     pub height: u32,
     pub host_mem: Vec<u8>,
+    /// Rectangles modified since the last `Rutabaga::export_frame` of the resource.
+    pub damage: Vec<RutabagaRect>,
 }
 
 /// A Rutabaga resource, supporting 2D and 3D rutabaga features.  Assumes a single-threaded library.
@@ -446,6 +449,7 @@ impl Rutabaga {
                             width: s.width,
                             height: s.height,
                             host_mem: vec![0; usize::try_from(size).unwrap()],
+                            damage: full_damage(s.width, s.height),
                         }),
                         info_3d: None,
                         vulkan_info: None,
@@ -708,6 +712,31 @@ impl Rutabaga {
         component.resource_flush(resource)
     }
 
+    /// Returns the contents of a 2D resource along with the rectangles modified since its
+    /// previous export, so that frames can be encoded without reading back unchanged areas.  The
+    /// whole resource is reported as damaged on the first export.
+    ///
+    /// Only resources created by the 2D component support this.
+    pub fn export_frame(&mut self, resource_id: u32) -> RutabagaResult<Rutabaga2DFrame<'_>> {
+        let resource = self
+            .resources
+            .get_mut(&resource_id)
+            .ok_or(RutabagaError::InvalidResourceId)?;
+
+        let info_2d = resource
+            .info_2d
+            .as_mut()
+            .ok_or(RutabagaError::Unsupported)?;
+
+        Ok(Rutabaga2DFrame {
+            width: info_2d.width,
+            height: info_2d.height,
+            stride: info_2d.width * 4,
+            damage: std::mem::take(&mut info_2d.damage),
+            pixels: &info_2d.host_mem,
+        })
+    }
+
     /// Creates a blob resource with the `ctx_id` and `resource_create_blob` metadata.
     /// Associates `iovecs` with the resource, if there are any.  Associates externally
     /// created `handle` with the resource, if there is any.
@@ -1363,4 +1392,94 @@ mod tests {
         // NOTE: We attached an backing iovec, but it should be gone post-restore.
         assert!(rutabaga_resource.backing_iovecs.is_none());
     }
+
+    #[test]
+    fn export_frame_2d_damage() {
+        let resource_id = 1;
+        let resource_create_3d = ResourceCreate3D {
+            target: RUTABAGA_PIPE_TEXTURE_2D,
+            format: 1,
+            bind: RUTABAGA_PIPE_BIND_RENDER_TARGET,
+            width: 8,
+            height: 8,
+            depth: 1,
+            array_size: 1,
+            last_level: 0,
+            nr_samples: 0,
+            flags: 0,
+        };
+        let mut backing = vec![0x55u8; 8 * 8 * 4];
+
+        let mut rutabaga = new_2d();
+        rutabaga
+            .resource_create_3d(resource_id, resource_create_3d)
+            .unwrap();
+        rutabaga
+            .attach_backing(
+                resource_id,
+                vec![RutabagaIovec {
+                    base: backing.as_mut_ptr() as *mut c_void,
+                    len: backing.len(),
+                }],
+            )
+            .unwrap();
+
+        // The first export covers the whole resource.
+        let frame = rutabaga.export_frame(resource_id).unwrap();
+        assert_eq!((frame.width, frame.height, frame.stride), (8, 8, 32));
+        assert_eq!(
+            frame.damage,
+            [RutabagaRect {
+                x: 0,
+                y: 0,
+                width: 8,
+                height: 8
+            }]
+        );
+        assert!(frame.pixels.iter().all(|&p| p == 0));
+
+        rutabaga
+            .transfer_write(0, resource_id, Transfer3D::new_2d(1, 1, 2, 2))
+            .unwrap();
+        // Contained in the previous transfer, so not reported separately.
+        rutabaga
+            .transfer_write(0, resource_id, Transfer3D::new_2d(1, 1, 1, 1))
+            .unwrap();
+        let frame = rutabaga.export_frame(resource_id).unwrap();
+        assert_eq!(
+            frame.damage,
+            [RutabagaRect {
+                x: 1,
+                y: 1,
+                width: 2,
+                height: 2
+            }]
+        );
+        assert_eq!(&frame.pixels[32..36], &[0; 4]);
+        assert_eq!(&frame.pixels[36..44], &[0x55; 8]);
+
+        assert!(rutabaga
+            .export_frame(resource_id)
+            .unwrap()
+            .damage
+            .is_empty());
+
+        // Too many rectangles are merged into their bounding box.
+        for i in 0..17 {
+            rutabaga
+                .transfer_write(0, resource_id, Transfer3D::new_2d(i % 8, i / 8, 1, 1))
+                .unwrap();
+        }
+        let frame = rutabaga.export_frame(resource_id).unwrap();
+        assert_eq!(frame.damage.len(), 1);
+        assert_eq!(
+            frame.damage[0],
+            RutabagaRect {
+                x: 0,
+                y: 0,
+                width: 8,
+                height: 3
+            }
+        );
+    }
 }
@@ -637,6 +637,53 @@ impl Transfer3D {
     }
 }
 
+/// A rectangle within a 2D resource.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RutabagaRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl RutabagaRect {
+    /// Returns true if this rectangle has an area of zero.
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    /// Returns true if `other` lies entirely within this rectangle.
+    pub fn contains(&self, other: &RutabagaRect) -> bool {
+        other.x >= self.x
+            && other.y >= self.y
+            && other.x + other.width <= self.x + self.width
+            && other.y + other.height <= self.y + self.height
+    }
+
+    /// Returns the smallest rectangle containing both this rectangle and `other`.
+    pub fn bounding_box(&self, other: &RutabagaRect) -> RutabagaRect {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        RutabagaRect {
+            x,
+            y,
+            width: (self.x + self.width).max(other.x + other.width) - x,
+            height: (self.y + self.height).max(other.y + other.height) - y,
+        }
+    }
+}
+
+/// Contents of a 2D resource exported with `Rutabaga::export_frame`.
+pub struct Rutabaga2DFrame<'a> {
+    pub width: u32,
+    pub height: u32,
+    pub stride: u32,
+    /// Rectangles modified since the previous export of the resource.
+    pub damage: Vec<RutabagaRect>,
+    /// Pixels of the resource, 4 bytes per pixel.
+    pub pixels: &'a [u8],
+}
+
 /// Rutabaga channel types
 pub const RUTABAGA_CHANNEL_TYPE_WAYLAND: u32 = 0x0001;
 pub const RUTABAGA_CHANNEL_TYPE_CAMERA: u32 = 0x0002;
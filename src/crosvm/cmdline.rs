@@ -6,6 +6,7 @@ cfg_if::cfg_if! {
     if #[cfg(any(target_os = "android", target_os = "linux"))] {
         use base::RawDescriptor;
         use devices::virtio::vhost::user::device::parse_wayland_sock;
+        use devices::SwtpmParameters;
 
         use crate::crosvm::sys::config::parse_pmem_ext2_option;
         use crate::crosvm::sys::config::VfioOption;
@@ -15,6 +16,10 @@ cfg_if::cfg_if! {
 }
 
 use std::collections::BTreeMap;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use std::net::SocketAddr;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use std::ops::RangeInclusive;
 #[cfg(feature = "config-file")]
 use std::path::Path;
 use std::path::PathBuf;
@@ -31,9 +36,12 @@ use arch::VcpuAffinity;
 use argh::FromArgs;
 use base::getpid;
 use cros_async::ExecutorKind;
+use devices::varstore::Guid;
 use devices::virtio::block::DiskOption;
 #[cfg(any(feature = "video-decoder", feature = "video-encoder"))]
 use devices::virtio::device_constants::video::VideoDeviceConfig;
+#[cfg(all(any(target_os = "android", target_os = "linux"), feature = "media"))]
+use devices::virtio::media::MediaDeviceConfig;
 use devices::virtio::scsi::ScsiOption;
 #[cfg(feature = "audio")]
 use devices::virtio::snd::parameters::Parameters as SndParameters;
@@ -51,6 +59,8 @@ use devices::virtio::NetParameters;
 #[cfg(all(unix, feature = "net"))]
 use devices::virtio::NetParametersMode;
 use devices::FwCfgParameters;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use devices::NvmeOption;
 use devices::PflashParameters;
 use devices::SerialHardware;
 use devices::SerialParameters;
@@ -68,6 +78,11 @@ use serde::Deserializer;
 use serde::Serialize;
 #[cfg(feature = "gpu")]
 use serde_keyvalue::FromKeyValues;
+#[cfg(all(feature = "gpu", any(target_os = "android", target_os = "linux")))]
+use vm_control::gpu::StreamEncoding;
+#[cfg(all(feature = "gpu", any(target_os = "android", target_os = "linux")))]
+use vm_control::gpu::StreamSink;
+use vm_control::input::KeyAction;
 
 use super::config::PmemOption;
 #[cfg(feature = "gpu")]
@@ -92,7 +107,10 @@ use crate::crosvm::config::parse_mmio_address_range;
 use crate::crosvm::config::parse_pflash_parameters;
 use crate::crosvm::config::parse_serial_options;
 use crate::crosvm::config::parse_touch_device_option;
+use crate::crosvm::config::parse_variable_attributes;
 use crate::crosvm::config::parse_vhost_user_fs_option;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use crate::crosvm::config::parse_virtio_iommu_domain_range;
 use crate::crosvm::config::BatteryConfig;
 use crate::crosvm::config::CpuOptions;
 use crate::crosvm::config::DtboOption;
@@ -100,6 +118,8 @@ use crate::crosvm::config::Executable;
 use crate::crosvm::config::FileBackedMappingParameters;
 use crate::crosvm::config::HypervisorKind;
 use crate::crosvm::config::InputDeviceOption;
+#[cfg(feature = "pci-hotplug")]
+use crate::crosvm::config::InputHotplugOption;
 use crate::crosvm::config::IrqChipKind;
 use crate::crosvm::config::MemOptions;
 use crate::crosvm::config::TouchDeviceOption;
@@ -151,9 +171,12 @@ pub enum CrossPlatformCommands {
     Disk(DiskCommand),
     #[cfg(feature = "gpu")]
     Gpu(GpuCommand),
+    Input(InputCommand),
     MakeRT(MakeRTCommand),
+    Pflash(PflashCommand),
     Resume(ResumeCommand),
     Run(RunCommand),
+    Scsi(ScsiCommand),
     Stop(StopCommand),
     Suspend(SuspendCommand),
     Swap(SwapCommand),
@@ -166,6 +189,8 @@ pub enum CrossPlatformCommands {
     #[cfg(feature = "pci-hotplug")]
     VirtioNet(VirtioNetCommand),
     Snapshot(SnapshotCommand),
+    #[cfg(feature = "audio")]
+    Snd(SndCommand),
 }
 
 #[allow(clippy::large_enum_variant)]
@@ -296,6 +321,85 @@ pub struct MakeRTCommand {
     pub socket_path: String,
 }
 
+#[derive(FromArgs)]
+#[argh(subcommand)]
+pub enum PflashSubcommand {
+    Add(PflashAddCommand),
+    Delete(PflashDeleteCommand),
+    List(PflashListCommand),
+    Reset(PflashResetCommand),
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "add")]
+/// Add a UEFI variable, replacing any variable with the same name and vendor GUID
+pub struct PflashAddCommand {
+    #[argh(positional, arg_name = "IMAGE")]
+    /// pflash image path
+    pub image: PathBuf,
+    #[argh(option)]
+    /// variable name
+    pub name: String,
+    #[argh(option)]
+    /// vendor GUID of the variable; optional for the secure boot variables PK, KEK, db and dbx
+    pub guid: Option<Guid>,
+    #[argh(option, from_str_fn(parse_variable_attributes))]
+    /// variable attributes; defaults to non-volatile, boot service and runtime access, plus
+    /// time-based authenticated write access for the secure boot variables
+    pub attributes: Option<u32>,
+    #[argh(option, arg_name = "PATH")]
+    /// file holding the contents of the variable
+    pub data: Option<PathBuf>,
+    #[argh(option, arg_name = "PATH")]
+    /// DER encoded X.509 certificate to store as a signature list, e.g. to enroll secure boot
+    /// keys
+    pub x509: Option<PathBuf>,
+    #[argh(option)]
+    /// owner GUID of the certificate given with --x509
+    pub owner: Option<Guid>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "delete")]
+/// Delete a UEFI variable
+pub struct PflashDeleteCommand {
+    #[argh(positional, arg_name = "IMAGE")]
+    /// pflash image path
+    pub image: PathBuf,
+    #[argh(option)]
+    /// variable name
+    pub name: String,
+    #[argh(option)]
+    /// vendor GUID of the variable; optional for the secure boot variables PK, KEK, db and dbx
+    pub guid: Option<Guid>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "list")]
+/// List the UEFI variables
+pub struct PflashListCommand {
+    #[argh(positional, arg_name = "IMAGE")]
+    /// pflash image path
+    pub image: PathBuf,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "reset")]
+/// Delete all UEFI variables
+pub struct PflashResetCommand {
+    #[argh(positional, arg_name = "IMAGE")]
+    /// pflash image path
+    pub image: PathBuf,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "pflash")]
+/// Manage the UEFI variables of a pflash image while the VM is not running
+pub struct PflashCommand {
+    #[argh(subcommand)]
+    pub command: PflashSubcommand,
+}
+
 #[derive(FromArgs)]
 #[argh(subcommand, name = "resume")]
 /// Resumes the crosvm instance. No-op if already running. When starting crosvm with `--restore`,
@@ -573,6 +677,13 @@ pub enum GpuSubCommand {
     ListDisplays(GpuListDisplaysCommand),
     RemoveDisplays(GpuRemoveDisplaysCommand),
     SetDisplayMouseMode(GpuSetDisplayMouseModeCommand),
+    Screenshot(GpuScreenshotCommand),
+    StartRecording(GpuStartRecordingCommand),
+    StopRecording(GpuStopRecordingCommand),
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    StartStreaming(GpuStartStreamingCommand),
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    StopStreaming(GpuStopStreamingCommand),
 }
 
 #[cfg(feature = "gpu")]
@@ -628,6 +739,90 @@ pub struct GpuSetDisplayMouseModeCommand {
     pub socket_path: String,
 }
 
+#[cfg(feature = "gpu")]
+#[derive(FromArgs)]
+/// Saves the contents of a display attached to the GPU device as a PNG image.
+#[argh(subcommand, name = "screenshot")]
+pub struct GpuScreenshotCommand {
+    #[argh(option, default = "0")]
+    /// display id (default: 0)
+    pub display_id: u32,
+    #[argh(option, arg_name = "PATH")]
+    /// path of the PNG file to create
+    pub path: String,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[cfg(feature = "gpu")]
+#[derive(FromArgs)]
+/// Starts recording the frames of a display attached to the GPU device. Frames are dropped while
+/// the file cannot keep up with the display.
+#[argh(subcommand, name = "start-recording")]
+pub struct GpuStartRecordingCommand {
+    #[argh(option, default = "0")]
+    /// display id (default: 0)
+    pub display_id: u32,
+    #[argh(option, arg_name = "PATH")]
+    /// path of the raw XRGB8888 frame recording to create
+    pub path: String,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[cfg(feature = "gpu")]
+#[derive(FromArgs)]
+/// Stops recording the frames of a display attached to the GPU device.
+#[argh(subcommand, name = "stop-recording")]
+pub struct GpuStopRecordingCommand {
+    #[argh(option, default = "0")]
+    /// display id (default: 0)
+    pub display_id: u32,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[cfg(all(feature = "gpu", any(target_os = "android", target_os = "linux")))]
+#[derive(FromArgs)]
+/// Starts streaming the frames of a display attached to the GPU device. Frames are dropped while
+/// the output cannot keep up with the display.
+#[argh(subcommand, name = "start-streaming")]
+pub struct GpuStartStreamingCommand {
+    #[argh(option, default = "0")]
+    /// display id (default: 0)
+    pub display_id: u32,
+    #[argh(option, arg_name = "SINK")]
+    /// where to send the frames: a path, tcp:ADDR to connect to
+    /// ADDR, or ffmpeg:OUTPUT to encode them to H.264 with ffmpeg
+    /// into OUTPUT, a file (e.g. desktop.mp4) or a URL (e.g.
+    /// rtp://10.0.0.2:5004)
+    pub output: StreamSink,
+    #[argh(option)]
+    /// frame encoding, either damage (only the modified
+    /// rectangles) or ppm (complete images). Defaults to ppm for
+    /// ffmpeg outputs, which only support it, and damage otherwise.
+    pub encoding: Option<StreamEncoding>,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[cfg(all(feature = "gpu", any(target_os = "android", target_os = "linux")))]
+#[derive(FromArgs)]
+/// Stops streaming the frames of a display attached to the GPU device.
+#[argh(subcommand, name = "stop-streaming")]
+pub struct GpuStopStreamingCommand {
+    #[argh(option, default = "0")]
+    /// display id (default: 0)
+    pub display_id: u32,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
 #[derive(FromArgs)]
 #[argh(subcommand)]
 pub enum UsbSubCommand {
@@ -761,6 +956,322 @@ pub enum SnapshotSubCommands {
     Take(SnapshotTakeCommand),
 }
 
+#[derive(FromArgs)]
+#[argh(subcommand)]
+pub enum ScsiSubcommand {
+    Eject(ScsiEjectCommand),
+    Insert(ScsiInsertCommand),
+}
+
+#[derive(FromArgs)]
+/// Eject the medium of a SCSI CD-ROM drive
+#[argh(subcommand, name = "eject")]
+pub struct ScsiEjectCommand {
+    #[argh(switch)]
+    /// eject even if the guest prevents the medium removal
+    pub force: bool,
+    #[argh(positional, arg_name = "TARGET")]
+    /// target id of the drive
+    pub target: u8,
+    #[argh(positional, arg_name = "LUN")]
+    /// logical unit number of the drive
+    pub lun: u16,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// Insert a disk image into a SCSI CD-ROM drive, replacing its medium
+#[argh(subcommand, name = "insert")]
+pub struct ScsiInsertCommand {
+    #[argh(positional, arg_name = "TARGET")]
+    /// target id of the drive
+    pub target: u8,
+    #[argh(positional, arg_name = "LUN")]
+    /// logical unit number of the drive
+    pub lun: u16,
+    #[argh(positional, arg_name = "PATH")]
+    /// path to the disk image
+    pub path: String,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "scsi")]
+/// Change the media of virtio-scsi CD-ROM drives
+pub struct ScsiCommand {
+    #[argh(subcommand)]
+    pub command: ScsiSubcommand,
+}
+
+#[cfg(feature = "audio")]
+#[derive(FromArgs)]
+#[argh(subcommand)]
+pub enum SndSubcommand {
+    List(SndListCommand),
+    Plug(SndPlugCommand),
+    Set(SndSetCommand),
+    Unplug(SndUnplugCommand),
+}
+
+#[cfg(feature = "audio")]
+#[derive(FromArgs)]
+/// List the control elements of a sound card and their values
+#[argh(subcommand, name = "list")]
+pub struct SndListCommand {
+    #[argh(positional, arg_name = "CARD_INDEX")]
+    /// sound card index
+    pub card_index: usize,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[cfg(feature = "audio")]
+#[derive(FromArgs)]
+/// Report a jack of a sound card as connected
+#[argh(subcommand, name = "plug")]
+pub struct SndPlugCommand {
+    #[argh(positional, arg_name = "CARD_INDEX")]
+    /// sound card index
+    pub card_index: usize,
+    #[argh(positional, arg_name = "JACK_ID")]
+    /// jack id
+    pub jack_id: u32,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[cfg(feature = "audio")]
+#[derive(FromArgs)]
+/// Set the values of a control element of a sound card
+#[argh(subcommand, name = "set")]
+pub struct SndSetCommand {
+    #[argh(positional, arg_name = "CARD_INDEX")]
+    /// sound card index
+    pub card_index: usize,
+    #[argh(positional, arg_name = "CONTROL_ID")]
+    /// control element id, as printed by `crosvm snd list`
+    pub control_id: u32,
+    #[argh(positional, arg_name = "VALUES")]
+    /// comma separated values, one per channel
+    pub values: String,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[cfg(feature = "audio")]
+#[derive(FromArgs)]
+/// Report a jack of a sound card as disconnected
+#[argh(subcommand, name = "unplug")]
+pub struct SndUnplugCommand {
+    #[argh(positional, arg_name = "CARD_INDEX")]
+    /// sound card index
+    pub card_index: usize,
+    #[argh(positional, arg_name = "JACK_ID")]
+    /// jack id
+    pub jack_id: u32,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[cfg(feature = "audio")]
+#[derive(FromArgs)]
+#[argh(subcommand, name = "snd")]
+/// Manage the jacks and control elements of virtio-snd devices
+pub struct SndCommand {
+    #[argh(subcommand)]
+    pub command: SndSubcommand,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+pub enum InputSubcommand {
+    Key(InputKeyCommand),
+    Move(InputMoveCommand),
+    Record(InputRecordCommand),
+    Replay(InputReplayCommand),
+    StopRecording(InputStopRecordingCommand),
+    Swipe(InputSwipeCommand),
+    Switch(InputSwitchCommand),
+    Tap(InputTapCommand),
+    Text(InputTextCommand),
+}
+
+#[derive(FromArgs)]
+/// Press, release or tap a key combination such as `ctrl+alt+t` or `BTN_LEFT`
+#[argh(subcommand, name = "key")]
+pub struct InputKeyCommand {
+    #[argh(option, default = "KeyAction::Tap")]
+    /// press, release or tap (default: tap)
+    pub action: KeyAction,
+    #[argh(positional, arg_name = "DEVICE_INDEX")]
+    /// input device index
+    pub device_index: usize,
+    #[argh(positional, arg_name = "KEYS")]
+    /// '+' separated key names, e.g. KEY_A, enter, ctrl+c
+    pub keys: String,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// Move a pointer by (X, Y), or to (X, Y) for absolute devices
+#[argh(subcommand, name = "move")]
+pub struct InputMoveCommand {
+    #[argh(option)]
+    /// horizontal position or motion
+    pub x: i32,
+    #[argh(option)]
+    /// vertical position or motion
+    pub y: i32,
+    #[argh(switch)]
+    /// send absolute coordinates instead of relative motion
+    pub absolute: bool,
+    #[argh(positional, arg_name = "DEVICE_INDEX")]
+    /// input device index
+    pub device_index: usize,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// Start recording the events sent to the guest by an input device into a trace
+#[argh(subcommand, name = "record")]
+pub struct InputRecordCommand {
+    #[argh(positional, arg_name = "DEVICE_INDEX")]
+    /// input device index
+    pub device_index: usize,
+    #[argh(positional, arg_name = "PATH")]
+    /// trace file to create
+    pub path: String,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// Send the events of a trace to an input device, keeping their timing
+#[argh(subcommand, name = "replay")]
+pub struct InputReplayCommand {
+    #[argh(positional, arg_name = "DEVICE_INDEX")]
+    /// input device index
+    pub device_index: usize,
+    #[argh(positional, arg_name = "PATH")]
+    /// trace file written by `crosvm input record`
+    pub path: String,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// Stop recording the events of an input device
+#[argh(subcommand, name = "stop-recording")]
+pub struct InputStopRecordingCommand {
+    #[argh(positional, arg_name = "DEVICE_INDEX")]
+    /// input device index
+    pub device_index: usize,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// Swipe a finger on a touch device
+#[argh(subcommand, name = "swipe")]
+pub struct InputSwipeCommand {
+    #[argh(option, arg_name = "X,Y")]
+    /// start position
+    pub from: String,
+    #[argh(option, arg_name = "X,Y")]
+    /// end position
+    pub to: String,
+    #[argh(option, default = "300")]
+    /// duration of the swipe in milliseconds (default: 300)
+    pub duration_ms: u64,
+    #[argh(option, default = "10")]
+    /// number of moves between the start and end positions (default: 10)
+    pub steps: u32,
+    #[argh(positional, arg_name = "DEVICE_INDEX")]
+    /// input device index
+    pub device_index: usize,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// Turn a switch such as `lid` or `SW_TABLET_MODE` on or off
+#[argh(subcommand, name = "switch")]
+pub struct InputSwitchCommand {
+    #[argh(switch)]
+    /// turn the switch off instead of on
+    pub off: bool,
+    #[argh(positional, arg_name = "DEVICE_INDEX")]
+    /// input device index
+    pub device_index: usize,
+    #[argh(positional, arg_name = "SWITCH")]
+    /// switch name
+    pub switch: String,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// Tap a touch device at (X, Y)
+#[argh(subcommand, name = "tap")]
+pub struct InputTapCommand {
+    #[argh(option)]
+    /// horizontal position
+    pub x: i32,
+    #[argh(option)]
+    /// vertical position
+    pub y: i32,
+    #[argh(positional, arg_name = "DEVICE_INDEX")]
+    /// input device index
+    pub device_index: usize,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// Type a string on a keyboard device
+#[argh(subcommand, name = "text")]
+pub struct InputTextCommand {
+    #[argh(option, arg_name = "PATH")]
+    /// file with lines of `CHAR KEYS` added to the US keymap
+    pub keymap: Option<String>,
+    #[argh(positional, arg_name = "DEVICE_INDEX")]
+    /// input device index
+    pub device_index: usize,
+    #[argh(positional, arg_name = "TEXT")]
+    /// text to type
+    pub text: String,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "input")]
+/// Inject events into or record events of virtio-input devices, chosen by their index among the
+/// --input options
+pub struct InputCommand {
+    #[argh(subcommand)]
+    pub command: InputSubcommand,
+}
+
 /// Container for GpuParameters that have been fixed after parsing using serde.
 ///
 /// This deserializes as a regular `GpuParameters` and applies validation.
@@ -1426,6 +1937,21 @@ pub struct RunCommand {
     ///     vertical-dpi=INT - The vertical DPI of the display
     ///        (default: 320)
     ///        Deprecated - use `dpi` instead.
+    ///     modes=[[width=INT,height=INT,refresh-rate=INT,
+    ///        blanking=[INT,INT],front-porch=[INT,INT],
+    ///        sync-width=[INT,INT]],...] - Up to 9 modes reported
+    ///        in the EDID, the first one being preferred. Only
+    ///        width and height are required.
+    ///        (default: the window size at `refresh-rate`)
+    ///     physical-size-mm=[INT,INT] - The physical width and
+    ///        height of the display in millimeters (default:
+    ///        derived from `dpi`)
+    ///     hdr=[max-luminance=INT,max-frame-average-luminance=INT,
+    ///        min-luminance=INT] - Report HDR support in the EDID
+    ///        with these luminances in cd/m² (thousandths of cd/m²
+    ///        for min-luminance)
+    ///     edid=PATH - Report the EDID read from PATH instead of
+    ///        generating one
     pub gpu: Vec<FixedGpuParameters>,
 
     #[cfg(all(unix, feature = "gpu"))]
@@ -1516,7 +2042,7 @@ pub struct RunCommand {
     /// virtio-input device
     /// TYPE is an input device type, and OPTIONS are key=value
     /// pairs specific to the device type:
-    ///     evdev[path=PATH]
+    ///     evdev[path=PATH,grab-hotkey=[KEY,...]]
     ///     keyboard[path=PATH]
     ///     mouse[path=PATH]
     ///     multi-touch[path=PATH,width=W,height=H,name=N]
@@ -1529,6 +2055,22 @@ pub struct RunCommand {
     /// information.
     pub input: Vec<InputDeviceOption>,
 
+    #[cfg(feature = "pci-hotplug")]
+    #[argh(option, arg_name = "MATCH")]
+    #[serde(default)]
+    #[merge(strategy = append)]
+    /// pass through host event devices as virtio-input devices
+    /// when they are plugged in, and remove them when they are
+    /// unplugged. Requires --pci-hotplug-slots.
+    /// Possible key values:
+    ///     vendor=ID - vendor ID of the device
+    ///     product=ID - product ID of the device
+    ///     name=NAME - name of the device
+    ///     grab-hotkey=[KEY,...] - key codes that hand the
+    ///        device back to the host, and to the guest again.
+    /// At least one of vendor, product or name must be given.
+    pub input_hotplug: Vec<InputHotplugOption>,
+
     #[argh(option, arg_name = "kernel|split|userspace")]
     #[merge(strategy = overwrite_option)]
     /// type of interrupt controller emulation. "split" is only available for x86 KVM.
@@ -1596,6 +2138,20 @@ pub struct RunCommand {
     /// MAC address for VM
     pub mac_address: Option<net_util::MacAddress>,
 
+    #[cfg(all(any(target_os = "android", target_os = "linux"), feature = "media"))]
+    #[argh(option, arg_name = "type=TYPE[,device=PATH]")]
+    #[serde(default)]
+    #[merge(strategy = append)]
+    /// (EXPERIMENTAL) add a virtio-media device
+    /// Possible key values:
+    ///     type=(proxy,test-pattern) - backend of the device.
+    ///        proxy forwards the V4L2 ioctls to a host device
+    ///        node, test-pattern emulates a camera producing
+    ///        color bars.
+    ///     device=PATH - path to the host V4L2 device node, e.g.
+    ///        /dev/video0. Required by the proxy backend.
+    pub media: Vec<MediaDeviceConfig>,
+
     #[argh(option, short = 'm', arg_name = "N")]
     #[merge(strategy = overwrite_option)]
     /// memory parameters.
@@ -1736,6 +2292,25 @@ pub struct RunCommand {
     /// don't use usb devices in the guest
     pub no_usb: Option<bool>,
 
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    #[argh(option, arg_name = "PATH[,key=value[,key=value[,...]]]")]
+    #[serde(default)]
+    #[merge(strategy = append)]
+    /// (EXPERIMENTAL) add a namespace to the emulated NVMe
+    /// controller. Can be given more than once; namespaces are
+    /// numbered from 1 in the order they are given.
+    /// Valid keys:
+    ///     path=PATH - Path to the disk image. Can be specified
+    ///         without the key as the first argument.
+    ///     ro=BOOL - Whether the namespace is write protected.
+    ///         (default: false)
+    ///     block-size=BYTES - Set the reported logical block
+    ///         size, a power of two from 512 to 4096.
+    ///         (default: 512)
+    ///     sparse=BOOL - Whether deallocated blocks are
+    ///         discarded from the disk image. (default: true)
+    nvme: Vec<NvmeOption>,
+
     #[cfg(target_arch = "x86_64")]
     #[argh(option, arg_name = "OEM_STRING")]
     #[serde(skip)] // Deprecated - use `smbios` instead.
@@ -2063,6 +2638,18 @@ pub struct RunCommand {
     ///         as the root filesystem. This will add the required
     ///         parameters to the kernel command-line. Can only be
     ///         specified once. (default: false)
+    ///     target=NUM - The target id of the device.
+    ///         (default: the index of the device)
+    ///     lun=NUM - The logical unit number of the device in its
+    ///         target. (default: 0)
+    ///     cdrom=BOOL - Whether the device is a CD-ROM drive whose
+    ///         medium can be changed with `crosvm scsi`. Implies
+    ///         ro. (default: false)
+    ///     pr-file=PATH - File keeping the persistent reservations
+    ///         of the device, shared with the other VMs using the
+    ///         image. (default: reservations are kept in memory)
+    ///     pr-initiator=NAME - Name of this VM in pr-file, unique
+    ///         among the VMs sharing it. Required with pr-file.
     // TODO(b/300580119): Add O_DIRECT and sparse file support.
     scsi_block: Vec<ScsiOption>,
 
@@ -2082,7 +2669,7 @@ pub struct RunCommand {
 
     #[argh(
         option,
-        arg_name = "type=TYPE,[hardware=HW,name=NAME,num=NUM,path=PATH,input=PATH,console,earlycon,stdin,pci-address=ADDR]",
+        arg_name = "type=TYPE,[hardware=HW,name=NAME,num=NUM,path=PATH,input=PATH,console,earlycon,stdin,pci-address=ADDR,address=ADDR,ring-size=KIB]",
         from_str_fn(parse_serial_options)
     )]
     #[serde(default)]
@@ -2090,12 +2677,20 @@ pub struct RunCommand {
     /// comma separated key=value pairs for setting up serial
     /// devices. Can be given more than once.
     /// Possible key values:
-    ///     type=(stdout,syslog,sink,file) - Where to route the
-    ///        serial device
+    ///     type=(stdout,syslog,sink,file,unix-server,tcp-server,
+    ///           pty,ring-buffer) - Where to route the
+    ///        serial device. unix-server and tcp-server listen
+    ///        for clients, the last one to connect being
+    ///        attached. pty allocates a pseudo-terminal and logs
+    ///        its path. ring-buffer keeps the last output in a
+    ///        file, printed by `crosvm serial dump PATH`.
     ///     hardware=(serial,virtio-console,debugcon,
     ///               legacy-virtio-console) - Which type of
     ///        serial hardware to emulate. Defaults to 8250 UART
-    ///        (serial).
+    ///        (serial). A virtio-console device has a single
+    ///        port, so ports cannot be added or removed at
+    ///        runtime; use the multiport `crosvm device console`
+    ///        vhost-user device for that.
     ///     name=NAME - Console Port Name, used for virtio-console
     ///        as a tag for identification within the guest.
     ///     num=(1,2,3,4) - Serial Device Number. If not provided,
@@ -2104,7 +2699,13 @@ pub struct RunCommand {
     ///        listen to. Defaults to 0x402, which is what OVMF
     ///        expects.
     ///     path=PATH - The path to the file to write to when
-    ///        type=file
+    ///        type=file or type=ring-buffer, of the socket to
+    ///        listen on when type=unix-server, or of a symlink to
+    ///        the pseudo-terminal when type=pty
+    ///     address=ADDR - The address to listen on when
+    ///        type=tcp-server, e.g. "127.0.0.1:4555"
+    ///     ring-size=KIB - How much output to keep when
+    ///        type=ring-buffer, in KiB. Defaults to 64.
     ///     input=PATH - The path to the file to read from when not
     ///        stdin
     ///     console - Use this serial device as the guest console.
@@ -2195,6 +2796,39 @@ pub struct RunCommand {
     ///         Error when FS_IOC_SETPATHXATTR ioctl is called
     ///         in the device if current dyamic permission path is
     ///         lager or equal to this value.
+    ///     uid_map=[GUEST HOST COUNT,...] - Translates the uids of
+    ///        guest requests to host uids, and the owners of host
+    ///        files back to guest uids, including in POSIX ACLs.
+    ///        Unlike uidmap, this is done by the fs device itself
+    ///        on every request. Guest uids outside of the map act
+    ///        as squash_uid, and unmapped host uids appear as
+    ///        65534. (default: identity)
+    ///     gid_map=[GUEST HOST COUNT,...] - Same as uid_map for
+    ///        gids. (default: identity)
+    ///     id_squash=(none|root|all) - Maps the guest root user
+    ///        and group, or every guest user and group, to
+    ///        squash_uid and squash_gid. Squashed root does not
+    ///        bypass permission checks. (default: none)
+    ///     squash_uid=UID - Host uid of squashed guest users.
+    ///        (default: 65534)
+    ///     squash_gid=GID - Host gid of squashed guest groups.
+    ///        (default: 65534)
+    ///     overlay_lower=[DIR,...] - Read-only host directories
+    ///        to layer the shared directory over, highest first.
+    ///        The guest sees the merged directories and its
+    ///        changes are only written to the shared directory.
+    ///        Incompatible with dax, ascii_casefold and id
+    ///        translation. (default: none)
+    ///     archive=BOOL - Serve the contents of the tar archive,
+    ///        squashfs image or EROFS image at the shared path
+    ///        read-only. Incompatible with dax and overlay_lower.
+    ///        (default: false)
+    ///     io_uring=BOOL - Submit large reads and writes through
+    ///        io_uring, copying file data directly between the
+    ///        host page cache and guest memory while later
+    ///        requests are being handled. Falls back to
+    ///        regular reads and writes if io_uring is not
+    ///        available. (default: false)
     ///     Options uid and gid are useful when the crosvm process
     ///     has no CAP_SETGID/CAP_SETUID but an identity mapping of
     ///     the current user/group between the VM and the host is
@@ -2309,6 +2943,20 @@ pub struct RunCommand {
     /// path to a socket from where to read switch input events and write status updates to
     pub switches: Vec<PathBuf>,
 
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    #[argh(option, arg_name = "socket=PATH[,interface=virtio|crb]")]
+    #[serde(skip)] // TODO(b/255223604)
+    #[merge(strategy = overwrite_option)]
+    /// add a TPM 2.0 device backed by an swtpm process. swtpm must be started with
+    /// `--ctrl type=unixio,path=PATH --terminate`; its `--tpmstate` directory holds the TPM
+    /// NVRAM.
+    /// Possible key values:
+    ///     socket=PATH - path of the swtpm control channel socket.
+    ///     interface=(virtio|crb) - expose the TPM as a virtio-tpm
+    ///         device (default) or as a TPM CRB MMIO device that
+    ///         firmware can use for measured boot (x86_64 only).
+    pub swtpm: Option<SwtpmParameters>,
+
     #[argh(option, arg_name = "TAG")]
     #[serde(skip)] // Deprecated - use `CrosvmCmdlineArgs::syslog_tag` instead.
     #[merge(strategy = overwrite_option)]
@@ -2380,7 +3028,7 @@ pub struct RunCommand {
     #[cfg(any(target_os = "android", target_os = "linux"))]
     #[argh(
         option,
-        arg_name = "PATH[,guest-address=<BUS:DEVICE.FUNCTION>][,iommu=viommu|coiommu|pkvm-iommu|off][,dt-symbol=<SYMBOL>]"
+        arg_name = "PATH[,guest-address=<BUS:DEVICE.FUNCTION>][,iommu=viommu|coiommu|pkvm-iommu|off][,dt-symbol=<SYMBOL>][,iommufd=true|false]"
     )]
     #[serde(default)]
     #[merge(strategy = append)]
@@ -2391,9 +3039,16 @@ pub struct RunCommand {
     ///        address that mirrors its address in the host.
     ///        Only valid for PCI devices.
     ///     iommu=viommu|coiommu|pkvm-iommu|off - indicates which type of IOMMU
-    ///        to use for this device.
+    ///        to use for this device. Translation faults of
+    ///        devices behind viommu are only reported to the
+    ///        guest with iommufd=true.
     ///     dt-symbol=<SYMBOL> - the symbol that labels the device tree
     ///        node in the device tree overlay file.
+    ///     iommufd=true|false - open the device through its VFIO
+    ///        cdev and bind it to /dev/iommu instead of using its
+    ///        VFIO group, and report its I/O page faults to the
+    ///        guest if the host IOMMU supports them. Requires
+    ///        iommu=viommu. (default: false)
     pub vfio: Vec<VfioOption>,
 
     #[cfg(any(target_os = "android", target_os = "linux"))]
@@ -2529,7 +3184,7 @@ pub struct RunCommand {
     #[serde(default)]
     #[merge(strategy = append)]
     /// (EXPERIMENTAL) enable virtio-video decoder device
-    /// Possible backend values: libvda, ffmpeg, vaapi
+    /// Possible backend values: libvda, ffmpeg, swdec, vaapi
     pub video_decoder: Vec<VideoDeviceConfig>,
 
     #[cfg(feature = "video-encoder")]
@@ -2550,6 +3205,18 @@ pub struct RunCommand {
     /// enable a virtual cpu freq device
     pub virt_cpufreq: Option<bool>,
 
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    #[argh(
+        option,
+        arg_name = "START-END",
+        from_str_fn(parse_virtio_iommu_domain_range)
+    )]
+    #[serde(skip)]
+    #[merge(strategy = overwrite_option)]
+    /// range of domain IDs the virtio-iommu driver may use,
+    /// inclusive. Defaults to all 32-bit IDs.
+    pub virtio_iommu_domain_range: Option<RangeInclusive<u32>>,
+
     #[cfg(feature = "audio")]
     #[argh(
         option,
@@ -2563,8 +3230,8 @@ pub struct RunCommand {
     /// Possible key values:
     ///     capture=(false,true) - Disable/enable audio capture.
     ///         Default is false.
-    ///     backend=(null,file,[cras]) - Which backend to use for
-    ///         virtio-snd.
+    ///     backend=(null,file,[cras],[alsa],[pipewire]) - Which
+    ///         backend to use for virtio-snd.
     ///     client_type=(crosvm,arcvm,borealis) - Set specific
     ///         client type for cras backend. Default is crosvm.
     ///     socket_type=(legacy,unified) Set specific socket type
@@ -2573,6 +3240,13 @@ pub struct RunCommand {
     ///         for file backend.
     ///     playback_size=INT - Set size of the output streams
     ///         from file backend.
+    ///     capture_path=PATH - WAV or raw PCM file played in a
+    ///         loop into the input streams of the file backend. Raw
+    ///         files must match the format chosen by the guest.
+    ///     jacks=(false,true) - Expose a jack for each PCM device,
+    ///         which can be plugged and unplugged with `crosvm snd`.
+    ///     controls=(false,true) - Expose volume and mute control
+    ///         elements for each PCM device.
     ///     num_output_devices=INT - Set number of output PCM
     ///         devices.
     ///     num_input_devices=INT - Set number of input PCM devices.
@@ -2580,8 +3254,26 @@ pub struct RunCommand {
     ///         streams per device.
     ///     num_input_streams=INT - Set number of input PCM streams
     ///         per device.
+    ///     output_device_config=[[device=STR],...] - Per output PCM
+    ///         device settings. `device` is the ALSA PCM name for
+    ///         the alsa backend or the target node for the pipewire
+    ///         backend.
+    ///     input_device_config=[[device=STR],...] - Same as
+    ///         output_device_config, for input PCM devices.
     pub virtio_snd: Vec<SndParameters>,
 
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    #[argh(option, arg_name = "ADDR")]
+    #[serde(skip)]
+    #[merge(strategy = overwrite_option)]
+    /// serve the GPU display over VNC at the given address
+    /// (e.g. 127.0.0.1:5900). Scanout N is served on the given
+    /// port plus N. Keyboard and pointer input is forwarded to the
+    /// devices added by --display-window-keyboard and
+    /// --display-window-mouse. Clients are not authenticated, so
+    /// only bind to addresses reachable by trusted clients.
+    pub vnc_display: Option<SocketAddr>,
+
     #[argh(option, arg_name = "cid=CID[,device=VHOST_DEVICE]")]
     #[serde(default)]
     #[merge(strategy = overwrite_option)]
@@ -2886,6 +3578,11 @@ impl TryFrom<RunCommand> for super::config::Config {
 
         cfg.scsis = cmd.scsi_block;
 
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        {
+            cfg.nvmes = cmd.nvme;
+        }
+
         cfg.pmems = cmd.pmem;
 
         if !cmd.pmem_device.is_empty() || !cmd.rw_pmem_device.is_empty() {
@@ -2918,12 +3615,31 @@ impl TryFrom<RunCommand> for super::config::Config {
             .filter(|(_, d)| d.root)
             .map(|(i, d)| (format_disk_letter("/dev/vd", i), d.read_only));
 
-        let virtio_scsi_root_devs = cfg
+        if cfg.scsis.iter().any(|s| s.root && s.cdrom) {
+            return Err("a scsi CD-ROM drive cannot be the root device".to_string());
+        }
+        if cfg
+            .scsis
+            .iter()
+            .any(|s| s.pr_file.is_some() && s.pr_initiator.is_none())
+        {
+            return Err("scsi pr-file requires pr-initiator".to_string());
+        }
+        // The guest names the scsi disks in the order of their addresses, skipping the CD-ROM
+        // drives which are named /dev/srN.
+        let mut scsi_disks: Vec<_> = cfg
             .scsis
             .iter()
             .enumerate()
-            .filter(|(_, s)| s.root)
-            .map(|(i, s)| (format_disk_letter("/dev/sd", i), s.read_only));
+            .filter(|(_, s)| !s.cdrom)
+            .map(|(i, s)| ((s.target.unwrap_or(i as u8), s.lun), s))
+            .collect();
+        scsi_disks.sort_by_key(|(address, _)| *address);
+        let virtio_scsi_root_devs = scsi_disks
+            .into_iter()
+            .enumerate()
+            .filter(|(_, (_, s))| s.root)
+            .map(|(i, (_, s))| (format_disk_letter("/dev/sd", i), s.read_only));
 
         let virtio_pmem_root_devs = cfg
             .pmems
@@ -3008,6 +3724,7 @@ impl TryFrom<RunCommand> for super::config::Config {
 
         #[cfg(any(target_os = "android", target_os = "linux"))]
         {
+            cfg.vnc_display = cmd.vnc_display;
             cfg.x_display = cmd.x_display;
         }
 
@@ -3114,6 +3831,11 @@ impl TryFrom<RunCommand> for super::config::Config {
             cfg.vtpm_proxy = cmd.vtpm_proxy.unwrap_or_default();
         }
 
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        {
+            cfg.swtpm = cmd.swtpm;
+        }
+
         cfg.virtio_input = cmd.input;
 
         if !cmd.single_touch.is_empty() {
@@ -3199,11 +3921,11 @@ impl TryFrom<RunCommand> for super::config::Config {
 
         if !cmd.evdev.is_empty() {
             log::warn!("`--evdev` is deprecated; please use `--input evdev[...]`");
-            cfg.virtio_input.extend(
-                cmd.evdev
-                    .into_iter()
-                    .map(|path| InputDeviceOption::Evdev { path }),
-            );
+            cfg.virtio_input
+                .extend(cmd.evdev.into_iter().map(|path| InputDeviceOption::Evdev {
+                    path,
+                    grab_hotkey: None,
+                }));
         }
 
         cfg.irq_chip = cmd.irqchip;
@@ -3235,6 +3957,10 @@ impl TryFrom<RunCommand> for super::config::Config {
         {
             cfg.video_dec = cmd.video_decoder;
         }
+        #[cfg(all(any(target_os = "android", target_os = "linux"), feature = "media"))]
+        {
+            cfg.media = cmd.media;
+        }
         #[cfg(feature = "video-encoder")]
         {
             cfg.video_enc = cmd.video_encoder;
@@ -3531,6 +4257,7 @@ impl TryFrom<RunCommand> for super::config::Config {
         #[cfg(feature = "pci-hotplug")]
         {
             cfg.pci_hotplug_slots = cmd.pci_hotplug_slots;
+            cfg.input_hotplug = cmd.input_hotplug;
         }
 
         cfg.vhost_user = cmd.vhost_user;
@@ -3607,6 +4334,7 @@ impl TryFrom<RunCommand> for super::config::Config {
             cfg.vfio.extend(cmd.vfio);
             cfg.vfio.extend(cmd.vfio_platform);
             cfg.vfio_isolate_hotplug = cmd.vfio_isolate_hotplug.unwrap_or_default();
+            cfg.virtio_iommu_domain_range = cmd.virtio_iommu_domain_range;
         }
 
         cfg.device_tree_overlay = cmd.device_tree_overlay;
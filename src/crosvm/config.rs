@@ -7,6 +7,9 @@ use std::arch::x86_64::__cpuid;
 #[cfg(target_arch = "x86_64")]
 use std::arch::x86_64::__cpuid_count;
 use std::collections::BTreeMap;
+use std::net::SocketAddr;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use std::ops::RangeInclusive;
 use std::path::PathBuf;
 use std::str::FromStr;
 use std::time::Duration;
@@ -28,6 +31,8 @@ use devices::virtio::block::DiskOption;
 use devices::virtio::device_constants::video::VideoDeviceConfig;
 #[cfg(feature = "gpu")]
 use devices::virtio::gpu::GpuParameters;
+#[cfg(all(any(target_os = "android", target_os = "linux"), feature = "media"))]
+use devices::virtio::media::MediaDeviceConfig;
 use devices::virtio::scsi::ScsiOption;
 #[cfg(feature = "audio")]
 use devices::virtio::snd::parameters::Parameters as SndParameters;
@@ -46,6 +51,8 @@ use devices::virtio::DeviceType;
 #[cfg(feature = "net")]
 use devices::virtio::NetParameters;
 use devices::FwCfgParameters;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use devices::NvmeOption;
 use devices::PciAddress;
 use devices::PflashParameters;
 use devices::StubPciParameters;
@@ -70,6 +77,8 @@ use crate::crosvm::sys::config::SharedDir;
 
 cfg_if::cfg_if! {
     if #[cfg(any(target_os = "android", target_os = "linux"))] {
+        use devices::SwtpmParameters;
+
         #[cfg(feature = "gpu")]
         use crate::crosvm::sys::GpuRenderServerParameters;
 
@@ -335,8 +344,10 @@ pub fn parse_touch_device_option(s: &str) -> Result<TouchDeviceOption, String> {
 #[derive(Serialize, Deserialize, Debug, FromKeyValues, Eq, PartialEq)]
 #[serde(deny_unknown_fields, rename_all = "kebab-case")]
 pub enum InputDeviceOption {
+    #[serde(rename_all = "kebab-case")]
     Evdev {
         path: PathBuf,
+        grab_hotkey: Option<Vec<u16>>,
     },
     Keyboard {
         path: PathBuf,
@@ -376,6 +387,20 @@ pub enum InputDeviceOption {
     },
 }
 
+/// Match for host event devices to pass through to the guest as they are plugged in.
+#[derive(Clone, Serialize, Deserialize, Debug, FromKeyValues, Eq, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct InputHotplugOption {
+    /// USB or bluetooth vendor ID of the device.
+    pub vendor: Option<u16>,
+    /// Product ID of the device.
+    pub product: Option<u16>,
+    /// Name reported by the device driver.
+    pub name: Option<String>,
+    /// Key codes that hand the device back to the host, or to the guest again.
+    pub grab_hotkey: Option<Vec<u16>>,
+}
+
 #[derive(Debug, Serialize, Deserialize, FromKeyValues)]
 #[serde(deny_unknown_fields)]
 pub struct FileBackedMappingParameters {
@@ -405,6 +430,41 @@ fn parse_hex_or_decimal(maybe_hex_string: &str) -> Result<u64, String> {
     .map_err(|e| format!("invalid numeric value {}: {}", maybe_hex_string, e))
 }
 
+pub fn parse_variable_attributes(s: &str) -> Result<u32, String> {
+    let attributes = parse_hex_or_decimal(s)?;
+    u32::try_from(attributes).map_err(|_| format!("invalid variable attributes {}", s))
+}
+
+/// Parses the comma separated values of a virtio-snd control element, one per channel.
+pub fn parse_snd_control_values(s: &str) -> Result<Vec<i32>, String> {
+    s.split(',')
+        .map(|v| {
+            v.trim()
+                .parse()
+                .map_err(|e| format!("invalid control value {}: {}", v, e))
+        })
+        .collect()
+}
+
+/// Parses an inclusive range of virtio-iommu domain IDs, as "START-END".
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub fn parse_virtio_iommu_domain_range(s: &str) -> Result<RangeInclusive<u32>, String> {
+    let (start, end) = s
+        .split_once('-')
+        .ok_or_else(|| invalid_value_err(s, "expected START-END"))?;
+    let parse = |v: &str| -> Result<u32, String> {
+        parse_hex_or_decimal(v)
+            .ok()
+            .and_then(|v| u32::try_from(v).ok())
+            .ok_or_else(|| invalid_value_err(v, "expected u32 value"))
+    };
+    let range = parse(start)?..=parse(end)?;
+    if range.is_empty() {
+        return Err(invalid_value_err(s, "START must not be greater than END"));
+    }
+    Ok(range)
+}
+
 pub fn parse_mmio_address_range(s: &str) -> Result<Vec<AddressRange>, String> {
     s.split(",")
         .map(|s| {
@@ -802,6 +862,8 @@ pub struct Config {
     pub initrd_path: Option<PathBuf>,
     #[cfg(all(windows, feature = "gpu"))]
     pub input_event_split_config: Option<InputEventSplitConfig>,
+    #[cfg(feature = "pci-hotplug")]
+    pub input_hotplug: Vec<InputHotplugOption>,
     pub irq_chip: Option<IrqChipKind>,
     pub itmt: bool,
     pub jail_config: Option<JailConfig>,
@@ -813,6 +875,8 @@ pub struct Config {
     pub log_file: Option<String>,
     #[cfg(windows)]
     pub logs_directory: Option<String>,
+    #[cfg(all(any(target_os = "android", target_os = "linux"), feature = "media"))]
+    pub media: Vec<MediaDeviceConfig>,
     pub memory: Option<u64>,
     pub memory_file: Option<PathBuf>,
     pub mmio_address_ranges: Vec<AddressRange>,
@@ -825,6 +889,8 @@ pub struct Config {
     pub no_i8042: bool,
     pub no_rtc: bool,
     pub no_smt: bool,
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    pub nvmes: Vec<NvmeOption>,
     pub params: Vec<String>,
     #[cfg(feature = "pci-hotplug")]
     pub pci_hotplug_slots: Option<u8>,
@@ -882,6 +948,8 @@ pub struct Config {
     pub suspended: bool,
     pub swap_dir: Option<PathBuf>,
     pub swiotlb: Option<u64>,
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    pub swtpm: Option<SwtpmParameters>,
     #[cfg(target_os = "android")]
     pub task_profiles: Vec<String>,
     #[cfg(any(target_os = "android", target_os = "linux"))]
@@ -914,9 +982,12 @@ pub struct Config {
     ))]
     pub virt_cpufreq: bool,
     pub virtio_input: Vec<InputDeviceOption>,
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    pub virtio_iommu_domain_range: Option<RangeInclusive<u32>>,
     #[cfg(feature = "audio")]
     #[serde(skip)]
     pub virtio_snds: Vec<SndParameters>,
+    pub vnc_display: Option<SocketAddr>,
     pub vsock: Option<VsockConfig>,
     #[cfg(feature = "vtpm")]
     pub vtpm_proxy: bool,
@@ -1022,6 +1093,8 @@ impl Default for Config {
             initrd_path: None,
             #[cfg(all(windows, feature = "gpu"))]
             input_event_split_config: None,
+            #[cfg(feature = "pci-hotplug")]
+            input_hotplug: Vec::new(),
             irq_chip: None,
             itmt: false,
             jail_config: if !cfg!(feature = "default-no-sandbox") {
@@ -1039,6 +1112,8 @@ impl Default for Config {
             logs_directory: None,
             #[cfg(any(target_os = "android", target_os = "linux"))]
             boost_uclamp: false,
+            #[cfg(all(any(target_os = "android", target_os = "linux"), feature = "media"))]
+            media: Vec::new(),
             memory: None,
             memory_file: None,
             mmio_address_ranges: Vec::new(),
@@ -1051,6 +1126,8 @@ impl Default for Config {
             no_i8042: false,
             no_rtc: false,
             no_smt: false,
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            nvmes: Vec::new(),
             params: Vec::new(),
             #[cfg(feature = "pci-hotplug")]
             pci_hotplug_slots: None,
@@ -1101,6 +1178,8 @@ impl Default for Config {
             suspended: false,
             swap_dir: None,
             swiotlb: None,
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            swtpm: None,
             #[cfg(target_os = "android")]
             task_profiles: Vec::new(),
             #[cfg(any(target_os = "android", target_os = "linux"))]
@@ -1134,8 +1213,11 @@ impl Default for Config {
             ))]
             virt_cpufreq: false,
             virtio_input: Vec::new(),
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            virtio_iommu_domain_range: None,
             #[cfg(feature = "audio")]
             virtio_snds: Vec::new(),
+            vnc_display: None,
             #[cfg(feature = "vtpm")]
             vtpm_proxy: false,
             wayland_socket_paths: BTreeMap::new(),
@@ -1322,6 +1404,21 @@ pub fn validate_config(cfg: &mut Config) -> std::result::Result<(), String> {
         }
     }
 
+    #[cfg(feature = "pci-hotplug")]
+    {
+        if !cfg.input_hotplug.is_empty() && cfg.pci_hotplug_slots.is_none() {
+            return Err("'input-hotplug' requires 'pci-hotplug-slots'".to_string());
+        }
+
+        if cfg
+            .input_hotplug
+            .iter()
+            .any(|m| m.vendor.is_none() && m.product.is_none() && m.name.is_none())
+        {
+            return Err("'input-hotplug' needs one of vendor, product or name".to_string());
+        }
+    }
+
     #[cfg(any(target_os = "android", target_os = "linux"))]
     if cfg.lock_guest_memory && cfg.jail_config.is_none() {
         return Err("'lock-guest-memory' and 'disable-sandbox' are mutually exclusive".to_string());
@@ -1996,6 +2093,12 @@ mod tests {
             assert_eq!(params.backend, VideoBackendType::Ffmpeg);
         }
 
+        #[cfg(feature = "swdec")]
+        {
+            let params: VideoDeviceConfig = from_key_values("swdec").unwrap();
+            assert_eq!(params.backend, VideoBackendType::Swdec);
+        }
+
         #[cfg(feature = "vaapi")]
         {
             let params: VideoDeviceConfig = from_key_values("vaapi").unwrap();
@@ -2054,6 +2157,7 @@ mod tests {
         test_device_type("wl", DeviceType::Wl);
         test_device_type("tpm", DeviceType::Tpm);
         test_device_type("pvclock", DeviceType::Pvclock);
+        test_device_type("media", DeviceType::Media);
     }
 
     #[test]
@@ -2201,6 +2305,78 @@ mod tests {
         );
     }
 
+    #[test]
+    fn parse_input_evdev() {
+        let cfg = TryInto::<Config>::try_into(
+            crate::crosvm::cmdline::RunCommand::from_args(
+                &[],
+                &[
+                    "--input",
+                    "evdev[path=/dev/input/event3,grab-hotkey=[29,56]]",
+                    "bzImage",
+                ],
+            )
+            .unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            cfg.virtio_input,
+            vec![InputDeviceOption::Evdev {
+                path: PathBuf::from("/dev/input/event3"),
+                grab_hotkey: Some(vec![29, 56]),
+            }]
+        );
+    }
+
+    #[cfg(feature = "pci-hotplug")]
+    #[test]
+    fn parse_input_hotplug() {
+        let cfg = TryInto::<Config>::try_into(
+            crate::crosvm::cmdline::RunCommand::from_args(
+                &[],
+                &[
+                    "--pci-hotplug-slots",
+                    "2",
+                    "--input-hotplug",
+                    "vendor=0x046d,product=0xc31c",
+                    "--input-hotplug",
+                    "name=Barcode Scanner",
+                    "bzImage",
+                ],
+            )
+            .unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            cfg.input_hotplug,
+            vec![
+                InputHotplugOption {
+                    vendor: Some(0x046d),
+                    product: Some(0xc31c),
+                    name: None,
+                    grab_hotkey: None,
+                },
+                InputHotplugOption {
+                    vendor: None,
+                    product: None,
+                    name: Some("Barcode Scanner".to_string()),
+                    grab_hotkey: None,
+                },
+            ]
+        );
+
+        TryInto::<Config>::try_into(
+            crate::crosvm::cmdline::RunCommand::from_args(
+                &[],
+                &["--input-hotplug", "name=Barcode Scanner", "bzImage"],
+            )
+            .unwrap(),
+        )
+        .expect_err("input-hotplug without hotplug slots should be rejected");
+    }
+
     #[test]
     fn single_touch_spec_and_track_pad_spec_default_size() {
         let config: Config = crate::crosvm::cmdline::RunCommand::from_args(
@@ -2473,4 +2649,24 @@ mod tests {
             .unwrap_err()
             .contains("swap-interval parameter can only be set for writable pmem device"));
     }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    #[test]
+    fn parse_virtio_iommu_domain_range_valid() {
+        assert_eq!(parse_virtio_iommu_domain_range("1-100").unwrap(), 1..=100);
+        assert_eq!(
+            parse_virtio_iommu_domain_range("0x10-0xffffffff").unwrap(),
+            0x10..=u32::MAX
+        );
+        assert_eq!(parse_virtio_iommu_domain_range("7-7").unwrap(), 7..=7);
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    #[test]
+    fn parse_virtio_iommu_domain_range_invalid() {
+        assert!(parse_virtio_iommu_domain_range("100").is_err());
+        assert!(parse_virtio_iommu_domain_range("100-1").is_err());
+        assert!(parse_virtio_iommu_domain_range("0-0x100000000").is_err());
+        assert!(parse_virtio_iommu_domain_range("a-b").is_err());
+    }
 }
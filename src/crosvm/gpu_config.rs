@@ -95,6 +95,7 @@ pub(crate) fn fixup_gpu_display_options(
             vertical_dpi_compat.unwrap_or(DEFAULT_DPI),
         ),
     });
+    display_params.validate()?;
 
     Ok(FixedGpuDisplayParameters(display_params))
 }
@@ -143,6 +144,10 @@ mod tests {
     use argh::FromArgs;
     #[cfg(feature = "gfxstream")]
     use devices::virtio::GpuWsi;
+    use vm_control::gpu::DisplayTiming;
+    use vm_control::gpu::HdrMetadata;
+    use vm_control::gpu::DEFAULT_REFRESH_RATE;
+    use vm_control::gpu::MAX_DISPLAY_MODES;
 
     use super::*;
     use crate::crosvm::config::from_key_values;
@@ -673,6 +678,65 @@ mod tests {
         .is_err());
     }
 
+    #[test]
+    fn parse_gpu_display_options_modes() {
+        let display_params = parse_gpu_display_options(
+            "modes=[[width=1920,height=1080],[width=1280,height=720,refresh-rate=30,\
+             blanking=[160,30],front-porch=[48,3],sync-width=[32,5]]]",
+        )
+        .unwrap();
+        assert_eq!(
+            display_params.modes,
+            vec![
+                DisplayTiming {
+                    width: 1920,
+                    height: 1080,
+                    refresh_rate: DEFAULT_REFRESH_RATE,
+                    blanking: None,
+                    front_porch: None,
+                    sync_width: None,
+                },
+                DisplayTiming {
+                    width: 1280,
+                    height: 720,
+                    refresh_rate: 30,
+                    blanking: Some((160, 30)),
+                    front_porch: Some((48, 3)),
+                    sync_width: Some((32, 5)),
+                },
+            ]
+        );
+        assert!(parse_gpu_display_options("modes=[[width=1920]]").is_err());
+    }
+
+    #[test]
+    fn parse_gpu_display_options_hdr_and_physical_size() {
+        let display_params = parse_gpu_display_options(
+            "physical-size-mm=[600,340],hdr=[max-luminance=1000,min-luminance=50]",
+        )
+        .unwrap();
+        assert_eq!(display_params.physical_size_mm, Some((600, 340)));
+        assert_eq!(
+            display_params.hdr,
+            Some(HdrMetadata {
+                max_luminance: 1000,
+                max_frame_average_luminance: None,
+                min_luminance: Some(50),
+            })
+        );
+    }
+
+    #[test]
+    fn fixup_gpu_display_options_rejects_invalid_edid_params() {
+        let fixup = |s: &str| from_key_values::<FixedGpuDisplayParameters>(s);
+
+        assert!(fixup("modes=[[width=4096,height=2160]]").is_err());
+        assert!(fixup("modes=[[width=1920,height=1080,front-porch=[48,64]]]").is_err());
+        let too_many_modes = vec!["[width=640,height=480]"; MAX_DISPLAY_MODES + 1].join(",");
+        assert!(fixup(&format!("modes=[{}]", too_many_modes)).is_err());
+        assert!(fixup("modes=[[width=3840,height=2160]]").is_ok());
+    }
+
     #[test]
     fn parse_gpu_options_single_display() {
         {
@@ -7,6 +7,8 @@ mod android;
 pub mod cmdline;
 pub mod config;
 mod device_helpers;
+#[cfg(feature = "pci-hotplug")]
+mod evdev_hotplug;
 #[cfg(feature = "gpu")]
 pub(crate) mod gpu;
 #[cfg(feature = "pci-hotplug")]
@@ -41,6 +43,8 @@ use std::ops::RangeInclusive;
 use std::os::unix::prelude::OpenOptionsExt;
 use std::os::unix::process::ExitStatusExt;
 use std::path::Path;
+#[cfg(feature = "pci-hotplug")]
+use std::path::PathBuf;
 use std::process;
 #[cfg(feature = "registered_events")]
 use std::rc::Rc;
@@ -97,6 +101,8 @@ use devices::virtio::BalloonFeatures;
 use devices::virtio::NetParameters;
 #[cfg(feature = "pci-hotplug")]
 use devices::virtio::NetParametersMode;
+#[cfg(target_arch = "x86_64")]
+use devices::virtio::TpmBackend;
 use devices::virtio::VirtioDevice;
 use devices::virtio::VirtioDeviceType;
 use devices::virtio::VirtioTransportType;
@@ -110,11 +116,15 @@ use devices::DeviceProvider;
 use devices::HotPlugBus;
 #[cfg(target_arch = "x86_64")]
 use devices::HotPlugKey;
+#[cfg(feature = "pci-hotplug")]
+use devices::InputResourceCarrier;
 use devices::IommuDevType;
 use devices::IrqEventIndex;
 use devices::IrqEventSource;
 #[cfg(feature = "pci-hotplug")]
 use devices::NetResourceCarrier;
+use devices::NvmeController;
+use devices::NvmeDiskConfig;
 #[cfg(target_arch = "x86_64")]
 use devices::PciAddress;
 #[cfg(target_arch = "x86_64")]
@@ -139,6 +149,9 @@ use devices::PvPanicPciDevice;
 #[cfg(feature = "pci-hotplug")]
 use devices::ResourceCarrier;
 use devices::StubPciDevice;
+#[cfg(target_arch = "x86_64")]
+use devices::Swtpm;
+use devices::TpmInterface;
 use devices::VirtioMmioDevice;
 use devices::VirtioPciDevice;
 #[cfg(feature = "usb")]
@@ -225,6 +238,9 @@ fn create_virtio_devices(
     disk_device_tubes: &mut Vec<Tube>,
     pmem_device_tubes: &mut Vec<Tube>,
     fs_device_tubes: &mut Vec<Tube>,
+    #[cfg(feature = "audio")] snd_device_tubes: &mut Vec<Tube>,
+    input_device_tubes: &mut Vec<Tube>,
+    scsi_device_tube: Option<Tube>,
     #[cfg(feature = "gpu")] gpu_control_tube: Tube,
     #[cfg(feature = "gpu")] render_server_fd: Option<SafeDescriptor>,
     #[cfg(feature = "gpu")] has_vfio_gfx_device: bool,
@@ -383,7 +399,7 @@ fn create_virtio_devices(
     }
 
     if !cfg.scsis.is_empty() {
-        let scsi_config = ScsiConfig(&cfg.scsis);
+        let scsi_config = ScsiConfig::new(&cfg.scsis, scsi_device_tube);
         devs.push(
             scsi_config.create_virtio_device_and_jail(cfg.protection_type, &cfg.jail_config)?,
         );
@@ -474,6 +490,16 @@ fn create_virtio_devices(
         }
     }
 
+    if let Some(swtpm) = &cfg.swtpm {
+        if swtpm.interface == TpmInterface::Virtio {
+            devs.push(create_swtpm_device(
+                cfg.protection_type,
+                &cfg.jail_config,
+                &swtpm.socket,
+            )?);
+        }
+    }
+
     let mut keyboard_idx = 0;
     let mut mouse_idx = 0;
     let mut rotary_idx = 0;
@@ -483,16 +509,22 @@ fn create_virtio_devices(
     let mut trackpad_idx = 0;
     let mut multi_touch_trackpad_idx = 0;
     for input in &cfg.virtio_input {
+        let control_tube = input_device_tubes.remove(0);
         let input_dev = match input {
-            InputDeviceOption::Evdev { path } => {
-                create_vinput_device(cfg.protection_type, &cfg.jail_config, path.as_path())?
-            }
+            InputDeviceOption::Evdev { path, grab_hotkey } => create_vinput_device(
+                cfg.protection_type,
+                &cfg.jail_config,
+                path.as_path(),
+                grab_hotkey.as_deref(),
+                control_tube,
+            )?,
             InputDeviceOption::Keyboard { path } => {
                 let dev = create_keyboard_device(
                     cfg.protection_type,
                     &cfg.jail_config,
                     path.as_path(),
                     keyboard_idx,
+                    control_tube,
                 )?;
                 keyboard_idx += 1;
                 dev
@@ -503,6 +535,7 @@ fn create_virtio_devices(
                     &cfg.jail_config,
                     path.as_path(),
                     mouse_idx,
+                    control_tube,
                 )?;
                 mouse_idx += 1;
                 dev
@@ -531,6 +564,7 @@ fn create_virtio_devices(
                     height.unwrap_or(DEFAULT_TOUCH_DEVICE_HEIGHT),
                     name.as_deref(),
                     multi_touch_idx,
+                    control_tube,
                 )?;
                 multi_touch_idx += 1;
                 dev
@@ -541,6 +575,7 @@ fn create_virtio_devices(
                     &cfg.jail_config,
                     path.as_path(),
                     rotary_idx,
+                    control_tube,
                 )?;
                 rotary_idx += 1;
                 dev
@@ -569,6 +604,7 @@ fn create_virtio_devices(
                     height.unwrap_or(DEFAULT_TOUCH_DEVICE_HEIGHT),
                     name.as_deref(),
                     single_touch_idx,
+                    control_tube,
                 )?;
                 single_touch_idx += 1;
                 dev
@@ -579,6 +615,7 @@ fn create_virtio_devices(
                     &cfg.jail_config,
                     path.as_path(),
                     switches_idx,
+                    control_tube,
                 )?;
                 switches_idx += 1;
                 dev
@@ -597,6 +634,7 @@ fn create_virtio_devices(
                     height.unwrap_or(DEFAULT_TOUCH_DEVICE_HEIGHT),
                     name.as_deref(),
                     trackpad_idx,
+                    control_tube,
                 )?;
                 trackpad_idx += 1;
                 dev
@@ -615,6 +653,7 @@ fn create_virtio_devices(
                     height.unwrap_or(DEFAULT_TOUCH_DEVICE_HEIGHT),
                     name.as_deref(),
                     multi_touch_trackpad_idx,
+                    control_tube,
                 )?;
                 multi_touch_trackpad_idx += 1;
                 dev
@@ -660,6 +699,7 @@ fn create_virtio_devices(
                 cfg.protection_type,
                 &cfg.jail_config,
                 snd_params,
+                snd_device_tubes.remove(0),
             )?);
         }
     }
@@ -692,6 +732,15 @@ fn create_virtio_devices(
         }
     }
 
+    #[cfg(feature = "media")]
+    for media_config in &cfg.media {
+        devs.push(create_media_device(
+            cfg.protection_type,
+            &cfg.jail_config,
+            media_config,
+        )?);
+    }
+
     if let Some(vsock_config) = &cfg.vsock {
         devs.push(
             vsock_config.create_virtio_device_and_jail(cfg.protection_type, &cfg.jail_config)?,
@@ -778,6 +827,7 @@ fn create_devices(
     resources: &mut SystemAllocator,
     vm_evt_wrtube: &SendTube,
     iommu_attached_endpoints: &mut BTreeMap<u32, Arc<Mutex<Box<dyn MemoryMapperTrait>>>>,
+    iommu_fault_queues: &mut BTreeMap<u32, File>,
     irq_control_tubes: &mut Vec<Tube>,
     vm_memory_control_tubes: &mut Vec<VmMemoryTube>,
     control_tubes: &mut Vec<TaggedControlTube>,
@@ -786,6 +836,9 @@ fn create_devices(
     disk_device_tubes: &mut Vec<Tube>,
     pmem_device_tubes: &mut Vec<Tube>,
     fs_device_tubes: &mut Vec<Tube>,
+    #[cfg(feature = "audio")] snd_device_tubes: &mut Vec<Tube>,
+    input_device_tubes: &mut Vec<Tube>,
+    scsi_device_tube: Option<Tube>,
     #[cfg(feature = "usb")] usb_provider: DeviceProvider,
     #[cfg(feature = "gpu")] gpu_control_tube: Tube,
     #[cfg(feature = "gpu")] render_server_fd: Option<SafeDescriptor>,
@@ -817,6 +870,7 @@ fn create_devices(
                 Some(&mut coiommu_attached_endpoints),
                 vfio_dev.iommu,
                 vfio_dev.dt_symbol.clone(),
+                vfio_dev.iommufd,
                 vfio_container_manager,
             )?;
             match dev {
@@ -831,14 +885,16 @@ fn create_devices(
                         has_vfio_gfx_device = true;
                     }
 
-                    if let Some(viommu_mapper) = viommu_mapper {
-                        iommu_attached_endpoints.insert(
-                            vfio_pci_device
-                                .pci_address()
-                                .context("not initialized")?
-                                .to_u32(),
-                            Arc::new(Mutex::new(Box::new(viommu_mapper))),
-                        );
+                    if let Some(mut viommu_mapper) = viommu_mapper {
+                        let endpoint = vfio_pci_device
+                            .pci_address()
+                            .context("not initialized")?
+                            .to_u32();
+                        if let Some(fault_queue) = viommu_mapper.take_fault_queue() {
+                            iommu_fault_queues.insert(endpoint, fault_queue);
+                        }
+                        iommu_attached_endpoints
+                            .insert(endpoint, Arc::new(Mutex::new(Box::new(viommu_mapper))));
                     }
 
                     devices.push((Box::new(vfio_pci_device), jail));
@@ -928,6 +984,10 @@ fn create_devices(
         disk_device_tubes,
         pmem_device_tubes,
         fs_device_tubes,
+        #[cfg(feature = "audio")]
+        snd_device_tubes,
+        input_device_tubes,
+        scsi_device_tube,
         #[cfg(feature = "gpu")]
         gpu_control_tube,
         #[cfg(feature = "gpu")]
@@ -1004,6 +1064,29 @@ fn create_devices(
         ));
     }
 
+    if !cfg.nvmes.is_empty() {
+        let disks = cfg
+            .nvmes
+            .iter()
+            .map(|nvme| {
+                Ok(NvmeDiskConfig {
+                    file: nvme.open()?,
+                    block_size: nvme.block_size,
+                    read_only: nvme.read_only,
+                    sparse: nvme.sparse,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let (msi_host_tube, msi_device_tube) = Tube::pair().context("failed to create tube")?;
+        irq_control_tubes.push(msi_host_tube);
+        let nvme_controller = NvmeController::new(vm.get_memory().clone(), disks, msi_device_tube)
+            .context("failed to create nvme controller")?;
+        devices.push((
+            Box::new(nvme_controller),
+            simple_jail(&cfg.jail_config, "nvme_device")?,
+        ));
+    }
+
     for params in &cfg.stub_pci_devices {
         // Stub devices don't need jailing since they don't do anything.
         devices.push((Box::new(StubPciDevice::new(params)), None));
@@ -1230,6 +1313,20 @@ fn setup_vm_components(cfg: &Config) -> Result<VmComponents> {
         (None, 0)
     };
 
+    #[cfg(target_arch = "x86_64")]
+    let tpm_crb: Option<Box<dyn TpmBackend>> = match &cfg.swtpm {
+        Some(swtpm) if swtpm.interface == TpmInterface::Crb => Some(Box::new(
+            Swtpm::new(&swtpm.socket).context("failed to connect to swtpm")?,
+        )),
+        _ => None,
+    };
+    // The CRB interface lives at an x86 specific MMIO address, don't silently drop the TPM on
+    // other platforms.
+    #[cfg(not(target_arch = "x86_64"))]
+    if matches!(&cfg.swtpm, Some(swtpm) if swtpm.interface == TpmInterface::Crb) {
+        bail!("swtpm interface=crb is only supported on x86_64");
+    }
+
     #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
     let mut cpu_frequencies = BTreeMap::new();
     #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
@@ -1361,6 +1458,8 @@ fn setup_vm_components(cfg: &Config) -> Result<VmComponents> {
         no_rtc: cfg.no_rtc,
         #[cfg(target_arch = "x86_64")]
         smbios: cfg.smbios.clone(),
+        #[cfg(target_arch = "x86_64")]
+        tpm_crb,
         host_cpu_topology: cfg.host_cpu_topology,
         itmt: cfg.itmt,
         #[cfg(target_arch = "x86_64")]
@@ -1426,6 +1525,31 @@ fn punch_holes_in_guest_mem_layout_for_mappings(
         .collect()
 }
 
+/// Fails early when `cfg` asks for a feature that `hypervisor` cannot provide.
+fn check_hypervisor_capabilities(hypervisor: &impl Hypervisor, cfg: &Config) -> Result<()> {
+    if cfg.restore_path.is_some() && !hypervisor.check_capability(HypervisorCap::VcpuSnapshot) {
+        bail!("restoring a snapshot is not supported by this hypervisor");
+    }
+    #[cfg(feature = "swap")]
+    if cfg.swap_dir.is_some() && !hypervisor.check_capability(HypervisorCap::SwappableGuestMemory) {
+        bail!("vmm-swap is not supported by this hypervisor");
+    }
+    Ok(())
+}
+
+/// Returns an error response for a runtime `request` that needs a feature that the hypervisor
+/// cannot provide, or `None` if the request can be handled.
+fn check_request_capabilities(request: &VmRequest, snapshot_supported: bool) -> Option<VmResponse> {
+    match request {
+        VmRequest::Snapshot(SnapshotCommand::Take { .. }) if !snapshot_supported => {
+            Some(VmResponse::ErrString(
+                "taking a snapshot is not supported by this hypervisor".to_owned(),
+            ))
+        }
+        _ => None,
+    }
+}
+
 fn create_guest_memory(
     cfg: &Config,
     components: &VmComponents,
@@ -1473,6 +1597,9 @@ fn run_gz(device_path: Option<&Path>, cfg: Config, components: VmComponents) ->
     let gzvm = Geniezone::new_with_path(device_path)
         .with_context(|| format!("failed to open GenieZone device {}", device_path.display()))?;
 
+    check_hypervisor_capabilities(&gzvm, &cfg)?;
+    let snapshot_supported = gzvm.check_capability(HypervisorCap::VcpuSnapshot);
+
     let guest_mem = create_guest_memory(&cfg, &components, &gzvm)?;
 
     #[cfg(feature = "swap")]
@@ -1511,6 +1638,7 @@ fn run_gz(device_path: Option<&Path>, cfg: Config, components: VmComponents) ->
         vm,
         &mut irq_chip,
         ioapic_host_tube,
+        snapshot_supported,
         #[cfg(feature = "swap")]
         swap_controller,
     )
@@ -1528,6 +1656,9 @@ fn run_kvm(device_path: Option<&Path>, cfg: Config, components: VmComponents) ->
     let kvm = Kvm::new_with_path(device_path)
         .with_context(|| format!("failed to open KVM device {}", device_path.display()))?;
 
+    check_hypervisor_capabilities(&kvm, &cfg)?;
+    let snapshot_supported = kvm.check_capability(HypervisorCap::VcpuSnapshot);
+
     let guest_mem = create_guest_memory(&cfg, &components, &kvm)?;
 
     #[cfg(feature = "swap")]
@@ -1609,6 +1740,7 @@ fn run_kvm(device_path: Option<&Path>, cfg: Config, components: VmComponents) ->
         vm,
         irq_chip.as_mut(),
         ioapic_host_tube,
+        snapshot_supported,
         #[cfg(feature = "swap")]
         swap_controller,
     )
@@ -1629,6 +1761,9 @@ fn run_gunyah(
     let gunyah = Gunyah::new_with_path(device_path)
         .with_context(|| format!("failed to open Gunyah device {}", device_path.display()))?;
 
+    check_hypervisor_capabilities(&gunyah, &cfg)?;
+    let snapshot_supported = gunyah.check_capability(HypervisorCap::VcpuSnapshot);
+
     let guest_mem = create_guest_memory(&cfg, &components, &gunyah)?;
 
     #[cfg(feature = "swap")]
@@ -1656,6 +1791,7 @@ fn run_gunyah(
         vm,
         &mut GunyahIrqChip::new(vm_clone)?,
         None,
+        snapshot_supported,
         #[cfg(feature = "swap")]
         swap_controller,
     )
@@ -1729,6 +1865,7 @@ fn run_vm<Vcpu, V>(
     mut vm: V,
     irq_chip: &mut dyn IrqChipArch,
     ioapic_host_tube: Option<Tube>,
+    snapshot_supported: bool,
     #[cfg(feature = "swap")] mut swap_controller: Option<SwapController>,
 ) -> Result<ExitState>
 where
@@ -1803,6 +1940,13 @@ where
         components.gdb = Some((port, gdb_control_tube));
     }
 
+    #[cfg(feature = "pci-hotplug")]
+    if !cfg.input_hotplug.is_empty() {
+        let (hotplug_host_tube, hotplug_tube) = Tube::pair().context("failed to create tube")?;
+        control_tubes.push(TaggedControlTube::Vm(hotplug_host_tube));
+        evdev_hotplug::start_evdev_hotplug_thread(cfg.input_hotplug.clone(), hotplug_tube)?;
+    }
+
     #[cfg(feature = "balloon")]
     let (balloon_host_tube, balloon_device_tube) = if cfg.balloon {
         if let Some(ref path) = cfg.balloon_control {
@@ -1837,6 +1981,36 @@ where
         disk_device_tubes.push(disk_device_tube);
     }
 
+    // Create one control socket per sound card.
+    #[cfg(feature = "audio")]
+    let (mut snd_device_tubes, snd_host_tubes) = {
+        let mut snd_device_tubes = Vec::new();
+        let mut snd_host_tubes = Vec::new();
+        for _ in 0..cfg.virtio_snds.len() {
+            let (snd_host_tube, snd_device_tube) = Tube::pair().context("failed to create tube")?;
+            snd_host_tubes.push(snd_host_tube);
+            snd_device_tubes.push(snd_device_tube);
+        }
+        (snd_device_tubes, snd_host_tubes)
+    };
+
+    // Create one control socket per `--input` device.
+    let mut input_device_tubes = Vec::new();
+    let mut input_host_tubes = Vec::new();
+    for _ in 0..cfg.virtio_input.len() {
+        let (input_host_tube, input_device_tube) = Tube::pair().context("failed to create tube")?;
+        input_host_tubes.push(input_host_tube);
+        input_device_tubes.push(input_device_tube);
+    }
+
+    // Create a control socket for the SCSI controller to change CD-ROM media.
+    let (scsi_host_tube, scsi_device_tube) = if cfg.scsis.is_empty() {
+        (None, None)
+    } else {
+        let (host, device) = Tube::pair().context("failed to create tube")?;
+        (Some(host), Some(device))
+    };
+
     let mut pmem_device_tubes = Vec::new();
     let pmem_count = cfg.pmems.len() + cfg.pmem_ext2.len();
     for _ in 0..pmem_count {
@@ -1935,6 +2109,7 @@ where
 
     let mut iommu_attached_endpoints: BTreeMap<u32, Arc<Mutex<Box<dyn MemoryMapperTrait>>>> =
         BTreeMap::new();
+    let mut iommu_fault_queues: BTreeMap<u32, File> = BTreeMap::new();
     let mut iova_max_addr: Option<u64> = None;
 
     let mut vfio_container_manager = VfioContainerManager::new();
@@ -1958,6 +2133,7 @@ where
         &mut sys_allocator,
         &vm_evt_wrtube,
         &mut iommu_attached_endpoints,
+        &mut iommu_fault_queues,
         &mut irq_control_tubes,
         &mut vm_memory_control_tubes,
         &mut control_tubes,
@@ -1968,6 +2144,10 @@ where
         &mut disk_device_tubes,
         &mut pmem_device_tubes,
         &mut fs_device_tubes,
+        #[cfg(feature = "audio")]
+        &mut snd_device_tubes,
+        &mut input_device_tubes,
+        scsi_device_tube,
         #[cfg(feature = "usb")]
         usb_provider,
         #[cfg(feature = "gpu")]
@@ -2068,7 +2248,11 @@ where
             &cfg.jail_config,
             iova_max_addr.unwrap_or(u64::MAX),
             iommu_attached_endpoints,
+            iommu_fault_queues,
             iommu_bus_ranges,
+            cfg.virtio_iommu_domain_range
+                .clone()
+                .unwrap_or(0..=u32::MAX),
             translate_response_senders,
             request_rx,
             iommu_device_tube,
@@ -2236,6 +2420,10 @@ where
         #[cfg(feature = "balloon")]
         balloon_host_tube,
         &disk_host_tubes,
+        #[cfg(feature = "audio")]
+        &snd_host_tubes,
+        &input_host_tubes,
+        scsi_host_tube,
         #[cfg(feature = "gpu")]
         gpu_control_host_tube,
         #[cfg(feature = "usb")]
@@ -2261,6 +2449,7 @@ where
         pvclock_host_tube,
         metrics_recv,
         vfio_container_manager,
+        snapshot_supported,
     )
 }
 
@@ -2449,6 +2638,7 @@ fn add_hotplug_device<V: VmArch, Vcpu: VcpuArch>(
                     IommuDevType::NoIommu
                 },
                 None,
+                false,
                 vfio_container_manager,
             )?;
             let vfio_pci_device = match vfio_device {
@@ -2496,16 +2686,14 @@ fn add_hotplug_device<V: VmArch, Vcpu: VcpuArch>(
     Ok(())
 }
 
+/// Creates the tubes a hotplugged virtio-pci device uses to reach the main process. The host ends
+/// are added to the given lists, and the device ends returned.
 #[cfg(feature = "pci-hotplug")]
-fn add_hotplug_net<V: VmArch, Vcpu: VcpuArch>(
-    linux: &mut RunnableLinuxVm<V, Vcpu>,
-    sys_allocator: &mut SystemAllocator,
+fn create_hotplug_device_tubes(
     irq_control_tubes: &mut Vec<Tube>,
     vm_memory_control_tubes: &mut Vec<VmMemoryTube>,
     vm_control_tubes: &mut Vec<TaggedControlTube>,
-    hotplug_manager: &mut PciHotPlugManager,
-    net_param: NetParameters,
-) -> Result<u8> {
+) -> Result<(Tube, VmMemoryClient, Tube)> {
     let (msi_host_tube, msi_device_tube) = Tube::pair().context("create tube")?;
     irq_control_tubes.push(msi_host_tube);
     let (ioevent_host_tube, ioevent_device_tube) = Tube::pair().context("create tube")?;
@@ -2516,6 +2704,25 @@ fn add_hotplug_net<V: VmArch, Vcpu: VcpuArch>(
     });
     let (vm_control_host_tube, vm_control_device_tube) = Tube::pair().context("create tube")?;
     vm_control_tubes.push(TaggedControlTube::Vm(vm_control_host_tube));
+    Ok((
+        msi_device_tube,
+        ioevent_vm_memory_client,
+        vm_control_device_tube,
+    ))
+}
+
+#[cfg(feature = "pci-hotplug")]
+fn add_hotplug_net<V: VmArch, Vcpu: VcpuArch>(
+    linux: &mut RunnableLinuxVm<V, Vcpu>,
+    sys_allocator: &mut SystemAllocator,
+    irq_control_tubes: &mut Vec<Tube>,
+    vm_memory_control_tubes: &mut Vec<VmMemoryTube>,
+    vm_control_tubes: &mut Vec<TaggedControlTube>,
+    hotplug_manager: &mut PciHotPlugManager,
+    net_param: NetParameters,
+) -> Result<u8> {
+    let (msi_device_tube, ioevent_vm_memory_client, vm_control_device_tube) =
+        create_hotplug_device_tubes(irq_control_tubes, vm_memory_control_tubes, vm_control_tubes)?;
     let net_carrier_device = NetResourceCarrier::new(
         net_param,
         msi_device_tube,
@@ -2550,7 +2757,7 @@ fn handle_hotplug_net_command<V: VmArch, Vcpu: VcpuArch>(
             &tap_name,
         ),
         NetControlCommand::RemoveTap(bus) => {
-            handle_hotplug_net_remove(linux, sys_allocator, hotplug_manager, bus)
+            handle_hotplug_remove(linux, sys_allocator, hotplug_manager, bus)
         }
     }
 }
@@ -2593,7 +2800,72 @@ fn handle_hotplug_net_add<V: VmArch, Vcpu: VcpuArch>(
 }
 
 #[cfg(feature = "pci-hotplug")]
-fn handle_hotplug_net_remove<V: VmArch, Vcpu: VcpuArch>(
+fn add_hotplug_input<V: VmArch, Vcpu: VcpuArch>(
+    linux: &mut RunnableLinuxVm<V, Vcpu>,
+    sys_allocator: &mut SystemAllocator,
+    irq_control_tubes: &mut Vec<Tube>,
+    vm_memory_control_tubes: &mut Vec<VmMemoryTube>,
+    vm_control_tubes: &mut Vec<TaggedControlTube>,
+    hotplug_manager: &mut PciHotPlugManager,
+    path: PathBuf,
+    grab_hotkey: Option<Vec<u16>>,
+) -> Result<u8> {
+    let evdev = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&path)
+        .with_context(|| format!("failed to open input device {}", path.display()))?;
+    let (msi_device_tube, ioevent_vm_memory_client, vm_control_device_tube) =
+        create_hotplug_device_tubes(irq_control_tubes, vm_memory_control_tubes, vm_control_tubes)?;
+    let input_carrier_device = InputResourceCarrier::new(
+        path,
+        evdev,
+        grab_hotkey,
+        msi_device_tube,
+        ioevent_vm_memory_client,
+        vm_control_device_tube,
+    );
+    hotplug_manager.hotplug_device(
+        vec![ResourceCarrier::VirtioInput(input_carrier_device)],
+        linux,
+        sys_allocator,
+    )
+}
+
+#[cfg(feature = "pci-hotplug")]
+fn handle_hotplug_input_command<V: VmArch, Vcpu: VcpuArch>(
+    input_cmd: InputHotplugCommand,
+    linux: &mut RunnableLinuxVm<V, Vcpu>,
+    sys_allocator: &mut SystemAllocator,
+    irq_control_tubes: &mut Vec<Tube>,
+    vm_memory_control_tubes: &mut Vec<VmMemoryTube>,
+    vm_control_tubes: &mut Vec<TaggedControlTube>,
+    hotplug_manager: &mut PciHotPlugManager,
+) -> VmResponse {
+    match input_cmd {
+        InputHotplugCommand::AddEvdev { path, grab_hotkey } => {
+            match add_hotplug_input(
+                linux,
+                sys_allocator,
+                irq_control_tubes,
+                vm_memory_control_tubes,
+                vm_control_tubes,
+                hotplug_manager,
+                path,
+                grab_hotkey,
+            ) {
+                Ok(pci_bus) => VmResponse::PciHotPlugResponse { bus: pci_bus },
+                Err(e) => VmResponse::ErrString(format!("{:?}", e)),
+            }
+        }
+        InputHotplugCommand::RemoveEvdev(bus) => {
+            handle_hotplug_remove(linux, sys_allocator, hotplug_manager, bus)
+        }
+    }
+}
+
+#[cfg(feature = "pci-hotplug")]
+fn handle_hotplug_remove<V: VmArch, Vcpu: VcpuArch>(
     linux: &mut RunnableLinuxVm<V, Vcpu>,
     sys_allocator: &mut SystemAllocator,
     hotplug_manager: &mut PciHotPlugManager,
@@ -2897,6 +3169,10 @@ struct ControlLoopState<'a, V: VmArch, Vcpu: VcpuArch> {
     sys_allocator: &'a Arc<Mutex<SystemAllocator>>,
     control_tubes: &'a BTreeMap<usize, TaggedControlTube>,
     disk_host_tubes: &'a [Tube],
+    #[cfg(feature = "audio")]
+    snd_host_tubes: &'a [Tube],
+    input_host_tubes: &'a [Tube],
+    scsi_host_tube: &'a Option<Tube>,
     #[cfg(feature = "gpu")]
     gpu_control_tube: &'a Tube,
     #[cfg(feature = "usb")]
@@ -2924,6 +3200,7 @@ struct ControlLoopState<'a, V: VmArch, Vcpu: VcpuArch> {
     vfio_container_manager: &'a mut VfioContainerManager,
     suspended_pvclock_state: &'a mut Option<hypervisor::ClockState>,
     vcpus_pid_tid: &'a BTreeMap<usize, (u32, u32)>,
+    snapshot_supported: bool,
 }
 
 fn process_vm_request<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
@@ -2945,6 +3222,10 @@ fn process_vm_request<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
     #[cfg(any(target_arch = "x86_64", feature = "pci-hotplug"))]
     let mut add_vm_memory_control_tubes = Vec::new();
 
+    if let Some(response) = check_request_capabilities(&request, state.snapshot_supported) {
+        return Ok((Some(response), false, None));
+    }
+
     let response = match request {
         VmRequest::HotPlugVfioCommand { device, add } => {
             #[cfg(target_arch = "x86_64")]
@@ -2990,6 +3271,22 @@ fn process_vm_request<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
                 VmResponse::ErrString("PCI hotplug is not enabled.".to_owned())
             }
         }
+        #[cfg(feature = "pci-hotplug")]
+        VmRequest::HotPlugInputCommand(input_cmd) => {
+            if let Some(hotplug_manager) = state.hotplug_manager.as_mut() {
+                handle_hotplug_input_command(
+                    input_cmd,
+                    state.linux,
+                    &mut state.sys_allocator.lock(),
+                    &mut add_irq_control_tubes,
+                    &mut add_vm_memory_control_tubes,
+                    add_tubes,
+                    hotplug_manager,
+                )
+            } else {
+                VmResponse::ErrString("PCI hotplug is not enabled.".to_owned())
+            }
+        }
         #[cfg(feature = "registered_events")]
         VmRequest::RegisterListener { socket_addr, event } => {
             let (registered_tube, already_registered) =
@@ -3048,6 +3345,25 @@ fn process_vm_request<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
                 VmResponse::Err(base::Error::new(libc::ENOTSUP))
             }
         }
+        #[cfg(feature = "audio")]
+        VmRequest::SndCommand {
+            card_index,
+            command,
+        } => match state.snd_host_tubes.get(card_index) {
+            Some(tube) => vm_control::handle_snd_command(&command, tube),
+            None => VmResponse::Err(base::Error::new(libc::ENODEV)),
+        },
+        VmRequest::InputCommand {
+            device_index,
+            command,
+        } => match state.input_host_tubes.get(device_index) {
+            Some(tube) => vm_control::handle_input_command(&command, tube),
+            None => VmResponse::Err(base::Error::new(libc::ENODEV)),
+        },
+        VmRequest::ScsiCommand { command } => match state.scsi_host_tube {
+            Some(tube) => vm_control::handle_scsi_command(&command, tube),
+            None => VmResponse::Err(base::Error::new(libc::ENODEV)),
+        },
         VmRequest::VcpuPidTid => VmResponse::VcpuPidTidResponse {
             pid_tid_map: state.vcpus_pid_tid.clone(),
         },
@@ -3345,6 +3661,9 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
     control_tubes: Vec<TaggedControlTube>,
     #[cfg(feature = "balloon")] balloon_host_tube: Option<Tube>,
     disk_host_tubes: &[Tube],
+    #[cfg(feature = "audio")] snd_host_tubes: &[Tube],
+    input_host_tubes: &[Tube],
+    scsi_host_tube: Option<Tube>,
     #[cfg(feature = "gpu")] gpu_control_tube: Tube,
     #[cfg(feature = "usb")] usb_control_tube: Tube,
     vm_evt_rdtube: RecvTube,
@@ -3364,6 +3683,7 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
     #[cfg(feature = "pvclock")] pvclock_host_tube: Option<Tube>,
     metrics_tube: RecvTube,
     mut vfio_container_manager: VfioContainerManager,
+    snapshot_supported: bool,
 ) -> Result<ExitState> {
     #[derive(EventToken)]
     enum Token {
@@ -3934,6 +4254,10 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
                             sys_allocator: &sys_allocator_mutex,
                             control_tubes: &control_tubes,
                             disk_host_tubes,
+                            #[cfg(feature = "audio")]
+                            snd_host_tubes,
+                            input_host_tubes,
+                            scsi_host_tube: &scsi_host_tube,
                             #[cfg(feature = "gpu")]
                             gpu_control_tube: &gpu_control_tube,
                             #[cfg(feature = "usb")]
@@ -3961,6 +4285,7 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
                             vfio_container_manager: &mut vfio_container_manager,
                             suspended_pvclock_state: &mut suspended_pvclock_state,
                             vcpus_pid_tid: &vcpus_pid_tid,
+                            snapshot_supported,
                         };
                         let (exit_requested, mut ids_to_remove, add_tubes) =
                             process_vm_control_event(&mut state, id, socket)?;
@@ -5023,4 +5348,64 @@ mod tests {
             ]
         );
     }
+
+    // Hypervisor that only reports the capabilities it was created with.
+    struct FakeHypervisor(Vec<HypervisorCap>);
+
+    impl Hypervisor for FakeHypervisor {
+        fn try_clone(&self) -> base::Result<Self> {
+            Ok(FakeHypervisor(self.0.clone()))
+        }
+
+        fn check_capability(&self, cap: HypervisorCap) -> bool {
+            self.0.contains(&cap)
+        }
+    }
+
+    #[test]
+    fn restore_requires_vcpu_snapshot() {
+        let cfg = Config {
+            restore_path: Some(PathBuf::from("/tmp/snapshot")),
+            ..Default::default()
+        };
+        assert!(check_hypervisor_capabilities(&FakeHypervisor(Vec::new()), &cfg).is_err());
+        assert!(check_hypervisor_capabilities(
+            &FakeHypervisor(vec![HypervisorCap::VcpuSnapshot]),
+            &cfg
+        )
+        .is_ok());
+        assert!(
+            check_hypervisor_capabilities(&FakeHypervisor(Vec::new()), &Config::default()).is_ok()
+        );
+    }
+
+    #[cfg(feature = "swap")]
+    #[test]
+    fn swap_requires_swappable_guest_memory() {
+        let cfg = Config {
+            swap_dir: Some(PathBuf::from("/tmp/swap")),
+            ..Default::default()
+        };
+        assert!(check_hypervisor_capabilities(&FakeHypervisor(Vec::new()), &cfg).is_err());
+        assert!(check_hypervisor_capabilities(
+            &FakeHypervisor(vec![HypervisorCap::SwappableGuestMemory]),
+            &cfg
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn snapshot_take_requires_vcpu_snapshot() {
+        let take = VmRequest::Snapshot(SnapshotCommand::Take {
+            snapshot_path: PathBuf::from("/tmp/snapshot"),
+            compress_memory: false,
+            encrypt: false,
+        });
+        assert!(matches!(
+            check_request_capabilities(&take, false),
+            Some(VmResponse::ErrString(_))
+        ));
+        assert!(check_request_capabilities(&take, true).is_none());
+        assert!(check_request_capabilities(&VmRequest::Suspend, false).is_none());
+    }
 }
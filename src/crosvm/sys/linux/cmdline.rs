@@ -128,10 +128,92 @@ pub struct DevicesCommand {
     pub control_socket: Option<PathBuf>,
 }
 
+#[derive(FromArgs)]
+#[argh(subcommand, name = "console")]
+/// Manage the socket ports of a multiport vhost-user console device
+pub struct ConsoleCommand {
+    #[argh(subcommand)]
+    pub command: ConsoleSubcommand,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+pub enum ConsoleSubcommand {
+    AddPort(ConsoleAddPortCommand),
+    List(ConsoleListCommand),
+    RemovePort(ConsoleRemovePortCommand),
+}
+
+#[derive(FromArgs)]
+/// Add a port bound to a Unix socket listening at PATH
+#[argh(subcommand, name = "add-port")]
+pub struct ConsoleAddPortCommand {
+    #[argh(switch)]
+    /// use the port as a guest console
+    pub console: bool,
+    #[argh(positional, arg_name = "NAME")]
+    /// port name
+    pub name: String,
+    #[argh(positional, arg_name = "PATH")]
+    /// path of the socket the port listens on
+    pub path: PathBuf,
+    #[argh(positional, arg_name = "CONTROL_SOCKET")]
+    /// control socket path of the console device
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// List the ports of the console device
+#[argh(subcommand, name = "list")]
+pub struct ConsoleListCommand {
+    #[argh(positional, arg_name = "CONTROL_SOCKET")]
+    /// control socket path of the console device
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// Remove the socket port named NAME
+#[argh(subcommand, name = "remove-port")]
+pub struct ConsoleRemovePortCommand {
+    #[argh(positional, arg_name = "NAME")]
+    /// port name
+    pub name: String,
+    #[argh(positional, arg_name = "CONTROL_SOCKET")]
+    /// control socket path of the console device
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "serial")]
+/// Inspect serial devices
+pub struct SerialCommand {
+    #[argh(subcommand)]
+    pub command: SerialSubcommand,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+pub enum SerialSubcommand {
+    Dump(SerialDumpCommand),
+}
+
+#[derive(FromArgs)]
+/// Print the output kept by a serial device of type ring-buffer
+#[argh(subcommand, name = "dump")]
+pub struct SerialDumpCommand {
+    #[argh(positional, arg_name = "PATH")]
+    /// path of the ring buffer file
+    pub path: PathBuf,
+}
+
 #[derive(FromArgs)]
 #[argh(subcommand)]
 /// Unix Commands
 pub enum Commands {
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    Console(ConsoleCommand),
     #[cfg(any(target_os = "android", target_os = "linux"))]
     Devices(DevicesCommand),
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    Serial(SerialCommand),
 }
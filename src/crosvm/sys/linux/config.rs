@@ -42,7 +42,15 @@ pub fn check_serial_params(_serial_params: &SerialParameters) -> Result<(), Stri
     Ok(())
 }
 
-pub fn validate_config(_cfg: &mut Config) -> std::result::Result<(), String> {
+pub fn validate_config(cfg: &mut Config) -> std::result::Result<(), String> {
+    if cfg
+        .vfio
+        .iter()
+        .any(|vfio| vfio.iommufd && vfio.iommu != IommuDevType::VirtioIommu)
+    {
+        return Err("vfio iommufd requires iommu=viommu".to_string());
+    }
+
     Ok(())
 }
 
@@ -64,6 +72,12 @@ pub struct VfioOption {
     /// The symbol that labels the overlay device tree node which corresponds to this
     /// VFIO device.
     pub dt_symbol: Option<String>,
+
+    /// Open the VFIO device through its cdev and bind it to iommufd rather than using its VFIO
+    /// group, so that its I/O page faults can be reported to the guest. Requires
+    /// `iommu=viommu`.
+    #[serde(default)]
+    pub iommufd: bool,
 }
 
 #[derive(Default, Eq, PartialEq, Serialize, Deserialize)]
@@ -141,6 +155,17 @@ impl FromStr for SharedDir {
         //   0) This feature is arc_quota specific feature.
         // * max_dynamic_xattr=uint - number of maximum number of dynamic xattr paths (default: 0).
         //   This feature is arc_quota specific feature.
+        // * uid_map=[GUEST HOST COUNT,...] - translation of guest uids to host uids performed by
+        //   the fs device on every request, unlike uidmap which configures the jail (default:
+        //   identity)
+        // * gid_map=[GUEST HOST COUNT,...] - same as uid_map for gids (default: identity)
+        // * id_squash=SQUASH - one of "none", "root" or "all" (default: none)
+        // * squash_uid=UID, squash_gid=GID - host ids squashed guest ids are mapped to (default:
+        //   65534)
+        // * overlay_lower=[DIR,...] - read-only directories the shared directory is layered over as
+        //   a copy-on-write overlay, highest first (default: none)
+        // * archive=BOOL - whether the source path is a tar, squashfs or EROFS image served
+        //   read-only instead of a directory (default: false)
         //
         // These two options (uid/gid) are useful when the crosvm process has no
         // CAP_SETGID/CAP_SETUID but an identity mapping of the current user/group
@@ -163,10 +188,6 @@ impl FromStr for SharedDir {
             .context("missing tag for `shared-dir`")?
             .to_owned();
 
-        if !src.is_dir() {
-            bail!("source path for `shared-dir` must be a directory");
-        }
-
         let mut shared_dir = SharedDir {
             src,
             tag,
@@ -221,6 +242,14 @@ impl FromStr for SharedDir {
                     .map_err(|e| anyhow!("failed to parse 9p config '{:?}': {e}", type_opts))?;
             }
         }
+
+        if shared_dir.kind == SharedDirKind::FS && shared_dir.fs_cfg.archive {
+            if !shared_dir.src.is_file() {
+                bail!("source path for an archive `shared-dir` must be a file");
+            }
+        } else if !shared_dir.src.is_dir() {
+            bail!("source path for `shared-dir` must be a directory");
+        }
         Ok(shared_dir)
     }
 }
@@ -440,6 +469,37 @@ mod tests {
             vfio.guest_address,
             Some(PciAddress::new(0, 0x42, 0x15, 4).unwrap())
         );
+        assert!(!vfio.iommufd);
+    }
+
+    #[test]
+    fn vfio_pci_path_viommu_iommufd() {
+        let config: Config = crate::crosvm::cmdline::RunCommand::from_args(
+            &[],
+            &[
+                "--vfio",
+                "/path/to/dev,iommu=viommu,iommufd=true",
+                "/dev/null",
+            ],
+        )
+        .unwrap()
+        .try_into()
+        .unwrap();
+
+        let vfio = config.vfio.first().unwrap();
+
+        assert_eq!(vfio.iommu, IommuDevType::VirtioIommu);
+        assert!(vfio.iommufd);
+
+        // Faults are only reported through virtio-iommu.
+        assert!(TryInto::<Config>::try_into(
+            crate::crosvm::cmdline::RunCommand::from_args(
+                &[],
+                &["--vfio", "/path/to/dev,iommufd=true", "/dev/null"],
+            )
+            .unwrap(),
+        )
+        .is_err());
     }
 
     #[test]
@@ -622,6 +682,70 @@ mod tests {
         assert_eq!(shared_dir.fs_cfg.posix_acl, false);
     }
 
+    #[test]
+    fn parse_shared_dir_id_map() {
+        let s = "/:usr_local_bin:type=fs:uid_map=[0 100000 1,1000 2000 100]:gid_map=[0 100000 1]:id_squash=root:squash_uid=3000";
+
+        let shared_dir: SharedDir = s.parse().unwrap();
+        assert_eq!(
+            shared_dir.fs_cfg.uid_map,
+            vec![
+                devices::virtio::fs::IdMapRange {
+                    guest: 0,
+                    host: 100000,
+                    count: 1,
+                },
+                devices::virtio::fs::IdMapRange {
+                    guest: 1000,
+                    host: 2000,
+                    count: 100,
+                },
+            ]
+        );
+        assert_eq!(shared_dir.fs_cfg.gid_map.len(), 1);
+        assert_eq!(
+            shared_dir.fs_cfg.id_squash,
+            devices::virtio::fs::IdSquash::Root
+        );
+        assert_eq!(shared_dir.fs_cfg.squash_uid, 3000);
+        assert_eq!(shared_dir.fs_cfg.squash_gid, 65534);
+
+        assert!("/:usr_local_bin:type=fs:uid_map=[0 100000 0]"
+            .parse::<SharedDir>()
+            .is_err());
+    }
+
+    #[test]
+    fn parse_shared_dir_overlay() {
+        let s = "/:usr_local_bin:type=fs:overlay_lower=[/usr/share,/usr/lib]";
+
+        let shared_dir: SharedDir = s.parse().unwrap();
+        assert_eq!(
+            shared_dir.fs_cfg.overlay_lower,
+            vec![PathBuf::from("/usr/share"), PathBuf::from("/usr/lib")]
+        );
+    }
+
+    #[test]
+    fn parse_shared_dir_archive() {
+        let image = tempfile::NamedTempFile::new().unwrap();
+        let path = image.path().to_str().unwrap();
+
+        let shared_dir: SharedDir = format!("{path}:rootfs:type=fs:archive=true")
+            .parse()
+            .unwrap();
+        assert!(shared_dir.fs_cfg.archive);
+        assert_eq!(shared_dir.src, image.path());
+
+        // Archives must be files, and other shared directories must be directories.
+        assert!("/:rootfs:type=fs:archive=true"
+            .parse::<SharedDir>()
+            .is_err());
+        assert!(format!("{path}:rootfs:type=fs")
+            .parse::<SharedDir>()
+            .is_err());
+    }
+
     #[test]
     fn parse_shared_dir_negative_timeout() {
         // Although I want to test /usr/local/bin, Use / instead of
@@ -35,6 +35,8 @@ use devices::virtio::device_constants::video::VideoBackendType;
 use devices::virtio::device_constants::video::VideoDeviceType;
 use devices::virtio::ipc_memory_mapper::create_ipc_mapper;
 use devices::virtio::ipc_memory_mapper::CreateIpcMapperRet;
+#[cfg(feature = "media")]
+use devices::virtio::media::MediaDeviceConfig;
 use devices::virtio::memory_mapper::BasicMemoryMapper;
 use devices::virtio::memory_mapper::MemoryMapperTrait;
 #[cfg(feature = "pvclock")]
@@ -63,6 +65,8 @@ use devices::BusDeviceObj;
 use devices::IommuDevType;
 use devices::PciAddress;
 use devices::PciDevice;
+use devices::Swtpm;
+use devices::VfioContainer;
 use devices::VfioDevice;
 use devices::VfioDeviceType;
 use devices::VfioPciDevice;
@@ -297,9 +301,20 @@ impl<'a> VirtioDeviceBuilder for DiskConfig<'a> {
     }
 }
 
-pub struct ScsiConfig<'a>(pub &'a [ScsiOption]);
+pub struct ScsiConfig<'a> {
+    /// Options for the SCSI devices attached to the controller.
+    scsis: &'a [ScsiOption],
+    /// Optional control tube to eject and insert CD-ROM media.
+    device_tube: Option<Tube>,
+}
+
+impl<'a> ScsiConfig<'a> {
+    pub fn new(scsis: &'a [ScsiOption], device_tube: Option<Tube>) -> Self {
+        Self { scsis, device_tube }
+    }
+}
 
-impl<'a> VirtioDeviceBuilder for &'a ScsiConfig<'a> {
+impl<'a> VirtioDeviceBuilder for ScsiConfig<'a> {
     const NAME: &'static str = "scsi";
 
     fn create_virtio_device(
@@ -308,19 +323,28 @@ impl<'a> VirtioDeviceBuilder for &'a ScsiConfig<'a> {
     ) -> anyhow::Result<Box<dyn VirtioDevice>> {
         let base_features = virtio::base_features(protection_type);
         let disks = self
-            .0
+            .scsis
             .iter()
-            .map(|op| {
+            .enumerate()
+            .map(|(i, op)| {
                 info!("Trying to attach a scsi device: {}", op.path.display());
                 let file = op.open()?;
+                let target = match op.target {
+                    Some(target) => target,
+                    None => u8::try_from(i).context("too many scsi devices")?,
+                };
                 Ok(virtio::ScsiDiskConfig {
                     file,
                     block_size: op.block_size,
-                    read_only: op.read_only,
+                    read_only: op.read_only || op.cdrom,
+                    target,
+                    lun: op.lun,
+                    cdrom: op.cdrom,
+                    reservations: op.open_reservations()?,
                 })
             })
             .collect::<anyhow::Result<_>>()?;
-        let controller = virtio::ScsiController::new(base_features, disks)
+        let controller = virtio::ScsiController::new(base_features, disks, self.device_tube)
             .context("failed to create a scsi controller")?;
         Ok(Box::new(controller))
     }
@@ -392,11 +416,13 @@ pub fn create_virtio_snd_device(
     protection_type: ProtectionType,
     jail_config: &Option<JailConfig>,
     snd_params: SndParameters,
+    control_tube: Tube,
 ) -> DeviceResult {
     let backend = snd_params.backend;
     let dev = virtio::snd::common_backend::VirtioSnd::new(
         virtio::base_features(protection_type),
         snd_params,
+        Some(control_tube),
     )
     .context("failed to create cras sound device")?;
 
@@ -408,7 +434,16 @@ pub fn create_virtio_snd_device(
         Backend::Sys(virtio::snd::sys::StreamSourceBackend::AAUDIO) => "snd_aaudio_device",
         #[cfg(feature = "audio_cras")]
         Backend::Sys(virtio::snd::sys::StreamSourceBackend::CRAS) => "snd_cras_device",
-        #[cfg(not(any(feature = "audio_cras", feature = "audio_aaudio")))]
+        #[cfg(feature = "audio_alsa")]
+        Backend::Sys(virtio::snd::sys::StreamSourceBackend::ALSA) => "snd_alsa_device",
+        #[cfg(feature = "audio_pipewire")]
+        Backend::Sys(virtio::snd::sys::StreamSourceBackend::PIPEWIRE) => "snd_pipewire_device",
+        #[cfg(not(any(
+            feature = "audio_alsa",
+            feature = "audio_cras",
+            feature = "audio_aaudio",
+            feature = "audio_pipewire"
+        )))]
         _ => unreachable!(),
     };
 
@@ -418,6 +453,14 @@ pub fn create_virtio_snd_device(
         if backend == Backend::Sys(virtio::snd::sys::StreamSourceBackend::CRAS) {
             config.bind_mounts = true;
         }
+        #[cfg(feature = "audio_alsa")]
+        if backend == Backend::Sys(virtio::snd::sys::StreamSourceBackend::ALSA) {
+            config.bind_mounts = true;
+        }
+        #[cfg(feature = "audio_pipewire")]
+        if backend == Backend::Sys(virtio::snd::sys::StreamSourceBackend::PIPEWIRE) {
+            config.bind_mounts = true;
+        }
         // TODO(b/267574679): running as current_user may not be required for snd device.
         config.run_as = RunAsUser::CurrentUser;
         #[allow(unused_mut)]
@@ -428,6 +471,22 @@ pub fn create_virtio_snd_device(
             let run_cras_path = Path::new("/run/cras");
             jail.mount_bind(run_cras_path, run_cras_path, true)?;
         }
+        #[cfg(feature = "audio_alsa")]
+        if backend == Backend::Sys(virtio::snd::sys::StreamSourceBackend::ALSA) {
+            let dev_snd_path = Path::new("/dev/snd");
+            jail.mount_bind(dev_snd_path, dev_snd_path, true)?;
+            // alsa-lib reads its configuration, including the definition of "default", from here.
+            let alsa_config_path = Path::new("/usr/share/alsa");
+            if alsa_config_path.exists() {
+                jail.mount_bind(alsa_config_path, alsa_config_path, false)?;
+            }
+        }
+        #[cfg(feature = "audio_pipewire")]
+        if backend == Backend::Sys(virtio::snd::sys::StreamSourceBackend::PIPEWIRE) {
+            let socket_path = virtio::snd::sys::pipewire_socket_path()
+                .context("failed to locate the PipeWire socket")?;
+            jail.mount_bind(&socket_path, &socket_path, true)?;
+        }
         Some(jail)
     } else {
         None
@@ -465,6 +524,32 @@ pub fn create_vtpm_proxy_device(
     })
 }
 
+pub fn create_swtpm_device(
+    protection_type: ProtectionType,
+    jail_config: &Option<JailConfig>,
+    socket: &Path,
+) -> DeviceResult {
+    let jail = if let Some(jail_config) = jail_config {
+        let config = SandboxConfig::new(jail_config, "swtpm_device");
+        Some(create_sandbox_minijail(
+            &jail_config.pivot_root,
+            MAX_OPEN_FILES_DEFAULT,
+            &config,
+        )?)
+    } else {
+        None
+    };
+
+    // Connect before the device is jailed; the jailed process only uses the connected sockets.
+    let backend = Swtpm::new(socket).context("failed to connect to swtpm")?;
+    let dev = virtio::Tpm::new(Box::new(backend), virtio::base_features(protection_type));
+
+    Ok(VirtioDeviceStub {
+        dev: Box::new(dev),
+        jail,
+    })
+}
+
 pub fn create_single_touch_device<T: IntoUnixStream>(
     protection_type: ProtectionType,
     jail_config: &Option<JailConfig>,
@@ -473,12 +558,13 @@ pub fn create_single_touch_device<T: IntoUnixStream>(
     height: u32,
     name: Option<&str>,
     idx: u32,
+    control_tube: Tube,
 ) -> DeviceResult {
     let socket = single_touch_socket
         .into_unix_stream()
         .context("failed configuring virtio single touch")?;
 
-    let dev = virtio::input::new_single_touch(
+    let mut dev = virtio::input::new_single_touch(
         idx,
         socket,
         width,
@@ -487,6 +573,7 @@ pub fn create_single_touch_device<T: IntoUnixStream>(
         virtio::base_features(protection_type),
     )
     .context("failed to set up input device")?;
+    dev.set_control_tube(control_tube);
     Ok(VirtioDeviceStub {
         dev: Box::new(dev),
         jail: simple_jail(jail_config, "input_device")?,
@@ -501,12 +588,13 @@ pub fn create_multi_touch_device<T: IntoUnixStream>(
     height: u32,
     name: Option<&str>,
     idx: u32,
+    control_tube: Tube,
 ) -> DeviceResult {
     let socket = multi_touch_socket
         .into_unix_stream()
         .context("failed configuring virtio multi touch")?;
 
-    let dev = virtio::input::new_multi_touch(
+    let mut dev = virtio::input::new_multi_touch(
         idx,
         socket,
         width,
@@ -515,6 +603,7 @@ pub fn create_multi_touch_device<T: IntoUnixStream>(
         virtio::base_features(protection_type),
     )
     .context("failed to set up input device")?;
+    dev.set_control_tube(control_tube);
 
     Ok(VirtioDeviceStub {
         dev: Box::new(dev),
@@ -530,12 +619,13 @@ pub fn create_trackpad_device<T: IntoUnixStream>(
     height: u32,
     name: Option<&str>,
     idx: u32,
+    control_tube: Tube,
 ) -> DeviceResult {
     let socket = trackpad_socket
         .into_unix_stream()
         .context("failed configuring virtio trackpad")?;
 
-    let dev = virtio::input::new_trackpad(
+    let mut dev = virtio::input::new_trackpad(
         idx,
         socket,
         width,
@@ -544,6 +634,7 @@ pub fn create_trackpad_device<T: IntoUnixStream>(
         virtio::base_features(protection_type),
     )
     .context("failed to set up input device")?;
+    dev.set_control_tube(control_tube);
 
     Ok(VirtioDeviceStub {
         dev: Box::new(dev),
@@ -559,12 +650,13 @@ pub fn create_multitouch_trackpad_device<T: IntoUnixStream>(
     height: u32,
     name: Option<&str>,
     idx: u32,
+    control_tube: Tube,
 ) -> DeviceResult {
     let socket = trackpad_socket
         .into_unix_stream()
         .context("failed configuring virtio trackpad")?;
 
-    let dev = virtio::input::new_multitouch_trackpad(
+    let mut dev = virtio::input::new_multitouch_trackpad(
         idx,
         socket,
         width,
@@ -573,6 +665,7 @@ pub fn create_multitouch_trackpad_device<T: IntoUnixStream>(
         virtio::base_features(protection_type),
     )
     .context("failed to set up input device")?;
+    dev.set_control_tube(control_tube);
 
     Ok(VirtioDeviceStub {
         dev: Box::new(dev),
@@ -585,13 +678,15 @@ pub fn create_mouse_device<T: IntoUnixStream>(
     jail_config: &Option<JailConfig>,
     mouse_socket: T,
     idx: u32,
+    control_tube: Tube,
 ) -> DeviceResult {
     let socket = mouse_socket
         .into_unix_stream()
         .context("failed configuring virtio mouse")?;
 
-    let dev = virtio::input::new_mouse(idx, socket, virtio::base_features(protection_type))
+    let mut dev = virtio::input::new_mouse(idx, socket, virtio::base_features(protection_type))
         .context("failed to set up input device")?;
+    dev.set_control_tube(control_tube);
 
     Ok(VirtioDeviceStub {
         dev: Box::new(dev),
@@ -604,13 +699,15 @@ pub fn create_keyboard_device<T: IntoUnixStream>(
     jail_config: &Option<JailConfig>,
     keyboard_socket: T,
     idx: u32,
+    control_tube: Tube,
 ) -> DeviceResult {
     let socket = keyboard_socket
         .into_unix_stream()
         .context("failed configuring virtio keyboard")?;
 
-    let dev = virtio::input::new_keyboard(idx, socket, virtio::base_features(protection_type))
+    let mut dev = virtio::input::new_keyboard(idx, socket, virtio::base_features(protection_type))
         .context("failed to set up input device")?;
+    dev.set_control_tube(control_tube);
 
     Ok(VirtioDeviceStub {
         dev: Box::new(dev),
@@ -623,13 +720,15 @@ pub fn create_switches_device<T: IntoUnixStream>(
     jail_config: &Option<JailConfig>,
     switches_socket: T,
     idx: u32,
+    control_tube: Tube,
 ) -> DeviceResult {
     let socket = switches_socket
         .into_unix_stream()
         .context("failed configuring virtio switches")?;
 
-    let dev = virtio::input::new_switches(idx, socket, virtio::base_features(protection_type))
+    let mut dev = virtio::input::new_switches(idx, socket, virtio::base_features(protection_type))
         .context("failed to set up input device")?;
+    dev.set_control_tube(control_tube);
 
     Ok(VirtioDeviceStub {
         dev: Box::new(dev),
@@ -642,13 +741,15 @@ pub fn create_rotary_device<T: IntoUnixStream>(
     jail_config: &Option<JailConfig>,
     rotary_socket: T,
     idx: u32,
+    control_tube: Tube,
 ) -> DeviceResult {
     let socket = rotary_socket
         .into_unix_stream()
         .context("failed configuring virtio rotary")?;
 
-    let dev = virtio::input::new_rotary(idx, socket, virtio::base_features(protection_type))
+    let mut dev = virtio::input::new_rotary(idx, socket, virtio::base_features(protection_type))
         .context("failed to set up input device")?;
+    dev.set_control_tube(control_tube);
 
     Ok(VirtioDeviceStub {
         dev: Box::new(dev),
@@ -660,6 +761,8 @@ pub fn create_vinput_device(
     protection_type: ProtectionType,
     jail_config: &Option<JailConfig>,
     dev_path: &Path,
+    grab_hotkey: Option<&[u16]>,
+    control_tube: Tube,
 ) -> DeviceResult {
     let dev_file = OpenOptions::new()
         .read(true)
@@ -667,8 +770,13 @@ pub fn create_vinput_device(
         .open(dev_path)
         .with_context(|| format!("failed to open vinput device {}", dev_path.display()))?;
 
-    let dev = virtio::input::new_evdev(dev_file, virtio::base_features(protection_type))
-        .context("failed to set up input device")?;
+    let mut dev = virtio::input::new_evdev(
+        dev_file,
+        grab_hotkey,
+        virtio::base_features(protection_type),
+    )
+    .context("failed to set up input device")?;
+    dev.set_control_tube(control_tube);
 
     Ok(VirtioDeviceStub {
         dev: Box::new(dev),
@@ -910,6 +1018,8 @@ pub fn create_video_device(
             VideoBackendType::Vaapi => true,
             #[cfg(feature = "ffmpeg")]
             VideoBackendType::Ffmpeg => false,
+            #[cfg(feature = "swdec")]
+            VideoBackendType::Swdec => false,
         };
 
         if need_drm_device {
@@ -969,6 +1079,35 @@ pub fn register_video_device(
     Ok(())
 }
 
+#[cfg(feature = "media")]
+pub fn create_media_device(
+    protection_type: ProtectionType,
+    jail_config: &Option<JailConfig>,
+    media_config: &MediaDeviceConfig,
+) -> DeviceResult {
+    let dev = virtio::MediaDevice::new(virtio::base_features(protection_type), media_config)
+        .context("failed to create media device")?;
+
+    let jail = if let Some(jail_config) = jail_config {
+        let mut config = SandboxConfig::new(jail_config, "media_device");
+        config.bind_mounts = true;
+        let mut jail =
+            create_sandbox_minijail(&jail_config.pivot_root, MAX_OPEN_FILES_DEFAULT, &config)?;
+        // The proxy backend opens the host device node for every guest session.
+        if let Some(device) = &media_config.device {
+            jail.mount_bind(device, device, true)?;
+        }
+        Some(jail)
+    } else {
+        None
+    };
+
+    Ok(VirtioDeviceStub {
+        dev: Box::new(dev),
+        jail,
+    })
+}
+
 impl VirtioDeviceBuilder for &VsockConfig {
     const NAME: &'static str = "vhost_vsock";
 
@@ -1026,7 +1165,19 @@ pub fn create_fs_device(
 ) -> DeviceResult {
     let max_open_files =
         base::linux::max_open_files().context("failed to get max number of open files")?;
+    // Archives are opened before the device enters its jail, which then needs no access to the
+    // host file system.
+    let image = if fs_cfg.archive {
+        Some(File::open(src).with_context(|| format!("failed to open archive {}", src.display()))?)
+    } else {
+        None
+    };
     let j = if let Some(jail_config) = jail_config {
+        let root = if image.is_some() {
+            jail_config.pivot_root.as_path()
+        } else {
+            src
+        };
         let mut config = SandboxConfig::new(jail_config, "fs_device");
         config.limit_caps = false;
         config.ugid_map = Some((uid_map, gid_map));
@@ -1038,21 +1189,33 @@ pub fn create_fs_device(
         } else {
             RunAsUser::Specified(ugid.0.unwrap_or(0), ugid.1.unwrap_or(0))
         };
-        create_sandbox_minijail(src, max_open_files, &config)?
+        create_sandbox_minijail(root, max_open_files, &config)?
     } else {
-        create_base_minijail(src, max_open_files)?
+        let root = if image.is_some() { Path::new("/") } else { src };
+        create_base_minijail(root, max_open_files)?
     };
 
     let features = virtio::base_features(protection_type);
     // TODO(chirantan): Use more than one worker once the kernel driver has been fixed to not panic
     // when num_queues > 1.
-    let dev = virtio::fs::Fs::new(features, tag, 1, fs_cfg, device_tube)
-        .context("failed to create fs device")?;
+    let dev: Box<dyn VirtioDevice> = if let Some(image) = image {
+        Box::new(
+            virtio::fs::Fs::new_archive(features, tag, 1, fs_cfg, image, device_tube)
+                .context("failed to create archive fs device")?,
+        )
+    } else if fs_cfg.overlay_lower.is_empty() {
+        Box::new(
+            virtio::fs::Fs::new(features, tag, 1, fs_cfg, device_tube)
+                .context("failed to create fs device")?,
+        )
+    } else {
+        Box::new(
+            virtio::fs::Fs::new_overlay(features, tag, 1, fs_cfg, device_tube)
+                .context("failed to create overlay fs device")?,
+        )
+    };
 
-    Ok(VirtioDeviceStub {
-        dev: Box::new(dev),
-        jail: Some(j),
-    })
+    Ok(VirtioDeviceStub { dev, jail: Some(j) })
 }
 
 pub fn create_9p_device(
@@ -1300,7 +1463,9 @@ pub fn create_iommu_device(
     jail_config: &Option<JailConfig>,
     iova_max_addr: u64,
     endpoints: BTreeMap<u32, Arc<Mutex<Box<dyn MemoryMapperTrait>>>>,
+    fault_queues: BTreeMap<u32, File>,
     hp_endpoints_ranges: Vec<RangeInclusive<u32>>,
+    domain_range: RangeInclusive<u32>,
     translate_response_senders: Option<BTreeMap<u32, Tube>>,
     translate_request_rx: Option<Tube>,
     iommu_device_tube: Tube,
@@ -1308,8 +1473,10 @@ pub fn create_iommu_device(
     let dev = virtio::Iommu::new(
         virtio::base_features(protection_type),
         endpoints,
+        fault_queues,
         iova_max_addr,
         hp_endpoints_ranges,
+        domain_range,
         translate_response_senders,
         translate_request_rx,
         Some(iommu_device_tube),
@@ -1424,11 +1591,19 @@ pub fn create_vfio_device(
     coiommu_endpoints: Option<&mut Vec<u16>>,
     iommu_dev: IommuDevType,
     dt_symbol: Option<String>,
+    iommufd: bool,
     vfio_container_manager: &mut VfioContainerManager,
 ) -> DeviceResult<(VfioDeviceVariant, Option<Minijail>, Option<VfioWrapper>)> {
-    let vfio_container = vfio_container_manager
-        .get_container(iommu_dev, Some(vfio_path))
-        .context("failed to get vfio container")?;
+    // Each device bound through iommufd gets an IOAS of its own.
+    let vfio_container = if iommufd {
+        Arc::new(Mutex::new(
+            VfioContainer::new_iommufd().context("failed to create iommufd vfio container")?,
+        ))
+    } else {
+        vfio_container_manager
+            .get_container(iommu_dev, Some(vfio_path))
+            .context("failed to get vfio container")?
+    };
 
     let (vfio_host_tube_mem, vfio_device_tube_mem) =
         Tube::pair().context("failed to create tube")?;
@@ -1440,9 +1615,14 @@ pub fn create_vfio_device(
     let (vfio_host_tube_vm, vfio_device_tube_vm) = Tube::pair().context("failed to create tube")?;
     control_tubes.push(TaggedControlTube::Vm(vfio_host_tube_vm));
 
-    let vfio_device =
+    let mut vfio_device = if iommufd {
+        VfioDevice::new_passthrough_iommufd(&vfio_path, vm, vfio_container.clone(), dt_symbol)
+    } else {
         VfioDevice::new_passthrough(&vfio_path, vm, vfio_container.clone(), iommu_dev, dt_symbol)
-            .context("failed to create vfio device")?;
+    }
+    .context("failed to create vfio device")?;
+    let group_id = vfio_device.group_id();
+    let fault_queue = vfio_device.take_fault_queue();
 
     match vfio_device.device_type() {
         VfioDeviceType::Pci => {
@@ -1472,6 +1652,12 @@ pub fn create_vfio_device(
 
             let viommu_mapper = match iommu_dev {
                 IommuDevType::NoIommu | IommuDevType::PkvmPviommu => None,
+                IommuDevType::VirtioIommu if iommufd => Some(VfioWrapper::new_iommufd(
+                    vfio_container,
+                    group_id,
+                    fault_queue,
+                    vm.get_memory().clone(),
+                )),
                 IommuDevType::VirtioIommu => {
                     Some(VfioWrapper::new(vfio_container, vm.get_memory().clone()))
                 }
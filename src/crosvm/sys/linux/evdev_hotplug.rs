@@ -0,0 +1,539 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Passes host event devices through to the guest as they are plugged in, and removes them when
+//! they are unplugged.
+//!
+//! The input class in sysfs is scanned for event devices matching one of the `--input-hotplug`
+//! options whenever a node is created, removed or changed in `/dev/input`, as reported by inotify.
+//! Each one is added to the guest as a hotplugged virtio-input PCI device through a regular VM
+//! control tube.
+//!
+//! Event device names are reused by the kernel, so devices are told apart by their sysfs path,
+//! which includes the number of the input device allocated at each plug.
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::ffi::CString;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::mem::size_of;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Context;
+use anyhow::Result;
+use base::error;
+use base::info;
+use base::AsRawDescriptor;
+use base::FromRawDescriptor;
+use base::SafeDescriptor;
+use base::Tube;
+use base::WaitContext;
+use vm_control::InputHotplugCommand;
+use vm_control::VmRequest;
+use vm_control::VmResponse;
+
+use crate::crosvm::config::InputHotplugOption;
+
+const INPUT_CLASS_DIR: &str = "/sys/class/input";
+const INPUT_DEV_DIR: &str = "/dev/input";
+/// Interval between scans when `INPUT_DEV_DIR` cannot be watched.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Identity of a host event device as reported by sysfs.
+#[derive(Debug, PartialEq, Eq)]
+struct EvdevInfo {
+    /// Resolved sysfs directory of the device, which differs each time a device is plugged even if
+    /// it gets the same event device name.
+    sysfs_path: PathBuf,
+    vendor: Option<u16>,
+    product: Option<u16>,
+    name: String,
+}
+
+impl EvdevInfo {
+    /// Reads the identity of the event device described by the sysfs directory `dir`.
+    fn read(dir: &Path) -> Option<EvdevInfo> {
+        let read_id = |id: &str| {
+            let value = fs::read_to_string(dir.join("device/id").join(id)).ok()?;
+            u16::from_str_radix(value.trim(), 16).ok()
+        };
+        let name = fs::read_to_string(dir.join("device/name")).ok()?;
+        Some(EvdevInfo {
+            sysfs_path: fs::canonicalize(dir).ok()?,
+            vendor: read_id("vendor"),
+            product: read_id("product"),
+            name: name.trim_end().to_owned(),
+        })
+    }
+
+    fn matches(&self, option: &InputHotplugOption) -> bool {
+        option.vendor.map_or(true, |v| self.vendor == Some(v))
+            && option.product.map_or(true, |p| self.product == Some(p))
+            && option.name.as_ref().map_or(true, |n| *n == self.name)
+    }
+}
+
+/// Returns the event devices listed in `class_dir`, indexed by their name (e.g. "event3").
+fn scan(class_dir: &Path) -> BTreeMap<String, EvdevInfo> {
+    let Ok(entries) = fs::read_dir(class_dir) else {
+        return BTreeMap::new();
+    };
+    entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            if !name.starts_with("event") {
+                return None;
+            }
+            Some((name, EvdevInfo::read(&entry.path())?))
+        })
+        .collect()
+}
+
+/// Returns the names of the files in the inotify events read into `buf`.
+fn inotify_names(mut buf: &[u8]) -> BTreeSet<String> {
+    const HEADER_LEN: usize = size_of::<libc::inotify_event>();
+    let mut names = BTreeSet::new();
+    while buf.len() >= HEADER_LEN {
+        // The length of the name is the last field of the header. It includes the NUL padding.
+        let len = u32::from_ne_bytes(buf[HEADER_LEN - 4..HEADER_LEN].try_into().unwrap()) as usize;
+        let Some(name) = buf.get(HEADER_LEN..HEADER_LEN + len) else {
+            break;
+        };
+        let name = name.split(|&b| b == 0).next().unwrap_or_default();
+        if let Ok(name) = std::str::from_utf8(name) {
+            if !name.is_empty() {
+                names.insert(name.to_owned());
+            }
+        }
+        buf = &buf[HEADER_LEN + len..];
+    }
+    names
+}
+
+/// Reports the nodes created, removed or modified in a directory.
+struct DirWatcher {
+    inotify: File,
+    wait_ctx: WaitContext<()>,
+}
+
+impl DirWatcher {
+    fn new(dir: &Path) -> Result<DirWatcher> {
+        // SAFETY:
+        // Trivially safe.
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error()).context("failed to create inotify instance");
+        }
+        // SAFETY:
+        // `fd` is a valid descriptor that nothing else owns.
+        let inotify = File::from(unsafe { SafeDescriptor::from_raw_descriptor(fd) });
+
+        let path = CString::new(dir.as_os_str().as_bytes()).context("invalid path")?;
+        // SAFETY:
+        // `inotify` is a valid inotify descriptor and `path` is a NUL-terminated string.
+        let ret = unsafe {
+            libc::inotify_add_watch(
+                inotify.as_raw_descriptor(),
+                path.as_ptr(),
+                libc::IN_CREATE | libc::IN_DELETE | libc::IN_ATTRIB,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error())
+                .with_context(|| format!("failed to watch {}", dir.display()));
+        }
+
+        let wait_ctx =
+            WaitContext::build_with(&[(&inotify, ())]).context("failed to create wait context")?;
+        Ok(DirWatcher { inotify, wait_ctx })
+    }
+
+    /// Waits for changes in the directory and returns the names of the nodes concerned.
+    fn wait(&mut self) -> Result<BTreeSet<String>> {
+        self.wait_ctx.wait().context("failed to wait for inotify")?;
+        let mut buf = [0u8; 4096];
+        let len = loop {
+            match self.inotify.read(&mut buf) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                r => break r.context("failed to read inotify events")?,
+            }
+        };
+        Ok(inotify_names(&buf[..len]))
+    }
+}
+
+/// A device plugged in the guest.
+struct PluggedDevice {
+    sysfs_path: PathBuf,
+    bus: u8,
+}
+
+struct EvdevHotplug {
+    options: Vec<InputHotplugOption>,
+    tube: Tube,
+    // Devices plugged in the guest, by event device name.
+    plugged: BTreeMap<String, PluggedDevice>,
+    // Sysfs paths of the matching devices that could not be added, by event device name, to avoid
+    // retrying until they are unplugged or their node changes.
+    failed: BTreeMap<String, PathBuf>,
+}
+
+impl EvdevHotplug {
+    fn request(&self, command: InputHotplugCommand) -> Result<VmResponse> {
+        self.tube
+            .send(&VmRequest::HotPlugInputCommand(command))
+            .context("failed to send hotplug request")?;
+        self.tube
+            .recv::<VmResponse>()
+            .context("failed to receive hotplug response")
+    }
+
+    /// Allows adding the devices of `nodes` again, e.g. after their permissions changed.
+    fn retry(&mut self, nodes: &BTreeSet<String>) {
+        self.failed.retain(|name, _| !nodes.contains(name));
+    }
+
+    /// Adds newly plugged matching devices to the guest and removes the ones that are gone,
+    /// including the ones replaced by another device with the same name.
+    fn update(&mut self, devices: &BTreeMap<String, EvdevInfo>) -> Result<()> {
+        let present = |name: &String, sysfs_path: &PathBuf| {
+            devices
+                .get(name)
+                .is_some_and(|device| device.sysfs_path == *sysfs_path)
+        };
+        self.failed
+            .retain(|name, sysfs_path| present(name, sysfs_path));
+
+        let removed: Vec<(String, u8)> = self
+            .plugged
+            .iter()
+            .filter(|(name, device)| !present(name, &device.sysfs_path))
+            .map(|(name, device)| (name.clone(), device.bus))
+            .collect();
+        for (name, bus) in removed {
+            self.plugged.remove(&name);
+            info!("removing unplugged input device {}", name);
+            match self.request(InputHotplugCommand::RemoveEvdev(bus))? {
+                VmResponse::Ok => {}
+                r => error!("failed to remove input device {}: {}", name, r),
+            }
+        }
+
+        for (name, device) in devices {
+            if self.plugged.contains_key(name) || self.failed.contains_key(name) {
+                continue;
+            }
+            let Some(option) = self.options.iter().find(|o| device.matches(o)) else {
+                continue;
+            };
+            info!("adding input device {} ({})", name, device.name);
+            let command = InputHotplugCommand::AddEvdev {
+                path: Path::new(INPUT_DEV_DIR).join(name),
+                grab_hotkey: option.grab_hotkey.clone(),
+            };
+            match self.request(command)? {
+                VmResponse::PciHotPlugResponse { bus } => {
+                    self.plugged.insert(
+                        name.clone(),
+                        PluggedDevice {
+                            sysfs_path: device.sysfs_path.clone(),
+                            bus,
+                        },
+                    );
+                }
+                r => {
+                    error!("failed to add input device {}: {}", name, r);
+                    self.failed.insert(name.clone(), device.sysfs_path.clone());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Starts a thread passing through the host event devices matching `options`, using `tube` to
+/// send VM requests. The thread exits once the other end of `tube` is closed.
+pub fn start_evdev_hotplug_thread(options: Vec<InputHotplugOption>, tube: Tube) -> Result<()> {
+    let mut hotplug = EvdevHotplug {
+        options,
+        tube,
+        plugged: BTreeMap::new(),
+        failed: BTreeMap::new(),
+    };
+    thread::Builder::new()
+        .name("input_hotplug".to_owned())
+        .spawn(move || {
+            let mut watcher = match DirWatcher::new(Path::new(INPUT_DEV_DIR)) {
+                Ok(watcher) => Some(watcher),
+                Err(e) => {
+                    error!("polling input devices: {:#}", e);
+                    None
+                }
+            };
+            loop {
+                if let Err(e) = hotplug.update(&scan(Path::new(INPUT_CLASS_DIR))) {
+                    info!("stopping input hotplug: {:#}", e);
+                    break;
+                }
+                match watcher.as_mut().map(DirWatcher::wait) {
+                    Some(Ok(nodes)) => hotplug.retry(&nodes),
+                    Some(Err(e)) => {
+                        error!("polling input devices: {:#}", e);
+                        watcher = None;
+                    }
+                    None => thread::sleep(POLL_INTERVAL),
+                }
+            }
+        })
+        .context("failed to spawn input hotplug thread")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    /// Creates a sysfs entry for `event` in `class_dir`, linking to a device directory under
+    /// `class_dir/devices/input`, like the kernel does.
+    fn add_evdev(class_dir: &Path, event: &str, input: u32, vendor: &str, name: &str) {
+        let target = class_dir
+            .join("devices")
+            .join(format!("input{}", input))
+            .join(event);
+        let device = target.join("device");
+        fs::create_dir_all(device.join("id")).unwrap();
+        fs::write(device.join("id/vendor"), vendor).unwrap();
+        fs::write(device.join("id/product"), "c31c\n").unwrap();
+        fs::write(device.join("name"), name).unwrap();
+        symlink(&target, class_dir.join(event)).unwrap();
+    }
+
+    fn remove_evdev(class_dir: &Path, event: &str) {
+        fs::remove_file(class_dir.join(event)).unwrap();
+    }
+
+    /// Answers the hotplug requests received on `tube` with `responses`, in order, and returns the
+    /// requests once the other end of `tube` is closed.
+    fn fake_vmm(
+        tube: Tube,
+        responses: Vec<VmResponse>,
+    ) -> thread::JoinHandle<Vec<InputHotplugCommand>> {
+        thread::spawn(move || {
+            let mut commands = Vec::new();
+            let mut responses = responses.into_iter();
+            while let Ok(VmRequest::HotPlugInputCommand(command)) = tube.recv::<VmRequest>() {
+                commands.push(command);
+                tube.send(&responses.next().expect("unexpected request"))
+                    .unwrap();
+            }
+            commands
+        })
+    }
+
+    fn keyboard_hotplug(tube: Tube) -> EvdevHotplug {
+        EvdevHotplug {
+            options: vec![InputHotplugOption {
+                vendor: Some(0x046d),
+                product: None,
+                name: None,
+                grab_hotkey: None,
+            }],
+            tube,
+            plugged: BTreeMap::new(),
+            failed: BTreeMap::new(),
+        }
+    }
+
+    fn added(command: &InputHotplugCommand) -> &Path {
+        match command {
+            InputHotplugCommand::AddEvdev { path, .. } => path,
+            c => panic!("unexpected command {:?}", c),
+        }
+    }
+
+    fn removed(command: &InputHotplugCommand) -> u8 {
+        match command {
+            InputHotplugCommand::RemoveEvdev(bus) => *bus,
+            c => panic!("unexpected command {:?}", c),
+        }
+    }
+
+    #[test]
+    fn scan_and_match() {
+        let class_dir = TempDir::new().unwrap();
+        add_evdev(class_dir.path(), "event0", 1, "0000\n", "Power Button\n");
+        add_evdev(
+            class_dir.path(),
+            "event5",
+            7,
+            "046d\n",
+            "Logitech USB Keyboard\n",
+        );
+        // Other input class entries don't have event nodes.
+        fs::create_dir(class_dir.path().join("input5")).unwrap();
+
+        let devices = scan(class_dir.path());
+        assert_eq!(devices.len(), 2);
+        let keyboard = &devices["event5"];
+        assert_eq!(
+            *keyboard,
+            EvdevInfo {
+                sysfs_path: fs::canonicalize(class_dir.path().join("devices/input7/event5"))
+                    .unwrap(),
+                vendor: Some(0x046d),
+                product: Some(0xc31c),
+                name: "Logitech USB Keyboard".to_owned(),
+            }
+        );
+
+        let option = |vendor, product, name: Option<&str>| InputHotplugOption {
+            vendor,
+            product,
+            name: name.map(str::to_owned),
+            grab_hotkey: None,
+        };
+        assert!(keyboard.matches(&option(Some(0x046d), None, None)));
+        assert!(keyboard.matches(&option(Some(0x046d), Some(0xc31c), None)));
+        assert!(keyboard.matches(&option(None, None, Some("Logitech USB Keyboard"))));
+        assert!(!keyboard.matches(&option(Some(0x046d), Some(0xc52b), None)));
+        assert!(!keyboard.matches(&option(None, None, Some("Logitech"))));
+        assert!(!devices["event0"].matches(&option(Some(0x046d), None, None)));
+    }
+
+    #[test]
+    fn unplug_and_replug() {
+        let class_dir = TempDir::new().unwrap();
+        let (tube, vmm_tube) = Tube::pair().unwrap();
+        let vmm = fake_vmm(
+            vmm_tube,
+            vec![
+                VmResponse::PciHotPlugResponse { bus: 3 },
+                VmResponse::Ok,
+                VmResponse::PciHotPlugResponse { bus: 4 },
+            ],
+        );
+        let mut hotplug = keyboard_hotplug(tube);
+
+        add_evdev(class_dir.path(), "event5", 7, "046d\n", "Keyboard\n");
+        add_evdev(class_dir.path(), "event6", 8, "0000\n", "Mouse\n");
+        hotplug.update(&scan(class_dir.path())).unwrap();
+        // Nothing changed, so nothing is sent.
+        hotplug.update(&scan(class_dir.path())).unwrap();
+        remove_evdev(class_dir.path(), "event5");
+        hotplug.update(&scan(class_dir.path())).unwrap();
+        add_evdev(class_dir.path(), "event5", 9, "046d\n", "Keyboard\n");
+        hotplug.update(&scan(class_dir.path())).unwrap();
+        drop(hotplug);
+
+        let commands = vmm.join().unwrap();
+        assert_eq!(commands.len(), 3);
+        assert_eq!(added(&commands[0]), Path::new("/dev/input/event5"));
+        assert_eq!(removed(&commands[1]), 3);
+        assert_eq!(added(&commands[2]), Path::new("/dev/input/event5"));
+    }
+
+    #[test]
+    fn replug_between_scans() {
+        let class_dir = TempDir::new().unwrap();
+        let (tube, vmm_tube) = Tube::pair().unwrap();
+        let vmm = fake_vmm(
+            vmm_tube,
+            vec![
+                VmResponse::PciHotPlugResponse { bus: 3 },
+                VmResponse::Ok,
+                VmResponse::PciHotPlugResponse { bus: 4 },
+            ],
+        );
+        let mut hotplug = keyboard_hotplug(tube);
+
+        add_evdev(class_dir.path(), "event5", 7, "046d\n", "Keyboard\n");
+        hotplug.update(&scan(class_dir.path())).unwrap();
+        // The keyboard is replaced by a device that gets the same event node name.
+        remove_evdev(class_dir.path(), "event5");
+        add_evdev(class_dir.path(), "event5", 8, "046d\n", "Other Keyboard\n");
+        hotplug.update(&scan(class_dir.path())).unwrap();
+        drop(hotplug);
+
+        let commands = vmm.join().unwrap();
+        assert_eq!(commands.len(), 3);
+        assert_eq!(removed(&commands[1]), 3);
+        assert_eq!(added(&commands[2]), Path::new("/dev/input/event5"));
+    }
+
+    #[test]
+    fn retry_failed_device() {
+        let class_dir = TempDir::new().unwrap();
+        let (tube, vmm_tube) = Tube::pair().unwrap();
+        let vmm = fake_vmm(
+            vmm_tube,
+            vec![
+                VmResponse::ErrString("permission denied".to_owned()),
+                VmResponse::PciHotPlugResponse { bus: 3 },
+            ],
+        );
+        let mut hotplug = keyboard_hotplug(tube);
+
+        add_evdev(class_dir.path(), "event5", 7, "046d\n", "Keyboard\n");
+        hotplug.update(&scan(class_dir.path())).unwrap();
+        hotplug.update(&scan(class_dir.path())).unwrap();
+        // Changes to other nodes don't matter.
+        hotplug.retry(&BTreeSet::from(["event6".to_owned()]));
+        hotplug.update(&scan(class_dir.path())).unwrap();
+        hotplug.retry(&BTreeSet::from(["event5".to_owned()]));
+        hotplug.update(&scan(class_dir.path())).unwrap();
+        assert_eq!(hotplug.plugged["event5"].bus, 3);
+        drop(hotplug);
+
+        assert_eq!(vmm.join().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn watch_dir() {
+        let dir = TempDir::new().unwrap();
+        let mut watcher = DirWatcher::new(dir.path()).unwrap();
+
+        fs::write(dir.path().join("event5"), "").unwrap();
+        assert_eq!(
+            watcher.wait().unwrap(),
+            BTreeSet::from(["event5".to_owned()])
+        );
+        fs::remove_file(dir.path().join("event5")).unwrap();
+        assert_eq!(
+            watcher.wait().unwrap(),
+            BTreeSet::from(["event5".to_owned()])
+        );
+    }
+
+    #[test]
+    fn parse_inotify_events() {
+        let mut buf = Vec::new();
+        for (name, len) in [("event1", 16u32), ("", 0), ("event12", 16)] {
+            buf.extend_from_slice(&1i32.to_ne_bytes());
+            buf.extend_from_slice(&libc::IN_CREATE.to_ne_bytes());
+            buf.extend_from_slice(&0u32.to_ne_bytes());
+            buf.extend_from_slice(&len.to_ne_bytes());
+            let mut padded = name.as_bytes().to_vec();
+            padded.resize(len as usize, 0);
+            buf.extend_from_slice(&padded);
+        }
+        assert_eq!(
+            inotify_names(&buf),
+            BTreeSet::from(["event1".to_owned(), "event12".to_owned()])
+        );
+        // Truncated events are ignored.
+        assert!(inotify_names(&buf[..20]).is_empty());
+    }
+}
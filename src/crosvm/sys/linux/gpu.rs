@@ -8,7 +8,9 @@ static_assertions::assert_cfg!(feature = "gpu");
 
 use std::collections::HashMap;
 use std::env;
+use std::net::TcpListener;
 use std::path::PathBuf;
+use std::sync::Arc;
 
 use base::linux::move_proc_to_cgroup;
 use jail::*;
@@ -138,6 +140,25 @@ pub fn create_gpu_device(
         );
     }
 
+    // The listeners are bound before the device is jailed since the jail has no network access.
+    if let Some(addr) = cfg.vnc_display {
+        let num_scanouts = gpu_params.display_params.len().max(1);
+        let listeners = (0..num_scanouts)
+            .map(|scanout_id| {
+                let mut addr = addr;
+                let port = u16::try_from(scanout_id)
+                    .ok()
+                    .and_then(|offset| addr.port().checked_add(offset))
+                    .context("vnc display port out of range")?;
+                addr.set_port(port);
+                let listener = TcpListener::bind(addr)
+                    .with_context(|| format!("failed to bind vnc display to {}", addr))?;
+                Ok(Arc::new(listener))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        display_backends.insert(0, virtio::DisplayBackend::Vnc(listeners));
+    }
+
     let dev = virtio::Gpu::new(
         exit_evt_wrtube
             .try_clone()
@@ -40,6 +40,7 @@ use swap::SwapDeviceHelper;
 use sync::Mutex;
 use vm_memory::GuestMemory;
 
+use crate::crosvm::sys::linux::pci_hotplug_helpers::build_hotplug_input_device;
 use crate::crosvm::sys::linux::pci_hotplug_helpers::build_hotplug_net_device;
 use crate::crosvm::sys::linux::pci_hotplug_helpers::NetLocalParameters;
 use crate::crosvm::sys::linux::VirtioDeviceBuilder;
@@ -198,6 +199,16 @@ fn jail_worker_process(
                             build_hotplug_net_device(net_resource_carrier, net_local_parameters)?;
                         (pci_device, jail)
                     }
+                    ResourceCarrier::VirtioInput(input_resource_carrier) => {
+                        let jail = jail::simple_jail(&config.jail_config, "input_device")?
+                            .ok_or(anyhow!("no jail created"))?;
+                        let pci_device = build_hotplug_input_device(
+                            input_resource_carrier,
+                            guest_memory.clone(),
+                            config.protection_type,
+                        )?;
+                        (pci_device, jail)
+                    }
                 };
                 let mut keep_rds = vec![];
                 syslog::push_descriptors(&mut keep_rds);
@@ -256,6 +267,11 @@ impl JailWarden for PermissiveJailWarden {
                     NetLocalParameters::new(self.guest_memory.clone(), self.config.protection_type);
                 build_hotplug_net_device(net_resource_carrier, net_local_parameters)?
             }
+            ResourceCarrier::VirtioInput(input_resource_carrier) => build_hotplug_input_device(
+                input_resource_carrier,
+                self.guest_memory.clone(),
+                self.config.protection_type,
+            )?,
         };
         Ok((Arc::new(Mutex::new(pci_device)), 0))
     }
@@ -8,12 +8,18 @@
 
 use anyhow::Context;
 use anyhow::Result;
+use base::Tube;
+use devices::virtio;
+use devices::virtio::VirtioDevice;
 use devices::HotPluggable;
+use devices::InputResourceCarrier;
 use devices::IntxParameter;
 use devices::NetResourceCarrier;
+use devices::PciAddress;
 use devices::PciDevice;
 use devices::VirtioPciDevice;
 use hypervisor::ProtectionType;
+use vm_control::api::VmMemoryClient;
 use vm_memory::GuestMemory;
 
 use crate::crosvm::sys::linux::VirtioDeviceBuilder;
@@ -23,21 +29,68 @@ pub fn build_hotplug_net_device(
     net_carrier_device: NetResourceCarrier,
     net_local_parameters: NetLocalParameters,
 ) -> Result<Box<dyn HotPluggable>> {
-    let pci_address = net_carrier_device
-        .pci_address
-        .context("PCI address not allocated")?;
     let virtio_device = net_carrier_device
         .net_param
         .create_virtio_device(net_local_parameters.protection_type)
         .context("create virtio device")?;
-    let mut virtio_pci_device = VirtioPciDevice::new(
-        net_local_parameters.guest_memory,
+    build_hotplug_virtio_pci_device(
         virtio_device,
+        net_local_parameters.guest_memory,
         net_carrier_device.msi_device_tube,
-        true,
-        None,
         net_carrier_device.ioevent_vm_memory_client,
         net_carrier_device.vm_control_tube,
+        net_carrier_device.pci_address,
+        net_carrier_device.intx_parameter,
+    )
+}
+
+/// Builds HotPlugPci from InputResourceCarrier.
+pub fn build_hotplug_input_device(
+    input_carrier_device: InputResourceCarrier,
+    guest_memory: GuestMemory,
+    protection_type: ProtectionType,
+) -> Result<Box<dyn HotPluggable>> {
+    let virtio_device = virtio::input::new_evdev(
+        input_carrier_device.evdev,
+        input_carrier_device.grab_hotkey.as_deref(),
+        virtio::base_features(protection_type),
+    )
+    .with_context(|| {
+        format!(
+            "set up input device {}",
+            input_carrier_device.path.display()
+        )
+    })?;
+    build_hotplug_virtio_pci_device(
+        Box::new(virtio_device),
+        guest_memory,
+        input_carrier_device.msi_device_tube,
+        input_carrier_device.ioevent_vm_memory_client,
+        input_carrier_device.vm_control_tube,
+        input_carrier_device.pci_address,
+        input_carrier_device.intx_parameter,
+    )
+}
+
+/// Wraps `virtio_device` in a VirtioPciDevice laid out at the address allocated for it.
+fn build_hotplug_virtio_pci_device(
+    virtio_device: Box<dyn VirtioDevice>,
+    guest_memory: GuestMemory,
+    msi_device_tube: Tube,
+    ioevent_vm_memory_client: VmMemoryClient,
+    vm_control_tube: Tube,
+    pci_address: Option<PciAddress>,
+    intx_parameter: Option<IntxParameter>,
+) -> Result<Box<dyn HotPluggable>> {
+    let pci_address = pci_address.context("PCI address not allocated")?;
+    let mut virtio_pci_device = VirtioPciDevice::new(
+        guest_memory,
+        virtio_device,
+        msi_device_tube,
+        true,
+        None,
+        ioevent_vm_memory_client,
+        vm_control_tube,
     )
     .context("create virtio PCI device")?;
     virtio_pci_device
@@ -53,9 +106,7 @@ pub fn build_hotplug_net_device(
         irq_evt,
         irq_num,
         pin,
-    } = net_carrier_device
-        .intx_parameter
-        .context("Missing INTx parameter.")?;
+    } = intx_parameter.context("Missing INTx parameter.")?;
     virtio_pci_device.assign_irq(irq_evt, pin, irq_num);
     Ok(Box::new(virtio_pci_device))
 }
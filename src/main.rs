@@ -10,8 +10,11 @@
 #[cfg(any(feature = "composite-disk", feature = "qcow"))]
 use std::fs::OpenOptions;
 use std::path::Path;
+use std::time::Duration;
+use std::time::Instant;
 
 use anyhow::anyhow;
+use anyhow::bail;
 use anyhow::Context;
 use anyhow::Result;
 use argh::FromArgs;
@@ -26,7 +29,14 @@ mod crosvm;
 use crosvm::cmdline;
 #[cfg(feature = "plugin")]
 use crosvm::config::executable_is_plugin;
+#[cfg(feature = "audio")]
+use crosvm::config::parse_snd_control_values;
 use crosvm::config::Config;
+use devices::varstore;
+use devices::varstore::EfiTime;
+use devices::varstore::Guid;
+use devices::varstore::VarStore;
+use devices::varstore::Variable;
 use devices::virtio::vhost::user::device::run_block_device;
 #[cfg(feature = "gpu")]
 use devices::virtio::vhost::user::device::run_gpu_device;
@@ -61,7 +71,17 @@ use vm_control::client::do_gpu_display_list;
 #[cfg(feature = "gpu")]
 use vm_control::client::do_gpu_display_remove;
 #[cfg(feature = "gpu")]
+use vm_control::client::do_gpu_screenshot;
+#[cfg(feature = "gpu")]
 use vm_control::client::do_gpu_set_display_mouse_mode;
+#[cfg(feature = "gpu")]
+use vm_control::client::do_gpu_start_recording;
+#[cfg(all(feature = "gpu", any(target_os = "android", target_os = "linux")))]
+use vm_control::client::do_gpu_start_streaming;
+#[cfg(feature = "gpu")]
+use vm_control::client::do_gpu_stop_recording;
+#[cfg(all(feature = "gpu", any(target_os = "android", target_os = "linux")))]
+use vm_control::client::do_gpu_stop_streaming;
 use vm_control::client::do_modify_battery;
 #[cfg(feature = "pci-hotplug")]
 use vm_control::client::do_net_add;
@@ -72,22 +92,35 @@ use vm_control::client::do_swap_status;
 use vm_control::client::do_usb_attach;
 use vm_control::client::do_usb_detach;
 use vm_control::client::do_usb_list;
-#[cfg(feature = "balloon")]
 use vm_control::client::handle_request;
 use vm_control::client::vms_request;
 #[cfg(feature = "gpu")]
 use vm_control::client::ModifyGpuResult;
 use vm_control::client::ModifyUsbResult;
+use vm_control::input::key_events;
+use vm_control::input::parse_key_combo;
+use vm_control::input::parse_point;
+use vm_control::input::parse_switch;
+use vm_control::input::parse_trace;
+use vm_control::input::pointer_move_events;
+use vm_control::input::swipe_frames;
+use vm_control::input::switch_events;
+use vm_control::input::tap_frames;
+use vm_control::input::InputControlCommand;
+use vm_control::input::InputFrame;
+use vm_control::input::Keymap;
 #[cfg(feature = "balloon")]
 use vm_control::BalloonControlCommand;
 use vm_control::DiskControlCommand;
 use vm_control::HotPlugDeviceInfo;
 use vm_control::HotPlugDeviceType;
+use vm_control::ScsiControlCommand;
 use vm_control::SnapshotCommand;
+#[cfg(feature = "audio")]
+use vm_control::SndControlCommand;
 use vm_control::SwapCommand;
 use vm_control::UsbControlResult;
 use vm_control::VmRequest;
-#[cfg(feature = "balloon")]
 use vm_control::VmResponse;
 
 use crate::sys::error_to_exit_code;
@@ -530,6 +563,93 @@ fn create_qcow2(cmd: cmdline::CreateQcow2Command) -> std::result::Result<(), ()>
     Ok(())
 }
 
+fn load_varstore(path: &Path) -> Result<VarStore> {
+    let image = std::fs::read(path)
+        .with_context(|| format!("failed to read pflash image {}", path.display()))?;
+    VarStore::new(image)
+        .with_context(|| format!("failed to parse the variable store in {}", path.display()))
+}
+
+fn save_varstore(path: &Path, store: &VarStore) -> Result<()> {
+    std::fs::write(path, store.image())
+        .with_context(|| format!("failed to write pflash image {}", path.display()))
+}
+
+fn pflash_vendor_guid(name: &str, guid: Option<Guid>) -> Result<Guid> {
+    guid.or_else(|| varstore::secure_boot_vendor_guid(name))
+        .with_context(|| format!("--guid is required for variable {}", name))
+}
+
+fn modify_pflash(cmd: cmdline::PflashCommand) -> Result<()> {
+    use cmdline::PflashSubcommand::*;
+    match cmd.command {
+        Add(cmd) => {
+            let vendor_guid = pflash_vendor_guid(&cmd.name, cmd.guid)?;
+            let data = match (cmd.data, cmd.x509) {
+                (Some(path), None) => std::fs::read(&path)
+                    .with_context(|| format!("failed to read {}", path.display()))?,
+                (None, Some(path)) => {
+                    let cert = std::fs::read(&path)
+                        .with_context(|| format!("failed to read {}", path.display()))?;
+                    varstore::x509_signature_list(cmd.owner.unwrap_or_default(), &cert)
+                }
+                _ => bail!("exactly one of --data and --x509 is required"),
+            };
+            let attributes = cmd.attributes.unwrap_or_else(|| {
+                let mut attributes = varstore::EFI_VARIABLE_NON_VOLATILE
+                    | varstore::EFI_VARIABLE_BOOTSERVICE_ACCESS
+                    | varstore::EFI_VARIABLE_RUNTIME_ACCESS;
+                if varstore::secure_boot_vendor_guid(&cmd.name) == Some(vendor_guid) {
+                    attributes |= varstore::EFI_VARIABLE_TIME_BASED_AUTHENTICATED_WRITE_ACCESS;
+                }
+                attributes
+            });
+            // Authenticated variables written by the firmware always carry the time of the update.
+            let timestamp =
+                if attributes & varstore::EFI_VARIABLE_TIME_BASED_AUTHENTICATED_WRITE_ACCESS != 0 {
+                    EfiTime::now()
+                } else {
+                    EfiTime::default()
+                };
+
+            let mut store = load_varstore(&cmd.image)?;
+            store.set(&Variable {
+                name: cmd.name,
+                vendor_guid,
+                attributes,
+                data,
+                timestamp,
+                ..Default::default()
+            })?;
+            save_varstore(&cmd.image, &store)
+        }
+        Delete(cmd) => {
+            let vendor_guid = pflash_vendor_guid(&cmd.name, cmd.guid)?;
+            let mut store = load_varstore(&cmd.image)?;
+            store.delete(&cmd.name, &vendor_guid)?;
+            save_varstore(&cmd.image, &store)
+        }
+        List(cmd) => {
+            let store = load_varstore(&cmd.image)?;
+            for var in store.variables()? {
+                println!(
+                    "{} {} attributes={:#x} size={}",
+                    var.vendor_guid,
+                    var.name,
+                    var.attributes,
+                    var.data.len()
+                );
+            }
+            Ok(())
+        }
+        Reset(cmd) => {
+            let mut store = load_varstore(&cmd.image)?;
+            store.clear();
+            save_varstore(&cmd.image, &store)
+        }
+    }
+}
+
 fn start_device(opts: cmdline::DeviceCommand) -> std::result::Result<(), ()> {
     if let Some(async_executor) = opts.async_executor {
         cros_async::Executor::set_default_executor_kind(async_executor)
@@ -592,6 +712,31 @@ fn gpu_set_display_mouse_mode(cmd: cmdline::GpuSetDisplayMouseModeCommand) -> Mo
     do_gpu_set_display_mouse_mode(cmd.socket_path, cmd.display_id, cmd.mouse_mode)
 }
 
+#[cfg(feature = "gpu")]
+fn gpu_screenshot(cmd: cmdline::GpuScreenshotCommand) -> ModifyGpuResult {
+    do_gpu_screenshot(cmd.socket_path, cmd.display_id, Path::new(&cmd.path))
+}
+
+#[cfg(feature = "gpu")]
+fn gpu_start_recording(cmd: cmdline::GpuStartRecordingCommand) -> ModifyGpuResult {
+    do_gpu_start_recording(cmd.socket_path, cmd.display_id, Path::new(&cmd.path))
+}
+
+#[cfg(feature = "gpu")]
+fn gpu_stop_recording(cmd: cmdline::GpuStopRecordingCommand) -> ModifyGpuResult {
+    do_gpu_stop_recording(cmd.socket_path, cmd.display_id)
+}
+
+#[cfg(all(feature = "gpu", any(target_os = "android", target_os = "linux")))]
+fn gpu_start_streaming(cmd: cmdline::GpuStartStreamingCommand) -> ModifyGpuResult {
+    do_gpu_start_streaming(cmd.socket_path, cmd.display_id, &cmd.output, cmd.encoding)
+}
+
+#[cfg(all(feature = "gpu", any(target_os = "android", target_os = "linux")))]
+fn gpu_stop_streaming(cmd: cmdline::GpuStopStreamingCommand) -> ModifyGpuResult {
+    do_gpu_stop_streaming(cmd.socket_path, cmd.display_id)
+}
+
 #[cfg(feature = "gpu")]
 fn modify_gpu(cmd: cmdline::GpuCommand) -> std::result::Result<(), ()> {
     let result = match cmd.command {
@@ -599,6 +744,13 @@ fn modify_gpu(cmd: cmdline::GpuCommand) -> std::result::Result<(), ()> {
         cmdline::GpuSubCommand::ListDisplays(cmd) => gpu_display_list(cmd),
         cmdline::GpuSubCommand::RemoveDisplays(cmd) => gpu_display_remove(cmd),
         cmdline::GpuSubCommand::SetDisplayMouseMode(cmd) => gpu_set_display_mouse_mode(cmd),
+        cmdline::GpuSubCommand::Screenshot(cmd) => gpu_screenshot(cmd),
+        cmdline::GpuSubCommand::StartRecording(cmd) => gpu_start_recording(cmd),
+        cmdline::GpuSubCommand::StopRecording(cmd) => gpu_stop_recording(cmd),
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        cmdline::GpuSubCommand::StartStreaming(cmd) => gpu_start_streaming(cmd),
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        cmdline::GpuSubCommand::StopStreaming(cmd) => gpu_stop_streaming(cmd),
     };
     match result {
         Ok(response) => {
@@ -651,6 +803,217 @@ fn modify_usb(cmd: cmdline::UsbCommand) -> std::result::Result<(), ()> {
     }
 }
 
+#[cfg(feature = "audio")]
+fn snd_cmd(cmd: cmdline::SndCommand) -> std::result::Result<(), ()> {
+    use cmdline::SndSubcommand::*;
+    let (card_index, command, socket_path) = match cmd.command {
+        List(cmd) => (
+            cmd.card_index,
+            SndControlCommand::ListControls,
+            cmd.socket_path,
+        ),
+        Plug(cmd) => (
+            cmd.card_index,
+            SndControlCommand::SetJackConnected {
+                jack_id: cmd.jack_id,
+                connected: true,
+            },
+            cmd.socket_path,
+        ),
+        Set(cmd) => {
+            let values = parse_snd_control_values(&cmd.values).map_err(|e| error!("{}", e))?;
+            (
+                cmd.card_index,
+                SndControlCommand::SetControl {
+                    control_id: cmd.control_id,
+                    values,
+                },
+                cmd.socket_path,
+            )
+        }
+        Unplug(cmd) => (
+            cmd.card_index,
+            SndControlCommand::SetJackConnected {
+                jack_id: cmd.jack_id,
+                connected: false,
+            },
+            cmd.socket_path,
+        ),
+    };
+    let request = VmRequest::SndCommand {
+        card_index,
+        command,
+    };
+    match handle_request(&request, socket_path)? {
+        VmResponse::Ok => Ok(()),
+        VmResponse::SndResponse(result) => {
+            print!("{}", result);
+            Ok(())
+        }
+        response => {
+            error!("snd command failed: {}", response);
+            Err(())
+        }
+    }
+}
+
+fn send_input_command(
+    device_index: usize,
+    command: InputControlCommand,
+    socket_path: &str,
+) -> std::result::Result<(), ()> {
+    let request = VmRequest::InputCommand {
+        device_index,
+        command,
+    };
+    match handle_request(&request, socket_path)? {
+        VmResponse::Ok => Ok(()),
+        response => {
+            error!("input command failed: {}", response);
+            Err(())
+        }
+    }
+}
+
+fn send_input_frames(
+    device_index: usize,
+    frames: Vec<InputFrame>,
+    socket_path: &str,
+) -> std::result::Result<(), ()> {
+    // Delays are applied against a fixed start so that the time spent sending requests does not
+    // accumulate over long traces.
+    let mut deadline = Instant::now();
+    for frame in frames {
+        deadline += frame.delay;
+        if let Some(wait) = deadline.checked_duration_since(Instant::now()) {
+            std::thread::sleep(wait);
+        }
+        send_input_command(
+            device_index,
+            InputControlCommand::SendEvents {
+                events: frame.events,
+            },
+            socket_path,
+        )?;
+    }
+    Ok(())
+}
+
+fn input_cmd(cmd: cmdline::InputCommand) -> std::result::Result<(), ()> {
+    use cmdline::InputSubcommand::*;
+    let single_frame = |events| {
+        vec![InputFrame {
+            delay: Duration::ZERO,
+            events,
+        }]
+    };
+    let (device_index, frames, socket_path) = match cmd.command {
+        Key(cmd) => {
+            let keys = parse_key_combo(&cmd.keys).map_err(|e| error!("{}", e))?;
+            (
+                cmd.device_index,
+                single_frame(key_events(&keys, cmd.action)),
+                cmd.socket_path,
+            )
+        }
+        Move(cmd) => (
+            cmd.device_index,
+            single_frame(pointer_move_events(cmd.x, cmd.y, cmd.absolute)),
+            cmd.socket_path,
+        ),
+        Record(cmd) => {
+            let file = std::fs::File::create(&cmd.path)
+                .map_err(|e| error!("failed to create {}: {}", cmd.path, e))?;
+            return send_input_command(
+                cmd.device_index,
+                InputControlCommand::StartRecording { file },
+                &cmd.socket_path,
+            );
+        }
+        Replay(cmd) => {
+            let contents = std::fs::read_to_string(&cmd.path)
+                .map_err(|e| error!("failed to read {}: {}", cmd.path, e))?;
+            let frames =
+                parse_trace(&contents).map_err(|e| error!("invalid trace {}: {}", cmd.path, e))?;
+            (cmd.device_index, frames, cmd.socket_path)
+        }
+        StopRecording(cmd) => {
+            return send_input_command(
+                cmd.device_index,
+                InputControlCommand::StopRecording,
+                &cmd.socket_path,
+            );
+        }
+        Swipe(cmd) => {
+            let from = parse_point(&cmd.from).map_err(|e| error!("{}", e))?;
+            let to = parse_point(&cmd.to).map_err(|e| error!("{}", e))?;
+            (
+                cmd.device_index,
+                swipe_frames(from, to, Duration::from_millis(cmd.duration_ms), cmd.steps),
+                cmd.socket_path,
+            )
+        }
+        Switch(cmd) => {
+            let code = parse_switch(&cmd.switch).map_err(|e| error!("{}", e))?;
+            (
+                cmd.device_index,
+                single_frame(switch_events(code, !cmd.off)),
+                cmd.socket_path,
+            )
+        }
+        Tap(cmd) => (cmd.device_index, tap_frames(cmd.x, cmd.y), cmd.socket_path),
+        Text(cmd) => {
+            let mut keymap = Keymap::us();
+            if let Some(path) = &cmd.keymap {
+                let contents = std::fs::read_to_string(path)
+                    .map_err(|e| error!("failed to read {}: {}", path, e))?;
+                keymap
+                    .load(&contents)
+                    .map_err(|e| error!("invalid keymap {}: {}", path, e))?;
+            }
+            let events = keymap.text_events(&cmd.text).map_err(|e| error!("{}", e))?;
+            (cmd.device_index, single_frame(events), cmd.socket_path)
+        }
+    };
+    send_input_frames(device_index, frames, &socket_path)
+}
+
+fn scsi_cmd(cmd: cmdline::ScsiCommand) -> std::result::Result<(), ()> {
+    use cmdline::ScsiSubcommand::*;
+    let (command, socket_path) = match cmd.command {
+        Eject(cmd) => (
+            ScsiControlCommand::EjectMedium {
+                target: cmd.target,
+                lun: cmd.lun,
+                force: cmd.force,
+            },
+            cmd.socket_path,
+        ),
+        Insert(cmd) => {
+            // The medium of a CD-ROM drive is read only.
+            let file = std::fs::File::open(&cmd.path)
+                .map_err(|e| error!("failed to open {}: {}", cmd.path, e))?;
+            (
+                ScsiControlCommand::InsertMedium {
+                    target: cmd.target,
+                    lun: cmd.lun,
+                    file,
+                    path: cmd.path.into(),
+                },
+                cmd.socket_path,
+            )
+        }
+    };
+    let request = VmRequest::ScsiCommand { command };
+    match handle_request(&request, socket_path)? {
+        VmResponse::Ok => Ok(()),
+        response => {
+            error!("scsi command failed: {}", response);
+            Err(())
+        }
+    }
+}
+
 fn snapshot_vm(cmd: cmdline::SnapshotCommand) -> std::result::Result<(), ()> {
     use cmdline::SnapshotSubCommands::*;
     let (socket_path, request) = match cmd.snapshot_command {
@@ -835,13 +1198,22 @@ fn crosvm_main<I: IntoIterator<Item = String>>(args: I) -> Result<CommandStatus>
                     CrossPlatformCommands::Gpu(cmd) => {
                         modify_gpu(cmd).map_err(|_| anyhow!("gpu subcommand failed"))
                     }
+                    CrossPlatformCommands::Input(cmd) => {
+                        input_cmd(cmd).map_err(|_| anyhow!("input subcommand failed"))
+                    }
                     CrossPlatformCommands::MakeRT(cmd) => {
                         make_rt(cmd).map_err(|_| anyhow!("make_rt subcommand failed"))
                     }
+                    CrossPlatformCommands::Pflash(cmd) => {
+                        modify_pflash(cmd).context("pflash subcommand failed")
+                    }
                     CrossPlatformCommands::Resume(cmd) => {
                         resume_vms(cmd).map_err(|_| anyhow!("resume subcommand failed"))
                     }
                     CrossPlatformCommands::Run(_) => unreachable!(),
+                    CrossPlatformCommands::Scsi(cmd) => {
+                        scsi_cmd(cmd).map_err(|_| anyhow!("scsi subcommand failed"))
+                    }
                     CrossPlatformCommands::Stop(cmd) => {
                         stop_vms(cmd).map_err(|_| anyhow!("stop subcommand failed"))
                     }
@@ -876,6 +1248,10 @@ fn crosvm_main<I: IntoIterator<Item = String>>(args: I) -> Result<CommandStatus>
                     CrossPlatformCommands::Snapshot(cmd) => {
                         snapshot_vm(cmd).map_err(|_| anyhow!("snapshot subcommand failed"))
                     }
+                    #[cfg(feature = "audio")]
+                    CrossPlatformCommands::Snd(cmd) => {
+                        snd_cmd(cmd).map_err(|_| anyhow!("snd subcommand failed"))
+                    }
                 }
                 .map(|_| CommandStatus::SuccessOrVmStop)
             }
@@ -2,6 +2,9 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::env::current_dir;
+use std::io::stdout;
+use std::io::Write;
 use std::thread::sleep;
 use std::time::Duration;
 
@@ -13,13 +16,22 @@ use base::syslog;
 use base::syslog::LogArgs;
 use base::syslog::LogConfig;
 use base::warn;
+use base::Tube;
+use base::UnixSeqpacket;
+use devices::serial_device::read_ring_buffer;
 use devices::virtio::vhost::user::device::run_console_device;
 use devices::virtio::vhost::user::device::run_fs_device;
 use devices::virtio::vhost::user::device::run_vsock_device;
 use devices::virtio::vhost::user::device::run_wl_device;
+use vm_control::ConsoleControlCommand;
+use vm_control::ConsoleControlResult;
 
 use crate::crosvm::sys::cmdline::Commands;
+use crate::crosvm::sys::cmdline::ConsoleCommand;
+use crate::crosvm::sys::cmdline::ConsoleSubcommand;
 use crate::crosvm::sys::cmdline::DeviceSubcommand;
+use crate::crosvm::sys::cmdline::SerialCommand;
+use crate::crosvm::sys::cmdline::SerialSubcommand;
 use crate::crosvm::sys::linux::start_devices;
 use crate::CommandStatus;
 use crate::Config;
@@ -79,9 +91,62 @@ pub fn get_library_watcher() -> std::io::Result<()> {
     Ok(())
 }
 
+fn console_command(cmd: ConsoleCommand) -> anyhow::Result<()> {
+    let (command, socket_path) = match cmd.command {
+        ConsoleSubcommand::AddPort(cmd) => (
+            ConsoleControlCommand::AddPort {
+                name: cmd.name,
+                // The socket is created by the device process, which may have another working
+                // directory.
+                path: current_dir()
+                    .context("failed to get current directory")?
+                    .join(cmd.path),
+                console: cmd.console,
+            },
+            cmd.socket_path,
+        ),
+        ConsoleSubcommand::List(cmd) => (ConsoleControlCommand::ListPorts, cmd.socket_path),
+        ConsoleSubcommand::RemovePort(cmd) => (
+            ConsoleControlCommand::RemovePort { name: cmd.name },
+            cmd.socket_path,
+        ),
+    };
+
+    let socket = UnixSeqpacket::connect(&socket_path)
+        .with_context(|| format!("failed to connect to {}", socket_path))?;
+    let tube = Tube::new_from_unix_seqpacket(socket).context("failed to create tube")?;
+    tube.send(&command)
+        .context("failed to send console control command")?;
+    match tube
+        .recv::<ConsoleControlResult>()
+        .context("failed to receive console control result")?
+    {
+        ConsoleControlResult::Ok => Ok(()),
+        ConsoleControlResult::Err(e) => Err(anyhow!("console command failed: {}", e)),
+        result => {
+            print!("{}", result);
+            Ok(())
+        }
+    }
+}
+
+fn serial_command(cmd: SerialCommand) -> anyhow::Result<()> {
+    match cmd.command {
+        SerialSubcommand::Dump(cmd) => {
+            let output = read_ring_buffer(&cmd.path)
+                .with_context(|| format!("failed to read {}", cmd.path.display()))?;
+            stdout()
+                .write_all(&output)
+                .context("failed to write serial output")
+        }
+    }
+}
+
 pub(crate) fn run_command(command: Commands, _log_args: LogArgs) -> anyhow::Result<()> {
     match command {
+        Commands::Console(cmd) => console_command(cmd),
         Commands::Devices(cmd) => start_devices(cmd).context("start_devices subcommand failed"),
+        Commands::Serial(cmd) => serial_command(cmd),
     }
 }
 
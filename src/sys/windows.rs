@@ -2105,6 +2105,8 @@ fn setup_vm_components(cfg: &Config) -> Result<VmComponents> {
         pcie_ecam: cfg.pcie_ecam,
         #[cfg(target_arch = "x86_64")]
         smbios: cfg.smbios.clone(),
+        #[cfg(target_arch = "x86_64")]
+        tpm_crb: None,
         dynamic_power_coefficient: cfg.dynamic_power_coefficient.clone(),
         #[cfg(target_arch = "x86_64")]
         break_linux_pci_config_io: cfg.break_linux_pci_config_io,
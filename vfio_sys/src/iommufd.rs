@@ -0,0 +1,104 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! The part of the iommufd uAPI (`include/uapi/linux/iommufd.h`) used to bind VFIO devices
+//! through their cdev and receive their I/O page faults.
+//!
+//! Unlike `vfio.rs`, this file is written by hand: the fault queue was added in Linux 6.11, after
+//! the kernel headers the generated bindings come from.
+
+use zerocopy::AsBytes;
+use zerocopy::FromBytes;
+use zerocopy::FromZeroes;
+
+pub const IOMMUFD_TYPE: u32 = b';' as u32;
+
+pub const IOMMUFD_CMD_IOAS_ALLOC: u32 = 0x81;
+pub const IOMMUFD_CMD_VFIO_IOAS: u32 = 0x88;
+pub const IOMMUFD_CMD_HWPT_ALLOC: u32 = 0x89;
+pub const IOMMUFD_CMD_FAULT_QUEUE_ALLOC: u32 = 0x8e;
+
+pub const IOMMU_VFIO_IOAS_GET: u16 = 0;
+pub const IOMMU_VFIO_IOAS_SET: u16 = 1;
+pub const IOMMU_VFIO_IOAS_CLEAR: u16 = 2;
+
+pub const IOMMU_HWPT_ALLOC_NEST_PARENT: u32 = 1 << 0;
+pub const IOMMU_HWPT_ALLOC_DIRTY_TRACKING: u32 = 1 << 1;
+pub const IOMMU_HWPT_FAULT_ID_VALID: u32 = 1 << 2;
+
+pub const IOMMU_HWPT_DATA_NONE: u32 = 0;
+
+pub const IOMMU_PGFAULT_FLAGS_PASID_VALID: u32 = 1 << 0;
+pub const IOMMU_PGFAULT_FLAGS_LAST_PAGE: u32 = 1 << 1;
+
+pub const IOMMU_PGFAULT_PERM_READ: u32 = 1 << 0;
+pub const IOMMU_PGFAULT_PERM_WRITE: u32 = 1 << 1;
+pub const IOMMU_PGFAULT_PERM_EXEC: u32 = 1 << 2;
+pub const IOMMU_PGFAULT_PERM_PRIV: u32 = 1 << 3;
+
+pub const IOMMUFD_PAGE_RESP_SUCCESS: u32 = 0;
+pub const IOMMUFD_PAGE_RESP_INVALID: u32 = 1;
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct iommu_ioas_alloc {
+    pub size: u32,
+    pub flags: u32,
+    pub out_ioas_id: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct iommu_vfio_ioas {
+    pub size: u32,
+    pub ioas_id: u32,
+    pub op: u16,
+    pub __reserved: u16,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct iommu_hwpt_alloc {
+    pub size: u32,
+    pub flags: u32,
+    pub dev_id: u32,
+    pub pt_id: u32,
+    pub out_hwpt_id: u32,
+    pub __reserved: u32,
+    pub data_type: u32,
+    pub data_len: u32,
+    pub data_uptr: u64,
+    pub fault_id: u32,
+    pub __reserved2: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct iommu_fault_alloc {
+    pub size: u32,
+    pub flags: u32,
+    pub out_fault_id: u32,
+    pub out_fault_fd: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, AsBytes, FromZeroes, FromBytes)]
+pub struct iommu_hwpt_pgfault {
+    pub flags: u32,
+    pub dev_id: u32,
+    pub pasid: u32,
+    pub grpid: u32,
+    pub perm: u32,
+    pub __reserved: u32,
+    pub addr: u64,
+    pub length: u32,
+    pub cookie: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, AsBytes, FromZeroes, FromBytes)]
+pub struct iommu_hwpt_page_response {
+    pub cookie: u32,
+    pub code: u32,
+}
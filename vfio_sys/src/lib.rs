@@ -12,12 +12,20 @@
 
 use base::ioctl_io_nr;
 
+pub mod iommufd;
 pub mod plat;
 pub mod vfio;
 
+use crate::iommufd::IOMMUFD_CMD_FAULT_QUEUE_ALLOC;
+use crate::iommufd::IOMMUFD_CMD_HWPT_ALLOC;
+use crate::iommufd::IOMMUFD_CMD_IOAS_ALLOC;
+use crate::iommufd::IOMMUFD_CMD_VFIO_IOAS;
+use crate::iommufd::IOMMUFD_TYPE;
 use crate::plat::ACPI_EVT_FORWARD_BASE;
 use crate::plat::PLAT_IRQ_FORWARD_BASE;
 use crate::plat::PLAT_IRQ_FORWARD_TYPE;
+pub use crate::vfio::vfio_device_attach_iommufd_pt;
+pub use crate::vfio::vfio_device_bind_iommufd;
 pub use crate::vfio::vfio_device_feature;
 pub use crate::vfio::vfio_device_info;
 pub use crate::vfio::vfio_device_low_power_entry_with_wakeup;
@@ -102,6 +110,17 @@ ioctl_io_nr!(VFIO_IOMMU_ENABLE, VFIO_TYPE, VFIO_BASE + 15);
 ioctl_io_nr!(VFIO_IOMMU_DISABLE, VFIO_TYPE, VFIO_BASE + 16);
 ioctl_io_nr!(VFIO_DEVICE_FEATURE, VFIO_TYPE, VFIO_BASE + 17);
 ioctl_io_nr!(VFIO_DEVICE_ACPI_DSM, VFIO_TYPE, VFIO_BASE + 18);
+ioctl_io_nr!(VFIO_DEVICE_BIND_IOMMUFD, VFIO_TYPE, VFIO_BASE + 18);
+ioctl_io_nr!(VFIO_DEVICE_ATTACH_IOMMUFD_PT, VFIO_TYPE, VFIO_BASE + 19);
+
+ioctl_io_nr!(IOMMU_IOAS_ALLOC, IOMMUFD_TYPE, IOMMUFD_CMD_IOAS_ALLOC);
+ioctl_io_nr!(IOMMU_VFIO_IOAS, IOMMUFD_TYPE, IOMMUFD_CMD_VFIO_IOAS);
+ioctl_io_nr!(IOMMU_HWPT_ALLOC, IOMMUFD_TYPE, IOMMUFD_CMD_HWPT_ALLOC);
+ioctl_io_nr!(
+    IOMMU_FAULT_QUEUE_ALLOC,
+    IOMMUFD_TYPE,
+    IOMMUFD_CMD_FAULT_QUEUE_ALLOC
+);
 
 ioctl_io_nr!(
     PLAT_IRQ_FORWARD_SET,
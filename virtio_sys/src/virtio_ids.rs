@@ -14,6 +14,8 @@
 // Added by virtio_sys/bindgen.sh - do not edit the generated file.
 // TODO(b/236144983): Fix this id when an official virtio-id is assigned to this device.
 pub const VIRTIO_ID_PVCLOCK: u32 = 61;
+// Assigned by the virtio specification, but not yet part of the kernel headers.
+pub const VIRTIO_ID_MEDIA: u32 = 48;
 
 pub const VIRTIO_ID_NET: u32 = 1;
 pub const VIRTIO_ID_BLOCK: u32 = 2;
@@ -20,8 +20,18 @@ pub use crate::gpu::do_gpu_display_list;
 #[cfg(feature = "gpu")]
 pub use crate::gpu::do_gpu_display_remove;
 #[cfg(feature = "gpu")]
+pub use crate::gpu::do_gpu_screenshot;
+#[cfg(feature = "gpu")]
 pub use crate::gpu::do_gpu_set_display_mouse_mode;
 #[cfg(feature = "gpu")]
+pub use crate::gpu::do_gpu_start_recording;
+#[cfg(all(feature = "gpu", any(target_os = "android", target_os = "linux")))]
+pub use crate::gpu::do_gpu_start_streaming;
+#[cfg(feature = "gpu")]
+pub use crate::gpu::do_gpu_stop_recording;
+#[cfg(feature = "gpu")]
+pub use crate::gpu::do_gpu_stop_streaming;
+#[cfg(feature = "gpu")]
 pub use crate::gpu::ModifyGpuResult;
 pub use crate::sys::handle_request;
 pub use crate::sys::handle_request_with_timeout;
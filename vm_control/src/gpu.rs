@@ -5,10 +5,28 @@
 use std::collections::BTreeMap as Map;
 use std::fmt;
 use std::fmt::Display;
+use std::fs::File;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use std::net::SocketAddr;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use std::net::TcpStream;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use std::os::fd::OwnedFd;
 use std::path::Path;
+use std::path::PathBuf;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use std::process::Command;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use std::process::Stdio;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use std::str::FromStr;
 
+use base::with_as_descriptor;
+use serde::de;
 use serde::Deserialize;
+use serde::Deserializer;
 use serde::Serialize;
+use serde::Serializer;
 use serde_keyvalue::FromKeyValues;
 
 pub use crate::sys::handle_request;
@@ -21,6 +39,15 @@ pub const DEFAULT_DISPLAY_HEIGHT: u32 = 1024;
 pub const DEFAULT_DPI: u32 = 320;
 pub const DEFAULT_REFRESH_RATE: u32 = 60;
 
+/// Largest number of modes that fit in the EDID generated for a display.
+///
+/// The base EDID block holds up to 3 detailed timings next to the display name, and a CTA-861
+/// extension block holds 6 more next to the HDR data blocks.
+pub const MAX_DISPLAY_MODES: usize = 9;
+/// Largest EDID supported by the virtio-gpu protocol.
+pub const MAX_EDID_SIZE: usize = 1024;
+const EDID_BLOCK_SIZE: usize = 128;
+
 fn default_refresh_rate() -> u32 {
     DEFAULT_REFRESH_RATE
 }
@@ -53,6 +80,120 @@ impl Default for DisplayMode {
     }
 }
 
+/// Timing of a display mode advertised to the guest in the EDID.
+///
+/// Blanking, front porch and sync pulse widths are given as (horizontal pixels, vertical lines)
+/// and default to typical values.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, FromKeyValues)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct DisplayTiming {
+    pub width: u32,
+    pub height: u32,
+    #[serde(default = "default_refresh_rate")]
+    pub refresh_rate: u32,
+    pub blanking: Option<(u16, u16)>,
+    pub front_porch: Option<(u16, u16)>,
+    pub sync_width: Option<(u16, u16)>,
+}
+
+impl DisplayTiming {
+    fn validate(&self) -> Result<(), String> {
+        // Limits of the fields of an EDID detailed timing descriptor.
+        if self.width == 0 || self.width > 0xfff || self.height == 0 || self.height > 0xfff {
+            return Err(format!(
+                "mode {}x{} is not in the range [1x1, 4095x4095]",
+                self.width, self.height
+            ));
+        }
+        if self.refresh_rate == 0 {
+            return Err("mode refresh rate must not be 0".to_string());
+        }
+        if matches!(self.blanking, Some((h, v)) if h > 0xfff || v > 0xfff) {
+            return Err("mode blanking must be below 4096".to_string());
+        }
+        if matches!(self.front_porch, Some((h, v)) if h > 0x3ff || v > 0x3f) {
+            return Err("mode front porch must be below [1024, 64]".to_string());
+        }
+        if matches!(self.sync_width, Some((h, v)) if h > 0x3ff || v > 0x3f) {
+            return Err("mode sync width must be below [1024, 64]".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// HDR capabilities advertised to the guest in the EDID.
+///
+/// Luminances are in cd/m², except `min_luminance` which is in thousandths of cd/m².
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, FromKeyValues)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct HdrMetadata {
+    pub max_luminance: u32,
+    pub max_frame_average_luminance: Option<u32>,
+    pub min_luminance: Option<u32>,
+}
+
+/// EDID blob given to the guest as is.
+///
+/// When parsed from a string, the string is the path of the file holding the EDID, so that the
+/// file is read by the process parsing the parameters rather than by the sandboxed GPU device.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EdidBlob(pub Vec<u8>);
+
+impl EdidBlob {
+    fn validate(&self) -> Result<(), String> {
+        let len = self.0.len();
+        if len == 0 || len % EDID_BLOCK_SIZE != 0 || len > MAX_EDID_SIZE {
+            return Err(format!(
+                "EDID of {} bytes is not made of 1 to {} blocks of {} bytes",
+                len,
+                MAX_EDID_SIZE / EDID_BLOCK_SIZE,
+                EDID_BLOCK_SIZE
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for EdidBlob {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for EdidBlob {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct EdidBlobVisitor;
+
+        impl<'de> de::Visitor<'de> for EdidBlobVisitor {
+            type Value = EdidBlob;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("an EDID file path or the EDID bytes")
+            }
+
+            fn visit_str<E: de::Error>(self, path: &str) -> Result<EdidBlob, E> {
+                std::fs::read(path)
+                    .map(EdidBlob)
+                    .map_err(|e| E::custom(format!("failed to read EDID {}: {}", path, e)))
+            }
+
+            fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<EdidBlob, E> {
+                Ok(EdidBlob(bytes.to_vec()))
+            }
+
+            fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<EdidBlob, A::Error> {
+                let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+                while let Some(byte) = seq.next_element()? {
+                    bytes.push(byte);
+                }
+                Ok(EdidBlob(bytes))
+            }
+        }
+
+        deserializer.deserialize_any(EdidBlobVisitor)
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, FromKeyValues)]
 #[serde(deny_unknown_fields, rename_all = "kebab-case")]
 pub struct DisplayParameters {
@@ -70,6 +211,15 @@ pub struct DisplayParameters {
     pub __horizontal_dpi_compat: Option<u32>,
     #[serde(rename = "vertical-dpi")]
     pub __vertical_dpi_compat: Option<u32>,
+    /// Modes advertised in the EDID, in order of preference. If empty, the only mode is the size
+    /// of `mode` at `refresh_rate`.
+    #[serde(default)]
+    pub modes: Vec<DisplayTiming>,
+    /// Physical (width, height) in millimeters. If `None`, it is derived from `dpi`.
+    pub physical_size_mm: Option<(u16, u16)>,
+    pub hdr: Option<HdrMetadata>,
+    /// EDID reported instead of the one generated from the other parameters.
+    pub edid: Option<EdidBlob>,
 }
 
 impl DisplayParameters {
@@ -87,6 +237,10 @@ impl DisplayParameters {
             dpi: Some((horizontal_dpi, vertical_dpi)),
             __horizontal_dpi_compat: None,
             __vertical_dpi_compat: None,
+            modes: Vec::new(),
+            physical_size_mm: None,
+            hdr: None,
+            edid: None,
         }
     }
 
@@ -113,6 +267,24 @@ impl DisplayParameters {
     pub fn vertical_dpi(&self) -> u32 {
         self.dpi.expect("'dpi' is None").1
     }
+
+    /// Checks that the EDID parameters can be reported to the guest.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.modes.len() > MAX_DISPLAY_MODES {
+            return Err(format!(
+                "{} modes requested but at most {} are supported",
+                self.modes.len(),
+                MAX_DISPLAY_MODES
+            ));
+        }
+        for mode in &self.modes {
+            mode.validate()?;
+        }
+        if let Some(edid) = &self.edid {
+            edid.validate()?;
+        }
+        Ok(())
+    }
 }
 
 impl Default for DisplayParameters {
@@ -121,6 +293,23 @@ impl Default for DisplayParameters {
     }
 }
 
+/// Format of the frames streamed by `GpuControlCommand::StartStreaming`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, FromKeyValues)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamEncoding {
+    /// Only the rectangles modified since the previous frame, the first frame covering the whole
+    /// display.
+    ///
+    /// Each frame is a little-endian header made of a u64 timestamp in nanoseconds since the start
+    /// of the stream, the u32 width and height of the display and the u32 number of rectangles.
+    /// Each rectangle is made of its u32 x, y, width and height and a u32 type, followed by its
+    /// XRGB8888 pixels for type 0 (raw), or by the single XRGB8888 pixel it is filled with for
+    /// type 1 (solid).
+    Damage,
+    /// Complete frames as binary PPM images, suitable for ffmpeg's `image2pipe` demuxer.
+    Ppm,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum GpuControlCommand {
     AddDisplays {
@@ -134,6 +323,40 @@ pub enum GpuControlCommand {
         display_id: u32,
         mouse_mode: MouseMode,
     },
+    /// Saves the current contents of a display to `file` as a PNG image.
+    ///
+    /// Files are opened by the requester since the GPU device is usually sandboxed.
+    Screenshot {
+        display_id: u32,
+        #[serde(with = "with_as_descriptor")]
+        file: File,
+    },
+    /// Appends every frame presented on a display to `file` until `StopRecording` is received.
+    ///
+    /// Each frame is a little-endian header made of a u64 timestamp in nanoseconds since the
+    /// start of the recording, the u32 width and the u32 height, followed by the XRGB8888 pixels.
+    StartRecording {
+        display_id: u32,
+        #[serde(with = "with_as_descriptor")]
+        file: File,
+    },
+    StopRecording {
+        display_id: u32,
+    },
+    /// Writes the frames presented on a display to `file` in the given encoding until
+    /// `StopStreaming` is received, starting with the frame the display currently shows.
+    ///
+    /// `file` may be a pipe or a socket so that frames can be forwarded to an encoder or over the
+    /// network.
+    StartStreaming {
+        display_id: u32,
+        encoding: StreamEncoding,
+        #[serde(with = "with_as_descriptor")]
+        file: File,
+    },
+    StopStreaming {
+        display_id: u32,
+    },
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -150,6 +373,11 @@ pub enum GpuControlResult {
         display_id: u32,
     },
     DisplayMouseModeSet,
+    ScreenshotTaken,
+    RecordingStarted,
+    RecordingStopped,
+    StreamingStarted,
+    StreamingStopped,
     ErrString(String),
 }
 
@@ -174,13 +402,25 @@ impl Display for GpuControlResult {
             ),
             NoSuchDisplay { display_id } => write!(f, "no_such_display {}", display_id),
             DisplayMouseModeSet => write!(f, "display_mouse_mode_set"),
+            ScreenshotTaken => write!(f, "screenshot_taken"),
+            RecordingStarted => write!(f, "recording_started"),
+            RecordingStopped => write!(f, "recording_stopped"),
+            StreamingStarted => write!(f, "streaming_started"),
+            StreamingStopped => write!(f, "streaming_stopped"),
             ErrString(reason) => write!(f, "err_string {}", reason),
         }
     }
 }
 
 pub enum ModifyGpuError {
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    ConnectSink(SocketAddr, std::io::Error),
+    CreateFile(PathBuf, std::io::Error),
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    EncodingNotSupported(StreamEncoding),
     SocketFailed,
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    SpawnEncoder(std::io::Error),
     UnexpectedResponse(VmResponse),
     UnknownCommand(String),
     GpuControl(GpuControlResult),
@@ -191,7 +431,16 @@ impl fmt::Display for ModifyGpuError {
         use self::ModifyGpuError::*;
 
         match self {
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            ConnectSink(addr, e) => write!(f, "failed to connect to {}: {}", addr, e),
+            CreateFile(path, e) => write!(f, "failed to create {}: {}", path.display(), e),
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            EncodingNotSupported(encoding) => {
+                write!(f, "encoding {:?} is not supported by this sink", encoding)
+            }
             SocketFailed => write!(f, "socket failed"),
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            SpawnEncoder(e) => write!(f, "failed to start ffmpeg: {}", e),
             UnexpectedResponse(r) => write!(f, "unexpected response: {}", r),
             UnknownCommand(c) => write!(f, "unknown display command: `{}`", c),
             GpuControl(e) => write!(f, "{}", e),
@@ -252,3 +501,170 @@ pub fn do_gpu_set_display_mouse_mode<T: AsRef<Path> + std::fmt::Debug>(
         .map_err(|_| ModifyGpuError::SocketFailed)?
         .into()
 }
+
+pub fn do_gpu_screenshot<T: AsRef<Path> + std::fmt::Debug>(
+    control_socket_path: T,
+    display_id: u32,
+    path: &Path,
+) -> ModifyGpuResult {
+    let file = File::create(path).map_err(|e| ModifyGpuError::CreateFile(path.to_path_buf(), e))?;
+    let request = VmRequest::GpuCommand(GpuControlCommand::Screenshot { display_id, file });
+    handle_request(&request, control_socket_path)
+        .map_err(|_| ModifyGpuError::SocketFailed)?
+        .into()
+}
+
+pub fn do_gpu_start_recording<T: AsRef<Path> + std::fmt::Debug>(
+    control_socket_path: T,
+    display_id: u32,
+    path: &Path,
+) -> ModifyGpuResult {
+    let file = File::create(path).map_err(|e| ModifyGpuError::CreateFile(path.to_path_buf(), e))?;
+    let request = VmRequest::GpuCommand(GpuControlCommand::StartRecording { display_id, file });
+    handle_request(&request, control_socket_path)
+        .map_err(|_| ModifyGpuError::SocketFailed)?
+        .into()
+}
+
+pub fn do_gpu_stop_recording<T: AsRef<Path> + std::fmt::Debug>(
+    control_socket_path: T,
+    display_id: u32,
+) -> ModifyGpuResult {
+    let request = VmRequest::GpuCommand(GpuControlCommand::StopRecording { display_id });
+    handle_request(&request, control_socket_path)
+        .map_err(|_| ModifyGpuError::SocketFailed)?
+        .into()
+}
+
+/// Destination of the frames streamed by `do_gpu_start_streaming`.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StreamSink {
+    /// A file created at the given path, which may also be an existing FIFO.
+    Path(PathBuf),
+    /// A TCP connection to the given address.
+    Tcp(SocketAddr),
+    /// An ffmpeg process encoding the frames to H.264 into the given output, which is either a
+    /// file such as `desktop.mp4` or a URL such as `rtp://10.0.0.2:5004`.
+    ///
+    /// ffmpeg is started by the requester, so it must be in its `PATH` rather than the one of the
+    /// VM.
+    Ffmpeg(String),
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+impl FromStr for StreamSink {
+    type Err = String;
+
+    /// Parses `tcp:ADDR`, `ffmpeg:OUTPUT`, or a path.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(addr) = s.strip_prefix("tcp:") {
+            addr.parse()
+                .map(StreamSink::Tcp)
+                .map_err(|e| format!("invalid address `{}`: {}", addr, e))
+        } else if let Some(output) = s.strip_prefix("ffmpeg:") {
+            if output.is_empty() {
+                return Err("missing ffmpeg output".to_string());
+            }
+            Ok(StreamSink::Ffmpeg(output.to_string()))
+        } else {
+            Ok(StreamSink::Path(PathBuf::from(s)))
+        }
+    }
+}
+
+/// Returns the arguments for ffmpeg to read PPM frames from its standard input and encode them
+/// into `output`.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn ffmpeg_args(output: &str) -> Vec<&str> {
+    let mut args = vec![
+        "-loglevel",
+        "error",
+        // Frames are only sent when the guest updates the display.
+        "-use_wallclock_as_timestamps",
+        "1",
+        "-f",
+        "image2pipe",
+        "-c:v",
+        "ppm",
+        "-i",
+        "-",
+        "-c:v",
+        "libx264",
+        "-preset",
+        "ultrafast",
+        "-tune",
+        "zerolatency",
+        "-pix_fmt",
+        "yuv420p",
+    ];
+    if output.starts_with("rtp://") {
+        args.extend(["-f", "rtp"]);
+    }
+    args.push(output);
+    args
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn open_stream_sink(sink: &StreamSink) -> Result<File, ModifyGpuError> {
+    match sink {
+        StreamSink::Path(path) => {
+            File::create(path).map_err(|e| ModifyGpuError::CreateFile(path.clone(), e))
+        }
+        StreamSink::Tcp(addr) => {
+            let stream =
+                TcpStream::connect(addr).map_err(|e| ModifyGpuError::ConnectSink(*addr, e))?;
+            Ok(OwnedFd::from(stream).into())
+        }
+        StreamSink::Ffmpeg(output) => {
+            // The child is not waited for: it exits once the device closes its end of the pipe.
+            let mut child = Command::new("ffmpeg")
+                .args(ffmpeg_args(output))
+                .stdin(Stdio::piped())
+                .stdout(Stdio::null())
+                .spawn()
+                .map_err(ModifyGpuError::SpawnEncoder)?;
+            let stdin = child.stdin.take().expect("missing ffmpeg stdin");
+            Ok(OwnedFd::from(stdin).into())
+        }
+    }
+}
+
+/// Starts streaming the frames of a display to `sink`.
+///
+/// ffmpeg sinks only accept the `Ppm` encoding, which is used when `encoding` is `None`. Other
+/// sinks default to `Damage`.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub fn do_gpu_start_streaming<T: AsRef<Path> + std::fmt::Debug>(
+    control_socket_path: T,
+    display_id: u32,
+    sink: &StreamSink,
+    encoding: Option<StreamEncoding>,
+) -> ModifyGpuResult {
+    let encoding = match (sink, encoding) {
+        (StreamSink::Ffmpeg(_), None | Some(StreamEncoding::Ppm)) => StreamEncoding::Ppm,
+        (StreamSink::Ffmpeg(_), Some(encoding)) => {
+            return Err(ModifyGpuError::EncodingNotSupported(encoding))
+        }
+        (_, encoding) => encoding.unwrap_or(StreamEncoding::Damage),
+    };
+    let file = open_stream_sink(sink)?;
+    let request = VmRequest::GpuCommand(GpuControlCommand::StartStreaming {
+        display_id,
+        encoding,
+        file,
+    });
+    handle_request(&request, control_socket_path)
+        .map_err(|_| ModifyGpuError::SocketFailed)?
+        .into()
+}
+
+pub fn do_gpu_stop_streaming<T: AsRef<Path> + std::fmt::Debug>(
+    control_socket_path: T,
+    display_id: u32,
+) -> ModifyGpuResult {
+    let request = VmRequest::GpuCommand(GpuControlCommand::StopStreaming { display_id });
+    handle_request(&request, control_socket_path)
+        .map_err(|_| ModifyGpuError::SocketFailed)?
+        .into()
+}
@@ -0,0 +1,719 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Injection and recording of events on virtio-input devices.
+//!
+//! The helpers in this module turn high level actions (key presses, text, pointer moves, touch
+//! gestures, switches) into the raw events sent with `InputControlCommand::SendEvents`. Actions
+//! that span time are described as a list of frames, each sent after a delay relative to the
+//! previous one.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::io::Write;
+use std::str::FromStr;
+use std::time::Duration;
+use std::time::Instant;
+
+use base::with_as_descriptor;
+use base::Error as SysError;
+use linux_input_sys::constants::*;
+use linux_input_sys::virtio_input_event;
+use serde::Deserialize;
+use serde::Serialize;
+
+/// Delay between the press and the release of a touch tap.
+const TAP_DURATION: Duration = Duration::from_millis(50);
+
+/// A single input event, as defined by the Linux input subsystem.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputEvent {
+    pub type_: u16,
+    pub code: u16,
+    pub value: i32,
+}
+
+impl InputEvent {
+    pub fn syn() -> InputEvent {
+        InputEvent {
+            type_: EV_SYN,
+            code: SYN_REPORT,
+            value: 0,
+        }
+    }
+
+    pub fn key(code: u16, down: bool) -> InputEvent {
+        InputEvent {
+            type_: EV_KEY,
+            code,
+            value: down as i32,
+        }
+    }
+
+    pub fn relative(code: u16, value: i32) -> InputEvent {
+        InputEvent {
+            type_: EV_REL,
+            code,
+            value,
+        }
+    }
+
+    pub fn absolute(code: u16, value: i32) -> InputEvent {
+        InputEvent {
+            type_: EV_ABS,
+            code,
+            value,
+        }
+    }
+
+    pub fn switch(code: u16, on: bool) -> InputEvent {
+        InputEvent {
+            type_: EV_SW,
+            code,
+            value: on as i32,
+        }
+    }
+}
+
+impl From<InputEvent> for virtio_input_event {
+    fn from(e: InputEvent) -> virtio_input_event {
+        virtio_input_event {
+            type_: e.type_.into(),
+            code: e.code.into(),
+            value: e.value.into(),
+        }
+    }
+}
+
+impl From<virtio_input_event> for InputEvent {
+    fn from(e: virtio_input_event) -> InputEvent {
+        InputEvent {
+            type_: e.type_.to_native(),
+            code: e.code.to_native(),
+            value: e.value.to_native(),
+        }
+    }
+}
+
+/// Commands for a virtio-input device.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum InputControlCommand {
+    /// Sends `events` to the guest after the events already received from the device's source.
+    SendEvents {
+        events: Vec<InputEvent>,
+    },
+    /// Writes every event sent to the guest to `file` in the trace format read by `parse_trace`.
+    StartRecording {
+        #[serde(with = "with_as_descriptor")]
+        file: File,
+    },
+    StopRecording,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum InputControlResult {
+    Ok,
+    Err(SysError),
+}
+
+/// Events to send after waiting for `delay` since the previous frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InputFrame {
+    pub delay: Duration,
+    pub events: Vec<InputEvent>,
+}
+
+impl InputFrame {
+    fn now(events: Vec<InputEvent>) -> InputFrame {
+        InputFrame {
+            delay: Duration::ZERO,
+            events,
+        }
+    }
+}
+
+/// Returns the code of a key or button from its Linux name, with or without the `KEY_` prefix
+/// (e.g. `KEY_A`, `enter`, `BTN_LEFT`), a common alias such as `ctrl`, or its numeric value.
+pub fn parse_key(name: &str) -> Result<u16, String> {
+    let upper = name.to_ascii_uppercase();
+    let short = upper.strip_prefix("KEY_").unwrap_or(&upper);
+    let code = match short {
+        "ESC" | "ESCAPE" => KEY_ESC,
+        "1" => KEY_1,
+        "2" => KEY_2,
+        "3" => KEY_3,
+        "4" => KEY_4,
+        "5" => KEY_5,
+        "6" => KEY_6,
+        "7" => KEY_7,
+        "8" => KEY_8,
+        "9" => KEY_9,
+        "0" => KEY_0,
+        "MINUS" => KEY_MINUS,
+        "EQUAL" => KEY_EQUAL,
+        "BACKSPACE" => KEY_BACKSPACE,
+        "TAB" => KEY_TAB,
+        "Q" => KEY_Q,
+        "W" => KEY_W,
+        "E" => KEY_E,
+        "R" => KEY_R,
+        "T" => KEY_T,
+        "Y" => KEY_Y,
+        "U" => KEY_U,
+        "I" => KEY_I,
+        "O" => KEY_O,
+        "P" => KEY_P,
+        "LEFTBRACE" => KEY_LEFTBRACE,
+        "RIGHTBRACE" => KEY_RIGHTBRACE,
+        "ENTER" | "RETURN" => KEY_ENTER,
+        "LEFTCTRL" | "CTRL" => KEY_LEFTCTRL,
+        "A" => KEY_A,
+        "S" => KEY_S,
+        "D" => KEY_D,
+        "F" => KEY_F,
+        "G" => KEY_G,
+        "H" => KEY_H,
+        "J" => KEY_J,
+        "K" => KEY_K,
+        "L" => KEY_L,
+        "SEMICOLON" => KEY_SEMICOLON,
+        "APOSTROPHE" => KEY_APOSTROPHE,
+        "GRAVE" => KEY_GRAVE,
+        "LEFTSHIFT" | "SHIFT" => KEY_LEFTSHIFT,
+        "BACKSLASH" => KEY_BACKSLASH,
+        "Z" => KEY_Z,
+        "X" => KEY_X,
+        "C" => KEY_C,
+        "V" => KEY_V,
+        "B" => KEY_B,
+        "N" => KEY_N,
+        "M" => KEY_M,
+        "COMMA" => KEY_COMMA,
+        "DOT" => KEY_DOT,
+        "SLASH" => KEY_SLASH,
+        "RIGHTSHIFT" => KEY_RIGHTSHIFT,
+        "LEFTALT" | "ALT" => KEY_LEFTALT,
+        "SPACE" => KEY_SPACE,
+        "CAPSLOCK" => KEY_CAPSLOCK,
+        "F1" => KEY_F1,
+        "F2" => KEY_F2,
+        "F3" => KEY_F3,
+        "F4" => KEY_F4,
+        "F5" => KEY_F5,
+        "F6" => KEY_F6,
+        "F7" => KEY_F7,
+        "F8" => KEY_F8,
+        "F9" => KEY_F9,
+        "F10" => KEY_F10,
+        "F11" => KEY_F11,
+        "F12" => KEY_F12,
+        "NUMLOCK" => KEY_NUMLOCK,
+        "SCROLLLOCK" => KEY_SCROLLLOCK,
+        "RIGHTCTRL" => KEY_RIGHTCTRL,
+        "SYSRQ" => KEY_SYSRQ,
+        "RIGHTALT" | "ALTGR" => KEY_RIGHTALT,
+        "HOME" => KEY_HOME,
+        "UP" => KEY_UP,
+        "PAGEUP" => KEY_PAGEUP,
+        "LEFT" => KEY_LEFT,
+        "RIGHT" => KEY_RIGHT,
+        "END" => KEY_END,
+        "DOWN" => KEY_DOWN,
+        "PAGEDOWN" => KEY_PAGEDOWN,
+        "INSERT" => KEY_INSERT,
+        "DELETE" => KEY_DELETE,
+        "MUTE" => KEY_MUTE,
+        "VOLUMEDOWN" => KEY_VOLUMEDOWN,
+        "VOLUMEUP" => KEY_VOLUMEUP,
+        "POWER" => KEY_POWER,
+        "PAUSE" => KEY_PAUSE,
+        "LEFTMETA" | "META" | "SUPER" => KEY_LEFTMETA,
+        "RIGHTMETA" => KEY_RIGHTMETA,
+        "COMPOSE" => KEY_COMPOSE,
+        "MENU" => KEY_MENU,
+        "BACK" => KEY_BACK,
+        "HOMEPAGE" => KEY_HOMEPAGE,
+        "SEARCH" => KEY_SEARCH,
+        "BTN_LEFT" => BTN_LEFT,
+        "BTN_RIGHT" => BTN_RIGHT,
+        "BTN_MIDDLE" => BTN_MIDDLE,
+        "BTN_SIDE" => BTN_SIDE,
+        "BTN_EXTRA" => BTN_EXTRA,
+        "BTN_FORWARD" => BTN_FORWARD,
+        "BTN_BACK" => BTN_BACK,
+        "BTN_TOUCH" => BTN_TOUCH,
+        // Names take precedence so that digits are keys rather than codes.
+        _ => return name.parse().map_err(|_| format!("unknown key `{}`", name)),
+    };
+    Ok(code)
+}
+
+/// Returns the code of a switch from its Linux name, with or without the `SW_` prefix (e.g.
+/// `SW_LID`, `tablet_mode`), or its numeric value.
+pub fn parse_switch(name: &str) -> Result<u16, String> {
+    let upper = name.to_ascii_uppercase();
+    let code = match upper.strip_prefix("SW_").unwrap_or(&upper) {
+        "LID" => SW_LID,
+        "TABLET_MODE" => SW_TABLET_MODE,
+        "HEADPHONE_INSERT" => SW_HEADPHONE_INSERT,
+        "RFKILL_ALL" | "RADIO" => SW_RFKILL_ALL,
+        "MICROPHONE_INSERT" => SW_MICROPHONE_INSERT,
+        "DOCK" => SW_DOCK,
+        "LINEOUT_INSERT" => SW_LINEOUT_INSERT,
+        "JACK_PHYSICAL_INSERT" => SW_JACK_PHYSICAL_INSERT,
+        "VIDEOOUT_INSERT" => SW_VIDEOOUT_INSERT,
+        "CAMERA_LENS_COVER" => SW_CAMERA_LENS_COVER,
+        "KEYPAD_SLIDE" => SW_KEYPAD_SLIDE,
+        "FRONT_PROXIMITY" => SW_FRONT_PROXIMITY,
+        "ROTATE_LOCK" => SW_ROTATE_LOCK,
+        "LINEIN_INSERT" => SW_LINEIN_INSERT,
+        "MUTE_DEVICE" => SW_MUTE_DEVICE,
+        "PEN_INSERTED" => SW_PEN_INSERTED,
+        "MACHINE_COVER" => SW_MACHINE_COVER,
+        _ => {
+            return name
+                .parse()
+                .map_err(|_| format!("unknown switch `{}`", name))
+        }
+    };
+    Ok(code)
+}
+
+/// Parses a `+` separated key combination such as `ctrl+alt+t`. Keys are pressed in order and
+/// released in reverse order.
+pub fn parse_key_combo(combo: &str) -> Result<Vec<u16>, String> {
+    combo.split('+').map(|key| parse_key(key.trim())).collect()
+}
+
+/// What to do with the keys of a combination.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyAction {
+    Press,
+    Release,
+    /// Press then release.
+    Tap,
+}
+
+impl FromStr for KeyAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "press" => Ok(KeyAction::Press),
+            "release" => Ok(KeyAction::Release),
+            "tap" => Ok(KeyAction::Tap),
+            _ => Err(format!(
+                "invalid key action `{}`, expected press, release or tap",
+                s
+            )),
+        }
+    }
+}
+
+/// Returns the events for `action` on the keys of a combination, one report per key.
+pub fn key_events(keys: &[u16], action: KeyAction) -> Vec<InputEvent> {
+    let mut events = Vec::new();
+    if action != KeyAction::Release {
+        for &key in keys {
+            events.extend([InputEvent::key(key, true), InputEvent::syn()]);
+        }
+    }
+    if action != KeyAction::Press {
+        for &key in keys.iter().rev() {
+            events.extend([InputEvent::key(key, false), InputEvent::syn()]);
+        }
+    }
+    events
+}
+
+/// Maps characters to the key combinations that type them.
+pub struct Keymap {
+    map: BTreeMap<char, Vec<u16>>,
+}
+
+impl Keymap {
+    /// Returns the keymap of a US keyboard layout.
+    pub fn us() -> Keymap {
+        // Characters typed by the same key, without and with shift.
+        const UNSHIFTED: &str = "abcdefghijklmnopqrstuvwxyz0123456789-=[]\\;'`,./";
+        const SHIFTED: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZ)!@#$%^&*(_+{}|:\"~<>?";
+
+        let mut map = BTreeMap::new();
+        for (unshifted, shifted) in UNSHIFTED.chars().zip(SHIFTED.chars()) {
+            let key = Self::us_key(unshifted);
+            map.insert(unshifted, vec![key]);
+            map.insert(shifted, vec![KEY_LEFTSHIFT, key]);
+        }
+        map.insert(' ', vec![KEY_SPACE]);
+        map.insert('\n', vec![KEY_ENTER]);
+        map.insert('\t', vec![KEY_TAB]);
+        Keymap { map }
+    }
+
+    // Returns the key that types `c` without modifiers on a US keyboard.
+    fn us_key(c: char) -> u16 {
+        match c {
+            '-' => KEY_MINUS,
+            '=' => KEY_EQUAL,
+            '[' => KEY_LEFTBRACE,
+            ']' => KEY_RIGHTBRACE,
+            '\\' => KEY_BACKSLASH,
+            ';' => KEY_SEMICOLON,
+            '\'' => KEY_APOSTROPHE,
+            '`' => KEY_GRAVE,
+            ',' => KEY_COMMA,
+            '.' => KEY_DOT,
+            '/' => KEY_SLASH,
+            // Letters and digits are key names accepted by `parse_key`.
+            c => parse_key(&c.to_string()).unwrap(),
+        }
+    }
+
+    /// Adds the mappings of a keymap file on top of this keymap.
+    ///
+    /// Each non-empty line holds a character, whitespace, then the key combination that types
+    /// it, e.g. `é altgr+e`.
+    pub fn load(&mut self, contents: &str) -> Result<(), String> {
+        for (n, line) in contents.lines().enumerate() {
+            let mut chars = line.chars();
+            let c = match chars.next() {
+                Some(c) => c,
+                None => continue,
+            };
+            let combo = chars.as_str().trim();
+            if combo.is_empty() {
+                return Err(format!("line {}: missing key combination", n + 1));
+            }
+            let keys = parse_key_combo(combo).map_err(|e| format!("line {}: {}", n + 1, e))?;
+            self.map.insert(c, keys);
+        }
+        Ok(())
+    }
+
+    /// Returns the events that type `text`.
+    pub fn text_events(&self, text: &str) -> Result<Vec<InputEvent>, String> {
+        let mut events = Vec::new();
+        for c in text.chars() {
+            let keys = self
+                .map
+                .get(&c)
+                .ok_or_else(|| format!("no key types {:?}", c))?;
+            events.extend(key_events(keys, KeyAction::Tap));
+        }
+        Ok(events)
+    }
+}
+
+/// Parses a point given as `X,Y`.
+pub fn parse_point(s: &str) -> Result<(i32, i32), String> {
+    let invalid = || format!("invalid point `{}`, expected X,Y", s);
+    let (x, y) = s.split_once(',').ok_or_else(invalid)?;
+    Ok((
+        x.trim().parse().map_err(|_| invalid())?,
+        y.trim().parse().map_err(|_| invalid())?,
+    ))
+}
+
+/// Returns the events that move a pointer, by `(x, y)` for relative devices or to `(x, y)` for
+/// absolute ones.
+pub fn pointer_move_events(x: i32, y: i32, absolute: bool) -> Vec<InputEvent> {
+    if absolute {
+        vec![
+            InputEvent::absolute(ABS_X, x),
+            InputEvent::absolute(ABS_Y, y),
+            InputEvent::syn(),
+        ]
+    } else {
+        vec![
+            InputEvent::relative(REL_X, x),
+            InputEvent::relative(REL_Y, y),
+            InputEvent::syn(),
+        ]
+    }
+}
+
+// Events putting a finger in contact at (x, y), understood by both single and multi touch
+// devices. The guest ignores the codes its device does not support.
+fn touch_down(x: i32, y: i32) -> Vec<InputEvent> {
+    vec![
+        InputEvent::absolute(ABS_MT_SLOT, 0),
+        InputEvent::absolute(ABS_MT_TRACKING_ID, 0),
+        InputEvent::absolute(ABS_MT_POSITION_X, x),
+        InputEvent::absolute(ABS_MT_POSITION_Y, y),
+        InputEvent::key(BTN_TOUCH, true),
+        InputEvent::absolute(ABS_X, x),
+        InputEvent::absolute(ABS_Y, y),
+        InputEvent::syn(),
+    ]
+}
+
+fn touch_move(x: i32, y: i32) -> Vec<InputEvent> {
+    vec![
+        InputEvent::absolute(ABS_MT_POSITION_X, x),
+        InputEvent::absolute(ABS_MT_POSITION_Y, y),
+        InputEvent::absolute(ABS_X, x),
+        InputEvent::absolute(ABS_Y, y),
+        InputEvent::syn(),
+    ]
+}
+
+fn touch_up() -> Vec<InputEvent> {
+    vec![
+        InputEvent::absolute(ABS_MT_TRACKING_ID, -1),
+        InputEvent::key(BTN_TOUCH, false),
+        InputEvent::syn(),
+    ]
+}
+
+/// Returns the frames of a tap at `(x, y)`.
+pub fn tap_frames(x: i32, y: i32) -> Vec<InputFrame> {
+    vec![
+        InputFrame::now(touch_down(x, y)),
+        InputFrame {
+            delay: TAP_DURATION,
+            events: touch_up(),
+        },
+    ]
+}
+
+/// Returns the frames of a swipe from `from` to `to` lasting `duration`, made of `steps` moves.
+pub fn swipe_frames(
+    from: (i32, i32),
+    to: (i32, i32),
+    duration: Duration,
+    steps: u32,
+) -> Vec<InputFrame> {
+    let steps = steps.max(1);
+    let delay = duration / steps;
+    let mut frames = vec![InputFrame::now(touch_down(from.0, from.1))];
+    for i in 1..=steps as i64 {
+        let x = from.0 as i64 + (to.0 as i64 - from.0 as i64) * i / steps as i64;
+        let y = from.1 as i64 + (to.1 as i64 - from.1 as i64) * i / steps as i64;
+        frames.push(InputFrame {
+            delay,
+            events: touch_move(x as i32, y as i32),
+        });
+    }
+    frames.push(InputFrame::now(touch_up()));
+    frames
+}
+
+/// Returns the events that turn a switch on or off.
+pub fn switch_events(code: u16, on: bool) -> Vec<InputEvent> {
+    vec![InputEvent::switch(code, on), InputEvent::syn()]
+}
+
+/// Writes events to a trace, one per line as `<microseconds> <type> <code> <value>`. The time is
+/// relative to the creation of the writer.
+pub struct TraceWriter<W: Write> {
+    writer: BufWriter<W>,
+    start: Instant,
+}
+
+impl<W: Write> TraceWriter<W> {
+    pub fn new(w: W) -> TraceWriter<W> {
+        TraceWriter {
+            writer: BufWriter::new(w),
+            start: Instant::now(),
+        }
+    }
+
+    pub fn write_event(&mut self, event: &InputEvent) -> io::Result<()> {
+        writeln!(
+            self.writer,
+            "{} {} {} {}",
+            self.start.elapsed().as_micros(),
+            event.type_,
+            event.code,
+            event.value
+        )?;
+        // Traces are small, flush on report boundaries so that they are complete at any time.
+        if event.type_ == EV_SYN {
+            self.writer.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses a trace written by `TraceWriter` into frames, each ending with a `SYN_REPORT`.
+///
+/// Empty lines and lines starting with `#` are ignored.
+pub fn parse_trace(contents: &str) -> Result<Vec<InputFrame>, String> {
+    let mut frames = Vec::new();
+    let mut events = Vec::new();
+    let mut frame_time = None;
+    let mut last_time = 0u64;
+    for (n, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let invalid = || format!("line {}: invalid event `{}`", n + 1, line);
+        if fields.len() != 4 {
+            return Err(invalid());
+        }
+        let time: u64 = fields[0].parse().map_err(|_| invalid())?;
+        let event = InputEvent {
+            type_: fields[1].parse().map_err(|_| invalid())?,
+            code: fields[2].parse().map_err(|_| invalid())?,
+            value: fields[3].parse().map_err(|_| invalid())?,
+        };
+        if time < frame_time.unwrap_or(last_time) {
+            return Err(format!("line {}: time goes backwards", n + 1));
+        }
+        let start = *frame_time.get_or_insert(time);
+        events.push(event);
+        if event.type_ == EV_SYN && event.code == SYN_REPORT {
+            frames.push(InputFrame {
+                delay: Duration::from_micros(start - last_time),
+                events: std::mem::take(&mut events),
+            });
+            last_time = start;
+            frame_time = None;
+        }
+    }
+    if !events.is_empty() {
+        return Err("trace ends with an incomplete report".to_string());
+    }
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_names() {
+        assert_eq!(parse_key("KEY_A"), Ok(KEY_A));
+        assert_eq!(parse_key("a"), Ok(KEY_A));
+        assert_eq!(parse_key("Ctrl"), Ok(KEY_LEFTCTRL));
+        assert_eq!(parse_key("btn_left"), Ok(BTN_LEFT));
+        assert_eq!(parse_key("1"), Ok(KEY_1));
+        assert_eq!(parse_key("30"), Ok(30));
+        assert!(parse_key("nope").is_err());
+        assert_eq!(parse_switch("lid"), Ok(SW_LID));
+        assert_eq!(parse_switch("SW_TABLET_MODE"), Ok(SW_TABLET_MODE));
+        assert!(parse_switch("KEY_A").is_err());
+    }
+
+    #[test]
+    fn combo_order() {
+        let keys = parse_key_combo("ctrl+alt+t").unwrap();
+        assert_eq!(keys, vec![KEY_LEFTCTRL, KEY_LEFTALT, KEY_T]);
+        let events = key_events(&keys, KeyAction::Tap);
+        let codes: Vec<(u16, i32)> = events
+            .iter()
+            .filter(|e| e.type_ == EV_KEY)
+            .map(|e| (e.code, e.value))
+            .collect();
+        assert_eq!(
+            codes,
+            vec![
+                (KEY_LEFTCTRL, 1),
+                (KEY_LEFTALT, 1),
+                (KEY_T, 1),
+                (KEY_T, 0),
+                (KEY_LEFTALT, 0),
+                (KEY_LEFTCTRL, 0)
+            ]
+        );
+        assert_eq!(key_events(&keys, KeyAction::Press).len(), 6);
+    }
+
+    #[test]
+    fn us_text() {
+        let keymap = Keymap::us();
+        let events = keymap.text_events("aB!\n").unwrap();
+        let downs: Vec<u16> = events
+            .iter()
+            .filter(|e| e.type_ == EV_KEY && e.value == 1)
+            .map(|e| e.code)
+            .collect();
+        assert_eq!(
+            downs,
+            vec![KEY_A, KEY_LEFTSHIFT, KEY_B, KEY_LEFTSHIFT, KEY_1, KEY_ENTER]
+        );
+        assert!(keymap.text_events("é").is_err());
+    }
+
+    #[test]
+    fn custom_keymap() {
+        let mut keymap = Keymap::us();
+        keymap.load("é altgr+e\n\n  space\n").unwrap();
+        let events = keymap.text_events("é").unwrap();
+        assert_eq!(events[0], InputEvent::key(KEY_RIGHTALT, true));
+        assert_eq!(events[2], InputEvent::key(KEY_E, true));
+        assert!(keymap.load("x\n").is_err());
+        assert!(keymap.load("x ctrl+nope\n").is_err());
+    }
+
+    #[test]
+    fn points() {
+        assert_eq!(parse_point("10,-20"), Ok((10, -20)));
+        assert_eq!(parse_point(" 1 , 2 "), Ok((1, 2)));
+        assert!(parse_point("10").is_err());
+        assert!(parse_point("a,2").is_err());
+    }
+
+    #[test]
+    fn swipe() {
+        let frames = swipe_frames((0, 0), (100, 50), Duration::from_millis(100), 4);
+        assert_eq!(frames.len(), 6);
+        assert_eq!(frames[0].delay, Duration::ZERO);
+        assert_eq!(frames[1].delay, Duration::from_millis(25));
+        assert_eq!(
+            frames[2].events[0],
+            InputEvent::absolute(ABS_MT_POSITION_X, 50)
+        );
+        assert_eq!(
+            frames[2].events[1],
+            InputEvent::absolute(ABS_MT_POSITION_Y, 25)
+        );
+        assert_eq!(
+            frames[4].events[0],
+            InputEvent::absolute(ABS_MT_POSITION_X, 100)
+        );
+        assert_eq!(frames[5].events, touch_up());
+    }
+
+    #[test]
+    fn trace_round_trip() {
+        let mut out = Vec::new();
+        {
+            let mut writer = TraceWriter::new(&mut out);
+            for event in key_events(&[KEY_A], KeyAction::Tap) {
+                writer.write_event(&event).unwrap();
+            }
+        }
+        let frames = parse_trace(std::str::from_utf8(&out).unwrap()).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].events, key_events(&[KEY_A], KeyAction::Press));
+        assert_eq!(frames[1].events, key_events(&[KEY_A], KeyAction::Release));
+    }
+
+    #[test]
+    fn trace_parsing() {
+        let trace = "# lid closed\n1000 5 0 1\n1000 0 0 0\n\n3000 5 0 0\n3500 0 0 0\n";
+        let frames = parse_trace(trace).unwrap();
+        assert_eq!(
+            frames,
+            vec![
+                InputFrame {
+                    delay: Duration::from_millis(1),
+                    events: switch_events(SW_LID, true),
+                },
+                InputFrame {
+                    delay: Duration::from_millis(2),
+                    events: switch_events(SW_LID, false),
+                },
+            ]
+        );
+        assert!(parse_trace("0 1 30\n").is_err());
+        assert!(parse_trace("0 1 30 1\n").is_err());
+        assert!(parse_trace("10 0 0 0\n5 0 0 0\n").is_err());
+    }
+}
@@ -15,6 +15,7 @@ pub mod api;
 pub mod gdb;
 #[cfg(feature = "gpu")]
 pub mod gpu;
+pub mod input;
 
 #[cfg(any(target_os = "android", target_os = "linux"))]
 use base::linux::MemoryMappingBuilderUnix;
@@ -123,6 +124,8 @@ pub use crate::gdb::VcpuDebugStatusMessage;
 use crate::gpu::GpuControlCommand;
 #[cfg(feature = "gpu")]
 use crate::gpu::GpuControlResult;
+use crate::input::InputControlCommand;
+use crate::input::InputControlResult;
 
 /// Control the state of a particular VM CPU.
 #[derive(Clone, Debug)]
@@ -225,6 +228,139 @@ pub enum DiskControlResult {
     Err(SysError),
 }
 
+/// Commands for a virtio-snd card.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum SndControlCommand {
+    /// Plugs (`connected == true`) or unplugs jack `jack_id`.
+    SetJackConnected { jack_id: u32, connected: bool },
+    /// Lists the control elements of the card with their current values.
+    ListControls,
+    /// Sets the values of control element `control_id`, one value per channel.
+    SetControl { control_id: u32, values: Vec<i32> },
+}
+
+/// A control element of a virtio-snd card and its current values.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SndControlValue {
+    pub control_id: u32,
+    pub name: String,
+    pub index: u32,
+    pub values: Vec<i32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum SndControlResult {
+    Ok,
+    Controls(Vec<SndControlValue>),
+    Err(SysError),
+}
+
+impl Display for SndControlResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SndControlResult::Ok => write!(f, "ok"),
+            SndControlResult::Controls(controls) => {
+                for control in controls {
+                    writeln!(
+                        f,
+                        "{}: '{}' index {} = {:?}",
+                        control.control_id, control.name, control.index, control.values
+                    )?;
+                }
+                Ok(())
+            }
+            SndControlResult::Err(e) => write!(f, "error: {}", e),
+        }
+    }
+}
+
+/// Commands for the CD-ROM drives of a virtio-scsi controller.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum ScsiControlCommand {
+    /// Removes the medium of the drive at `target`:`lun`. Fails if the guest prevents the
+    /// removal, unless `force` is set.
+    EjectMedium { target: u8, lun: u16, force: bool },
+    /// Inserts the disk image `file` opened from `path` into the drive at `target`:`lun`,
+    /// replacing the current medium.
+    InsertMedium {
+        target: u8,
+        lun: u16,
+        #[serde(with = "with_as_descriptor")]
+        file: File,
+        path: PathBuf,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ScsiControlResult {
+    Ok,
+    Err(SysError),
+}
+
+/// Commands for a multi-port virtio-console device.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ConsoleControlCommand {
+    /// Adds a port named `name`, bound to a Unix socket listening at `path`.
+    AddPort {
+        name: String,
+        path: PathBuf,
+        console: bool,
+    },
+    /// Removes the socket port named `name`.
+    RemovePort { name: String },
+    /// Lists the ports of the device.
+    ListPorts,
+}
+
+/// A port of a multi-port virtio-console device.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ConsolePortStatus {
+    pub id: u32,
+    pub name: String,
+    pub console: bool,
+    /// Path of the Unix socket the port is bound to, if any.
+    pub path: Option<PathBuf>,
+    /// Whether the host end of the port is connected.
+    pub connected: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ConsoleControlResult {
+    Ok,
+    Ports(Vec<ConsolePortStatus>),
+    Err(SysError),
+}
+
+impl Display for ConsoleControlResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConsoleControlResult::Ok => write!(f, "ok"),
+            ConsoleControlResult::Ports(ports) => {
+                for port in ports {
+                    write!(f, "{}: '{}'", port.id, port.name)?;
+                    if port.console {
+                        write!(f, " console")?;
+                    }
+                    if let Some(path) = &port.path {
+                        write!(f, " at {}", path.display())?;
+                    }
+                    writeln!(
+                        f,
+                        " {}",
+                        if port.connected {
+                            "connected"
+                        } else {
+                            "disconnected"
+                        }
+                    )?;
+                }
+                Ok(())
+            }
+            ConsoleControlResult::Err(e) => write!(f, "error: {}", e),
+        }
+    }
+}
+
 /// Net control commands for adding and removing tap devices.
 #[cfg(feature = "pci-hotplug")]
 #[derive(Serialize, Deserialize, Debug)]
@@ -233,6 +369,19 @@ pub enum NetControlCommand {
     RemoveTap(u8),
 }
 
+/// Input control commands for passing host event devices through to the guest while it runs.
+#[cfg(feature = "pci-hotplug")]
+#[derive(Serialize, Deserialize, Debug)]
+pub enum InputHotplugCommand {
+    /// Adds a virtio-input device backed by the event device at `path`.
+    AddEvdev {
+        path: PathBuf,
+        grab_hotkey: Option<Vec<u16>>,
+    },
+    /// Removes the device plugged on the given PCI bus.
+    RemoveEvdev(u8),
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum UsbControlCommand {
     AttachDevice {
@@ -1335,6 +1484,21 @@ pub enum VmRequest {
         disk_index: usize,
         command: DiskControlCommand,
     },
+    /// Send a command to a virtio-snd card chosen by `card_index`.
+    /// `card_index` is a 0-based count of `--virtio-snd` command-line options.
+    SndCommand {
+        card_index: usize,
+        command: SndControlCommand,
+    },
+    /// Send a command to a virtio-input device chosen by `device_index`.
+    /// `device_index` is a 0-based count of `--input` command-line options, followed by the
+    /// deprecated per-type input options.
+    InputCommand {
+        device_index: usize,
+        command: InputControlCommand,
+    },
+    /// Send a command to the virtio-scsi controller.
+    ScsiCommand { command: ScsiControlCommand },
     /// Command to use controller.
     UsbCommand(UsbControlCommand),
     /// Command to modify the gpu.
@@ -1350,6 +1514,9 @@ pub enum VmRequest {
     /// Command to add/remove network tap device as virtio-pci device
     #[cfg(feature = "pci-hotplug")]
     HotPlugNetCommand(NetControlCommand),
+    /// Command to add/remove host event device as virtio-pci device
+    #[cfg(feature = "pci-hotplug")]
+    HotPlugInputCommand(InputHotplugCommand),
     /// Command to Snapshot devices
     Snapshot(SnapshotCommand),
     /// Register for event notification
@@ -1462,6 +1629,58 @@ pub fn handle_disk_command(command: &DiskControlCommand, disk_host_tube: &Tube)
     }
 }
 
+pub fn handle_snd_command(command: &SndControlCommand, snd_host_tube: &Tube) -> VmResponse {
+    // Forward the request to the snd device process via its control socket.
+    if let Err(e) = snd_host_tube.send(command) {
+        error!("snd socket send failed: {}", e);
+        return VmResponse::Err(SysError::new(EINVAL));
+    }
+
+    match snd_host_tube.recv() {
+        Ok(SndControlResult::Ok) => VmResponse::Ok,
+        Ok(SndControlResult::Err(e)) => VmResponse::Err(e),
+        Ok(result) => VmResponse::SndResponse(result),
+        Err(e) => {
+            error!("snd socket recv failed: {}", e);
+            VmResponse::Err(SysError::new(EINVAL))
+        }
+    }
+}
+
+pub fn handle_input_command(command: &InputControlCommand, input_host_tube: &Tube) -> VmResponse {
+    // Forward the request to the input device process via its control socket.
+    if let Err(e) = input_host_tube.send(command) {
+        error!("input socket send failed: {}", e);
+        return VmResponse::Err(SysError::new(EINVAL));
+    }
+
+    match input_host_tube.recv() {
+        Ok(InputControlResult::Ok) => VmResponse::Ok,
+        Ok(InputControlResult::Err(e)) => VmResponse::Err(e),
+        Err(e) => {
+            error!("input socket recv failed: {}", e);
+            VmResponse::Err(SysError::new(EINVAL))
+        }
+    }
+}
+
+pub fn handle_scsi_command(command: &ScsiControlCommand, scsi_host_tube: &Tube) -> VmResponse {
+    // Forward the request to the scsi device process via its control socket.
+    if let Err(e) = scsi_host_tube.send(command) {
+        error!("scsi socket send failed: {}", e);
+        return VmResponse::Err(SysError::new(EINVAL));
+    }
+
+    match scsi_host_tube.recv() {
+        Ok(ScsiControlResult::Ok) => VmResponse::Ok,
+        Ok(ScsiControlResult::Err(e)) => VmResponse::Err(e),
+        Err(e) => {
+            error!("scsi socket recv failed: {}", e);
+            VmResponse::Err(SysError::new(EINVAL))
+        }
+    }
+}
+
 /// WARNING: descriptor must be a mapping handle on Windows.
 fn map_descriptor(
     descriptor: &dyn AsRawDescriptor,
@@ -1953,6 +2172,12 @@ impl VmRequest {
                 Some(tube) => handle_disk_command(command, tube),
                 None => VmResponse::Err(SysError::new(ENODEV)),
             },
+            // Only supported where the platform's control loop routes it to the snd device.
+            VmRequest::SndCommand { .. } => VmResponse::Err(SysError::new(ENOTSUP)),
+            // Only supported where the platform's control loop routes it to the input device.
+            VmRequest::InputCommand { .. } => VmResponse::Err(SysError::new(ENOTSUP)),
+            // Only supported where the platform's control loop routes it to the scsi device.
+            VmRequest::ScsiCommand { .. } => VmResponse::Err(SysError::new(ENOTSUP)),
             #[cfg(feature = "gpu")]
             VmRequest::GpuCommand(ref cmd) => match gpu_control_tube {
                 Some(gpu_control) => {
@@ -2025,6 +2250,10 @@ impl VmRequest {
             VmRequest::HotPlugNetCommand(ref _net_cmd) => {
                 VmResponse::ErrString("hot plug not supported".to_owned())
             }
+            #[cfg(feature = "pci-hotplug")]
+            VmRequest::HotPlugInputCommand(ref _input_cmd) => {
+                VmResponse::ErrString("hot plug not supported".to_owned())
+            }
             VmRequest::Snapshot(SnapshotCommand::Take {
                 ref snapshot_path,
                 compress_memory,
@@ -2293,6 +2522,8 @@ pub enum VmResponse {
     #[cfg(feature = "gpu")]
     /// Results of gpu control commands.
     GpuResponse(GpuControlResult),
+    /// Results of virtio-snd control commands.
+    SndResponse(SndControlResult),
     /// Results of battery control commands.
     BatResponse(BatControlResult),
     /// Results of swap status command.
@@ -2347,6 +2578,7 @@ impl Display for VmResponse {
             #[cfg(feature = "gpu")]
             GpuResponse(result) => write!(f, "gpu control request result {:?}", result),
             BatResponse(result) => write!(f, "{}", result),
+            SndResponse(result) => write!(f, "{}", result),
             SwapStatus(status) => {
                 write!(
                     f,
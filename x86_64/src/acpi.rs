@@ -9,6 +9,7 @@ use std::collections::BTreeMap;
 use std::sync::Arc;
 
 use acpi_tables::aml;
+use acpi_tables::aml::Aml;
 use acpi_tables::facs::FACS;
 use acpi_tables::rsdp::RSDP;
 use acpi_tables::sdt::SDT;
@@ -16,10 +17,12 @@ use arch::CpuSet;
 use arch::VcpuAffinity;
 use base::error;
 use base::warn;
+use devices::tpm_crb;
 use devices::ACPIPMResource;
 use devices::PciAddress;
 use devices::PciInterruptPin;
 use devices::PciRoot;
+use devices::TpmCrb;
 use sync::Mutex;
 use vm_memory::GuestAddress;
 use vm_memory::GuestMemory;
@@ -176,6 +179,13 @@ const MCFG_FIELD_START_BUS_NUMBER: usize = 54;
 const MCFG_FIELD_END_BUS_NUMBER: usize = 55;
 
 const SSDT_REVISION: u8 = 2;
+
+// TPM2
+const TPM2_LEN: u32 = 64;
+const TPM2_REVISION: u8 = 4;
+const TPM2_FIELD_CONTROL_AREA_ADDR: usize = 40;
+const TPM2_FIELD_START_METHOD: usize = 48;
+const TPM2_START_METHOD_CRB: u32 = 7;
 pub fn create_customize_ssdt(
     pci_root: Arc<Mutex<PciRoot>>,
     amls: BTreeMap<PciAddress, Vec<u8>>,
@@ -207,6 +217,44 @@ pub fn create_customize_ssdt(
     Some(ssdt)
 }
 
+/// Creates the TPM2 table describing the TPM CRB device.
+pub fn create_tpm2_table() -> SDT {
+    let mut tpm2 = SDT::new(
+        *b"TPM2",
+        TPM2_LEN,
+        TPM2_REVISION,
+        *b"CROSVM",
+        *b"CROSVMDT",
+        OEM_REVISION,
+    );
+
+    tpm2.write(
+        TPM2_FIELD_CONTROL_AREA_ADDR,
+        tpm_crb::TPM_CRB_BASE + tpm_crb::CRB_CTRL_REQ,
+    );
+    tpm2.write(TPM2_FIELD_START_METHOD, TPM2_START_METHOD_CRB);
+
+    tpm2
+}
+
+/// Creates an SSDT containing the ACPI device of the TPM CRB interface.
+pub fn create_tpm_ssdt(tpm_crb: &TpmCrb) -> SDT {
+    let mut ssdt = SDT::new(
+        *b"SSDT",
+        acpi_tables::HEADER_LEN,
+        SSDT_REVISION,
+        *b"CROSVM",
+        *b"CROSVMTP",
+        OEM_REVISION,
+    );
+
+    let mut amls = Vec::new();
+    tpm_crb.to_aml_bytes(&mut amls);
+    ssdt.append_slice(&aml::Scope::raw("\\_SB_".into(), amls));
+
+    ssdt
+}
+
 fn create_dsdt_table(amls: &[u8]) -> SDT {
     let mut dsdt = SDT::new(
         *b"DSDT",
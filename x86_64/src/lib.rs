@@ -58,6 +58,7 @@ use arch::DtbOverlay;
 use arch::FdtPosition;
 use arch::GetSerialCmdlineError;
 use arch::RunnableLinuxVm;
+use arch::SmbiosOptions;
 use arch::VmComponents;
 use arch::VmImage;
 #[cfg(feature = "seccomp_trace")]
@@ -75,6 +76,7 @@ use chrono::Utc;
 pub use cpuid::adjust_cpuid;
 pub use cpuid::CpuIdContext;
 use devices::acpi::PM_WAKEUP_GPIO;
+use devices::virtio::TpmBackend;
 use devices::Bus;
 use devices::BusDevice;
 use devices::BusDeviceObj;
@@ -99,6 +101,7 @@ use devices::ProxyDevice;
 use devices::Serial;
 use devices::SerialHardware;
 use devices::SerialParameters;
+use devices::TpmCrb;
 use devices::VirtualPmc;
 use devices::FW_CFG_BASE_PORT;
 use devices::FW_CFG_MAX_FILE_SLOTS;
@@ -330,6 +333,7 @@ pub struct SetupData {
     pub type_: SetupDataType,
 }
 
+#[derive(Clone, Copy)]
 enum E820Type {
     Ram = 0x01,
     Reserved = 0x2,
@@ -488,50 +492,10 @@ fn configure_system(
         params.ext_ramdisk_size = (initrd_size as u64 >> 32) as u32;
     }
 
-    // Some guest kernels expect a typical PC memory layout where the region between 640 KB and 1 MB
-    // is reserved for device memory/ROMs and get confused if there is a RAM region spanning this
-    // area, so we provide the traditional 640 KB low memory and 1 MB+ high memory regions.
-    let ram_below_1m_end = 640 * 1024;
-    let ram_below_1m = AddressRange {
-        start: START_OF_RAM_32BITS,
-        end: ram_below_1m_end - 1,
-    };
-    // GuestMemory::end_addr() returns the first address past the end, so subtract 1 to get the
-    // inclusive end.
-    let guest_mem_end = guest_mem.end_addr().offset() - 1;
-    let ram_below_4g = AddressRange {
-        start: FIRST_ADDR_PAST_20BITS,
-        end: guest_mem_end.min(read_pci_mmio_before_32bit().start - 1),
-    };
-    let ram_above_4g = AddressRange {
-        start: FIRST_ADDR_PAST_32BITS,
-        end: guest_mem_end,
-    };
-    add_e820_entry(&mut params, ram_below_1m, E820Type::Ram)?;
-    add_e820_entry(&mut params, ram_below_4g, E820Type::Ram)?;
-    if !ram_above_4g.is_empty() {
-        add_e820_entry(&mut params, ram_above_4g, E820Type::Ram)?
+    for (range, mem_type) in e820_entries(guest_mem) {
+        add_e820_entry(&mut params, range, mem_type)?;
     }
 
-    let pcie_cfg_mmio_range = read_pcie_cfg_mmio();
-    add_e820_entry(&mut params, pcie_cfg_mmio_range, E820Type::Reserved)?;
-
-    add_e820_entry(
-        &mut params,
-        X8664arch::get_pcie_vcfg_mmio_range(guest_mem, &pcie_cfg_mmio_range),
-        E820Type::Reserved,
-    )?;
-
-    // Reserve memory section for Identity map and TSS
-    add_e820_entry(
-        &mut params,
-        AddressRange {
-            start: identity_map_addr_start().offset(),
-            end: tss_addr_end().offset() - 1,
-        },
-        E820Type::Reserved,
-    )?;
-
     let zero_page_addr = GuestAddress(ZERO_PAGE_OFFSET);
     if !guest_mem.is_valid_range(zero_page_addr, mem::size_of::<boot_params>() as u64) {
         return Err(Error::ZeroPagePastRamEnd);
@@ -621,6 +585,63 @@ fn setup_data_rng_seed() -> SetupData {
     }
 }
 
+/// Returns the e820 memory map of the guest, as passed to the kernel and to the firmware.
+fn e820_entries(guest_mem: &GuestMemory) -> Vec<(AddressRange, E820Type)> {
+    // Some guest kernels expect a typical PC memory layout where the region between 640 KB and 1 MB
+    // is reserved for device memory/ROMs and get confused if there is a RAM region spanning this
+    // area, so we provide the traditional 640 KB low memory and 1 MB+ high memory regions.
+    let ram_below_1m_end = 640 * 1024;
+    let ram_below_1m = AddressRange {
+        start: START_OF_RAM_32BITS,
+        end: ram_below_1m_end - 1,
+    };
+    // GuestMemory::end_addr() returns the first address past the end, so subtract 1 to get the
+    // inclusive end.
+    let guest_mem_end = guest_mem.end_addr().offset() - 1;
+    let ram_below_4g = AddressRange {
+        start: FIRST_ADDR_PAST_20BITS,
+        end: guest_mem_end.min(read_pci_mmio_before_32bit().start - 1),
+    };
+    let ram_above_4g = AddressRange {
+        start: FIRST_ADDR_PAST_32BITS,
+        end: guest_mem_end,
+    };
+    let mut entries = vec![(ram_below_1m, E820Type::Ram), (ram_below_4g, E820Type::Ram)];
+    if !ram_above_4g.is_empty() {
+        entries.push((ram_above_4g, E820Type::Ram));
+    }
+
+    let pcie_cfg_mmio_range = read_pcie_cfg_mmio();
+    entries.push((pcie_cfg_mmio_range, E820Type::Reserved));
+
+    entries.push((
+        X8664arch::get_pcie_vcfg_mmio_range(guest_mem, &pcie_cfg_mmio_range),
+        E820Type::Reserved,
+    ));
+
+    // Reserve memory section for Identity map and TSS
+    entries.push((
+        AddressRange {
+            start: identity_map_addr_start().offset(),
+            end: tss_addr_end().offset() - 1,
+        },
+        E820Type::Reserved,
+    ));
+
+    entries
+}
+
+/// Returns the e820 memory map in the format of QEMU's `etc/e820` fw_cfg file.
+fn e820_fw_cfg_blob(guest_mem: &GuestMemory) -> Vec<u8> {
+    let mut blob = Vec::new();
+    for (range, mem_type) in e820_entries(guest_mem) {
+        blob.extend_from_slice(&range.start.to_le_bytes());
+        blob.extend_from_slice(&range.len().unwrap_or(0).to_le_bytes());
+        blob.extend_from_slice(&(mem_type as u32).to_le_bytes());
+    }
+    blob
+}
+
 /// Add an e820 region to the e820 map.
 /// Returns Ok(()) if successful, or an error if there is no space left in the map.
 fn add_e820_entry(params: &mut boot_params, range: AddressRange, mem_type: E820Type) -> Result<()> {
@@ -850,17 +871,6 @@ impl arch::LinuxArch for X8664arch {
             Tube::directional_pair().map_err(Error::CreateTube)?;
         let suspend_tube_send = Arc::new(Mutex::new(suspend_tube_send));
 
-        if components.fw_cfg_enable {
-            Self::setup_fw_cfg_device(
-                &io_bus,
-                components.fw_cfg_parameters.clone(),
-                components.bootorder_fw_cfg_blob.clone(),
-                fw_cfg_jail,
-                #[cfg(feature = "swap")]
-                swap_controller,
-            )?;
-        }
-
         if !components.no_i8042 {
             Self::setup_legacy_i8042_device(
                 &io_bus,
@@ -913,6 +923,28 @@ impl arch::LinuxArch for X8664arch {
             )?;
         }
 
+        if let Some(tpm_backend) = components.tpm_crb {
+            let tpm_crb = Self::setup_tpm_crb(tpm_backend, &mmio_bus)?;
+            components.acpi_sdts.push(acpi::create_tpm2_table());
+            components
+                .acpi_sdts
+                .push(acpi::create_tpm_ssdt(&*tpm_crb.lock()));
+        }
+
+        if components.fw_cfg_enable {
+            Self::setup_fw_cfg_device(
+                &io_bus,
+                &mem,
+                components.fw_cfg_parameters.clone(),
+                components.bootorder_fw_cfg_blob.clone(),
+                &components.smbios,
+                bios_size,
+                fw_cfg_jail,
+                #[cfg(feature = "swap")]
+                swap_controller,
+            )?;
+        }
+
         // Functions that use/create jails MUST be used before the call to
         // setup_acpi_devices below, as this move us into a multiprocessing state
         // from which we can no longer fork.
@@ -1637,6 +1669,18 @@ impl X8664arch {
         Ok(())
     }
 
+    fn setup_tpm_crb(backend: Box<dyn TpmBackend>, mmio_bus: &Bus) -> Result<Arc<Mutex<TpmCrb>>> {
+        let tpm_crb = Arc::new(Mutex::new(TpmCrb::new(backend)));
+        mmio_bus
+            .insert(
+                tpm_crb.clone(),
+                devices::tpm_crb::TPM_CRB_BASE,
+                devices::tpm_crb::TPM_CRB_SIZE,
+            )
+            .map_err(Error::InsertBus)?;
+        Ok(tpm_crb)
+    }
+
     /// Loads the kernel from an open file.
     ///
     /// # Arguments
@@ -1783,37 +1827,57 @@ impl X8664arch {
     ///  # Arguments
     ///
     /// * - `io_bus` - the IO bus object
+    /// * - `mem` - the guest memory, used for DMA and to publish the ACPI tables
     /// * - `fw_cfg_parameters` - command-line specified data to add to device. May contain
     /// all None fields if user did not specify data to add to the device
+    /// * - `smbios` - options of the SMBIOS tables, which are also published to the firmware
     fn setup_fw_cfg_device(
         io_bus: &Bus,
+        mem: &GuestMemory,
         fw_cfg_parameters: Vec<FwCfgParameters>,
         bootorder_fw_cfg_blob: Vec<u8>,
+        smbios: &SmbiosOptions,
+        bios_size: u64,
         fw_cfg_jail: Option<Minijail>,
         #[cfg(feature = "swap")] swap_controller: &mut Option<swap::SwapController>,
     ) -> Result<()> {
-        let fw_cfg = match devices::FwCfgDevice::new(FW_CFG_MAX_FILE_SLOTS, fw_cfg_parameters) {
-            Ok(mut device) => {
-                // this condition will only be true if the user specified at least one bootindex
-                // option on the command line. If none were specified, bootorder_fw_cfg_blob will
-                // only have a null byte (null terminator)
-                if bootorder_fw_cfg_blob.len() > 1 {
-                    // Add boot order file to the device. If the file is not present, firmware may
-                    // not be able to boot.
-                    if let Err(err) = device.add_file(
-                        "bootorder",
-                        bootorder_fw_cfg_blob,
-                        devices::FwCfgItemType::GenericItem,
-                    ) {
-                        return Err(Error::CreateFwCfgDevice(err));
-                    }
-                }
-                device
-            }
-            Err(err) => {
-                return Err(Error::CreateFwCfgDevice(err));
-            }
-        };
+        let mut fw_cfg = devices::FwCfgDevice::new(FW_CFG_MAX_FILE_SLOTS, fw_cfg_parameters)
+            .map_err(Error::CreateFwCfgDevice)?;
+        // this condition will only be true if the user specified at least one bootindex
+        // option on the command line. If none were specified, bootorder_fw_cfg_blob will
+        // only have a null byte (null terminator)
+        if bootorder_fw_cfg_blob.len() > 1 {
+            // Add boot order file to the device. If the file is not present, firmware may
+            // not be able to boot.
+            fw_cfg
+                .add_file(
+                    "bootorder",
+                    bootorder_fw_cfg_blob,
+                    devices::FwCfgItemType::GenericItem,
+                )
+                .map_err(Error::CreateFwCfgDevice)?;
+        }
+
+        // The tables are the same as the ones written to guest memory by setup_smbios, and the
+        // firmware fills in the address in the entry point.
+        let smbios_tables =
+            smbios::create_smbios_tables(smbios, bios_size).map_err(Error::SetupSmbios)?;
+        let smbios_anchor = smbios::create_smbios_entrypoint(0, smbios_tables.len() as u32);
+        for (name, data) in [
+            ("etc/e820", e820_fw_cfg_blob(mem)),
+            ("etc/smbios/smbios-tables", smbios_tables),
+            (
+                "etc/smbios/smbios-anchor",
+                smbios_anchor.as_bytes().to_vec(),
+            ),
+        ] {
+            fw_cfg
+                .add_file(name, data, devices::FwCfgItemType::GenericItem)
+                .map_err(Error::CreateFwCfgDevice)?;
+        }
+
+        fw_cfg.set_guest_memory(mem.clone());
+        fw_cfg.publish_acpi_tables(GuestAddress(ACPI_HI_RSDP_WINDOW_BASE));
 
         let fw_cfg: Arc<Mutex<dyn BusDevice>> = match fw_cfg_jail.as_ref() {
             #[cfg(any(target_os = "android", target_os = "linux"))]
@@ -1829,7 +1893,7 @@ impl X8664arch {
                     ProxyDevice::new(
                         fw_cfg,
                         jail_clone,
-                        Vec::new(),
+                        mem.as_raw_descriptors(),
                         #[cfg(feature = "swap")]
                         swap_controller,
                     )
@@ -2484,6 +2548,27 @@ mod tests {
         assert_eq!(read_pci_mmio_before_32bit().start % (256 * MB), 0);
     }
 
+    #[test]
+    fn e820_fw_cfg_blob_layout() {
+        setup();
+        let mem = GuestMemory::new(&[(GuestAddress(0), 512 * MB)]).unwrap();
+        let blob = e820_fw_cfg_blob(&mem);
+        // Two RAM entries below 4 GB and three reserved ones, 20 bytes each.
+        assert_eq!(blob.len(), 5 * 20);
+
+        let entry = |i: usize| {
+            let raw = &blob[i * 20..(i + 1) * 20];
+            (
+                u64::from_le_bytes(raw[0..8].try_into().unwrap()),
+                u64::from_le_bytes(raw[8..16].try_into().unwrap()),
+                u32::from_le_bytes(raw[16..20].try_into().unwrap()),
+            )
+        };
+        assert_eq!(entry(0), (0, 640 * 1024, E820Type::Ram as u32));
+        assert_eq!(entry(1), (MB, 511 * MB, E820Type::Ram as u32));
+        assert_eq!(entry(2), (3 * GB, 256 * MB, E820Type::Reserved as u32));
+    }
+
     #[test]
     fn write_setup_data_empty() {
         let mem = GuestMemory::new(&[(GuestAddress(0), 0x2_0000)]).unwrap();
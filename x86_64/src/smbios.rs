@@ -169,35 +169,22 @@ pub struct SmbiosEndOfTable {
     pub handle: u16,
 }
 
-fn write_and_incr<T: AsBytes + FromBytes>(
-    mem: &GuestMemory,
-    val: T,
-    mut curptr: GuestAddress,
-) -> Result<GuestAddress> {
-    mem.write_obj_at_addr(val, curptr)
-        .map_err(|_| Error::WriteData)?;
-    curptr = curptr
-        .checked_add(mem::size_of::<T>() as u64)
-        .ok_or(Error::NotEnoughMemory)?;
-    Ok(curptr)
+fn push_obj<T: AsBytes>(table: &mut Vec<u8>, val: T) {
+    table.extend_from_slice(val.as_bytes());
 }
 
-fn write_string(mem: &GuestMemory, val: &str, mut curptr: GuestAddress) -> Result<GuestAddress> {
-    for c in val.as_bytes().iter() {
-        if *c == 0 {
-            return Err(Error::StringHasNullCharacter);
-        }
-        curptr = write_and_incr(mem, *c, curptr)?;
+fn push_string(table: &mut Vec<u8>, val: &str) -> Result<()> {
+    if val.as_bytes().contains(&0) {
+        return Err(Error::StringHasNullCharacter);
     }
-    curptr = write_and_incr(mem, 0_u8, curptr)?;
-    Ok(curptr)
+    table.extend_from_slice(val.as_bytes());
+    table.push(0);
+    Ok(())
 }
 
-pub fn setup_smbios(mem: &GuestMemory, options: &SmbiosOptions, bios_size: u64) -> Result<()> {
-    let physptr = GuestAddress(SMBIOS_START)
-        .checked_add(mem::size_of::<Smbios30Entrypoint>() as u64)
-        .ok_or(Error::NotEnoughMemory)?;
-    let mut curptr = physptr;
+/// Builds the SMBIOS structure table, without the entry point.
+pub fn create_smbios_tables(options: &SmbiosOptions, bios_size: u64) -> Result<Vec<u8>> {
+    let mut table = Vec::new();
     let mut handle = 0;
 
     {
@@ -220,24 +207,22 @@ pub fn setup_smbios(mem: &GuestMemory, options: &SmbiosOptions, bios_size: u64)
             rom_size,
             ..Default::default()
         };
-        curptr = write_and_incr(mem, smbios_biosinfo, curptr)?;
-        curptr = write_string(
-            mem,
+        push_obj(&mut table, smbios_biosinfo);
+        push_string(
+            &mut table,
             options
                 .bios_vendor
                 .as_deref()
                 .unwrap_or(DEFAULT_SMBIOS_BIOS_VENDOR),
-            curptr,
         )?;
-        curptr = write_string(
-            mem,
+        push_string(
+            &mut table,
             options
                 .bios_version
                 .as_deref()
                 .unwrap_or(DEFAULT_SMBIOS_BIOS_VERSION),
-            curptr,
         )?;
-        curptr = write_and_incr(mem, 0_u8, curptr)?;
+        table.push(0);
     }
 
     {
@@ -257,27 +242,25 @@ pub fn setup_smbios(mem: &GuestMemory, options: &SmbiosOptions, bios_size: u64)
             },
             ..Default::default()
         };
-        curptr = write_and_incr(mem, smbios_sysinfo, curptr)?;
-        curptr = write_string(
-            mem,
+        push_obj(&mut table, smbios_sysinfo);
+        push_string(
+            &mut table,
             options
                 .manufacturer
                 .as_deref()
                 .unwrap_or(DEFAULT_SMBIOS_MANUFACTURER),
-            curptr,
         )?;
-        curptr = write_string(
-            mem,
+        push_string(
+            &mut table,
             options
                 .product_name
                 .as_deref()
                 .unwrap_or(DEFAULT_SMBIOS_PRODUCT_NAME),
-            curptr,
         )?;
         if let Some(serial_number) = options.serial_number.as_deref() {
-            curptr = write_string(mem, serial_number, curptr)?;
+            push_string(&mut table, serial_number)?;
         }
-        curptr = write_and_incr(mem, 0u8, curptr)?;
+        table.push(0);
     }
 
     if !options.oem_strings.is_empty() {
@@ -294,11 +277,11 @@ pub fn setup_smbios(mem: &GuestMemory, options: &SmbiosOptions, bios_size: u64)
             handle,
             count: options.oem_strings.len() as u8,
         };
-        curptr = write_and_incr(mem, smbios_oemstring, curptr)?;
+        push_obj(&mut table, smbios_oemstring);
         for oem_string in &options.oem_strings {
-            curptr = write_string(mem, oem_string, curptr)?;
+            push_string(&mut table, oem_string)?;
         }
-        curptr = write_and_incr(mem, 0u8, curptr)?;
+        table.push(0);
     }
 
     {
@@ -308,26 +291,41 @@ pub fn setup_smbios(mem: &GuestMemory, options: &SmbiosOptions, bios_size: u64)
             length: mem::size_of::<SmbiosEndOfTable>() as u8,
             handle,
         };
-        curptr = write_and_incr(mem, smbios_sysinfo, curptr)?;
-        curptr = write_and_incr(mem, 0_u8, curptr)?; // No strings
-        curptr = write_and_incr(mem, 0_u8, curptr)?; // Structure terminator
+        push_obj(&mut table, smbios_sysinfo);
+        table.push(0); // No strings
+        table.push(0); // Structure terminator
     }
 
-    {
-        let mut smbios_ep = Smbios30Entrypoint::default();
-        smbios_ep.signature = *SM3_MAGIC_IDENT;
-        smbios_ep.length = mem::size_of::<Smbios30Entrypoint>() as u8;
-        // SMBIOS rev 3.2.0
-        smbios_ep.majorver = 0x03;
-        smbios_ep.minorver = 0x02;
-        smbios_ep.docrev = 0x00;
-        smbios_ep.revision = 0x01; // SMBIOS 3.0
-        smbios_ep.max_size = curptr.offset_from(physptr) as u32;
-        smbios_ep.physptr = physptr.offset();
-        smbios_ep.checksum = compute_checksum(&smbios_ep);
-        mem.write_obj_at_addr(smbios_ep, GuestAddress(SMBIOS_START))
-            .map_err(|_| Error::WriteSmbiosEp)?;
-    }
+    Ok(table)
+}
+
+/// Builds the SMBIOS 3.0 entry point of a structure table of `max_size` bytes at `physptr`.
+pub fn create_smbios_entrypoint(physptr: u64, max_size: u32) -> Smbios30Entrypoint {
+    let mut smbios_ep = Smbios30Entrypoint::default();
+    smbios_ep.signature = *SM3_MAGIC_IDENT;
+    smbios_ep.length = mem::size_of::<Smbios30Entrypoint>() as u8;
+    // SMBIOS rev 3.2.0
+    smbios_ep.majorver = 0x03;
+    smbios_ep.minorver = 0x02;
+    smbios_ep.docrev = 0x00;
+    smbios_ep.revision = 0x01; // SMBIOS 3.0
+    smbios_ep.max_size = max_size;
+    smbios_ep.physptr = physptr;
+    smbios_ep.checksum = compute_checksum(&smbios_ep);
+    smbios_ep
+}
+
+pub fn setup_smbios(mem: &GuestMemory, options: &SmbiosOptions, bios_size: u64) -> Result<()> {
+    let physptr = GuestAddress(SMBIOS_START)
+        .checked_add(mem::size_of::<Smbios30Entrypoint>() as u64)
+        .ok_or(Error::NotEnoughMemory)?;
+    let table = create_smbios_tables(options, bios_size)?;
+    mem.write_all_at_addr(&table, physptr)
+        .map_err(|_| Error::WriteData)?;
+
+    let smbios_ep = create_smbios_entrypoint(physptr.offset(), table.len() as u32);
+    mem.write_obj_at_addr(smbios_ep, GuestAddress(SMBIOS_START))
+        .map_err(|_| Error::WriteSmbiosEp)?;
 
     Ok(())
 }
@@ -0,0 +1,98 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Runs the long mode boot setup of the x86_64 crate on the software emulator, so that it is
+//! exercised on hosts without `/dev/kvm`.
+
+#![cfg(feature = "emulator")]
+
+use std::collections::BTreeMap;
+
+use hypervisor::emulator::Emulator;
+use hypervisor::emulator::EmulatorVm;
+use hypervisor::IoOperation;
+use hypervisor::IoParams;
+use hypervisor::Regs;
+use hypervisor::Sregs;
+use hypervisor::VcpuExit;
+use hypervisor::VmX86_64;
+use vm_memory::GuestAddress;
+use vm_memory::GuestMemory;
+use x86_64::regs;
+
+const CODE_ADDR: u64 = 0x10000;
+// In the second 2MB page of the identity map.
+const DATA_ADDR: u64 = 0x30_0000;
+// Mapped by the boot page tables, but not backed by guest memory.
+const MMIO_ADDR: u64 = 0x3000_0000;
+const MAGIC: u64 = 0x0123_4567_89ab_cdef;
+
+#[test]
+fn long_mode_boot() {
+    let mem = GuestMemory::new(&[(GuestAddress(0), 0x40_0000)]).unwrap();
+    let code = [
+        0x48, 0x8b, 0x04, 0x25, 0x00, 0x00, 0x30, 0x00, // mov rax, [DATA_ADDR]
+        0x48, 0x89, 0x04, 0x25, 0x00, 0x00, 0x00, 0x30, // mov [MMIO_ADDR], rax
+        0xf4, // hlt
+    ];
+    mem.write_all_at_addr(&code, GuestAddress(CODE_ADDR))
+        .unwrap();
+    mem.write_obj_at_addr(MAGIC, GuestAddress(DATA_ADDR))
+        .unwrap();
+
+    // Same sequence as `X8664arch::build_vm` for a 64-bit kernel.
+    let mut sregs = Sregs::default();
+    regs::configure_segments_and_sregs(&mem, &mut sregs).unwrap();
+    regs::setup_page_tables(&mem, &mut sregs).unwrap();
+    let mut msrs = BTreeMap::new();
+    regs::set_long_mode_msrs(&mut msrs);
+
+    let emulator = Emulator::new();
+    let vm = EmulatorVm::new(&emulator, mem).unwrap();
+    let mut vcpu = vm.create_vcpu(0).unwrap();
+    vcpu.set_regs(&Regs {
+        rip: CODE_ADDR,
+        rflags: 2,
+        ..Default::default()
+    })
+    .unwrap();
+    vcpu.set_sregs(&sregs).unwrap();
+    for (msr_index, value) in msrs {
+        vcpu.set_msr(msr_index, value).unwrap();
+    }
+
+    let mut mmio_data = None;
+    loop {
+        match vcpu.run().unwrap() {
+            VcpuExit::Mmio => {
+                vcpu.handle_mmio(&mut |IoParams {
+                                           address,
+                                           size,
+                                           operation,
+                                       }| {
+                    assert_eq!(address, MMIO_ADDR);
+                    assert_eq!(size, 8);
+                    match operation {
+                        IoOperation::Write { data } => {
+                            mmio_data = Some(u64::from_le_bytes(data));
+                            None
+                        }
+                        IoOperation::Read => panic!("unexpected MMIO read"),
+                    }
+                })
+                .unwrap();
+            }
+            VcpuExit::Hlt => break,
+            r => panic!("unexpected exit reason: {:?}", r),
+        }
+    }
+
+    assert_eq!(mmio_data, Some(MAGIC));
+    let regs = vcpu.get_regs().unwrap();
+    assert_eq!(regs.rax, MAGIC);
+    assert_eq!(regs.rip, CODE_ADDR + code.len() as u64);
+    let sregs = vcpu.get_sregs().unwrap();
+    assert_eq!(sregs.cs.l, 1);
+    assert_ne!(sregs.efer & 0x400, 0, "long mode is not active");
+}